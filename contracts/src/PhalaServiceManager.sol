@@ -35,6 +35,64 @@ contract PhalaServiceManager is ServiceManagerBase {
     /// @notice Whether the contract has been initialized.
     bool public initialized;
 
+    /// @notice A planned maintenance window registered by an operator.
+    struct MaintenanceWindow {
+        address operator;
+        bytes32 workloadId;
+        uint64 startTime;
+        uint64 endTime;
+        bool cancelled;
+    }
+
+    /// @notice Maintenance windows by ID.
+    mapping(uint256 => MaintenanceWindow) public maintenanceWindows;
+
+    /// @notice ID assigned to the next registered maintenance window.
+    uint256 public nextMaintenanceWindowId;
+
+    /// @notice Anchored evidence roots by operator and window ID.
+    mapping(address => mapping(uint64 => bytes32)) public evidenceRoots;
+
+    /// @notice Capacity an operator last advertised for new workloads.
+    struct Capacity {
+        uint32 vcpus;
+        uint64 memoryMb;
+        uint64 storageGb;
+        uint8 platform;
+        uint64 updatedAt;
+    }
+
+    /// @notice Advertised capacity by operator.
+    mapping(address => Capacity) public operatorCapacity;
+
+    /// @notice Workloads each operator is assigned to host.
+    mapping(address => bytes32[]) internal assignedWorkloads;
+
+    /// @notice Blocks between an operator's exit request and its earliest quorum deregistration.
+    uint64 public exitDelayBlocks;
+
+    /// @notice First block each exiting operator may deregister from its quorums in.
+    mapping(address => uint256) public exitAllowedFrom;
+
+    /// @notice Account each operator sends deferrable transactions from, if any.
+    mapping(address => address) public maintenanceSigners;
+
+    /// @notice Operator each maintenance signer acts for.
+    mapping(address => address) public maintenanceSignerOperator;
+
+    /// @notice Wei credited to an operator for each accepted challenge response; zero disables
+    ///         reimbursement.
+    uint256 public reimbursementPerResponse;
+
+    /// @notice Reimbursement each operator has been credited and not claimed yet.
+    mapping(address => uint256) public claimableReimbursement;
+
+    /// @notice Reimbursement clawed back after it was claimed, settled against future credits.
+    mapping(address => uint256) public reimbursementDebt;
+
+    /// @notice Amount credited per operator and challenge, kept for clawbacks.
+    mapping(address => mapping(uint256 => uint256)) public reimbursements;
+
     // --- Events ---
 
     /// @notice Emitted when an operator submits an attestation for registration.
@@ -52,6 +110,56 @@ contract PhalaServiceManager is ServiceManagerBase {
     /// @notice Emitted when a liveness failure is recorded from the SLA Oracle.
     event OperatorLivenessFailureRecorded(address indexed operator);
 
+    /// @notice Emitted when an operator registers a planned maintenance window.
+    event MaintenanceWindowRegistered(
+        address indexed operator,
+        uint256 indexed windowId,
+        bytes32 workloadId,
+        uint64 startTime,
+        uint64 endTime
+    );
+
+    /// @notice Emitted when an operator cancels a maintenance window.
+    event MaintenanceWindowCancelled(address indexed operator, uint256 indexed windowId);
+
+    /// @notice Emitted when an operator anchors the Merkle root of its evidence for a window.
+    event EvidenceRootAnchored(address indexed operator, uint64 indexed windowId, bytes32 root);
+
+    /// @notice Emitted when an operator advertises the TEE capacity it can take workloads on.
+    event CapacityUpdated(
+        address indexed operator, uint32 vcpus, uint64 memoryMb, uint64 storageGb, uint8 platform
+    );
+
+    /// @notice Emitted when a workload is assigned to an operator.
+    event WorkloadAssigned(address indexed operator, bytes32 indexed workloadId);
+
+    /// @notice Emitted when a workload assignment is removed.
+    event WorkloadUnassigned(address indexed operator, bytes32 indexed workloadId);
+
+    /// @notice Emitted when an operator opts out of the AVS.
+    event OperatorExitRequested(address indexed operator, uint256 deregisterFromBlock);
+
+    /// @notice Emitted when the exit delay is changed.
+    event ExitDelayUpdated(uint64 delayBlocks);
+
+    /// @notice Emitted when an operator designates, or clears, its maintenance signer.
+    event MaintenanceSignerSet(address indexed operator, address indexed signer);
+
+    /// @notice Emitted when the reimbursement per accepted response is changed.
+    event ReimbursementPolicyUpdated(uint256 perResponse);
+
+    /// @notice Emitted when the reimbursement pool is funded.
+    event ReimbursementPoolFunded(address indexed from, uint256 amount);
+
+    /// @notice Emitted when an accepted response is credited to its operator.
+    event ReimbursementCredited(address indexed operator, uint256 indexed challengeId, uint256 amount);
+
+    /// @notice Emitted when an operator claims its credited reimbursement.
+    event ReimbursementClaimed(address indexed operator, uint256 amount);
+
+    /// @notice Emitted when the reimbursement of a response rejected in a dispute is taken back.
+    event ReimbursementClawedBack(address indexed operator, uint256 indexed challengeId, uint256 amount);
+
     // --- Modifiers ---
 
     /// @notice Ensures the caller is the authorized Tokenomic Manager.
@@ -186,8 +294,247 @@ contract PhalaServiceManager is ServiceManagerBase {
         emit OperatorLivenessFailureRecorded(operator);
     }
 
+    // --- Maintenance Windows ---
+
+    /**
+     * @notice Registers a window during which SLA challenges are not held against the caller.
+     * @param workloadId The exempted workload, or zero for all of the caller's workloads.
+     * @param startTime Window start (unix seconds, inclusive).
+     * @param endTime Window end (unix seconds, exclusive).
+     * @return windowId The unique ID assigned to this window.
+     */
+    function registerMaintenanceWindow(bytes32 workloadId, uint64 startTime, uint64 endTime)
+        external
+        isInitialized
+        returns (uint256 windowId)
+    {
+        require(isOperatorRegistered(msg.sender), "PhalaSM: Operator not registered");
+        require(startTime < endTime, "PhalaSM: Invalid maintenance window");
+        require(startTime >= block.timestamp, "PhalaSM: Maintenance window in the past");
+        windowId = nextMaintenanceWindowId++;
+        maintenanceWindows[windowId] = MaintenanceWindow(msg.sender, workloadId, startTime, endTime, false);
+        emit MaintenanceWindowRegistered(msg.sender, windowId, workloadId, startTime, endTime);
+    }
+
+    /**
+     * @notice Cancels a maintenance window registered by the caller.
+     * @param windowId The ID of the window to cancel.
+     */
+    function cancelMaintenanceWindow(uint256 windowId) external isInitialized {
+        MaintenanceWindow storage window = maintenanceWindows[windowId];
+        require(window.operator == msg.sender, "PhalaSM: Not the window owner");
+        require(!window.cancelled, "PhalaSM: Maintenance window already cancelled");
+        window.cancelled = true;
+        emit MaintenanceWindowCancelled(msg.sender, windowId);
+    }
+
+    // --- Evidence Anchoring ---
+
+    /**
+     * @notice Commits the Merkle root of the caller's evidence for an elapsed window.
+     * @param root Merkle root over the caller's evidence records of the window.
+     * @param windowId The evidence window the root covers.
+     */
+    function anchorEvidenceRoot(bytes32 root, uint64 windowId) external isInitialized {
+        address operator = _maintainedOperator();
+        require(isOperatorRegistered(operator), "PhalaSM: Operator not registered");
+        require(root != bytes32(0), "PhalaSM: Empty evidence root");
+        require(evidenceRoots[operator][windowId] == bytes32(0), "PhalaSM: Window already anchored");
+        evidenceRoots[operator][windowId] = root;
+        emit EvidenceRootAnchored(operator, windowId, root);
+    }
+
+    /**
+     * @notice Commits the capacity the caller currently has available for new workloads.
+     * @param vcpus Available vCPUs.
+     * @param memoryMb Available memory, in MiB.
+     * @param storageGb Available storage, in GiB.
+     * @param platform TEE platform: 1 for TDX, 2 for SGX.
+     */
+    function updateCapacity(uint32 vcpus, uint64 memoryMb, uint64 storageGb, uint8 platform)
+        external
+        isInitialized
+    {
+        address operator = _maintainedOperator();
+        require(isOperatorRegistered(operator), "PhalaSM: Operator not registered");
+        require(platform == 1 || platform == 2, "PhalaSM: Unknown TEE platform");
+        operatorCapacity[operator] = Capacity(vcpus, memoryMb, storageGb, platform, uint64(block.timestamp));
+        emit CapacityUpdated(operator, vcpus, memoryMb, storageGb, platform);
+    }
+
+    // --- Maintenance Signers ---
+
+    /**
+     * @notice Designates the account the caller sends deferrable transactions from.
+     * @dev Evidence anchors and capacity updates sent by `signer` are attributed to the caller.
+     *      Passing the zero address clears the designation.
+     * @param signer The maintenance account, or zero.
+     */
+    function setMaintenanceSigner(address signer) external isInitialized {
+        require(isOperatorRegistered(msg.sender), "PhalaSM: Operator not registered");
+        require(signer != msg.sender, "PhalaSM: Signer is the operator");
+        require(
+            signer == address(0) || maintenanceSignerOperator[signer] == address(0),
+            "PhalaSM: Signer already in use"
+        );
+        address previous = maintenanceSigners[msg.sender];
+        if (previous != address(0)) {
+            delete maintenanceSignerOperator[previous];
+        }
+        maintenanceSigners[msg.sender] = signer;
+        if (signer != address(0)) {
+            maintenanceSignerOperator[signer] = msg.sender;
+        }
+        emit MaintenanceSignerSet(msg.sender, signer);
+    }
+
+    /// @notice The operator the caller acts for: its designating operator, or itself.
+    function _maintainedOperator() internal view returns (address) {
+        address operator = maintenanceSignerOperator[msg.sender];
+        return operator == address(0) ? msg.sender : operator;
+    }
+
+    // --- Response Reimbursement ---
+
+    /**
+     * @notice Funds the pool accepted challenge responses are reimbursed from.
+     */
+    function fundReimbursementPool() external payable isInitialized {
+        emit ReimbursementPoolFunded(msg.sender, msg.value);
+    }
+
+    /**
+     * @notice Credits an operator for an accepted challenge response, under the current policy.
+     * @dev Only callable by the Phala SLA Oracle. Credits settle any outstanding debt first.
+     * @param operator The operator whose response was accepted.
+     * @param challengeId The challenge the response answered.
+     */
+    function creditReimbursement(address operator, uint256 challengeId) external onlySlaOracle isInitialized {
+        uint256 amount = reimbursementPerResponse;
+        if (amount == 0 || reimbursements[operator][challengeId] != 0) {
+            return;
+        }
+        reimbursements[operator][challengeId] = amount;
+        uint256 debt = reimbursementDebt[operator];
+        uint256 settled = debt < amount ? debt : amount;
+        reimbursementDebt[operator] = debt - settled;
+        claimableReimbursement[operator] += amount - settled;
+        emit ReimbursementCredited(operator, challengeId, amount);
+    }
+
+    /**
+     * @notice Pays out the caller's credited reimbursement.
+     * @dev A maintenance signer claims for its operator; the reimbursement is paid to the operator.
+     * @return amount The amount paid.
+     */
+    function claimReimbursement() external isInitialized returns (uint256 amount) {
+        address operator = _maintainedOperator();
+        amount = claimableReimbursement[operator];
+        require(amount > 0, "PhalaSM: Nothing to claim");
+        require(address(this).balance >= amount, "PhalaSM: Reimbursement pool exhausted");
+        claimableReimbursement[operator] = 0;
+        (bool sent,) = payable(operator).call{value: amount}("");
+        require(sent, "PhalaSM: Reimbursement transfer failed");
+        emit ReimbursementClaimed(operator, amount);
+    }
+
+    /**
+     * @notice Takes back the reimbursement of a response later rejected in a dispute.
+     * @dev Only callable by the Tokenomic Manager. What was already claimed becomes debt.
+     * @param operator The operator the response was credited to.
+     * @param challengeId The challenge the rejected response answered.
+     */
+    function clawbackReimbursement(address operator, uint256 challengeId)
+        external
+        onlyTokenomicManager
+        isInitialized
+    {
+        uint256 amount = reimbursements[operator][challengeId];
+        require(amount > 0, "PhalaSM: Response was not reimbursed");
+        delete reimbursements[operator][challengeId];
+        uint256 claimable = claimableReimbursement[operator];
+        if (claimable >= amount) {
+            claimableReimbursement[operator] = claimable - amount;
+        } else {
+            claimableReimbursement[operator] = 0;
+            reimbursementDebt[operator] += amount - claimable;
+        }
+        emit ReimbursementClawedBack(operator, challengeId, amount);
+    }
+
+    // --- Workload Assignments ---
+
+    /**
+     * @notice Assigns a workload to an operator.
+     * @dev Only callable by the contract owner.
+     * @param operator The operator to host the workload.
+     * @param workloadId The workload to assign.
+     */
+    function assignWorkload(address operator, bytes32 workloadId) external onlyOwner isInitialized {
+        require(isOperatorRegistered(operator), "PhalaSM: Operator not registered");
+        bytes32[] storage workloads = assignedWorkloads[operator];
+        for (uint256 i = 0; i < workloads.length; i++) {
+            require(workloads[i] != workloadId, "PhalaSM: Workload already assigned");
+        }
+        workloads.push(workloadId);
+        emit WorkloadAssigned(operator, workloadId);
+    }
+
+    /**
+     * @notice Removes a workload assignment.
+     * @dev Only callable by the contract owner.
+     * @param operator The operator hosting the workload.
+     * @param workloadId The workload to unassign.
+     */
+    function unassignWorkload(address operator, bytes32 workloadId) external onlyOwner isInitialized {
+        bytes32[] storage workloads = assignedWorkloads[operator];
+        for (uint256 i = 0; i < workloads.length; i++) {
+            if (workloads[i] == workloadId) {
+                workloads[i] = workloads[workloads.length - 1];
+                workloads.pop();
+                emit WorkloadUnassigned(operator, workloadId);
+                return;
+            }
+        }
+        revert("PhalaSM: Workload not assigned");
+    }
+
+    // --- Voluntary Exit ---
+
+    /**
+     * @notice Opts the caller out of the AVS, starting its exit.
+     * @dev The caller may deregister from its quorums once `exitDelayBlocks` have passed.
+     */
+    function requestExit() external isInitialized {
+        require(isOperatorRegistered(msg.sender), "PhalaSM: Operator not registered");
+        require(exitAllowedFrom[msg.sender] == 0, "PhalaSM: Exit already requested");
+        uint256 allowedFrom = block.number + exitDelayBlocks;
+        exitAllowedFrom[msg.sender] = allowedFrom;
+        emit OperatorExitRequested(msg.sender, allowedFrom);
+    }
+
     // --- Admin Functions ---
 
+    /**
+     * @notice Sets the wei credited for each accepted challenge response.
+     * @dev Only callable by the Tokenomic Manager. Zero stops crediting new responses.
+     * @param _perResponse The new reimbursement per response.
+     */
+    function setReimbursementPolicy(uint256 _perResponse) external onlyTokenomicManager isInitialized {
+        reimbursementPerResponse = _perResponse;
+        emit ReimbursementPolicyUpdated(_perResponse);
+    }
+
+    /**
+     * @notice Sets the delay between an exit request and the quorum deregistration.
+     * @dev Only callable by the contract owner.
+     * @param _delayBlocks The new delay, in blocks.
+     */
+    function setExitDelayBlocks(uint64 _delayBlocks) external onlyOwner isInitialized {
+        exitDelayBlocks = _delayBlocks;
+        emit ExitDelayUpdated(_delayBlocks);
+    }
+
     /**
      * @notice Updates the address of the Tokenomic Manager.
      * @dev Only callable by the contract owner.
@@ -209,4 +556,21 @@ contract PhalaServiceManager is ServiceManagerBase {
     function isOperatorRegistered(address operator) public view returns (bool) {
         return registeredOperatorAttestationHash[operator] != bytes32(0);
     }
+
+    /**
+     * @notice Number of workloads assigned to an operator.
+     * @param operator The address of the operator.
+     */
+    function assignedWorkloadCount(address operator) external view returns (uint256) {
+        return assignedWorkloads[operator].length;
+    }
+
+    /**
+     * @notice A workload assigned to an operator, by position.
+     * @param operator The address of the operator.
+     * @param index Position in the operator's assignments, below `assignedWorkloadCount`.
+     */
+    function assignedWorkloadAt(address operator, uint256 index) external view returns (bytes32) {
+        return assignedWorkloads[operator][index];
+    }
 } 
\ No newline at end of file
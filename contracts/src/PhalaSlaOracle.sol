@@ -23,6 +23,11 @@ contract PhalaSlaOracle is IPhalaSlaOracle, Ownable, Pausable, ReentrancyGuard {
         bool reported; // Flag indicating if expiry has been reported
     }
 
+    // --- Constants ---
+
+    /// @notice `SlaResponseRejected` reason code of a response carrying no data.
+    uint8 public constant REJECT_EMPTY_RESPONSE = 1;
+
     // --- State Variables ---
 
     /// @notice Address of the Phala Service Manager.
@@ -32,17 +37,26 @@ contract PhalaSlaOracle is IPhalaSlaOracle, Ownable, Pausable, ReentrancyGuard {
     address public challengeIssuer;
 
     /// @notice Counter for generating unique challenge IDs.
-    uint256 public challengeCounter;
+    uint256 public override challengeCounter;
 
     /// @notice Mapping from challenge ID to the Challenge struct.
     mapping(uint256 => Challenge) public challenges;
 
+    /// @notice Challenges withdrawn by the Challenge Issuer.
+    mapping(uint256 => bool) public cancelledChallenges;
+
     /// @notice The duration (in blocks) operators have to respond to a challenge.
     uint256 public responseWindowBlocks;
 
     /// @notice Whether the contract has been initialized.
     bool public initialized;
 
+    /// @notice Published response schemas, one per challenge kind and version.
+    ResponseSchema[] internal _responseSchemas;
+
+    /// @notice Policy attestation responses are verified against.
+    AttestationPolicy internal _attestationPolicy;
+
     // --- Events ---
 
     /// @notice Emitted when the Challenge Issuer address is updated.
@@ -123,7 +137,8 @@ contract PhalaSlaOracle is IPhalaSlaOracle, Ownable, Pausable, ReentrancyGuard {
 
     /**
      * @notice Allows an operator to respond to an active SLA challenge.
-     * @dev Can only be called by the challenged operator before the window ends.
+     * @dev Can only be called by the challenged operator before the window ends. An empty response
+     *      is rejected without reverting, leaving the challenge open for a corrected one.
      * @param challengeId The ID of the challenge to respond to.
      * @param responseData Data proving the operator meets the challenged SLA.
      */
@@ -136,12 +151,60 @@ contract PhalaSlaOracle is IPhalaSlaOracle, Ownable, Pausable, ReentrancyGuard {
         require(msg.sender == challenge.operator, "PhalaSLA: Caller is not the challenged operator");
         require(block.number <= challenge.responseWindowEndBlock, "PhalaSLA: Response window closed");
         require(!challenge.responded, "PhalaSLA: Challenge already responded to");
+        require(!cancelledChallenges[challengeId], "PhalaSLA: Challenge was cancelled");
+
+        if (responseData.length == 0) {
+            emit SlaResponseRejected(challengeId, msg.sender, REJECT_EMPTY_RESPONSE);
+            return;
+        }
 
         challenge.responded = true;
 
         // The response data itself is just stored via the event for off-chain verification/logging.
         // The act of calling this function successfully is the proof of liveness for this mechanism.
         emit SlaChallengeResponded(challengeId, msg.sender, responseData);
+        serviceManager.creditReimbursement(msg.sender, challengeId);
+    }
+
+    /**
+     * @notice Withdraws an open challenge.
+     * @dev Only callable by the authorized Challenge Issuer, before the challenge was responded to or reported.
+     * @param challengeId The ID of the challenge to cancel.
+     */
+    function cancelSlaChallenge(uint256 challengeId) external override onlyChallengeIssuer isInitialized {
+        _openChallenge(challengeId);
+        cancelledChallenges[challengeId] = true;
+
+        emit SlaChallengeCancelled(challengeId);
+    }
+
+    /**
+     * @notice Replaces the data and deadline of an open challenge.
+     * @dev Only callable by the authorized Challenge Issuer, before the challenge was responded to or reported.
+     * @param challengeId The ID of the challenge to amend.
+     * @param newData The data replacing the challenge's data.
+     * @param newDeadline The block number by which the operator must now respond; must be in the future.
+     */
+    function amendSlaChallenge(
+        uint256 challengeId,
+        bytes calldata newData,
+        uint256 newDeadline
+    ) external override onlyChallengeIssuer isInitialized {
+        require(newDeadline > block.number, "PhalaSLA: Deadline must be in the future");
+        Challenge storage challenge = _openChallenge(challengeId);
+        challenge.challengeData = newData;
+        challenge.responseWindowEndBlock = newDeadline;
+
+        emit SlaChallengeAmended(challengeId, newData, newDeadline);
+    }
+
+    /// @notice Returns a challenge that exists and is neither responded to, reported nor cancelled.
+    function _openChallenge(uint256 challengeId) internal view returns (Challenge storage challenge) {
+        challenge = challenges[challengeId];
+        require(challenge.operator != address(0), "PhalaSLA: Challenge does not exist");
+        require(!cancelledChallenges[challengeId], "PhalaSLA: Challenge was cancelled");
+        require(!challenge.responded, "PhalaSLA: Challenge already responded to");
+        require(!challenge.reported, "PhalaSLA: Challenge expiry already reported");
     }
 
     /**
@@ -155,6 +218,7 @@ contract PhalaSlaOracle is IPhalaSlaOracle, Ownable, Pausable, ReentrancyGuard {
         require(block.number > challenge.responseWindowEndBlock, "PhalaSLA: Response window not yet closed");
         require(!challenge.responded, "PhalaSLA: Challenge was responded to");
         require(!challenge.reported, "PhalaSLA: Challenge expiry already reported");
+        require(!cancelledChallenges[challengeId], "PhalaSLA: Challenge was cancelled");
 
         challenge.reported = true;
 
@@ -164,6 +228,69 @@ contract PhalaSlaOracle is IPhalaSlaOracle, Ownable, Pausable, ReentrancyGuard {
         emit SlaChallengeExpired(challengeId, challenge.operator);
     }
 
+    // --- Response Schemas ---
+
+    /**
+     * @notice Publishes the expected response schema for a challenge kind and version.
+     * @dev Only callable by the contract owner. Replaces an existing entry for the same kind and version.
+     */
+    function publishResponseSchema(
+        bytes32 kind,
+        uint32 version,
+        bytes32 schemaHash,
+        string calldata uri
+    ) external override onlyOwner isInitialized {
+        require(schemaHash != bytes32(0), "PhalaSLA: Empty schema hash");
+        ResponseSchema memory schema = ResponseSchema(kind, version, schemaHash, uri);
+        uint256 count = _responseSchemas.length;
+        uint256 i;
+        for (; i < count; i++) {
+            if (_responseSchemas[i].kind == kind && _responseSchemas[i].version == version) {
+                _responseSchemas[i] = schema;
+                break;
+            }
+        }
+        if (i == count) {
+            _responseSchemas.push(schema);
+        }
+        emit ResponseSchemaPublished(kind, version, schemaHash, uri);
+    }
+
+    /**
+     * @notice Returns every published response schema.
+     */
+    function responseSchemas() external view override returns (ResponseSchema[] memory) {
+        return _responseSchemas;
+    }
+
+    // --- Attestation Policy ---
+
+    /**
+     * @notice Replaces the policy attestation responses are verified against.
+     * @dev Only callable by the contract owner.
+     */
+    function setAttestationPolicy(
+        MeasurementEntry[] calldata allowedMeasurements,
+        uint64 maxQuoteAgeSecs
+    ) external override onlyOwner isInitialized {
+        require(maxQuoteAgeSecs > 0, "PhalaSLA: Quote age must be positive");
+        delete _attestationPolicy.allowedMeasurements;
+        for (uint256 i = 0; i < allowedMeasurements.length; i++) {
+            uint8 platform = allowedMeasurements[i].platform;
+            require(platform == 1 || platform == 2, "PhalaSLA: Unknown TEE platform");
+            _attestationPolicy.allowedMeasurements.push(allowedMeasurements[i]);
+        }
+        _attestationPolicy.maxQuoteAgeSecs = maxQuoteAgeSecs;
+        emit AttestationPolicyUpdated(allowedMeasurements.length, maxQuoteAgeSecs);
+    }
+
+    /**
+     * @notice Returns the policy attestation responses are verified against.
+     */
+    function attestationPolicy() external view override returns (AttestationPolicy memory) {
+        return _attestationPolicy;
+    }
+
     // --- Admin Functions ---
 
     /**
@@ -215,7 +342,7 @@ contract PhalaSlaOracle is IPhalaSlaOracle, Ownable, Pausable, ReentrancyGuard {
      * @return responded True if the operator responded, false otherwise.
      * @return reported True if expiry has been reported, false otherwise.
      */
-    function getChallengeDetails(uint256 challengeId) external view returns (
+    function getChallengeDetails(uint256 challengeId) external view override returns (
         address operator,
         bytes memory challengeData,
         uint256 responseWindowEndBlock,
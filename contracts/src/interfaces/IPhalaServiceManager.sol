@@ -18,4 +18,174 @@ interface IPhalaServiceManager {
      * @param operator The address of the operator who failed the liveness check.
      */
     function recordOperatorLivenessFailure(address operator) external;
-} 
\ No newline at end of file
+
+    /**
+     * @notice Emitted when an operator registers a planned maintenance window.
+     * @param operator The operator that registered the window.
+     * @param windowId Unique identifier for the window.
+     * @param workloadId The exempted workload, or zero for all of the operator's workloads.
+     * @param startTime Window start (unix seconds, inclusive).
+     * @param endTime Window end (unix seconds, exclusive).
+     */
+    event MaintenanceWindowRegistered(
+        address indexed operator,
+        uint256 indexed windowId,
+        bytes32 workloadId,
+        uint64 startTime,
+        uint64 endTime
+    );
+
+    /**
+     * @notice Emitted when an operator cancels a previously registered maintenance window.
+     */
+    event MaintenanceWindowCancelled(address indexed operator, uint256 indexed windowId);
+
+    /**
+     * @notice Registers a window during which SLA challenges are not held against the caller.
+     * @param workloadId The exempted workload, or zero for all of the caller's workloads.
+     * @param startTime Window start (unix seconds, inclusive).
+     * @param endTime Window end (unix seconds, exclusive).
+     * @return windowId The unique ID assigned to this window.
+     */
+    function registerMaintenanceWindow(bytes32 workloadId, uint64 startTime, uint64 endTime)
+        external
+        returns (uint256 windowId);
+
+    /**
+     * @notice Cancels a maintenance window registered by the caller.
+     * @param windowId The ID of the window to cancel.
+     */
+    function cancelMaintenanceWindow(uint256 windowId) external;
+
+    /**
+     * @notice Emitted when an operator anchors the Merkle root of its evidence for a window.
+     * @param operator The anchoring operator.
+     * @param windowId The evidence window the root covers.
+     * @param root Merkle root over the operator's evidence records of the window.
+     */
+    event EvidenceRootAnchored(address indexed operator, uint64 indexed windowId, bytes32 root);
+
+    /**
+     * @notice Commits the Merkle root of the caller's evidence for an elapsed window.
+     * @dev A window can only be anchored once per operator.
+     * @param root Merkle root over the caller's evidence records of the window.
+     * @param windowId The evidence window the root covers.
+     */
+    function anchorEvidenceRoot(bytes32 root, uint64 windowId) external;
+
+    /**
+     * @notice Returns the evidence root an operator anchored for a window, or zero.
+     */
+    function evidenceRoots(address operator, uint64 windowId) external view returns (bytes32);
+
+    /**
+     * @notice Emitted when an operator advertises the TEE capacity it can take workloads on.
+     * @param operator The advertising operator.
+     * @param vcpus Available vCPUs.
+     * @param memoryMb Available memory, in MiB.
+     * @param storageGb Available storage, in GiB.
+     * @param platform TEE platform: 1 for TDX, 2 for SGX.
+     */
+    event CapacityUpdated(
+        address indexed operator, uint32 vcpus, uint64 memoryMb, uint64 storageGb, uint8 platform
+    );
+
+    /**
+     * @notice Commits the capacity the caller currently has available for new workloads.
+     * @param vcpus Available vCPUs.
+     * @param memoryMb Available memory, in MiB.
+     * @param storageGb Available storage, in GiB.
+     * @param platform TEE platform: 1 for TDX, 2 for SGX.
+     */
+    function updateCapacity(uint32 vcpus, uint64 memoryMb, uint64 storageGb, uint8 platform) external;
+
+    /**
+     * @notice Number of workloads assigned to an operator.
+     * @param operator The address of the operator.
+     */
+    function assignedWorkloadCount(address operator) external view returns (uint256);
+
+    /**
+     * @notice A workload assigned to an operator, by position.
+     * @param operator The address of the operator.
+     * @param index Position in the operator's assignments, below `assignedWorkloadCount`.
+     */
+    function assignedWorkloadAt(address operator, uint256 index) external view returns (bytes32);
+
+    /**
+     * @notice Emitted when an operator opts out of the AVS.
+     * @param operator The exiting operator.
+     * @param deregisterFromBlock First block the operator may deregister from its quorums in.
+     */
+    event OperatorExitRequested(address indexed operator, uint256 deregisterFromBlock);
+
+    /**
+     * @notice Opts the caller out of the AVS, starting its exit.
+     */
+    function requestExit() external;
+
+    /**
+     * @notice First block an exiting operator may deregister from its quorums in, or zero if it
+     *         has not requested an exit.
+     * @param operator The address of the operator.
+     */
+    function exitAllowedFrom(address operator) external view returns (uint256);
+
+    /**
+     * @notice Emitted when an operator designates, or clears, its maintenance signer.
+     */
+    event MaintenanceSignerSet(address indexed operator, address indexed signer);
+
+    /**
+     * @notice Designates the account the caller sends deferrable transactions from; evidence
+     *         anchors and capacity updates it sends are attributed to the caller.
+     * @param signer The maintenance account, or zero to clear it.
+     */
+    function setMaintenanceSigner(address signer) external;
+
+    /**
+     * @notice The maintenance signer an operator designated, or zero.
+     * @param operator The address of the operator.
+     */
+    function maintenanceSigners(address operator) external view returns (address);
+
+    /**
+     * @notice Emitted when an accepted response is credited to its operator.
+     */
+    event ReimbursementCredited(address indexed operator, uint256 indexed challengeId, uint256 amount);
+
+    /**
+     * @notice Emitted when an operator claims its credited reimbursement.
+     */
+    event ReimbursementClaimed(address indexed operator, uint256 amount);
+
+    /**
+     * @notice Emitted when the reimbursement of a response rejected in a dispute is taken back.
+     */
+    event ReimbursementClawedBack(address indexed operator, uint256 indexed challengeId, uint256 amount);
+
+    /**
+     * @notice Called by the SLA Oracle to credit an operator for an accepted response.
+     * @param operator The operator whose response was accepted.
+     * @param challengeId The challenge the response answered.
+     */
+    function creditReimbursement(address operator, uint256 challengeId) external;
+
+    /**
+     * @notice Wei credited for each accepted challenge response; zero when reimbursement is off.
+     */
+    function reimbursementPerResponse() external view returns (uint256);
+
+    /**
+     * @notice Reimbursement an operator has been credited and not claimed yet.
+     * @param operator The address of the operator.
+     */
+    function claimableReimbursement(address operator) external view returns (uint256);
+
+    /**
+     * @notice Pays out the caller's credited reimbursement, to its operator for a maintenance
+     *         signer.
+     * @return amount The amount paid.
+     */
+    function claimReimbursement() external returns (uint256 amount);
+}
@@ -6,6 +6,41 @@ pragma solidity ^0.8.20;
  * @notice Defines the functions for interacting with the SLA challenge and response mechanism.
  */
 interface IPhalaSlaOracle {
+    /**
+     * @notice The response encoding operators must use for a challenge kind and version.
+     * @param kind keccak256 of the challenge kind name, as carried in versioned challenge data.
+     * @param version The challenge kind's schema version.
+     * @param schemaHash keccak256 of the canonical response schema.
+     * @param uri Where the full schema description is published.
+     */
+    struct ResponseSchema {
+        bytes32 kind;
+        uint32 version;
+        bytes32 schemaHash;
+        string uri;
+    }
+
+    /**
+     * @notice A measurement accepted from one TEE platform.
+     * @param platform 1 for Intel TDX, 2 for SGX, as in `updateCapacity`.
+     * @param measurement keccak256 of the MRTD for TDX, or of
+     *        abi.encodePacked(mrEnclave, mrSigner, isvSvn) for SGX.
+     */
+    struct MeasurementEntry {
+        uint8 platform;
+        bytes32 measurement;
+    }
+
+    /**
+     * @notice What off-chain verifiers accept in responses to attestation challenges.
+     * @param allowedMeasurements Each accepted measurement, with its platform.
+     * @param maxQuoteAgeSecs How old a quote may be when the response is verified.
+     */
+    struct AttestationPolicy {
+        MeasurementEntry[] allowedMeasurements;
+        uint64 maxQuoteAgeSecs;
+    }
+
     /**
      * @notice Emitted when a new SLA challenge is issued for an operator.
      * @param challengeId Unique identifier for the challenge.
@@ -28,6 +63,17 @@ interface IPhalaSlaOracle {
      */
     event SlaChallengeResponded(uint256 indexed challengeId, address indexed operator, bytes responseData);
 
+    /**
+     * @notice Emitted when a response is turned away without reverting; the challenge stays open.
+     * @dev Reason codes: 1 empty response, 2 response does not match the published schema,
+     *      3 attestation quote too old, 4 measurement not allowed by the attestation policy,
+     *      5 response signature invalid. Codes above 5 are reserved.
+     * @param challengeId The ID of the challenge the response was for.
+     * @param operator The address of the responding operator.
+     * @param reasonCode Why the response was rejected.
+     */
+    event SlaResponseRejected(uint256 indexed challengeId, address indexed operator, uint8 reasonCode);
+
     /**
      * @notice Emitted when an operator fails to respond to an SLA challenge within the window.
      * @param challengeId The ID of the challenge that expired.
@@ -35,6 +81,31 @@ interface IPhalaSlaOracle {
      */
     event SlaChallengeExpired(uint256 indexed challengeId, address indexed operator);
 
+    /**
+     * @notice Emitted when an open challenge is withdrawn; it can no longer be responded to or expire.
+     * @param challengeId The ID of the cancelled challenge.
+     */
+    event SlaChallengeCancelled(uint256 indexed challengeId);
+
+    /**
+     * @notice Emitted when an open challenge's data and deadline are replaced.
+     * @dev Responses must answer the new data; the response window now ends at `newDeadline`.
+     * @param challengeId The ID of the amended challenge.
+     * @param newData The data replacing the challenge's data.
+     * @param newDeadline The block number by which the operator must now respond.
+     */
+    event SlaChallengeAmended(uint256 indexed challengeId, bytes newData, uint256 newDeadline);
+
+    /**
+     * @notice Emitted when the expected response schema of a challenge kind is published or replaced.
+     */
+    event ResponseSchemaPublished(bytes32 indexed kind, uint32 version, bytes32 schemaHash, string uri);
+
+    /**
+     * @notice Emitted when the attestation policy is replaced.
+     */
+    event AttestationPolicyUpdated(uint256 measurements, uint64 maxQuoteAgeSecs);
+
     /**
      * @notice Issues a new SLA challenge to an operator.
      * @dev Typically called by the authorized Tokenomic Manager.
@@ -46,11 +117,29 @@ interface IPhalaSlaOracle {
 
     /**
      * @notice Allows an operator to respond to an active SLA challenge.
+     * @dev A response the oracle cannot accept emits `SlaResponseRejected` instead of
+     *      `SlaChallengeResponded`, without reverting.
      * @param challengeId The ID of the challenge to respond to.
      * @param responseData Data proving the operator meets the challenged SLA (e.g., signed heartbeat, TEE proof).
      */
     function respondToSlaChallenge(uint256 challengeId, bytes calldata responseData) external;
 
+    /**
+     * @notice Withdraws an open challenge, e.g. one issued under a misconfiguration.
+     * @dev Typically called by the authorized Tokenomic Manager.
+     * @param challengeId The ID of the challenge to cancel.
+     */
+    function cancelSlaChallenge(uint256 challengeId) external;
+
+    /**
+     * @notice Replaces the data and deadline of an open challenge.
+     * @dev Typically called by the authorized Tokenomic Manager.
+     * @param challengeId The ID of the challenge to amend.
+     * @param newData The data replacing the challenge's data.
+     * @param newDeadline The block number by which the operator must now respond.
+     */
+    function amendSlaChallenge(uint256 challengeId, bytes calldata newData, uint256 newDeadline) external;
+
     /**
      * @notice Checks if a challenge has expired and reports the failure if necessary.
      * @dev Can be called by anyone after the response window has passed.
@@ -58,4 +147,46 @@ interface IPhalaSlaOracle {
      * @param challengeId The ID of the challenge to check.
      */
     function checkAndReportChallengeExpiry(uint256 challengeId) external;
-} 
\ No newline at end of file
+
+    /**
+     * @notice Publishes the expected response schema for a challenge kind and version.
+     * @dev Replaces any schema previously published for the same kind and version.
+     */
+    function publishResponseSchema(bytes32 kind, uint32 version, bytes32 schemaHash, string calldata uri) external;
+
+    /**
+     * @notice Returns every published response schema.
+     */
+    function responseSchemas() external view returns (ResponseSchema[] memory);
+
+    /**
+     * @notice Replaces the policy attestation responses are verified against.
+     */
+    function setAttestationPolicy(MeasurementEntry[] calldata allowedMeasurements, uint64 maxQuoteAgeSecs) external;
+
+    /**
+     * @notice Returns the policy attestation responses are verified against.
+     */
+    function attestationPolicy() external view returns (AttestationPolicy memory);
+
+    /**
+     * @notice Number of blocks an operator has to respond to a challenge.
+     */
+    function responseWindowBlocks() external view returns (uint256);
+
+    /**
+     * @notice Number of challenges issued so far; challenge IDs run from 1 to this count.
+     */
+    function challengeCounter() external view returns (uint256);
+
+    /**
+     * @notice Returns the recorded state of a challenge; the operator is zero for unknown IDs.
+     */
+    function getChallengeDetails(uint256 challengeId) external view returns (
+        address operator,
+        bytes memory challengeData,
+        uint256 responseWindowEndBlock,
+        bool responded,
+        bool reported
+    );
+}
@@ -0,0 +1,291 @@
+use clap::{Parser, Subcommand};
+use phala_tee_cloud_avs_blueprint_lib::catchup::CatchUpMode;
+use std::path::PathBuf;
+
+/// Phala Cloud AVS operator.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML config file; defaults to `CONFIG_PATH`. The environment overrides it.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Set a value over the config file and the environment, e.g. `--set STATE_BACKEND=sqlite`.
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+    /// How far back to catch up on missed challenges at startup: `full`, `bounded:<blocks>` or
+    /// `skip`; sets `CURSOR_CATCHUP_MODE`.
+    #[arg(long, global = true)]
+    pub catchup_mode: Option<CatchUpMode>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the operator (default).
+    Run,
+    /// Inspect the operator's configuration.
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+    /// Inspect and manage persistent state.
+    State {
+        #[command(subcommand)]
+        action: StateCommand,
+    },
+    /// Manage planned maintenance windows exempting workloads from SLA challenges.
+    Maintenance {
+        #[command(subcommand)]
+        action: MaintenanceCommand,
+    },
+    /// Collect a diagnostics bundle from the running operator.
+    Diagnostics {
+        #[command(subcommand)]
+        action: Option<DiagnosticsCommand>,
+        /// Bundle format: `json` or `compact`.
+        #[arg(long, default_value = "json")]
+        format: String,
+        /// Where to write the bundle; defaults to `diagnostics.json` or `diagnostics.phdiag`.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Upload the bundle to `DIAGNOSTICS_UPLOAD_URL` after writing it.
+        #[arg(long)]
+        upload: bool,
+        /// Base URL of the operator's status server.
+        #[arg(long, default_value = "http://127.0.0.1:9100")]
+        operator_url: String,
+    },
+    /// Leave the AVS: drain, deregister, and wait until it is safe to shut down.
+    ///
+    /// Exits with status 0 once the operator is safe to shut down and 2 while it is not.
+    Exit {
+        /// Only report the progress of an exit already started.
+        #[arg(long)]
+        status: bool,
+        /// Keep polling until the operator is safe to shut down.
+        #[arg(long)]
+        wait: bool,
+        /// Base URL of the operator's status server.
+        #[arg(long, default_value = "http://127.0.0.1:9100")]
+        operator_url: String,
+    },
+    /// Hold response submission and report whether the operator is safe to restart.
+    ///
+    /// Exits with status 0 once the operator is safe to restart and 2 while it is not; it keeps
+    /// holding submission either way, until `abort-restart`.
+    PrepareRestart {
+        /// Base URL of the operator's status server.
+        #[arg(long, default_value = "http://127.0.0.1:9100")]
+        operator_url: String,
+    },
+    /// Resume response submission after `prepare-restart`.
+    AbortRestart {
+        /// Base URL of the operator's status server.
+        #[arg(long, default_value = "http://127.0.0.1:9100")]
+        operator_url: String,
+    },
+    /// Show the running operator's pending challenges and the blocks left to answer each.
+    ///
+    /// Rows below their oracle's safety margin are marked `!!` and those within twice of it `! `;
+    /// the markers are colored only on a terminal, and never with `NO_COLOR` set.
+    Status {
+        /// Base URL of the operator's status server.
+        #[arg(long, default_value = "http://127.0.0.1:9100")]
+        operator_url: String,
+    },
+    /// Attach a note to a challenge, an epoch or an incident (an alert id) on the running
+    /// operator, e.g. `annotate challenge 42 "RPC outage, ticket #123"`.
+    Annotate {
+        #[arg(value_parser = ["challenge", "epoch", "incident"])]
+        kind: String,
+        id: String,
+        note: String,
+        /// Base URL of the operator's status server.
+        #[arg(long, default_value = "http://127.0.0.1:9100")]
+        operator_url: String,
+    },
+    /// Inspect encoder rollouts and promote shadow encoders.
+    Rollout {
+        #[command(subcommand)]
+        action: RolloutCommand,
+        /// Base URL of the operator's status server.
+        #[arg(long, default_value = "http://127.0.0.1:9100")]
+        operator_url: String,
+    },
+    /// Migrate the running operator's signing keystore to the secondary signer.
+    Keystore {
+        #[command(subcommand)]
+        action: KeystoreCommand,
+        /// Base URL of the operator's status server.
+        #[arg(long, default_value = "http://127.0.0.1:9100")]
+        operator_url: String,
+    },
+    /// Export or verify the signed performance summary offered to delegators.
+    Reputation {
+        #[command(subcommand)]
+        action: ReputationCommand,
+    },
+    /// Capture the chain state of a block range, or replay a captured one offline.
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotCommand,
+    },
+    /// Print what this binary was built from, including the build hash its quotes carry.
+    BuildInfo,
+    /// Inspect the submissions the aggregator dead-lettered and the equivocations it logged.
+    #[cfg(feature = "aggregator")]
+    Aggregator {
+        #[command(subcommand)]
+        action: AggregatorCommand,
+        /// URL of the aggregator's JSON-RPC server.
+        #[arg(long, default_value = "http://127.0.0.1:8081")]
+        aggregator_url: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the values taken from the config file, or with `--effective` every setting after
+    /// layering, each annotated with its source. Secrets are redacted.
+    Show {
+        #[arg(long)]
+        effective: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StateCommand {
+    /// Copy state to the configured migration target and verify it.
+    Migrate {
+        /// Make the migration target the primary once verification passes.
+        #[arg(long)]
+        finalize: bool,
+    },
+    /// Rebuild the derived state from the chain and the raw evidence into a fresh store, report
+    /// how it differs, and swap it in once confirmed. Run with the operator stopped; an
+    /// interrupted rebuild resumes where it stopped.
+    Rebuild {
+        /// Swap in the rebuilt state without asking.
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MaintenanceCommand {
+    /// Register a maintenance window on-chain and record it locally.
+    Schedule {
+        /// Workload id, or `all`.
+        #[arg(long)]
+        workload: String,
+        /// Window start, unix seconds.
+        #[arg(long)]
+        from: u64,
+        /// Window end, unix seconds.
+        #[arg(long)]
+        to: u64,
+    },
+    /// Cancel a previously scheduled window.
+    Cancel {
+        /// The on-chain window id.
+        #[arg(long)]
+        id: String,
+    },
+    /// List recorded windows.
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RolloutCommand {
+    /// List rollouts with their shadow results against the promotion threshold.
+    Status,
+    /// Make the shadow encoder of a kind at an oracle the active one, if its shadow results are
+    /// clean.
+    Promote {
+        #[arg(long)]
+        oracle: String,
+        /// Challenge kind name, e.g. `attestation`, or its 32-byte id.
+        #[arg(long)]
+        kind: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ReputationCommand {
+    /// Fetch the running operator's signed summary and write it to disk.
+    Export {
+        /// Where to write the summary; defaults to `reputation.json`.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Base URL of the operator's status server.
+        #[arg(long, default_value = "http://127.0.0.1:9100")]
+        operator_url: String,
+    },
+    /// Check a summary's signature, and its strikes and evidence roots against the chain.
+    Verify { file: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SnapshotCommand {
+    /// Run the challenge pipeline over a block range against an archive RPC, recording every
+    /// chain read it makes.
+    Capture {
+        #[arg(long)]
+        from_block: u64,
+        #[arg(long)]
+        to_block: u64,
+        /// Where to write the snapshot; defaults to `chain.snapshot`.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Run the pipeline against a snapshot only, and print the outcome of every challenge.
+    Replay { file: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeystoreCommand {
+    /// Move signing from the primary keystore to `SIGNER_SECONDARY_URL`.
+    Migrate {
+        #[command(subcommand)]
+        step: MigrateStep,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateStep {
+    /// Check the secondary signs as the primary with a canary digest, then soak with the
+    /// primary as fallback.
+    Start,
+    /// Take the primary out of the signing path after a soak without fallbacks.
+    Finalize,
+    /// Return signing to the primary.
+    Abort,
+    /// Show the migration's phase, canary check and fallbacks.
+    Status,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DiagnosticsCommand {
+    /// List the sections of a compact bundle, or extract one to stdout.
+    Inspect {
+        file: PathBuf,
+        /// Name of the section to extract.
+        #[arg(long)]
+        extract: Option<String>,
+        /// Show section checksums in full instead of abbreviated.
+        #[arg(long)]
+        full_hashes: bool,
+    },
+    /// Upload an existing bundle, resuming a previous interrupted upload.
+    Upload { file: PathBuf },
+}
+
+#[cfg(feature = "aggregator")]
+#[derive(Debug, Subcommand)]
+pub enum AggregatorCommand {
+    /// List dead-lettered submissions.
+    DeadLetters,
+    /// List operators caught sending conflicting responses to a task.
+    Equivocations,
+}
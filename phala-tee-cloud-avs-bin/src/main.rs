@@ -1,3 +1,5 @@
+mod cli;
+
 use blueprint_sdk::Router;
 use blueprint_sdk::alloy::primitives::Address;
 use blueprint_sdk::evm::producer::{PollingConfig, PollingProducer};
@@ -6,65 +8,335 @@ use blueprint_sdk::producers::CronJob;
 use blueprint_sdk::runner::BlueprintRunner;
 use blueprint_sdk::runner::config::BlueprintEnvironment;
 use blueprint_sdk::runner::eigenlayer::bls::EigenlayerBLSConfig;
+use clap::Parser;
+#[cfg(feature = "aggregator")]
+use cli::AggregatorCommand;
+use cli::{
+    Cli, Command, ConfigCommand, DiagnosticsCommand, KeystoreCommand, MaintenanceCommand,
+    MigrateStep, ReputationCommand, RolloutCommand, SnapshotCommand, StateCommand,
+};
+#[cfg(feature = "aggregator")]
+use phala_tee_cloud_avs_blueprint_lib::aggregator_admin::AggregatorAdminClient;
+use phala_tee_cloud_avs_blueprint_lib::annotations::{self, AnnotationTarget};
+#[cfg(feature = "http-api")]
+use phala_tee_cloud_avs_blueprint_lib::api_keys;
+use phala_tee_cloud_avs_blueprint_lib::build_info::BuildInfo;
+use phala_tee_cloud_avs_blueprint_lib::challenge::{ConfirmationPolicy, window};
+use phala_tee_cloud_avs_blueprint_lib::config::{self, ConfigLayers, ConfigSource};
+use phala_tee_cloud_avs_blueprint_lib::cursor::{CursorStore, ProducerKind};
+use phala_tee_cloud_avs_blueprint_lib::diagnostics::{
+    BundleFormat, CompactReader, UploadConfig, Uploader, fetch_bundle,
+};
+use phala_tee_cloud_avs_blueprint_lib::evm::ProviderEvmClient;
+use phala_tee_cloud_avs_blueprint_lib::lanes::TxClass;
+use phala_tee_cloud_avs_blueprint_lib::logs::LogRingLayer;
+use phala_tee_cloud_avs_blueprint_lib::maintenance::{
+    MaintenanceConfig, MaintenanceSchedule, ServiceManagerExemptions,
+};
+use phala_tee_cloud_avs_blueprint_lib::snapshot::{self, ChainSnapshot};
+use phala_tee_cloud_avs_blueprint_lib::startup::{
+    self, StartupOrchestrator, StartupStatus, default_plan,
+};
+use phala_tee_cloud_avs_blueprint_lib::state::rebuild::{
+    self, ProviderHistorySource, RebuildConfig, RebuildScope, StateRebuild,
+};
+use phala_tee_cloud_avs_blueprint_lib::state::{FinalizeOutcome, StateBackend, StateConfig};
+#[cfg(feature = "http-api")]
+use phala_tee_cloud_avs_blueprint_lib::status::{
+    StatusState, spawn_status_server, status_addr_from_env,
+};
+use phala_tee_cloud_avs_blueprint_lib::supervisor::ProducerSupervisor;
 use phala_tee_cloud_avs_blueprint_lib::{
-    HEARTBEAT_JOB_ID, PhalaAvsContext, RESPOND_TO_CHALLENGE_JOB_ID, heartbeat_job,
-    respond_to_challenge_job,
+    HEARTBEAT_JOB_ID, PhalaAvsContext, PhalaAvsError, RESPOND_TO_CHALLENGE_JOB_ID,
+    SELF_AUDIT_JOB_ID, heartbeat_job, respond_to_challenge_job, self_audit_job,
 };
+use phala_tee_cloud_avs_blueprint_lib::{
+    approvals, artifacts, capacity, disk, display, drift, duties, evidence, exit, freshness,
+    heartbeat, ingestion, keystore, lanes, operator_set, preflight, receipts, registration,
+    reimbursement, remote_write, replica, reputation, restart, rollout, schema, sender, slo, tee,
+    upgrade,
+};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tracing::level_filters::LevelFilter;
 use tracing::{error, info};
+use tracing_subscriber::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cli = Cli::parse();
+    let config_path = cli
+        .config
+        .or_else(|| std::env::var_os("CONFIG_PATH").map(PathBuf::from));
+    if let Some(mode) = cli.catchup_mode {
+        cli.overrides.push(format!("CURSOR_CATCHUP_MODE={mode}"));
+    }
+    config::install(ConfigLayers::load(config_path, &cli.overrides)?);
     setup_log();
-    info!("Starting Phala Cloud AVS Operator...");
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run().await,
+        Command::Config {
+            action: ConfigCommand::Show { effective },
+        } => config_show(effective),
+        Command::State {
+            action: StateCommand::Migrate { finalize },
+        } => state_migrate(finalize).await,
+        Command::State {
+            action: StateCommand::Rebuild { yes },
+        } => state_rebuild(yes).await,
+        Command::Maintenance { action } => maintenance(action).await,
+        Command::Diagnostics {
+            action:
+                Some(DiagnosticsCommand::Inspect {
+                    file,
+                    extract,
+                    full_hashes,
+                }),
+            ..
+        } => diagnostics_inspect(&file, extract.as_deref(), full_hashes),
+        Command::Diagnostics {
+            action: Some(DiagnosticsCommand::Upload { file }),
+            ..
+        } => diagnostics_upload(&file).await,
+        Command::Diagnostics {
+            action: None,
+            format,
+            output,
+            upload,
+            operator_url,
+        } => diagnostics(&format, output, upload, &operator_url).await,
+        #[cfg(feature = "aggregator")]
+        Command::Aggregator {
+            action,
+            aggregator_url,
+        } => aggregator(action, aggregator_url).await,
+        Command::Exit {
+            status,
+            wait,
+            operator_url,
+        } => voluntary_exit(status, wait, &operator_url).await,
+        Command::PrepareRestart { operator_url } => prepare_restart(&operator_url).await,
+        Command::AbortRestart { operator_url } => abort_restart(&operator_url).await,
+        Command::Status { operator_url } => challenge_status(&operator_url).await,
+        Command::Annotate {
+            kind,
+            id,
+            note,
+            operator_url,
+        } => annotate(&kind, &id, &note, &operator_url).await,
+        Command::Rollout {
+            action,
+            operator_url,
+        } => encoder_rollout(action, &operator_url).await,
+        Command::Keystore {
+            action: KeystoreCommand::Migrate { step },
+            operator_url,
+        } => keystore_migrate(step, &operator_url).await,
+        Command::Reputation { action } => reputation_summary(action).await,
+        Command::Snapshot { action } => chain_snapshot(action).await,
+        Command::BuildInfo => {
+            println!("{}", serde_json::to_string_pretty(BuildInfo::current())?);
+            Ok(())
+        }
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let build = BuildInfo::current();
+    info!(
+        version = %build.version,
+        git_commit = %build.git_commit,
+        build_hash = %build.build_hash,
+        "Starting Phala Cloud AVS Operator..."
+    );
+    build.record_metric();
+
+    // --- Status server first, so operators can watch the remaining stages ---
+    let orchestrator = StartupOrchestrator::new(default_plan()?, StartupStatus::default());
+    #[cfg(feature = "http-api")]
+    let status_state = {
+        let status_state = StatusState::from_env(orchestrator.status().clone())?;
+        api_keys::spawn_reload(Arc::clone(status_state.auth()));
+        orchestrator
+            .run_required(
+                startup::STATUS,
+                spawn_status_server(status_addr_from_env()?, status_state.clone()),
+            )
+            .await?;
+        status_state
+    };
+    // Built without `http-api` there is no server to wait for.
+    #[cfg(not(feature = "http-api"))]
+    orchestrator
+        .run_required(startup::STATUS, async { Ok(()) })
+        .await?;
 
     let env = BlueprintEnvironment::load()?;
     info!("Environment loaded.");
 
-    // --- EVM Setup ---
-    let http_rpc_url = env.http_rpc_endpoint.clone();
-    let provider = get_provider_http(&http_rpc_url);
-    info!("EVM Provider initialized.");
+    // --- Context ---
+    let context = PhalaAvsContext::build(env.clone(), &orchestrator).await?;
+    #[cfg(feature = "http-api")]
+    status_state.attach_context(context.clone());
+    info!("PhalaAvsContext initialized.");
+    // Settle the transactions the last run left unfinished before anything new is sent.
+    let pending = context.tx_sender.recover().await?;
+    sender::spawn_replay(Arc::clone(&context.tx_sender), pending);
+    schema::spawn_refresh(Arc::clone(&context.schemas));
+    registration::spawn_watcher(Arc::clone(&context.registration), Arc::clone(&context.evm));
+    upgrade::spawn_watcher(Arc::clone(&context.upgrades), Arc::clone(&context.schemas));
+    preflight::spawn_self_check(Arc::clone(&context.preflight));
+    if let Some(operator_set) = &context.operator_set {
+        operator_set::spawn_refresh(Arc::clone(operator_set), Arc::clone(&context.evm));
+    }
+    if let Some(anchorer) = &context.anchorer {
+        evidence::spawn_anchoring(
+            Arc::clone(anchorer),
+            Arc::clone(&context.registration),
+            Arc::clone(&context.lanes),
+        );
+    }
+    artifacts::spawn_pruning(Arc::clone(&context.artifacts), Arc::clone(&context.evm));
+    ingestion::spawn_drain(Arc::clone(&context.ingestion));
+    lanes::spawn_balance_monitor(Arc::clone(&context.lanes), Arc::clone(&context.notifier));
+    if let Some(reporter) = &context.capacity {
+        // Capacity updates are deferrable: they wait behind queued challenge responses.
+        let registration = Arc::clone(&context.registration);
+        let response_queue = Arc::clone(&context.response_queue);
+        let lanes = Arc::clone(&context.lanes);
+        capacity::spawn_reporter(
+            Arc::clone(reporter),
+            context.tee_handler.clone(),
+            move || {
+                !registration.permits_submission()
+                    || lanes.holds(TxClass::Deferrable)
+                    || !response_queue
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .is_empty()
+            },
+        );
+    }
 
-    // --- Polling Producer ---
-    let polling_config = PollingConfig::default().poll_interval(Duration::from_secs(5)); // Adjust interval as needed
-    let producer = PollingProducer::new(Arc::new(provider), polling_config).await?;
-    info!("PollingProducer initialized.");
+    // --- Polling Producer and Heartbeat Cron ---
+    let http_rpc_url = env.http_rpc_endpoint.clone();
+    let heartbeat_schedule = context.jitter.heartbeat_schedule();
+    let chain_id = context.evm.chain_id().await?;
+    // Pushed metrics are told apart by operator and chain.
+    remote_write::spawn_exporter(vec![
+        ("network".to_string(), chain_id.to_string()),
+        ("operator".to_string(), context.operator_address.to_string()),
+    ]);
+    let (producer, heartbeat_cron) = orchestrator
+        .run_required(startup::PRODUCERS, async {
+            let producer = challenge_poller(&http_rpc_url, &context.cursors, chain_id).await?;
+            let heartbeat_cron = CronJob::new(HEARTBEAT_JOB_ID, &heartbeat_schedule)
+                .await
+                .map_err(|e| PhalaAvsError::Other(e.to_string()))?;
+            Ok((producer, heartbeat_cron))
+        })
+        .await?;
+    info!("PollingProducer and heartbeat cron job initialized.");
+    // The watchdog recreates the cron producer if it stops triggering heartbeats.
+    let heartbeat_supervisor = ProducerSupervisor::new("heartbeat_cron");
+    let heartbeat_cron = heartbeat_supervisor.supervise(heartbeat_cron, move || {
+        let schedule = heartbeat_schedule.clone();
+        async move {
+            CronJob::new(HEARTBEAT_JOB_ID, &schedule)
+                .await
+                .map_err(|e| PhalaAvsError::Other(e.to_string()))
+        }
+    });
+    let self_audit_cron = match &context.self_audit {
+        Some(auditor) => Some(
+            CronJob::new(SELF_AUDIT_JOB_ID, &auditor.config().schedule)
+                .await
+                .map_err(|e| PhalaAvsError::Other(e.to_string()))?,
+        ),
+        None => None,
+    };
+    if let Some(reconciler) = &context.drift {
+        drift::spawn_reconciler(Arc::clone(reconciler), Arc::clone(&context.notifier));
+    }
+    if let Some(monitor) = &context.collateral {
+        tee::collateral::spawn_collateral_monitor(Arc::clone(monitor));
+    }
+    if let Some(monitor) = &context.freshness {
+        freshness::spawn_freshness_monitor(Arc::clone(monitor));
+    }
+    reimbursement::spawn_reimbursement_claimer(Arc::clone(&context.reimbursement));
+    duties::spawn_calendar(
+        Arc::clone(&context.duties),
+        Arc::clone(&context.challenge_tracker),
+        Arc::clone(&context.evm),
+        Arc::clone(&context.notifier),
+    );
+    slo::spawn_monitor(
+        Arc::clone(&context.slo),
+        Arc::clone(&context.evm),
+        Arc::clone(&context.notifier),
+    );
+    replica::spawn_lease(Arc::clone(&context.replica), Arc::clone(&context.notifier));
+    exit::spawn_exit(
+        Arc::clone(&context.exit),
+        Arc::clone(&context.evm),
+        Arc::clone(&context.challenge_tracker),
+        Arc::clone(&context.notifier),
+    );
+    approvals::spawn_expiry_alerts(
+        Arc::clone(&context.approvals),
+        Arc::clone(&context.notifier),
+    );
+    receipts::spawn_verifier(Arc::clone(&context.receipts), Arc::clone(&context.notifier));
+    keystore::spawn_monitor(Arc::clone(&context.keystore), Arc::clone(&context.notifier));
+    if let Some(disk) = &context.disk {
+        disk::spawn_disk_monitor(
+            Arc::clone(disk),
+            Arc::clone(&context.evm),
+            Arc::clone(&context.notifier),
+        );
+    }
+    heartbeat::spawn_watchdog(context.clone(), Arc::clone(&heartbeat_supervisor));
+    // A reorg rewinding a cursor restarts the poller from the cursors.
+    let cursors = Arc::clone(&context.cursors);
+    let tee_handler = context.tee_handler.clone();
+    let producer = context.poller.supervise(producer, move || {
+        let (url, cursors) = (http_rpc_url.clone(), Arc::clone(&cursors));
+        async move { challenge_poller(&url, &cursors, chain_id).await }
+    });
 
     // --- Eigenlayer Config ---
     let eigen_config = EigenlayerBLSConfig::new(Address::default(), Address::default());
     info!("EigenlayerBLSConfig initialized.");
 
-    // --- Context ---
-    let context = PhalaAvsContext::new(env.clone()).await?;
-    info!("PhalaAvsContext initialized.");
-
-    // --- Cron Job for Heartbeat ---
-    let heartbeat_cron = CronJob::new(HEARTBEAT_JOB_ID, "* * * * *").await?;
-    info!("Heartbeat cron job scheduled.");
-
     // --- Router ---
     let router = Router::new()
-        // TODO: Define job ID and handler for responding to on-chain challenges/events
+        .route(HEARTBEAT_JOB_ID, heartbeat_job)
         .route(RESPOND_TO_CHALLENGE_JOB_ID, respond_to_challenge_job)
+        .route(SELF_AUDIT_JOB_ID, self_audit_job)
         .with_context(context.clone());
     info!("Router configured.");
 
-    // --- Aggregator Client (Optional Background Service) ---
-    // Example: If you need to interact with an external aggregator service
-    // let aggregator_client_config = EigenDaConfig { /* Load from env */ };
-    // let aggregator_client = AggregatorClient::new(aggregator_client_config)?;
-    // let aggregator_service = ServiceBuilder::new().service(aggregator_client);
-
     // --- Runner ---
-    let runner_result = BlueprintRunner::builder(eigen_config, env)
+    let cursors = Arc::clone(&context.cursors);
+    let mut runner = BlueprintRunner::builder(eigen_config, env)
         .router(router)
         .producer(producer)
-        .producer(heartbeat_cron) // Add cron job as a producer
-        // .background_service(aggregator_service) // Example: Add background service if needed
-        .with_shutdown_handler(async { info!("Shutting down Phala Cloud AVS Operator...") })
+        .producer(heartbeat_cron); // Add cron job as a producer
+    if let Some(self_audit_cron) = self_audit_cron {
+        runner = runner.producer(self_audit_cron);
+    }
+    let runner_result = runner
+        .with_shutdown_handler(async move {
+            info!("Shutting down Phala Cloud AVS Operator...");
+            tee_handler.cancel_retries();
+            if let Err(e) = cursors.flush() {
+                error!("Failed to flush producer cursors: {e}");
+            }
+        })
         .run()
         .await;
 
@@ -77,15 +349,416 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Creates the challenge log producer, catching up from the lowest challenge cursor of the chain.
+async fn challenge_poller(
+    http_rpc_url: &str,
+    cursors: &CursorStore,
+    chain_id: u64,
+) -> Result<PollingProducer, PhalaAvsError> {
+    let provider = get_provider_http(http_rpc_url);
+    let mut polling_config = PollingConfig::default().poll_interval(Duration::from_secs(5)); // Adjust interval as needed
+    if let Some(from) = cursors.catch_up_from(chain_id, ProducerKind::ChallengeLogs) {
+        info!("Catching up on challenge logs from block {from}");
+        polling_config = polling_config.start_block(from);
+    }
+    PollingProducer::new(Arc::new(provider), polling_config)
+        .await
+        .map_err(|e| PhalaAvsError::EvmError(e.to_string()))
+}
+
+/// Prints the config file's values, or every setting with `effective`.
+fn config_show(effective: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let values: Vec<_> = config::effective()
+        .into_iter()
+        .filter(|v| effective || v.source == ConfigSource::File)
+        .collect();
+    print!("{}", config::render(&values));
+    Ok(())
+}
+
+/// Copies state to the migration target, verifies it, and optionally flips the primary.
+async fn state_migrate(finalize: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let config = StateConfig::from_env()?;
+    if config.effective_primary()? == StateBackend::Memory {
+        return Err("an in-memory primary can only be migrated by the running operator".into());
+    }
+    let Some(store) = config.open_migration()? else {
+        return Err("STATE_MIGRATION_TARGET is not set or is already the primary".into());
+    };
+
+    let copied = store.copy_existing(config.migration_rate_limit).await?;
+    info!("Copied {} entries to {}", copied, store.target_backend());
+
+    let report = if finalize {
+        match store.finalize(&config.state_dir)? {
+            FinalizeOutcome::Finalized(report) => report,
+            FinalizeOutcome::Refused(report) => {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Err("verification failed, primary was not changed".into());
+            }
+        }
+    } else {
+        store.verify()?
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Rebuilds the derived state into a fresh store and swaps it in once confirmed.
+async fn state_rebuild(yes: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let config = StateConfig::from_env()?;
+    let primary = config.effective_primary()?;
+    if primary == StateBackend::Memory {
+        return Err("an in-memory primary has no state to rebuild".into());
+    }
+    let env = BlueprintEnvironment::load()?;
+    let target = rebuild::rebuild_backend(&config.state_dir, &primary);
+    let rebuild = StateRebuild::new(
+        RebuildConfig::from_env()?,
+        RebuildScope::from_env()?,
+        primary.open()?,
+        target.open()?,
+        target.clone(),
+        Arc::new(ProviderHistorySource::new(get_provider_http(
+            &env.http_rpc_endpoint,
+        ))),
+    )
+    .with_policy(ConfirmationPolicy::from_env()?);
+
+    let report = rebuild.run().await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.copied_intact() {
+        return Err("the copy of the existing state does not match it; nothing was swapped".into());
+    }
+    if !report.differs() {
+        println!("The local state matches the chain; nothing to swap");
+        return Ok(());
+    }
+    if !yes {
+        print!(
+            "Swap in the rebuilt state from {target}, replacing {} challenge record(s)? [y/N] ",
+            report.differences.len()
+        );
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            println!("Left the rebuilt state in {target}; run again to swap it in");
+            return Ok(());
+        }
+    }
+    rebuild.swap(&config.state_dir)?;
+    println!("Swapped in {target}; it becomes the primary on the next start");
+    Ok(())
+}
+
+/// Schedules, cancels or lists maintenance windows against the operator's persistent state.
+async fn maintenance(action: MaintenanceCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let config = StateConfig::from_env()?;
+    if config.effective_primary()? == StateBackend::Memory {
+        return Err(
+            "maintenance windows need a persistent STATE_BACKEND; use the admin API instead".into(),
+        );
+    }
+    let env = BlueprintEnvironment::load()?;
+    let schedule = MaintenanceSchedule::new(
+        MaintenanceConfig::from_env()?,
+        config.open()?,
+        Arc::new(ServiceManagerExemptions::from_env(env.http_rpc_endpoint)),
+    );
+
+    match action {
+        MaintenanceCommand::Schedule { workload, from, to } => {
+            let window = schedule.schedule(workload.parse()?, from, to).await?;
+            println!("{}", serde_json::to_string_pretty(&window)?);
+        }
+        MaintenanceCommand::Cancel { id } => {
+            let window = schedule.cancel(id.parse()?).await?;
+            println!("{}", serde_json::to_string_pretty(&window)?);
+        }
+        MaintenanceCommand::List => {
+            println!("{}", serde_json::to_string_pretty(&schedule.windows()?)?);
+        }
+    }
+    Ok(())
+}
+
+/// Calls the aggregator's admin RPC with `AGGREGATOR_ADMIN_TOKEN`.
+#[cfg(feature = "aggregator")]
+async fn aggregator(
+    action: AggregatorCommand,
+    aggregator_url: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token =
+        config::lookup("AGGREGATOR_ADMIN_TOKEN").ok_or("AGGREGATOR_ADMIN_TOKEN is not set")?;
+    let client = AggregatorAdminClient::new(aggregator_url, token);
+    match action {
+        AggregatorCommand::DeadLetters => {
+            let entries = client.list_dead_letters().await?;
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+        }
+        AggregatorCommand::Equivocations => {
+            let entries = client.list_equivocations().await?;
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+        }
+    }
+    Ok(())
+}
+
+/// Starts or follows the running operator's voluntary exit with `ADMIN_TOKEN`.
+async fn voluntary_exit(
+    status_only: bool,
+    wait: bool,
+    operator_url: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token = config::lookup("ADMIN_TOKEN").ok_or("ADMIN_TOKEN is not set")?;
+    let mut state = if status_only {
+        exit::fetch_exit(operator_url, &token).await?
+    } else {
+        Some(exit::request_exit(operator_url, &token).await?)
+    };
+    loop {
+        println!("{}", serde_json::to_string_pretty(&state)?);
+        match &state {
+            Some(s) if s.is_safe() => return Ok(()),
+            Some(_) if wait => {
+                tokio::time::sleep(Duration::from_secs(12)).await;
+                state = exit::fetch_exit(operator_url, &token).await?;
+            }
+            _ => std::process::exit(2),
+        }
+    }
+}
+
+/// Prepares the running operator for a restart with `ADMIN_TOKEN`.
+async fn prepare_restart(operator_url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let token = config::lookup("ADMIN_TOKEN").ok_or("ADMIN_TOKEN is not set")?;
+    let report = restart::request_prepare(operator_url, &token).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.is_safe() {
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+/// Resumes response submission on the running operator with `ADMIN_TOKEN`.
+async fn abort_restart(operator_url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let token = config::lookup("ADMIN_TOKEN").ok_or("ADMIN_TOKEN is not set")?;
+    if restart::request_abort(operator_url, &token).await? {
+        println!("Restart aborted; response submission resumed");
+    } else {
+        println!("No restart was being prepared");
+    }
+    Ok(())
+}
+
+/// Prints the running operator's pending challenges with `ADMIN_TOKEN`, most urgent first.
+async fn challenge_status(operator_url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let token = config::lookup("ADMIN_TOKEN").ok_or("ADMIN_TOKEN is not set")?;
+    let summary = window::fetch_windows(operator_url, &token).await?;
+    let color = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    print!("{}", summary.render_table(color));
+    Ok(())
+}
+
+/// Attaches a note to an entity on the running operator with `ADMIN_TOKEN`.
+async fn annotate(
+    kind: &str,
+    id: &str,
+    note: &str,
+    operator_url: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token = config::lookup("ADMIN_TOKEN").ok_or("ADMIN_TOKEN is not set")?;
+    let target = AnnotationTarget::parse(kind, id)?;
+    let annotation = annotations::request_annotation(operator_url, &token, &target, note).await?;
+    println!("{}", serde_json::to_string_pretty(&annotation)?);
+    Ok(())
+}
+
+/// Shows or promotes the running operator's encoder rollouts with `ADMIN_TOKEN`.
+async fn encoder_rollout(
+    action: RolloutCommand,
+    operator_url: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token = config::lookup("ADMIN_TOKEN").ok_or("ADMIN_TOKEN is not set")?;
+    match action {
+        RolloutCommand::Status => {
+            let rollouts = rollout::fetch_rollouts(operator_url, &token).await?;
+            println!("{}", serde_json::to_string_pretty(&rollouts)?);
+        }
+        RolloutCommand::Promote { oracle, kind } => {
+            let oracle = display::parse_address(&oracle)?;
+            let promoted = rollout::request_promotion(operator_url, &token, oracle, &kind).await?;
+            println!("{}", serde_json::to_string_pretty(&promoted)?);
+        }
+    }
+    Ok(())
+}
+
+/// Runs a step of the running operator's keystore migration with `ADMIN_TOKEN`.
+async fn keystore_migrate(
+    step: MigrateStep,
+    operator_url: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token = config::lookup("ADMIN_TOKEN").ok_or("ADMIN_TOKEN is not set")?;
+    let state = match step {
+        MigrateStep::Status => keystore::fetch_migration(operator_url, &token).await?,
+        MigrateStep::Start => keystore::request_migration(operator_url, &token, "start").await?,
+        MigrateStep::Finalize => {
+            keystore::request_migration(operator_url, &token, "finalize").await?
+        }
+        MigrateStep::Abort => keystore::request_migration(operator_url, &token, "abort").await?,
+    };
+    println!("{}", serde_json::to_string_pretty(&state)?);
+    Ok(())
+}
+
+/// Exports the running operator's signed summary with `ADMIN_TOKEN`, or verifies one against
+/// the chain.
+async fn reputation_summary(action: ReputationCommand) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        ReputationCommand::Export {
+            output,
+            operator_url,
+        } => {
+            let output = output.unwrap_or_else(|| PathBuf::from("reputation.json"));
+            let token = config::lookup("ADMIN_TOKEN").ok_or("ADMIN_TOKEN is not set")?;
+            let signed = reputation::fetch_reputation(&operator_url, &token).await?;
+            std::fs::write(&output, serde_json::to_vec_pretty(&signed)?)?;
+            println!("Wrote {}", output.display());
+        }
+        ReputationCommand::Verify { file } => {
+            let signed: reputation::SignedReputationSummary =
+                serde_json::from_slice(&std::fs::read(&file)?)?;
+            let env = BlueprintEnvironment::load()?;
+            let chain = reputation::ContractReputationChain::from_env(env.http_rpc_endpoint);
+            reputation::verify_reputation_summary(&signed, &chain).await?;
+            println!(
+                "Summary of {} verified: signed by the operator, strikes and roots match the chain",
+                signed.summary.operator
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Captures the chain state of an incident window, or replays a captured one offline.
+async fn chain_snapshot(action: SnapshotCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let policy = ConfirmationPolicy::from_env()?;
+    let outcomes = match action {
+        SnapshotCommand::Capture {
+            from_block,
+            to_block,
+            output,
+        } => {
+            let output = output.unwrap_or_else(|| PathBuf::from("chain.snapshot"));
+            let env = BlueprintEnvironment::load()?;
+            let scope = RebuildScope::from_env()?;
+            let provider = get_provider_http(&env.http_rpc_endpoint);
+            let (captured, outcomes) = snapshot::capture(
+                Arc::new(ProviderEvmClient::new(
+                    provider.clone(),
+                    scope.service_manager,
+                )),
+                Arc::new(ProviderHistorySource::new(provider)),
+                policy,
+                scope,
+                from_block,
+                to_block,
+            )
+            .await?;
+            captured.write(&output)?;
+            println!("Wrote {}", output.display());
+            outcomes
+        }
+        SnapshotCommand::Replay { file } => {
+            snapshot::replay(ChainSnapshot::read(&file)?, policy).await?
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&outcomes)?);
+    Ok(())
+}
+
+/// Fetches a diagnostics bundle from the running operator and writes it to disk.
+async fn diagnostics(
+    format: &str,
+    output: Option<PathBuf>,
+    upload: bool,
+    operator_url: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let format: BundleFormat = format.parse()?;
+    let output = output.unwrap_or_else(|| match format {
+        BundleFormat::Json => PathBuf::from("diagnostics.json"),
+        BundleFormat::Compact => PathBuf::from("diagnostics.phdiag"),
+    });
+    let token = config::lookup("ADMIN_TOKEN").ok_or("ADMIN_TOKEN is not set")?;
+    let bundle = fetch_bundle(operator_url, &token, format).await?;
+    std::fs::write(&output, bundle)?;
+    println!("Wrote {}", output.display());
+    if upload {
+        diagnostics_upload(&output).await?;
+    }
+    Ok(())
+}
+
+async fn diagnostics_upload(file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let retrieval_id = Uploader::new(UploadConfig::from_env()?)
+        .upload(file)
+        .await?;
+    println!("Uploaded {}; retrieval id: {retrieval_id}", file.display());
+    Ok(())
+}
+
+/// Lists or extracts sections of a compact bundle without reading the whole file.
+fn diagnostics_inspect(
+    file: &Path,
+    extract: Option<&str>,
+    full_hashes: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = CompactReader::open(file)?;
+    match extract {
+        Some(name) => {
+            let section = reader.read_section(name)?;
+            std::io::stdout().write_all(&section.data)?;
+        }
+        None => {
+            for entry in reader.index() {
+                println!(
+                    "{:<12} {:<18} {:>10} bytes ({} compressed) sha256 {}",
+                    entry.name,
+                    entry.content_type,
+                    entry.raw_len,
+                    entry.compressed_len,
+                    display::abbreviate(&entry.sha256, full_hashes)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 pub fn setup_log() {
-    let _ = tracing_subscriber::fmt::SubscriberBuilder::default()
-        .with_max_level(LevelFilter::INFO) // Set default level
-        .with_env_filter(
+    // Exported over OTLP when built with `otel` and an endpoint is configured.
+    #[cfg(feature = "otel")]
+    let otel = phala_tee_cloud_avs_blueprint_lib::otel::layer().unwrap_or_else(|e| {
+        eprintln!("Not exporting traces: {e}");
+        None
+    });
+    #[cfg(not(feature = "otel"))]
+    let otel: Option<tracing_subscriber::layer::Identity> = None;
+
+    let _ = tracing_subscriber::registry()
+        .with(
             tracing_subscriber::EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
+                .with_default_directive(LevelFilter::INFO.into()) // Set default level
                 .from_env_lossy(),
         )
-        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE) // Log span events
-        .with_target(true) // Show module targets
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE) // Log span events
+                .with_target(true), // Show module targets
+        )
+        .with(LogRingLayer) // Keep recent events for diagnostics
+        .with(otel)
         .try_init();
 }
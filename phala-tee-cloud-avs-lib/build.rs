@@ -0,0 +1,96 @@
+//! Records what the crate was built from, for `crate::build_info`.
+//!
+//! Emits the git commit (`PHALA_AVS_GIT_COMMIT` overrides it for builds outside a checkout), whether
+//! the checkout had uncommitted changes, the enabled features, and a SHA-256 over `Cargo.toml`,
+//! the workspace `Cargo.lock` and every file under `src/`, walked in path order.
+
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn main() {
+    let manifest_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let lock = manifest_dir.join("../Cargo.lock");
+
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed={}", lock.display());
+    println!("cargo:rerun-if-env-changed=PHALA_AVS_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    if let Some(git_dir) = git(&manifest_dir, &["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        println!("cargo:rerun-if-changed={git_dir}/index");
+    }
+
+    let commit = std::env::var("PHALA_AVS_GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| git(&manifest_dir, &["rev-parse", "HEAD"]))
+        .unwrap_or_default();
+    let dirty = git(&manifest_dir, &["status", "--porcelain", "--", "."])
+        .is_some_and(|status| !status.is_empty());
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    let mut files = vec![manifest_dir.join("Cargo.toml")];
+    if lock.exists() {
+        files.push(lock);
+    }
+    collect(&manifest_dir.join("src"), &mut files);
+    let mut hasher = Sha256::new();
+    for file in &files {
+        let relative = file.strip_prefix(&manifest_dir).unwrap_or(file);
+        let contents = std::fs::read(file).unwrap();
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update((contents.len() as u64).to_be_bytes());
+        hasher.update(&contents);
+    }
+    let source_hash: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    println!("cargo:rustc-env=PHALA_AVS_BUILD_GIT_COMMIT={commit}");
+    println!("cargo:rustc-env=PHALA_AVS_BUILD_DIRTY={dirty}");
+    println!("cargo:rustc-env=PHALA_AVS_BUILD_FEATURES={}", features.join(","));
+    println!("cargo:rustc-env=PHALA_AVS_BUILD_SOURCE_HASH={source_hash}");
+    println!(
+        "cargo:rustc-env=PHALA_AVS_BUILD_SOURCE_DATE_EPOCH={}",
+        std::env::var("SOURCE_DATE_EPOCH").unwrap_or_default()
+    );
+}
+
+/// Every file under `dir`, in path order.
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -1,82 +1,402 @@
-use alloy_rpc_client::ReqwestClient;
-use color_eyre::Result;
-use eigensdk::crypto_bls::{OperatorId, Signature};
-use reqwest::Url;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tokio::time::{Duration, sleep};
-use tracing::{debug, info};
-
-const MAX_RETRIES: u32 = 5;
-const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SignedTaskResponse {
-    pub task_response: TaskResponse,
-    pub signature: Signature,
-    pub operator_id: OperatorId,
-}
+//! The operator's client of the aggregator's JSON-RPC server.
+//!
+//! [`AggregatorClient::connect`] asks the aggregator for `get_server_info` and settles on the
+//! highest wire-format version both sides speak; an aggregator without the method is spoken to
+//! in version 1. Submissions are then sent in the negotiated version's envelope.
+//!
+//! A submission is retried under the `RETRY_AGGREGATOR_SUBMIT_*` policy when it goes
+//! unanswered or fails with an internal error; a refusal would only repeat. Every attempt
+//! carries the same idempotency key, generated per submission, so an aggregator that processed
+//! an attempt whose reply was lost replays that reply instead of aggregating the response again.
+//! Whether a retried submission was resolved by such a replay is counted in
+//! [`CLIENT_RETRIES_TOTAL`].
+//!
+//! Calls carry the current span's context in a `traceparent` header and, when the aggregator
+//! announces trace context, submissions in the envelope's `traceparent` field, which the
+//! aggregator continues.
+
+use crate::aggregator_admin::parse_reply;
+use crate::aggregator_wire::{
+    FEATURE_IDEMPOTENCY_KEYS, FEATURE_TRACE_CONTEXT, METHOD_NOT_FOUND_ERROR_CODE,
+    SERVER_INFO_METHOD, SUBMIT_RESPONSE_METHOD, ServerInfo, SignedTaskResponse, Submission,
+    SubmitReply, negotiate,
+};
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::otel::{current_traceparent, with_traceparent};
+use crate::retry::{RetryPolicy, retry_with};
+use crate::sanitize;
+use blueprint_sdk::info;
+use jsonrpc_core::ErrorCode;
+use serde_json::{Value, json};
+use std::time::Duration;
+use uuid::Uuid;
 
-/// Client for interacting with the Aggregator RPC server
-#[derive(Debug, Clone)]
+/// Site of the submission retry policy.
+pub const SUBMIT_RETRY_SITE: &str = "AGGREGATOR_SUBMIT";
+/// Submissions accepted after a retry, by whether the aggregator replayed the reply to an
+/// earlier attempt or processed the retry afresh.
+pub const CLIENT_RETRIES_TOTAL: &str = "phala_avs_aggregator_client_retries_total";
+/// How long an attempt waits for the aggregator's reply.
+pub const SUBMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Submits signed task responses to the aggregator at `url`.
+#[derive(Clone, Debug)]
 pub struct AggregatorClient {
-    client: ReqwestClient,
+    url: String,
+    client: reqwest::Client,
+    /// What the aggregator reported; `None` for one predating `get_server_info`.
+    info: Option<ServerInfo>,
+    wire_version: u32,
+    timeout: Duration,
+    /// Read from the config at each submission when unset.
+    policy: Option<RetryPolicy>,
 }
 
 impl AggregatorClient {
-    /// Creates a new AggregatorClient
-    pub fn new(aggregator_address: &str) -> Result<Self> {
-        let url = Url::parse(&format!("http://{}", aggregator_address))?;
-        let client = ReqwestClient::new_http(url);
-        Ok(Self { client })
-    }
-
-    /// Sends a signed task response to the aggregator
-    pub async fn send_signed_task_response(&self, response: SignedTaskResponse) -> Result<()> {
-        let params = json!({
-            "params": response,
-            "id": 1,
-            "jsonrpc": "2.0"
-        });
+    /// Probes the aggregator at `url` and negotiates the wire-format version, failing when the
+    /// two builds have none in common.
+    pub async fn connect(url: String) -> Result<Self, PhalaAvsError> {
+        let client = reqwest::Client::new();
+        let reply = post(&client, &url, SERVER_INFO_METHOD, json!({})).await?;
+        let info = if reply["error"]["code"].as_i64() == Some(METHOD_NOT_FOUND_ERROR_CODE) {
+            None
+        } else {
+            Some(parse_reply::<ServerInfo>(SERVER_INFO_METHOD, reply)?)
+        };
+        let wire_version = negotiate(info.as_ref())?;
+        info!("Speaking wire-format version {wire_version} with the aggregator at {url}");
+        Ok(Self {
+            url,
+            client,
+            info,
+            wire_version,
+            timeout: SUBMIT_TIMEOUT,
+            policy: None,
+        })
+    }
 
-        for attempt in 1..=MAX_RETRIES {
-            match self
-                .client
-                .request::<_, bool>("process_signed_task_response", &params)
-                .await
-            {
-                Ok(true) => {
-                    info!("Task response accepted by aggregator");
-                    // MARK: Uncomment when metrics are implemented
-                    // incredible_metrics::inc_num_tasks_accepted_by_aggregator();
-                    return Ok(());
-                }
-                Ok(false) => debug!("Task response not accepted, retrying..."),
-                Err(e) => debug!("Error sending task response: {}", e),
-            }
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
 
-            if attempt < MAX_RETRIES {
-                let delay = INITIAL_RETRY_DELAY * 2u32.pow(attempt - 1);
-                info!("Retrying in {} seconds...", delay.as_secs());
-                sleep(delay).await;
-            }
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    pub fn wire_version(&self) -> u32 {
+        self.wire_version
+    }
+
+    /// Whether the aggregator announced `feature`.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.info
+            .as_ref()
+            .is_some_and(|info| info.supports(feature))
+    }
+
+    /// Sends `response` to the aggregator in the negotiated version, retrying until it is
+    /// answered.
+    pub async fn send_signed_task_response(
+        &self,
+        response: &SignedTaskResponse,
+    ) -> Result<SubmitReply, PhalaAvsError> {
+        let idempotency_key = self
+            .supports(FEATURE_IDEMPOTENCY_KEYS)
+            .then(|| Uuid::new_v4().to_string());
+        let submission = Submission {
+            response: serde_json::to_value(response)
+                .map_err(|e| PhalaAvsError::Other(format!("Unserializable response: {e}")))?,
+            idempotency_key,
+            traceparent: self
+                .supports(FEATURE_TRACE_CONTEXT)
+                .then(current_traceparent)
+                .flatten(),
+        };
+        let params = submission.envelope(self.wire_version)?;
+        let policy = self.policy.clone().unwrap_or_else(|| {
+            RetryPolicy::from_config_or(SUBMIT_RETRY_SITE, RetryPolicy::default())
+        });
+        let mut attempts = 0;
+        let reply = retry_with(&policy, SUBMIT_RETRY_SITE, None, || {
+            attempts += 1;
+            self.submit(params.clone())
+        })
+        .await?;
+        if attempts > 1 {
+            let resolution = if reply.replayed() {
+                "replayed"
+            } else {
+                "fresh"
+            };
+            METRICS.inc_counter(CLIENT_RETRIES_TOTAL, &[("resolution", resolution)], 1);
+            info!(
+                "Submission {} answered after {attempts} attempts ({resolution})",
+                submission
+                    .idempotency_key
+                    .as_deref()
+                    .unwrap_or("without a key")
+            );
         }
+        Ok(reply)
+    }
 
-        debug!(
-            "Failed to send signed task response after {} attempts",
-            MAX_RETRIES
-        );
-        Ok(())
+    /// One attempt at a submission. Unanswered attempts and internal errors are retryable;
+    /// the aggregator's refusals are not.
+    async fn submit(&self, params: Value) -> Result<SubmitReply, PhalaAvsError> {
+        let call = post(&self.client, &self.url, SUBMIT_RESPONSE_METHOD, params);
+        let reply = tokio::time::timeout(self.timeout, call)
+            .await
+            .map_err(|_| {
+                PhalaAvsError::AggregatorError(format!(
+                    "No reply from the aggregator within {:?}",
+                    self.timeout
+                ))
+            })??;
+        let code = reply["error"]["code"].as_i64();
+        parse_reply(SUBMIT_RESPONSE_METHOD, reply).map_err(|e| match code {
+            Some(code) if code != ErrorCode::InternalError.code() => {
+                PhalaAvsError::ValidationError(e.to_string())
+            }
+            _ => e,
+        })
     }
 }
 
+/// Calls `method` at `url`, returning the JSON-RPC reply whether it is a result or an error.
+async fn post(
+    client: &reqwest::Client,
+    url: &str,
+    method: &str,
+    params: Value,
+) -> Result<Value, PhalaAvsError> {
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    with_traceparent(client.post(url))
+        .json(&request)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| {
+            PhalaAvsError::AggregatorError(format!(
+                "Failed to reach aggregator: {}",
+                sanitize::message("aggregator_error", e)
+            ))
+        })?
+        .json()
+        .await
+        .map_err(|e| {
+            PhalaAvsError::AggregatorError(format!(
+                "Invalid aggregator reply: {}",
+                sanitize::message("aggregator_error", e)
+            ))
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::aggregator::dedupe::{DedupeConfig, ResponseLedger};
+    use crate::aggregator::idempotency::IdempotencyCache;
+    use crate::aggregator::server::{Aggregation, AggregatorServer};
+    use crate::aggregator_wire::{CURRENT_WIRE_VERSION, FEATURE_ADMIN, parse_submission};
+    use crate::evm::BoxFuture;
+    use crate::retry::Jitter;
+    use crate::state::{MemoryStateStore, StateStore};
+    use blueprint_sdk::alloy::primitives::{B256, Bytes};
+    use jsonrpc_core::{IoHandler, Params};
+    use jsonrpc_http_server::{Server, ServerBuilder};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Accepts every response, taking `delay` to aggregate one.
+    #[derive(Default)]
+    struct AcceptAll {
+        delay: Duration,
+        passes: AtomicU32,
+    }
+
+    impl Aggregation for AcceptAll {
+        fn encode(&self, task_response: &Value) -> Result<Bytes, PhalaAvsError> {
+            Ok(serde_json::to_vec(task_response).unwrap().into())
+        }
+
+        fn verify<'a>(
+            &'a self,
+            _response: &'a SignedTaskResponse,
+        ) -> BoxFuture<'a, Result<(), PhalaAvsError>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn aggregate(
+            &self,
+            _response: SignedTaskResponse,
+        ) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.passes.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                Ok(())
+            })
+        }
+    }
+
+    fn response() -> SignedTaskResponse {
+        SignedTaskResponse {
+            task_response: json!({ "referenceTaskIndex": 7, "numberSquared": "0x31" }),
+            signature: json!({ "g1_point": { "X": "0x1", "Y": "0x2" } }),
+            operator_id: B256::repeat_byte(0xaa),
+        }
+    }
+
+    /// An aggregator of another build, which only has `io`'s methods.
+    fn serve(io: IoHandler) -> (Server, String) {
+        let server = ServerBuilder::new(io)
+            .start_http(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let url = format!("http://{}", server.address());
+        (server, url)
+    }
+
+    /// The current build's aggregator over `aggregation`.
+    fn start(aggregation: &Arc<AcceptAll>) -> (Server, String) {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let config = DedupeConfig {
+            retention_secs: 600,
+            exclude_after: None,
+            conflict_window_secs: 600,
+        };
+        let idempotency = IdempotencyCache::new(Arc::clone(&store), 600);
+        let ledger = ResponseLedger::new(config, store).unwrap();
+        let aggregation = Arc::clone(aggregation) as Arc<dyn Aggregation>;
+        let server = AggregatorServer::new(aggregation, ledger, idempotency, None)
+            .start(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let url = format!("http://{}", server.address());
+        (server, url)
+    }
+
+    #[test]
+    fn the_current_aggregator_is_spoken_to_in_the_current_version() {
+        let (server, url) = start(&Arc::default());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let client = AggregatorClient::connect(url).await.unwrap();
+            assert_eq!(client.wire_version(), CURRENT_WIRE_VERSION);
+            assert!(!client.supports(FEATURE_ADMIN));
+            let reply = client.send_signed_task_response(&response()).await.unwrap();
+            assert_eq!(reply, SubmitReply::Keyed {
+                accepted: true,
+                replayed: false,
+            });
+        });
+        server.close();
+    }
+
+    #[test]
+    fn a_retry_after_a_lost_reply_is_not_aggregated_twice() {
+        // The aggregator answers the first attempt after the client stopped waiting for it.
+        let aggregation = Arc::new(AcceptAll {
+            delay: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let (server, url) = start(&aggregation);
+        let replayed = || METRICS.counter(CLIENT_RETRIES_TOTAL, &[("resolution", "replayed")]);
+        let before = replayed().unwrap_or(0);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let reply = runtime.block_on(async {
+            let policy = RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(600),
+                jitter: Jitter::None,
+                ..RetryPolicy::default()
+            };
+            let client = AggregatorClient::connect(url)
+                .await
+                .unwrap()
+                .with_timeout(Duration::from_millis(100))
+                .with_retry_policy(policy);
+            client.send_signed_task_response(&response()).await.unwrap()
+        });
+        server.close();
+        assert_eq!(reply, SubmitReply::Keyed {
+            accepted: true,
+            replayed: true,
+        });
+        assert_eq!(aggregation.passes.load(Ordering::SeqCst), 1);
+        assert_eq!(replayed(), Some(before + 1));
+    }
+
+    #[test]
+    fn refusals_are_not_retried() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let mut io = IoHandler::new();
+        let counted = Arc::clone(&attempts);
+        io.add_sync_method(SUBMIT_RESPONSE_METHOD, move |_: Params| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Err(jsonrpc_core::Error::invalid_params(
+                "Invalid signed response",
+            ))
+        });
+        let (server, url) = serve(io);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let err = runtime.block_on(async {
+            let client = AggregatorClient::connect(url).await.unwrap();
+            client
+                .send_signed_task_response(&response())
+                .await
+                .unwrap_err()
+        });
+        server.close();
+        assert!(matches!(err, PhalaAvsError::ValidationError(_)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
 
     #[test]
-    fn test_new_client() {
-        let client = AggregatorClient::new("127.0.0.1:8545");
-        assert!(client.is_ok());
+    fn a_version_1_aggregator_gets_version_1_envelopes() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut io = IoHandler::new();
+        let recorded = Arc::clone(&received);
+        io.add_sync_method(SUBMIT_RESPONSE_METHOD, move |params: Params| {
+            let params: Value = params.parse()?;
+            recorded.lock().unwrap().push(params);
+            Ok(Value::Bool(true))
+        });
+        let (server, url) = serve(io);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let client = AggregatorClient::connect(url).await.unwrap();
+            assert_eq!(client.wire_version(), 1);
+            let reply = client.send_signed_task_response(&response()).await.unwrap();
+            assert!(reply.accepted());
+        });
+        server.close();
+        let received = received.lock().unwrap();
+        assert!(received[0].get("params").is_some());
+        let submission = parse_submission(received[0].clone()).unwrap();
+        assert_eq!(submission.response, json!(response()));
+    }
+
+    #[test]
+    fn an_aggregator_without_a_common_version_is_refused() {
+        let mut io = IoHandler::new();
+        io.add_sync_method(SERVER_INFO_METHOD, |_: Params| {
+            Ok(json!(ServerInfo {
+                wire_versions: vec![3],
+                features: vec![],
+            }))
+        });
+        let (server, url) = serve(io);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let err = runtime
+            .block_on(AggregatorClient::connect(url))
+            .unwrap_err();
+        server.close();
+        assert!(err.to_string().contains("No wire-format version in common"));
     }
 }
@@ -0,0 +1,144 @@
+//! Aggregated responses whose submission failed, kept for inspection.
+//!
+//! A dead letter is what `admin_list_dead_letters` lists, a [`DeadLetterEntry`], kept next to
+//! the aggregation result the submission was built from.
+
+use super::TaskIndex;
+use crate::aggregator_admin::{DeadLetterEntry, DeadLetterStatus};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// A dead-lettered submission, with `T`, what it was built from.
+#[derive(Clone, Debug)]
+pub struct DeadLetter<T> {
+    pub entry: DeadLetterEntry,
+    pub aggregation: T,
+}
+
+/// Dead letters by task index, shared between the response sender and the admin RPC.
+#[derive(Clone, Debug)]
+pub struct DeadLetterStore<T> {
+    letters: Arc<Mutex<BTreeMap<TaskIndex, DeadLetter<T>>>>,
+}
+
+impl<T> Default for DeadLetterStore<T> {
+    fn default() -> Self {
+        Self {
+            letters: Arc::default(),
+        }
+    }
+}
+
+impl<T: Clone> DeadLetterStore<T> {
+    /// Dead-letters a failed submission at `now_unix`, returning its entry; a later failure of
+    /// the same task replaces it. The entry's status and attempts are kept here.
+    pub fn record(
+        &self,
+        mut entry: DeadLetterEntry,
+        aggregation: T,
+        now_unix: u64,
+    ) -> DeadLetterEntry {
+        let mut letters = self.letters.lock().unwrap_or_else(|e| e.into_inner());
+        entry.attempts = letters
+            .get(&entry.task_index)
+            .map_or(0, |l| l.entry.attempts)
+            + 1;
+        entry.status = DeadLetterStatus::Failed;
+        entry.dead_lettered_unix = now_unix;
+        letters.insert(entry.task_index, DeadLetter {
+            entry: entry.clone(),
+            aggregation,
+        });
+        entry
+    }
+
+    pub fn get(&self, task_index: TaskIndex) -> Option<DeadLetter<T>> {
+        self.letters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&task_index)
+            .cloned()
+    }
+
+    /// Applies `f` to the entry of `task_index`, returning it updated.
+    pub fn update(
+        &self,
+        task_index: TaskIndex,
+        f: impl FnOnce(&mut DeadLetterEntry),
+    ) -> Option<DeadLetterEntry> {
+        let mut letters = self.letters.lock().unwrap_or_else(|e| e.into_inner());
+        let letter = letters.get_mut(&task_index)?;
+        f(&mut letter.entry);
+        Some(letter.entry.clone())
+    }
+
+    pub fn entries(&self) -> Vec<DeadLetterEntry> {
+        self.letters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(|l| l.entry.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator_admin::AggregationSummary;
+    use blueprint_sdk::alloy::primitives::{B256, Bytes};
+
+    fn entry(task_index: TaskIndex, failure: &str) -> DeadLetterEntry {
+        DeadLetterEntry {
+            task_index,
+            task_created_block: 100,
+            quorum_numbers: Bytes::from_static(&[0]),
+            response: Bytes::from_static(&[1; 64]),
+            aggregation: AggregationSummary {
+                non_signers: 1,
+                quorum_apks: 1,
+                signers_apk_g2: "apk".to_string(),
+            },
+            failure: failure.to_string(),
+            revert: None,
+            status: DeadLetterStatus::AlreadyResponded,
+            attempts: 0,
+            dead_lettered_unix: 0,
+        }
+    }
+
+    #[test]
+    fn repeated_failures_replace_the_letter_and_count_attempts() {
+        let store = DeadLetterStore::default();
+        let first = store.record(entry(7, "nonce too low"), "aggregation 1", 1_000);
+        assert_eq!(
+            (first.status, first.attempts, first.dead_lettered_unix),
+            (DeadLetterStatus::Failed, 1, 1_000)
+        );
+        store.record(entry(8, "out of gas"), "aggregation 2", 1_500);
+        let again = store.record(entry(7, "replacement underpriced"), "aggregation 3", 2_000);
+        assert_eq!(again.attempts, 2);
+        assert_eq!(again.failure, "replacement underpriced");
+
+        let letter = store.get(7).unwrap();
+        assert_eq!(letter.aggregation, "aggregation 3");
+        assert_eq!(letter.entry, again);
+        let tasks: Vec<_> = store.entries().iter().map(|e| e.task_index).collect();
+        assert_eq!(tasks, [7, 8]);
+    }
+
+    #[test]
+    fn updates_apply_to_the_listed_entry() {
+        let store = DeadLetterStore::default();
+        store.record(entry(7, "nonce too low"), (), 1_000);
+        let replayed = DeadLetterStatus::Replayed {
+            transaction_hash: B256::repeat_byte(3),
+        };
+        let updated = store
+            .update(7, |entry| entry.status = replayed.clone())
+            .unwrap();
+        assert_eq!(updated.status, replayed);
+        assert_eq!(store.entries(), [updated]);
+        assert!(store.update(9, |_| {}).is_none());
+    }
+}
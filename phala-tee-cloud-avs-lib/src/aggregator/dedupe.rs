@@ -0,0 +1,328 @@
+//! Deduplication of operator responses per `(task, operator)`, and the equivocation log.
+//!
+//! The first response accepted from an operator for a task is kept in the [`ResponseHistory`]
+//! until the task is pruned, `AGGREGATOR_RESPONSE_RETENTION_SECS` after it was finalized.
+//! Resending that response is acknowledged without reprocessing it; a different one is an
+//! equivocation: it is rejected and logged, with both digests, to the aggregator's state store
+//! (`AGGREGATOR_STATE_BACKEND`, memory when unset). With `AGGREGATOR_EXCLUDE_AFTER_CONFLICTS`
+//! set, an operator with that many equivocations within `AGGREGATOR_CONFLICT_WINDOW_SECS` has
+//! all its later responses refused.
+
+use super::TaskIndex;
+use super::history::ResponseHistory;
+use crate::aggregator_admin::EquivocationEntry;
+use crate::config::{env_opt, env_or};
+use crate::display::Hash;
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::state::{StateBackend, StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{B256, keccak256};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+/// Counter of equivocations, by operator.
+pub const EQUIVOCATIONS_TOTAL: &str = "phala_avs_aggregator_equivocations_total";
+
+/// Namespace of the equivocation log, keyed by task, operator and conflicting digest.
+pub const EQUIVOCATION_NAMESPACE: &str = "aggregator_equivocations";
+/// Namespace of excluded operators, keyed by operator id.
+pub const EXCLUSION_NAMESPACE: &str = "aggregator_exclusions";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DedupeConfig {
+    /// How long a task's responses are kept after it was finalized.
+    pub retention_secs: u64,
+    /// Equivocations after which an operator is excluded; never when unset.
+    pub exclude_after: Option<usize>,
+    pub conflict_window_secs: u64,
+}
+
+impl DedupeConfig {
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            retention_secs: env_or("AGGREGATOR_RESPONSE_RETENTION_SECS", 7 * 86_400)?,
+            exclude_after: env_opt("AGGREGATOR_EXCLUDE_AFTER_CONFLICTS")?,
+            conflict_window_secs: env_or("AGGREGATOR_CONFLICT_WINDOW_SECS", 86_400)?,
+        })
+    }
+}
+
+/// How a response was admitted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Admission {
+    /// The first response of the operator to the task; process it.
+    Fresh,
+    /// The response accepted before, resent.
+    Duplicate,
+    /// A response differing from the one accepted before, now logged.
+    Conflict {
+        entry: EquivocationEntry,
+        /// This equivocation got the operator excluded.
+        excluded: bool,
+    },
+    /// The operator is excluded.
+    Excluded,
+}
+
+/// Digest a response is deduplicated by, from its ABI encoding.
+pub fn response_digest(encoded: &[u8]) -> B256 {
+    keccak256(encoded)
+}
+
+/// The first-accepted responses and the equivocation log, shared by the RPC handlers.
+#[derive(Clone, Debug)]
+pub struct ResponseLedger {
+    config: DedupeConfig,
+    store: Arc<dyn StateStore>,
+    history: ResponseHistory,
+    excluded: Arc<Mutex<BTreeSet<B256>>>,
+}
+
+impl ResponseLedger {
+    /// Opens the ledger on `AGGREGATOR_STATE_BACKEND`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let backend = env_opt("AGGREGATOR_STATE_BACKEND")?.unwrap_or(StateBackend::Memory);
+        Self::new(DedupeConfig::from_env()?, backend.open()?)
+    }
+
+    /// Creates the ledger, restoring the exclusions recorded in `store`.
+    pub fn new(config: DedupeConfig, store: Arc<dyn StateStore>) -> Result<Self, PhalaAvsError> {
+        let excluded = store
+            .scan(EXCLUSION_NAMESPACE)?
+            .into_iter()
+            .filter(|(key, _)| key.len() == 32)
+            .map(|(key, _)| B256::from_slice(&key))
+            .collect();
+        Ok(Self {
+            history: ResponseHistory::new(Arc::clone(&store), config.retention_secs),
+            config,
+            store,
+            excluded: Arc::new(Mutex::new(excluded)),
+        })
+    }
+
+    /// The aggregator's state store the ledger is kept in.
+    pub fn store(&self) -> &Arc<dyn StateStore> {
+        &self.store
+    }
+
+    /// The accepted responses, which deduplication reads from.
+    pub fn history(&self) -> &ResponseHistory {
+        &self.history
+    }
+
+    /// Admits the response of `operator_id` to `task_index` with `digest`, recording it when it
+    /// is the first and logging it when it conflicts with the one accepted before.
+    pub fn admit(
+        &self,
+        task_index: TaskIndex,
+        operator_id: B256,
+        digest: B256,
+        now_ms: u64,
+    ) -> Result<Admission, PhalaAvsError> {
+        if self.is_excluded(operator_id) {
+            return Ok(Admission::Excluded);
+        }
+        let first = match self.history.response(task_index, operator_id)? {
+            Some(first) if first.digest == digest => return Ok(Admission::Duplicate),
+            Some(first) => first,
+            None => {
+                self.history
+                    .record(task_index, operator_id, digest, now_ms)?;
+                return Ok(Admission::Fresh);
+            }
+        };
+
+        let now_unix = now_ms / 1000;
+        let entry = EquivocationEntry {
+            operator_id,
+            task_index,
+            first_digest: first.digest,
+            first_unix: first.received_unix_ms / 1000,
+            conflicting_digest: digest,
+            conflict_unix: now_unix,
+        };
+        self.store
+            .put_json(EQUIVOCATION_NAMESPACE, &log_key(&entry), &entry)?;
+        let operator = Hash(operator_id).to_string();
+        METRICS.inc_counter(EQUIVOCATIONS_TOTAL, &[("operator_id", &operator)], 1);
+
+        let excluded = match self.config.exclude_after {
+            Some(limit) => {
+                let since = now_unix.saturating_sub(self.config.conflict_window_secs);
+                let recent = self
+                    .equivocations()?
+                    .iter()
+                    .filter(|e| e.operator_id == operator_id && e.conflict_unix >= since)
+                    .count();
+                recent >= limit
+            }
+            None => false,
+        };
+        if excluded {
+            self.store
+                .put_json(EXCLUSION_NAMESPACE, operator_id.as_slice(), &now_unix)?;
+            self.excluded
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(operator_id);
+        }
+        Ok(Admission::Conflict { entry, excluded })
+    }
+
+    pub fn is_excluded(&self, operator_id: B256) -> bool {
+        self.excluded
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(&operator_id)
+    }
+
+    /// The equivocation log, by task and operator.
+    pub fn equivocations(&self) -> Result<Vec<EquivocationEntry>, PhalaAvsError> {
+        self.store
+            .scan(EQUIVOCATION_NAMESPACE)?
+            .into_iter()
+            .map(|(_, value)| {
+                serde_json::from_slice(&value).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Corrupt equivocation record: {e}"))
+                })
+            })
+            .collect()
+    }
+}
+
+fn log_key(entry: &EquivocationEntry) -> Vec<u8> {
+    let mut key = entry.task_index.to_be_bytes().to_vec();
+    key.extend_from_slice(entry.operator_id.as_slice());
+    key.extend_from_slice(entry.conflicting_digest.as_slice());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    const OPERATOR: B256 = B256::repeat_byte(0xaa);
+
+    fn ledger(store: &Arc<dyn StateStore>, exclude_after: Option<usize>) -> ResponseLedger {
+        let config = DedupeConfig {
+            retention_secs: 600,
+            exclude_after,
+            conflict_window_secs: 600,
+        };
+        ResponseLedger::new(config, Arc::clone(store)).unwrap()
+    }
+
+    #[test]
+    fn resent_responses_are_duplicates_and_differing_ones_are_logged() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let ledger = ledger(&store, None);
+        let (first, second) = (response_digest(&[1; 64]), response_digest(&[2; 64]));
+        assert_eq!(response_digest(&[1; 64]), first);
+
+        assert_eq!(
+            ledger.admit(7, OPERATOR, first, 100_000).unwrap(),
+            Admission::Fresh
+        );
+        assert_eq!(
+            ledger.admit(7, OPERATOR, first, 101_000).unwrap(),
+            Admission::Duplicate
+        );
+        // Other operators and tasks are deduplicated on their own.
+        let other = B256::repeat_byte(0xbb);
+        assert_eq!(
+            ledger.admit(7, other, second, 102_000).unwrap(),
+            Admission::Fresh
+        );
+        assert_eq!(
+            ledger.admit(8, OPERATOR, second, 102_000).unwrap(),
+            Admission::Fresh
+        );
+
+        let Admission::Conflict { entry, excluded } =
+            ledger.admit(7, OPERATOR, second, 103_000).unwrap()
+        else {
+            panic!("a differing response was not a conflict");
+        };
+        assert!(!excluded);
+        assert_eq!(ledger.equivocations().unwrap(), vec![EquivocationEntry {
+            operator_id: OPERATOR,
+            task_index: 7,
+            first_digest: first,
+            first_unix: 100,
+            conflicting_digest: second,
+            conflict_unix: 103,
+        }]);
+        assert_eq!(entry, ledger.equivocations().unwrap()[0]);
+        // The accepted response stays the first one.
+        assert_eq!(
+            ledger.admit(7, OPERATOR, first, 104_000).unwrap(),
+            Admission::Duplicate
+        );
+        // Read from the response history, so it survives a restart.
+        assert_eq!(
+            self::ledger(&store, None)
+                .admit(7, OPERATOR, first, 105_000)
+                .unwrap(),
+            Admission::Duplicate
+        );
+        assert_eq!(
+            METRICS.counter(EQUIVOCATIONS_TOTAL, &[(
+                "operator_id",
+                &Hash(OPERATOR).to_string()
+            )]),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn operators_are_excluded_after_repeated_conflicts() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let ledger = ledger(&store, Some(2));
+        // Not OPERATOR, whose equivocation counter the other test checks.
+        let operator = B256::repeat_byte(0xcc);
+        for task in 1..=2 {
+            ledger
+                .admit(task, operator, B256::repeat_byte(1), 100_000)
+                .unwrap();
+        }
+        let conflict = ledger
+            .admit(1, operator, B256::repeat_byte(2), 200_000)
+            .unwrap();
+        assert!(matches!(conflict, Admission::Conflict {
+            excluded: false,
+            ..
+        }));
+        let conflict = ledger
+            .admit(2, operator, B256::repeat_byte(2), 300_000)
+            .unwrap();
+        assert!(matches!(conflict, Admission::Conflict {
+            excluded: true,
+            ..
+        }));
+
+        // Excluded even for fresh tasks, and still after a restart; nothing more is logged.
+        let Admission::Excluded = ledger
+            .admit(3, operator, B256::repeat_byte(1), 301_000)
+            .unwrap()
+        else {
+            panic!("an excluded operator's response was admitted");
+        };
+        let restarted = self::ledger(&store, Some(2));
+        assert_eq!(
+            restarted
+                .admit(4, operator, B256::repeat_byte(1), 302_000)
+                .unwrap(),
+            Admission::Excluded
+        );
+        assert_eq!(restarted.equivocations().unwrap().len(), 2);
+        let other = B256::repeat_byte(0xbb);
+        assert_eq!(
+            restarted
+                .admit(4, other, B256::repeat_byte(1), 302_000)
+                .unwrap(),
+            Admission::Fresh
+        );
+    }
+}
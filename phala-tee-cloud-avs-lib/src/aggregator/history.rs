@@ -0,0 +1,264 @@
+//! Every response the aggregator accepted, per task, kept after aggregation so disputes about
+//! an operator's response can be settled.
+//!
+//! Records live in the aggregator's state store next to the equivocation log. A task's records
+//! are pruned `AGGREGATOR_RESPONSE_RETENTION_SECS` after its aggregated response was confirmed
+//! on-chain; tasks never finalized are kept.
+
+use super::TaskIndex;
+use crate::aggregator_admin::{ResponseRecord, ResponseVerification, TaskResponseHistory};
+use crate::error::PhalaAvsError;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::B256;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Namespace of accepted responses, keyed by task and operator.
+pub const RESPONSE_NAMESPACE: &str = "aggregator_responses";
+/// Namespace of task timestamps, keyed by task.
+pub const TASK_NAMESPACE: &str = "aggregator_tasks";
+
+/// When a task was registered and finalized, as seen by the aggregator.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct TaskRecord {
+    created_unix_ms: Option<u64>,
+    finalized_unix_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResponseHistory {
+    store: Arc<dyn StateStore>,
+    retention_ms: u64,
+}
+
+impl ResponseHistory {
+    pub fn new(store: Arc<dyn StateStore>, retention_secs: u64) -> Self {
+        Self {
+            store,
+            retention_ms: retention_secs.saturating_mul(1000),
+        }
+    }
+
+    /// Records the registration of `task_index`; latencies are measured from it.
+    pub fn task_created(&self, task_index: TaskIndex, now_ms: u64) -> Result<(), PhalaAvsError> {
+        let mut task = self.task(task_index)?;
+        task.created_unix_ms.get_or_insert(now_ms);
+        self.store
+            .put_json(TASK_NAMESPACE, &task_index.to_be_bytes(), &task)
+    }
+
+    /// The first response of `operator_id` to `task_index`, if one was accepted.
+    pub fn response(
+        &self,
+        task_index: TaskIndex,
+        operator_id: B256,
+    ) -> Result<Option<ResponseRecord>, PhalaAvsError> {
+        self.store
+            .get_json(RESPONSE_NAMESPACE, &response_key(task_index, operator_id))
+    }
+
+    /// Stores a freshly accepted response, pending aggregation.
+    pub fn record(
+        &self,
+        task_index: TaskIndex,
+        operator_id: B256,
+        digest: B256,
+        now_ms: u64,
+    ) -> Result<ResponseRecord, PhalaAvsError> {
+        let created = self.task(task_index)?.created_unix_ms;
+        let record = ResponseRecord {
+            operator_id,
+            task_index,
+            digest,
+            received_unix_ms: now_ms,
+            latency_ms: created.map(|created| now_ms.saturating_sub(created)),
+            verification: ResponseVerification::Pending,
+        };
+        self.store.put_json(
+            RESPONSE_NAMESPACE,
+            &response_key(task_index, operator_id),
+            &record,
+        )?;
+        Ok(record)
+    }
+
+    /// Forgets the response of `operator_id` to `task_index`, which the aggregation failed to
+    /// take, so a retry of it is accepted afresh.
+    pub fn remove(&self, task_index: TaskIndex, operator_id: B256) -> Result<(), PhalaAvsError> {
+        self.store
+            .delete(RESPONSE_NAMESPACE, &response_key(task_index, operator_id))
+    }
+
+    /// Marks `task_index` confirmed on-chain: responses from `non_signers` were left out of
+    /// the aggregate, the others are part of it. Starts the retention period.
+    pub fn finalize(
+        &self,
+        task_index: TaskIndex,
+        non_signers: &[B256],
+        now_ms: u64,
+    ) -> Result<(), PhalaAvsError> {
+        for mut record in self.task_responses(task_index)?.responses {
+            record.verification = if non_signers.contains(&record.operator_id) {
+                ResponseVerification::NonSigner
+            } else {
+                ResponseVerification::Aggregated
+            };
+            self.store.put_json(
+                RESPONSE_NAMESPACE,
+                &response_key(task_index, record.operator_id),
+                &record,
+            )?;
+        }
+        let mut task = self.task(task_index)?;
+        task.finalized_unix_ms.get_or_insert(now_ms);
+        self.store
+            .put_json(TASK_NAMESPACE, &task_index.to_be_bytes(), &task)
+    }
+
+    /// The responses accepted for `task_index`, by operator id.
+    pub fn task_responses(
+        &self,
+        task_index: TaskIndex,
+    ) -> Result<TaskResponseHistory, PhalaAvsError> {
+        let task = self.task(task_index)?;
+        let prefix = task_index.to_be_bytes();
+        let responses = self
+            .store
+            .scan(RESPONSE_NAMESPACE)?
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, value)| {
+                serde_json::from_slice(&value).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Corrupt response record: {e}"))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(TaskResponseHistory {
+            task_index,
+            created_unix_ms: task.created_unix_ms,
+            finalized_unix_ms: task.finalized_unix_ms,
+            responses,
+        })
+    }
+
+    /// Deletes the tasks finalized more than the retention period before `now_ms`, with their
+    /// responses. Returns how many tasks were pruned.
+    pub fn prune(&self, now_ms: u64) -> Result<usize, PhalaAvsError> {
+        let expired: Vec<Vec<u8>> = self
+            .store
+            .scan(TASK_NAMESPACE)?
+            .into_iter()
+            .filter(|(_, value)| {
+                serde_json::from_slice::<TaskRecord>(value)
+                    .ok()
+                    .and_then(|task| task.finalized_unix_ms)
+                    .is_some_and(|finalized| now_ms.saturating_sub(finalized) >= self.retention_ms)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        for (key, _) in self.store.scan(RESPONSE_NAMESPACE)? {
+            if expired.iter().any(|task| key.starts_with(task)) {
+                self.store.delete(RESPONSE_NAMESPACE, &key)?;
+            }
+        }
+        for task in &expired {
+            self.store.delete(TASK_NAMESPACE, task)?;
+        }
+        Ok(expired.len())
+    }
+
+    fn task(&self, task_index: TaskIndex) -> Result<TaskRecord, PhalaAvsError> {
+        Ok(self
+            .store
+            .get_json(TASK_NAMESPACE, &task_index.to_be_bytes())?
+            .unwrap_or_default())
+    }
+}
+
+fn response_key(task_index: TaskIndex, operator_id: B256) -> Vec<u8> {
+    let mut key = task_index.to_be_bytes().to_vec();
+    key.extend_from_slice(operator_id.as_slice());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    const DAY_SECS: u64 = 86_400;
+
+    #[test]
+    fn responses_are_queryable_until_pruned_after_finalization() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let history = ResponseHistory::new(store, DAY_SECS);
+        let operators = [1, 2, 3].map(B256::repeat_byte);
+        // A mocked clock, in unix ms.
+        let created = 1_700_000_000_000;
+        history.task_created(7, created).unwrap();
+        for (i, operator) in operators.iter().enumerate() {
+            let digest = B256::repeat_byte(0x10 + i as u8);
+            let received = created + 250 * (i as u64 + 1);
+            history.record(7, *operator, digest, received).unwrap();
+        }
+        history
+            .record(8, operators[0], B256::ZERO, created)
+            .unwrap();
+
+        let task = history.task_responses(7).unwrap();
+        let summary: Vec<_> = task
+            .responses
+            .iter()
+            .map(|r| (r.operator_id, r.digest, r.latency_ms, r.verification))
+            .collect();
+        assert_eq!(summary, [
+            (
+                operators[0],
+                B256::repeat_byte(0x10),
+                Some(250),
+                ResponseVerification::Pending
+            ),
+            (
+                operators[1],
+                B256::repeat_byte(0x11),
+                Some(500),
+                ResponseVerification::Pending
+            ),
+            (
+                operators[2],
+                B256::repeat_byte(0x12),
+                Some(750),
+                ResponseVerification::Pending
+            ),
+        ]);
+        // Task 8 was never registered with this aggregator.
+        assert_eq!(
+            history.task_responses(8).unwrap().responses[0].latency_ms,
+            None
+        );
+
+        let finalized = created + 10_000;
+        history.finalize(7, &[operators[2]], finalized).unwrap();
+        let task = history.task_responses(7).unwrap();
+        assert_eq!(task.finalized_unix_ms, Some(finalized));
+        let verifications: Vec<_> = task.responses.iter().map(|r| r.verification).collect();
+        assert_eq!(verifications, [
+            ResponseVerification::Aggregated,
+            ResponseVerification::Aggregated,
+            ResponseVerification::NonSigner,
+        ]);
+
+        // Kept through the retention period; unfinalized tasks are never pruned.
+        let retention_ms = DAY_SECS * 1000;
+        assert_eq!(history.prune(finalized + retention_ms - 1).unwrap(), 0);
+        assert_eq!(history.task_responses(7).unwrap().responses.len(), 3);
+        assert_eq!(history.prune(finalized + retention_ms).unwrap(), 1);
+        let pruned = history.task_responses(7).unwrap();
+        assert!(pruned.responses.is_empty());
+        assert_eq!(pruned.created_unix_ms, None);
+        assert_eq!(history.task_responses(8).unwrap().responses.len(), 1);
+    }
+}
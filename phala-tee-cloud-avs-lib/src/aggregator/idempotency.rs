@@ -0,0 +1,390 @@
+//! Idempotency keys of `process_signed_task_response` submissions.
+//!
+//! The client generates a key per logical submission and sends it with every retry, in the
+//! envelope's `idempotency_key` field. The reply to the first attempt is stored under that key,
+//! in the aggregator's state store, so a retry of a submission the aggregator already processed
+//! (say, one whose reply was lost to a client timeout) gets the original reply back without a
+//! second aggregation pass, even when the payload was rebuilt. Keys expire
+//! `AGGREGATOR_IDEMPOTENCY_TTL_SECS` after they were first seen.
+//!
+//! The handling lives in the RPC method, not the transport, so every transport serving the
+//! method shares it. Clients sending a key get a [`SubmitReply`] object telling them whether
+//! the reply was replayed; clients without one keep getting a bare `true`.
+
+use super::TaskIndex;
+pub use crate::aggregator_wire::{IDEMPOTENCY_KEY, SubmitReply};
+use crate::config::env_or;
+use crate::display::Hash;
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::B256;
+use jsonrpc_core::{ErrorCode, Value};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Namespace of recorded replies, keyed by idempotency key.
+pub const IDEMPOTENCY_NAMESPACE: &str = "aggregator_idempotency";
+/// Submissions answered, by whether the reply was replayed.
+pub const IDEMPOTENT_REPLIES_TOTAL: &str = "phala_avs_aggregator_idempotent_replies_total";
+
+/// A JSON-RPC reply, as recorded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RecordedReply {
+    Result {
+        result: Value,
+    },
+    Error {
+        code: i64,
+        message: String,
+        data: Option<Value>,
+    },
+}
+
+impl From<&Result<Value, jsonrpc_core::Error>> for RecordedReply {
+    fn from(reply: &Result<Value, jsonrpc_core::Error>) -> Self {
+        match reply {
+            Ok(result) => RecordedReply::Result {
+                result: result.clone(),
+            },
+            Err(e) => RecordedReply::Error {
+                code: e.code.code(),
+                message: e.message.clone(),
+                data: e.data.clone(),
+            },
+        }
+    }
+}
+
+impl From<RecordedReply> for Result<Value, jsonrpc_core::Error> {
+    fn from(reply: RecordedReply) -> Self {
+        match reply {
+            RecordedReply::Result { result } => Ok(result),
+            RecordedReply::Error {
+                code,
+                message,
+                data,
+            } => Err(jsonrpc_core::Error {
+                code: ErrorCode::from(code),
+                message,
+                data,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct KeyRecord {
+    task_index: TaskIndex,
+    operator_id: B256,
+    reply: RecordedReply,
+    first_seen_unix_ms: u64,
+}
+
+/// Replies recorded per idempotency key.
+#[derive(Clone, Debug)]
+pub struct IdempotencyCache {
+    store: Arc<dyn StateStore>,
+    ttl_ms: u64,
+}
+
+impl IdempotencyCache {
+    pub fn new(store: Arc<dyn StateStore>, ttl_secs: u64) -> Self {
+        Self {
+            store,
+            ttl_ms: ttl_secs.saturating_mul(1000),
+        }
+    }
+
+    /// Reads `AGGREGATOR_IDEMPOTENCY_TTL_SECS`.
+    pub fn from_env(store: Arc<dyn StateStore>) -> Result<Self, PhalaAvsError> {
+        Ok(Self::new(
+            store,
+            env_or("AGGREGATOR_IDEMPOTENCY_TTL_SECS", 3_600)?,
+        ))
+    }
+
+    /// Answers the submission of `operator_id`'s response to `task_index` under `key`: with the
+    /// reply recorded for the key, or with what `process` replies, which is then recorded.
+    ///
+    /// Replies are shaped as [`SubmitReply`] for keyed submissions. A key reused for another
+    /// task or operator is refused. Internal errors, which a retry may get past, are not
+    /// recorded.
+    pub async fn resolve<F>(
+        &self,
+        key: Option<&str>,
+        task_index: TaskIndex,
+        operator_id: B256,
+        now_ms: u64,
+        process: F,
+    ) -> Result<Value, jsonrpc_core::Error>
+    where
+        F: Future<Output = Result<Value, jsonrpc_core::Error>>,
+    {
+        let Some(key) = key else {
+            return process.await;
+        };
+        let key = Uuid::parse_str(key).map_err(|e| {
+            jsonrpc_core::Error::invalid_params(format!("Invalid {IDEMPOTENCY_KEY}: {e}"))
+        })?;
+        if let Some(record) = self.get(&key, now_ms).map_err(internal)? {
+            if record.task_index != task_index || record.operator_id != operator_id {
+                return Err(jsonrpc_core::Error::invalid_params(format!(
+                    "{IDEMPOTENCY_KEY} {key} was used for operator {} on task {}",
+                    Hash(record.operator_id),
+                    record.task_index
+                )));
+            }
+            METRICS.inc_counter(IDEMPOTENT_REPLIES_TOTAL, &[("outcome", "replayed")], 1);
+            return keyed(record.reply.into(), true);
+        }
+        let reply = process.await;
+        if !matches!(&reply, Err(e) if e.code == ErrorCode::InternalError) {
+            let record = KeyRecord {
+                task_index,
+                operator_id,
+                reply: RecordedReply::from(&reply),
+                first_seen_unix_ms: now_ms,
+            };
+            self.store
+                .put_json(IDEMPOTENCY_NAMESPACE, key.as_bytes(), &record)
+                .map_err(internal)?;
+        }
+        METRICS.inc_counter(IDEMPOTENT_REPLIES_TOTAL, &[("outcome", "fresh")], 1);
+        keyed(reply, false)
+    }
+
+    /// The record of `key`, unless it expired.
+    fn get(&self, key: &Uuid, now_ms: u64) -> Result<Option<KeyRecord>, PhalaAvsError> {
+        let record: Option<KeyRecord> =
+            self.store.get_json(IDEMPOTENCY_NAMESPACE, key.as_bytes())?;
+        Ok(record.filter(|r| !self.expired(r, now_ms)))
+    }
+
+    fn expired(&self, record: &KeyRecord, now_ms: u64) -> bool {
+        now_ms.saturating_sub(record.first_seen_unix_ms) >= self.ttl_ms
+    }
+
+    /// Deletes expired keys, returning how many.
+    pub fn prune(&self, now_ms: u64) -> Result<usize, PhalaAvsError> {
+        let mut pruned = 0;
+        for (key, value) in self.store.scan(IDEMPOTENCY_NAMESPACE)? {
+            let expired = match serde_json::from_slice::<KeyRecord>(&value) {
+                Ok(record) => self.expired(&record, now_ms),
+                // Unreadable records go with the expired ones.
+                Err(_) => true,
+            };
+            if expired {
+                self.store.delete(IDEMPOTENCY_NAMESPACE, &key)?;
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+}
+
+/// Wraps an accepting reply as a [`SubmitReply`]; errors pass through.
+fn keyed(
+    reply: Result<Value, jsonrpc_core::Error>,
+    replayed: bool,
+) -> Result<Value, jsonrpc_core::Error> {
+    let accepted = reply?.as_bool().unwrap_or(false);
+    serde_json::to_value(SubmitReply::Keyed { accepted, replayed }).map_err(internal)
+}
+
+/// An internal error, which is not recorded under the submission's key.
+pub fn internal(e: impl std::fmt::Display) -> jsonrpc_core::Error {
+    jsonrpc_core::Error {
+        code: ErrorCode::InternalError,
+        message: e.to_string(),
+        data: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator_wire::{
+        CURRENT_WIRE_VERSION, SUBMIT_RESPONSE_METHOD, Submission, parse_submission,
+    };
+    use crate::state::MemoryStateStore;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const OPERATOR: B256 = B256::repeat_byte(0xaa);
+    const KEY: &str = "6f1c1c2e-4a53-4d4e-9d2a-0d5e0b4a7c11";
+
+    fn cache() -> IdempotencyCache {
+        IdempotencyCache::new(Arc::new(MemoryStateStore::default()), 60)
+    }
+
+    /// Processes a submission, counting aggregation passes.
+    async fn submit(
+        cache: &IdempotencyCache,
+        passes: &AtomicU32,
+        key: Option<&str>,
+        operator_id: B256,
+        now_ms: u64,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        cache
+            .resolve(key, 7, operator_id, now_ms, async {
+                passes.fetch_add(1, Ordering::SeqCst);
+                Ok(Value::Bool(true))
+            })
+            .await
+    }
+
+    fn reply(value: Value) -> SubmitReply {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_retry_after_a_lost_reply_gets_the_original_success() {
+        let cache = cache();
+        let passes = AtomicU32::new(0);
+        // The first attempt is processed, but its reply never reaches the timed-out client.
+        let first = submit(&cache, &passes, Some(KEY), OPERATOR, 1_000).await;
+        assert_eq!(reply(first.unwrap()), SubmitReply::Keyed {
+            accepted: true,
+            replayed: false,
+        });
+
+        let retry = submit(&cache, &passes, Some(KEY), OPERATOR, 2_000).await;
+        assert_eq!(reply(retry.unwrap()), SubmitReply::Keyed {
+            accepted: true,
+            replayed: true,
+        });
+        assert_eq!(passes.load(Ordering::SeqCst), 1);
+
+        // Another operator cannot reuse the key, and unkeyed clients keep the bare reply.
+        let reused = submit(&cache, &passes, Some(KEY), B256::repeat_byte(0xbb), 2_000).await;
+        assert!(reused.is_err());
+        let unkeyed = submit(&cache, &passes, None, OPERATOR, 2_000).await;
+        assert_eq!(reply(unkeyed.unwrap()), SubmitReply::Accepted(true));
+    }
+
+    #[tokio::test]
+    async fn refusals_are_replayed_and_internal_errors_retried() {
+        let cache = cache();
+        let refusal = || async {
+            Err(jsonrpc_core::Error {
+                code: ErrorCode::ServerError(-32010),
+                message: "conflict".to_string(),
+                data: None,
+            })
+        };
+        let first = cache.resolve(Some(KEY), 7, OPERATOR, 0, refusal()).await;
+        let retry = cache
+            .resolve(Some(KEY), 7, OPERATOR, 0, async { Ok(Value::Bool(true)) })
+            .await;
+        assert_eq!(first, retry);
+
+        let other = "0b5cf2a6-8f0e-4a8f-bd3c-3f3f1d9e2a77";
+        let failed = cache
+            .resolve(Some(other), 7, OPERATOR, 0, async {
+                Err(internal("aggregator not initialized"))
+            })
+            .await;
+        assert!(failed.is_err());
+        let retried = cache
+            .resolve(Some(other), 7, OPERATOR, 0, async { Ok(Value::Bool(true)) })
+            .await;
+        assert!(!reply(retried.unwrap()).replayed());
+    }
+
+    #[tokio::test]
+    async fn keys_expire_after_the_ttl() {
+        let cache = cache();
+        let passes = AtomicU32::new(0);
+        submit(&cache, &passes, Some(KEY), OPERATOR, 0)
+            .await
+            .unwrap();
+        let within = submit(&cache, &passes, Some(KEY), OPERATOR, 59_999).await;
+        assert!(reply(within.unwrap()).replayed());
+        assert_eq!(cache.prune(59_999).unwrap(), 0);
+
+        // Expired: processed again, and pruned once the new record expires too.
+        let after = submit(&cache, &passes, Some(KEY), OPERATOR, 60_000).await;
+        assert!(!reply(after.unwrap()).replayed());
+        assert_eq!(passes.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.prune(119_999).unwrap(), 0);
+        assert_eq!(cache.prune(120_000).unwrap(), 1);
+        assert!(cache.store.scan(IDEMPOTENCY_NAMESPACE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_served_submit_method_replays_keyed_retries() {
+        let cache = cache();
+        let passes = Arc::new(AtomicU32::new(0));
+        let mut io = jsonrpc_core::IoHandler::new();
+        let counted = Arc::clone(&passes);
+        io.add_method(
+            SUBMIT_RESPONSE_METHOD,
+            move |params: jsonrpc_core::Params| {
+                let (cache, passes) = (cache.clone(), Arc::clone(&counted));
+                async move {
+                    let submission = parse_submission(params.parse()?)
+                        .map_err(|e| jsonrpc_core::Error::invalid_params(e.to_string()))?;
+                    let key = submission.idempotency_key.as_deref();
+                    cache
+                        .resolve(key, 7, OPERATOR, 1_000, async {
+                            passes.fetch_add(1, Ordering::SeqCst);
+                            Ok(Value::Bool(true))
+                        })
+                        .await
+                }
+            },
+        );
+        let server = jsonrpc_http_server::ServerBuilder::new(io)
+            .start_http(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let url = format!("http://{}", server.address());
+        let envelope = Submission {
+            response: serde_json::json!({ "task_index": 7 }),
+            idempotency_key: Some(KEY.to_string()),
+            traceparent: None,
+        }
+        .envelope(CURRENT_WIRE_VERSION)
+        .unwrap();
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": SUBMIT_RESPONSE_METHOD,
+            "params": envelope,
+        });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let replies: Vec<SubmitReply> = runtime.block_on(async {
+            let client = reqwest::Client::new();
+            let mut replies = Vec::new();
+            // The client retries a submission whose reply it lost with the same key.
+            for _ in 0..2 {
+                let reply: Value = client
+                    .post(&url)
+                    .json(&request)
+                    .send()
+                    .await
+                    .unwrap()
+                    .json()
+                    .await
+                    .unwrap();
+                replies.push(self::reply(reply["result"].clone()));
+            }
+            replies
+        });
+        server.close();
+        assert_eq!(replies, [
+            SubmitReply::Keyed {
+                accepted: true,
+                replayed: false,
+            },
+            SubmitReply::Keyed {
+                accepted: true,
+                replayed: true,
+            },
+        ]);
+        assert_eq!(passes.load(Ordering::SeqCst), 1);
+    }
+}
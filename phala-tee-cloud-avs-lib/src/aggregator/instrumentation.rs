@@ -0,0 +1,197 @@
+use super::TaskIndex;
+use crate::metrics::METRICS;
+use jsonrpc_http_server::hyper::{self, Body, Request, Response};
+use jsonrpc_http_server::{RequestMiddleware, RequestMiddlewareAction};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Size of `process_signed_task_response` request bodies.
+pub const RPC_PAYLOAD_BYTES: &str = "phala_avs_aggregator_rpc_payload_bytes";
+/// Time spent parsing a signed task response out of the JSON-RPC envelope.
+pub const RPC_PARSE_SECONDS: &str = "phala_avs_aggregator_rpc_parse_seconds";
+/// Time spent verifying a response signature and handing it to the BLS aggregation service.
+pub const VERIFY_SECONDS: &str = "phala_avs_aggregator_verify_seconds";
+/// Time from task registration until the BLS service reported an aggregated result.
+pub const AGGREGATION_SECONDS: &str = "phala_avs_aggregator_aggregation_seconds";
+/// Time spent building `NonSignerStakesAndSignature` for submission.
+pub const SUBMISSION_BUILD_SECONDS: &str = "phala_avs_aggregator_submission_build_seconds";
+/// Time from sending the aggregated response until its receipt.
+pub const CONFIRMATION_SECONDS: &str = "phala_avs_aggregator_confirmation_seconds";
+/// Signed responses received, by outcome.
+pub const RESPONSES_TOTAL: &str = "phala_avs_aggregator_responses_total";
+
+/// Renders the quorum numbers of a task as a stable label value, e.g. `0,1`.
+pub fn quorum_label(quorum_numbers: &[u8]) -> String {
+    quorum_numbers
+        .iter()
+        .map(u8::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Records the duration of a pipeline stage.
+///
+/// Stage metrics are labeled by quorum only; the task index is carried on the tracing spans
+/// instead, to keep metric cardinality bounded.
+pub fn observe_stage(metric: &str, quorum: &str, elapsed: Duration) {
+    METRICS.observe(metric, &[("quorum", quorum)], elapsed.as_secs_f64());
+}
+
+/// Tracks per-task timestamps so latencies spanning several stages can be measured.
+///
+/// Kept by the aggregator server, which the aggregation tells when a task was registered,
+/// aggregated and finalized.
+#[derive(Clone, Debug, Default)]
+pub struct TaskTimings {
+    /// Registration time, until the task is aggregated, and quorum label, until it is forgotten.
+    registered: Arc<Mutex<HashMap<TaskIndex, (Option<Instant>, String)>>>,
+    /// `traceparent` the task's submission continues.
+    traces: Arc<Mutex<HashMap<TaskIndex, String>>>,
+}
+
+impl TaskTimings {
+    pub fn task_registered(&self, task_index: TaskIndex, quorum_numbers: &[u8]) {
+        self.registered
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                task_index,
+                (Some(Instant::now()), quorum_label(quorum_numbers)),
+            );
+    }
+
+    /// The quorum label recorded for `task_index`, or `unknown`.
+    pub fn quorum(&self, task_index: TaskIndex) -> String {
+        self.registered
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&task_index)
+            .map(|(_, quorum)| quorum.clone())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /// Keeps the first `traceparent` recorded for `task_index`.
+    pub fn record_trace(&self, task_index: TaskIndex, traceparent: Option<String>) {
+        if let Some(traceparent) = traceparent {
+            self.traces
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(task_index)
+                .or_insert(traceparent);
+        }
+    }
+
+    pub fn take_trace(&self, task_index: TaskIndex) -> Option<String> {
+        self.traces
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&task_index)
+    }
+
+    /// Records the aggregation latency of `task_index`, once.
+    pub fn aggregation_completed(&self, task_index: TaskIndex) -> Option<Duration> {
+        let mut registered = self.registered.lock().unwrap_or_else(|e| e.into_inner());
+        let (registered_at, quorum) = registered.get_mut(&task_index)?;
+        let elapsed = registered_at.take()?.elapsed();
+        observe_stage(AGGREGATION_SECONDS, quorum, elapsed);
+        Some(elapsed)
+    }
+
+    /// Forgets `task_index`, once its aggregated response is finalized.
+    pub fn forget(&self, task_index: TaskIndex) {
+        self.registered
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&task_index);
+        self.take_trace(task_index);
+    }
+}
+
+/// Serves `GET /metrics` from the aggregator's JSON-RPC HTTP server.
+pub struct MetricsMiddleware;
+
+impl RequestMiddleware for MetricsMiddleware {
+    fn on_request(&self, request: Request<Body>) -> RequestMiddlewareAction {
+        if request.method() != hyper::Method::GET || request.uri().path() != "/metrics" {
+            return request.into();
+        }
+        let response = Response::builder()
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(Body::from(METRICS.render()))
+            .expect("static response is valid");
+        RequestMiddlewareAction::Respond {
+            should_validate_hosts: true,
+            response: Box::pin(async move { Ok(response) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_verification_lands_in_verify_histogram() {
+        observe_stage(VERIFY_SECONDS, "7", Duration::from_millis(1500));
+        let (count, sum) = METRICS
+            .histogram(VERIFY_SECONDS, &[("quorum", "7")])
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(sum >= 1.5);
+        assert!(
+            METRICS
+                .histogram(AGGREGATION_SECONDS, &[("quorum", "7")])
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn aggregation_latency_is_measured_from_registration() {
+        let timings = TaskTimings::default();
+        timings.task_registered(3, &[8, 9]);
+        assert_eq!(timings.quorum(3), "8,9");
+        assert!(timings.aggregation_completed(3).is_some());
+        assert!(timings.aggregation_completed(3).is_none());
+        // The quorum labels the later stages until the task is forgotten.
+        assert_eq!(timings.quorum(3), "8,9");
+        timings.forget(3);
+        assert_eq!(timings.quorum(3), "unknown");
+        let (count, _) = METRICS
+            .histogram(AGGREGATION_SECONDS, &[("quorum", "8,9")])
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn metrics_are_served_next_to_the_rpc_methods() {
+        observe_stage(CONFIRMATION_SECONDS, "4", Duration::from_millis(250));
+        let mut io = jsonrpc_core::IoHandler::new();
+        io.add_sync_method("ping", |_| Ok(jsonrpc_core::Value::Bool(true)));
+        let server = jsonrpc_http_server::ServerBuilder::new(io)
+            .request_middleware(MetricsMiddleware)
+            .start_http(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let url = format!("http://{}", server.address());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let client = reqwest::Client::new();
+            let metrics = client.get(format!("{url}/metrics")).send().await.unwrap();
+            assert!(metrics.status().is_success());
+            let body = metrics.text().await.unwrap();
+            assert!(body.contains(CONFIRMATION_SECONDS), "{body}");
+
+            let reply: serde_json::Value = client
+                .post(&url)
+                .json(&serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "ping"}))
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            assert_eq!(reply["result"], true);
+        });
+        server.close();
+    }
+}
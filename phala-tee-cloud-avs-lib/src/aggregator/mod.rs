@@ -0,0 +1,18 @@
+//! The parts of the aggregator that build without the task manager bindings: the JSON-RPC
+//! [`server`], the stores behind it and the operator's [`client`] of it.
+//!
+//! BLS aggregation and submission in `context.rs` and `task.rs` are the Incredible Squaring
+//! template and stay out of the build until the crate depends on eigensdk and the task manager
+//! contracts. The aggregation plugs into the server as
+//! its [`server::Aggregation`].
+
+pub mod client;
+pub mod dead_letter;
+pub mod dedupe;
+pub mod history;
+pub mod idempotency;
+pub mod instrumentation;
+pub mod server;
+
+/// Index of a task in the task manager.
+pub type TaskIndex = u32;
@@ -0,0 +1,767 @@
+//! The aggregator's JSON-RPC server.
+//!
+//! `get_server_info` reports the wire-format versions and features of [`ServerInfo::current`];
+//! submissions are read in any of them by [`parse_submission`].
+//!
+//! Operators submit signed task responses with `process_signed_task_response`. A response is
+//! checked against the operator's first response to the task in the [`ResponseLedger`]: a
+//! resent one is acknowledged without reprocessing, a conflicting one is refused with
+//! [`EQUIVOCATION_ERROR_CODE`] and logged. Fresh responses are verified and handed to the
+//! [`Aggregation`]. Accepted responses are kept in the ledger's [`ResponseHistory`], which
+//! `get_majority_digest` counts and `admin_get_task_responses` lists. A submission carrying an
+//! idempotency key is answered through the [`IdempotencyCache`], so a retry gets the reply to
+//! the first attempt. `admin_list_dead_letters` lists the aggregation's
+//! [`dead_letters`](Aggregation::dead_letters). The `admin_*` methods of [`crate::aggregator_admin`] take
+//! `AGGREGATOR_ADMIN_TOKEN` and are refused when it is unset.
+//!
+//! Each submission is traced in an `aggregator.rpc_receive` span, with its parsing,
+//! verification and handoff to the aggregation in child spans carrying the task index and
+//! quorum, and each stage is timed in the histograms of [`super::instrumentation`]. The
+//! aggregation reports when a task was registered, aggregated, submitted and finalized, which
+//! [`TaskTimings`] turns into the latencies of the later stages. The receive span continues the
+//! trace of the envelope's `traceparent`, and the aggregated response is submitted in the
+//! [`submission_span`](AggregatorServer::submission_span) continuing the trace of the first
+//! response accepted for the task, so one trace runs from an operator's submission to the
+//! on-chain response.
+
+use super::TaskIndex;
+use super::dedupe::{Admission, ResponseLedger, response_digest};
+use super::history::ResponseHistory;
+use super::idempotency::{IdempotencyCache, internal};
+use super::instrumentation::{
+    CONFIRMATION_SECONDS, MetricsMiddleware, RESPONSES_TOTAL, RPC_PARSE_SECONDS, RPC_PAYLOAD_BYTES,
+    SUBMISSION_BUILD_SECONDS, TaskTimings, VERIFY_SECONDS, observe_stage,
+};
+use crate::aggregator_admin::{
+    DeadLetterEntry, EQUIVOCATION_ERROR_CODE, EXCLUDED_OPERATOR_ERROR_CODE,
+    GET_TASK_RESPONSES_METHOD, LIST_DEAD_LETTERS_METHOD, LIST_EQUIVOCATIONS_METHOD,
+    TaskResponsesRequest,
+};
+use crate::aggregator_wire::{
+    MAJORITY_DIGEST_METHOD, MajorityDigest, MajorityRequest, SERVER_INFO_METHOD,
+    SUBMIT_RESPONSE_METHOD, ServerInfo, SignedTaskResponse, parse_submission,
+};
+use crate::api_keys::constant_time_eq;
+use crate::config;
+use crate::display::Hash;
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::otel::{TRACEPARENT, current_traceparent, set_parent};
+use blueprint_sdk::alloy::primitives::{B256, Bytes};
+use blueprint_sdk::{info, warn};
+use jsonrpc_core::{ErrorCode, IoHandler, Params, Value};
+use jsonrpc_http_server::{AccessControlAllowOrigin, DomainsValidation, Server, ServerBuilder};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{Instrument, Span, field, info_span};
+
+/// Params field carrying the admin token.
+const ADMIN_TOKEN_FIELD: &str = "admin_token";
+
+/// The BLS side of the aggregator, which the server hands verified responses to.
+///
+/// Implemented over the BLS aggregation service and the task manager bindings, which build and
+/// submit the aggregated response once a task reaches its quorum.
+pub trait Aggregation: Send + Sync {
+    /// The ABI encoding of a task response; responses are deduplicated by its digest.
+    fn encode(&self, task_response: &Value) -> Result<Bytes, PhalaAvsError>;
+
+    /// Checks the response's signature against the operator's registered key.
+    fn verify<'a>(
+        &'a self,
+        response: &'a SignedTaskResponse,
+    ) -> BoxFuture<'a, Result<(), PhalaAvsError>>;
+
+    /// Hands a verified response to the aggregation of its task.
+    fn aggregate(&self, response: SignedTaskResponse) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+
+    /// The aggregated responses whose submission failed, as kept in its
+    /// [`DeadLetterStore`](super::dead_letter::DeadLetterStore).
+    fn dead_letters(&self) -> Vec<DeadLetterEntry> {
+        Vec::new()
+    }
+}
+
+/// Answers the aggregator's JSON-RPC methods; clones share their state.
+#[derive(Clone)]
+pub struct AggregatorServer {
+    aggregation: Arc<dyn Aggregation>,
+    ledger: ResponseLedger,
+    idempotency: IdempotencyCache,
+    timings: TaskTimings,
+    admin_token: Option<String>,
+}
+
+impl AggregatorServer {
+    pub fn new(
+        aggregation: Arc<dyn Aggregation>,
+        ledger: ResponseLedger,
+        idempotency: IdempotencyCache,
+        admin_token: Option<String>,
+    ) -> Self {
+        Self {
+            aggregation,
+            ledger,
+            idempotency,
+            timings: TaskTimings::default(),
+            admin_token,
+        }
+    }
+
+    /// Opens the ledger on `AGGREGATOR_STATE_BACKEND`, keeps idempotency keys next to it and
+    /// reads `AGGREGATOR_ADMIN_TOKEN`.
+    pub fn from_env(aggregation: Arc<dyn Aggregation>) -> Result<Self, PhalaAvsError> {
+        let ledger = ResponseLedger::from_env()?;
+        let idempotency = IdempotencyCache::from_env(Arc::clone(ledger.store()))?;
+        Ok(Self::new(
+            aggregation,
+            ledger,
+            idempotency,
+            config::lookup("AGGREGATOR_ADMIN_TOKEN"),
+        ))
+    }
+
+    pub fn ledger(&self) -> &ResponseLedger {
+        &self.ledger
+    }
+
+    /// Records the registration of `task_index` for `quorum_numbers`; response and aggregation
+    /// latencies are measured from it.
+    pub fn task_registered(
+        &self,
+        task_index: TaskIndex,
+        quorum_numbers: &[u8],
+        now_ms: u64,
+    ) -> Result<(), PhalaAvsError> {
+        self.timings.task_registered(task_index, quorum_numbers);
+        self.history().task_created(task_index, now_ms)
+    }
+
+    /// Records that the BLS service aggregated `task_index`, returning the time since its
+    /// registration.
+    pub fn task_aggregated(&self, task_index: TaskIndex) -> Option<Duration> {
+        self.timings.aggregation_completed(task_index)
+    }
+
+    /// Records how long building the submission of `task_index` and waiting for its receipt
+    /// took.
+    pub fn task_submitted(&self, task_index: TaskIndex, build: Duration, confirmation: Duration) {
+        let quorum = self.timings.quorum(task_index);
+        observe_stage(SUBMISSION_BUILD_SECONDS, &quorum, build);
+        observe_stage(CONFIRMATION_SECONDS, &quorum, confirmation);
+    }
+
+    /// The span to build and submit the aggregated response to `task_index` in, which continues
+    /// the trace of the first response accepted for it.
+    pub fn submission_span(&self, task_index: TaskIndex) -> Span {
+        let quorum = self.timings.quorum(task_index);
+        let span = info_span!("aggregator.submit", task_index, quorum = quorum.as_str());
+        set_parent(&span, self.timings.take_trace(task_index).as_deref());
+        span
+    }
+
+    /// Records the aggregated response to `task_index` confirmed on-chain without the
+    /// signatures of `non_signers`, which starts its retention period.
+    pub fn task_finalized(
+        &self,
+        task_index: TaskIndex,
+        non_signers: &[B256],
+        now_ms: u64,
+    ) -> Result<(), PhalaAvsError> {
+        self.timings.forget(task_index);
+        self.history().finalize(task_index, non_signers, now_ms)
+    }
+
+    /// Deletes the responses to tasks past their retention period, returning how many tasks,
+    /// and the expired idempotency keys.
+    pub fn prune(&self, now_ms: u64) -> Result<usize, PhalaAvsError> {
+        self.idempotency.prune(now_ms)?;
+        self.history().prune(now_ms)
+    }
+
+    fn history(&self) -> &ResponseHistory {
+        self.ledger.history()
+    }
+
+    /// The server's methods, for any transport.
+    pub fn io_handler(&self) -> IoHandler {
+        let mut io = IoHandler::new();
+        self.method(&mut io, SERVER_INFO_METHOD, |server, _| async move {
+            to_value(Ok(ServerInfo::current(server.admin_token.is_some())))
+        });
+        self.method(
+            &mut io,
+            SUBMIT_RESPONSE_METHOD,
+            |server, params| async move {
+                // Runs to the end when the operator hangs up, so the reply is recorded under
+                // the submission's key for its retry.
+                tokio::spawn(async move { server.submit(params).await })
+                    .await
+                    .map_err(internal)?
+            },
+        );
+        self.method(
+            &mut io,
+            LIST_DEAD_LETTERS_METHOD,
+            |server, params| async move {
+                server.authorize(&params)?;
+                to_value(Ok(server.aggregation.dead_letters()))
+            },
+        );
+        self.method(
+            &mut io,
+            LIST_EQUIVOCATIONS_METHOD,
+            |server, params| async move {
+                server.authorize(&params)?;
+                to_value(server.ledger.equivocations())
+            },
+        );
+        self.method(
+            &mut io,
+            GET_TASK_RESPONSES_METHOD,
+            |server, params| async move {
+                server.authorize(&params)?;
+                let request: TaskResponsesRequest = parse_params(params)?;
+                to_value(server.history().task_responses(request.task_index))
+            },
+        );
+        // Asked by operators' safety checks, so not behind the admin token.
+        self.method(
+            &mut io,
+            MAJORITY_DIGEST_METHOD,
+            |server, params| async move {
+                let MajorityRequest { task_index } = parse_params(params)?;
+                let history = server.history().task_responses(task_index);
+                to_value(history.map(|history| {
+                    let digests = history.responses.iter().map(|r| r.digest);
+                    MajorityDigest::count(task_index, digests)
+                }))
+            },
+        );
+        io
+    }
+
+    /// Serves the methods over HTTP at `address`, with `GET /metrics` next to them.
+    pub fn start(&self, address: &SocketAddr) -> Result<Server, PhalaAvsError> {
+        let server = ServerBuilder::new(self.io_handler())
+            .cors(DomainsValidation::AllowOnly(vec![
+                AccessControlAllowOrigin::Any,
+            ]))
+            .request_middleware(MetricsMiddleware)
+            .start_http(address)
+            .map_err(|e| {
+                PhalaAvsError::AggregatorError(format!(
+                    "Failed to serve the aggregator RPC at {address}: {e}"
+                ))
+            })?;
+        info!("Aggregator RPC listening at {}", server.address());
+        Ok(server)
+    }
+
+    fn method<F, Fut>(&self, io: &mut IoHandler, name: &str, call: F)
+    where
+        F: Fn(AggregatorServer, Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Value, jsonrpc_core::Error>> + Send + 'static,
+    {
+        let server = self.clone();
+        io.add_method(name, move |params: Params| {
+            let server = server.clone();
+            let pending = params.parse::<Value>().map(|params| call(server, params));
+            async move { pending?.await }
+        });
+    }
+
+    /// Handles `process_signed_task_response`.
+    async fn submit(&self, params: Value) -> Result<Value, jsonrpc_core::Error> {
+        let payload_bytes = serde_json::to_vec(&params).map_or(0, |payload| payload.len());
+        let receive = info_span!(
+            "aggregator.rpc_receive",
+            task_index = field::Empty,
+            quorum = field::Empty,
+            payload_bytes
+        );
+        // Continued before the envelope is parsed, so the parse span joins the operator's trace
+        // too; the propagator ignores a malformed value.
+        set_parent(&receive, params.get(TRACEPARENT).and_then(Value::as_str));
+        let started = Instant::now();
+        let parsed = info_span!(parent: &receive, "aggregator.rpc_parse")
+            .in_scope(|| read_submission(params));
+        let parse_time = started.elapsed();
+        let (key, response, task_index) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                count_response("unknown", "invalid");
+                return Err(e);
+            }
+        };
+        let quorum = self.timings.quorum(task_index);
+        receive.record("task_index", task_index);
+        receive.record("quorum", quorum.as_str());
+        observe_stage(RPC_PARSE_SECONDS, &quorum, parse_time);
+        METRICS.observe(
+            RPC_PAYLOAD_BYTES,
+            &[("quorum", quorum.as_str())],
+            payload_bytes as f64,
+        );
+
+        let operator_id = response.operator_id;
+        let process = self.process(task_index, &quorum, response);
+        self.idempotency
+            .resolve(
+                key.as_deref(),
+                task_index,
+                operator_id,
+                now_unix_ms(),
+                process,
+            )
+            .instrument(receive)
+            .await
+    }
+
+    /// Admits `response` to `task_index`'s aggregation, unless the operator already sent it
+    /// or equivocated, counting the outcome.
+    async fn process(
+        &self,
+        task_index: TaskIndex,
+        quorum: &str,
+        response: SignedTaskResponse,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let operator_id = response.operator_id;
+        let encoded = match self.aggregation.encode(&response.task_response) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                count_response(quorum, "invalid");
+                return Err(rpc_error(e));
+            }
+        };
+        let started = Instant::now();
+        let verified = self
+            .aggregation
+            .verify(&response)
+            .instrument(info_span!("aggregator.verify", task_index, quorum))
+            .await;
+        observe_stage(VERIFY_SECONDS, quorum, started.elapsed());
+        if let Err(e) = verified {
+            count_response(quorum, "invalid");
+            return Err(rpc_error(e));
+        }
+        let digest = response_digest(&encoded);
+        let admission = self
+            .ledger
+            .admit(task_index, operator_id, digest, now_unix_ms())
+            .map_err(rpc_error)?;
+        match admission {
+            Admission::Fresh => {}
+            Admission::Duplicate => {
+                count_response(quorum, "duplicate");
+                return Ok(Value::Bool(true));
+            }
+            Admission::Conflict { entry, excluded } => {
+                count_response(quorum, "equivocation");
+                warn!(
+                    "Operator {} equivocated on task {task_index}: sent {} after {}",
+                    Hash(operator_id),
+                    Hash(entry.conflicting_digest),
+                    Hash(entry.first_digest)
+                );
+                if excluded {
+                    warn!(
+                        "Excluding operator {} after repeated equivocations",
+                        Hash(operator_id)
+                    );
+                }
+                let mut error = server_error(
+                    EQUIVOCATION_ERROR_CODE,
+                    format!("Response conflicts with the one accepted for task {task_index}"),
+                );
+                error.data = serde_json::to_value(&entry).ok();
+                return Err(error);
+            }
+            Admission::Excluded => {
+                count_response(quorum, "excluded");
+                return Err(server_error(
+                    EXCLUDED_OPERATOR_ERROR_CODE,
+                    format!(
+                        "Operator {} is excluded for equivocating",
+                        Hash(operator_id)
+                    ),
+                ));
+            }
+        }
+        self.timings.record_trace(task_index, current_traceparent());
+        let handoff = info_span!("aggregator.aggregate", task_index, quorum);
+        if let Err(e) = self
+            .aggregation
+            .aggregate(response)
+            .instrument(handoff)
+            .await
+        {
+            count_response(quorum, "failed");
+            // Forgotten, so a retry of the response is aggregated rather than acknowledged.
+            if let Err(e) = self.history().remove(task_index, operator_id) {
+                warn!("Failed to forget a response to task {task_index}: {e}");
+            }
+            return Err(rpc_error(e));
+        }
+        count_response(quorum, "accepted");
+        Ok(Value::Bool(true))
+    }
+
+    /// Checks the admin token in `params`.
+    fn authorize(&self, params: &Value) -> Result<(), jsonrpc_core::Error> {
+        let Some(expected) = &self.admin_token else {
+            return Err(unauthorized("admin methods are disabled"));
+        };
+        let token = params
+            .get(ADMIN_TOKEN_FIELD)
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        if !constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+            return Err(unauthorized("invalid admin token"));
+        }
+        Ok(())
+    }
+}
+
+/// Invalid input is refused as invalid params; anything else is an internal error, which
+/// clients retry.
+fn rpc_error(e: PhalaAvsError) -> jsonrpc_core::Error {
+    match e {
+        PhalaAvsError::ValidationError(_) => jsonrpc_core::Error::invalid_params(e.to_string()),
+        e => jsonrpc_core::Error {
+            code: ErrorCode::InternalError,
+            message: e.to_string(),
+            data: None,
+        },
+    }
+}
+
+/// The idempotency key, signed response and task of `process_signed_task_response` params.
+fn read_submission(
+    params: Value,
+) -> Result<(Option<String>, SignedTaskResponse, TaskIndex), jsonrpc_core::Error> {
+    let submission = parse_submission(params).map_err(rpc_error)?;
+    let response: SignedTaskResponse =
+        serde_json::from_value(submission.response).map_err(|e| {
+            jsonrpc_core::Error::invalid_params(format!("Invalid signed response: {e}"))
+        })?;
+    let task_index = response.task_index().map_err(rpc_error)?;
+    Ok((submission.idempotency_key, response, task_index))
+}
+
+fn count_response(quorum: &str, outcome: &str) {
+    METRICS.inc_counter(
+        RESPONSES_TOTAL,
+        &[("quorum", quorum), ("outcome", outcome)],
+        1,
+    );
+}
+
+fn server_error(code: i64, message: String) -> jsonrpc_core::Error {
+    jsonrpc_core::Error {
+        code: ErrorCode::ServerError(code),
+        message,
+        data: None,
+    }
+}
+
+fn unauthorized(reason: &str) -> jsonrpc_core::Error {
+    jsonrpc_core::Error {
+        code: ErrorCode::InvalidRequest,
+        message: format!("Unauthorized: {reason}"),
+        data: None,
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, jsonrpc_core::Error> {
+    serde_json::from_value(params)
+        .map_err(|e| jsonrpc_core::Error::invalid_params(format!("Invalid params: {e}")))
+}
+
+fn to_value<T: serde::Serialize>(
+    result: Result<T, PhalaAvsError>,
+) -> Result<Value, jsonrpc_core::Error> {
+    serde_json::to_value(result.map_err(rpc_error)?)
+        .map_err(|e| rpc_error(PhalaAvsError::Other(e.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::dead_letter::DeadLetterStore;
+    use crate::aggregator::dedupe::DedupeConfig;
+    use crate::aggregator::instrumentation::AGGREGATION_SECONDS;
+    use crate::aggregator_admin::{
+        AggregationSummary, AggregatorAdminClient, DeadLetterStatus, ResponseVerification,
+    };
+    use crate::response_safety::{AggregatorMajority, PeerSource};
+    use crate::state::{MemoryStateStore, StateStore};
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    const TOKEN: &str = "admin-secret";
+
+    /// Aggregates into a list; signatures of `"bad"` fail verification, which takes
+    /// `verify_delay`.
+    #[derive(Default)]
+    struct Recorder {
+        aggregated: Mutex<Vec<SignedTaskResponse>>,
+        verify_delay: Duration,
+        dead: DeadLetterStore<()>,
+    }
+
+    impl Aggregation for Recorder {
+        fn encode(&self, task_response: &Value) -> Result<Bytes, PhalaAvsError> {
+            Ok(serde_json::to_vec(task_response).unwrap().into())
+        }
+
+        fn verify<'a>(
+            &'a self,
+            response: &'a SignedTaskResponse,
+        ) -> BoxFuture<'a, Result<(), PhalaAvsError>> {
+            let valid = response.signature != json!("bad");
+            Box::pin(async move {
+                tokio::time::sleep(self.verify_delay).await;
+                if valid {
+                    Ok(())
+                } else {
+                    Err(PhalaAvsError::ValidationError("bad signature".to_string()))
+                }
+            })
+        }
+
+        fn aggregate(
+            &self,
+            response: SignedTaskResponse,
+        ) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.aggregated.lock().unwrap().push(response);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn dead_letters(&self) -> Vec<DeadLetterEntry> {
+            self.dead.entries()
+        }
+    }
+
+    fn signed(task_index: u32, operator: u8, squared: u64) -> Value {
+        json!({
+            "task_response": { "referenceTaskIndex": task_index, "numberSquared": squared },
+            "signature": { "g1_point": { "X": "0x1", "Y": "0x2" } },
+            "operator_id": B256::repeat_byte(operator),
+        })
+    }
+
+    async fn call(url: &str, method: &str, params: Value) -> Value {
+        reqwest::Client::new()
+            .post(url)
+            .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    fn server(recorder: &Arc<Recorder>, exclude_after: Option<usize>) -> AggregatorServer {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let config = DedupeConfig {
+            retention_secs: 600,
+            exclude_after,
+            conflict_window_secs: 600,
+        };
+        let idempotency = IdempotencyCache::new(Arc::clone(&store), 600);
+        let ledger = ResponseLedger::new(config, store).unwrap();
+        let aggregation = Arc::clone(recorder) as Arc<dyn Aggregation>;
+        AggregatorServer::new(aggregation, ledger, idempotency, Some(TOKEN.to_string()))
+    }
+
+    fn digest(squared: u64) -> B256 {
+        let encoded = serde_json::to_vec(&signed(7, 0, squared)["task_response"]).unwrap();
+        response_digest(&encoded)
+    }
+
+    #[test]
+    fn conflicting_responses_are_refused_and_listed() {
+        let recorder = Arc::new(Recorder::default());
+        let server = server(&recorder, Some(2))
+            .start(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let url = format!("http://{}", server.address());
+        let submit = |task, squared| {
+            let envelope = json!({ "wire_version": 2, "response": signed(task, 0xaa, squared) });
+            call(&url, SUBMIT_RESPONSE_METHOD, envelope)
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            // Resending the accepted response is acknowledged without aggregating it again.
+            for _ in 0..2 {
+                assert_eq!(submit(7, 49).await["result"], true);
+            }
+            assert_eq!(recorder.aggregated.lock().unwrap().len(), 1);
+
+            let conflict = submit(7, 50).await;
+            assert_eq!(conflict["error"]["code"], EQUIVOCATION_ERROR_CODE);
+            // Refused before it is recorded; a version 1 envelope, as older operators send.
+            let mut forged = signed(8, 0xbb, 64);
+            forged["signature"] = json!("bad");
+            let v1 = json!({ "params": forged, "id": 1, "jsonrpc": "2.0" });
+            let refused = call(&url, SUBMIT_RESPONSE_METHOD, v1).await;
+            assert_eq!(refused["error"]["code"], -32602);
+
+            // The second equivocation excludes the operator, from fresh tasks too.
+            assert_eq!(submit(8, 64).await["result"], true);
+            let conflict = submit(8, 65).await;
+            assert_eq!(conflict["error"]["code"], EQUIVOCATION_ERROR_CODE);
+            let excluded = submit(9, 81).await;
+            assert_eq!(excluded["error"]["code"], EXCLUDED_OPERATOR_ERROR_CODE);
+
+            let admin = AggregatorAdminClient::new(url.clone(), TOKEN.to_string());
+            let equivocations = admin.list_equivocations().await.unwrap();
+            let tasks: Vec<_> = equivocations.iter().map(|e| e.task_index).collect();
+            assert_eq!(tasks, [7, 8]);
+
+            // The aggregation failed to submit task 7's aggregated response.
+            let failed = DeadLetterEntry {
+                task_index: 7,
+                task_created_block: 100,
+                quorum_numbers: Bytes::from_static(&[0]),
+                response: Bytes::from_static(&[1; 64]),
+                aggregation: AggregationSummary {
+                    non_signers: 0,
+                    quorum_apks: 1,
+                    signers_apk_g2: "apk".to_string(),
+                },
+                failure: "nonce too low".to_string(),
+                revert: None,
+                status: DeadLetterStatus::Failed,
+                attempts: 0,
+                dead_lettered_unix: 0,
+            };
+            recorder.dead.record(failed, (), 1_000);
+            let dead = admin.list_dead_letters().await.unwrap();
+            assert_eq!(recorder.dead.entries(), dead);
+            let intruder = AggregatorAdminClient::new(url.clone(), "guess".to_string());
+            let err = intruder.list_equivocations().await.unwrap_err();
+            assert!(err.to_string().contains("invalid admin token"));
+        });
+        assert_eq!(recorder.aggregated.lock().unwrap().len(), 2);
+        server.close();
+    }
+
+    #[test]
+    fn accepted_responses_are_kept_until_pruned() {
+        let recorder = Arc::new(Recorder::default());
+        let server = server(&recorder, None);
+        let created = now_unix_ms();
+        server.task_registered(7, &[0], created).unwrap();
+        let http = server.start(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("http://{}", http.address());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            for (operator, squared) in [(1, 49), (2, 49), (3, 50)] {
+                let envelope =
+                    json!({ "wire_version": 2, "response": signed(7, operator, squared) });
+                let reply = call(&url, SUBMIT_RESPONSE_METHOD, envelope).await;
+                assert_eq!(reply["result"], true);
+            }
+            // What operators' safety checks see.
+            let majority = AggregatorMajority::new(url.clone())
+                .observe("squaring", 7)
+                .await
+                .unwrap();
+            let counts: Vec<_> = majority.iter().map(|c| (c.digest, c.count)).collect();
+            assert_eq!(counts, [(digest(49), 2), (digest(50), 1)]);
+
+            let admin = AggregatorAdminClient::new(url.clone(), TOKEN.to_string());
+            let history = admin.get_task_responses(7).await.unwrap();
+            assert_eq!(history.created_unix_ms, Some(created));
+            let digests: Vec<_> = history.responses.iter().map(|r| r.digest).collect();
+            assert_eq!(digests, [digest(49), digest(49), digest(50)]);
+            assert!(history.responses.iter().all(|r| r.latency_ms.is_some()));
+
+            let finalized = now_unix_ms();
+            server
+                .task_finalized(7, &[B256::repeat_byte(3)], finalized)
+                .unwrap();
+            let history = admin.get_task_responses(7).await.unwrap();
+            let verifications: Vec<_> = history.responses.iter().map(|r| r.verification).collect();
+            assert_eq!(verifications, [
+                ResponseVerification::Aggregated,
+                ResponseVerification::Aggregated,
+                ResponseVerification::NonSigner,
+            ]);
+
+            assert_eq!(server.prune(finalized + 599_999).unwrap(), 0);
+            assert_eq!(server.prune(finalized + 600_000).unwrap(), 1);
+            let pruned = admin.get_task_responses(7).await.unwrap();
+            assert!(pruned.responses.is_empty());
+        });
+        http.close();
+    }
+
+    #[test]
+    fn a_task_is_measured_through_every_stage() {
+        // Verification is slowed down; the other stages are not.
+        let recorder = Arc::new(Recorder {
+            verify_delay: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let server = server(&recorder, None);
+        server.task_registered(11, &[2, 5], now_unix_ms()).unwrap();
+        let http = server.start(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("http://{}", http.address());
+        let quorum = [("quorum", "2,5")];
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let metrics = runtime.block_on(async {
+            let envelope = json!({ "wire_version": 2, "response": signed(11, 1, 121) });
+            let reply = call(&url, SUBMIT_RESPONSE_METHOD, envelope).await;
+            assert_eq!(reply["result"], true);
+
+            // The aggregation reports the later stages of the task.
+            let aggregated = server.task_aggregated(11).unwrap();
+            assert!(aggregated >= Duration::from_millis(300));
+            assert!(server.task_aggregated(11).is_none());
+            server.task_submitted(11, Duration::from_millis(20), Duration::from_millis(4_000));
+            server.task_finalized(11, &[], now_unix_ms()).unwrap();
+
+            let metrics = reqwest::get(format!("{url}/metrics")).await.unwrap();
+            assert!(metrics.status().is_success());
+            metrics.text().await.unwrap()
+        });
+        http.close();
+
+        let (parses, parse_time) = METRICS.histogram(RPC_PARSE_SECONDS, &quorum).unwrap();
+        let (verifications, verify_time) = METRICS.histogram(VERIFY_SECONDS, &quorum).unwrap();
+        assert_eq!((parses, verifications), (1, 1));
+        assert!(verify_time >= 0.3, "verification took {verify_time}s");
+        assert!(parse_time < 0.3, "parsing took {parse_time}s");
+        let (_, payload) = METRICS.histogram(RPC_PAYLOAD_BYTES, &quorum).unwrap();
+        assert!(payload > 100.0);
+        let (_, aggregation) = METRICS.histogram(AGGREGATION_SECONDS, &quorum).unwrap();
+        assert!(aggregation >= verify_time);
+        let (_, build) = METRICS
+            .histogram(SUBMISSION_BUILD_SECONDS, &quorum)
+            .unwrap();
+        let (_, confirmation) = METRICS.histogram(CONFIRMATION_SECONDS, &quorum).unwrap();
+        assert_eq!((build, confirmation), (0.02, 4.0));
+        let accepted = [("quorum", "2,5"), ("outcome", "accepted")];
+        assert_eq!(METRICS.counter(RESPONSES_TOTAL, &accepted), Some(1));
+        for series in [
+            RPC_PARSE_SECONDS,
+            VERIFY_SECONDS,
+            AGGREGATION_SECONDS,
+            RESPONSES_TOTAL,
+        ] {
+            assert!(metrics.contains(series), "{series} is not served");
+        }
+    }
+}
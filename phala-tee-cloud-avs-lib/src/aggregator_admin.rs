@@ -0,0 +1,306 @@
+//! Types and client for the aggregator's admin RPC.
+//!
+//! An aggregated response whose submission failed is dead-lettered by the aggregator with its
+//! task, response and aggregation result. `admin_list_dead_letters` lists them.
+//! `admin_replay_dead_letter`, which would rebuild and resubmit one with [`ReplayOverrides`], is
+//! frozen in the wire-format fixtures but not served: replaying needs the BLS aggregation and
+//! task manager bindings the aggregator is built without.
+//! `admin_list_equivocations` lists the operators caught sending conflicting responses to a
+//! task, and `admin_get_task_responses` the responses each operator sent to one task, with
+//! when they arrived and whether they made it into the submitted aggregate. All take the `AGGREGATOR_ADMIN_TOKEN` in their params and are disabled when it is unset.
+
+use crate::error::PhalaAvsError;
+use crate::error_catalog::DecodedRevert;
+use crate::sanitize;
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+pub const LIST_DEAD_LETTERS_METHOD: &str = "admin_list_dead_letters";
+pub const REPLAY_DEAD_LETTER_METHOD: &str = "admin_replay_dead_letter";
+pub const LIST_EQUIVOCATIONS_METHOD: &str = "admin_list_equivocations";
+pub const GET_TASK_RESPONSES_METHOD: &str = "admin_get_task_responses";
+
+/// JSON-RPC error code of a response conflicting with the one accepted before.
+pub const EQUIVOCATION_ERROR_CODE: i64 = -32010;
+/// JSON-RPC error code of a response from an excluded operator.
+pub const EXCLUDED_OPERATOR_ERROR_CODE: i64 = -32011;
+
+/// Adjustments for a replayed submission; unset fields keep the defaults of the normal path.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gas_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_fee_per_gas: Option<u128>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_priority_fee_per_gas: Option<u128>,
+    /// Submits from this account, which must be one of the aggregator wallet's signers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<Address>,
+    /// Re-fetches the stake and apk indices at the task's reference block before rebuilding
+    /// `NonSignerStakesAndSignature`, e.g. after the operator set changed.
+    #[serde(default)]
+    pub refresh_stake_indices: bool,
+}
+
+/// Params of `admin_replay_dead_letter`, besides the token.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayRequest {
+    pub task_index: u32,
+    #[serde(default)]
+    pub overrides: ReplayOverrides,
+}
+
+/// Params of `admin_get_task_responses`, besides the token.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskResponsesRequest {
+    pub task_index: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DeadLetterStatus {
+    /// Waiting for a replay.
+    Failed,
+    /// A replay confirmed in `transaction_hash`.
+    Replayed { transaction_hash: B256 },
+    /// The task turned out to be responded on-chain already, so nothing was sent.
+    AlreadyResponded,
+}
+
+/// What was aggregated for a dead-lettered response.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregationSummary {
+    pub non_signers: usize,
+    pub quorum_apks: usize,
+    pub signers_apk_g2: String,
+}
+
+/// A dead-lettered submission as listed by the aggregator.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetterEntry {
+    pub task_index: u32,
+    pub task_created_block: u32,
+    pub quorum_numbers: Bytes,
+    /// The ABI-encoded task response.
+    pub response: Bytes,
+    pub aggregation: AggregationSummary,
+    /// Why the last submission or replay failed.
+    pub failure: String,
+    /// The contract error it reverted with, if the node returned one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revert: Option<DecodedRevert>,
+    pub status: DeadLetterStatus,
+    pub attempts: u32,
+    pub dead_lettered_unix: u64,
+}
+
+/// Two differing responses of an operator to the same task.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EquivocationEntry {
+    pub operator_id: B256,
+    pub task_index: u32,
+    /// Digest of the ABI-encoded response accepted first.
+    pub first_digest: B256,
+    pub first_unix: u64,
+    pub conflicting_digest: B256,
+    pub conflict_unix: u64,
+}
+
+/// What became of an accepted response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseVerification {
+    /// Handed to BLS aggregation; the task is not finalized yet.
+    Pending,
+    /// Its signature is part of the aggregate submitted on-chain.
+    Aggregated,
+    /// The task was finalized without it: its signature did not verify, or it arrived after
+    /// the quorum was reached.
+    NonSigner,
+}
+
+/// An operator's first accepted response to a task.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseRecord {
+    pub operator_id: B256,
+    pub task_index: u32,
+    /// Digest of the ABI-encoded response.
+    pub digest: B256,
+    pub received_unix_ms: u64,
+    /// Time from the task's registration with the aggregator to this response; unknown for
+    /// tasks registered before the aggregator restarted.
+    pub latency_ms: Option<u64>,
+    pub verification: ResponseVerification,
+}
+
+/// The responses the aggregator accepted for a task.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskResponseHistory {
+    pub task_index: u32,
+    pub created_unix_ms: Option<u64>,
+    /// When the aggregated response was confirmed on-chain.
+    pub finalized_unix_ms: Option<u64>,
+    /// By operator id.
+    pub responses: Vec<ResponseRecord>,
+}
+
+/// Calls the aggregator's admin RPC at `url`.
+#[derive(Clone, Debug)]
+pub struct AggregatorAdminClient {
+    url: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl AggregatorAdminClient {
+    pub fn new(url: String, token: String) -> Self {
+        Self {
+            url,
+            token,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn list_dead_letters(&self) -> Result<Vec<DeadLetterEntry>, PhalaAvsError> {
+        self.call(LIST_DEAD_LETTERS_METHOD, json!({})).await
+    }
+
+    pub async fn list_equivocations(&self) -> Result<Vec<EquivocationEntry>, PhalaAvsError> {
+        self.call(LIST_EQUIVOCATIONS_METHOD, json!({})).await
+    }
+
+    pub async fn get_task_responses(
+        &self,
+        task_index: u32,
+    ) -> Result<TaskResponseHistory, PhalaAvsError> {
+        let params = json!(TaskResponsesRequest { task_index });
+        self.call(GET_TASK_RESPONSES_METHOD, params).await
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, PhalaAvsError> {
+        let reply: Value = self
+            .client
+            .post(&self.url)
+            .json(&request(method, params, &self.token))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| {
+                PhalaAvsError::Other(format!(
+                    "Failed to reach aggregator: {}",
+                    sanitize::message("aggregator_error", e)
+                ))
+            })?
+            .json()
+            .await
+            .map_err(|e| {
+                PhalaAvsError::Other(format!(
+                    "Invalid aggregator reply: {}",
+                    sanitize::message("aggregator_error", e)
+                ))
+            })?;
+        parse_reply(method, reply)
+    }
+}
+
+/// The JSON-RPC request calling `method` with `params` and the admin token.
+pub fn request(method: &str, mut params: Value, token: &str) -> Value {
+    params["admin_token"] = token.into();
+    json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params })
+}
+
+/// The result of a JSON-RPC reply to `method`, or its error.
+pub fn parse_reply<T: DeserializeOwned>(
+    method: &str,
+    mut reply: Value,
+) -> Result<T, PhalaAvsError> {
+    if let Some(error) = reply.get("error") {
+        let message = error
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error");
+        return Err(PhalaAvsError::Other(format!(
+            "{method} failed: {}",
+            sanitize::message("aggregator_error", message)
+        )));
+    }
+    serde_json::from_value(reply["result"].take()).map_err(|e| {
+        PhalaAvsError::Other(format!(
+            "Invalid {method} result: {}",
+            sanitize::message("aggregator_error", e)
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_requests_carry_the_token_and_overrides() {
+        let overrides = ReplayOverrides {
+            gas_limit: Some(2_000_000),
+            refresh_stake_indices: true,
+            ..Default::default()
+        };
+        let params = json!({ "task_index": 7, "overrides": overrides });
+        let request = request(REPLAY_DEAD_LETTER_METHOD, params, "secret");
+        assert_eq!(request["params"]["admin_token"], "secret");
+        let parsed: ReplayOverrides =
+            serde_json::from_value(request["params"]["overrides"].clone()).unwrap();
+        assert_eq!(parsed, overrides);
+
+        let error = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": -32602, "message": "no dead letter for task 7" },
+        });
+        let err = parse_reply::<DeadLetterEntry>(REPLAY_DEAD_LETTER_METHOD, error).unwrap_err();
+        assert!(err.to_string().contains("no dead letter for task 7"));
+    }
+
+    #[test]
+    fn dead_letters_carry_the_decoded_revert() {
+        use crate::error_catalog::decode_revert;
+        use blueprint_sdk::alloy::sol_types::{Revert, SolError};
+
+        let data = Revert::from("Pausable: paused").abi_encode();
+        let entry = DeadLetterEntry {
+            task_index: 7,
+            task_created_block: 100,
+            quorum_numbers: Bytes::from_static(&[0]),
+            response: Bytes::new(),
+            aggregation: AggregationSummary {
+                non_signers: 0,
+                quorum_apks: 1,
+                signers_apk_g2: String::new(),
+            },
+            failure: "respondToSquaringTask failed: execution reverted".to_string(),
+            revert: decode_revert(&data),
+            status: DeadLetterStatus::Failed,
+            attempts: 1,
+            dead_lettered_unix: 1_700_000_000,
+        };
+        let reply = json!({ "jsonrpc": "2.0", "id": 1, "result": [entry] });
+        let parsed = parse_reply::<Vec<DeadLetterEntry>>(LIST_DEAD_LETTERS_METHOD, reply).unwrap();
+        let revert = parsed[0].revert.as_ref().unwrap();
+        assert_eq!(revert.name.as_deref(), Some("Paused"));
+        assert!(revert.hint.contains("unpaused"));
+    }
+
+    #[test]
+    fn hostile_error_messages_are_bounded_and_escaped() {
+        for message in sanitize::adversarial::strings() {
+            let reply = json!({ "jsonrpc": "2.0", "id": 1, "error": { "message": message } });
+            let err = parse_reply::<Value>(LIST_DEAD_LETTERS_METHOD, reply).unwrap_err();
+            sanitize::adversarial::assert_safe(&err.to_string(), sanitize::MAX_MESSAGE_BYTES + 64);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! The JSON-RPC contract between operators and the aggregator, by wire-format version.
+//!
+//! Operators and the aggregator are upgraded independently, so during a rollout either may be a
+//! release behind the other. Since version 2 the aggregator answers `get_server_info` with the
+//! versions and features it supports; the operator's client probes it at startup and speaks the
+//! highest version both support. An aggregator without the method only speaks version 1.
+//! Methods added within a version are announced as features, such as `get_majority_digest`,
+//! which operators' pre-signing safety checks ask for the digests accepted for a task.
+//!
+//! Version 1 nests the signed response in a JSON-RPC-shaped object under `params`; version 2
+//! carries it under `response`, next to `wire_version`. The aggregator keeps accepting the
+//! previous version's envelope for one release window: [`parse_submission`] maps either to a
+//! [`Submission`], defaulting the fields the older one lacks. Golden requests and replies of
+//! every method, per version, are frozen under `tests/fixtures/wire`; a change that breaks one
+//! fails `tests/wire_compat.rs`.
+
+use crate::error::PhalaAvsError;
+use crate::otel::TRACEPARENT;
+use crate::response_safety::DigestCount;
+use crate::sanitize;
+use blueprint_sdk::alloy::primitives::B256;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+pub const SERVER_INFO_METHOD: &str = "get_server_info";
+pub const SUBMIT_RESPONSE_METHOD: &str = "process_signed_task_response";
+pub const MAJORITY_DIGEST_METHOD: &str = "get_majority_digest";
+
+/// Envelope field naming its wire-format version; absent in version 1.
+pub const WIRE_VERSION_FIELD: &str = "wire_version";
+/// Envelope field carrying the idempotency key.
+pub const IDEMPOTENCY_KEY: &str = "idempotency_key";
+
+/// The version this build sends when the other side supports it.
+pub const CURRENT_WIRE_VERSION: u32 = 2;
+/// The versions this build speaks, oldest first. The previous one is dropped a release after
+/// the current one was introduced.
+pub const SUPPORTED_WIRE_VERSIONS: &[u32] = &[1, 2];
+
+/// Submissions may carry an `idempotency_key`.
+pub const FEATURE_IDEMPOTENCY_KEYS: &str = "idempotency_keys";
+/// Submissions may carry a W3C `traceparent`.
+pub const FEATURE_TRACE_CONTEXT: &str = "trace_context";
+/// The `admin_*` methods are enabled.
+pub const FEATURE_ADMIN: &str = "admin";
+/// `get_majority_digest` is answered.
+pub const FEATURE_MAJORITY_DIGEST: &str = "majority_digest";
+
+/// JSON-RPC error code of an unknown method.
+pub const METHOD_NOT_FOUND_ERROR_CODE: i64 = -32601;
+/// JSON-RPC error code of an envelope in a wire-format version the aggregator doesn't speak.
+pub const UNSUPPORTED_WIRE_VERSION_ERROR_CODE: i64 = -32012;
+
+/// The reply to `get_server_info`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub wire_versions: Vec<u32>,
+    /// Optional features, which an older client ignores.
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl ServerInfo {
+    /// What this build's aggregator reports.
+    pub fn current(admin: bool) -> Self {
+        let mut features = vec![
+            FEATURE_IDEMPOTENCY_KEYS.to_string(),
+            FEATURE_TRACE_CONTEXT.to_string(),
+            FEATURE_MAJORITY_DIGEST.to_string(),
+        ];
+        if admin {
+            features.push(FEATURE_ADMIN.to_string());
+        }
+        Self {
+            wire_versions: SUPPORTED_WIRE_VERSIONS.to_vec(),
+            features,
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// The highest version both this build and the aggregator speak. `server` is `None` for an
+/// aggregator without `get_server_info`, which predates version 2.
+pub fn negotiate(server: Option<&ServerInfo>) -> Result<u32, PhalaAvsError> {
+    let server_versions = server.map_or(&[1][..], |info| &info.wire_versions);
+    SUPPORTED_WIRE_VERSIONS
+        .iter()
+        .rev()
+        .find(|v| server_versions.contains(v))
+        .copied()
+        .ok_or_else(|| {
+            PhalaAvsError::AggregatorError(format!(
+                "No wire-format version in common with the aggregator: it speaks \
+                 {server_versions:?}, this operator {SUPPORTED_WIRE_VERSIONS:?}; upgrade the \
+                 older of the two"
+            ))
+        })
+}
+
+/// An operator's signed task response, as carried in a submission.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedTaskResponse {
+    /// The task manager's `TaskResponse`, whose `referenceTaskIndex` names the task.
+    pub task_response: Value,
+    /// The operator's BLS signature of the response.
+    pub signature: Value,
+    pub operator_id: B256,
+}
+
+impl SignedTaskResponse {
+    /// The task the response answers.
+    pub fn task_index(&self) -> Result<u32, PhalaAvsError> {
+        self.task_response
+            .get("referenceTaskIndex")
+            .and_then(Value::as_u64)
+            .and_then(|index| u32::try_from(index).ok())
+            .ok_or_else(|| invalid("the task response has no referenceTaskIndex"))
+    }
+}
+
+/// A submitted task response, whichever version it arrived in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Submission {
+    /// The signed task response, as sent.
+    pub response: Value,
+    pub idempotency_key: Option<String>,
+    pub traceparent: Option<String>,
+}
+
+impl Submission {
+    /// The `process_signed_task_response` params of the submission in `version`.
+    pub fn envelope(&self, version: u32) -> Result<Value, PhalaAvsError> {
+        let mut envelope = match version {
+            1 => json!({ "params": self.response, "id": 1, "jsonrpc": "2.0" }),
+            2 => json!({ WIRE_VERSION_FIELD: 2, "response": self.response }),
+            other => return Err(unsupported(other)),
+        };
+        if let Some(key) = &self.idempotency_key {
+            envelope[IDEMPOTENCY_KEY] = key.as_str().into();
+        }
+        if let Some(traceparent) = &self.traceparent {
+            envelope[TRACEPARENT] = traceparent.as_str().into();
+        }
+        Ok(envelope)
+    }
+}
+
+/// Reads `process_signed_task_response` params in any supported version.
+pub fn parse_submission(params: Value) -> Result<Submission, PhalaAvsError> {
+    let Value::Object(mut fields) = params else {
+        return Err(invalid("params must be an object"));
+    };
+    let version = match fields.remove(WIRE_VERSION_FIELD) {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| invalid("wire_version must be an integer"))?,
+    };
+    let response = match version {
+        1 => fields.remove("params"),
+        2 => fields.remove("response"),
+        other => return Err(unsupported(other)),
+    };
+    Ok(Submission {
+        response: response.ok_or_else(|| invalid("missing the signed response"))?,
+        idempotency_key: string_field(&mut fields, IDEMPOTENCY_KEY)?,
+        traceparent: string_field(&mut fields, TRACEPARENT)?,
+    })
+}
+
+fn string_field(
+    fields: &mut Map<String, Value>,
+    name: &str,
+) -> Result<Option<String>, PhalaAvsError> {
+    match fields.remove(name) {
+        None | Some(Value::Null) => Ok(None),
+        // Both are opaque tokens (a UUID, a W3C traceparent) that end up in logs and stores.
+        Some(Value::String(value)) => {
+            sanitize::identifier(name, &value)?;
+            Ok(Some(value))
+        }
+        Some(_) => Err(invalid(&format!("{name} must be a string"))),
+    }
+}
+
+fn invalid(reason: &str) -> PhalaAvsError {
+    PhalaAvsError::ValidationError(format!("Invalid submission: {reason}"))
+}
+
+fn unsupported(version: u32) -> PhalaAvsError {
+    PhalaAvsError::AggregatorError(format!(
+        "Unsupported wire-format version {version}; supported: {SUPPORTED_WIRE_VERSIONS:?}"
+    ))
+}
+
+/// The reply to a submission.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SubmitReply {
+    /// To an unkeyed submission.
+    Accepted(bool),
+    Keyed {
+        accepted: bool,
+        /// The reply recorded for an earlier attempt, not a fresh processing.
+        replayed: bool,
+    },
+}
+
+impl SubmitReply {
+    pub fn accepted(&self) -> bool {
+        match *self {
+            SubmitReply::Accepted(accepted) | SubmitReply::Keyed { accepted, .. } => accepted,
+        }
+    }
+
+    pub fn replayed(&self) -> bool {
+        matches!(self, SubmitReply::Keyed { replayed: true, .. })
+    }
+}
+
+/// Params of `get_majority_digest`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MajorityRequest {
+    pub task_index: u32,
+}
+
+/// The reply to `get_majority_digest`: the digests of the responses accepted for a task, most
+/// common first.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MajorityDigest {
+    pub task_index: u32,
+    pub digests: Vec<DigestCount>,
+}
+
+impl MajorityDigest {
+    /// Counts `digests`, one per operator's accepted response.
+    pub fn count(task_index: u32, digests: impl IntoIterator<Item = B256>) -> Self {
+        let mut counts: Vec<DigestCount> = Vec::new();
+        for digest in digests {
+            match counts.iter_mut().find(|c| c.digest == digest) {
+                Some(seen) => seen.count += 1,
+                None => counts.push(DigestCount { digest, count: 1 }),
+            }
+        }
+        counts.sort_by(|a, b| b.count.cmp(&a.count).then(a.digest.cmp(&b.digest)));
+        Self {
+            task_index,
+            digests: counts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_picks_the_highest_common_version() {
+        assert_eq!(negotiate(None).unwrap(), 1);
+        let info = ServerInfo::current(false);
+        assert_eq!(negotiate(Some(&info)).unwrap(), CURRENT_WIRE_VERSION);
+        assert!(!info.supports(FEATURE_ADMIN));
+
+        let future = ServerInfo {
+            wire_versions: vec![3, 4],
+            features: vec![],
+        };
+        let err = negotiate(Some(&future)).unwrap_err();
+        assert!(err.to_string().contains("[3, 4]"));
+    }
+
+    #[test]
+    fn majority_digests_are_counted_most_common_first() {
+        let (a, b) = (B256::repeat_byte(1), B256::repeat_byte(2));
+        let majority = MajorityDigest::count(7, [a, b, b, a, b]);
+        assert_eq!(majority.digests, vec![
+            DigestCount {
+                digest: b,
+                count: 3
+            },
+            DigestCount {
+                digest: a,
+                count: 2
+            },
+        ]);
+        assert!(ServerInfo::current(false).supports(FEATURE_MAJORITY_DIGEST));
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let params = json!({ WIRE_VERSION_FIELD: 3, "response": {} });
+        let err = parse_submission(params).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Unsupported wire-format version 3")
+        );
+        assert!(parse_submission(json!({ WIRE_VERSION_FIELD: 2 })).is_err());
+    }
+
+    #[test]
+    fn hostile_tokens_are_refused_before_they_are_stored() {
+        for token in sanitize::adversarial::strings() {
+            let params = json!({ WIRE_VERSION_FIELD: 2, "response": {}, TRACEPARENT: token });
+            match parse_submission(params) {
+                Ok(submission) => sanitize::adversarial::assert_safe(
+                    submission.traceparent.as_deref().unwrap_or_default(),
+                    sanitize::MAX_IDENTIFIER_BYTES,
+                ),
+                Err(e) => {
+                    sanitize::adversarial::assert_safe(&e.to_string(), sanitize::MAX_MESSAGE_BYTES)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! The operator notebook: notes attached to challenges, epochs and incidents.
+//!
+//! Operators annotate an entity with `POST /admin/annotations/{kind}/{id}` or
+//! `annotate <kind> <id> <note>`; each note records who wrote it (the API key id) and when.
+//! Notes are stored per entity and surfaced wherever the entity appears: the challenge detail
+//! at `/challenges/{id}`, the self-audit report and its dispute bundles (which carry the notes
+//! of the disputed challenge). `GET /annotations` searches them.
+//!
+//! An epoch is `SELF_AUDIT_EPOCH_BLOCKS` blocks long, epoch `n` starting at block
+//! `n * SELF_AUDIT_EPOCH_BLOCKS`; an incident is the `id` of an [`Alert`](crate::notify::Alert).
+//! Notes are operator input rather than derived state, so a [state rebuild](crate::state::rebuild)
+//! carries them over unchanged. `ANNOTATION_MAX_NOTE_BYTES` and `ANNOTATION_MAX_PER_ENTITY`
+//! bound what one entity can accumulate.
+
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::U256;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// StateStore namespace holding the notes of each entity, keyed by [`AnnotationTarget`].
+pub const ANNOTATIONS_NAMESPACE: &str = "annotations";
+
+/// Longest incident id accepted; alert ids are much shorter.
+const MAX_INCIDENT_ID_LEN: usize = 64;
+
+/// Most notes a search returns.
+const MAX_SEARCH_RESULTS: usize = 1_000;
+
+#[derive(Clone, Debug)]
+pub struct AnnotationLimits {
+    /// Longest note, in bytes.
+    pub max_note_bytes: usize,
+    /// Most notes one entity holds.
+    pub max_per_entity: usize,
+}
+
+impl Default for AnnotationLimits {
+    fn default() -> Self {
+        Self {
+            max_note_bytes: 2_048,
+            max_per_entity: 100,
+        }
+    }
+}
+
+impl AnnotationLimits {
+    /// Reads `ANNOTATION_MAX_NOTE_BYTES` and `ANNOTATION_MAX_PER_ENTITY`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        Ok(Self {
+            max_note_bytes: env_or("ANNOTATION_MAX_NOTE_BYTES", defaults.max_note_bytes)?,
+            max_per_entity: env_or("ANNOTATION_MAX_PER_ENTITY", defaults.max_per_entity)?,
+        })
+    }
+}
+
+/// The entity a note is attached to, written `challenge:<id>`, `epoch:<n>` or
+/// `incident:<alert-id>`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum AnnotationTarget {
+    Challenge(U256),
+    Epoch(u64),
+    Incident(String),
+}
+
+impl AnnotationTarget {
+    /// Parses the `kind` and `id` path segments of the annotation endpoints.
+    pub fn parse(kind: &str, id: &str) -> Result<Self, PhalaAvsError> {
+        let invalid = |what: &str| PhalaAvsError::ValidationError(format!("Invalid {what} {id}"));
+        match kind {
+            "challenge" => id
+                .parse()
+                .map(Self::Challenge)
+                .map_err(|_| invalid("challenge id")),
+            "epoch" => id.parse().map(Self::Epoch).map_err(|_| invalid("epoch")),
+            "incident" => {
+                let valid = !id.is_empty()
+                    && id.len() <= MAX_INCIDENT_ID_LEN
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                if valid {
+                    Ok(Self::Incident(id.to_string()))
+                } else {
+                    Err(invalid("incident id"))
+                }
+            }
+            _ => Err(PhalaAvsError::ValidationError(format!(
+                "Unknown annotation kind {kind}; expected challenge, epoch or incident"
+            ))),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Challenge(_) => "challenge",
+            Self::Epoch(_) => "epoch",
+            Self::Incident(_) => "incident",
+        }
+    }
+
+    /// The id within the kind, e.g. the challenge id.
+    pub fn id(&self) -> String {
+        match self {
+            Self::Challenge(id) => id.to_string(),
+            Self::Epoch(epoch) => epoch.to_string(),
+            Self::Incident(id) => id.clone(),
+        }
+    }
+
+    fn key(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl fmt::Display for AnnotationTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.kind(), self.id())
+    }
+}
+
+impl FromStr for AnnotationTarget {
+    type Err = PhalaAvsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, id) = s.split_once(':').ok_or_else(|| {
+            PhalaAvsError::ValidationError(format!("Invalid annotation target {s}"))
+        })?;
+        Self::parse(kind, id)
+    }
+}
+
+impl TryFrom<String> for AnnotationTarget {
+    type Error = PhalaAvsError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<AnnotationTarget> for String {
+    fn from(target: AnnotationTarget) -> Self {
+        target.to_string()
+    }
+}
+
+/// A note attached to an entity.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub target: AnnotationTarget,
+    /// Id of the API key that wrote it.
+    pub author: String,
+    pub note: String,
+    pub created_unix_ms: u64,
+}
+
+/// Filters of `GET /annotations`; unset filters match every note.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AnnotationQuery {
+    pub target: Option<AnnotationTarget>,
+    /// `challenge`, `epoch` or `incident`.
+    pub kind: Option<String>,
+    pub author: Option<String>,
+    /// Case-insensitive substring of the note.
+    pub text: Option<String>,
+    pub since_ms: Option<u64>,
+    pub until_ms: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl AnnotationQuery {
+    fn matches(&self, annotation: &Annotation, text: Option<&str>) -> bool {
+        self.target.as_ref().is_none_or(|t| *t == annotation.target)
+            && self
+                .kind
+                .as_deref()
+                .is_none_or(|k| k == annotation.target.kind())
+            && self
+                .author
+                .as_deref()
+                .is_none_or(|a| a == annotation.author)
+            && text.is_none_or(|t| annotation.note.to_lowercase().contains(t))
+            && self
+                .since_ms
+                .is_none_or(|since| annotation.created_unix_ms >= since)
+            && self
+                .until_ms
+                .is_none_or(|until| annotation.created_unix_ms < until)
+    }
+}
+
+/// The notes of every entity, persisted in the StateStore.
+pub struct Annotations {
+    store: Arc<dyn StateStore>,
+    limits: AnnotationLimits,
+    /// Serializes the read-modify-write of an entity's notes.
+    lock: Mutex<()>,
+}
+
+impl Annotations {
+    pub fn new(store: Arc<dyn StateStore>, limits: AnnotationLimits) -> Self {
+        Self {
+            store,
+            limits,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Attaches `note` by `author` to `target`.
+    pub fn add(
+        &self,
+        target: AnnotationTarget,
+        author: &str,
+        note: &str,
+        now_ms: u64,
+    ) -> Result<Annotation, PhalaAvsError> {
+        let note = note.trim();
+        if note.is_empty() {
+            return Err(PhalaAvsError::ValidationError(
+                "An annotation needs a note".to_string(),
+            ));
+        }
+        if note.len() > self.limits.max_note_bytes {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Note is {} bytes, over the {} byte limit",
+                note.len(),
+                self.limits.max_note_bytes
+            )));
+        }
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut notes = self.for_target(&target)?;
+        if notes.len() >= self.limits.max_per_entity {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "{target} already has {} notes, the most allowed",
+                notes.len()
+            )));
+        }
+        let annotation = Annotation {
+            target,
+            author: author.to_string(),
+            note: note.to_string(),
+            created_unix_ms: now_ms,
+        };
+        notes.push(annotation.clone());
+        self.store
+            .put_json(ANNOTATIONS_NAMESPACE, &annotation.target.key(), &notes)?;
+        Ok(annotation)
+    }
+
+    /// The notes of `target`, oldest first.
+    pub fn for_target(&self, target: &AnnotationTarget) -> Result<Vec<Annotation>, PhalaAvsError> {
+        Ok(self
+            .store
+            .get_json(ANNOTATIONS_NAMESPACE, &target.key())?
+            .unwrap_or_default())
+    }
+
+    /// The notes of each of `targets`, in that order.
+    pub fn for_targets(
+        &self,
+        targets: impl IntoIterator<Item = AnnotationTarget>,
+    ) -> Result<Vec<Annotation>, PhalaAvsError> {
+        let mut notes = Vec::new();
+        for target in targets {
+            notes.extend(self.for_target(&target)?);
+        }
+        Ok(notes)
+    }
+
+    /// The notes matching `query`, newest first.
+    pub fn search(&self, query: &AnnotationQuery) -> Result<Vec<Annotation>, PhalaAvsError> {
+        let text = query.text.as_deref().map(str::to_lowercase);
+        let mut found = Vec::new();
+        for (_, raw) in self.store.scan(ANNOTATIONS_NAMESPACE)? {
+            let notes: Vec<Annotation> = serde_json::from_slice(&raw)
+                .map_err(|e| PhalaAvsError::StorageError(format!("Corrupt annotations: {e}")))?;
+            found.extend(
+                notes
+                    .into_iter()
+                    .filter(|n| query.matches(n, text.as_deref())),
+            );
+        }
+        found.sort_by(|a, b| b.created_unix_ms.cmp(&a.created_unix_ms));
+        found.truncate(query.limit.unwrap_or(100).min(MAX_SEARCH_RESULTS));
+        Ok(found)
+    }
+}
+
+/// Adds a note to an entity on a running operator.
+pub async fn request_annotation(
+    operator_url: &str,
+    token: &str,
+    target: &AnnotationTarget,
+    note: &str,
+) -> Result<Annotation, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/admin/annotations/{}/{}",
+            operator_url.trim_end_matches('/'),
+            target.kind(),
+            target.id()
+        ))
+        .bearer_auth(token)
+        .json(&serde_json::json!({ "note": note }))
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(PhalaAvsError::Other(format!(
+            "Operator returned {status} for the annotation: {body}"
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to read annotation: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    fn annotations(limits: AnnotationLimits) -> Annotations {
+        Annotations::new(Arc::new(MemoryStateStore::default()), limits)
+    }
+
+    #[test]
+    fn notes_attach_to_each_entity_type() {
+        let notes = annotations(AnnotationLimits::default());
+        let challenge: AnnotationTarget = "challenge:0x2a".parse().unwrap();
+        assert_eq!(challenge, AnnotationTarget::Challenge(U256::from(42)));
+        let epoch = AnnotationTarget::parse("epoch", "17").unwrap();
+        let incident = AnnotationTarget::parse("incident", "3f2a9c01b7de").unwrap();
+
+        notes
+            .add(challenge.clone(), "ops", "RPC outage, ticket #123", 1_000)
+            .unwrap();
+        notes
+            .add(challenge.clone(), "alice", "  resolved upstream ", 2_000)
+            .unwrap();
+        notes
+            .add(epoch.clone(), "ops", "node upgrade", 3_000)
+            .unwrap();
+        notes
+            .add(incident.clone(), "ops", "false positive", 4_000)
+            .unwrap();
+
+        let on_challenge = notes.for_target(&challenge).unwrap();
+        assert_eq!(on_challenge.len(), 2);
+        assert_eq!(on_challenge[0].author, "ops");
+        assert_eq!(on_challenge[1].note, "resolved upstream");
+        assert_eq!(on_challenge[1].created_unix_ms, 2_000);
+        assert_eq!(notes.for_target(&epoch).unwrap()[0].note, "node upgrade");
+        assert_eq!(notes.for_target(&incident).unwrap()[0].target, incident);
+        assert!(
+            notes
+                .for_target(&AnnotationTarget::Epoch(18))
+                .unwrap()
+                .is_empty()
+        );
+
+        for (kind, id) in [
+            ("challenge", "forty-two"),
+            ("epoch", "-1"),
+            ("incident", ""),
+            ("incident", "../../etc"),
+            ("workload", "7"),
+        ] {
+            let err = AnnotationTarget::parse(kind, id).unwrap_err();
+            assert!(
+                matches!(err, PhalaAvsError::ValidationError(_)),
+                "{kind}:{id}"
+            );
+        }
+    }
+
+    #[test]
+    fn note_size_and_count_are_limited_per_entity() {
+        let notes = annotations(AnnotationLimits {
+            max_note_bytes: 16,
+            max_per_entity: 2,
+        });
+        let target = AnnotationTarget::Epoch(5);
+        let err = notes
+            .add(target.clone(), "ops", "a note well over sixteen bytes", 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("16 byte limit"), "{err}");
+        assert!(notes.add(target.clone(), "ops", "   ", 1).is_err());
+
+        notes.add(target.clone(), "ops", "first", 1).unwrap();
+        notes.add(target.clone(), "ops", "second", 2).unwrap();
+        let err = notes.add(target.clone(), "ops", "third", 3).unwrap_err();
+        assert!(matches!(err, PhalaAvsError::ValidationError(_)));
+        assert_eq!(notes.for_target(&target).unwrap().len(), 2);
+        // The limit is per entity.
+        notes
+            .add(AnnotationTarget::Epoch(6), "ops", "third", 3)
+            .unwrap();
+    }
+
+    #[test]
+    fn search_filters_and_orders_newest_first() {
+        let notes = annotations(AnnotationLimits::default());
+        let challenge = AnnotationTarget::Challenge(U256::from(7));
+        notes
+            .add(challenge.clone(), "ops", "RPC outage, ticket #123", 1_000)
+            .unwrap();
+        notes
+            .add(
+                AnnotationTarget::Epoch(3),
+                "alice",
+                "rpc provider switched",
+                2_000,
+            )
+            .unwrap();
+        notes
+            .add(
+                AnnotationTarget::Incident("abc".into()),
+                "ops",
+                "paged",
+                3_000,
+            )
+            .unwrap();
+
+        let all = notes.search(&AnnotationQuery::default()).unwrap();
+        let times: Vec<u64> = all.iter().map(|n| n.created_unix_ms).collect();
+        assert_eq!(times, [3_000, 2_000, 1_000]);
+
+        let rpc = AnnotationQuery {
+            text: Some("RPC".into()),
+            ..Default::default()
+        };
+        assert_eq!(notes.search(&rpc).unwrap().len(), 2);
+        let by_ops = AnnotationQuery {
+            author: Some("ops".into()),
+            since_ms: Some(1_500),
+            ..Default::default()
+        };
+        assert_eq!(notes.search(&by_ops).unwrap()[0].note, "paged");
+        let epochs = AnnotationQuery {
+            kind: Some("epoch".into()),
+            ..Default::default()
+        };
+        assert_eq!(notes.search(&epochs).unwrap().len(), 1);
+        let query: AnnotationQuery =
+            serde_json::from_value(serde_json::json!({ "target": "challenge:7", "limit": 1 }))
+                .unwrap();
+        assert_eq!(notes.search(&query).unwrap()[0].target, challenge);
+    }
+}
@@ -0,0 +1,540 @@
+//! API keys with scopes for the status and admin HTTP server.
+//!
+//! Keys are defined in `API_KEYS_FILE`, a JSON list of `{"id", "sha256", "scopes",
+//! "rate_limit_per_min"}` where `sha256` is the hex SHA-256 of the key, so the file holds no
+//! usable secret. The file is re-read when it changes (checked every `API_KEYS_RELOAD_SECS`) and
+//! on `POST /admin/api-keys/reload`, so keys rotate without a restart.
+//!
+//! Without a keys file the server behaves as before: status endpoints are open and admin
+//! endpoints take `ADMIN_TOKEN`. Once keys are configured every endpoint needs a key with the
+//! endpoint's [`Scope`], except `/healthz`, which is always open, and `/readyz` and `/metrics`
+//! when `API_PUBLIC_PROBES` is set.
+//! `ADMIN_TOKEN` keeps every scope and `ARTIFACTS_TOKEN` keeps artifact retrieval.
+//!
+//! Each key is limited to `rate_limit_per_min` requests (`API_KEY_RATE_LIMIT_PER_MIN` by
+//! default), and every decision is appended to an audit log, persisted once the state store is
+//! up and included in diagnostics bundles.
+
+use crate::approvals::PendingAction;
+use crate::config::{env_flag, env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::B256;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+const AUDIT_NAMESPACE: &str = "api_audit";
+/// Persisted audit entries are pruned to the limit once per this many entries.
+const PRUNE_EVERY: u64 = 100;
+
+const ADMIN_TOKEN_ID: &str = "admin-token";
+const ARTIFACTS_TOKEN_ID: &str = "artifacts-token";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// `/status`, `/readyz`, `/metrics` and the other read-only views.
+    ReadStatus,
+    /// Diagnostics bundles and artifact retrieval.
+    Export,
+    /// Acknowledging upgrade and other alerts.
+    AckAlerts,
+    /// The aggregator's admin operations.
+    AdminAggregator,
+    /// Runtime configuration: memory budgets, chaos policies, key reloads.
+    AdminConfig,
+    /// Operations changing persisted state, such as maintenance windows.
+    AdminState,
+}
+
+const ALL_SCOPES: [Scope; 6] = [
+    Scope::ReadStatus,
+    Scope::Export,
+    Scope::AckAlerts,
+    Scope::AdminAggregator,
+    Scope::AdminConfig,
+    Scope::AdminState,
+];
+
+/// What an endpoint requires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Liveness probes, public with `API_PUBLIC_PROBES`.
+    Probe,
+    /// Read-only views, public until keys are configured.
+    Read,
+    Require(Scope),
+}
+
+/// A key as defined in `API_KEYS_FILE`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKeyDefinition {
+    pub id: String,
+    /// SHA-256 of the key.
+    pub sha256: B256,
+    pub scopes: BTreeSet<Scope>,
+    #[serde(default)]
+    pub rate_limit_per_min: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Allowed,
+    /// No key, or one that is not (or no longer) defined.
+    Unauthenticated,
+    /// The key lacks the endpoint's scope, or the endpoint is disabled.
+    Forbidden,
+    RateLimited,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub key_id: Option<String>,
+    pub endpoint: String,
+    pub outcome: AuditOutcome,
+    pub unix_ms: u64,
+    /// For an action run once enough admins approved it: the action and its approvals.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approved_action: Option<PendingAction>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ApiAuthConfig {
+    pub keys_file: Option<PathBuf>,
+    pub public_probes: bool,
+    pub default_rate_limit_per_min: u32,
+    pub reload_secs: u64,
+    /// Audit entries kept in memory and in the state store.
+    pub audit_limit: usize,
+    pub admin_token: Option<String>,
+    pub artifacts_token: Option<String>,
+}
+
+impl ApiAuthConfig {
+    /// Reads `API_KEYS_FILE`, `API_PUBLIC_PROBES`, `API_KEY_RATE_LIMIT_PER_MIN`,
+    /// `API_KEYS_RELOAD_SECS`, `API_AUDIT_LIMIT`, `ADMIN_TOKEN` and `ARTIFACTS_TOKEN`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            keys_file: env_opt("API_KEYS_FILE")?,
+            public_probes: env_flag("API_PUBLIC_PROBES", false)?,
+            default_rate_limit_per_min: env_or("API_KEY_RATE_LIMIT_PER_MIN", 120)?,
+            reload_secs: env_or("API_KEYS_RELOAD_SECS", 30)?,
+            audit_limit: env_or("API_AUDIT_LIMIT", 10_000)?,
+            admin_token: env_opt("ADMIN_TOKEN")?,
+            artifacts_token: env_opt("ARTIFACTS_TOKEN")?,
+        })
+    }
+}
+
+impl Default for ApiAuthConfig {
+    fn default() -> Self {
+        Self {
+            keys_file: None,
+            public_probes: false,
+            default_rate_limit_per_min: 120,
+            reload_secs: 30,
+            audit_limit: 10_000,
+            admin_token: None,
+            artifacts_token: None,
+        }
+    }
+}
+
+/// Requests of a key in the current one-minute window.
+#[derive(Debug)]
+struct RateWindow {
+    started_ms: u64,
+    count: u32,
+}
+
+/// Authenticates requests and keeps the audit log.
+#[derive(Debug, Default)]
+pub struct ApiAuth {
+    config: ApiAuthConfig,
+    keys: RwLock<Vec<ApiKeyDefinition>>,
+    loaded_modified: Mutex<Option<SystemTime>>,
+    windows: Mutex<BTreeMap<String, RateWindow>>,
+    audit: Mutex<VecDeque<AuditEntry>>,
+    audited: AtomicU64,
+}
+
+impl ApiAuth {
+    /// Loads the keys file, if configured.
+    pub fn new(config: ApiAuthConfig) -> Result<Self, PhalaAvsError> {
+        let auth = Self {
+            config,
+            ..Default::default()
+        };
+        auth.reload()?;
+        Ok(auth)
+    }
+
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Self::new(ApiAuthConfig::from_env()?)
+    }
+
+    pub fn config(&self) -> &ApiAuthConfig {
+        &self.config
+    }
+
+    /// Whether any credential is configured; without one the admin endpoints are disabled.
+    pub fn enabled(&self) -> bool {
+        self.config.keys_file.is_some()
+            || self.config.admin_token.is_some()
+            || self.config.artifacts_token.is_some()
+    }
+
+    /// Re-reads the keys file, returning how many keys it defines. Keys no longer defined are
+    /// rejected from the next request on.
+    pub fn reload(&self) -> Result<usize, PhalaAvsError> {
+        let Some(path) = &self.config.keys_file else {
+            return Ok(0);
+        };
+        let storage_err = |e: &dyn std::fmt::Display| {
+            PhalaAvsError::StorageError(format!("Failed to load {}: {e}", path.display()))
+        };
+        let modified = std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .map_err(|e| storage_err(&e))?;
+        let raw = std::fs::read(path).map_err(|e| storage_err(&e))?;
+        let keys: Vec<ApiKeyDefinition> = serde_json::from_slice(&raw).map_err(|e| {
+            PhalaAvsError::ConfigError(format!("Invalid API keys in {}: {e}", path.display()))
+        })?;
+        let ids: BTreeSet<_> = keys.iter().map(|k| k.id.clone()).collect();
+        if ids.len() != keys.len() {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "Duplicate API key ids in {}",
+                path.display()
+            )));
+        }
+        let count = keys.len();
+        *self.keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+        *self
+            .loaded_modified
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(modified);
+        self.windows
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|id, _| ids.contains(id) || id == ADMIN_TOKEN_ID || id == ARTIFACTS_TOKEN_ID);
+        info!("Loaded {count} API keys from {}", path.display());
+        Ok(count)
+    }
+
+    /// Reloads the keys file if it changed since it was last loaded.
+    pub fn reload_if_changed(&self) -> Result<bool, PhalaAvsError> {
+        let Some(path) = &self.config.keys_file else {
+            return Ok(false);
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let loaded = *self
+            .loaded_modified
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if modified.is_none() || modified == loaded {
+            return Ok(false);
+        }
+        self.reload().map(|_| true)
+    }
+
+    /// Decides whether `token` may call `endpoint` (its route path), returning the key id used,
+    /// if any.
+    pub fn authorize(
+        &self,
+        token: Option<&str>,
+        endpoint: &str,
+        access: Access,
+        now_ms: u64,
+    ) -> Result<Option<String>, AuditOutcome> {
+        let keyed = self.config.keys_file.is_some();
+        let scope = match access {
+            Access::Probe if self.config.public_probes || !keyed => return Ok(None),
+            Access::Read if !keyed => return Ok(None),
+            Access::Probe | Access::Read => Scope::ReadStatus,
+            Access::Require(scope) => scope,
+        };
+        let token = token.ok_or(AuditOutcome::Unauthenticated)?;
+        let (id, scopes, rate_limit) = self
+            .identify(token, endpoint)
+            .ok_or(AuditOutcome::Unauthenticated)?;
+        if !scopes.contains(&scope) {
+            return Err(AuditOutcome::Forbidden);
+        }
+
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        let window = windows.entry(id.clone()).or_insert(RateWindow {
+            started_ms: now_ms,
+            count: 0,
+        });
+        if now_ms.saturating_sub(window.started_ms) >= 60_000 {
+            *window = RateWindow {
+                started_ms: now_ms,
+                count: 0,
+            };
+        }
+        if window.count >= rate_limit {
+            return Err(AuditOutcome::RateLimited);
+        }
+        window.count += 1;
+        Ok(Some(id))
+    }
+
+    fn identify(&self, token: &str, endpoint: &str) -> Option<(String, BTreeSet<Scope>, u32)> {
+        let default_limit = self.config.default_rate_limit_per_min;
+        if matches(token, &self.config.admin_token) {
+            return Some((ADMIN_TOKEN_ID.to_string(), ALL_SCOPES.into(), default_limit));
+        }
+        // The verifiers' token predates scopes and stays limited to artifact retrieval.
+        if endpoint.starts_with("/artifacts/") && matches(token, &self.config.artifacts_token) {
+            return Some((
+                ARTIFACTS_TOKEN_ID.to_string(),
+                [Scope::Export].into(),
+                default_limit,
+            ));
+        }
+        let hash = B256::from_slice(&Sha256::digest(token.as_bytes()));
+        self.keys
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|k| constant_time_eq(k.sha256.as_slice(), hash.as_slice()))
+            .map(|k| {
+                (
+                    k.id.clone(),
+                    k.scopes.clone(),
+                    k.rate_limit_per_min.unwrap_or(default_limit),
+                )
+            })
+    }
+
+    /// Appends `entry` to the audit log, persisting it to `store` when one is available.
+    pub fn record(&self, entry: AuditEntry, store: Option<&dyn StateStore>) {
+        let seq = self.audited.fetch_add(1, Ordering::SeqCst);
+        if let Some(store) = store {
+            let mut key = entry.unix_ms.to_be_bytes().to_vec();
+            key.extend_from_slice(&seq.to_be_bytes());
+            if let Err(e) = store.put_json(AUDIT_NAMESPACE, &key, &entry) {
+                warn!("Failed to persist audit entry: {e}");
+            }
+            if seq % PRUNE_EVERY == PRUNE_EVERY - 1 {
+                self.prune(store)
+                    .unwrap_or_else(|e| warn!("Failed to prune the audit log: {e}"));
+            }
+        }
+        let mut audit = self.audit.lock().unwrap_or_else(|e| e.into_inner());
+        if audit.len() >= self.config.audit_limit {
+            audit.pop_front();
+        }
+        audit.push_back(entry);
+    }
+
+    fn prune(&self, store: &dyn StateStore) -> Result<(), PhalaAvsError> {
+        let entries = store.scan(AUDIT_NAMESPACE)?;
+        let excess = entries.len().saturating_sub(self.config.audit_limit);
+        for (key, _) in entries.into_iter().take(excess) {
+            store.delete(AUDIT_NAMESPACE, &key)?;
+        }
+        Ok(())
+    }
+
+    /// The most recent audit entries, oldest first.
+    pub fn audit_log(&self) -> Vec<AuditEntry> {
+        self.audit
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+fn matches(token: &str, expected: &Option<String>) -> bool {
+    expected
+        .as_ref()
+        .is_some_and(|expected| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reloads the keys file whenever it changes, in the background.
+pub fn spawn_reload(auth: Arc<ApiAuth>) {
+    if auth.config.keys_file.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(auth.config.reload_secs));
+        loop {
+            interval.tick().await;
+            if let Err(e) = auth.reload_if_changed() {
+                warn!("Failed to reload API keys, keeping the previous set: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    fn definition(id: &str, key: &str, scopes: &[Scope], limit: Option<u32>) -> ApiKeyDefinition {
+        ApiKeyDefinition {
+            id: id.to_string(),
+            sha256: B256::from_slice(&Sha256::digest(key.as_bytes())),
+            scopes: scopes.iter().copied().collect(),
+            rate_limit_per_min: limit,
+        }
+    }
+
+    fn write_keys(path: &std::path::Path, keys: &[ApiKeyDefinition]) {
+        std::fs::write(path, serde_json::to_vec(keys).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn scopes_are_enforced_per_endpoint_class_and_rate_limited() {
+        let path = std::env::temp_dir().join(format!("phala-avs-keys-{}.json", std::process::id()));
+        write_keys(&path, &[
+            definition(
+                "oncall",
+                "oncall-key",
+                &[Scope::ReadStatus, Scope::AckAlerts],
+                None,
+            ),
+            definition("exporter", "export-key", &[Scope::Export], Some(2)),
+        ]);
+        let auth = ApiAuth::new(ApiAuthConfig {
+            keys_file: Some(path.clone()),
+            public_probes: true,
+            admin_token: Some("admin".to_string()),
+            artifacts_token: Some("verifier".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let now = 1_000;
+
+        assert_eq!(
+            auth.authorize(None, "/metrics", Access::Probe, now),
+            Ok(None)
+        );
+        assert_eq!(
+            auth.authorize(None, "/status", Access::Read, now),
+            Err(AuditOutcome::Unauthenticated)
+        );
+        let oncall = Some("oncall-key");
+        assert_eq!(
+            auth.authorize(oncall, "/status", Access::Read, now),
+            Ok(Some("oncall".to_string()))
+        );
+        let ack = Access::Require(Scope::AckAlerts);
+        assert!(
+            auth.authorize(oncall, "/admin/upgrades/ack", ack, now)
+                .is_ok()
+        );
+        let state = Access::Require(Scope::AdminState);
+        assert_eq!(
+            auth.authorize(oncall, "/admin/maintenance", state, now),
+            Err(AuditOutcome::Forbidden)
+        );
+        assert!(
+            auth.authorize(Some("admin"), "/admin/maintenance", state, now)
+                .is_ok()
+        );
+
+        // The verifier token only opens artifact retrieval.
+        let export = Access::Require(Scope::Export);
+        assert!(
+            auth.authorize(Some("verifier"), "/artifacts/{hash}", export, now)
+                .is_ok()
+        );
+        assert_eq!(
+            auth.authorize(Some("verifier"), "/admin/diagnostics", export, now),
+            Err(AuditOutcome::Unauthenticated)
+        );
+
+        let exporter = Some("export-key");
+        assert!(
+            auth.authorize(exporter, "/admin/diagnostics", export, now)
+                .is_ok()
+        );
+        assert!(
+            auth.authorize(exporter, "/admin/diagnostics", export, now)
+                .is_ok()
+        );
+        assert_eq!(
+            auth.authorize(exporter, "/admin/diagnostics", export, now + 1_000),
+            Err(AuditOutcome::RateLimited)
+        );
+        assert!(
+            auth.authorize(exporter, "/admin/diagnostics", export, now + 60_000)
+                .is_ok()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rotation_invalidates_the_old_key_and_decisions_are_audited() {
+        let path =
+            std::env::temp_dir().join(format!("phala-avs-rotate-{}.json", std::process::id()));
+        write_keys(&path, &[definition(
+            "oncall",
+            "old",
+            &[Scope::ReadStatus],
+            None,
+        )]);
+        let auth = ApiAuth::new(ApiAuthConfig {
+            keys_file: Some(path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        let store: &dyn StateStore = &MemoryStateStore::default();
+        let decide = |token: &str, now_ms: u64| {
+            let result = auth.authorize(Some(token), "/status", Access::Read, now_ms);
+            auth.record(
+                AuditEntry {
+                    key_id: result.clone().ok().flatten(),
+                    endpoint: "GET /status".to_string(),
+                    outcome: result.err().unwrap_or(AuditOutcome::Allowed),
+                    unix_ms: now_ms,
+                    approved_action: None,
+                },
+                Some(store),
+            );
+        };
+        decide("old", 1);
+
+        write_keys(&path, &[definition(
+            "oncall",
+            "new",
+            &[Scope::ReadStatus],
+            None,
+        )]);
+        assert_eq!(auth.reload().unwrap(), 1);
+        decide("old", 2);
+        decide("new", 3);
+
+        let outcomes: Vec<_> = auth
+            .audit_log()
+            .into_iter()
+            .map(|e| (e.key_id, e.outcome, e.unix_ms))
+            .collect();
+        assert_eq!(outcomes, vec![
+            (Some("oncall".to_string()), AuditOutcome::Allowed, 1),
+            (None, AuditOutcome::Unauthenticated, 2),
+            (Some("oncall".to_string()), AuditOutcome::Allowed, 3),
+        ]);
+        let persisted = store.scan(AUDIT_NAMESPACE).unwrap();
+        assert_eq!(persisted.len(), 3);
+        let last: AuditEntry = serde_json::from_slice(&persisted[2].1).unwrap();
+        assert_eq!(last.endpoint, "GET /status");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,716 @@
+//! M-of-N approval of destructive admin actions.
+//!
+//! By default an admin action runs as soon as a key with the right scope calls its endpoint.
+//! Listing the admin keys allowed to approve in `ADMIN_APPROVERS` turns on multi-sig mode:
+//! destructive endpoints then record a [`PendingAction`] instead of running, and the action runs
+//! once `ADMIN_APPROVAL_THRESHOLD` of those keys approved it at `POST /admin/approvals/{id}`,
+//! each with an EIP-191 signature over the action's [digest](PendingAction::id). An action not
+//! approved within `ADMIN_APPROVAL_TTL_SECS` expires; one still short of approvals
+//! `ADMIN_APPROVAL_ALERT_SECS` before it expires raises an alert. Executed actions are recorded
+//! in the API audit log with their full approval trail.
+
+use crate::config::env_or;
+use crate::display::parse_address;
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{Address, B256, PrimitiveSignature, keccak256};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Counter of admin actions by outcome: `requested`, `approval`, `executed`, `failed`,
+/// `expired` or `rejected`.
+pub const APPROVALS_METRIC: &str = "phala_avs_admin_approvals_total";
+
+/// StateStore namespace holding every recorded action, by id.
+const APPROVALS_NAMESPACE: &str = "admin_approvals";
+
+/// Separates approval digests from anything else an admin key might sign.
+const DIGEST_DOMAIN: &str = "phala-tee-cloud-avs/admin-approval/v1";
+
+#[derive(Clone, Debug)]
+pub struct ApprovalConfig {
+    /// Admin keys allowed to approve; empty in single-admin mode.
+    pub approvers: Vec<Address>,
+    /// Approvals an action needs before it runs.
+    pub threshold: usize,
+    /// How long an action waits for approvals.
+    pub ttl: Duration,
+    /// How long before expiry an action short of approvals is alerted about.
+    pub alert_before: Duration,
+    pub check_secs: u64,
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        Self {
+            approvers: Vec::new(),
+            threshold: 1,
+            ttl: Duration::from_secs(3600),
+            alert_before: Duration::from_secs(600),
+            check_secs: 60,
+        }
+    }
+}
+
+impl ApprovalConfig {
+    /// Reads `ADMIN_APPROVERS` (comma-separated addresses), `ADMIN_APPROVAL_THRESHOLD` (default
+    /// two, or one with a single approver), `ADMIN_APPROVAL_TTL_SECS`,
+    /// `ADMIN_APPROVAL_ALERT_SECS` and `ADMIN_APPROVAL_CHECK_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        let mut approvers = Vec::new();
+        for approver in env_or("ADMIN_APPROVERS", String::new())?
+            .split(',')
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+        {
+            let approver = parse_address(approver).map_err(|e| {
+                PhalaAvsError::ConfigError(format!("Invalid ADMIN_APPROVERS entry: {e}"))
+            })?;
+            if !approvers.contains(&approver) {
+                approvers.push(approver);
+            }
+        }
+        let threshold = env_or("ADMIN_APPROVAL_THRESHOLD", approvers.len().clamp(1, 2))?;
+        if !approvers.is_empty() && !(1..=approvers.len()).contains(&threshold) {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "ADMIN_APPROVAL_THRESHOLD must be between 1 and the {} ADMIN_APPROVERS, got \
+                 {threshold}",
+                approvers.len()
+            )));
+        }
+        Ok(Self {
+            approvers,
+            threshold,
+            ttl: Duration::from_secs(env_or("ADMIN_APPROVAL_TTL_SECS", defaults.ttl.as_secs())?),
+            alert_before: Duration::from_secs(env_or(
+                "ADMIN_APPROVAL_ALERT_SECS",
+                defaults.alert_before.as_secs(),
+            )?),
+            check_secs: env_or("ADMIN_APPROVAL_CHECK_SECS", defaults.check_secs)?.max(1),
+        })
+    }
+
+    /// Whether destructive actions wait for approvals.
+    pub fn enabled(&self) -> bool {
+        !self.approvers.is_empty()
+    }
+}
+
+/// A destructive admin action and its parameters.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AdminAction {
+    /// Starts a voluntary exit.
+    ForceExit,
+    /// Stops submissions to one oracle target.
+    SuspendDomain { chain_id: u64, oracle: Address },
+    /// Issues a workload a new responder token, revoking the previous one.
+    RotateResponderToken { workload_id: B256 },
+    /// Issues a workload a new evidence token.
+    RotateEvidenceToken { workload_id: B256 },
+    /// Clears a task response the pre-signing safety checks blocked for signing.
+    OverrideResponseSafety { review: B256 },
+    /// Starts moving signing to the secondary keystore signer.
+    StartKeystoreMigration,
+    /// Takes the primary keystore signer out of the signing path.
+    FinalizeKeystoreMigration,
+    /// Returns signing to the primary keystore signer.
+    AbortKeystoreMigration,
+}
+
+impl AdminAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ForceExit => "force_exit",
+            Self::SuspendDomain { .. } => "suspend_domain",
+            Self::RotateResponderToken { .. } => "rotate_responder_token",
+            Self::RotateEvidenceToken { .. } => "rotate_evidence_token",
+            Self::OverrideResponseSafety { .. } => "override_response_safety",
+            Self::StartKeystoreMigration => "start_keystore_migration",
+            Self::FinalizeKeystoreMigration => "finalize_keystore_migration",
+            Self::AbortKeystoreMigration => "abort_keystore_migration",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionStatus {
+    /// Waiting for approvals.
+    Pending,
+    /// Approved and being executed.
+    Approved,
+    Executed,
+    /// Approved, but executing it failed.
+    Failed,
+    /// Not approved in time.
+    Expired,
+}
+
+impl ActionStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Executed => "executed",
+            Self::Failed => "failed",
+            Self::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Approval {
+    pub approver: Address,
+    /// The approver's EIP-191 signature over the action's id, hex encoded.
+    pub signature: String,
+    pub unix_ms: u64,
+}
+
+/// A destructive admin action waiting for, or decided by, its approvals.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingAction {
+    /// The digest approvers sign; see [`PendingAction::digest`].
+    pub id: B256,
+    #[serde(flatten)]
+    pub action: AdminAction,
+    /// API key id of the admin who requested it.
+    pub requested_by: Option<String>,
+    pub requested_unix_ms: u64,
+    pub expires_unix_ms: u64,
+    /// Makes the digests of identical requests differ.
+    pub nonce: B256,
+    pub threshold: usize,
+    pub approvals: Vec<Approval>,
+    pub status: ActionStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decided_unix_ms: Option<u64>,
+}
+
+impl PendingAction {
+    /// The digest of everything the approvers agree to: keccak256 of the canonical JSON of the
+    /// action, who requested it, when, until when it may be approved, and the nonce, under a
+    /// fixed domain tag. Approvers sign it with `personal_sign`.
+    pub fn digest(&self) -> Result<B256, PhalaAvsError> {
+        #[derive(Serialize)]
+        struct Canonical<'a> {
+            domain: &'static str,
+            action: &'a AdminAction,
+            requested_by: &'a Option<String>,
+            requested_unix_ms: u64,
+            expires_unix_ms: u64,
+            nonce: B256,
+        }
+        let canonical = serde_json::to_vec(&Canonical {
+            domain: DIGEST_DOMAIN,
+            action: &self.action,
+            requested_by: &self.requested_by,
+            requested_unix_ms: self.requested_unix_ms,
+            expires_unix_ms: self.expires_unix_ms,
+            nonce: self.nonce,
+        })
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to encode approval digest: {e}")))?;
+        Ok(keccak256(canonical))
+    }
+
+    /// Whether the action still counts towards `/status` and expiry alerts.
+    pub fn is_pending(&self) -> bool {
+        self.status == ActionStatus::Pending
+    }
+}
+
+/// Why an approval was turned away.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ApprovalRejection {
+    NotFound,
+    Expired,
+    /// The action was already approved, executed or expired.
+    Decided(ActionStatus),
+    InvalidSignature(String),
+    /// The signature is valid, but not from an authorized admin key.
+    NotAnApprover(Address),
+    /// The action could not be read or stored.
+    Unavailable(String),
+}
+
+impl ApprovalRejection {
+    /// The `reason` label of rejections counted in [`APPROVALS_METRIC`].
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::Expired => "expired",
+            Self::Decided(_) => "decided",
+            Self::InvalidSignature(_) => "invalid_signature",
+            Self::NotAnApprover(_) => "not_an_approver",
+            Self::Unavailable(_) => "unavailable",
+        }
+    }
+}
+
+impl fmt::Display for ApprovalRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "no such admin action"),
+            Self::Expired => write!(f, "admin action expired before it was approved"),
+            Self::Decided(status) => write!(f, "admin action is already {}", status.as_str()),
+            Self::InvalidSignature(e) => write!(f, "invalid approval signature: {e}"),
+            Self::NotAnApprover(signer) => write!(f, "{signer} is not an authorized approver"),
+            Self::Unavailable(e) => write!(f, "admin approvals unavailable: {e}"),
+        }
+    }
+}
+
+impl From<PhalaAvsError> for ApprovalRejection {
+    fn from(e: PhalaAvsError) -> Self {
+        Self::Unavailable(e.to_string())
+    }
+}
+
+/// Destructive admin actions waiting for approvals, persisted in the state store.
+pub struct AdminApprovals {
+    config: ApprovalConfig,
+    state: Arc<dyn StateStore>,
+    /// Serializes read-modify-write of actions, so an action runs at most once.
+    update: Mutex<()>,
+    /// Actions already alerted about.
+    alerted: Mutex<HashSet<B256>>,
+}
+
+impl AdminApprovals {
+    pub fn new(config: ApprovalConfig, state: Arc<dyn StateStore>) -> Self {
+        Self {
+            config,
+            state,
+            update: Mutex::new(()),
+            alerted: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> &ApprovalConfig {
+        &self.config
+    }
+
+    /// Whether destructive actions wait for approvals instead of running.
+    pub fn enabled(&self) -> bool {
+        self.config.enabled()
+    }
+
+    /// Records `action` to run once approved.
+    pub fn request(
+        &self,
+        action: AdminAction,
+        requested_by: Option<String>,
+        now_ms: u64,
+    ) -> Result<PendingAction, PhalaAvsError> {
+        let mut pending = PendingAction {
+            id: B256::ZERO,
+            action,
+            requested_by,
+            requested_unix_ms: now_ms,
+            expires_unix_ms: now_ms + self.config.ttl.as_millis() as u64,
+            nonce: B256::from_slice(
+                &[
+                    *uuid::Uuid::new_v4().as_bytes(),
+                    *uuid::Uuid::new_v4().as_bytes(),
+                ]
+                .concat(),
+            ),
+            threshold: self.config.threshold,
+            approvals: Vec::new(),
+            status: ActionStatus::Pending,
+            error: None,
+            decided_unix_ms: None,
+        };
+        pending.id = pending.digest()?;
+        self.state
+            .put_json(APPROVALS_NAMESPACE, pending.id.as_slice(), &pending)?;
+        METRICS.inc_counter(
+            APPROVALS_METRIC,
+            &[
+                ("action", pending.action.as_str()),
+                ("outcome", "requested"),
+            ],
+            1,
+        );
+        info!(
+            id = %pending.id,
+            action = pending.action.as_str(),
+            requested_by = pending.requested_by.as_deref().unwrap_or("admin"),
+            "Admin action waiting for {} approvals",
+            pending.threshold
+        );
+        Ok(pending)
+    }
+
+    /// Adds the approval signed by `signature` to action `id`. Once the threshold is reached the
+    /// action is returned [`Approved`](ActionStatus::Approved), exactly once, and the caller runs
+    /// it and reports back with [`complete`](Self::complete). A key approving twice is counted
+    /// once.
+    pub fn approve(
+        &self,
+        id: B256,
+        signature: &str,
+        now_ms: u64,
+    ) -> Result<PendingAction, ApprovalRejection> {
+        let result = self.add_approval(id, signature, now_ms);
+        if let Err(rejection) = &result {
+            METRICS.inc_counter(
+                APPROVALS_METRIC,
+                &[("outcome", "rejected"), ("reason", rejection.reason())],
+                1,
+            );
+        }
+        result
+    }
+
+    fn add_approval(
+        &self,
+        id: B256,
+        signature: &str,
+        now_ms: u64,
+    ) -> Result<PendingAction, ApprovalRejection> {
+        let approver = recover_approver(id, signature)?;
+        if !self.config.approvers.contains(&approver) {
+            warn!(%id, %approver, "Rejected an admin approval from an unauthorized key");
+            return Err(ApprovalRejection::NotAnApprover(approver));
+        }
+        let _update = self.update.lock().unwrap_or_else(|e| e.into_inner());
+        let mut pending = self.get(id)?.ok_or(ApprovalRejection::NotFound)?;
+        if self.expire(&mut pending, now_ms)? {
+            return Err(ApprovalRejection::Expired);
+        }
+        if !pending.is_pending() {
+            return Err(ApprovalRejection::Decided(pending.status));
+        }
+        if pending.approvals.iter().any(|a| a.approver == approver) {
+            return Ok(pending);
+        }
+        pending.approvals.push(Approval {
+            approver,
+            signature: signature.to_string(),
+            unix_ms: now_ms,
+        });
+        if pending.approvals.len() >= pending.threshold {
+            pending.status = ActionStatus::Approved;
+        }
+        self.state
+            .put_json(APPROVALS_NAMESPACE, id.as_slice(), &pending)?;
+        METRICS.inc_counter(
+            APPROVALS_METRIC,
+            &[("action", pending.action.as_str()), ("outcome", "approval")],
+            1,
+        );
+        info!(
+            %id,
+            %approver,
+            "Admin action {} has {}/{} approvals",
+            pending.action.as_str(),
+            pending.approvals.len(),
+            pending.threshold
+        );
+        Ok(pending)
+    }
+
+    /// Records the result of running approved action `id`.
+    pub fn complete(
+        &self,
+        id: B256,
+        result: Result<(), String>,
+        now_ms: u64,
+    ) -> Result<PendingAction, PhalaAvsError> {
+        let _update = self.update.lock().unwrap_or_else(|e| e.into_inner());
+        let mut pending = self
+            .get(id)?
+            .ok_or_else(|| PhalaAvsError::ValidationError(format!("No admin action {id}")))?;
+        if pending.status != ActionStatus::Approved {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Admin action {id} is {}, not approved",
+                pending.status.as_str()
+            )));
+        }
+        (pending.status, pending.error) = match result {
+            Ok(()) => (ActionStatus::Executed, None),
+            Err(e) => (ActionStatus::Failed, Some(e)),
+        };
+        pending.decided_unix_ms = Some(now_ms);
+        self.state
+            .put_json(APPROVALS_NAMESPACE, id.as_slice(), &pending)?;
+        METRICS.inc_counter(
+            APPROVALS_METRIC,
+            &[
+                ("action", pending.action.as_str()),
+                ("outcome", pending.status.as_str()),
+            ],
+            1,
+        );
+        Ok(pending)
+    }
+
+    pub fn get(&self, id: B256) -> Result<Option<PendingAction>, PhalaAvsError> {
+        self.state.get_json(APPROVALS_NAMESPACE, id.as_slice())
+    }
+
+    /// Every recorded action, oldest first, marking those past their expiry as expired.
+    pub fn actions(&self, now_ms: u64) -> Result<Vec<PendingAction>, PhalaAvsError> {
+        let _update = self.update.lock().unwrap_or_else(|e| e.into_inner());
+        let mut actions = Vec::new();
+        for (_, value) in self.state.scan(APPROVALS_NAMESPACE)? {
+            let mut action: PendingAction = serde_json::from_slice(&value).map_err(|e| {
+                PhalaAvsError::StorageError(format!("Corrupt admin action record: {e}"))
+            })?;
+            self.expire(&mut action, now_ms)?;
+            actions.push(action);
+        }
+        actions.sort_by_key(|a| a.requested_unix_ms);
+        Ok(actions)
+    }
+
+    /// Actions still waiting for approvals, for `/status`.
+    pub fn pending(&self, now_ms: u64) -> Result<Vec<PendingAction>, PhalaAvsError> {
+        Ok(self
+            .actions(now_ms)?
+            .into_iter()
+            .filter(PendingAction::is_pending)
+            .collect())
+    }
+
+    /// Alerts, once each, about pending actions expiring within the alert window without
+    /// enough approvals.
+    pub fn expiring(&self, now_ms: u64) -> Result<Vec<Alert>, PhalaAvsError> {
+        let alert_before = self.config.alert_before.as_millis() as u64;
+        let mut alerted = self.alerted.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(self
+            .pending(now_ms)?
+            .into_iter()
+            .filter(|a| now_ms + alert_before >= a.expires_unix_ms)
+            .filter(|a| alerted.insert(a.id))
+            .map(|a| {
+                Alert::new(
+                    "approvals",
+                    Severity::Warning,
+                    format!(
+                        "Admin action {} ({}) requested by {} expires in {}s with {}/{} \
+                         approvals",
+                        a.action.as_str(),
+                        a.id,
+                        a.requested_by.as_deref().unwrap_or("admin"),
+                        a.expires_unix_ms.saturating_sub(now_ms) / 1000,
+                        a.approvals.len(),
+                        a.threshold
+                    ),
+                )
+            })
+            .collect())
+    }
+
+    /// Marks a pending action past its expiry as expired, returning whether it did.
+    fn expire(&self, action: &mut PendingAction, now_ms: u64) -> Result<bool, PhalaAvsError> {
+        if !action.is_pending() || now_ms < action.expires_unix_ms {
+            return Ok(false);
+        }
+        action.status = ActionStatus::Expired;
+        action.decided_unix_ms = Some(now_ms);
+        self.state
+            .put_json(APPROVALS_NAMESPACE, action.id.as_slice(), &*action)?;
+        METRICS.inc_counter(
+            APPROVALS_METRIC,
+            &[("action", action.action.as_str()), ("outcome", "expired")],
+            1,
+        );
+        warn!(
+            id = %action.id,
+            "Admin action {} expired with {}/{} approvals",
+            action.action.as_str(),
+            action.approvals.len(),
+            action.threshold
+        );
+        Ok(true)
+    }
+}
+
+/// The address whose EIP-191 signature over `id` is `signature`.
+fn recover_approver(id: B256, signature: &str) -> Result<Address, ApprovalRejection> {
+    let invalid = |e: &dyn fmt::Display| ApprovalRejection::InvalidSignature(e.to_string());
+    let raw = hex::decode(signature.trim_start_matches("0x")).map_err(|e| invalid(&e))?;
+    PrimitiveSignature::try_from(raw.as_slice())
+        .map_err(|e| invalid(&e))?
+        .recover_address_from_msg(id)
+        .map_err(|e| invalid(&e))
+}
+
+/// Periodically alerts about actions about to expire without enough approvals.
+pub fn spawn_expiry_alerts(approvals: Arc<AdminApprovals>, notifier: Arc<dyn Notifier>) {
+    if !approvals.enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(approvals.config.check_secs));
+        loop {
+            interval.tick().await;
+            match approvals.expiring(crate::evidence::now_unix_ms()) {
+                Ok(alerts) => {
+                    for alert in alerts {
+                        if let Err(e) = notifier.notify(alert).await {
+                            warn!("Failed to deliver admin approval alert: {e}");
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to check pending admin actions: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+    use blueprint_sdk::alloy::signers::SignerSync;
+    use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+
+    const HOUR_MS: u64 = 3_600_000;
+
+    fn approvals(admins: &[PrivateKeySigner], threshold: usize) -> AdminApprovals {
+        let config = ApprovalConfig {
+            approvers: admins.iter().map(|a| a.address()).collect(),
+            threshold,
+            ..ApprovalConfig::default()
+        };
+        AdminApprovals::new(config, Arc::new(MemoryStateStore::default()))
+    }
+
+    fn sign(admin: &PrivateKeySigner, id: B256) -> String {
+        hex::encode(admin.sign_message_sync(id.as_slice()).unwrap().as_bytes())
+    }
+
+    fn admins(n: usize) -> Vec<PrivateKeySigner> {
+        (0..n).map(|_| PrivateKeySigner::random()).collect()
+    }
+
+    #[test]
+    fn an_action_runs_once_m_of_n_admins_approved_it() {
+        let admins = admins(3);
+        let approvals = approvals(&admins, 2);
+        let action = AdminAction::SuspendDomain {
+            chain_id: 1,
+            oracle: Address::repeat_byte(0x0c),
+        };
+        let pending = approvals
+            .request(action.clone(), Some("oncall".to_string()), 0)
+            .unwrap();
+        assert_eq!(pending.id, pending.digest().unwrap());
+        assert_eq!(approvals.pending(1).unwrap(), vec![pending.clone()]);
+
+        let first = approvals.approve(pending.id, &sign(&admins[0], pending.id), 10);
+        assert_eq!(first.unwrap().status, ActionStatus::Pending);
+        let second = approvals
+            .approve(pending.id, &sign(&admins[2], pending.id), 20)
+            .unwrap();
+        assert_eq!(second.status, ActionStatus::Approved);
+        assert_eq!(second.action, action);
+        // Approved actions run once: a late approval does not hand it out again.
+        assert_eq!(
+            approvals.approve(pending.id, &sign(&admins[1], pending.id), 30),
+            Err(ApprovalRejection::Decided(ActionStatus::Approved))
+        );
+        assert!(approvals.pending(40).unwrap().is_empty());
+
+        let executed = approvals.complete(pending.id, Ok(()), 50).unwrap();
+        assert_eq!(executed.status, ActionStatus::Executed);
+        assert_eq!(executed.decided_unix_ms, Some(50));
+        let trail: Vec<_> = executed.approvals.iter().map(|a| a.approver).collect();
+        assert_eq!(trail, vec![admins[0].address(), admins[2].address()]);
+        assert_eq!(approvals.get(pending.id).unwrap(), Some(executed));
+    }
+
+    #[test]
+    fn an_action_not_approved_in_time_expires_after_an_alert() {
+        let admins = admins(2);
+        let approvals = approvals(&admins, 2);
+        let pending = approvals.request(AdminAction::ForceExit, None, 0).unwrap();
+        approvals
+            .approve(pending.id, &sign(&admins[0], pending.id), 10)
+            .unwrap();
+
+        assert!(approvals.expiring(HOUR_MS / 2).unwrap().is_empty());
+        let alerts = approvals.expiring(HOUR_MS - 60_000).unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].message.contains("1/2 approvals"));
+        assert!(approvals.expiring(HOUR_MS - 30_000).unwrap().is_empty());
+
+        assert_eq!(
+            approvals.approve(pending.id, &sign(&admins[1], pending.id), HOUR_MS),
+            Err(ApprovalRejection::Expired)
+        );
+        assert!(approvals.pending(HOUR_MS).unwrap().is_empty());
+        let expired = approvals.get(pending.id).unwrap().unwrap();
+        assert_eq!(expired.status, ActionStatus::Expired);
+        assert!(approvals.complete(pending.id, Ok(()), HOUR_MS).is_err());
+    }
+
+    #[test]
+    fn a_repeated_approval_from_one_key_counts_once() {
+        let admins = admins(3);
+        let approvals = approvals(&admins, 2);
+        let workload_id = B256::repeat_byte(1);
+        let pending = approvals
+            .request(AdminAction::RotateEvidenceToken { workload_id }, None, 0)
+            .unwrap();
+        let signature = sign(&admins[1], pending.id);
+        for at in [10, 20] {
+            let action = approvals.approve(pending.id, &signature, at).unwrap();
+            assert_eq!(action.status, ActionStatus::Pending);
+            assert_eq!(action.approvals.len(), 1);
+        }
+        let approved = approvals
+            .approve(pending.id, &sign(&admins[0], pending.id), 30)
+            .unwrap();
+        assert_eq!(approved.status, ActionStatus::Approved);
+        assert_eq!(approved.approvals.len(), 2);
+    }
+
+    #[test]
+    fn signatures_from_other_keys_or_over_other_actions_are_rejected() {
+        let admins = admins(2);
+        let approvals = approvals(&admins, 1);
+        let pending = approvals.request(AdminAction::ForceExit, None, 0).unwrap();
+
+        let outsider = PrivateKeySigner::random();
+        assert_eq!(
+            approvals.approve(pending.id, &sign(&outsider, pending.id), 10),
+            Err(ApprovalRejection::NotAnApprover(outsider.address()))
+        );
+        // An admin's signature over another digest recovers to some other address.
+        let other = sign(&admins[0], B256::repeat_byte(2));
+        assert!(matches!(
+            approvals.approve(pending.id, &other, 10),
+            Err(ApprovalRejection::NotAnApprover(_))
+        ));
+        assert!(matches!(
+            approvals.approve(pending.id, "0xnot-a-signature", 10),
+            Err(ApprovalRejection::InvalidSignature(_))
+        ));
+        assert!(
+            approvals
+                .get(pending.id)
+                .unwrap()
+                .unwrap()
+                .approvals
+                .is_empty()
+        );
+
+        let approved = approvals
+            .approve(pending.id, &sign(&admins[1], pending.id), 20)
+            .unwrap();
+        assert_eq!(approved.status, ActionStatus::Approved);
+    }
+}
@@ -0,0 +1,333 @@
+//! Full proof artifacts behind compact on-chain responses, for the oracle's verifiers.
+//!
+//! When a response is built, its artifacts (raw metrics, the quote, intermediate hashes) are
+//! archived under `keccak256` of the on-chain payload, which the contract stores, and served
+//! from `/artifacts/<payload-hash>` on the status server. A bundle carries the hash of every
+//! artifact and a bundle hash over them, so a verifier can check what it fetched.
+//!
+//! Artifacts are kept until their challenge's dispute window has closed, that is
+//! `ARTIFACT_DISPUTE_WINDOW_BLOCKS` past its deadline, and for at least
+//! `ARTIFACT_RETENTION_EPOCHS` epochs of `ARTIFACT_EPOCH_BLOCKS` after they were archived.
+
+use crate::config::{env_opt, env_or};
+use crate::disk::ReleaseSpace;
+use crate::error::PhalaAvsError;
+use crate::evm::EvmClient;
+use crate::metrics::METRICS;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{B256, Bytes, keccak256};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+const NAMESPACE: &str = "artifacts";
+
+/// Counter of archived bundles.
+pub const ARTIFACTS_ARCHIVED_METRIC: &str = "phala_avs_artifacts_archived_total";
+/// Counter of bundles pruned after their retention.
+pub const ARTIFACTS_PRUNED_METRIC: &str = "phala_avs_artifacts_pruned_total";
+
+#[derive(Clone, Debug)]
+pub struct ArtifactConfig {
+    /// Epochs a bundle is kept after it is archived; zero keeps it only for the dispute window.
+    pub retention_epochs: u64,
+    pub epoch_blocks: u64,
+    /// Blocks past a challenge's deadline during which its response can be disputed.
+    pub dispute_window_blocks: u64,
+    pub prune_secs: u64,
+    /// Base URL verifiers reach the status server at, used for retrieval links.
+    pub public_url: Option<String>,
+}
+
+impl Default for ArtifactConfig {
+    fn default() -> Self {
+        Self {
+            retention_epochs: 7,
+            epoch_blocks: 7_200,
+            dispute_window_blocks: 7_200,
+            prune_secs: 3_600,
+            public_url: None,
+        }
+    }
+}
+
+impl ArtifactConfig {
+    /// Reads `ARTIFACT_RETENTION_EPOCHS`, `ARTIFACT_EPOCH_BLOCKS`,
+    /// `ARTIFACT_DISPUTE_WINDOW_BLOCKS`, `ARTIFACT_PRUNE_SECS` and `ARTIFACT_PUBLIC_URL`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let default = Self::default();
+        Ok(Self {
+            retention_epochs: env_or("ARTIFACT_RETENTION_EPOCHS", default.retention_epochs)?,
+            epoch_blocks: env_or("ARTIFACT_EPOCH_BLOCKS", default.epoch_blocks)?,
+            dispute_window_blocks: env_or(
+                "ARTIFACT_DISPUTE_WINDOW_BLOCKS",
+                default.dispute_window_blocks,
+            )?,
+            prune_secs: env_or("ARTIFACT_PRUNE_SECS", default.prune_secs)?,
+            public_url: env_opt("ARTIFACT_PUBLIC_URL")?,
+        })
+    }
+
+    /// The first block at which a bundle may be pruned.
+    pub fn expiry_block(&self, archived_block: u64, deadline_block: u64) -> u64 {
+        let retained = archived_block.saturating_add(self.retention_epochs * self.epoch_blocks);
+        let disputable = deadline_block.saturating_add(self.dispute_window_blocks) + 1;
+        retained.max(disputable)
+    }
+}
+
+/// One named artifact and its `keccak256`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Artifact {
+    pub name: String,
+    pub data: Bytes,
+    pub hash: B256,
+}
+
+impl Artifact {
+    pub fn new(name: impl Into<String>, data: impl Into<Bytes>) -> Self {
+        let data = data.into();
+        Self {
+            name: name.into(),
+            hash: keccak256(&data),
+            data,
+        }
+    }
+}
+
+/// Everything archived for one response.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArtifactBundle {
+    /// `keccak256(payload)`, as stored by the contract.
+    pub payload_hash: B256,
+    /// `keccak256(payload_hash ‖ keccak256(name) ‖ hash ‖ ...)` over the artifacts in order.
+    pub bundle_hash: B256,
+    pub challenge_id: String,
+    pub deadline_block: u64,
+    pub archived_block: u64,
+    pub archived_unix_ms: u64,
+    pub payload: Bytes,
+    pub artifacts: Vec<Artifact>,
+}
+
+impl ArtifactBundle {
+    fn compute_hash(payload_hash: B256, artifacts: &[Artifact]) -> B256 {
+        let mut preimage = payload_hash.to_vec();
+        for artifact in artifacts {
+            preimage.extend_from_slice(keccak256(artifact.name.as_bytes()).as_slice());
+            preimage.extend_from_slice(artifact.hash.as_slice());
+        }
+        keccak256(preimage)
+    }
+
+    /// Whether the payload, every artifact and the bundle hash match their contents.
+    pub fn verify(&self) -> bool {
+        keccak256(&self.payload) == self.payload_hash
+            && self.artifacts.iter().all(|a| keccak256(&a.data) == a.hash)
+            && Self::compute_hash(self.payload_hash, &self.artifacts) == self.bundle_hash
+    }
+}
+
+/// Archived artifact bundles in the operator's state store.
+#[derive(Debug)]
+pub struct ArtifactArchive {
+    config: ArtifactConfig,
+    store: Arc<dyn StateStore>,
+}
+
+impl ArtifactArchive {
+    pub fn new(config: ArtifactConfig, store: Arc<dyn StateStore>) -> Self {
+        Self { config, store }
+    }
+
+    pub fn config(&self) -> &ArtifactConfig {
+        &self.config
+    }
+
+    /// Archives the artifacts behind `payload`, returning the payload hash they are served under.
+    pub fn store(
+        &self,
+        challenge_id: &str,
+        deadline_block: u64,
+        head: u64,
+        payload: Bytes,
+        artifacts: Vec<Artifact>,
+    ) -> Result<B256, PhalaAvsError> {
+        let payload_hash = keccak256(&payload);
+        let bundle = ArtifactBundle {
+            payload_hash,
+            bundle_hash: ArtifactBundle::compute_hash(payload_hash, &artifacts),
+            challenge_id: challenge_id.to_string(),
+            deadline_block,
+            archived_block: head,
+            archived_unix_ms: now_unix_ms(),
+            payload,
+            artifacts,
+        };
+        self.store
+            .put_json(NAMESPACE, payload_hash.as_slice(), &bundle)?;
+        METRICS.inc_counter(ARTIFACTS_ARCHIVED_METRIC, &[], 1);
+        Ok(payload_hash)
+    }
+
+    pub fn get(&self, payload_hash: B256) -> Result<Option<ArtifactBundle>, PhalaAvsError> {
+        self.store.get_json(NAMESPACE, payload_hash.as_slice())
+    }
+
+    /// Where verifiers retrieve the bundle for `payload_hash`, if `ARTIFACT_PUBLIC_URL` is set.
+    pub fn link(&self, payload_hash: B256) -> Option<String> {
+        let base = self.config.public_url.as_deref()?.trim_end_matches('/');
+        Some(format!("{base}/artifacts/{payload_hash}"))
+    }
+
+    /// Deletes bundles whose retention and dispute window have both passed at `head`.
+    pub fn prune(&self, head: u64) -> Result<usize, PhalaAvsError> {
+        self.prune_expired(head, |bundle| {
+            self.config
+                .expiry_block(bundle.archived_block, bundle.deadline_block)
+        })
+    }
+
+    fn prune_expired(
+        &self,
+        head: u64,
+        expiry: impl Fn(&ArtifactBundle) -> u64,
+    ) -> Result<usize, PhalaAvsError> {
+        let mut pruned = 0;
+        for (key, raw) in self.store.scan(NAMESPACE)? {
+            let bundle: ArtifactBundle = match serde_json::from_slice(&raw) {
+                Ok(bundle) => bundle,
+                Err(e) => {
+                    warn!(
+                        "Keeping undecodable artifact bundle 0x{}: {e}",
+                        hex::encode(&key)
+                    );
+                    continue;
+                }
+            };
+            if head >= expiry(&bundle) {
+                self.store.delete(NAMESPACE, &key)?;
+                pruned += 1;
+            }
+        }
+        if pruned > 0 {
+            info!("Pruned {pruned} artifact bundles at block {head}");
+            METRICS.inc_counter(ARTIFACTS_PRUNED_METRIC, &[], pruned as u64);
+        }
+        Ok(pruned)
+    }
+}
+
+/// Under disk pressure, bundles go as soon as their dispute window has closed, whatever their
+/// retention.
+impl ReleaseSpace for ArtifactArchive {
+    fn release(&self, head: u64) -> Result<usize, PhalaAvsError> {
+        self.prune_expired(head, |bundle| {
+            bundle
+                .deadline_block
+                .saturating_add(self.config.dispute_window_blocks)
+                + 1
+        })
+    }
+}
+
+/// Prunes expired bundles every `prune_secs`.
+pub fn spawn_pruning(archive: Arc<ArtifactArchive>, evm: Arc<dyn EvmClient>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(archive.config.prune_secs));
+        loop {
+            interval.tick().await;
+            let pruned = match evm.block_number().await {
+                Ok(head) => archive.prune(head).map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = pruned {
+                warn!("Failed to prune artifacts: {e}");
+            }
+        }
+    });
+}
+
+fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    fn archive_with(retention_epochs: u64) -> ArtifactArchive {
+        ArtifactArchive::new(
+            ArtifactConfig {
+                retention_epochs,
+                epoch_blocks: 100,
+                dispute_window_blocks: 50,
+                public_url: Some("https://operator.example/".to_string()),
+                ..Default::default()
+            },
+            Arc::new(MemoryStateStore::default()),
+        )
+    }
+
+    fn artifacts() -> Vec<Artifact> {
+        vec![
+            Artifact::new("metrics", br#"{"uptime":0.999}"#.to_vec()),
+            Artifact::new("quote", vec![4u8; 64]),
+        ]
+    }
+
+    #[test]
+    fn bundles_are_retrieved_by_payload_hash() {
+        let archive = archive_with(1);
+        let payload = Bytes::from_static(b"compact response");
+        let hash = archive
+            .store("7", 1_000, 900, payload.clone(), artifacts())
+            .unwrap();
+        assert_eq!(hash, keccak256(&payload));
+
+        let bundle = archive.get(hash).unwrap().unwrap();
+        assert!(bundle.verify());
+        assert_eq!(bundle.artifacts, artifacts());
+        assert_eq!(
+            archive.link(hash).unwrap(),
+            format!("https://operator.example/artifacts/{hash}")
+        );
+
+        let mut tampered = bundle;
+        tampered.artifacts[1].data = Bytes::from_static(b"forged");
+        assert!(!tampered.verify());
+        assert_eq!(archive.get(keccak256(b"unknown")).unwrap(), None);
+    }
+
+    #[test]
+    fn retention_never_cuts_into_the_dispute_window() {
+        // Kept for one 100-block epoch, or until 50 blocks past the deadline if that is later.
+        let archive = archive_with(1);
+        let short = archive
+            .store("1", 1_000, 990, Bytes::from_static(b"a"), artifacts())
+            .unwrap();
+        let long = archive
+            .store("2", 1_000, 1_000, Bytes::from_static(b"b"), artifacts())
+            .unwrap();
+
+        assert_eq!(archive.prune(1_050).unwrap(), 0);
+        assert_eq!(archive.prune(1_090).unwrap(), 1);
+        assert_eq!(archive.get(short).unwrap(), None);
+        assert!(archive.get(long).unwrap().is_some());
+        assert_eq!(archive.prune(1_100).unwrap(), 1);
+
+        // Without epoch retention, only the dispute window holds a bundle.
+        let archive = archive_with(0);
+        let hash = archive
+            .store("3", 1_000, 1_000, Bytes::from_static(b"c"), artifacts())
+            .unwrap();
+        assert_eq!(archive.prune(1_050).unwrap(), 0);
+        assert!(archive.get(hash).unwrap().is_some());
+        assert_eq!(archive.prune(1_051).unwrap(), 1);
+    }
+}
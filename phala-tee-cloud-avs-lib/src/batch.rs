@@ -0,0 +1,270 @@
+//! Per-event isolation within a job batch.
+//!
+//! A job handles a batch of events, and one event failing, or a decoder panicking on it, must
+//! not abort the rest. Each event's outcome is tallied in a [`BatchSummary`], reported once per
+//! batch. Events whose observation failed go to the [`EventRetryQueue`] and responses that failed
+//! go back on the response queue, both for the job's next invocation; only batch-level failures,
+//! such as reading the head block, fail the job.
+
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::scheduler::{FairScheduler, Scheduled};
+use blueprint_sdk::alloy::rpc::types::Log;
+use futures::FutureExt;
+use std::any::Any;
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Counter of batch events, by job, stage and outcome.
+pub const BATCH_EVENTS_METRIC: &str = "phala_avs_batch_events_total";
+
+/// Events kept for retry; the oldest are dropped beyond this.
+const RETRY_CAPACITY: usize = 1024;
+
+/// What happened to one event, short of failing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventOutcome {
+    Processed,
+    /// Not for us, already seen or deliberately not handled.
+    Skipped,
+    /// Put back for the next invocation without having failed, e.g. held during an upgrade.
+    Deferred,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub processed: usize,
+    pub skipped: usize,
+    pub deferred: usize,
+    pub failed: usize,
+}
+
+impl BatchSummary {
+    pub fn record<T>(&mut self, outcome: &Result<EventOutcome, T>) {
+        match outcome {
+            Ok(EventOutcome::Processed) => self.processed += 1,
+            Ok(EventOutcome::Skipped) => self.skipped += 1,
+            Ok(EventOutcome::Deferred) => self.deferred += 1,
+            Err(_) => self.failed += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.processed + self.skipped + self.deferred + self.failed
+    }
+
+    /// Logs the summary and counts it under `job` and `stage`.
+    pub fn report(&self, job: &str, stage: &str) {
+        if self.total() == 0 {
+            return;
+        }
+        for (outcome, count) in [
+            ("processed", self.processed),
+            ("skipped", self.skipped),
+            ("deferred", self.deferred),
+            ("failed", self.failed),
+        ] {
+            if count > 0 {
+                METRICS.inc_counter(
+                    BATCH_EVENTS_METRIC,
+                    &[("job", job), ("stage", stage), ("outcome", outcome)],
+                    count as u64,
+                );
+            }
+        }
+        let message = format!(
+            "{job} {stage}: {} processed, {} skipped, {} deferred, {} failed",
+            self.processed, self.skipped, self.deferred, self.failed
+        );
+        if self.failed > 0 {
+            warn!("{message}");
+        } else {
+            info!("{message}");
+        }
+    }
+}
+
+/// Runs `f` for one event, turning a panic into an error.
+pub fn isolate<T>(f: impl FnOnce() -> Result<T, PhalaAvsError>) -> Result<T, PhalaAvsError> {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| Err(panicked(panic)))
+}
+
+/// Awaits `fut` for one event, turning a panic into an error.
+pub async fn isolate_async<T>(
+    fut: impl Future<Output = Result<T, PhalaAvsError>>,
+) -> Result<T, PhalaAvsError> {
+    AssertUnwindSafe(fut)
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|panic| Err(panicked(panic)))
+}
+
+fn panicked(panic: Box<dyn Any + Send>) -> PhalaAvsError {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    PhalaAvsError::TaskError(format!("event handler panicked: {message}"))
+}
+
+/// Polled events whose observation failed, replayed ahead of the next batch.
+#[derive(Debug, Default)]
+pub struct EventRetryQueue {
+    logs: Mutex<Vec<Log>>,
+}
+
+impl EventRetryQueue {
+    pub fn retry(&self, logs: Vec<Log>) {
+        let mut queued = self.logs.lock().unwrap_or_else(|e| e.into_inner());
+        queued.extend(logs);
+        if queued.len() > RETRY_CAPACITY {
+            let dropped = queued.len() - RETRY_CAPACITY;
+            warn!("Event retry queue full, dropping the {dropped} oldest events");
+            queued.drain(..dropped);
+        }
+    }
+
+    /// Takes every queued event.
+    pub fn take(&self) -> Vec<Log> {
+        std::mem::take(&mut *self.logs.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.logs.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Responds to everything `queue` releases at `head`, one item at a time.
+///
+/// Items that fail or are deferred go back on the queue once the drain is done, so they are not
+/// retried within the same invocation.
+pub async fn drain_queue<T, F, Fut>(
+    queue: &Mutex<FairScheduler<T>>,
+    head: u64,
+    mut respond: F,
+) -> BatchSummary
+where
+    T: Clone,
+    F: FnMut(Scheduled<T>) -> Fut,
+    Fut: Future<Output = Result<EventOutcome, PhalaAvsError>>,
+{
+    let mut summary = BatchSummary::default();
+    let mut requeue = Vec::new();
+    loop {
+        let next = queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop(head, now_unix_ms());
+        let Some(next) = next else {
+            break;
+        };
+        let outcome = isolate_async(respond(next.clone())).await;
+        match &outcome {
+            Ok(EventOutcome::Deferred) => requeue.push(next),
+            Err(e) => {
+                warn!("Response failed, retrying on the next invocation: {e}");
+                requeue.push(next);
+            }
+            Ok(_) => {}
+        }
+        summary.record(&outcome);
+    }
+    if !requeue.is_empty() {
+        let mut queue = queue.lock().unwrap_or_else(|e| e.into_inner());
+        for next in requeue {
+            queue.push(next.target, next.deadline_block, now_unix_ms(), next.item);
+        }
+    }
+    summary
+}
+
+fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::{
+        ChallengeTracker, ConfirmationPolicy, decode_challenge, observe_events,
+    };
+    use crate::fixtures::{ChallengeEventFixture, OPERATOR, ORACLE};
+    use crate::response_window::OracleTarget;
+    use crate::scheduler::SchedulerConfig;
+    use crate::state::{MemoryStateStore, StateStore};
+    use crate::tee::{TeeHandler, TeeHandlerConfig};
+    use blueprint_sdk::alloy::primitives::U256;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn one_bad_event_does_not_abort_the_batch() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker = ChallengeTracker::new(ConfirmationPolicy::default(), store).unwrap();
+        let tee = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        let logs: Vec<_> = (1..=5)
+            .map(|id| {
+                ChallengeEventFixture::new()
+                    .id(id)
+                    .window(1_000)
+                    .build_log()
+            })
+            .collect();
+
+        // The decoder panics on the second event.
+        let observed = observe_events(&tracker, &tee, OPERATOR, 1, &logs, |log| {
+            let challenge = decode_challenge(log)?;
+            assert_ne!(challenge.challenge_id, U256::from(2), "poisoned event");
+            Some(challenge)
+        })
+        .await;
+        assert_eq!(observed.summary, BatchSummary {
+            processed: 4,
+            failed: 1,
+            ..Default::default()
+        });
+        assert_eq!(observed.undecodable, vec![(Some(1), Some(0))]);
+
+        let queue = Mutex::new(FairScheduler::new(SchedulerConfig::default()));
+        for entry in tracker.release_ready(100, |_| 0).unwrap() {
+            let deadline = entry.challenge.deadline_block;
+            queue
+                .lock()
+                .unwrap()
+                .push(OracleTarget::new(1, ORACLE), deadline, 0, entry);
+        }
+
+        // Submitting the third fails transiently.
+        let mut submitted = Vec::new();
+        let summary = drain_queue(&queue, 100, |next| {
+            let id = next.item.challenge.challenge_id;
+            let result = if id == U256::from(3) {
+                Err(PhalaAvsError::EvmError("nonce too low".into()))
+            } else {
+                submitted.push(id);
+                Ok(EventOutcome::Processed)
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(submitted, [1, 4, 5].map(U256::from));
+        assert_eq!(summary, BatchSummary {
+            processed: 3,
+            failed: 1,
+            ..Default::default()
+        });
+        let retried = queue.lock().unwrap().pop(100, 0).unwrap();
+        assert_eq!(retried.item.challenge.challenge_id, U256::from(3));
+        assert!(queue.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+//! What the operator was built from.
+//!
+//! `build.rs` records the git commit, whether the checkout had uncommitted changes, the enabled
+//! features, and a SHA-256 over the crate's sources and the workspace `Cargo.lock`.
+//! [`BuildInfo::current`] gathers them with the crate version, and its `build_hash` commits to
+//! all of them. The build is shown on `/status`, exported as `phala_avs_build_info`, included in
+//! reputation summaries, and quoted in the second half of the attestation report data (see
+//! [`attestation_report_data`](crate::encoding::attestation_report_data)), where
+//! [`verify_build_info`] checks it against the hashes a verifier expects.
+//!
+//! A build is `reproducible` when it was made from a clean checkout of a known commit with
+//! `SOURCE_DATE_EPOCH` set; only then can a verifier rebuild the commit and arrive at the same
+//! hash. The hash covers the declared inputs, not the compiled binary: the toolchain and target
+//! are not part of it, and the TEE measurement remains what attests the code actually running.
+
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::tee::attestation::AttestationReport;
+use blueprint_sdk::alloy::primitives::{B256, keccak256};
+use blueprint_sdk::alloy::sol_types::SolValue;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Empty when built outside a git checkout without `PHALA_AVS_GIT_COMMIT`.
+    pub git_commit: String,
+    /// Whether the checkout had uncommitted changes.
+    pub dirty: bool,
+    /// Enabled cargo features, sorted.
+    pub features: Vec<String>,
+    /// SHA-256 over `Cargo.toml`, the workspace `Cargo.lock` and the sources.
+    pub source_hash: B256,
+    pub reproducible: bool,
+    pub build_hash: B256,
+}
+
+impl BuildInfo {
+    pub fn new(
+        version: impl Into<String>,
+        git_commit: impl Into<String>,
+        dirty: bool,
+        features: Vec<String>,
+        source_hash: B256,
+        reproducible: bool,
+    ) -> Self {
+        let version = version.into();
+        let git_commit = git_commit.into();
+        let build_hash = keccak256(
+            (
+                version.clone(),
+                git_commit.clone(),
+                features.join(","),
+                source_hash,
+                dirty,
+            )
+                .abi_encode(),
+        );
+        Self {
+            version,
+            git_commit,
+            dirty,
+            features,
+            source_hash,
+            reproducible,
+            build_hash,
+        }
+    }
+
+    /// The build of this binary.
+    pub fn current() -> &'static BuildInfo {
+        static CURRENT: OnceLock<BuildInfo> = OnceLock::new();
+        CURRENT.get_or_init(|| {
+            let git_commit = env!("PHALA_AVS_BUILD_GIT_COMMIT");
+            let dirty = env!("PHALA_AVS_BUILD_DIRTY") == "true";
+            let features = env!("PHALA_AVS_BUILD_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(str::to_string)
+                .collect();
+            let source_hash = env!("PHALA_AVS_BUILD_SOURCE_HASH")
+                .parse()
+                .expect("build.rs records a 32-byte source hash");
+            let reproducible = !env!("PHALA_AVS_BUILD_SOURCE_DATE_EPOCH").is_empty()
+                && !git_commit.is_empty()
+                && !dirty;
+            BuildInfo::new(
+                env!("CARGO_PKG_VERSION"),
+                git_commit,
+                dirty,
+                features,
+                source_hash,
+                reproducible,
+            )
+        })
+    }
+
+    /// Exports the build as the labels of `phala_avs_build_info`, which is always 1.
+    pub fn record_metric(&self) {
+        METRICS.set_gauge(
+            "phala_avs_build_info",
+            &[
+                ("version", self.version.as_str()),
+                ("git_commit", self.git_commit.as_str()),
+                ("features", &self.features.join(",")),
+                ("build_hash", &self.build_hash.to_string()),
+                (
+                    "reproducible",
+                    if self.reproducible { "true" } else { "false" },
+                ),
+            ],
+            1.0,
+        );
+    }
+}
+
+/// The build hash quoted in the second half of attestation report data.
+pub fn attested_build_hash(report_data: &[u8]) -> Result<B256, PhalaAvsError> {
+    if report_data.len() != 64 {
+        return Err(PhalaAvsError::ValidationError(format!(
+            "Report data is {} bytes, expected 64",
+            report_data.len()
+        )));
+    }
+    Ok(B256::from_slice(&report_data[32..]))
+}
+
+/// Checks that `report` quotes one of the `expected` build hashes, and returns it.
+pub fn verify_build_info(
+    report: &AttestationReport,
+    expected: &[B256],
+) -> Result<B256, PhalaAvsError> {
+    let attested = attested_build_hash(&report.report_data)?;
+    if !expected.contains(&attested) {
+        let expected: Vec<String> = expected.iter().map(B256::to_string).collect();
+        return Err(PhalaAvsError::ValidationError(format!(
+            "The quote attests build {attested}, expected one of [{}]",
+            expected.join(", ")
+        )));
+    }
+    Ok(attested)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::attestation_report_data;
+    use crate::fixtures::QuoteFixture;
+    use crate::tee::attestation::parse_quote;
+    use blueprint_sdk::alloy::primitives::U256;
+
+    #[test]
+    fn the_build_round_trips_through_a_quote() {
+        let build = BuildInfo::current();
+        assert_eq!(build.version, env!("CARGO_PKG_VERSION"));
+        assert!(build.features.is_sorted());
+
+        for fixture in [QuoteFixture::tdx(), QuoteFixture::sgx()] {
+            let report_data = attestation_report_data(
+                U256::from(7),
+                B256::repeat_byte(9),
+                1_000,
+                build.build_hash,
+            );
+            let report = parse_quote(&fixture.report_data(report_data).build()).unwrap();
+            let other = B256::repeat_byte(1);
+            assert_eq!(
+                verify_build_info(&report, &[other, build.build_hash]).unwrap(),
+                build.build_hash
+            );
+        }
+    }
+
+    #[test]
+    fn a_mismatched_build_is_rejected() {
+        let build = BuildInfo::new("0.1.0", "abc", false, vec![], B256::repeat_byte(2), true);
+        let report_data =
+            attestation_report_data(U256::from(7), B256::repeat_byte(9), 1_000, build.build_hash);
+        let report = parse_quote(&QuoteFixture::tdx().report_data(report_data).build()).unwrap();
+
+        // Any declared input changes the hash.
+        let patched = BuildInfo::new("0.1.0", "abc", true, vec![], B256::repeat_byte(2), false);
+        let err = verify_build_info(&report, &[patched.build_hash]).unwrap_err();
+        assert!(err.to_string().contains(&build.build_hash.to_string()));
+        let featured = BuildInfo::new(
+            "0.1.0",
+            "abc",
+            false,
+            vec!["chaos".to_string()],
+            B256::repeat_byte(2),
+            true,
+        );
+        assert!(verify_build_info(&report, &[featured.build_hash]).is_err());
+
+        // A quote from before builds were quoted carries zeros.
+        let unbound = parse_quote(&QuoteFixture::tdx().build()).unwrap();
+        assert!(verify_build_info(&unbound, &[build.build_hash]).is_err());
+    }
+}
@@ -0,0 +1,470 @@
+//! Advertising this operator's TEE capacity to Phala's workload scheduler.
+//!
+//! The advertised capacity is what the host has available minus what workloads being
+//! provisioned have [`Reservations::reserve`]d, so we never advertise resources we are about to
+//! consume. It is committed on-chain with `updateCapacity` when it changes: every shrink is
+//! published at once, since the scheduler must never see more than we have, while growth is only
+//! published once it exceeds `CAPACITY_HYSTERESIS_PCT` of the total. Publication is deferrable:
+//! it waits while challenge responses are pending or submissions are suspended.
+//!
+//! While the operator drains for an exit, new reservations are refused and zero capacity is
+//! advertised, so the scheduler stops placing workloads here.
+
+use crate::config::{env_flag, env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::lanes::TxClass;
+use crate::metrics::METRICS;
+use crate::sender::{TxCall, TxSender};
+use crate::tee::TeeHandler;
+use crate::tee::capacity::{Resources, TeeCapacity, TeePlatform};
+use crate::{IPhalaServiceManager, SERVICE_MANAGER_ADDRESS};
+use blueprint_sdk::alloy::primitives::{Address, B256};
+use blueprint_sdk::alloy::sol_types::SolCall;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Gauge of host resources available, by resource.
+pub const CAPACITY_AVAILABLE_METRIC: &str = "phala_avs_capacity_available";
+/// Gauge of resources advertised on-chain, by resource.
+pub const CAPACITY_ADVERTISED_METRIC: &str = "phala_avs_capacity_advertised";
+/// Counter of capacity publications, by reason.
+pub const CAPACITY_PUBLICATIONS_METRIC: &str = "phala_avs_capacity_publications_total";
+
+#[derive(Clone, Debug)]
+pub struct CapacityConfig {
+    pub enabled: bool,
+    /// The dstack host API, queried for the host's resources.
+    pub host_url: Option<String>,
+    pub check_secs: u64,
+    /// Growth, as a percentage of the total of a resource, needed to republish.
+    pub hysteresis_pct: f64,
+}
+
+impl CapacityConfig {
+    /// Reads `CAPACITY_REPORTING_ENABLED` (off by default, publication costs gas),
+    /// `TEE_HOST_URL`, `CAPACITY_CHECK_SECS` and `CAPACITY_HYSTERESIS_PCT`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            enabled: env_flag("CAPACITY_REPORTING_ENABLED", false)?,
+            host_url: env_opt("TEE_HOST_URL")?,
+            check_secs: env_or("CAPACITY_CHECK_SECS", 300)?,
+            hysteresis_pct: env_or("CAPACITY_HYSTERESIS_PCT", 10.0)?,
+        })
+    }
+}
+
+/// Resources held for workloads being provisioned, by workload id.
+#[derive(Debug, Default)]
+pub struct Reservations {
+    held: Mutex<BTreeMap<String, Resources>>,
+    changed: Notify,
+    draining: AtomicBool,
+}
+
+impl Reservations {
+    /// Holds `resources` for `workload_id` until it is released. Refused while draining, so no
+    /// new workload is acknowledged.
+    pub fn reserve(&self, workload_id: &str, resources: Resources) -> Result<(), PhalaAvsError> {
+        if self.is_draining() {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Not accepting workload {workload_id}: the operator is exiting"
+            )));
+        }
+        self.held
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(workload_id.to_string(), resources);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Releases `workload_id`'s reservation, once it runs (and shows up in the host's
+    /// available resources) or its provisioning failed.
+    pub fn release(&self, workload_id: &str) {
+        self.held
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(workload_id);
+        self.changed.notify_one();
+    }
+
+    /// Stops accepting reservations and advertises zero capacity from the next check.
+    pub fn set_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.changed.notify_one();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn total(&self) -> Resources {
+        self.held
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .fold(Resources::default(), |sum, r| sum.saturating_add(*r))
+    }
+}
+
+/// On-chain capacity commitments.
+pub trait CapacityRegistry: Send + Sync {
+    /// Commits `advertised`, returning the transaction hash.
+    fn update_capacity(
+        &self,
+        advertised: Resources,
+        platform: TeePlatform,
+    ) -> BoxFuture<'_, Result<B256, PhalaAvsError>>;
+}
+
+/// [`CapacityRegistry`] backed by the `PhalaServiceManager` contract. Updates are deferrable,
+/// so they are sent from the maintenance account when there is one.
+#[derive(Clone)]
+pub struct ServiceManagerCapacity {
+    service_manager: Address,
+    sender: Arc<TxSender>,
+}
+
+impl ServiceManagerCapacity {
+    pub fn new(service_manager: Address, sender: Arc<TxSender>) -> Self {
+        Self {
+            service_manager,
+            sender,
+        }
+    }
+
+    /// Uses `SERVICE_MANAGER_ADDRESS`.
+    pub fn from_env(sender: Arc<TxSender>) -> Self {
+        Self::new(*SERVICE_MANAGER_ADDRESS, sender)
+    }
+}
+
+impl CapacityRegistry for ServiceManagerCapacity {
+    fn update_capacity(
+        &self,
+        advertised: Resources,
+        platform: TeePlatform,
+    ) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        Box::pin(async move {
+            let call = IPhalaServiceManager::updateCapacityCall {
+                vcpus: advertised.vcpus,
+                memoryMb: advertised.memory_mb,
+                storageGb: advertised.storage_gb,
+                platform: platform.code(),
+            };
+            let call = TxCall::new("updateCapacity", self.service_manager, call.abi_encode());
+            self.sender
+                .send(TxClass::Deferrable, call)
+                .await?
+                .into_success("updateCapacity")
+        })
+    }
+}
+
+/// Why capacity was republished.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PublishReason {
+    Initial,
+    Shrunk,
+    Grew,
+}
+
+impl PublishReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            PublishReason::Initial => "initial",
+            PublishReason::Shrunk => "shrunk",
+            PublishReason::Grew => "grew",
+        }
+    }
+}
+
+/// Capacity as shown on `/status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CapacityStatus {
+    pub platform: TeePlatform,
+    pub total: Resources,
+    pub available: Resources,
+    pub reserved: Resources,
+    pub advertised: Resources,
+    /// What is committed on-chain, once published.
+    pub published: Option<Resources>,
+    pub published_unix_ms: Option<u64>,
+}
+
+/// Publishes capacity commitments.
+pub struct CapacityReporter {
+    config: CapacityConfig,
+    registry: Arc<dyn CapacityRegistry>,
+    reservations: Arc<Reservations>,
+    status: Mutex<Option<CapacityStatus>>,
+}
+
+impl CapacityReporter {
+    pub fn new(
+        config: CapacityConfig,
+        registry: Arc<dyn CapacityRegistry>,
+        reservations: Arc<Reservations>,
+    ) -> Self {
+        Self {
+            config,
+            registry,
+            reservations,
+            status: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &CapacityConfig {
+        &self.config
+    }
+
+    pub fn status(&self) -> Option<CapacityStatus> {
+        self.status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Whether `advertised` should replace `published`.
+    pub fn publish_reason(
+        &self,
+        published: Option<Resources>,
+        advertised: Resources,
+        total: Resources,
+    ) -> Option<PublishReason> {
+        let Some(published) = published else {
+            return Some(PublishReason::Initial);
+        };
+        let (old, new, total) = (
+            dimensions(published),
+            dimensions(advertised),
+            dimensions(total),
+        );
+        if (0..3).any(|i| new[i] < old[i]) {
+            return Some(PublishReason::Shrunk);
+        }
+        (0..3)
+            .any(|i| (new[i] - old[i]) * 100.0 >= self.config.hysteresis_pct * total[i].max(1.0))
+            .then_some(PublishReason::Grew)
+    }
+
+    /// Reads the host's capacity and publishes it if it changed enough and `deferred` is false.
+    pub async fn check(
+        &self,
+        tee: &TeeHandler,
+        deferred: bool,
+    ) -> Result<Option<PublishReason>, PhalaAvsError> {
+        let TeeCapacity {
+            total,
+            available,
+            platform,
+        } = tee.get_capacity().await?;
+        let reserved = self.reservations.total();
+        let advertised = if self.reservations.is_draining() {
+            Resources::default()
+        } else {
+            available.saturating_sub(reserved)
+        };
+        set_gauges(CAPACITY_AVAILABLE_METRIC, available);
+
+        let previous = self.status();
+        let published = previous.as_ref().and_then(|s| s.published);
+        let reason = self
+            .publish_reason(published, advertised, total)
+            .filter(|_| !deferred);
+        let mut status = CapacityStatus {
+            platform,
+            total,
+            available,
+            reserved,
+            advertised,
+            published,
+            published_unix_ms: previous.and_then(|s| s.published_unix_ms),
+        };
+        if let Some(reason) = reason {
+            let tx = self.registry.update_capacity(advertised, platform).await?;
+            info!("Published capacity {advertised:?} ({reason:?}) in {tx}");
+            METRICS.inc_counter(
+                CAPACITY_PUBLICATIONS_METRIC,
+                &[("reason", reason.as_str())],
+                1,
+            );
+            set_gauges(CAPACITY_ADVERTISED_METRIC, advertised);
+            status.published = Some(advertised);
+            status.published_unix_ms = Some(now_unix_ms());
+        }
+        *self.status.lock().unwrap_or_else(|e| e.into_inner()) = Some(status);
+        Ok(reason)
+    }
+}
+
+const RESOURCE_LABELS: [&str; 3] = ["vcpus", "memory_mb", "storage_gb"];
+
+fn dimensions(resources: Resources) -> [f64; 3] {
+    [
+        resources.vcpus as f64,
+        resources.memory_mb as f64,
+        resources.storage_gb as f64,
+    ]
+}
+
+fn set_gauges(name: &str, resources: Resources) {
+    for (label, value) in RESOURCE_LABELS.into_iter().zip(dimensions(resources)) {
+        METRICS.set_gauge(name, &[("resource", label)], value);
+    }
+}
+
+/// Checks capacity every `check_secs`, and right away when a reservation changes.
+///
+/// `deferred` is asked before each check, so publication waits behind challenge responses.
+pub fn spawn_reporter(
+    reporter: Arc<CapacityReporter>,
+    tee: TeeHandler,
+    deferred: impl Fn() -> bool + Send + 'static,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(reporter.config.check_secs));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = reporter.reservations.changed.notified() => {}
+            }
+            if let Err(e) = reporter.check(&tee, deferred()).await {
+                warn!("Failed to report capacity: {e}");
+            }
+        }
+    });
+}
+
+fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandlerConfig;
+    use crate::tee::capacity::HostApi;
+
+    #[derive(Debug)]
+    struct MockHost(Mutex<TeeCapacity>);
+
+    impl HostApi for MockHost {
+        fn capacity(&self) -> BoxFuture<'_, Result<TeeCapacity, PhalaAvsError>> {
+            let capacity = *self.0.lock().unwrap();
+            Box::pin(async move { Ok(capacity) })
+        }
+    }
+
+    #[derive(Default)]
+    struct MockRegistry(Mutex<Vec<Resources>>);
+
+    impl CapacityRegistry for MockRegistry {
+        fn update_capacity(
+            &self,
+            advertised: Resources,
+            platform: TeePlatform,
+        ) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            assert_eq!(platform, TeePlatform::Tdx);
+            self.0.lock().unwrap().push(advertised);
+            Box::pin(async { Ok(B256::ZERO) })
+        }
+    }
+
+    fn resources(vcpus: u32, memory_mb: u64) -> Resources {
+        Resources {
+            vcpus,
+            memory_mb,
+            storage_gb: 500,
+        }
+    }
+
+    #[tokio::test]
+    async fn capacity_is_published_with_hysteresis_and_reservations() {
+        let host = Arc::new(MockHost(Mutex::new(TeeCapacity {
+            total: resources(32, 65_536),
+            available: resources(24, 49_152),
+            platform: TeePlatform::Tdx,
+        })));
+        let tee = TeeHandler::new(TeeHandlerConfig::default())
+            .await
+            .unwrap()
+            .with_host(Arc::clone(&host) as Arc<dyn HostApi>);
+        let registry = Arc::new(MockRegistry::default());
+        let reservations = Arc::new(Reservations::default());
+        let config = CapacityConfig {
+            enabled: true,
+            host_url: None,
+            check_secs: 300,
+            hysteresis_pct: 10.0,
+        };
+        let reporter = CapacityReporter::new(
+            config,
+            Arc::clone(&registry) as Arc<dyn CapacityRegistry>,
+            Arc::clone(&reservations),
+        );
+        let set_available = |available| host.0.lock().unwrap().available = available;
+
+        // Initial publication.
+        assert_eq!(
+            reporter.check(&tee, false).await.unwrap(),
+            Some(PublishReason::Initial)
+        );
+        // Growth below 10% of the total is suppressed, as is anything while deferred.
+        set_available(resources(26, 49_152));
+        assert_eq!(reporter.check(&tee, false).await.unwrap(), None);
+        set_available(resources(24, 40_000));
+        assert_eq!(reporter.check(&tee, true).await.unwrap(), None);
+
+        // A shrink is published at once.
+        assert_eq!(
+            reporter.check(&tee, false).await.unwrap(),
+            Some(PublishReason::Shrunk)
+        );
+
+        // A workload being provisioned holds its resources back from the advertisement.
+        reservations.reserve("workload-1", resources(8, 16_384));
+        assert_eq!(
+            reporter.check(&tee, false).await.unwrap(),
+            Some(PublishReason::Shrunk)
+        );
+        let status = reporter.status().unwrap();
+        assert_eq!(status.reserved, resources(8, 16_384));
+        assert_eq!(status.advertised, Resources {
+            vcpus: 16,
+            memory_mb: 23_616,
+            storage_gb: 0,
+        });
+
+        // Once it runs, the host reports it as used and the reservation is released.
+        set_available(Resources {
+            vcpus: 16,
+            memory_mb: 23_616,
+            storage_gb: 0,
+        });
+        reservations.release("workload-1");
+        assert_eq!(reporter.check(&tee, false).await.unwrap(), None);
+        assert_eq!(registry.0.lock().unwrap().len(), 3);
+
+        // Growth past the threshold is republished.
+        set_available(resources(30, 60_000));
+        assert_eq!(
+            reporter.check(&tee, false).await.unwrap(),
+            Some(PublishReason::Grew)
+        );
+        assert_eq!(
+            registry.0.lock().unwrap().last(),
+            Some(&resources(30, 60_000))
+        );
+    }
+}
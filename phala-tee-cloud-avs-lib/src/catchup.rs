@@ -0,0 +1,419 @@
+//! Startup catch-up of the challenge poller from the persisted cursors.
+//!
+//! After long downtime the poller may have hundreds of thousands of blocks to scan before it
+//! reaches the head. `CURSOR_CATCHUP_MODE` (or `--catchup-mode`) decides how far back it goes:
+//!
+//! - `full` (default): everything from the cursors.
+//! - `bounded:<blocks>`: at most that many blocks behind the head.
+//! - `skip`: only the last `CURSOR_CATCHUP_SKIP_MARGIN_BLOCKS`, to still see challenges whose
+//!   response window is open.
+//!
+//! The cursors are moved past whatever is left out, and the range is recorded as a
+//! [`SkippedRange`] first. Skipped ranges are kept in the state store and shown on `/status` and
+//! in the diagnostics bundle, so historical misses can be audited later instead of being
+//! silently forgotten.
+//!
+//! While catching up, the blocks remaining, observed scan rate, estimated completion and the
+//! challenges found so far are on `/status`, and logged every `CURSOR_CATCHUP_LOG_EVERY` ranges.
+
+use crate::config::env_or;
+use crate::cursor::{CursorStore, ProducerKind};
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::state::{StateStore, StateStoreExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Where skipped ranges are kept.
+pub const SKIPPED_NAMESPACE: &str = "catchup_skipped";
+/// Blocks the startup catch-up has yet to scan.
+pub const CATCHUP_REMAINING_METRIC: &str = "phala_avs_catchup_blocks_remaining";
+
+/// How far back the startup catch-up scans.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CatchUpMode {
+    #[default]
+    Full,
+    /// At most this many blocks behind the head.
+    Bounded(u64),
+    /// Only the skip margin behind the head.
+    Skip,
+}
+
+impl FromStr for CatchUpMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "full" => Ok(Self::Full),
+            "skip" => Ok(Self::Skip),
+            other => match other.strip_prefix("bounded:") {
+                Some(blocks) => blocks
+                    .parse()
+                    .map(Self::Bounded)
+                    .map_err(|e| format!("invalid bounded block count {blocks:?}: {e}")),
+                None => Err(format!(
+                    "expected full, bounded:<blocks> or skip, got {other}"
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for CatchUpMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "full"),
+            Self::Bounded(blocks) => write!(f, "bounded:{blocks}"),
+            Self::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CatchUpConfig {
+    pub mode: CatchUpMode,
+    /// Blocks behind the head still scanned in `skip` mode.
+    pub skip_margin_blocks: u64,
+    /// Ranges between progress log lines.
+    pub log_every: u64,
+}
+
+impl CatchUpConfig {
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            mode: env_or("CURSOR_CATCHUP_MODE", CatchUpMode::Full)?,
+            skip_margin_blocks: env_or("CURSOR_CATCHUP_SKIP_MARGIN_BLOCKS", 256)?,
+            log_every: env_or("CURSOR_CATCHUP_LOG_EVERY", 50u64)?.max(1),
+        })
+    }
+}
+
+/// Blocks a startup catch-up left unscanned, kept for auditing.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedRange {
+    pub chain_id: u64,
+    pub from_block: u64,
+    pub to_block: u64,
+    /// The catch-up mode that skipped it.
+    pub mode: String,
+    pub recorded_unix_ms: u64,
+}
+
+impl SkippedRange {
+    fn key(&self) -> Vec<u8> {
+        let mut key = self.chain_id.to_be_bytes().to_vec();
+        key.extend_from_slice(&self.from_block.to_be_bytes());
+        key
+    }
+}
+
+/// Catch-up progress, as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CatchUpStatus {
+    pub from_block: u64,
+    /// The head when the catch-up started.
+    pub target_block: u64,
+    /// The highest block of a scanned range.
+    pub scanned_through: Option<u64>,
+    pub blocks_remaining: u64,
+    pub ranges: u64,
+    pub challenges_found: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocks_per_sec: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_unix_ms: Option<u64>,
+    pub complete: bool,
+}
+
+#[derive(Debug)]
+struct Progress {
+    from: u64,
+    target: u64,
+    started_unix_ms: u64,
+    scanned_through: Option<u64>,
+    ranges: u64,
+    challenges_found: u64,
+    complete: bool,
+}
+
+impl Progress {
+    fn status(&self, now_ms: u64) -> CatchUpStatus {
+        let remaining = match self.scanned_through {
+            Some(block) => self.target.saturating_sub(block),
+            None => self.target + 1 - self.from,
+        };
+        let scanned = self.scanned_through.map(|block| block + 1 - self.from);
+        let elapsed_ms = now_ms.saturating_sub(self.started_unix_ms);
+        let blocks_per_sec = scanned
+            .filter(|_| elapsed_ms > 0)
+            .map(|scanned| scanned as f64 * 1000.0 / elapsed_ms as f64);
+        CatchUpStatus {
+            from_block: self.from,
+            target_block: self.target,
+            scanned_through: self.scanned_through,
+            blocks_remaining: remaining,
+            ranges: self.ranges,
+            challenges_found: self.challenges_found,
+            blocks_per_sec,
+            eta_unix_ms: blocks_per_sec
+                .filter(|rate| *rate > 0.0 && !self.complete)
+                .map(|rate| now_ms + (remaining as f64 / rate * 1000.0) as u64),
+            complete: self.complete,
+        }
+    }
+}
+
+/// Applies the catch-up mode at startup and follows the catch-up to the head.
+#[derive(Debug)]
+pub struct CatchUp {
+    config: CatchUpConfig,
+    store: Arc<dyn StateStore>,
+    progress: Mutex<Option<Progress>>,
+}
+
+impl CatchUp {
+    pub fn new(config: CatchUpConfig, store: Arc<dyn StateStore>) -> Self {
+        Self {
+            config,
+            store,
+            progress: Mutex::default(),
+        }
+    }
+
+    fn progress(&self) -> std::sync::MutexGuard<'_, Option<Progress>> {
+        self.progress.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Bounds the challenge catch-up on `chain_id` to the configured mode, moving the cursors
+    /// past any range left out, and starts following its progress. Returns the skipped range.
+    ///
+    /// Nothing is caught up, or skipped, for a chain without cursors.
+    pub fn prepare(
+        &self,
+        cursors: &CursorStore,
+        chain_id: u64,
+        head: u64,
+        now_ms: u64,
+    ) -> Result<Option<SkippedRange>, PhalaAvsError> {
+        let kind = ProducerKind::ChallengeLogs;
+        let Some(from) = cursors.catch_up_from(chain_id, kind) else {
+            return Ok(None);
+        };
+        if from > head {
+            return Ok(None);
+        }
+        let lookback = match self.config.mode {
+            CatchUpMode::Full => None,
+            CatchUpMode::Bounded(blocks) => Some(blocks),
+            CatchUpMode::Skip => Some(self.config.skip_margin_blocks),
+        };
+        let start = lookback.map_or(from, |blocks| from.max(head.saturating_sub(blocks)));
+
+        let skipped = if start > from {
+            let skipped = SkippedRange {
+                chain_id,
+                from_block: from,
+                to_block: start - 1,
+                mode: self.config.mode.to_string(),
+                recorded_unix_ms: now_ms,
+            };
+            // Recorded before the cursors move, so a crash in between loses nothing.
+            self.store
+                .put_json(SKIPPED_NAMESPACE, &skipped.key(), &skipped)?;
+            cursors.fast_forward(chain_id, kind, start - 1);
+            cursors.flush()?;
+            warn!(
+                "Skipped catching up on blocks {from}..={} of chain {chain_id} ({} mode); the \
+                 range is recorded for auditing",
+                start - 1,
+                self.config.mode
+            );
+            Some(skipped)
+        } else {
+            None
+        };
+
+        info!(
+            "Catching up on {} blocks of chain {chain_id}, from {start} to {head}",
+            head + 1 - start
+        );
+        self.follow(start, head, now_ms);
+        Ok(skipped)
+    }
+
+    /// Starts following a scan of blocks `from..=target`, reported through
+    /// [`observe`](Self::observe) and [`status`](Self::status). Also used by scans other than
+    /// the startup catch-up, such as a [state rebuild](crate::state::rebuild).
+    pub fn follow(&self, from: u64, target: u64, now_ms: u64) {
+        *self.progress() = Some(Progress {
+            from,
+            target,
+            started_unix_ms: now_ms,
+            scanned_through: None,
+            ranges: 0,
+            challenges_found: 0,
+            complete: false,
+        });
+        METRICS.set_gauge(
+            CATCHUP_REMAINING_METRIC,
+            &[],
+            (target + 1).saturating_sub(from) as f64,
+        );
+    }
+
+    /// Notes a polled range ending at `block`, in which `challenges` were found.
+    pub fn observe(&self, block: u64, challenges: usize, now_ms: u64) {
+        let mut guard = self.progress();
+        let Some(progress) = guard.as_mut().filter(|p| !p.complete) else {
+            return;
+        };
+        progress.ranges += 1;
+        progress.challenges_found += challenges as u64;
+        progress.scanned_through = progress.scanned_through.max(Some(block));
+        progress.complete = block >= progress.target;
+
+        let status = progress.status(now_ms);
+        METRICS.set_gauge(
+            CATCHUP_REMAINING_METRIC,
+            &[],
+            status.blocks_remaining as f64,
+        );
+        if progress.complete {
+            info!(
+                "Caught up to block {} in {} ranges; {} challenges found",
+                progress.target, progress.ranges, progress.challenges_found
+            );
+        } else if progress.ranges % self.config.log_every == 0 {
+            let rate = status.blocks_per_sec.unwrap_or_default();
+            let eta_secs = status
+                .eta_unix_ms
+                .map(|eta| eta.saturating_sub(now_ms) / 1000);
+            info!(
+                "Catching up: {} blocks remaining at {rate:.1} blocks/s, done in {}; {} \
+                 challenges found so far",
+                status.blocks_remaining,
+                eta_secs.map_or("unknown".to_string(), |secs| format!("~{secs}s")),
+                status.challenges_found
+            );
+        }
+    }
+
+    /// The catch-up's progress, once one was started.
+    pub fn status(&self, now_ms: u64) -> Option<CatchUpStatus> {
+        self.progress().as_ref().map(|p| p.status(now_ms))
+    }
+
+    /// Every range a catch-up skipped, by chain and block.
+    pub fn skipped(&self) -> Result<Vec<SkippedRange>, PhalaAvsError> {
+        let mut ranges = Vec::new();
+        for (_, raw) in self.store.scan(SKIPPED_NAMESPACE)? {
+            match serde_json::from_slice(&raw) {
+                Ok(range) => ranges.push(range),
+                Err(e) => warn!("Skipping corrupt skipped range record: {e}"),
+            }
+        }
+        Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cursor::CursorKey;
+    use crate::state::MemoryStateStore;
+    use blueprint_sdk::alloy::primitives::Address;
+
+    const A: Address = Address::repeat_byte(0xaa);
+    const B: Address = Address::repeat_byte(0xbb);
+    const HEAD: u64 = 500_000;
+
+    /// Cursors left 400k blocks behind the head by a long downtime.
+    fn after_downtime(mode: CatchUpMode) -> (Arc<dyn StateStore>, CursorStore, CatchUp) {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let cursors = CursorStore::new(Arc::clone(&store)).unwrap();
+        cursors.advance(CursorKey::challenges(1, A), 100_000);
+        cursors.advance(CursorKey::challenges(1, B), 120_000);
+        cursors.flush().unwrap();
+        let config = CatchUpConfig {
+            mode,
+            skip_margin_blocks: 256,
+            log_every: 10,
+        };
+        let catch_up = CatchUp::new(config, Arc::clone(&store));
+        (store, cursors, catch_up)
+    }
+
+    #[test]
+    fn modes_parse_and_round_trip() {
+        for mode in ["full", "bounded:5000", "skip"] {
+            assert_eq!(mode.parse::<CatchUpMode>().unwrap().to_string(), mode);
+        }
+        assert!("bounded:".parse::<CatchUpMode>().is_err());
+        assert!("latest".parse::<CatchUpMode>().is_err());
+    }
+
+    #[test]
+    fn each_mode_moves_the_cursors_and_records_what_it_skipped() {
+        let kind = ProducerKind::ChallengeLogs;
+
+        let (_, cursors, catch_up) = after_downtime(CatchUpMode::Full);
+        assert_eq!(catch_up.prepare(&cursors, 1, HEAD, 0).unwrap(), None);
+        assert_eq!(cursors.catch_up_from(1, kind), Some(100_001));
+        assert!(catch_up.skipped().unwrap().is_empty());
+
+        let (store, cursors, catch_up) = after_downtime(CatchUpMode::Bounded(50_000));
+        let skipped = catch_up.prepare(&cursors, 1, HEAD, 7).unwrap().unwrap();
+        assert_eq!((skipped.from_block, skipped.to_block), (100_001, 449_999));
+        assert_eq!(skipped.mode, "bounded:50000");
+        assert_eq!(cursors.catch_up_from(1, kind), Some(450_000));
+        // The skipped range and the moved cursors survive a restart.
+        let recovered = CursorStore::new(Arc::clone(&store)).unwrap();
+        assert_eq!(recovered.get(&CursorKey::challenges(1, A)), Some(449_999));
+        assert_eq!(recovered.get(&CursorKey::challenges(1, B)), Some(449_999));
+        assert_eq!(catch_up.skipped().unwrap(), vec![skipped]);
+
+        let (_, cursors, catch_up) = after_downtime(CatchUpMode::Skip);
+        let skipped = catch_up.prepare(&cursors, 1, HEAD, 0).unwrap().unwrap();
+        assert_eq!(skipped.to_block, HEAD - 257);
+        assert_eq!(cursors.catch_up_from(1, kind), Some(HEAD - 256));
+
+        // A bound wider than the gap skips nothing.
+        let (_, cursors, catch_up) = after_downtime(CatchUpMode::Bounded(1_000_000));
+        assert_eq!(catch_up.prepare(&cursors, 1, HEAD, 0).unwrap(), None);
+        assert_eq!(cursors.catch_up_from(1, kind), Some(100_001));
+    }
+
+    #[test]
+    fn progress_follows_the_scan_to_the_head() {
+        let (_, cursors, catch_up) = after_downtime(CatchUpMode::Full);
+        catch_up.prepare(&cursors, 1, HEAD, 1_000).unwrap();
+        let status = catch_up.status(1_000).unwrap();
+        assert_eq!(status.blocks_remaining, 400_000);
+        assert_eq!((status.ranges, status.blocks_per_sec), (0, None));
+
+        // 100k blocks in 10 seconds, one challenge per range.
+        for i in 1..=10 {
+            catch_up.observe(100_000 + i * 10_000, 1, 1_000 + i * 1_000);
+        }
+        let status = catch_up.status(11_000).unwrap();
+        assert_eq!(status.scanned_through, Some(200_000));
+        assert_eq!(status.blocks_remaining, 300_000);
+        assert_eq!((status.ranges, status.challenges_found), (10, 10));
+        assert_eq!(status.blocks_per_sec, Some(10_000.0));
+        assert_eq!(status.eta_unix_ms, Some(41_000));
+        assert!(!status.complete);
+
+        catch_up.observe(HEAD, 2, 41_000);
+        let status = catch_up.status(41_000).unwrap();
+        assert!(status.complete);
+        assert_eq!((status.blocks_remaining, status.challenges_found), (0, 12));
+        assert_eq!(status.eta_unix_ms, None);
+        // Ranges past the head are not catch-up.
+        catch_up.observe(HEAD + 1, 1, 42_000);
+        assert_eq!(catch_up.status(42_000).unwrap().ranges, 11);
+    }
+}
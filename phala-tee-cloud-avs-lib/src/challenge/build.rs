@@ -0,0 +1,151 @@
+//! Cooperative cancellation of response builds.
+//!
+//! A worker registers the response it builds with [`BuildRegistry::start`] and checks the
+//! returned [`BuildGuard`] between build stages. The oracle cancelling or amending the challenge
+//! aborts the build at its next check, so no more work goes into a response nobody will accept.
+
+use blueprint_sdk::alloy::primitives::U256;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Why a build was aborted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbortReason {
+    /// The oracle cancelled the challenge.
+    Cancelled,
+    /// The oracle amended the challenge; the build answers its old parameters.
+    Amended,
+}
+
+impl AbortReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Cancelled => "cancelled",
+            Self::Amended => "amended",
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Self::Cancelled => 1,
+            Self::Amended => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::Cancelled),
+            2 => Some(Self::Amended),
+            _ => None,
+        }
+    }
+}
+
+type Builds = Mutex<HashMap<U256, (u64, Arc<AtomicU8>)>>;
+
+/// The response builds in progress, by challenge.
+#[derive(Debug, Default)]
+pub struct BuildRegistry {
+    builds: Arc<Builds>,
+    next: AtomicU64,
+}
+
+impl BuildRegistry {
+    /// Registers a build of `challenge_id`'s response, replacing any earlier one.
+    pub fn start(&self, challenge_id: U256) -> BuildGuard {
+        let generation = self.next.fetch_add(1, Ordering::Relaxed);
+        let token = Arc::new(AtomicU8::new(0));
+        self.builds
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(challenge_id, (generation, Arc::clone(&token)));
+        BuildGuard {
+            challenge_id,
+            generation,
+            token,
+            builds: Arc::clone(&self.builds),
+        }
+    }
+
+    /// Aborts the build of `challenge_id`'s response at its next check. Returns whether one was
+    /// in progress.
+    pub fn abort(&self, challenge_id: &U256, reason: AbortReason) -> bool {
+        let builds = self.builds.lock().unwrap_or_else(|e| e.into_inner());
+        match builds.get(challenge_id) {
+            Some((_, token)) => {
+                token.store(reason.code(), Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of builds in progress.
+    pub fn len(&self) -> usize {
+        self.builds.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A registered build; unregisters it when dropped.
+#[derive(Debug)]
+pub struct BuildGuard {
+    challenge_id: U256,
+    generation: u64,
+    token: Arc<AtomicU8>,
+    builds: Arc<Builds>,
+}
+
+impl BuildGuard {
+    pub fn challenge_id(&self) -> U256 {
+        self.challenge_id
+    }
+
+    /// Why the build was aborted, if it was.
+    pub fn aborted(&self) -> Option<AbortReason> {
+        AbortReason::from_code(self.token.load(Ordering::Acquire))
+    }
+}
+
+impl Drop for BuildGuard {
+    fn drop(&mut self) {
+        let mut builds = self.builds.lock().unwrap_or_else(|e| e.into_inner());
+        // A later build of the same challenge may have replaced this one.
+        if builds
+            .get(&self.challenge_id)
+            .is_some_and(|(generation, _)| *generation == self.generation)
+        {
+            builds.remove(&self.challenge_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aborts_reach_only_the_current_build() {
+        let registry = BuildRegistry::default();
+        let id = U256::from(1);
+        assert!(!registry.abort(&id, AbortReason::Cancelled));
+
+        let first = registry.start(id);
+        let second = registry.start(id);
+        assert!(registry.abort(&id, AbortReason::Amended));
+        assert_eq!(first.aborted(), None);
+        assert_eq!(second.aborted(), Some(AbortReason::Amended));
+
+        // The replaced build leaving does not unregister the current one.
+        drop(first);
+        assert_eq!(registry.len(), 1);
+        drop(second);
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+//! Per-challenge guard against late detection.
+//!
+//! A challenge first decoded many blocks after it was issued means something upstream (the
+//! RPC, the producer, the log filter) fell behind, and its response window may already be too
+//! short. Every newly tracked challenge is assessed once, at the head it was first seen at.
+
+use super::ObservedChallenge;
+use super::window::remaining_blocks;
+use crate::config::env_or;
+use crate::display::Addr;
+use crate::error::PhalaAvsError;
+use crate::notify::{Alert, Severity};
+use blueprint_sdk::alloy::primitives::{Address, U256};
+use serde::Serialize;
+
+/// Histogram of blocks between a challenge's issuance and its first decoding.
+pub const DETECTION_DELAY_METRIC: &str = "phala_avs_challenge_detection_delay_blocks";
+
+/// When a late detection is alerted on.
+#[derive(Clone, Debug)]
+pub struct DetectionPolicy {
+    /// Detection delay, in blocks, above which a warning is raised.
+    pub warn_delay_blocks: u64,
+}
+
+impl Default for DetectionPolicy {
+    fn default() -> Self {
+        Self {
+            warn_delay_blocks: 10,
+        }
+    }
+}
+
+impl DetectionPolicy {
+    /// Loads the policy from `DETECTION_DELAY_WARN_BLOCKS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            warn_delay_blocks: env_or(
+                "DETECTION_DELAY_WARN_BLOCKS",
+                Self::default().warn_delay_blocks,
+            )?,
+        })
+    }
+
+    /// Assesses a challenge first seen at `head`, given its oracle's current safety margin.
+    /// Returns `None` when it was detected in time.
+    pub fn assess(
+        &self,
+        challenge: &ObservedChallenge,
+        head: u64,
+        safety_margin: u64,
+    ) -> Option<LateDetection> {
+        let delay_blocks = head.saturating_sub(challenge.issued_block);
+        let remaining_blocks = remaining_blocks(challenge.deadline_block, head);
+        let tier = if remaining_blocks < safety_margin {
+            DetectionTier::Critical
+        } else if delay_blocks > self.warn_delay_blocks {
+            DetectionTier::Warning
+        } else {
+            return None;
+        };
+        Some(LateDetection {
+            challenge_id: challenge.challenge_id,
+            oracle: challenge.oracle,
+            issued_block: challenge.issued_block,
+            detected_block: head,
+            delay_blocks,
+            remaining_blocks,
+            safety_margin,
+            tier,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectionTier {
+    /// Detected later than the policy allows, with the window still usable.
+    Warning,
+    /// Less than the safety margin is left; the challenge is escalated.
+    Critical,
+}
+
+/// A challenge detected too long after it was issued.
+#[derive(Clone, Debug, Serialize)]
+pub struct LateDetection {
+    pub challenge_id: U256,
+    pub oracle: Address,
+    pub issued_block: u64,
+    pub detected_block: u64,
+    pub delay_blocks: u64,
+    pub remaining_blocks: u64,
+    pub safety_margin: u64,
+    pub tier: DetectionTier,
+}
+
+impl LateDetection {
+    pub fn alert(&self) -> Alert {
+        let severity = match self.tier {
+            DetectionTier::Warning => Severity::Warning,
+            DetectionTier::Critical => Severity::Critical,
+        };
+        Alert::new(
+            "challenge_detection",
+            severity,
+            format!(
+                "Challenge {} from oracle {} was first seen at block {}, {} blocks after issuance; {} blocks remain against a safety margin of {}",
+                self.challenge_id,
+                Addr(self.oracle),
+                self.detected_block,
+                self.delay_blocks,
+                self.remaining_blocks,
+                self.safety_margin
+            ),
+        )
+        .with_remaining_window(self.remaining_blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::tracker::ReleaseReason;
+    use crate::challenge::{ChallengeTracker, ConfirmationPolicy, process_events};
+    use crate::evm::{BoxFuture, EvmClient};
+    use crate::fixtures::{ChallengeEventFixture, OPERATOR, block_hash};
+    use crate::state::{MemoryStateStore, StateStore};
+    use crate::tee::{TeeHandler, TeeHandlerConfig};
+    use blueprint_sdk::alloy::primitives::B256;
+    use std::sync::Arc;
+
+    const HEAD: u64 = 100;
+    const MARGIN: u64 = 5;
+
+    /// A canonical chain at [`HEAD`].
+    struct Chain;
+
+    impl EvmClient for Chain {
+        fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(31337) })
+        }
+
+        fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(HEAD) })
+        }
+
+        fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+            Box::pin(async move { Ok(Some(block_hash(number))) })
+        }
+
+        fn block_timestamp(
+            &self,
+            number: u64,
+        ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+            Box::pin(async move { Ok(Some(number * 12)) })
+        }
+
+        fn is_operator_registered(
+            &self,
+            _operator: Address,
+        ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+            Box::pin(async { Ok(true) })
+        }
+    }
+
+    #[test]
+    fn tiers_follow_delay_and_remaining_window() {
+        let policy = DetectionPolicy::default();
+        let at = |block: u64, window: u64| {
+            let challenge = ChallengeEventFixture::new()
+                .block(block)
+                .window(window)
+                .build_observed();
+            policy
+                .assess(&challenge, HEAD, MARGIN)
+                .map(|late| (late.tier, late.delay_blocks))
+        };
+        assert_eq!(at(95, 100), None);
+        assert_eq!(at(90, 100), None);
+        assert_eq!(at(89, 100), Some((DetectionTier::Warning, 11)));
+        // Fresh, but the window is already shorter than the margin.
+        assert_eq!(at(99, 5), Some((DetectionTier::Critical, 1)));
+        assert_eq!(at(50, 54), Some((DetectionTier::Critical, 50)));
+    }
+
+    #[tokio::test]
+    async fn late_events_alert_and_critical_ones_skip_confirmations() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        // Never submits unconfirmed on its own.
+        let policy = ConfirmationPolicy {
+            confirmations: 1_000,
+            early_submit_multiplier: 0.0,
+        };
+        let tracker = ChallengeTracker::new(policy, store).unwrap();
+        let tee = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        let log = |id: u64, block: u64, window: u64| {
+            ChallengeEventFixture::new()
+                .id(id)
+                .block(block)
+                .window(window)
+                .build_log()
+        };
+        // On time, delivered 30 blocks late, and delivered so late the window is nearly over.
+        let events = [log(1, 98, 500), log(2, 70, 500), log(3, 20, 83)];
+        let processed = process_events(&tracker, &Chain, &tee, OPERATOR, &events, true, |_| MARGIN)
+            .await
+            .unwrap();
+
+        let tiers: Vec<_> = processed
+            .late
+            .iter()
+            .map(|late| (late.challenge_id, late.tier, late.alert().severity))
+            .collect();
+        assert_eq!(tiers, [
+            (U256::from(2), DetectionTier::Warning, Severity::Warning),
+            (U256::from(3), DetectionTier::Critical, Severity::Critical),
+        ]);
+        let delays =
+            [1u64, 2, 3].map(|id| tracker.get(&U256::from(id)).unwrap().detection_delay_blocks);
+        assert_eq!(delays, [Some(2), Some(30), Some(80)]);
+
+        // Only the critical one is escalated and released without its confirmations.
+        assert_eq!(processed.ready.len(), 1);
+        let ready = &processed.ready[0];
+        assert_eq!(ready.challenge.challenge_id, U256::from(3));
+        assert!(ready.urgent);
+        assert_eq!(ready.release_reason, Some(ReleaseReason::DeadlineForced));
+        assert!(!tracker.get(&U256::from(2)).unwrap().urgent);
+    }
+}
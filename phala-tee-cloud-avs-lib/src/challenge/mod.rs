@@ -0,0 +1,306 @@
+//! Decoding and tracking of SLA challenges issued by the oracle.
+//!
+//! The oracle may cancel a challenge or amend its parameters after issuing it; both are
+//! decoded with [`decode_update`] and applied to the tracker by [`process_events`].
+
+pub mod build;
+pub mod detection;
+pub mod state;
+pub mod tracker;
+pub mod window;
+
+use crate::IPhalaSlaOracle::{SlaChallengeAmended, SlaChallengeCancelled, SlaChallengeIssued};
+use crate::batch::{self, BatchSummary, EventOutcome};
+use crate::display::Addr;
+use crate::error::PhalaAvsError;
+use crate::evm::EvmClient;
+use crate::tee::TeeHandler;
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, U256};
+use blueprint_sdk::alloy::rpc::types::Log;
+use blueprint_sdk::alloy::sol_types::SolEvent;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+pub use build::{AbortReason, BuildGuard, BuildRegistry};
+pub use detection::{DetectionPolicy, DetectionTier, LateDetection};
+pub use state::{ChallengeState, IllegalTransition, Transition};
+pub use tracker::{
+    Amendment, Cancellation, ChallengeTracker, ConfirmationPolicy, TrackedChallenge,
+};
+pub use window::{ChainClock, RemainingWindow, WindowSummary};
+
+/// An `SlaChallengeIssued` event as seen in a polled block.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObservedChallenge {
+    pub challenge_id: U256,
+    pub operator: Address,
+    pub challenge_data: Bytes,
+    pub deadline_block: u64,
+    /// The oracle contract that emitted the event.
+    pub oracle: Address,
+    pub issued_block: u64,
+    /// Hash of the issuing block, used to detect the block being orphaned.
+    pub issued_block_hash: Option<B256>,
+    pub transaction_hash: Option<B256>,
+}
+
+/// Decodes an `SlaChallengeIssued` log, returning `None` for any other log.
+pub fn decode_challenge(log: &Log) -> Option<ObservedChallenge> {
+    let decoded = log.log_decode::<SlaChallengeIssued>().ok()?;
+    let event = decoded.inner.data;
+    Some(ObservedChallenge {
+        challenge_id: event.challengeId,
+        operator: event.operator,
+        challenge_data: event.challengeData,
+        deadline_block: event.responseWindowEndBlock.saturating_to(),
+        oracle: log.address(),
+        issued_block: log.block_number?,
+        issued_block_hash: log.block_hash,
+        transaction_hash: log.transaction_hash,
+    })
+}
+
+/// An oracle's change to a challenge it issued.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChallengeUpdate {
+    /// `SlaChallengeCancelled`: the challenge needs no response.
+    Cancelled { challenge_id: U256 },
+    /// `SlaChallengeAmended`: the challenge is to be answered for `data` by `deadline_block`.
+    Amended {
+        challenge_id: U256,
+        data: Bytes,
+        deadline_block: u64,
+    },
+}
+
+impl ChallengeUpdate {
+    pub fn challenge_id(&self) -> U256 {
+        match self {
+            Self::Cancelled { challenge_id } | Self::Amended { challenge_id, .. } => *challenge_id,
+        }
+    }
+}
+
+/// Decodes an `SlaChallengeCancelled` or `SlaChallengeAmended` log, returning `None` for any
+/// other log.
+pub fn decode_update(log: &Log) -> Option<ChallengeUpdate> {
+    if let Ok(decoded) = log.log_decode::<SlaChallengeCancelled>() {
+        return Some(ChallengeUpdate::Cancelled {
+            challenge_id: decoded.inner.data.challengeId,
+        });
+    }
+    let event = log.log_decode::<SlaChallengeAmended>().ok()?.inner.data;
+    Some(ChallengeUpdate::Amended {
+        challenge_id: event.challengeId,
+        data: event.newData,
+        deadline_block: event.newDeadline.saturating_to(),
+    })
+}
+
+/// Whether `log` claims to be an `SlaChallengeIssued` event, whether or not it decodes.
+pub(crate) fn is_challenge_event(log: &Log) -> bool {
+    log.topic0() == Some(&SlaChallengeIssued::SIGNATURE_HASH)
+}
+
+/// Outcome of observing a batch of polled logs.
+#[derive(Debug, Default)]
+pub struct ObservedEvents {
+    pub summary: BatchSummary,
+    /// Logs that failed to decode, or whose decoder panicked, by `(block, log index)`.
+    pub undecodable: Vec<(Option<u64>, Option<u64>)>,
+    /// Logs whose challenge could not be recorded, to be retried.
+    pub failed: Vec<Log>,
+    /// Challenges tracked for the first time.
+    pub tracked: Vec<ObservedChallenge>,
+}
+
+/// Outcome of feeding a batch of polled logs through the challenge pipeline.
+#[derive(Debug, Default)]
+pub struct ProcessedEvents {
+    /// Challenges released for submission by this batch.
+    pub ready: Vec<TrackedChallenge>,
+    /// Per-event outcomes of observing the batch.
+    pub summary: BatchSummary,
+    /// Logs that failed to decode, or whose decoder panicked, by `(block, log index)`.
+    pub undecodable: Vec<(Option<u64>, Option<u64>)>,
+    /// Logs whose challenge could not be recorded, to be retried.
+    pub failed: Vec<Log>,
+    /// Provisional challenges invalidated because their issuing block was orphaned.
+    pub orphaned: Vec<ObservedChallenge>,
+    /// Newly tracked challenges detected too long after issuance.
+    pub late: Vec<LateDetection>,
+    /// Challenges the oracle cancelled in this batch.
+    pub cancelled: Vec<TrackedChallenge>,
+    /// Challenges the oracle amended in this batch, with their new parameters.
+    pub amended: Vec<TrackedChallenge>,
+}
+
+/// Observes the challenges for `operator` in `events`, decoded with `decode` at `head`, one
+/// event at a time: a decoder panic or a storage error affects only its own event.
+pub async fn observe_events(
+    tracker: &ChallengeTracker,
+    tee: &TeeHandler,
+    operator: Address,
+    head: u64,
+    events: &[Log],
+    decode: impl Fn(&Log) -> Option<ObservedChallenge>,
+) -> ObservedEvents {
+    let mut observed = ObservedEvents::default();
+    for event in events {
+        let outcome = match batch::isolate(|| Ok(decode(event))) {
+            Ok(Some(challenge)) => {
+                let outcome = observe_one(tracker, tee, operator, head, challenge.clone()).await;
+                match outcome {
+                    Ok(EventOutcome::Processed) => observed.tracked.push(challenge),
+                    Err(_) => observed.failed.push(event.clone()),
+                    Ok(_) => {}
+                }
+                outcome
+            }
+            Ok(None) if !is_challenge_event(event) => Ok(EventOutcome::Skipped),
+            decoded => {
+                observed
+                    .undecodable
+                    .push((event.block_number, event.log_index));
+                Err(decoded.err().unwrap_or_else(|| {
+                    PhalaAvsError::ValidationError("undecodable SlaChallengeIssued log".into())
+                }))
+            }
+        };
+        if let Err(e) = &outcome {
+            warn!(
+                "Failed to observe log in block {:?} (index {:?}): {e}",
+                event.block_number, event.log_index
+            );
+        }
+        observed.summary.record(&outcome);
+    }
+    observed
+}
+
+async fn observe_one(
+    tracker: &ChallengeTracker,
+    tee: &TeeHandler,
+    operator: Address,
+    head: u64,
+    challenge: ObservedChallenge,
+) -> Result<EventOutcome, PhalaAvsError> {
+    if challenge.operator != operator {
+        debug!(
+            "Ignoring challenge {} for operator {}",
+            challenge.challenge_id,
+            Addr(challenge.operator)
+        );
+        return Ok(EventOutcome::Skipped);
+    }
+    if !tracker.observe(challenge, head)? {
+        return Ok(EventOutcome::Skipped);
+    }
+    if let Err(e) = tee.warm_caches().await {
+        warn!("Failed to warm TEE caches: {:?}", e);
+    }
+    Ok(EventOutcome::Processed)
+}
+
+/// Applies the oracle's cancellations and amendments in `events` to the challenges it issued,
+/// at `head`. Updates of untracked challenges, or sent by another contract than the one that
+/// issued the challenge, are ignored.
+pub(crate) fn apply_updates(
+    tracker: &ChallengeTracker,
+    head: u64,
+    events: &[Log],
+    safety_margin: &impl Fn(&ObservedChallenge) -> u64,
+    processed: &mut ProcessedEvents,
+) {
+    for event in events {
+        let Some(update) = decode_update(event) else {
+            continue;
+        };
+        let challenge_id = update.challenge_id();
+        let Some(tracked) = tracker
+            .get(&challenge_id)
+            .filter(|t| t.challenge.oracle == event.address())
+        else {
+            debug!("Ignoring update of untracked challenge {challenge_id}");
+            continue;
+        };
+        let block = event.block_number.unwrap_or(head);
+        let applied = match update {
+            ChallengeUpdate::Cancelled { .. } => tracker
+                .cancel(&challenge_id, block)
+                .map(|cancelled| cancelled.map(|c| processed.cancelled.push(c))),
+            ChallengeUpdate::Amended {
+                data,
+                deadline_block,
+                ..
+            } => {
+                let margin = safety_margin(&tracked.challenge);
+                tracker
+                    .amend(&challenge_id, data, deadline_block, block, head, margin)
+                    .map(|amended| amended.map(|a| processed.amended.push(a)))
+            }
+        };
+        if let Err(e) = applied {
+            warn!("Failed to apply the oracle's update of challenge {challenge_id}: {e}");
+        }
+    }
+}
+
+/// Observes the challenges for `operator` in `events`, applies the oracle's cancellations and
+/// amendments, invalidates orphaned provisional ones and releases those that may be submitted.
+///
+/// Events are observed independently (see [`observe_events`]); only reading the chain and the
+/// tracker's own bookkeeping fail the batch. With `submissions_enabled` false, challenges are
+/// still observed and tracked but none are released, so they are picked up once submissions
+/// resume. `safety_margin` is passed to [`ChallengeTracker::release_ready`], and also decides
+/// which late detections are critical; those challenges are escalated.
+pub async fn process_events(
+    tracker: &ChallengeTracker,
+    evm: &dyn EvmClient,
+    tee: &TeeHandler,
+    operator: Address,
+    events: &[Log],
+    submissions_enabled: bool,
+    safety_margin: impl Fn(&ObservedChallenge) -> u64,
+) -> Result<ProcessedEvents, PhalaAvsError> {
+    let head = evm.block_number().await?;
+    let observed = observe_events(tracker, tee, operator, head, events, decode_challenge).await;
+    let mut processed = ProcessedEvents {
+        summary: observed.summary,
+        undecodable: observed.undecodable,
+        failed: observed.failed,
+        ..Default::default()
+    };
+    for challenge in &observed.tracked {
+        let margin = safety_margin(challenge);
+        let Some(late) = tracker.detection().assess(challenge, head, margin) else {
+            continue;
+        };
+        warn!(
+            "Challenge {} detected {} blocks after issuance ({:?})",
+            late.challenge_id, late.delay_blocks, late.tier
+        );
+        if late.tier == DetectionTier::Critical {
+            if let Err(e) = tracker.escalate(&late.challenge_id) {
+                warn!("Failed to escalate challenge {}: {e}", late.challenge_id);
+            }
+        }
+        processed.late.push(late);
+    }
+    apply_updates(tracker, head, events, &safety_margin, &mut processed);
+    processed.orphaned = tracker.reconcile(evm).await?;
+    if !processed.orphaned.is_empty() {
+        info!(
+            "Invalidated {} challenges from orphaned blocks",
+            processed.orphaned.len()
+        );
+    }
+
+    if submissions_enabled {
+        processed.ready = tracker.release_ready(head, safety_margin)?;
+    }
+    if let Err(e) = tracker.enforce_budget(head) {
+        warn!("Failed to spill completed challenges: {e}");
+    }
+    Ok(processed)
+}
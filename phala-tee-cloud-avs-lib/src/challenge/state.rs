@@ -0,0 +1,212 @@
+//! The challenge lifecycle as an explicit state machine.
+//!
+//! A challenge moves `Seen → Provisional → Queued → Building → Submitting → AwaitingInclusion
+//! → Responded`, and may end `Missed`, `Disputed`, `Invalid` or `Cancelled` instead. Only the
+//! moves in [`ChallengeState::can_transition_to`] are allowed; anything else is an
+//! [`IllegalTransition`]. Each move is kept as a [`Transition`] in the challenge's history.
+
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::primitives::U256;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Gauge of tracked challenges per lifecycle state.
+pub const CHALLENGE_STATE_METRIC: &str = "phala_avs_challenges";
+
+/// Where a tracked challenge is in its lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeState {
+    /// Decoded from a polled log, not yet recorded.
+    Seen,
+    /// Seen in a block that has not reached the confirmation depth. Only cheap work is done.
+    Provisional,
+    /// Released for response submission and waiting for a worker. Records written before the
+    /// state machine existed said `confirmed`.
+    #[serde(alias = "confirmed")]
+    Queued,
+    /// A worker is building the response.
+    Building,
+    /// The response transaction is being sent.
+    Submitting,
+    /// The response transaction was sent and is waiting to be included.
+    AwaitingInclusion,
+    /// The response was included before the deadline.
+    Responded,
+    /// The response window closed without an included response.
+    Missed,
+    /// The outcome of the challenge is being disputed.
+    Disputed,
+    /// The challenge cannot be answered; its issuing block was orphaned, for example.
+    Invalid,
+    /// The oracle withdrew the challenge before it was answered.
+    Cancelled,
+    /// The response was included, but the oracle rejected it (`SlaResponseRejected`).
+    RejectedByOracle,
+}
+
+impl ChallengeState {
+    pub const ALL: [Self; 12] = [
+        Self::Seen,
+        Self::Provisional,
+        Self::Queued,
+        Self::Building,
+        Self::Submitting,
+        Self::AwaitingInclusion,
+        Self::Responded,
+        Self::Missed,
+        Self::Disputed,
+        Self::Invalid,
+        Self::Cancelled,
+        Self::RejectedByOracle,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Seen => "seen",
+            Self::Provisional => "provisional",
+            Self::Queued => "queued",
+            Self::Building => "building",
+            Self::Submitting => "submitting",
+            Self::AwaitingInclusion => "awaiting_inclusion",
+            Self::Responded => "responded",
+            Self::Missed => "missed",
+            Self::Disputed => "disputed",
+            Self::Invalid => "invalid",
+            Self::Cancelled => "cancelled",
+            Self::RejectedByOracle => "rejected_by_oracle",
+        }
+    }
+
+    /// Whether a challenge may move from `self` to `to`.
+    ///
+    /// Failed and amended attempts go back to `Queued`; every state before inclusion can be
+    /// missed or cancelled; an included response is accepted or rejected by the oracle; only a
+    /// settled outcome can be disputed.
+    pub fn can_transition_to(self, to: Self) -> bool {
+        use ChallengeState::*;
+        matches!(
+            (self, to),
+            (Seen, Provisional | Invalid)
+                | (Provisional, Queued | Missed | Invalid | Cancelled)
+                | (Queued, Building | Missed | Invalid | Cancelled)
+                | (Building, Submitting | Queued | Missed | Invalid | Cancelled)
+                | (Submitting, AwaitingInclusion | Queued | Missed | Cancelled)
+                | (
+                    AwaitingInclusion,
+                    Responded | RejectedByOracle | Queued | Missed | Cancelled
+                )
+                | (Responded | Missed | RejectedByOracle, Disputed)
+        )
+    }
+
+    /// Whether the challenge needs no further work from the operator.
+    pub fn is_settled(self) -> bool {
+        matches!(
+            self,
+            Self::Responded
+                | Self::Missed
+                | Self::Disputed
+                | Self::Invalid
+                | Self::Cancelled
+                | Self::RejectedByOracle
+        )
+    }
+
+    /// Whether the challenge was released for submission, whatever became of it.
+    pub fn is_released(self) -> bool {
+        !matches!(self, Self::Seen | Self::Provisional | Self::Invalid)
+    }
+}
+
+impl fmt::Display for ChallengeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A move the state machine does not allow.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IllegalTransition {
+    pub challenge_id: U256,
+    pub from: ChallengeState,
+    pub to: ChallengeState,
+}
+
+impl fmt::Display for IllegalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "challenge {} cannot move from {} to {}",
+            self.challenge_id, self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for IllegalTransition {}
+
+impl From<IllegalTransition> for PhalaAvsError {
+    fn from(e: IllegalTransition) -> Self {
+        PhalaAvsError::ValidationError(e.to_string())
+    }
+}
+
+/// One recorded move of a challenge. A lifecycle starts with a `None` source.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transition {
+    pub from: Option<ChallengeState>,
+    pub to: ChallengeState,
+    pub unix_ms: u64,
+    pub cause: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settled_states_only_lead_to_disputes() {
+        for from in ChallengeState::ALL.into_iter().filter(|s| s.is_settled()) {
+            for to in ChallengeState::ALL {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    to == ChallengeState::Disputed
+                        && matches!(
+                            from,
+                            ChallengeState::Responded
+                                | ChallengeState::Missed
+                                | ChallengeState::RejectedByOracle
+                        ),
+                    "{from} -> {to}"
+                );
+            }
+        }
+        // Every state is reachable from `Seen`.
+        let reachable: Vec<_> = ChallengeState::ALL
+            .into_iter()
+            .filter(|s| reaches(ChallengeState::Seen, *s))
+            .collect();
+        assert_eq!(reachable, ChallengeState::ALL);
+    }
+
+    fn reaches(from: ChallengeState, to: ChallengeState) -> bool {
+        let mut seen = vec![from];
+        let mut frontier = vec![from];
+        while let Some(state) = frontier.pop() {
+            for next in ChallengeState::ALL {
+                if state.can_transition_to(next) && !seen.contains(&next) {
+                    seen.push(next);
+                    frontier.push(next);
+                }
+            }
+        }
+        seen.contains(&to)
+    }
+
+    #[test]
+    fn legacy_confirmed_records_read_as_queued() {
+        let state: ChallengeState = serde_json::from_str("\"confirmed\"").unwrap();
+        assert_eq!(state, ChallengeState::Queued);
+        assert_eq!(serde_json::to_string(&state).unwrap(), "\"queued\"");
+    }
+}
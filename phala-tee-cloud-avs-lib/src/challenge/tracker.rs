@@ -0,0 +1,1390 @@
+use super::ObservedChallenge;
+use super::build::{AbortReason, BuildGuard, BuildRegistry};
+use super::detection::{DETECTION_DELAY_METRIC, DetectionPolicy};
+use super::state::{CHALLENGE_STATE_METRIC, ChallengeState, IllegalTransition, Transition};
+use super::window::{self, ChainClock, WindowSummary};
+use crate::config::env_or;
+use crate::delegation::DelegationRecord;
+use crate::error::PhalaAvsError;
+use crate::evm::EvmClient;
+use crate::memory::{self, ApproxSize, CHALLENGE_TRACKER, MemoryBudgets};
+use crate::metrics::METRICS;
+use crate::sender::Withdrawal;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{Bytes, U256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// StateStore namespace holding tracked challenges keyed by challenge id.
+pub const TRACKER_NAMESPACE: &str = "challenge_tracker";
+
+/// StateStore namespace holding completed challenges spilled out of memory, keyed by id.
+pub const ARCHIVE_NAMESPACE: &str = "challenge_archive";
+
+/// Histogram of the time a challenge spent provisional before being released for submission.
+pub const CONFIRMATION_WAIT_METRIC: &str = "phala_avs_challenge_confirmation_wait_seconds";
+
+/// Why a provisional challenge was released for submission.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReleaseReason {
+    /// The issuing block reached the configured confirmation depth.
+    Confirmed,
+    /// The deadline was too close to keep waiting for confirmations.
+    DeadlineForced,
+}
+
+impl ReleaseReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReleaseReason::Confirmed => "confirmed",
+            ReleaseReason::DeadlineForced => "deadline_forced",
+        }
+    }
+}
+
+/// The oracle replacing a challenge's parameters.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Amendment {
+    /// Block of the `SlaChallengeAmended` event.
+    pub block: u64,
+    pub unix_ms: u64,
+    /// The state the challenge was in when amended.
+    pub from: ChallengeState,
+    pub previous_data: Bytes,
+    pub previous_deadline_block: u64,
+    /// What came of withdrawing a response to the previous parameters, if one was pending.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub withdrawal: Option<Withdrawal>,
+}
+
+/// The oracle withdrawing a challenge.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cancellation {
+    /// Block of the `SlaChallengeCancelled` event.
+    pub block: u64,
+    pub unix_ms: u64,
+    /// The state the challenge was in when cancelled.
+    pub from: ChallengeState,
+    /// What came of withdrawing the response, if one was pending.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub withdrawal: Option<Withdrawal>,
+}
+
+/// A challenge tracked by the operator.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrackedChallenge {
+    pub challenge: ObservedChallenge,
+    pub state: ChallengeState,
+    pub first_seen_unix_ms: u64,
+    pub released_unix_ms: Option<u64>,
+    pub release_reason: Option<ReleaseReason>,
+    /// Every state the challenge went through, oldest first.
+    #[serde(default)]
+    pub history: Vec<Transition>,
+    /// Blocks between issuance and the head the challenge was first decoded at.
+    #[serde(default)]
+    pub detection_delay_blocks: Option<u64>,
+    /// Detected too late to wait for confirmations: released at once and submitted with
+    /// escalated fees.
+    #[serde(default)]
+    pub urgent: bool,
+    /// What came of forwarding the challenge to its workload's responder, if it was.
+    #[serde(default)]
+    pub delegation: Option<DelegationRecord>,
+    /// Every amendment of the challenge by the oracle, oldest first.
+    #[serde(default)]
+    pub amendments: Vec<Amendment>,
+    #[serde(default)]
+    pub cancellation: Option<Cancellation>,
+}
+
+impl TrackedChallenge {
+    fn seen(challenge: ObservedChallenge, now_ms: u64) -> Self {
+        let cause = format!("decoded from block {}", challenge.issued_block);
+        Self {
+            challenge,
+            state: ChallengeState::Seen,
+            first_seen_unix_ms: now_ms,
+            released_unix_ms: None,
+            release_reason: None,
+            detection_delay_blocks: None,
+            urgent: false,
+            delegation: None,
+            amendments: Vec::new(),
+            cancellation: None,
+            history: vec![Transition {
+                from: None,
+                to: ChallengeState::Seen,
+                unix_ms: now_ms,
+                cause,
+            }],
+        }
+    }
+
+    /// Moves the challenge to `to`, recording why.
+    fn advance(
+        &mut self,
+        to: ChallengeState,
+        cause: impl Into<String>,
+        now_ms: u64,
+    ) -> Result<(), IllegalTransition> {
+        if !self.state.can_transition_to(to) {
+            return Err(IllegalTransition {
+                challenge_id: self.challenge.challenge_id,
+                from: self.state,
+                to,
+            });
+        }
+        self.history.push(Transition {
+            from: Some(self.state),
+            to,
+            unix_ms: now_ms,
+            cause: cause.into(),
+        });
+        self.state = to;
+        Ok(())
+    }
+
+    /// Rebuilds the history of a record written before transitions were recorded. Returns
+    /// whether anything changed.
+    fn migrate(&mut self) -> bool {
+        if !self.history.is_empty() {
+            return false;
+        }
+        let state = self.state;
+        let mut migrated = Self::seen(self.challenge.clone(), self.first_seen_unix_ms);
+        let _ = migrated.advance(
+            ChallengeState::Provisional,
+            "migrated",
+            self.first_seen_unix_ms,
+        );
+        if let Some(released) = self.released_unix_ms {
+            let cause = self
+                .release_reason
+                .map_or("migrated", ReleaseReason::as_str);
+            let _ = migrated.advance(ChallengeState::Queued, cause, released);
+        }
+        self.history = migrated.history;
+        self.state = state;
+        true
+    }
+
+    /// How many times the oracle amended the challenge; responses answer one revision.
+    pub fn revision(&self) -> u32 {
+        self.amendments.len() as u32
+    }
+
+    /// When the current parameters were first seen: response latency is measured from here.
+    pub fn latency_origin_ms(&self) -> u64 {
+        self.amendments
+            .last()
+            .map_or(self.first_seen_unix_ms, |amendment| amendment.unix_ms)
+    }
+
+    /// Blocks left to answer the challenge at `head`.
+    pub fn remaining_blocks(&self, head: u64) -> u64 {
+        window::remaining_blocks(self.challenge.deadline_block, head)
+    }
+
+    /// Settled, or released with its response window closed at `head`.
+    fn is_completed(&self, head: u64) -> bool {
+        self.state.is_settled()
+            || (self.state.is_released() && self.challenge.deadline_block < head)
+    }
+}
+
+impl ApproxSize for TrackedChallenge {
+    fn approx_size(&self) -> usize {
+        let history: usize = self
+            .history
+            .iter()
+            .map(|t| std::mem::size_of::<Transition>() + t.cause.len())
+            .sum();
+        let amendments: usize = self
+            .amendments
+            .iter()
+            .map(|a| std::mem::size_of::<Amendment>() + a.previous_data.len())
+            .sum();
+        std::mem::size_of::<Self>() + self.challenge.challenge_data.len() + history + amendments
+    }
+}
+
+/// When a provisional challenge may proceed to transaction submission.
+#[derive(Clone, Debug)]
+pub struct ConfirmationPolicy {
+    /// Blocks on top of the issuing block required before submitting.
+    pub confirmations: u64,
+    /// Risk tolerance: submit early, unconfirmed, once fewer than `margin * multiplier` blocks
+    /// remain before the deadline. `0` never submits before confirmation.
+    pub early_submit_multiplier: f64,
+}
+
+impl Default for ConfirmationPolicy {
+    fn default() -> Self {
+        Self {
+            confirmations: 2,
+            early_submit_multiplier: 2.0,
+        }
+    }
+}
+
+impl ConfirmationPolicy {
+    /// Loads the policy from `CHALLENGE_CONFIRMATIONS` and `CHALLENGE_EARLY_SUBMIT_MULTIPLIER`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        Ok(Self {
+            confirmations: env_or("CHALLENGE_CONFIRMATIONS", defaults.confirmations)?,
+            early_submit_multiplier: env_or(
+                "CHALLENGE_EARLY_SUBMIT_MULTIPLIER",
+                defaults.early_submit_multiplier,
+            )?,
+        })
+    }
+
+    fn release_reason(
+        &self,
+        challenge: &ObservedChallenge,
+        head: u64,
+        safety_margin: u64,
+    ) -> Option<ReleaseReason> {
+        if head >= challenge.issued_block.saturating_add(self.confirmations) {
+            return Some(ReleaseReason::Confirmed);
+        }
+        self.deadline_forced(challenge, head, safety_margin)
+            .then_some(ReleaseReason::DeadlineForced)
+    }
+
+    /// Whether too few blocks remain before the deadline to keep waiting for confirmations.
+    fn deadline_forced(
+        &self,
+        challenge: &ObservedChallenge,
+        head: u64,
+        safety_margin: u64,
+    ) -> bool {
+        let remaining = challenge.deadline_block.saturating_sub(head);
+        let threshold = (safety_margin as f64 * self.early_submit_multiplier).ceil() as u64;
+        remaining <= threshold
+    }
+}
+
+/// Tracks challenges through their lifecycle.
+///
+/// Challenges are first recorded as [`ChallengeState::Provisional`]; [`release_ready`] gates
+/// the move to submission on confirmation depth, and [`reconcile`] invalidates provisional
+/// entries whose issuing block was orphaned. Workers drive the rest with [`transition`]. Every
+/// move is checked against the state machine and persisted, with its cause, to the
+/// [`StateStore`].
+///
+/// The oracle may cancel or amend a challenge: [`cancel`] and [`amend`] record it and abort
+/// the response build workers registered with [`start_build`].
+///
+/// Past its memory budget, [`enforce_budget`] spills the least recently used completed
+/// challenges to [`ARCHIVE_NAMESPACE`], where [`get`] still finds them. Challenges that are
+/// provisional or still in their response window are never spilled.
+///
+/// [`release_ready`]: Self::release_ready
+/// [`reconcile`]: Self::reconcile
+/// [`transition`]: Self::transition
+/// [`enforce_budget`]: Self::enforce_budget
+/// [`get`]: Self::get
+/// [`cancel`]: Self::cancel
+/// [`amend`]: Self::amend
+/// [`start_build`]: Self::start_build
+#[derive(Debug)]
+pub struct ChallengeTracker {
+    policy: ConfirmationPolicy,
+    detection: DetectionPolicy,
+    store: Arc<dyn StateStore>,
+    entries: Mutex<BTreeMap<U256, TrackedChallenge>>,
+    budgets: Arc<MemoryBudgets>,
+    /// Access tick of each entry, for least-recently-used spilling.
+    last_used: Mutex<HashMap<U256, u64>>,
+    clock: AtomicU64,
+    /// The latest chain head, from which remaining windows are derived.
+    chain: Arc<ChainClock>,
+    builds: BuildRegistry,
+}
+
+impl ChallengeTracker {
+    /// Creates a tracker, restoring previously persisted entries.
+    ///
+    /// Records written before transitions were recorded are rewritten with a history
+    /// reconstructed from their timestamps.
+    pub fn new(
+        policy: ConfirmationPolicy,
+        store: Arc<dyn StateStore>,
+    ) -> Result<Self, PhalaAvsError> {
+        let mut entries = BTreeMap::new();
+        let mut migrated = 0;
+        for (key, raw) in store.scan(TRACKER_NAMESPACE)? {
+            match serde_json::from_slice::<TrackedChallenge>(&raw) {
+                Ok(mut entry) => {
+                    if entry.migrate() {
+                        store.put_json(TRACKER_NAMESPACE, &key, &entry)?;
+                        migrated += 1;
+                    }
+                    entries.insert(entry.challenge.challenge_id, entry);
+                }
+                Err(e) => warn!("Skipping corrupt tracker record: {e}"),
+            }
+        }
+        if migrated > 0 {
+            info!("Migrated {migrated} tracked challenges to the lifecycle state machine");
+        }
+        report_states(&entries, None);
+        Ok(Self {
+            policy,
+            detection: DetectionPolicy::default(),
+            store,
+            entries: Mutex::new(entries),
+            budgets: Arc::default(),
+            last_used: Mutex::default(),
+            clock: AtomicU64::new(0),
+            chain: Arc::default(),
+            builds: BuildRegistry::default(),
+        })
+    }
+
+    /// Limits the tracker's memory to the [`CHALLENGE_TRACKER`] budget; unlimited by default.
+    pub fn with_budgets(mut self, budgets: Arc<MemoryBudgets>) -> Self {
+        self.budgets = budgets;
+        self
+    }
+
+    /// Sets when late detections are alerted on.
+    pub fn with_detection(mut self, detection: DetectionPolicy) -> Self {
+        self.detection = detection;
+        self
+    }
+
+    pub fn detection(&self) -> &DetectionPolicy {
+        &self.detection
+    }
+
+    /// Derives remaining windows from `clock`, shared with whatever else reads the head.
+    pub fn with_chain_clock(mut self, clock: Arc<ChainClock>) -> Self {
+        self.chain = clock;
+        self
+    }
+
+    pub fn chain_clock(&self) -> &Arc<ChainClock> {
+        &self.chain
+    }
+
+    /// Records the chain head and re-derives the remaining windows from it.
+    pub fn observe_head(&self, head: u64) {
+        self.chain.observe(head);
+        report_states(&self.entries(), self.chain.head());
+    }
+
+    /// Blocks left to answer a tracked challenge at the latest head, `None` if it is not tracked
+    /// in memory or no head was observed yet.
+    pub fn remaining_window(&self, challenge_id: &U256) -> Option<u64> {
+        let head = self.chain.head()?;
+        self.entries()
+            .get(challenge_id)
+            .map(|entry| entry.remaining_blocks(head))
+    }
+
+    /// The remaining windows of every pending challenge at the latest head, `None` before a head
+    /// was observed.
+    pub fn window_summary(&self) -> Option<WindowSummary> {
+        let head = self.chain.head()?;
+        Some(WindowSummary::of(self.entries().values(), head))
+    }
+
+    fn touch(&self, challenge_id: U256) {
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+        self.last_used
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(challenge_id, tick);
+    }
+
+    fn archived(&self, challenge_id: &U256) -> Option<TrackedChallenge> {
+        let mut entry: TrackedChallenge = self
+            .store
+            .get_json(ARCHIVE_NAMESPACE, &challenge_id.to_be_bytes::<32>())
+            .unwrap_or_else(|e| {
+                warn!("Failed to read archived challenge {challenge_id}: {e}");
+                None
+            })?;
+        entry.migrate();
+        Some(entry)
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, BTreeMap<U256, TrackedChallenge>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn persist(&self, entry: &TrackedChallenge) -> Result<(), PhalaAvsError> {
+        self.store.put_json(
+            TRACKER_NAMESPACE,
+            &entry.challenge.challenge_id.to_be_bytes::<32>(),
+            entry,
+        )
+    }
+
+    /// Records a challenge newly decoded at `head` as provisional, along with how late it was
+    /// detected.
+    ///
+    /// Returns `false` if the challenge is already tracked, so callers only do the cheap
+    /// first-sight work (cache warming) once.
+    pub fn observe(&self, challenge: ObservedChallenge, head: u64) -> Result<bool, PhalaAvsError> {
+        let mut entries = self.entries();
+        let existing = match entries.get(&challenge.challenge_id) {
+            Some(existing) => Some(existing.clone()),
+            None => self.archived(&challenge.challenge_id),
+        };
+        if existing.is_some_and(|e| e.challenge.issued_block_hash == challenge.issued_block_hash) {
+            return Ok(false);
+        }
+        let now = now_unix_ms();
+        let delay = head.saturating_sub(challenge.issued_block);
+        let mut entry = TrackedChallenge::seen(challenge, now);
+        entry.detection_delay_blocks = Some(delay);
+        let cause = format!("awaiting {} confirmations", self.policy.confirmations);
+        entry.advance(ChallengeState::Provisional, cause, now)?;
+        self.persist(&entry)?;
+        self.chain.observe(head);
+        info!(
+            challenge_id = %entry.challenge.challenge_id,
+            remaining_blocks = entry.remaining_blocks(head),
+            "Tracking provisional challenge {} (block {}, deadline {})",
+            entry.challenge.challenge_id,
+            entry.challenge.issued_block,
+            entry.challenge.deadline_block
+        );
+        METRICS.observe(DETECTION_DELAY_METRIC, &[], delay as f64);
+        self.touch(entry.challenge.challenge_id);
+        entries.insert(entry.challenge.challenge_id, entry);
+        report_states(&entries, self.chain.head());
+        Ok(true)
+    }
+
+    /// Moves a tracked challenge, spilled or not, to `to`, persisting the transition with its
+    /// `cause`. Moves the state machine does not allow fail without changing anything.
+    pub fn transition(
+        &self,
+        challenge_id: U256,
+        to: ChallengeState,
+        cause: impl Into<String>,
+    ) -> Result<TrackedChallenge, PhalaAvsError> {
+        let mut entries = self.entries();
+        let key = challenge_id.to_be_bytes::<32>();
+        if let Some(entry) = entries.get(&challenge_id) {
+            let mut updated = entry.clone();
+            updated.advance(to, cause, now_unix_ms())?;
+            self.persist(&updated)?;
+            let head = self.chain.head();
+            debug!(
+                challenge_id = %challenge_id,
+                state = %to,
+                remaining_blocks = ?head.map(|head| updated.remaining_blocks(head)),
+                "Challenge {challenge_id} moved to {to}"
+            );
+            entries.insert(challenge_id, updated.clone());
+            report_states(&entries, head);
+            return Ok(updated);
+        }
+        let mut archived = self.archived(&challenge_id).ok_or_else(|| {
+            PhalaAvsError::ValidationError(format!("challenge {challenge_id} is not tracked"))
+        })?;
+        archived.advance(to, cause, now_unix_ms())?;
+        self.store.put_json(ARCHIVE_NAMESPACE, &key, &archived)?;
+        Ok(archived)
+    }
+
+    /// Marks a provisional challenge urgent, so it is released without waiting for
+    /// confirmations and submitted with escalated fees.
+    pub fn escalate(&self, challenge_id: &U256) -> Result<(), PhalaAvsError> {
+        let mut entries = self.entries();
+        let Some(entry) = entries.get(challenge_id).filter(|e| !e.urgent) else {
+            return Ok(());
+        };
+        let mut updated = entry.clone();
+        updated.urgent = true;
+        self.persist(&updated)?;
+        entries.insert(*challenge_id, updated);
+        Ok(())
+    }
+
+    /// Records the outcome of delegating a tracked challenge's response to its workload.
+    pub fn record_delegation(
+        &self,
+        challenge_id: &U256,
+        record: DelegationRecord,
+    ) -> Result<(), PhalaAvsError> {
+        let mut entries = self.entries();
+        let Some(entry) = entries.get(challenge_id) else {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "challenge {challenge_id} is not tracked"
+            )));
+        };
+        let mut updated = entry.clone();
+        updated.delegation = Some(record);
+        self.persist(&updated)?;
+        entries.insert(*challenge_id, updated);
+        Ok(())
+    }
+
+    /// Registers a build of the challenge's response, aborted if the oracle cancels or amends
+    /// the challenge before it is done.
+    pub fn start_build(&self, challenge_id: U256) -> BuildGuard {
+        self.builds.start(challenge_id)
+    }
+
+    /// Records the oracle cancelling a tracked challenge in `block`: the challenge is
+    /// [`ChallengeState::Cancelled`] and its response build aborted.
+    ///
+    /// Returns the cancelled challenge, or `None` if it is not tracked or already settled.
+    pub fn cancel(
+        &self,
+        challenge_id: &U256,
+        block: u64,
+    ) -> Result<Option<TrackedChallenge>, PhalaAvsError> {
+        let mut entries = self.entries();
+        let Some(entry) = entries.get(challenge_id).filter(|e| !e.state.is_settled()) else {
+            return Ok(None);
+        };
+        let now = now_unix_ms();
+        let mut updated = entry.clone();
+        updated.cancellation = Some(Cancellation {
+            block,
+            unix_ms: now,
+            from: entry.state,
+            withdrawal: None,
+        });
+        updated.advance(
+            ChallengeState::Cancelled,
+            format!("cancelled by the oracle in block {block}"),
+            now,
+        )?;
+        self.persist(&updated)?;
+        entries.insert(*challenge_id, updated.clone());
+        report_states(&entries, self.chain.head());
+        drop(entries);
+        if self.builds.abort(challenge_id, AbortReason::Cancelled) {
+            info!("Aborting the response build of cancelled challenge {challenge_id}");
+        }
+        Ok(Some(updated))
+    }
+
+    /// Records the oracle amending a tracked challenge in `block` to answer `data` by
+    /// `deadline_block`: the parameters are replaced, the delegated response for the old ones
+    /// dropped, the deadline policy re-evaluated at `head` and any build aborted. A challenge
+    /// already released goes back to [`ChallengeState::Queued`] to be answered anew.
+    ///
+    /// Returns the amended challenge, or `None` if it is not tracked, already settled, or
+    /// already carries these parameters.
+    pub fn amend(
+        &self,
+        challenge_id: &U256,
+        data: Bytes,
+        deadline_block: u64,
+        block: u64,
+        head: u64,
+        safety_margin: u64,
+    ) -> Result<Option<TrackedChallenge>, PhalaAvsError> {
+        let mut entries = self.entries();
+        let Some(entry) = entries.get(challenge_id).filter(|e| !e.state.is_settled()) else {
+            return Ok(None);
+        };
+        if entry.challenge.challenge_data == data
+            && entry.challenge.deadline_block == deadline_block
+        {
+            return Ok(None);
+        }
+        let now = now_unix_ms();
+        let mut updated = entry.clone();
+        updated.amendments.push(Amendment {
+            block,
+            unix_ms: now,
+            from: entry.state,
+            previous_data: entry.challenge.challenge_data.clone(),
+            previous_deadline_block: entry.challenge.deadline_block,
+            withdrawal: None,
+        });
+        updated.challenge.challenge_data = data;
+        updated.challenge.deadline_block = deadline_block;
+        updated.delegation = None;
+        updated.urgent = self
+            .policy
+            .deadline_forced(&updated.challenge, head, safety_margin);
+        if updated.state.is_released() && updated.state != ChallengeState::Queued {
+            let cause = format!("amended by the oracle in block {block}");
+            updated.advance(ChallengeState::Queued, cause, now)?;
+        }
+        self.persist(&updated)?;
+        info!(
+            "Challenge {challenge_id} was amended in block {block} (deadline {} -> {deadline_block})",
+            entry.challenge.deadline_block
+        );
+        entries.insert(*challenge_id, updated.clone());
+        report_states(&entries, self.chain.head());
+        drop(entries);
+        if self.builds.abort(challenge_id, AbortReason::Amended) {
+            info!("Aborting the response build of amended challenge {challenge_id}");
+        }
+        Ok(Some(updated))
+    }
+
+    /// Records what came of withdrawing the pending response of a cancelled or amended
+    /// challenge, on its latest cancellation or amendment.
+    pub fn record_withdrawal(
+        &self,
+        challenge_id: &U256,
+        withdrawal: Withdrawal,
+    ) -> Result<(), PhalaAvsError> {
+        let mut entries = self.entries();
+        let Some(entry) = entries.get(challenge_id) else {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "challenge {challenge_id} is not tracked"
+            )));
+        };
+        let mut updated = entry.clone();
+        let slot = match (&mut updated.cancellation, updated.amendments.last_mut()) {
+            (Some(cancellation), _) => &mut cancellation.withdrawal,
+            (None, Some(amendment)) => &mut amendment.withdrawal,
+            (None, None) => {
+                return Err(PhalaAvsError::ValidationError(format!(
+                    "challenge {challenge_id} was neither cancelled nor amended"
+                )));
+            }
+        };
+        *slot = Some(withdrawal);
+        self.persist(&updated)?;
+        entries.insert(*challenge_id, updated);
+        Ok(())
+    }
+
+    /// The recorded transitions of a challenge, oldest first.
+    pub fn history(&self, challenge_id: &U256) -> Option<Vec<Transition>> {
+        self.get(challenge_id).map(|entry| entry.history)
+    }
+
+    /// Returns a snapshot of a tracked challenge, including spilled ones.
+    pub fn get(&self, challenge_id: &U256) -> Option<TrackedChallenge> {
+        let entry = self.entries().get(challenge_id).cloned();
+        match entry {
+            Some(entry) => {
+                self.touch(*challenge_id);
+                Some(entry)
+            }
+            None => self.archived(challenge_id),
+        }
+    }
+
+    /// Spills least recently used completed challenges until the tracker fits its budget at
+    /// `head`, and reports its usage. Returns how many were spilled.
+    pub fn enforce_budget(&self, head: u64) -> Result<usize, PhalaAvsError> {
+        self.chain.observe(head);
+        let mut entries = self.entries();
+        let (mut used, per_entry) = memory::estimate(entries.len(), entries.values());
+        let mut spilled = 0;
+        let over_budget = self.budgets.budget(CHALLENGE_TRACKER).filter(|&b| used > b);
+        if let Some(budget) = over_budget {
+            let mut last_used = self.last_used.lock().unwrap_or_else(|e| e.into_inner());
+            let mut completed: Vec<_> = entries
+                .values()
+                .filter(|e| e.is_completed(head))
+                .map(|e| e.challenge.challenge_id)
+                .collect();
+            completed.sort_by_key(|id| last_used.get(id).copied().unwrap_or_default());
+            for id in completed {
+                if used <= budget {
+                    break;
+                }
+                let key = id.to_be_bytes::<32>();
+                self.store
+                    .put_json(ARCHIVE_NAMESPACE, &key, &entries[&id])?;
+                self.store.delete(TRACKER_NAMESPACE, &key)?;
+                entries.remove(&id);
+                last_used.remove(&id);
+                used = used.saturating_sub(per_entry);
+                spilled += 1;
+            }
+            if used > budget {
+                warn!(
+                    "Challenge tracker is over its {budget} byte budget ({used} bytes) with only active challenges left"
+                );
+            }
+        }
+        if spilled > 0 {
+            info!("Spilled {spilled} completed challenges to the state store");
+        }
+        report_states(&entries, self.chain.head());
+        self.budgets.report(CHALLENGE_TRACKER, used, now_unix_ms());
+        Ok(spilled)
+    }
+
+    /// Returns a snapshot of every tracked challenge, ordered by id.
+    pub fn snapshot(&self) -> Vec<TrackedChallenge> {
+        self.entries().values().cloned().collect()
+    }
+
+    /// Every tracked challenge, spilled or not, whose response window closed in
+    /// `[from_block, to_block)`, ordered by id.
+    pub fn closed_between(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<TrackedChallenge>, PhalaAvsError> {
+        let closed = |entry: &TrackedChallenge| {
+            (from_block..to_block).contains(&entry.challenge.deadline_block)
+        };
+        let mut found: BTreeMap<U256, TrackedChallenge> = self
+            .entries()
+            .values()
+            .filter(|e| closed(e))
+            .map(|e| (e.challenge.challenge_id, e.clone()))
+            .collect();
+        for (_, raw) in self.store.scan(ARCHIVE_NAMESPACE)? {
+            match serde_json::from_slice::<TrackedChallenge>(&raw) {
+                Ok(mut entry) if closed(&entry) => {
+                    entry.migrate();
+                    found.entry(entry.challenge.challenge_id).or_insert(entry);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Skipping corrupt archived challenge: {e}"),
+            }
+        }
+        Ok(found.into_values().collect())
+    }
+
+    /// Invalidates provisional challenges whose issuing block is no longer canonical, moving
+    /// them to the archive so the same challenge seen again in the new block is tracked afresh.
+    ///
+    /// Returns the orphaned challenges.
+    pub async fn reconcile(
+        &self,
+        evm: &dyn EvmClient,
+    ) -> Result<Vec<ObservedChallenge>, PhalaAvsError> {
+        let provisional: Vec<ObservedChallenge> = self
+            .entries()
+            .values()
+            .filter(|e| e.state == ChallengeState::Provisional)
+            .map(|e| e.challenge.clone())
+            .collect();
+
+        let mut orphaned = Vec::new();
+        for challenge in provisional {
+            let Some(expected) = challenge.issued_block_hash else {
+                continue;
+            };
+            let canonical = evm.block_hash(challenge.issued_block).await?;
+            if canonical != Some(expected) {
+                warn!(
+                    "Challenge {} was issued in orphaned block {} ({expected}), invalidating it",
+                    challenge.challenge_id, challenge.issued_block
+                );
+                let key = challenge.challenge_id.to_be_bytes::<32>();
+                let mut entries = self.entries();
+                let Some(mut entry) = entries.get(&challenge.challenge_id).cloned() else {
+                    continue;
+                };
+                let cause = format!("issuing block {} orphaned", challenge.issued_block);
+                entry.advance(ChallengeState::Invalid, cause, now_unix_ms())?;
+                self.store.put_json(ARCHIVE_NAMESPACE, &key, &entry)?;
+                self.store.delete(TRACKER_NAMESPACE, &key)?;
+                entries.remove(&challenge.challenge_id);
+                report_states(&entries, self.chain.head());
+                self.last_used
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(&challenge.challenge_id);
+                orphaned.push(challenge);
+            }
+        }
+        Ok(orphaned)
+    }
+
+    /// Releases provisional challenges that may now be submitted.
+    ///
+    /// `safety_margin` returns the current inclusion safety margin for a challenge's oracle,
+    /// which together with the policy's risk tolerance decides when the deadline forces an
+    /// unconfirmed submission. Each challenge is released exactly once.
+    pub fn release_ready(
+        &self,
+        head: u64,
+        safety_margin: impl Fn(&ObservedChallenge) -> u64,
+    ) -> Result<Vec<TrackedChallenge>, PhalaAvsError> {
+        self.chain.observe(head);
+        let mut entries = self.entries();
+        let mut released = Vec::new();
+        for entry in entries.values_mut() {
+            if entry.state != ChallengeState::Provisional {
+                continue;
+            }
+            let margin = safety_margin(&entry.challenge);
+            let reason = match entry.urgent {
+                true => Some(ReleaseReason::DeadlineForced),
+                false => self.policy.release_reason(&entry.challenge, head, margin),
+            };
+            let Some(reason) = reason else {
+                continue;
+            };
+            let now = now_unix_ms();
+            let mut updated = entry.clone();
+            updated.advance(ChallengeState::Queued, reason.as_str(), now)?;
+            updated.released_unix_ms = Some(now);
+            updated.release_reason = Some(reason);
+            // The entry stays provisional until the release is persisted, and challenges already
+            // released in this call are still returned, so a storage failure never loses one.
+            if let Err(e) = self.persist(&updated) {
+                if released.is_empty() {
+                    return Err(e);
+                }
+                warn!(
+                    "Failed to persist release of challenge {}, retrying later: {e}",
+                    updated.challenge.challenge_id
+                );
+                break;
+            }
+            *entry = updated;
+
+            let waited = now.saturating_sub(entry.first_seen_unix_ms) as f64 / 1000.0;
+            METRICS.observe(
+                CONFIRMATION_WAIT_METRIC,
+                &[("reason", reason.as_str())],
+                waited,
+            );
+            released.push(entry.clone());
+        }
+        report_states(&entries, self.chain.head());
+        Ok(released)
+    }
+}
+
+/// Sets the state gauges, and with a known `head` the remaining window gauges.
+fn report_states(entries: &BTreeMap<U256, TrackedChallenge>, head: Option<u64>) {
+    let mut counts: BTreeMap<ChallengeState, usize> = BTreeMap::new();
+    for entry in entries.values() {
+        *counts.entry(entry.state).or_default() += 1;
+    }
+    for state in ChallengeState::ALL {
+        let count = counts.get(&state).copied().unwrap_or_default();
+        METRICS.set_gauge(
+            CHALLENGE_STATE_METRIC,
+            &[("state", state.as_str())],
+            count as f64,
+        );
+    }
+    if let Some(head) = head {
+        WindowSummary::of(entries.values(), head).report(&METRICS);
+    }
+}
+
+fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::{AbortReason, process_events};
+    use crate::delegation::DelegationOutcome;
+    use crate::evm::BoxFuture;
+    use crate::fixtures::{ChallengeEventFixture, ChallengeUpdateFixture, OPERATOR, block_hash};
+    use crate::response_window::OracleTarget;
+    use crate::scheduler::{FairScheduler, SchedulerConfig};
+    use crate::state::MemoryStateStore;
+    use crate::tee::{TeeHandler, TeeHandlerConfig};
+    use blueprint_sdk::alloy::primitives::{Address, B256};
+    use std::collections::HashMap;
+
+    /// A chain whose canonical block hashes can be rewritten to simulate reorgs.
+    #[derive(Default)]
+    struct MockChain {
+        head: AtomicU64,
+        hashes: Mutex<HashMap<u64, B256>>,
+    }
+
+    impl EvmClient for MockChain {
+        fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(31337) })
+        }
+
+        fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            let head = self.head.load(Ordering::SeqCst);
+            Box::pin(async move { Ok(head) })
+        }
+
+        fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+            let hash = self.hashes.lock().unwrap().get(&number).copied();
+            Box::pin(async move { Ok(hash) })
+        }
+
+        fn block_timestamp(
+            &self,
+            _number: u64,
+        ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn is_operator_registered(
+            &self,
+            _operator: Address,
+        ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+            Box::pin(async { Ok(true) })
+        }
+    }
+
+    fn challenge(id: u64, block: u64, hash: B256) -> ObservedChallenge {
+        ChallengeEventFixture::new()
+            .id(id)
+            .block(block)
+            .block_hash(Some(hash))
+            .build_observed()
+    }
+
+    #[tokio::test]
+    async fn surviving_challenge_is_released_once_and_orphan_is_dropped() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker =
+            ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap();
+        let chain = MockChain::default();
+        chain
+            .hashes
+            .lock()
+            .unwrap()
+            .insert(10, B256::repeat_byte(0xaa));
+        chain
+            .hashes
+            .lock()
+            .unwrap()
+            .insert(11, B256::repeat_byte(0xbb));
+
+        assert!(
+            tracker
+                .observe(challenge(1, 10, B256::repeat_byte(0xaa)), 10)
+                .unwrap()
+        );
+        assert!(
+            tracker
+                .observe(challenge(2, 11, B256::repeat_byte(0xbb)), 11)
+                .unwrap()
+        );
+        assert!(
+            !tracker
+                .observe(challenge(1, 10, B256::repeat_byte(0xaa)), 10)
+                .unwrap()
+        );
+
+        // Nothing is released before the confirmation depth.
+        assert!(tracker.release_ready(11, |_| 5).unwrap().is_empty());
+
+        // Block 11 is reorged away.
+        chain
+            .hashes
+            .lock()
+            .unwrap()
+            .insert(11, B256::repeat_byte(0xcc));
+        let orphaned = tracker.reconcile(&chain).await.unwrap();
+        assert_eq!(
+            orphaned.iter().map(|c| c.challenge_id).collect::<Vec<_>>(),
+            vec![U256::from(2)]
+        );
+        let orphan = tracker.get(&U256::from(2)).unwrap();
+        assert_eq!(orphan.state, ChallengeState::Invalid);
+        assert_eq!(store.scan(TRACKER_NAMESPACE).unwrap().len(), 1);
+        // Seen again in the replacement block, it starts a new lifecycle.
+        assert!(
+            tracker
+                .observe(challenge(2, 11, B256::repeat_byte(0xcc)), 12)
+                .unwrap()
+        );
+        assert_eq!(
+            tracker.get(&U256::from(2)).unwrap().state,
+            ChallengeState::Provisional
+        );
+
+        let released = tracker.release_ready(12, |_| 5).unwrap();
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].release_reason, Some(ReleaseReason::Confirmed));
+        let released = tracker.release_ready(13, |_| 5).unwrap();
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].challenge.challenge_id, U256::from(2));
+        assert!(tracker.release_ready(14, |_| 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn deadline_forces_early_release() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let policy = ConfirmationPolicy {
+            confirmations: 100,
+            early_submit_multiplier: 2.0,
+        };
+        let tracker = ChallengeTracker::new(policy, store).unwrap();
+        tracker.observe(challenge(1, 10, B256::ZERO), 10).unwrap();
+
+        assert!(tracker.release_ready(49, |_| 5).unwrap().is_empty());
+        let released = tracker.release_ready(50, |_| 5).unwrap();
+        assert_eq!(
+            released[0].release_reason,
+            Some(ReleaseReason::DeadlineForced)
+        );
+    }
+
+    #[test]
+    fn completed_challenges_spill_past_budget_and_active_ones_stay() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let budgets = Arc::new(MemoryBudgets::default());
+        let tracker = ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store))
+            .unwrap()
+            .with_budgets(Arc::clone(&budgets));
+        let observe = |id: u64, block: u64| {
+            let challenge = ChallengeEventFixture::new()
+                .id(id)
+                .block(block)
+                .window(10)
+                .block_hash(Some(B256::repeat_byte(id as u8)))
+                .build_observed();
+            tracker.observe(challenge, block).unwrap();
+        };
+        // Ten challenges complete (deadline 20), two are released but still in their window
+        // (deadline 110) and one is provisional.
+        for id in 1..=10 {
+            observe(id, 10);
+        }
+        tracker.release_ready(12, |_| 0).unwrap();
+        observe(11, 100);
+        observe(12, 100);
+        tracker.release_ready(102, |_| 0).unwrap();
+        observe(13, 101);
+        // Challenge 1 is the most recently used completed one.
+        tracker.get(&U256::from(1)).unwrap();
+
+        let per_entry = tracker.get(&U256::from(1)).unwrap().approx_size() as u64;
+        budgets
+            .set_budgets(BTreeMap::from([(
+                CHALLENGE_TRACKER.to_string(),
+                5 * per_entry,
+            )]))
+            .unwrap();
+        assert_eq!(tracker.enforce_budget(102).unwrap(), 8);
+        assert_eq!(store.scan(ARCHIVE_NAMESPACE).unwrap().len(), 8);
+        assert_eq!(tracker.snapshot().len(), 5);
+        assert!(
+            tracker
+                .snapshot()
+                .iter()
+                .any(|e| e.challenge.challenge_id == U256::from(1))
+        );
+
+        // Spilled challenges are still found, and not tracked afresh when seen again.
+        let spilled = tracker.get(&U256::from(2)).unwrap();
+        assert_eq!(spilled.state, ChallengeState::Queued);
+        observe(2, 10);
+        assert_eq!(tracker.snapshot().len(), 5);
+
+        // Under any pressure, only completed challenges go.
+        budgets
+            .set_budgets(BTreeMap::from([(CHALLENGE_TRACKER.to_string(), 0)]))
+            .unwrap();
+        assert_eq!(tracker.enforce_budget(102).unwrap(), 2);
+        let active: Vec<_> = tracker
+            .snapshot()
+            .iter()
+            .map(|e| e.challenge.challenge_id)
+            .collect();
+        assert_eq!(active, [11, 12, 13].map(U256::from));
+        assert_eq!(
+            budgets.snapshot()[CHALLENGE_TRACKER].used_bytes,
+            3 * per_entry
+        );
+    }
+
+    #[test]
+    fn random_walks_follow_the_state_machine_and_survive_restarts() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker =
+            ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap();
+        let mut rng = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = || {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng
+        };
+        for id in 1..=50u64 {
+            tracker
+                .observe(challenge(id, 10, B256::repeat_byte(id as u8)), 10)
+                .unwrap();
+            let id = U256::from(id);
+            for _ in 0..20 {
+                let from = tracker.get(&id).unwrap().state;
+                let to = ChallengeState::ALL[(next() % ChallengeState::ALL.len() as u64) as usize];
+                let moved = tracker.transition(id, to, "random walk");
+                assert_eq!(moved.is_ok(), from.can_transition_to(to), "{from} -> {to}");
+                let expected = if moved.is_ok() { to } else { from };
+                assert_eq!(tracker.get(&id).unwrap().state, expected);
+            }
+        }
+
+        let restored = ChallengeTracker::new(ConfirmationPolicy::default(), store).unwrap();
+        for entry in tracker.snapshot() {
+            let history = restored.history(&entry.challenge.challenge_id).unwrap();
+            assert_eq!(history, entry.history);
+            assert_eq!(history[0].from, None);
+            for pair in history.windows(2) {
+                assert_eq!(pair[1].from, Some(pair[0].to));
+                assert!(pair[0].to.can_transition_to(pair[1].to));
+            }
+            assert_eq!(history.last().unwrap().to, entry.state);
+        }
+    }
+
+    #[test]
+    fn records_without_history_are_migrated() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let mut legacy = serde_json::to_value(TrackedChallenge {
+            challenge: challenge(1, 10, B256::ZERO),
+            state: ChallengeState::Queued,
+            first_seen_unix_ms: 1_000,
+            released_unix_ms: Some(5_000),
+            release_reason: Some(ReleaseReason::DeadlineForced),
+            history: Vec::new(),
+            detection_delay_blocks: None,
+            urgent: false,
+            delegation: None,
+            amendments: Vec::new(),
+            cancellation: None,
+        })
+        .unwrap();
+        legacy["state"] = "confirmed".into();
+        let legacy_fields = legacy.as_object_mut().unwrap();
+        for field in [
+            "history",
+            "detection_delay_blocks",
+            "urgent",
+            "delegation",
+            "amendments",
+            "cancellation",
+        ] {
+            legacy_fields.remove(field);
+        }
+        store
+            .put_json(
+                TRACKER_NAMESPACE,
+                &U256::from(1).to_be_bytes::<32>(),
+                &legacy,
+            )
+            .unwrap();
+
+        let tracker =
+            ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap();
+        let entry = tracker.get(&U256::from(1)).unwrap();
+        assert_eq!(entry.state, ChallengeState::Queued);
+        let moves: Vec<_> = entry.history.iter().map(|t| (t.to, t.unix_ms)).collect();
+        assert_eq!(moves, [
+            (ChallengeState::Seen, 1_000),
+            (ChallengeState::Provisional, 1_000),
+            (ChallengeState::Queued, 5_000)
+        ]);
+        let persisted: TrackedChallenge = store
+            .get_json(TRACKER_NAMESPACE, &U256::from(1).to_be_bytes::<32>())
+            .unwrap()
+            .unwrap();
+        assert_eq!(persisted.history, entry.history);
+        tracker
+            .transition(U256::from(1), ChallengeState::Building, "worker")
+            .unwrap();
+    }
+
+    /// Challenges 1 and 2 issued in blocks 10 and 11, released at head 20 into a queue.
+    async fn queued() -> (
+        ChallengeTracker,
+        MockChain,
+        TeeHandler,
+        FairScheduler<TrackedChallenge>,
+    ) {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker = ChallengeTracker::new(ConfirmationPolicy::default(), store).unwrap();
+        let chain = MockChain::default();
+        for block in [10, 11] {
+            chain
+                .hashes
+                .lock()
+                .unwrap()
+                .insert(block, block_hash(block));
+        }
+        chain.head.store(20, Ordering::SeqCst);
+        let tee = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        let events = [10, 11].map(|block| {
+            ChallengeEventFixture::new()
+                .id(block - 9)
+                .block(block)
+                .build_log()
+        });
+        let processed = process_events(&tracker, &chain, &tee, OPERATOR, &events, true, |_| 3)
+            .await
+            .unwrap();
+        let mut queue = FairScheduler::new(SchedulerConfig::default());
+        for entry in processed.ready {
+            let target = OracleTarget::new(31337, entry.challenge.oracle);
+            queue.push(target, entry.challenge.deadline_block, 0, entry);
+        }
+        assert_eq!(queue.len(), 2);
+        (tracker, chain, tee, queue)
+    }
+
+    #[tokio::test]
+    async fn cancellation_before_build_settles_the_challenge() {
+        let (tracker, chain, tee, mut queue) = queued().await;
+        let cancel = [ChallengeUpdateFixture::cancelled(1).block(20).build_log()];
+        let processed = process_events(&tracker, &chain, &tee, OPERATOR, &cancel, true, |_| 3)
+            .await
+            .unwrap();
+
+        assert_eq!(processed.cancelled.len(), 1);
+        let cancelled = tracker.get(&U256::from(1)).unwrap();
+        assert_eq!(cancelled.state, ChallengeState::Cancelled);
+        let cancellation = cancelled.cancellation.unwrap();
+        assert_eq!(
+            (cancellation.block, cancellation.from),
+            (20, ChallengeState::Queued)
+        );
+        queue.retain(|entry| entry.challenge.challenge_id != U256::from(1));
+        assert_eq!(
+            queue
+                .pop(20, 0)
+                .map(|next| next.item.challenge.challenge_id),
+            Some(U256::from(2))
+        );
+        assert!(queue.is_empty());
+        // Cancelling a settled challenge again changes nothing.
+        assert!(tracker.cancel(&U256::from(1), 21).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn cancellation_during_build_aborts_it() {
+        let (tracker, chain, tee, _) = queued().await;
+        let id = U256::from(1);
+        let build = tracker.start_build(id);
+        tracker
+            .transition(id, ChallengeState::Building, "worker")
+            .unwrap();
+        assert_eq!(build.aborted(), None);
+
+        // Another oracle cannot cancel the challenge.
+        let foreign = [ChallengeUpdateFixture::cancelled(1)
+            .oracle(Address::repeat_byte(0xee))
+            .build_log()];
+        process_events(&tracker, &chain, &tee, OPERATOR, &foreign, true, |_| 3)
+            .await
+            .unwrap();
+        assert_eq!(build.aborted(), None);
+
+        let cancel = [ChallengeUpdateFixture::cancelled(1).block(20).build_log()];
+        process_events(&tracker, &chain, &tee, OPERATOR, &cancel, true, |_| 3)
+            .await
+            .unwrap();
+        assert_eq!(build.aborted(), Some(AbortReason::Cancelled));
+        let cancelled = tracker.get(&id).unwrap();
+        assert_eq!(cancelled.state, ChallengeState::Cancelled);
+        assert_eq!(
+            cancelled.cancellation.unwrap().from,
+            ChallengeState::Building
+        );
+        // The other challenge's build is untouched.
+        assert_eq!(tracker.start_build(U256::from(2)).aborted(), None);
+    }
+
+    #[tokio::test]
+    async fn cancellation_after_broadcast_records_the_withdrawal() {
+        let (tracker, chain, tee, _) = queued().await;
+        let id = U256::from(1);
+        for state in [
+            ChallengeState::Building,
+            ChallengeState::Submitting,
+            ChallengeState::AwaitingInclusion,
+        ] {
+            tracker.transition(id, state, "worker").unwrap();
+        }
+        let cancel = [ChallengeUpdateFixture::cancelled(1).block(20).build_log()];
+        let processed = process_events(&tracker, &chain, &tee, OPERATOR, &cancel, true, |_| 3)
+            .await
+            .unwrap();
+        let cancellation = processed.cancelled[0].cancellation.clone().unwrap();
+        assert_eq!(cancellation.from, ChallengeState::AwaitingInclusion);
+
+        let withdrawal = Withdrawal::Replaced {
+            nonce: 4,
+            tx_hash: B256::repeat_byte(0x44),
+        };
+        tracker.record_withdrawal(&id, withdrawal.clone()).unwrap();
+        let restored = ChallengeTracker::new(ConfirmationPolicy::default(), tracker.store.clone())
+            .unwrap()
+            .get(&id)
+            .unwrap();
+        assert_eq!(restored.state, ChallengeState::Cancelled);
+        assert_eq!(restored.cancellation.unwrap().withdrawal, Some(withdrawal));
+    }
+
+    #[tokio::test]
+    async fn amendment_mid_queue_requeues_with_the_new_deadline() {
+        let (tracker, chain, tee, mut queue) = queued().await;
+        let id = U256::from(1);
+        let stale = tracker.get(&id).unwrap();
+        tracker
+            .record_delegation(&id, DelegationRecord {
+                workload_id: B256::ZERO,
+                outcome: DelegationOutcome::Fallback,
+                reason: None,
+                elapsed_ms: 1,
+                unix_ms: 1,
+            })
+            .unwrap();
+        let deadline = stale.challenge.deadline_block + 100;
+        let amend = [
+            ChallengeUpdateFixture::amended(1, b"amended".to_vec(), deadline)
+                .block(20)
+                .build_log(),
+        ];
+        let processed = process_events(&tracker, &chain, &tee, OPERATOR, &amend, true, |_| 3)
+            .await
+            .unwrap();
+
+        let amended = tracker.get(&id).unwrap();
+        assert_eq!(processed.amended.len(), 1);
+        assert_eq!(amended.state, ChallengeState::Queued);
+        assert_eq!(amended.challenge.challenge_data.as_ref(), b"amended");
+        assert_eq!(amended.challenge.deadline_block, deadline);
+        assert_eq!((amended.revision(), stale.revision()), (1, 0));
+        assert!(amended.delegation.is_none());
+        let amendment = &amended.amendments[0];
+        assert_eq!(
+            amendment.previous_deadline_block,
+            stale.challenge.deadline_block
+        );
+        assert_eq!(amended.latency_origin_ms(), amendment.unix_ms);
+        // The same amendment seen again is not applied twice.
+        process_events(&tracker, &chain, &tee, OPERATOR, &amend, true, |_| 3)
+            .await
+            .unwrap();
+        assert_eq!(tracker.get(&id).unwrap().revision(), 1);
+
+        // Requeued behind challenge 2, whose deadline is now the earlier one.
+        queue.retain(|entry| entry.challenge.challenge_id != id);
+        for entry in processed.amended {
+            let target = OracleTarget::new(31337, entry.challenge.oracle);
+            queue.push(target, entry.challenge.deadline_block, 0, entry);
+        }
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop(20, 0))
+            .map(|next| (next.item.challenge.challenge_id, next.deadline_block))
+            .collect();
+        assert_eq!(order, [
+            (U256::from(2), stale.challenge.deadline_block + 1),
+            (id, deadline)
+        ]);
+    }
+}
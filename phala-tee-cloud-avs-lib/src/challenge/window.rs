@@ -0,0 +1,389 @@
+//! How many blocks each pending challenge has left to be answered.
+//!
+//! The remaining window of a challenge is its deadline block less the chain head, zero once the
+//! head reaches the deadline. The tracker derives it from the head in its [`ChainClock`] on every
+//! transition and every head it is told about, exports it per challenge as
+//! `phala_avs_challenge_remaining_window_blocks`, and the smallest one as
+//! `phala_avs_challenge_min_remaining_window_blocks`. Every challenge that is not settled counts,
+//! provisional ones and those held back from submission included; with none pending the summary
+//! gauge reads [`NOTHING_PENDING`].
+
+use super::state::ChallengeState;
+use super::tracker::TrackedChallenge;
+use crate::error::PhalaAvsError;
+use crate::metrics::MetricsRegistry;
+use crate::response_window::SubmissionUrgency;
+use blueprint_sdk::alloy::primitives::{Address, U256};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Gauge of the blocks left to answer each pending challenge, by `challenge_id`.
+pub const REMAINING_WINDOW_METRIC: &str = "phala_avs_challenge_remaining_window_blocks";
+
+/// Gauge of the fewest blocks left to answer any pending challenge.
+pub const MIN_REMAINING_WINDOW_METRIC: &str = "phala_avs_challenge_min_remaining_window_blocks";
+
+/// What [`MIN_REMAINING_WINDOW_METRIC`] reads with no challenge pending. Alerts on a low window
+/// should only consider values of zero and above.
+pub const NOTHING_PENDING: f64 = -1.0;
+
+/// The latest chain head seen by any job. It only moves forward, so a job that read the head
+/// before another cannot wind it back.
+#[derive(Debug, Default)]
+pub struct ChainClock {
+    head: AtomicU64,
+}
+
+impl ChainClock {
+    pub fn observe(&self, head: u64) {
+        self.head.fetch_max(head, Ordering::Relaxed);
+    }
+
+    /// The latest head, `None` until one was observed.
+    pub fn head(&self) -> Option<u64> {
+        Some(self.head.load(Ordering::Relaxed)).filter(|&head| head > 0)
+    }
+}
+
+/// Blocks left before `deadline_block` at `head`.
+pub fn remaining_blocks(deadline_block: u64, head: u64) -> u64 {
+    deadline_block.saturating_sub(head)
+}
+
+/// The remaining window of one pending challenge.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemainingWindow {
+    pub challenge_id: U256,
+    pub oracle: Address,
+    pub state: ChallengeState,
+    pub deadline_block: u64,
+    pub remaining_blocks: u64,
+    /// Detected too late to wait for confirmations.
+    pub urgent: bool,
+    /// The oracle's current inclusion safety margin, when the view was built with one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safety_margin: Option<u64>,
+}
+
+impl RemainingWindow {
+    pub fn of(entry: &TrackedChallenge, head: u64) -> Self {
+        Self {
+            challenge_id: entry.challenge.challenge_id,
+            oracle: entry.challenge.oracle,
+            state: entry.state,
+            deadline_block: entry.challenge.deadline_block,
+            remaining_blocks: entry.remaining_blocks(head),
+            urgent: entry.urgent,
+            safety_margin: None,
+        }
+    }
+
+    /// How the window compares to the safety margin, as the submission path classifies it.
+    pub fn urgency(&self) -> Option<SubmissionUrgency> {
+        let margin = self.safety_margin?;
+        Some(if self.remaining_blocks < margin {
+            SubmissionUrgency::TooLate
+        } else if self.remaining_blocks < margin.saturating_mul(2) {
+            SubmissionUrgency::Escalate
+        } else {
+            SubmissionUrgency::Comfortable
+        })
+    }
+}
+
+/// The remaining windows of every pending challenge at one head.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowSummary {
+    pub head: u64,
+    /// Blocks left for the most urgent pending challenge; `None` with nothing pending.
+    pub min_remaining_blocks: Option<u64>,
+    /// Pending challenges, most urgent first.
+    pub pending: Vec<RemainingWindow>,
+}
+
+impl WindowSummary {
+    /// Summarizes the challenges among `entries` that are not settled.
+    pub fn of<'a>(entries: impl IntoIterator<Item = &'a TrackedChallenge>, head: u64) -> Self {
+        let mut pending: Vec<RemainingWindow> = entries
+            .into_iter()
+            .filter(|entry| !entry.state.is_settled())
+            .map(|entry| RemainingWindow::of(entry, head))
+            .collect();
+        pending.sort_by_key(|window| (window.remaining_blocks, window.challenge_id));
+        Self {
+            head,
+            min_remaining_blocks: pending.first().map(|window| window.remaining_blocks),
+            pending,
+        }
+    }
+
+    pub fn most_urgent(&self) -> Option<&RemainingWindow> {
+        self.pending.first()
+    }
+
+    /// Replaces the per-challenge gauges with the pending challenges and sets the summary gauge.
+    pub fn report(&self, metrics: &MetricsRegistry) {
+        let ids: Vec<String> = self
+            .pending
+            .iter()
+            .map(|window| window.challenge_id.to_string())
+            .collect();
+        let series: Vec<(Vec<(&str, &str)>, f64)> = self
+            .pending
+            .iter()
+            .zip(&ids)
+            .map(|(window, id)| {
+                (
+                    vec![("challenge_id", id.as_str())],
+                    window.remaining_blocks as f64,
+                )
+            })
+            .collect();
+        metrics.replace_gauges(REMAINING_WINDOW_METRIC, &series);
+        metrics.set_gauge(
+            MIN_REMAINING_WINDOW_METRIC,
+            &[],
+            self.min_remaining_blocks
+                .map_or(NOTHING_PENDING, |blocks| blocks as f64),
+        );
+    }
+
+    /// Renders the pending challenges as a table. Rows are marked `!!` below the safety margin
+    /// and `! ` within twice of it; with `color` the markers are also colored red and yellow.
+    pub fn render_table(&self, color: bool) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "head {}, {} pending, most urgent: {}",
+            self.head,
+            self.pending.len(),
+            self.min_remaining_blocks
+                .map_or("none".to_string(), |blocks| format!("{blocks} blocks")),
+        );
+        if self.pending.is_empty() {
+            return out;
+        }
+        let _ = writeln!(
+            out,
+            "   {:>20}  {:<18}  {:>10}  {:>9}  {:>6}",
+            "challenge", "state", "deadline", "remaining", "margin"
+        );
+        for window in &self.pending {
+            let (marker, ansi) = match window.urgency() {
+                Some(SubmissionUrgency::TooLate) => ("!!", "\x1b[31m"),
+                Some(SubmissionUrgency::Escalate) => ("! ", "\x1b[33m"),
+                _ => ("  ", ""),
+            };
+            let margin = window
+                .safety_margin
+                .map_or("-".to_string(), |margin| margin.to_string());
+            let row = format!(
+                "{marker} {:>20}  {:<18}  {:>10}  {:>9}  {:>6}",
+                window.challenge_id.to_string(),
+                window.state.as_str(),
+                window.deadline_block,
+                window.remaining_blocks,
+                margin
+            );
+            if color && !ansi.is_empty() {
+                let _ = writeln!(out, "{ansi}{row}\x1b[0m");
+            } else {
+                let _ = writeln!(out, "{row}");
+            }
+        }
+        out
+    }
+}
+
+/// The pending challenges of a running operator and their remaining windows.
+pub async fn fetch_windows(
+    operator_url: &str,
+    token: &str,
+) -> Result<WindowSummary, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/challenges", operator_url.trim_end_matches('/')))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(PhalaAvsError::Other(format!(
+            "Operator returned {status} for the pending challenges: {body}"
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to read pending challenges: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::{ChallengeTracker, ConfirmationPolicy};
+    use crate::fixtures::ChallengeEventFixture;
+    use crate::state::MemoryStateStore;
+    use std::sync::Arc;
+
+    fn tracker() -> ChallengeTracker {
+        // Releases only when told to: confirmations never arrive on their own.
+        let policy = ConfirmationPolicy {
+            confirmations: 1_000,
+            early_submit_multiplier: 0.0,
+        };
+        ChallengeTracker::new(policy, Arc::new(MemoryStateStore::default())).unwrap()
+    }
+
+    fn observe(tracker: &ChallengeTracker, id: u64, block: u64, window: u64, head: u64) {
+        let challenge = ChallengeEventFixture::new()
+            .id(id)
+            .block(block)
+            .window(window)
+            .build_observed();
+        assert!(tracker.observe(challenge, head).unwrap());
+    }
+
+    fn remaining(tracker: &ChallengeTracker, id: u64) -> Option<u64> {
+        tracker.remaining_window(&U256::from(id))
+    }
+
+    #[test]
+    fn the_window_is_derived_at_each_lifecycle_point() {
+        let tracker = tracker();
+        assert_eq!(tracker.window_summary(), None);
+
+        // Issued at 100 with 50 blocks to answer, seen at 110.
+        observe(&tracker, 1, 100, 50, 110);
+        assert_eq!(remaining(&tracker, 1), Some(40));
+        let provisional = tracker.window_summary().unwrap();
+        assert_eq!(provisional.pending[0].state, ChallengeState::Provisional);
+
+        tracker.escalate(&U256::from(1)).unwrap();
+        let released = tracker.release_ready(115, |_| 5).unwrap();
+        assert_eq!(released.len(), 1);
+        assert_eq!(remaining(&tracker, 1), Some(35));
+
+        // A worker picks it up a few blocks later.
+        tracker.observe_head(130);
+        let picked = tracker
+            .transition(
+                U256::from(1),
+                ChallengeState::Building,
+                "picked up by a worker",
+            )
+            .unwrap();
+        assert_eq!(picked.remaining_blocks(130), 20);
+        assert_eq!(remaining(&tracker, 1), Some(20));
+
+        // An older head read by a slower job does not wind the clock back.
+        tracker.observe_head(120);
+        assert_eq!(remaining(&tracker, 1), Some(20));
+
+        // Past the deadline but not yet marked missed, nothing is left.
+        tracker.observe_head(160);
+        assert_eq!(remaining(&tracker, 1), Some(0));
+        assert_eq!(
+            tracker.window_summary().unwrap().min_remaining_blocks,
+            Some(0)
+        );
+
+        tracker
+            .transition(U256::from(1), ChallengeState::Missed, "window closed")
+            .unwrap();
+        assert!(tracker.window_summary().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn the_summary_covers_every_pending_state_and_falls_to_the_sentinel() {
+        let tracker = tracker();
+        // Still provisional and the most urgent, released and held back, in flight, and
+        // answered.
+        observe(&tracker, 1, 100, 30, 110);
+        observe(&tracker, 2, 100, 60, 110);
+        observe(&tracker, 3, 100, 80, 110);
+        observe(&tracker, 4, 100, 20, 110);
+        for id in [2, 3, 4] {
+            tracker.escalate(&U256::from(id)).unwrap();
+        }
+        tracker.release_ready(110, |_| 5).unwrap();
+        for (id, to) in [
+            (3, ChallengeState::Building),
+            (3, ChallengeState::Submitting),
+            (3, ChallengeState::AwaitingInclusion),
+            (4, ChallengeState::Building),
+            (4, ChallengeState::Submitting),
+            (4, ChallengeState::AwaitingInclusion),
+            (4, ChallengeState::Responded),
+        ] {
+            tracker.transition(U256::from(id), to, "test").unwrap();
+        }
+
+        let summary = tracker.window_summary().unwrap();
+        let pending: Vec<_> = summary
+            .pending
+            .iter()
+            .map(|w| (w.challenge_id.to::<u64>(), w.state, w.remaining_blocks))
+            .collect();
+        assert_eq!(pending, [
+            (1, ChallengeState::Provisional, 20),
+            (2, ChallengeState::Queued, 50),
+            (3, ChallengeState::AwaitingInclusion, 70),
+        ]);
+        assert_eq!(summary.min_remaining_blocks, Some(20));
+
+        let metrics = MetricsRegistry::default();
+        summary.report(&metrics);
+        assert_eq!(metrics.gauge(MIN_REMAINING_WINDOW_METRIC, &[]), Some(20.0));
+        let gauge = |id: &str| metrics.gauge(REMAINING_WINDOW_METRIC, &[("challenge_id", id)]);
+        assert_eq!(
+            (gauge("1"), gauge("3"), gauge("4")),
+            (Some(20.0), Some(70.0), None)
+        );
+
+        // The provisional challenge is cancelled and the rest settle: nothing is pending.
+        tracker.cancel(&U256::from(1), 111).unwrap();
+        tracker
+            .transition(U256::from(2), ChallengeState::Missed, "test")
+            .unwrap();
+        tracker
+            .transition(U256::from(3), ChallengeState::Responded, "test")
+            .unwrap();
+        let settled = tracker.window_summary().unwrap();
+        assert_eq!(settled.min_remaining_blocks, None);
+        settled.report(&metrics);
+        assert_eq!(
+            metrics.gauge(MIN_REMAINING_WINDOW_METRIC, &[]),
+            Some(NOTHING_PENDING)
+        );
+        assert_eq!(gauge("1"), None);
+        assert_eq!(gauge("3"), None);
+    }
+
+    #[test]
+    fn the_table_marks_tight_windows_without_color_when_piped() {
+        let window = |id: u64, remaining_blocks: u64| RemainingWindow {
+            challenge_id: U256::from(id),
+            oracle: Address::ZERO,
+            state: ChallengeState::Queued,
+            deadline_block: 200 + remaining_blocks,
+            remaining_blocks,
+            urgent: false,
+            safety_margin: Some(10),
+        };
+        let summary = WindowSummary {
+            head: 200,
+            min_remaining_blocks: Some(5),
+            pending: vec![window(1, 5), window(2, 15), window(3, 40)],
+        };
+        let plain = summary.render_table(false);
+        assert!(!plain.contains('\x1b'));
+        let rows: Vec<&str> = plain.lines().skip(2).collect();
+        assert!(rows[0].starts_with("!!"));
+        assert!(rows[1].starts_with("! "));
+        assert!(rows[2].starts_with("  "));
+        assert!(summary.render_table(true).contains("\x1b[31m!!"));
+    }
+}
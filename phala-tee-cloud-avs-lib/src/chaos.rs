@@ -0,0 +1,577 @@
+//! Failure injection for chaos testing the operator pipeline.
+//!
+//! Only compiled with the `chaos` feature. A [`ChaosEngine`] holds a set of [`FaultPolicy`]s and
+//! a seeded random generator, so a run with the same seed and call sequence injects the same
+//! faults. The TEE handler, [`EvmClient`], [`StateStore`] and polled events are wrapped with it;
+//! every injected fault is logged, and every injected error carries [`CHAOS_MARKER`] so tests can
+//! tell injected failures from organic ones. A [`CrashPoint`] armed on the engine panics the
+//! transaction sender between two of its write-ahead steps, standing in for a process crash.
+//!
+//! Policies come from a named profile (`CHAOS_PROFILE`) or a JSON file (`CHAOS_CONFIG`), and can
+//! be replaced at runtime through `PUT /admin/chaos`.
+
+use crate::config::env_opt;
+use crate::error::PhalaAvsError;
+use crate::evm::{BoxFuture, EvmClient};
+use crate::metrics::METRICS;
+use crate::sender::CrashPoint;
+use crate::state::StateStore;
+use blueprint_sdk::alloy::primitives::{Address, B256, LogData};
+use blueprint_sdk::alloy::rpc::types::Log;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Present in the log line of every injected fault and in the message of every injected error.
+pub const CHAOS_MARKER: &str = "chaos-injected";
+
+/// Counter of injected faults by target and kind.
+pub const CHAOS_FAULTS_METRIC: &str = "phala_avs_chaos_faults_total";
+
+/// The component a fault is injected into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultTarget {
+    Tee,
+    Evm,
+    /// Decoded events handed to the challenge pipeline; only [`FaultKind::Corrupt`] applies.
+    Events,
+    /// The aggregator connection.
+    Aggregator,
+    State,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    /// The call fails with an error of the target's kind.
+    Error,
+    /// The call is delayed by `latency_ms`, then proceeds.
+    Latency,
+    /// The payload is corrupted; events only.
+    Corrupt,
+}
+
+/// When and how often a fault is injected into a target.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FaultPolicy {
+    pub target: FaultTarget,
+    pub kind: FaultKind,
+    /// Chance (0..=1) that a call is affected.
+    pub probability: f64,
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// The policy is inactive until this long after the engine was (re)configured.
+    #[serde(default)]
+    pub active_after_secs: u64,
+    /// The policy is inactive again after this long; forever when unset.
+    #[serde(default)]
+    pub active_for_secs: Option<u64>,
+}
+
+impl FaultPolicy {
+    pub fn new(target: FaultTarget, kind: FaultKind, probability: f64) -> Self {
+        Self {
+            target,
+            kind,
+            probability,
+            latency_ms: 0,
+            active_after_secs: 0,
+            active_for_secs: None,
+        }
+    }
+
+    pub fn with_latency_ms(mut self, latency_ms: u64) -> Self {
+        self.latency_ms = latency_ms;
+        self
+    }
+
+    fn is_active(&self, elapsed: Duration) -> bool {
+        let start = Duration::from_secs(self.active_after_secs);
+        elapsed >= start
+            && self
+                .active_for_secs
+                .is_none_or(|d| elapsed < start + Duration::from_secs(d))
+    }
+}
+
+/// A seed plus the fault policies to apply.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChaosConfig {
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default)]
+    pub policies: Vec<FaultPolicy>,
+}
+
+impl ChaosConfig {
+    /// Loads `CHAOS_PROFILE` or the JSON file at `CHAOS_CONFIG`, with `CHAOS_SEED` overriding
+    /// the seed. Without either, no faults are injected until configured through the admin API.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let mut config = match (
+            env_opt::<String>("CHAOS_PROFILE")?,
+            env_opt::<PathBuf>("CHAOS_CONFIG")?,
+        ) {
+            (Some(_), Some(_)) => {
+                return Err(PhalaAvsError::ConfigError(
+                    "CHAOS_PROFILE and CHAOS_CONFIG are mutually exclusive".to_string(),
+                ));
+            }
+            (Some(name), None) => Self::profile(&name).ok_or_else(|| {
+                PhalaAvsError::ConfigError(format!("Unknown chaos profile {name:?}"))
+            })?,
+            (None, Some(path)) => serde_json::from_slice(&std::fs::read(&path)?).map_err(|e| {
+                PhalaAvsError::ConfigError(format!("Invalid chaos config {}: {e}", path.display()))
+            })?,
+            (None, None) => Self::default(),
+        };
+        if let Some(seed) = env_opt("CHAOS_SEED")? {
+            config.seed = seed;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Looks up one of [`default_profiles`] by name.
+    pub fn profile(name: &str) -> Option<Self> {
+        default_profiles()
+            .into_iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, config)| config)
+    }
+
+    pub fn validate(&self) -> Result<(), PhalaAvsError> {
+        for policy in &self.policies {
+            if !(0.0..=1.0).contains(&policy.probability) {
+                return Err(PhalaAvsError::ValidationError(format!(
+                    "fault probability {} is outside 0..=1",
+                    policy.probability
+                )));
+            }
+            if (policy.kind == FaultKind::Corrupt) != (policy.target == FaultTarget::Events) {
+                return Err(PhalaAvsError::ValidationError(format!(
+                    "{:?} faults cannot be injected into {:?}; events only support corruption",
+                    policy.kind, policy.target
+                )));
+            }
+            if policy.kind == FaultKind::Latency && policy.latency_ms == 0 {
+                return Err(PhalaAvsError::ValidationError(
+                    "latency faults need a non-zero latency_ms".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The fault profiles the chaos suite must survive.
+pub fn default_profiles() -> Vec<(&'static str, ChaosConfig)> {
+    use FaultKind::*;
+    use FaultTarget::*;
+    let profile = |seed, policies| ChaosConfig { seed, policies };
+    vec![
+        (
+            "flaky-rpc",
+            profile(1, vec![FaultPolicy::new(Evm, Error, 0.3)]),
+        ),
+        (
+            "slow-rpc",
+            profile(2, vec![
+                FaultPolicy::new(Evm, Latency, 0.5).with_latency_ms(5),
+            ]),
+        ),
+        (
+            "tee-down",
+            profile(3, vec![FaultPolicy::new(Tee, Error, 1.0)]),
+        ),
+        (
+            "flaky-storage",
+            profile(4, vec![FaultPolicy::new(State, Error, 0.2)]),
+        ),
+        (
+            "corrupt-events",
+            profile(5, vec![FaultPolicy::new(Events, Corrupt, 0.3)]),
+        ),
+        (
+            "everything",
+            profile(6, vec![
+                FaultPolicy::new(Evm, Error, 0.2),
+                FaultPolicy::new(Evm, Latency, 0.2).with_latency_ms(2),
+                FaultPolicy::new(Tee, Error, 0.5),
+                FaultPolicy::new(State, Error, 0.1),
+                FaultPolicy::new(Events, Corrupt, 0.2),
+                FaultPolicy::new(Aggregator, Error, 0.5),
+            ]),
+        ),
+    ]
+}
+
+/// SplitMix64; small, seedable and good enough to decide fault injection.
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Debug)]
+struct EngineState {
+    config: ChaosConfig,
+    rng: SplitMix64,
+    configured_at: Instant,
+    injected: BTreeMap<FaultTarget, u64>,
+    armed: BTreeSet<CrashPoint>,
+}
+
+/// Decides, deterministically for a given seed and call order, which calls get faults.
+#[derive(Debug)]
+pub struct ChaosEngine {
+    state: Mutex<EngineState>,
+}
+
+/// The engine's configuration and how many faults it injected per target.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChaosStatus {
+    pub config: ChaosConfig,
+    pub injected: BTreeMap<FaultTarget, u64>,
+}
+
+impl ChaosEngine {
+    pub fn new(config: ChaosConfig) -> Result<Self, PhalaAvsError> {
+        config.validate()?;
+        Ok(Self {
+            state: Mutex::new(EngineState {
+                rng: SplitMix64(config.seed),
+                config,
+                configured_at: Instant::now(),
+                injected: BTreeMap::new(),
+                armed: BTreeSet::new(),
+            }),
+        })
+    }
+
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Self::new(ChaosConfig::from_env()?)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, EngineState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replaces the policies, reseeding the generator and restarting policy time windows.
+    pub fn reconfigure(&self, config: ChaosConfig) -> Result<(), PhalaAvsError> {
+        config.validate()?;
+        let mut state = self.state();
+        state.rng = SplitMix64(config.seed);
+        state.config = config;
+        state.configured_at = Instant::now();
+        Ok(())
+    }
+
+    pub fn status(&self) -> ChaosStatus {
+        let state = self.state();
+        ChaosStatus {
+            config: state.config.clone(),
+            injected: state.injected.clone(),
+        }
+    }
+
+    /// Total number of faults injected into `target`.
+    pub fn injected(&self, target: FaultTarget) -> u64 {
+        self.state()
+            .injected
+            .get(&target)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Rolls every active policy for `target`, returning the faults that fire.
+    fn roll(&self, target: FaultTarget) -> Vec<FaultPolicy> {
+        let mut state = self.state();
+        let elapsed = state.configured_at.elapsed();
+        let candidates: Vec<_> = state
+            .config
+            .policies
+            .iter()
+            .filter(|p| p.target == target && p.is_active(elapsed))
+            .cloned()
+            .collect();
+        let mut fired = Vec::new();
+        for policy in candidates {
+            if state.rng.next_f64() < policy.probability {
+                *state.injected.entry(target).or_default() += 1;
+                fired.push(policy);
+            }
+        }
+        drop(state);
+        for policy in &fired {
+            warn!(
+                "{CHAOS_MARKER}: {:?} fault on {:?} (latency {}ms)",
+                policy.kind, policy.target, policy.latency_ms
+            );
+            METRICS.inc_counter(
+                CHAOS_FAULTS_METRIC,
+                &[
+                    ("target", &format!("{:?}", policy.target).to_lowercase()),
+                    ("kind", &format!("{:?}", policy.kind).to_lowercase()),
+                ],
+                1,
+            );
+        }
+        fired
+    }
+
+    /// Applies latency and error faults for a call into `target`.
+    pub async fn inject(&self, target: FaultTarget) -> Result<(), PhalaAvsError> {
+        for policy in self.roll(target) {
+            match policy.kind {
+                FaultKind::Latency => {
+                    tokio::time::sleep(Duration::from_millis(policy.latency_ms)).await
+                }
+                FaultKind::Error => return Err(injected_error(target)),
+                FaultKind::Corrupt => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`inject`](Self::inject), for synchronous calls; latency blocks the thread.
+    pub fn inject_blocking(&self, target: FaultTarget) -> Result<(), PhalaAvsError> {
+        for policy in self.roll(target) {
+            match policy.kind {
+                FaultKind::Latency => std::thread::sleep(Duration::from_millis(policy.latency_ms)),
+                FaultKind::Error => return Err(injected_error(target)),
+                FaultKind::Corrupt => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the next pass through `point` panic.
+    pub fn arm_crash(&self, point: CrashPoint) {
+        self.state().armed.insert(point);
+    }
+
+    /// Panics, once per arming, when `point` is armed.
+    pub fn crash_if_armed(&self, point: CrashPoint) {
+        if self.state().armed.remove(&point) {
+            warn!("{CHAOS_MARKER}: crash {point:?}");
+            panic!("{CHAOS_MARKER}: crash {point:?}");
+        }
+    }
+
+    /// Truncates the data of the events picked by the [`FaultTarget::Events`] policies, so they
+    /// no longer decode.
+    pub fn corrupt_events(&self, mut events: Vec<Log>) -> Vec<Log> {
+        for event in &mut events {
+            if self
+                .roll(FaultTarget::Events)
+                .iter()
+                .any(|p| p.kind == FaultKind::Corrupt)
+            {
+                let data = &event.inner.data;
+                let truncated = data.data.slice(..data.data.len() / 2);
+                let topics: Vec<B256> = data.topics().to_vec();
+                event.inner.data = LogData::new_unchecked(topics, truncated);
+            }
+        }
+        events
+    }
+}
+
+fn injected_error(target: FaultTarget) -> PhalaAvsError {
+    let message = format!("{CHAOS_MARKER}: {target:?} call failed");
+    match target {
+        FaultTarget::Tee => PhalaAvsError::TeeError(message),
+        FaultTarget::Evm => PhalaAvsError::EvmError(message),
+        FaultTarget::Events => PhalaAvsError::ValidationError(message),
+        FaultTarget::Aggregator => PhalaAvsError::AggregatorError(message),
+        FaultTarget::State => PhalaAvsError::StorageError(message),
+    }
+}
+
+/// Whether `error` was injected by a [`ChaosEngine`].
+pub fn is_injected(error: &PhalaAvsError) -> bool {
+    error.to_string().contains(CHAOS_MARKER)
+}
+
+/// [`EvmClient`] injecting [`FaultTarget::Evm`] faults before delegating.
+pub struct ChaosEvmClient {
+    inner: Arc<dyn EvmClient>,
+    engine: Arc<ChaosEngine>,
+}
+
+impl ChaosEvmClient {
+    pub fn new(inner: Arc<dyn EvmClient>, engine: Arc<ChaosEngine>) -> Self {
+        Self { inner, engine }
+    }
+}
+
+impl EvmClient for ChaosEvmClient {
+    fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            self.engine.inject(FaultTarget::Evm).await?;
+            self.inner.chain_id().await
+        })
+    }
+
+    fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            self.engine.inject(FaultTarget::Evm).await?;
+            self.inner.block_number().await
+        })
+    }
+
+    fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+        Box::pin(async move {
+            self.engine.inject(FaultTarget::Evm).await?;
+            self.inner.block_hash(number).await
+        })
+    }
+
+    fn block_timestamp(&self, number: u64) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+        Box::pin(async move {
+            self.engine.inject(FaultTarget::Evm).await?;
+            self.inner.block_timestamp(number).await
+        })
+    }
+
+    fn is_operator_registered(
+        &self,
+        operator: Address,
+    ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+        Box::pin(async move {
+            self.engine.inject(FaultTarget::Evm).await?;
+            self.inner.is_operator_registered(operator).await
+        })
+    }
+}
+
+/// [`StateStore`] injecting [`FaultTarget::State`] faults before delegating.
+pub struct ChaosStateStore {
+    inner: Arc<dyn StateStore>,
+    engine: Arc<ChaosEngine>,
+}
+
+impl ChaosStateStore {
+    pub fn new(inner: Arc<dyn StateStore>, engine: Arc<ChaosEngine>) -> Self {
+        Self { inner, engine }
+    }
+}
+
+impl fmt::Debug for ChaosStateStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChaosStateStore")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StateStore for ChaosStateStore {
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, PhalaAvsError> {
+        self.engine.inject_blocking(FaultTarget::State)?;
+        self.inner.get(namespace, key)
+    }
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), PhalaAvsError> {
+        self.engine.inject_blocking(FaultTarget::State)?;
+        self.inner.put(namespace, key, value)
+    }
+
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<(), PhalaAvsError> {
+        self.engine.inject_blocking(FaultTarget::State)?;
+        self.inner.delete(namespace, key)
+    }
+
+    fn scan(&self, namespace: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        self.engine.inject_blocking(FaultTarget::State)?;
+        self.inner.scan(namespace)
+    }
+
+    fn scan_range(
+        &self,
+        namespace: &str,
+        from: &[u8],
+        to: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        self.engine.inject_blocking(FaultTarget::State)?;
+        self.inner.scan_range(namespace, from, to)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>, PhalaAvsError> {
+        self.engine.inject_blocking(FaultTarget::State)?;
+        self.inner.namespaces()
+    }
+
+    fn size(&self, namespace: &str) -> Result<u64, PhalaAvsError> {
+        self.engine.inject_blocking(FaultTarget::State)?;
+        self.inner.size(namespace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fired(engine: &ChaosEngine, n: usize) -> Vec<bool> {
+        (0..n)
+            .map(|_| engine.inject_blocking(FaultTarget::Evm).is_err())
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_injects_same_faults() {
+        let config = ChaosConfig::profile("flaky-rpc").unwrap();
+        let a = ChaosEngine::new(config.clone()).unwrap();
+        let b = ChaosEngine::new(config.clone()).unwrap();
+        let run = fired(&a, 200);
+        assert_eq!(run, fired(&b, 200));
+        let count = run.iter().filter(|f| **f).count();
+        assert!((30..90).contains(&count), "{count} faults");
+        assert_eq!(a.injected(FaultTarget::Evm), count as u64);
+
+        // Reconfiguring reseeds.
+        a.reconfigure(config).unwrap();
+        assert_eq!(run, fired(&a, 200));
+
+        let err = a
+            .inject_blocking(FaultTarget::Evm)
+            .err()
+            .or_else(|| (0..100).find_map(|_| a.inject_blocking(FaultTarget::Evm).err()))
+            .unwrap();
+        assert!(is_injected(&err));
+        assert!(!is_injected(&PhalaAvsError::EvmError(
+            "timeout".to_string()
+        )));
+    }
+
+    #[test]
+    fn invalid_policies_and_inactive_windows() {
+        let corrupt_rpc = ChaosConfig {
+            seed: 0,
+            policies: vec![FaultPolicy::new(FaultTarget::Evm, FaultKind::Corrupt, 0.5)],
+        };
+        assert!(ChaosEngine::new(corrupt_rpc).is_err());
+
+        let mut later = FaultPolicy::new(FaultTarget::Evm, FaultKind::Error, 1.0);
+        later.active_after_secs = 3600;
+        let engine = ChaosEngine::new(ChaosConfig {
+            seed: 0,
+            policies: vec![later],
+        })
+        .unwrap();
+        assert!(fired(&engine, 10).iter().all(|f| !f));
+    }
+}
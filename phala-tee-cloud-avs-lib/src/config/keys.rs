@@ -0,0 +1,294 @@
+//! Every setting the operator reads, by environment variable name.
+//!
+//! A key missing here is rejected in the config file, so a new setting is added to [`KEYS`],
+//! or to [`FAMILIES`] when its name is built at runtime.
+
+/// Settings with a fixed name.
+pub const KEYS: &[&str] = &[
+    "ADMIN_APPROVAL_ALERT_SECS",
+    "ADMIN_APPROVAL_CHECK_SECS",
+    "ADMIN_APPROVAL_THRESHOLD",
+    "ADMIN_APPROVAL_TTL_SECS",
+    "ADMIN_APPROVERS",
+    "ADMIN_TOKEN",
+    "AGGREGATOR_ADMIN_TOKEN",
+    "AGGREGATOR_CONFLICT_WINDOW_SECS",
+    "AGGREGATOR_EXCLUDE_AFTER_CONFLICTS",
+    "AGGREGATOR_IDEMPOTENCY_TTL_SECS",
+    "AGGREGATOR_RESPONSE_RETENTION_SECS",
+    "AGGREGATOR_STATE_BACKEND",
+    "ALERT_WEBHOOK_URL",
+    "ANNOTATION_MAX_NOTE_BYTES",
+    "ANNOTATION_MAX_PER_ENTITY",
+    "API_AUDIT_LIMIT",
+    "API_KEYS_FILE",
+    "API_KEYS_RELOAD_SECS",
+    "API_KEY_RATE_LIMIT_PER_MIN",
+    "API_PUBLIC_PROBES",
+    "ARTIFACTS_TOKEN",
+    "ARTIFACT_DISPUTE_WINDOW_BLOCKS",
+    "ARTIFACT_EPOCH_BLOCKS",
+    "ARTIFACT_PRUNE_SECS",
+    "ARTIFACT_PUBLIC_URL",
+    "ARTIFACT_RETENTION_EPOCHS",
+    "ATTESTATION_SELF_CHECK_SECS",
+    "CAPACITY_CHECK_SECS",
+    "CAPACITY_HYSTERESIS_PCT",
+    "CAPACITY_REPORTING_ENABLED",
+    "CHALLENGE_CONFIRMATIONS",
+    "CHALLENGE_EARLY_SUBMIT_MULTIPLIER",
+    "CHAOS_CONFIG",
+    "CHAOS_PROFILE",
+    "CHAOS_SEED",
+    "COLLATERAL_CHECK_ENABLED",
+    "COLLATERAL_CHECK_SECS",
+    "COLLATERAL_CPU_SVN",
+    "COLLATERAL_FMSPC",
+    "COLLATERAL_PCCS_URL",
+    "COLLATERAL_PCE_SVN",
+    "COLLATERAL_PCS_URL",
+    "COLLATERAL_QUOTE_TTL_SECS",
+    "COLLATERAL_TEE_TCB_SVN",
+    "CURSOR_CATCHUP_LOG_EVERY",
+    "CURSOR_CATCHUP_MODE",
+    "CURSOR_CATCHUP_SKIP_MARGIN_BLOCKS",
+    "CURSOR_LEGACY_FILE",
+    "DCAP_COLLATERAL_DIR",
+    "DCAP_ROOT_CA_PATH",
+    "DELEGATION_BUDGET_MS",
+    "DELEGATION_KINDS",
+    "DELEGATION_MAX_PAYLOAD_BYTES",
+    "DETECTION_DELAY_WARN_BLOCKS",
+    "DIAGNOSTICS_UPLOAD_CHUNK_BYTES",
+    "DIAGNOSTICS_UPLOAD_URL",
+    "DISK_BUDGET_BYTES",
+    "DISK_CHECK_SECS",
+    "DISK_CRITICAL_PCT",
+    "DISK_WARN_PCT",
+    "DRIFT_CHECK_ENABLED",
+    "DRIFT_CHECK_SECS",
+    "DRIFT_MAX_REDEPLOYS",
+    "DRIFT_STOP_GRACE_SECS",
+    "DRIFT_UNASSIGNED_ACTION",
+    "DUTY_ATTESTATION_INTERVAL_SECS",
+    "DUTY_BLOCK_TIME_MS",
+    "DUTY_BURST_SPREAD_BLOCKS",
+    "DUTY_CHECK_SECS",
+    "DUTY_COLLATERAL_LEAD_SECS",
+    "DUTY_EPOCH_BLOCKS",
+    "DUTY_EPOCH_OFFSET_BLOCKS",
+    "DUTY_GAS_LEAD_SECS",
+    "DUTY_HISTORY_EPOCHS",
+    "DUTY_HORIZON_SECS",
+    "DUTY_QUOTE_LEAD_SECS",
+    "DUTY_QUOTE_MAX_AGE_SECS",
+    "DUTY_RESPONSE_COST_WEI",
+    "DUTY_STAKE_LEAD_SECS",
+    "DUTY_SUMMARY_LEAD_SECS",
+    "ENCODER_ROLLOUT",
+    "ENCODER_ROLLOUT_PROMOTION_SAMPLES",
+    "EVIDENCE_ANCHOR_CHECK_SECS",
+    "EVIDENCE_ANCHOR_ENABLED",
+    "EVIDENCE_ANCHOR_WINDOW_SECS",
+    "EVIDENCE_CADENCE_HISTORY_SECS",
+    "EVIDENCE_CADENCE_MIN_PUSHES",
+    "EVIDENCE_CADENCE_SECS",
+    "EVIDENCE_GAP_CHECK_ENABLED",
+    "EVIDENCE_GAP_CHECK_SECS",
+    "EVIDENCE_GAP_MIN_SECS",
+    "EVIDENCE_GAP_MULTIPLE",
+    "EVIDENCE_INGEST_MAX_PAYLOAD_BYTES",
+    "EVIDENCE_INGEST_QUEUE_CAPACITY",
+    "EVIDENCE_INGEST_RATE_LIMIT_PER_MIN",
+    "EVIDENCE_INGEST_RETRY_AFTER_SECS",
+    "EVIDENCE_INGEST_TOKEN_GRACE_SECS",
+    "EXIT_CHECK_SECS",
+    "EXIT_QUORUMS",
+    "FORCE_SUBMIT_WHEN_UNREGISTERED",
+    "HEARTBEAT_PERIOD_SECS",
+    "HEARTBEAT_PHASE_SPREAD",
+    "HEARTBEAT_WATCHDOG_CHECK_SECS",
+    "HEARTBEAT_WATCHDOG_ESCALATE_AFTER",
+    "INDEX_REGISTRY_ADDRESS",
+    "LOG_CHECK_ENABLED",
+    "LOG_CHECK_LAG_BLOCKS",
+    "LOG_CHECK_MAX_BLOCKS",
+    "LOG_CHECK_RETRY_DELAY_MS",
+    "LOG_CHECK_RPC_URLS",
+    "LOG_CHECK_SAMPLE_RATE",
+    "LOG_RING_CAPACITY",
+    "LOG_RING_LEVEL",
+    "MAINTENANCE_MAX_DURATION_SECS",
+    "MAINTENANCE_MIN_NOTICE_SECS",
+    "MAINTENANCE_SIGNER_ENABLED",
+    "MAINTENANCE_SIGNER_FALLBACK",
+    "MAINTENANCE_SIGNER_KEY",
+    "MEMORY_PRESSURE_WARN_SECS",
+    "MULTICALL_ADDRESS",
+    "OPERATOR_SET_HISTORY_LIMIT",
+    "OPERATOR_SET_REFRESH_SECS",
+    "OPERATOR_SET_WARN_SHARE_BPS",
+    "PHALA_CLOUD_API_KEY",
+    "PHALA_CLOUD_API_URL",
+    "PHALA_CLOUD_BOOT_TIMEOUT_SECS",
+    "PHALA_CLOUD_LIST_LIMIT",
+    "PHALA_CLOUD_POLL_INTERVAL_MS",
+    "PHALA_CLOUD_TRANSITION_TIMEOUT_SECS",
+    "PRIVATE_KEY",
+    "QUORUM_THRESHOLD_BPS",
+    "RECEIPT_VERIFY_CHECK_SECS",
+    "REGISTRATION_CHECK_SECS",
+    "REGISTRY_COORDINATOR_ADDRESS",
+    "REIMBURSEMENT_CHECK_SECS",
+    "REIMBURSEMENT_CLAIM_COST_MULTIPLE",
+    "REIMBURSEMENT_CLAIM_ENABLED",
+    "REIMBURSEMENT_LOOKBACK_BLOCKS",
+    "REIMBURSEMENT_MIN_CLAIM_WEI",
+    "REMOTE_WRITE_BUFFER_SAMPLES",
+    "REMOTE_WRITE_EXTERNAL_LABELS",
+    "REMOTE_WRITE_INTERVAL_SECS",
+    "REMOTE_WRITE_MAX_SAMPLES_PER_SEND",
+    "REMOTE_WRITE_TOKEN",
+    "REMOTE_WRITE_URL",
+    "REPLICA_DIVERGENCE_GRACE_MS",
+    "REPLICA_ID",
+    "REPLICA_LEASE",
+    "REPLICA_LEASE_RENEW_MS",
+    "REPLICA_LEASE_TTL_MS",
+    "REPUTATION_BLOCK_SECS",
+    "REPUTATION_EPOCH_BLOCKS",
+    "REPUTATION_WINDOWS_DAYS",
+    "RESPONSE_DOMAIN_PROBE_SECS",
+    "RESPONSE_DOMAIN_SUSPEND_AFTER",
+    "RESPONSE_MARGIN_BASE_FEE_WINDOW",
+    "RESPONSE_MARGIN_FLOOR_BLOCKS",
+    "RESPONSE_MARGIN_MAX_SAMPLES",
+    "RESPONSE_MARGIN_MIN_SAMPLES",
+    "RESPONSE_MARGIN_QUANTILE",
+    "RESPONSE_MARGIN_STATIC_BLOCKS",
+    "RESPONSE_SAFETY_AGGREGATOR_URL",
+    "RESPONSE_SAFETY_MAJORITY_BPS",
+    "RESPONSE_SAFETY_MIN_OBSERVATIONS",
+    "RESPONSE_SAFETY_PEERS",
+    "RESPONSE_SAFETY_PEER_TOKEN",
+    "RESPONSE_SAFETY_SKIP_KINDS",
+    "RESPONSE_SAFETY_TIMEOUT_MS",
+    "RESPONSE_SCHEDULER_STARVATION_SECS",
+    "RESPONSE_SCHEDULER_URGENT_BLOCKS",
+    "RESPONSE_SCHEDULER_WEIGHTS",
+    "RESTART_DRAIN_TIMEOUT_SECS",
+    "RESTART_EXPECTED_BLOCKS",
+    "SCHEMA_MANIFEST_SIGNER",
+    "SCHEMA_MANIFEST_URL",
+    "SCHEMA_REFRESH_SECS",
+    "SELF_AUDIT_BATCH_SIZE",
+    "SELF_AUDIT_ENABLED",
+    "SELF_AUDIT_EPOCHS",
+    "SELF_AUDIT_EPOCH_BLOCKS",
+    "SELF_AUDIT_GATE_READINESS",
+    "SELF_AUDIT_MAX_CHALLENGES",
+    "SELF_AUDIT_SCHEDULE",
+    "SERVICE_MANAGER_ADDRESS",
+    "SIGNED_PAYLOAD_SEQUENCE_BLOCK",
+    "SIGNED_PAYLOAD_TTL_SECS",
+    "SIGNER_BALANCE_CHECK_SECS",
+    "SIGNER_MIGRATION_CHECK_SECS",
+    "SIGNER_MIGRATION_SOAK_SECS",
+    "SIGNER_MIN_BALANCE_WEI",
+    "SIGNER_SECONDARY_TOKEN",
+    "SIGNER_SECONDARY_URL",
+    "SIGN_BATCH_MAX",
+    "SIGN_BATCH_WINDOW_MS",
+    "SLA_ORACLE_ADDRESS",
+    "SLO_BLOCK_TIME_MS",
+    "SLO_CHECK_SECS",
+    "SLO_FAST_BURN_RATE",
+    "SLO_FAST_BURN_WINDOW_SECS",
+    "SLO_OBJECTIVES",
+    "SLO_SLOW_BURN_RATE",
+    "SLO_SLOW_BURN_WINDOW_SECS",
+    "STAKE_REGISTRY_ADDRESS",
+    "STARTUP_JITTER_MAX_SECS",
+    "STATE_BACKEND",
+    "STATE_DIR",
+    "STATE_MIGRATION_RATE_LIMIT",
+    "STATE_MIGRATION_TARGET",
+    "STATE_REBUILD_FROM_BLOCK",
+    "STATE_REBUILD_LOG_EVERY",
+    "STATE_REBUILD_RANGE_BLOCKS",
+    "STATE_REBUILD_RATE_LIMIT",
+    "STATUS_ADDR",
+    "TASK_MANAGER_ADDRESS",
+    "TEE_COMPUTE_MAX_INPUTS_BYTES",
+    "TEE_COMPUTE_MAX_PARAMS_BYTES",
+    "TEE_COMPUTE_PROGRAMS",
+    "TEE_COMPUTE_URL",
+    "TEE_ENDPOINT",
+    "TEE_FLEET",
+    "TEE_FLEET_CONCURRENCY",
+    "TEE_HEALTH_CHECK_TIMEOUT_MS",
+    "TEE_HEALTH_MAX_CLOCK_SKEW_SECS",
+    "TEE_HEALTH_MIN_DISK_FREE_PCT",
+    "TEE_HEALTH_THRESHOLD",
+    "TEE_HEALTH_WORKLOAD",
+    "TEE_HOST_URL",
+    "TEE_LIVENESS_FAIL_MS",
+    "TEE_LIVENESS_TIMEOUT_SECS",
+    "TEE_LIVENESS_WARN_MS",
+    "TEE_MEASUREMENT_POLICY",
+    "TEE_MEASUREMENT_POLICY_PATH",
+    "TEE_PLATFORM",
+    "TEE_QUOTE_CACHE_TTL_SECS",
+    "TEE_RA_TLS",
+    "TEE_REQUEST_TIMEOUT_SECS",
+    "TEE_RETRIES",
+    "TEE_SIGNER_KEY_PATH",
+    "TEE_TAPPD_SOCKET",
+    "TEE_TLS_ACCEPT_INVALID_CERTS",
+    "TEE_TLS_CA_CERT",
+    "TX_BUMP_AFTER_SECS",
+    "TX_BUMP_PCT",
+    "TX_GAS_MARGIN_PCT",
+    "TX_INTENTS_RETAINED",
+    "TX_RECEIPT_POLL_MS",
+    "UPGRADE_CHECK_SECS",
+    "UPGRADE_REQUIRE_ACK",
+    "WORKLOAD_PRIVACY",
+    "WORKLOAD_PRIVACY_DEFAULT",
+];
+
+/// Settings named per component or chain: `<prefix><name><suffix>`.
+pub const FAMILIES: &[(&str, &str)] = &[
+    ("DISK_QUOTA_", "_BYTES"),
+    ("FEE_MODEL_", ""),
+    ("HEALTH_CRITICALITY_", ""),
+    ("MEMORY_BUDGET_", "_BYTES"),
+    ("RETRY_", ""),
+    ("STARTUP_", "_TIMEOUT_SECS"),
+];
+
+/// Whether `key` names a setting.
+pub fn is_known(key: &str) -> bool {
+    KEYS.binary_search(&key).is_ok()
+        || FAMILIES.iter().any(|(prefix, suffix)| {
+            key.len() > prefix.len() + suffix.len()
+                && key.starts_with(prefix)
+                && key.ends_with(suffix)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_sorted_and_families_match_named_components() {
+        assert!(KEYS.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(is_known("DETECTION_DELAY_WARN_BLOCKS"));
+        assert!(is_known("DISK_QUOTA_STATE_BYTES"));
+        assert!(is_known("FEE_MODEL_31337"));
+        assert!(is_known("RETRY_PRODUCER_RECREATE_MAX_ATTEMPTS"));
+        assert!(!is_known("DISK_QUOTA__BYTES"));
+        assert!(!is_known("FEE_MODEL_"));
+    }
+}
@@ -0,0 +1,370 @@
+//! The config file and flag layers above the environment.
+//!
+//! The file is TOML. A key is the setting's variable name, either written out at the top level
+//! or split into tables on `_`, in any case:
+//!
+//! ```toml
+//! STATE_BACKEND = "sqlite"
+//!
+//! [detection]
+//! delay_warn_blocks = 20
+//! ```
+//!
+//! Arrays are joined with commas, the form list settings take in the environment. A key naming
+//! no setting fails the load, suggesting the closest one.
+
+use super::is_secret;
+use super::keys::{self, KEYS};
+use crate::error::PhalaAvsError;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Where a setting's value came from, lowest precedence first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    /// Unset; the default of the code reading it applies.
+    Default,
+    File,
+    Env,
+    Cli,
+}
+
+impl ConfigSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::File => "file",
+            Self::Env => "env",
+            Self::Cli => "cli",
+        }
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A setting as the operator sees it after layering.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EffectiveValue {
+    pub key: String,
+    /// `None` when unset.
+    pub value: Option<String>,
+    pub source: ConfigSource,
+}
+
+impl EffectiveValue {
+    /// This value, replaced with `<redacted>` when it is a secret.
+    pub fn redacted(mut self) -> Self {
+        if self.value.is_some() && is_secret(&self.key) {
+            self.value = Some("<redacted>".to_string());
+        }
+        self
+    }
+}
+
+/// The config file and `--set` values.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigLayers {
+    path: Option<PathBuf>,
+    file: BTreeMap<String, String>,
+    cli: BTreeMap<String, String>,
+}
+
+impl ConfigLayers {
+    /// No file and no flags: the environment alone.
+    pub const fn new() -> Self {
+        Self {
+            path: None,
+            file: BTreeMap::new(),
+            cli: BTreeMap::new(),
+        }
+    }
+
+    /// Loads the file at `path`, if any, and the `KEY=VALUE` flags in `overrides`.
+    pub fn load(path: Option<PathBuf>, overrides: &[String]) -> Result<Self, PhalaAvsError> {
+        let file = match &path {
+            Some(path) => read_file(path)?,
+            None => BTreeMap::new(),
+        };
+        let mut cli = BTreeMap::new();
+        for raw in overrides {
+            let (key, value) = raw.split_once('=').ok_or_else(|| {
+                PhalaAvsError::ConfigError(format!("Invalid --set {raw:?}: expected KEY=VALUE"))
+            })?;
+            let key = key.trim().to_ascii_uppercase();
+            check_known(&key, &key)?;
+            cli.insert(key, value.to_string());
+        }
+        Ok(Self { path, file, cli })
+    }
+
+    /// Layers parsed from a TOML document, as if read from a file.
+    pub fn from_toml(text: &str) -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            file: parse(text)?,
+            ..Self::new()
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Re-reads the file this was loaded from, returning how many values it sets.
+    pub fn reload(&mut self) -> Result<usize, PhalaAvsError> {
+        if let Some(path) = &self.path {
+            self.file = read_file(path)?;
+        }
+        Ok(self.file.len())
+    }
+
+    /// The value of `key` and its layer, reading the environment through `env`.
+    pub fn lookup(
+        &self,
+        key: &str,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Option<(String, ConfigSource)> {
+        if let Some(value) = self.cli.get(key) {
+            return Some((value.clone(), ConfigSource::Cli));
+        }
+        if let Some(value) = env(key) {
+            return Some((value, ConfigSource::Env));
+        }
+        self.file
+            .get(key)
+            .map(|value| (value.clone(), ConfigSource::File))
+    }
+
+    /// Every setting, by key, given the environment `env`. Settings named at runtime are
+    /// included once some layer sets them.
+    pub fn effective(&self, env: &BTreeMap<String, String>) -> Vec<EffectiveValue> {
+        let mut names: Vec<String> = KEYS.iter().map(|key| key.to_string()).collect();
+        names.extend(
+            env.keys()
+                .chain(self.file.keys())
+                .chain(self.cli.keys())
+                .filter(|key| keys::is_known(key) && KEYS.binary_search(&key.as_str()).is_err())
+                .cloned(),
+        );
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .map(|key| match self.lookup(&key, |key| env.get(key).cloned()) {
+                Some((value, source)) => EffectiveValue {
+                    key,
+                    value: Some(value),
+                    source,
+                },
+                None => EffectiveValue {
+                    key,
+                    value: None,
+                    source: ConfigSource::Default,
+                },
+            })
+            .collect()
+    }
+}
+
+/// Renders `values` as a config file annotated with each value's source. Secrets are redacted
+/// and unset settings are left commented out.
+pub fn render(values: &[EffectiveValue]) -> String {
+    let mut out = String::new();
+    for value in values.iter().cloned().map(EffectiveValue::redacted) {
+        match &value.value {
+            Some(raw) => {
+                let quoted = toml::Value::String(raw.clone()).to_string();
+                out.push_str(&format!("{} = {quoted} # {}\n", value.key, value.source));
+            }
+            None => out.push_str(&format!("# {} # {}\n", value.key, value.source)),
+        }
+    }
+    out
+}
+
+fn read_file(path: &Path) -> Result<BTreeMap<String, String>, PhalaAvsError> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        PhalaAvsError::ConfigError(format!("Failed to read {}: {e}", path.display()))
+    })?;
+    parse(&text).map_err(|e| PhalaAvsError::ConfigError(format!("In {}: {e}", path.display())))
+}
+
+fn parse(text: &str) -> Result<BTreeMap<String, String>, PhalaAvsError> {
+    let table: toml::Table = text
+        .parse()
+        .map_err(|e| PhalaAvsError::ConfigError(format!("Invalid config file: {e}")))?;
+    let mut values = BTreeMap::new();
+    flatten(&mut values, "", "", table)?;
+    Ok(values)
+}
+
+/// Collects the settings of `table`, whose keys are prefixed with `path` (as written) and
+/// `name` (as a variable name).
+fn flatten(
+    values: &mut BTreeMap<String, String>,
+    path: &str,
+    name: &str,
+    table: toml::Table,
+) -> Result<(), PhalaAvsError> {
+    for (key, value) in table {
+        let path = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        let name = format!("{name}{}", key.to_ascii_uppercase());
+        let raw = match value {
+            toml::Value::Table(table) => {
+                flatten(values, &path, &format!("{name}_"), table)?;
+                continue;
+            }
+            toml::Value::String(s) => s,
+            toml::Value::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    toml::Value::String(s) => s,
+                    other => other.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            other => other.to_string(),
+        };
+        check_known(&path, &name)?;
+        values.insert(name, raw);
+    }
+    Ok(())
+}
+
+/// Fails on a key naming no setting, suggesting the closest one.
+fn check_known(written: &str, name: &str) -> Result<(), PhalaAvsError> {
+    if keys::is_known(name) {
+        return Ok(());
+    }
+    let label = if written == name {
+        name.to_string()
+    } else {
+        format!("{written} ({name})")
+    };
+    let closest = KEYS
+        .iter()
+        .map(|key| (edit_distance(name, key), *key))
+        .min()
+        .filter(|(distance, _)| *distance <= name.len().div_ceil(3).max(2));
+    Err(PhalaAvsError::ConfigError(match closest {
+        Some((_, key)) => format!("Unknown config key {label}; did you mean {key}?"),
+        None => format!("Unknown config key {label}"),
+    }))
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+STATE_BACKEND = "sqlite"
+DETECTION_DELAY_WARN_BLOCKS = 20
+LOG_CHECK_RPC_URLS = ["http://a", "http://b"]
+
+[challenge]
+confirmations = 3
+
+[aggregator]
+admin_token = "from-file"
+"#;
+
+    fn value<'a>(values: &'a [EffectiveValue], key: &str) -> &'a EffectiveValue {
+        values.iter().find(|v| v.key == key).unwrap()
+    }
+
+    #[test]
+    fn flags_beat_env_beats_file_beats_default() {
+        let mut layers = ConfigLayers::from_toml(FILE).unwrap();
+        layers.cli.insert("STATE_BACKEND".into(), "memory".into());
+        let env = BTreeMap::from([
+            ("STATE_BACKEND".to_string(), "file".to_string()),
+            ("CHALLENGE_CONFIRMATIONS".to_string(), "5".to_string()),
+        ]);
+        let lookup = |key: &str| layers.lookup(key, |key| env.get(key).cloned());
+
+        assert_eq!(
+            lookup("STATE_BACKEND"),
+            Some(("memory".into(), ConfigSource::Cli))
+        );
+        assert_eq!(
+            lookup("CHALLENGE_CONFIRMATIONS"),
+            Some(("5".into(), ConfigSource::Env))
+        );
+        assert_eq!(
+            lookup("DETECTION_DELAY_WARN_BLOCKS"),
+            Some(("20".into(), ConfigSource::File))
+        );
+        assert_eq!(
+            lookup("LOG_CHECK_RPC_URLS"),
+            Some(("http://a,http://b".into(), ConfigSource::File))
+        );
+        assert_eq!(lookup("STATE_DIR"), None);
+
+        let effective = layers.effective(&env);
+        assert_eq!(value(&effective, "STATE_DIR").source, ConfigSource::Default);
+        assert_eq!(value(&effective, "STATE_BACKEND").source, ConfigSource::Cli);
+    }
+
+    #[test]
+    fn unknown_keys_name_the_closest_setting() {
+        let err = ConfigLayers::from_toml("[detection]\ndelay_warn_block = 20\n").unwrap_err();
+        assert!(err.to_string().contains(
+            "Unknown config key detection.delay_warn_block (DETECTION_DELAY_WARN_BLOCK); did you mean DETECTION_DELAY_WARN_BLOCKS?"
+        ));
+        // Nothing is suggested for a key unlike any setting.
+        let err = ConfigLayers::from_toml("[zz]\nqqqqqqqq = 1\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("Unknown config key zz.qqqqqqqq (ZZ_QQQQQQQQ)")
+        );
+        let err = ConfigLayers::load(None, &["STATE_BACKNED=memory".into()]).unwrap_err();
+        assert!(err.to_string().contains("did you mean STATE_BACKEND?"));
+    }
+
+    #[test]
+    fn rendered_config_redacts_secrets_and_loads_back() {
+        let layers = ConfigLayers::from_toml(FILE).unwrap();
+        let effective = layers.effective(&BTreeMap::new());
+        let rendered = render(&effective);
+        assert!(rendered.contains("AGGREGATOR_ADMIN_TOKEN = \"<redacted>\" # file\n"));
+        assert!(!rendered.contains("from-file"));
+        assert!(rendered.contains("CHALLENGE_CONFIRMATIONS = \"3\" # file\n"));
+        assert!(rendered.contains("# STATE_DIR # default\n"));
+
+        let reloaded = ConfigLayers::from_toml(&rendered).unwrap();
+        let set = |layers: &ConfigLayers| {
+            layers
+                .effective(&BTreeMap::new())
+                .into_iter()
+                .filter(|v| v.value.is_some() && !is_secret(&v.key))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(set(&reloaded), set(&layers));
+    }
+}
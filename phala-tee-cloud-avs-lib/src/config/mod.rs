@@ -0,0 +1,143 @@
+//! Operator settings, each named by its environment variable.
+//!
+//! A setting is looked up, in increasing precedence, in the default at its call site, the TOML
+//! file given with `--config` or `CONFIG_PATH`, the environment, and `--set KEY=VALUE` flags.
+//! The layers are installed once at startup with [`install`]; see [`layers`].
+
+pub mod keys;
+pub mod layers;
+
+pub use layers::{ConfigLayers, ConfigSource, EffectiveValue, render};
+
+use crate::display::parse_address;
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::primitives::Address;
+use blueprint_sdk::std::env;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// Names containing any of these are never shown verbatim.
+pub const SECRET_MARKERS: &[&str] = &["KEY", "TOKEN", "SECRET", "PASSWORD", "MNEMONIC"];
+
+static LAYERS: RwLock<ConfigLayers> = RwLock::new(ConfigLayers::new());
+
+/// Whether the setting `key` holds a secret.
+pub fn is_secret(key: &str) -> bool {
+    SECRET_MARKERS.iter().any(|m| key.contains(m))
+}
+
+/// Makes `layers` the source of every later lookup.
+pub fn install(layers: ConfigLayers) {
+    *LAYERS.write().unwrap_or_else(|e| e.into_inner()) = layers;
+}
+
+/// Re-reads the installed config file, returning how many values it sets. The previous values
+/// are kept when it fails to load.
+pub fn reload_file() -> Result<usize, PhalaAvsError> {
+    let mut reloaded = LAYERS.read().unwrap_or_else(|e| e.into_inner()).clone();
+    let count = reloaded.reload()?;
+    install(reloaded);
+    Ok(count)
+}
+
+/// Every setting with its value and where it came from.
+pub fn effective() -> Vec<EffectiveValue> {
+    let env: BTreeMap<String, String> = env::vars().collect();
+    LAYERS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .effective(&env)
+}
+
+/// The raw value of `key` from the highest layer setting it.
+pub fn lookup(key: &str) -> Option<String> {
+    LAYERS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .lookup(key, |key| env::var(key).ok())
+        .map(|(value, _)| value)
+}
+
+/// Reads `key` and parses it, falling back to `default` when unset.
+///
+/// A value that is present but fails to parse is reported as a [`PhalaAvsError::ConfigError`]
+/// naming the variable, so misconfiguration fails fast instead of silently using the default.
+pub fn env_or<T>(key: &str, default: T) -> Result<T, PhalaAvsError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    parse_or(key, lookup(key), default)
+}
+
+/// Parses `raw`, the value of `key` from any source, as [`env_or`] does.
+pub fn parse_or<T>(key: &str, raw: Option<String>, default: T) -> Result<T, PhalaAvsError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match raw {
+        Some(raw) => raw
+            .trim()
+            .parse()
+            .map_err(|e| PhalaAvsError::ConfigError(format!("Invalid {key}={raw:?}: {e}"))),
+        None => Ok(default),
+    }
+}
+
+/// Reads an optional value, returning `None` when unset or empty.
+pub fn env_opt<T>(key: &str) -> Result<Option<T>, PhalaAvsError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    parse_opt(key, lookup(key))
+}
+
+/// Parses `raw`, the value of `key` from any source, as [`env_opt`] does.
+pub fn parse_opt<T>(key: &str, raw: Option<String>) -> Result<Option<T>, PhalaAvsError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match raw {
+        Some(raw) if !raw.trim().is_empty() => raw
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| PhalaAvsError::ConfigError(format!("Invalid {key}={raw:?}: {e}"))),
+        _ => Ok(None),
+    }
+}
+
+/// Reads an optional address in any case, warning when its checksum does
+/// not match.
+pub fn env_address(key: &str) -> Result<Option<Address>, PhalaAvsError> {
+    match lookup(key) {
+        Some(raw) if !raw.trim().is_empty() => parse_address(&raw)
+            .map(Some)
+            .map_err(|e| PhalaAvsError::ConfigError(format!("Invalid {key}={raw:?}: {e}"))),
+        _ => Ok(None),
+    }
+}
+
+/// Reads a boolean flag (`true`/`false`/`1`/`0`).
+pub fn env_flag(key: &str, default: bool) -> Result<bool, PhalaAvsError> {
+    parse_flag(key, lookup(key), default)
+}
+
+/// Parses `raw`, the value of `key` from any source, as [`env_flag`] does.
+pub fn parse_flag(key: &str, raw: Option<String>, default: bool) -> Result<bool, PhalaAvsError> {
+    match raw {
+        Some(raw) => match raw.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" => Ok(true),
+            "0" | "false" | "no" => Ok(false),
+            _ => Err(PhalaAvsError::ConfigError(format!(
+                "Invalid {key}={raw:?}: expected a boolean"
+            ))),
+        },
+        None => Ok(default),
+    }
+}
@@ -1,6 +1,84 @@
+use crate::annotations::{AnnotationLimits, Annotations};
+use crate::approvals::{AdminApprovals, ApprovalConfig};
+use crate::artifacts::{ArtifactArchive, ArtifactConfig};
+use crate::batch::EventRetryQueue;
+use crate::capacity::{CapacityConfig, CapacityReporter, Reservations, ServiceManagerCapacity};
+use crate::catchup::{CatchUp, CatchUpConfig};
+use crate::challenge::{ChallengeTracker, ConfirmationPolicy, DetectionPolicy, TrackedChallenge};
+#[cfg(feature = "chaos")]
+use crate::chaos::{ChaosEngine, ChaosEvmClient, ChaosStateStore};
+use crate::costs::CostLedger;
+use crate::cursor::{self, CursorKey, CursorStore};
+use crate::delegation::{DelegationConfig, Delegator, HttpWorkloadResponder};
+use crate::disk::{
+    ARTIFACTS_PRIORITY, AUDIT_PRIORITY, BudgetedStateStore, DiskBudget, DiskConfig, NamespaceTail,
+    OPERATOR_SET_PRIORITY,
+};
+use crate::drift::{DriftConfig, DriftReconciler, ServiceManagerAssignments};
+use crate::duties::{DutyCalendar, DutyConfig, OperatorPrewarm};
 use crate::error::PhalaAvsError;
-use crate::tee::TeeHandler;
-use blueprint_sdk::{info, macros::context::KeystoreContext, runner::config::BlueprintEnvironment};
+use crate::evidence::{
+    AnchorConfig, EvidenceAnchorer, EvidenceLog, ServiceManagerAnchors, now_unix_ms,
+};
+use crate::evm::{EvmClient, ProviderEvmClient};
+use crate::exit::{ContractExits, ExitConfig, ExitWorkflow};
+use crate::failure_domain::{DomainConfig, FailureDomains};
+use crate::fees::{FeeModels, ProviderFeeProbe};
+use crate::freshness::{FreshnessConfig, FreshnessMonitor};
+use crate::heartbeat::{HeartbeatMonitor, WatchdogConfig};
+use crate::ingestion::{EvidenceIngestion, IngestionConfig};
+use crate::jitter::{JitterConfig, JitterSlot};
+use crate::keystore::{KeystoreMigration, KeystoreMigrationConfig, LocalKeyBackend};
+use crate::lanes::{LaneConfig, ProviderAccountSource, SignerLanes};
+use crate::log_consistency::{LogCheckConfig, LogConsistencyChecker, LogSource, ProviderLogSource};
+use crate::maintenance::{MaintenanceConfig, MaintenanceSchedule, ServiceManagerExemptions};
+use crate::memory::MemoryBudgets;
+use crate::notify::{self, Notifier};
+use crate::operator_set::{OperatorSetConfig, OperatorSetTracker, RegistryOperatorSetSource};
+use crate::preflight::{OraclePolicySource, Preflight, PreflightConfig};
+use crate::receipts::{ProviderReceiptLogs, ReceiptVerifierConfig, SubmissionVerifier};
+use crate::redaction::{PrivacySettings, SlaProofBuilder};
+use crate::registration::{RegistrationConfig, RegistrationGate};
+use crate::reimbursement::{
+    ReimbursementClaimer, ReimbursementConfig, ServiceManagerReimbursements,
+};
+use crate::replica::ReplicaCoordinator;
+use crate::reputation::{ContractReputationChain, ReputationConfig, ReputationReporter};
+use crate::response_safety::{ResponseSafety, SafetyConfig};
+use crate::response_window::{InclusionLatencyPredictor, SafetyMarginConfig};
+use crate::restart::{RestartConfig, RestartCoordinator};
+use crate::rollout::{EncoderRollout, OracleSimulator, RolloutConfig};
+use crate::scheduler::{FairScheduler, SchedulerConfig};
+use crate::schema::{SchemaRegistry, SchemaRegistryConfig, source_from_config};
+use crate::self_audit::{SelfAuditConfig, SelfAuditor, SlaOracleLedger};
+use crate::sender::{ProviderTxChain, TxSender, TxSenderConfig};
+use crate::signed_payload::{PayloadConfig, PayloadSigner};
+use crate::slo::{SloConfig, SloMonitor};
+use crate::startup::{self, StartupOrchestrator, StartupStatus, default_plan};
+use crate::state::{StateConfig, StateStore};
+use crate::supervisor::ProducerSupervisor;
+use crate::tee::capacity::HttpHostApi;
+use crate::tee::collateral::{CollateralConfig, CollateralMonitor, HttpCollateralSource};
+use crate::tee::compute::{ComputeConfig, HttpComputeBackend};
+use crate::tee::dcap::{DcapConfig, DcapVerifier};
+use crate::tee::deploy::{CloudConfig, HttpCloudApi};
+use crate::tee::fleet::FleetConfig;
+use crate::tee::health::HealthConfig;
+use crate::tee::key_derivation::signer_key_path_from_env;
+use crate::tee::liveness::Liveness;
+use crate::tee::measurement_policy::{MeasurementAllowlist, MeasurementPolicyConfig};
+use crate::tee::platform::PlatformSetting;
+use crate::tee::workloads::HttpWorkloadHost;
+use crate::tee::{TeeHandler, TeeHandlerConfig};
+use crate::upgrade::{ProviderContractInspector, UpgradeConfig, UpgradeWatcher};
+use crate::{PRIVATE_KEY, SERVICE_MANAGER_ADDRESS, SLA_ORACLE_ADDRESS};
+use blueprint_sdk::alloy::primitives::Address;
+use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+use blueprint_sdk::evm::util::get_provider_http;
+use blueprint_sdk::{
+    info, macros::context::KeystoreContext, runner::config::BlueprintEnvironment, warn,
+};
+use std::sync::{Arc, Mutex};
 
 /// The context for the Phala Cloud AVS blueprint jobs.
 ///
@@ -15,21 +93,739 @@ pub struct PhalaAvsContext {
 
     /// Handler for interacting with the TEE component.
     pub tee_handler: TeeHandler,
-    // Add other shared resources here, e.g.:
-    // - EVM Provider/Client (if needed directly in jobs, though often passed via args)
-    // - Database connection pool
-    // - Metrics registry
+
+    /// Per-oracle inclusion latency predictor deciding how close to a deadline we can submit.
+    pub margin_predictor: Arc<InclusionLatencyPredictor>,
+
+    /// Persistent operator state.
+    pub state: Arc<dyn StateStore>,
+
+    /// The operator's EVM address; challenges for other operators are ignored.
+    pub operator_address: Address,
+
+    /// Chain access used by jobs.
+    pub evm: Arc<dyn EvmClient>,
+
+    /// Challenges seen on-chain, from provisional first sight to submission.
+    pub challenge_tracker: Arc<ChallengeTracker>,
+
+    /// Memory budgets of in-memory components, adjustable at runtime.
+    pub memory: Arc<MemoryBudgets>,
+
+    /// Released challenges awaiting a response, ordered fairly across oracle targets.
+    pub response_queue: Arc<Mutex<FairScheduler<TrackedChallenge>>>,
+
+    /// Health of each oracle target, suspending submissions to a failing one.
+    pub domains: Arc<FailureDomains>,
+
+    /// Polled events whose observation failed, replayed with the next batch.
+    pub event_retries: Arc<EventRetryQueue>,
+
+    /// Last fully processed block of each producer target.
+    pub cursors: Arc<CursorStore>,
+
+    /// Progress of the startup catch-up, and the ranges it skipped.
+    pub catch_up: Arc<CatchUp>,
+
+    /// Restarts the challenge log producer, e.g. to replay blocks after a reorg.
+    pub poller: Arc<ProducerSupervisor>,
+
+    /// Planned maintenance windows exempting workloads from SLA challenges.
+    pub maintenance: Arc<MaintenanceSchedule>,
+
+    /// Operator set of our quorums, when the registry addresses are configured.
+    pub operator_set: Option<Arc<OperatorSetTracker>>,
+
+    /// Whether the operator is registered; on-chain submissions are suspended while it isn't.
+    pub registration: Arc<RegistrationGate>,
+
+    /// Response schemas the oracle expects, compared against our encoders.
+    pub schemas: Arc<SchemaRegistry>,
+
+    /// Encoder version answering each kind per oracle, and shadow runs of new versions.
+    pub rollout: Arc<EncoderRollout>,
+
+    /// Implementations of the contracts we talk to, and whether one changed recently.
+    pub upgrades: Arc<UpgradeWatcher>,
+
+    /// Heartbeat and response records, kept whether or not they are anchored.
+    pub evidence: EvidenceLog,
+
+    /// Commits evidence roots on-chain, when `EVIDENCE_ANCHOR_ENABLED` is set.
+    pub anchorer: Option<Arc<EvidenceAnchorer>>,
+
+    /// Cross-checks polled logs for challenges the RPC provider left out, when
+    /// `LOG_CHECK_ENABLED` is set.
+    pub log_checker: Option<Arc<LogConsistencyChecker>>,
+
+    /// Full artifacts behind submitted responses, served to the oracle's verifiers.
+    pub artifacts: Arc<ArtifactArchive>,
+
+    /// Builds SLA proofs under each workload's privacy mode.
+    pub sla_proofs: Arc<SlaProofBuilder>,
+
+    /// Forwards delegable challenges to the responders embedded in their workloads.
+    pub delegation: Arc<Delegator>,
+
+    /// Authenticates, rate-limits and queues evidence pushed by workloads.
+    pub ingestion: Arc<EvidenceIngestion>,
+
+    /// Builds the signed performance summaries served at `/reputation`.
+    pub reputation: Arc<ReputationReporter>,
+
+    /// Tracks response latency objectives and their error budgets.
+    pub slo: Arc<SloMonitor>,
+
+    /// Verifies attestation responses the way the oracle will, before they are submitted.
+    pub preflight: Arc<Preflight>,
+
+    /// When heartbeats last ran, watched for a stalled heartbeat cron.
+    pub heartbeat: Arc<HeartbeatMonitor>,
+
+    /// Signs heartbeats for off-chain consumers with replay protection.
+    pub payloads: Arc<PayloadSigner>,
+
+    /// Checks task responses against redundancy and peer majorities before they are signed.
+    pub response_safety: Arc<ResponseSafety>,
+
+    /// This operator's startup delay and heartbeat cron phase.
+    pub jitter: JitterSlot,
+
+    /// Where operator alerts are delivered.
+    pub notifier: Arc<dyn Notifier>,
+
+    /// Resources held for workloads being provisioned, kept out of the advertised capacity.
+    pub reservations: Arc<Reservations>,
+
+    /// Advertises TEE capacity on-chain, when `CAPACITY_REPORTING_ENABLED` is set.
+    pub capacity: Option<Arc<CapacityReporter>>,
+
+    /// Reconciles running workloads against on-chain assignments, when `DRIFT_CHECK_ENABLED`
+    /// is set.
+    pub drift: Option<Arc<DriftReconciler>>,
+
+    /// Tracks the platform's DCAP collateral and TCB status, when `COLLATERAL_CHECK_ENABLED` is
+    /// set.
+    pub collateral: Option<Arc<CollateralMonitor>>,
+
+    /// Fetches and keeps the DCAP collateral of quotes' platforms, for attestation packages.
+    pub quote_collateral: Arc<HttpCollateralSource>,
+
+    /// Watches workloads' evidence pushes for gaps, when `EVIDENCE_GAP_CHECK_ENABLED` is set.
+    pub freshness: Option<Arc<FreshnessMonitor>>,
+
+    /// Duties expected over the next day, and their pre-warm actions.
+    pub duties: Arc<DutyCalendar>,
+
+    /// Audits response records against the oracle's, unless `SELF_AUDIT_ENABLED` is unset.
+    pub self_audit: Option<Arc<SelfAuditor>>,
+
+    /// Operator notes on challenges, epochs and incidents.
+    pub annotations: Arc<Annotations>,
+
+    /// The operator's voluntary exit, idle until one is started.
+    pub exit: Arc<ExitWorkflow>,
+
+    /// Holds response submission while the operator is prepared for a restart.
+    pub restart: Arc<RestartCoordinator>,
+
+    /// Destructive admin actions waiting for M-of-N approvals, when `ADMIN_APPROVERS` is set.
+    pub approvals: Arc<AdminApprovals>,
+
+    /// Fee model of each chain transactions are sent to, detected at startup.
+    pub fees: Arc<FeeModels>,
+
+    /// The signer accounts urgent and deferrable transactions are sent from.
+    pub lanes: Arc<SignerLanes>,
+
+    /// Sends transactions from the lanes, recording a write-ahead intent for each.
+    pub tx_sender: Arc<TxSender>,
+
+    /// The response lease shared with this operator's other replicas, when `REPLICA_LEASE` is
+    /// set; only its holder sends.
+    pub replica: Arc<ReplicaCoordinator>,
+
+    /// Signs response digests, migrating to `SIGNER_SECONDARY_URL` when one is started.
+    pub keystore: Arc<KeystoreMigration>,
+
+    /// Settles our included submissions from their receipt logs.
+    pub receipts: Arc<SubmissionVerifier>,
+
+    /// Gas spent and reimbursement credited, claimed and clawed back.
+    pub costs: Arc<CostLedger>,
+
+    /// Tracks the reimbursement credited for accepted responses, and claims it when
+    /// `REIMBURSEMENT_CLAIM_ENABLED` is set.
+    pub reimbursement: Arc<ReimbursementClaimer>,
+
+    /// Disk budget over the state store, when `DISK_BUDGET_BYTES` is set.
+    pub disk: Option<Arc<DiskBudget>>,
+
+    /// Failure injection wrapped around the TEE handler, chain access, storage and events.
+    #[cfg(feature = "chaos")]
+    pub chaos: Arc<ChaosEngine>,
 }
 
 impl PhalaAvsContext {
-    /// Creates a new instance of the AVS context.
+    /// Creates a new instance of the AVS context outside of a staged startup, e.g. in tests.
     pub async fn new(env: BlueprintEnvironment) -> Result<Self, PhalaAvsError> {
+        let orchestrator = StartupOrchestrator::new(default_plan()?, StartupStatus::default());
+        // No status server is run for a standalone context.
+        orchestrator
+            .run_required(startup::STATUS, async { Ok(()) })
+            .await?;
+        Self::build(env, &orchestrator).await
+    }
+
+    /// Creates the context, initializing each subsystem as a stage of `orchestrator`.
+    pub async fn build(
+        env: BlueprintEnvironment,
+        orchestrator: &StartupOrchestrator,
+    ) -> Result<Self, PhalaAvsError> {
         info!("Creating PhalaAvsContext...");
-        let tee_handler = TeeHandler::new().await?;
+        #[cfg(feature = "chaos")]
+        let chaos = Arc::new(ChaosEngine::from_env()?);
+
+        let state = orchestrator
+            .run_required(startup::STORAGE, async { StateConfig::from_env()?.open() })
+            .await?;
+        #[cfg(feature = "chaos")]
+        let state: Arc<dyn StateStore> = Arc::new(ChaosStateStore::new(state, Arc::clone(&chaos)));
+        let disk_config = DiskConfig::from_env()?;
+        let disk = disk_config
+            .budget_bytes
+            .is_some()
+            .then(|| Arc::new(DiskBudget::new(disk_config, Arc::clone(&state))));
+        let state: Arc<dyn StateStore> = match &disk {
+            Some(disk) => Arc::new(BudgetedStateStore::new(state, Arc::clone(disk))),
+            None => state,
+        };
+
+        let fees = Arc::new(FeeModels::default());
+        let evm = orchestrator
+            .run_required(startup::EVM, async {
+                let evm: Arc<dyn EvmClient> = Arc::new(ProviderEvmClient::new(
+                    get_provider_http(&env.http_rpc_endpoint),
+                    *SERVICE_MANAGER_ADDRESS,
+                ));
+                let chain_id = evm.chain_id().await?;
+                info!("Connected to chain {chain_id}");
+                let probe = ProviderFeeProbe::new(get_provider_http(&env.http_rpc_endpoint));
+                fees.detect(chain_id, &probe).await?;
+                Ok(evm)
+            })
+            .await?;
+        #[cfg(feature = "chaos")]
+        let evm: Arc<dyn EvmClient> = Arc::new(ChaosEvmClient::new(evm, Arc::clone(&chaos)));
+
+        let (operator_signer, operator_address, lanes) = orchestrator
+            .run_required(startup::KEYSTORE, async {
+                // A key derived in the TEE, when asked for, takes the place of `PRIVATE_KEY`.
+                let operator_signer = match signer_key_path_from_env()? {
+                    Some(path) => {
+                        let signer = TeeHandler::from_env().await?.derive_signer(&path).await?;
+                        info!("Signing as {} derived in the TEE at {path}", signer.address());
+                        signer
+                    }
+                    None => PRIVATE_KEY.parse::<PrivateKeySigner>().map_err(|e| {
+                        PhalaAvsError::ConfigError(format!("Invalid PRIVATE_KEY: {e}"))
+                    })?,
+                };
+                let operator_address = operator_signer.address();
+                let lanes = Arc::new(SignerLanes::new(
+                    LaneConfig::from_env()?,
+                    &hex::encode(operator_signer.to_bytes()),
+                    Arc::new(ProviderAccountSource::new(
+                        get_provider_http(&env.http_rpc_endpoint),
+                        *SERVICE_MANAGER_ADDRESS,
+                    )),
+                )?);
+                lanes.verify_designation(operator_address).await?;
+                if let Err(e) = lanes.check_balances().await {
+                    warn!("Failed to read signer balances at startup: {e}");
+                }
+                Ok((operator_signer, operator_address, lanes))
+            })
+            .await?;
+
+        // Spread a fleet's registration checks, first polls and first heartbeats.
+        let watchdog_config = WatchdogConfig::from_env()?;
+        let jitter = JitterSlot::derive(
+            &JitterConfig::from_env()?,
+            operator_address,
+            watchdog_config.stall_after_secs(),
+        );
+        if !jitter.delay.is_zero() {
+            info!(
+                "Waiting {:?} for this operator's startup slot; heartbeat schedule {}",
+                jitter.delay,
+                jitter.heartbeat_schedule()
+            );
+            tokio::time::sleep(jitter.delay).await;
+        }
+
+        let cursors = Arc::new(CursorStore::new(Arc::clone(&state))?);
+        let chain_id = evm.chain_id().await?;
+        cursors.migrate_legacy(
+            &cursor::legacy_file_from_env()?,
+            CursorKey::challenges(chain_id, *SLA_ORACLE_ADDRESS),
+        )?;
+        let catch_up = Arc::new(CatchUp::new(CatchUpConfig::from_env()?, Arc::clone(&state)));
+        catch_up.prepare(&cursors, chain_id, evm.block_number().await?, now_unix_ms())?;
+
+        // The lease is taken before anything could be sent, including the intents replayed at
+        // startup.
+        let replica = Arc::new(ReplicaCoordinator::from_env(
+            operator_address,
+            Arc::clone(&state),
+        )?);
+        let role = replica.tick(now_unix_ms())?;
+        info!("Starting as replica role {}", role.as_str());
+        let tx_sender = TxSender::new(
+            TxSenderConfig::from_env()?,
+            chain_id,
+            Arc::clone(&lanes),
+            Arc::new(ProviderTxChain::new(
+                get_provider_http(&env.http_rpc_endpoint),
+                chain_id,
+                Arc::clone(&fees),
+            )),
+            Arc::clone(&state),
+        );
+        let tx_sender = tx_sender.with_replica(Arc::clone(&replica));
+        #[cfg(feature = "chaos")]
+        let tx_sender = tx_sender.with_chaos(Arc::clone(&chaos));
+        let tx_sender = Arc::new(tx_sender);
+
+        let registration = Arc::new(RegistrationGate::new(
+            operator_address,
+            RegistrationConfig::from_env()?,
+        ));
+        if let Err(e) = registration.check(evm.as_ref()).await {
+            warn!("Failed to check operator registration at startup: {e}");
+        }
+
+        // The handler is always constructed; the stage only gates on the TEE being live, so a
+        // slow or unhealthy TEE shows up as degraded on `/status` instead of blocking startup.
+        let tee_config = TeeHandlerConfig::from_env()?;
+        let tee_handler = TeeHandler::new(tee_config.clone()).await?;
+        let compute_config = ComputeConfig::from_env()?;
+        let tee_handler = match compute_config.url.clone() {
+            Some(url) => {
+                tee_handler.with_compute(compute_config, Arc::new(HttpComputeBackend::new(url)))
+            }
+            None => tee_handler,
+        };
+        let cloud_config = CloudConfig::from_env()?;
+        let tee_handler = match cloud_config.api_url.clone() {
+            Some(url) => {
+                let api = HttpCloudApi::new(url, cloud_config.api_key.clone())
+                    .with_retry_policy(tee_config.retry_policy())
+                    .with_cancel(tee_handler.retry_cancel().clone());
+                tee_handler.with_cloud(cloud_config, Arc::new(api))
+            }
+            None => tee_handler,
+        };
+        let collateral_config = CollateralConfig::from_env()?;
+        let quote_collateral = Arc::new(HttpCollateralSource::new(&collateral_config));
+        let tee_handler = tee_handler
+            .with_fleet(FleetConfig::from_env()?)?
+            .with_health(HealthConfig::from_env()?)
+            .with_chain(Arc::clone(&evm))
+            .with_operator(operator_address)
+            .with_quote_collateral(Arc::clone(&quote_collateral));
+        let capacity_config = CapacityConfig::from_env()?;
+        let tee_handler = match capacity_config.host_url.clone() {
+            Some(url) => tee_handler
+                .with_host(Arc::new(HttpHostApi::new(url.clone())))
+                .with_workloads(Arc::new(HttpWorkloadHost::new(url))),
+            None => tee_handler,
+        };
+        let tee_handler = match DcapVerifier::from_config(&DcapConfig::from_env()?)? {
+            Some(verifier) => tee_handler.with_dcap(Arc::new(verifier)),
+            None => tee_handler,
+        };
+        let tee_handler =
+            match MeasurementAllowlist::from_config(&MeasurementPolicyConfig::from_env()?)? {
+                Some(allowlist) => tee_handler.with_measurement_policy(Arc::new(allowlist)),
+                None => tee_handler,
+            };
+        let tee_handler = tee_handler
+            .detect_platform(PlatformSetting::from_env()?)
+            .await;
+        #[cfg(feature = "chaos")]
+        let tee_handler = tee_handler.with_chaos(Arc::clone(&chaos));
+        orchestrator
+            .run(startup::TEE, async {
+                match tee_handler.check_liveness().await?.liveness {
+                    Liveness::Live => Ok(()),
+                    not_live => Err(PhalaAvsError::TeeError(format!(
+                        "TEE is not live: {not_live}"
+                    ))),
+                }
+            })
+            .await?;
+
+        let schema_config = SchemaRegistryConfig::from_env()?;
+        let schemas = Arc::new(SchemaRegistry::new(
+            schema_config.clone(),
+            source_from_config(
+                &schema_config,
+                get_provider_http(&env.http_rpc_endpoint),
+                *SLA_ORACLE_ADDRESS,
+            ),
+        ));
+        orchestrator
+            .run(startup::SCHEMAS, async {
+                schemas.refresh().await.map(|_| ())
+            })
+            .await?;
+        let rollout = Arc::new(EncoderRollout::new(
+            RolloutConfig::from_env()?,
+            Arc::clone(&state),
+            Arc::new(OracleSimulator::new(
+                get_provider_http(&env.http_rpc_endpoint),
+                operator_address,
+            )),
+        )?);
+
+        let upgrades = Arc::new(UpgradeWatcher::new(
+            UpgradeConfig::from_env()?,
+            Arc::new(ProviderContractInspector::new(get_provider_http(
+                &env.http_rpc_endpoint,
+            ))),
+        ));
+        if let Err(e) = upgrades.check().await {
+            warn!("Failed to record contract implementations at startup: {e}");
+        }
+
+        let margin_predictor = Arc::new(InclusionLatencyPredictor::new(
+            SafetyMarginConfig::from_env()?,
+        ));
+        let memory = Arc::new(MemoryBudgets::from_env()?);
+        let challenge_tracker = Arc::new(
+            ChallengeTracker::new(ConfirmationPolicy::from_env()?, Arc::clone(&state))?
+                .with_budgets(Arc::clone(&memory))
+                .with_detection(DetectionPolicy::from_env()?),
+        );
+        let response_queue = Arc::new(Mutex::new(FairScheduler::new(SchedulerConfig::from_env()?)));
+        let maintenance = Arc::new(MaintenanceSchedule::new(
+            MaintenanceConfig::from_env()?,
+            Arc::clone(&state),
+            Arc::new(ServiceManagerExemptions::from_env(
+                env.http_rpc_endpoint.clone(),
+            )),
+        ));
+        let operator_set = match OperatorSetConfig::from_env()? {
+            Some(config) => {
+                let source = RegistryOperatorSetSource::new(
+                    get_provider_http(&env.http_rpc_endpoint),
+                    config.clone(),
+                );
+                Some(Arc::new(OperatorSetTracker::new(
+                    config,
+                    Arc::new(source),
+                    Arc::clone(&state),
+                    operator_address,
+                )?))
+            }
+            None => None,
+        };
+        let anchor_config = AnchorConfig::from_env()?;
+        let anchorer = anchor_config.enabled.then(|| {
+            Arc::new(EvidenceAnchorer::new(
+                anchor_config.clone(),
+                operator_address,
+                Arc::clone(&state),
+                Arc::new(ServiceManagerAnchors::from_env(
+                    Arc::clone(&tx_sender),
+                    env.http_rpc_endpoint.clone(),
+                )),
+            ))
+        });
+        let log_check_config = LogCheckConfig::from_env()?;
+        let log_checker = log_check_config.enabled.then(|| {
+            let source = |url: &String| -> Arc<dyn LogSource> {
+                Arc::new(ProviderLogSource::new(url, get_provider_http(url)))
+            };
+            let cross_check = log_check_config.rpc_urls.iter().map(source).collect();
+            Arc::new(LogConsistencyChecker::new(
+                log_check_config,
+                *SLA_ORACLE_ADDRESS,
+                source(&env.http_rpc_endpoint),
+                cross_check,
+            ))
+        });
+        let evidence = EvidenceLog::new(Arc::clone(&state));
+        let artifacts = Arc::new(ArtifactArchive::new(
+            ArtifactConfig::from_env()?,
+            Arc::clone(&state),
+        ));
+        let sla_proofs = Arc::new(
+            SlaProofBuilder::new(PrivacySettings::from_env()?, Arc::clone(&state)).with_evidence(
+                evidence.clone(),
+                anchor_config.clone(),
+                watchdog_config.period_secs * 1000,
+            ),
+        );
+        let delegation = Arc::new(Delegator::new(
+            DelegationConfig::from_env()?,
+            Arc::clone(&state),
+            Arc::new(HttpWorkloadResponder::default()),
+            operator_signer.clone(),
+        ));
+        let ingestion = Arc::new(EvidenceIngestion::new(
+            IngestionConfig::from_env()?,
+            Arc::clone(&state),
+            evidence.clone(),
+        ));
+        let slo = Arc::new(SloMonitor::new(
+            SloConfig::from_env()?,
+            operator_address,
+            Arc::clone(&challenge_tracker),
+            Arc::clone(&tx_sender) as _,
+        ));
+        let costs = Arc::new(CostLedger::new(Arc::clone(&state)));
+        let reputation = Arc::new(
+            ReputationReporter::new(
+                ReputationConfig::from_env()?,
+                chain_id,
+                Arc::clone(&state),
+                Arc::clone(&challenge_tracker),
+                Arc::new(ContractReputationChain::from_env(
+                    env.http_rpc_endpoint.clone(),
+                )),
+                operator_signer.clone(),
+            )
+            .with_slo(Arc::clone(&slo))
+            .with_costs(Arc::clone(&costs)),
+        );
+        let notifier = notify::notifier_from_env()?;
+        let collateral = collateral_config.enabled.then(|| {
+            Arc::new(CollateralMonitor::new(
+                collateral_config,
+                tee_handler.platform(),
+                Arc::clone(&quote_collateral),
+                Arc::clone(&notifier),
+            ))
+        });
+        let preflight = Preflight::new(
+            PreflightConfig::from_env()?,
+            Arc::new(OraclePolicySource::new(
+                get_provider_http(&env.http_rpc_endpoint),
+                *SLA_ORACLE_ADDRESS,
+            )),
+            Arc::clone(&state),
+        );
+        let preflight = Arc::new(match &collateral {
+            Some(monitor) => preflight.with_collateral(Arc::clone(monitor)),
+            None => preflight,
+        });
+        let duties = Arc::new(DutyCalendar::new(
+            DutyConfig::from_env()?,
+            Arc::new(OperatorPrewarm {
+                tee: tee_handler.clone(),
+                evm: Arc::clone(&evm),
+                evidence: evidence.clone(),
+                windows: anchor_config,
+                heartbeat_period_ms: watchdog_config.period_secs * 1000,
+                lanes: Arc::clone(&lanes),
+                operator_set: operator_set.clone(),
+                collateral: collateral.clone(),
+            }),
+        ));
+        let heartbeat = Arc::new(HeartbeatMonitor::new(watchdog_config, now_unix_ms()));
+        let payloads = Arc::new(PayloadSigner::new(
+            PayloadConfig::from_env()?,
+            operator_signer.clone(),
+            Arc::clone(&evm),
+            Arc::clone(&state),
+        ));
+        let keystore_config = KeystoreMigrationConfig::from_env()?;
+        let secondary = keystore_config.secondary();
+        let keystore = Arc::new(KeystoreMigration::new(
+            keystore_config,
+            Arc::new(LocalKeyBackend::new(
+                operator_signer.clone(),
+            )),
+            secondary,
+            Arc::clone(&state),
+        )?);
+        let response_safety = Arc::new(ResponseSafety::new(
+            SafetyConfig::from_env()?,
+            Arc::clone(&state),
+            Arc::clone(&notifier),
+        ));
+        let reservations = Arc::new(Reservations::default());
+        let capacity = capacity_config.enabled.then(|| {
+            Arc::new(CapacityReporter::new(
+                capacity_config,
+                Arc::new(ServiceManagerCapacity::from_env(Arc::clone(&tx_sender))),
+                Arc::clone(&reservations),
+            ))
+        });
+        let drift_config = DriftConfig::from_env()?;
+        let assignments = Arc::new(ServiceManagerAssignments::from_env(
+            env.http_rpc_endpoint.clone(),
+            drift_config.multicall,
+        ));
+        let drift = if drift_config.enabled {
+            Some(Arc::new(DriftReconciler::new(
+                drift_config,
+                operator_address,
+                Arc::clone(&assignments) as _,
+                Arc::new(tee_handler.clone()),
+                Arc::clone(&state),
+            )?))
+        } else {
+            None
+        };
+        let freshness_config = FreshnessConfig::from_env()?;
+        let freshness = freshness_config.enabled.then(|| {
+            Arc::new(
+                FreshnessMonitor::new(
+                    freshness_config,
+                    evidence.clone(),
+                    Arc::clone(&maintenance),
+                    Arc::clone(&notifier),
+                )
+                .with_assignments(operator_address, assignments),
+            )
+        });
+        let annotations = Arc::new(Annotations::new(
+            Arc::clone(&state),
+            AnnotationLimits::from_env()?,
+        ));
+        let self_audit_config = SelfAuditConfig::from_env()?;
+        let self_audit = if self_audit_config.enabled {
+            let ledger = SlaOracleLedger::from_env(
+                env.http_rpc_endpoint.clone(),
+                self_audit_config.multicall,
+            );
+            Some(Arc::new(
+                SelfAuditor::new(
+                    self_audit_config,
+                    operator_address,
+                    Arc::new(ledger),
+                    Arc::clone(&challenge_tracker),
+                    Arc::clone(&state),
+                )?
+                .with_anchorer(anchorer.clone())
+                .with_log_checker(log_checker.clone())
+                .with_annotations(Arc::clone(&annotations)),
+            ))
+        } else {
+            None
+        };
+        let receipts = Arc::new(
+            SubmissionVerifier::new(
+                ReceiptVerifierConfig::from_env()?,
+                operator_address,
+                *SLA_ORACLE_ADDRESS,
+                *SERVICE_MANAGER_ADDRESS,
+                Arc::new(ProviderReceiptLogs::new(env.http_rpc_endpoint.clone())),
+                Arc::clone(&challenge_tracker),
+                Arc::clone(&tx_sender),
+            )
+            .with_self_audit(self_audit.clone())
+            .with_costs(Arc::clone(&costs)),
+        );
+        let reimbursement = Arc::new(ReimbursementClaimer::new(
+            ReimbursementConfig::from_env()?,
+            operator_address,
+            Arc::new(ServiceManagerReimbursements::from_env(
+                Arc::clone(&tx_sender),
+                env.http_rpc_endpoint.clone(),
+            )),
+            Arc::clone(&costs),
+            Arc::clone(&state),
+            Arc::clone(&notifier),
+        ));
+        let exit = Arc::new(ExitWorkflow::new(
+            ExitConfig::from_env()?,
+            operator_address,
+            Arc::new(ContractExits::from_env(env.http_rpc_endpoint.clone())?),
+            Arc::clone(&state),
+            Arc::clone(&registration),
+            Arc::clone(&reservations),
+        )?);
+        if let Some(disk) = &disk {
+            disk.register(
+                AUDIT_PRIORITY,
+                "audit",
+                Arc::new(NamespaceTail::audit(Arc::clone(&state))),
+            );
+            if let Some(operator_set) = &operator_set {
+                disk.register(
+                    OPERATOR_SET_PRIORITY,
+                    "operator_set",
+                    Arc::clone(operator_set) as _,
+                );
+            }
+            disk.register(ARTIFACTS_PRIORITY, "artifacts", Arc::clone(&artifacts) as _);
+        }
         Ok(Self {
             env,
             tee_handler,
-            // Initialize other fields here
+            margin_predictor,
+            state,
+            operator_address,
+            evm,
+            challenge_tracker,
+            memory,
+            response_queue,
+            domains: Arc::new(FailureDomains::new(DomainConfig::from_env()?)),
+            event_retries: Arc::new(EventRetryQueue::default()),
+            cursors,
+            catch_up,
+            poller: ProducerSupervisor::new("challenge_poller"),
+            maintenance,
+            operator_set,
+            registration,
+            schemas,
+            rollout,
+            upgrades,
+            evidence,
+            anchorer,
+            log_checker,
+            artifacts,
+            sla_proofs,
+            delegation,
+            ingestion,
+            reputation,
+            slo,
+            preflight,
+            heartbeat,
+            payloads,
+            response_safety,
+            jitter,
+            notifier,
+            reservations,
+            capacity,
+            drift,
+            collateral,
+            quote_collateral,
+            freshness,
+            duties,
+            self_audit,
+            annotations,
+            exit,
+            restart: Arc::new(RestartCoordinator::new(RestartConfig::from_env()?)),
+            approvals: Arc::new(AdminApprovals::new(
+                ApprovalConfig::from_env()?,
+                Arc::clone(&state),
+            )),
+            fees,
+            lanes,
+            tx_sender,
+            replica,
+            keystore,
+            receipts,
+            costs,
+            reimbursement,
+            disk,
+            #[cfg(feature = "chaos")]
+            chaos,
         })
     }
 }
@@ -0,0 +1,262 @@
+//! What operating the AVS costs, net of what the service manager reimburses.
+//!
+//! The ledger records gas spent on each included transaction as it is verified by
+//! [`crate::receipts`], and the reimbursement flow of the service manager as its events are
+//! seen: credits for accepted responses, claims paying them out, and clawbacks of responses
+//! later rejected in a dispute. Credits offset gas spend whether claimed or not; a clawback
+//! cancels its credit. Entries are keyed by what they record, so seeing the same receipt or
+//! event twice records it once.
+//!
+//! Gas spend is the most the transaction could have cost (its gas limit at its fee caps), since
+//! receipts are read for their logs only; the net cost is an upper bound.
+
+use crate::error::PhalaAvsError;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{B256, U256};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Ledger entries by kind and what they record.
+pub const COST_NAMESPACE: &str = "cost_ledger";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CostKind {
+    /// Gas spent on one of our included transactions.
+    Gas,
+    /// Reimbursement credited for an accepted response.
+    Credit,
+    /// Credited reimbursement paid out to the operator.
+    Claim,
+    /// Reimbursement taken back after the response was rejected in a dispute.
+    Clawback,
+}
+
+impl CostKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Gas => "gas",
+            Self::Credit => "credit",
+            Self::Claim => "claim",
+            Self::Clawback => "clawback",
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Self::Gas => 0,
+            Self::Credit => 1,
+            Self::Claim => 2,
+            Self::Clawback => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CostEntry {
+    pub unix_ms: u64,
+    pub kind: CostKind,
+    pub wei: u128,
+    /// The challenge a credit or clawback is for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge_id: Option<U256>,
+    /// The transaction gas was spent on, or that paid out a claim.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<B256>,
+}
+
+impl CostEntry {
+    fn key(&self) -> Vec<u8> {
+        let mut key = vec![self.kind.tag()];
+        match (self.challenge_id, self.tx_hash) {
+            (Some(challenge_id), _) => key.extend_from_slice(&challenge_id.to_be_bytes::<32>()),
+            (None, Some(tx_hash)) => key.extend_from_slice(tx_hash.as_slice()),
+            (None, None) => key.extend_from_slice(&self.unix_ms.to_be_bytes()),
+        }
+        key
+    }
+}
+
+/// The reimbursement flow, in wei.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReimbursementTotals {
+    /// Accepted responses credited a reimbursement.
+    pub eligible_responses: u64,
+    pub credited_wei: u128,
+    pub claimed_wei: u128,
+    pub clawed_back_wei: u128,
+    /// Credited, not clawed back and not claimed yet; what a claim would pay at most.
+    pub pending_wei: u128,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CostTotals {
+    /// Upper bound of the gas spent on our included transactions.
+    pub gas_spent_wei: u128,
+    pub reimbursement: ReimbursementTotals,
+    /// Gas spent less reimbursement credited and not clawed back; negative once reimbursement
+    /// exceeds spend.
+    pub net_wei: i128,
+}
+
+/// The ledger and its totals, as exported.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CostReport {
+    pub totals: CostTotals,
+    pub entries: Vec<CostEntry>,
+}
+
+/// Gas spend and reimbursement, persisted in the state store.
+pub struct CostLedger {
+    store: Arc<dyn StateStore>,
+}
+
+impl CostLedger {
+    pub fn new(store: Arc<dyn StateStore>) -> Self {
+        Self { store }
+    }
+
+    /// Records `entry`, unless what it records is on file already.
+    pub fn record(&self, entry: CostEntry) -> Result<bool, PhalaAvsError> {
+        let key = entry.key();
+        if self
+            .store
+            .get_json::<CostEntry>(COST_NAMESPACE, &key)?
+            .is_some()
+        {
+            return Ok(false);
+        }
+        self.store.put_json(COST_NAMESPACE, &key, &entry)?;
+        Ok(true)
+    }
+
+    /// Every entry, oldest first.
+    pub fn entries(&self) -> Result<Vec<CostEntry>, PhalaAvsError> {
+        let mut entries = self
+            .store
+            .scan(COST_NAMESPACE)?
+            .into_iter()
+            .map(|(_, raw)| {
+                serde_json::from_slice::<CostEntry>(&raw).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Corrupt cost ledger entry: {e}"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.unix_ms);
+        Ok(entries)
+    }
+
+    pub fn totals(&self) -> Result<CostTotals, PhalaAvsError> {
+        Ok(totals(&self.entries()?))
+    }
+
+    pub fn report(&self) -> Result<CostReport, PhalaAvsError> {
+        let entries = self.entries()?;
+        Ok(CostReport {
+            totals: totals(&entries),
+            entries,
+        })
+    }
+}
+
+fn totals(entries: &[CostEntry]) -> CostTotals {
+    let mut totals = CostTotals::default();
+    let reimbursement = &mut totals.reimbursement;
+    for entry in entries {
+        match entry.kind {
+            CostKind::Gas => totals.gas_spent_wei = totals.gas_spent_wei.saturating_add(entry.wei),
+            CostKind::Credit => {
+                reimbursement.eligible_responses += 1;
+                reimbursement.credited_wei = reimbursement.credited_wei.saturating_add(entry.wei);
+            }
+            CostKind::Claim => {
+                reimbursement.claimed_wei = reimbursement.claimed_wei.saturating_add(entry.wei)
+            }
+            CostKind::Clawback => {
+                reimbursement.clawed_back_wei =
+                    reimbursement.clawed_back_wei.saturating_add(entry.wei)
+            }
+        }
+    }
+    let earned = reimbursement
+        .credited_wei
+        .saturating_sub(reimbursement.clawed_back_wei);
+    reimbursement.pending_wei = earned.saturating_sub(reimbursement.claimed_wei);
+    totals.net_wei = to_i128(totals.gas_spent_wei).saturating_sub(to_i128(earned));
+    totals
+}
+
+fn to_i128(wei: u128) -> i128 {
+    i128::try_from(wei).unwrap_or(i128::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    fn entry(unix_ms: u64, kind: CostKind, wei: u128, challenge: Option<u64>) -> CostEntry {
+        CostEntry {
+            unix_ms,
+            kind,
+            wei,
+            challenge_id: challenge.map(U256::from),
+            tx_hash: challenge
+                .is_none()
+                .then(|| B256::with_last_byte(unix_ms as u8)),
+        }
+    }
+
+    #[test]
+    fn credits_offset_gas_and_clawbacks_cancel_them() {
+        let ledger = CostLedger::new(Arc::new(MemoryStateStore::default()));
+        ledger.record(entry(1, CostKind::Gas, 500, None)).unwrap();
+        ledger.record(entry(2, CostKind::Gas, 300, None)).unwrap();
+        ledger
+            .record(entry(3, CostKind::Credit, 400, Some(7)))
+            .unwrap();
+        ledger
+            .record(entry(4, CostKind::Credit, 400, Some(8)))
+            .unwrap();
+        let totals = ledger.totals().unwrap();
+        assert_eq!(totals.net_wei, 0);
+        assert_eq!(totals.reimbursement.pending_wei, 800);
+
+        ledger.record(entry(5, CostKind::Claim, 800, None)).unwrap();
+        ledger
+            .record(entry(6, CostKind::Clawback, 400, Some(8)))
+            .unwrap();
+        let totals = ledger.totals().unwrap();
+        assert_eq!(totals.gas_spent_wei, 800);
+        assert_eq!(totals.net_wei, 400);
+        assert_eq!(totals.reimbursement, ReimbursementTotals {
+            eligible_responses: 2,
+            credited_wei: 800,
+            claimed_wei: 800,
+            clawed_back_wei: 400,
+            pending_wei: 0,
+        });
+    }
+
+    #[test]
+    fn the_same_credit_is_recorded_once() {
+        let ledger = CostLedger::new(Arc::new(MemoryStateStore::default()));
+        assert!(
+            ledger
+                .record(entry(1, CostKind::Credit, 400, Some(7)))
+                .unwrap()
+        );
+        assert!(
+            !ledger
+                .record(entry(9, CostKind::Credit, 400, Some(7)))
+                .unwrap()
+        );
+        // A clawback of the same challenge is a separate entry.
+        assert!(
+            ledger
+                .record(entry(9, CostKind::Clawback, 400, Some(7)))
+                .unwrap()
+        );
+        assert_eq!(ledger.entries().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,356 @@
+//! Block cursors of the event producers, one per chain, producer kind and contract.
+//!
+//! A cursor is the last block whose events were fully processed. Cursors live in the state store
+//! under `(chain id, producer kind, contract)`, so targets advance independently and one target's
+//! cursor never moves past another's unprocessed blocks. Jobs advance cursors in memory and
+//! [`CursorStore::flush`] writes the changed ones once per processed range. A restarted producer
+//! catches up from the lowest cursor of its chain, and a reorg rewinds the affected target's.
+//!
+//! Older operators kept a single last-processed block in a file, by default
+//! `<STATE_DIR>/last_processed_block` (`CURSOR_LEGACY_FILE`). [`CursorStore::migrate_legacy`]
+//! applies it once to the primary target and archives the file next to itself as `.migrated`.
+
+use crate::challenge::is_challenge_event;
+use crate::config::env_opt;
+use crate::error::PhalaAvsError;
+use crate::state::{StateConfig, StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::Address;
+use blueprint_sdk::alloy::rpc::types::Log;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+const NAMESPACE: &str = "producer_cursors";
+
+/// What a producer polls for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProducerKind {
+    /// `SlaChallengeIssued` logs of an oracle contract.
+    ChallengeLogs,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CursorKey {
+    pub chain_id: u64,
+    pub kind: ProducerKind,
+    pub contract: Address,
+}
+
+impl CursorKey {
+    pub fn challenges(chain_id: u64, oracle: Address) -> Self {
+        Self {
+            chain_id,
+            kind: ProducerKind::ChallengeLogs,
+            contract: oracle,
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut key = self.chain_id.to_be_bytes().to_vec();
+        key.push(self.kind as u8);
+        key.extend_from_slice(self.contract.as_slice());
+        key
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CursorRecord {
+    key: CursorKey,
+    block: u64,
+}
+
+/// A cursor as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CursorStatus {
+    #[serde(flatten)]
+    pub key: CursorKey,
+    pub block: u64,
+    /// Blocks behind the last seen chain head.
+    pub lag: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Cursors {
+    blocks: BTreeMap<CursorKey, u64>,
+    /// Advanced since the last flush.
+    dirty: BTreeSet<CursorKey>,
+    head: Option<u64>,
+}
+
+/// The persisted producer cursors.
+#[derive(Debug)]
+pub struct CursorStore {
+    store: Arc<dyn StateStore>,
+    cursors: Mutex<Cursors>,
+}
+
+impl CursorStore {
+    /// Loads the persisted cursors.
+    pub fn new(store: Arc<dyn StateStore>) -> Result<Self, PhalaAvsError> {
+        let mut blocks = BTreeMap::new();
+        for (_, raw) in store.scan(NAMESPACE)? {
+            match serde_json::from_slice::<CursorRecord>(&raw) {
+                Ok(record) => {
+                    blocks.insert(record.key, record.block);
+                }
+                Err(e) => warn!("Skipping corrupt cursor record: {e}"),
+            }
+        }
+        Ok(Self {
+            store,
+            cursors: Mutex::new(Cursors {
+                blocks,
+                ..Default::default()
+            }),
+        })
+    }
+
+    fn cursors(&self) -> std::sync::MutexGuard<'_, Cursors> {
+        self.cursors.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn persist(&self, key: CursorKey, block: u64) -> Result<(), PhalaAvsError> {
+        self.store
+            .put_json(NAMESPACE, &key.to_bytes(), &CursorRecord { key, block })
+    }
+
+    pub fn get(&self, key: &CursorKey) -> Option<u64> {
+        self.cursors().blocks.get(key).copied()
+    }
+
+    /// The first block a producer of `kind` on `chain_id` must poll from so that no target
+    /// misses a block, or `None` if none of them has a cursor yet.
+    pub fn catch_up_from(&self, chain_id: u64, kind: ProducerKind) -> Option<u64> {
+        self.cursors()
+            .blocks
+            .iter()
+            .filter(|(key, _)| key.chain_id == chain_id && key.kind == kind)
+            .map(|(_, block)| block + 1)
+            .min()
+    }
+
+    /// Moves `key` forward to `block`, in memory until the next [`flush`](Self::flush).
+    pub fn advance(&self, key: CursorKey, block: u64) {
+        let mut cursors = self.cursors();
+        if cursors
+            .blocks
+            .get(&key)
+            .is_some_and(|&current| current >= block)
+        {
+            return;
+        }
+        cursors.blocks.insert(key, block);
+        cursors.dirty.insert(key);
+    }
+
+    /// Moves every cursor of `kind` on `chain_id` forward to at least `block`, in memory until
+    /// the next [`flush`](Self::flush).
+    pub fn fast_forward(&self, chain_id: u64, kind: ProducerKind, block: u64) {
+        let mut cursors = self.cursors();
+        let Cursors { blocks, dirty, .. } = &mut *cursors;
+        for (key, current) in blocks.iter_mut() {
+            if key.chain_id == chain_id && key.kind == kind && *current < block {
+                *current = block;
+                dirty.insert(*key);
+            }
+        }
+    }
+
+    /// Writes every cursor advanced since the last flush, returning how many were written.
+    pub fn flush(&self) -> Result<usize, PhalaAvsError> {
+        let mut cursors = self.cursors();
+        let dirty = std::mem::take(&mut cursors.dirty);
+        for (i, key) in dirty.iter().enumerate() {
+            if let Err(e) = self.persist(*key, cursors.blocks[key]) {
+                cursors.dirty.extend(dirty.iter().skip(i));
+                return Err(e);
+            }
+        }
+        Ok(dirty.len())
+    }
+
+    /// Moves `key` back before the orphaned `block`, persisting it at once. Returns whether the
+    /// cursor had already passed it, so the producer needs to replay from there.
+    pub fn rewind(&self, key: CursorKey, block: u64) -> Result<bool, PhalaAvsError> {
+        let mut cursors = self.cursors();
+        match cursors.blocks.get(&key) {
+            Some(&current) if current >= block => {
+                let rewound = block.saturating_sub(1);
+                self.persist(key, rewound)?;
+                cursors.blocks.insert(key, rewound);
+                cursors.dirty.remove(&key);
+                info!(
+                    "Rewound {:?} cursor of {} on chain {} from {current} to {rewound}",
+                    key.kind, key.contract, key.chain_id
+                );
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Records the chain head the cursors' lag is measured against.
+    pub fn observe_head(&self, head: u64) {
+        self.cursors().head = Some(head);
+    }
+
+    pub fn status(&self) -> Vec<CursorStatus> {
+        let cursors = self.cursors();
+        cursors
+            .blocks
+            .iter()
+            .map(|(&key, &block)| CursorStatus {
+                key,
+                block,
+                lag: cursors.head.map(|head| head.saturating_sub(block)),
+            })
+            .collect()
+    }
+
+    /// Applies the legacy single-cursor file at `path` to `primary`, unless it already has a
+    /// cursor, and archives the file. Returns the applied block.
+    pub fn migrate_legacy(
+        &self,
+        path: &Path,
+        primary: CursorKey,
+    ) -> Result<Option<u64>, PhalaAvsError> {
+        let raw = match std::fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(PhalaAvsError::StorageError(format!(
+                    "Failed to read legacy cursor {}: {e}",
+                    path.display()
+                )));
+            }
+        };
+        let block: u64 = raw.trim().parse().map_err(|e| {
+            PhalaAvsError::StorageError(format!("Invalid legacy cursor {}: {e}", path.display()))
+        })?;
+
+        let applied = if self.get(&primary).is_none() {
+            self.persist(primary, block)?;
+            self.cursors().blocks.insert(primary, block);
+            info!(
+                "Migrated legacy cursor {block} from {} to the primary target {}",
+                path.display(),
+                primary.contract
+            );
+            Some(block)
+        } else {
+            // Migrated before, but archiving the file did not complete.
+            None
+        };
+        let mut archived = path.as_os_str().to_owned();
+        archived.push(".migrated");
+        std::fs::rename(path, &archived).map_err(|e| {
+            PhalaAvsError::StorageError(format!(
+                "Failed to archive legacy cursor {}: {e}",
+                path.display()
+            ))
+        })?;
+        Ok(applied)
+    }
+}
+
+/// Reads the legacy cursor file path from `CURSOR_LEGACY_FILE`.
+pub fn legacy_file_from_env() -> Result<PathBuf, PhalaAvsError> {
+    match env_opt("CURSOR_LEGACY_FILE")? {
+        Some(path) => Ok(path),
+        None => Ok(StateConfig::from_env()?
+            .state_dir
+            .join("last_processed_block")),
+    }
+}
+
+/// The block each oracle's challenge logs in `events` are processed through, by oracle.
+///
+/// An oracle with `failed` logs is held before its first failed block, so those are replayed.
+pub fn processed_through(events: &[Log], failed: &[Log]) -> BTreeMap<Address, u64> {
+    let mut through = BTreeMap::new();
+    for event in events.iter().filter(|e| is_challenge_event(e)) {
+        if let Some(block) = event.block_number {
+            let entry = through.entry(event.address()).or_insert(block);
+            *entry = (*entry).max(block);
+        }
+    }
+    for event in failed {
+        if let (Some(block), Some(entry)) = (event.block_number, through.get_mut(&event.address()))
+        {
+            *entry = (*entry).min(block.saturating_sub(1));
+        }
+    }
+    through
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    const A: Address = Address::repeat_byte(0xaa);
+    const B: Address = Address::repeat_byte(0xbb);
+
+    #[test]
+    fn legacy_cursor_migrates_to_the_primary_target_once() {
+        let dir = std::env::temp_dir().join(format!("phala-avs-cursor-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("last_processed_block");
+        std::fs::write(&path, "1234\n").unwrap();
+        let cursors = CursorStore::new(Arc::new(MemoryStateStore::default())).unwrap();
+
+        let primary = CursorKey::challenges(1, A);
+        assert_eq!(cursors.migrate_legacy(&path, primary).unwrap(), Some(1234));
+        assert_eq!(cursors.get(&primary), Some(1234));
+        assert_eq!(cursors.get(&CursorKey::challenges(1, B)), None);
+        assert!(!path.exists());
+        assert!(dir.join("last_processed_block.migrated").exists());
+
+        // Once archived there is nothing left to migrate.
+        assert_eq!(cursors.migrate_legacy(&path, primary).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn targets_advance_independently_and_recover_their_own_range() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let cursors = CursorStore::new(Arc::clone(&store)).unwrap();
+        let (a, b) = (CursorKey::challenges(1, A), CursorKey::challenges(1, B));
+        cursors.advance(a, 100);
+        cursors.advance(b, 100);
+        assert_eq!(cursors.flush().unwrap(), 2);
+
+        // A's next range is flushed; the operator crashes before B's is.
+        cursors.advance(a, 150);
+        cursors.advance(a, 120);
+        assert_eq!(cursors.flush().unwrap(), 1);
+        cursors.advance(b, 150);
+        assert_eq!(
+            cursors.catch_up_from(1, ProducerKind::ChallengeLogs),
+            Some(151)
+        );
+
+        let recovered = CursorStore::new(store).unwrap();
+        assert_eq!(recovered.get(&a), Some(150));
+        assert_eq!(recovered.get(&b), Some(100));
+        assert_eq!(
+            recovered.catch_up_from(1, ProducerKind::ChallengeLogs),
+            Some(101)
+        );
+        assert_eq!(
+            recovered.catch_up_from(2, ProducerKind::ChallengeLogs),
+            None
+        );
+
+        // A reorg at block 140 rewinds A only.
+        assert!(recovered.rewind(a, 140).unwrap());
+        assert!(!recovered.rewind(b, 140).unwrap());
+        recovered.observe_head(160);
+        let status = recovered.status();
+        assert_eq!((status[0].block, status[0].lag), (139, Some(21)));
+        assert_eq!((status[1].block, status[1].lag), (100, Some(60)));
+    }
+}
@@ -0,0 +1,632 @@
+//! Delegation of challenge responses to responders embedded in workloads.
+//!
+//! Some workloads hold the best evidence about themselves and would rather answer the SLA
+//! challenges about them; the operator then only checks, countersigns and submits the answer.
+//! Deploy tooling issues such a workload a token (`POST /admin/workloads/{id}/responder-token`),
+//! with which the workload registers its responder endpoint (`PUT /workloads/{id}/responder`).
+//!
+//! Kinds listed in `DELEGATION_KINDS` are delegable; their envelope params lead with the
+//! `bytes32` id of the workload the challenge is about. A challenge of such a kind whose workload
+//! registered a responder is forwarded to it, which has `DELEGATION_BUDGET_MS` to answer. The
+//! payload must fit in `DELEGATION_MAX_PAYLOAD_BYTES` and pass the encoder's
+//! [`ResponseEncoder::validate`]; the operator then signs
+//! `keccak256(abi.encode(challengeId, workloadId, keccak256(payload)))` over it. A timeout,
+//! failure or invalid payload falls back to the operator's own proof builder. Every outcome is
+//! recorded on the challenge tracker and counted in [`DELEGATION_OUTCOMES_METRIC`].
+
+use crate::api_keys::constant_time_eq;
+use crate::challenge::ObservedChallenge;
+use crate::config::{self, env_or};
+use crate::encoding::{ChallengeEnvelope, ResponseEncoder, kind_id};
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{B256, Bytes, U256, keccak256};
+use blueprint_sdk::alloy::signers::SignerSync;
+use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+use blueprint_sdk::alloy::sol_types::SolValue;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Counter of delegated challenges, by kind and outcome (`delegated`, `fallback`, `rejected`).
+pub const DELEGATION_OUTCOMES_METRIC: &str = "phala_avs_delegation_outcomes_total";
+
+/// StateStore namespace holding each workload's responder token hash and endpoint.
+const NAMESPACE: &str = "workload_responders";
+
+#[derive(Clone, Debug)]
+pub struct DelegationConfig {
+    /// Kinds whose responses may be delegated.
+    pub kinds: BTreeSet<B256>,
+    /// How long a responder has to answer.
+    pub budget: Duration,
+    pub max_payload_bytes: usize,
+}
+
+impl Default for DelegationConfig {
+    fn default() -> Self {
+        Self {
+            kinds: BTreeSet::new(),
+            budget: Duration::from_millis(2_000),
+            max_payload_bytes: 16 * 1024,
+        }
+    }
+}
+
+impl DelegationConfig {
+    /// Reads `DELEGATION_KINDS` (comma-separated kind names), `DELEGATION_BUDGET_MS` and
+    /// `DELEGATION_MAX_PAYLOAD_BYTES`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        let kinds = config::lookup("DELEGATION_KINDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(kind_id)
+            .collect();
+        Ok(Self {
+            kinds,
+            budget: Duration::from_millis(env_or(
+                "DELEGATION_BUDGET_MS",
+                defaults.budget.as_millis() as u64,
+            )?),
+            max_payload_bytes: env_or("DELEGATION_MAX_PAYLOAD_BYTES", defaults.max_payload_bytes)?,
+        })
+    }
+}
+
+/// The workload a challenge of a delegable kind is about: the leading word of its params.
+pub fn challenge_workload(challenge: &ObservedChallenge) -> Option<B256> {
+    let envelope = ChallengeEnvelope::abi_decode_params(&challenge.challenge_data, true).ok()?;
+    envelope.params.get(..32).map(B256::from_slice)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ResponderRecord {
+    token_hash: B256,
+    issued_unix_ms: u64,
+    endpoint: Option<String>,
+    registered_unix_ms: Option<u64>,
+}
+
+/// A workload's registered responder.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisteredResponder {
+    pub workload_id: B256,
+    pub endpoint: String,
+    pub registered_unix_ms: u64,
+}
+
+/// Per-workload responder tokens and endpoints, persisted in [`StateStore`].
+#[derive(Clone)]
+pub struct ResponderRegistry {
+    store: Arc<dyn StateStore>,
+}
+
+impl ResponderRegistry {
+    pub fn new(store: Arc<dyn StateStore>) -> Self {
+        Self { store }
+    }
+
+    /// Issues a new responder token for a workload, revoking its previous token and endpoint.
+    /// Only the token's hash is kept, so it is returned this once.
+    pub fn issue_token(&self, workload_id: B256, now_ms: u64) -> Result<String, PhalaAvsError> {
+        let token = hex::encode(
+            [
+                *uuid::Uuid::new_v4().as_bytes(),
+                *uuid::Uuid::new_v4().as_bytes(),
+            ]
+            .concat(),
+        );
+        let record = ResponderRecord {
+            token_hash: keccak256(token.as_bytes()),
+            issued_unix_ms: now_ms,
+            endpoint: None,
+            registered_unix_ms: None,
+        };
+        self.store
+            .put_json(NAMESPACE, workload_id.as_slice(), &record)?;
+        info!("Issued a responder token for workload {workload_id}");
+        Ok(token)
+    }
+
+    /// Registers the responder endpoint of a workload holding a valid `token`. Returns `None`
+    /// when the token is not the workload's.
+    pub fn register(
+        &self,
+        workload_id: B256,
+        token: &str,
+        endpoint: &str,
+        now_ms: u64,
+    ) -> Result<Option<RegisteredResponder>, PhalaAvsError> {
+        let Some(mut record) = self
+            .store
+            .get_json::<ResponderRecord>(NAMESPACE, workload_id.as_slice())?
+        else {
+            return Ok(None);
+        };
+        if !constant_time_eq(
+            keccak256(token.as_bytes()).as_slice(),
+            record.token_hash.as_slice(),
+        ) {
+            return Ok(None);
+        }
+        if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Responder endpoint {endpoint:?} is not an HTTP URL"
+            )));
+        }
+        record.endpoint = Some(endpoint.to_string());
+        record.registered_unix_ms = Some(now_ms);
+        self.store
+            .put_json(NAMESPACE, workload_id.as_slice(), &record)?;
+        info!("Workload {workload_id} registered responder {endpoint}");
+        Ok(Some(RegisteredResponder {
+            workload_id,
+            endpoint: endpoint.to_string(),
+            registered_unix_ms: now_ms,
+        }))
+    }
+
+    /// The registered responder of a workload, if any.
+    pub fn responder(
+        &self,
+        workload_id: B256,
+    ) -> Result<Option<RegisteredResponder>, PhalaAvsError> {
+        let record = self
+            .store
+            .get_json::<ResponderRecord>(NAMESPACE, workload_id.as_slice())?;
+        Ok(record.and_then(|r| {
+            Some(RegisteredResponder {
+                workload_id,
+                endpoint: r.endpoint?,
+                registered_unix_ms: r.registered_unix_ms?,
+            })
+        }))
+    }
+}
+
+/// A decoded challenge, as forwarded to a workload's responder.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelegatedChallenge {
+    pub challenge_id: U256,
+    pub workload_id: B256,
+    pub kind: String,
+    pub version: u32,
+    /// The ABI tuple type the payload must encode.
+    pub schema: String,
+    pub params: Bytes,
+    pub deadline_block: u64,
+    pub budget_ms: u64,
+    pub max_payload_bytes: usize,
+}
+
+/// A responder's answer: the response payload, encoded in the challenge's schema.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DelegatedReply {
+    pub payload: Bytes,
+}
+
+/// The responders embedded in workloads.
+pub trait WorkloadResponder: Send + Sync {
+    fn respond(
+        &self,
+        endpoint: &str,
+        challenge: &DelegatedChallenge,
+    ) -> BoxFuture<'_, Result<Bytes, PhalaAvsError>>;
+}
+
+/// [`WorkloadResponder`] posting challenges to the registered endpoint.
+#[derive(Clone, Debug, Default)]
+pub struct HttpWorkloadResponder {
+    client: reqwest::Client,
+}
+
+impl WorkloadResponder for HttpWorkloadResponder {
+    fn respond(
+        &self,
+        endpoint: &str,
+        challenge: &DelegatedChallenge,
+    ) -> BoxFuture<'_, Result<Bytes, PhalaAvsError>> {
+        let endpoint = endpoint.to_string();
+        let challenge = challenge.clone();
+        Box::pin(async move {
+            let reply: DelegatedReply = self
+                .client
+                .post(endpoint)
+                .json(&challenge)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| PhalaAvsError::TeeError(format!("Workload responder failed: {e}")))?
+                .json()
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::TeeError(format!("Invalid workload responder reply: {e}"))
+                })?;
+            Ok(reply.payload)
+        })
+    }
+}
+
+/// What came of delegating a challenge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DelegationOutcome {
+    /// The workload's payload is submitted, countersigned by the operator.
+    Delegated,
+    /// The responder timed out or failed; the operator builds the response.
+    Fallback,
+    /// The payload was invalid; the operator builds the response.
+    Rejected,
+}
+
+impl DelegationOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DelegationOutcome::Delegated => "delegated",
+            DelegationOutcome::Fallback => "fallback",
+            DelegationOutcome::Rejected => "rejected",
+        }
+    }
+}
+
+/// A delegation attempt, as recorded on the challenge tracker.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelegationRecord {
+    pub workload_id: B256,
+    pub outcome: DelegationOutcome,
+    /// Why the operator fell back to its own builder.
+    pub reason: Option<String>,
+    pub elapsed_ms: u64,
+    pub unix_ms: u64,
+}
+
+/// A workload's payload with the operator's signature over it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DelegatedResponse {
+    pub workload_id: B256,
+    pub payload: Bytes,
+    pub operator_signature: Bytes,
+}
+
+/// The result of [`Delegator::delegate`]; `response` is set when the outcome is
+/// [`DelegationOutcome::Delegated`].
+#[derive(Clone, Debug)]
+pub struct Delegation {
+    pub record: DelegationRecord,
+    pub response: Option<DelegatedResponse>,
+}
+
+/// Digest the operator signs over a delegated payload.
+pub fn countersign_digest(challenge_id: U256, workload_id: B256, payload: &[u8]) -> B256 {
+    keccak256((challenge_id, workload_id, keccak256(payload)).abi_encode())
+}
+
+/// Forwards delegable challenges to their workload's responder.
+pub struct Delegator {
+    config: DelegationConfig,
+    registry: ResponderRegistry,
+    responder: Arc<dyn WorkloadResponder>,
+    signer: PrivateKeySigner,
+}
+
+impl Delegator {
+    pub fn new(
+        config: DelegationConfig,
+        store: Arc<dyn StateStore>,
+        responder: Arc<dyn WorkloadResponder>,
+        signer: PrivateKeySigner,
+    ) -> Self {
+        Self {
+            config,
+            registry: ResponderRegistry::new(store),
+            responder,
+            signer,
+        }
+    }
+
+    pub fn config(&self) -> &DelegationConfig {
+        &self.config
+    }
+
+    pub fn registry(&self) -> &ResponderRegistry {
+        &self.registry
+    }
+
+    /// Asks the challenge's workload for the response. Returns `None` without asking when the
+    /// kind is not delegable or the workload has no responder.
+    pub async fn delegate(
+        &self,
+        challenge: &ObservedChallenge,
+        encoder: &dyn ResponseEncoder,
+    ) -> Result<Option<Delegation>, PhalaAvsError> {
+        if !self.config.kinds.contains(&encoder.key().kind) {
+            return Ok(None);
+        }
+        let Some(workload_id) = challenge_workload(challenge) else {
+            return Ok(None);
+        };
+        let Some(responder) = self.registry.responder(workload_id)? else {
+            return Ok(None);
+        };
+        let envelope = ChallengeEnvelope::abi_decode_params(&challenge.challenge_data, true)
+            .map_err(|e| {
+                PhalaAvsError::ValidationError(format!("Invalid challenge envelope: {e}"))
+            })?;
+        let request = DelegatedChallenge {
+            challenge_id: challenge.challenge_id,
+            workload_id,
+            kind: encoder.kind_name().to_string(),
+            version: encoder.version(),
+            schema: encoder.schema().to_string(),
+            params: envelope.params,
+            deadline_block: challenge.deadline_block,
+            budget_ms: self.config.budget.as_millis() as u64,
+            max_payload_bytes: self.config.max_payload_bytes,
+        };
+
+        let started = Instant::now();
+        let answered = tokio::time::timeout(
+            self.config.budget,
+            self.responder.respond(&responder.endpoint, &request),
+        )
+        .await;
+        let checked = match answered {
+            Err(_) => Err((
+                DelegationOutcome::Fallback,
+                format!(
+                    "responder did not answer within {}ms",
+                    self.config.budget.as_millis()
+                ),
+            )),
+            Ok(Err(e)) => Err((DelegationOutcome::Fallback, e.to_string())),
+            Ok(Ok(payload)) => self.check(challenge, encoder, workload_id, payload),
+        };
+        let (outcome, reason, response) = match checked {
+            Ok(response) => (DelegationOutcome::Delegated, None, Some(response)),
+            Err((outcome, reason)) => {
+                warn!(
+                    "Falling back from delegating challenge {} to workload {workload_id}: {reason}",
+                    challenge.challenge_id
+                );
+                (outcome, Some(reason), None)
+            }
+        };
+        METRICS.inc_counter(
+            DELEGATION_OUTCOMES_METRIC,
+            &[("kind", encoder.kind_name()), ("outcome", outcome.as_str())],
+            1,
+        );
+        Ok(Some(Delegation {
+            record: DelegationRecord {
+                workload_id,
+                outcome,
+                reason,
+                elapsed_ms: started.elapsed().as_millis() as u64,
+                unix_ms: now_unix_ms(),
+            },
+            response,
+        }))
+    }
+
+    /// Validates a responder's payload and countersigns it.
+    fn check(
+        &self,
+        challenge: &ObservedChallenge,
+        encoder: &dyn ResponseEncoder,
+        workload_id: B256,
+        payload: Bytes,
+    ) -> Result<DelegatedResponse, (DelegationOutcome, String)> {
+        if payload.len() > self.config.max_payload_bytes {
+            return Err((
+                DelegationOutcome::Rejected,
+                format!(
+                    "payload is {} bytes, the limit is {}",
+                    payload.len(),
+                    self.config.max_payload_bytes
+                ),
+            ));
+        }
+        encoder
+            .validate(challenge, &payload)
+            .map_err(|e| (DelegationOutcome::Rejected, e.to_string()))?;
+        let digest = countersign_digest(challenge.challenge_id, workload_id, &payload);
+        let signature = self.signer.sign_hash_sync(&digest).map_err(|e| {
+            (
+                DelegationOutcome::Fallback,
+                format!("countersigning failed: {e}"),
+            )
+        })?;
+        Ok(DelegatedResponse {
+            workload_id,
+            payload,
+            operator_signature: Bytes::copy_from_slice(&signature.as_bytes()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{
+        ATTESTATION_KIND, AttestationChallengeV1, AttestationEncoderV1, AttestationResponseV1,
+        LivenessResponseV1, envelope,
+    };
+    use crate::state::MemoryStateStore;
+    use blueprint_sdk::alloy::primitives::{Address, PrimitiveSignature};
+    use std::sync::Mutex;
+
+    const WORKLOAD: B256 = B256::repeat_byte(0x42);
+
+    /// A responder answering with `reply` after `delay`.
+    struct MockResponder {
+        delay: Duration,
+        reply: Bytes,
+        asked: Mutex<Vec<DelegatedChallenge>>,
+    }
+
+    impl WorkloadResponder for MockResponder {
+        fn respond(
+            &self,
+            endpoint: &str,
+            challenge: &DelegatedChallenge,
+        ) -> BoxFuture<'_, Result<Bytes, PhalaAvsError>> {
+            assert_eq!(endpoint, "http://workload:8080/respond");
+            self.asked.lock().unwrap().push(challenge.clone());
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                Ok(self.reply.clone())
+            })
+        }
+    }
+
+    fn challenge() -> ObservedChallenge {
+        let params = AttestationChallengeV1 { nonce: WORKLOAD }.abi_encode_params();
+        ObservedChallenge {
+            challenge_id: U256::from(7),
+            operator: Address::repeat_byte(1),
+            challenge_data: envelope(ATTESTATION_KIND, 1, params.into()),
+            deadline_block: 100,
+            oracle: Address::repeat_byte(2),
+            issued_block: 10,
+            issued_block_hash: None,
+            transaction_hash: None,
+        }
+    }
+
+    fn answer(challenge_id: u64) -> Bytes {
+        AttestationResponseV1 {
+            challengeId: U256::from(challenge_id),
+            quotedAtUnix: 1_700_000_000,
+            quote: Bytes::from_static(b"quote"),
+        }
+        .abi_encode()
+        .into()
+    }
+
+    fn delegator(delay: Duration, reply: Bytes) -> (Delegator, Arc<MockResponder>) {
+        let responder = Arc::new(MockResponder {
+            delay,
+            reply,
+            asked: Mutex::default(),
+        });
+        let config = DelegationConfig {
+            kinds: [kind_id(ATTESTATION_KIND)].into(),
+            budget: Duration::from_millis(100),
+            ..DelegationConfig::default()
+        };
+        let delegator = Delegator::new(
+            config,
+            Arc::new(MemoryStateStore::default()),
+            Arc::clone(&responder) as _,
+            PrivateKeySigner::random(),
+        );
+        let token = delegator.registry().issue_token(WORKLOAD, 1).unwrap();
+        delegator
+            .registry()
+            .register(WORKLOAD, &token, "http://workload:8080/respond", 2)
+            .unwrap()
+            .unwrap();
+        (delegator, responder)
+    }
+
+    #[tokio::test]
+    async fn valid_payloads_are_countersigned() {
+        let (delegator, responder) = delegator(Duration::ZERO, answer(7));
+        let delegation = delegator
+            .delegate(&challenge(), &AttestationEncoderV1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(delegation.record.outcome, DelegationOutcome::Delegated);
+        assert_eq!(delegation.record.workload_id, WORKLOAD);
+        let response = delegation.response.unwrap();
+        assert_eq!(response.payload, answer(7));
+
+        let digest = countersign_digest(U256::from(7), WORKLOAD, &response.payload);
+        let signature = PrimitiveSignature::try_from(&response.operator_signature[..]).unwrap();
+        assert_eq!(
+            signature.recover_address_from_prehash(&digest).unwrap(),
+            delegator.signer.address()
+        );
+        let asked = responder.asked.lock().unwrap();
+        assert_eq!(asked[0].kind, ATTESTATION_KIND);
+        assert_eq!(asked[0].budget_ms, 100);
+    }
+
+    #[tokio::test]
+    async fn slow_responders_fall_back() {
+        let (delegator, _) = delegator(Duration::from_secs(5), answer(7));
+        let delegation = delegator
+            .delegate(&challenge(), &AttestationEncoderV1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(delegation.record.outcome, DelegationOutcome::Fallback);
+        assert!(delegation.record.reason.unwrap().contains("100ms"));
+        assert!(delegation.response.is_none());
+    }
+
+    #[tokio::test]
+    async fn payloads_outside_the_schema_are_rejected() {
+        let liveness: Bytes = LivenessResponseV1 {
+            challengeId: U256::from(7),
+            respondedAtUnix: 1,
+            live: true,
+        }
+        .abi_encode()
+        .into();
+        for payload in [liveness, answer(8), Bytes::from(vec![0u8; 32 * 1024])] {
+            let (delegator, _) = delegator(Duration::ZERO, payload);
+            let delegation = delegator
+                .delegate(&challenge(), &AttestationEncoderV1)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(delegation.record.outcome, DelegationOutcome::Rejected);
+            assert!(delegation.response.is_none());
+        }
+        assert!(
+            METRICS
+                .counter(DELEGATION_OUTCOMES_METRIC, &[
+                    ("kind", ATTESTATION_KIND),
+                    ("outcome", "rejected")
+                ])
+                .is_some_and(|n| n >= 3)
+        );
+    }
+
+    #[tokio::test]
+    async fn only_registered_workloads_are_asked() {
+        let (delegator, responder) = delegator(Duration::ZERO, answer(7));
+        let registry = delegator.registry();
+        assert!(
+            registry
+                .register(WORKLOAD, "wrong", "http://evil", 3)
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            registry
+                .register(B256::ZERO, "any", "http://evil", 3)
+                .unwrap()
+                .is_none()
+        );
+        // A reissued token revokes the registered endpoint.
+        registry.issue_token(WORKLOAD, 4).unwrap();
+        assert!(registry.responder(WORKLOAD).unwrap().is_none());
+        let delegation = delegator
+            .delegate(&challenge(), &AttestationEncoderV1)
+            .await
+            .unwrap();
+        assert!(delegation.is_none());
+        assert!(responder.asked.lock().unwrap().is_empty());
+    }
+}
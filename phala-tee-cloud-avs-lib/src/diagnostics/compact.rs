@@ -0,0 +1,170 @@
+//! Compact diagnostics container.
+//!
+//! Layout:
+//!
+//! ```text
+//! MAGIC (8 bytes) | index length (u32 LE) | index (JSON) | section frames...
+//! ```
+//!
+//! Each section is compressed independently with zstd and the index records its offset (from the
+//! end of the index), so a single section can be read without loading the rest of the file.
+
+use super::Section;
+use crate::error::PhalaAvsError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 8] = b"PHDIAG\x00\x01";
+
+/// zstd level used for sections; favours size since bundles are written rarely.
+const COMPRESSION_LEVEL: i32 = 19;
+
+/// Upper bound on the index size accepted when reading, to reject garbage input early.
+const MAX_INDEX_BYTES: u32 = 1 << 20;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SectionEntry {
+    pub name: String,
+    pub content_type: String,
+    pub offset: u64,
+    pub compressed_len: u64,
+    pub raw_len: u64,
+    /// Hex SHA-256 of the uncompressed section.
+    pub sha256: String,
+}
+
+/// Writes `sections` as a compact container.
+pub fn write_compact(sections: &[Section], out: &mut impl Write) -> Result<(), PhalaAvsError> {
+    let mut index = Vec::with_capacity(sections.len());
+    let mut frames = Vec::with_capacity(sections.len());
+    let mut offset = 0u64;
+    for section in sections {
+        let compressed = zstd::encode_all(section.data.as_slice(), COMPRESSION_LEVEL)?;
+        index.push(SectionEntry {
+            name: section.name.clone(),
+            content_type: section.content_type.clone(),
+            offset,
+            compressed_len: compressed.len() as u64,
+            raw_len: section.data.len() as u64,
+            sha256: hex::encode(Sha256::digest(&section.data)),
+        });
+        offset += compressed.len() as u64;
+        frames.push(compressed);
+    }
+
+    let index = serde_json::to_vec(&index)
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to encode bundle index: {e}")))?;
+    out.write_all(MAGIC)?;
+    out.write_all(&(index.len() as u32).to_le_bytes())?;
+    out.write_all(&index)?;
+    for frame in frames {
+        out.write_all(&frame)?;
+    }
+    Ok(())
+}
+
+/// Reads sections out of a compact container on demand.
+#[derive(Debug)]
+pub struct CompactReader<R> {
+    reader: R,
+    index: Vec<SectionEntry>,
+    data_start: u64,
+}
+
+impl CompactReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, PhalaAvsError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> CompactReader<R> {
+    /// Reads and validates the header and index only.
+    pub fn new(mut reader: R) -> Result<Self, PhalaAvsError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(PhalaAvsError::ValidationError(
+                "not a compact diagnostics bundle".to_string(),
+            ));
+        }
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if len > MAX_INDEX_BYTES {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "bundle index of {len} bytes is too large"
+            )));
+        }
+        let mut index = vec![0u8; len as usize];
+        reader.read_exact(&mut index)?;
+        let index = serde_json::from_slice(&index)
+            .map_err(|e| PhalaAvsError::ValidationError(format!("corrupt bundle index: {e}")))?;
+        Ok(Self {
+            reader,
+            index,
+            data_start: 12 + len as u64,
+        })
+    }
+
+    pub fn index(&self) -> &[SectionEntry] {
+        &self.index
+    }
+
+    /// Decompresses section `name`, verifying its hash.
+    pub fn read_section(&mut self, name: &str) -> Result<Section, PhalaAvsError> {
+        let entry = self
+            .index
+            .iter()
+            .find(|e| e.name == name)
+            .cloned()
+            .ok_or_else(|| PhalaAvsError::ValidationError(format!("no section named {name}")))?;
+        self.reader
+            .seek(SeekFrom::Start(self.data_start + entry.offset))?;
+        let frame = (&mut self.reader).take(entry.compressed_len);
+        let data = zstd::decode_all(frame)?;
+        if data.len() as u64 != entry.raw_len || hex::encode(Sha256::digest(&data)) != entry.sha256
+        {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "section {name} is corrupt"
+            )));
+        }
+        Ok(Section {
+            name: entry.name,
+            content_type: entry.content_type,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn sections_round_trip_individually() {
+        let sections = vec![
+            Section::text("metrics", "phala_avs_up 1\n".repeat(1000)),
+            Section::json("status", &serde_json::json!({ "ready": true })).unwrap(),
+        ];
+        let mut out = Vec::new();
+        write_compact(&sections, &mut out).unwrap();
+        assert!(out.len() < sections[0].data.len());
+
+        let mut reader = CompactReader::new(Cursor::new(&out)).unwrap();
+        let names: Vec<_> = reader.index().iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["metrics", "status"]);
+        assert_eq!(reader.read_section("status").unwrap(), sections[1]);
+        assert_eq!(reader.read_section("metrics").unwrap(), sections[0]);
+        assert!(reader.read_section("logs").is_err());
+
+        // Flipping a byte in the last frame is detected.
+        let last = out.len() - 1;
+        out[last] ^= 0xff;
+        let mut reader = CompactReader::new(Cursor::new(&out)).unwrap();
+        assert!(reader.read_section("status").is_err());
+    }
+}
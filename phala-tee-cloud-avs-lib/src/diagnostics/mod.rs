@@ -0,0 +1,297 @@
+//! Diagnostics bundles operators attach to support tickets.
+//!
+//! A bundle is a set of named sections collected from the running operator. It is rendered as a
+//! single JSON document, or in the [`compact`] container for slow links, which can be uploaded
+//! in resumable chunks with [`upload`].
+
+pub mod compact;
+pub mod upload;
+
+use crate::config::{self, EffectiveValue};
+use crate::context::PhalaAvsContext;
+use crate::error::PhalaAvsError;
+use crate::error_catalog;
+use crate::logs::LOG_RING;
+use crate::metrics::METRICS;
+use crate::startup::StartupStatus;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+pub use compact::{CompactReader, SectionEntry, write_compact};
+pub use upload::{UploadConfig, Uploader};
+
+/// Environment variable prefixes included in the `config` section.
+const CONFIG_PREFIXES: &[&str] = &[
+    "STATE_",
+    "CURSOR_",
+    "STARTUP_",
+    "STATUS_",
+    "REPUTATION_",
+    "RESPONSE_MARGIN_",
+    "RESPONSE_SCHEDULER_",
+    "RESPONSE_SAFETY_",
+    "RESPONSE_DOMAIN_",
+    "RESTART_",
+    "REPLICA_",
+    "RETRY_",
+    "RECEIPT_",
+    "REMOTE_WRITE_",
+    "CHALLENGE_",
+    "OPERATOR_SET_",
+    "QUORUM_",
+    "MAINTENANCE_",
+    "MEMORY_",
+    "DRIFT_",
+    "DUTY_",
+    "SELF_AUDIT_",
+    "SLO_",
+    "EXIT_",
+    "DISK_",
+    "FEE_MODEL_",
+    "SIGN_BATCH_",
+    "SIGNER_",
+    "SIGNED_PAYLOAD_",
+    "DETECTION_",
+    "DELEGATION_",
+    "API_",
+    "ADMIN_APPROV",
+    "ANNOTATION_",
+    "COLLATERAL_",
+    "DCAP_",
+    "LOG_RING_",
+    "LOG_CHECK_",
+    "DIAGNOSTICS_",
+    "CHAOS_",
+    "SCHEMA_",
+    "ENCODER_ROLLOUT",
+    "EVIDENCE_",
+    "WORKLOAD_PRIVACY",
+    "TEE_COMPUTE_",
+    "TEE_HOST_",
+    "TEE_PLATFORM",
+    "TEE_TAPPD_",
+    "TEE_LIVENESS_",
+    "TEE_ENDPOINT",
+    "TEE_FLEET",
+    "TEE_HEALTH_",
+    "TEE_REQUEST_",
+    "TEE_RETRIES",
+    "TEE_SIGNER_",
+    "TEE_TLS_",
+    "TEE_QUOTE_CACHE_",
+    "TEE_RA_TLS",
+    "TEE_MEASUREMENT_",
+    "PHALA_CLOUD_",
+    "TX_",
+    "CAPACITY_",
+    "UPGRADE_",
+    "ATTESTATION_",
+    "HEALTH_",
+    "HEARTBEAT_",
+    "ARTIFACT_",
+    "ALERT_",
+    "REGISTRATION_",
+    "REIMBURSEMENT_",
+    "FORCE_SUBMIT_WHEN_UNREGISTERED",
+    "SLA_ORACLE_ADDRESS",
+    "TASK_MANAGER_ADDRESS",
+    "SERVICE_MANAGER_ADDRESS",
+    "RUST_LOG",
+];
+
+/// Bundle rendering selected with `--format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BundleFormat {
+    #[default]
+    Json,
+    Compact,
+}
+
+impl FromStr for BundleFormat {
+    type Err = PhalaAvsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "compact" => Ok(Self::Compact),
+            other => Err(PhalaAvsError::ValidationError(format!(
+                "unknown diagnostics format {other:?}, expected json or compact"
+            ))),
+        }
+    }
+}
+
+/// A named piece of diagnostics data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    /// `application/json` or `text/plain`.
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Section {
+    pub fn json<T: Serialize>(name: &str, value: &T) -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            name: name.to_string(),
+            content_type: "application/json".to_string(),
+            data: serde_json::to_vec(value)
+                .map_err(|e| PhalaAvsError::Other(format!("Failed to encode {name}: {e}")))?,
+        })
+    }
+
+    pub fn text(name: &str, text: String) -> Self {
+        Self {
+            name: name.to_string(),
+            content_type: "text/plain".to_string(),
+            data: text.into_bytes(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DiagnosticsBundle {
+    pub sections: Vec<Section>,
+}
+
+impl DiagnosticsBundle {
+    /// Collects every section. Context-backed sections are omitted while the operator is still
+    /// starting.
+    pub fn collect(
+        startup: &StartupStatus,
+        context: Option<&PhalaAvsContext>,
+    ) -> Result<Self, PhalaAvsError> {
+        let logs = LOG_RING.snapshot();
+        let heartbeat: Vec<_> = logs
+            .iter()
+            .filter(|e| {
+                e.message.starts_with("Heartbeat") || e.message.starts_with("Running heartbeat")
+            })
+            .collect();
+
+        let mut sections = vec![
+            Section::json("config", &config_snapshot())?,
+            Section::json("status", &startup.snapshot())?,
+            Section::json("logs", &logs)?,
+        ];
+        if let Some(context) = context {
+            sections.push(Section::json(
+                "challenges",
+                &context.challenge_tracker.snapshot(),
+            )?);
+            sections.push(Section::json(
+                "skipped_ranges",
+                &context.catch_up.skipped()?,
+            )?);
+            sections.push(Section::json("tx_intents", &context.tx_sender.intents()?)?);
+        }
+        sections.push(Section::json("heartbeat", &heartbeat)?);
+        sections.push(Section::json("contract_errors", &error_catalog::recent())?);
+        sections.push(Section::text("metrics", METRICS.render()));
+        Ok(Self { sections })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// Renders the bundle in `format`.
+    pub fn render(&self, format: BundleFormat) -> Result<Vec<u8>, PhalaAvsError> {
+        match format {
+            BundleFormat::Json => self.to_json(),
+            BundleFormat::Compact => {
+                let mut out = Vec::new();
+                write_compact(&self.sections, &mut out)?;
+                Ok(out)
+            }
+        }
+    }
+
+    /// A single JSON object keyed by section name; text sections are embedded as strings.
+    pub fn to_json(&self) -> Result<Vec<u8>, PhalaAvsError> {
+        let mut object = serde_json::Map::new();
+        for section in &self.sections {
+            let value = if section.content_type == "application/json" {
+                serde_json::from_slice(&section.data)
+                    .map_err(|e| PhalaAvsError::Other(format!("Invalid {}: {e}", section.name)))?
+            } else {
+                serde_json::Value::String(String::from_utf8_lossy(&section.data).into_owned())
+            };
+            object.insert(section.name.clone(), value);
+        }
+        serde_json::to_vec_pretty(&object)
+            .map_err(|e| PhalaAvsError::Other(format!("Failed to encode bundle: {e}")))
+    }
+}
+
+/// Operator configuration from the environment and config file, with secrets redacted.
+fn config_snapshot() -> BTreeMap<String, String> {
+    config::effective()
+        .into_iter()
+        .filter(|v| CONFIG_PREFIXES.iter().any(|p| v.key.starts_with(p)))
+        .map(EffectiveValue::redacted)
+        .filter_map(|v| Some((v.key, v.value?)))
+        .collect()
+}
+
+/// Fetches a bundle from a running operator's admin API.
+pub async fn fetch_bundle(
+    operator_url: &str,
+    admin_token: &str,
+    format: BundleFormat,
+) -> Result<Vec<u8>, PhalaAvsError> {
+    let format = match format {
+        BundleFormat::Json => "json",
+        BundleFormat::Compact => "compact",
+    };
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/admin/diagnostics?format={format}",
+            operator_url.trim_end_matches('/')
+        ))
+        .bearer_auth(admin_token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    if !response.status().is_success() {
+        return Err(PhalaAvsError::Other(format!(
+            "Operator returned {} for diagnostics",
+            response.status()
+        )));
+    }
+    response
+        .bytes()
+        .await
+        .map(|b| b.to_vec())
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to read diagnostics: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn collected_bundle_is_inspectable_in_compact_form() {
+        let bundle = DiagnosticsBundle::collect(&StartupStatus::default(), None).unwrap();
+        let compact = bundle.render(BundleFormat::Compact).unwrap();
+
+        let mut reader = CompactReader::new(Cursor::new(compact)).unwrap();
+        let names: Vec<_> = reader.index().iter().map(|e| e.name.clone()).collect();
+        assert_eq!(
+            names,
+            ["config", "status", "logs", "heartbeat", "contract_errors", "metrics"]
+        );
+        assert_eq!(
+            reader.read_section("status").unwrap(),
+            *bundle.section("status").unwrap()
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&bundle.render(BundleFormat::Json).unwrap()).unwrap();
+        assert!(json["metrics"].is_string());
+        assert!(json["logs"].is_array());
+    }
+}
@@ -0,0 +1,303 @@
+//! Resumable, chunked upload of diagnostics bundles to a support endpoint.
+//!
+//! Protocol:
+//!
+//! - `POST {endpoint}` with `X-Bundle-Sha256` and `X-Bundle-Length` starts an upload and returns
+//!   `{"upload_id": ...}`.
+//! - `GET {endpoint}/{upload_id}` returns `{"received": n}`, the offset to resume from.
+//! - `PUT {endpoint}/{upload_id}` with `Content-Range: bytes start-end/total` appends a chunk and
+//!   returns `{"received": n}`, plus `"retrieval_id"` once the whole bundle has been received and
+//!   its hash verified.
+//!
+//! Progress is remembered next to the bundle in `<bundle>.upload`, so re-running the upload after
+//! an interruption continues where it stopped.
+
+use crate::config::{env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::otel::with_traceparent;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    pub endpoint: String,
+    pub chunk_bytes: usize,
+}
+
+impl UploadConfig {
+    /// Loads `DIAGNOSTICS_UPLOAD_URL` and `DIAGNOSTICS_UPLOAD_CHUNK_BYTES` (default 1 MiB).
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let endpoint = env_opt::<String>("DIAGNOSTICS_UPLOAD_URL")?.ok_or_else(|| {
+            PhalaAvsError::ConfigError("DIAGNOSTICS_UPLOAD_URL is not set".to_string())
+        })?;
+        Ok(Self {
+            endpoint,
+            chunk_bytes: env_or("DIAGNOSTICS_UPLOAD_CHUNK_BYTES", 1 << 20)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadProgress {
+    upload_id: String,
+    sha256: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StartResponse {
+    upload_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkResponse {
+    received: u64,
+    retrieval_id: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Uploader {
+    config: UploadConfig,
+    client: reqwest::Client,
+}
+
+fn upload_err(e: impl std::fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::Other(format!("Diagnostics upload failed: {e}"))
+}
+
+async fn json_body<T: for<'de> Deserialize<'de>>(
+    response: reqwest::Response,
+) -> Result<T, PhalaAvsError> {
+    let status = response.status();
+    let body = response.bytes().await.map_err(upload_err)?;
+    if !status.is_success() {
+        return Err(upload_err(format!(
+            "{status}: {}",
+            String::from_utf8_lossy(&body)
+        )));
+    }
+    serde_json::from_slice(&body).map_err(upload_err)
+}
+
+impl Uploader {
+    pub fn new(config: UploadConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn progress_path(bundle: &Path) -> PathBuf {
+        let mut path = bundle.as_os_str().to_owned();
+        path.push(".upload");
+        PathBuf::from(path)
+    }
+
+    /// Uploads `bundle`, resuming a previous attempt if one was recorded, and returns the
+    /// retrieval id assigned by the support endpoint.
+    pub async fn upload(&self, bundle: &Path) -> Result<String, PhalaAvsError> {
+        let endpoint = self.config.endpoint.trim_end_matches('/');
+        let total = fs::metadata(bundle)?.len();
+        let sha256 = hash_file(bundle)?;
+        let progress_path = Self::progress_path(bundle);
+
+        let previous = fs::read(&progress_path)
+            .ok()
+            .and_then(|raw| serde_json::from_slice::<UploadProgress>(&raw).ok())
+            .filter(|p| p.sha256 == sha256);
+        let (upload_id, mut offset) = match previous {
+            Some(progress) => {
+                let status: ChunkResponse = json_body(
+                    self.client
+                        .get(format!("{endpoint}/{}", progress.upload_id))
+                        .send()
+                        .await
+                        .map_err(upload_err)?,
+                )
+                .await?;
+                info!(
+                    "Resuming upload {} at byte {} of {}",
+                    progress.upload_id, status.received, total
+                );
+                (progress.upload_id, status.received)
+            }
+            None => {
+                let start: StartResponse = json_body(
+                    with_traceparent(self.client.post(endpoint))
+                        .header("X-Bundle-Sha256", &sha256)
+                        .header("X-Bundle-Length", total)
+                        .send()
+                        .await
+                        .map_err(upload_err)?,
+                )
+                .await?;
+                fs::write(
+                    &progress_path,
+                    serde_json::to_vec(&UploadProgress {
+                        upload_id: start.upload_id.clone(),
+                        sha256: sha256.clone(),
+                    })
+                    .map_err(upload_err)?,
+                )?;
+                (start.upload_id, 0)
+            }
+        };
+
+        let mut file = File::open(bundle)?;
+        let mut chunk = vec![0u8; self.config.chunk_bytes.max(1)];
+        loop {
+            file.seek(SeekFrom::Start(offset))?;
+            let len = (total - offset).min(chunk.len() as u64) as usize;
+            file.read_exact(&mut chunk[..len])?;
+            let end = (offset + len as u64).saturating_sub(1);
+            let response: ChunkResponse = json_body(
+                with_traceparent(self.client.put(format!("{endpoint}/{upload_id}")))
+                    .header("Content-Range", format!("bytes {offset}-{end}/{total}"))
+                    .body(chunk[..len].to_vec())
+                    .send()
+                    .await
+                    .map_err(upload_err)?,
+            )
+            .await?;
+            if let Some(retrieval_id) = response.retrieval_id {
+                let _ = fs::remove_file(&progress_path);
+                return Ok(retrieval_id);
+            }
+            if response.received <= offset || response.received >= total {
+                return Err(upload_err(format!(
+                    "endpoint reported {} of {total} bytes received without completing",
+                    response.received
+                )));
+            }
+            offset = response.received;
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String, PhalaAvsError> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use axum::extract::{Path as UrlPath, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::{post, put};
+    use axum::{Json, Router};
+    use blueprint_sdk::testing::tempfile;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SupportServer {
+        uploads: Arc<Mutex<HashMap<String, (String, Vec<u8>)>>>,
+        chunks_received: Arc<Mutex<usize>>,
+        fail_on_chunk: Arc<Mutex<Option<usize>>>,
+    }
+
+    async fn start(State(s): State<SupportServer>, headers: HeaderMap) -> Json<serde_json::Value> {
+        let sha = headers["x-bundle-sha256"].to_str().unwrap().to_string();
+        s.uploads
+            .lock()
+            .unwrap()
+            .insert("u1".to_string(), (sha, Vec::new()));
+        Json(serde_json::json!({ "upload_id": "u1" }))
+    }
+
+    async fn status(
+        State(s): State<SupportServer>,
+        UrlPath(id): UrlPath<String>,
+    ) -> Json<serde_json::Value> {
+        let received = s.uploads.lock().unwrap()[&id].1.len();
+        Json(serde_json::json!({ "received": received }))
+    }
+
+    async fn chunk(
+        State(s): State<SupportServer>,
+        UrlPath(id): UrlPath<String>,
+        headers: HeaderMap,
+        body: axum::body::Bytes,
+    ) -> Result<Json<serde_json::Value>, StatusCode> {
+        let n = {
+            let mut count = s.chunks_received.lock().unwrap();
+            *count += 1;
+            *count
+        };
+        if s.fail_on_chunk
+            .lock()
+            .unwrap()
+            .take_if(|f| *f == n)
+            .is_some()
+        {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let range = headers["content-range"].to_str().unwrap();
+        let (start, total) = range
+            .strip_prefix("bytes ")
+            .and_then(|r| r.split_once('-'))
+            .map(|(start, rest)| {
+                (
+                    start.parse::<usize>().unwrap(),
+                    rest.split('/').nth(1).unwrap().parse::<usize>().unwrap(),
+                )
+            })
+            .unwrap();
+        let mut uploads = s.uploads.lock().unwrap();
+        let (sha, data) = uploads.get_mut(&id).unwrap();
+        assert_eq!(start, data.len(), "chunks must be contiguous");
+        data.extend_from_slice(&body);
+        if data.len() == total {
+            assert_eq!(*sha, hex::encode(Sha256::digest(&data)));
+            return Ok(Json(
+                serde_json::json!({ "received": total, "retrieval_id": "SUPPORT-42" }),
+            ));
+        }
+        Ok(Json(serde_json::json!({ "received": data.len() })))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn interrupted_upload_resumes_to_completion() {
+        let server = SupportServer::default();
+        *server.fail_on_chunk.lock().unwrap() = Some(3);
+        let app = Router::new()
+            .route("/bundles", post(start))
+            .route("/bundles/{id}", put(chunk).get(status))
+            .with_state(server.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let bundle = dir.path().join("bundle.phdiag");
+        let contents: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&bundle, &contents).unwrap();
+
+        let uploader = Uploader::new(UploadConfig {
+            endpoint: format!("http://{addr}/bundles"),
+            chunk_bytes: 1024,
+        });
+        assert!(uploader.upload(&bundle).await.is_err());
+        assert_eq!(server.uploads.lock().unwrap()["u1"].1.len(), 2048);
+        assert!(Uploader::progress_path(&bundle).exists());
+
+        assert_eq!(uploader.upload(&bundle).await.unwrap(), "SUPPORT-42");
+        assert_eq!(server.uploads.lock().unwrap()["u1"].1, contents);
+        assert!(!Uploader::progress_path(&bundle).exists());
+        // 10 chunks plus the one that failed; nothing was re-sent.
+        assert_eq!(*server.chunks_received.lock().unwrap(), 11);
+    }
+}
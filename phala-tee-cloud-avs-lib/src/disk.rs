@@ -0,0 +1,633 @@
+//! Disk budget for the state store, shared by every persistent component.
+//!
+//! `DISK_BUDGET_BYTES` caps the data the operator keeps; each component has a soft quota,
+//! `DISK_QUOTA_<COMPONENT>_BYTES`, defaulting to its share of the budget. Every
+//! `DISK_CHECK_SECS` the monitor measures each component's namespaces. Once usage passes
+//! `DISK_WARN_PCT` of the budget, or a component its quota, the registered components are asked
+//! to release space under their own retention rules, in priority order: the audit index first,
+//! then operator set history, then artifacts past their dispute window. Challenge state is never
+//! pruned.
+//!
+//! Above `DISK_CRITICAL_PCT`, writes to non-essential components are refused by
+//! [`BudgetedStateStore`] until usage drops back, while essential ones (cursors, the challenge
+//! tracker, evidence, ...) continue. Usage is the size of the stored records, which is what
+//! pruning gives back; the SQLite file itself only shrinks when vacuumed.
+
+use crate::catchup::SKIPPED_NAMESPACE;
+use crate::challenge::tracker::{ARCHIVE_NAMESPACE, TRACKER_NAMESPACE};
+use crate::config::{env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evidence::gaps::GAP_NAMESPACE;
+use crate::evidence::summary::{DIRTY_NAMESPACE, SUMMARY_NAMESPACE};
+use crate::evidence::{
+    ANCHOR_NAMESPACE, HEARTBEAT_EVIDENCE, RESPONSE_EVIDENCE, WORKLOAD_EVIDENCE, now_unix_ms,
+};
+use crate::evm::EvmClient;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::operator_set::OPERATOR_SET_NAMESPACE;
+use crate::state::StateStore;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Gauge of the bytes stored, by component.
+pub const DISK_USAGE_METRIC: &str = "phala_avs_disk_usage_bytes";
+/// Gauge of each component's quota, and of the total budget under `component="total"`.
+pub const DISK_QUOTA_METRIC: &str = "phala_avs_disk_quota_bytes";
+/// 1 while non-essential writes are suspended.
+pub const DISK_SUSPENDED_METRIC: &str = "phala_avs_disk_writes_suspended";
+/// Counter of refused writes, by component.
+pub const DISK_REFUSED_METRIC: &str = "phala_avs_disk_writes_refused_total";
+
+/// Audit entries kept when the audit index releases space.
+const AUDIT_KEEP: usize = 1_000;
+
+/// A group of namespaces accounted together.
+#[derive(Clone, Copy, Debug)]
+pub struct Component {
+    pub name: &'static str,
+    pub namespaces: &'static [&'static str],
+    /// Writes continue above the critical threshold.
+    pub essential: bool,
+    /// Default quota, in percent of the budget.
+    pub share_pct: u64,
+}
+
+/// Namespaces no component claims.
+const OTHER: Component = Component {
+    name: "other",
+    namespaces: &[],
+    essential: true,
+    share_pct: 4,
+};
+
+pub const COMPONENTS: &[Component] = &[
+    Component {
+        name: "audit",
+        namespaces: &["api_audit"],
+        essential: false,
+        share_pct: 5,
+    },
+    Component {
+        name: "operator_set",
+        namespaces: &[OPERATOR_SET_NAMESPACE],
+        essential: false,
+        share_pct: 5,
+    },
+    Component {
+        name: "artifacts",
+        namespaces: &["artifacts"],
+        essential: true,
+        share_pct: 40,
+    },
+    Component {
+        name: "evidence",
+        namespaces: &[
+            HEARTBEAT_EVIDENCE,
+            RESPONSE_EVIDENCE,
+            WORKLOAD_EVIDENCE,
+            ANCHOR_NAMESPACE,
+            SUMMARY_NAMESPACE,
+            DIRTY_NAMESPACE,
+            GAP_NAMESPACE,
+        ],
+        essential: true,
+        share_pct: 30,
+    },
+    Component {
+        name: "challenges",
+        namespaces: &[TRACKER_NAMESPACE, ARCHIVE_NAMESPACE],
+        essential: true,
+        share_pct: 15,
+    },
+    Component {
+        name: "cursors",
+        namespaces: &["producer_cursors", SKIPPED_NAMESPACE],
+        essential: true,
+        share_pct: 1,
+    },
+];
+
+/// The component `namespace` is accounted to.
+pub fn component_of(namespace: &str) -> &'static Component {
+    COMPONENTS
+        .iter()
+        .find(|c| c.namespaces.contains(&namespace))
+        .unwrap_or(&OTHER)
+}
+
+/// Pruning priorities; lower releases first.
+pub const AUDIT_PRIORITY: u8 = 10;
+pub const OPERATOR_SET_PRIORITY: u8 = 20;
+pub const ARTIFACTS_PRIORITY: u8 = 30;
+
+/// A component able to give back space under disk pressure.
+pub trait ReleaseSpace: Send + Sync {
+    /// Deletes what its retention rules let go early at `head`, returning the records deleted.
+    fn release(&self, head: u64) -> Result<usize, PhalaAvsError>;
+}
+
+#[derive(Clone, Debug)]
+pub struct DiskConfig {
+    /// Unset disables the budget.
+    pub budget_bytes: Option<u64>,
+    pub warn_pct: u64,
+    pub critical_pct: u64,
+    pub check_secs: u64,
+    pub quotas: BTreeMap<&'static str, u64>,
+}
+
+impl DiskConfig {
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let budget_bytes: Option<u64> = env_opt("DISK_BUDGET_BYTES")?;
+        let mut quotas = BTreeMap::new();
+        for component in COMPONENTS.iter().chain([&OTHER]) {
+            let key = format!("DISK_QUOTA_{}_BYTES", component.name.to_ascii_uppercase());
+            let default = budget_bytes.map(|b| b / 100 * component.share_pct);
+            if let Some(quota) = env_opt(&key)?.or(default) {
+                quotas.insert(component.name, quota);
+            }
+        }
+        Ok(Self {
+            budget_bytes,
+            warn_pct: env_or("DISK_WARN_PCT", 80)?,
+            critical_pct: env_or("DISK_CRITICAL_PCT", 95)?,
+            check_secs: env_or("DISK_CHECK_SECS", 60)?,
+            quotas,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskLevel {
+    #[default]
+    Ok,
+    Warning,
+    Critical,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ComponentDisk {
+    pub used_bytes: u64,
+    pub quota_bytes: Option<u64>,
+    pub essential: bool,
+}
+
+/// Disk usage as shown in `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DiskReport {
+    pub used_bytes: u64,
+    pub budget_bytes: Option<u64>,
+    pub level: DiskLevel,
+    pub writes_suspended: bool,
+    pub components: BTreeMap<String, ComponentDisk>,
+    /// Records released by the last check, by component.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub released: BTreeMap<String, usize>,
+    pub checked_unix_ms: u64,
+}
+
+/// Measures usage, coordinates pruning and decides whether non-essential writes may proceed.
+pub struct DiskBudget {
+    config: DiskConfig,
+    store: Arc<dyn StateStore>,
+    pruners: RwLock<Vec<(u8, &'static str, Arc<dyn ReleaseSpace>)>>,
+    suspended: AtomicBool,
+    level: Mutex<DiskLevel>,
+    report: RwLock<Option<DiskReport>>,
+}
+
+impl fmt::Debug for DiskBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskBudget")
+            .field("config", &self.config)
+            .field("suspended", &self.suspended)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DiskBudget {
+    /// Measures `store`, the store a [`BudgetedStateStore`] wraps.
+    pub fn new(config: DiskConfig, store: Arc<dyn StateStore>) -> Self {
+        Self {
+            config,
+            store,
+            pruners: RwLock::default(),
+            suspended: AtomicBool::new(false),
+            level: Mutex::default(),
+            report: RwLock::default(),
+        }
+    }
+
+    pub fn config(&self) -> &DiskConfig {
+        &self.config
+    }
+
+    /// Registers `pruner` for `component`, released in `priority` order under pressure.
+    pub fn register(&self, priority: u8, component: &'static str, pruner: Arc<dyn ReleaseSpace>) {
+        let mut pruners = self.pruners.write().unwrap_or_else(|e| e.into_inner());
+        pruners.push((priority, component, pruner));
+        pruners.sort_by_key(|(priority, _, _)| *priority);
+    }
+
+    pub fn writes_suspended(&self) -> bool {
+        self.suspended.load(Ordering::SeqCst)
+    }
+
+    pub fn report(&self) -> Option<DiskReport> {
+        self.report
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Usage of every component.
+    pub fn measure(&self) -> Result<BTreeMap<String, ComponentDisk>, PhalaAvsError> {
+        let mut components: BTreeMap<String, ComponentDisk> = COMPONENTS
+            .iter()
+            .chain([&OTHER])
+            .map(|c| {
+                (c.name.to_string(), ComponentDisk {
+                    used_bytes: 0,
+                    quota_bytes: self.config.quotas.get(c.name).copied(),
+                    essential: c.essential,
+                })
+            })
+            .collect();
+        for namespace in self.store.namespaces()? {
+            let size = self.store.size(&namespace)?;
+            if let Some(usage) = components.get_mut(component_of(&namespace).name) {
+                usage.used_bytes += size;
+            }
+        }
+        Ok(components)
+    }
+
+    fn level_of(&self, used_bytes: u64) -> DiskLevel {
+        match self.config.budget_bytes {
+            Some(budget) if used_bytes * 100 >= budget * self.config.critical_pct => {
+                DiskLevel::Critical
+            }
+            Some(budget) if used_bytes * 100 >= budget * self.config.warn_pct => DiskLevel::Warning,
+            _ => DiskLevel::Ok,
+        }
+    }
+
+    /// Measures usage, releases space while above the warning threshold or a quota, and
+    /// suspends or resumes non-essential writes. Returns the alert to raise when the level
+    /// changed.
+    pub fn check(
+        &self,
+        head: u64,
+        now_ms: u64,
+    ) -> Result<(DiskReport, Option<Alert>), PhalaAvsError> {
+        let mut components = self.measure()?;
+        let mut released = BTreeMap::new();
+        let pruners = self
+            .pruners
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        for (_, component, pruner) in pruners {
+            let used: u64 = components.values().map(|c| c.used_bytes).sum();
+            let over_quota = components
+                .get(component)
+                .is_some_and(|c| c.quota_bytes.is_some_and(|q| c.used_bytes > q));
+            if self.level_of(used) == DiskLevel::Ok && !over_quota {
+                continue;
+            }
+            let count = pruner.release(head)?;
+            if count > 0 {
+                info!("Released {count} {component} records under disk pressure");
+                released.insert(component.to_string(), count);
+                components = self.measure()?;
+            }
+        }
+
+        let used_bytes = components.values().map(|c| c.used_bytes).sum();
+        let level = self.level_of(used_bytes);
+        let suspended = level == DiskLevel::Critical;
+        self.suspended.store(suspended, Ordering::SeqCst);
+        for (name, usage) in &components {
+            let labels = [("component", name.as_str())];
+            METRICS.set_gauge(DISK_USAGE_METRIC, &labels, usage.used_bytes as f64);
+            if let Some(quota) = usage.quota_bytes {
+                METRICS.set_gauge(DISK_QUOTA_METRIC, &labels, quota as f64);
+            }
+        }
+        if let Some(budget) = self.config.budget_bytes {
+            METRICS.set_gauge(DISK_QUOTA_METRIC, &[("component", "total")], budget as f64);
+        }
+        METRICS.set_gauge(
+            DISK_SUSPENDED_METRIC,
+            &[],
+            if suspended { 1.0 } else { 0.0 },
+        );
+
+        let previous = std::mem::replace(
+            &mut *self.level.lock().unwrap_or_else(|e| e.into_inner()),
+            level,
+        );
+        let budget = self.config.budget_bytes.unwrap_or_default();
+        let alert = (previous != level).then(|| match level {
+            DiskLevel::Critical => Alert::new(
+                "disk",
+                Severity::Critical,
+                format!(
+                    "State uses {used_bytes} of {budget} bytes; non-essential writes are suspended"
+                ),
+            ),
+            DiskLevel::Warning => Alert::new(
+                "disk",
+                Severity::Warning,
+                format!("State uses {used_bytes} of {budget} bytes"),
+            ),
+            DiskLevel::Ok => Alert::new(
+                "disk",
+                Severity::Info,
+                format!("State usage is back to {used_bytes} of {budget} bytes"),
+            ),
+        });
+
+        let report = DiskReport {
+            used_bytes,
+            budget_bytes: self.config.budget_bytes,
+            level,
+            writes_suspended: suspended,
+            components,
+            released,
+            checked_unix_ms: now_ms,
+        };
+        *self.report.write().unwrap_or_else(|e| e.into_inner()) = Some(report.clone());
+        Ok((report, alert))
+    }
+}
+
+/// Checks the budget every `check_secs`.
+pub fn spawn_disk_monitor(
+    disk: Arc<DiskBudget>,
+    evm: Arc<dyn EvmClient>,
+    notifier: Arc<dyn Notifier>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(disk.config.check_secs));
+        loop {
+            interval.tick().await;
+            let checked = match evm.block_number().await {
+                Ok(head) => disk.check(head, now_unix_ms()),
+                Err(e) => Err(e),
+            };
+            match checked {
+                Ok((_, Some(alert))) => {
+                    if let Err(e) = notifier.notify(alert).await {
+                        warn!("Failed to raise the disk alert: {e}");
+                    }
+                }
+                Ok((_, None)) => {}
+                Err(e) => warn!("Failed to check disk usage: {e}"),
+            }
+        }
+    });
+}
+
+/// Releases a namespace's oldest entries, by key order, down to the most recent `keep`.
+#[derive(Debug)]
+pub struct NamespaceTail {
+    pub store: Arc<dyn StateStore>,
+    pub namespace: &'static str,
+    pub keep: usize,
+}
+
+impl NamespaceTail {
+    /// The API audit index, keeping its most recent entries.
+    pub fn audit(store: Arc<dyn StateStore>) -> Self {
+        Self {
+            store,
+            namespace: "api_audit",
+            keep: AUDIT_KEEP,
+        }
+    }
+}
+
+impl ReleaseSpace for NamespaceTail {
+    fn release(&self, _head: u64) -> Result<usize, PhalaAvsError> {
+        let entries = self.store.scan(self.namespace)?;
+        let excess = entries.len().saturating_sub(self.keep);
+        for (key, _) in entries.into_iter().take(excess) {
+            self.store.delete(self.namespace, &key)?;
+        }
+        Ok(excess)
+    }
+}
+
+/// [`StateStore`] refusing writes to non-essential components while disk usage is critical.
+pub struct BudgetedStateStore {
+    inner: Arc<dyn StateStore>,
+    disk: Arc<DiskBudget>,
+}
+
+impl BudgetedStateStore {
+    pub fn new(inner: Arc<dyn StateStore>, disk: Arc<DiskBudget>) -> Self {
+        Self { inner, disk }
+    }
+}
+
+impl fmt::Debug for BudgetedStateStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BudgetedStateStore")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StateStore for BudgetedStateStore {
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, PhalaAvsError> {
+        self.inner.get(namespace, key)
+    }
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), PhalaAvsError> {
+        let component = component_of(namespace);
+        if !component.essential && self.disk.writes_suspended() {
+            METRICS.inc_counter(DISK_REFUSED_METRIC, &[("component", component.name)], 1);
+            return Err(PhalaAvsError::StorageError(format!(
+                "Disk usage is critical; writes to {namespace} are suspended"
+            )));
+        }
+        self.inner.put(namespace, key, value)
+    }
+
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<(), PhalaAvsError> {
+        self.inner.delete(namespace, key)
+    }
+
+    fn scan(&self, namespace: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        self.inner.scan(namespace)
+    }
+
+    fn scan_range(
+        &self,
+        namespace: &str,
+        from: &[u8],
+        to: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        self.inner.scan_range(namespace, from, to)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>, PhalaAvsError> {
+        self.inner.namespaces()
+    }
+
+    fn size(&self, namespace: &str) -> Result<u64, PhalaAvsError> {
+        self.inner.size(namespace)
+    }
+}
+
+#[cfg(all(test, feature = "storage-sqlite"))]
+mod tests {
+    use super::*;
+    use crate::artifacts::{Artifact, ArtifactArchive, ArtifactConfig};
+    use crate::state::SqliteStateStore;
+    use blueprint_sdk::alloy::primitives::keccak256;
+    use blueprint_sdk::testing::tempfile::TempDir;
+
+    struct Fixture {
+        _dir: TempDir,
+        store: Arc<dyn StateStore>,
+        archive: Arc<ArtifactArchive>,
+    }
+
+    /// Twenty audit entries, challenge state, and two bundles: one past its dispute window at
+    /// block 100 but within its retention, one still disputable.
+    fn fixture() -> Fixture {
+        let dir = TempDir::new().unwrap();
+        let store: Arc<dyn StateStore> =
+            Arc::new(SqliteStateStore::open(&dir.path().join("state.db")).unwrap());
+        for i in 0u64..20 {
+            store
+                .put("api_audit", &i.to_be_bytes(), &[b'a'; 400])
+                .unwrap();
+        }
+        for i in 0u8..2 {
+            store.put(TRACKER_NAMESPACE, &[i], &[b'c'; 200]).unwrap();
+        }
+        let archive = Arc::new(ArtifactArchive::new(
+            ArtifactConfig {
+                retention_epochs: 7,
+                epoch_blocks: 100,
+                dispute_window_blocks: 50,
+                ..Default::default()
+            },
+            Arc::clone(&store),
+        ));
+        for (challenge, deadline) in [("closed", 10), ("open", 1_000)] {
+            let artifacts = vec![Artifact::new("quote", vec![4u8; 300])];
+            archive
+                .store(
+                    challenge,
+                    deadline,
+                    0,
+                    challenge.as_bytes().to_vec().into(),
+                    artifacts,
+                )
+                .unwrap();
+        }
+        Fixture {
+            _dir: dir,
+            store,
+            archive,
+        }
+    }
+
+    fn budget(fixture: &Fixture, budget_bytes: u64) -> Arc<DiskBudget> {
+        let config = DiskConfig {
+            budget_bytes: Some(budget_bytes),
+            warn_pct: 80,
+            critical_pct: 95,
+            check_secs: 60,
+            quotas: BTreeMap::new(),
+        };
+        Arc::new(DiskBudget::new(config, Arc::clone(&fixture.store)))
+    }
+
+    fn register(disk: &DiskBudget, fixture: &Fixture) {
+        let audit = NamespaceTail {
+            store: Arc::clone(&fixture.store),
+            namespace: "api_audit",
+            keep: 2,
+        };
+        disk.register(
+            ARTIFACTS_PRIORITY,
+            "artifacts",
+            Arc::clone(&fixture.archive) as _,
+        );
+        disk.register(AUDIT_PRIORITY, "audit", Arc::new(audit));
+    }
+
+    fn used(fixture: &Fixture) -> u64 {
+        let disk = budget(fixture, u64::MAX);
+        disk.measure().unwrap().values().map(|c| c.used_bytes).sum()
+    }
+
+    #[test]
+    fn critical_usage_suspends_non_essential_writes_until_space_is_released() {
+        let fixture = fixture();
+        let disk = budget(&fixture, used(&fixture));
+        let budgeted = BudgetedStateStore::new(Arc::clone(&fixture.store), Arc::clone(&disk));
+
+        // Nothing registered to release space yet.
+        let (report, alert) = disk.check(100, 1).unwrap();
+        assert_eq!(report.level, DiskLevel::Critical);
+        assert!(report.writes_suspended);
+        assert_eq!(alert.unwrap().severity, Severity::Critical);
+        assert!(budgeted.put("api_audit", b"late", b"entry").is_err());
+        budgeted.put("producer_cursors", b"poller", b"7").unwrap();
+        budgeted.put(TRACKER_NAMESPACE, &[9], b"challenge").unwrap();
+        assert_eq!(
+            METRICS.counter(DISK_REFUSED_METRIC, &[("component", "audit")]),
+            Some(1)
+        );
+
+        // The audit index alone gets usage back under the warning threshold.
+        register(&disk, &fixture);
+        let (report, alert) = disk.check(100, 2).unwrap();
+        assert_eq!(report.level, DiskLevel::Ok);
+        assert!(!report.writes_suspended);
+        assert_eq!(alert.unwrap().severity, Severity::Info);
+        assert_eq!(report.released, BTreeMap::from([("audit".to_string(), 18)]));
+        let kept: Vec<_> = fixture.store.scan("api_audit").unwrap();
+        assert_eq!(kept[0].0, 18u64.to_be_bytes());
+        assert_eq!(fixture.store.scan("artifacts").unwrap().len(), 2);
+        budgeted.put("api_audit", b"late", b"entry").unwrap();
+        assert_eq!(disk.check(100, 3).unwrap().1.map(|a| a.severity), None);
+    }
+
+    #[test]
+    fn pressure_prunes_in_priority_order_and_never_challenge_state() {
+        let fixture = fixture();
+        // Retention keeps both bundles at block 100.
+        assert_eq!(fixture.archive.prune(100).unwrap(), 0);
+        // Less than even challenge state and the latest records take.
+        let disk = budget(&fixture, 1_000);
+        register(&disk, &fixture);
+
+        let (report, _) = disk.check(100, 1).unwrap();
+        assert_eq!(
+            report.released,
+            BTreeMap::from([("artifacts".to_string(), 1), ("audit".to_string(), 18)])
+        );
+        let remaining = fixture.store.scan("artifacts").unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert!(fixture.archive.get(keccak256("open")).unwrap().is_some());
+        assert_eq!(fixture.store.scan(TRACKER_NAMESPACE).unwrap().len(), 2);
+        // Nothing else may go, so writes stay suspended.
+        assert_eq!(report.level, DiskLevel::Critical);
+        assert!(disk.writes_suspended());
+        assert_eq!(report.components["challenges"].used_bytes, 2 * 201);
+    }
+}
@@ -0,0 +1,189 @@
+//! How addresses, hashes and keys are rendered in user-facing output.
+//!
+//! Addresses are always EIP-55 checksummed, and hashes full-length and 0x-prefixed; only human
+//! tables abbreviate hashes, and the CLI's `--full-hashes` turns that off. BLS public keys are
+//! compressed hex followed by a short fingerprint. The wrappers implement `Display`, so log
+//! fields render the same way: `info!(operator = %Addr(operator), tx = %Hash(tx), "...")`.
+//!
+//! Addresses supplied by users are accepted in any case, but a mixed-case address whose
+//! checksum does not match was likely mangled, and is warned about.
+
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::primitives::{Address, B256, keccak256};
+use std::fmt;
+use tracing::warn;
+
+/// An address, checksummed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Addr(pub Address);
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.to_checksum(None))
+    }
+}
+
+/// A transaction or block hash, full-length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hash(pub B256);
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// A compressed BLS public key and its fingerprint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlsKey<'a>(pub &'a [u8]);
+
+impl fmt::Display for BlsKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{} ({})", hex::encode(self.0), fingerprint(self.0))
+    }
+}
+
+/// The first four bytes of the key's keccak256, enough to tell keys apart at a glance.
+pub fn fingerprint(key: &[u8]) -> String {
+    hex::encode(&keccak256(key)[..4])
+}
+
+/// Shortens a hex string to its first and last characters for a human table, unless `full`.
+pub fn abbreviate(hex: &str, full: bool) -> String {
+    let (prefix, digits) = hex.split_at(if hex.starts_with("0x") { 2 } else { 0 });
+    if full || digits.len() <= 12 {
+        return hex.to_string();
+    }
+    format!("{prefix}{}…{}", &digits[..6], &digits[digits.len() - 4..])
+}
+
+/// Parses a user-supplied address in any case, warning when it is mixed-case but not a valid
+/// EIP-55 checksum.
+pub fn parse_address(input: &str) -> Result<Address, PhalaAvsError> {
+    let input = input.trim();
+    let address: Address = input
+        .parse()
+        .map_err(|e| PhalaAvsError::ValidationError(format!("Invalid address {input:?}: {e}")))?;
+    if !checksum_matches(input, address) {
+        warn!(
+            "Address {input} does not match its checksum {}; double-check it was not mangled",
+            Addr(address)
+        );
+    }
+    Ok(address)
+}
+
+/// All-lowercase and all-uppercase addresses carry no checksum, so only mixed case is checked.
+fn checksum_matches(input: &str, address: Address) -> bool {
+    let digits = input.strip_prefix("0x").unwrap_or(input);
+    let lower = digits.chars().all(|c| !c.is_ascii_uppercase());
+    let upper = digits.chars().all(|c| !c.is_ascii_lowercase());
+    lower || upper || address.to_checksum(None)[2..] == *digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::{LOG_RING, LogRingLayer};
+    use crate::notify::{Alert, Severity};
+    use crate::upgrade::UpgradeEvent;
+    use blueprint_sdk::alloy::primitives::address;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const OPERATOR: Address = address!("5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed");
+
+    /// Every 40-digit hex token in `text` that is not checksummed.
+    fn unchecksummed(text: &str) -> Vec<String> {
+        let mut found = Vec::new();
+        for (start, _) in text.match_indices("0x") {
+            let digits: String = text[start + 2..]
+                .chars()
+                .take_while(char::is_ascii_hexdigit)
+                .collect();
+            if digits.len() == 40 {
+                let address: Address = digits.parse().unwrap();
+                if address.to_checksum(None)[2..] != digits {
+                    found.push(digits);
+                }
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn addresses_hashes_and_keys_render_canonically() {
+        assert_eq!(
+            Addr(OPERATOR).to_string(),
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"
+        );
+        let tx = B256::repeat_byte(0xab);
+        assert_eq!(Hash(tx).to_string(), format!("0x{}", "ab".repeat(32)));
+        assert_eq!(abbreviate(&Hash(tx).to_string(), false), "0xababab…abab");
+        assert_eq!(
+            abbreviate(&Hash(tx).to_string(), true),
+            Hash(tx).to_string()
+        );
+        let key = [2u8; 33];
+        assert_eq!(
+            BlsKey(&key).to_string(),
+            format!("0x{} ({})", "02".repeat(33), fingerprint(&key))
+        );
+        assert_eq!(fingerprint(&key).len(), 8);
+
+        // Whatever reaches status and alerts is checksummed.
+        let upgrade = UpgradeEvent {
+            name: "oracle".to_string(),
+            address: OPERATOR,
+            old_implementation: Some(Address::repeat_byte(0xab)),
+            new_implementation: Some(address!("fB6916095ca1df60bB79Ce92cE3Ea74c37c5d359")),
+            old_code_hash: tx,
+            new_code_hash: tx,
+            detected_unix_ms: 0,
+        };
+        let alert = Alert::new(
+            "registration",
+            Severity::Warning,
+            format!("Operator {} is not registered", Addr(OPERATOR)),
+        );
+        let serialized = [
+            serde_json::to_string(&upgrade).unwrap(),
+            serde_json::to_string(&alert).unwrap(),
+        ]
+        .concat();
+        assert!(serialized.contains("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"));
+        assert_eq!(unchecksummed(&serialized), Vec::<String>::new());
+        assert_eq!(
+            unchecksummed(&format!("0x{}", hex::encode(OPERATOR))).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn mangled_checksums_are_accepted_with_a_warning() {
+        let subscriber = tracing_subscriber::registry().with(LogRingLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            for input in [
+                "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+                "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+                "0x5AAEB6053F3E94C9B9A09F33669435E7EF1BEAED",
+            ] {
+                assert_eq!(parse_address(input).unwrap(), OPERATOR);
+            }
+            assert!(!LOG_RING.snapshot().iter().any(|e| {
+                e.message
+                    .contains("5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed")
+            }));
+
+            let mangled = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD";
+            assert_eq!(parse_address(mangled).unwrap(), OPERATOR);
+            let warnings: Vec<_> = LOG_RING
+                .snapshot()
+                .into_iter()
+                .filter(|e| e.level == "WARN" && e.message.contains(mangled))
+                .collect();
+            assert_eq!(warnings.len(), 1);
+            assert!(warnings[0].message.contains(&Addr(OPERATOR).to_string()));
+        });
+        assert!(parse_address("0x5aAeb6").is_err());
+    }
+}
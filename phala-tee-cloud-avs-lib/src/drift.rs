@@ -0,0 +1,520 @@
+//! Reconciliation of the workloads running on this host against the on-chain assignments.
+//!
+//! Every `DRIFT_CHECK_SECS`, the operator's assignments are read from the service manager
+//! (`assignedWorkloadCount` and one multicall of `assignedWorkloadAt`) and diffed against the
+//! host's running workloads:
+//!
+//! - assigned but not running: redeployed, at most `DRIFT_MAX_REDEPLOYS` times until it runs,
+//!   with a warning alert when found and a critical one when we give up;
+//! - running but not assigned: alerted, and with `DRIFT_UNASSIGNED_ACTION=stop` stopped once it
+//!   has stayed unassigned for `DRIFT_STOP_GRACE_SECS`, so a lagging assignment is not killed;
+//! - matched: their last-verified time is updated.
+//!
+//! The latest [`DriftReport`] is persisted, shown on `/status` and exported as gauges.
+
+use crate::config::{env_flag, env_or};
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::multicall::{MULTICALL3_ADDRESS, MulticallBatch, decode};
+use crate::notify::{Alert, Notifier, Severity};
+use crate::state::{StateStore, StateStoreExt};
+use crate::tee::TeeHandler;
+use crate::{IPhalaServiceManager, SERVICE_MANAGER_ADDRESS};
+use blueprint_sdk::alloy::primitives::{Address, B256, U256};
+use blueprint_sdk::evm::util::get_provider_http;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Gauge of drifted workloads, by kind (`missing`, `unassigned`).
+pub const WORKLOAD_DRIFT_METRIC: &str = "phala_avs_workload_drift";
+/// Counter of redeploy attempts, by outcome.
+pub const WORKLOAD_REDEPLOYS_METRIC: &str = "phala_avs_workload_redeploys_total";
+
+const NAMESPACE: &str = "workload_drift";
+const REPORT_KEY: &[u8] = b"report";
+
+/// What to do with a workload running without an assignment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnassignedAction {
+    Alert,
+    Stop,
+}
+
+impl FromStr for UnassignedAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alert" => Ok(Self::Alert),
+            "stop" => Ok(Self::Stop),
+            other => Err(format!("expected alert or stop, got {other}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DriftConfig {
+    pub enabled: bool,
+    pub check_secs: u64,
+    /// Redeploys of a missing workload before giving up on it.
+    pub max_redeploys: u32,
+    pub unassigned_action: UnassignedAction,
+    /// How long a workload must stay unassigned before it is stopped.
+    pub stop_grace_secs: u64,
+    pub multicall: Address,
+}
+
+impl DriftConfig {
+    /// Reads `DRIFT_CHECK_ENABLED`, `DRIFT_CHECK_SECS`, `DRIFT_MAX_REDEPLOYS`,
+    /// `DRIFT_UNASSIGNED_ACTION`, `DRIFT_STOP_GRACE_SECS` and `MULTICALL_ADDRESS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            enabled: env_flag("DRIFT_CHECK_ENABLED", false)?,
+            check_secs: env_or("DRIFT_CHECK_SECS", 300)?,
+            max_redeploys: env_or("DRIFT_MAX_REDEPLOYS", 3)?,
+            unassigned_action: env_or("DRIFT_UNASSIGNED_ACTION", UnassignedAction::Alert)?,
+            stop_grace_secs: env_or("DRIFT_STOP_GRACE_SECS", 600)?,
+            multicall: env_or("MULTICALL_ADDRESS", MULTICALL3_ADDRESS)?,
+        })
+    }
+}
+
+/// The workloads assigned to an operator on-chain.
+pub trait WorkloadAssignments: Send + Sync {
+    fn assigned(&self, operator: Address) -> BoxFuture<'_, Result<Vec<B256>, PhalaAvsError>>;
+}
+
+/// [`WorkloadAssignments`] read from the `PhalaServiceManager` contract.
+#[derive(Clone, Debug)]
+pub struct ServiceManagerAssignments {
+    service_manager: Address,
+    multicall: Address,
+    rpc_url: String,
+}
+
+impl ServiceManagerAssignments {
+    pub fn new(service_manager: Address, multicall: Address, rpc_url: String) -> Self {
+        Self {
+            service_manager,
+            multicall,
+            rpc_url,
+        }
+    }
+
+    /// Uses `SERVICE_MANAGER_ADDRESS`.
+    pub fn from_env(rpc_url: String, multicall: Address) -> Self {
+        Self::new(*SERVICE_MANAGER_ADDRESS, multicall, rpc_url)
+    }
+}
+
+impl WorkloadAssignments for ServiceManagerAssignments {
+    fn assigned(&self, operator: Address) -> BoxFuture<'_, Result<Vec<B256>, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            let count = IPhalaServiceManager::new(self.service_manager, &provider)
+                .assignedWorkloadCount(operator)
+                .call()
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(format!("assignedWorkloadCount failed: {e}")))?
+                ._0;
+            let count = u64::try_from(count).map_err(|_| {
+                PhalaAvsError::EvmError(format!("assignedWorkloadCount {count} is out of range"))
+            })?;
+            let mut batch = MulticallBatch::new();
+            for index in 0..count {
+                batch.add(
+                    self.service_manager,
+                    &IPhalaServiceManager::assignedWorkloadAtCall {
+                        operator,
+                        index: U256::from(index),
+                    },
+                );
+            }
+            let results = batch.execute(&provider, self.multicall).await?;
+            results
+                .iter()
+                .map(|data| {
+                    Ok(decode::<IPhalaServiceManager::assignedWorkloadAtCall>(data.as_ref())?._0)
+                })
+                .collect()
+        })
+    }
+}
+
+/// The workloads running on this host.
+pub trait WorkloadRuntime: Send + Sync {
+    fn running(&self) -> BoxFuture<'_, Result<Vec<B256>, PhalaAvsError>>;
+
+    fn redeploy(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+
+    fn stop(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+}
+
+impl WorkloadRuntime for TeeHandler {
+    fn running(&self) -> BoxFuture<'_, Result<Vec<B256>, PhalaAvsError>> {
+        Box::pin(self.running_workloads())
+    }
+
+    fn redeploy(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(self.start_workload(workload_id))
+    }
+
+    fn stop(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(self.stop_workload(workload_id))
+    }
+}
+
+/// An assigned workload that is not running.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MissingWorkload {
+    pub workload_id: B256,
+    pub redeploy_attempts: u32,
+    pub last_error: Option<String>,
+    /// Redeploys are exhausted; the workload waits for an operator.
+    pub gave_up: bool,
+}
+
+/// A running workload without an assignment.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnassignedWorkload {
+    pub workload_id: B256,
+    pub first_seen_unix_ms: u64,
+    pub stopped: bool,
+}
+
+/// The result of the latest reconciliation.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DriftReport {
+    pub checked_unix_ms: u64,
+    pub missing: Vec<MissingWorkload>,
+    pub unassigned: Vec<UnassignedWorkload>,
+    pub matched: usize,
+    /// When each assigned workload was last seen running.
+    pub last_verified_unix_ms: BTreeMap<B256, u64>,
+}
+
+/// Diffs assignments against the host and reconciles the drift.
+pub struct DriftReconciler {
+    config: DriftConfig,
+    operator: Address,
+    assignments: Arc<dyn WorkloadAssignments>,
+    runtime: Arc<dyn WorkloadRuntime>,
+    store: Arc<dyn StateStore>,
+    report: Mutex<DriftReport>,
+}
+
+impl DriftReconciler {
+    /// Resumes from the persisted report, so redeploy attempts survive restarts.
+    pub fn new(
+        config: DriftConfig,
+        operator: Address,
+        assignments: Arc<dyn WorkloadAssignments>,
+        runtime: Arc<dyn WorkloadRuntime>,
+        store: Arc<dyn StateStore>,
+    ) -> Result<Self, PhalaAvsError> {
+        let report = store.get_json(NAMESPACE, REPORT_KEY)?.unwrap_or_default();
+        Ok(Self {
+            config,
+            operator,
+            assignments,
+            runtime,
+            store,
+            report: Mutex::new(report),
+        })
+    }
+
+    pub fn config(&self) -> &DriftConfig {
+        &self.config
+    }
+
+    pub fn report(&self) -> DriftReport {
+        self.report
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Runs one reconciliation, alerting through `notifier`.
+    pub async fn reconcile(
+        &self,
+        now_ms: u64,
+        notifier: &dyn Notifier,
+    ) -> Result<DriftReport, PhalaAvsError> {
+        let assigned: BTreeSet<B256> = self
+            .assignments
+            .assigned(self.operator)
+            .await?
+            .into_iter()
+            .collect();
+        let running: BTreeSet<B256> = self.runtime.running().await?.into_iter().collect();
+        let previous = self.report();
+        let mut alerts = Vec::new();
+
+        let mut last_verified = previous.last_verified_unix_ms.clone();
+        last_verified.retain(|id, _| assigned.contains(id));
+        let matched = assigned.intersection(&running).count();
+        for id in assigned.intersection(&running) {
+            last_verified.insert(*id, now_ms);
+        }
+
+        let mut missing = Vec::new();
+        for &workload_id in assigned.difference(&running) {
+            let mut entry = previous
+                .missing
+                .iter()
+                .find(|m| m.workload_id == workload_id)
+                .cloned()
+                .unwrap_or_else(|| {
+                    alerts.push(Alert::new(
+                        "drift",
+                        Severity::Warning,
+                        format!("Assigned workload {workload_id} is not running; redeploying"),
+                    ));
+                    MissingWorkload {
+                        workload_id,
+                        redeploy_attempts: 0,
+                        last_error: None,
+                        gave_up: false,
+                    }
+                });
+            if entry.redeploy_attempts < self.config.max_redeploys {
+                entry.redeploy_attempts += 1;
+                match self.runtime.redeploy(workload_id).await {
+                    Ok(()) => {
+                        info!("Redeployed missing workload {workload_id}");
+                        METRICS.inc_counter(WORKLOAD_REDEPLOYS_METRIC, &[("outcome", "ok")], 1);
+                        entry.last_error = None;
+                    }
+                    Err(e) => {
+                        warn!("Failed to redeploy workload {workload_id}: {e}");
+                        METRICS.inc_counter(WORKLOAD_REDEPLOYS_METRIC, &[("outcome", "failed")], 1);
+                        entry.last_error = Some(e.to_string());
+                    }
+                }
+            } else if !entry.gave_up {
+                entry.gave_up = true;
+                alerts.push(Alert::new(
+                    "drift",
+                    Severity::Critical,
+                    format!(
+                        "Assigned workload {workload_id} is still not running after {} redeploys",
+                        entry.redeploy_attempts
+                    ),
+                ));
+            }
+            missing.push(entry);
+        }
+
+        let mut unassigned = Vec::new();
+        for &workload_id in running.difference(&assigned) {
+            let mut entry = previous
+                .unassigned
+                .iter()
+                .find(|u| u.workload_id == workload_id)
+                .cloned()
+                .unwrap_or_else(|| {
+                    alerts.push(Alert::new(
+                        "drift",
+                        Severity::Warning,
+                        format!("Workload {workload_id} is running without an assignment"),
+                    ));
+                    UnassignedWorkload {
+                        workload_id,
+                        first_seen_unix_ms: now_ms,
+                        stopped: false,
+                    }
+                });
+            let grace_over = now_ms.saturating_sub(entry.first_seen_unix_ms)
+                >= self.config.stop_grace_secs * 1000;
+            if self.config.unassigned_action == UnassignedAction::Stop && grace_over {
+                match self.runtime.stop(workload_id).await {
+                    Ok(()) => {
+                        info!("Stopped unassigned workload {workload_id}");
+                        entry.stopped = true;
+                    }
+                    Err(e) => warn!("Failed to stop unassigned workload {workload_id}: {e}"),
+                }
+            }
+            unassigned.push(entry);
+        }
+
+        let report = DriftReport {
+            checked_unix_ms: now_ms,
+            missing,
+            unassigned,
+            matched,
+            last_verified_unix_ms: last_verified,
+        };
+        METRICS.set_gauge(
+            WORKLOAD_DRIFT_METRIC,
+            &[("kind", "missing")],
+            report.missing.len() as f64,
+        );
+        METRICS.set_gauge(
+            WORKLOAD_DRIFT_METRIC,
+            &[("kind", "unassigned")],
+            report.unassigned.len() as f64,
+        );
+        self.store.put_json(NAMESPACE, REPORT_KEY, &report)?;
+        *self.report.lock().unwrap_or_else(|e| e.into_inner()) = report.clone();
+
+        for alert in alerts {
+            if let Err(e) = notifier.notify(alert).await {
+                warn!("Failed to deliver workload drift alert: {e}");
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Reconciles every `check_secs`, in the background.
+pub fn spawn_reconciler(reconciler: Arc<DriftReconciler>, notifier: Arc<dyn Notifier>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(reconciler.config.check_secs));
+        loop {
+            interval.tick().await;
+            if let Err(e) = reconciler.reconcile(now_unix_ms(), notifier.as_ref()).await {
+                warn!("Failed to reconcile workloads: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    const OPERATOR: Address = Address::repeat_byte(0x01);
+
+    struct MockAssignments(Vec<B256>);
+
+    impl WorkloadAssignments for MockAssignments {
+        fn assigned(&self, operator: Address) -> BoxFuture<'_, Result<Vec<B256>, PhalaAvsError>> {
+            assert_eq!(operator, OPERATOR);
+            let assigned = self.0.clone();
+            Box::pin(async move { Ok(assigned) })
+        }
+    }
+
+    /// A host where redeploys of `broken` fail and everything else starts.
+    #[derive(Default)]
+    struct MockRuntime {
+        running: Mutex<BTreeSet<B256>>,
+        broken: BTreeSet<B256>,
+        redeploys: Mutex<Vec<B256>>,
+    }
+
+    impl WorkloadRuntime for MockRuntime {
+        fn running(&self) -> BoxFuture<'_, Result<Vec<B256>, PhalaAvsError>> {
+            let running = self.running.lock().unwrap().iter().copied().collect();
+            Box::pin(async move { Ok(running) })
+        }
+
+        fn redeploy(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.redeploys.lock().unwrap().push(workload_id);
+            let result = if self.broken.contains(&workload_id) {
+                Err(PhalaAvsError::TeeError("image pull failed".to_string()))
+            } else {
+                self.running.lock().unwrap().insert(workload_id);
+                Ok(())
+            };
+            Box::pin(async move { result })
+        }
+
+        fn stop(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.running.lock().unwrap().remove(&workload_id);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorded(Mutex<Vec<Alert>>);
+
+    impl Notifier for Recorded {
+        fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.0.lock().unwrap().push(alert);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn config(unassigned_action: UnassignedAction) -> DriftConfig {
+        DriftConfig {
+            enabled: true,
+            check_secs: 60,
+            max_redeploys: 2,
+            unassigned_action,
+            stop_grace_secs: 600,
+            multicall: MULTICALL3_ADDRESS,
+        }
+    }
+
+    #[tokio::test]
+    async fn each_drift_category_is_reconciled_and_redeploys_are_bounded() {
+        let (healthy, flaky, broken, stray) = (
+            B256::repeat_byte(1),
+            B256::repeat_byte(2),
+            B256::repeat_byte(3),
+            B256::repeat_byte(4),
+        );
+        let assignments = Arc::new(MockAssignments(vec![healthy, flaky, broken]));
+        let runtime = Arc::new(MockRuntime {
+            running: Mutex::new([healthy, stray].into()),
+            broken: [broken].into(),
+            ..Default::default()
+        });
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let reconciler = DriftReconciler::new(
+            config(UnassignedAction::Stop),
+            OPERATOR,
+            assignments,
+            Arc::clone(&runtime) as Arc<dyn WorkloadRuntime>,
+            Arc::clone(&store),
+        )
+        .unwrap();
+        let notifier = Recorded::default();
+
+        let report = reconciler.reconcile(1_000, &notifier).await.unwrap();
+        assert_eq!(report.matched, 1);
+        assert_eq!(report.last_verified_unix_ms[&healthy], 1_000);
+        assert_eq!(report.missing.len(), 2);
+        assert_eq!(report.unassigned[0].workload_id, stray);
+        assert!(!report.unassigned[0].stopped);
+        assert_eq!(notifier.0.lock().unwrap().len(), 3);
+
+        // The flaky workload came up; the broken one is retried up to the bound.
+        let report = reconciler.reconcile(61_000, &notifier).await.unwrap();
+        assert_eq!(report.matched, 2);
+        assert_eq!(report.last_verified_unix_ms[&flaky], 61_000);
+        assert_eq!(report.missing[0].redeploy_attempts, 2);
+        let report = reconciler.reconcile(121_000, &notifier).await.unwrap();
+        assert!(report.missing[0].gave_up);
+        assert_eq!(
+            report.missing[0].last_error.as_deref(),
+            Some("TEE interaction error: image pull failed")
+        );
+        assert_eq!(runtime.redeploys.lock().unwrap().as_slice(), &[
+            flaky, broken, broken
+        ]);
+        assert_eq!(
+            notifier.0.lock().unwrap().last().unwrap().severity,
+            Severity::Critical
+        );
+
+        // The stray workload is stopped once its grace period is over.
+        let report = reconciler.reconcile(601_000, &notifier).await.unwrap();
+        assert!(report.unassigned[0].stopped);
+        assert!(!runtime.running.lock().unwrap().contains(&stray));
+        assert_eq!(runtime.redeploys.lock().unwrap().len(), 3);
+
+        let resumed: DriftReport = store.get_json(NAMESPACE, REPORT_KEY).unwrap().unwrap();
+        assert_eq!(resumed, reconciler.report());
+    }
+}
@@ -0,0 +1,1042 @@
+//! Duty calendar: the challenges expected over the next day, prepared for ahead of time.
+//!
+//! SLA challenges cluster after epoch boundaries, every `DUTY_EPOCH_BLOCKS` from
+//! `DUTY_EPOCH_OFFSET_BLOCKS`, and attestation challenges recur at a steady interval. From the
+//! issuing blocks of the challenges tracked so far, the calendar estimates each coming epoch's
+//! burst (how many challenges, how far after the boundary) and when the next attestation
+//! challenges are due, falling back to `DUTY_ATTESTATION_INTERVAL_SECS` without enough history.
+//!
+//! Ahead of each duty it runs pre-warm actions: the TEE quote is refreshed before an attestation
+//! window, the evidence summaries of the closing epoch are composed, the balance of the account
+//! responses are sent from is checked against the burst, and the operator set's stakes are
+//! refreshed. When a duty's window arrives with a stale quote or a balance short of the burst's
+//! `DUTY_RESPONSE_COST_WEI` per challenge, an alert is raised.
+//!
+//! With [collateral tracking](crate::tee::collateral), the expiry of the platform's collateral is
+//! a duty too: `DUTY_COLLATERAL_LEAD_SECS` ahead of it the collateral and the quote are
+//! refreshed, and if the expiry arrives without newer collateral, an alert is raised.
+//!
+//! Every duty is an estimate, and shown as one. The calendar only prepares and alerts: challenges
+//! are handled as they are observed, whether they were predicted or not.
+
+use crate::challenge::{ChallengeTracker, ObservedChallenge, RemainingWindow};
+use crate::config::env_or;
+use crate::encoding::{ATTESTATION_KIND, SchemaKey, kind_id};
+use crate::error::PhalaAvsError;
+use crate::evidence::range::evidence_for_range;
+use crate::evidence::{AnchorConfig, EvidenceLog, now_unix_ms};
+use crate::evm::{BoxFuture, EvmClient};
+use crate::lanes::{SignerLanes, TxClass};
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::operator_set::OperatorSetTracker;
+use crate::tee::TeeHandler;
+use crate::tee::collateral::CollateralMonitor;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Counter of pre-warm actions run, by action and outcome.
+pub const DUTY_PREWARM_METRIC: &str = "phala_avs_duty_prewarm_total";
+/// Counter of duty windows that arrived with a prerequisite unmet, by prerequisite.
+pub const DUTY_UNMET_METRIC: &str = "phala_avs_duty_unmet_prerequisites_total";
+
+#[derive(Clone, Debug)]
+pub struct DutyConfig {
+    /// Length of an oracle epoch; zero predicts no epoch bursts.
+    pub epoch_blocks: u64,
+    /// Block of the first epoch boundary.
+    pub epoch_offset_blocks: u64,
+    pub block_time_ms: u64,
+    /// How far ahead duties are predicted.
+    pub horizon_secs: u64,
+    /// Past epochs a burst is estimated from.
+    pub history_epochs: u64,
+    /// Challenges issued this many blocks after a boundary count towards its burst.
+    pub burst_spread_blocks: u64,
+    /// Interval of attestation challenges until two have been seen; zero predicts none.
+    pub attestation_interval_secs: u64,
+    pub quote_lead_secs: u64,
+    pub summary_lead_secs: u64,
+    pub gas_lead_secs: u64,
+    pub stake_lead_secs: u64,
+    pub collateral_lead_secs: u64,
+    /// A quote refreshed longer ago than this is stale when an attestation window arrives.
+    pub quote_max_age_secs: u64,
+    /// Balance needed per expected challenge.
+    pub response_cost_wei: u128,
+    pub check_secs: u64,
+}
+
+impl Default for DutyConfig {
+    fn default() -> Self {
+        Self {
+            epoch_blocks: 0,
+            epoch_offset_blocks: 0,
+            block_time_ms: 12_000,
+            horizon_secs: 86_400,
+            history_epochs: 8,
+            burst_spread_blocks: 100,
+            attestation_interval_secs: 0,
+            quote_lead_secs: 300,
+            summary_lead_secs: 600,
+            gas_lead_secs: 900,
+            stake_lead_secs: 300,
+            collateral_lead_secs: 3_600,
+            quote_max_age_secs: 600,
+            response_cost_wei: 1_000_000_000_000_000,
+            check_secs: 30,
+        }
+    }
+}
+
+impl DutyConfig {
+    /// Reads the `DUTY_*` settings.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let d = Self::default();
+        Ok(Self {
+            epoch_blocks: env_or("DUTY_EPOCH_BLOCKS", d.epoch_blocks)?,
+            epoch_offset_blocks: env_or("DUTY_EPOCH_OFFSET_BLOCKS", d.epoch_offset_blocks)?,
+            block_time_ms: env_or("DUTY_BLOCK_TIME_MS", d.block_time_ms)?.max(1),
+            horizon_secs: env_or("DUTY_HORIZON_SECS", d.horizon_secs)?,
+            history_epochs: env_or("DUTY_HISTORY_EPOCHS", d.history_epochs)?.max(1),
+            burst_spread_blocks: env_or("DUTY_BURST_SPREAD_BLOCKS", d.burst_spread_blocks)?,
+            attestation_interval_secs: env_or(
+                "DUTY_ATTESTATION_INTERVAL_SECS",
+                d.attestation_interval_secs,
+            )?,
+            quote_lead_secs: env_or("DUTY_QUOTE_LEAD_SECS", d.quote_lead_secs)?,
+            summary_lead_secs: env_or("DUTY_SUMMARY_LEAD_SECS", d.summary_lead_secs)?,
+            gas_lead_secs: env_or("DUTY_GAS_LEAD_SECS", d.gas_lead_secs)?,
+            stake_lead_secs: env_or("DUTY_STAKE_LEAD_SECS", d.stake_lead_secs)?,
+            collateral_lead_secs: env_or("DUTY_COLLATERAL_LEAD_SECS", d.collateral_lead_secs)?,
+            quote_max_age_secs: env_or("DUTY_QUOTE_MAX_AGE_SECS", d.quote_max_age_secs)?,
+            response_cost_wei: env_or("DUTY_RESPONSE_COST_WEI", d.response_cost_wei)?,
+            check_secs: env_or("DUTY_CHECK_SECS", d.check_secs)?.max(1),
+        })
+    }
+
+    fn blocks_in(&self, secs: u64) -> u64 {
+        secs * 1000 / self.block_time_ms
+    }
+
+    fn boundary(&self, epoch: u64) -> u64 {
+        self.epoch_offset_blocks + epoch * self.epoch_blocks
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DutyKind {
+    /// The burst of SLA challenges after an epoch boundary.
+    EpochChallenges,
+    AttestationRefresh,
+    /// The first `nextUpdate` of the platform's TCB info and QE identity.
+    CollateralExpiry,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrewarmAction {
+    /// Runs before [`RefreshQuote`](Self::RefreshQuote) when both are due together.
+    RefreshCollateral,
+    RefreshQuote,
+    ComposeSummaries,
+    CheckGas,
+    PrefetchStake,
+}
+
+impl PrewarmAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PrewarmAction::RefreshCollateral => "refresh_collateral",
+            PrewarmAction::RefreshQuote => "refresh_quote",
+            PrewarmAction::ComposeSummaries => "compose_summaries",
+            PrewarmAction::CheckGas => "check_gas",
+            PrewarmAction::PrefetchStake => "prefetch_stake",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedAction {
+    pub action: PrewarmAction,
+    pub at_unix: u64,
+}
+
+/// One expected duty.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Duty {
+    pub kind: DutyKind,
+    /// Estimated block the duty's window opens at.
+    pub expected_block: u64,
+    pub expected_unix: u64,
+    /// Estimated challenges in the window.
+    pub expected_challenges: u32,
+    /// Pre-warm actions, in the order they run.
+    pub actions: Vec<PlannedAction>,
+}
+
+impl Duty {
+    /// Collateral expires at a time rather than a block, so re-estimating its block on every
+    /// plan does not make it a new duty.
+    fn key(&self) -> (DutyKind, u64) {
+        match self.kind {
+            DutyKind::CollateralExpiry => (self.kind, self.expected_unix),
+            _ => (self.kind, self.expected_block),
+        }
+    }
+}
+
+/// The duties expected over the horizon, as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DutyForecast {
+    /// Always set: duties are predicted from history, not announced by the oracle.
+    pub estimated: bool,
+    pub head_block: u64,
+    pub generated_unix: u64,
+    pub duties: Vec<Duty>,
+    /// The pending challenge with the fewest blocks left when the forecast was made.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub most_urgent: Option<RemainingWindow>,
+}
+
+/// A challenge seen in the past, as the forecast uses it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IssuedChallenge {
+    pub block: u64,
+    pub attestation: bool,
+}
+
+impl From<&ObservedChallenge> for IssuedChallenge {
+    fn from(challenge: &ObservedChallenge) -> Self {
+        Self {
+            block: challenge.issued_block,
+            attestation: SchemaKey::of(challenge).kind == kind_id(ATTESTATION_KIND),
+        }
+    }
+}
+
+/// Predicts the duties of the `horizon_secs` after `head_block`, mined at `head_unix`.
+pub fn forecast(
+    config: &DutyConfig,
+    head_block: u64,
+    head_unix: u64,
+    issued: &[IssuedChallenge],
+) -> Vec<Duty> {
+    let horizon_end = head_block + config.blocks_in(config.horizon_secs);
+    let unix_of =
+        |block: u64| head_unix + block.saturating_sub(head_block) * config.block_time_ms / 1000;
+    let mut duties = Vec::new();
+
+    if config.epoch_blocks > 0 {
+        let (challenges, offset) = estimate_burst(config, head_block, issued);
+        let mut epoch = head_block.saturating_sub(config.epoch_offset_blocks) / config.epoch_blocks;
+        loop {
+            let block = config.boundary(epoch) + offset;
+            if block > horizon_end {
+                break;
+            }
+            if block > head_block {
+                duties.push((DutyKind::EpochChallenges, block, challenges));
+            }
+            epoch += 1;
+        }
+    }
+
+    let mut attested: Vec<u64> = issued
+        .iter()
+        .filter(|c| c.attestation)
+        .map(|c| c.block)
+        .collect();
+    attested.sort_unstable();
+    let interval =
+        median_gap(&attested).unwrap_or_else(|| config.blocks_in(config.attestation_interval_secs));
+    if interval > 0 {
+        let mut block = attested.last().copied().unwrap_or(head_block) + interval;
+        while block <= head_block {
+            block += interval;
+        }
+        while block <= horizon_end {
+            duties.push((DutyKind::AttestationRefresh, block, 1));
+            block += interval;
+        }
+    }
+
+    let mut duties: Vec<Duty> = duties
+        .into_iter()
+        .map(|(kind, block, expected_challenges)| {
+            let expected_unix = unix_of(block);
+            let before = |lead: u64| expected_unix.saturating_sub(lead);
+            let mut actions = match kind {
+                DutyKind::EpochChallenges => vec![
+                    (PrewarmAction::ComposeSummaries, config.summary_lead_secs),
+                    (PrewarmAction::CheckGas, config.gas_lead_secs),
+                    (PrewarmAction::PrefetchStake, config.stake_lead_secs),
+                ],
+                DutyKind::AttestationRefresh => vec![
+                    (PrewarmAction::RefreshQuote, config.quote_lead_secs),
+                    (PrewarmAction::CheckGas, config.gas_lead_secs),
+                ],
+                DutyKind::CollateralExpiry => Vec::new(),
+            }
+            .into_iter()
+            .map(|(action, lead)| PlannedAction {
+                action,
+                at_unix: before(lead),
+            })
+            .collect::<Vec<_>>();
+            actions.sort_by_key(|a| (a.at_unix, a.action));
+            Duty {
+                kind,
+                expected_block: block,
+                expected_unix,
+                expected_challenges,
+                actions,
+            }
+        })
+        .collect();
+    duties.sort_by_key(|d| (d.expected_block, d.kind));
+    duties
+}
+
+/// The duty of collateral expiring at `expires_unix`, when that is within the horizon.
+fn collateral_duty(
+    config: &DutyConfig,
+    head_block: u64,
+    head_unix: u64,
+    expires_unix: u64,
+) -> Option<Duty> {
+    if expires_unix <= head_unix || expires_unix > head_unix + config.horizon_secs {
+        return None;
+    }
+    let at_unix = expires_unix.saturating_sub(config.collateral_lead_secs);
+    Some(Duty {
+        kind: DutyKind::CollateralExpiry,
+        expected_block: head_block + config.blocks_in(expires_unix - head_unix),
+        expected_unix: expires_unix,
+        expected_challenges: 0,
+        actions: vec![
+            PlannedAction {
+                action: PrewarmAction::RefreshCollateral,
+                at_unix,
+            },
+            PlannedAction {
+                action: PrewarmAction::RefreshQuote,
+                at_unix,
+            },
+        ],
+    })
+}
+
+/// The average count of non-attestation challenges in the bursts of the last `history_epochs`
+/// complete epochs, and their average distance from the boundary. At least one challenge is
+/// expected.
+fn estimate_burst(config: &DutyConfig, head_block: u64, issued: &[IssuedChallenge]) -> (u32, u64) {
+    let Some(last) = head_block
+        .checked_sub(config.epoch_offset_blocks + config.burst_spread_blocks)
+        .map(|b| b / config.epoch_blocks)
+    else {
+        return (1, 0);
+    };
+    let first = last.saturating_sub(config.history_epochs - 1);
+    let (mut count, mut offsets) = (0u64, 0u64);
+    for epoch in first..=last {
+        let boundary = config.boundary(epoch);
+        for challenge in issued.iter().filter(|c| !c.attestation) {
+            if (boundary..boundary + config.burst_spread_blocks).contains(&challenge.block) {
+                count += 1;
+                offsets += challenge.block - boundary;
+            }
+        }
+    }
+    if count == 0 {
+        return (1, 0);
+    }
+    let epochs = last - first + 1;
+    (count.div_ceil(epochs).max(1) as u32, offsets / count)
+}
+
+fn median_gap(sorted: &[u64]) -> Option<u64> {
+    let mut gaps: Vec<u64> = sorted
+        .windows(2)
+        .map(|w| w[1] - w[0])
+        .filter(|gap| *gap > 0)
+        .collect();
+    gaps.sort_unstable();
+    gaps.get(gaps.len() / 2).copied()
+}
+
+/// What the pre-warm actions act on.
+pub trait Prewarm: Send + Sync {
+    /// Refetches the platform's collateral.
+    fn refresh_collateral(&self) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+
+    /// When the current collateral expires, `None` without collateral tracking.
+    fn collateral_expiry(&self) -> Option<u64>;
+
+    fn refresh_quote(&self) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+
+    /// Composes the evidence summaries from `from_block` to the head.
+    fn compose_summaries(&self, from_block: u64) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+
+    /// The balance of the account challenge responses are sent from.
+    fn response_balance(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>>;
+
+    fn prefetch_stake(&self) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+}
+
+/// [`Prewarm`] over the operator's TEE, evidence log, signers and operator set.
+pub struct OperatorPrewarm {
+    pub tee: TeeHandler,
+    pub evm: Arc<dyn EvmClient>,
+    pub evidence: EvidenceLog,
+    pub windows: AnchorConfig,
+    pub heartbeat_period_ms: u64,
+    pub lanes: Arc<SignerLanes>,
+    pub operator_set: Option<Arc<OperatorSetTracker>>,
+    pub collateral: Option<Arc<CollateralMonitor>>,
+}
+
+impl Prewarm for OperatorPrewarm {
+    fn refresh_collateral(&self) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(async move {
+            if let Some(collateral) = &self.collateral {
+                collateral.refresh(now_unix_ms() / 1000).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn collateral_expiry(&self) -> Option<u64> {
+        self.collateral.as_ref()?.expires_unix()
+    }
+
+    fn refresh_quote(&self) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(self.tee.warm_caches())
+    }
+
+    fn compose_summaries(&self, from_block: u64) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(async move {
+            let head = self.evm.block_number().await?;
+            evidence_for_range(
+                &self.evidence,
+                &self.windows,
+                self.evm.as_ref(),
+                from_block.min(head),
+                head,
+                self.heartbeat_period_ms,
+                now_unix_ms(),
+            )
+            .await
+            .map(|_| ())
+        })
+    }
+
+    fn response_balance(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+        Box::pin(self.lanes.read_balance(TxClass::Urgent))
+    }
+
+    fn prefetch_stake(&self) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(async move {
+            if let Some(tracker) = &self.operator_set {
+                tracker.refresh(self.evm.block_number().await?).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct CalendarState {
+    forecast: Option<DutyForecast>,
+    /// Actions already run, by duty key.
+    done: BTreeSet<(DutyKind, u64, PrewarmAction)>,
+    /// Duties whose window arrived and whose prerequisites were checked.
+    arrived: BTreeSet<(DutyKind, u64)>,
+    quote_refreshed_unix: Option<u64>,
+    balance_wei: Option<u128>,
+}
+
+/// Predicts duties and runs their pre-warm actions.
+pub struct DutyCalendar {
+    config: DutyConfig,
+    prewarm: Arc<dyn Prewarm>,
+    state: Mutex<CalendarState>,
+}
+
+impl DutyCalendar {
+    pub fn new(config: DutyConfig, prewarm: Arc<dyn Prewarm>) -> Self {
+        Self {
+            config,
+            prewarm,
+            state: Mutex::new(CalendarState::default()),
+        }
+    }
+
+    pub fn config(&self) -> &DutyConfig {
+        &self.config
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, CalendarState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The current forecast, `None` before the first [`plan`](Self::plan).
+    pub fn forecast(&self) -> Option<DutyForecast> {
+        self.state().forecast.clone()
+    }
+
+    /// Replaces the forecast with one from `head_block`, mined at `head_unix`.
+    pub fn plan(&self, head_block: u64, head_unix: u64, issued: &[IssuedChallenge]) {
+        let mut duties = forecast(&self.config, head_block, head_unix, issued);
+        if let Some(expires_unix) = self.prewarm.collateral_expiry() {
+            duties.extend(collateral_duty(
+                &self.config,
+                head_block,
+                head_unix,
+                expires_unix,
+            ));
+            duties.sort_by_key(|d| (d.expected_block, d.kind));
+        }
+        let mut state = self.state();
+        // Keep what was done for duties still ahead or only just past.
+        let kept = |kind: &DutyKind, at: &u64| match kind {
+            DutyKind::CollateralExpiry => *at + self.config.horizon_secs >= head_unix,
+            _ => *at + self.config.blocks_in(self.config.horizon_secs) >= head_block,
+        };
+        state.done.retain(|(kind, at, _)| kept(kind, at));
+        state.arrived.retain(|(kind, at)| kept(kind, at));
+        state.forecast = Some(DutyForecast {
+            estimated: true,
+            head_block,
+            generated_unix: head_unix,
+            duties,
+            most_urgent: None,
+        });
+    }
+
+    /// Attaches the most urgent pending challenge to the current forecast.
+    pub fn record_most_urgent(&self, most_urgent: Option<RemainingWindow>) {
+        if let Some(forecast) = self.state().forecast.as_mut() {
+            forecast.most_urgent = most_urgent;
+        }
+    }
+
+    /// Runs the pre-warm actions due at `now_unix` and checks the prerequisites of the duties
+    /// whose window arrived, returning an alert for each unmet one.
+    pub async fn tick(&self, now_unix: u64) -> Vec<Alert> {
+        let Some(forecast) = self.forecast() else {
+            return Vec::new();
+        };
+        for duty in &forecast.duties {
+            for planned in &duty.actions {
+                let due = {
+                    let mut state = self.state();
+                    let (kind, at) = duty.key();
+                    planned.at_unix <= now_unix
+                        && !state.arrived.contains(&duty.key())
+                        && state.done.insert((kind, at, planned.action))
+                };
+                if !due {
+                    continue;
+                }
+                self.run(duty, planned.action, now_unix).await;
+            }
+        }
+
+        let mut alerts = Vec::new();
+        for duty in &forecast.duties {
+            if duty.expected_unix > now_unix || !self.state().arrived.insert(duty.key()) {
+                continue;
+            }
+            alerts.extend(self.check_prerequisites(duty, now_unix));
+        }
+        alerts
+    }
+
+    async fn run(&self, duty: &Duty, action: PrewarmAction, now_unix: u64) {
+        let result = match action {
+            PrewarmAction::RefreshCollateral => self.prewarm.refresh_collateral().await,
+            PrewarmAction::RefreshQuote => self.prewarm.refresh_quote().await.map(|()| {
+                self.state().quote_refreshed_unix = Some(now_unix);
+            }),
+            PrewarmAction::ComposeSummaries => {
+                let closing = duty.expected_block.saturating_sub(self.config.epoch_blocks);
+                self.prewarm.compose_summaries(closing).await
+            }
+            PrewarmAction::CheckGas => self.prewarm.response_balance().await.map(|balance| {
+                self.state().balance_wei = Some(balance);
+            }),
+            PrewarmAction::PrefetchStake => self.prewarm.prefetch_stake().await,
+        };
+        let outcome = match &result {
+            Ok(()) => "ok",
+            Err(e) => {
+                warn!(
+                    "Pre-warm action {} for the expected duty at block {} failed: {e}",
+                    action.as_str(),
+                    duty.expected_block
+                );
+                "failed"
+            }
+        };
+        METRICS.inc_counter(
+            DUTY_PREWARM_METRIC,
+            &[("action", action.as_str()), ("outcome", outcome)],
+            1,
+        );
+    }
+
+    fn check_prerequisites(&self, duty: &Duty, now_unix: u64) -> Vec<Alert> {
+        let state = self.state();
+        let mut unmet = Vec::new();
+        if duty.kind == DutyKind::AttestationRefresh
+            && state
+                .quote_refreshed_unix
+                .is_none_or(|at| now_unix.saturating_sub(at) > self.config.quote_max_age_secs)
+        {
+            unmet.push((
+                "quote",
+                format!(
+                    "the TEE quote was not refreshed in the last {}s",
+                    self.config.quote_max_age_secs
+                ),
+            ));
+        }
+        let needed = self.config.response_cost_wei * duty.expected_challenges as u128;
+        match state.balance_wei {
+            _ if duty.kind == DutyKind::CollateralExpiry => {}
+            Some(balance) if balance >= needed => {}
+            Some(balance) => unmet.push((
+                "balance",
+                format!("the response account holds {balance} wei of the {needed} wei expected"),
+            )),
+            None => unmet.push((
+                "balance",
+                "the response account's balance could not be checked".to_string(),
+            )),
+        }
+        if duty.kind == DutyKind::CollateralExpiry
+            && self
+                .prewarm
+                .collateral_expiry()
+                .is_none_or(|at| at <= duty.expected_unix)
+        {
+            unmet.push((
+                "collateral",
+                "no collateral valid past it could be fetched".to_string(),
+            ));
+        }
+        if unmet.is_empty() {
+            info!(
+                "Expected duty window at block {} arrived with its prerequisites met",
+                duty.expected_block
+            );
+        }
+        unmet
+            .into_iter()
+            .map(|(prerequisite, reason)| {
+                METRICS.inc_counter(DUTY_UNMET_METRIC, &[("prerequisite", prerequisite)], 1);
+                Alert::new(
+                    "duties",
+                    Severity::Warning,
+                    format!(
+                        "The expected {} window at block {} (an estimate) arrived unprepared: \
+                         {reason}",
+                        match duty.kind {
+                            DutyKind::EpochChallenges => "epoch challenge",
+                            DutyKind::AttestationRefresh => "attestation",
+                            DutyKind::CollateralExpiry => "collateral expiry",
+                        },
+                        duty.expected_block
+                    ),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Replans from the chain head and the tracked challenges every `check_secs`, running the
+/// pre-warm actions that fall due and alerting on unprepared duty windows.
+pub fn spawn_calendar(
+    calendar: Arc<DutyCalendar>,
+    tracker: Arc<ChallengeTracker>,
+    evm: Arc<dyn EvmClient>,
+    notifier: Arc<dyn Notifier>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(calendar.config.check_secs));
+        loop {
+            interval.tick().await;
+            // Ticks against the previous forecast first, so a window the head just passed is
+            // still checked.
+            for alert in calendar.tick(now_unix_ms() / 1000).await {
+                if let Err(e) = notifier.notify(alert).await {
+                    warn!("Failed to deliver duty alert: {e}");
+                }
+            }
+            let head = match evm.block_number().await {
+                Ok(head) => head,
+                Err(e) => {
+                    warn!("Failed to read the head for the duty calendar: {e}");
+                    continue;
+                }
+            };
+            let history_blocks = calendar.config.history_epochs * calendar.config.epoch_blocks
+                + calendar.config.blocks_in(calendar.config.horizon_secs);
+            let issued: Vec<IssuedChallenge> =
+                match tracker.closed_between(head.saturating_sub(history_blocks), u64::MAX) {
+                    Ok(challenges) => challenges
+                        .iter()
+                        .map(|c| IssuedChallenge::from(&c.challenge))
+                        .collect(),
+                    Err(e) => {
+                        warn!("Failed to read challenge history for the duty calendar: {e}");
+                        continue;
+                    }
+                };
+            calendar.plan(head, now_unix_ms() / 1000, &issued);
+            tracker.observe_head(head);
+            calendar.record_most_urgent(
+                tracker
+                    .window_summary()
+                    .and_then(|summary| summary.most_urgent().cloned()),
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const HEAD: u64 = 10_050;
+    const NOW: u64 = 1_700_000_000;
+
+    fn config() -> DutyConfig {
+        DutyConfig {
+            epoch_blocks: 1_000,
+            epoch_offset_blocks: 0,
+            block_time_ms: 12_000,
+            horizon_secs: 86_400,
+            history_epochs: 4,
+            burst_spread_blocks: 100,
+            attestation_interval_secs: 0,
+            ..DutyConfig::default()
+        }
+    }
+
+    fn sla(block: u64) -> IssuedChallenge {
+        IssuedChallenge {
+            block,
+            attestation: false,
+        }
+    }
+
+    fn attestation(block: u64) -> IssuedChallenge {
+        IssuedChallenge {
+            block,
+            attestation: true,
+        }
+    }
+
+    /// Three challenges 10 blocks after each of the last four boundaries, a stray one mid-epoch,
+    /// and attestations every 600 blocks.
+    fn history() -> Vec<IssuedChallenge> {
+        let mut issued: Vec<_> = (6..10)
+            .flat_map(|epoch| (0..3).map(move |_| sla(epoch * 1_000 + 10)))
+            .collect();
+        issued.push(sla(9_500));
+        issued.extend([8_800, 9_400, 10_000].map(attestation));
+        issued
+    }
+
+    #[derive(Default)]
+    struct MockPrewarm {
+        balance: AtomicU64,
+        calls: Mutex<Vec<PrewarmAction>>,
+        collateral_expiry: Mutex<Option<u64>>,
+        /// What a collateral refresh moves the expiry to; unset leaves it.
+        renewed_expiry: Mutex<Option<u64>>,
+    }
+
+    impl Prewarm for MockPrewarm {
+        fn refresh_collateral(&self) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(PrewarmAction::RefreshCollateral);
+            if let Some(renewed) = *self.renewed_expiry.lock().unwrap() {
+                *self.collateral_expiry.lock().unwrap() = Some(renewed);
+            }
+            Box::pin(async { Ok(()) })
+        }
+
+        fn collateral_expiry(&self) -> Option<u64> {
+            *self.collateral_expiry.lock().unwrap()
+        }
+
+        fn refresh_quote(&self) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.calls.lock().unwrap().push(PrewarmAction::RefreshQuote);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn compose_summaries(&self, from_block: u64) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            assert_eq!(from_block % 1_000, 10, "the closing epoch's burst block");
+            self.calls
+                .lock()
+                .unwrap()
+                .push(PrewarmAction::ComposeSummaries);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn response_balance(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+            self.calls.lock().unwrap().push(PrewarmAction::CheckGas);
+            let balance = self.balance.load(Ordering::SeqCst) as u128;
+            Box::pin(async move { Ok(balance) })
+        }
+
+        fn prefetch_stake(&self) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(PrewarmAction::PrefetchStake);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn calendar_is_derived_from_epochs_and_issuance_history() {
+        let duties = forecast(&config(), HEAD, NOW, &history());
+        let epochs: Vec<_> = duties
+            .iter()
+            .filter(|d| d.kind == DutyKind::EpochChallenges)
+            .collect();
+        // 7200 blocks a day: boundaries 11000 to 17000, each burst 10 blocks in.
+        assert_eq!(
+            epochs.iter().map(|d| d.expected_block).collect::<Vec<_>>(),
+            (11..=17).map(|e| e * 1_000 + 10).collect::<Vec<_>>()
+        );
+        // The mid-epoch challenge is outside every burst.
+        assert!(epochs.iter().all(|d| d.expected_challenges == 3));
+        let first = epochs[0];
+        assert_eq!(first.expected_unix, NOW + 960 * 12);
+        assert_eq!(first.actions, vec![
+            PlannedAction {
+                action: PrewarmAction::CheckGas,
+                at_unix: first.expected_unix - 900,
+            },
+            PlannedAction {
+                action: PrewarmAction::ComposeSummaries,
+                at_unix: first.expected_unix - 600,
+            },
+            PlannedAction {
+                action: PrewarmAction::PrefetchStake,
+                at_unix: first.expected_unix - 300,
+            },
+        ]);
+
+        let attestations: Vec<_> = duties
+            .iter()
+            .filter(|d| d.kind == DutyKind::AttestationRefresh)
+            .map(|d| d.expected_block)
+            .collect();
+        assert_eq!(attestations.len(), 12);
+        assert_eq!(attestations[0], 10_600);
+        assert!(attestations.windows(2).all(|w| w[1] - w[0] == 600));
+        assert!(
+            duties
+                .windows(2)
+                .all(|w| w[0].expected_block <= w[1].expected_block)
+        );
+
+        // Without history, one challenge per epoch at the boundary and the configured
+        // attestation interval.
+        let fallback = DutyConfig {
+            attestation_interval_secs: 3_600,
+            ..config()
+        };
+        let duties = forecast(&fallback, HEAD, NOW, &[]);
+        assert_eq!(duties[0].expected_block, 10_350);
+        assert_eq!(duties[0].kind, DutyKind::AttestationRefresh);
+        let epoch = duties
+            .iter()
+            .find(|d| d.kind == DutyKind::EpochChallenges)
+            .unwrap();
+        assert_eq!(
+            (epoch.expected_block, epoch.expected_challenges),
+            (11_000, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn prewarm_actions_fire_at_their_offsets() {
+        let prewarm = Arc::new(MockPrewarm::default());
+        prewarm.balance.store(u64::MAX, Ordering::SeqCst);
+        let calendar = DutyCalendar::new(config(), prewarm.clone());
+        calendar.plan(HEAD, NOW, &history());
+        let forecast = calendar.forecast().unwrap();
+        assert!(forecast.estimated);
+        let attestation = forecast.duties[0].clone();
+        assert_eq!(attestation.kind, DutyKind::AttestationRefresh);
+        let calls = || prewarm.calls.lock().unwrap().clone();
+
+        assert!(
+            calendar
+                .tick(attestation.expected_unix - 901)
+                .await
+                .is_empty()
+        );
+        assert!(calls().is_empty());
+        calendar.tick(attestation.expected_unix - 900).await;
+        assert_eq!(calls(), [PrewarmAction::CheckGas]);
+        // Nothing runs twice.
+        calendar.tick(attestation.expected_unix - 600).await;
+        assert_eq!(calls(), [PrewarmAction::CheckGas]);
+        calendar.tick(attestation.expected_unix - 300).await;
+        assert_eq!(calls(), [
+            PrewarmAction::CheckGas,
+            PrewarmAction::RefreshQuote
+        ]);
+
+        let alerts = calendar.tick(attestation.expected_unix).await;
+        assert!(alerts.is_empty(), "{alerts:?}");
+
+        // The epoch burst's actions, in order of their leads.
+        let epoch = forecast
+            .duties
+            .iter()
+            .find(|d| d.kind == DutyKind::EpochChallenges)
+            .unwrap();
+        prewarm.calls.lock().unwrap().clear();
+        for planned in &epoch.actions {
+            calendar.tick(planned.at_unix).await;
+        }
+        let epoch_calls: Vec<_> = calls()
+            .into_iter()
+            .filter(|a| *a != PrewarmAction::RefreshQuote)
+            .collect();
+        assert!(epoch_calls.ends_with(&[
+            PrewarmAction::CheckGas,
+            PrewarmAction::ComposeSummaries,
+            PrewarmAction::PrefetchStake,
+        ]));
+    }
+
+    #[tokio::test]
+    async fn unmet_prerequisites_raise_an_alert_when_the_window_arrives() {
+        let prewarm = Arc::new(MockPrewarm::default());
+        // Short of the 3 * 10^15 wei three challenges are expected to cost.
+        prewarm
+            .balance
+            .store(2_000_000_000_000_000, Ordering::SeqCst);
+        let calendar = DutyCalendar::new(
+            DutyConfig {
+                quote_max_age_secs: 120,
+                ..config()
+            },
+            prewarm.clone(),
+        );
+        calendar.plan(HEAD, NOW, &history());
+        let forecast = calendar.forecast().unwrap();
+        let attestation = &forecast.duties[0];
+        let epoch = forecast
+            .duties
+            .iter()
+            .find(|d| d.kind == DutyKind::EpochChallenges)
+            .unwrap();
+
+        // The quote is refreshed 300s ahead, older than 120s by the time the window arrives.
+        calendar.tick(attestation.expected_unix - 900).await;
+        calendar.tick(attestation.expected_unix - 300).await;
+        let alerts = calendar.tick(attestation.expected_unix).await;
+        assert_eq!(alerts.len(), 1, "{alerts:?}");
+        assert!(alerts[0].message.contains("quote"));
+        assert!(alerts[0].message.contains("estimate"));
+        assert!(
+            calendar
+                .tick(attestation.expected_unix + 1)
+                .await
+                .is_empty()
+        );
+
+        let alerts = calendar.tick(epoch.expected_unix).await;
+        let balance: Vec<_> = alerts
+            .iter()
+            .filter(|a| a.message.contains("wei"))
+            .collect();
+        assert_eq!(balance.len(), 1, "{alerts:?}");
+        assert!(
+            balance[0]
+                .message
+                .contains(&format!("block {}", epoch.expected_block))
+        );
+    }
+
+    #[tokio::test]
+    async fn collateral_is_refreshed_ahead_of_its_expiry() {
+        let prewarm = Arc::new(MockPrewarm::default());
+        let expires = NOW + 7_200;
+        *prewarm.collateral_expiry.lock().unwrap() = Some(expires);
+        *prewarm.renewed_expiry.lock().unwrap() = Some(NOW + 30 * 86_400);
+        let calendar = DutyCalendar::new(
+            DutyConfig {
+                epoch_blocks: 0,
+                ..config()
+            },
+            prewarm.clone(),
+        );
+        calendar.plan(HEAD, NOW, &[]);
+        let duties = calendar.forecast().unwrap().duties;
+        assert_eq!(duties.len(), 1, "{duties:?}");
+        let duty = &duties[0];
+        assert_eq!(duty.kind, DutyKind::CollateralExpiry);
+        assert_eq!(duty.expected_unix, expires);
+        assert_eq!(duty.expected_block, HEAD + 600);
+        assert!(duty.actions.iter().all(|a| a.at_unix == expires - 3_600));
+
+        assert!(calendar.tick(expires - 3_601).await.is_empty());
+        assert!(prewarm.calls.lock().unwrap().is_empty());
+        calendar.tick(expires - 3_600).await;
+        assert_eq!(*prewarm.calls.lock().unwrap(), [
+            PrewarmAction::RefreshCollateral,
+            PrewarmAction::RefreshQuote
+        ]);
+
+        // Replanned a few blocks later, the renewed collateral is past the horizon, and nothing
+        // is due when the old expiry passes; no balance is needed for it either.
+        calendar.plan(HEAD + 5, NOW + 61, &[]);
+        assert!(calendar.forecast().unwrap().duties.is_empty());
+        assert!(calendar.tick(expires).await.is_empty());
+        assert_eq!(prewarm.calls.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unrenewed_collateral_alerts_when_it_expires() {
+        let prewarm = Arc::new(MockPrewarm::default());
+        let expires = NOW + 7_200;
+        *prewarm.collateral_expiry.lock().unwrap() = Some(expires);
+        let calendar = DutyCalendar::new(
+            DutyConfig {
+                epoch_blocks: 0,
+                ..config()
+            },
+            prewarm.clone(),
+        );
+        calendar.plan(HEAD, NOW, &[]);
+        calendar.tick(expires - 3_600).await;
+        // The block estimate moving does not make it a new duty, so nothing runs twice.
+        calendar.plan(HEAD + 7, NOW + 60, &[]);
+        calendar.tick(expires - 1_800).await;
+        assert_eq!(prewarm.calls.lock().unwrap().len(), 2);
+
+        let alerts = calendar.tick(expires).await;
+        assert_eq!(alerts.len(), 1, "{alerts:?}");
+        assert!(alerts[0].message.contains("collateral expiry"));
+        assert!(!alerts[0].message.contains("wei"));
+    }
+}
@@ -0,0 +1,494 @@
+//! Challenge response encoders.
+//!
+//! Every challenge kind and version has an encoder that declares the response schema it
+//! produces; the schema hash is compared against what the oracle publishes (see
+//! [`crate::schema`]) before any response is sent.
+
+use crate::challenge::ObservedChallenge;
+use crate::error::PhalaAvsError;
+use crate::sanitize;
+use crate::tee::compute::TeeComputation;
+use crate::tee::platform::TeePlatform;
+use blueprint_sdk::alloy::primitives::{B256, Bytes, U256, keccak256};
+use blueprint_sdk::alloy::sol;
+use blueprint_sdk::alloy::sol_types::SolValue;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Kind assumed for challenges whose data is not a [`ChallengeEnvelope`].
+pub const LIVENESS_KIND: &str = "liveness";
+/// Oracle-specified computation, only ever run in the TEE.
+pub const TEE_COMPUTE_KIND: &str = "tee_compute";
+/// A TDX or SGX quote bound to the challenge; see [`crate::preflight`].
+pub const ATTESTATION_KIND: &str = "attestation";
+
+sol! {
+    /// The `challengeData` of versioned challenges: `abi.encode(kind, version, params)`.
+    struct ChallengeEnvelope {
+        bytes32 kind;
+        uint32 version;
+        bytes params;
+    }
+
+    /// Response to a liveness challenge, version 1.
+    struct LivenessResponseV1 {
+        uint256 challengeId;
+        uint64 respondedAtUnix;
+        bool live;
+    }
+
+    /// Envelope params of a TEE computation challenge, version 1.
+    struct ComputeChallengeV1 {
+        bytes32 programId;
+        bytes params;
+    }
+
+    /// Response to a TEE computation challenge, version 1.
+    struct ComputeResponseV1 {
+        uint256 challengeId;
+        bytes32 programId;
+        bytes32 paramsHash;
+        bytes32 inputsHash;
+        bytes32 outputHash;
+        bytes output;
+        bytes attestation;
+    }
+
+    /// Envelope params of an attestation challenge, versions 1 and 2.
+    struct AttestationChallengeV1 {
+        bytes32 nonce;
+    }
+
+    /// Response to an attestation challenge, version 1.
+    struct AttestationResponseV1 {
+        uint256 challengeId;
+        uint64 quotedAtUnix;
+        bytes quote;
+    }
+
+    /// Response to an attestation challenge, version 2: tagged with the quote's platform code.
+    struct AttestationResponseV2 {
+        uint256 challengeId;
+        uint8 platform;
+        uint64 quotedAtUnix;
+        bytes quote;
+    }
+}
+
+/// On-chain identifier of a challenge kind name.
+pub fn kind_id(name: &str) -> B256 {
+    keccak256(name.as_bytes())
+}
+
+/// A challenge kind at a schema version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct SchemaKey {
+    pub kind: B256,
+    pub version: u32,
+}
+
+impl SchemaKey {
+    pub fn new(kind_name: &str, version: u32) -> Self {
+        Self {
+            kind: kind_id(kind_name),
+            version,
+        }
+    }
+
+    /// The kind and version a challenge asks for; unversioned challenge data is liveness v1.
+    pub fn of(challenge: &ObservedChallenge) -> Self {
+        match ChallengeEnvelope::abi_decode_params(&challenge.challenge_data, true) {
+            Ok(envelope) => Self {
+                kind: envelope.kind,
+                version: envelope.version,
+            },
+            Err(_) => Self::new(LIVENESS_KIND, 1),
+        }
+    }
+}
+
+impl fmt::Display for SchemaKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match encoders().iter().find(|e| e.key() == *self) {
+            Some(encoder) => write!(f, "{} v{}", encoder.kind_name(), self.version),
+            None => write!(f, "{} v{}", self.kind, self.version),
+        }
+    }
+}
+
+/// What a response encoder needs besides the challenge itself.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseInputs {
+    pub responded_at_unix: u64,
+    pub live: bool,
+    /// Result of [`crate::TeeHandler::compute_in_tee`], for `tee_compute` kinds.
+    pub computation: Option<TeeComputation>,
+    /// Quote over [`attestation_report_data`], for `attestation` kinds.
+    pub attestation: Option<AttestationQuote>,
+}
+
+/// A raw quote, the platform that produced it and the time its report data commits to.
+#[derive(Clone, Debug)]
+pub struct AttestationQuote {
+    pub platform: TeePlatform,
+    pub quoted_at_unix: u64,
+    pub quote: Bytes,
+}
+
+/// Encodes responses for one challenge kind and version.
+pub trait ResponseEncoder: Send + Sync {
+    fn kind_name(&self) -> &'static str;
+
+    fn version(&self) -> u32;
+
+    /// Canonical description of the encoded response, e.g. its ABI tuple type.
+    fn schema(&self) -> &'static str;
+
+    /// Whether the response is computed by the TEE; see [`crate::tee::compute`].
+    fn tee_compute(&self) -> bool {
+        false
+    }
+
+    fn key(&self) -> SchemaKey {
+        SchemaKey::new(self.kind_name(), self.version())
+    }
+
+    /// Hash the oracle publishes for this schema.
+    fn schema_hash(&self) -> B256 {
+        keccak256(self.schema().as_bytes())
+    }
+
+    fn encode(
+        &self,
+        challenge: &ObservedChallenge,
+        inputs: &ResponseInputs,
+    ) -> Result<Bytes, PhalaAvsError>;
+
+    /// Checks that a payload built elsewhere, e.g. by a workload's responder, is a response to
+    /// `challenge` in this schema.
+    fn validate(&self, challenge: &ObservedChallenge, payload: &[u8]) -> Result<(), PhalaAvsError>;
+}
+
+fn decode_response<T>(payload: &[u8], schema: &str) -> Result<T, PhalaAvsError>
+where
+    T: SolValue + From<<T::SolType as blueprint_sdk::alloy::sol_types::SolType>::RustType>,
+{
+    T::abi_decode(payload, true).map_err(|e| {
+        PhalaAvsError::ValidationError(format!(
+            "Payload does not match schema {schema}: {}",
+            sanitize::message("response_payload", e)
+        ))
+    })
+}
+
+/// Decoding errors can quote the challenge data, which is the challenger's to choose.
+fn invalid_challenge(what: &str, e: impl fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::ValidationError(format!(
+        "Invalid {what}: {}",
+        sanitize::message("challenge_data", e)
+    ))
+}
+
+fn check_answers(challenge: &ObservedChallenge, challenge_id: U256) -> Result<(), PhalaAvsError> {
+    if challenge_id != challenge.challenge_id {
+        return Err(PhalaAvsError::ValidationError(format!(
+            "Payload answers challenge {challenge_id}, not {}",
+            challenge.challenge_id
+        )));
+    }
+    Ok(())
+}
+
+pub struct LivenessEncoderV1;
+
+impl ResponseEncoder for LivenessEncoderV1 {
+    fn kind_name(&self) -> &'static str {
+        LIVENESS_KIND
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn schema(&self) -> &'static str {
+        "(uint256 challengeId,uint64 respondedAtUnix,bool live)"
+    }
+
+    fn encode(
+        &self,
+        challenge: &ObservedChallenge,
+        inputs: &ResponseInputs,
+    ) -> Result<Bytes, PhalaAvsError> {
+        Ok(LivenessResponseV1 {
+            challengeId: challenge.challenge_id,
+            respondedAtUnix: inputs.responded_at_unix,
+            live: inputs.live,
+        }
+        .abi_encode()
+        .into())
+    }
+
+    fn validate(&self, challenge: &ObservedChallenge, payload: &[u8]) -> Result<(), PhalaAvsError> {
+        let response: LivenessResponseV1 = decode_response(payload, self.schema())?;
+        check_answers(challenge, response.challengeId)
+    }
+}
+
+/// The program and parameters a `tee_compute` challenge asks for.
+pub fn compute_challenge(
+    challenge: &ObservedChallenge,
+) -> Result<ComputeChallengeV1, PhalaAvsError> {
+    let envelope = ChallengeEnvelope::abi_decode_params(&challenge.challenge_data, true)
+        .map_err(|e| invalid_challenge("challenge envelope", e))?;
+    ComputeChallengeV1::abi_decode_params(&envelope.params, true)
+        .map_err(|e| invalid_challenge("computation challenge", e))
+}
+
+/// Wraps a [`TeeComputation`] and its attested binding; there is no host-computed variant.
+pub struct TeeComputeEncoderV1;
+
+impl ResponseEncoder for TeeComputeEncoderV1 {
+    fn kind_name(&self) -> &'static str {
+        TEE_COMPUTE_KIND
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn schema(&self) -> &'static str {
+        "(uint256 challengeId,bytes32 programId,bytes32 paramsHash,bytes32 inputsHash,bytes32 outputHash,bytes output,bytes attestation)"
+    }
+
+    fn tee_compute(&self) -> bool {
+        true
+    }
+
+    fn encode(
+        &self,
+        challenge: &ObservedChallenge,
+        inputs: &ResponseInputs,
+    ) -> Result<Bytes, PhalaAvsError> {
+        let computation = inputs.computation.as_ref().ok_or_else(|| {
+            PhalaAvsError::ValidationError(
+                "tee_compute responses can only be built from a TEE computation".to_string(),
+            )
+        })?;
+        let requested = compute_challenge(challenge)?;
+        let binding = computation.binding();
+        if binding.program_id != requested.programId
+            || binding.params_hash != keccak256(&requested.params)
+        {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "TEE computation does not answer challenge {}",
+                challenge.challenge_id
+            )));
+        }
+        Ok(ComputeResponseV1 {
+            challengeId: challenge.challenge_id,
+            programId: binding.program_id,
+            paramsHash: binding.params_hash,
+            inputsHash: binding.inputs_hash,
+            outputHash: binding.output_hash,
+            output: computation.output().clone(),
+            attestation: computation.attestation().clone(),
+        }
+        .abi_encode()
+        .into())
+    }
+
+    fn validate(&self, challenge: &ObservedChallenge, payload: &[u8]) -> Result<(), PhalaAvsError> {
+        let response: ComputeResponseV1 = decode_response(payload, self.schema())?;
+        check_answers(challenge, response.challengeId)?;
+        let requested = compute_challenge(challenge)?;
+        if response.programId != requested.programId
+            || response.paramsHash != keccak256(&requested.params)
+            || response.outputHash != keccak256(&response.output)
+        {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Payload does not answer the computation of challenge {}",
+                challenge.challenge_id
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The nonce an `attestation` challenge asks to be quoted.
+pub fn attestation_challenge(
+    challenge: &ObservedChallenge,
+) -> Result<AttestationChallengeV1, PhalaAvsError> {
+    let envelope = ChallengeEnvelope::abi_decode_params(&challenge.challenge_data, true)
+        .map_err(|e| invalid_challenge("challenge envelope", e))?;
+    AttestationChallengeV1::abi_decode_params(&envelope.params, true)
+        .map_err(|e| invalid_challenge("attestation challenge", e))
+}
+
+/// Report data of an attestation response: `keccak256(abi.encode(challengeId, nonce,
+/// quotedAtUnix))`, followed by the hash of the build that quoted it (see [`crate::build_info`]).
+pub fn attestation_report_data(
+    challenge_id: U256,
+    nonce: B256,
+    quoted_at_unix: u64,
+    build_hash: B256,
+) -> [u8; 64] {
+    let mut report_data = [0u8; 64];
+    report_data[..32]
+        .copy_from_slice(keccak256((challenge_id, nonce, quoted_at_unix).abi_encode()).as_slice());
+    report_data[32..].copy_from_slice(build_hash.as_slice());
+    report_data
+}
+
+pub struct AttestationEncoderV1;
+
+impl ResponseEncoder for AttestationEncoderV1 {
+    fn kind_name(&self) -> &'static str {
+        ATTESTATION_KIND
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn schema(&self) -> &'static str {
+        "(uint256 challengeId,uint64 quotedAtUnix,bytes quote)"
+    }
+
+    fn encode(
+        &self,
+        challenge: &ObservedChallenge,
+        inputs: &ResponseInputs,
+    ) -> Result<Bytes, PhalaAvsError> {
+        let attestation = inputs.attestation.as_ref().ok_or_else(|| {
+            PhalaAvsError::ValidationError("attestation responses need a quote".to_string())
+        })?;
+        Ok(AttestationResponseV1 {
+            challengeId: challenge.challenge_id,
+            quotedAtUnix: attestation.quoted_at_unix,
+            quote: attestation.quote.clone(),
+        }
+        .abi_encode()
+        .into())
+    }
+
+    fn validate(&self, challenge: &ObservedChallenge, payload: &[u8]) -> Result<(), PhalaAvsError> {
+        let response: AttestationResponseV1 = decode_response(payload, self.schema())?;
+        check_answers(challenge, response.challengeId)
+    }
+}
+
+/// Version 2 carries the platform tag, so the oracle knows which verification flow applies
+/// without parsing the quote header.
+pub struct AttestationEncoderV2;
+
+impl ResponseEncoder for AttestationEncoderV2 {
+    fn kind_name(&self) -> &'static str {
+        ATTESTATION_KIND
+    }
+
+    fn version(&self) -> u32 {
+        2
+    }
+
+    fn schema(&self) -> &'static str {
+        "(uint256 challengeId,uint8 platform,uint64 quotedAtUnix,bytes quote)"
+    }
+
+    fn encode(
+        &self,
+        challenge: &ObservedChallenge,
+        inputs: &ResponseInputs,
+    ) -> Result<Bytes, PhalaAvsError> {
+        let attestation = inputs.attestation.as_ref().ok_or_else(|| {
+            PhalaAvsError::ValidationError("attestation responses need a quote".to_string())
+        })?;
+        Ok(AttestationResponseV2 {
+            challengeId: challenge.challenge_id,
+            platform: attestation.platform.code(),
+            quotedAtUnix: attestation.quoted_at_unix,
+            quote: attestation.quote.clone(),
+        }
+        .abi_encode()
+        .into())
+    }
+
+    fn validate(&self, challenge: &ObservedChallenge, payload: &[u8]) -> Result<(), PhalaAvsError> {
+        let response: AttestationResponseV2 = decode_response(payload, self.schema())?;
+        check_answers(challenge, response.challengeId)
+    }
+}
+
+/// Every encoder compiled into this operator.
+pub fn encoders() -> &'static [&'static dyn ResponseEncoder] {
+    &[
+        &LivenessEncoderV1,
+        &TeeComputeEncoderV1,
+        &AttestationEncoderV1,
+        &AttestationEncoderV2,
+    ]
+}
+
+/// The compiled encoder for `key`, if any.
+pub fn encoder_for(key: &SchemaKey) -> Option<&'static dyn ResponseEncoder> {
+    encoders().iter().copied().find(|e| e.key() == *key)
+}
+
+/// The compiled encoders of `kind`, oldest version first.
+///
+/// Versions of a kind are held side by side so a new one can be rolled out per oracle target
+/// (see [`crate::rollout`]); each has its own golden vectors under `tests/fixtures/encoders`.
+pub fn versions(kind: B256) -> Vec<&'static dyn ResponseEncoder> {
+    let mut versions: Vec<_> = encoders()
+        .iter()
+        .copied()
+        .filter(|e| e.key().kind == kind)
+        .collect();
+    versions.sort_by_key(|e| e.version());
+    versions
+}
+
+/// Builds versioned challenge data, as the oracle's challenge issuer does.
+pub fn envelope(kind_name: &str, version: u32, params: Bytes) -> Bytes {
+    ChallengeEnvelope {
+        kind: kind_id(kind_name),
+        version,
+        params,
+    }
+    .abi_encode_params()
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::{MAX_MESSAGE_BYTES, adversarial};
+    use blueprint_sdk::alloy::primitives::Address;
+
+    #[test]
+    fn hostile_challenge_data_and_payloads_give_bounded_errors() {
+        let hostile = adversarial::strings()
+            .into_iter()
+            .map(String::into_bytes)
+            .chain(adversarial::bytes());
+        for raw in hostile {
+            let challenge = ObservedChallenge {
+                challenge_id: U256::from(1),
+                operator: Address::ZERO,
+                challenge_data: envelope(TEE_COMPUTE_KIND, 1, raw.clone().into()),
+                deadline_block: 0,
+                oracle: Address::ZERO,
+                issued_block: 0,
+                issued_block_hash: None,
+                transaction_hash: None,
+            };
+            if let Err(e) = compute_challenge(&challenge) {
+                adversarial::assert_safe(&e.to_string(), MAX_MESSAGE_BYTES + 64);
+            }
+            for encoder in encoders() {
+                if let Err(e) = encoder.validate(&challenge, &raw) {
+                    adversarial::assert_safe(&e.to_string(), MAX_MESSAGE_BYTES + 64);
+                }
+            }
+        }
+    }
+}
@@ -15,6 +15,22 @@ pub enum PhalaAvsError {
     #[error("Task error: {0}")]
     TaskError(String),
 
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+
+    #[error("Storage error: {0}")]
+    StorageError(String),
+
+    #[error("Invalid request: {0}")]
+    ValidationError(String),
+
+    #[error("Startup failed: {0}")]
+    StartupError(String),
+
+    /// The thing asked for does not exist, as opposed to being unavailable.
+    #[error("Not found: {0}")]
+    NotFound(String),
+
     #[error("Keystore error: {0}")]
     KeystoreError(#[from] blueprint_sdk::keystore::Error),
 
@@ -28,6 +44,28 @@ pub enum PhalaAvsError {
     Other(String),
 }
 
+impl PhalaAvsError {
+    /// Whether the failure may clear up on its own, so the call is worth retrying. Bad input,
+    /// configuration and key material fail the same way every time.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::EvmError(_)
+            | Self::TeeError(_)
+            | Self::AggregatorError(_)
+            | Self::StorageError(_)
+            | Self::IoError(_)
+            | Self::Other(_) => true,
+            Self::TaskError(_)
+            | Self::ConfigError(_)
+            | Self::ValidationError(_)
+            | Self::StartupError(_)
+            | Self::NotFound(_)
+            | Self::KeystoreError(_)
+            | Self::CronError(_) => false,
+        }
+    }
+}
+
 // Implement conversion from alloy RpcError if needed, for example:
 // impl From<blueprint_sdk::alloy::rpc::RpcError<alloy_transport_http::HttpError>> for PhalaAvsError {
 //     fn from(err: blueprint_sdk::alloy::rpc::RpcError<alloy_transport_http::HttpError>) -> Self {
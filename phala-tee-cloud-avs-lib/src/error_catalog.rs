@@ -0,0 +1,666 @@
+//! Named contract errors, decoded from revert data, with what to do about them.
+//!
+//! The Phala contracts revert with `require` reasons rather than custom errors, so most of
+//! [`CATALOG`] matches `Error(string)` reasons and gives each a name. The EigenLayer contracts the
+//! operator calls during registration revert with custom errors, matched by signature, and
+//! compiler panics are matched by code. Every entry carries a remediation hint; keep it next to
+//! the entry when a contract changes.
+//!
+//! Revert data is decoded wherever a node returns it: gas estimation and broadcast in the
+//! [`sender`](crate::sender), `eth_call` simulations in the [`rollout`](crate::rollout), and the
+//! aggregator's submissions. Receipts of reverted transactions carry no revert data, so a revert
+//! that slips past estimation is only known by its transaction hash. The decoded form is appended
+//! to the error, so it reaches logs, tracker failure causes, alerts and dead letters with it; the
+//! latest occurrences are kept for diagnostics bundles and counted in
+//! `phala_avs_contract_errors_total`.
+//!
+//! Revert data the catalog does not know is reported with its raw selector and a pointer to add
+//! it here.
+
+use crate::evidence::now_unix_ms;
+use crate::metrics::METRICS;
+use blueprint_sdk::alloy::primitives::{hex, keccak256};
+use blueprint_sdk::alloy::sol_types::{Panic, Revert, SolError};
+use blueprint_sdk::alloy::transports::{RpcError, TransportErrorKind};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use tracing::warn;
+
+pub const CONTRACT_ERRORS_METRIC: &str = "phala_avs_contract_errors_total";
+
+/// How many occurrences [`recent`] keeps.
+const RECENT_CAPACITY: usize = 64;
+
+const UNKNOWN_HINT: &str = "not in the error catalog; add it to error_catalog::CATALOG";
+
+/// What revert data a catalog entry matches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matcher {
+    /// `Error(string)` with exactly this reason.
+    Reason(&'static str),
+    /// A custom error, by its canonical signature, e.g. `SaltSpent()`.
+    Signature(&'static str),
+    /// `Panic(uint256)` with this code.
+    Panic(u8),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CatalogEntry {
+    pub name: &'static str,
+    pub contract: &'static str,
+    pub matcher: Matcher,
+    pub hint: &'static str,
+}
+
+const fn reason(
+    name: &'static str,
+    contract: &'static str,
+    reason: &'static str,
+    hint: &'static str,
+) -> CatalogEntry {
+    CatalogEntry {
+        name,
+        contract,
+        matcher: Matcher::Reason(reason),
+        hint,
+    }
+}
+
+const fn custom(
+    name: &'static str,
+    contract: &'static str,
+    signature: &'static str,
+    hint: &'static str,
+) -> CatalogEntry {
+    CatalogEntry {
+        name,
+        contract,
+        matcher: Matcher::Signature(signature),
+        hint,
+    }
+}
+
+const fn panic(name: &'static str, code: u8, hint: &'static str) -> CatalogEntry {
+    CatalogEntry {
+        name,
+        contract: "any",
+        matcher: Matcher::Panic(code),
+        hint,
+    }
+}
+
+/// Every contract error the operator knows how to explain.
+pub const CATALOG: &[CatalogEntry] = &[
+    // PhalaSlaOracle, as the challenged operator sees it.
+    reason(
+        "ChallengeWindowClosed",
+        "PhalaSlaOracle",
+        "PhalaSLA: Response window closed",
+        "the response was too late; compare phala_avs_challenge_detection_delay_blocks and the \
+         remaining windows on /challenges with the response safety margin",
+    ),
+    reason(
+        "OracleOperatorNotRegistered",
+        "PhalaSlaOracle",
+        "PhalaSLA: Operator not registered",
+        "the service manager does not list this operator; check the registration at startup and \
+         SERVICE_MANAGER_ADDRESS",
+    ),
+    reason(
+        "ChallengeNotFound",
+        "PhalaSlaOracle",
+        "PhalaSLA: Challenge does not exist",
+        "the challenge id is unknown to this oracle; check SLA_ORACLE_ADDRESS and the chain the \
+         challenge was observed on",
+    ),
+    reason(
+        "NotChallengedOperator",
+        "PhalaSlaOracle",
+        "PhalaSLA: Caller is not the challenged operator",
+        "the response is sent from another account than the challenged operator; check the \
+         signer lanes and the keystore",
+    ),
+    reason(
+        "ChallengeAlreadyResponded",
+        "PhalaSlaOracle",
+        "PhalaSLA: Challenge already responded to",
+        "an earlier response landed; check the tx intents for a duplicate or another replica \
+         sending",
+    ),
+    reason(
+        "ChallengeCancelled",
+        "PhalaSlaOracle",
+        "PhalaSLA: Challenge was cancelled",
+        "the issuer cancelled the challenge; nothing to respond to",
+    ),
+    reason(
+        "ChallengeExpiryReported",
+        "PhalaSlaOracle",
+        "PhalaSLA: Challenge expiry already reported",
+        "the challenge expired and was reported; the response window is gone",
+    ),
+    // PhalaSlaOracle, issuer and owner calls.
+    reason(
+        "OracleNotInitialized",
+        "PhalaSlaOracle",
+        "PhalaSLA: Contract is not initialized",
+        "the oracle is deployed but not initialized; check SLA_ORACLE_ADDRESS points at the proxy",
+    ),
+    reason(
+        "OracleAlreadyInitialized",
+        "PhalaSlaOracle",
+        "PhalaSLA: Contract is already initialized",
+        "the oracle was initialized before; nothing to do",
+    ),
+    reason(
+        "NotChallengeIssuer",
+        "PhalaSlaOracle",
+        "PhalaSLA: Caller is not the Challenge Issuer",
+        "only the challenge issuer may call this; check which account sent it",
+    ),
+    reason(
+        "DeadlineNotInFuture",
+        "PhalaSlaOracle",
+        "PhalaSLA: Deadline must be in the future",
+        "the new deadline is at or before the current block",
+    ),
+    reason(
+        "ChallengeWindowOpen",
+        "PhalaSlaOracle",
+        "PhalaSLA: Response window not yet closed",
+        "expiry can only be reported after the response window ends",
+    ),
+    reason(
+        "ChallengeResponded",
+        "PhalaSlaOracle",
+        "PhalaSLA: Challenge was responded to",
+        "the operator responded in time; there is no expiry to report",
+    ),
+    reason(
+        "OracleUnknownTeePlatform",
+        "PhalaSlaOracle",
+        "PhalaSLA: Unknown TEE platform",
+        "the platform must be 1 (TDX) or 2 (SGX)",
+    ),
+    reason(
+        "EmptySchemaHash",
+        "PhalaSlaOracle",
+        "PhalaSLA: Empty schema hash",
+        "a response schema needs a non-zero hash",
+    ),
+    reason(
+        "QuoteAgeNotPositive",
+        "PhalaSlaOracle",
+        "PhalaSLA: Quote age must be positive",
+        "the attestation policy needs a positive maximum quote age",
+    ),
+    reason(
+        "ResponseWindowNotPositive",
+        "PhalaSlaOracle",
+        "PhalaSLA: Response window must be positive",
+        "the response window needs at least one block",
+    ),
+    // PhalaServiceManager, as the operator sees it.
+    reason(
+        "OperatorNotRegistered",
+        "PhalaServiceManager",
+        "PhalaSM: Operator not registered",
+        "this operator is not registered with the service manager; check the registration at \
+         startup and SERVICE_MANAGER_ADDRESS",
+    ),
+    reason(
+        "InvalidMaintenanceWindow",
+        "PhalaServiceManager",
+        "PhalaSM: Invalid maintenance window",
+        "the window must start before it ends",
+    ),
+    reason(
+        "MaintenanceWindowInPast",
+        "PhalaServiceManager",
+        "PhalaSM: Maintenance window in the past",
+        "schedule the window from now on; check the host clock if it should be in the future",
+    ),
+    reason(
+        "NotWindowOwner",
+        "PhalaServiceManager",
+        "PhalaSM: Not the window owner",
+        "the window was scheduled by another operator; check `maintenance list`",
+    ),
+    reason(
+        "MaintenanceWindowCancelled",
+        "PhalaServiceManager",
+        "PhalaSM: Maintenance window already cancelled",
+        "the window was cancelled before; nothing to do",
+    ),
+    reason(
+        "EmptyEvidenceRoot",
+        "PhalaServiceManager",
+        "PhalaSM: Empty evidence root",
+        "the evidence window has no records; check the evidence log",
+    ),
+    reason(
+        "EvidenceWindowAnchored",
+        "PhalaServiceManager",
+        "PhalaSM: Window already anchored",
+        "the window's root is on chain already; nothing to anchor",
+    ),
+    reason(
+        "UnknownTeePlatform",
+        "PhalaServiceManager",
+        "PhalaSM: Unknown TEE platform",
+        "the platform must be 1 (TDX) or 2 (SGX)",
+    ),
+    reason(
+        "SignerIsOperator",
+        "PhalaServiceManager",
+        "PhalaSM: Signer is the operator",
+        "the delegated maintenance signer must be a different key than the operator's",
+    ),
+    reason(
+        "SignerInUse",
+        "PhalaServiceManager",
+        "PhalaSM: Signer already in use",
+        "another operator delegated to this signer; pick another key",
+    ),
+    reason(
+        "ExitAlreadyRequested",
+        "PhalaServiceManager",
+        "PhalaSM: Exit already requested",
+        "an exit is in progress; check `exit --status`",
+    ),
+    // PhalaServiceManager, owner and oracle calls.
+    reason(
+        "WorkloadAlreadyAssigned",
+        "PhalaServiceManager",
+        "PhalaSM: Workload already assigned",
+        "the workload is assigned to this operator already",
+    ),
+    reason(
+        "WorkloadNotAssigned",
+        "PhalaServiceManager",
+        "PhalaSM: Workload not assigned",
+        "the workload is not assigned to this operator; check the drift report",
+    ),
+    reason(
+        "ServiceManagerNotInitialized",
+        "PhalaServiceManager",
+        "PhalaSM: Contract is not initialized",
+        "the service manager is deployed but not initialized; check the configured address",
+    ),
+    reason(
+        "NotSlaOracle",
+        "PhalaServiceManager",
+        "PhalaSM: Caller is not the SLA Oracle",
+        "only the SLA oracle may call this",
+    ),
+    reason(
+        "NotTokenomicManager",
+        "PhalaServiceManager",
+        "PhalaSM: Caller is not the Tokenomic Manager",
+        "only the tokenomic manager may call this",
+    ),
+    reason(
+        "InsufficientPhaBalance",
+        "PhalaServiceManager",
+        "PhalaSM: Insufficient PHA balance",
+        "the service manager holds too little PHA for the rewards; top it up first",
+    ),
+    reason(
+        "RewardTokenNotPha",
+        "PhalaServiceManager",
+        "PhalaSM: Reward token must be PHA",
+        "rewards can only be paid in PHA",
+    ),
+    // OpenZeppelin.
+    reason(
+        "Paused",
+        "Pausable",
+        "Pausable: paused",
+        "the contract is paused by its owner; wait for it to be unpaused",
+    ),
+    reason(
+        "NotOwner",
+        "Ownable",
+        "Ownable: caller is not the owner",
+        "only the contract owner may call this; check which account sent it",
+    ),
+    // EigenLayer.
+    custom(
+        "OperatorNotRegisteredToEigenLayer",
+        "AVSDirectory",
+        "OperatorNotRegisteredToEigenLayer()",
+        "register the operator with the EigenLayer DelegationManager first",
+    ),
+    custom(
+        "OperatorNotRegisteredToAVS",
+        "AVSDirectory",
+        "OperatorNotRegisteredToAVS()",
+        "the operator is not registered with this AVS; check the registration at startup",
+    ),
+    custom(
+        "OperatorAlreadyRegisteredToAVS",
+        "AVSDirectory",
+        "OperatorAlreadyRegisteredToAVS()",
+        "the operator is registered already; nothing to do",
+    ),
+    custom(
+        "SaltSpent",
+        "AVSDirectory",
+        "SaltSpent()",
+        "the registration signature's salt was used; registering again picks a new one",
+    ),
+    custom(
+        "SignatureExpired",
+        "AVSDirectory",
+        "SignatureExpired()",
+        "the registration signature expired before it landed; check the host clock and retry",
+    ),
+    // Compiler panics.
+    panic(
+        "AssertionFailed",
+        0x01,
+        "an internal invariant of the contract failed; report it with the diagnostics bundle",
+    ),
+    panic(
+        "ArithmeticOverflow",
+        0x11,
+        "an amount over- or underflowed; check the values sent",
+    ),
+    panic(
+        "DivisionByZero",
+        0x12,
+        "the contract divided by zero; check its configuration",
+    ),
+    panic(
+        "InvalidEnumValue",
+        0x21,
+        "a value does not fit its enum; check the encoder version",
+    ),
+    panic(
+        "ArrayOutOfBounds",
+        0x32,
+        "an index is out of bounds; check the ids sent",
+    ),
+];
+
+/// Revert data, decoded against the [`CATALOG`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DecodedRevert {
+    /// The catalog name; `None` for errors the catalog does not know.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract: Option<String>,
+    /// The first four bytes of the revert data, `0x`-prefixed.
+    pub selector: String,
+    /// The reason of `Error(string)`, the code of `Panic(uint256)`, or the 32-byte words of a
+    /// custom error.
+    pub args: Vec<String>,
+    pub hint: String,
+}
+
+impl DecodedRevert {
+    fn known(entry: &CatalogEntry, selector: &[u8], args: Vec<String>) -> Self {
+        Self {
+            name: Some(entry.name.to_string()),
+            contract: Some(entry.contract.to_string()),
+            selector: hex::encode_prefixed(selector),
+            args,
+            hint: entry.hint.to_string(),
+        }
+    }
+
+    fn unknown(selector: &[u8], args: Vec<String>) -> Self {
+        Self {
+            name: None,
+            contract: None,
+            selector: hex::encode_prefixed(selector),
+            args,
+            hint: UNKNOWN_HINT.to_string(),
+        }
+    }
+
+    pub fn is_known(&self) -> bool {
+        self.name.is_some()
+    }
+}
+
+impl fmt::Display for DecodedRevert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args = self.args.join(", ");
+        match (&self.name, &self.contract) {
+            (Some(name), Some(contract)) => {
+                write!(f, "{contract}.{name}({args}): {}", self.hint)
+            }
+            _ => write!(
+                f,
+                "unknown contract error {}({args}): {}",
+                self.selector, self.hint
+            ),
+        }
+    }
+}
+
+/// Decodes `data` against the [`CATALOG`]; `None` if it is too short to carry a selector.
+pub fn decode_revert(data: &[u8]) -> Option<DecodedRevert> {
+    let selector = data.get(..4)?;
+    let (entry, args) = if selector == Revert::SELECTOR {
+        match Revert::abi_decode(data, true) {
+            Ok(revert) => (
+                lookup(|m| matches!(m, Matcher::Reason(reason) if reason == revert.reason)),
+                vec![format!("{:?}", revert.reason)],
+            ),
+            Err(_) => (None, words(&data[4..])),
+        }
+    } else if selector == Panic::SELECTOR {
+        match Panic::abi_decode(data, true) {
+            Ok(panic) => {
+                let code = u8::try_from(panic.code).ok();
+                (
+                    lookup(|m| code.is_some_and(|code| m == Matcher::Panic(code))),
+                    vec![format!("{:#x}", panic.code)],
+                )
+            }
+            Err(_) => (None, words(&data[4..])),
+        }
+    } else {
+        (
+            lookup(|m| match m {
+                Matcher::Signature(signature) => keccak256(signature)[..4] == *selector,
+                _ => false,
+            }),
+            words(&data[4..]),
+        )
+    };
+    Some(match entry {
+        Some(entry) => DecodedRevert::known(entry, selector, args),
+        None => DecodedRevert::unknown(selector, args),
+    })
+}
+
+fn lookup(matches: impl Fn(Matcher) -> bool) -> Option<&'static CatalogEntry> {
+    CATALOG.iter().find(|entry| matches(entry.matcher))
+}
+
+fn words(data: &[u8]) -> Vec<String> {
+    data.chunks(32).map(hex::encode_prefixed).collect()
+}
+
+/// A decoded revert, as kept for diagnostics.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContractErrorOccurrence {
+    /// The call that reverted, e.g. `eth_estimateGas of respondToSlaChallenge`.
+    pub call: String,
+    pub error: DecodedRevert,
+    pub unix_ms: u64,
+}
+
+static RECENT: Mutex<VecDeque<ContractErrorOccurrence>> = Mutex::new(VecDeque::new());
+
+/// The latest decoded reverts, oldest first.
+pub fn recent() -> Vec<ContractErrorOccurrence> {
+    let recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    recent.iter().cloned().collect()
+}
+
+/// Decodes the revert data `call` failed with, and records it.
+pub fn explain(call: &str, data: &[u8]) -> Option<DecodedRevert> {
+    let decoded = decode_revert(data)?;
+    METRICS.inc_counter(
+        CONTRACT_ERRORS_METRIC,
+        &[("error", decoded.name.as_deref().unwrap_or("unknown"))],
+        1,
+    );
+    if !decoded.is_known() {
+        warn!(
+            selector = %decoded.selector,
+            "{call} reverted with {decoded}"
+        );
+    }
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    if recent.len() == RECENT_CAPACITY {
+        recent.pop_front();
+    }
+    recent.push_back(ContractErrorOccurrence {
+        call: call.to_string(),
+        error: decoded.clone(),
+        unix_ms: now_unix_ms(),
+    });
+    Some(decoded)
+}
+
+/// The contract error an RPC call reverted with, if the node returned revert data.
+pub fn rpc_revert(call: &str, error: &RpcError<TransportErrorKind>) -> Option<DecodedRevert> {
+    let data = error.as_error_resp()?.as_revert_data()?;
+    explain(call, &data)
+}
+
+/// The contract error a contract call reverted with, if the node returned revert data.
+pub fn contract_revert(
+    call: &str,
+    error: &blueprint_sdk::alloy::contract::Error,
+) -> Option<DecodedRevert> {
+    let data = error.as_revert_data()?;
+    explain(call, &data)
+}
+
+/// `"{call} failed: {error}"`, followed by the decoded contract error if there is one.
+pub fn describe_rpc_error(call: &str, error: &RpcError<TransportErrorKind>) -> String {
+    describe(call, error, rpc_revert(call, error).as_ref())
+}
+
+/// `"{call} failed: {error}"`, followed by the decoded contract error if there is one.
+pub fn describe_contract_error(
+    call: &str,
+    error: &blueprint_sdk::alloy::contract::Error,
+) -> String {
+    describe(call, error, contract_revert(call, error).as_ref())
+}
+
+/// `"{call} failed: {error}"`, followed by `revert` if there is one.
+pub fn describe(call: &str, error: &dyn fmt::Display, revert: Option<&DecodedRevert>) -> String {
+    match revert {
+        Some(revert) => format!("{call} failed: {error}; reverted with {revert}"),
+        None => format!("{call} failed: {error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blueprint_sdk::alloy::primitives::U256;
+
+    fn revert_data(reason: &str) -> Vec<u8> {
+        Revert {
+            reason: reason.to_string(),
+        }
+        .abi_encode()
+    }
+
+    fn panic_data(code: u8) -> Vec<u8> {
+        Panic {
+            code: U256::from(code),
+        }
+        .abi_encode()
+    }
+
+    #[test]
+    fn every_catalog_entry_decodes_to_its_name() {
+        for entry in CATALOG {
+            let data = match entry.matcher {
+                Matcher::Reason(reason) => revert_data(reason),
+                Matcher::Panic(code) => panic_data(code),
+                Matcher::Signature(signature) => {
+                    let mut data = keccak256(signature)[..4].to_vec();
+                    data.extend([0u8; 32]);
+                    data
+                }
+            };
+            let decoded = decode_revert(&data).unwrap();
+            assert_eq!(decoded.name.as_deref(), Some(entry.name), "{entry:?}");
+            assert_eq!(decoded.hint, entry.hint);
+            assert!(decoded.to_string().contains(entry.hint));
+        }
+
+        let names: std::collections::BTreeSet<_> = CATALOG.iter().map(|e| e.name).collect();
+        assert_eq!(names.len(), CATALOG.len(), "catalog names are unique");
+    }
+
+    #[test]
+    fn decoded_errors_carry_their_arguments() {
+        let decoded = decode_revert(&revert_data("PhalaSLA: Response window closed")).unwrap();
+        assert_eq!(decoded.selector, "0x08c379a0");
+        assert_eq!(decoded.args, ["\"PhalaSLA: Response window closed\""]);
+        assert!(
+            decoded
+                .to_string()
+                .starts_with("PhalaSlaOracle.ChallengeWindowClosed(\"PhalaSLA: Response")
+        );
+
+        let decoded = decode_revert(&panic_data(0x11)).unwrap();
+        assert_eq!(decoded.selector, "0x4e487b71");
+        assert_eq!(decoded.args, ["0x11"]);
+    }
+
+    #[test]
+    fn unknown_errors_point_at_the_catalog() {
+        let mut data = vec![0xde, 0xad, 0xbe, 0xef];
+        data.extend([0u8; 31]);
+        data.push(7);
+        let decoded = decode_revert(&data).unwrap();
+        assert!(!decoded.is_known());
+        assert_eq!(decoded.selector, "0xdeadbeef");
+        assert_eq!(decoded.args.len(), 1);
+        let shown = decoded.to_string();
+        assert!(shown.starts_with("unknown contract error 0xdeadbeef("));
+        assert!(shown.contains("error_catalog::CATALOG"));
+
+        // A reason nobody catalogued keeps its text.
+        let decoded = decode_revert(&revert_data("PhalaSLA: Something new")).unwrap();
+        assert!(!decoded.is_known());
+        assert!(decoded.to_string().contains("PhalaSLA: Something new"));
+
+        // Unknown panic codes and truncated data degrade the same way.
+        assert!(!decode_revert(&panic_data(0x99)).unwrap().is_known());
+        assert!(!decode_revert(&revert_data("x")[..10]).unwrap().is_known());
+        assert_eq!(decode_revert(&[0x08, 0xc3]), None);
+    }
+
+    #[test]
+    fn explained_reverts_are_kept_for_diagnostics() {
+        let decoded = explain("eth_call of test", &panic_data(0x32)).unwrap();
+        assert_eq!(decoded.name.as_deref(), Some("ArrayOutOfBounds"));
+        assert!(
+            recent()
+                .iter()
+                .any(|o| o.call == "eth_call of test" && o.error == decoded)
+        );
+        assert!(
+            METRICS
+                .counter(CONTRACT_ERRORS_METRIC, &[("error", "ArrayOutOfBounds")])
+                .is_some()
+        );
+    }
+}
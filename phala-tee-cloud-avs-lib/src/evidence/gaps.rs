@@ -0,0 +1,91 @@
+//! Gaps in the evidence workloads push, as marked by [`crate::freshness`].
+//!
+//! A gap runs from a workload's last push until the push that ended it, and is open until then.
+//! Gaps are kept beside the evidence log, not in it: they are the operator's own observation and
+//! are not anchored. Range evidence carries the gaps overlapping its span, so a proof built over
+//! a range with missing workload evidence is known to be partial before it is submitted.
+
+use super::EvidenceLog;
+use crate::error::PhalaAvsError;
+use crate::state::StateStoreExt;
+use blueprint_sdk::alloy::primitives::B256;
+use serde::{Deserialize, Serialize};
+
+/// Gaps by workload id and start time.
+pub const GAP_NAMESPACE: &str = "evidence_gaps";
+
+/// A span in which a workload pushed no evidence although its cadence called for it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvidenceGap {
+    pub workload_id: B256,
+    /// The workload's last push before the gap, or when it was last expected to start pushing.
+    pub from_ms: u64,
+    /// The push that ended the gap; `None` while it is still open.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_ms: Option<u64>,
+    /// The push interval the gap was measured against.
+    pub cadence_ms: u64,
+    pub detected_unix_ms: u64,
+}
+
+impl EvidenceGap {
+    /// Whether the gap overlaps `[from_ms, to_ms)`. An open gap extends indefinitely.
+    pub fn overlaps(&self, from_ms: u64, to_ms: u64) -> bool {
+        self.from_ms < to_ms && self.to_ms.is_none_or(|end| end > from_ms)
+    }
+
+    fn key(&self) -> Vec<u8> {
+        let mut key = self.workload_id.to_vec();
+        key.extend_from_slice(&self.from_ms.to_be_bytes());
+        key
+    }
+}
+
+impl EvidenceLog {
+    /// Records `gap`, replacing the record of the same gap, e.g. once it closes.
+    pub fn mark_gap(&self, gap: &EvidenceGap) -> Result<(), PhalaAvsError> {
+        self.store.put_json(GAP_NAMESPACE, &gap.key(), gap)
+    }
+
+    /// Gaps of every workload overlapping `[from_ms, to_ms)`, by workload then start.
+    pub fn gaps(&self, from_ms: u64, to_ms: u64) -> Result<Vec<EvidenceGap>, PhalaAvsError> {
+        let mut gaps = Vec::new();
+        for (_, raw) in self.store.scan(GAP_NAMESPACE)? {
+            let gap: EvidenceGap = serde_json::from_slice(&raw)
+                .map_err(|e| PhalaAvsError::StorageError(format!("Corrupt evidence gap: {e}")))?;
+            if gap.overlaps(from_ms, to_ms) {
+                gaps.push(gap);
+            }
+        }
+        Ok(gaps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{MemoryStateStore, StateStore};
+    use std::sync::Arc;
+
+    #[test]
+    fn closing_a_gap_replaces_its_record() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let log = EvidenceLog::new(store);
+        let mut gap = EvidenceGap {
+            workload_id: B256::repeat_byte(0x31),
+            from_ms: 10_000,
+            to_ms: None,
+            cadence_ms: 1_000,
+            detected_unix_ms: 14_000,
+        };
+        log.mark_gap(&gap).unwrap();
+        assert_eq!(log.gaps(0, 5_000).unwrap(), []);
+        assert_eq!(log.gaps(50_000, 60_000).unwrap(), [gap.clone()]);
+
+        gap.to_ms = Some(20_000);
+        log.mark_gap(&gap).unwrap();
+        assert_eq!(log.gaps(0, u64::MAX).unwrap(), [gap.clone()]);
+        assert_eq!(log.gaps(20_000, 60_000).unwrap(), []);
+        assert_eq!(log.gaps(19_999, 60_000).unwrap(), [gap]);
+    }
+}
@@ -0,0 +1,108 @@
+//! Keccak Merkle trees over evidence leaves.
+//!
+//! Pairs are hashed in sorted order and an unpaired node is carried up unchanged, so proofs are
+//! plain sibling lists that verify like OpenZeppelin's `MerkleProof.verify`.
+
+use blueprint_sdk::alloy::primitives::{B256, keccak256};
+
+/// Hashes two nodes in sorted order.
+pub fn hash_pair(a: B256, b: B256) -> B256 {
+    let (low, high) = if a <= b { (a, b) } else { (b, a) };
+    let mut buf = [0u8; 64];
+    buf[..32].copy_from_slice(low.as_slice());
+    buf[32..].copy_from_slice(high.as_slice());
+    keccak256(buf)
+}
+
+/// Whether `proof` links `leaf` to `root`.
+pub fn verify(root: B256, leaf: B256, proof: &[B256]) -> bool {
+    proof
+        .iter()
+        .fold(leaf, |node, sibling| hash_pair(node, *sibling))
+        == root
+}
+
+/// A tree built bottom-up from its leaves, keeping every layer for proof generation.
+#[derive(Clone, Debug)]
+pub struct MerkleTree {
+    layers: Vec<Vec<B256>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<B256>) -> Self {
+        let mut layers = vec![leaves];
+        while layers.last().is_some_and(|layer| layer.len() > 1) {
+            let next = layers
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => hash_pair(*a, *b),
+                    [a] => *a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            layers.push(next);
+        }
+        Self { layers }
+    }
+
+    /// The root, or zero for a tree without leaves.
+    pub fn root(&self) -> B256 {
+        self.layers
+            .last()
+            .and_then(|layer| layer.first())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sibling path from the leaf at `index` to the root.
+    pub fn proof(&self, index: usize) -> Option<Vec<B256>> {
+        if index >= self.len() {
+            return None;
+        }
+        let mut proof = Vec::new();
+        let mut index = index;
+        for layer in &self.layers[..self.layers.len() - 1] {
+            if let Some(sibling) = layer.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+        Some(proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blueprint_sdk::alloy::primitives::U256;
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        for count in 1..=9u64 {
+            let leaves: Vec<B256> = (0..count)
+                .map(|i| keccak256(B256::from(U256::from(i))))
+                .collect();
+            let tree = MerkleTree::new(leaves.clone());
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert!(
+                    verify(tree.root(), *leaf, &proof),
+                    "{count} leaves, #{index}"
+                );
+                assert!(!verify(tree.root(), keccak256(leaf), &proof));
+            }
+            assert!(tree.proof(leaves.len()).is_none());
+        }
+        assert_eq!(MerkleTree::new(Vec::new()).root(), B256::ZERO);
+    }
+}
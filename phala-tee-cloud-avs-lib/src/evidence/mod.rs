@@ -0,0 +1,637 @@
+//! Local evidence log and its on-chain anchoring.
+//!
+//! Heartbeats, challenge responses and evidence pushed by workloads are appended to per-kind
+//! namespaces, keyed by the time they were recorded. Once an anchoring window has elapsed, a
+//! Merkle tree over its records is built and the root committed through the service manager's
+//! `anchorEvidenceRoot`. The tree's leaves are kept locally so inclusion proofs can be produced
+//! for disputes long after the window closed.
+//!
+//! Windows are `EVIDENCE_ANCHOR_WINDOW_SECS` long and counted from the unix epoch, so window `n`
+//! covers `[n * len, (n + 1) * len)`.
+
+pub mod gaps;
+pub mod merkle;
+pub mod range;
+pub mod summary;
+
+use crate::config::{env_flag, env_or};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::lanes::{SignerLanes, TxClass};
+use crate::metrics::METRICS;
+use crate::registration::RegistrationGate;
+use crate::sender::{TxCall, TxSender};
+use crate::state::{StateStore, StateStoreExt};
+use crate::{IPhalaServiceManager, SERVICE_MANAGER_ADDRESS};
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, keccak256};
+use blueprint_sdk::alloy::sol_types::SolCall;
+use blueprint_sdk::evm::util::get_provider_http;
+use merkle::MerkleTree;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Heartbeat results, one record per heartbeat run.
+pub const HEARTBEAT_EVIDENCE: &str = "evidence_heartbeats";
+/// Challenges released for response.
+pub const RESPONSE_EVIDENCE: &str = "evidence_responses";
+/// Evidence pushed by workloads (see [`crate::ingestion`]).
+pub const WORKLOAD_EVIDENCE: &str = "evidence_workloads";
+/// Every namespace covered by an anchored root, in leaf order.
+pub const EVIDENCE_NAMESPACES: &[&str] =
+    &[HEARTBEAT_EVIDENCE, RESPONSE_EVIDENCE, WORKLOAD_EVIDENCE];
+/// Anchored windows by window id, plus the anchoring cursor.
+pub const ANCHOR_NAMESPACE: &str = "evidence_anchors";
+/// Gauge: id of the most recently anchored window.
+pub const ANCHORED_WINDOW_METRIC: &str = "phala_avs_evidence_anchored_window";
+
+const CURSOR_KEY: &[u8] = b"cursor";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeartbeatEvidence {
+    pub unix_ms: u64,
+    /// `None` when the liveness check itself failed.
+    pub live: Option<bool>,
+    pub in_maintenance: bool,
+    /// The TEE's health score, from 0 to 100 (see [`crate::tee::health`]). `None` in
+    /// heartbeats recorded before it was scored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_score: Option<u8>,
+    /// Whether the score reached `TEE_HEALTH_THRESHOLD`, so the node could attest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub healthy: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResponseEvidence {
+    pub unix_ms: u64,
+    pub challenge_id: String,
+    pub issued_block: u64,
+    pub deadline_block: u64,
+    pub release_reason: String,
+    /// Where verifiers retrieve the response's full artifacts, once archived.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifacts_url: Option<String>,
+    /// Blocks between issuance and first detection, for post-mortems.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detection_delay_blocks: Option<u64>,
+    /// The maintenance window the challenge fell in, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance_window_id: Option<String>,
+    /// keccak256 of the SLA proof backing the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sla_proof_hash: Option<B256>,
+}
+
+/// Append-only evidence records in the operator's state store.
+///
+/// Keys are the record time (big-endian milliseconds) followed by an optional discriminator, so
+/// a scan returns records in time order.
+#[derive(Clone, Debug)]
+pub struct EvidenceLog {
+    store: Arc<dyn StateStore>,
+}
+
+impl EvidenceLog {
+    pub fn new(store: Arc<dyn StateStore>) -> Self {
+        Self { store }
+    }
+
+    /// Appends a record; `id` distinguishes records of the same millisecond. A record behind the
+    /// newest [`summary`] is noted so the summaries it affects are rebuilt.
+    pub fn record<T: Serialize>(
+        &self,
+        namespace: &str,
+        unix_ms: u64,
+        id: &[u8],
+        record: &T,
+    ) -> Result<(), PhalaAvsError> {
+        let mut key = unix_ms.to_be_bytes().to_vec();
+        key.extend_from_slice(id);
+        self.store.put_json(namespace, &key, record)?;
+        self.note_written(namespace, unix_ms)
+    }
+
+    /// Leaves of every namespace's records in `[from_ms, to_ms)`, in tree order: by namespace,
+    /// then by key.
+    pub fn leaves(&self, from_ms: u64, to_ms: u64) -> Result<Vec<EvidenceLeaf>, PhalaAvsError> {
+        let mut leaves = Vec::new();
+        for namespace in EVIDENCE_NAMESPACES {
+            for (key, value) in self.records(namespace, from_ms, to_ms)? {
+                leaves.push(EvidenceLeaf {
+                    namespace: namespace.to_string(),
+                    leaf: leaf_hash(namespace, &key, &value),
+                    key: key.into(),
+                });
+            }
+        }
+        Ok(leaves)
+    }
+
+    /// Records of `namespace` with a time in `[from_ms, to_ms)`.
+    pub fn records(
+        &self,
+        namespace: &str,
+        from_ms: u64,
+        to_ms: u64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        Ok(self
+            .store
+            .scan_range(namespace, &from_ms.to_be_bytes(), &to_ms.to_be_bytes())?
+            .into_iter()
+            .filter(|(key, _)| {
+                record_time(key).is_some_and(|unix_ms| (from_ms..to_ms).contains(&unix_ms))
+            })
+            .collect())
+    }
+}
+
+/// The time a record key was written at.
+pub fn record_time(key: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(key.get(..8)?.try_into().ok()?))
+}
+
+/// Leaf hash of a record: `keccak256(keccak256(namespace) ++ keccak256(key) ++ keccak256(value))`.
+pub fn leaf_hash(namespace: &str, key: &[u8], value: &[u8]) -> B256 {
+    let mut buf = Vec::with_capacity(96);
+    buf.extend_from_slice(keccak256(namespace.as_bytes()).as_slice());
+    buf.extend_from_slice(keccak256(key).as_slice());
+    buf.extend_from_slice(keccak256(value).as_slice());
+    keccak256(buf)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvidenceLeaf {
+    pub namespace: String,
+    pub key: Bytes,
+    pub leaf: B256,
+}
+
+/// A window's tree, as anchored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnchoredWindow {
+    pub window_id: u64,
+    pub from_unix: u64,
+    pub to_unix: u64,
+    pub root: B256,
+    /// Leaves in tree order; enough to rebuild the tree and any proof.
+    pub leaves: Vec<EvidenceLeaf>,
+    /// The root is on-chain; windows without evidence are never submitted.
+    pub anchored: bool,
+    /// `None` if the anchoring receipt was lost.
+    pub transaction_hash: Option<B256>,
+}
+
+impl AnchoredWindow {
+    fn tree(&self) -> MerkleTree {
+        MerkleTree::new(self.leaves.iter().map(|l| l.leaf).collect())
+    }
+}
+
+/// Proof that a record was part of an anchored window.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub window_id: u64,
+    pub root: B256,
+    pub namespace: String,
+    pub key: Bytes,
+    /// The record as currently stored.
+    pub record: Bytes,
+    pub proof: Vec<B256>,
+}
+
+impl InclusionProof {
+    /// Whether the record hashes to a leaf under `root`, e.g. the root read back from chain.
+    pub fn verify(&self, root: B256) -> bool {
+        let leaf = leaf_hash(&self.namespace, &self.key, &self.record);
+        merkle::verify(root, leaf, &self.proof)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AnchorConfig {
+    pub enabled: bool,
+    pub window_secs: u64,
+    /// How often elapsed windows are looked for.
+    pub check_secs: u64,
+}
+
+impl AnchorConfig {
+    /// Reads `EVIDENCE_ANCHOR_ENABLED` (off by default, anchoring costs gas),
+    /// `EVIDENCE_ANCHOR_WINDOW_SECS` and `EVIDENCE_ANCHOR_CHECK_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let config = Self {
+            enabled: env_flag("EVIDENCE_ANCHOR_ENABLED", false)?,
+            window_secs: env_or("EVIDENCE_ANCHOR_WINDOW_SECS", 3600)?,
+            check_secs: env_or("EVIDENCE_ANCHOR_CHECK_SECS", 300)?,
+        };
+        if config.window_secs == 0 {
+            return Err(PhalaAvsError::ConfigError(
+                "EVIDENCE_ANCHOR_WINDOW_SECS must be positive".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    pub fn window_of(&self, unix: u64) -> u64 {
+        unix / self.window_secs
+    }
+
+    /// `[from, to)` of a window, in unix seconds.
+    pub fn bounds(&self, window_id: u64) -> (u64, u64) {
+        let from = window_id * self.window_secs;
+        (from, from + self.window_secs)
+    }
+}
+
+/// On-chain storage of evidence roots.
+pub trait AnchorRegistry: Send + Sync {
+    /// Anchors `root` for `window_id`, returning the transaction hash.
+    fn anchor(&self, root: B256, window_id: u64) -> BoxFuture<'_, Result<B256, PhalaAvsError>>;
+
+    /// The root `operator` anchored for `window_id`, zero if none.
+    fn anchored_root(
+        &self,
+        operator: Address,
+        window_id: u64,
+    ) -> BoxFuture<'_, Result<B256, PhalaAvsError>>;
+}
+
+/// [`AnchorRegistry`] backed by the `PhalaServiceManager` contract. Anchors are deferrable, so
+/// they are sent from the maintenance account when there is one.
+#[derive(Clone)]
+pub struct ServiceManagerAnchors {
+    service_manager: Address,
+    sender: Arc<TxSender>,
+    rpc_url: String,
+}
+
+impl ServiceManagerAnchors {
+    pub fn new(service_manager: Address, sender: Arc<TxSender>, rpc_url: String) -> Self {
+        Self {
+            service_manager,
+            sender,
+            rpc_url,
+        }
+    }
+
+    /// Uses `SERVICE_MANAGER_ADDRESS`.
+    pub fn from_env(sender: Arc<TxSender>, rpc_url: String) -> Self {
+        Self::new(*SERVICE_MANAGER_ADDRESS, sender, rpc_url)
+    }
+}
+
+fn evm_err(e: impl fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::EvmError(e.to_string())
+}
+
+impl AnchorRegistry for ServiceManagerAnchors {
+    fn anchor(&self, root: B256, window_id: u64) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        Box::pin(async move {
+            let call = IPhalaServiceManager::anchorEvidenceRootCall {
+                root,
+                windowId: window_id,
+            };
+            let call = TxCall::new(
+                "anchorEvidenceRoot",
+                self.service_manager,
+                call.abi_encode(),
+            );
+            self.sender
+                .send(TxClass::Deferrable, call)
+                .await?
+                .into_success("anchorEvidenceRoot")
+        })
+    }
+
+    fn anchored_root(
+        &self,
+        operator: Address,
+        window_id: u64,
+    ) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            let contract = IPhalaServiceManager::new(self.service_manager, provider);
+            Ok(contract
+                .evidenceRoots(operator, window_id)
+                .call()
+                .await
+                .map_err(evm_err)?
+                ._0)
+        })
+    }
+}
+
+/// Builds, anchors and proves evidence windows.
+pub struct EvidenceAnchorer {
+    config: AnchorConfig,
+    operator: Address,
+    log: EvidenceLog,
+    store: Arc<dyn StateStore>,
+    registry: Arc<dyn AnchorRegistry>,
+}
+
+impl EvidenceAnchorer {
+    pub fn new(
+        config: AnchorConfig,
+        operator: Address,
+        store: Arc<dyn StateStore>,
+        registry: Arc<dyn AnchorRegistry>,
+    ) -> Self {
+        Self {
+            config,
+            operator,
+            log: EvidenceLog::new(Arc::clone(&store)),
+            store,
+            registry,
+        }
+    }
+
+    pub fn config(&self) -> &AnchorConfig {
+        &self.config
+    }
+
+    /// The anchored window `window_id`, if it was anchored.
+    pub fn window(&self, window_id: u64) -> Result<Option<AnchoredWindow>, PhalaAvsError> {
+        self.store
+            .get_json(ANCHOR_NAMESPACE, &window_id.to_be_bytes())
+    }
+
+    /// Builds the tree of a window from the records currently stored, without anchoring it.
+    pub fn build(&self, window_id: u64) -> Result<AnchoredWindow, PhalaAvsError> {
+        let (from_unix, to_unix) = self.config.bounds(window_id);
+        let leaves = self.log.leaves(from_unix * 1000, to_unix * 1000)?;
+        let root = MerkleTree::new(leaves.iter().map(|l| l.leaf).collect()).root();
+        Ok(AnchoredWindow {
+            window_id,
+            from_unix,
+            to_unix,
+            root,
+            leaves,
+            anchored: false,
+            transaction_hash: None,
+        })
+    }
+
+    /// Anchors an elapsed window, or returns it if it already was.
+    ///
+    /// The tree is stored before the root is submitted; if a previous submission landed but its
+    /// receipt was lost, the on-chain root is picked up instead of submitting again.
+    pub async fn anchor(&self, window_id: u64) -> Result<AnchoredWindow, PhalaAvsError> {
+        if let Some(window) = self.window(window_id)? {
+            if window.anchored || window.leaves.is_empty() {
+                return Ok(window);
+            }
+        }
+        let mut window = self.build(window_id)?;
+        let key = window_id.to_be_bytes();
+        self.store.put_json(ANCHOR_NAMESPACE, &key, &window)?;
+        if window.leaves.is_empty() {
+            return Ok(window);
+        }
+        let on_chain = self
+            .registry
+            .anchored_root(self.operator, window_id)
+            .await?;
+        if on_chain != B256::ZERO && on_chain != window.root {
+            return Err(PhalaAvsError::EvmError(format!(
+                "Window {window_id} is anchored with root {on_chain}, local root is {}",
+                window.root
+            )));
+        }
+        if on_chain == B256::ZERO {
+            window.transaction_hash = Some(self.registry.anchor(window.root, window_id).await?);
+        }
+        window.anchored = true;
+        self.store.put_json(ANCHOR_NAMESPACE, &key, &window)?;
+        METRICS.set_gauge(ANCHORED_WINDOW_METRIC, &[], window_id as f64);
+        info!(
+            "Anchored evidence window {window_id} ({} records, root {})",
+            window.leaves.len(),
+            window.root
+        );
+        Ok(window)
+    }
+
+    /// Anchors every window elapsed since the last anchored one, returning what was anchored.
+    ///
+    /// On the first run only the most recent elapsed window is anchored.
+    pub async fn anchor_elapsed(
+        &self,
+        now_unix: u64,
+    ) -> Result<Vec<AnchoredWindow>, PhalaAvsError> {
+        let Some(last) = self.config.window_of(now_unix).checked_sub(1) else {
+            return Ok(Vec::new());
+        };
+        let cursor: Option<u64> = self.store.get_json(ANCHOR_NAMESPACE, CURSOR_KEY)?;
+        let first = cursor.map_or(last, |c| c + 1);
+        let mut anchored = Vec::new();
+        for window_id in first..=last {
+            anchored.push(self.anchor(window_id).await?);
+            self.store
+                .put_json(ANCHOR_NAMESPACE, CURSOR_KEY, &window_id)?;
+        }
+        Ok(anchored)
+    }
+
+    /// Inclusion proof for a stored record, once its window has been anchored.
+    pub fn prove(&self, namespace: &str, key: &[u8]) -> Result<InclusionProof, PhalaAvsError> {
+        let unix_ms = record_time(key).ok_or_else(|| {
+            PhalaAvsError::ValidationError("Evidence key has no timestamp".to_string())
+        })?;
+        let window_id = self.config.window_of(unix_ms / 1000);
+        let window = self
+            .window(window_id)?
+            .filter(|w| w.anchored)
+            .ok_or_else(|| {
+                PhalaAvsError::ValidationError(format!("Window {window_id} is not anchored"))
+            })?;
+        let index = window
+            .leaves
+            .iter()
+            .position(|l| l.namespace == namespace && l.key.as_ref() == key)
+            .ok_or_else(|| {
+                PhalaAvsError::ValidationError(format!(
+                    "Record is not part of anchored window {window_id}"
+                ))
+            })?;
+        let record = self.store.get(namespace, key)?.ok_or_else(|| {
+            PhalaAvsError::StorageError(format!("Evidence record missing from {namespace}"))
+        })?;
+        Ok(InclusionProof {
+            window_id,
+            root: window.root,
+            namespace: namespace.to_string(),
+            key: key.to_vec().into(),
+            record: record.into(),
+            proof: window.tree().proof(index).unwrap_or_default(),
+        })
+    }
+
+    /// Proofs for every evidence record in `[from_unix, to_unix)` whose window is anchored, for
+    /// attaching to a dispute.
+    pub fn proofs_between(
+        &self,
+        from_unix: u64,
+        to_unix: u64,
+    ) -> Result<Vec<InclusionProof>, PhalaAvsError> {
+        let mut proofs = Vec::new();
+        for namespace in EVIDENCE_NAMESPACES {
+            for (key, _) in self
+                .log
+                .records(namespace, from_unix * 1000, to_unix * 1000)?
+            {
+                match self.prove(namespace, &key) {
+                    Ok(proof) => proofs.push(proof),
+                    Err(PhalaAvsError::ValidationError(_)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(proofs)
+    }
+}
+
+/// Anchors elapsed windows every `check_secs`, while submissions are permitted and deferrable
+/// transactions are not held.
+pub fn spawn_anchoring(
+    anchorer: Arc<EvidenceAnchorer>,
+    gate: Arc<RegistrationGate>,
+    lanes: Arc<SignerLanes>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(anchorer.config.check_secs));
+        loop {
+            interval.tick().await;
+            // Skipped windows stay behind the cursor and are anchored once submissions resume.
+            if !gate.permits_submission() || lanes.holds(TxClass::Deferrable) {
+                continue;
+            }
+            if let Err(e) = anchorer.anchor_elapsed(now_unix_ms() / 1000).await {
+                warn!("Failed to anchor evidence: {e}");
+            }
+        }
+    });
+}
+
+pub fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::HeartbeatFixture;
+    use crate::state::MemoryStateStore;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    const OPERATOR: Address = Address::repeat_byte(7);
+
+    #[derive(Default)]
+    struct MockRegistry {
+        roots: Mutex<HashMap<u64, B256>>,
+    }
+
+    impl AnchorRegistry for MockRegistry {
+        fn anchor(&self, root: B256, window_id: u64) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            let previous = self.roots.lock().unwrap().insert(window_id, root);
+            Box::pin(async move {
+                match previous {
+                    Some(_) => Err(PhalaAvsError::EvmError("Window already anchored".into())),
+                    None => Ok(B256::repeat_byte(0xaa)),
+                }
+            })
+        }
+
+        fn anchored_root(
+            &self,
+            _operator: Address,
+            window_id: u64,
+        ) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            let root = self
+                .roots
+                .lock()
+                .unwrap()
+                .get(&window_id)
+                .copied()
+                .unwrap_or_default();
+            Box::pin(async move { Ok(root) })
+        }
+    }
+
+    fn heartbeat(unix_ms: u64) -> HeartbeatEvidence {
+        HeartbeatFixture::new().at(unix_ms).build()
+    }
+
+    #[tokio::test]
+    async fn mid_window_heartbeat_proves_against_the_anchored_root() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let registry = Arc::new(MockRegistry::default());
+        let config = AnchorConfig {
+            enabled: true,
+            window_secs: 3600,
+            check_secs: 60,
+        };
+        let anchorer = EvidenceAnchorer::new(
+            config,
+            OPERATOR,
+            Arc::clone(&store),
+            Arc::clone(&registry) as Arc<dyn AnchorRegistry>,
+        );
+        let log = EvidenceLog::new(Arc::clone(&store));
+
+        // Window 10 covers [36000s, 39600s).
+        for unix_ms in [36_000_000, 37_800_000, 39_599_999, 39_600_000] {
+            log.record(HEARTBEAT_EVIDENCE, unix_ms, &[], &heartbeat(unix_ms))
+                .unwrap();
+        }
+        let response = ResponseEvidence {
+            unix_ms: 37_000_000,
+            challenge_id: "1".to_string(),
+            issued_block: 90,
+            deadline_block: 200,
+            release_reason: "Confirmed".to_string(),
+            artifacts_url: None,
+            detection_delay_blocks: None,
+            maintenance_window_id: None,
+            sla_proof_hash: None,
+        };
+        log.record(RESPONSE_EVIDENCE, response.unix_ms, &[1], &response)
+            .unwrap();
+
+        let anchored = anchorer.anchor_elapsed(39_700).await.unwrap();
+        assert_eq!(anchored.len(), 1);
+        assert_eq!(anchored[0].window_id, 10);
+        assert_eq!(anchored[0].leaves.len(), 4);
+        // Re-running within the same window anchors nothing new.
+        assert!(anchorer.anchor_elapsed(39_800).await.unwrap().is_empty());
+
+        let key = 37_800_000u64.to_be_bytes();
+        let proof = anchorer.prove(HEARTBEAT_EVIDENCE, &key).unwrap();
+        let on_chain = registry.anchored_root(OPERATOR, 10).await.unwrap();
+        assert_eq!(proof.root, on_chain);
+        assert!(proof.verify(on_chain));
+
+        store
+            .put_json(HEARTBEAT_EVIDENCE, &key, &HeartbeatEvidence {
+                live: Some(false),
+                ..heartbeat(37_800_000)
+            })
+            .unwrap();
+        let tampered = anchorer.prove(HEARTBEAT_EVIDENCE, &key).unwrap();
+        assert!(!tampered.verify(on_chain));
+
+        // The record after the window's end belongs to the next, unanchored window.
+        assert!(
+            anchorer
+                .prove(HEARTBEAT_EVIDENCE, &39_600_000u64.to_be_bytes())
+                .is_err()
+        );
+    }
+}
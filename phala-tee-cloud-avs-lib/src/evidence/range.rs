@@ -0,0 +1,381 @@
+//! Evidence over arbitrary block ranges, for challenges whose measurement window is not a whole
+//! anchoring window.
+//!
+//! A range of blocks `[from_block, to_block]` is resolved to the time span
+//! `[timestamp(from_block), timestamp(to_block + 1))` and stitched together from every window it
+//! touches, each clipped to the span. Leaves keep the windows' tree order, so a span equal to a
+//! window yields exactly that window's leaves and root. A heartbeat covers the time until the
+//! next one, at most `heartbeat_period_ms`, and an interval straddling a boundary only counts
+//! with its share inside the span.
+//!
+//! A span reaching into the window that has not closed yet, or past the chain head, is flagged
+//! provisional: its evidence may still grow. Everything is derived from stored records in key
+//! order, so building the same range twice yields the same result.
+//!
+//! Windows the span covers entirely, up to the time it is served, are taken from their
+//! [`super::summary`]; only the windows at its edges are read record by record.
+//!
+//! Workload evidence [`super::gaps`] overlapping the span are carried along, so a proof built
+//! from the range is known to be missing workload evidence.
+
+use super::gaps::EvidenceGap;
+use super::merkle::MerkleTree;
+use super::record_time;
+use super::{AnchorConfig, EvidenceLeaf, EvidenceLog, HEARTBEAT_EVIDENCE, HeartbeatEvidence};
+use crate::error::PhalaAvsError;
+use crate::evm::EvmClient;
+use blueprint_sdk::alloy::primitives::B256;
+use serde::{Deserialize, Serialize};
+
+/// A block range and the time span it covers, in unix milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockSpan {
+    pub from_block: u64,
+    pub to_block: u64,
+    pub from_ms: u64,
+    pub to_ms: u64,
+    /// `to_block + 1` was not mined yet, so the span ends at the time it was served.
+    pub past_head: bool,
+}
+
+/// The evidence served for a [`BlockSpan`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeEvidence {
+    #[serde(flatten)]
+    pub span: BlockSpan,
+    /// Windows the span touches, in order.
+    pub windows: Vec<u64>,
+    pub leaves: Vec<EvidenceLeaf>,
+    /// Merkle root over `leaves`.
+    pub root: B256,
+    /// Time of the span covered by a live heartbeat.
+    pub live_ms: u64,
+    /// Time of the span under maintenance, left out of the uptime.
+    pub maintenance_ms: u64,
+    pub uptime_bps: u32,
+    pub provisional: bool,
+    /// Gaps in workload evidence overlapping the span.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gaps: Vec<EvidenceGap>,
+}
+
+impl RangeEvidence {
+    /// Whether workload evidence is missing from part of the span, so a proof over it is partial.
+    pub fn is_partial(&self) -> bool {
+        !self.gaps.is_empty()
+    }
+}
+
+/// Resolves `[from_block, to_block]` against the chain and collects its evidence.
+pub async fn evidence_for_range(
+    log: &EvidenceLog,
+    windows: &AnchorConfig,
+    evm: &dyn EvmClient,
+    from_block: u64,
+    to_block: u64,
+    heartbeat_period_ms: u64,
+    now_ms: u64,
+) -> Result<RangeEvidence, PhalaAvsError> {
+    if to_block < from_block {
+        return Err(PhalaAvsError::ValidationError(format!(
+            "Block range {from_block}..={to_block} is empty"
+        )));
+    }
+    let from_ms = evm.block_timestamp(from_block).await?.ok_or_else(|| {
+        PhalaAvsError::ValidationError(format!("Block {from_block} is not mined yet"))
+    })? * 1000;
+    let end = evm.block_timestamp(to_block + 1).await?;
+    let span = BlockSpan {
+        from_block,
+        to_block,
+        from_ms,
+        to_ms: end.map_or(now_ms.max(from_ms), |unix| unix * 1000),
+        past_head: end.is_none(),
+    };
+    log.range_evidence(windows, span, heartbeat_period_ms, now_ms)
+}
+
+impl EvidenceLog {
+    /// Collects the evidence of `span` as of `now_ms`.
+    pub fn range_evidence(
+        &self,
+        windows: &AnchorConfig,
+        span: BlockSpan,
+        heartbeat_period_ms: u64,
+        now_ms: u64,
+    ) -> Result<RangeEvidence, PhalaAvsError> {
+        self.invalidate_summaries(windows, heartbeat_period_ms)?;
+        self.collect_range(windows, span, heartbeat_period_ms, now_ms, true)
+    }
+
+    /// [`Self::range_evidence`] read record by record, without summaries.
+    pub fn range_evidence_raw(
+        &self,
+        windows: &AnchorConfig,
+        span: BlockSpan,
+        heartbeat_period_ms: u64,
+        now_ms: u64,
+    ) -> Result<RangeEvidence, PhalaAvsError> {
+        self.collect_range(windows, span, heartbeat_period_ms, now_ms, false)
+    }
+
+    fn collect_range(
+        &self,
+        windows: &AnchorConfig,
+        span: BlockSpan,
+        heartbeat_period_ms: u64,
+        now_ms: u64,
+        summaries: bool,
+    ) -> Result<RangeEvidence, PhalaAvsError> {
+        let (from_ms, to_ms) = (span.from_ms, span.to_ms);
+        // Nothing can have been recorded past `now_ms`.
+        let measured_to = to_ms.min(now_ms).max(from_ms);
+        let mut touched = Vec::new();
+        let mut leaves = Vec::new();
+        let (mut live_ms, mut maintenance_ms) = (0, 0);
+        if to_ms > from_ms {
+            for window_id in
+                windows.window_of(from_ms / 1000)..=windows.window_of((to_ms - 1) / 1000)
+            {
+                let (start, end) = windows.bounds(window_id);
+                let (start_ms, end_ms) = (start * 1000, end * 1000);
+                touched.push(window_id);
+                if summaries && from_ms <= start_ms && end_ms <= measured_to {
+                    let summary = self.window_summary(windows, window_id, heartbeat_period_ms)?;
+                    leaves.extend(summary.leaves);
+                    live_ms += summary.live_ms;
+                    maintenance_ms += summary.maintenance_ms;
+                    continue;
+                }
+                leaves.extend(self.leaves(from_ms.max(start_ms), to_ms.min(end_ms))?);
+                let (measured_from, measured_until) =
+                    (from_ms.max(start_ms), measured_to.min(end_ms));
+                if measured_until > measured_from {
+                    let heartbeats = self.heartbeats(
+                        measured_from.saturating_sub(heartbeat_period_ms),
+                        measured_until,
+                    )?;
+                    let (live, maintenance) = coverage(
+                        &heartbeats,
+                        measured_from,
+                        measured_until,
+                        heartbeat_period_ms,
+                    );
+                    live_ms += live;
+                    maintenance_ms += maintenance;
+                }
+            }
+        }
+        let root = MerkleTree::new(leaves.iter().map(|l| l.leaf).collect()).root();
+
+        let accountable = (measured_to - from_ms).saturating_sub(maintenance_ms);
+        let uptime_bps = match accountable {
+            0 => 10_000,
+            _ => (u128::from(live_ms) * 10_000 / u128::from(accountable)) as u32,
+        };
+
+        let (open_from, _) = windows.bounds(windows.window_of(now_ms / 1000));
+        Ok(RangeEvidence {
+            span,
+            windows: touched,
+            leaves,
+            root,
+            live_ms,
+            maintenance_ms,
+            uptime_bps,
+            provisional: span.past_head || to_ms > open_from * 1000,
+            gaps: self.gaps(from_ms, to_ms)?,
+        })
+    }
+
+    /// Heartbeats recorded in `[from_ms, to_ms)`, with the time they were recorded at.
+    pub(super) fn heartbeats(
+        &self,
+        from_ms: u64,
+        to_ms: u64,
+    ) -> Result<Vec<(u64, HeartbeatEvidence)>, PhalaAvsError> {
+        self.records(HEARTBEAT_EVIDENCE, from_ms, to_ms)?
+            .into_iter()
+            .map(|(key, value)| {
+                let evidence: HeartbeatEvidence = serde_json::from_slice(&value).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Corrupt heartbeat evidence: {e}"))
+                })?;
+                Ok((record_time(&key).unwrap_or(evidence.unix_ms), evidence))
+            })
+            .collect()
+    }
+}
+
+/// Time of `[from_ms, to_ms)` covered by a live heartbeat, and under maintenance.
+///
+/// A heartbeat covers the time until the next one, at most `period_ms`, so `heartbeats` has to
+/// hold every heartbeat in `[from_ms - period_ms, to_ms)`. The sums over adjacent intervals add
+/// up to the sum over their union.
+pub(super) fn coverage(
+    heartbeats: &[(u64, HeartbeatEvidence)],
+    from_ms: u64,
+    to_ms: u64,
+    period_ms: u64,
+) -> (u64, u64) {
+    let (mut live_ms, mut maintenance_ms) = (0, 0);
+    for (i, (at, heartbeat)) in heartbeats.iter().enumerate() {
+        let mut until = at + period_ms;
+        if let Some((next, _)) = heartbeats.get(i + 1) {
+            until = until.min(*next);
+        }
+        let overlap = until.min(to_ms).saturating_sub((*at).max(from_ms));
+        if heartbeat.in_maintenance {
+            maintenance_ms += overlap;
+        } else if heartbeat.live == Some(true) {
+            live_ms += overlap;
+        }
+    }
+    (live_ms, maintenance_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evidence::{AnchorRegistry, EvidenceAnchorer};
+    use crate::evm::BoxFuture;
+    use crate::fixtures::HeartbeatFixture;
+    use crate::state::{MemoryStateStore, StateStore};
+    use blueprint_sdk::alloy::primitives::Address;
+    use std::sync::Arc;
+
+    struct NoRegistry;
+
+    impl AnchorRegistry for NoRegistry {
+        fn anchor(&self, _root: B256, _window: u64) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            unreachable!("ranges are never anchored")
+        }
+
+        fn anchored_root(
+            &self,
+            _operator: Address,
+            _window: u64,
+        ) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            unreachable!("ranges are never anchored")
+        }
+    }
+
+    const CONFIG: AnchorConfig = AnchorConfig {
+        enabled: true,
+        window_secs: 3600,
+        check_secs: 60,
+    };
+    const PERIOD_MS: u64 = 60_000;
+
+    /// Minutely heartbeats through windows 10 to 12, down for ten minutes early in window 11.
+    fn store() -> (Arc<dyn StateStore>, EvidenceLog) {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let log = EvidenceLog::new(Arc::clone(&store));
+        for k in 0..180 {
+            let unix_ms = 36_000_000 + k * PERIOD_MS;
+            let heartbeat = HeartbeatFixture::new()
+                .at(unix_ms)
+                .live(!(60..70).contains(&k))
+                .build();
+            log.record(HEARTBEAT_EVIDENCE, unix_ms, &[], &heartbeat)
+                .unwrap();
+        }
+        (store, log)
+    }
+
+    fn span(from_ms: u64, to_ms: u64) -> BlockSpan {
+        BlockSpan {
+            from_block: 100,
+            to_block: 200,
+            from_ms,
+            to_ms,
+            past_head: false,
+        }
+    }
+
+    #[test]
+    fn ranges_match_whole_windows_and_stitch_partial_ones() {
+        let (store, log) = store();
+        let now = 50_000_000;
+
+        let window = log
+            .range_evidence(&CONFIG, span(36_000_000, 39_600_000), PERIOD_MS, now)
+            .unwrap();
+        let anchorer = EvidenceAnchorer::new(CONFIG, Address::ZERO, store, Arc::new(NoRegistry));
+        let built = anchorer.build(10).unwrap();
+        assert_eq!(window.windows, vec![10]);
+        assert_eq!(window.root, built.root);
+        assert_eq!(
+            serde_json::to_vec(&window.leaves).unwrap(),
+            serde_json::to_vec(&built.leaves).unwrap()
+        );
+        assert_eq!((window.uptime_bps, window.provisional), (10_000, false));
+
+        // Starts 30ms into a heartbeat interval of window 10 and ends inside window 12.
+        let stitched = span(37_800_030, 45_000_000);
+        let range = log
+            .range_evidence(&CONFIG, stitched, PERIOD_MS, now)
+            .unwrap();
+        assert_eq!(range.windows, vec![10, 11, 12]);
+        assert_eq!(range.leaves.len(), 119);
+        assert_eq!(range.live_ms, 7_199_970 - 600_000);
+        assert_eq!(range.uptime_bps, 9_166);
+        assert!(!range.provisional);
+        let again = log
+            .range_evidence(&CONFIG, stitched, PERIOD_MS, now)
+            .unwrap();
+        assert_eq!(
+            serde_json::to_vec(&range).unwrap(),
+            serde_json::to_vec(&again).unwrap()
+        );
+    }
+
+    #[test]
+    fn ranges_into_the_open_window_are_provisional() {
+        let (_, log) = store();
+        // Window 12, [43200s, 46800s), is still open at 46000s.
+        let range = log
+            .range_evidence(&CONFIG, span(43_200_000, 46_800_000), PERIOD_MS, 46_000_000)
+            .unwrap();
+        assert!(range.provisional);
+        assert_eq!(range.windows, vec![12]);
+        assert_eq!(range.live_ms, 2_800_000);
+        assert_eq!(range.uptime_bps, 10_000);
+
+        let past_head = BlockSpan {
+            past_head: true,
+            ..span(36_000_000, 39_600_000)
+        };
+        let range = log
+            .range_evidence(&CONFIG, past_head, PERIOD_MS, 50_000_000)
+            .unwrap();
+        assert!(range.provisional);
+    }
+
+    #[test]
+    fn ranges_carry_the_workload_gaps_they_overlap() {
+        let (_, log) = store();
+        let gap = EvidenceGap {
+            workload_id: B256::repeat_byte(0x77),
+            from_ms: 40_000_000,
+            to_ms: Some(41_000_000),
+            cadence_ms: 60_000,
+            detected_unix_ms: 40_300_000,
+        };
+        log.mark_gap(&gap).unwrap();
+
+        let range = log
+            .range_evidence(&CONFIG, span(39_600_000, 43_200_000), PERIOD_MS, 50_000_000)
+            .unwrap();
+        assert!(range.is_partial());
+        assert_eq!(range.gaps, [gap]);
+        let before = log
+            .range_evidence(&CONFIG, span(36_000_000, 39_600_000), PERIOD_MS, 50_000_000)
+            .unwrap();
+        assert!(!before.is_partial());
+        assert!(
+            !serde_json::to_string(&before)
+                .unwrap()
+                .contains("\"gaps\"")
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! Per-window summaries of the evidence log, so the evidence of a long range is composed from
+//! one stored summary per anchoring window instead of every record in it.
+//!
+//! A window's summary holds its leaves, its heartbeat counts, and the time in it covered by a
+//! live heartbeat or under maintenance. It is built on first use once the window has closed, from
+//! the window's records and the heartbeats up to one heartbeat period before it.
+//!
+//! [`EvidenceLog::record`] notes records written behind the newest summarized window, e.g. a
+//! heartbeat delivered late. Before summaries are read, each noted record drops the summaries it
+//! affects, and those are rebuilt from the raw records on their next use. All summaries are
+//! dropped when the window length or heartbeat period changes.
+//!
+//! A range only adds up per-window sums the raw scan would have added, so the evidence composed
+//! from summaries is identical to the evidence read record by record.
+
+use super::range::coverage;
+use super::{AnchorConfig, EVIDENCE_NAMESPACES, EvidenceLeaf, EvidenceLog, record_time};
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::state::StateStoreExt;
+use serde::{Deserialize, Serialize};
+
+/// Window summaries by window id, plus the parameters they were built with.
+pub const SUMMARY_NAMESPACE: &str = "evidence_summaries";
+/// Times of records written behind the newest summarized window, not yet applied.
+pub const DIRTY_NAMESPACE: &str = "evidence_summaries_dirty";
+/// Counter of summaries read, by outcome: `hit` or `built`.
+pub const SUMMARY_METRIC: &str = "phala_avs_evidence_summaries_total";
+
+const PARAMS_KEY: &[u8] = b"params";
+/// End of the newest summarized window, in unix milliseconds.
+const WATERMARK_KEY: &[u8] = b"watermark";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct SummaryParams {
+    window_secs: u64,
+    heartbeat_period_ms: u64,
+}
+
+/// The evidence of one closed anchoring window.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowSummary {
+    pub window_id: u64,
+    /// Leaves of every record in the window, in tree order.
+    pub leaves: Vec<EvidenceLeaf>,
+    pub heartbeats: u64,
+    /// Heartbeats outside maintenance that found the TEE down or failed to check it.
+    pub failed_heartbeats: u64,
+    pub first_heartbeat_ms: Option<u64>,
+    pub last_heartbeat_ms: Option<u64>,
+    /// Time of the window covered by a live heartbeat.
+    pub live_ms: u64,
+    /// Time of the window under maintenance.
+    pub maintenance_ms: u64,
+}
+
+impl EvidenceLog {
+    /// Notes a record at `unix_ms` if a summary may already cover it.
+    pub(super) fn note_written(&self, namespace: &str, unix_ms: u64) -> Result<(), PhalaAvsError> {
+        if !EVIDENCE_NAMESPACES.contains(&namespace) {
+            return Ok(());
+        }
+        let watermark: Option<u64> = self.store.get_json(SUMMARY_NAMESPACE, WATERMARK_KEY)?;
+        if watermark.is_some_and(|watermark| unix_ms < watermark) {
+            self.store
+                .put(DIRTY_NAMESPACE, &unix_ms.to_be_bytes(), &[])?;
+        }
+        Ok(())
+    }
+
+    /// Drops the summaries noted records affect, or all of them if `windows` or
+    /// `heartbeat_period_ms` changed since they were built.
+    pub(super) fn invalidate_summaries(
+        &self,
+        windows: &AnchorConfig,
+        heartbeat_period_ms: u64,
+    ) -> Result<(), PhalaAvsError> {
+        let params = SummaryParams {
+            window_secs: windows.window_secs,
+            heartbeat_period_ms,
+        };
+        let dirty = self.store.scan(DIRTY_NAMESPACE)?;
+        if self
+            .store
+            .get_json::<SummaryParams>(SUMMARY_NAMESPACE, PARAMS_KEY)?
+            != Some(params)
+        {
+            for (key, _) in self.store.scan(SUMMARY_NAMESPACE)? {
+                self.store.delete(SUMMARY_NAMESPACE, &key)?;
+            }
+            self.store
+                .put_json(SUMMARY_NAMESPACE, PARAMS_KEY, &params)?;
+        } else {
+            // A heartbeat's time counts up to one period after it was recorded.
+            for unix_ms in dirty.iter().filter_map(|(key, _)| record_time(key)) {
+                let last = windows.window_of((unix_ms + heartbeat_period_ms) / 1000);
+                for window_id in windows.window_of(unix_ms / 1000)..=last {
+                    self.store
+                        .delete(SUMMARY_NAMESPACE, &window_id.to_be_bytes())?;
+                }
+            }
+        }
+        for (key, _) in dirty {
+            self.store.delete(DIRTY_NAMESPACE, &key)?;
+        }
+        Ok(())
+    }
+
+    /// The summary of a closed window, built from its records if it is not stored.
+    ///
+    /// Callers invalidate stale summaries first.
+    pub(super) fn window_summary(
+        &self,
+        windows: &AnchorConfig,
+        window_id: u64,
+        heartbeat_period_ms: u64,
+    ) -> Result<WindowSummary, PhalaAvsError> {
+        let key = window_id.to_be_bytes();
+        if let Some(summary) = self.store.get_json(SUMMARY_NAMESPACE, &key)? {
+            METRICS.inc_counter(SUMMARY_METRIC, &[("outcome", "hit")], 1);
+            return Ok(summary);
+        }
+
+        let (start, end) = windows.bounds(window_id);
+        let (start_ms, end_ms) = (start * 1000, end * 1000);
+        // Moved before the records are read, so one written meanwhile is noted.
+        let watermark: Option<u64> = self.store.get_json(SUMMARY_NAMESPACE, WATERMARK_KEY)?;
+        if watermark.is_none_or(|watermark| watermark < end_ms) {
+            self.store
+                .put_json(SUMMARY_NAMESPACE, WATERMARK_KEY, &end_ms)?;
+        }
+
+        let heartbeats = self.heartbeats(start_ms.saturating_sub(heartbeat_period_ms), end_ms)?;
+        let (live_ms, maintenance_ms) =
+            coverage(&heartbeats, start_ms, end_ms, heartbeat_period_ms);
+        let own: Vec<_> = heartbeats
+            .iter()
+            .filter(|(at, _)| *at >= start_ms)
+            .collect();
+        let summary = WindowSummary {
+            window_id,
+            leaves: self.leaves(start_ms, end_ms)?,
+            heartbeats: own.len() as u64,
+            failed_heartbeats: own
+                .iter()
+                .filter(|(_, h)| !h.in_maintenance && h.live != Some(true))
+                .count() as u64,
+            first_heartbeat_ms: own.first().map(|(at, _)| *at),
+            last_heartbeat_ms: own.last().map(|(at, _)| *at),
+            live_ms,
+            maintenance_ms,
+        };
+        self.store.put_json(SUMMARY_NAMESPACE, &key, &summary)?;
+        METRICS.inc_counter(SUMMARY_METRIC, &[("outcome", "built")], 1);
+        Ok(summary)
+    }
+
+    /// The stored summary of a window, if one was built and is still valid.
+    pub fn stored_summary(
+        &self,
+        windows: &AnchorConfig,
+        window_id: u64,
+        heartbeat_period_ms: u64,
+    ) -> Result<Option<WindowSummary>, PhalaAvsError> {
+        self.invalidate_summaries(windows, heartbeat_period_ms)?;
+        self.store
+            .get_json(SUMMARY_NAMESPACE, &window_id.to_be_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evidence::range::BlockSpan;
+    use crate::evidence::{HEARTBEAT_EVIDENCE, RESPONSE_EVIDENCE, ResponseEvidence};
+    use crate::fixtures::HeartbeatFixture;
+    use crate::state::{MemoryStateStore, StateStore};
+    use std::sync::Arc;
+
+    const CONFIG: AnchorConfig = AnchorConfig {
+        enabled: true,
+        window_secs: 3600,
+        check_secs: 60,
+    };
+    const PERIOD_MS: u64 = 60_000;
+    const HOUR_MS: u64 = 3_600_000;
+
+    fn heartbeat(log: &EvidenceLog, unix_ms: u64, fixture: HeartbeatFixture) {
+        log.record(
+            HEARTBEAT_EVIDENCE,
+            unix_ms,
+            &[],
+            &fixture.at(unix_ms).build(),
+        )
+        .unwrap();
+    }
+
+    /// Heartbeats about every 50s through windows 10 to 15, with an outage in window 11,
+    /// maintenance from late in window 12 into window 13, failed checks, a gap in window 14, and a
+    /// response in window 12.
+    fn log() -> EvidenceLog {
+        let log = EvidenceLog::new(Arc::new(MemoryStateStore::default()) as Arc<dyn StateStore>);
+        for k in 0..(6 * HOUR_MS / 50_000) {
+            let unix_ms = 10 * HOUR_MS + k * 50_000 + k % 7;
+            let fixture = match k {
+                100..130 => HeartbeatFixture::new().live(false),
+                200..260 => HeartbeatFixture::new().in_maintenance(),
+                300..310 => continue,
+                _ if k % 97 == 0 => HeartbeatFixture::new().check_failed(),
+                _ => HeartbeatFixture::new(),
+            };
+            heartbeat(&log, unix_ms, fixture);
+        }
+        let response = ResponseEvidence {
+            unix_ms: 12 * HOUR_MS + 5,
+            challenge_id: "9".to_string(),
+            issued_block: 10,
+            deadline_block: 60,
+            release_reason: "Confirmed".to_string(),
+            artifacts_url: None,
+            detection_delay_blocks: None,
+            maintenance_window_id: None,
+            sla_proof_hash: None,
+        };
+        log.record(RESPONSE_EVIDENCE, response.unix_ms, &[9], &response)
+            .unwrap();
+        log
+    }
+
+    fn span(from_ms: u64, to_ms: u64) -> BlockSpan {
+        BlockSpan {
+            from_block: 1,
+            to_block: 2,
+            from_ms,
+            to_ms,
+            past_head: false,
+        }
+    }
+
+    fn assert_equivalent(log: &EvidenceLog, span: BlockSpan, now_ms: u64) {
+        let composed = log
+            .range_evidence(&CONFIG, span, PERIOD_MS, now_ms)
+            .unwrap();
+        let raw = log
+            .range_evidence_raw(&CONFIG, span, PERIOD_MS, now_ms)
+            .unwrap();
+        assert_eq!(
+            serde_json::to_vec(&composed).unwrap(),
+            serde_json::to_vec(&raw).unwrap(),
+            "{span:?} at {now_ms}"
+        );
+    }
+
+    #[test]
+    fn composed_ranges_are_identical_to_raw_scans() {
+        let log = log();
+        let now = 15 * HOUR_MS + 1_800_000;
+        let spans = [
+            span(10 * HOUR_MS, 16 * HOUR_MS),
+            span(10 * HOUR_MS + 1, 14 * HOUR_MS - 1),
+            span(11 * HOUR_MS, 12 * HOUR_MS),
+            span(12 * HOUR_MS + 17, 12 * HOUR_MS + 900_000),
+            span(9 * HOUR_MS, 13 * HOUR_MS + 123),
+        ];
+        for span in spans {
+            assert_equivalent(&log, span, now);
+            // The second time round, from stored summaries.
+            assert_equivalent(&log, span, now);
+        }
+        // The open window is never summarized.
+        assert!(
+            log.stored_summary(&CONFIG, 15, PERIOD_MS)
+                .unwrap()
+                .is_none()
+        );
+
+        let window = log.stored_summary(&CONFIG, 12, PERIOD_MS).unwrap().unwrap();
+        assert_eq!(window.heartbeats, 72);
+        assert_eq!(window.failed_heartbeats, 1);
+        assert_eq!(window.leaves.len(), 73);
+        // Maintenance starts with heartbeat 200, 4ms after 12h 46m 40s.
+        assert_eq!(window.maintenance_ms, 799_996);
+    }
+
+    #[test]
+    fn late_evidence_invalidates_the_summaries_it_affects() {
+        let log = log();
+        let now = 20 * HOUR_MS;
+        let all = span(10 * HOUR_MS, 16 * HOUR_MS);
+        assert_equivalent(&log, all, now);
+        let before = log.stored_summary(&CONFIG, 13, PERIOD_MS).unwrap().unwrap();
+
+        // Late, 10s before the end of window 12. It cuts short the previous heartbeat's
+        // maintenance, which ran 6ms into window 13.
+        heartbeat(
+            &log,
+            13 * HOUR_MS - 10_000,
+            HeartbeatFixture::new().live(false),
+        );
+        assert!(
+            log.stored_summary(&CONFIG, 13, PERIOD_MS)
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            log.stored_summary(&CONFIG, 11, PERIOD_MS)
+                .unwrap()
+                .is_some()
+        );
+        assert_equivalent(&log, all, now);
+        let after = log.stored_summary(&CONFIG, 13, PERIOD_MS).unwrap().unwrap();
+        assert_eq!(before.maintenance_ms - after.maintenance_ms, 6);
+
+        // A different heartbeat period rebuilds everything.
+        assert!(log.stored_summary(&CONFIG, 11, 30_000).unwrap().is_none());
+        let composed = log.range_evidence(&CONFIG, all, 30_000, now).unwrap();
+        let raw = log.range_evidence_raw(&CONFIG, all, 30_000, now).unwrap();
+        assert_eq!(composed, raw);
+    }
+}
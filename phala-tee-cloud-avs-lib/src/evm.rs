@@ -0,0 +1,104 @@
+use crate::IPhalaServiceManager;
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::eips::BlockNumberOrTag;
+use blueprint_sdk::alloy::primitives::{Address, B256};
+use blueprint_sdk::alloy::providers::Provider;
+use std::future::Future;
+use std::pin::Pin;
+
+/// A boxed, sendable future, so [`EvmClient`] can be used as a trait object.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The subset of chain access the operator pipeline needs.
+///
+/// Jobs go through this trait instead of a concrete provider so tests can substitute a mock
+/// chain and so wrappers (failover, caching) can be layered in one place.
+pub trait EvmClient: Send + Sync {
+    /// The chain id of the connected network.
+    fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+
+    /// The current head block number.
+    fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+
+    /// The canonical hash of block `number`, or `None` if the chain is not that long.
+    fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>>;
+
+    /// The unix timestamp of block `number`, or `None` if the chain is not that long.
+    fn block_timestamp(&self, number: u64) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>>;
+
+    /// Whether `operator` is currently registered with the service manager.
+    fn is_operator_registered(
+        &self,
+        operator: Address,
+    ) -> BoxFuture<'_, Result<bool, PhalaAvsError>>;
+}
+
+/// [`EvmClient`] backed by an alloy [`Provider`].
+#[derive(Clone, Debug)]
+pub struct ProviderEvmClient<P> {
+    provider: P,
+    service_manager: Address,
+}
+
+impl<P> ProviderEvmClient<P> {
+    pub fn new(provider: P, service_manager: Address) -> Self {
+        Self {
+            provider,
+            service_manager,
+        }
+    }
+}
+
+impl<P: Provider + Send + Sync + 'static> EvmClient for ProviderEvmClient<P> {
+    fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_chain_id()
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_chainId failed: {e}")))
+        })
+    }
+
+    fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_block_number()
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_blockNumber failed: {e}")))
+        })
+    }
+
+    fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_block_by_number(BlockNumberOrTag::Number(number))
+                .await
+                .map(|block| block.map(|b| b.header.hash))
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_getBlockByNumber failed: {e}")))
+        })
+    }
+
+    fn block_timestamp(&self, number: u64) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_block_by_number(BlockNumberOrTag::Number(number))
+                .await
+                .map(|block| block.map(|b| b.header.timestamp))
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_getBlockByNumber failed: {e}")))
+        })
+    }
+
+    fn is_operator_registered(
+        &self,
+        operator: Address,
+    ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+        Box::pin(async move {
+            IPhalaServiceManager::new(self.service_manager, &self.provider)
+                .isOperatorRegistered(operator)
+                .call()
+                .await
+                .map(|r| r._0)
+                .map_err(|e| PhalaAvsError::EvmError(format!("isOperatorRegistered failed: {e}")))
+        })
+    }
+}
@@ -0,0 +1,566 @@
+//! Voluntary exit: leaving the AVS without being slashed for challenges issued before the
+//! deregistration takes effect.
+//!
+//! `POST /admin/exit`, or the `exit` subcommand, starts the workflow. The operator drains first:
+//! new workload reservations are refused and zero capacity is advertised, while every challenge
+//! keeps being answered, including those issued until the deregistration takes effect (the
+//! [`RegistrationGate`] keeps permitting submissions through it). The workflow then opts out of
+//! the AVS with `requestExit`, waits until the service manager's `exitAllowedFrom` block, submits
+//! the quorum deregistration to the registry coordinator and waits for it to take effect. Finally
+//! it waits for the last response window that can cover our active period, the oracle's
+//! `responseWindowBlocks` after the deregistration, to close, and only then reports the operator
+//! safe to shut down.
+//!
+//! Every phase is persisted, so a restarted operator resumes its exit and drains again at once.
+//! Transactions are skipped when the chain shows they already took effect, so a crash between
+//! submitting one and persisting the phase never submits it twice.
+
+use crate::capacity::Reservations;
+use crate::challenge::ChallengeTracker;
+use crate::config::{env_address, env_or};
+use crate::display::Addr;
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::evm::{BoxFuture, EvmClient};
+use crate::notify::{Alert, Notifier, Severity};
+use crate::operator_set::IRegistryCoordinator;
+use crate::registration::RegistrationGate;
+use crate::state::{StateStore, StateStoreExt};
+use crate::{
+    IPhalaServiceManager, IPhalaSlaOracle, PRIVATE_KEY, SERVICE_MANAGER_ADDRESS, SLA_ORACLE_ADDRESS,
+};
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes};
+use blueprint_sdk::evm::util::{get_provider_from_signer, get_provider_http};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// StateStore namespace holding the exit state.
+pub const EXIT_NAMESPACE: &str = "exit";
+
+const STATE_KEY: &[u8] = b"state";
+
+/// Where an exit is, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitPhase {
+    /// Draining; the AVS opt-out is not confirmed yet.
+    Draining,
+    /// Opted out; waiting for the block the quorum deregistration is allowed from.
+    OptedOut,
+    /// Quorum deregistration submitted; waiting for it to take effect.
+    Deregistering,
+    /// Deregistered; waiting for the last response windows to close.
+    WindingDown,
+    SafeToShutdown,
+}
+
+/// The persisted progress of an exit, as shown on `/status` and `/exit`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitState {
+    pub phase: ExitPhase,
+    pub started_unix_ms: u64,
+    pub opt_out_tx: Option<B256>,
+    /// First block the quorum deregistration may be submitted in.
+    pub deregister_from_block: Option<u64>,
+    pub deregistration_tx: Option<B256>,
+    /// Head at which the deregistration was first seen in effect.
+    pub deregistered_block: Option<u64>,
+    /// Last block a challenge covering our active period can be answered in.
+    pub final_deadline_block: Option<u64>,
+    pub safe_unix_ms: Option<u64>,
+}
+
+impl ExitState {
+    pub fn is_safe(&self) -> bool {
+        self.phase == ExitPhase::SafeToShutdown
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ExitConfig {
+    pub check_secs: u64,
+    /// Quorums to deregister from.
+    pub quorums: Vec<u8>,
+}
+
+impl ExitConfig {
+    /// Reads `EXIT_CHECK_SECS` and `EXIT_QUORUMS` (comma-separated, `0` by default).
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let quorums = env_or("EXIT_QUORUMS", "0".to_string())?
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|q| {
+                q.parse().map_err(|e| {
+                    PhalaAvsError::ConfigError(format!("Invalid quorum {q} in EXIT_QUORUMS: {e}"))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            check_secs: env_or("EXIT_CHECK_SECS", 12)?,
+            quorums,
+        })
+    }
+}
+
+/// The contracts an exit goes through.
+pub trait ExitRegistry: Send + Sync {
+    /// Opts out of the AVS, returning the transaction hash.
+    fn opt_out(&self) -> BoxFuture<'_, Result<B256, PhalaAvsError>>;
+
+    /// First block `operator` may deregister from its quorums in, or `None` before it opted out.
+    fn deregister_from_block(
+        &self,
+        operator: Address,
+    ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>>;
+
+    /// Deregisters from `quorums`, returning the transaction hash.
+    fn deregister(&self, quorums: Bytes) -> BoxFuture<'_, Result<B256, PhalaAvsError>>;
+
+    /// Blocks the oracle gives to answer a challenge.
+    fn response_window_blocks(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+}
+
+/// [`ExitRegistry`] backed by the service manager, the registry coordinator and the oracle.
+#[derive(Clone, Debug)]
+pub struct ContractExits {
+    service_manager: Address,
+    /// Unset unless `REGISTRY_COORDINATOR_ADDRESS` is configured.
+    registry_coordinator: Option<Address>,
+    oracle: Address,
+    private_key: String,
+    rpc_url: String,
+}
+
+impl ContractExits {
+    /// Uses `SERVICE_MANAGER_ADDRESS`, `REGISTRY_COORDINATOR_ADDRESS`, `SLA_ORACLE_ADDRESS` and
+    /// the operator's `PRIVATE_KEY`.
+    pub fn from_env(rpc_url: String) -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            service_manager: *SERVICE_MANAGER_ADDRESS,
+            registry_coordinator: env_address("REGISTRY_COORDINATOR_ADDRESS")?,
+            oracle: *SLA_ORACLE_ADDRESS,
+            private_key: PRIVATE_KEY.clone(),
+            rpc_url,
+        })
+    }
+}
+
+fn evm_err(e: impl fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::EvmError(e.to_string())
+}
+
+impl ExitRegistry for ContractExits {
+    fn opt_out(&self) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_from_signer(&self.private_key, &self.rpc_url);
+            let receipt = IPhalaServiceManager::new(self.service_manager, provider)
+                .requestExit()
+                .send()
+                .await
+                .map_err(evm_err)?
+                .get_receipt()
+                .await
+                .map_err(evm_err)?;
+            if !receipt.status() {
+                return Err(PhalaAvsError::EvmError(format!(
+                    "requestExit reverted in {}",
+                    receipt.transaction_hash
+                )));
+            }
+            Ok(receipt.transaction_hash)
+        })
+    }
+
+    fn deregister_from_block(
+        &self,
+        operator: Address,
+    ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            let from = IPhalaServiceManager::new(self.service_manager, provider)
+                .exitAllowedFrom(operator)
+                .call()
+                .await
+                .map_err(evm_err)?
+                ._0;
+            Ok((!from.is_zero()).then(|| from.saturating_to()))
+        })
+    }
+
+    fn deregister(&self, quorums: Bytes) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        Box::pin(async move {
+            let coordinator = self.registry_coordinator.ok_or_else(|| {
+                PhalaAvsError::ConfigError(
+                    "REGISTRY_COORDINATOR_ADDRESS is needed to deregister".to_string(),
+                )
+            })?;
+            let provider = get_provider_from_signer(&self.private_key, &self.rpc_url);
+            let receipt = IRegistryCoordinator::new(coordinator, provider)
+                .deregisterOperator(quorums)
+                .send()
+                .await
+                .map_err(evm_err)?
+                .get_receipt()
+                .await
+                .map_err(evm_err)?;
+            if !receipt.status() {
+                return Err(PhalaAvsError::EvmError(format!(
+                    "deregisterOperator reverted in {}",
+                    receipt.transaction_hash
+                )));
+            }
+            Ok(receipt.transaction_hash)
+        })
+    }
+
+    fn response_window_blocks(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            let blocks = IPhalaSlaOracle::new(self.oracle, provider)
+                .responseWindowBlocks()
+                .call()
+                .await
+                .map_err(evm_err)?
+                ._0;
+            Ok(blocks.saturating_to())
+        })
+    }
+}
+
+/// Drives an exit through its phases.
+pub struct ExitWorkflow {
+    config: ExitConfig,
+    operator: Address,
+    registry: Arc<dyn ExitRegistry>,
+    store: Arc<dyn StateStore>,
+    registration: Arc<RegistrationGate>,
+    reservations: Arc<Reservations>,
+    state: Mutex<Option<ExitState>>,
+}
+
+impl ExitWorkflow {
+    /// Loads a persisted exit, draining again at once if one is in progress.
+    pub fn new(
+        config: ExitConfig,
+        operator: Address,
+        registry: Arc<dyn ExitRegistry>,
+        store: Arc<dyn StateStore>,
+        registration: Arc<RegistrationGate>,
+        reservations: Arc<Reservations>,
+    ) -> Result<Self, PhalaAvsError> {
+        let state: Option<ExitState> = store.get_json(EXIT_NAMESPACE, STATE_KEY)?;
+        let workflow = Self {
+            config,
+            operator,
+            registry,
+            store,
+            registration,
+            reservations,
+            state: Mutex::new(state.clone()),
+        };
+        if let Some(state) = state {
+            info!("Resuming exit in phase {:?}", state.phase);
+            workflow.drain();
+        }
+        Ok(workflow)
+    }
+
+    pub fn config(&self) -> &ExitConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> Option<ExitState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn drain(&self) {
+        self.registration.set_exiting();
+        self.reservations.set_draining();
+    }
+
+    fn persist(&self, state: ExitState) -> Result<ExitState, PhalaAvsError> {
+        self.store.put_json(EXIT_NAMESPACE, STATE_KEY, &state)?;
+        *self.state.lock().unwrap_or_else(|e| e.into_inner()) = Some(state.clone());
+        Ok(state)
+    }
+
+    /// Starts the exit, or returns the one in progress.
+    pub fn start(&self, now_ms: u64) -> Result<ExitState, PhalaAvsError> {
+        if let Some(state) = self.state() {
+            return Ok(state);
+        }
+        let state = self.persist(ExitState {
+            phase: ExitPhase::Draining,
+            started_unix_ms: now_ms,
+            opt_out_tx: None,
+            deregister_from_block: None,
+            deregistration_tx: None,
+            deregistered_block: None,
+            final_deadline_block: None,
+            safe_unix_ms: None,
+        })?;
+        self.drain();
+        info!("Operator {} is exiting; draining", Addr(self.operator));
+        Ok(state)
+    }
+
+    /// Advances the exit as far as the chain allows, returning the new phase if it changed.
+    pub async fn step(
+        &self,
+        evm: &dyn EvmClient,
+        tracker: &ChallengeTracker,
+        now_ms: u64,
+    ) -> Result<Option<ExitPhase>, PhalaAvsError> {
+        let Some(mut state) = self.state() else {
+            return Ok(None);
+        };
+        let previous = state.phase;
+        let head = evm.block_number().await?;
+        if state.phase == ExitPhase::Draining {
+            let from = match self.registry.deregister_from_block(self.operator).await? {
+                Some(from) => from,
+                None => {
+                    state.opt_out_tx = Some(self.registry.opt_out().await?);
+                    self.registry
+                        .deregister_from_block(self.operator)
+                        .await?
+                        .ok_or_else(|| {
+                            PhalaAvsError::EvmError(
+                                "Opt-out confirmed but no exit is recorded".to_string(),
+                            )
+                        })?
+                }
+            };
+            state.deregister_from_block = Some(from);
+            state.phase = ExitPhase::OptedOut;
+            state = self.persist(state)?;
+            info!("Opted out of the AVS; quorum deregistration allowed from block {from}");
+        }
+        if state.phase == ExitPhase::OptedOut
+            && state.deregister_from_block.is_some_and(|from| head >= from)
+        {
+            if evm.is_operator_registered(self.operator).await? {
+                let quorums = Bytes::from(self.config.quorums.clone());
+                state.deregistration_tx = Some(self.registry.deregister(quorums).await?);
+            }
+            state.phase = ExitPhase::Deregistering;
+            state = self.persist(state)?;
+        }
+        if state.phase == ExitPhase::Deregistering
+            && !evm.is_operator_registered(self.operator).await?
+        {
+            let window = self.registry.response_window_blocks().await?;
+            state.deregistered_block = Some(head);
+            state.final_deadline_block = Some(head + window);
+            state.phase = ExitPhase::WindingDown;
+            state = self.persist(state)?;
+            info!(
+                "Deregistered at block {head}; answering challenges until block {}",
+                head + window
+            );
+        }
+        if state.phase == ExitPhase::WindingDown {
+            // A challenge seen late may still carry a later deadline than the expected one.
+            let tracked = tracker
+                .snapshot()
+                .iter()
+                .map(|c| c.challenge.deadline_block)
+                .max();
+            let final_deadline = state.final_deadline_block.max(tracked).unwrap_or(head);
+            if state.final_deadline_block != Some(final_deadline) {
+                state.final_deadline_block = Some(final_deadline);
+                state = self.persist(state)?;
+            }
+            if head > final_deadline {
+                state.phase = ExitPhase::SafeToShutdown;
+                state.safe_unix_ms = Some(now_ms);
+                state = self.persist(state)?;
+            }
+        }
+        Ok((state.phase != previous).then_some(state.phase))
+    }
+}
+
+/// Advances a started exit every `check_secs`, alerting once it is safe to shut down.
+pub fn spawn_exit(
+    workflow: Arc<ExitWorkflow>,
+    evm: Arc<dyn EvmClient>,
+    tracker: Arc<ChallengeTracker>,
+    notifier: Arc<dyn Notifier>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(workflow.config.check_secs));
+        loop {
+            interval.tick().await;
+            match workflow.step(evm.as_ref(), &tracker, now_unix_ms()).await {
+                Ok(Some(ExitPhase::SafeToShutdown)) => {
+                    let alert = Alert::new(
+                        "exit",
+                        Severity::Info,
+                        "The last response window closed; the operator is safe to shut down",
+                    );
+                    if let Err(e) = notifier.notify(alert).await {
+                        warn!("Failed to deliver exit alert: {e}");
+                    }
+                    return;
+                }
+                Ok(Some(phase)) => info!("Exit advanced to {phase:?}"),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to advance exit: {e}"),
+            }
+        }
+    });
+}
+
+/// Starts the exit of a running operator through its admin API.
+pub async fn request_exit(operator_url: &str, token: &str) -> Result<ExitState, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/exit", operator_url.trim_end_matches('/')))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    read_state(response).await
+}
+
+/// Fetches the progress of a running operator's exit.
+pub async fn fetch_exit(
+    operator_url: &str,
+    token: &str,
+) -> Result<Option<ExitState>, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/exit", operator_url.trim_end_matches('/')))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    read_state(response).await
+}
+
+async fn read_state<T: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<T, PhalaAvsError> {
+    if !response.status().is_success() {
+        return Err(PhalaAvsError::Other(format!(
+            "Operator returned {} for exit",
+            response.status()
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to read exit state: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::ConfirmationPolicy;
+    use crate::registration::RegistrationConfig;
+    use crate::state::MemoryStateStore;
+
+    const OPERATOR: Address = Address::repeat_byte(1);
+
+    /// Opted out on-chain, as if the operator crashed before persisting the next phase.
+    struct AlreadyOptedOut;
+
+    impl ExitRegistry for AlreadyOptedOut {
+        fn opt_out(&self) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            unreachable!("the opt-out already took effect")
+        }
+
+        fn deregister_from_block(
+            &self,
+            _operator: Address,
+        ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+            Box::pin(async { Ok(Some(50)) })
+        }
+
+        fn deregister(&self, _quorums: Bytes) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            unreachable!("the exit delay has not passed")
+        }
+
+        fn response_window_blocks(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(30) })
+        }
+    }
+
+    struct Head(u64);
+
+    impl EvmClient for Head {
+        fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(31337) })
+        }
+
+        fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            let head = self.0;
+            Box::pin(async move { Ok(head) })
+        }
+
+        fn block_hash(&self, _number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn block_timestamp(
+            &self,
+            _number: u64,
+        ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn is_operator_registered(
+            &self,
+            _operator: Address,
+        ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+            Box::pin(async { Ok(true) })
+        }
+    }
+
+    #[tokio::test]
+    async fn resumed_exit_drains_and_skips_a_confirmed_opt_out() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let workflow = |store: &Arc<dyn StateStore>| {
+            ExitWorkflow::new(
+                ExitConfig {
+                    check_secs: 12,
+                    quorums: vec![0],
+                },
+                OPERATOR,
+                Arc::new(AlreadyOptedOut),
+                Arc::clone(store),
+                Arc::new(RegistrationGate::new(OPERATOR, RegistrationConfig {
+                    check_secs: 60,
+                    force_submit: false,
+                })),
+                Arc::new(Reservations::default()),
+            )
+            .unwrap()
+        };
+        assert!(workflow(&store).state().is_none());
+        workflow(&store).start(1_000).unwrap();
+
+        let resumed = workflow(&store);
+        assert!(resumed.registration.is_exiting());
+        assert!(resumed.reservations.is_draining());
+        assert_eq!(resumed.start(2_000).unwrap().started_unix_ms, 1_000);
+
+        let tracker =
+            ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap();
+        let phase = resumed.step(&Head(40), &tracker, 3_000).await.unwrap();
+        assert_eq!(phase, Some(ExitPhase::OptedOut));
+        let state = resumed.state().unwrap();
+        assert_eq!(
+            (state.opt_out_tx, state.deregister_from_block),
+            (None, Some(50))
+        );
+        // Before the exit delay passed, nothing more happens.
+        assert_eq!(
+            resumed.step(&Head(49), &tracker, 4_000).await.unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,365 @@
+//! Failure domains of the oracle targets responses are submitted to.
+//!
+//! Each target's responses are queued apart by the [`FairScheduler`](crate::scheduler), and
+//! each target's health is tracked here, so a broken target (a revert storm, a bug in its
+//! encoder) only holds up its own responses. After `RESPONSE_DOMAIN_SUSPEND_AFTER` consecutive
+//! failures a target is suspended: its responses stay queued and are not submitted, and an
+//! alert is raised. Every `RESPONSE_DOMAIN_PROBE_SECS` one response is let through as a probe;
+//! its success resumes the target. Suspension is also manual, through
+//! `POST /admin/domains/{chain_id}/{oracle}/suspend` and `.../resume`, and is then only lifted
+//! by hand.
+//!
+//! Automatic suspension needs a second target to protect, so a single-target operator keeps
+//! retrying as before.
+
+use crate::batch::EventOutcome;
+use crate::config::env_or;
+use crate::display::Addr;
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Severity};
+use crate::response_window::OracleTarget;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// 1 while a target is suspended, by chain and oracle.
+pub const DOMAIN_SUSPENDED_METRIC: &str = "phala_avs_response_domain_suspended";
+/// Counter of response outcomes, by chain, oracle and outcome.
+pub const DOMAIN_OUTCOMES_METRIC: &str = "phala_avs_response_domain_outcomes_total";
+
+#[derive(Clone, Debug)]
+pub struct DomainConfig {
+    pub suspend_after: u32,
+    pub probe_ms: u64,
+}
+
+impl DomainConfig {
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let probe_secs: u64 = env_or("RESPONSE_DOMAIN_PROBE_SECS", 300)?;
+        Ok(Self {
+            suspend_after: env_or("RESPONSE_DOMAIN_SUSPEND_AFTER", 5u32)?.max(1),
+            probe_ms: probe_secs * 1000,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuspendReason {
+    /// Too many consecutive failures; probed and resumed automatically.
+    Failures,
+    /// Through the admin API; resumed by hand.
+    Manual,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Suspension {
+    pub reason: SuspendReason,
+    pub since_unix_ms: u64,
+    /// When a probe is next let through, for automatic suspensions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_probe_unix_ms: Option<u64>,
+}
+
+/// Whether a target's next response may be submitted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Submit,
+    /// Submit it as a probe of a suspended target.
+    Probe,
+    Suspended,
+}
+
+/// A target's domain, as shown in `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DomainStatus {
+    pub chain_id: u64,
+    pub oracle: String,
+    pub consecutive_failures: u32,
+    pub processed: u64,
+    pub failed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suspension: Option<Suspension>,
+}
+
+#[derive(Debug, Default)]
+struct Domain {
+    consecutive_failures: u32,
+    processed: u64,
+    failed: u64,
+    last_error: Option<String>,
+    suspension: Option<Suspension>,
+}
+
+/// The failure domains of every target seen.
+#[derive(Debug)]
+pub struct FailureDomains {
+    config: DomainConfig,
+    domains: Mutex<HashMap<OracleTarget, Domain>>,
+}
+
+impl FailureDomains {
+    pub fn new(config: DomainConfig) -> Self {
+        Self {
+            config,
+            domains: Mutex::default(),
+        }
+    }
+
+    fn domains(&self) -> std::sync::MutexGuard<'_, HashMap<OracleTarget, Domain>> {
+        self.domains.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether a response to `target` may be submitted at `now_ms`. A probe pushes the next one
+    /// back, so a suspended target is probed once per period.
+    pub fn admit(&self, target: OracleTarget, now_ms: u64) -> Admission {
+        let mut domains = self.domains();
+        let Some(suspension) = domains.get_mut(&target).and_then(|d| d.suspension.as_mut()) else {
+            return Admission::Submit;
+        };
+        match suspension.next_probe_unix_ms {
+            Some(probe) if now_ms >= probe => {
+                suspension.next_probe_unix_ms = Some(now_ms + self.config.probe_ms);
+                Admission::Probe
+            }
+            _ => Admission::Suspended,
+        }
+    }
+
+    /// Records the outcome of a submission to `target`, returning the alert to raise when it
+    /// got suspended or resumed.
+    pub fn record(
+        &self,
+        target: OracleTarget,
+        outcome: &Result<EventOutcome, PhalaAvsError>,
+        now_ms: u64,
+    ) -> Option<Alert> {
+        let result = match outcome {
+            Ok(EventOutcome::Deferred) => return None,
+            Ok(_) => "processed",
+            Err(_) => "failed",
+        };
+        let (chain_id, oracle) = (target.chain_id, Addr(target.oracle));
+        METRICS.inc_counter(
+            DOMAIN_OUTCOMES_METRIC,
+            &[
+                ("chain_id", &chain_id.to_string()),
+                ("oracle", &oracle.to_string()),
+                ("outcome", result),
+            ],
+            1,
+        );
+        let mut domains = self.domains();
+        domains.entry(target).or_default();
+        let several = domains.len() > 1;
+        let domain = domains.get_mut(&target)?;
+        let Err(e) = outcome else {
+            domain.processed += 1;
+            domain.consecutive_failures = 0;
+            domain
+                .suspension
+                .take_if(|s| s.reason == SuspendReason::Failures)?;
+            info!("Resumed submissions to {oracle} on chain {chain_id}");
+            self.set_gauge(target, false);
+            return Some(Alert::new(
+                "failure_domain",
+                Severity::Info,
+                format!("Submissions to oracle {oracle} on chain {chain_id} resumed"),
+            ));
+        };
+        domain.failed += 1;
+        domain.consecutive_failures += 1;
+        domain.last_error = Some(e.to_string());
+        // A failed probe keeps the target suspended until the next one.
+        if !several
+            || domain.suspension.is_some()
+            || domain.consecutive_failures < self.config.suspend_after
+        {
+            return None;
+        }
+        domain.suspension = Some(Suspension {
+            reason: SuspendReason::Failures,
+            since_unix_ms: now_ms,
+            next_probe_unix_ms: Some(now_ms + self.config.probe_ms),
+        });
+        let failures = domain.consecutive_failures;
+        warn!(
+            "Suspended submissions to {oracle} on chain {chain_id} after {failures} failures: {e}"
+        );
+        self.set_gauge(target, true);
+        Some(Alert::new(
+            "failure_domain",
+            Severity::Critical,
+            format!(
+                "Submissions to oracle {oracle} on chain {chain_id} suspended after {failures} \
+                 consecutive failures, last: {e}"
+            ),
+        ))
+    }
+
+    /// Suspends `target` until [`resume`](Self::resume)d.
+    pub fn suspend(&self, target: OracleTarget, now_ms: u64) -> DomainStatus {
+        let mut domains = self.domains();
+        let domain = domains.entry(target).or_default();
+        domain.suspension = Some(Suspension {
+            reason: SuspendReason::Manual,
+            since_unix_ms: now_ms,
+            next_probe_unix_ms: None,
+        });
+        self.set_gauge(target, true);
+        status_of(target, domain)
+    }
+
+    pub fn resume(&self, target: OracleTarget) -> DomainStatus {
+        let mut domains = self.domains();
+        let domain = domains.entry(target).or_default();
+        domain.suspension = None;
+        domain.consecutive_failures = 0;
+        self.set_gauge(target, false);
+        status_of(target, domain)
+    }
+
+    /// Every target's domain, by chain and oracle.
+    pub fn status(&self) -> Vec<DomainStatus> {
+        let mut status: Vec<_> = self
+            .domains()
+            .iter()
+            .map(|(target, domain)| status_of(*target, domain))
+            .collect();
+        status.sort_by(|a, b| (a.chain_id, &a.oracle).cmp(&(b.chain_id, &b.oracle)));
+        status
+    }
+
+    fn set_gauge(&self, target: OracleTarget, suspended: bool) {
+        METRICS.set_gauge(
+            DOMAIN_SUSPENDED_METRIC,
+            &[
+                ("chain_id", &target.chain_id.to_string()),
+                ("oracle", &Addr(target.oracle).to_string()),
+            ],
+            if suspended { 1.0 } else { 0.0 },
+        );
+    }
+}
+
+fn status_of(target: OracleTarget, domain: &Domain) -> DomainStatus {
+    DomainStatus {
+        chain_id: target.chain_id,
+        oracle: Addr(target.oracle).to_string(),
+        consecutive_failures: domain.consecutive_failures,
+        processed: domain.processed,
+        failed: domain.failed,
+        last_error: domain.last_error.clone(),
+        suspension: domain.suspension.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::drain_queue;
+    use crate::scheduler::{FairScheduler, SchedulerConfig};
+    use blueprint_sdk::alloy::primitives::Address;
+
+    const BROKEN: OracleTarget = OracleTarget {
+        chain_id: 31337,
+        oracle: Address::repeat_byte(0xaa),
+    };
+    const HEALTHY: OracleTarget = OracleTarget {
+        chain_id: 31337,
+        oracle: Address::repeat_byte(0xbb),
+    };
+
+    fn domains() -> FailureDomains {
+        FailureDomains::new(DomainConfig {
+            suspend_after: 3,
+            probe_ms: 1_000,
+        })
+    }
+
+    fn reverted() -> Result<EventOutcome, PhalaAvsError> {
+        Err(PhalaAvsError::EvmError("execution reverted".to_string()))
+    }
+
+    /// Drains a queue holding `per_target` responses to each target through `domains`, the
+    /// broken target's always reverting. Returns the healthy target's results.
+    async fn drain(domains: &FailureDomains, per_target: u64, now_ms: u64) -> Vec<bool> {
+        let queue = std::sync::Mutex::new(FairScheduler::new(SchedulerConfig::default()));
+        for i in 0..per_target {
+            let mut queue = queue.lock().unwrap();
+            queue.push(BROKEN, 1_000 + i, 0, ());
+            queue.push(HEALTHY, 1_000 + i, 0, ());
+        }
+        let healthy = std::sync::Mutex::new(Vec::new());
+        drain_queue(&queue, 0, |next| {
+            let outcome = match domains.admit(next.target, now_ms) {
+                Admission::Suspended => Ok(EventOutcome::Deferred),
+                _ if next.target == BROKEN => reverted(),
+                _ => Ok(EventOutcome::Processed),
+            };
+            domains.record(next.target, &outcome, now_ms);
+            if next.target == HEALTHY {
+                healthy.lock().unwrap().push(outcome.is_ok());
+            }
+            async move { outcome }
+        })
+        .await;
+        healthy.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_broken_target_suspends_without_affecting_the_other() {
+        let domains = domains();
+        let healthy = drain(&domains, 5, 0).await;
+        assert_eq!(healthy, vec![true; 5]);
+        let status = domains.status();
+        let broken = status
+            .iter()
+            .find(|s| s.oracle == Addr(BROKEN.oracle).to_string())
+            .unwrap();
+        assert_eq!(broken.failed, 3);
+        assert_eq!(
+            broken.suspension.as_ref().map(|s| s.reason),
+            Some(SuspendReason::Failures)
+        );
+        assert!(
+            status
+                .iter()
+                .any(|s| s.processed == 5 && s.suspension.is_none())
+        );
+
+        // Held until the probe period, then probed once per period.
+        assert_eq!(domains.admit(BROKEN, 500), Admission::Suspended);
+        assert_eq!(domains.admit(HEALTHY, 500), Admission::Submit);
+        assert_eq!(domains.admit(BROKEN, 1_000), Admission::Probe);
+        assert!(domains.record(BROKEN, &reverted(), 1_000).is_none());
+        assert_eq!(domains.admit(BROKEN, 1_500), Admission::Suspended);
+        assert_eq!(domains.admit(BROKEN, 2_000), Admission::Probe);
+        let alert = domains
+            .record(BROKEN, &Ok(EventOutcome::Processed), 2_000)
+            .unwrap();
+        assert_eq!(alert.severity, Severity::Info);
+        assert_eq!(domains.admit(BROKEN, 2_001), Admission::Submit);
+    }
+
+    #[test]
+    fn single_targets_keep_retrying_and_manual_suspensions_are_not_probed() {
+        let domains = domains();
+        for now in 0..10 {
+            assert!(domains.record(BROKEN, &reverted(), now).is_none());
+            assert_eq!(domains.admit(BROKEN, now), Admission::Submit);
+        }
+
+        domains.suspend(HEALTHY, 0);
+        assert_eq!(domains.admit(HEALTHY, 1_000_000), Admission::Suspended);
+        // Successes elsewhere, or before the suspension, do not lift a manual one.
+        domains.record(BROKEN, &Ok(EventOutcome::Processed), 1);
+        assert_eq!(domains.admit(HEALTHY, 1_000_000), Admission::Suspended);
+        assert!(domains.resume(HEALTHY).suspension.is_none());
+        assert_eq!(domains.admit(HEALTHY, 1_000_000), Admission::Submit);
+    }
+}
@@ -0,0 +1,390 @@
+//! Fee models of the chains transactions are sent to.
+//!
+//! Not every target chain supports EIP-1559. The model of each chain is detected once, at
+//! startup, by probing for `baseFeePerGas` in the latest block and for `eth_feeHistory`, and
+//! cached by chain id; `FEE_MODEL_<CHAIN_ID>` (`legacy` or `eip1559`) overrides a misdetection.
+//! [`Fees`] carries the fields of either model, so escalation and fee caps work alike under
+//! both: legacy transactions bump and cap their gas price, EIP-1559 ones their priority fee and
+//! fee cap. Blob gas is priced separately and only paid by blob transactions, which are never
+//! sent, so it does not enter the estimate.
+
+use crate::config::env_opt;
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use blueprint_sdk::alloy::eips::BlockNumberOrTag;
+use blueprint_sdk::alloy::network::TransactionBuilder;
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::TransactionRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing::info;
+
+/// Gauge set to 1 for the fee model detected for a chain.
+pub const FEE_MODEL_METRIC: &str = "phala_avs_fee_model";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeModel {
+    /// A single `gasPrice`.
+    Legacy,
+    /// A base fee burnt per block plus a priority fee, capped by `maxFeePerGas`.
+    Eip1559,
+}
+
+impl FromStr for FeeModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "legacy" => Ok(Self::Legacy),
+            "eip1559" | "eip-1559" => Ok(Self::Eip1559),
+            other => Err(format!("unknown fee model {other:?}")),
+        }
+    }
+}
+
+impl fmt::Display for FeeModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Legacy => "legacy",
+            Self::Eip1559 => "eip1559",
+        })
+    }
+}
+
+/// The fee fields of one transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum Fees {
+    Legacy {
+        gas_price: u128,
+    },
+    Eip1559 {
+        max_fee_per_gas: u128,
+        max_priority_fee_per_gas: u128,
+    },
+}
+
+impl Fees {
+    /// The model to record alongside what the transaction cost.
+    pub fn model(&self) -> FeeModel {
+        match self {
+            Self::Legacy { .. } => FeeModel::Legacy,
+            Self::Eip1559 { .. } => FeeModel::Eip1559,
+        }
+    }
+
+    /// The most the transaction may pay per gas, comparable across models.
+    pub fn max_per_gas(&self) -> u128 {
+        match *self {
+            Self::Legacy { gas_price } => gas_price,
+            Self::Eip1559 {
+                max_fee_per_gas, ..
+            } => max_fee_per_gas,
+        }
+    }
+
+    /// Bumps the fees by `pct` percent for a replacement. Nodes only accept a replacement whose
+    /// fee cap and priority fee both increase, so both are bumped under EIP-1559.
+    pub fn escalate(&self, pct: u32) -> Self {
+        let bump = |fee: u128| fee.saturating_add((fee * pct as u128).div_ceil(100).max(1));
+        match *self {
+            Self::Legacy { gas_price } => Self::Legacy {
+                gas_price: bump(gas_price),
+            },
+            Self::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => Self::Eip1559 {
+                max_fee_per_gas: bump(max_fee_per_gas),
+                max_priority_fee_per_gas: bump(max_priority_fee_per_gas),
+            },
+        }
+    }
+
+    /// Whether paying this could exceed `cap` wei per gas.
+    pub fn exceeds(&self, cap: u128) -> bool {
+        self.max_per_gas() > cap
+    }
+
+    /// The fees limited to `cap` wei per gas.
+    pub fn capped(&self, cap: u128) -> Self {
+        match *self {
+            Self::Legacy { gas_price } => Self::Legacy {
+                gas_price: gas_price.min(cap),
+            },
+            Self::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => Self::Eip1559 {
+                max_fee_per_gas: max_fee_per_gas.min(cap),
+                max_priority_fee_per_gas: max_priority_fee_per_gas.min(cap),
+            },
+        }
+    }
+
+    /// Sets the fee fields of `tx`, which then is a legacy or an EIP-1559 transaction.
+    pub fn apply(&self, tx: TransactionRequest) -> TransactionRequest {
+        match *self {
+            Self::Legacy { gas_price } => tx.with_gas_price(gas_price),
+            Self::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => tx
+                .with_max_fee_per_gas(max_fee_per_gas)
+                .with_max_priority_fee_per_gas(max_priority_fee_per_gas),
+        }
+    }
+}
+
+/// The fee data a chain exposes.
+pub trait FeeProbe: Send + Sync {
+    /// `baseFeePerGas` of the latest block; `None` before London.
+    fn base_fee(&self) -> BoxFuture<'_, Result<Option<u128>, PhalaAvsError>>;
+
+    /// Whether `eth_feeHistory` is served.
+    fn fee_history_supported(&self) -> BoxFuture<'_, Result<bool, PhalaAvsError>>;
+
+    fn gas_price(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>>;
+
+    fn max_priority_fee(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>>;
+}
+
+/// [`FeeProbe`] backed by an alloy [`Provider`].
+#[derive(Clone, Debug)]
+pub struct ProviderFeeProbe<P> {
+    provider: P,
+}
+
+impl<P> ProviderFeeProbe<P> {
+    pub fn new(provider: P) -> Self {
+        Self { provider }
+    }
+}
+
+impl<P: Provider + Send + Sync + 'static> FeeProbe for ProviderFeeProbe<P> {
+    fn base_fee(&self) -> BoxFuture<'_, Result<Option<u128>, PhalaAvsError>> {
+        Box::pin(async move {
+            let block = self
+                .provider
+                .get_block_by_number(BlockNumberOrTag::Latest)
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::EvmError(format!("eth_getBlockByNumber failed: {e}"))
+                })?;
+            Ok(block.and_then(|b| b.header.base_fee_per_gas.map(u128::from)))
+        })
+    }
+
+    fn fee_history_supported(&self) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+        Box::pin(async move {
+            Ok(self
+                .provider
+                .get_fee_history(1, BlockNumberOrTag::Latest, &[])
+                .await
+                .is_ok())
+        })
+    }
+
+    fn gas_price(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_gas_price()
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_gasPrice failed: {e}")))
+        })
+    }
+
+    fn max_priority_fee(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_max_priority_fee_per_gas()
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::EvmError(format!("eth_maxPriorityFeePerGas failed: {e}"))
+                })
+        })
+    }
+}
+
+/// Fee models by chain id, detected once each.
+#[derive(Debug, Default)]
+pub struct FeeModels {
+    models: Mutex<BTreeMap<u64, FeeModel>>,
+}
+
+impl FeeModels {
+    /// The model of `chain_id`: the one cached, else `FEE_MODEL_<CHAIN_ID>` when set, else the
+    /// one probed.
+    pub async fn detect(
+        &self,
+        chain_id: u64,
+        probe: &dyn FeeProbe,
+    ) -> Result<FeeModel, PhalaAvsError> {
+        if let Some(model) = self.cached(chain_id) {
+            return Ok(model);
+        }
+        let model = match env_opt(&format!("FEE_MODEL_{chain_id}"))? {
+            Some(model) => model,
+            None if probe.base_fee().await?.is_some() && probe.fee_history_supported().await? => {
+                FeeModel::Eip1559
+            }
+            None => FeeModel::Legacy,
+        };
+        info!("Chain {chain_id} uses {model} fees");
+        self.set(chain_id, model);
+        Ok(model)
+    }
+
+    /// Pins the model of `chain_id`, e.g. from configuration.
+    pub fn set(&self, chain_id: u64, model: FeeModel) {
+        METRICS.set_gauge(
+            FEE_MODEL_METRIC,
+            &[
+                ("chain_id", &chain_id.to_string()),
+                ("model", &model.to_string()),
+            ],
+            1.0,
+        );
+        self.models
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(chain_id, model);
+    }
+
+    pub fn cached(&self, chain_id: u64) -> Option<FeeModel> {
+        self.models
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&chain_id)
+            .copied()
+    }
+
+    /// Current fees of `chain_id` under its model. The EIP-1559 fee cap allows the base fee to
+    /// double before the transaction is priced out.
+    pub async fn estimate(
+        &self,
+        chain_id: u64,
+        probe: &dyn FeeProbe,
+    ) -> Result<Fees, PhalaAvsError> {
+        match self.detect(chain_id, probe).await? {
+            FeeModel::Legacy => Ok(Fees::Legacy {
+                gas_price: probe.gas_price().await?,
+            }),
+            FeeModel::Eip1559 => {
+                let base_fee = probe.base_fee().await?.ok_or_else(|| {
+                    PhalaAvsError::EvmError(format!(
+                        "Chain {chain_id} reports no base fee; set FEE_MODEL_{chain_id}=legacy"
+                    ))
+                })?;
+                let priority = probe.max_priority_fee().await?;
+                Ok(Fees::Eip1559 {
+                    max_fee_per_gas: base_fee.saturating_mul(2).saturating_add(priority),
+                    max_priority_fee_per_gas: priority,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A chain without EIP-1559 when `base_fee` is `None`.
+    #[derive(Default)]
+    struct MockChain {
+        base_fee: Option<u128>,
+        probes: AtomicUsize,
+    }
+
+    impl FeeProbe for MockChain {
+        fn base_fee(&self) -> BoxFuture<'_, Result<Option<u128>, PhalaAvsError>> {
+            self.probes.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(self.base_fee) })
+        }
+
+        fn fee_history_supported(&self) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+            Box::pin(async move { Ok(self.base_fee.is_some()) })
+        }
+
+        fn gas_price(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+            Box::pin(async { Ok(20) })
+        }
+
+        fn max_priority_fee(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+            Box::pin(async { Ok(2) })
+        }
+    }
+
+    #[tokio::test]
+    async fn models_are_detected_once_per_chain_and_can_be_overridden() {
+        let models = FeeModels::default();
+        let legacy = MockChain::default();
+        let london = MockChain {
+            base_fee: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            models.detect(9_001, &legacy).await.unwrap(),
+            FeeModel::Legacy
+        );
+        assert_eq!(
+            models.detect(9_002, &london).await.unwrap(),
+            FeeModel::Eip1559
+        );
+        models.detect(9_001, &legacy).await.unwrap();
+        assert_eq!(legacy.probes.load(Ordering::SeqCst), 1);
+
+        assert_eq!(
+            models.estimate(9_001, &legacy).await.unwrap(),
+            Fees::Legacy { gas_price: 20 }
+        );
+        assert_eq!(
+            models.estimate(9_002, &london).await.unwrap(),
+            Fees::Eip1559 {
+                max_fee_per_gas: 22,
+                max_priority_fee_per_gas: 2,
+            }
+        );
+
+        models.set(9_003, FeeModel::Legacy);
+        assert_eq!(
+            models.detect(9_003, &london).await.unwrap(),
+            FeeModel::Legacy
+        );
+    }
+
+    #[test]
+    fn escalation_and_caps_apply_to_the_fields_of_each_model() {
+        let legacy = Fees::Legacy { gas_price: 100 };
+        assert_eq!(legacy.escalate(10), Fees::Legacy { gas_price: 110 });
+        let london = Fees::Eip1559 {
+            max_fee_per_gas: 200,
+            max_priority_fee_per_gas: 1,
+        };
+        assert_eq!(london.escalate(10), Fees::Eip1559 {
+            max_fee_per_gas: 220,
+            max_priority_fee_per_gas: 2,
+        });
+
+        // Caps compare the most either model may pay per gas.
+        assert!(!legacy.exceeds(150));
+        assert!(london.exceeds(150));
+        assert_eq!(london.capped(150).max_per_gas(), 150);
+        assert_eq!(legacy.escalate(100).capped(150), Fees::Legacy {
+            gas_price: 150
+        });
+
+        let tx = legacy.apply(TransactionRequest::default());
+        assert_eq!((tx.gas_price, tx.max_fee_per_gas), (Some(100), None));
+        let tx = london.apply(TransactionRequest::default());
+        assert_eq!((tx.gas_price, tx.max_fee_per_gas), (None, Some(200)));
+        assert_eq!(london.model(), FeeModel::Eip1559);
+    }
+}
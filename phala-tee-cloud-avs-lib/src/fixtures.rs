@@ -0,0 +1,917 @@
+//! Deterministic builders for the chain events and records the operator consumes.
+//!
+//! Compiled for unit tests, and with the `testing` feature for integration tests. Every builder
+//! starts from fixed defaults, so a test only spells out what it is about, and encodes through
+//! the same bindings the decoders use. [`EventGenerator`] produces seeded batches of valid and
+//! malformed logs for property tests.
+
+use crate::IPhalaServiceManager::ReimbursementCredited;
+use crate::IPhalaSlaOracle::{
+    SlaChallengeAmended, SlaChallengeCancelled, SlaChallengeIssued, SlaChallengeResponded,
+    SlaResponseRejected,
+};
+use crate::challenge::ObservedChallenge;
+use crate::encoding::{
+    ATTESTATION_KIND, AttestationChallengeV1, ComputeChallengeV1, TEE_COMPUTE_KIND, envelope,
+};
+use crate::evidence::HeartbeatEvidence;
+use crate::operator_set::IRegistryCoordinator::{OperatorDeregistered, OperatorRegistered};
+use crate::tee::platform::TeePlatform;
+use crate::tee::quote::{SgxQuote, TDX_TEE_TYPE, TdxQuote};
+use crate::upgrade::Upgraded;
+use blueprint_sdk::alloy::primitives::{self, Address, B256, Bytes, LogData, U256};
+use blueprint_sdk::alloy::rpc::types::Log;
+use blueprint_sdk::alloy::sol_types::{SolEvent, SolValue};
+
+/// Operator the fixtures target by default.
+pub const OPERATOR: Address = Address::repeat_byte(1);
+/// Oracle contract emitting challenge events by default.
+pub const ORACLE: Address = Address::repeat_byte(2);
+/// Registry coordinator emitting churn events by default.
+pub const REGISTRY_COORDINATOR: Address = Address::repeat_byte(3);
+/// Service manager emitting reimbursement events by default.
+pub const SERVICE_MANAGER: Address = Address::repeat_byte(4);
+/// Response window of challenges built without [`ChallengeEventFixture::window`].
+pub const DEFAULT_WINDOW_BLOCKS: u64 = 50;
+
+/// The canonical hash fixtures give block `number`.
+pub fn block_hash(number: u64) -> B256 {
+    B256::from(U256::from(number))
+}
+
+/// Position of a fixture log in the chain.
+#[derive(Clone, Debug)]
+struct Position {
+    block: u64,
+    block_hash: Option<B256>,
+    transaction_hash: Option<B256>,
+    log_index: u64,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self {
+            block: 1,
+            block_hash: Some(block_hash(1)),
+            transaction_hash: None,
+            log_index: 0,
+        }
+    }
+}
+
+impl Position {
+    fn log(&self, address: Address, data: LogData) -> Log {
+        Log {
+            inner: primitives::Log { address, data },
+            block_hash: self.block_hash,
+            block_number: Some(self.block),
+            transaction_hash: self.transaction_hash,
+            log_index: Some(self.log_index),
+            ..Default::default()
+        }
+    }
+}
+
+/// Implements the builder methods placing a fixture log in the chain.
+macro_rules! positioned {
+    ($fixture:ty) => {
+        impl $fixture {
+            /// Places the log in block `number`, with [`block_hash`]`(number)` as its hash.
+            pub fn block(mut self, number: u64) -> Self {
+                self.position.block = number;
+                self.position.block_hash = Some(block_hash(number));
+                self
+            }
+
+            /// Overrides the block hash set by [`Self::block`]; `None` models a pending log.
+            pub fn block_hash(mut self, hash: Option<B256>) -> Self {
+                self.position.block_hash = hash;
+                self
+            }
+
+            pub fn transaction_hash(mut self, hash: B256) -> Self {
+                self.position.transaction_hash = Some(hash);
+                self
+            }
+
+            pub fn log_index(mut self, index: u64) -> Self {
+                self.position.log_index = index;
+                self
+            }
+        }
+    };
+}
+
+/// An `SlaChallengeIssued` event.
+#[derive(Clone, Debug)]
+pub struct ChallengeEventFixture {
+    id: U256,
+    operator: Address,
+    oracle: Address,
+    data: Bytes,
+    window_blocks: u64,
+    position: Position,
+}
+
+impl Default for ChallengeEventFixture {
+    fn default() -> Self {
+        Self {
+            id: U256::from(1),
+            operator: OPERATOR,
+            oracle: ORACLE,
+            data: Bytes::new(),
+            window_blocks: DEFAULT_WINDOW_BLOCKS,
+            position: Position::default(),
+        }
+    }
+}
+
+positioned!(ChallengeEventFixture);
+
+impl ChallengeEventFixture {
+    /// Challenge 1 for [`OPERATOR`] in block 1, with unversioned (liveness) data.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn id(mut self, id: u64) -> Self {
+        self.id = U256::from(id);
+        self
+    }
+
+    pub fn operator(mut self, operator: Address) -> Self {
+        self.operator = operator;
+        self
+    }
+
+    pub fn oracle(mut self, oracle: Address) -> Self {
+        self.oracle = oracle;
+        self
+    }
+
+    /// Versioned challenge data of `kind_name` at `version`.
+    pub fn kind(mut self, kind_name: &str, version: u32, params: impl Into<Bytes>) -> Self {
+        self.data = envelope(kind_name, version, params.into());
+        self
+    }
+
+    /// Raw challenge data.
+    pub fn data(mut self, data: impl Into<Bytes>) -> Self {
+        self.data = data.into();
+        self
+    }
+
+    /// Response window length; the deadline is the issuing block plus `blocks`.
+    pub fn window(mut self, blocks: u64) -> Self {
+        self.window_blocks = blocks;
+        self
+    }
+
+    pub fn deadline_block(&self) -> u64 {
+        self.position.block + self.window_blocks
+    }
+
+    pub fn build_event(&self) -> SlaChallengeIssued {
+        SlaChallengeIssued {
+            challengeId: self.id,
+            operator: self.operator,
+            challengeData: self.data.clone(),
+            responseWindowEndBlock: U256::from(self.deadline_block()),
+        }
+    }
+
+    pub fn build_log(&self) -> Log {
+        self.position
+            .log(self.oracle, self.build_event().encode_log_data())
+    }
+
+    /// The challenge [`crate::challenge::decode_challenge`] yields for [`Self::build_log`].
+    pub fn build_observed(&self) -> ObservedChallenge {
+        ObservedChallenge {
+            challenge_id: self.id,
+            operator: self.operator,
+            challenge_data: self.data.clone(),
+            deadline_block: self.deadline_block(),
+            oracle: self.oracle,
+            issued_block: self.position.block,
+            issued_block_hash: self.position.block_hash,
+            transaction_hash: self.position.transaction_hash,
+        }
+    }
+}
+
+/// An `SlaChallengeResponded` event.
+#[derive(Clone, Debug)]
+pub struct ResponseEventFixture {
+    id: U256,
+    operator: Address,
+    oracle: Address,
+    response: Bytes,
+    position: Position,
+}
+
+impl Default for ResponseEventFixture {
+    fn default() -> Self {
+        Self {
+            id: U256::from(1),
+            operator: OPERATOR,
+            oracle: ORACLE,
+            response: Bytes::new(),
+            position: Position::default(),
+        }
+    }
+}
+
+positioned!(ResponseEventFixture);
+
+impl ResponseEventFixture {
+    /// [`OPERATOR`]'s empty response to challenge 1, in block 1.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn id(mut self, id: u64) -> Self {
+        self.id = U256::from(id);
+        self
+    }
+
+    pub fn operator(mut self, operator: Address) -> Self {
+        self.operator = operator;
+        self
+    }
+
+    pub fn oracle(mut self, oracle: Address) -> Self {
+        self.oracle = oracle;
+        self
+    }
+
+    pub fn response(mut self, response: impl Into<Bytes>) -> Self {
+        self.response = response.into();
+        self
+    }
+
+    pub fn build_event(&self) -> SlaChallengeResponded {
+        SlaChallengeResponded {
+            challengeId: self.id,
+            operator: self.operator,
+            responseData: self.response.clone(),
+        }
+    }
+
+    pub fn build_log(&self) -> Log {
+        self.position
+            .log(self.oracle, self.build_event().encode_log_data())
+    }
+}
+
+/// An `SlaResponseRejected` event.
+#[derive(Clone, Debug)]
+pub struct RejectionEventFixture {
+    id: U256,
+    operator: Address,
+    oracle: Address,
+    reason_code: u8,
+    position: Position,
+}
+
+positioned!(RejectionEventFixture);
+
+impl RejectionEventFixture {
+    /// [`ORACLE`] rejecting [`OPERATOR`]'s response to challenge `id` as empty, in block 1.
+    pub fn new(id: u64) -> Self {
+        Self {
+            id: U256::from(id),
+            operator: OPERATOR,
+            oracle: ORACLE,
+            reason_code: 1,
+            position: Position::default(),
+        }
+    }
+
+    pub fn operator(mut self, operator: Address) -> Self {
+        self.operator = operator;
+        self
+    }
+
+    pub fn oracle(mut self, oracle: Address) -> Self {
+        self.oracle = oracle;
+        self
+    }
+
+    pub fn reason_code(mut self, code: u8) -> Self {
+        self.reason_code = code;
+        self
+    }
+
+    pub fn build_log(&self) -> Log {
+        let event = SlaResponseRejected {
+            challengeId: self.id,
+            operator: self.operator,
+            reasonCode: self.reason_code,
+        };
+        self.position.log(self.oracle, event.encode_log_data())
+    }
+}
+
+/// A `ReimbursementCredited` event of the service manager.
+#[derive(Clone, Debug)]
+pub struct ReimbursementEventFixture {
+    challenge_id: U256,
+    operator: Address,
+    amount: U256,
+    service_manager: Address,
+    position: Position,
+}
+
+positioned!(ReimbursementEventFixture);
+
+impl ReimbursementEventFixture {
+    /// [`SERVICE_MANAGER`] crediting [`OPERATOR`] `amount` wei for its response to challenge
+    /// `id`, in block 1.
+    pub fn credited(id: u64, amount: u128) -> Self {
+        Self {
+            challenge_id: U256::from(id),
+            operator: OPERATOR,
+            amount: U256::from(amount),
+            service_manager: SERVICE_MANAGER,
+            position: Position::default(),
+        }
+    }
+
+    pub fn operator(mut self, operator: Address) -> Self {
+        self.operator = operator;
+        self
+    }
+
+    pub fn service_manager(mut self, service_manager: Address) -> Self {
+        self.service_manager = service_manager;
+        self
+    }
+
+    pub fn build_log(&self) -> Log {
+        let event = ReimbursementCredited {
+            operator: self.operator,
+            challengeId: self.challenge_id,
+            amount: self.amount,
+        };
+        self.position
+            .log(self.service_manager, event.encode_log_data())
+    }
+}
+
+/// An `SlaChallengeCancelled` or `SlaChallengeAmended` event.
+#[derive(Clone, Debug)]
+pub struct ChallengeUpdateFixture {
+    id: U256,
+    oracle: Address,
+    /// The new data and deadline of an amendment; `None` cancels.
+    amended: Option<(Bytes, u64)>,
+    position: Position,
+}
+
+positioned!(ChallengeUpdateFixture);
+
+impl ChallengeUpdateFixture {
+    /// [`ORACLE`] cancelling challenge `id`, in block 1.
+    pub fn cancelled(id: u64) -> Self {
+        Self {
+            id: U256::from(id),
+            oracle: ORACLE,
+            amended: None,
+            position: Position::default(),
+        }
+    }
+
+    /// [`ORACLE`] amending challenge `id` to `data`, answered by `deadline_block`, in block 1.
+    pub fn amended(id: u64, data: impl Into<Bytes>, deadline_block: u64) -> Self {
+        Self {
+            amended: Some((data.into(), deadline_block)),
+            ..Self::cancelled(id)
+        }
+    }
+
+    pub fn oracle(mut self, oracle: Address) -> Self {
+        self.oracle = oracle;
+        self
+    }
+
+    pub fn build_log(&self) -> Log {
+        let data = match &self.amended {
+            None => SlaChallengeCancelled {
+                challengeId: self.id,
+            }
+            .encode_log_data(),
+            Some((data, deadline_block)) => SlaChallengeAmended {
+                challengeId: self.id,
+                newData: data.clone(),
+                newDeadline: U256::from(*deadline_block),
+            }
+            .encode_log_data(),
+        };
+        self.position.log(self.oracle, data)
+    }
+}
+
+/// An `OperatorRegistered` or `OperatorDeregistered` event of the registry coordinator.
+#[derive(Clone, Debug)]
+pub struct RegistryEventFixture {
+    registered: bool,
+    operator: Address,
+    operator_id: B256,
+    coordinator: Address,
+    position: Position,
+}
+
+positioned!(RegistryEventFixture);
+
+impl RegistryEventFixture {
+    pub fn registered(operator: Address) -> Self {
+        Self {
+            registered: true,
+            operator,
+            operator_id: operator.into_word(),
+            coordinator: REGISTRY_COORDINATOR,
+            position: Position::default(),
+        }
+    }
+
+    pub fn deregistered(operator: Address) -> Self {
+        Self {
+            registered: false,
+            ..Self::registered(operator)
+        }
+    }
+
+    pub fn operator_id(mut self, operator_id: B256) -> Self {
+        self.operator_id = operator_id;
+        self
+    }
+
+    pub fn coordinator(mut self, coordinator: Address) -> Self {
+        self.coordinator = coordinator;
+        self
+    }
+
+    pub fn build_log(&self) -> Log {
+        let data = if self.registered {
+            OperatorRegistered {
+                operator: self.operator,
+                operatorId: self.operator_id,
+            }
+            .encode_log_data()
+        } else {
+            OperatorDeregistered {
+                operator: self.operator,
+                operatorId: self.operator_id,
+            }
+            .encode_log_data()
+        };
+        self.position.log(self.coordinator, data)
+    }
+}
+
+/// An EIP-1967 `Upgraded` event.
+#[derive(Clone, Debug)]
+pub struct UpgradeEventFixture {
+    proxy: Address,
+    implementation: Address,
+    position: Position,
+}
+
+positioned!(UpgradeEventFixture);
+
+impl UpgradeEventFixture {
+    pub fn new(proxy: Address, implementation: Address) -> Self {
+        Self {
+            proxy,
+            implementation,
+            position: Position::default(),
+        }
+    }
+
+    pub fn build_log(&self) -> Log {
+        let event = Upgraded {
+            implementation: self.implementation,
+        };
+        self.position.log(self.proxy, event.encode_log_data())
+    }
+}
+
+/// A heartbeat evidence record.
+#[derive(Clone, Debug)]
+pub struct HeartbeatFixture {
+    evidence: HeartbeatEvidence,
+}
+
+impl Default for HeartbeatFixture {
+    fn default() -> Self {
+        Self {
+            evidence: HeartbeatEvidence {
+                unix_ms: 0,
+                live: Some(true),
+                in_maintenance: false,
+                health_score: Some(100),
+                healthy: Some(true),
+            },
+        }
+    }
+}
+
+impl HeartbeatFixture {
+    /// A live, fully healthy heartbeat outside maintenance, at time 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn at(mut self, unix_ms: u64) -> Self {
+        self.evidence.unix_ms = unix_ms;
+        self
+    }
+
+    pub fn live(mut self, live: bool) -> Self {
+        self.evidence.live = Some(live);
+        self
+    }
+
+    /// The liveness check itself failed.
+    pub fn check_failed(mut self) -> Self {
+        self.evidence.live = None;
+        self
+    }
+
+    pub fn in_maintenance(mut self) -> Self {
+        self.evidence.in_maintenance = true;
+        self
+    }
+
+    pub fn build(&self) -> HeartbeatEvidence {
+        self.evidence.clone()
+    }
+}
+
+/// MRTD of fixture TDX quotes.
+pub const TDX_MR_TD: [u8; 48] = [0x5a; 48];
+/// MRENCLAVE of fixture SGX quotes.
+pub const SGX_MR_ENCLAVE: [u8; 32] = [0xe1; 32];
+/// MRSIGNER of fixture SGX quotes.
+pub const SGX_MR_SIGNER: [u8; 32] = [0x51; 32];
+
+/// A TDX (version 4) or SGX (version 3) quote, with empty signature data.
+#[derive(Clone, Debug)]
+pub enum QuoteFixture {
+    Tdx(TdxQuote),
+    Sgx(SgxQuote),
+}
+
+impl QuoteFixture {
+    /// A TDX quote of [`TDX_MR_TD`], RTMR `i` filled with `0x31 + i`, and zero report data.
+    pub fn tdx() -> Self {
+        Self::Tdx(TdxQuote {
+            version: 4,
+            tee_type: TDX_TEE_TYPE,
+            mr_td: TDX_MR_TD,
+            rtmrs: std::array::from_fn(|i| [0x31 + i as u8; 48]),
+            report_data: [0; 64],
+        })
+    }
+
+    /// An SGX quote of [`SGX_MR_ENCLAVE`] and [`SGX_MR_SIGNER`] at product 1, SVN 2, with zero
+    /// report data.
+    pub fn sgx() -> Self {
+        Self::Sgx(SgxQuote {
+            version: 3,
+            mr_enclave: SGX_MR_ENCLAVE,
+            mr_signer: SGX_MR_SIGNER,
+            isv_prod_id: 1,
+            isv_svn: 2,
+            report_data: [0; 64],
+        })
+    }
+
+    pub fn report_data(mut self, report_data: [u8; 64]) -> Self {
+        match &mut self {
+            Self::Tdx(quote) => quote.report_data = report_data,
+            Self::Sgx(quote) => quote.report_data = report_data,
+        }
+        self
+    }
+
+    /// Sets the ISV SVN of an SGX quote.
+    pub fn isv_svn(mut self, isv_svn: u16) -> Self {
+        if let Self::Sgx(quote) = &mut self {
+            quote.isv_svn = isv_svn;
+        }
+        self
+    }
+
+    pub fn platform(&self) -> TeePlatform {
+        match self {
+            Self::Tdx(_) => TeePlatform::Tdx,
+            Self::Sgx(_) => TeePlatform::Sgx,
+        }
+    }
+
+    /// The digest the oracle's policy allow-lists for this quote.
+    pub fn measurement(&self) -> B256 {
+        match self {
+            Self::Tdx(quote) => quote.measurement(),
+            Self::Sgx(quote) => quote.measurement(),
+        }
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        match self {
+            Self::Tdx(quote) => quote.to_bytes(),
+            Self::Sgx(quote) => quote.to_bytes(),
+        }
+    }
+}
+
+/// What a generated log was built as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GeneratedKind {
+    Challenge,
+    Response,
+    Registry,
+    Upgrade,
+}
+
+#[derive(Clone, Debug)]
+pub struct GeneratedEvent {
+    pub kind: GeneratedKind,
+    pub log: Log,
+    /// `false` if the log was corrupted after encoding and must not decode.
+    pub valid: bool,
+    /// For valid challenges, what decoding must yield.
+    pub expected: Option<ObservedChallenge>,
+}
+
+/// Seeded generator of mixed valid and malformed event batches.
+///
+/// The same seed always yields the same batches, so a failing property test can be replayed.
+#[derive(Clone, Debug)]
+pub struct EventGenerator {
+    state: u64,
+    block: u64,
+    next_id: u64,
+}
+
+impl EventGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            block: 1,
+            next_id: 1,
+        }
+    }
+
+    /// `len` logs in ascending blocks, about a third of them malformed.
+    pub fn batch(&mut self, len: usize) -> Vec<GeneratedEvent> {
+        let mut events = Vec::with_capacity(len);
+        let mut log_index = 0;
+        for _ in 0..len {
+            if self.below(3) == 0 {
+                self.block += 1 + self.below(3);
+                log_index = 0;
+            }
+            let mut event = self.valid_event(log_index);
+            if self.below(3) == 0 {
+                self.corrupt(&mut event);
+            }
+            events.push(event);
+            log_index += 1;
+        }
+        events
+    }
+
+    fn valid_event(&mut self, log_index: u64) -> GeneratedEvent {
+        let operator = if self.below(4) == 0 {
+            Address::from_word(B256::from(self.word()))
+        } else {
+            OPERATOR
+        };
+        let block = self.block;
+        let tx = B256::from(self.word());
+        let (kind, log, expected) = match self.below(5) {
+            0 | 1 => {
+                let id = self.next_id;
+                self.next_id += 1;
+                let fixture = ChallengeEventFixture::new()
+                    .id(id)
+                    .operator(operator)
+                    .window(1 + self.below(100));
+                let fixture = match self.below(3) {
+                    0 => fixture,
+                    1 => fixture.kind(
+                        TEE_COMPUTE_KIND,
+                        1,
+                        ComputeChallengeV1 {
+                            programId: B256::from(self.word()),
+                            params: self.bytes(64).into(),
+                        }
+                        .abi_encode_params(),
+                    ),
+                    _ => fixture.kind(
+                        ATTESTATION_KIND,
+                        1,
+                        AttestationChallengeV1 {
+                            nonce: B256::from(self.word()),
+                        }
+                        .abi_encode_params(),
+                    ),
+                };
+                let fixture = fixture
+                    .block(block)
+                    .transaction_hash(tx)
+                    .log_index(log_index);
+                (
+                    GeneratedKind::Challenge,
+                    fixture.build_log(),
+                    Some(fixture.build_observed()),
+                )
+            }
+            2 => {
+                let id = 1 + self.below(self.next_id);
+                let fixture = ResponseEventFixture::new()
+                    .id(id)
+                    .operator(operator)
+                    .response(self.bytes(96))
+                    .block(block)
+                    .transaction_hash(tx)
+                    .log_index(log_index);
+                (GeneratedKind::Response, fixture.build_log(), None)
+            }
+            3 => {
+                let fixture = if self.below(2) == 0 {
+                    RegistryEventFixture::registered(operator)
+                } else {
+                    RegistryEventFixture::deregistered(operator)
+                };
+                let fixture = fixture
+                    .block(block)
+                    .transaction_hash(tx)
+                    .log_index(log_index);
+                (GeneratedKind::Registry, fixture.build_log(), None)
+            }
+            _ => {
+                let fixture =
+                    UpgradeEventFixture::new(ORACLE, Address::from_word(self.word().into()))
+                        .block(block)
+                        .transaction_hash(tx)
+                        .log_index(log_index);
+                (GeneratedKind::Upgrade, fixture.build_log(), None)
+            }
+        };
+        GeneratedEvent {
+            kind,
+            log,
+            valid: true,
+            expected,
+        }
+    }
+
+    /// Breaks `event` so that it no longer decodes as what it was built as.
+    fn corrupt(&mut self, event: &mut GeneratedEvent) {
+        let topics = event.log.inner.data.topics().to_vec();
+        let data = event.log.inner.data.data.clone();
+        let (topics, data) = if data.len() >= 32 && self.below(2) == 0 {
+            // Cut at least one word, so the encoding is always short.
+            let len = self.below(data.len() as u64 - 31) as usize;
+            (topics, data.slice(..len))
+        } else if data.len() >= 32 && self.below(2) == 0 {
+            let len = self.below(160) as usize;
+            (topics, Bytes::from(self.bytes(len)))
+        } else {
+            // Drop an indexed argument.
+            (topics[..topics.len() - 1].to_vec(), data)
+        };
+        event.log.inner.data = LogData::new_unchecked(topics, data);
+        event.valid = false;
+        event.expected = None;
+    }
+
+    /// SplitMix64.
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound.max(1)
+    }
+
+    fn word(&mut self) -> [u8; 32] {
+        let mut word = [0u8; 32];
+        for chunk in word.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_be_bytes());
+        }
+        word
+    }
+
+    fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let len = self.below(max_len as u64 + 1) as usize;
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::decode_challenge;
+    use crate::encoding::SchemaKey;
+    use crate::operator_set::is_registry_event;
+    use crate::upgrade::{WatchedContract, is_upgrade_event};
+
+    #[test]
+    fn fixtures_decode_through_the_real_decoders() {
+        let params = AttestationChallengeV1 {
+            nonce: B256::repeat_byte(0x42),
+        }
+        .abi_encode_params();
+        let challenge = ChallengeEventFixture::new()
+            .id(7)
+            .kind(ATTESTATION_KIND, 1, params)
+            .window(30)
+            .block(12)
+            .transaction_hash(B256::repeat_byte(9))
+            .log_index(3);
+        let log = challenge.build_log();
+        let observed = decode_challenge(&log).unwrap();
+        assert_eq!(observed, challenge.build_observed());
+        assert_eq!(observed.deadline_block, 42);
+        assert_eq!(
+            SchemaKey::of(&observed),
+            SchemaKey::new(ATTESTATION_KIND, 1)
+        );
+        assert_eq!(log.log_index, Some(3));
+
+        let response = ResponseEventFixture::new().id(7).response(vec![1, 2, 3]);
+        let decoded = response
+            .build_log()
+            .log_decode::<SlaChallengeResponded>()
+            .unwrap()
+            .inner
+            .data;
+        assert_eq!(decoded.challengeId, U256::from(7));
+        assert_eq!(decoded.responseData, Bytes::from(vec![1, 2, 3]));
+        assert!(decode_challenge(&response.build_log()).is_none());
+
+        for churn in [
+            RegistryEventFixture::registered(OPERATOR),
+            RegistryEventFixture::deregistered(OPERATOR),
+        ] {
+            assert!(is_registry_event(&churn.build_log(), REGISTRY_COORDINATOR));
+            assert!(!is_registry_event(&churn.build_log(), ORACLE));
+        }
+
+        let proxy = Address::repeat_byte(5);
+        let upgrade = UpgradeEventFixture::new(proxy, Address::repeat_byte(6)).build_log();
+        assert!(is_upgrade_event(&upgrade, &[WatchedContract::new(
+            "oracle", proxy
+        )]));
+
+        let heartbeat = HeartbeatFixture::new().at(5_000).check_failed().build();
+        let json = serde_json::to_vec(&heartbeat).unwrap();
+        let decoded: HeartbeatEvidence = serde_json::from_slice(&json).unwrap();
+        assert_eq!((decoded.unix_ms, decoded.live), (5_000, None));
+    }
+
+    #[test]
+    fn decoders_never_panic_on_generated_batches() {
+        let oracle = [WatchedContract::new("oracle", ORACLE)];
+        for seed in 0..64 {
+            let mut generator = EventGenerator::new(seed);
+            let batch = generator.batch(64);
+            assert!(batch.iter().any(|e| !e.valid), "seed {seed}");
+            for event in batch {
+                let decoded = decode_challenge(&event.log);
+                let registry = is_registry_event(&event.log, REGISTRY_COORDINATOR);
+                let upgrade = is_upgrade_event(&event.log, &oracle);
+                let (is_registry, is_upgrade) = match event.kind {
+                    GeneratedKind::Challenge if event.valid => {
+                        assert_eq!(decoded, event.expected);
+                        (false, false)
+                    }
+                    GeneratedKind::Registry => (event.valid, false),
+                    GeneratedKind::Upgrade => (false, event.valid),
+                    _ => (false, false),
+                };
+                if event.kind != GeneratedKind::Challenge || !event.valid {
+                    assert!(decoded.is_none(), "seed {seed}: {:?}", event.log);
+                }
+                assert_eq!(
+                    (registry, upgrade),
+                    (is_registry, is_upgrade),
+                    "seed {seed}"
+                );
+            }
+        }
+        // Seeds replay exactly.
+        let a = EventGenerator::new(7).batch(16);
+        let b = EventGenerator::new(7).batch(16);
+        assert!(a.iter().zip(&b).all(|(a, b)| a.log == b.log));
+    }
+}
@@ -0,0 +1,706 @@
+//! Freshness of the evidence workloads push, and the gaps in it.
+//!
+//! Each workload is expected to push at a cadence, either declared with `EVIDENCE_CADENCE_SECS`
+//! (`<workload id>=<secs>,...`) or learned as the median interval between its recent pushes, once
+//! it has pushed `EVIDENCE_CADENCE_MIN_PUSHES` times. Every `EVIDENCE_GAP_CHECK_SECS`, a workload
+//! silent for longer than `EVIDENCE_GAP_MULTIPLE` times its cadence, and at least
+//! `EVIDENCE_GAP_MIN_SECS`, has a gap opened: it is marked in the evidence store (see
+//! [`crate::evidence::gaps`]), so range evidence over it is known to be partial, and alerted with
+//! the workload and how long it has been silent, before a challenge over that range arrives. The
+//! gap closes with the workload's next push.
+//!
+//! Pushes are read back from the evidence log, so a restart loses nothing: the first check
+//! replays the last `EVIDENCE_CADENCE_HISTORY_SECS` of pushes and picks up the gaps still open.
+//! Inside a maintenance window covering the workload no gap is opened, and silence is counted
+//! from the window's end. Workloads no longer assigned to the operator on-chain are
+//! decommissioned: they are not alerted, and their open gaps are closed.
+
+use crate::config::{self, env_flag, env_or};
+use crate::drift::WorkloadAssignments;
+use crate::error::PhalaAvsError;
+use crate::evidence::gaps::EvidenceGap;
+use crate::evidence::{EvidenceLog, WORKLOAD_EVIDENCE, now_unix_ms, record_time};
+use crate::ingestion::WorkloadEvidence;
+use crate::maintenance::MaintenanceSchedule;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use blueprint_sdk::alloy::primitives::{Address, B256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Gauge: seconds since each workload's last push.
+pub const EVIDENCE_AGE_METRIC: &str = "phala_avs_evidence_age_seconds";
+/// Counter of gaps opened, by workload.
+pub const EVIDENCE_GAPS_METRIC: &str = "phala_avs_evidence_gaps_total";
+
+/// Pushes kept per workload to learn its cadence from.
+const CADENCE_WINDOW: usize = 32;
+/// How far behind the last check pushes are looked for, as records are keyed by the time they
+/// were received, not appended.
+const APPEND_LAG_MS: u64 = 60_000;
+
+#[derive(Clone, Debug)]
+pub struct FreshnessConfig {
+    pub enabled: bool,
+    pub check_secs: u64,
+    /// Silence, in cadences, after which a gap is opened.
+    pub gap_multiple: f64,
+    /// Shortest silence treated as a gap, however short the cadence.
+    pub min_gap_secs: u64,
+    /// Pushes needed before a cadence is learned.
+    pub min_pushes: usize,
+    /// Pushes replayed on the first check.
+    pub history_secs: u64,
+    /// Declared cadences, in seconds, taking precedence over learned ones.
+    pub declared: BTreeMap<B256, u64>,
+}
+
+impl Default for FreshnessConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            check_secs: 60,
+            gap_multiple: 3.0,
+            min_gap_secs: 300,
+            min_pushes: 5,
+            history_secs: 24 * 3600,
+            declared: BTreeMap::new(),
+        }
+    }
+}
+
+impl FreshnessConfig {
+    /// Reads `EVIDENCE_GAP_*`, `EVIDENCE_CADENCE_*` and `EVIDENCE_CADENCE_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        let mut declared = BTreeMap::new();
+        let raw = config::lookup("EVIDENCE_CADENCE_SECS").unwrap_or_default();
+        for item in raw.split(',').filter(|s| !s.trim().is_empty()) {
+            let (id, secs) = item.split_once('=').ok_or_else(|| {
+                PhalaAvsError::ConfigError(format!(
+                    "Invalid EVIDENCE_CADENCE_SECS entry {item:?}, expected <workload id>=<secs>"
+                ))
+            })?;
+            let id = id.trim().parse().map_err(|e| {
+                PhalaAvsError::ConfigError(format!(
+                    "Invalid workload id in EVIDENCE_CADENCE_SECS: {e}"
+                ))
+            })?;
+            let secs = secs
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|s| *s > 0)
+                .ok_or_else(|| {
+                    PhalaAvsError::ConfigError(format!(
+                        "Invalid cadence in EVIDENCE_CADENCE_SECS entry {item:?}"
+                    ))
+                })?;
+            declared.insert(id, secs);
+        }
+        let gap_multiple: f64 = env_or("EVIDENCE_GAP_MULTIPLE", defaults.gap_multiple)?;
+        if gap_multiple.is_nan() || gap_multiple < 1.0 {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "EVIDENCE_GAP_MULTIPLE must be at least 1, got {gap_multiple}"
+            )));
+        }
+        Ok(Self {
+            enabled: env_flag("EVIDENCE_GAP_CHECK_ENABLED", defaults.enabled)?,
+            check_secs: env_or("EVIDENCE_GAP_CHECK_SECS", defaults.check_secs)?.max(1),
+            gap_multiple,
+            min_gap_secs: env_or("EVIDENCE_GAP_MIN_SECS", defaults.min_gap_secs)?,
+            min_pushes: env_or("EVIDENCE_CADENCE_MIN_PUSHES", defaults.min_pushes)?
+                .clamp(2, CADENCE_WINDOW),
+            history_secs: env_or("EVIDENCE_CADENCE_HISTORY_SECS", defaults.history_secs)?,
+            declared,
+        })
+    }
+}
+
+/// Where a workload's cadence comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CadenceSource {
+    Declared,
+    Learned,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FreshnessState {
+    Fresh,
+    /// Too few pushes yet to learn a cadence from.
+    Learning,
+    /// A gap is open.
+    Gap,
+    Maintenance,
+    Decommissioned,
+}
+
+/// A row of the freshness table on `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkloadFreshness {
+    pub workload_id: B256,
+    pub state: FreshnessState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_push_unix_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cadence_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cadence_source: Option<CadenceSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gap_since_unix_ms: Option<u64>,
+}
+
+#[derive(Default)]
+struct Tracked {
+    /// Most recent push times, oldest first.
+    pushes: VecDeque<u64>,
+    gap: Option<EvidenceGap>,
+}
+
+#[derive(Default)]
+struct State {
+    /// When the first check ran; silence of declared workloads that never pushed counts from it.
+    started_ms: Option<u64>,
+    /// Pushes before this have been read.
+    cursor_ms: u64,
+    workloads: BTreeMap<B256, Tracked>,
+    /// Workloads assigned to the operator, once read.
+    assigned: Option<BTreeSet<B256>>,
+}
+
+/// Watches each workload's pushes for gaps.
+pub struct FreshnessMonitor {
+    config: FreshnessConfig,
+    operator: Address,
+    log: EvidenceLog,
+    maintenance: Arc<MaintenanceSchedule>,
+    assignments: Option<Arc<dyn WorkloadAssignments>>,
+    notifier: Arc<dyn Notifier>,
+    state: Mutex<State>,
+}
+
+impl FreshnessMonitor {
+    pub fn new(
+        config: FreshnessConfig,
+        log: EvidenceLog,
+        maintenance: Arc<MaintenanceSchedule>,
+        notifier: Arc<dyn Notifier>,
+    ) -> Self {
+        Self {
+            config,
+            operator: Address::ZERO,
+            log,
+            maintenance,
+            assignments: None,
+            notifier,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Treats workloads missing from `operator`'s assignments as decommissioned.
+    pub fn with_assignments(
+        mut self,
+        operator: Address,
+        assignments: Arc<dyn WorkloadAssignments>,
+    ) -> Self {
+        self.operator = operator;
+        self.assignments = Some(assignments);
+        self
+    }
+
+    pub fn config(&self) -> &FreshnessConfig {
+        &self.config
+    }
+
+    /// Reads new pushes, closes the gaps they end and opens gaps for workloads gone silent.
+    /// Returns the gaps opened.
+    pub async fn check(&self, now_ms: u64) -> Result<Vec<EvidenceGap>, PhalaAvsError> {
+        let assigned = match &self.assignments {
+            Some(assignments) => match assignments.assigned(self.operator).await {
+                Ok(assigned) => Some(assigned.into_iter().collect()),
+                Err(e) => {
+                    warn!("Failed to read workload assignments, keeping the last ones: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        let windows: Vec<_> = self
+            .maintenance
+            .windows()?
+            .into_iter()
+            .filter(|w| !w.cancelled)
+            .collect();
+
+        let mut opened = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if assigned.is_some() {
+                state.assigned = assigned;
+            }
+            if state.started_ms.is_none() {
+                state.started_ms = Some(now_ms);
+                state.cursor_ms = now_ms.saturating_sub(self.config.history_secs * 1000);
+                for gap in self.log.gaps(0, u64::MAX)? {
+                    if gap.to_ms.is_none() {
+                        state.workloads.entry(gap.workload_id).or_default().gap = Some(gap);
+                    }
+                }
+            }
+            self.read_pushes(&mut state, now_ms)?;
+            for id in self.config.declared.keys() {
+                state.workloads.entry(*id).or_default();
+            }
+
+            let started_ms = state.started_ms.unwrap_or(now_ms);
+            let State {
+                workloads,
+                assigned,
+                ..
+            } = &mut *state;
+            for (id, tracked) in workloads.iter_mut() {
+                if assigned.as_ref().is_some_and(|a| !a.contains(id)) {
+                    if let Some(mut gap) = tracked.gap.take() {
+                        gap.to_ms = Some(now_ms);
+                        self.log.mark_gap(&gap)?;
+                        info!("Closed the evidence gap of decommissioned workload {id}");
+                    }
+                    continue;
+                }
+                if tracked.gap.is_some() {
+                    continue;
+                }
+                let Some((cadence_ms, _)) = self.cadence(id, tracked) else {
+                    continue;
+                };
+                // Silence starts at the last push, or the end of maintenance after it.
+                let mut since = tracked.pushes.back().copied().unwrap_or(started_ms);
+                let mut in_maintenance = false;
+                for window in windows.iter().filter(|w| w.scope.covers_id(*id)) {
+                    if window.contains(now_ms / 1000) {
+                        in_maintenance = true;
+                    } else if window.from_unix * 1000 <= now_ms {
+                        since = since.max(window.to_unix * 1000);
+                    }
+                }
+                let silent_ms = now_ms.saturating_sub(since);
+                if in_maintenance || silent_ms <= self.threshold_ms(cadence_ms) {
+                    continue;
+                }
+                let gap = EvidenceGap {
+                    workload_id: *id,
+                    from_ms: since,
+                    to_ms: None,
+                    cadence_ms,
+                    detected_unix_ms: now_ms,
+                };
+                self.log.mark_gap(&gap)?;
+                METRICS.inc_counter(EVIDENCE_GAPS_METRIC, &[("workload", &id.to_string())], 1);
+                tracked.gap = Some(gap.clone());
+                opened.push(gap);
+            }
+            self.export(workloads, now_ms);
+        }
+
+        for gap in &opened {
+            let silent_secs = (now_ms - gap.from_ms) / 1000;
+            warn!(
+                "Workload {} has pushed no evidence for {silent_secs}s",
+                gap.workload_id
+            );
+            let alert = Alert::new(
+                "evidence_freshness",
+                Severity::Warning,
+                format!(
+                    "Workload {} has pushed no evidence for {silent_secs}s (expected every {}s); \
+                     challenges over this time will only have partial evidence",
+                    gap.workload_id,
+                    gap.cadence_ms / 1000
+                ),
+            );
+            if let Err(e) = self.notifier.notify(alert).await {
+                warn!("Failed to deliver the evidence gap alert: {e}");
+            }
+        }
+        Ok(opened)
+    }
+
+    /// Freshness of every known workload as of `now_ms`.
+    pub fn status(&self, now_ms: u64) -> Vec<WorkloadFreshness> {
+        let windows = self.maintenance.windows().unwrap_or_default();
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .workloads
+            .iter()
+            .map(|(id, tracked)| {
+                let last = tracked.pushes.back().copied();
+                let cadence = self.cadence(id, tracked);
+                let freshness = if state.assigned.as_ref().is_some_and(|a| !a.contains(id)) {
+                    FreshnessState::Decommissioned
+                } else if tracked.gap.is_some() {
+                    FreshnessState::Gap
+                } else if windows
+                    .iter()
+                    .any(|w| w.contains(now_ms / 1000) && w.scope.covers_id(*id))
+                {
+                    FreshnessState::Maintenance
+                } else if cadence.is_none() {
+                    FreshnessState::Learning
+                } else {
+                    FreshnessState::Fresh
+                };
+                WorkloadFreshness {
+                    workload_id: *id,
+                    state: freshness,
+                    last_push_unix_ms: last,
+                    age_secs: last.map(|last| now_ms.saturating_sub(last) / 1000),
+                    cadence_secs: cadence.map(|(ms, _)| ms / 1000),
+                    cadence_source: cadence.map(|(_, source)| source),
+                    gap_since_unix_ms: tracked.gap.as_ref().map(|gap| gap.from_ms),
+                }
+            })
+            .collect()
+    }
+
+    fn read_pushes(&self, state: &mut State, now_ms: u64) -> Result<(), PhalaAvsError> {
+        let from_ms = state.cursor_ms.saturating_sub(APPEND_LAG_MS);
+        for (key, raw) in self.log.records(WORKLOAD_EVIDENCE, from_ms, now_ms + 1)? {
+            let evidence: WorkloadEvidence = serde_json::from_slice(&raw).map_err(|e| {
+                PhalaAvsError::StorageError(format!("Corrupt workload evidence: {e}"))
+            })?;
+            let at = record_time(&key).unwrap_or(evidence.received_unix_ms);
+            let tracked = state.workloads.entry(evidence.workload_id).or_default();
+            if tracked.pushes.back().is_some_and(|last| *last >= at) {
+                continue;
+            }
+            tracked.pushes.push_back(at);
+            if tracked.pushes.len() > CADENCE_WINDOW {
+                tracked.pushes.pop_front();
+            }
+            if let Some(mut gap) = tracked.gap.take_if(|gap| gap.from_ms < at) {
+                gap.to_ms = Some(at);
+                self.log.mark_gap(&gap)?;
+                info!(
+                    "Workload {} resumed pushing evidence after {}s",
+                    gap.workload_id,
+                    (at - gap.from_ms) / 1000
+                );
+            }
+        }
+        state.cursor_ms = state.cursor_ms.max(now_ms);
+        Ok(())
+    }
+
+    /// The workload's cadence in milliseconds: declared, or the median interval between its
+    /// recent pushes.
+    fn cadence(&self, id: &B256, tracked: &Tracked) -> Option<(u64, CadenceSource)> {
+        if let Some(secs) = self.config.declared.get(id) {
+            return Some((secs * 1000, CadenceSource::Declared));
+        }
+        if tracked.pushes.len() < self.config.min_pushes {
+            return None;
+        }
+        let mut intervals: Vec<u64> = tracked
+            .pushes
+            .iter()
+            .zip(tracked.pushes.iter().skip(1))
+            .map(|(a, b)| b - a)
+            .collect();
+        intervals.sort_unstable();
+        Some((
+            intervals[intervals.len() / 2].max(1),
+            CadenceSource::Learned,
+        ))
+    }
+
+    fn threshold_ms(&self, cadence_ms: u64) -> u64 {
+        ((cadence_ms as f64 * self.config.gap_multiple) as u64).max(self.config.min_gap_secs * 1000)
+    }
+
+    fn export(&self, workloads: &BTreeMap<B256, Tracked>, now_ms: u64) {
+        let ids: Vec<_> = workloads
+            .iter()
+            .filter_map(|(id, tracked)| Some((id.to_string(), *tracked.pushes.back()?)))
+            .collect();
+        let series: Vec<_> = ids
+            .iter()
+            .map(|(id, last)| {
+                (
+                    vec![("workload", id.as_str())],
+                    now_ms.saturating_sub(*last) as f64 / 1000.0,
+                )
+            })
+            .collect();
+        METRICS.replace_gauges(EVIDENCE_AGE_METRIC, &series);
+    }
+}
+
+/// Checks evidence freshness every `EVIDENCE_GAP_CHECK_SECS`.
+pub fn spawn_freshness_monitor(monitor: Arc<FreshnessMonitor>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(monitor.config.check_secs));
+        loop {
+            interval.tick().await;
+            if let Err(e) = monitor.check(now_unix_ms()).await {
+                warn!("Failed to check evidence freshness: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm::BoxFuture;
+    use crate::maintenance::{
+        ExemptionRegistry, MAINTENANCE_NAMESPACE, MaintenanceConfig, MaintenanceWindow,
+        WorkloadScope,
+    };
+    use crate::state::{MemoryStateStore, StateStore, StateStoreExt};
+    use blueprint_sdk::alloy::primitives::U256;
+
+    const T0: u64 = 1_700_000_000_000;
+    const CADENCE_MS: u64 = 60_000;
+
+    struct NoExemptions;
+
+    impl ExemptionRegistry for NoExemptions {
+        fn register(
+            &self,
+            _workload_id: B256,
+            _from_unix: u64,
+            _to_unix: u64,
+        ) -> BoxFuture<'_, Result<(U256, B256), PhalaAvsError>> {
+            unreachable!("windows are written to the store directly")
+        }
+
+        fn cancel(&self, _window_id: U256) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            unreachable!("windows are written to the store directly")
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorded(Mutex<Vec<Alert>>);
+
+    impl Notifier for Recorded {
+        fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.0.lock().unwrap().push(alert);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    struct Assigned(Vec<B256>);
+
+    impl WorkloadAssignments for Assigned {
+        fn assigned(&self, _operator: Address) -> BoxFuture<'_, Result<Vec<B256>, PhalaAvsError>> {
+            let assigned = self.0.clone();
+            Box::pin(async move { Ok(assigned) })
+        }
+    }
+
+    struct Fixture {
+        store: Arc<dyn StateStore>,
+        log: EvidenceLog,
+        maintenance: Arc<MaintenanceSchedule>,
+        alerts: Arc<Recorded>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+            Self {
+                log: EvidenceLog::new(Arc::clone(&store)),
+                maintenance: Arc::new(MaintenanceSchedule::new(
+                    MaintenanceConfig::default(),
+                    Arc::clone(&store),
+                    Arc::new(NoExemptions),
+                )),
+                store,
+                alerts: Arc::default(),
+            }
+        }
+
+        fn monitor(&self, config: FreshnessConfig) -> FreshnessMonitor {
+            FreshnessMonitor::new(
+                config,
+                self.log.clone(),
+                Arc::clone(&self.maintenance),
+                Arc::clone(&self.alerts) as _,
+            )
+        }
+
+        fn push(&self, workload_id: B256, at_ms: u64) {
+            let evidence = WorkloadEvidence {
+                workload_id,
+                received_unix_ms: at_ms,
+                kind: "usage".to_string(),
+                observed_unix_ms: None,
+                data: serde_json::Value::Null,
+            };
+            self.log
+                .record(WORKLOAD_EVIDENCE, at_ms, workload_id.as_slice(), &evidence)
+                .unwrap();
+        }
+
+        /// Pushes every minute in `[from_ms, to_ms)`.
+        fn push_every_minute(&self, workload_id: B256, from_ms: u64, to_ms: u64) {
+            for at_ms in (from_ms..to_ms).step_by(CADENCE_MS as usize) {
+                self.push(workload_id, at_ms);
+            }
+        }
+
+        fn alerts(&self) -> Vec<Alert> {
+            self.alerts.0.lock().unwrap().clone()
+        }
+    }
+
+    fn config() -> FreshnessConfig {
+        FreshnessConfig {
+            min_gap_secs: 0,
+            ..FreshnessConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_workload_opens_a_gap_after_three_cadences() {
+        let fixture = Fixture::new();
+        let workload = B256::repeat_byte(0x61);
+        // Minutely pushes, the last at T0 + 9min.
+        fixture.push_every_minute(workload, T0, T0 + 10 * CADENCE_MS);
+        let last = T0 + 9 * CADENCE_MS;
+        let monitor = fixture.monitor(config());
+
+        assert!(
+            monitor
+                .check(last + 3 * CADENCE_MS)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let status = &monitor.status(last + 3 * CADENCE_MS)[0];
+        assert_eq!(status.state, FreshnessState::Fresh);
+        assert_eq!(
+            (status.cadence_secs, status.cadence_source),
+            (Some(60), Some(CadenceSource::Learned))
+        );
+        assert!(fixture.alerts().is_empty());
+
+        let detected = last + 3 * CADENCE_MS + 1_000;
+        let opened = monitor.check(detected).await.unwrap();
+        assert_eq!(opened.len(), 1);
+        assert_eq!(
+            (opened[0].workload_id, opened[0].from_ms, opened[0].to_ms),
+            (workload, last, None)
+        );
+        assert_eq!(fixture.log.gaps(last, detected).unwrap(), opened);
+        let alerts = fixture.alerts();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].severity, Severity::Warning);
+        assert!(alerts[0].message.contains(&workload.to_string()));
+        assert!(alerts[0].message.contains("for 181s"));
+        assert_eq!(monitor.status(detected)[0].state, FreshnessState::Gap);
+
+        // Still silent: the open gap is not alerted again, even after a restart.
+        assert!(
+            monitor
+                .check(detected + CADENCE_MS)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let restarted = fixture.monitor(config());
+        assert!(
+            restarted
+                .check(detected + 2 * CADENCE_MS)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(fixture.alerts().len(), 1);
+
+        // The next push closes the gap in the store.
+        let resumed = detected + 3 * CADENCE_MS;
+        fixture.push(workload, resumed);
+        restarted.check(resumed + 1_000).await.unwrap();
+        let gaps = fixture.log.gaps(0, u64::MAX).unwrap();
+        assert_eq!((gaps[0].from_ms, gaps[0].to_ms), (last, Some(resumed)));
+        assert_eq!(
+            restarted.status(resumed + 1_000)[0].state,
+            FreshnessState::Fresh
+        );
+    }
+
+    #[tokio::test]
+    async fn maintenance_suppresses_gaps_and_restarts_the_silence() {
+        let fixture = Fixture::new();
+        let workload = B256::repeat_byte(0x62);
+        fixture.push_every_minute(workload, T0, T0 + 10 * CADENCE_MS);
+        let last = T0 + 9 * CADENCE_MS;
+        // Maintenance of this workload from one minute after its last push, for half an hour.
+        let (from_unix, to_unix) = ((last + CADENCE_MS) / 1000, (last + 31 * CADENCE_MS) / 1000);
+        let window = MaintenanceWindow {
+            window_id: U256::from(1),
+            scope: WorkloadScope::Workload(workload.to_string()),
+            from_unix,
+            to_unix,
+            registration_tx: B256::ZERO,
+            cancelled: false,
+        };
+        fixture
+            .store
+            .put_json(MAINTENANCE_NAMESPACE, &[1], &window)
+            .unwrap();
+        let monitor = fixture.monitor(config());
+
+        let inside = last + 20 * CADENCE_MS;
+        assert!(monitor.check(inside).await.unwrap().is_empty());
+        assert_eq!(monitor.status(inside)[0].state, FreshnessState::Maintenance);
+        // Silence counts from the end of the window.
+        let end_ms = to_unix * 1000;
+        assert!(
+            monitor
+                .check(end_ms + 3 * CADENCE_MS)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let opened = monitor.check(end_ms + 4 * CADENCE_MS).await.unwrap();
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0].from_ms, end_ms);
+        assert!(fixture.alerts()[0].message.contains("for 240s"));
+    }
+
+    #[tokio::test]
+    async fn decommissioned_workloads_are_not_alerted() {
+        let fixture = Fixture::new();
+        let (kept, gone) = (B256::repeat_byte(0x63), B256::repeat_byte(0x64));
+        fixture.push_every_minute(gone, T0, T0 + 10 * CADENCE_MS);
+        // Declared workloads are watched before their first push.
+        let monitor = fixture
+            .monitor(FreshnessConfig {
+                declared: BTreeMap::from([(kept, 120)]),
+                ..config()
+            })
+            .with_assignments(Address::ZERO, Arc::new(Assigned(vec![kept])));
+
+        monitor.check(T0 + 10 * CADENCE_MS).await.unwrap();
+        let opened = monitor.check(T0 + 17 * CADENCE_MS).await.unwrap();
+        assert_eq!(opened.len(), 1);
+        assert_eq!(
+            (opened[0].workload_id, opened[0].cadence_ms),
+            (kept, 120_000)
+        );
+        let status = monitor.status(T0 + 17 * CADENCE_MS);
+        let states: BTreeMap<_, _> = status.iter().map(|s| (s.workload_id, s.state)).collect();
+        assert_eq!(states[&gone], FreshnessState::Decommissioned);
+        assert_eq!(states[&kept], FreshnessState::Gap);
+        assert_eq!(fixture.alerts().len(), 1);
+    }
+}
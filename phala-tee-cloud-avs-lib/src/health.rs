@@ -0,0 +1,457 @@
+//! Readiness composed from the health of each subsystem.
+//!
+//! Every subsystem contributes its health to a [`HealthRegistry`] through a
+//! [`HealthContributor`], with a default [`Criticality`]: a failing critical contributor makes
+//! `/readyz` fail, a failing `degraded_ok` one only marks the operator degraded, and an
+//! informational one is only reported. `HEALTH_CRITICALITY_<NAME>` overrides a contributor's
+//! criticality; contributors named `<group>:<id>`, like each oracle target, also follow
+//! `HEALTH_CRITICALITY_<GROUP>`. Overrides are read on every evaluation, so a config reload
+//! applies to the next probe.
+//!
+//! The startup stages are contributors too, so `/readyz` and `/status` report the orchestrator's
+//! progress from the same [`HealthReport`].
+
+use crate::config;
+use crate::disk::{DiskBudget, DiskLevel};
+use crate::failure_domain::FailureDomains;
+use crate::ingestion::EvidenceIngestion;
+use crate::registration::RegistrationGate;
+use crate::self_audit::SelfAuditor;
+use crate::startup::{InitState, StartupStatus};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+/// Prefix of the settings overriding a contributor's criticality.
+pub const CRITICALITY_PREFIX: &str = "HEALTH_CRITICALITY_";
+
+/// What a failing contributor means for readiness.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Criticality {
+    /// Failing removes the operator from traffic.
+    Critical,
+    /// Failing leaves the operator ready, but degraded.
+    DegradedOk,
+    /// Failing is only reported.
+    Informational,
+}
+
+impl fmt::Display for Criticality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Critical => "critical",
+            Self::DegradedOk => "degraded_ok",
+            Self::Informational => "informational",
+        })
+    }
+}
+
+impl FromStr for Criticality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "critical" => Ok(Self::Critical),
+            "degraded_ok" | "degraded" => Ok(Self::DegradedOk),
+            "informational" | "info" => Ok(Self::Informational),
+            other => Err(format!(
+                "unknown criticality {other:?}; expected critical, degraded_ok or informational"
+            )),
+        }
+    }
+}
+
+/// The health of one subsystem, with its default criticality.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Contribution {
+    pub name: String,
+    pub criticality: Criticality,
+    pub healthy: bool,
+    /// Why it is unhealthy.
+    pub detail: Option<String>,
+}
+
+impl Contribution {
+    pub fn healthy(name: impl Into<String>, criticality: Criticality) -> Self {
+        Self {
+            name: name.into(),
+            criticality,
+            healthy: true,
+            detail: None,
+        }
+    }
+
+    pub fn failing(
+        name: impl Into<String>,
+        criticality: Criticality,
+        detail: impl Into<String>,
+    ) -> Self {
+        Self {
+            healthy: false,
+            detail: Some(detail.into()),
+            ..Self::healthy(name, criticality)
+        }
+    }
+}
+
+/// Reports the health of one or more subsystems.
+pub trait HealthContributor: Send + Sync {
+    fn contribute(&self) -> Vec<Contribution>;
+}
+
+/// A contributor's health, after overrides.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ContributorHealth {
+    pub name: String,
+    pub criticality: Criticality,
+    /// Whether `criticality` comes from an override.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub overridden: bool,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Every contributor's health, and what it adds up to.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    /// No critical contributor is failing.
+    pub ready: bool,
+    /// Some `degraded_ok` contributor is failing.
+    pub degraded: bool,
+    /// The failing critical and `degraded_ok` contributors.
+    pub failing: Vec<String>,
+    pub contributors: Vec<ContributorHealth>,
+}
+
+impl HealthReport {
+    /// The details of the failing critical contributors.
+    pub fn blockers(&self) -> Vec<String> {
+        self.contributors
+            .iter()
+            .filter(|c| !c.healthy && c.criticality == Criticality::Critical)
+            .map(|c| match &c.detail {
+                Some(detail) => format!("{}: {detail}", c.name),
+                None => c.name.clone(),
+            })
+            .collect()
+    }
+}
+
+/// The setting overriding the criticality of `name`.
+pub fn criticality_key(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+    format!("{CRITICALITY_PREFIX}{name}")
+}
+
+/// The registered contributors.
+#[derive(Default)]
+pub struct HealthRegistry {
+    contributors: RwLock<Vec<Arc<dyn HealthContributor>>>,
+}
+
+impl fmt::Debug for HealthRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HealthRegistry").finish_non_exhaustive()
+    }
+}
+
+impl HealthRegistry {
+    pub fn register(&self, contributor: Arc<dyn HealthContributor>) {
+        self.contributors
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(contributor);
+    }
+
+    /// Evaluates every contributor with the overrides of the installed config.
+    pub fn report(&self) -> HealthReport {
+        self.evaluate(config::lookup)
+    }
+
+    /// Evaluates every contributor, reading overrides through `lookup`.
+    pub fn evaluate(&self, lookup: impl Fn(&str) -> Option<String>) -> HealthReport {
+        let contributors: Vec<_> = self
+            .contributors
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let mut report = HealthReport::default();
+        for contribution in contributors.iter().flat_map(|c| c.contribute()) {
+            let overridden = override_of(&contribution.name, &lookup);
+            let health = ContributorHealth {
+                criticality: overridden.unwrap_or(contribution.criticality),
+                overridden: overridden.is_some(),
+                name: contribution.name,
+                healthy: contribution.healthy,
+                detail: contribution.detail,
+            };
+            if !health.healthy {
+                match health.criticality {
+                    Criticality::Critical => report.failing.push(health.name.clone()),
+                    Criticality::DegradedOk => {
+                        report.degraded = true;
+                        report.failing.push(health.name.clone());
+                    }
+                    Criticality::Informational => {}
+                }
+            }
+            report.contributors.push(health);
+        }
+        report.ready = report
+            .contributors
+            .iter()
+            .all(|c| c.healthy || c.criticality != Criticality::Critical);
+        report
+    }
+}
+
+/// The override of `name`, or of its group. An invalid override is ignored, so a typo in the
+/// config cannot fail the probe.
+fn override_of(name: &str, lookup: &impl Fn(&str) -> Option<String>) -> Option<Criticality> {
+    let group = name.split_once(':').map(|(group, _)| group);
+    std::iter::once(name)
+        .chain(group)
+        .map(criticality_key)
+        .find_map(|key| {
+            let raw = lookup(&key).filter(|raw| !raw.trim().is_empty())?;
+            raw.parse()
+                .inspect_err(|e| warn!("Ignoring {key}={raw:?}: {e}"))
+                .ok()
+        })
+}
+
+/// Each startup stage: required ones are critical, optional ones `degraded_ok`.
+impl HealthContributor for StartupStatus {
+    fn contribute(&self) -> Vec<Contribution> {
+        self.snapshot()
+            .into_iter()
+            .map(|stage| {
+                let criticality = match stage.required {
+                    true => Criticality::Critical,
+                    false => Criticality::DegradedOk,
+                };
+                match stage.state {
+                    InitState::Ready => Contribution::healthy(stage.name, criticality),
+                    InitState::Pending => {
+                        Contribution::failing(stage.name, criticality, "not started")
+                    }
+                    InitState::Initializing => {
+                        Contribution::failing(stage.name, criticality, "initializing")
+                    }
+                    InitState::Degraded | InitState::Failed => {
+                        let detail = stage.error.unwrap_or_else(|| "failed".to_string());
+                        Contribution::failing(stage.name, criticality, detail)
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+impl HealthContributor for RegistrationGate {
+    fn contribute(&self) -> Vec<Contribution> {
+        vec![match self.permits_submission() {
+            true => Contribution::healthy("registration", Criticality::Critical),
+            false => Contribution::failing(
+                "registration",
+                Criticality::Critical,
+                "operator is not registered; on-chain submissions are suspended",
+            ),
+        }]
+    }
+}
+
+/// Critical with `SELF_AUDIT_GATE_READINESS`, informational otherwise.
+impl HealthContributor for SelfAuditor {
+    fn contribute(&self) -> Vec<Contribution> {
+        let criticality = match self.config().gate_readiness {
+            true => Criticality::Critical,
+            false => Criticality::Informational,
+        };
+        let critical = self.report().map_or(0, |report| report.critical());
+        vec![match critical {
+            0 => Contribution::healthy("self_audit", criticality),
+            n => Contribution::failing(
+                "self_audit",
+                criticality,
+                format!("self-audit found {n} critical divergences"),
+            ),
+        }]
+    }
+}
+
+/// Each oracle target, failing while submissions to it are suspended.
+impl HealthContributor for FailureDomains {
+    fn contribute(&self) -> Vec<Contribution> {
+        self.status()
+            .into_iter()
+            .map(|domain| {
+                let name = format!("oracle:{}:{}", domain.chain_id, domain.oracle);
+                match (&domain.suspension, &domain.last_error) {
+                    (None, _) => Contribution::healthy(name, Criticality::DegradedOk),
+                    (Some(suspension), error) => Contribution::failing(
+                        name,
+                        Criticality::DegradedOk,
+                        format!(
+                            "submissions suspended ({:?}) after {} consecutive failures{}",
+                            suspension.reason,
+                            domain.consecutive_failures,
+                            error.as_ref().map_or(String::new(), |e| format!(": {e}"))
+                        ),
+                    ),
+                }
+            })
+            .collect()
+    }
+}
+
+impl HealthContributor for DiskBudget {
+    fn contribute(&self) -> Vec<Contribution> {
+        let level = self.report().map_or(DiskLevel::Ok, |report| report.level);
+        vec![match level {
+            DiskLevel::Ok => Contribution::healthy("disk", Criticality::DegradedOk),
+            level => Contribution::failing(
+                "disk",
+                Criticality::DegradedOk,
+                format!(
+                    "disk usage is at the {level:?} level{}",
+                    match self.writes_suspended() {
+                        true => "; non-essential writes are suspended",
+                        false => "",
+                    }
+                ),
+            ),
+        }]
+    }
+}
+
+/// The evidence listener, failing while pushed evidence cannot be appended.
+impl HealthContributor for EvidenceIngestion {
+    fn contribute(&self) -> Vec<Contribution> {
+        vec![match self.status().outage {
+            None => Contribution::healthy("evidence_listener", Criticality::Informational),
+            Some(outage) => Contribution::failing(
+                "evidence_listener",
+                Criticality::Informational,
+                outage.reason,
+            ),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigLayers;
+
+    struct Fixed(Vec<Contribution>);
+
+    impl HealthContributor for Fixed {
+        fn contribute(&self) -> Vec<Contribution> {
+            self.0.clone()
+        }
+    }
+
+    fn registry(contributions: Vec<Contribution>) -> HealthRegistry {
+        let registry = HealthRegistry::default();
+        registry.register(Arc::new(Fixed(contributions)));
+        registry
+    }
+
+    fn no_overrides(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn failing_contributors_aggregate_by_criticality() {
+        let healthy = registry(vec![
+            Contribution::healthy("keystore", Criticality::Critical),
+            Contribution::failing("telemetry", Criticality::Informational, "exporter down"),
+        ])
+        .evaluate(no_overrides);
+        assert!(healthy.ready && !healthy.degraded);
+        assert!(healthy.failing.is_empty());
+
+        let degraded = registry(vec![
+            Contribution::healthy("keystore", Criticality::Critical),
+            Contribution::failing("tee", Criticality::DegradedOk, "timed out"),
+        ])
+        .evaluate(no_overrides);
+        assert!(degraded.ready && degraded.degraded);
+        assert_eq!(degraded.failing, ["tee"]);
+
+        let failing = registry(vec![
+            Contribution::failing("keystore", Criticality::Critical, "locked"),
+            Contribution::failing("tee", Criticality::DegradedOk, "timed out"),
+        ])
+        .evaluate(no_overrides);
+        assert!(!failing.ready && failing.degraded);
+        assert_eq!(failing.failing, ["keystore", "tee"]);
+        assert_eq!(failing.blockers(), ["keystore: locked"]);
+    }
+
+    #[test]
+    fn overrides_apply_on_reload() {
+        let dir = std::env::temp_dir().join(format!("health-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("operator.toml");
+        std::fs::write(
+            &path,
+            "[health.criticality]\ntelemetry = \"informational\"\n",
+        )
+        .unwrap();
+        let mut layers = ConfigLayers::load(Some(path.clone()), &[]).unwrap();
+
+        let registry = registry(vec![
+            Contribution::failing("telemetry", Criticality::Critical, "exporter down"),
+            Contribution::failing("evidence_listener", Criticality::Informational, "full"),
+            Contribution::failing("oracle:1:0xabc", Criticality::DegradedOk, "suspended"),
+        ]);
+        let evaluate = |layers: &ConfigLayers| {
+            registry.evaluate(|key| layers.lookup(key, |_| None).map(|(value, _)| value))
+        };
+        let before = evaluate(&layers);
+        assert!(before.ready);
+        assert!(before.contributors[0].overridden);
+        assert_eq!(before.failing, ["oracle:1:0xabc"]);
+
+        std::fs::write(
+            &path,
+            "[health.criticality]\nevidence_listener = \"critical\"\noracle = \"informational\"\n",
+        )
+        .unwrap();
+        layers.reload().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let after = evaluate(&layers);
+        assert!(!after.ready && !after.degraded);
+        assert_eq!(after.failing, ["telemetry", "evidence_listener"]);
+        assert_eq!(
+            after.contributors[2].criticality,
+            Criticality::Informational
+        );
+    }
+
+    #[test]
+    fn invalid_overrides_keep_the_default() {
+        let registry = registry(vec![Contribution::failing(
+            "keystore",
+            Criticality::Critical,
+            "locked",
+        )]);
+        let report =
+            registry.evaluate(|key| (key == "HEALTH_CRITICALITY_KEYSTORE").then(|| "meh".into()));
+        assert!(!report.ready);
+        assert!(!report.contributors[0].overridden);
+    }
+}
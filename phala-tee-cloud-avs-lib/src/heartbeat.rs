@@ -0,0 +1,408 @@
+//! The heartbeat pipeline and a watchdog for the cron producer that drives it.
+//!
+//! Every heartbeat records when it started and what triggered it. A plain tokio interval,
+//! independent of the cron machinery, checks that the cron last triggered one within twice
+//! `HEARTBEAT_PERIOD_SECS`. When it has not, the heartbeat is stalled: the watchdog alerts,
+//! sets [`HEARTBEAT_STALLED_METRIC`], runs the pipeline itself and restarts the cron producer.
+//! It does so again every period for as long as the stall lasts, and alerts escalate to critical
+//! after `HEARTBEAT_WATCHDOG_ESCALATE_AFTER` consecutive recoveries.
+//!
+//! Each heartbeat's evidence carries the TEE's health score and whether it reached the
+//! threshold (see [`crate::tee::health`]). It is also signed as a [`SignedPayload`], and the
+//! latest is served at `/heartbeat` for off-chain consumers.
+
+use crate::PhalaAvsError;
+use crate::config::env_or;
+use crate::context::PhalaAvsContext;
+use crate::evidence::{HEARTBEAT_EVIDENCE, HeartbeatEvidence, now_unix_ms};
+use crate::maintenance::now_unix;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::signed_payload::{HEARTBEAT_PAYLOAD, SignedPayload};
+use crate::supervisor::ProducerSupervisor;
+use crate::tee::fleet::{FleetLiveness, FleetVerdict};
+use crate::tee::health::HealthReport;
+use crate::tee::liveness::{Liveness, LivenessCheck};
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Gauge set to 1 while the cron producer has stopped triggering heartbeats.
+pub const HEARTBEAT_STALLED_METRIC: &str = "phala_avs_heartbeat_stalled";
+
+#[derive(Clone, Debug)]
+pub struct WatchdogConfig {
+    /// Period of the heartbeat cron schedule.
+    pub period_secs: u64,
+    pub check_secs: u64,
+    pub escalate_after: u32,
+}
+
+impl WatchdogConfig {
+    /// Reads `HEARTBEAT_PERIOD_SECS`, `HEARTBEAT_WATCHDOG_CHECK_SECS` and
+    /// `HEARTBEAT_WATCHDOG_ESCALATE_AFTER`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            period_secs: env_or("HEARTBEAT_PERIOD_SECS", 60)?,
+            check_secs: env_or("HEARTBEAT_WATCHDOG_CHECK_SECS", 15)?,
+            escalate_after: env_or("HEARTBEAT_WATCHDOG_ESCALATE_AFTER", 3)?,
+        })
+    }
+
+    /// How long the cron may go without triggering a heartbeat before it counts as stalled.
+    pub fn stall_after_secs(&self) -> u64 {
+        2 * self.period_secs
+    }
+}
+
+/// What started a heartbeat.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Cron,
+    Watchdog,
+}
+
+/// What the watchdog should do after a check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchdogAction {
+    Healthy,
+    /// Stalled, but the watchdog ran a heartbeat within the last period.
+    Stalled,
+    /// Stalled and a heartbeat is due: run it and restart the producer.
+    Recover {
+        consecutive: u32,
+        severity: Severity,
+    },
+}
+
+/// When heartbeats last started, shared by the pipeline and the watchdog.
+#[derive(Debug)]
+pub struct HeartbeatMonitor {
+    config: WatchdogConfig,
+    last_cron_ms: AtomicU64,
+    last_run_ms: AtomicU64,
+    consecutive_stalls: AtomicU32,
+    signed: Mutex<Option<SignedPayload<HeartbeatEvidence>>>,
+}
+
+impl HeartbeatMonitor {
+    /// Starts the clock at `now_ms`, so a cron that never ticks is caught too.
+    pub fn new(config: WatchdogConfig, now_ms: u64) -> Self {
+        Self {
+            config,
+            last_cron_ms: AtomicU64::new(now_ms),
+            last_run_ms: AtomicU64::new(now_ms),
+            consecutive_stalls: AtomicU32::new(0),
+            signed: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &WatchdogConfig {
+        &self.config
+    }
+
+    pub fn started(&self, trigger: Trigger, now_ms: u64) {
+        self.last_run_ms.store(now_ms, Ordering::SeqCst);
+        if trigger == Trigger::Cron {
+            self.last_cron_ms.store(now_ms, Ordering::SeqCst);
+        }
+    }
+
+    /// Keeps `signed` as the heartbeat served to consumers.
+    pub fn record_signed(&self, signed: SignedPayload<HeartbeatEvidence>) {
+        *self.signed.lock().unwrap() = Some(signed);
+    }
+
+    /// The most recent signed heartbeat.
+    pub fn signed(&self) -> Option<SignedPayload<HeartbeatEvidence>> {
+        self.signed.lock().unwrap().clone()
+    }
+
+    pub fn check(&self, now_ms: u64) -> WatchdogAction {
+        let period_ms = self.config.period_secs * 1000;
+        let since_cron = now_ms.saturating_sub(self.last_cron_ms.load(Ordering::SeqCst));
+        if since_cron <= self.config.stall_after_secs() * 1000 {
+            self.consecutive_stalls.store(0, Ordering::SeqCst);
+            METRICS.set_gauge(HEARTBEAT_STALLED_METRIC, &[], 0.0);
+            return WatchdogAction::Healthy;
+        }
+        METRICS.set_gauge(HEARTBEAT_STALLED_METRIC, &[], 1.0);
+        if now_ms.saturating_sub(self.last_run_ms.load(Ordering::SeqCst)) < period_ms {
+            return WatchdogAction::Stalled;
+        }
+        let consecutive = self.consecutive_stalls.fetch_add(1, Ordering::SeqCst) + 1;
+        let severity = if consecutive >= self.config.escalate_after {
+            Severity::Critical
+        } else {
+            Severity::Warning
+        };
+        WatchdogAction::Recover {
+            consecutive,
+            severity,
+        }
+    }
+
+    /// Checks once, recovering through `run` and `supervisor` if heartbeats are stalled.
+    pub async fn watch<F, Fut>(
+        &self,
+        now_ms: u64,
+        notifier: &dyn Notifier,
+        supervisor: &ProducerSupervisor,
+        run: F,
+    ) -> WatchdogAction
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(), PhalaAvsError>>,
+    {
+        let action = self.check(now_ms);
+        let WatchdogAction::Recover {
+            consecutive,
+            severity,
+        } = action
+        else {
+            return action;
+        };
+        let since_secs = now_ms.saturating_sub(self.last_cron_ms.load(Ordering::SeqCst)) / 1000;
+        error!(
+            "CRITICAL: the heartbeat cron has not run for {since_secs}s (stall #{consecutive}); running the heartbeat directly and restarting the cron producer"
+        );
+        let alert = Alert::new(
+            "heartbeat",
+            severity,
+            format!(
+                "Heartbeat cron stalled for {since_secs}s, {consecutive} consecutive recoveries"
+            ),
+        );
+        if let Err(e) = notifier.notify(alert).await {
+            warn!("Failed to deliver heartbeat stall alert: {e}");
+        }
+        if let Err(e) = run().await {
+            warn!("Watchdog-triggered heartbeat failed: {e}");
+        }
+        supervisor.restart();
+        action
+    }
+}
+
+/// Runs every heartbeat check once.
+pub async fn run_all(ctx: &PhalaAvsContext, trigger: Trigger) -> Result<(), PhalaAvsError> {
+    let unix_ms = now_unix_ms();
+    ctx.heartbeat.started(trigger, unix_ms);
+
+    let in_maintenance = ctx.maintenance.suppresses_alerts(None, now_unix());
+    let check = ctx.tee_handler.check_liveness().await;
+    let health = ctx.tee_handler.score_health(&check).await;
+    let evidence = HeartbeatEvidence {
+        unix_ms,
+        live: check.as_ref().ok().map(LivenessCheck::is_live),
+        in_maintenance,
+        health_score: Some(health.score),
+        healthy: Some(health.healthy),
+    };
+    if let Err(e) = ctx
+        .evidence
+        .record(HEARTBEAT_EVIDENCE, unix_ms, &[], &evidence)
+    {
+        warn!("Failed to record heartbeat evidence: {:?}", e);
+    }
+    match ctx
+        .payloads
+        .sign(HEARTBEAT_PAYLOAD, evidence, unix_ms / 1000)
+        .await
+    {
+        Ok(signed) => ctx.heartbeat.record_signed(signed),
+        Err(e) => warn!("Failed to sign heartbeat: {e}"),
+    }
+    let latency_ms = check.as_ref().map_or(0, LivenessCheck::latency_ms);
+    if check
+        .as_ref()
+        .is_ok_and(|check| check.slow && check.is_live())
+    {
+        warn!("Heartbeat check: tappd took {latency_ms}ms to answer; SLA windows may be missed");
+    }
+    match check.map(|check| check.liveness) {
+        Ok(Liveness::Live) if !ctx.registration.permits_submission() => {
+            info!("Heartbeat check: TEE/Node is live; not reporting it while unregistered.");
+        }
+        Ok(Liveness::Live) if !health.healthy => {
+            warn!(
+                "Heartbeat check: TEE/Node is live but not healthy enough to attest: score {} \
+                 is below {} (failed: {})",
+                health.score,
+                health.threshold,
+                failed_components(&health)
+            );
+        }
+        Ok(Liveness::Live) => {
+            info!(
+                "Heartbeat check: TEE/Node is live ({latency_ms}ms, health score {}).",
+                health.score
+            );
+            // TODO: Potentially report liveness status if required by the AVS design.
+        }
+        Ok(not_live) if in_maintenance => {
+            info!("Heartbeat check: TEE/Node is not live during planned maintenance: {not_live}");
+        }
+        // Nothing to wait out: dstack is down or TEE_ENDPOINT points elsewhere.
+        Ok(not_live @ (Liveness::SocketMissing { .. } | Liveness::Unreachable { .. })) => {
+            error!("Heartbeat check: TEE/Node is NOT live: {not_live}; is dstack running?");
+        }
+        Ok(not_live @ (Liveness::TimedOut { .. } | Liveness::TooSlow { .. })) => {
+            warn!("Heartbeat check: TEE/Node is NOT live: {not_live}; tappd may be overloaded");
+        }
+        Ok(not_live) => {
+            warn!("Heartbeat check: TEE/Node is NOT live: {not_live}");
+        }
+        Err(e) => {
+            warn!("Heartbeat check failed: {:?}", e);
+            // TODO: Handle error appropriately.
+        }
+    }
+    if ctx.tee_handler.has_fleet() {
+        log_fleet(&ctx.tee_handler.check_liveness_all().await);
+    }
+    Ok(())
+}
+
+fn failed_components(health: &HealthReport) -> String {
+    let failed: Vec<_> = health.failed.iter().map(|c| c.as_str()).collect();
+    failed.join(", ")
+}
+
+/// Logs the verdict of a fleet sweep, then each instance.
+fn log_fleet(sweep: &FleetLiveness) {
+    let summary = format!(
+        "Heartbeat fleet check: {} ({}/{} instances live)",
+        sweep.verdict,
+        sweep.live_count(),
+        sweep.instances.len()
+    );
+    match sweep.verdict {
+        FleetVerdict::AllLive => info!("{summary}"),
+        FleetVerdict::PartiallyLive => warn!("{summary}"),
+        FleetVerdict::NoneLive => error!("{summary}"),
+    }
+    for instance in &sweep.instances {
+        let name = &instance.name;
+        match &instance.check {
+            Ok(check) if check.is_live() => info!("  {name}: live ({}ms)", check.latency_ms()),
+            Ok(check) => warn!("  {name}: NOT live: {}", check.liveness),
+            Err(e) => warn!("  {name}: check failed: {e}"),
+        }
+    }
+}
+
+/// Checks for a stalled heartbeat cron every `check_secs`, in the background.
+pub fn spawn_watchdog(ctx: PhalaAvsContext, supervisor: Arc<ProducerSupervisor>) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(ctx.heartbeat.config().check_secs));
+        loop {
+            interval.tick().await;
+            ctx.heartbeat
+                .watch(now_unix_ms(), ctx.notifier.as_ref(), &supervisor, || {
+                    run_all(&ctx, Trigger::Watchdog)
+                })
+                .await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm::BoxFuture;
+    use futures::StreamExt;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::sync::mpsc;
+
+    #[derive(Default)]
+    struct Recorded(Mutex<Vec<Alert>>);
+
+    impl Notifier for Recorded {
+        fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.0.lock().unwrap().push(alert);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// A cron stand-in whose ticks the test controls.
+    fn cron(created: &Arc<AtomicUsize>) -> futures::stream::BoxStream<'static, u32> {
+        let (tx, rx) = mpsc::unbounded_channel::<u32>();
+        let generation = created.fetch_add(1, Ordering::SeqCst) as u32;
+        // Ticks once on creation and then goes silent, like a producer whose timer died.
+        tx.send(generation).unwrap();
+        futures::stream::unfold((rx, tx), |(mut rx, tx)| async move {
+            rx.recv().await.map(|tick| (tick, (rx, tx)))
+        })
+        .boxed()
+    }
+
+    #[tokio::test]
+    async fn stalled_cron_is_detected_recovered_and_restarted() {
+        let config = WatchdogConfig {
+            period_secs: 60,
+            check_secs: 15,
+            escalate_after: 2,
+        };
+        let monitor = Arc::new(HeartbeatMonitor::new(config, 0));
+        let notifier = Recorded::default();
+        let created = Arc::new(AtomicUsize::new(0));
+        let supervisor = ProducerSupervisor::new("heartbeat_cron");
+        let mut producer = supervisor.supervise(cron(&created), {
+            let created = Arc::clone(&created);
+            move || {
+                let stream = cron(&created);
+                async move { Ok(stream) }
+            }
+        });
+        assert_eq!(producer.next().await, Some(0));
+        monitor.started(Trigger::Cron, 0);
+
+        // Heartbeats the watchdog runs itself.
+        let direct_runs = AtomicUsize::new(0);
+        let run = |now_ms| {
+            direct_runs.fetch_add(1, Ordering::SeqCst);
+            monitor.started(Trigger::Watchdog, now_ms);
+            async { Ok(()) }
+        };
+
+        // Within twice the period nothing happens.
+        let action = monitor.watch(120_000, &notifier, &supervisor, || run(120_000));
+        assert_eq!(action.await, WatchdogAction::Healthy);
+        assert_eq!(direct_runs.load(Ordering::SeqCst), 0);
+
+        // The cron stays silent: detected, recovered directly, producer restarted.
+        let action = monitor.watch(121_000, &notifier, &supervisor, || run(121_000));
+        assert_eq!(action.await, WatchdogAction::Recover {
+            consecutive: 1,
+            severity: Severity::Warning
+        });
+        assert_eq!(direct_runs.load(Ordering::SeqCst), 1);
+        assert_eq!(METRICS.gauge(HEARTBEAT_STALLED_METRIC, &[]), Some(1.0));
+        assert_eq!(producer.next().await, Some(1));
+        assert_eq!(supervisor.restarts(), 1);
+        assert_eq!(notifier.0.lock().unwrap()[0].severity, Severity::Warning);
+
+        // Checks within a period of the direct run only report the stall; the next one escalates.
+        let action = monitor.watch(136_000, &notifier, &supervisor, || run(136_000));
+        assert_eq!(action.await, WatchdogAction::Stalled);
+        let action = monitor.watch(181_000, &notifier, &supervisor, || run(181_000));
+        assert_eq!(action.await, WatchdogAction::Recover {
+            consecutive: 2,
+            severity: Severity::Critical
+        });
+        assert_eq!(direct_runs.load(Ordering::SeqCst), 2);
+        assert_eq!(producer.next().await, Some(2));
+        assert_eq!(notifier.0.lock().unwrap()[1].severity, Severity::Critical);
+
+        // A cron tick ends the stall.
+        monitor.started(Trigger::Cron, 190_000);
+        let action = monitor.watch(200_000, &notifier, &supervisor, || run(200_000));
+        assert_eq!(action.await, WatchdogAction::Healthy);
+        assert_eq!(METRICS.gauge(HEARTBEAT_STALLED_METRIC, &[]), Some(0.0));
+    }
+}
@@ -0,0 +1,766 @@
+//! Ingestion of evidence pushed by workloads.
+//!
+//! Workloads push evidence about themselves to `POST /workloads/{id}/evidence` with an ingestion
+//! token the operator issues them (`POST /admin/workloads/{id}/evidence-token`). Issuing a token
+//! rotates it: the new token is pushed to the workload over the TEE handler's workload config
+//! channel, and the previous one keeps working for `EVIDENCE_INGEST_TOKEN_GRACE_SECS` so pushes
+//! in flight are not lost, after which it is rejected.
+//!
+//! Each workload may push `EVIDENCE_INGEST_RATE_LIMIT_PER_MIN` records a minute, each of at most
+//! `EVIDENCE_INGEST_MAX_PAYLOAD_BYTES`; beyond that it is answered `429` or `413`. Accepted
+//! records wait in a queue of `EVIDENCE_INGEST_QUEUE_CAPACITY` until they are appended to the
+//! evidence log under [`WORKLOAD_EVIDENCE`]. A full queue rejects pushes with `503` and a
+//! `Retry-After` of `EVIDENCE_INGEST_RETRY_AFTER_SECS` rather than buffering them. A full queue or
+//! a failed append is reported on `/status` as an ingestion outage until the queue drains, since
+//! evidence missing from the log weakens the SLA proofs built from it.
+
+use crate::api_keys::constant_time_eq;
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use crate::evidence::{EvidenceLog, WORKLOAD_EVIDENCE, now_unix_ms};
+use crate::metrics::METRICS;
+use crate::retry::{RetryPolicy, retry_with};
+use crate::sanitize;
+use crate::state::{StateStore, StateStoreExt};
+use crate::tee::TeeHandler;
+use blueprint_sdk::alloy::primitives::{B256, keccak256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Counter of accepted pushes, by workload.
+pub const INGEST_ACCEPTED_METRIC: &str = "phala_avs_evidence_ingest_accepted_total";
+/// Counter of rejected pushes, by workload and reason. Pushes without a valid token are counted
+/// under the workload `unauthenticated`.
+pub const INGEST_REJECTED_METRIC: &str = "phala_avs_evidence_ingest_rejected_total";
+/// Gauge: records waiting to be appended to the evidence log, in total and by workload.
+pub const INGEST_QUEUE_DEPTH_METRIC: &str = "phala_avs_evidence_ingest_queue_depth";
+
+/// Retry policy of appending queued evidence.
+pub const APPEND_RETRY_SITE: &str = "INGEST_APPEND";
+
+/// StateStore namespace holding each workload's current and previous token hashes.
+const TOKEN_NAMESPACE: &str = "workload_evidence_tokens";
+
+#[derive(Clone, Debug)]
+pub struct IngestionConfig {
+    pub rate_limit_per_min: u32,
+    pub max_payload_bytes: usize,
+    pub queue_capacity: usize,
+    /// Suggested to workloads turned away by a full queue.
+    pub retry_after: Duration,
+    /// How long a rotated-out token keeps working.
+    pub token_grace: Duration,
+}
+
+impl Default for IngestionConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit_per_min: 60,
+            max_payload_bytes: 64 * 1024,
+            queue_capacity: 1024,
+            retry_after: Duration::from_secs(5),
+            token_grace: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl IngestionConfig {
+    /// Reads the `EVIDENCE_INGEST_*` settings.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        Ok(Self {
+            rate_limit_per_min: env_or(
+                "EVIDENCE_INGEST_RATE_LIMIT_PER_MIN",
+                defaults.rate_limit_per_min,
+            )?,
+            max_payload_bytes: env_or(
+                "EVIDENCE_INGEST_MAX_PAYLOAD_BYTES",
+                defaults.max_payload_bytes,
+            )?,
+            queue_capacity: env_or("EVIDENCE_INGEST_QUEUE_CAPACITY", defaults.queue_capacity)?
+                .max(1),
+            retry_after: Duration::from_secs(env_or(
+                "EVIDENCE_INGEST_RETRY_AFTER_SECS",
+                defaults.retry_after.as_secs(),
+            )?),
+            token_grace: Duration::from_secs(env_or(
+                "EVIDENCE_INGEST_TOKEN_GRACE_SECS",
+                defaults.token_grace.as_secs(),
+            )?),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TokenRecord {
+    token_hash: B256,
+    issued_unix_ms: u64,
+    previous_hash: Option<B256>,
+    previous_valid_until_unix_ms: Option<u64>,
+}
+
+/// A workload's newly issued ingestion token.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IssuedToken {
+    pub workload_id: B256,
+    pub token: String,
+    pub issued_unix_ms: u64,
+    /// Until when the token it replaced keeps working.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_valid_until_unix_ms: Option<u64>,
+    /// Whether the token reached the workload over its config channel; if not, deploy tooling
+    /// must deliver it before the previous one expires.
+    pub pushed: bool,
+}
+
+/// Evidence as pushed by a workload.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PushedEvidence {
+    pub kind: String,
+    /// When the workload observed what it reports, by its own clock.
+    #[serde(default)]
+    pub observed_unix_ms: Option<u64>,
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+/// A record of [`WORKLOAD_EVIDENCE`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkloadEvidence {
+    pub workload_id: B256,
+    pub received_unix_ms: u64,
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_unix_ms: Option<u64>,
+    pub data: serde_json::Value,
+}
+
+/// Why a push was turned away.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    Unauthorized,
+    TooLarge {
+        limit: usize,
+    },
+    RateLimited {
+        retry_after: Duration,
+    },
+    Invalid(String),
+    QueueFull {
+        retry_after: Duration,
+    },
+    /// The workload's token could not be read.
+    Unavailable(String),
+}
+
+impl Rejection {
+    /// The `reason` label of [`INGEST_REJECTED_METRIC`].
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Unauthorized => "unauthorized",
+            Self::TooLarge { .. } => "too_large",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Invalid(_) => "invalid",
+            Self::QueueFull { .. } => "queue_full",
+            Self::Unavailable(_) => "unavailable",
+        }
+    }
+
+    /// When the workload should push again, for rejections that pass with time.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after } | Self::QueueFull { retry_after } => {
+                Some(*retry_after)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized => write!(f, "invalid evidence token"),
+            Self::TooLarge { limit } => write!(f, "evidence exceeds {limit} bytes"),
+            Self::RateLimited { .. } => write!(f, "evidence rate limit exceeded"),
+            Self::Invalid(e) => write!(f, "invalid evidence: {e}"),
+            Self::QueueFull { .. } => write!(f, "evidence ingestion queue is full"),
+            Self::Unavailable(e) => write!(f, "evidence ingestion unavailable: {e}"),
+        }
+    }
+}
+
+/// Ingestion of one workload since startup.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct WorkloadIngestion {
+    pub accepted: u64,
+    /// Records waiting to be appended.
+    pub queued: usize,
+    /// Rejections by reason.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rejected: BTreeMap<&'static str, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_accepted_unix_ms: Option<u64>,
+}
+
+/// Pushed evidence is being lost or delayed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct IngestionOutage {
+    pub since_unix_ms: u64,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct IngestionStatus {
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_appended_unix_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outage: Option<IngestionOutage>,
+    pub workloads: BTreeMap<B256, WorkloadIngestion>,
+}
+
+/// Pushes of a workload in the current one-minute window.
+#[derive(Debug)]
+struct RateWindow {
+    started_ms: u64,
+    count: u32,
+}
+
+#[derive(Debug)]
+struct Queued {
+    id: Vec<u8>,
+    evidence: WorkloadEvidence,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    workloads: BTreeMap<B256, WorkloadIngestion>,
+    last_appended_unix_ms: Option<u64>,
+    outage: Option<IngestionOutage>,
+}
+
+/// Authenticates, limits and queues workload evidence, and appends it to the evidence log.
+pub struct EvidenceIngestion {
+    config: IngestionConfig,
+    store: Arc<dyn StateStore>,
+    log: EvidenceLog,
+    windows: Mutex<BTreeMap<B256, RateWindow>>,
+    queue: Mutex<VecDeque<Queued>>,
+    queued: Notify,
+    sequence: AtomicU64,
+    stats: Mutex<Stats>,
+}
+
+impl EvidenceIngestion {
+    pub fn new(config: IngestionConfig, store: Arc<dyn StateStore>, log: EvidenceLog) -> Self {
+        Self {
+            config,
+            store,
+            log,
+            windows: Mutex::default(),
+            queue: Mutex::default(),
+            queued: Notify::new(),
+            sequence: AtomicU64::new(0),
+            stats: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> &IngestionConfig {
+        &self.config
+    }
+
+    /// Issues a workload a new token. Its previous token stays valid for the grace period. Only
+    /// the tokens' hashes are kept, so the token is returned this once.
+    pub fn issue_token(
+        &self,
+        workload_id: B256,
+        now_ms: u64,
+    ) -> Result<IssuedToken, PhalaAvsError> {
+        let token = hex::encode(
+            [
+                *uuid::Uuid::new_v4().as_bytes(),
+                *uuid::Uuid::new_v4().as_bytes(),
+            ]
+            .concat(),
+        );
+        let previous = self
+            .store
+            .get_json::<TokenRecord>(TOKEN_NAMESPACE, workload_id.as_slice())?;
+        let previous_valid_until_unix_ms = previous
+            .is_some()
+            .then(|| now_ms + self.config.token_grace.as_millis() as u64);
+        let record = TokenRecord {
+            token_hash: keccak256(token.as_bytes()),
+            issued_unix_ms: now_ms,
+            previous_hash: previous.map(|p| p.token_hash),
+            previous_valid_until_unix_ms,
+        };
+        self.store
+            .put_json(TOKEN_NAMESPACE, workload_id.as_slice(), &record)?;
+        info!("Issued an evidence token for workload {workload_id}");
+        Ok(IssuedToken {
+            workload_id,
+            token,
+            issued_unix_ms: now_ms,
+            previous_valid_until_unix_ms,
+            pushed: false,
+        })
+    }
+
+    /// Issues a workload a new token and pushes it to the workload through `tee`.
+    pub async fn rotate_token(
+        &self,
+        tee: &TeeHandler,
+        workload_id: B256,
+        now_ms: u64,
+    ) -> Result<IssuedToken, PhalaAvsError> {
+        let mut issued = self.issue_token(workload_id, now_ms)?;
+        let config = serde_json::json!({ "evidence_token": issued.token });
+        match tee.configure_workload(workload_id, config).await {
+            Ok(()) => issued.pushed = true,
+            Err(e) => warn!("Evidence token of workload {workload_id} was not pushed: {e}"),
+        }
+        Ok(issued)
+    }
+
+    fn authenticate(
+        &self,
+        workload_id: B256,
+        token: &str,
+        now_ms: u64,
+    ) -> Result<bool, PhalaAvsError> {
+        let Some(record) = self
+            .store
+            .get_json::<TokenRecord>(TOKEN_NAMESPACE, workload_id.as_slice())?
+        else {
+            return Ok(false);
+        };
+        let hash = keccak256(token.as_bytes());
+        if constant_time_eq(hash.as_slice(), record.token_hash.as_slice()) {
+            return Ok(true);
+        }
+        Ok(
+            match (record.previous_hash, record.previous_valid_until_unix_ms) {
+                (Some(previous), Some(until)) => {
+                    now_ms < until && constant_time_eq(hash.as_slice(), previous.as_slice())
+                }
+                _ => false,
+            },
+        )
+    }
+
+    /// Queues a push of `body` by a workload holding `token`, returning the queue depth.
+    pub fn ingest(
+        &self,
+        workload_id: B256,
+        token: Option<&str>,
+        body: &[u8],
+        now_ms: u64,
+    ) -> Result<usize, Rejection> {
+        let result = self.admit(workload_id, token, body, now_ms);
+        let depths = self.depths();
+        let label = match &result {
+            Err(Rejection::Unauthorized) => "unauthenticated".to_string(),
+            _ => workload_id.to_string(),
+        };
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        match &result {
+            Ok(_) => {
+                METRICS.inc_counter(INGEST_ACCEPTED_METRIC, &[("workload", &label)], 1);
+                let workload = stats.workloads.entry(workload_id).or_default();
+                workload.accepted += 1;
+                workload.last_accepted_unix_ms = Some(now_ms);
+            }
+            Err(rejection) => {
+                METRICS.inc_counter(
+                    INGEST_REJECTED_METRIC,
+                    &[("workload", &label), ("reason", rejection.reason())],
+                    1,
+                );
+                if *rejection != Rejection::Unauthorized {
+                    *stats
+                        .workloads
+                        .entry(workload_id)
+                        .or_default()
+                        .rejected
+                        .entry(rejection.reason())
+                        .or_default() += 1;
+                }
+                if matches!(rejection, Rejection::QueueFull { .. }) && stats.outage.is_none() {
+                    warn!("Evidence ingestion queue is full; rejecting pushes");
+                    stats.outage = Some(IngestionOutage {
+                        since_unix_ms: now_ms,
+                        reason: rejection.to_string(),
+                    });
+                }
+            }
+        }
+        publish_depths(&stats, &depths);
+        result
+    }
+
+    /// Queued records in total and by workload.
+    fn depths(&self) -> (usize, BTreeMap<B256, usize>) {
+        let queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let mut by_workload = BTreeMap::<B256, usize>::new();
+        for queued in queue.iter() {
+            *by_workload.entry(queued.evidence.workload_id).or_default() += 1;
+        }
+        (queue.len(), by_workload)
+    }
+
+    fn admit(
+        &self,
+        workload_id: B256,
+        token: Option<&str>,
+        body: &[u8],
+        now_ms: u64,
+    ) -> Result<usize, Rejection> {
+        match self.authenticate(workload_id, token.unwrap_or_default(), now_ms) {
+            Ok(true) => {}
+            Ok(false) => return Err(Rejection::Unauthorized),
+            Err(e) => return Err(Rejection::Unavailable(e.to_string())),
+        }
+        if body.len() > self.config.max_payload_bytes {
+            return Err(Rejection::TooLarge {
+                limit: self.config.max_payload_bytes,
+            });
+        }
+        {
+            let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+            let window = windows.entry(workload_id).or_insert(RateWindow {
+                started_ms: now_ms,
+                count: 0,
+            });
+            let elapsed = now_ms.saturating_sub(window.started_ms);
+            if elapsed >= 60_000 {
+                *window = RateWindow {
+                    started_ms: now_ms,
+                    count: 0,
+                };
+            } else if window.count >= self.config.rate_limit_per_min {
+                return Err(Rejection::RateLimited {
+                    retry_after: Duration::from_millis(60_000 - elapsed),
+                });
+            }
+            window.count += 1;
+        }
+        let pushed: PushedEvidence = serde_json::from_slice(body)
+            .map_err(|e| Rejection::Invalid(sanitize::message("evidence", e)))?;
+        sanitize::identifier("evidence_kind", &pushed.kind)
+            .map_err(|e| Rejection::Invalid(e.to_string()))?;
+
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if queue.len() >= self.config.queue_capacity {
+            return Err(Rejection::QueueFull {
+                retry_after: self.config.retry_after,
+            });
+        }
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        queue.push_back(Queued {
+            id: [workload_id.as_slice(), &sequence.to_be_bytes()].concat(),
+            evidence: WorkloadEvidence {
+                workload_id,
+                received_unix_ms: now_ms,
+                kind: pushed.kind,
+                observed_unix_ms: pushed.observed_unix_ms,
+                data: pushed.data,
+            },
+        });
+        let depth = queue.len();
+        drop(queue);
+        self.queued.notify_one();
+        Ok(depth)
+    }
+
+    /// Appends every queued record to the evidence log, oldest first, returning how many were
+    /// appended. A failed append leaves the record queued and is reported as an outage.
+    pub fn drain(&self, now_ms: u64) -> Result<usize, PhalaAvsError> {
+        let mut appended = 0;
+        let result = loop {
+            let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(next) = queue.front() else {
+                break Ok(appended);
+            };
+            let evidence = &next.evidence;
+            if let Err(e) = self.log.record(
+                WORKLOAD_EVIDENCE,
+                evidence.received_unix_ms,
+                &next.id,
+                evidence,
+            ) {
+                break Err(e);
+            }
+            queue.pop_front();
+            appended += 1;
+        };
+        let depths = self.depths();
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        publish_depths(&stats, &depths);
+        if appended > 0 {
+            stats.last_appended_unix_ms = Some(now_ms);
+        }
+        match &result {
+            Ok(_) => {
+                if let Some(outage) = stats.outage.take() {
+                    info!(
+                        "Evidence ingestion recovered after {}s",
+                        now_ms.saturating_sub(outage.since_unix_ms) / 1000
+                    );
+                }
+            }
+            Err(e) => {
+                let reason = format!("failed to append workload evidence: {e}");
+                match &mut stats.outage {
+                    Some(outage) => outage.reason = reason,
+                    None => {
+                        stats.outage = Some(IngestionOutage {
+                            since_unix_ms: now_ms,
+                            reason,
+                        })
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub fn status(&self) -> IngestionStatus {
+        let (queue_depth, by_workload) = self.depths();
+        let stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        let mut workloads = stats.workloads.clone();
+        for (workload_id, workload) in &mut workloads {
+            workload.queued = by_workload.get(workload_id).copied().unwrap_or_default();
+        }
+        IngestionStatus {
+            queue_depth,
+            queue_capacity: self.config.queue_capacity,
+            last_appended_unix_ms: stats.last_appended_unix_ms,
+            outage: stats.outage.clone(),
+            workloads,
+        }
+    }
+}
+
+fn publish_depths(stats: &Stats, (total, by_workload): &(usize, BTreeMap<B256, usize>)) {
+    METRICS.set_gauge(INGEST_QUEUE_DEPTH_METRIC, &[], *total as f64);
+    for workload_id in stats.workloads.keys() {
+        let depth = by_workload.get(workload_id).copied().unwrap_or_default();
+        METRICS.set_gauge(
+            INGEST_QUEUE_DEPTH_METRIC,
+            &[("workload", &workload_id.to_string())],
+            depth as f64,
+        );
+    }
+}
+
+/// Appends queued workload evidence as it arrives, retrying failed appends under the
+/// `RETRY_INGEST_APPEND_*` policy and then again at the next wake-up.
+pub fn spawn_drain(ingestion: Arc<EvidenceIngestion>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = ingestion.queued.notified() => {}
+                _ = tokio::time::sleep(ingestion.config.retry_after) => {}
+            }
+            let policy = RetryPolicy::from_config_or(APPEND_RETRY_SITE, RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(500),
+                max_delay: ingestion.config.retry_after,
+                ..RetryPolicy::default()
+            });
+            let drained = retry_with(&policy, "ingest_append", None, || async {
+                ingestion.drain(now_unix_ms())
+            })
+            .await;
+            if let Err(e) = drained {
+                warn!("Failed to append workload evidence: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::{MAX_MESSAGE_BYTES, adversarial};
+    use crate::state::MemoryStateStore;
+
+    const T0: u64 = 1_700_000_000_000;
+
+    fn ingestion(config: IngestionConfig) -> (EvidenceIngestion, EvidenceLog) {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let log = EvidenceLog::new(Arc::clone(&store));
+        (EvidenceIngestion::new(config, store, log.clone()), log)
+    }
+
+    fn body(kind: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({ "kind": kind, "data": { "ok": true } })).unwrap()
+    }
+
+    #[test]
+    fn oversized_and_too_frequent_pushes_are_rejected() {
+        let (ingestion, _) = ingestion(IngestionConfig {
+            rate_limit_per_min: 2,
+            max_payload_bytes: 64,
+            ..IngestionConfig::default()
+        });
+        let workload = B256::repeat_byte(0x51);
+        let token = ingestion.issue_token(workload, T0).unwrap().token;
+        let push = |body: &[u8], now_ms| ingestion.ingest(workload, Some(&token), body, now_ms);
+
+        assert_eq!(
+            push(&[b' '; 65], T0),
+            Err(Rejection::TooLarge { limit: 64 })
+        );
+        assert_eq!(push(&body("a"), T0), Ok(1));
+        assert_eq!(push(&body("b"), T0 + 1_000), Ok(2));
+        let limited = push(&body("c"), T0 + 20_000).unwrap_err();
+        assert_eq!(limited.retry_after(), Some(Duration::from_secs(40)));
+        // The next window admits the workload again.
+        assert_eq!(push(&body("c"), T0 + 60_000), Ok(3));
+
+        let status = ingestion.status();
+        let counted = &status.workloads[&workload];
+        assert_eq!(counted.accepted, 3);
+        assert_eq!(counted.rejected["too_large"], 1);
+        assert_eq!(counted.rejected["rate_limited"], 1);
+        assert_eq!(
+            METRICS.counter(INGEST_REJECTED_METRIC, &[
+                ("workload", &workload.to_string()),
+                ("reason", "rate_limited")
+            ]),
+            Some(1)
+        );
+        assert!(status.outage.is_none());
+    }
+
+    #[test]
+    fn full_queue_pushes_back_and_is_an_outage_until_drained() {
+        let (ingestion, log) = ingestion(IngestionConfig {
+            queue_capacity: 2,
+            retry_after: Duration::from_secs(7),
+            ..IngestionConfig::default()
+        });
+        let workload = B256::repeat_byte(0x52);
+        let token = ingestion.issue_token(workload, T0).unwrap().token;
+        for i in 0..2 {
+            ingestion
+                .ingest(workload, Some(&token), &body("probe"), T0 + i)
+                .unwrap();
+        }
+
+        let rejected = ingestion
+            .ingest(workload, Some(&token), &body("probe"), T0 + 2)
+            .unwrap_err();
+        assert_eq!(rejected, Rejection::QueueFull {
+            retry_after: Duration::from_secs(7)
+        });
+        let status = ingestion.status();
+        assert_eq!(status.queue_depth, 2);
+        assert_eq!(status.workloads[&workload].queued, 2);
+        assert_eq!(status.outage.unwrap().since_unix_ms, T0 + 2);
+
+        // Nothing was buffered past the capacity.
+        assert_eq!(ingestion.drain(T0 + 10).unwrap(), 2);
+        let records = log.records(WORKLOAD_EVIDENCE, T0, T0 + 10).unwrap();
+        assert_eq!(records.len(), 2);
+        let stored: WorkloadEvidence = serde_json::from_slice(&records[0].1).unwrap();
+        assert_eq!(
+            (stored.workload_id, stored.kind.as_str()),
+            (workload, "probe")
+        );
+
+        let status = ingestion.status();
+        assert_eq!(status.queue_depth, 0);
+        assert_eq!(
+            METRICS.gauge(INGEST_QUEUE_DEPTH_METRIC, &[(
+                "workload",
+                &workload.to_string()
+            )]),
+            Some(0.0)
+        );
+        assert!(status.outage.is_none());
+        assert_eq!(status.last_appended_unix_ms, Some(T0 + 10));
+        assert_eq!(
+            ingestion.ingest(workload, Some(&token), &body("probe"), T0 + 11),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn rotated_out_token_works_until_the_grace_period_ends() {
+        let (ingestion, _) = ingestion(IngestionConfig {
+            token_grace: Duration::from_secs(600),
+            ..IngestionConfig::default()
+        });
+        let workload = B256::repeat_byte(0x53);
+        let first = ingestion.issue_token(workload, T0).unwrap();
+        assert_eq!(first.previous_valid_until_unix_ms, None);
+        let rotated_at = T0 + 3_600_000;
+        let second = ingestion.issue_token(workload, rotated_at).unwrap();
+        let grace_end = rotated_at + 600_000;
+        assert_eq!(second.previous_valid_until_unix_ms, Some(grace_end));
+        let push =
+            |token: &str, now_ms| ingestion.ingest(workload, Some(token), &body("a"), now_ms);
+
+        // Both tokens work during the overlap.
+        assert!(push(&first.token, grace_end - 1).is_ok());
+        assert!(push(&second.token, grace_end - 1).is_ok());
+        // Then only the new one.
+        assert_eq!(push(&first.token, grace_end), Err(Rejection::Unauthorized));
+        assert!(push(&second.token, grace_end).is_ok());
+
+        // Rotating again drops the first token for good and opens a new overlap for the second.
+        let third = ingestion.issue_token(workload, grace_end + 1).unwrap();
+        assert_eq!(
+            push(&first.token, grace_end + 2),
+            Err(Rejection::Unauthorized)
+        );
+        assert!(push(&second.token, grace_end + 2).is_ok());
+        assert!(push(&third.token, grace_end + 2).is_ok());
+        assert_eq!(
+            ingestion.ingest(workload, None, &body("a"), grace_end + 2),
+            Err(Rejection::Unauthorized)
+        );
+        // Unauthenticated pushes are not attributed to the workload.
+        assert!(ingestion.status().workloads[&workload].rejected.is_empty());
+    }
+
+    #[test]
+    fn adversarial_kinds_are_rejected_without_being_echoed() {
+        let (ingestion, log) = ingestion(IngestionConfig {
+            rate_limit_per_min: u32::MAX,
+            ..IngestionConfig::default()
+        });
+        let workload = B256::repeat_byte(0x54);
+        let token = ingestion.issue_token(workload, T0).unwrap().token;
+        let bodies = adversarial::strings()
+            .iter()
+            .map(|kind| body(kind))
+            .chain(adversarial::bytes());
+        for (i, body) in bodies.enumerate() {
+            match ingestion.ingest(workload, Some(&token), &body, T0 + i as u64) {
+                Ok(_) | Err(Rejection::TooLarge { .. }) => {}
+                Err(rejection) => {
+                    adversarial::assert_safe(&rejection.to_string(), MAX_MESSAGE_BYTES + 64)
+                }
+            }
+        }
+
+        // Only well-formed kinds were stored.
+        ingestion.drain(T0 + 1_000).unwrap();
+        for (_, record) in log.records(WORKLOAD_EVIDENCE, T0, T0 + 1_000).unwrap() {
+            let stored: WorkloadEvidence = serde_json::from_slice(&record).unwrap();
+            assert!(sanitize::identifier("kind", &stored.kind).is_ok());
+        }
+    }
+}
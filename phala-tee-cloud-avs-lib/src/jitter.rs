@@ -0,0 +1,115 @@
+//! Startup jitter, so a fleet restarting together doesn't hit the RPC, the registry and the
+//! aggregator at the same instant.
+//!
+//! Each operator derives a [`JitterSlot`] from `keccak256` of its address, so restarts of the
+//! same operator land in the same slot. The slot's delay, below `STARTUP_JITTER_MAX_SECS`, is
+//! waited once the keystore is up and before the registration check, the first poll and the
+//! first heartbeat; the status server is already serving by then. With
+//! `HEARTBEAT_PHASE_SPREAD` set, the slot also picks the second of the minute the heartbeat cron
+//! fires at, instead of all operators firing at second zero.
+//!
+//! The delay is capped so that, even after waiting a full minute for its phase, the first
+//! heartbeat starts within the watchdog's stall bound.
+
+use crate::config::{env_flag, env_or};
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::primitives::{Address, keccak256};
+use std::time::Duration;
+
+/// Heartbeat cron schedule without a phase: every minute at second zero.
+pub const HEARTBEAT_SCHEDULE: &str = "* * * * *";
+
+/// Longest the heartbeat cron waits for its phase: one minute.
+const PHASE_WAIT_SECS: u64 = 60;
+
+#[derive(Clone, Debug, Default)]
+pub struct JitterConfig {
+    /// Upper bound of the startup delay; zero starts immediately.
+    pub max_delay_secs: u64,
+    /// Spreads heartbeat cron phases across the minute.
+    pub spread_heartbeats: bool,
+}
+
+impl JitterConfig {
+    /// Reads `STARTUP_JITTER_MAX_SECS` and `HEARTBEAT_PHASE_SPREAD`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            max_delay_secs: env_or("STARTUP_JITTER_MAX_SECS", 0)?,
+            spread_heartbeats: env_flag("HEARTBEAT_PHASE_SPREAD", false)?,
+        })
+    }
+}
+
+/// An operator's startup delay and heartbeat phase.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JitterSlot {
+    pub delay: Duration,
+    /// Second of the minute the heartbeat cron fires at, when phases are spread.
+    pub heartbeat_phase_secs: Option<u64>,
+}
+
+impl JitterSlot {
+    /// Derives the slot of `operator`, keeping the first heartbeat within `max_silence_secs` of
+    /// startup.
+    pub fn derive(config: &JitterConfig, operator: Address, max_silence_secs: u64) -> Self {
+        let hash = keccak256(operator);
+        let word = |i: usize| u64::from_be_bytes(hash[i * 8..i * 8 + 8].try_into().unwrap());
+
+        let max_delay_ms = config
+            .max_delay_secs
+            .min(max_silence_secs.saturating_sub(PHASE_WAIT_SECS))
+            * 1000;
+        Self {
+            delay: Duration::from_millis(word(0) % (max_delay_ms + 1)),
+            heartbeat_phase_secs: config.spread_heartbeats.then(|| word(1) % PHASE_WAIT_SECS),
+        }
+    }
+
+    /// The heartbeat cron schedule for this slot.
+    pub fn heartbeat_schedule(&self) -> String {
+        match self.heartbeat_phase_secs {
+            Some(phase) => format!("{phase} * * * * *"),
+            None => HEARTBEAT_SCHEDULE.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operators_get_distinct_stable_slots_within_bounds() {
+        let config = JitterConfig {
+            max_delay_secs: 300,
+            spread_heartbeats: true,
+        };
+        let operators: Vec<_> = (1..=4).map(Address::repeat_byte).collect();
+        let slots: Vec<_> = operators
+            .iter()
+            .map(|&operator| JitterSlot::derive(&config, operator, 120))
+            .collect();
+
+        for (slot, &operator) in slots.iter().zip(&operators) {
+            assert_eq!(*slot, JitterSlot::derive(&config, operator, 120));
+            // Capped by the 120s silence bound less a minute's wait for the phase.
+            assert!(slot.delay <= Duration::from_secs(60));
+            assert!(slot.heartbeat_phase_secs.unwrap() < 60);
+        }
+        for (i, a) in slots.iter().enumerate() {
+            for b in &slots[i + 1..] {
+                assert_ne!(a.delay, b.delay);
+                assert_ne!(a.heartbeat_phase_secs, b.heartbeat_phase_secs);
+            }
+        }
+        let phase = slots[0].heartbeat_phase_secs.unwrap();
+        assert_eq!(slots[0].heartbeat_schedule(), format!("{phase} * * * * *"));
+    }
+
+    #[test]
+    fn zero_jitter_keeps_the_current_schedule() {
+        let slot = JitterSlot::derive(&JitterConfig::default(), Address::repeat_byte(1), 120);
+        assert_eq!(slot, JitterSlot::default());
+        assert_eq!(slot.heartbeat_schedule(), HEARTBEAT_SCHEDULE);
+    }
+}
@@ -1,9 +1,39 @@
-use crate::PhalaAvsError;
+use crate::artifacts::Artifact;
+use crate::batch::{EventOutcome, drain_queue, isolate_async};
+use crate::build_info::BuildInfo;
+use crate::challenge::{
+    AbortReason, ChallengeState, ChallengeTracker, ObservedChallenge, TrackedChallenge,
+    is_challenge_event, process_events,
+};
 use crate::context::PhalaAvsContext;
+use crate::cursor::{self, CursorKey};
+use crate::display::Addr;
+use crate::encoding::{
+    ATTESTATION_KIND, AttestationQuote, LIVENESS_KIND, ResponseEncoder, ResponseInputs, SchemaKey,
+    attestation_challenge, attestation_report_data, compute_challenge,
+};
+use crate::evidence::{RESPONSE_EVIDENCE, ResponseEvidence, now_unix_ms};
+use crate::failure_domain::Admission;
+use crate::heartbeat::Trigger;
+use crate::lanes::TxClass;
+use crate::maintenance::now_unix;
+use crate::operator_set::is_registry_event;
+use crate::preflight::PreflightOutcome;
+use crate::response_window::{OracleTarget, SubmissionUrgency};
+use crate::scheduler::Scheduled;
+use crate::sender::{TxCall, TxOutcome};
+use crate::tee::TeeHandler;
+use crate::upgrade::is_upgrade_event;
+use crate::{IPhalaSlaOracle, PhalaAvsError};
+use blueprint_sdk::alloy::primitives::{Bytes, U256, keccak256};
+use blueprint_sdk::alloy::sol_types::SolCall;
 use blueprint_sdk::evm::extract::BlockEvents;
 use blueprint_sdk::extract::Context;
 use blueprint_sdk::macros::debug_job;
 use blueprint_sdk::{info, warn};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use tracing::{Instrument, info_span};
 
 // --- Job IDs ---
 
@@ -13,6 +43,9 @@ pub const HEARTBEAT_JOB_ID: u32 = 0;
 /// Job ID for handling potential on-chain challenges or other EVM events.
 pub const RESPOND_TO_CHALLENGE_JOB_ID: u32 = 1; // Example ID
 
+/// Job ID for the scheduled self-audit against the oracle (Cron Job).
+pub const SELF_AUDIT_JOB_ID: u32 = 2;
+
 // --- Job Handlers ---
 
 /// Cron job handler for periodic heartbeat/SLA check.
@@ -23,25 +56,22 @@ pub const RESPOND_TO_CHALLENGE_JOB_ID: u32 = 1; // Example ID
 #[debug_job]
 pub async fn heartbeat_job(Context(ctx): Context<PhalaAvsContext>) -> Result<(), PhalaAvsError> {
     info!("Running heartbeat job...");
+    crate::heartbeat::run_all(&ctx, Trigger::Cron).await
+}
 
-    match ctx.tee_handler.check_liveness().await {
-        Ok(is_live) => {
-            if is_live {
-                info!("Heartbeat check: TEE/Node is live.");
-                // TODO: Potentially report liveness status if required by the AVS design.
-            } else {
-                warn!("Heartbeat check: TEE/Node is NOT live!");
-                // TODO: Implement alerting or recovery logic.
-            }
-        }
-        Err(e) => {
-            warn!("Heartbeat check failed: {:?}", e);
-            // TODO: Handle error appropriately.
-        }
-    }
-
-    // Cron jobs typically don't return data for Eigenlayer tasks,
-    // but might interact with context or external systems.
+/// Cron job handler for the self-audit of response records against the oracle.
+///
+/// Triggered on `SELF_AUDIT_SCHEDULE`; see [`crate::self_audit`].
+#[debug_job]
+pub async fn self_audit_job(Context(ctx): Context<PhalaAvsContext>) -> Result<(), PhalaAvsError> {
+    let Some(auditor) = &ctx.self_audit else {
+        return Ok(());
+    };
+    info!("Running self-audit job...");
+    let head = ctx.evm.block_number().await?;
+    auditor
+        .run(head, now_unix_ms(), ctx.notifier.as_ref())
+        .await?;
     Ok(())
 }
 
@@ -49,30 +79,708 @@ pub async fn heartbeat_job(Context(ctx): Context<PhalaAvsContext>) -> Result<(),
 ///
 /// This function is triggered by the `PollingProducer` when relevant
 /// logs matching configured filters are detected on the EVM chain.
+///
+/// Challenges are processed in two phases. On first sight, possibly in an unconfirmed block,
+/// only cheap work is done: decoding, recording the challenge as provisional and warming TEE
+/// caches. Submission is gated on the issuing block reaching the configured confirmation depth,
+/// unless the deadline forces an early submit. Provisional challenges whose block was orphaned
+/// are invalidated.
+///
+/// Challenges the oracle cancels leave the response queue; amended ones are requeued with their
+/// new deadline. A response to either still pending in the mempool is withdrawn where that is
+/// cheaper than letting it revert.
 #[debug_job]
 pub async fn respond_to_challenge_job(
-    Context(_ctx): Context<PhalaAvsContext>,
+    Context(ctx): Context<PhalaAvsContext>,
     BlockEvents(events): BlockEvents,
 ) -> Result<(), PhalaAvsError> {
     info!("Received {} potential challenge events.", events.len());
+    #[cfg(feature = "chaos")]
+    let events = ctx.chaos.corrupt_events(events);
+
+    // Events whose observation failed last time go first.
+    let mut retried = ctx.event_retries.take();
+    if !retried.is_empty() {
+        info!("Retrying {} previously failed events.", retried.len());
+    }
+    retried.extend(events);
+    let mut events = retried;
+    if let Some(checker) = &ctx.log_checker {
+        checker.record(&events);
+        let checked = match ctx.evm.block_number().await {
+            Ok(head) => checker.check(head).await,
+            Err(e) => Err(e),
+        };
+        match checked {
+            Ok(report) => events.extend(report.recovered),
+            Err(e) => warn!("Failed to cross-check polled logs: {e}"),
+        }
+    }
+
+    let chain_id = match ctx.evm.chain_id().await {
+        Ok(chain_id) => chain_id,
+        Err(e) => {
+            ctx.event_retries.retry(events);
+            return Err(e);
+        }
+    };
+    // The newest polled block marks how far the poller has scanned.
+    let polled_through = events.iter().filter_map(|e| e.block_number).max();
+    let processed = match process_events(
+        &ctx.challenge_tracker,
+        ctx.evm.as_ref(),
+        &ctx.tee_handler,
+        ctx.operator_address,
+        &events,
+        ctx.registration.permits_submission(),
+        |challenge| {
+            ctx.margin_predictor
+                .safety_margin(&OracleTarget::new(chain_id, challenge.oracle))
+        },
+    )
+    .await
+    {
+        Ok(processed) => processed,
+        Err(e) => {
+            // Observation is idempotent, so the whole batch is simply replayed.
+            ctx.event_retries.retry(events);
+            return Err(e);
+        }
+    };
+    processed.summary.report("respond_to_challenge", "observe");
+    for late in &processed.late {
+        if let Err(e) = ctx.notifier.notify(late.alert()).await {
+            warn!("Failed to deliver late detection alert: {e}");
+        }
+    }
+
+    // Each oracle's cursor advances past this batch, unless a reorg rewinds it.
+    for (oracle, block) in cursor::processed_through(&events, &processed.failed) {
+        ctx.cursors
+            .advance(CursorKey::challenges(chain_id, oracle), block);
+    }
+    let mut rewound = false;
+    for orphan in &processed.orphaned {
+        let key = CursorKey::challenges(chain_id, orphan.oracle);
+        match ctx.cursors.rewind(key, orphan.issued_block) {
+            Ok(replay) => rewound |= replay,
+            Err(e) => warn!("Failed to rewind the cursor of {}: {e}", orphan.oracle),
+        }
+    }
+    if rewound {
+        ctx.poller.restart();
+    }
+    if let Err(e) = ctx.cursors.flush() {
+        warn!("Failed to flush producer cursors: {e}");
+    }
+    if let Some(block) = polled_through {
+        let found = events.iter().filter(|e| is_challenge_event(e)).count();
+        ctx.catch_up.observe(block, found, now_unix_ms());
+    }
+    ctx.event_retries.retry(processed.failed);
+
+    if let Some(operator_set) = &ctx.operator_set {
+        let coordinator = operator_set.config().registry_coordinator;
+        if events.iter().any(|e| is_registry_event(e, coordinator)) {
+            let refreshed = match ctx.evm.block_number().await {
+                Ok(head) => operator_set.refresh(head).await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = refreshed {
+                warn!("Failed to refresh operator set: {:?}", e);
+            }
+        }
+    }
 
-    // TODO: Implement logic to:
-    // 1. Decode relevant logs using Alloy (e.g., `MyChallengeEvent::decode_log`).
-    // 2. Filter for actual challenge events relevant to this operator.
-    // 3. Perform the required action based on the challenge (e.g., interact with TEE, query state).
-    // 4. Potentially submit a response transaction or sign data for the aggregator.
+    if events
+        .iter()
+        .any(|e| is_upgrade_event(e, &ctx.upgrades.config().contracts))
+    {
+        if let Err(e) = ctx.upgrades.check_and_revalidate(&ctx.schemas).await {
+            warn!("Failed to check contracts for upgrades: {:?}", e);
+        }
+    }
 
-    for event in events {
-        // Example: Log raw event data (use specific decoding in practice)
+    withdraw_responses(&ctx, &processed.cancelled, &processed.amended).await;
+    {
+        let mut queue = ctx.response_queue.lock().unwrap_or_else(|e| e.into_inner());
+        let updated: HashSet<_> = processed
+            .cancelled
+            .iter()
+            .chain(&processed.amended)
+            .map(|entry| entry.challenge.challenge_id)
+            .collect();
+        if !updated.is_empty() {
+            let dropped = queue.retain(|entry| !updated.contains(&entry.challenge.challenge_id));
+            info!("Dropped {dropped} queued responses to cancelled or amended challenges");
+        }
+        let requeued = processed
+            .amended
+            .into_iter()
+            .filter(|entry| entry.state == ChallengeState::Queued);
+        for entry in processed.ready.into_iter().chain(requeued) {
+            let target = OracleTarget::new(chain_id, entry.challenge.oracle);
+            queue.push(target, entry.challenge.deadline_block, now_unix_ms(), entry);
+        }
+    }
+    let head = ctx.evm.block_number().await?;
+    ctx.cursors.observe_head(head);
+    ctx.challenge_tracker.observe_head(head);
+
+    // Failed and held challenges go back on the queue for the next invocation.
+    let summary = drain_queue(&ctx.response_queue, head, |next| respond(&ctx, head, next)).await;
+    summary.report("respond_to_challenge", "respond");
+    Ok(())
+}
+
+/// Withdraws the pending responses to challenges the oracle cancelled or amended, recording
+/// what came of it on the challenge.
+async fn withdraw_responses(
+    ctx: &PhalaAvsContext,
+    cancelled: &[TrackedChallenge],
+    amended: &[TrackedChallenge],
+) {
+    let cancelled = cancelled
+        .iter()
+        .filter_map(|entry| Some((entry, entry.cancellation.as_ref()?.from, "cancelled")));
+    let amended = amended
+        .iter()
+        .filter_map(|entry| Some((entry, entry.amendments.last()?.from, "amended")));
+    for (entry, from, reason) in cancelled.chain(amended) {
+        if !matches!(
+            from,
+            ChallengeState::Submitting | ChallengeState::AwaitingInclusion
+        ) {
+            continue;
+        }
+        let challenge_id = entry.challenge.challenge_id;
+        let withdrawal = match ctx.tx_sender.withdraw(challenge_id, reason).await {
+            Ok(withdrawal) => withdrawal,
+            Err(e) => {
+                warn!("Failed to withdraw the response to challenge {challenge_id}: {e}");
+                continue;
+            }
+        };
+        if let Err(e) = ctx
+            .challenge_tracker
+            .record_withdrawal(&challenge_id, withdrawal)
+        {
+            warn!("Failed to record the withdrawal of challenge {challenge_id}: {e}");
+        }
+    }
+}
+
+/// Ends a build the oracle's cancellation or amendment aborted. An amended challenge the build
+/// already took is handed back to the queue, where its new parameters wait.
+fn abandon(
+    ctx: &PhalaAvsContext,
+    challenge_id: U256,
+    reason: AbortReason,
+) -> Result<EventOutcome, PhalaAvsError> {
+    info!(
+        "Abandoning the response to challenge {challenge_id}: the oracle {} it",
+        reason.as_str()
+    );
+    let building = ctx
+        .challenge_tracker
+        .get(&challenge_id)
+        .is_some_and(|entry| entry.state == ChallengeState::Building);
+    if reason == AbortReason::Amended && building {
+        ctx.challenge_tracker.transition(
+            challenge_id,
+            ChallengeState::Queued,
+            "build aborted by an amendment",
+        )?;
+    }
+    Ok(EventOutcome::Skipped)
+}
+
+/// Whether the queued `entry` needs no response: its challenge settled, or was amended since.
+fn superseded(tracker: &ChallengeTracker, entry: &TrackedChallenge) -> bool {
+    let challenge_id = entry.challenge.challenge_id;
+    match tracker.get(&challenge_id) {
+        Some(current) if current.state.is_settled() => true,
+        Some(current) if current.revision() != entry.revision() => {
+            info!("Skipping a response to the superseded parameters of challenge {challenge_id}");
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Responds to one released challenge, unless its oracle target is suspended.
+async fn respond(
+    ctx: &PhalaAvsContext,
+    head: u64,
+    next: Scheduled<TrackedChallenge>,
+) -> Result<EventOutcome, PhalaAvsError> {
+    let target = next.target;
+    match ctx.domains.admit(target, now_unix_ms()) {
+        Admission::Suspended => return Ok(EventOutcome::Deferred),
+        Admission::Probe => info!("Probing suspended oracle {}", Addr(target.oracle)),
+        Admission::Submit => {}
+    }
+    let challenge_id = next.item.challenge.challenge_id;
+    // Everything logged while responding carries the blocks left to do it in.
+    let span = info_span!(
+        "challenge.respond",
+        challenge_id = %challenge_id,
+        remaining_blocks = next.item.remaining_blocks(head),
+    );
+    let outcome = isolate_async(submit(ctx, head, next).instrument(span)).await;
+    if let Err(e) = &outcome {
+        // A failed attempt goes back to the queue; the scheduler retries it.
+        let building = ctx
+            .challenge_tracker
+            .get(&challenge_id)
+            .is_some_and(|entry| entry.state == ChallengeState::Building);
+        if building {
+            let cause = format!("attempt failed: {e}");
+            if let Err(e) =
+                ctx.challenge_tracker
+                    .transition(challenge_id, ChallengeState::Queued, cause)
+            {
+                warn!("Failed to requeue challenge {challenge_id}: {e}");
+            }
+        }
+    }
+    if let Some(alert) = ctx.domains.record(target, &outcome, now_unix_ms()) {
+        if let Err(e) = ctx.notifier.notify(alert).await {
+            warn!("Failed to deliver failure domain alert: {e}");
+        }
+    }
+    outcome
+}
+
+async fn submit(
+    ctx: &PhalaAvsContext,
+    head: u64,
+    next: Scheduled<TrackedChallenge>,
+) -> Result<EventOutcome, PhalaAvsError> {
+    let challenge_id = next.item.challenge.challenge_id;
+    let tracker = &ctx.challenge_tracker;
+    if superseded(tracker, &next.item) {
+        return Ok(EventOutcome::Skipped);
+    }
+    if head > next.item.challenge.deadline_block {
+        let cause = format!(
+            "response window closed at block {}",
+            next.item.challenge.deadline_block
+        );
+        tracker.transition(challenge_id, ChallengeState::Missed, cause)?;
+        warn!("Missed challenge {challenge_id} at block {head}");
+        return Ok(EventOutcome::Skipped);
+    }
+    let urgent = next.item.urgent
+        || ctx
+            .margin_predictor
+            .urgency(&next.target, head, next.deadline_block)
+            != SubmissionUrgency::Comfortable;
+    if !ctx.upgrades.permits_submission(urgent) {
         info!(
-            "Processing event from block: {:?}, tx: {:?}, log index: {:?}",
-            event.block_number, event.transaction_hash, event.log_index
+            "Holding challenge {} until the contract upgrade is acknowledged",
+            next.item.challenge.challenge_id
         );
-        // Add decoding and handling logic here
+        return Ok(EventOutcome::Deferred);
+    }
+    if !ctx.restart.permits_submission() {
+        info!(
+            "Holding challenge {} while the operator is prepared for a restart",
+            next.item.challenge.challenge_id
+        );
+        return Ok(EventOutcome::Deferred);
+    }
+    let entry = next.item;
+    // Registered before checking again, so a cancellation or amendment after the check aborts it.
+    let build = tracker.start_build(challenge_id);
+    if superseded(tracker, &entry) {
+        return Ok(EventOutcome::Skipped);
+    }
+    let picked = tracker.transition(
+        challenge_id,
+        ChallengeState::Building,
+        "picked up by a worker",
+    )?;
+    info!(
+        "Challenge {} ready for submission ({:?}), {} blocks left",
+        entry.challenge.challenge_id,
+        entry.release_reason,
+        picked.remaining_blocks(head)
+    );
+    if let Some(reason) = build.aborted() {
+        return abandon(ctx, challenge_id, reason);
+    }
+    let key = ctx
+        .rollout
+        .response_key(entry.challenge.oracle, SchemaKey::of(&entry.challenge));
+    let encoder = match ctx.schemas.encoder(&key) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            warn!(
+                "Not responding to challenge {}: {e}",
+                entry.challenge.challenge_id
+            );
+            tracker.transition(challenge_id, ChallengeState::Invalid, e.to_string())?;
+            return Ok(EventOutcome::Skipped);
+        }
+    };
+    if let Some(reason) = build.aborted() {
+        return abandon(ctx, challenge_id, reason);
+    }
+    // Challenges don't name a workload yet, so only windows covering all workloads apply.
+    let annotation = ctx.maintenance.annotation_for(None, now_unix())?;
+    if let Some(annotation) = &annotation {
+        info!(
+            "Challenge {} falls in maintenance window {}",
+            entry.challenge.challenge_id, annotation.window_id
+        );
+    }
+    info!(
+        "Challenge {} will be answered with {key} (schema {})",
+        entry.challenge.challenge_id,
+        encoder.schema_hash()
+    );
+    // Workloads answering their own challenges get the delegation budget, unless the deadline
+    // leaves no time for it.
+    let delegated = if urgent {
+        None
+    } else {
+        match ctx.delegation.delegate(&entry.challenge, encoder).await {
+            Ok(Some(delegation)) => {
+                tracker.record_delegation(&challenge_id, delegation.record)?;
+                delegation.response
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Failed to delegate challenge {challenge_id}: {e}");
+                None
+            }
+        }
+    };
+    if let Some(reason) = build.aborted() {
+        return abandon(ctx, challenge_id, reason);
+    }
+    if let Some(response) = &delegated {
+        info!(
+            "Challenge {challenge_id} was answered by workload {}",
+            response.workload_id
+        );
+    }
+    // A delegated payload is the workload's, countersigned; any other is built from the TEE,
+    // attestation responses only once they pass the oracle's checks locally.
+    let built = match &delegated {
+        Some(response) => Ok(Some(BuiltResponse {
+            payload: response.payload.clone(),
+            inputs: None,
+        })),
+        None if encoder.kind_name() == ATTESTATION_KIND => {
+            verified_attestation(ctx, &entry.challenge, encoder).await
+        }
+        None => build_response(&ctx.tee_handler, &entry.challenge, encoder)
+            .await
+            .map(Some),
+    };
+    let built = match built {
+        Ok(Some(built)) => built,
+        Ok(None) => {
+            tracker.transition(challenge_id, ChallengeState::Invalid, "dead-lettered")?;
+            return Ok(EventOutcome::Skipped);
+        }
+        Err(PhalaAvsError::ValidationError(e)) => {
+            warn!("Not responding to challenge {challenge_id}: {e}");
+            tracker.transition(challenge_id, ChallengeState::Invalid, e)?;
+            return Ok(EventOutcome::Skipped);
+        }
+        Err(e) => return Err(e),
+    };
+    if let Some(reason) = build.aborted() {
+        return abandon(ctx, challenge_id, reason);
     }
 
-    // This job might need to return data or interact with the Eigenlayer task manager,
-    // depending on the specific challenge mechanism.
-    // For now, returning Ok indicates successful processing of the received batch.
-    Ok(())
+    // A shadow version rolled out to the target is checked against the same inputs, unless the
+    // deadline leaves no time for its simulation; the active payload is submitted either way.
+    if let Some(inputs) = built.inputs.as_ref().filter(|_| !urgent) {
+        let shadowed = ctx
+            .rollout
+            .shadow(&entry.challenge, inputs, &built.payload, now_unix_ms())
+            .await;
+        if let Err(e) = shadowed {
+            warn!("Failed to run the shadow encoder on challenge {challenge_id}: {e}");
+        }
+    }
+
+    // The proof covers what the operator recorded from the challenge's issuance to its answer.
+    let sla_proof = match ctx
+        .sla_proofs
+        .encode_for_blocks(
+            ctx.evm.as_ref(),
+            entry.challenge.issued_block,
+            head,
+            now_unix_ms(),
+        )
+        .await
+    {
+        Ok(proof) => Some(proof),
+        Err(e) => {
+            warn!("Failed to build the SLA proof for challenge {challenge_id}: {e}");
+            None
+        }
+    };
+    // Verifiers fetch what the payload was built from by the hash the contract stores.
+    let mut artifacts = response_artifacts(built.inputs.as_ref(), sla_proof.as_ref());
+    if let Some(response) = &delegated {
+        let delegation = serde_json::json!({
+            "workload_id": response.workload_id,
+            "operator_signature": response.operator_signature,
+        });
+        artifacts.push(Artifact::new(
+            "delegation.json",
+            delegation.to_string().into_bytes(),
+        ));
+    }
+    let artifacts_url = match ctx.artifacts.store(
+        &challenge_id.to_string(),
+        entry.challenge.deadline_block,
+        head,
+        built.payload.clone(),
+        artifacts,
+    ) {
+        Ok(payload_hash) => ctx.artifacts.link(payload_hash),
+        Err(e) => {
+            warn!("Failed to archive the artifacts of challenge {challenge_id}: {e}");
+            None
+        }
+    };
+    let unix_ms = now_unix_ms();
+    let evidence = ResponseEvidence {
+        unix_ms,
+        challenge_id: challenge_id.to_string(),
+        issued_block: entry.challenge.issued_block,
+        deadline_block: entry.challenge.deadline_block,
+        release_reason: format!("{:?}", entry.release_reason),
+        artifacts_url,
+        detection_delay_blocks: entry.detection_delay_blocks,
+        maintenance_window_id: annotation.map(|a| a.window_id.to_string()),
+        sla_proof_hash: sla_proof.as_ref().map(keccak256),
+    };
+    let id = challenge_id.to_be_bytes::<32>();
+    if let Err(e) = ctx
+        .evidence
+        .record(RESPONSE_EVIDENCE, unix_ms, &id, &evidence)
+    {
+        warn!("Failed to record response evidence: {:?}", e);
+    }
+    if let Some(reason) = build.aborted() {
+        return abandon(ctx, challenge_id, reason);
+    }
+
+    tracker.transition(challenge_id, ChallengeState::Submitting, "response built")?;
+    let input = IPhalaSlaOracle::respondToSlaChallengeCall {
+        challengeId: challenge_id,
+        responseData: built.payload,
+    }
+    .abi_encode();
+    let call = TxCall::new("respondToSlaChallenge", entry.challenge.oracle, input)
+        .responding_to(challenge_id)
+        .at_revision(entry.revision())
+        .escalated(urgent);
+    let outcome = match ctx.tx_sender.send(TxClass::Urgent, call).await {
+        Ok(outcome) => outcome,
+        Err(e) => return requeue(ctx, challenge_id, e),
+    };
+    match outcome {
+        // Cancellations and amendments already recorded the withdrawal.
+        TxOutcome::Withdrawn { .. } => return Ok(EventOutcome::Skipped),
+        TxOutcome::Shadowed => {
+            tracker.transition(
+                challenge_id,
+                ChallengeState::Queued,
+                "held by a standby replica",
+            )?;
+            return Ok(EventOutcome::Deferred);
+        }
+        TxOutcome::Included { .. } | TxOutcome::NonceTaken => {}
+    }
+    let tx_hash = match outcome.into_success("respondToSlaChallenge") {
+        Ok(tx_hash) => tx_hash,
+        Err(e) => return requeue(ctx, challenge_id, e),
+    };
+    tracker.transition(
+        challenge_id,
+        ChallengeState::AwaitingInclusion,
+        format!("included as {tx_hash}"),
+    )?;
+    info!("Responded to challenge {challenge_id} in {tx_hash}");
+    // Settled from the oracle's events right away; the periodic sweep retries receipts the
+    // node doesn't have yet.
+    if let Some(intent) = ctx.tx_sender.intent_for(challenge_id)? {
+        match ctx.receipts.verify(&intent, now_unix_ms()).await {
+            Ok(alerts) => {
+                for alert in alerts {
+                    if let Err(e) = ctx.notifier.notify(alert).await {
+                        warn!("Failed to deliver receipt alert: {e}");
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to verify the response to challenge {challenge_id}: {e}"),
+        }
+    }
+    Ok(EventOutcome::Processed)
+}
+
+/// Hands a challenge whose response failed to send back to the queue, failing with `error`.
+fn requeue(
+    ctx: &PhalaAvsContext,
+    challenge_id: U256,
+    error: PhalaAvsError,
+) -> Result<EventOutcome, PhalaAvsError> {
+    ctx.challenge_tracker.transition(
+        challenge_id,
+        ChallengeState::Queued,
+        format!("submission failed: {error}"),
+    )?;
+    Err(error)
+}
+
+/// A payload ready to submit, with what it was built from when it was built here.
+struct BuiltResponse {
+    payload: Bytes,
+    inputs: Option<ResponseInputs>,
+}
+
+/// Builds an attestation response through [`crate::preflight`], rebuilding it once with a fresh
+/// quote. `None` once it failed twice and was dead-lettered, which is alerted on.
+async fn verified_attestation(
+    ctx: &PhalaAvsContext,
+    challenge: &ObservedChallenge,
+    encoder: &dyn ResponseEncoder,
+) -> Result<Option<BuiltResponse>, PhalaAvsError> {
+    let tee = &ctx.tee_handler;
+    let last_inputs = Mutex::new(None);
+    let outcome = ctx
+        .preflight
+        .run(challenge, |attempt| {
+            let last_inputs = &last_inputs;
+            async move {
+                if attempt > 1 {
+                    tee.invalidate_quote_cache();
+                }
+                let built = build_response(tee, challenge, encoder).await?;
+                *last_inputs.lock().unwrap_or_else(|e| e.into_inner()) = built.inputs;
+                Ok(built.payload)
+            }
+        })
+        .await?;
+    match outcome {
+        PreflightOutcome::Verified {
+            response, attempts, ..
+        } => {
+            info!(
+                "Attestation response to challenge {} passed local verification after {attempts} build(s)",
+                challenge.challenge_id
+            );
+            Ok(Some(BuiltResponse {
+                payload: response,
+                inputs: last_inputs.into_inner().unwrap_or_else(|e| e.into_inner()),
+            }))
+        }
+        PreflightOutcome::DeadLettered(letter) => {
+            if let Err(e) = ctx.notifier.notify(letter.alert()).await {
+                warn!("Failed to deliver dead letter alert: {e}");
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Builds the response to `challenge` in `encoder`'s schema from what the TEE reports now.
+async fn build_response(
+    tee: &TeeHandler,
+    challenge: &ObservedChallenge,
+    encoder: &dyn ResponseEncoder,
+) -> Result<BuiltResponse, PhalaAvsError> {
+    let inputs = response_inputs(tee, challenge, encoder).await?;
+    Ok(BuiltResponse {
+        payload: encoder.encode(challenge, &inputs)?,
+        inputs: Some(inputs),
+    })
+}
+
+/// What a response in `encoder`'s schema is built from: the TEE's liveness, its computation for
+/// `tee_compute` kinds, or a quote bound to the challenge for attestation kinds.
+async fn response_inputs(
+    tee: &TeeHandler,
+    challenge: &ObservedChallenge,
+    encoder: &dyn ResponseEncoder,
+) -> Result<ResponseInputs, PhalaAvsError> {
+    let mut inputs = ResponseInputs {
+        responded_at_unix: now_unix(),
+        ..ResponseInputs::default()
+    };
+    match encoder.kind_name() {
+        LIVENESS_KIND => inputs.live = tee.check_liveness().await?.is_live(),
+        ATTESTATION_KIND => {
+            let nonce = attestation_challenge(challenge)?.nonce;
+            let quoted_at_unix = inputs.responded_at_unix;
+            let report_data = attestation_report_data(
+                challenge.challenge_id,
+                nonce,
+                quoted_at_unix,
+                BuildInfo::current().build_hash,
+            );
+            let bundle = tee.quote_bundle(report_data).await?;
+            inputs.attestation = Some(AttestationQuote {
+                platform: tee.platform(),
+                quoted_at_unix,
+                quote: bundle.raw.into(),
+            });
+        }
+        _ if encoder.tee_compute() => {
+            let requested = compute_challenge(challenge)?;
+            // The oracle's parameters are all a computation challenge gives the program.
+            let computation = tee
+                .compute_in_tee(requested.programId, requested.params, Bytes::new())
+                .await?;
+            inputs.computation = Some(computation);
+        }
+        _ => {}
+    }
+    Ok(inputs)
+}
+
+/// The artifacts behind a response: the inputs it was built from, with the quote and the
+/// computation's output and attested hashes, and the SLA proof recorded alongside it.
+fn response_artifacts(inputs: Option<&ResponseInputs>, sla_proof: Option<&Bytes>) -> Vec<Artifact> {
+    let mut artifacts = Vec::new();
+    if let Some(inputs) = inputs {
+        let summary = serde_json::json!({
+            "responded_at_unix": inputs.responded_at_unix,
+            "live": inputs.live,
+            "compute_binding": inputs.computation.as_ref().map(|c| c.binding()),
+            "compute_binding_digest": inputs.computation.as_ref().map(|c| c.binding().digest()),
+            "platform": inputs.attestation.as_ref().map(|a| a.platform),
+            "quoted_at_unix": inputs.attestation.as_ref().map(|a| a.quoted_at_unix),
+        });
+        artifacts.push(Artifact::new(
+            "inputs.json",
+            summary.to_string().into_bytes(),
+        ));
+        if let Some(computation) = &inputs.computation {
+            artifacts.push(Artifact::new(
+                "compute_output",
+                computation.output().clone(),
+            ));
+            artifacts.push(Artifact::new(
+                "compute_attestation",
+                computation.attestation().clone(),
+            ));
+        }
+        if let Some(attestation) = &inputs.attestation {
+            artifacts.push(Artifact::new("quote", attestation.quote.clone()));
+        }
+    }
+    if let Some(proof) = sla_proof {
+        artifacts.push(Artifact::new("sla_proof", proof.clone()));
+    }
+    artifacts
 }
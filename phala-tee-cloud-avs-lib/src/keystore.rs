@@ -0,0 +1,881 @@
+//! Zero-downtime migration of the signing keystore to another backend.
+//!
+//! A secondary backend, e.g. a remote signer at `SIGNER_SECONDARY_URL`, is configured next to
+//! the primary. The migration moves through three persisted phases, so a restart resumes the
+//! right one:
+//!
+//! - `idle`: the primary signs;
+//! - `soaking`, entered by [`KeystoreMigration::start`] once a canary digest signed by both
+//!   backends recovers to the same address, with the same BLS public key and signature: the
+//!   secondary signs, and any failure of it, or a signature not recovering to the operator's
+//!   address, falls back to the primary and is alerted on. The soak lasts
+//!   `SIGNER_MIGRATION_SOAK_SECS`;
+//! - `finalized`, entered by [`KeystoreMigration::finalize`] after a soak without fallbacks:
+//!   the primary is out of the signing path.
+//!
+//! [`KeystoreMigration::abort`] returns to the primary from either. A remote signer serves
+//! `GET /identity` with a [`KeyIdentity`] and `POST /sign` of `{"digest": ...}` with a
+//! [`BackendSignature`], bearer-authenticated with `SIGNER_SECONDARY_TOKEN` when set.
+
+use crate::config::{env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::signing::DigestSigner;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, PrimitiveSignature, keccak256};
+use blueprint_sdk::alloy::signers::SignerSync;
+use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// State store namespace of the migration.
+pub const NAMESPACE: &str = "keystore_migration";
+const STATE_KEY: &[u8] = b"state";
+
+/// Gauge: 1 for the migration's current phase, 0 for the others.
+pub const KEYSTORE_PHASE_METRIC: &str = "phala_avs_keystore_migration_phase";
+/// Counter of signing operations the secondary failed during the soak and the primary signed.
+pub const KEYSTORE_FALLBACKS_METRIC: &str = "phala_avs_keystore_fallbacks_total";
+
+const SOURCE: &str = "keystore";
+/// Domain of the canary digest, so a canary signature is never a valid signature of anything
+/// else.
+const CANARY_DOMAIN: &[u8] = b"phala-avs:keystore-migration-canary:v1";
+
+/// The keys a backend signs with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyIdentity {
+    pub address: Address,
+    /// Compressed BLS public key, for backends holding one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bls_pubkey: Option<Bytes>,
+}
+
+/// A backend's signature of a digest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackendSignature {
+    /// 65-byte ECDSA signature of the digest itself.
+    pub ecdsa: Bytes,
+    /// BLS signature of the digest, for backends holding a BLS key. BLS signing is
+    /// deterministic, so the same key always produces the same signature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bls: Option<Bytes>,
+}
+
+impl BackendSignature {
+    /// The address the ECDSA signature of `digest` recovers to.
+    pub fn signer(&self, digest: B256) -> Result<Address, PhalaAvsError> {
+        PrimitiveSignature::try_from(self.ecdsa.as_ref())
+            .and_then(|signature| signature.recover_address_from_prehash(&digest))
+            .map_err(|e| PhalaAvsError::ValidationError(format!("Invalid signature: {e}")))
+    }
+}
+
+/// A keystore backend.
+pub trait SignerBackend: Send + Sync {
+    /// Names the backend in logs and reports, e.g. `local`.
+    fn name(&self) -> &str;
+
+    fn identity(&self) -> BoxFuture<'_, Result<KeyIdentity, PhalaAvsError>>;
+
+    fn sign(&self, digest: B256) -> BoxFuture<'_, Result<BackendSignature, PhalaAvsError>>;
+}
+
+/// A key held in process, e.g. the operator's `PRIVATE_KEY`.
+pub struct LocalKeyBackend {
+    signer: PrivateKeySigner,
+}
+
+impl LocalKeyBackend {
+    pub fn new(signer: PrivateKeySigner) -> Self {
+        Self { signer }
+    }
+}
+
+impl SignerBackend for LocalKeyBackend {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn identity(&self) -> BoxFuture<'_, Result<KeyIdentity, PhalaAvsError>> {
+        let identity = KeyIdentity {
+            address: self.signer.address(),
+            bls_pubkey: None,
+        };
+        Box::pin(async move { Ok(identity) })
+    }
+
+    fn sign(&self, digest: B256) -> BoxFuture<'_, Result<BackendSignature, PhalaAvsError>> {
+        let signature = self
+            .signer
+            .sign_hash_sync(&digest)
+            .map(|signature| BackendSignature {
+                ecdsa: Bytes::copy_from_slice(&signature.as_bytes()),
+                bls: None,
+            })
+            .map_err(|e| PhalaAvsError::Other(format!("Local signing failed: {e}")));
+        Box::pin(async move { signature })
+    }
+}
+
+/// A remote signer over HTTP.
+pub struct RemoteSignerBackend {
+    url: String,
+    token: Option<String>,
+    client: reqwest::Client,
+}
+
+impl RemoteSignerBackend {
+    pub fn new(url: String, token: Option<String>) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            token,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn send<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+        endpoint: &str,
+    ) -> Result<T, PhalaAvsError> {
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response = request
+            .send()
+            .await
+            .map_err(|e| PhalaAvsError::Other(format!("Failed to reach the remote signer: {e}")))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(PhalaAvsError::Other(format!(
+                "Remote signer returned {status} for {endpoint}"
+            )));
+        }
+        response.json().await.map_err(|e| {
+            PhalaAvsError::Other(format!(
+                "Invalid {endpoint} reply from the remote signer: {e}"
+            ))
+        })
+    }
+}
+
+impl SignerBackend for RemoteSignerBackend {
+    fn name(&self) -> &str {
+        "remote"
+    }
+
+    fn identity(&self) -> BoxFuture<'_, Result<KeyIdentity, PhalaAvsError>> {
+        Box::pin(async move {
+            let request = self.client.get(format!("{}/identity", self.url));
+            self.send(request, "identity").await
+        })
+    }
+
+    fn sign(&self, digest: B256) -> BoxFuture<'_, Result<BackendSignature, PhalaAvsError>> {
+        Box::pin(async move {
+            let request = self
+                .client
+                .post(format!("{}/sign", self.url))
+                .json(&serde_json::json!({ "digest": digest }));
+            self.send(request, "sign").await
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KeystoreMigrationConfig {
+    /// The remote signer migrated to.
+    pub secondary_url: Option<String>,
+    pub secondary_token: Option<String>,
+    /// How long the secondary signs with the primary as fallback before it can be finalized.
+    pub soak: Duration,
+    /// How often fallbacks and the end of the soak are checked for alerts.
+    pub check_secs: u64,
+}
+
+impl Default for KeystoreMigrationConfig {
+    fn default() -> Self {
+        Self {
+            secondary_url: None,
+            secondary_token: None,
+            soak: Duration::from_secs(86_400),
+            check_secs: 60,
+        }
+    }
+}
+
+impl KeystoreMigrationConfig {
+    /// Reads `SIGNER_SECONDARY_URL`, `SIGNER_SECONDARY_TOKEN`, `SIGNER_MIGRATION_SOAK_SECS` and
+    /// `SIGNER_MIGRATION_CHECK_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        Ok(Self {
+            secondary_url: env_opt("SIGNER_SECONDARY_URL"),
+            secondary_token: env_opt("SIGNER_SECONDARY_TOKEN"),
+            soak: Duration::from_secs(env_or(
+                "SIGNER_MIGRATION_SOAK_SECS",
+                defaults.soak.as_secs(),
+            )?),
+            check_secs: env_or("SIGNER_MIGRATION_CHECK_SECS", defaults.check_secs)?.max(1),
+        })
+    }
+
+    /// The configured secondary backend, if any.
+    pub fn secondary(&self) -> Option<Arc<dyn SignerBackend>> {
+        self.secondary_url.clone().map(|url| {
+            Arc::new(RemoteSignerBackend::new(url, self.secondary_token.clone()))
+                as Arc<dyn SignerBackend>
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationPhase {
+    /// The primary signs.
+    #[default]
+    Idle,
+    /// The secondary signs, falling back to the primary.
+    Soaking,
+    /// The secondary alone signs.
+    Finalized,
+}
+
+impl MigrationPhase {
+    pub const ALL: [Self; 3] = [Self::Idle, Self::Soaking, Self::Finalized];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Idle => "idle",
+            Self::Soaking => "soaking",
+            Self::Finalized => "finalized",
+        }
+    }
+}
+
+/// The canary check run before the soak.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanaryReport {
+    pub digest: B256,
+    pub checked_unix_ms: u64,
+    pub primary: KeyIdentity,
+    pub secondary: KeyIdentity,
+    /// What differs between the backends; empty if the check passed.
+    pub mismatches: Vec<String>,
+}
+
+impl CanaryReport {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// The persisted migration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationState {
+    pub phase: MigrationPhase,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_unix_ms: Option<u64>,
+    /// When the soak ends and the migration can be finalized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soak_until_unix_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finalized_unix_ms: Option<u64>,
+    /// Signing operations the secondary failed during the soak, which the primary signed.
+    #[serde(default)]
+    pub fallbacks: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_fallback: Option<String>,
+    /// The latest canary check, passed or not.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canary: Option<CanaryReport>,
+    /// Why the latest migration was aborted, if it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aborted: Option<String>,
+}
+
+/// The operator's signing keystore, migrating from the primary backend to the secondary.
+pub struct KeystoreMigration {
+    config: KeystoreMigrationConfig,
+    primary: Arc<dyn SignerBackend>,
+    secondary: Option<Arc<dyn SignerBackend>>,
+    store: Arc<dyn StateStore>,
+    state: Mutex<MigrationState>,
+    /// Fallbacks already alerted on.
+    alerted_fallbacks: AtomicU64,
+    alerted_soak: AtomicBool,
+}
+
+impl KeystoreMigration {
+    /// Restores the persisted phase. Fails if a migration is under way without a secondary.
+    pub fn new(
+        config: KeystoreMigrationConfig,
+        primary: Arc<dyn SignerBackend>,
+        secondary: Option<Arc<dyn SignerBackend>>,
+        store: Arc<dyn StateStore>,
+    ) -> Result<Self, PhalaAvsError> {
+        let state: MigrationState = store.get_json(NAMESPACE, STATE_KEY)?.unwrap_or_default();
+        if state.phase != MigrationPhase::Idle && secondary.is_none() {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "The keystore migration is {} but SIGNER_SECONDARY_URL is not set; restore it or \
+                 abort the migration",
+                state.phase.as_str()
+            )));
+        }
+        if state.phase != MigrationPhase::Idle {
+            info!("Resuming the {} keystore migration", state.phase.as_str());
+        }
+        report_phase(state.phase);
+        Ok(Self {
+            config,
+            primary,
+            secondary,
+            store,
+            alerted_fallbacks: AtomicU64::new(state.fallbacks),
+            alerted_soak: AtomicBool::new(false),
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn config(&self) -> &KeystoreMigrationConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> MigrationState {
+        self.lock().clone()
+    }
+
+    /// Checks with a canary digest that the secondary signs as the primary, and starts the
+    /// soak if it does. The canary report is kept either way.
+    pub async fn start(&self, now_ms: u64) -> Result<MigrationState, PhalaAvsError> {
+        let secondary = self.secondary()?;
+        let phase = self.lock().phase;
+        if phase != MigrationPhase::Idle {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "A keystore migration is already {}",
+                phase.as_str()
+            )));
+        }
+        let canary = self.canary(secondary.as_ref(), now_ms).await?;
+        let mut state = self.lock();
+        let mut updated = state.clone();
+        updated.canary = Some(canary.clone());
+        if canary.passed() {
+            updated = MigrationState {
+                phase: MigrationPhase::Soaking,
+                started_unix_ms: Some(now_ms),
+                soak_until_unix_ms: Some(now_ms + self.config.soak.as_millis() as u64),
+                canary: Some(canary.clone()),
+                ..MigrationState::default()
+            };
+        }
+        self.persist(&mut state, updated)?;
+        if !canary.passed() {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "The {} signer failed the canary check: {}",
+                secondary.name(),
+                canary.mismatches.join("; ")
+            )));
+        }
+        self.alerted_fallbacks.store(0, Ordering::Relaxed);
+        self.alerted_soak.store(false, Ordering::Relaxed);
+        info!(
+            "Keystore migration to the {} signer started; soaking until {}",
+            secondary.name(),
+            now_ms + self.config.soak.as_millis() as u64
+        );
+        Ok(state.clone())
+    }
+
+    /// Takes the primary out of the signing path after a soak without fallbacks.
+    pub fn finalize(&self, now_ms: u64) -> Result<MigrationState, PhalaAvsError> {
+        let mut state = self.lock();
+        if state.phase != MigrationPhase::Soaking {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Only a soaking keystore migration can be finalized; it is {}",
+                state.phase.as_str()
+            )));
+        }
+        if state.fallbacks > 0 {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "The secondary signer fell back {} times during the soak, last: {}; abort and \
+                 start again",
+                state.fallbacks,
+                state.last_fallback.as_deref().unwrap_or("unknown")
+            )));
+        }
+        let soak_until = state.soak_until_unix_ms.unwrap_or_default();
+        if now_ms < soak_until {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "The soak ends in {}s",
+                (soak_until - now_ms).div_ceil(1000)
+            )));
+        }
+        let updated = MigrationState {
+            phase: MigrationPhase::Finalized,
+            finalized_unix_ms: Some(now_ms),
+            ..state.clone()
+        };
+        self.persist(&mut state, updated)?;
+        info!("Keystore migration finalized; the primary no longer signs");
+        Ok(state.clone())
+    }
+
+    /// Returns signing to the primary.
+    pub fn abort(&self, now_ms: u64) -> Result<MigrationState, PhalaAvsError> {
+        let mut state = self.lock();
+        if state.phase == MigrationPhase::Idle {
+            return Err(PhalaAvsError::ValidationError(
+                "No keystore migration is in progress".to_string(),
+            ));
+        }
+        let updated = MigrationState {
+            phase: MigrationPhase::Idle,
+            aborted: Some(format!(
+                "aborted while {} at {now_ms}",
+                state.phase.as_str()
+            )),
+            ..state.clone()
+        };
+        self.persist(&mut state, updated)?;
+        warn!("Keystore migration aborted; the primary signs again");
+        Ok(state.clone())
+    }
+
+    /// Alerts on fallbacks since the last check, and once on the soak ending cleanly.
+    pub fn check(&self, now_ms: u64) -> Vec<Alert> {
+        let state = self.state();
+        let mut alerts = Vec::new();
+        if state.phase != MigrationPhase::Soaking {
+            return alerts;
+        }
+        let alerted = self
+            .alerted_fallbacks
+            .swap(state.fallbacks, Ordering::Relaxed);
+        if state.fallbacks > alerted {
+            alerts.push(Alert::new(
+                SOURCE,
+                Severity::Critical,
+                format!(
+                    "The secondary signer failed {} signing operations the primary signed \
+                     instead ({} during this soak), last: {}",
+                    state.fallbacks - alerted,
+                    state.fallbacks,
+                    state.last_fallback.as_deref().unwrap_or("unknown")
+                ),
+            ));
+        }
+        let soaked = state
+            .soak_until_unix_ms
+            .is_some_and(|until| now_ms >= until);
+        if soaked && state.fallbacks == 0 && !self.alerted_soak.swap(true, Ordering::Relaxed) {
+            alerts.push(Alert::new(
+                SOURCE,
+                Severity::Info,
+                "The keystore migration soaked without fallbacks; run `keystore migrate \
+                 finalize` to take the primary out of the signing path",
+            ));
+        }
+        alerts
+    }
+
+    fn secondary(&self) -> Result<&Arc<dyn SignerBackend>, PhalaAvsError> {
+        self.secondary.as_ref().ok_or_else(|| {
+            PhalaAvsError::ConfigError("SIGNER_SECONDARY_URL is not set".to_string())
+        })
+    }
+
+    async fn canary(
+        &self,
+        secondary: &dyn SignerBackend,
+        now_ms: u64,
+    ) -> Result<CanaryReport, PhalaAvsError> {
+        let digest = keccak256([CANARY_DOMAIN, &now_ms.to_be_bytes()].concat());
+        let primary_identity = self.primary.identity().await?;
+        let secondary_identity = secondary.identity().await?;
+        let primary_signature = self.primary.sign(digest).await?;
+        let mut mismatches = Vec::new();
+        if secondary_identity.address != primary_identity.address {
+            mismatches.push(format!(
+                "secondary reports address {}, the primary {}",
+                secondary_identity.address, primary_identity.address
+            ));
+        }
+        match secondary.sign(digest).await {
+            Ok(signature) => {
+                match signature.signer(digest) {
+                    Ok(signer) if signer == primary_identity.address => {}
+                    Ok(signer) => mismatches.push(format!(
+                        "canary signature recovers to {signer}, expected {}",
+                        primary_identity.address
+                    )),
+                    Err(e) => mismatches.push(format!("canary signature: {e}")),
+                }
+                if let Some(pubkey) = &primary_identity.bls_pubkey {
+                    if secondary_identity.bls_pubkey.as_ref() != Some(pubkey) {
+                        mismatches.push("BLS public keys differ".to_string());
+                    } else if signature.bls != primary_signature.bls {
+                        mismatches.push("canary BLS signatures differ".to_string());
+                    }
+                }
+            }
+            Err(e) => mismatches.push(format!("canary signing failed: {e}")),
+        }
+        Ok(CanaryReport {
+            digest,
+            checked_unix_ms: now_ms,
+            primary: primary_identity,
+            secondary: secondary_identity,
+            mismatches,
+        })
+    }
+
+    /// Signs with the secondary, checking the signature recovers to the operator's address.
+    async fn sign_soaking(
+        &self,
+        secondary: &dyn SignerBackend,
+        expected: Option<Address>,
+        digest: B256,
+    ) -> Result<BackendSignature, PhalaAvsError> {
+        let signature = secondary.sign(digest).await?;
+        let signer = signature.signer(digest)?;
+        match expected {
+            Some(expected) if signer != expected => Err(PhalaAvsError::ValidationError(format!(
+                "signature recovers to {signer}, expected {expected}"
+            ))),
+            _ => Ok(signature),
+        }
+    }
+
+    fn record_fallback(&self, error: &PhalaAvsError) {
+        warn!("Secondary signer failed, falling back to the primary: {error}");
+        METRICS.inc_counter(KEYSTORE_FALLBACKS_METRIC, &[], 1);
+        let mut state = self.lock();
+        let mut updated = state.clone();
+        updated.fallbacks += 1;
+        updated.last_fallback = Some(error.to_string());
+        if let Err(e) = self.persist(&mut state, updated) {
+            warn!("Failed to record a keystore fallback: {e}");
+        }
+    }
+
+    fn persist(
+        &self,
+        state: &mut MigrationState,
+        updated: MigrationState,
+    ) -> Result<(), PhalaAvsError> {
+        self.store.put_json(NAMESPACE, STATE_KEY, &updated)?;
+        report_phase(updated.phase);
+        *state = updated;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MigrationState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl DigestSigner for KeystoreMigration {
+    type Signature = BackendSignature;
+
+    fn sign(&self, digest: B256) -> BoxFuture<'_, Result<BackendSignature, PhalaAvsError>> {
+        Box::pin(async move {
+            let (phase, expected) = {
+                let state = self.lock();
+                let expected = state.canary.as_ref().map(|canary| canary.primary.address);
+                (state.phase, expected)
+            };
+            match phase {
+                MigrationPhase::Idle => self.primary.sign(digest).await,
+                MigrationPhase::Finalized => self.secondary()?.sign(digest).await,
+                MigrationPhase::Soaking => {
+                    let secondary = self.secondary()?;
+                    match self
+                        .sign_soaking(secondary.as_ref(), expected, digest)
+                        .await
+                    {
+                        Ok(signature) => Ok(signature),
+                        Err(e) => {
+                            self.record_fallback(&e);
+                            self.primary.sign(digest).await
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn report_phase(current: MigrationPhase) {
+    for phase in MigrationPhase::ALL {
+        METRICS.set_gauge(
+            KEYSTORE_PHASE_METRIC,
+            &[("phase", phase.as_str())],
+            if phase == current { 1.0 } else { 0.0 },
+        );
+    }
+}
+
+/// Alerts on fallbacks and the end of the soak every `SIGNER_MIGRATION_CHECK_SECS`.
+pub fn spawn_monitor(migration: Arc<KeystoreMigration>, notifier: Arc<dyn Notifier>) {
+    if migration.secondary.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(migration.config.check_secs));
+        loop {
+            interval.tick().await;
+            for alert in migration.check(crate::evidence::now_unix_ms()) {
+                if let Err(e) = notifier.notify(alert).await {
+                    warn!("Failed to deliver keystore migration alert: {e}");
+                }
+            }
+        }
+    });
+}
+
+/// The running operator's keystore migration, through its status server.
+pub async fn fetch_migration(
+    operator_url: &str,
+    token: &str,
+) -> Result<MigrationState, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/keystore/migration",
+            operator_url.trim_end_matches('/')
+        ))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    read_reply(response).await
+}
+
+/// Runs `step` (`start`, `finalize` or `abort`) of the running operator's keystore migration
+/// through its admin API.
+pub async fn request_migration(
+    operator_url: &str,
+    token: &str,
+    step: &str,
+) -> Result<MigrationState, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/admin/keystore/migration/{step}",
+            operator_url.trim_end_matches('/')
+        ))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    read_reply(response).await
+}
+
+async fn read_reply(response: reqwest::Response) -> Result<MigrationState, PhalaAvsError> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(PhalaAvsError::Other(format!(
+            "Operator returned {status} for the keystore migration: {body}"
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to read the keystore migration: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+    use std::sync::atomic::AtomicUsize;
+
+    const HOUR_MS: u64 = 3_600_000;
+
+    /// A backend with an ECDSA key and a stand-in BLS key, whose BLS signatures are
+    /// deterministic like real ones.
+    struct MockBackend {
+        name: &'static str,
+        signer: PrivateKeySigner,
+        bls_key: Bytes,
+        failing: AtomicBool,
+        signed: AtomicUsize,
+    }
+
+    impl MockBackend {
+        fn new(name: &'static str, signer: PrivateKeySigner, bls_key: &[u8]) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                signer,
+                bls_key: Bytes::copy_from_slice(bls_key),
+                failing: AtomicBool::new(false),
+                signed: AtomicUsize::new(0),
+            })
+        }
+
+        fn signed(&self) -> usize {
+            self.signed.load(Ordering::SeqCst)
+        }
+    }
+
+    impl SignerBackend for MockBackend {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn identity(&self) -> BoxFuture<'_, Result<KeyIdentity, PhalaAvsError>> {
+            let identity = KeyIdentity {
+                address: self.signer.address(),
+                bls_pubkey: Some(keccak256(&self.bls_key).into()),
+            };
+            Box::pin(async move { Ok(identity) })
+        }
+
+        fn sign(&self, digest: B256) -> BoxFuture<'_, Result<BackendSignature, PhalaAvsError>> {
+            let result = if self.failing.load(Ordering::SeqCst) {
+                Err(PhalaAvsError::Other(
+                    "remote signer unavailable".to_string(),
+                ))
+            } else {
+                self.signed.fetch_add(1, Ordering::SeqCst);
+                Ok(BackendSignature {
+                    ecdsa: Bytes::copy_from_slice(
+                        &self.signer.sign_hash_sync(&digest).unwrap().as_bytes(),
+                    ),
+                    bls: Some(
+                        keccak256([self.bls_key.as_ref(), digest.as_slice()].concat()).into(),
+                    ),
+                })
+            };
+            Box::pin(async move { result })
+        }
+    }
+
+    fn migration(
+        primary: &Arc<MockBackend>,
+        secondary: &Arc<MockBackend>,
+        store: &Arc<dyn StateStore>,
+    ) -> KeystoreMigration {
+        let config = KeystoreMigrationConfig {
+            soak: Duration::from_millis(HOUR_MS),
+            ..KeystoreMigrationConfig::default()
+        };
+        KeystoreMigration::new(
+            config,
+            Arc::clone(primary) as _,
+            Some(Arc::clone(secondary) as _),
+            Arc::clone(store),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn canary_check_refuses_a_secondary_with_another_key() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let key = PrivateKeySigner::random();
+        let primary = MockBackend::new("primary", key.clone(), b"bls");
+
+        let wrong = MockBackend::new("wrong", PrivateKeySigner::random(), b"bls");
+        let keystore = migration(&primary, &wrong, &store);
+        let error = keystore.start(0).await.unwrap_err().to_string();
+        assert!(error.contains("canary signature recovers to"), "{error}");
+        let state = keystore.state();
+        assert_eq!(state.phase, MigrationPhase::Idle);
+        assert!(!state.canary.unwrap().passed());
+
+        // The same ECDSA key with another BLS key is refused too.
+        let other_bls = MockBackend::new("other-bls", key, b"other");
+        let keystore = migration(&primary, &other_bls, &store);
+        let error = keystore.start(0).await.unwrap_err().to_string();
+        assert!(error.contains("BLS public keys differ"), "{error}");
+
+        // Signing stays with the primary.
+        keystore.sign(B256::repeat_byte(1)).await.unwrap();
+        assert_eq!(other_bls.signed(), 1); // the canary
+        assert_eq!(primary.signed(), 3); // two canaries and the operation
+    }
+
+    #[tokio::test]
+    async fn clean_soak_finalizes_and_survives_a_restart() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let key = PrivateKeySigner::random();
+        let primary = MockBackend::new("primary", key.clone(), b"bls");
+        let secondary = MockBackend::new("secondary", key.clone(), b"bls");
+        let keystore = migration(&primary, &secondary, &store);
+
+        let state = keystore.start(0).await.unwrap();
+        assert_eq!(state.phase, MigrationPhase::Soaking);
+        assert_eq!(state.soak_until_unix_ms, Some(HOUR_MS));
+        let digest = B256::repeat_byte(7);
+        let signature = keystore.sign(digest).await.unwrap();
+        assert_eq!(signature.signer(digest).unwrap(), key.address());
+        assert_eq!((primary.signed(), secondary.signed()), (1, 2));
+
+        // Not before the soak ends.
+        assert!(keystore.finalize(HOUR_MS - 1).is_err());
+        assert!(keystore.check(HOUR_MS - 1).is_empty());
+        let alerts = keystore.check(HOUR_MS);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].severity, Severity::Info);
+
+        // A restart mid-soak resumes it.
+        let keystore = migration(&primary, &secondary, &store);
+        assert_eq!(keystore.state().phase, MigrationPhase::Soaking);
+        let state = keystore.finalize(HOUR_MS).unwrap();
+        assert_eq!(state.phase, MigrationPhase::Finalized);
+
+        let keystore = migration(&primary, &secondary, &store);
+        assert_eq!(keystore.state().phase, MigrationPhase::Finalized);
+        keystore.sign(digest).await.unwrap();
+        assert_eq!((primary.signed(), secondary.signed()), (1, 3));
+
+        // Without the secondary, the finalized migration cannot start.
+        let config = KeystoreMigrationConfig::default();
+        assert!(KeystoreMigration::new(config, primary as _, None, store).is_err());
+    }
+
+    #[tokio::test]
+    async fn secondary_failure_mid_soak_falls_back_without_losing_the_signature() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let key = PrivateKeySigner::random();
+        let primary = MockBackend::new("primary", key.clone(), b"bls");
+        let secondary = MockBackend::new("secondary", key.clone(), b"bls");
+        let keystore = migration(&primary, &secondary, &store);
+        keystore.start(0).await.unwrap();
+
+        secondary.failing.store(true, Ordering::SeqCst);
+        let digest = B256::repeat_byte(9);
+        let signature = keystore.sign(digest).await.unwrap();
+        assert_eq!(signature.signer(digest).unwrap(), key.address());
+        assert_eq!(primary.signed(), 2);
+
+        let state = keystore.state();
+        assert_eq!(state.fallbacks, 1);
+        assert!(
+            state
+                .last_fallback
+                .unwrap()
+                .contains("remote signer unavailable")
+        );
+        let alerts = keystore.check(1);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].severity, Severity::Critical);
+        assert!(keystore.check(2).is_empty());
+
+        // The soak was not clean, so it cannot be finalized; aborting reverts to the primary.
+        let error = keystore.finalize(HOUR_MS).unwrap_err().to_string();
+        assert!(error.contains("fell back 1 times"), "{error}");
+        secondary.failing.store(false, Ordering::SeqCst);
+        let state = keystore.abort(HOUR_MS).unwrap();
+        assert_eq!(state.phase, MigrationPhase::Idle);
+        keystore.sign(digest).await.unwrap();
+        assert_eq!((primary.signed(), secondary.signed()), (3, 1));
+    }
+}
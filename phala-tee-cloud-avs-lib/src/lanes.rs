@@ -0,0 +1,648 @@
+//! Signer accounts transactions are sent from, by urgency.
+//!
+//! Transactions from one account share one nonce sequence, so a queued deferrable transaction
+//! (an evidence anchor, a capacity update) can sit ahead of an urgent challenge response, and
+//! bumping the response's fee means bumping everything before it. With
+//! `MAINTENANCE_SIGNER_ENABLED`, deferrable transactions are sent from a second, separately
+//! funded account (`MAINTENANCE_SIGNER_KEY`) and the operator's `PRIVATE_KEY` is kept for
+//! deadline-bearing submissions. The operator designates the second account on-chain with
+//! `setMaintenanceSigner`, so the service manager attributes its transactions to the operator.
+//!
+//! Each [`Lane`] hands out its own nonces and has its balance watched every
+//! `SIGNER_BALANCE_CHECK_SECS`. While the maintenance account is below `SIGNER_MIN_BALANCE_WEI`,
+//! deferrable work is held back rather than sent from the primary account, unless
+//! `MAINTENANCE_SIGNER_FALLBACK` is set.
+
+use crate::config::{env_flag, env_opt, env_or};
+use crate::display::Addr;
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::{IPhalaServiceManager, PRIVATE_KEY};
+use blueprint_sdk::alloy::primitives::{Address, B256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Gauge of each signer account's balance, by lane.
+pub const LANE_BALANCE_METRIC: &str = "phala_avs_signer_balance_wei";
+/// Counter of transactions sent, by lane and class.
+pub const LANE_TRANSACTIONS_METRIC: &str = "phala_avs_signer_transactions_total";
+
+/// How a transaction may be scheduled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxClass {
+    /// Bound to a deadline, e.g. a challenge response.
+    Urgent,
+    /// Can wait, e.g. evidence anchors and capacity updates.
+    Deferrable,
+}
+
+impl TxClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TxClass::Urgent => "urgent",
+            TxClass::Deferrable => "deferrable",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LaneId {
+    /// The operator's own account.
+    Primary,
+    /// The account designated for deferrable transactions.
+    Maintenance,
+}
+
+impl LaneId {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LaneId::Primary => "primary",
+            LaneId::Maintenance => "maintenance",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LaneConfig {
+    /// Key of the maintenance account; `None` sends everything from the primary account.
+    pub maintenance_key: Option<String>,
+    /// Send deferrable work from the primary account while the maintenance one is unfunded.
+    pub fallback_to_primary: bool,
+    pub min_balance_wei: u128,
+    pub check_secs: u64,
+}
+
+impl fmt::Debug for LaneConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LaneConfig")
+            .field(
+                "maintenance_key",
+                &self.maintenance_key.as_ref().map(|_| "<redacted>"),
+            )
+            .field("fallback_to_primary", &self.fallback_to_primary)
+            .field("min_balance_wei", &self.min_balance_wei)
+            .field("check_secs", &self.check_secs)
+            .finish()
+    }
+}
+
+impl LaneConfig {
+    /// Reads `MAINTENANCE_SIGNER_ENABLED` (off by default), `MAINTENANCE_SIGNER_KEY`,
+    /// `MAINTENANCE_SIGNER_FALLBACK` (off), `SIGNER_MIN_BALANCE_WEI` and
+    /// `SIGNER_BALANCE_CHECK_SECS`. Enabling the split without a key is an error.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let enabled = env_flag("MAINTENANCE_SIGNER_ENABLED", false)?;
+        let key: Option<String> = env_opt("MAINTENANCE_SIGNER_KEY")?;
+        if enabled && key.is_none() {
+            return Err(PhalaAvsError::ConfigError(
+                "MAINTENANCE_SIGNER_ENABLED is set but MAINTENANCE_SIGNER_KEY is missing"
+                    .to_string(),
+            ));
+        }
+        Ok(Self {
+            maintenance_key: key.filter(|_| enabled),
+            fallback_to_primary: env_flag("MAINTENANCE_SIGNER_FALLBACK", false)?,
+            min_balance_wei: env_or("SIGNER_MIN_BALANCE_WEI", 10_000_000_000_000_000)?,
+            check_secs: env_or("SIGNER_BALANCE_CHECK_SECS", 300)?,
+        })
+    }
+}
+
+/// Account state the lanes need from the chain.
+pub trait AccountSource: Send + Sync {
+    /// Transactions sent from `account`, counting pending ones: its next nonce.
+    fn pending_nonce(&self, account: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+
+    fn balance(&self, account: Address) -> BoxFuture<'_, Result<u128, PhalaAvsError>>;
+
+    /// The maintenance signer `operator` designated with the service manager, zero if none.
+    fn maintenance_signer(
+        &self,
+        operator: Address,
+    ) -> BoxFuture<'_, Result<Address, PhalaAvsError>>;
+}
+
+/// [`AccountSource`] backed by an alloy [`Provider`].
+#[derive(Clone, Debug)]
+pub struct ProviderAccountSource<P> {
+    provider: P,
+    service_manager: Address,
+}
+
+impl<P> ProviderAccountSource<P> {
+    pub fn new(provider: P, service_manager: Address) -> Self {
+        Self {
+            provider,
+            service_manager,
+        }
+    }
+}
+
+impl<P: Provider + Send + Sync + 'static> AccountSource for ProviderAccountSource<P> {
+    fn pending_nonce(&self, account: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_transaction_count(account)
+                .pending()
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::EvmError(format!("eth_getTransactionCount failed: {e}"))
+                })
+        })
+    }
+
+    fn balance(&self, account: Address) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_balance(account)
+                .await
+                .map(|balance| balance.saturating_to())
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_getBalance failed: {e}")))
+        })
+    }
+
+    fn maintenance_signer(
+        &self,
+        operator: Address,
+    ) -> BoxFuture<'_, Result<Address, PhalaAvsError>> {
+        Box::pin(async move {
+            IPhalaServiceManager::new(self.service_manager, &self.provider)
+                .maintenanceSigners(operator)
+                .call()
+                .await
+                .map(|r| r._0)
+                .map_err(|e| PhalaAvsError::EvmError(format!("maintenanceSigners failed: {e}")))
+        })
+    }
+}
+
+/// One signer account and its nonce sequence.
+pub struct Lane {
+    id: LaneId,
+    address: Address,
+    private_key: String,
+    /// The next nonce to hand out, once read from the chain.
+    next_nonce: Mutex<Option<u64>>,
+    balance_wei: Mutex<Option<u128>>,
+    sent: AtomicU64,
+}
+
+impl Lane {
+    fn new(id: LaneId, private_key: &str) -> Result<Self, PhalaAvsError> {
+        let address = private_key
+            .parse::<PrivateKeySigner>()
+            .map_err(|e| PhalaAvsError::ConfigError(format!("Invalid {} key: {e}", id.as_str())))?
+            .address();
+        Ok(Self {
+            id,
+            address,
+            private_key: private_key.to_string(),
+            next_nonce: Mutex::new(None),
+            balance_wei: Mutex::new(None),
+            sent: AtomicU64::new(0),
+        })
+    }
+
+    pub fn id(&self) -> LaneId {
+        self.id
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// The key to sign this lane's transactions with.
+    pub fn private_key(&self) -> &str {
+        &self.private_key
+    }
+
+    /// The last balance read, `None` before the first check.
+    pub fn balance(&self) -> Option<u128> {
+        *self.balance_wei.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn nonce(&self) -> std::sync::MutexGuard<'_, Option<u64>> {
+        self.next_nonce.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for Lane {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lane")
+            .field("id", &self.id)
+            .field("address", &Addr(self.address))
+            .finish_non_exhaustive()
+    }
+}
+
+/// A lane as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LaneStatus {
+    pub lane: LaneId,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance_wei: Option<u128>,
+    pub funded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_nonce: Option<u64>,
+    /// Transactions sent from the account since startup.
+    pub sent: u64,
+}
+
+/// The operator's signer accounts, and which one each class of transaction is sent from.
+pub struct SignerLanes {
+    config: LaneConfig,
+    primary: Lane,
+    maintenance: Option<Lane>,
+    accounts: Arc<dyn AccountSource>,
+}
+
+impl SignerLanes {
+    pub fn new(
+        config: LaneConfig,
+        primary_key: &str,
+        accounts: Arc<dyn AccountSource>,
+    ) -> Result<Self, PhalaAvsError> {
+        let primary = Lane::new(LaneId::Primary, primary_key)?;
+        let maintenance = config
+            .maintenance_key
+            .as_deref()
+            .map(|key| Lane::new(LaneId::Maintenance, key))
+            .transpose()?;
+        if maintenance
+            .as_ref()
+            .is_some_and(|m| m.address == primary.address)
+        {
+            return Err(PhalaAvsError::ConfigError(
+                "MAINTENANCE_SIGNER_KEY must be a different account than PRIVATE_KEY".to_string(),
+            ));
+        }
+        Ok(Self {
+            config,
+            primary,
+            maintenance,
+            accounts,
+        })
+    }
+
+    /// Uses the operator's `PRIVATE_KEY` as the primary account.
+    pub fn from_env(accounts: Arc<dyn AccountSource>) -> Result<Self, PhalaAvsError> {
+        Self::new(LaneConfig::from_env()?, &PRIVATE_KEY, accounts)
+    }
+
+    pub fn config(&self) -> &LaneConfig {
+        &self.config
+    }
+
+    /// Whether deferrable transactions have an account of their own.
+    pub fn is_split(&self) -> bool {
+        self.maintenance.is_some()
+    }
+
+    fn lanes(&self) -> impl Iterator<Item = &Lane> {
+        std::iter::once(&self.primary).chain(&self.maintenance)
+    }
+
+    /// The lane `id`, if configured.
+    pub fn lane(&self, id: LaneId) -> Option<&Lane> {
+        self.lanes().find(|lane| lane.id == id)
+    }
+
+    fn is_funded(&self, lane: &Lane) -> bool {
+        lane.balance()
+            .is_none_or(|balance| balance >= self.config.min_balance_wei)
+    }
+
+    /// The lane `class` is sent from, or `None` while deferrable work is held back.
+    pub fn route(&self, class: TxClass) -> Option<&Lane> {
+        match (class, &self.maintenance) {
+            (TxClass::Urgent, _) | (_, None) => Some(&self.primary),
+            (TxClass::Deferrable, Some(maintenance)) if self.is_funded(maintenance) => {
+                Some(maintenance)
+            }
+            (TxClass::Deferrable, Some(_)) => {
+                self.config.fallback_to_primary.then_some(&self.primary)
+            }
+        }
+    }
+
+    /// Whether `class` transactions are held back for now.
+    pub fn holds(&self, class: TxClass) -> bool {
+        self.route(class).is_none()
+    }
+
+    /// Like [`route`](Self::route), failing while the work is held back.
+    pub fn signer_for(&self, class: TxClass) -> Result<&Lane, PhalaAvsError> {
+        self.route(class).ok_or_else(|| {
+            PhalaAvsError::EvmError(format!(
+                "{} transactions are held: the maintenance account is below \
+                 SIGNER_MIN_BALANCE_WEI",
+                class.as_str()
+            ))
+        })
+    }
+
+    /// Reads the balance of the account `class` is sent from, leaving the monitored balances
+    /// and their alerts to [`check_balances`](Self::check_balances).
+    pub async fn read_balance(&self, class: TxClass) -> Result<u128, PhalaAvsError> {
+        let lane = self.signer_for(class)?;
+        self.accounts.balance(lane.address).await
+    }
+
+    /// Hands out `lane`'s next nonce, reading it from the chain on first use and after a
+    /// failed send.
+    pub async fn next_nonce(&self, lane: &Lane) -> Result<u64, PhalaAvsError> {
+        if let Some(next) = lane.nonce().as_mut() {
+            *next += 1;
+            return Ok(*next - 1);
+        }
+        let pending = self.accounts.pending_nonce(lane.address).await?;
+        // Another sender may have read it meanwhile; the sequence it started wins.
+        let mut nonce = lane.nonce();
+        let next = nonce.get_or_insert(pending);
+        *next += 1;
+        Ok(*next - 1)
+    }
+
+    /// Counts a transaction sent from `lane` towards its account.
+    pub fn record_sent(&self, lane: &Lane, class: TxClass, tx: B256) {
+        lane.sent.fetch_add(1, Ordering::Relaxed);
+        METRICS.inc_counter(
+            LANE_TRANSACTIONS_METRIC,
+            &[("lane", lane.id.as_str()), ("class", class.as_str())],
+            1,
+        );
+        info!(
+            lane = lane.id.as_str(),
+            account = %Addr(lane.address),
+            "Sent {} transaction {tx}",
+            class.as_str()
+        );
+    }
+
+    /// Forgets `lane`'s nonce after a failed send, so the next one is read from the chain and
+    /// no gap is left behind.
+    pub fn record_failed(&self, lane: &Lane) {
+        *lane.nonce() = None;
+    }
+
+    /// Fails when the maintenance account is not the one `operator` designated on-chain, since
+    /// its transactions would then not be attributed to the operator.
+    pub async fn verify_designation(&self, operator: Address) -> Result<(), PhalaAvsError> {
+        let Some(maintenance) = &self.maintenance else {
+            return Ok(());
+        };
+        let designated = self.accounts.maintenance_signer(operator).await?;
+        if designated != maintenance.address {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "The maintenance account {} is not designated for operator {} (found {}); call \
+                 setMaintenanceSigner from the operator account first",
+                Addr(maintenance.address),
+                Addr(operator),
+                Addr(designated)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads every account's balance, returning an alert for each that dropped below
+    /// `SIGNER_MIN_BALANCE_WEI`.
+    pub async fn check_balances(&self) -> Result<Vec<Alert>, PhalaAvsError> {
+        let mut alerts = Vec::new();
+        for lane in self.lanes() {
+            let balance = self.accounts.balance(lane.address).await?;
+            let was_funded = self.is_funded(lane);
+            *lane.balance_wei.lock().unwrap_or_else(|e| e.into_inner()) = Some(balance);
+            METRICS.set_gauge(
+                LANE_BALANCE_METRIC,
+                &[("lane", lane.id.as_str())],
+                balance as f64,
+            );
+            match (was_funded, self.is_funded(lane)) {
+                (true, false) => alerts.push(self.underfunded(lane, balance)),
+                (false, true) => info!(
+                    "The {} account {} is funded again",
+                    lane.id.as_str(),
+                    Addr(lane.address)
+                ),
+                _ => {}
+            }
+        }
+        Ok(alerts)
+    }
+
+    fn underfunded(&self, lane: &Lane, balance: u128) -> Alert {
+        let (severity, consequence) = match lane.id {
+            LaneId::Primary => (
+                Severity::Critical,
+                "challenge responses may fail to be included",
+            ),
+            LaneId::Maintenance if self.config.fallback_to_primary => (
+                Severity::Warning,
+                "deferrable transactions are sent from the primary account",
+            ),
+            LaneId::Maintenance => (Severity::Warning, "deferrable transactions are held"),
+        };
+        Alert::new(
+            "signers",
+            severity,
+            format!(
+                "The {} account {} holds {balance} wei, below the {} wei minimum; {consequence}",
+                lane.id.as_str(),
+                Addr(lane.address),
+                self.config.min_balance_wei
+            ),
+        )
+    }
+
+    pub fn status(&self) -> Vec<LaneStatus> {
+        self.lanes()
+            .map(|lane| LaneStatus {
+                lane: lane.id,
+                address: Addr(lane.address).to_string(),
+                balance_wei: lane.balance(),
+                funded: self.is_funded(lane),
+                next_nonce: *lane.nonce(),
+                sent: lane.sent.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// Checks the signer accounts' balances every `check_secs`.
+pub fn spawn_balance_monitor(lanes: Arc<SignerLanes>, notifier: Arc<dyn Notifier>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(lanes.config.check_secs));
+        loop {
+            interval.tick().await;
+            match lanes.check_balances().await {
+                Ok(alerts) => {
+                    for alert in alerts {
+                        if let Err(e) = notifier.notify(alert).await {
+                            warn!("Failed to deliver signer balance alert: {e}");
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to check signer balances: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const PRIMARY_KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const MAINTENANCE_KEY: &str =
+        "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+
+    /// A chain holding each account's balance and sent nonces.
+    #[derive(Default)]
+    struct MockAccounts {
+        nonces: Mutex<HashMap<Address, u64>>,
+        balances: Mutex<HashMap<Address, u128>>,
+        designated: Mutex<Address>,
+    }
+
+    impl AccountSource for MockAccounts {
+        fn pending_nonce(&self, account: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            let nonce = self.nonces.lock().unwrap().get(&account).copied();
+            Box::pin(async move { Ok(nonce.unwrap_or_default()) })
+        }
+
+        fn balance(&self, account: Address) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+            let balance = self.balances.lock().unwrap().get(&account).copied();
+            Box::pin(async move { Ok(balance.unwrap_or_default()) })
+        }
+
+        fn maintenance_signer(
+            &self,
+            _operator: Address,
+        ) -> BoxFuture<'_, Result<Address, PhalaAvsError>> {
+            let designated = *self.designated.lock().unwrap();
+            Box::pin(async move { Ok(designated) })
+        }
+    }
+
+    fn config(maintenance_key: Option<&str>, fallback_to_primary: bool) -> LaneConfig {
+        LaneConfig {
+            maintenance_key: maintenance_key.map(str::to_string),
+            fallback_to_primary,
+            min_balance_wei: 1_000,
+            check_secs: 60,
+        }
+    }
+
+    fn lanes(config: LaneConfig) -> (SignerLanes, Arc<MockAccounts>) {
+        let accounts = Arc::new(MockAccounts::default());
+        let lanes = SignerLanes::new(config, PRIMARY_KEY, Arc::clone(&accounts) as _).unwrap();
+        (lanes, accounts)
+    }
+
+    #[tokio::test]
+    async fn urgent_nonces_never_follow_deferrable_ones() {
+        let (lanes, accounts) = lanes(config(Some(MAINTENANCE_KEY), false));
+        let primary = lanes.route(TxClass::Urgent).unwrap().address();
+        accounts.nonces.lock().unwrap().insert(primary, 7);
+
+        // Interleaved work: every urgent transaction takes the next primary nonce, however many
+        // deferrable ones were queued before it.
+        let mut urgent = Vec::new();
+        let mut deferrable = Vec::new();
+        for round in 0..4 {
+            for _ in 0..round {
+                let lane = lanes.signer_for(TxClass::Deferrable).unwrap();
+                deferrable.push((lane.address(), lanes.next_nonce(lane).await.unwrap()));
+            }
+            let lane = lanes.signer_for(TxClass::Urgent).unwrap();
+            urgent.push((lane.address(), lanes.next_nonce(lane).await.unwrap()));
+        }
+        assert_eq!(urgent, (7..11).map(|n| (primary, n)).collect::<Vec<_>>());
+        assert!(deferrable.iter().all(|(account, _)| *account != primary));
+        assert_eq!(
+            deferrable.iter().map(|(_, n)| *n).collect::<Vec<_>>(),
+            (0..6).collect::<Vec<_>>()
+        );
+
+        // A failed send re-reads the nonce instead of leaving a gap.
+        let lane = lanes.signer_for(TxClass::Urgent).unwrap();
+        lanes.record_failed(lane);
+        accounts.nonces.lock().unwrap().insert(primary, 10);
+        assert_eq!(lanes.next_nonce(lane).await.unwrap(), 10);
+    }
+
+    #[tokio::test]
+    async fn an_unfunded_maintenance_account_holds_deferrable_work() {
+        let (lanes, accounts) = lanes(config(Some(MAINTENANCE_KEY), false));
+        let primary = lanes.primary.address();
+        let maintenance = lanes.maintenance.as_ref().unwrap().address();
+        accounts.balances.lock().unwrap().insert(primary, 5_000);
+        accounts.balances.lock().unwrap().insert(maintenance, 10);
+
+        let alerts = lanes.check_balances().await.unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].severity, Severity::Warning);
+        assert!(lanes.holds(TxClass::Deferrable));
+        assert!(lanes.signer_for(TxClass::Deferrable).is_err());
+        assert_eq!(lanes.route(TxClass::Urgent).unwrap().address(), primary);
+        // Still underfunded: alerted once.
+        assert!(lanes.check_balances().await.unwrap().is_empty());
+
+        accounts.balances.lock().unwrap().insert(maintenance, 5_000);
+        assert!(lanes.check_balances().await.unwrap().is_empty());
+        assert_eq!(
+            lanes.route(TxClass::Deferrable).unwrap().address(),
+            maintenance
+        );
+
+        let (fallback, accounts) = self::lanes(config(Some(MAINTENANCE_KEY), true));
+        accounts.balances.lock().unwrap().insert(primary, 5_000);
+        fallback.check_balances().await.unwrap();
+        assert_eq!(
+            fallback.route(TxClass::Deferrable).unwrap().address(),
+            primary
+        );
+    }
+
+    #[tokio::test]
+    async fn transactions_are_attributed_to_their_account() {
+        let (lanes, accounts) = lanes(config(Some(MAINTENANCE_KEY), false));
+        let maintenance = lanes.signer_for(TxClass::Deferrable).unwrap();
+        lanes.record_sent(maintenance, TxClass::Deferrable, B256::ZERO);
+        lanes.record_sent(maintenance, TxClass::Deferrable, B256::ZERO);
+        let primary = lanes.signer_for(TxClass::Urgent).unwrap();
+        lanes.record_sent(primary, TxClass::Urgent, B256::ZERO);
+        let status = lanes.status();
+        assert_eq!(
+            status.iter().map(|s| (s.lane, s.sent)).collect::<Vec<_>>(),
+            vec![(LaneId::Primary, 1), (LaneId::Maintenance, 2)]
+        );
+
+        assert!(lanes.verify_designation(Address::ZERO).await.is_err());
+        *accounts.designated.lock().unwrap() = maintenance.address();
+        lanes.verify_designation(Address::ZERO).await.unwrap();
+    }
+
+    #[test]
+    fn the_split_needs_a_distinct_maintenance_key() {
+        let (unsplit, _) = lanes(config(None, false));
+        assert!(!unsplit.is_split());
+        assert_eq!(unsplit.status().len(), 1);
+
+        let accounts: Arc<dyn AccountSource> = Arc::new(MockAccounts::default());
+        let same = SignerLanes::new(config(Some(PRIMARY_KEY), false), PRIMARY_KEY, accounts);
+        assert!(matches!(same, Err(PhalaAvsError::ConfigError(_))));
+    }
+}
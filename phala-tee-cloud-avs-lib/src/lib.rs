@@ -1,35 +1,119 @@
+#[cfg(feature = "aggregator")]
+pub mod aggregator;
+#[cfg(feature = "aggregator")]
+pub mod aggregator_admin;
+#[cfg(feature = "aggregator")]
+pub mod aggregator_wire;
+pub mod annotations;
+pub mod api_keys;
+pub mod approvals;
+pub mod artifacts;
+pub mod batch;
+pub mod build_info;
+pub mod capacity;
+pub mod catchup;
+pub mod challenge;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod config;
 pub mod context;
+pub mod costs;
+pub mod cursor;
+pub mod delegation;
+pub mod diagnostics;
+pub mod disk;
+pub mod display;
+pub mod drift;
+pub mod duties;
+pub mod encoding;
 pub mod error;
+pub mod error_catalog;
+pub mod evidence;
+pub mod evm;
+pub mod exit;
+pub mod failure_domain;
+pub mod fees;
+#[cfg(any(test, feature = "testing"))]
+pub mod fixtures;
+pub mod freshness;
+pub mod health;
+pub mod heartbeat;
+pub mod ingestion;
+pub mod jitter;
 pub mod jobs;
+pub mod keystore;
+pub mod lanes;
+pub mod log_consistency;
+pub mod logs;
+pub mod maintenance;
+pub mod memory;
+pub mod metrics;
+pub mod multicall;
+pub mod notify;
+pub mod operator_set;
+pub mod otel;
+pub mod preflight;
+pub mod receipts;
+pub mod redaction;
+pub mod registration;
+pub mod reimbursement;
+pub mod remote_write;
+pub mod replica;
+pub mod reputation;
+pub mod response_safety;
+pub mod response_window;
+pub mod restart;
+pub mod retry;
+pub mod rollout;
+pub mod sanitize;
+pub mod scheduler;
+pub mod schema;
+pub mod self_audit;
+pub mod sender;
+pub mod signed_payload;
+pub mod signing;
+pub mod slo;
+pub mod snapshot;
+pub mod startup;
+pub mod state;
+#[cfg(feature = "http-api")]
+pub mod status;
+pub mod supervisor;
 pub mod tee;
+pub mod upgrade;
 
 // Re-export key types for easy access in the binary
-use blueprint_sdk::{
-    alloy::{
-        primitives::{Address, address},
-        sol,
-    },
-    std::env,
+use blueprint_sdk::alloy::{
+    primitives::{Address, address},
+    sol,
 };
 pub use context::PhalaAvsContext;
 pub use error::PhalaAvsError;
 pub use jobs::{
-    HEARTBEAT_JOB_ID, RESPOND_TO_CHALLENGE_JOB_ID, heartbeat_job, respond_to_challenge_job,
+    HEARTBEAT_JOB_ID, RESPOND_TO_CHALLENGE_JOB_ID, SELF_AUDIT_JOB_ID, heartbeat_job,
+    respond_to_challenge_job, self_audit_job,
 };
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-pub use tee::TeeHandler;
+pub use tee::{TeeHandler, TeeHandlerConfig};
 
 lazy_static! {
-    pub static ref TASK_MANAGER_ADDRESS: Address = env::var("TASK_MANAGER_ADDRESS")
-        .map(|addr| addr.parse().expect("Invalid TASK_MANAGER_ADDRESS"))
-        .unwrap_or_else(|_| address!("0000000000000000000000000000000000000000"));
-    pub static ref PRIVATE_KEY: String = env::var("PRIVATE_KEY").unwrap_or_else(|_| {
+    pub static ref TASK_MANAGER_ADDRESS: Address = config::lookup("TASK_MANAGER_ADDRESS")
+        .map(|addr| display::parse_address(&addr).expect("Invalid TASK_MANAGER_ADDRESS"))
+        .unwrap_or_else(|| address!("0000000000000000000000000000000000000000"));
+    pub static ref SERVICE_MANAGER_ADDRESS: Address = config::lookup("SERVICE_MANAGER_ADDRESS")
+        .map(|addr| display::parse_address(&addr).expect("Invalid SERVICE_MANAGER_ADDRESS"))
+        .unwrap_or_else(|| address!("0000000000000000000000000000000000000000"));
+    pub static ref SLA_ORACLE_ADDRESS: Address = config::lookup("SLA_ORACLE_ADDRESS")
+        .map(|addr| display::parse_address(&addr).expect("Invalid SLA_ORACLE_ADDRESS"))
+        .unwrap_or_else(|| address!("0000000000000000000000000000000000000000"));
+    pub static ref PRIVATE_KEY: String = config::lookup("PRIVATE_KEY").unwrap_or_else(|| {
         "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80".to_string()
     });
-    pub static ref AGGREGATOR_PRIVATE_KEY: String = env::var("PRIVATE_KEY").unwrap_or_else(|_| {
-        "2a871d0798f97d79848a013d4936a73bf4cc922c825d33c1cf7073dff6d409c6".to_string()
-    });
+    pub static ref AGGREGATOR_PRIVATE_KEY: String =
+        config::lookup("PRIVATE_KEY").unwrap_or_else(|| {
+            "2a871d0798f97d79848a013d4936a73bf4cc922c825d33c1cf7073dff6d409c6".to_string()
+        });
 }
 
 sol!(
@@ -39,3 +123,19 @@ sol!(
     ERC20,
     "../contracts/out/ERC20.sol/ERC20.json"
 );
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    #[derive(Debug, Serialize, Deserialize)]
+    IPhalaSlaOracle,
+    "../contracts/out/IPhalaSlaOracle.sol/IPhalaSlaOracle.json"
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    #[derive(Debug, Serialize, Deserialize)]
+    IPhalaServiceManager,
+    "../contracts/out/IPhalaServiceManager.sol/IPhalaServiceManager.json"
+);
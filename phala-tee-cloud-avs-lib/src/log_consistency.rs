@@ -0,0 +1,507 @@
+//! Detection of challenge logs an RPC provider silently left out of `eth_getLogs`.
+//!
+//! Load-balanced providers sometimes answer from a lagging replica and return an incomplete log
+//! set, which would mean a missed challenge with no error anywhere. Once a block is
+//! `LOG_CHECK_LAG_BLOCKS` behind head, and so should have been polled, a sample of blocks
+//! (`LOG_CHECK_SAMPLE_RATE`) has its logs bloom read. If the bloom says an `SlaChallengeIssued`
+//! log of the oracle could be there, the block's challenge logs are fetched again, from one of
+//! `LOG_CHECK_RPC_URLS` or, without any, from the primary after `LOG_CHECK_RETRY_DELAY_MS`, and
+//! compared with what was delivered. Logs missing from the delivery are handed back for normal
+//! processing; either way the provider that left logs out is named and counted. Blocks where
+//! the [self-audit](crate::self_audit) found a challenge that was never delivered are queued
+//! with [`LogConsistencyChecker::recheck`] and checked on the next run, sampled or not.
+
+use crate::IPhalaSlaOracle::SlaChallengeIssued;
+use crate::config::{env_flag, env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use blueprint_sdk::alloy::eips::BlockNumberOrTag;
+use blueprint_sdk::alloy::primitives::{Address, B256, Bloom, BloomInput, keccak256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::{Filter, Log};
+use blueprint_sdk::alloy::sol_types::SolEvent;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+/// Counter of sampled blocks whose logs were cross-checked.
+pub const LOG_CHECKS_METRIC: &str = "phala_avs_log_checks_total";
+/// Counter of logs a provider left out, by provider.
+pub const LOG_DISCREPANCIES_METRIC: &str = "phala_avs_log_discrepancies_total";
+/// Counter of missed challenge logs recovered by the checker.
+pub const LOGS_RECOVERED_METRIC: &str = "phala_avs_logs_recovered_total";
+
+#[derive(Clone, Debug)]
+pub struct LogCheckConfig {
+    pub enabled: bool,
+    /// Fraction of blocks cross-checked, bounding the extra RPC cost.
+    pub sample_rate: f64,
+    /// Blocks behind head before a block is checked, so the producer has polled it.
+    pub lag_blocks: u64,
+    /// Most blocks considered per check; older unchecked blocks are skipped.
+    pub max_blocks: u64,
+    pub retry_delay_ms: u64,
+    /// Other providers to cross-check against.
+    pub rpc_urls: Vec<String>,
+}
+
+impl LogCheckConfig {
+    /// Reads `LOG_CHECK_ENABLED` (off by default), `LOG_CHECK_SAMPLE_RATE`,
+    /// `LOG_CHECK_LAG_BLOCKS`, `LOG_CHECK_MAX_BLOCKS`, `LOG_CHECK_RETRY_DELAY_MS` and
+    /// `LOG_CHECK_RPC_URLS` (comma-separated).
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let config = Self {
+            enabled: env_flag("LOG_CHECK_ENABLED", false)?,
+            sample_rate: env_or("LOG_CHECK_SAMPLE_RATE", 0.1)?,
+            lag_blocks: env_or("LOG_CHECK_LAG_BLOCKS", 3)?,
+            max_blocks: env_or("LOG_CHECK_MAX_BLOCKS", 32)?,
+            retry_delay_ms: env_or("LOG_CHECK_RETRY_DELAY_MS", 2_000)?,
+            rpc_urls: env_opt::<String>("LOG_CHECK_RPC_URLS")?
+                .map(|raw| {
+                    raw.split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        if !(0.0..=1.0).contains(&config.sample_rate) {
+            return Err(PhalaAvsError::ConfigError(
+                "LOG_CHECK_SAMPLE_RATE must be between 0 and 1".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// A provider blocks and logs can be read from.
+pub trait LogSource: Send + Sync {
+    /// Named in warnings and metrics.
+    fn name(&self) -> &str;
+
+    /// The logs bloom of `block`, or `None` if the provider does not have it yet.
+    fn logs_bloom(&self, block: u64) -> BoxFuture<'_, Result<Option<Bloom>, PhalaAvsError>>;
+
+    /// The `SlaChallengeIssued` logs `oracle` emitted in `block`.
+    fn challenge_logs(
+        &self,
+        block: u64,
+        oracle: Address,
+    ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>>;
+}
+
+/// [`LogSource`] backed by an alloy [`Provider`].
+#[derive(Clone, Debug)]
+pub struct ProviderLogSource<P> {
+    name: String,
+    provider: P,
+}
+
+impl<P> ProviderLogSource<P> {
+    /// Names the source after `url`'s scheme and host, leaving out any key in its path.
+    pub fn new(url: &str, provider: P) -> Self {
+        Self {
+            name: url.split('/').take(3).collect::<Vec<_>>().join("/"),
+            provider,
+        }
+    }
+}
+
+impl<P: Provider + Send + Sync + 'static> LogSource for ProviderLogSource<P> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn logs_bloom(&self, block: u64) -> BoxFuture<'_, Result<Option<Bloom>, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_block_by_number(BlockNumberOrTag::Number(block))
+                .await
+                .map(|block| block.map(|b| b.header.logs_bloom))
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_getBlockByNumber failed: {e}")))
+        })
+    }
+
+    fn challenge_logs(
+        &self,
+        block: u64,
+        oracle: Address,
+    ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>> {
+        Box::pin(async move {
+            let filter = Filter::new()
+                .from_block(block)
+                .to_block(block)
+                .address(oracle)
+                .event_signature(SlaChallengeIssued::SIGNATURE_HASH);
+            self.provider
+                .get_logs(&filter)
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_getLogs failed: {e}")))
+        })
+    }
+}
+
+/// Identifies a log within its block.
+type LogKey = (Option<B256>, Option<u64>);
+
+fn key(log: &Log) -> LogKey {
+    (log.transaction_hash, log.log_index)
+}
+
+/// A provider that returned fewer logs for a block than another.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Discrepancy {
+    pub block: u64,
+    pub provider: String,
+    pub missing: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct ConsistencyReport {
+    /// Blocks whose logs were cross-checked.
+    pub checked: usize,
+    /// Challenge logs the producer was not delivered, to be processed as usual.
+    pub recovered: Vec<Log>,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// Cross-checks delivered challenge logs against a second query.
+pub struct LogConsistencyChecker {
+    config: LogCheckConfig,
+    oracle: Address,
+    primary: Arc<dyn LogSource>,
+    cross_check: Vec<Arc<dyn LogSource>>,
+    /// Challenge logs delivered for blocks not yet checked.
+    delivered: Mutex<BTreeMap<u64, HashSet<LogKey>>>,
+    /// The last block checked.
+    cursor: Mutex<Option<u64>>,
+    /// Blocks to check on the next run regardless of sampling and the cursor.
+    rechecks: Mutex<BTreeSet<u64>>,
+}
+
+impl LogConsistencyChecker {
+    /// Checks what `primary` delivered, against `cross_check` or, if empty, `primary` again.
+    pub fn new(
+        config: LogCheckConfig,
+        oracle: Address,
+        primary: Arc<dyn LogSource>,
+        cross_check: Vec<Arc<dyn LogSource>>,
+    ) -> Self {
+        Self {
+            config,
+            oracle,
+            primary,
+            cross_check,
+            delivered: Mutex::default(),
+            cursor: Mutex::default(),
+            rechecks: Mutex::default(),
+        }
+    }
+
+    /// Notes the challenge logs among a polled batch.
+    pub fn record(&self, logs: &[Log]) {
+        let mut delivered = self.delivered.lock().unwrap_or_else(|e| e.into_inner());
+        for log in logs.iter().filter(|l| self.is_challenge_log(l)) {
+            if let Some(block) = log.block_number {
+                delivered.entry(block).or_default().insert(key(log));
+            }
+        }
+    }
+
+    /// Queues `blocks` for the next [`check`](Self::check), even if they are not sampled or were
+    /// already checked.
+    pub fn recheck(&self, blocks: impl IntoIterator<Item = u64>) {
+        self.rechecks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend(blocks);
+    }
+
+    fn is_challenge_log(&self, log: &Log) -> bool {
+        log.address() == self.oracle && log.topic0() == Some(&SlaChallengeIssued::SIGNATURE_HASH)
+    }
+
+    /// Whether `block` is among the sampled ones; the choice is stable per block.
+    fn sampled(&self, block: u64) -> bool {
+        let hash = keccak256(block.to_be_bytes());
+        let draw = u64::from_be_bytes(hash[..8].try_into().expect("8 bytes"));
+        (draw as f64) < self.config.sample_rate * u64::MAX as f64
+    }
+
+    /// Cross-checks the queued rechecks and the sampled blocks that settled since the last
+    /// check; the first check only looks at the newest settled block.
+    pub async fn check(&self, head: u64) -> Result<ConsistencyReport, PhalaAvsError> {
+        let mut report = ConsistencyReport::default();
+        let rechecks =
+            std::mem::take(&mut *self.rechecks.lock().unwrap_or_else(|e| e.into_inner()));
+        for block in rechecks {
+            self.check_block(block, &mut report).await?;
+        }
+        let to = head.saturating_sub(self.config.lag_blocks);
+        let cursor = *self.cursor.lock().unwrap_or_else(|e| e.into_inner());
+        let from = cursor
+            .map_or(to, |c| c + 1)
+            .max(to.saturating_sub(self.config.max_blocks.saturating_sub(1)));
+        for block in (from..=to).filter(|b| self.sampled(*b)) {
+            self.check_block(block, &mut report).await?;
+        }
+        if from <= to {
+            *self.cursor.lock().unwrap_or_else(|e| e.into_inner()) = Some(to);
+            let mut delivered = self.delivered.lock().unwrap_or_else(|e| e.into_inner());
+            *delivered = delivered.split_off(&(to + 1));
+        }
+        Ok(report)
+    }
+
+    async fn check_block(
+        &self,
+        block: u64,
+        report: &mut ConsistencyReport,
+    ) -> Result<(), PhalaAvsError> {
+        let Some(bloom) = self.primary.logs_bloom(block).await? else {
+            return Ok(());
+        };
+        if !bloom.contains_input(BloomInput::Raw(self.oracle.as_slice()))
+            || !bloom.contains_input(BloomInput::Raw(
+                SlaChallengeIssued::SIGNATURE_HASH.as_slice(),
+            ))
+        {
+            return Ok(());
+        }
+        report.checked += 1;
+        METRICS.inc_counter(LOG_CHECKS_METRIC, &[], 1);
+
+        let reference = match self.cross_check.len() {
+            0 => {
+                tokio::time::sleep(Duration::from_millis(self.config.retry_delay_ms)).await;
+                &self.primary
+            }
+            n => &self.cross_check[block as usize % n],
+        };
+        let logs = reference.challenge_logs(block, self.oracle).await?;
+        let delivered = self
+            .delivered
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&block)
+            .cloned()
+            .unwrap_or_default();
+        let found: HashSet<_> = logs.iter().map(key).collect();
+
+        let missing: Vec<Log> = logs
+            .into_iter()
+            .filter(|l| !delivered.contains(&key(l)))
+            .collect();
+        if !missing.is_empty() {
+            warn!(
+                "{} omitted {} challenge logs of block {block}, found by {}; processing them now",
+                self.primary.name(),
+                missing.len(),
+                reference.name()
+            );
+            self.discrepancy(report, block, self.primary.name(), missing.len());
+            METRICS.inc_counter(LOGS_RECOVERED_METRIC, &[], missing.len() as u64);
+            report.recovered.extend(missing);
+        }
+        let unconfirmed = delivered.difference(&found).count();
+        if unconfirmed > 0 {
+            warn!(
+                "{} omitted {unconfirmed} challenge logs of block {block} that {} delivered",
+                reference.name(),
+                self.primary.name()
+            );
+            self.discrepancy(report, block, reference.name(), unconfirmed);
+        }
+        Ok(())
+    }
+
+    fn discrepancy(
+        &self,
+        report: &mut ConsistencyReport,
+        block: u64,
+        provider: &str,
+        missing: usize,
+    ) {
+        METRICS.inc_counter(
+            LOG_DISCREPANCIES_METRIC,
+            &[("provider", provider)],
+            missing as u64,
+        );
+        report.discrepancies.push(Discrepancy {
+            block,
+            provider: provider.to_string(),
+            missing,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{ChallengeEventFixture, ORACLE};
+    use blueprint_sdk::alloy::primitives::U256;
+
+    const BLOCKS: u64 = 40;
+
+    /// A chain with two challenge logs per block past genesis, served by a provider that drops
+    /// some unless `seed` is zero.
+    #[derive(Clone, Copy)]
+    struct DroppingSource {
+        name: &'static str,
+        seed: u64,
+    }
+
+    impl DroppingSource {
+        fn full_logs(block: u64) -> Vec<Log> {
+            (0..2)
+                .filter(|_| block > 0)
+                .map(|i| {
+                    ChallengeEventFixture::new()
+                        .id(block * 2 + i)
+                        .block(block)
+                        .transaction_hash(B256::from(U256::from(block)))
+                        .log_index(i)
+                        .build_log()
+                })
+                .collect()
+        }
+
+        /// Drops about a third of the logs, deterministically per seed.
+        fn served_logs(&self, block: u64) -> Vec<Log> {
+            Self::full_logs(block)
+                .into_iter()
+                .filter(|log| {
+                    let index = log.log_index.unwrap_or_default();
+                    let draw = keccak256([self.seed, block, index].map(u64::to_be_bytes).concat());
+                    self.seed == 0 || draw[0] % 3 != 0
+                })
+                .collect()
+        }
+    }
+
+    impl LogSource for DroppingSource {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn logs_bloom(&self, block: u64) -> BoxFuture<'_, Result<Option<Bloom>, PhalaAvsError>> {
+            let mut bloom = Bloom::default();
+            for log in Self::full_logs(block) {
+                bloom.accrue_log(&log.inner);
+            }
+            Box::pin(async move { Ok(Some(bloom)) })
+        }
+
+        fn challenge_logs(
+            &self,
+            block: u64,
+            _oracle: Address,
+        ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>> {
+            let logs = self.served_logs(block);
+            Box::pin(async move { Ok(logs) })
+        }
+    }
+
+    fn config() -> LogCheckConfig {
+        LogCheckConfig {
+            enabled: true,
+            sample_rate: 1.0,
+            lag_blocks: 0,
+            max_blocks: BLOCKS,
+            retry_delay_ms: 0,
+            rpc_urls: Vec::new(),
+        }
+    }
+
+    /// Polls every block from `primary` as the producer would, then checks them all.
+    async fn run(
+        primary: DroppingSource,
+        secondary: DroppingSource,
+    ) -> (Vec<Log>, ConsistencyReport) {
+        let checker =
+            LogConsistencyChecker::new(config(), ORACLE, Arc::new(primary), vec![Arc::new(
+                secondary,
+            )]);
+        // Starts the cursor at genesis.
+        checker.check(0).await.unwrap();
+        let delivered: Vec<_> = (1..=BLOCKS).flat_map(|b| primary.served_logs(b)).collect();
+        checker.record(&delivered);
+        (delivered, checker.check(BLOCKS).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn dropped_challenge_logs_are_recovered_and_attributed() {
+        let primary = DroppingSource {
+            name: "https://flaky.example",
+            seed: 7,
+        };
+        let secondary = DroppingSource {
+            name: "https://backup.example",
+            seed: 0,
+        };
+        let (delivered, report) = run(primary, secondary).await;
+        let dropped = (2 * BLOCKS) as usize - delivered.len();
+        assert!(dropped > 0, "the provider should have dropped some logs");
+
+        assert_eq!(report.checked, BLOCKS as usize);
+        assert_eq!(report.recovered.len(), dropped);
+        assert!(
+            report
+                .discrepancies
+                .iter()
+                .all(|d| d.provider == "https://flaky.example")
+        );
+        let mut keys: HashSet<_> = delivered.iter().map(key).collect();
+        keys.extend(report.recovered.iter().map(key));
+        assert_eq!(keys.len(), (2 * BLOCKS) as usize);
+
+        // A cross-check provider that drops logs is blamed instead, and nothing is recovered.
+        let primary = DroppingSource {
+            name: "https://reliable.example",
+            seed: 0,
+        };
+        let secondary = DroppingSource {
+            name: "https://lagging.example",
+            seed: 11,
+        };
+        let (_, report) = run(primary, secondary).await;
+        assert!(report.recovered.is_empty());
+        assert!(!report.discrepancies.is_empty());
+        assert!(
+            report
+                .discrepancies
+                .iter()
+                .all(|d| d.provider == "https://lagging.example")
+        );
+    }
+
+    #[tokio::test]
+    async fn sampling_bounds_the_blocks_checked() {
+        let source = DroppingSource {
+            name: "primary",
+            seed: 0,
+        };
+        let checker = LogConsistencyChecker::new(
+            LogCheckConfig {
+                sample_rate: 0.25,
+                max_blocks: 1_000,
+                ..config()
+            },
+            ORACLE,
+            Arc::new(source),
+            Vec::new(),
+        );
+        checker.check(0).await.unwrap();
+        let report = checker.check(1_000).await.unwrap();
+        assert!((150..350).contains(&report.checked), "{}", report.checked);
+        // Each block is checked once, unless queued again.
+        assert_eq!(checker.check(1_000).await.unwrap().checked, 0);
+        checker.recheck([5]);
+        let report = checker.check(1_000).await.unwrap();
+        assert_eq!(report.checked, 1);
+        assert_eq!(report.recovered.len(), 2);
+    }
+}
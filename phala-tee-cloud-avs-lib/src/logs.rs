@@ -0,0 +1,430 @@
+//! In-process ring buffer of recent log events.
+//!
+//! [`LogRingLayer`] is installed next to the regular formatter and keeps the last
+//! `LOG_RING_CAPACITY` events (default 5000) at or above `LOG_RING_LEVEL` (default `trace`) in
+//! [`LOG_RING`], so they can be queried from `/logs` and included in diagnostics without
+//! access to the host's log files. Both settings can be changed at runtime through
+//! `PUT /admin/logs`.
+//!
+//! Logging must stay cheap, so the ring is a fixed array of slots, each behind its own lock: a
+//! writer claims the next slot with an atomic increment and only contends with another writer
+//! `capacity` events later. Events below the minimum level are dropped before being formatted,
+//! and messages and field values are truncated to `MAX_VALUE_BYTES`, which bounds the ring's
+//! memory.
+
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+
+/// Longest message or field value kept, in bytes.
+const MAX_VALUE_BYTES: usize = 2048;
+
+lazy_static! {
+    /// Process-wide log ring fed by [`LogRingLayer`].
+    pub static ref LOG_RING: LogRing = {
+        let ring = LogRing::new(env_or("LOG_RING_CAPACITY", 5000).unwrap_or(5000));
+        ring.set_min_level(env_or("LOG_RING_LEVEL", Level::TRACE).unwrap_or(Level::TRACE));
+        ring
+    };
+}
+
+/// A captured log event.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub unix_ms: u64,
+    pub level: String,
+    pub target: String,
+    /// The message followed by the event's fields as `key=value`.
+    pub message: String,
+    /// The event's fields other than the message, e.g. `challenge_id`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+/// The ring's runtime settings.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogRingConfig {
+    pub capacity: usize,
+    /// `error`, `warn`, `info`, `debug` or `trace`.
+    pub min_level: String,
+}
+
+/// Filters for [`LogRing::query`]; every one set must match.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LogQuery {
+    /// Minimum level, e.g. `warn` for warnings and errors.
+    pub level: Option<String>,
+    /// Prefix of the event's target, e.g. `phala_tee_cloud_avs_blueprint_lib::challenge`.
+    pub target: Option<String>,
+    pub since_ms: Option<u64>,
+    pub until_ms: Option<u64>,
+    /// `key=value` a field must equal, e.g. `challenge_id=0x2a`.
+    pub field: Option<String>,
+    /// Most recent events returned.
+    pub limit: Option<usize>,
+}
+
+impl LogQuery {
+    fn matcher(&self) -> Result<impl Fn(&LogEntry) -> bool + '_, PhalaAvsError> {
+        let level = self.level.as_deref().map(parse_level).transpose()?;
+        let field = match self.field.as_deref() {
+            Some(field) => Some(field.split_once('=').ok_or_else(|| {
+                PhalaAvsError::ValidationError(format!(
+                    "Invalid field filter {field:?}: expected key=value"
+                ))
+            })?),
+            None => None,
+        };
+        Ok(move |entry: &LogEntry| {
+            level.is_none_or(|min| {
+                Level::from_str(&entry.level).is_ok_and(|l| verbosity(l) <= verbosity(min))
+            }) && self
+                .target
+                .as_deref()
+                .is_none_or(|prefix| entry.target.starts_with(prefix))
+                && self.since_ms.is_none_or(|since| entry.unix_ms >= since)
+                && self.until_ms.is_none_or(|until| entry.unix_ms <= until)
+                && field
+                    .is_none_or(|(key, value)| entry.fields.get(key).is_some_and(|v| v == value))
+        })
+    }
+}
+
+fn parse_level(level: &str) -> Result<Level, PhalaAvsError> {
+    level
+        .parse()
+        .map_err(|_| PhalaAvsError::ValidationError(format!("Invalid log level {level:?}")))
+}
+
+/// 1 for `ERROR` up to 5 for `TRACE`.
+fn verbosity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 1,
+        Level::WARN => 2,
+        Level::INFO => 3,
+        Level::DEBUG => 4,
+        Level::TRACE => 5,
+    }
+}
+
+type Slot = Mutex<Option<(u64, LogEntry)>>;
+
+/// A bounded buffer of the most recent log events.
+#[derive(Debug)]
+pub struct LogRing {
+    slots: RwLock<Vec<Slot>>,
+    next: AtomicU64,
+    min_verbosity: AtomicU8,
+}
+
+impl LogRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: RwLock::new((0..capacity).map(|_| Mutex::new(None)).collect()),
+            next: AtomicU64::new(0),
+            min_verbosity: AtomicU8::new(verbosity(Level::TRACE)),
+        }
+    }
+
+    /// Whether events at `level` are captured.
+    pub fn captures(&self, level: Level) -> bool {
+        verbosity(level) <= self.min_verbosity.load(Ordering::Relaxed)
+    }
+
+    pub fn set_min_level(&self, level: Level) {
+        self.min_verbosity
+            .store(verbosity(level), Ordering::Relaxed);
+    }
+
+    pub fn config(&self) -> LogRingConfig {
+        let min_level = [
+            Level::ERROR,
+            Level::WARN,
+            Level::INFO,
+            Level::DEBUG,
+            Level::TRACE,
+        ]
+        .into_iter()
+        .find(|l| verbosity(*l) == self.min_verbosity.load(Ordering::Relaxed))
+        .unwrap_or(Level::TRACE);
+        LogRingConfig {
+            capacity: self.slots.read().unwrap_or_else(|e| e.into_inner()).len(),
+            min_level: min_level.to_string().to_ascii_lowercase(),
+        }
+    }
+
+    /// Applies `config`; a new capacity keeps the most recent events that fit.
+    pub fn configure(&self, config: &LogRingConfig) -> Result<(), PhalaAvsError> {
+        let level = parse_level(&config.min_level)?;
+        let mut slots = self.slots.write().unwrap_or_else(|e| e.into_inner());
+        if slots.len() != config.capacity {
+            let mut kept = ordered(&slots);
+            kept.drain(..kept.len().saturating_sub(config.capacity));
+            *slots = (0..config.capacity).map(|_| Mutex::new(None)).collect();
+            self.next.store(0, Ordering::SeqCst);
+            for (i, entry) in kept.into_iter().enumerate() {
+                *slots[i].lock().unwrap_or_else(|e| e.into_inner()) = Some((i as u64, entry));
+                self.next.store(i as u64 + 1, Ordering::SeqCst);
+            }
+        }
+        self.set_min_level(level);
+        Ok(())
+    }
+
+    pub fn push(&self, entry: LogEntry) {
+        let slots = self.slots.read().unwrap_or_else(|e| e.into_inner());
+        if slots.is_empty() {
+            return;
+        }
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &slots[(sequence % slots.len() as u64) as usize];
+        let mut slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+        // A writer that claimed the slot later may already have filled it.
+        if slot.as_ref().is_none_or(|(s, _)| *s < sequence) {
+            *slot = Some((sequence, entry));
+        }
+    }
+
+    /// Returns the buffered events, oldest first.
+    pub fn snapshot(&self) -> Vec<LogEntry> {
+        ordered(&self.slots.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Returns the buffered events matching `query`, oldest first.
+    pub fn query(&self, query: &LogQuery) -> Result<Vec<LogEntry>, PhalaAvsError> {
+        let matches = query.matcher()?;
+        let mut entries: Vec<_> = self.snapshot().into_iter().filter(|e| matches(e)).collect();
+        if let Some(limit) = query.limit {
+            entries.drain(..entries.len().saturating_sub(limit));
+        }
+        Ok(entries)
+    }
+}
+
+/// Copies the entries out of `slots` in the order they were logged.
+fn ordered(slots: &[Slot]) -> Vec<LogEntry> {
+    let mut entries: Vec<_> = slots
+        .iter()
+        .filter_map(|slot| slot.lock().unwrap_or_else(|e| e.into_inner()).clone())
+        .collect();
+    entries.sort_by_key(|(sequence, _)| *sequence);
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+/// Tracing layer copying every event into [`LOG_RING`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogRingLayer;
+
+impl<S: Subscriber> Layer<S> for LogRingLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        capture(&LOG_RING, event);
+    }
+}
+
+fn capture(ring: &LogRing, event: &Event<'_>) {
+    let metadata = event.metadata();
+    if !ring.captures(*metadata.level()) {
+        return;
+    }
+    let mut visitor = FieldVisitor::default();
+    event.record(&mut visitor);
+    ring.push(LogEntry {
+        unix_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default(),
+        level: metadata.level().to_string(),
+        target: metadata.target().to_string(),
+        message: visitor.message,
+        fields: visitor.fields,
+    });
+}
+
+/// Renders the `message` field followed by any other fields as `key=value`, and collects the
+/// other fields.
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: BTreeMap<String, String>,
+}
+
+impl FieldVisitor {
+    fn record(&mut self, field: &Field, value: String) {
+        let value = truncate(value);
+        if field.name() == "message" {
+            self.message = truncate(format!("{value}{}", self.message));
+        } else {
+            let _ = write!(self.message, " {}={value}", field.name());
+            self.message = truncate(std::mem::take(&mut self.message));
+            self.fields.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{value:?}"));
+    }
+}
+
+fn truncate(mut value: String) -> String {
+    if value.len() > MAX_VALUE_BYTES {
+        let mut end = MAX_VALUE_BYTES;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value.truncate(end);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// [`LogRingLayer`] on a private ring.
+    struct TestLayer(Arc<LogRing>);
+
+    impl<S: Subscriber> Layer<S> for TestLayer {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            capture(&self.0, event);
+        }
+    }
+
+    fn logging_to(ring: &Arc<LogRing>, f: impl FnOnce()) {
+        let subscriber = tracing_subscriber::registry().with(TestLayer(Arc::clone(ring)));
+        tracing::subscriber::with_default(subscriber, f);
+    }
+
+    fn query(ring: &LogRing, query: LogQuery) -> Vec<String> {
+        ring.query(&query)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.message)
+            .collect()
+    }
+
+    #[test]
+    fn events_are_queried_by_level_target_time_and_field() {
+        let ring = Arc::new(LogRing::new(100));
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        logging_to(&ring, || {
+            tracing::info!(target: "avs::challenge", challenge_id = "0x2a", "Challenge observed");
+            tracing::warn!(target: "avs::challenge", challenge_id = %"0x2b", "Challenge late");
+            tracing::error!(target: "avs::tee", task_index = 7u32, "Quote failed");
+            tracing::debug!(target: "avs::tee", "Quote cached");
+        });
+
+        assert_eq!(
+            query(&ring, LogQuery {
+                level: Some("warn".to_string()),
+                ..Default::default()
+            }),
+            [
+                "Challenge late challenge_id=0x2b",
+                "Quote failed task_index=7"
+            ]
+        );
+        assert_eq!(
+            query(&ring, LogQuery {
+                target: Some("avs::tee".to_string()),
+                ..Default::default()
+            })
+            .len(),
+            2
+        );
+        assert_eq!(
+            query(&ring, LogQuery {
+                field: Some("challenge_id=0x2a".to_string()),
+                ..Default::default()
+            }),
+            ["Challenge observed challenge_id=0x2a"]
+        );
+        assert_eq!(
+            query(&ring, LogQuery {
+                since_ms: Some(before),
+                limit: Some(1),
+                ..Default::default()
+            }),
+            ["Quote cached"]
+        );
+        assert!(
+            query(&ring, LogQuery {
+                until_ms: Some(before.saturating_sub(1)),
+                ..Default::default()
+            })
+            .is_empty()
+        );
+        assert!(
+            ring.query(&LogQuery {
+                field: Some("challenge_id".to_string()),
+                ..Default::default()
+            })
+            .is_err()
+        );
+
+        // Below the minimum level nothing is captured; shrinking keeps the latest events.
+        ring.configure(&LogRingConfig {
+            capacity: 2,
+            min_level: "info".to_string(),
+        })
+        .unwrap();
+        assert_eq!(query(&ring, LogQuery::default()), [
+            "Quote failed task_index=7",
+            "Quote cached"
+        ]);
+        logging_to(&ring, || tracing::debug!("dropped"));
+        assert_eq!(ring.snapshot().len(), 2);
+        assert_eq!(ring.config().min_level, "info");
+    }
+
+    #[test]
+    fn floods_stay_bounded_and_cheap() {
+        let ring = Arc::new(LogRing::new(1000));
+        let events = 50_000;
+        let started = Instant::now();
+        logging_to(&ring, || {
+            for i in 0..events {
+                tracing::info!(challenge_id = i, payload = %"x".repeat(10_000), "Flood");
+            }
+        });
+        let per_event = started.elapsed() / events;
+        // Generous enough for unoptimized builds on a loaded machine; a lock held across
+        // formatting or an unbounded buffer would blow through it.
+        assert!(per_event.as_micros() < 100, "{per_event:?} per event");
+
+        let entries = ring.snapshot();
+        assert_eq!(entries.len(), 1000);
+        assert_eq!(
+            entries[999].fields["challenge_id"],
+            (events - 1).to_string()
+        );
+        assert!(entries.iter().all(|e| e.message.len() <= MAX_VALUE_BYTES));
+        assert!(
+            entries
+                .iter()
+                .all(|e| e.fields["payload"].len() <= MAX_VALUE_BYTES)
+        );
+    }
+}
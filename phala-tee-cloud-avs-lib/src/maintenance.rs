@@ -0,0 +1,491 @@
+//! Planned maintenance windows during which SLA challenges are not held against the operator.
+//!
+//! Windows are registered on-chain through the service manager and recorded locally. While a
+//! window is active, responses for exempted workloads are annotated and liveness alerts are
+//! suppressed.
+
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::state::{StateStore, StateStoreExt};
+use crate::{IPhalaServiceManager, PRIVATE_KEY, SERVICE_MANAGER_ADDRESS};
+use blueprint_sdk::alloy::primitives::{Address, B256, U256, keccak256};
+use blueprint_sdk::evm::util::get_provider_from_signer;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// StateStore namespace holding maintenance windows keyed by on-chain window id.
+pub const MAINTENANCE_NAMESPACE: &str = "maintenance";
+
+/// The workloads a maintenance window applies to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkloadScope {
+    All,
+    Workload(String),
+}
+
+impl WorkloadScope {
+    /// The `workloadId` registered on-chain: zero for all workloads, otherwise the hash of the id.
+    pub fn onchain_id(&self) -> B256 {
+        match self {
+            Self::All => B256::ZERO,
+            Self::Workload(id) => keccak256(id.as_bytes()),
+        }
+    }
+
+    /// Whether the scope covers `workload`. Only `All` covers challenges of unknown workload.
+    pub fn covers(&self, workload: Option<&str>) -> bool {
+        match self {
+            Self::All => true,
+            Self::Workload(id) => workload == Some(id.as_str()),
+        }
+    }
+
+    /// Whether the scope covers the workload with on-chain id `workload_id`, as the ids of
+    /// pushed evidence are given.
+    pub fn covers_id(&self, workload_id: B256) -> bool {
+        match self {
+            Self::All => true,
+            Self::Workload(id) => {
+                self.onchain_id() == workload_id || id.parse::<B256>().ok() == Some(workload_id)
+            }
+        }
+    }
+}
+
+impl FromStr for WorkloadScope {
+    type Err = PhalaAvsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err(PhalaAvsError::ValidationError(
+                "workload must be a workload id or `all`".to_string(),
+            )),
+            "all" => Ok(Self::All),
+            id => Ok(Self::Workload(id.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for WorkloadScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => write!(f, "all"),
+            Self::Workload(id) => write!(f, "{id}"),
+        }
+    }
+}
+
+/// A registered maintenance window.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub window_id: U256,
+    pub scope: WorkloadScope,
+    /// Start, unix seconds, inclusive.
+    pub from_unix: u64,
+    /// End, unix seconds, exclusive.
+    pub to_unix: u64,
+    pub registration_tx: B256,
+    pub cancelled: bool,
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, at_unix: u64) -> bool {
+        !self.cancelled && self.from_unix <= at_unix && at_unix < self.to_unix
+    }
+}
+
+/// Attached to responses for challenges that fall inside a maintenance window.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceAnnotation {
+    pub window_id: U256,
+    pub from_unix: u64,
+    pub to_unix: u64,
+}
+
+/// Limits applied when scheduling a window.
+#[derive(Clone, Debug)]
+pub struct MaintenanceConfig {
+    pub max_duration_secs: u64,
+    /// Minimum time between scheduling a window and its start.
+    pub min_notice_secs: u64,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            max_duration_secs: 6 * 3600,
+            min_notice_secs: 3600,
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    /// Loads the limits from `MAINTENANCE_MAX_DURATION_SECS` and `MAINTENANCE_MIN_NOTICE_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        Ok(Self {
+            max_duration_secs: env_or("MAINTENANCE_MAX_DURATION_SECS", defaults.max_duration_secs)?,
+            min_notice_secs: env_or("MAINTENANCE_MIN_NOTICE_SECS", defaults.min_notice_secs)?,
+        })
+    }
+}
+
+/// On-chain registration of maintenance exemptions.
+pub trait ExemptionRegistry: Send + Sync {
+    /// Registers a window, returning the assigned window id and the transaction hash.
+    fn register(
+        &self,
+        workload_id: B256,
+        from_unix: u64,
+        to_unix: u64,
+    ) -> BoxFuture<'_, Result<(U256, B256), PhalaAvsError>>;
+
+    /// Cancels a window, returning the transaction hash.
+    fn cancel(&self, window_id: U256) -> BoxFuture<'_, Result<B256, PhalaAvsError>>;
+}
+
+/// [`ExemptionRegistry`] backed by the `PhalaServiceManager` contract.
+#[derive(Clone, Debug)]
+pub struct ServiceManagerExemptions {
+    service_manager: Address,
+    private_key: String,
+    rpc_url: String,
+}
+
+impl ServiceManagerExemptions {
+    pub fn new(service_manager: Address, private_key: String, rpc_url: String) -> Self {
+        Self {
+            service_manager,
+            private_key,
+            rpc_url,
+        }
+    }
+
+    /// Uses `SERVICE_MANAGER_ADDRESS` and the operator's `PRIVATE_KEY`.
+    pub fn from_env(rpc_url: String) -> Self {
+        Self::new(*SERVICE_MANAGER_ADDRESS, PRIVATE_KEY.clone(), rpc_url)
+    }
+}
+
+fn evm_err(e: impl fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::EvmError(e.to_string())
+}
+
+impl ExemptionRegistry for ServiceManagerExemptions {
+    fn register(
+        &self,
+        workload_id: B256,
+        from_unix: u64,
+        to_unix: u64,
+    ) -> BoxFuture<'_, Result<(U256, B256), PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_from_signer(&self.private_key, &self.rpc_url);
+            let contract = IPhalaServiceManager::new(self.service_manager, provider);
+            let receipt = contract
+                .registerMaintenanceWindow(workload_id, from_unix, to_unix)
+                .send()
+                .await
+                .map_err(evm_err)?
+                .get_receipt()
+                .await
+                .map_err(evm_err)?;
+            if !receipt.status() {
+                return Err(PhalaAvsError::EvmError(format!(
+                    "registerMaintenanceWindow reverted in {}",
+                    receipt.transaction_hash
+                )));
+            }
+            let window_id = receipt
+                .inner
+                .logs()
+                .iter()
+                .find_map(|log| {
+                    log.log_decode::<IPhalaServiceManager::MaintenanceWindowRegistered>()
+                        .ok()
+                })
+                .map(|log| log.inner.data.windowId)
+                .ok_or_else(|| {
+                    PhalaAvsError::EvmError(
+                        "MaintenanceWindowRegistered missing from receipt".to_string(),
+                    )
+                })?;
+            Ok((window_id, receipt.transaction_hash))
+        })
+    }
+
+    fn cancel(&self, window_id: U256) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_from_signer(&self.private_key, &self.rpc_url);
+            let contract = IPhalaServiceManager::new(self.service_manager, provider);
+            let receipt = contract
+                .cancelMaintenanceWindow(window_id)
+                .send()
+                .await
+                .map_err(evm_err)?
+                .get_receipt()
+                .await
+                .map_err(evm_err)?;
+            if !receipt.status() {
+                return Err(PhalaAvsError::EvmError(format!(
+                    "cancelMaintenanceWindow reverted in {}",
+                    receipt.transaction_hash
+                )));
+            }
+            Ok(receipt.transaction_hash)
+        })
+    }
+}
+
+/// The operator's maintenance windows.
+///
+/// Windows are read from the store on every lookup so windows scheduled from the CLI are seen by
+/// a running operator sharing the same state backend.
+pub struct MaintenanceSchedule {
+    config: MaintenanceConfig,
+    store: Arc<dyn StateStore>,
+    registry: Arc<dyn ExemptionRegistry>,
+}
+
+impl MaintenanceSchedule {
+    pub fn new(
+        config: MaintenanceConfig,
+        store: Arc<dyn StateStore>,
+        registry: Arc<dyn ExemptionRegistry>,
+    ) -> Self {
+        Self {
+            config,
+            store,
+            registry,
+        }
+    }
+
+    /// Checks a window against the configured duration and advance notice limits.
+    pub fn validate(
+        &self,
+        from_unix: u64,
+        to_unix: u64,
+        now_unix: u64,
+    ) -> Result<(), PhalaAvsError> {
+        if to_unix <= from_unix {
+            return Err(PhalaAvsError::ValidationError(
+                "maintenance window must end after it starts".to_string(),
+            ));
+        }
+        if to_unix - from_unix > self.config.max_duration_secs {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "maintenance window of {}s exceeds the maximum of {}s",
+                to_unix - from_unix,
+                self.config.max_duration_secs
+            )));
+        }
+        if from_unix < now_unix.saturating_add(self.config.min_notice_secs) {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "maintenance windows must be scheduled at least {}s in advance",
+                self.config.min_notice_secs
+            )));
+        }
+        Ok(())
+    }
+
+    /// Validates, registers on-chain and records a window.
+    pub async fn schedule(
+        &self,
+        scope: WorkloadScope,
+        from_unix: u64,
+        to_unix: u64,
+    ) -> Result<MaintenanceWindow, PhalaAvsError> {
+        self.validate(from_unix, to_unix, now_unix())?;
+        let (window_id, registration_tx) = self
+            .registry
+            .register(scope.onchain_id(), from_unix, to_unix)
+            .await?;
+        let window = MaintenanceWindow {
+            window_id,
+            scope,
+            from_unix,
+            to_unix,
+            registration_tx,
+            cancelled: false,
+        };
+        self.store.put_json(
+            MAINTENANCE_NAMESPACE,
+            &window_id.to_be_bytes::<32>(),
+            &window,
+        )?;
+        info!(
+            "Scheduled maintenance window {} for {} from {} to {}",
+            window_id, window.scope, from_unix, to_unix
+        );
+        Ok(window)
+    }
+
+    /// Cancels a window on-chain and locally.
+    pub async fn cancel(&self, window_id: U256) -> Result<MaintenanceWindow, PhalaAvsError> {
+        let key = window_id.to_be_bytes::<32>();
+        let mut window: MaintenanceWindow = self
+            .store
+            .get_json(MAINTENANCE_NAMESPACE, &key)?
+            .ok_or_else(|| {
+                PhalaAvsError::ValidationError(format!("unknown maintenance window {window_id}"))
+            })?;
+        if window.cancelled {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "maintenance window {window_id} is already cancelled"
+            )));
+        }
+        self.registry.cancel(window_id).await?;
+        window.cancelled = true;
+        self.store.put_json(MAINTENANCE_NAMESPACE, &key, &window)?;
+        info!("Cancelled maintenance window {window_id}");
+        Ok(window)
+    }
+
+    /// Every recorded window, including cancelled ones, ordered by window id.
+    pub fn windows(&self) -> Result<Vec<MaintenanceWindow>, PhalaAvsError> {
+        self.store
+            .scan(MAINTENANCE_NAMESPACE)?
+            .into_iter()
+            .map(|(_, raw)| {
+                serde_json::from_slice(&raw).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Corrupt maintenance window: {e}"))
+                })
+            })
+            .collect()
+    }
+
+    /// The active window covering `workload` at `at_unix`, if any.
+    pub fn covering(
+        &self,
+        workload: Option<&str>,
+        at_unix: u64,
+    ) -> Result<Option<MaintenanceWindow>, PhalaAvsError> {
+        Ok(self
+            .windows()?
+            .into_iter()
+            .find(|w| w.contains(at_unix) && w.scope.covers(workload)))
+    }
+
+    /// The annotation to attach to a response for `workload` built at `at_unix`.
+    pub fn annotation_for(
+        &self,
+        workload: Option<&str>,
+        at_unix: u64,
+    ) -> Result<Option<MaintenanceAnnotation>, PhalaAvsError> {
+        Ok(self
+            .covering(workload, at_unix)?
+            .map(|w| MaintenanceAnnotation {
+                window_id: w.window_id,
+                from_unix: w.from_unix,
+                to_unix: w.to_unix,
+            }))
+    }
+
+    /// Whether alerts for missed liveness of `workload` at `at_unix` should be suppressed.
+    pub fn suppresses_alerts(&self, workload: Option<&str>, at_unix: u64) -> bool {
+        matches!(self.covering(workload, at_unix), Ok(Some(_)))
+    }
+}
+
+pub fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockRegistry {
+        cancelled: Mutex<Vec<U256>>,
+    }
+
+    impl ExemptionRegistry for MockRegistry {
+        fn register(
+            &self,
+            _workload_id: B256,
+            _from_unix: u64,
+            _to_unix: u64,
+        ) -> BoxFuture<'_, Result<(U256, B256), PhalaAvsError>> {
+            Box::pin(async { Ok((U256::from(7), B256::repeat_byte(1))) })
+        }
+
+        fn cancel(&self, window_id: U256) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            self.cancelled.lock().unwrap().push(window_id);
+            Box::pin(async { Ok(B256::repeat_byte(2)) })
+        }
+    }
+
+    fn schedule(registry: Arc<MockRegistry>) -> MaintenanceSchedule {
+        MaintenanceSchedule::new(
+            MaintenanceConfig {
+                max_duration_secs: 3600,
+                min_notice_secs: 60,
+            },
+            Arc::new(MemoryStateStore::default()),
+            registry,
+        )
+    }
+
+    #[test]
+    fn rejects_windows_outside_limits() {
+        let schedule = schedule(Arc::default());
+        assert!(schedule.validate(1_000, 1_000, 0).is_err());
+        assert!(schedule.validate(1_000, 1_000 + 3601, 0).is_err());
+        assert!(schedule.validate(1_000, 2_000, 990).is_err());
+        assert!(schedule.validate(1_000, 2_000, 900).is_ok());
+    }
+
+    #[tokio::test]
+    async fn window_exempts_challenges_until_cancelled() {
+        let registry = Arc::new(MockRegistry::default());
+        let schedule = schedule(Arc::clone(&registry));
+        let from = now_unix() + 120;
+        let window = schedule
+            .schedule(WorkloadScope::All, from, from + 600)
+            .await
+            .unwrap();
+
+        // A challenge issued inside the window is annotated and its alerts are suppressed.
+        let inside = from + 10;
+        let annotation = schedule.annotation_for(Some("app-1"), inside).unwrap();
+        assert_eq!(annotation.unwrap().window_id, window.window_id);
+        assert!(schedule.suppresses_alerts(Some("app-1"), inside));
+        assert!(!schedule.suppresses_alerts(Some("app-1"), from + 600));
+
+        schedule.cancel(window.window_id).await.unwrap();
+        assert_eq!(*registry.cancelled.lock().unwrap(), vec![window.window_id]);
+        assert!(
+            schedule
+                .annotation_for(Some("app-1"), inside)
+                .unwrap()
+                .is_none()
+        );
+        assert!(!schedule.suppresses_alerts(Some("app-1"), inside));
+        assert!(schedule.cancel(window.window_id).await.is_err());
+    }
+
+    #[test]
+    fn workload_scope_only_covers_its_workload() {
+        let scope: WorkloadScope = "app-1".parse().unwrap();
+        assert!(scope.covers(Some("app-1")));
+        assert!(!scope.covers(Some("app-2")));
+        assert!(!scope.covers(None));
+        assert!("all".parse::<WorkloadScope>().unwrap().covers(None));
+
+        assert!(scope.covers_id(keccak256("app-1")));
+        assert!(!scope.covers_id(keccak256("app-2")));
+        let hex = B256::repeat_byte(0x42);
+        assert!(WorkloadScope::Workload(hex.to_string()).covers_id(hex));
+    }
+}
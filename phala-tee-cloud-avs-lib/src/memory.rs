@@ -0,0 +1,170 @@
+//! Approximate memory accounting and budgets for in-memory components.
+//!
+//! A component estimates its usage as entry count × the size of a sample of entries (see
+//! [`ApproxSize`]) and reports it against its budget, `MEMORY_BUDGET_<COMPONENT>_BYTES`. Over
+//! budget, it sheds what it can: the challenge tracker spills completed challenges to the state
+//! store. Budgets can be changed at runtime through `PUT /admin/memory`; components read them on
+//! every admission. Usage and budgets are exported as gauges, and a component running above 90%
+//! of its budget for `MEMORY_PRESSURE_WARN_SECS` logs a warning.
+
+use crate::config::{env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, RwLock};
+use tracing::warn;
+
+/// Gauge of the approximate bytes held, by component.
+pub const MEMORY_USAGE_METRIC: &str = "phala_avs_memory_usage_bytes";
+/// Gauge of the configured budget, by component.
+pub const MEMORY_BUDGET_METRIC: &str = "phala_avs_memory_budget_bytes";
+
+/// In-memory challenge tracker entries.
+pub const CHALLENGE_TRACKER: &str = "challenge_tracker";
+
+/// Components with a budget, as used in `MEMORY_BUDGET_<COMPONENT>_BYTES`.
+const COMPONENTS: &[&str] = &[CHALLENGE_TRACKER];
+
+/// Entries sampled to estimate a component's entry size.
+const SAMPLE_SIZE: usize = 16;
+
+/// Approximate heap and inline size of a stored value.
+pub trait ApproxSize {
+    fn approx_size(&self) -> usize;
+}
+
+/// Estimates the size of `entries` from a sample of them.
+pub fn estimate<'a, T: ApproxSize + 'a>(
+    len: usize,
+    entries: impl Iterator<Item = &'a T>,
+) -> (u64, u64) {
+    let (sampled, bytes) = entries.take(SAMPLE_SIZE).fold((0u64, 0u64), |(n, b), e| {
+        (n + 1, b + e.approx_size() as u64)
+    });
+    let per_entry = bytes.checked_div(sampled).unwrap_or_default();
+    (per_entry * len as u64, per_entry)
+}
+
+/// A component's usage as shown by `GET /admin/memory`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ComponentUsage {
+    pub used_bytes: u64,
+    pub budget_bytes: Option<u64>,
+    /// Since when usage has been above 90% of the budget.
+    pub pressure_since_unix_ms: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Pressure {
+    used_bytes: u64,
+    since_unix_ms: Option<u64>,
+    warned: bool,
+}
+
+/// Budgets of every component, and their last reported usage.
+#[derive(Debug, Default)]
+pub struct MemoryBudgets {
+    budgets: RwLock<BTreeMap<String, u64>>,
+    usage: Mutex<BTreeMap<String, Pressure>>,
+    warn_after_ms: u64,
+}
+
+impl MemoryBudgets {
+    /// Reads `MEMORY_BUDGET_<COMPONENT>_BYTES` for every component, unset meaning unlimited,
+    /// and `MEMORY_PRESSURE_WARN_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let mut budgets = BTreeMap::new();
+        for component in COMPONENTS {
+            let key = format!("MEMORY_BUDGET_{}_BYTES", component.to_ascii_uppercase());
+            if let Some(bytes) = env_opt(&key)? {
+                budgets.insert(component.to_string(), bytes);
+            }
+        }
+        Ok(Self {
+            budgets: RwLock::new(budgets),
+            usage: Mutex::default(),
+            warn_after_ms: env_or("MEMORY_PRESSURE_WARN_SECS", 300u64)? * 1000,
+        })
+    }
+
+    pub fn budget(&self, component: &str) -> Option<u64> {
+        self.budgets
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(component)
+            .copied()
+    }
+
+    /// Replaces the budgets of the given components, taking effect at their next admission.
+    pub fn set_budgets(&self, budgets: BTreeMap<String, u64>) -> Result<(), PhalaAvsError> {
+        if let Some(unknown) = budgets.keys().find(|c| !COMPONENTS.contains(&c.as_str())) {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "unknown memory component {unknown}"
+            )));
+        }
+        let mut current = self.budgets.write().unwrap_or_else(|e| e.into_inner());
+        for (component, bytes) in budgets {
+            METRICS.set_gauge(
+                MEMORY_BUDGET_METRIC,
+                &[("component", component.as_str())],
+                bytes as f64,
+            );
+            current.insert(component, bytes);
+        }
+        Ok(())
+    }
+
+    /// Records `used_bytes` of `component`, warning once it has run above 90% of its budget for
+    /// the configured period.
+    pub fn report(&self, component: &str, used_bytes: u64, now_ms: u64) {
+        let budget = self.budget(component);
+        METRICS.set_gauge(
+            MEMORY_USAGE_METRIC,
+            &[("component", component)],
+            used_bytes as f64,
+        );
+        if let Some(budget) = budget {
+            METRICS.set_gauge(
+                MEMORY_BUDGET_METRIC,
+                &[("component", component)],
+                budget as f64,
+            );
+        }
+
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        let pressure = usage.entry(component.to_string()).or_default();
+        pressure.used_bytes = used_bytes;
+        match budget {
+            Some(budget) if used_bytes * 10 > budget * 9 => {
+                let since = *pressure.since_unix_ms.get_or_insert(now_ms);
+                if !pressure.warned && now_ms.saturating_sub(since) >= self.warn_after_ms {
+                    pressure.warned = true;
+                    warn!(
+                        "Memory of {component} has been above 90% of its {budget} byte budget for {}s ({used_bytes} bytes)",
+                        now_ms.saturating_sub(since) / 1000
+                    );
+                }
+            }
+            _ => {
+                pressure.since_unix_ms = None;
+                pressure.warned = false;
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, ComponentUsage> {
+        let usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        COMPONENTS
+            .iter()
+            .map(|&component| {
+                let pressure = usage.get(component);
+                (component.to_string(), ComponentUsage {
+                    used_bytes: pressure.map_or(0, |p| p.used_bytes),
+                    budget_bytes: self.budget(component),
+                    pressure_since_unix_ms: pressure.and_then(|p| p.since_unix_ms),
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,255 @@
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::RwLock;
+
+/// Default histogram buckets, suitable for both block counts and seconds.
+const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0,
+];
+
+lazy_static! {
+    /// Process-wide metrics registry shared by all subsystems.
+    pub static ref METRICS: MetricsRegistry = MetricsRegistry::default();
+}
+
+/// A metric name plus its sorted label set.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct SeriesKey {
+    name: String,
+    labels: Vec<(String, String)>,
+}
+
+impl SeriesKey {
+    fn new(name: &str, labels: &[(&str, &str)]) -> Self {
+        let mut labels: Vec<(String, String)> = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        labels.sort();
+        Self {
+            name: name.to_string(),
+            labels,
+        }
+    }
+
+    fn render_labels(&self, extra: Option<(&str, &str)>) -> String {
+        let mut parts: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{k}=\"{}\"", escape_label(v)))
+            .collect();
+        if let Some((k, v)) = extra {
+            parts.push(format!("{k}=\"{v}\""));
+        }
+        if parts.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", parts.join(","))
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// The value of one series at the time it was read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    /// Sorted by label name.
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// A minimal in-process metrics registry rendering the Prometheus text format.
+///
+/// Series are created lazily on first use; there is no registration step.
+#[derive(Debug, Default)]
+pub struct MetricsRegistry {
+    gauges: RwLock<BTreeMap<SeriesKey, f64>>,
+    counters: RwLock<BTreeMap<SeriesKey, u64>>,
+    histograms: RwLock<BTreeMap<SeriesKey, Histogram>>,
+}
+
+impl MetricsRegistry {
+    /// Sets a gauge to `value`.
+    pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut gauges = self.gauges.write().unwrap_or_else(|e| e.into_inner());
+        gauges.insert(SeriesKey::new(name, labels), value);
+    }
+
+    /// Replaces every series of the gauge `name` with `series`, so label sets no longer reported
+    /// disappear.
+    pub fn replace_gauges(&self, name: &str, series: &[(Vec<(&str, &str)>, f64)]) {
+        let mut gauges = self.gauges.write().unwrap_or_else(|e| e.into_inner());
+        gauges.retain(|key, _| key.name != name);
+        for (labels, value) in series {
+            gauges.insert(SeriesKey::new(name, labels), *value);
+        }
+    }
+
+    /// Increments a counter by `by`.
+    pub fn inc_counter(&self, name: &str, labels: &[(&str, &str)], by: u64) {
+        let mut counters = self.counters.write().unwrap_or_else(|e| e.into_inner());
+        *counters.entry(SeriesKey::new(name, labels)).or_default() += by;
+    }
+
+    /// Records an observation in a histogram using the default buckets.
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut histograms = self.histograms.write().unwrap_or_else(|e| e.into_inner());
+        let histogram = histograms
+            .entry(SeriesKey::new(name, labels))
+            .or_insert_with(|| Histogram {
+                counts: vec![0; DEFAULT_BUCKETS.len()],
+                ..Default::default()
+            });
+        for (bucket, count) in DEFAULT_BUCKETS.iter().zip(histogram.counts.iter_mut()) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    /// Returns the current value of a gauge, if it has been set.
+    pub fn gauge(&self, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        let gauges = self.gauges.read().unwrap_or_else(|e| e.into_inner());
+        gauges.get(&SeriesKey::new(name, labels)).copied()
+    }
+
+    /// Returns the current value of a counter, if it has been incremented.
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> Option<u64> {
+        let counters = self.counters.read().unwrap_or_else(|e| e.into_inner());
+        counters.get(&SeriesKey::new(name, labels)).copied()
+    }
+
+    /// Returns the `(count, sum)` of a histogram, if it has observations.
+    pub fn histogram(&self, name: &str, labels: &[(&str, &str)]) -> Option<(u64, f64)> {
+        let histograms = self.histograms.read().unwrap_or_else(|e| e.into_inner());
+        histograms
+            .get(&SeriesKey::new(name, labels))
+            .map(|h| (h.count, h.sum))
+    }
+
+    /// Every series as a scrape would see it: counters, gauges, and the `_bucket`, `_sum` and
+    /// `_count` series of each histogram.
+    pub fn samples(&self) -> Vec<Sample> {
+        let sample = |key: &SeriesKey, suffix: &str, extra: Option<(&str, String)>, value| {
+            let mut labels = key.labels.clone();
+            if let Some((name, value)) = extra {
+                labels.push((name.to_string(), value));
+                labels.sort();
+            }
+            Sample {
+                name: format!("{}{suffix}", key.name),
+                labels,
+                value,
+            }
+        };
+        let mut samples = Vec::new();
+        let counters = self.counters.read().unwrap_or_else(|e| e.into_inner());
+        for (key, value) in counters.iter() {
+            samples.push(sample(key, "", None, *value as f64));
+        }
+        drop(counters);
+        let gauges = self.gauges.read().unwrap_or_else(|e| e.into_inner());
+        for (key, value) in gauges.iter() {
+            samples.push(sample(key, "", None, *value));
+        }
+        drop(gauges);
+        let histograms = self.histograms.read().unwrap_or_else(|e| e.into_inner());
+        for (key, histogram) in histograms.iter() {
+            for (bucket, count) in DEFAULT_BUCKETS.iter().zip(histogram.counts.iter()) {
+                let le = Some(("le", bucket.to_string()));
+                samples.push(sample(key, "_bucket", le, *count as f64));
+            }
+            let le = Some(("le", "+Inf".to_string()));
+            samples.push(sample(key, "_bucket", le, histogram.count as f64));
+            samples.push(sample(key, "_sum", None, histogram.sum));
+            samples.push(sample(key, "_count", None, histogram.count as f64));
+        }
+        samples
+    }
+
+    /// Renders every series in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = self.counters.read().unwrap_or_else(|e| e.into_inner());
+        let mut last_name = None;
+        for (key, value) in counters.iter() {
+            if last_name != Some(&key.name) {
+                let _ = writeln!(out, "# TYPE {} counter", key.name);
+                last_name = Some(&key.name);
+            }
+            let _ = writeln!(out, "{}{} {}", key.name, key.render_labels(None), value);
+        }
+        drop(counters);
+
+        let gauges = self.gauges.read().unwrap_or_else(|e| e.into_inner());
+        let mut last_name = None;
+        for (key, value) in gauges.iter() {
+            if last_name != Some(&key.name) {
+                let _ = writeln!(out, "# TYPE {} gauge", key.name);
+                last_name = Some(&key.name);
+            }
+            let _ = writeln!(out, "{}{} {}", key.name, key.render_labels(None), value);
+        }
+        drop(gauges);
+
+        let histograms = self.histograms.read().unwrap_or_else(|e| e.into_inner());
+        let mut last_name = None;
+        for (key, histogram) in histograms.iter() {
+            if last_name != Some(&key.name) {
+                let _ = writeln!(out, "# TYPE {} histogram", key.name);
+                last_name = Some(&key.name);
+            }
+            for (bucket, count) in DEFAULT_BUCKETS.iter().zip(histogram.counts.iter()) {
+                let le = bucket.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    key.name,
+                    key.render_labels(Some(("le", &le))),
+                    count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                key.name,
+                key.render_labels(Some(("le", "+Inf"))),
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{} {}",
+                key.name,
+                key.render_labels(None),
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{} {}",
+                key.name,
+                key.render_labels(None),
+                histogram.count
+            );
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
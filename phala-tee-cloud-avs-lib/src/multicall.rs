@@ -0,0 +1,85 @@
+//! Batched read-only calls through the canonical Multicall3 contract.
+
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::primitives::{Address, Bytes, address};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::sol;
+use blueprint_sdk::alloy::sol_types::SolCall;
+
+/// Multicall3 is deployed at the same address on every major chain and on Anvil forks.
+pub const MULTICALL3_ADDRESS: Address = address!("cA11bde05977b3631167028862bE2a173976CA11");
+
+sol! {
+    #[sol(rpc)]
+    interface IMulticall3 {
+        struct Call3 {
+            address target;
+            bool allowFailure;
+            bytes callData;
+        }
+
+        struct Result {
+            bool success;
+            bytes returnData;
+        }
+
+        function aggregate3(Call3[] calldata calls) external payable returns (Result[] memory returnData);
+    }
+}
+
+/// Accumulates calls and executes them in a single `eth_call`.
+#[derive(Debug, Default)]
+pub struct MulticallBatch {
+    calls: Vec<IMulticall3::Call3>,
+}
+
+impl MulticallBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `call` against `target`, returning its position in the results.
+    pub fn add<C: SolCall>(&mut self, target: Address, call: &C) -> usize {
+        self.calls.push(IMulticall3::Call3 {
+            target,
+            allowFailure: true,
+            callData: Bytes::from(call.abi_encode()),
+        });
+        self.calls.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Executes the batch; a failed individual call yields `None` at its position.
+    pub async fn execute<P: Provider>(
+        self,
+        provider: &P,
+        multicall: Address,
+    ) -> Result<Vec<Option<Bytes>>, PhalaAvsError> {
+        if self.calls.is_empty() {
+            return Ok(Vec::new());
+        }
+        let results = IMulticall3::new(multicall, provider)
+            .aggregate3(self.calls)
+            .call()
+            .await
+            .map_err(|e| PhalaAvsError::EvmError(format!("multicall failed: {e}")))?
+            .returnData;
+        Ok(results
+            .into_iter()
+            .map(|r| r.success.then_some(r.returnData))
+            .collect())
+    }
+}
+
+/// Decodes the return data of a batched call.
+pub fn decode<C: SolCall>(data: Option<&Bytes>) -> Result<C::Return, PhalaAvsError> {
+    let data = data.ok_or_else(|| {
+        PhalaAvsError::EvmError(format!("batched call {} reverted", C::SIGNATURE))
+    })?;
+    C::abi_decode_returns(data, true).map_err(|e| {
+        PhalaAvsError::EvmError(format!("invalid return data for {}: {e}", C::SIGNATURE))
+    })
+}
@@ -0,0 +1,146 @@
+//! Operator alerts.
+//!
+//! Subsystems raise an [`Alert`] through the context's [`Notifier`]. Alerts are always logged;
+//! with `ALERT_WEBHOOK_URL` set they are also posted there as JSON.
+
+use crate::config::env_opt;
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::otel::with_traceparent;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// Counter of raised alerts, by source and severity.
+pub const ALERTS_METRIC: &str = "phala_avs_alerts_total";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Alert {
+    /// Identifies the alert as an incident operators can annotate.
+    #[serde(default)]
+    pub id: String,
+    /// The subsystem raising the alert, e.g. `heartbeat`.
+    pub source: String,
+    pub severity: Severity,
+    pub message: String,
+    /// Blocks left to answer the challenge the alert is about, if it is about one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining_window_blocks: Option<u64>,
+}
+
+impl Alert {
+    pub fn new(source: &str, severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().simple().to_string()[..12].to_string(),
+            source: source.to_string(),
+            severity,
+            message: message.into(),
+            remaining_window_blocks: None,
+        }
+    }
+
+    pub fn with_remaining_window(mut self, blocks: u64) -> Self {
+        self.remaining_window_blocks = Some(blocks);
+        self
+    }
+}
+
+/// Where alerts are delivered.
+pub trait Notifier: Send + Sync {
+    fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+}
+
+/// Logs alerts at a level matching their severity.
+#[derive(Clone, Debug, Default)]
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        log_alert(&alert);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Logs alerts and posts them to a webhook.
+#[derive(Clone, Debug)]
+pub struct WebhookNotifier {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        log_alert(&alert);
+        Box::pin(async move {
+            with_traceparent(self.client.post(&self.url))
+                .json(&alert)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map(|_| ())
+                .map_err(|e| PhalaAvsError::Other(format!("Failed to post alert: {e}")))
+        })
+    }
+}
+
+/// The notifier configured by `ALERT_WEBHOOK_URL`.
+pub fn notifier_from_env() -> Result<Arc<dyn Notifier>, PhalaAvsError> {
+    Ok(match env_opt::<String>("ALERT_WEBHOOK_URL")? {
+        Some(url) => Arc::new(WebhookNotifier::new(url)),
+        None => Arc::new(LogNotifier),
+    })
+}
+
+fn log_alert(alert: &Alert) {
+    METRICS.inc_counter(
+        ALERTS_METRIC,
+        &[
+            ("source", &alert.source),
+            ("severity", alert.severity.as_str()),
+        ],
+        1,
+    );
+    let remaining_blocks = alert.remaining_window_blocks;
+    match alert.severity {
+        Severity::Info => info!(
+            remaining_blocks,
+            "[alert:{} {}] {}", alert.source, alert.id, alert.message
+        ),
+        Severity::Warning => warn!(
+            remaining_blocks,
+            "[alert:{} {}] {}", alert.source, alert.id, alert.message
+        ),
+        Severity::Critical => error!(
+            remaining_blocks,
+            "[alert:{} {}] CRITICAL: {}", alert.source, alert.id, alert.message
+        ),
+    }
+}
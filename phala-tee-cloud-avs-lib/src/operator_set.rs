@@ -0,0 +1,634 @@
+//! Tracking of the operator set of our quorums and of our share of their stake.
+//!
+//! The tracker snapshots every operator with its stake per quorum, periodically and whenever the
+//! registry coordinator emits a registration event, diffs each snapshot against the previous one
+//! and reports churn. Changes moving more than the configured share of a quorum's stake are
+//! logged as warnings, smaller ones at info level.
+
+use crate::config::{env_address, env_or};
+use crate::disk::ReleaseSpace;
+use crate::error::PhalaAvsError;
+use crate::evm::{BoxFuture, EvmClient};
+use crate::metrics::METRICS;
+use crate::multicall::{MULTICALL3_ADDRESS, MulticallBatch, decode};
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{Address, B256, U256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::Log;
+use blueprint_sdk::alloy::sol;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// StateStore namespace holding snapshots keyed by block number.
+pub const OPERATOR_SET_NAMESPACE: &str = "operator_set";
+
+/// Gauge of our share (0..1) of each quorum's total stake.
+pub const STAKE_SHARE_METRIC: &str = "phala_avs_operator_stake_share";
+
+sol! {
+    #[sol(rpc)]
+    interface IRegistryCoordinator {
+        event OperatorRegistered(address indexed operator, bytes32 indexed operatorId);
+        event OperatorDeregistered(address indexed operator, bytes32 indexed operatorId);
+
+        function quorumCount() external view returns (uint8);
+        function getOperatorFromId(bytes32 operatorId) external view returns (address);
+        function deregisterOperator(bytes calldata quorumNumbers) external;
+    }
+
+    #[sol(rpc)]
+    interface IIndexRegistry {
+        function getOperatorListAtBlockNumber(uint8 quorumNumber, uint32 blockNumber) external view returns (bytes32[] memory);
+    }
+
+    #[sol(rpc)]
+    interface IStakeRegistry {
+        function getCurrentStake(bytes32 operatorId, uint8 quorumNumber) external view returns (uint96);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OperatorSetConfig {
+    pub registry_coordinator: Address,
+    pub index_registry: Address,
+    pub stake_registry: Address,
+    pub multicall: Address,
+    pub refresh_secs: u64,
+    /// Churn moving at least this share of a quorum's stake is reported as a warning.
+    pub warn_share_bps: u32,
+    /// Share of a quorum's stake that must sign for a response to be accepted.
+    pub quorum_threshold_bps: u32,
+    /// Number of snapshots kept in the state store.
+    pub history_limit: usize,
+}
+
+impl OperatorSetConfig {
+    /// Loads the configuration; returns `None` unless the registry addresses are configured.
+    pub fn from_env() -> Result<Option<Self>, PhalaAvsError> {
+        let (Some(registry_coordinator), Some(index_registry), Some(stake_registry)) = (
+            env_address("REGISTRY_COORDINATOR_ADDRESS")?,
+            env_address("INDEX_REGISTRY_ADDRESS")?,
+            env_address("STAKE_REGISTRY_ADDRESS")?,
+        ) else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            registry_coordinator,
+            index_registry,
+            stake_registry,
+            multicall: env_or("MULTICALL_ADDRESS", MULTICALL3_ADDRESS)?,
+            refresh_secs: env_or("OPERATOR_SET_REFRESH_SECS", 300)?,
+            warn_share_bps: env_or("OPERATOR_SET_WARN_SHARE_BPS", 500)?,
+            quorum_threshold_bps: env_or("QUORUM_THRESHOLD_BPS", 6667)?,
+            history_limit: env_or("OPERATOR_SET_HISTORY_LIMIT", 100)?,
+        }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperatorStake {
+    pub operator: Address,
+    pub operator_id: B256,
+    pub stake: U256,
+}
+
+/// Every operator with its stake, per quorum, at a block.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperatorSetSnapshot {
+    pub block: u64,
+    pub taken_unix_ms: u64,
+    pub quorums: BTreeMap<u8, Vec<OperatorStake>>,
+}
+
+impl OperatorSetSnapshot {
+    pub fn total_stake(&self, quorum: u8) -> U256 {
+        self.quorums
+            .get(&quorum)
+            .map(|ops| ops.iter().map(|o| o.stake).sum())
+            .unwrap_or_default()
+    }
+
+    pub fn stake_of(&self, quorum: u8, operator: Address) -> U256 {
+        self.quorums
+            .get(&quorum)
+            .and_then(|ops| ops.iter().find(|o| o.operator == operator))
+            .map(|o| o.stake)
+            .unwrap_or_default()
+    }
+
+    /// `operator`'s share (0..1) of `quorum`'s total stake.
+    pub fn share_of(&self, quorum: u8, operator: Address) -> f64 {
+        share(self.stake_of(quorum, operator), self.total_stake(quorum))
+    }
+}
+
+fn share(part: U256, total: U256) -> f64 {
+    if total.is_zero() {
+        return 0.0;
+    }
+    f64::from(part) / f64::from(total)
+}
+
+/// An operator that joined or left, with its share of the quorum stake it affected.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OperatorChange {
+    pub operator: Address,
+    pub stake: U256,
+    /// Share of the larger of the quorum's old and new total stake.
+    pub share: f64,
+}
+
+/// Difference between two snapshots for one quorum.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuorumDiff {
+    pub quorum: u8,
+    pub joined: Vec<OperatorChange>,
+    pub left: Vec<OperatorChange>,
+    pub total_stake_before: U256,
+    pub total_stake_after: U256,
+    pub our_share_before: f64,
+    pub our_share_after: f64,
+    /// Stake that must sign for the quorum threshold to be met, after the change.
+    pub threshold_stake_after: U256,
+    /// How much of the threshold our stake alone covers, after the change.
+    pub our_threshold_coverage_after: f64,
+    /// True when a single joined or left operator moved at least the warning share.
+    pub significant: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OperatorSetDiff {
+    pub from_block: u64,
+    pub to_block: u64,
+    pub quorums: Vec<QuorumDiff>,
+}
+
+impl OperatorSetDiff {
+    pub fn is_empty(&self) -> bool {
+        self.quorums.is_empty()
+    }
+
+    pub fn is_significant(&self) -> bool {
+        self.quorums.iter().any(|q| q.significant)
+    }
+}
+
+/// Computes the changes between `before` and `after` for every quorum present in either.
+pub fn diff_snapshots(
+    before: &OperatorSetSnapshot,
+    after: &OperatorSetSnapshot,
+    ours: Address,
+    warn_share_bps: u32,
+    quorum_threshold_bps: u32,
+) -> OperatorSetDiff {
+    let empty = Vec::new();
+    let mut quorums = Vec::new();
+    let all_quorums: std::collections::BTreeSet<u8> = before
+        .quorums
+        .keys()
+        .chain(after.quorums.keys())
+        .copied()
+        .collect();
+    for quorum in all_quorums {
+        let old = before.quorums.get(&quorum).unwrap_or(&empty);
+        let new = after.quorums.get(&quorum).unwrap_or(&empty);
+        let total_before = before.total_stake(quorum);
+        let total_after = after.total_stake(quorum);
+        let reference = total_before.max(total_after);
+
+        let change = |o: &OperatorStake| OperatorChange {
+            operator: o.operator,
+            stake: o.stake,
+            share: share(o.stake, reference),
+        };
+        let joined: Vec<_> = new
+            .iter()
+            .filter(|n| old.iter().all(|o| o.operator != n.operator))
+            .map(change)
+            .collect();
+        let left: Vec<_> = old
+            .iter()
+            .filter(|o| new.iter().all(|n| n.operator != o.operator))
+            .map(change)
+            .collect();
+        if joined.is_empty() && left.is_empty() && total_before == total_after {
+            continue;
+        }
+
+        let warn_share = f64::from(warn_share_bps) / 10_000.0;
+        let significant = joined.iter().chain(&left).any(|c| c.share >= warn_share);
+        let threshold_stake_after =
+            total_after * U256::from(quorum_threshold_bps) / U256::from(10_000u32);
+        quorums.push(QuorumDiff {
+            quorum,
+            joined,
+            left,
+            total_stake_before: total_before,
+            total_stake_after: total_after,
+            our_share_before: before.share_of(quorum, ours),
+            our_share_after: after.share_of(quorum, ours),
+            our_threshold_coverage_after: share(
+                after.stake_of(quorum, ours),
+                threshold_stake_after,
+            )
+            .min(1.0),
+            threshold_stake_after,
+            significant,
+        });
+    }
+    OperatorSetDiff {
+        from_block: before.block,
+        to_block: after.block,
+        quorums,
+    }
+}
+
+/// Where operator set snapshots come from.
+pub trait OperatorSetSource: Send + Sync {
+    fn snapshot(&self, block: u64) -> BoxFuture<'_, Result<OperatorSetSnapshot, PhalaAvsError>>;
+}
+
+/// Reads the operator set from the EigenLayer middleware registries in three batched calls.
+#[derive(Clone, Debug)]
+pub struct RegistryOperatorSetSource<P> {
+    provider: P,
+    config: OperatorSetConfig,
+}
+
+impl<P> RegistryOperatorSetSource<P> {
+    pub fn new(provider: P, config: OperatorSetConfig) -> Self {
+        Self { provider, config }
+    }
+}
+
+impl<P: Provider + Send + Sync + 'static> OperatorSetSource for RegistryOperatorSetSource<P> {
+    fn snapshot(&self, block: u64) -> BoxFuture<'_, Result<OperatorSetSnapshot, PhalaAvsError>> {
+        Box::pin(async move {
+            let config = &self.config;
+            let quorum_count =
+                IRegistryCoordinator::new(config.registry_coordinator, &self.provider)
+                    .quorumCount()
+                    .call()
+                    .await
+                    .map_err(|e| PhalaAvsError::EvmError(format!("quorumCount failed: {e}")))?
+                    ._0;
+
+            // Operator ids per quorum.
+            let block_u32 = u32::try_from(block)
+                .map_err(|_| PhalaAvsError::EvmError(format!("block {block} exceeds uint32")))?;
+            let mut batch = MulticallBatch::new();
+            for quorum in 0..quorum_count {
+                batch.add(
+                    config.index_registry,
+                    &IIndexRegistry::getOperatorListAtBlockNumberCall {
+                        quorumNumber: quorum,
+                        blockNumber: block_u32,
+                    },
+                );
+            }
+            let lists = batch.execute(&self.provider, config.multicall).await?;
+            let mut ids_per_quorum = Vec::with_capacity(lists.len());
+            for (quorum, data) in (0..quorum_count).zip(&lists) {
+                let ids =
+                    decode::<IIndexRegistry::getOperatorListAtBlockNumberCall>(data.as_ref())?._0;
+                ids_per_quorum.push((quorum, ids));
+            }
+
+            // Stake and address of every (quorum, operator).
+            let mut batch = MulticallBatch::new();
+            for (quorum, ids) in &ids_per_quorum {
+                for id in ids {
+                    batch.add(
+                        config.stake_registry,
+                        &IStakeRegistry::getCurrentStakeCall {
+                            operatorId: *id,
+                            quorumNumber: *quorum,
+                        },
+                    );
+                    batch.add(
+                        config.registry_coordinator,
+                        &IRegistryCoordinator::getOperatorFromIdCall { operatorId: *id },
+                    );
+                }
+            }
+            let results = batch.execute(&self.provider, config.multicall).await?;
+            let mut results = results.chunks(2);
+
+            let mut quorums = BTreeMap::new();
+            for (quorum, ids) in ids_per_quorum {
+                let mut operators = Vec::with_capacity(ids.len());
+                for id in ids {
+                    let pair = results.next().ok_or_else(|| {
+                        PhalaAvsError::EvmError("multicall returned too few results".to_string())
+                    })?;
+                    let stake = decode::<IStakeRegistry::getCurrentStakeCall>(pair[0].as_ref())?._0;
+                    let operator =
+                        decode::<IRegistryCoordinator::getOperatorFromIdCall>(pair[1].as_ref())?._0;
+                    operators.push(OperatorStake {
+                        operator,
+                        operator_id: id,
+                        stake: U256::from(stake),
+                    });
+                }
+                quorums.insert(quorum, operators);
+            }
+            Ok(OperatorSetSnapshot {
+                block,
+                taken_unix_ms: now_unix_ms(),
+                quorums,
+            })
+        })
+    }
+}
+
+/// Our view of the operator sets of the quorums we are in.
+pub struct OperatorSetTracker {
+    config: OperatorSetConfig,
+    source: Arc<dyn OperatorSetSource>,
+    store: Arc<dyn StateStore>,
+    ours: Address,
+    current: RwLock<Option<OperatorSetSnapshot>>,
+}
+
+impl OperatorSetTracker {
+    /// Creates a tracker, restoring the latest persisted snapshot.
+    pub fn new(
+        config: OperatorSetConfig,
+        source: Arc<dyn OperatorSetSource>,
+        store: Arc<dyn StateStore>,
+        ours: Address,
+    ) -> Result<Self, PhalaAvsError> {
+        let current = match store.scan(OPERATOR_SET_NAMESPACE)?.pop() {
+            Some((_, raw)) => serde_json::from_slice(&raw).ok(),
+            None => None,
+        };
+        Ok(Self {
+            config,
+            source,
+            store,
+            ours,
+            current: RwLock::new(current),
+        })
+    }
+
+    pub fn config(&self) -> &OperatorSetConfig {
+        &self.config
+    }
+
+    pub fn current(&self) -> Option<OperatorSetSnapshot> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Persisted snapshots, oldest first.
+    pub fn history(&self) -> Result<Vec<OperatorSetSnapshot>, PhalaAvsError> {
+        self.store
+            .scan(OPERATOR_SET_NAMESPACE)?
+            .into_iter()
+            .map(|(_, raw)| {
+                serde_json::from_slice(&raw).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Corrupt operator set snapshot: {e}"))
+                })
+            })
+            .collect()
+    }
+
+    /// Our share of each quorum's stake in the current snapshot.
+    pub fn our_shares(&self) -> BTreeMap<u8, f64> {
+        self.current()
+            .map(|s| {
+                s.quorums
+                    .keys()
+                    .map(|q| (*q, s.share_of(*q, self.ours)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Takes a snapshot at `block`, persists it and reports the diff against the previous one.
+    pub async fn refresh(&self, block: u64) -> Result<Option<OperatorSetDiff>, PhalaAvsError> {
+        let snapshot = self.source.snapshot(block).await?;
+        self.store
+            .put_json(OPERATOR_SET_NAMESPACE, &block.to_be_bytes(), &snapshot)?;
+        self.trim_history(self.config.history_limit)?;
+
+        for quorum in snapshot.quorums.keys() {
+            METRICS.set_gauge(
+                STAKE_SHARE_METRIC,
+                &[("quorum", &quorum.to_string())],
+                snapshot.share_of(*quorum, self.ours),
+            );
+        }
+
+        let previous = self
+            .current
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .replace(snapshot.clone());
+        let Some(previous) = previous else {
+            return Ok(None);
+        };
+        let diff = diff_snapshots(
+            &previous,
+            &snapshot,
+            self.ours,
+            self.config.warn_share_bps,
+            self.config.quorum_threshold_bps,
+        );
+        notify(&diff);
+        Ok(Some(diff))
+    }
+
+    /// Deletes all but the latest `keep` persisted snapshots, returning how many were deleted.
+    fn trim_history(&self, keep: usize) -> Result<usize, PhalaAvsError> {
+        let keys: Vec<_> = self
+            .store
+            .scan(OPERATOR_SET_NAMESPACE)?
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        let excess = keys.len().saturating_sub(keep);
+        for key in keys.iter().take(excess) {
+            self.store.delete(OPERATOR_SET_NAMESPACE, key)?;
+        }
+        Ok(excess)
+    }
+}
+
+/// Under disk pressure only the latest snapshot is kept.
+impl ReleaseSpace for OperatorSetTracker {
+    fn release(&self, _head: u64) -> Result<usize, PhalaAvsError> {
+        self.trim_history(1)
+    }
+}
+
+/// Refreshes `tracker` at the chain head every `refresh_secs`, in the background.
+pub fn spawn_refresh(tracker: Arc<OperatorSetTracker>, evm: Arc<dyn EvmClient>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(tracker.config.refresh_secs));
+        loop {
+            interval.tick().await;
+            let result = match evm.block_number().await {
+                Ok(head) => tracker.refresh(head).await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Failed to refresh operator set: {e}");
+            }
+        }
+    });
+}
+
+fn notify(diff: &OperatorSetDiff) {
+    for q in &diff.quorums {
+        let message = format!(
+            "Quorum {} operator set changed between blocks {} and {}: {} joined, {} left, total stake {} -> {}, our share {:.2}% -> {:.2}%, our stake covers {:.2}% of the signing threshold",
+            q.quorum,
+            diff.from_block,
+            diff.to_block,
+            q.joined.len(),
+            q.left.len(),
+            q.total_stake_before,
+            q.total_stake_after,
+            q.our_share_before * 100.0,
+            q.our_share_after * 100.0,
+            q.our_threshold_coverage_after * 100.0,
+        );
+        if q.significant {
+            warn!("{message}");
+        } else {
+            info!("{message}");
+        }
+    }
+}
+
+/// Whether `log` is a registration event of `registry_coordinator`, warranting a refresh.
+pub fn is_registry_event(log: &Log, registry_coordinator: Address) -> bool {
+    log.address() == registry_coordinator
+        && (log
+            .log_decode::<IRegistryCoordinator::OperatorRegistered>()
+            .is_ok()
+            || log
+                .log_decode::<IRegistryCoordinator::OperatorDeregistered>()
+                .is_ok())
+}
+
+fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+    use std::sync::Mutex;
+
+    fn op(byte: u8, stake: u64) -> OperatorStake {
+        OperatorStake {
+            operator: Address::repeat_byte(byte),
+            operator_id: B256::repeat_byte(byte),
+            stake: U256::from(stake),
+        }
+    }
+
+    struct ScriptedSource(Mutex<Vec<Vec<OperatorStake>>>);
+
+    impl OperatorSetSource for ScriptedSource {
+        fn snapshot(
+            &self,
+            block: u64,
+        ) -> BoxFuture<'_, Result<OperatorSetSnapshot, PhalaAvsError>> {
+            let operators = self.0.lock().unwrap().remove(0);
+            Box::pin(async move {
+                Ok(OperatorSetSnapshot {
+                    block,
+                    taken_unix_ms: 0,
+                    quorums: BTreeMap::from([(0, operators)]),
+                })
+            })
+        }
+    }
+
+    fn config() -> OperatorSetConfig {
+        OperatorSetConfig {
+            registry_coordinator: Address::ZERO,
+            index_registry: Address::ZERO,
+            stake_registry: Address::ZERO,
+            multicall: MULTICALL3_ADDRESS,
+            refresh_secs: 300,
+            warn_share_bps: 1000,
+            quorum_threshold_bps: 5000,
+            history_limit: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn diffs_report_churn_and_share_math() {
+        let ours = Address::repeat_byte(1);
+        let source = ScriptedSource(Mutex::new(vec![
+            vec![op(1, 100), op(2, 100)],
+            // A small operator joins.
+            vec![op(1, 100), op(2, 100), op(3, 10)],
+            // A large operator leaves and a large one joins.
+            vec![op(1, 100), op(3, 10), op(4, 290)],
+        ]));
+        let store = Arc::new(MemoryStateStore::default());
+        let tracker =
+            OperatorSetTracker::new(config(), Arc::new(source), store.clone(), ours).unwrap();
+
+        assert!(tracker.refresh(10).await.unwrap().is_none());
+        assert_eq!(tracker.our_shares()[&0], 0.5);
+
+        let diff = tracker.refresh(20).await.unwrap().unwrap();
+        let q = &diff.quorums[0];
+        assert_eq!(q.joined.len(), 1);
+        assert!(q.left.is_empty());
+        assert!(!diff.is_significant());
+        assert_eq!(q.total_stake_after, U256::from(210));
+        assert!((q.our_share_after - 100.0 / 210.0).abs() < 1e-9);
+        // Threshold is half of 210; our 100 covers 100/105 of it.
+        assert_eq!(q.threshold_stake_after, U256::from(105));
+        assert!((q.our_threshold_coverage_after - 100.0 / 105.0).abs() < 1e-9);
+
+        let diff = tracker.refresh(30).await.unwrap().unwrap();
+        let q = &diff.quorums[0];
+        assert_eq!(q.left[0].operator, Address::repeat_byte(2));
+        assert_eq!(q.joined[0].operator, Address::repeat_byte(4));
+        assert!(diff.is_significant());
+        assert_eq!(q.our_share_after, 0.25);
+        assert_eq!(q.our_threshold_coverage_after, 0.5);
+
+        // Only the configured number of snapshots is kept, and the latest is restored.
+        let history = tracker.history().unwrap();
+        assert_eq!(history.iter().map(|s| s.block).collect::<Vec<_>>(), [
+            20, 30
+        ]);
+        let restored = OperatorSetTracker::new(
+            config(),
+            Arc::new(ScriptedSource(Mutex::new(Vec::new()))),
+            store,
+            ours,
+        )
+        .unwrap();
+        assert_eq!(restored.current().unwrap().block, 30);
+    }
+
+    #[test]
+    fn unchanged_sets_produce_empty_diffs() {
+        let snapshot = OperatorSetSnapshot {
+            block: 1,
+            taken_unix_ms: 0,
+            quorums: BTreeMap::from([(0, vec![op(1, 5)])]),
+        };
+        let diff = diff_snapshots(&snapshot, &snapshot, Address::ZERO, 500, 6667);
+        assert!(diff.is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+//! Optional OpenTelemetry trace export and W3C trace-context propagation.
+//!
+//! With the `otel` feature, [`layer`] exports spans over OTLP, configured through the standard
+//! `OTEL_EXPORTER_OTLP_*` and `OTEL_SERVICE_NAME` variables, once an OTLP endpoint is set.
+//! Outgoing HTTP calls carry the current span's context in a `traceparent` header; JSON-RPC
+//! calls to the aggregator carry it as a `traceparent` field of the request envelope, which the
+//! aggregator continues with [`set_parent`]. Without the feature these functions do nothing.
+
+use tracing::Span;
+
+/// Header and JSON-RPC envelope field carrying the W3C trace context.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// The `traceparent` of `span`, if it is being exported.
+pub fn traceparent(span: &Span) -> Option<String> {
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::propagation::TextMapPropagator;
+        use opentelemetry_sdk::propagation::TraceContextPropagator;
+        use std::collections::HashMap;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+        carrier.remove(TRACEPARENT)
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = span;
+        None
+    }
+}
+
+/// The `traceparent` of the current span.
+pub fn current_traceparent() -> Option<String> {
+    traceparent(&Span::current())
+}
+
+/// Continues the trace of `traceparent` in `span`. Must be called before `span` is entered.
+pub fn set_parent(span: &Span, traceparent: Option<&str>) {
+    #[cfg(feature = "otel")]
+    if let Some(traceparent) = traceparent {
+        use opentelemetry::propagation::TextMapPropagator;
+        use opentelemetry_sdk::propagation::TraceContextPropagator;
+        use std::collections::HashMap;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+        span.set_parent(TraceContextPropagator::new().extract(&carrier));
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, traceparent);
+}
+
+/// Adds the current span's `traceparent` header to `request`.
+pub fn with_traceparent(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match current_traceparent() {
+        Some(traceparent) => request.header(TRACEPARENT, traceparent),
+        None => request,
+    }
+}
+
+/// A layer exporting spans over OTLP, or `None` when no OTLP endpoint is configured.
+///
+/// The exporter batches on the Tokio runtime, so this must be called from within one.
+#[cfg(feature = "otel")]
+pub fn layer<S>() -> Result<
+    Option<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>>,
+    crate::PhalaAvsError,
+>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use opentelemetry::trace::TracerProvider as _;
+
+    let configured = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|key| std::env::var(key).is_ok_and(|v| !v.trim().is_empty()));
+    if !configured {
+        return Ok(None);
+    }
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .map_err(|e| {
+            crate::PhalaAvsError::ConfigError(format!("Invalid OTLP exporter settings: {e}"))
+        })?;
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .build();
+    let tracer = provider.tracer("phala-tee-cloud-avs");
+    opentelemetry::global::set_tracer_provider(provider);
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+#[cfg(all(test, feature = "otel", feature = "aggregator"))]
+mod tests {
+    use super::*;
+    use crate::aggregator::client::AggregatorClient;
+    use crate::aggregator::dedupe::{DedupeConfig, ResponseLedger};
+    use crate::aggregator::idempotency::IdempotencyCache;
+    use crate::aggregator::server::{Aggregation, AggregatorServer};
+    use crate::aggregator_wire::SignedTaskResponse;
+    use crate::error::PhalaAvsError;
+    use crate::evidence::now_unix_ms;
+    use crate::evm::BoxFuture;
+    use crate::state::{MemoryStateStore, StateStore};
+    use blueprint_sdk::alloy::primitives::{B256, Bytes};
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use serde_json::{Value, json};
+    use std::sync::Arc;
+    use tracing::{Instrument, info_span};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    struct AcceptAll;
+
+    impl Aggregation for AcceptAll {
+        fn encode(&self, task_response: &Value) -> Result<Bytes, PhalaAvsError> {
+            Ok(serde_json::to_vec(task_response).unwrap().into())
+        }
+
+        fn verify<'a>(
+            &'a self,
+            _response: &'a SignedTaskResponse,
+        ) -> BoxFuture<'a, Result<(), PhalaAvsError>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn aggregate(
+            &self,
+            _response: SignedTaskResponse,
+        ) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn operator_submission_and_aggregation_share_a_trace() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        // The server answers on threads of its own, so the subscriber is global; spans of other
+        // tests are told apart by their trace.
+        tracing::subscriber::set_global_default(subscriber).unwrap();
+
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let config = DedupeConfig {
+            retention_secs: 600,
+            exclude_after: None,
+            conflict_window_secs: 600,
+        };
+        let idempotency = IdempotencyCache::new(Arc::clone(&store), 600);
+        let ledger = ResponseLedger::new(config, store).unwrap();
+        let server = AggregatorServer::new(Arc::new(AcceptAll), ledger, idempotency, None);
+        server.task_registered(7, &[0], now_unix_ms()).unwrap();
+        let http = server.start(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("http://{}", http.address());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let client = AggregatorClient::connect(url).await.unwrap();
+            let response = SignedTaskResponse {
+                task_response: json!({ "referenceTaskIndex": 7, "numberSquared": "0x31" }),
+                signature: json!({ "g1_point": { "X": "0x1", "Y": "0x2" } }),
+                operator_id: B256::repeat_byte(0xaa),
+            };
+            let reply = client
+                .send_signed_task_response(&response)
+                .instrument(info_span!("operator.send_response", task_index = 7))
+                .await
+                .unwrap();
+            assert!(reply.accepted());
+        });
+        http.close();
+        // The aggregation submits the aggregated response once the task reached its quorum.
+        drop(server.submission_span(7));
+
+        provider.force_flush();
+        let spans = exporter.get_finished_spans().unwrap();
+        let send = spans
+            .iter()
+            .find(|s| s.name == "operator.send_response")
+            .unwrap_or_else(|| panic!("no operator.send_response span in {spans:?}"));
+        let trace_id = send.span_context.trace_id();
+        let span = |name: &str| {
+            spans
+                .iter()
+                .find(|s| s.name == name && s.span_context.trace_id() == trace_id)
+                .unwrap_or_else(|| panic!("no {name} span in the operator's trace: {spans:?}"))
+        };
+        let receive = span("aggregator.rpc_receive");
+        assert_eq!(receive.parent_span_id, send.span_context.span_id());
+        for stage in [
+            "aggregator.rpc_parse",
+            "aggregator.verify",
+            "aggregator.aggregate",
+        ] {
+            assert_eq!(span(stage).parent_span_id, receive.span_context.span_id());
+        }
+        let submit = span("aggregator.submit");
+        assert_eq!(submit.parent_span_id, receive.span_context.span_id());
+    }
+}
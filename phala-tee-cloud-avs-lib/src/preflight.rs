@@ -0,0 +1,1119 @@
+//! Local simulation of the oracle's verification of attestation responses.
+//!
+//! Attestation responses carry large quotes and are verified strictly, so before one is
+//! submitted it goes through the checks the oracle's verifier applies, against the policy it
+//! reads from `IPhalaSlaOracle.attestationPolicy()`:
+//!
+//! 1. The response decodes and answers the challenge.
+//! 2. The quote parses as a TDX or SGX quote, of the platform the response is tagged with.
+//! 3. Its platform is in the policy, and its measurement is allow-listed for that platform.
+//! 4. Its report data is [`attestation_report_data`] of the challenge and of this build.
+//! 5. It is no older than `maxQuoteAgeSecs`, and not from the future.
+//!
+//! The policy is read for every verification rather than configured locally, so the two cannot
+//! drift apart. A failed check is diagnosed, the response is rebuilt once with a fresh quote, and
+//! if that fails too it is dead-lettered. The last response that passed is kept as a fixture and
+//! re-verified every `ATTESTATION_SELF_CHECK_SECS`.
+//!
+//! With [collateral tracking](crate::tee::collateral), a response passing these checks is still
+//! held back while the platform's TCB is `SWHardeningNeeded` or worse: the attestation schemas
+//! have no field for the TCB status, so the oracle's verifier rejects such quotes. A fresh quote
+//! does not change the TCB, so these are dead-lettered without a rebuild.
+
+use crate::IPhalaSlaOracle;
+use crate::build_info::BuildInfo;
+use crate::challenge::ObservedChallenge;
+use crate::config::env_or;
+use crate::encoding::{
+    AttestationResponseV1, AttestationResponseV2, SchemaKey, attestation_challenge,
+    attestation_report_data,
+};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Severity};
+use crate::state::{StateStore, StateStoreExt};
+use crate::tee::attestation::{AttestationReport, verifier_for};
+use crate::tee::collateral::{CollateralMonitor, TcbStatus};
+use crate::tee::platform::TeePlatform;
+use crate::tee::quote::{QuoteHeader, TDX_TEE_TYPE};
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, U256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::sol_types::SolValue;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// Responses that failed verification twice, by challenge id.
+pub const DEAD_LETTER_NAMESPACE: &str = "attestation_dead_letters";
+/// The last response that passed verification.
+pub const FIXTURE_NAMESPACE: &str = "attestation_fixture";
+const FIXTURE_KEY: &[u8] = b"known_good";
+
+/// Counter of failed local verifications, by check.
+pub const PREFLIGHT_FAILURES_METRIC: &str = "phala_avs_preflight_failures_total";
+/// Counter of dead-lettered attestation responses.
+pub const PREFLIGHT_DEAD_LETTERS_METRIC: &str = "phala_avs_preflight_dead_letters_total";
+/// Counter of responses that passed local verification, by platform.
+pub const PREFLIGHT_VERIFIED_METRIC: &str = "phala_avs_preflight_verified_total";
+/// Gauge set to 0 while the known-good fixture fails verification.
+pub const PREFLIGHT_SELF_CHECK_METRIC: &str = "phala_avs_preflight_self_check_ok";
+
+#[derive(Clone, Debug)]
+pub struct PreflightConfig {
+    pub self_check_secs: u64,
+}
+
+impl PreflightConfig {
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            self_check_secs: env_or("ATTESTATION_SELF_CHECK_SECS", 3600)?,
+        })
+    }
+}
+
+/// A measurement the oracle accepts from one platform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyMeasurement {
+    pub platform: TeePlatform,
+    /// See [`AttestationReport::measurement`].
+    pub measurement: B256,
+}
+
+/// What the oracle accepts in attestation responses.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttestationPolicy {
+    pub allowed_measurements: Vec<PolicyMeasurement>,
+    pub max_quote_age_secs: u64,
+}
+
+impl AttestationPolicy {
+    /// The platforms with at least one allowed measurement.
+    pub fn platforms(&self) -> Vec<TeePlatform> {
+        let mut platforms: Vec<_> = self
+            .allowed_measurements
+            .iter()
+            .map(|m| m.platform)
+            .collect();
+        platforms.sort();
+        platforms.dedup();
+        platforms
+    }
+}
+
+/// Where the attestation policy comes from.
+pub trait PolicySource: Send + Sync {
+    fn fetch(&self) -> BoxFuture<'_, Result<AttestationPolicy, PhalaAvsError>>;
+}
+
+/// Reads the policy the oracle publishes on-chain.
+pub struct OraclePolicySource<P> {
+    provider: P,
+    oracle: Address,
+}
+
+impl<P> OraclePolicySource<P> {
+    pub fn new(provider: P, oracle: Address) -> Self {
+        Self { provider, oracle }
+    }
+}
+
+impl<P: Provider + Send + Sync + 'static> PolicySource for OraclePolicySource<P> {
+    fn fetch(&self) -> BoxFuture<'_, Result<AttestationPolicy, PhalaAvsError>> {
+        Box::pin(async move {
+            let policy = IPhalaSlaOracle::new(self.oracle, &self.provider)
+                .attestationPolicy()
+                .call()
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(format!("attestationPolicy failed: {e}")))?
+                ._0;
+            let allowed_measurements = policy
+                .allowedMeasurements
+                .into_iter()
+                .map(|entry| {
+                    let platform = TeePlatform::from_code(entry.platform).ok_or_else(|| {
+                        PhalaAvsError::EvmError(format!(
+                            "attestationPolicy has unknown platform code {}",
+                            entry.platform
+                        ))
+                    })?;
+                    Ok(PolicyMeasurement {
+                        platform,
+                        measurement: entry.measurement,
+                    })
+                })
+                .collect::<Result<_, PhalaAvsError>>()?;
+            Ok(AttestationPolicy {
+                allowed_measurements,
+                max_quote_age_secs: policy.maxQuoteAgeSecs,
+            })
+        })
+    }
+}
+
+/// Which check a response failed, and with what.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "check", rename_all = "snake_case")]
+pub enum Diagnosis {
+    Malformed {
+        reason: String,
+    },
+    WrongChallenge {
+        expected: U256,
+        actual: U256,
+    },
+    InvalidQuote {
+        reason: String,
+    },
+    WrongTeeType {
+        expected: u32,
+        actual: u32,
+    },
+    /// The response is tagged with one platform and carries another's quote.
+    PlatformMismatch {
+        tagged: u8,
+        quoted: TeePlatform,
+    },
+    PlatformNotAllowed {
+        platform: TeePlatform,
+        allowed: Vec<TeePlatform>,
+    },
+    MeasurementNotAllowed {
+        platform: TeePlatform,
+        actual: B256,
+        allowed: Vec<B256>,
+    },
+    ReportDataMismatch {
+        expected: Bytes,
+        actual: Bytes,
+    },
+    Stale {
+        quoted_at_unix: u64,
+        now_unix: u64,
+        max_age_secs: u64,
+    },
+    FromTheFuture {
+        quoted_at_unix: u64,
+        now_unix: u64,
+    },
+    /// The platform's TCB is `SWHardeningNeeded` or worse under the current collateral.
+    DegradedTcb {
+        platform: TeePlatform,
+        status: TcbStatus,
+        advisory_ids: Vec<String>,
+    },
+}
+
+impl Diagnosis {
+    /// The check that failed, as used in metric labels.
+    pub fn check(&self) -> &'static str {
+        match self {
+            Diagnosis::Malformed { .. } | Diagnosis::WrongChallenge { .. } => "decode",
+            Diagnosis::InvalidQuote { .. } | Diagnosis::WrongTeeType { .. } => "quote",
+            Diagnosis::PlatformMismatch { .. } | Diagnosis::PlatformNotAllowed { .. } => "platform",
+            Diagnosis::MeasurementNotAllowed { .. } => "measurement",
+            Diagnosis::ReportDataMismatch { .. } => "report_data",
+            Diagnosis::Stale { .. } | Diagnosis::FromTheFuture { .. } => "freshness",
+            Diagnosis::DegradedTcb { .. } => "tcb",
+        }
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} check failed: ", self.check())?;
+        match self {
+            Diagnosis::Malformed { reason } | Diagnosis::InvalidQuote { reason } => {
+                f.write_str(reason)
+            }
+            Diagnosis::WrongChallenge { expected, actual } => {
+                write!(f, "expected challenge {expected}, got {actual}")
+            }
+            Diagnosis::WrongTeeType { expected, actual } => {
+                write!(f, "expected TEE type {expected:#x}, got {actual:#x}")
+            }
+            Diagnosis::PlatformMismatch { tagged, quoted } => {
+                write!(
+                    f,
+                    "response is tagged platform {tagged}, but carries a {quoted} quote"
+                )
+            }
+            Diagnosis::PlatformNotAllowed { platform, allowed } => {
+                let allowed: Vec<_> = allowed.iter().map(|p| p.as_str()).collect();
+                write!(
+                    f,
+                    "{platform} quotes are not accepted, the policy allows [{}]",
+                    allowed.join(", ")
+                )
+            }
+            Diagnosis::MeasurementNotAllowed {
+                platform,
+                actual,
+                allowed,
+            } => {
+                write!(
+                    f,
+                    "{platform} measurement {actual} is not among the {} allowed",
+                    allowed.len()
+                )
+            }
+            Diagnosis::ReportDataMismatch { expected, actual } => {
+                write!(f, "expected report data {expected}, got {actual}")
+            }
+            Diagnosis::Stale {
+                quoted_at_unix,
+                now_unix,
+                max_age_secs,
+            } => write!(
+                f,
+                "quote is {}s old, at most {max_age_secs}s is accepted",
+                now_unix - quoted_at_unix
+            ),
+            Diagnosis::FromTheFuture {
+                quoted_at_unix,
+                now_unix,
+            } => write!(f, "quoted at {quoted_at_unix}, after {now_unix}"),
+            Diagnosis::DegradedTcb {
+                platform,
+                status,
+                advisory_ids,
+            } => write!(
+                f,
+                "the {platform} TCB is {} (advisories [{}])",
+                status.as_str(),
+                advisory_ids.join(", ")
+            ),
+        }
+    }
+}
+
+/// The fields of an attestation response of any version; version 1 has no platform tag.
+struct DecodedResponse {
+    challenge_id: U256,
+    platform: Option<u8>,
+    quoted_at_unix: u64,
+    quote: Bytes,
+}
+
+fn decode(challenge: &ObservedChallenge, response: &[u8]) -> Result<DecodedResponse, String> {
+    let version = SchemaKey::of(challenge).version;
+    let invalid = |e| format!("response does not decode as version {version}: {e}");
+    match version {
+        1 => AttestationResponseV1::abi_decode(response, true)
+            .map(|r| DecodedResponse {
+                challenge_id: r.challengeId,
+                platform: None,
+                quoted_at_unix: r.quotedAtUnix,
+                quote: r.quote,
+            })
+            .map_err(invalid),
+        2 => AttestationResponseV2::abi_decode(response, true)
+            .map(|r| DecodedResponse {
+                challenge_id: r.challengeId,
+                platform: Some(r.platform),
+                quoted_at_unix: r.quotedAtUnix,
+                quote: r.quote,
+            })
+            .map_err(invalid),
+        other => Err(format!("unknown attestation response version {other}")),
+    }
+}
+
+/// The oracle's verification of an attestation response to `challenge` at `now_unix`, and what
+/// the quote attests if it passes.
+pub fn verify(
+    policy: &AttestationPolicy,
+    challenge: &ObservedChallenge,
+    response: &[u8],
+    now_unix: u64,
+) -> Result<AttestationReport, Diagnosis> {
+    let malformed = |reason: String| Diagnosis::Malformed { reason };
+    let nonce = attestation_challenge(challenge)
+        .map_err(|e| malformed(e.to_string()))?
+        .nonce;
+    let response = decode(challenge, response).map_err(malformed)?;
+    if response.challenge_id != challenge.challenge_id {
+        return Err(Diagnosis::WrongChallenge {
+            expected: challenge.challenge_id,
+            actual: response.challenge_id,
+        });
+    }
+
+    let invalid_quote = |e: PhalaAvsError| Diagnosis::InvalidQuote {
+        reason: e.to_string(),
+    };
+    let header = QuoteHeader::parse(&response.quote).map_err(invalid_quote)?;
+    let platform = match header.platform() {
+        Some(platform) => platform,
+        // A version 4 quote of another TEE type is what TDX verification would reject it for.
+        None if header.version == 4 => {
+            return Err(Diagnosis::WrongTeeType {
+                expected: TDX_TEE_TYPE,
+                actual: header.tee_type,
+            });
+        }
+        None => {
+            return Err(Diagnosis::InvalidQuote {
+                reason: format!(
+                    "version {} with TEE type {:#x} is neither TDX nor SGX",
+                    header.version, header.tee_type
+                ),
+            });
+        }
+    };
+    if let Some(tagged) = response
+        .platform
+        .filter(|&tagged| tagged != platform.code())
+    {
+        return Err(Diagnosis::PlatformMismatch {
+            tagged,
+            quoted: platform,
+        });
+    }
+    let report = verifier_for(platform)
+        .parse(&response.quote)
+        .map_err(invalid_quote)?;
+
+    let allowed: Vec<B256> = policy
+        .allowed_measurements
+        .iter()
+        .filter(|m| m.platform == platform)
+        .map(|m| m.measurement)
+        .collect();
+    if allowed.is_empty() {
+        return Err(Diagnosis::PlatformNotAllowed {
+            platform,
+            allowed: policy.platforms(),
+        });
+    }
+    if !allowed.contains(&report.measurement) {
+        return Err(Diagnosis::MeasurementNotAllowed {
+            platform,
+            actual: report.measurement,
+            allowed,
+        });
+    }
+
+    let expected = attestation_report_data(
+        challenge.challenge_id,
+        nonce,
+        response.quoted_at_unix,
+        BuildInfo::current().build_hash,
+    );
+    if report.report_data.as_ref() != expected {
+        return Err(Diagnosis::ReportDataMismatch {
+            expected: Bytes::copy_from_slice(&expected),
+            actual: report.report_data,
+        });
+    }
+
+    let quoted_at_unix = response.quoted_at_unix;
+    if quoted_at_unix > now_unix {
+        return Err(Diagnosis::FromTheFuture {
+            quoted_at_unix,
+            now_unix,
+        });
+    }
+    if now_unix - quoted_at_unix > policy.max_quote_age_secs {
+        return Err(Diagnosis::Stale {
+            quoted_at_unix,
+            now_unix,
+            max_age_secs: policy.max_quote_age_secs,
+        });
+    }
+    Ok(report)
+}
+
+/// A response that failed verification, rebuilt or not.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub challenge: ObservedChallenge,
+    pub response: Bytes,
+    /// One per attempt, in order.
+    pub diagnoses: Vec<Diagnosis>,
+    pub dead_lettered_unix: u64,
+}
+
+impl DeadLetter {
+    /// A critical alert naming the checks the response failed.
+    pub fn alert(&self) -> Alert {
+        let diagnoses: Vec<_> = self.diagnoses.iter().map(ToString::to_string).collect();
+        Alert::new(
+            "preflight",
+            Severity::Critical,
+            format!(
+                "Dead-lettered the attestation response to challenge {} after {} build(s): {}",
+                self.challenge.challenge_id,
+                self.diagnoses.len(),
+                diagnoses.join("; ")
+            ),
+        )
+    }
+}
+
+/// A response that passed, re-verified by [`Preflight::self_check`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KnownGood {
+    pub challenge: ObservedChallenge,
+    pub response: Bytes,
+    pub verified_at_unix: u64,
+    /// Absent from fixtures stored before responses were platform-tagged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<AttestationReport>,
+}
+
+#[derive(Debug)]
+pub enum PreflightOutcome {
+    /// Ready to submit, after `attempts` builds.
+    Verified {
+        response: Bytes,
+        report: AttestationReport,
+        attempts: u32,
+    },
+    DeadLettered(DeadLetter),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SelfCheck {
+    /// Nothing has passed verification yet.
+    NoFixture,
+    Passed,
+    Failed(Diagnosis),
+}
+
+/// Verifies attestation responses before submission.
+pub struct Preflight {
+    config: PreflightConfig,
+    source: Arc<dyn PolicySource>,
+    store: Arc<dyn StateStore>,
+    collateral: Option<Arc<CollateralMonitor>>,
+}
+
+impl Preflight {
+    pub fn new(
+        config: PreflightConfig,
+        source: Arc<dyn PolicySource>,
+        store: Arc<dyn StateStore>,
+    ) -> Self {
+        Self {
+            config,
+            source,
+            store,
+            collateral: None,
+        }
+    }
+
+    /// Holds back responses while `collateral` finds the platform's TCB degraded.
+    pub fn with_collateral(mut self, collateral: Arc<CollateralMonitor>) -> Self {
+        self.collateral = Some(collateral);
+        self
+    }
+
+    /// Verifies one response against the current on-chain policy.
+    pub async fn verify(
+        &self,
+        challenge: &ObservedChallenge,
+        response: &Bytes,
+        now_unix: u64,
+    ) -> Result<Result<AttestationReport, Diagnosis>, PhalaAvsError> {
+        let policy = self.source.fetch().await?;
+        let verdict = verify(&policy, challenge, response, now_unix).and_then(|report| match self
+            .collateral
+            .as_ref()
+            .and_then(|c| c.degraded(report.platform))
+        {
+            Some(tcb) => Err(Diagnosis::DegradedTcb {
+                platform: report.platform,
+                status: tcb.status,
+                advisory_ids: tcb.advisory_ids,
+            }),
+            None => Ok(report),
+        });
+        match &verdict {
+            Ok(report) => {
+                METRICS.inc_counter(
+                    PREFLIGHT_VERIFIED_METRIC,
+                    &[("platform", report.platform.as_str())],
+                    1,
+                );
+                self.store
+                    .put_json(FIXTURE_NAMESPACE, FIXTURE_KEY, &KnownGood {
+                        challenge: challenge.clone(),
+                        response: response.clone(),
+                        verified_at_unix: now_unix,
+                        report: Some(report.clone()),
+                    })?
+            }
+            Err(diagnosis) => {
+                METRICS.inc_counter(
+                    PREFLIGHT_FAILURES_METRIC,
+                    &[("check", diagnosis.check())],
+                    1,
+                );
+                error!(
+                    "Attestation response to challenge {} would be rejected by the oracle: {diagnosis}",
+                    challenge.challenge_id
+                );
+            }
+        }
+        Ok(verdict)
+    }
+
+    /// Builds and verifies a response, rebuilding once with a fresh quote before dead-lettering
+    /// it; a degraded TCB is dead-lettered without a rebuild. `build` is passed the attempt
+    /// number, starting at 1.
+    pub async fn run<F, Fut>(
+        &self,
+        challenge: &ObservedChallenge,
+        mut build: F,
+    ) -> Result<PreflightOutcome, PhalaAvsError>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<Bytes, PhalaAvsError>>,
+    {
+        let mut diagnoses = Vec::new();
+        let mut response = Bytes::new();
+        for attempt in 1..=2 {
+            response = build(attempt).await?;
+            match self.verify(challenge, &response, now_unix()).await? {
+                Ok(report) => {
+                    return Ok(PreflightOutcome::Verified {
+                        response,
+                        report,
+                        attempts: attempt,
+                    });
+                }
+                // A rebuilt quote has the same TCB.
+                Err(diagnosis @ Diagnosis::DegradedTcb { .. }) => {
+                    diagnoses.push(diagnosis);
+                    break;
+                }
+                Err(diagnosis) => diagnoses.push(diagnosis),
+            }
+        }
+
+        let letter = DeadLetter {
+            challenge: challenge.clone(),
+            response,
+            diagnoses,
+            dead_lettered_unix: now_unix(),
+        };
+        self.store.put_json(
+            DEAD_LETTER_NAMESPACE,
+            &challenge.challenge_id.to_be_bytes::<32>(),
+            &letter,
+        )?;
+        METRICS.inc_counter(PREFLIGHT_DEAD_LETTERS_METRIC, &[], 1);
+        error!(
+            "Dead-lettered the attestation response to challenge {} after {} build(s)",
+            challenge.challenge_id,
+            letter.diagnoses.len()
+        );
+        Ok(PreflightOutcome::DeadLettered(letter))
+    }
+
+    /// The last response that passed verification.
+    pub fn known_good(&self) -> Result<Option<KnownGood>, PhalaAvsError> {
+        self.store.get_json(FIXTURE_NAMESPACE, FIXTURE_KEY)
+    }
+
+    pub fn dead_letters(&self) -> Result<Vec<DeadLetter>, PhalaAvsError> {
+        self.store
+            .scan(DEAD_LETTER_NAMESPACE)?
+            .into_iter()
+            .map(|(_, value)| {
+                serde_json::from_slice(&value)
+                    .map_err(|e| PhalaAvsError::StorageError(format!("Invalid dead letter: {e}")))
+            })
+            .collect()
+    }
+
+    /// Re-verifies the known-good fixture, at the time it passed, against the current policy.
+    pub async fn self_check(&self) -> Result<SelfCheck, PhalaAvsError> {
+        let Some(fixture) = self.known_good()? else {
+            return Ok(SelfCheck::NoFixture);
+        };
+        let policy = self.source.fetch().await?;
+        let result = match verify(
+            &policy,
+            &fixture.challenge,
+            &fixture.response,
+            fixture.verified_at_unix,
+        ) {
+            Ok(_) => SelfCheck::Passed,
+            Err(diagnosis) => {
+                error!("Known-good attestation fixture no longer verifies: {diagnosis}");
+                SelfCheck::Failed(diagnosis)
+            }
+        };
+        METRICS.set_gauge(
+            PREFLIGHT_SELF_CHECK_METRIC,
+            &[],
+            if result == SelfCheck::Passed {
+                1.0
+            } else {
+                0.0
+            },
+        );
+        Ok(result)
+    }
+}
+
+/// Runs [`Preflight::self_check`] every `self_check_secs`, in the background.
+pub fn spawn_self_check(preflight: Arc<Preflight>) {
+    tokio::spawn(async move {
+        let period = Duration::from_secs(preflight.config.self_check_secs);
+        loop {
+            tokio::time::sleep(period).await;
+            match preflight.self_check().await {
+                Ok(SelfCheck::Passed) => info!("Attestation verifier self-check passed"),
+                Ok(_) => {}
+                Err(e) => warn!("Attestation verifier self-check failed to run: {e}"),
+            }
+        }
+    });
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{
+        ATTESTATION_KIND, AttestationChallengeV1, AttestationEncoderV1, AttestationEncoderV2,
+        AttestationQuote, ResponseEncoder, ResponseInputs,
+    };
+    use crate::fixtures::{ChallengeEventFixture, QuoteFixture};
+    use crate::state::MemoryStateStore;
+    use crate::tee::quote::TdxQuote;
+    use blueprint_sdk::alloy::primitives::keccak256;
+    use std::sync::Mutex;
+
+    const MR_TD: [u8; 48] = [0x5a; 48];
+    const NONCE: B256 = B256::repeat_byte(0x42);
+
+    struct FixedPolicy(Mutex<AttestationPolicy>);
+
+    impl PolicySource for FixedPolicy {
+        fn fetch(&self) -> BoxFuture<'_, Result<AttestationPolicy, PhalaAvsError>> {
+            let policy = self.0.lock().unwrap().clone();
+            Box::pin(async move { Ok(policy) })
+        }
+    }
+
+    /// A TDX-only policy.
+    fn policy() -> AttestationPolicy {
+        AttestationPolicy {
+            allowed_measurements: vec![PolicyMeasurement {
+                platform: TeePlatform::Tdx,
+                measurement: keccak256(MR_TD),
+            }],
+            max_quote_age_secs: 600,
+        }
+    }
+
+    fn challenge_version(id: u64, version: u32) -> ObservedChallenge {
+        let params = AttestationChallengeV1 { nonce: NONCE }.abi_encode_params();
+        ChallengeEventFixture::new()
+            .id(id)
+            .kind(ATTESTATION_KIND, version, params)
+            .window(99)
+            .build_observed()
+    }
+
+    fn challenge(id: u64) -> ObservedChallenge {
+        challenge_version(id, 1)
+    }
+
+    /// A response of the challenge's version, tagged with `platform` where that has a tag.
+    fn encode(
+        challenge: &ObservedChallenge,
+        platform: TeePlatform,
+        quote: Vec<u8>,
+        quoted_at_unix: u64,
+    ) -> Bytes {
+        let inputs = ResponseInputs {
+            attestation: Some(AttestationQuote {
+                platform,
+                quoted_at_unix,
+                quote: quote.into(),
+            }),
+            ..Default::default()
+        };
+        match SchemaKey::of(challenge).version {
+            1 => AttestationEncoderV1.encode(challenge, &inputs),
+            _ => AttestationEncoderV2.encode(challenge, &inputs),
+        }
+        .unwrap()
+    }
+
+    fn response(challenge: &ObservedChallenge, quote: TdxQuote, quoted_at_unix: u64) -> Bytes {
+        encode(
+            challenge,
+            TeePlatform::Tdx,
+            quote.to_bytes(),
+            quoted_at_unix,
+        )
+    }
+
+    fn quote(challenge: &ObservedChallenge, quoted_at_unix: u64) -> TdxQuote {
+        TdxQuote {
+            version: 4,
+            tee_type: TDX_TEE_TYPE,
+            mr_td: MR_TD,
+            rtmrs: [[0; 48]; 4],
+            report_data: attestation_report_data(
+                challenge.challenge_id,
+                NONCE,
+                quoted_at_unix,
+                BuildInfo::current().build_hash,
+            ),
+        }
+    }
+
+    /// A fixture quote bound to `challenge` at `quoted_at_unix`.
+    fn bound(
+        fixture: QuoteFixture,
+        challenge: &ObservedChallenge,
+        quoted_at_unix: u64,
+    ) -> QuoteFixture {
+        fixture.report_data(attestation_report_data(
+            challenge.challenge_id,
+            NONCE,
+            quoted_at_unix,
+            BuildInfo::current().build_hash,
+        ))
+    }
+
+    #[test]
+    fn each_failed_check_is_diagnosed() {
+        let policy = policy();
+        let c = challenge(7);
+        let now = 10_000;
+        let good = response(&c, quote(&c, now - 30), now - 30);
+        let report = verify(&policy, &c, &good, now).unwrap();
+        assert_eq!(report.platform, TeePlatform::Tdx);
+        assert_eq!(report.measurement, keccak256(MR_TD));
+
+        let check = |response: &[u8]| verify(&policy, &c, response, now).unwrap_err();
+        assert!(matches!(check(b"garbage"), Diagnosis::Malformed { .. }));
+        assert_eq!(
+            check(&response(&challenge(8), quote(&c, now), now)),
+            Diagnosis::WrongChallenge {
+                expected: U256::from(7),
+                actual: U256::from(8)
+            }
+        );
+
+        let truncated = AttestationResponseV1 {
+            challengeId: c.challenge_id,
+            quotedAtUnix: now,
+            quote: Bytes::from_static(&[4, 0, 0, 0]),
+        }
+        .abi_encode();
+        assert!(matches!(check(&truncated), Diagnosis::InvalidQuote { .. }));
+
+        let sgx = TdxQuote {
+            tee_type: 0,
+            ..quote(&c, now)
+        };
+        assert_eq!(check(&response(&c, sgx, now)), Diagnosis::WrongTeeType {
+            expected: TDX_TEE_TYPE,
+            actual: 0
+        });
+
+        let unknown = TdxQuote {
+            mr_td: [0; 48],
+            ..quote(&c, now)
+        };
+        assert_eq!(check(&response(&c, unknown, now)).check(), "measurement");
+
+        // A version 3 header is parsed as SGX, which this policy does not allow.
+        let sgx = bound(QuoteFixture::sgx(), &c, now).build();
+        assert_eq!(
+            check(&encode(&c, TeePlatform::Sgx, sgx, now)).check(),
+            "platform"
+        );
+
+        // A quote over another time than the one claimed.
+        let rebound = check(&response(&c, quote(&c, now - 1), now));
+        assert_eq!(rebound.check(), "report_data");
+        assert!(rebound.to_string().contains("expected report data"));
+        // A quote by another build.
+        let mut unbuilt = quote(&c, now);
+        unbuilt.report_data[32..].fill(0);
+        assert_eq!(check(&response(&c, unbuilt, now)).check(), "report_data");
+
+        assert_eq!(
+            check(&response(&c, quote(&c, now - 601), now - 601)),
+            Diagnosis::Stale {
+                quoted_at_unix: now - 601,
+                now_unix: now,
+                max_age_secs: 600
+            }
+        );
+        assert_eq!(
+            check(&response(&c, quote(&c, now + 1), now + 1)).check(),
+            "freshness"
+        );
+    }
+
+    #[test]
+    fn each_platform_verifies_against_its_own_policy_entries() {
+        let now = 10_000;
+        let c = challenge_version(3, 2);
+        let tdx = bound(QuoteFixture::tdx(), &c, now);
+        let sgx = bound(QuoteFixture::sgx(), &c, now);
+        let both = AttestationPolicy {
+            allowed_measurements: [&tdx, &sgx]
+                .map(|q| PolicyMeasurement {
+                    platform: q.platform(),
+                    measurement: q.measurement(),
+                })
+                .to_vec(),
+            max_quote_age_secs: 600,
+        };
+
+        for quote in [&tdx, &sgx] {
+            let response = encode(&c, quote.platform(), quote.build(), now);
+            let report = verify(&both, &c, &response, now).unwrap();
+            assert_eq!(report.platform, quote.platform());
+            assert_eq!(report.measurement, quote.measurement());
+        }
+
+        // An SGX quote against a TDX-only policy is rejected for its platform, not its
+        // measurement.
+        let tdx_only = AttestationPolicy {
+            allowed_measurements: both.allowed_measurements[..1].to_vec(),
+            ..both.clone()
+        };
+        let rejected = verify(
+            &tdx_only,
+            &c,
+            &encode(&c, TeePlatform::Sgx, sgx.build(), now),
+            now,
+        )
+        .unwrap_err();
+        assert_eq!(rejected, Diagnosis::PlatformNotAllowed {
+            platform: TeePlatform::Sgx,
+            allowed: vec![TeePlatform::Tdx],
+        });
+        assert_eq!(
+            rejected.to_string(),
+            "platform check failed: sgx quotes are not accepted, the policy allows [tdx]"
+        );
+
+        // Measurements are allow-listed per platform, and the SGX one pins the SVN.
+        let downgraded = sgx.clone().isv_svn(1);
+        let rejected = verify(
+            &both,
+            &c,
+            &encode(&c, TeePlatform::Sgx, downgraded.build(), now),
+            now,
+        )
+        .unwrap_err();
+        assert!(matches!(rejected, Diagnosis::MeasurementNotAllowed {
+            platform: TeePlatform::Sgx,
+            ..
+        }));
+
+        // The version 2 tag has to match the quote.
+        assert_eq!(
+            verify(
+                &both,
+                &c,
+                &encode(&c, TeePlatform::Tdx, sgx.build(), now),
+                now
+            ),
+            Err(Diagnosis::PlatformMismatch {
+                tagged: TeePlatform::Tdx.code(),
+                quoted: TeePlatform::Sgx,
+            })
+        );
+        // A version 1 response does not decode as version 2.
+        let untagged = encode(&challenge_version(3, 1), TeePlatform::Tdx, tdx.build(), now);
+        assert_eq!(
+            verify(&both, &c, &untagged, now).unwrap_err().check(),
+            "decode"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_responses_are_rebuilt_once_then_dead_lettered() {
+        let source = Arc::new(FixedPolicy(Mutex::new(policy())));
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let preflight = Preflight::new(
+            PreflightConfig {
+                self_check_secs: 60,
+            },
+            Arc::clone(&source) as Arc<dyn PolicySource>,
+            store,
+        );
+        assert_eq!(preflight.self_check().await.unwrap(), SelfCheck::NoFixture);
+
+        // A stale cached quote, replaced by a fresh one on rebuild.
+        let c = challenge(1);
+        let outcome = preflight
+            .run(&c, |attempt| {
+                let quoted_at = now_unix() - if attempt == 1 { 3_600 } else { 0 };
+                let response = response(&c, quote(&c, quoted_at), quoted_at);
+                async move { Ok(response) }
+            })
+            .await
+            .unwrap();
+        assert!(matches!(outcome, PreflightOutcome::Verified {
+            attempts: 2,
+            ..
+        }));
+        assert_eq!(preflight.self_check().await.unwrap(), SelfCheck::Passed);
+
+        // A measurement the oracle does not accept is not fixed by a fresh quote.
+        let c = challenge(2);
+        let mut builds = 0;
+        let outcome = preflight
+            .run(&c, |_| {
+                builds += 1;
+                let now = now_unix();
+                let quote = TdxQuote {
+                    mr_td: [1; 48],
+                    ..quote(&c, now)
+                };
+                let response = response(&c, quote, now);
+                async move { Ok(response) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(builds, 2);
+        let PreflightOutcome::DeadLettered(letter) = outcome else {
+            panic!("expected a dead letter, got {outcome:?}");
+        };
+        assert_eq!(letter.diagnoses.len(), 2);
+        assert!(letter.diagnoses.iter().all(|d| d.check() == "measurement"));
+        let alert = letter.alert();
+        assert_eq!(alert.severity, Severity::Critical);
+        assert!(alert.message.contains("challenge 2 after 2 build(s)"));
+        let stored = preflight.dead_letters().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].challenge.challenge_id, U256::from(2));
+
+        // Changing the on-chain policy is picked up by the self-check.
+        source.0.lock().unwrap().allowed_measurements[0].measurement = B256::ZERO;
+        assert!(matches!(
+            preflight.self_check().await.unwrap(),
+            SelfCheck::Failed(Diagnosis::MeasurementNotAllowed { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn degraded_tcb_responses_are_held_back_without_a_rebuild() {
+        use crate::notify::LogNotifier;
+        use crate::tee::collateral::{
+            Collateral, CollateralConfig, CollateralOrigin, CollateralSource, PlatformTcb,
+        };
+        use std::path::PathBuf;
+
+        struct Fixed(Collateral);
+
+        impl CollateralSource for Fixed {
+            fn fetch(
+                &self,
+                _platform: TeePlatform,
+            ) -> BoxFuture<'_, Result<Collateral, PhalaAvsError>> {
+                let collateral = self.0.clone();
+                Box::pin(async move { Ok(collateral) })
+            }
+        }
+
+        let fixture = |name: &str| {
+            std::fs::read_to_string(
+                PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("tests/fixtures/collateral")
+                    .join(name),
+            )
+            .unwrap()
+        };
+        // After the recovery, the platform's level is SWHardeningNeeded.
+        let collateral = Collateral::from_json(
+            &fixture("tdx_tcb_info_recovery.json"),
+            &fixture("tdx_qe_identity.json"),
+            CollateralOrigin::Pccs,
+        )
+        .unwrap();
+        let (mut cpu_svn, mut tee_tcb_svn) = ([0; 16], [0; 16]);
+        cpu_svn[..8].copy_from_slice(&[2, 2, 2, 2, 3, 1, 0, 3]);
+        tee_tcb_svn[..3].copy_from_slice(&[5, 0, 2]);
+        let monitor = Arc::new(CollateralMonitor::new(
+            CollateralConfig {
+                enabled: true,
+                pccs_url: None,
+                pcs_url: String::new(),
+                fmspc: "90c06f000000".to_string(),
+                check_secs: 3_600,
+                quote_ttl_secs: 3_600,
+                platform_tcb: Some(PlatformTcb {
+                    cpu_svn,
+                    pce_svn: 13,
+                    tee_tcb_svn: Some(tee_tcb_svn),
+                }),
+            },
+            TeePlatform::Tdx,
+            Arc::new(Fixed(collateral)),
+            Arc::new(LogNotifier),
+        ));
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let preflight = Preflight::new(
+            PreflightConfig {
+                self_check_secs: 60,
+            },
+            Arc::new(FixedPolicy(Mutex::new(policy()))),
+            store,
+        )
+        .with_collateral(Arc::clone(&monitor));
+
+        // Before the collateral is fetched, nothing is known to be wrong.
+        let c = challenge(1);
+        let build = |c: &ObservedChallenge| {
+            let now = now_unix();
+            response(c, quote(c, now), now)
+        };
+        let response_1 = build(&c);
+        let outcome = preflight.run(&c, |_| async { Ok(response_1.clone()) });
+        assert!(matches!(
+            outcome.await.unwrap(),
+            PreflightOutcome::Verified { .. }
+        ));
+
+        monitor.refresh(now_unix()).await.unwrap();
+        let c = challenge(2);
+        let mut builds = 0;
+        let outcome = preflight
+            .run(&c, |_| {
+                builds += 1;
+                let response = build(&c);
+                async move { Ok(response) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(builds, 1);
+        let PreflightOutcome::DeadLettered(letter) = outcome else {
+            panic!("expected a dead letter, got {outcome:?}");
+        };
+        assert_eq!(letter.diagnoses, [Diagnosis::DegradedTcb {
+            platform: TeePlatform::Tdx,
+            status: TcbStatus::SwHardeningNeeded,
+            advisory_ids: vec!["INTEL-SA-01036".to_string(), "INTEL-SA-01079".to_string()],
+        }]);
+        assert_eq!(letter.diagnoses[0].check(), "tcb");
+        assert!(letter.diagnoses[0].to_string().contains("INTEL-SA-01079"));
+        // The oracle-side checks still pass: the self-check does not flag the fixture.
+        assert_eq!(preflight.self_check().await.unwrap(), SelfCheck::Passed);
+    }
+}
@@ -0,0 +1,870 @@
+//! Verification of what the contracts made of our own included transactions.
+//!
+//! A transaction that succeeded is not necessarily one that achieved anything: the oracle turns
+//! a bad response away with `SlaResponseRejected` instead of reverting, so the challenge stays
+//! open and expires unless answered again. Every `RECEIPT_VERIFY_CHECK_SECS`, the logs in the
+//! receipt of each succeeded [`Intent`] not verified yet are decoded against the oracle and
+//! service manager ABIs. Events are attributed by their indexed `challengeId`, so a receipt
+//! answering several challenges settles each on its own:
+//!
+//! - `SlaChallengeResponded` moves the challenge from `AwaitingInclusion` to `Responded`;
+//! - `SlaResponseRejected` moves it to `RejectedByOracle`, raises a critical alert with the
+//!   reason translated by [`rejection_message`], files a [`DisputeBundle`] with the
+//!   [self-audit](crate::self_audit) and marks the intent's spend as wasted.
+//!
+//! A response whose receipt carries neither event is marked wasted too, with a warning.
+//!
+//! With a [`CostLedger`], the gas of every verified intent is recorded, as are the
+//! `ReimbursementCredited` and `ReimbursementClaimed` events of the service manager for us.
+
+use crate::IPhalaServiceManager;
+use crate::IPhalaSlaOracle::{
+    AttestationPolicyUpdated, ResponseSchemaPublished, SlaChallengeAmended, SlaChallengeCancelled,
+    SlaChallengeExpired, SlaChallengeIssued, SlaChallengeResponded, SlaResponseRejected,
+};
+use crate::challenge::{ChallengeState, ChallengeTracker};
+use crate::config::env_or;
+use crate::costs::{CostEntry, CostKind, CostLedger};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::self_audit::{DisputeBundle, SelfAuditor};
+use crate::sender::{Intent, TxSender};
+use blueprint_sdk::alloy::primitives::{Address, B256, U256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::Log;
+use blueprint_sdk::alloy::sol_types::SolEvent;
+use blueprint_sdk::evm::util::get_provider_http;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Counter of challenge responses settled from receipt logs, by verdict (`accepted`,
+/// `rejected`, `missing`).
+pub const SUBMISSION_VERDICTS_METRIC: &str = "phala_avs_submission_verdicts_total";
+
+const SOURCE: &str = "receipts";
+
+/// What each `SlaResponseRejected` reason code means, as documented on the event.
+pub const REJECTION_REASONS: [(u8, &str); 5] = [
+    (1, "the response was empty"),
+    (
+        2,
+        "the response does not match the published schema of its kind",
+    ),
+    (3, "the attestation quote is older than the policy allows"),
+    (
+        4,
+        "the attested measurement is not allowed by the attestation policy",
+    ),
+    (5, "the response signature is invalid"),
+];
+
+/// A human-readable explanation of rejection reason `code`.
+pub fn rejection_message(code: u8) -> String {
+    REJECTION_REASONS
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or_else(
+            || format!("unknown reason code {code}"),
+            |(_, message)| message.to_string(),
+        )
+}
+
+/// The oracle's rejection of one of our responses.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OracleRejection {
+    /// The transaction carrying the response.
+    pub tx_hash: B256,
+    pub reason_code: u8,
+    /// [`rejection_message`] of the code.
+    pub reason: String,
+}
+
+/// Signatures of the events the oracle and service manager may emit in our receipts, besides
+/// the verdicts on responses.
+const KNOWN_EVENTS: [(B256, &str); 12] = [
+    (
+        SlaChallengeIssued::SIGNATURE_HASH,
+        SlaChallengeIssued::SIGNATURE,
+    ),
+    (
+        SlaChallengeExpired::SIGNATURE_HASH,
+        SlaChallengeExpired::SIGNATURE,
+    ),
+    (
+        SlaChallengeCancelled::SIGNATURE_HASH,
+        SlaChallengeCancelled::SIGNATURE,
+    ),
+    (
+        SlaChallengeAmended::SIGNATURE_HASH,
+        SlaChallengeAmended::SIGNATURE,
+    ),
+    (
+        ResponseSchemaPublished::SIGNATURE_HASH,
+        ResponseSchemaPublished::SIGNATURE,
+    ),
+    (
+        AttestationPolicyUpdated::SIGNATURE_HASH,
+        AttestationPolicyUpdated::SIGNATURE,
+    ),
+    (
+        IPhalaServiceManager::MaintenanceWindowRegistered::SIGNATURE_HASH,
+        IPhalaServiceManager::MaintenanceWindowRegistered::SIGNATURE,
+    ),
+    (
+        IPhalaServiceManager::MaintenanceWindowCancelled::SIGNATURE_HASH,
+        IPhalaServiceManager::MaintenanceWindowCancelled::SIGNATURE,
+    ),
+    (
+        IPhalaServiceManager::EvidenceRootAnchored::SIGNATURE_HASH,
+        IPhalaServiceManager::EvidenceRootAnchored::SIGNATURE,
+    ),
+    (
+        IPhalaServiceManager::CapacityUpdated::SIGNATURE_HASH,
+        IPhalaServiceManager::CapacityUpdated::SIGNATURE,
+    ),
+    (
+        IPhalaServiceManager::OperatorExitRequested::SIGNATURE_HASH,
+        IPhalaServiceManager::OperatorExitRequested::SIGNATURE,
+    ),
+    (
+        IPhalaServiceManager::MaintenanceSignerSet::SIGNATURE_HASH,
+        IPhalaServiceManager::MaintenanceSignerSet::SIGNATURE,
+    ),
+];
+
+/// A log of one of our receipts, decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReceiptEvent {
+    /// The oracle accepted our response to the challenge.
+    Accepted { challenge_id: U256 },
+    /// The oracle rejected our response to the challenge.
+    Rejected { challenge_id: U256, reason_code: u8 },
+    /// The service manager credited a reimbursement for our accepted response.
+    Credited { challenge_id: U256, amount: u128 },
+    /// The service manager paid out our credited reimbursement.
+    Claimed { amount: u128 },
+    /// Another event of the oracle or service manager.
+    Other { signature: &'static str },
+    /// A log none of our contracts' ABIs decode, or a verdict on another operator's response.
+    Unknown { address: Address },
+}
+
+/// Decodes the logs of one of `operator`'s receipts, in order.
+pub fn decode_receipt(
+    logs: &[Log],
+    oracle: Address,
+    service_manager: Address,
+    operator: Address,
+) -> Vec<ReceiptEvent> {
+    logs.iter()
+        .map(|log| {
+            let address = log.address();
+            if address == oracle {
+                if let Ok(event) = log.log_decode::<SlaChallengeResponded>() {
+                    let event = event.inner.data;
+                    if event.operator == operator {
+                        return ReceiptEvent::Accepted {
+                            challenge_id: event.challengeId,
+                        };
+                    }
+                    return ReceiptEvent::Unknown { address };
+                }
+                if let Ok(event) = log.log_decode::<SlaResponseRejected>() {
+                    let event = event.inner.data;
+                    if event.operator == operator {
+                        return ReceiptEvent::Rejected {
+                            challenge_id: event.challengeId,
+                            reason_code: event.reasonCode,
+                        };
+                    }
+                    return ReceiptEvent::Unknown { address };
+                }
+            }
+            if address == service_manager {
+                if let Ok(event) = log.log_decode::<IPhalaServiceManager::ReimbursementCredited>() {
+                    let event = event.inner.data;
+                    if event.operator == operator {
+                        return ReceiptEvent::Credited {
+                            challenge_id: event.challengeId,
+                            amount: event.amount.saturating_to(),
+                        };
+                    }
+                    return ReceiptEvent::Unknown { address };
+                }
+                if let Ok(event) = log.log_decode::<IPhalaServiceManager::ReimbursementClaimed>() {
+                    let event = event.inner.data;
+                    if event.operator == operator {
+                        return ReceiptEvent::Claimed {
+                            amount: event.amount.saturating_to(),
+                        };
+                    }
+                    return ReceiptEvent::Unknown { address };
+                }
+            }
+            if address != oracle && address != service_manager {
+                return ReceiptEvent::Unknown { address };
+            }
+            log.topic0()
+                .and_then(|topic| KNOWN_EVENTS.iter().find(|(hash, _)| hash == topic))
+                .map_or(ReceiptEvent::Unknown { address }, |(_, signature)| {
+                    ReceiptEvent::Other {
+                        signature: *signature,
+                    }
+                })
+        })
+        .collect()
+}
+
+/// Reads the logs of included transactions.
+pub trait ReceiptLogs: Send + Sync {
+    /// The logs in `tx_hash`'s receipt; `None` if the node has no receipt for it.
+    fn receipt_logs(&self, tx_hash: B256)
+    -> BoxFuture<'_, Result<Option<Vec<Log>>, PhalaAvsError>>;
+}
+
+/// [`ReceiptLogs`] read with `eth_getTransactionReceipt`.
+#[derive(Clone, Debug)]
+pub struct ProviderReceiptLogs {
+    rpc_url: String,
+}
+
+impl ProviderReceiptLogs {
+    pub fn new(rpc_url: String) -> Self {
+        Self { rpc_url }
+    }
+}
+
+impl ReceiptLogs for ProviderReceiptLogs {
+    fn receipt_logs(
+        &self,
+        tx_hash: B256,
+    ) -> BoxFuture<'_, Result<Option<Vec<Log>>, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            let receipt = provider
+                .get_transaction_receipt(tx_hash)
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::EvmError(format!("eth_getTransactionReceipt failed: {e}"))
+                })?;
+            Ok(receipt.map(|r| r.inner.logs().to_vec()))
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReceiptVerifierConfig {
+    /// How often receipts of newly included transactions are verified.
+    pub check_secs: u64,
+}
+
+impl Default for ReceiptVerifierConfig {
+    fn default() -> Self {
+        Self { check_secs: 12 }
+    }
+}
+
+impl ReceiptVerifierConfig {
+    /// Reads `RECEIPT_VERIFY_CHECK_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            check_secs: env_or("RECEIPT_VERIFY_CHECK_SECS", Self::default().check_secs)?,
+        })
+    }
+}
+
+/// Settles our included submissions from their receipt logs.
+pub struct SubmissionVerifier {
+    config: ReceiptVerifierConfig,
+    operator: Address,
+    oracle: Address,
+    service_manager: Address,
+    receipts: Arc<dyn ReceiptLogs>,
+    tracker: Arc<ChallengeTracker>,
+    sender: Arc<TxSender>,
+    self_audit: Option<Arc<SelfAuditor>>,
+    costs: Option<Arc<CostLedger>>,
+}
+
+impl SubmissionVerifier {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: ReceiptVerifierConfig,
+        operator: Address,
+        oracle: Address,
+        service_manager: Address,
+        receipts: Arc<dyn ReceiptLogs>,
+        tracker: Arc<ChallengeTracker>,
+        sender: Arc<TxSender>,
+    ) -> Self {
+        Self {
+            config,
+            operator,
+            oracle,
+            service_manager,
+            receipts,
+            tracker,
+            sender,
+            self_audit: None,
+            costs: None,
+        }
+    }
+
+    /// Files a dispute bundle with `self_audit` for each rejected response.
+    pub fn with_self_audit(mut self, self_audit: Option<Arc<SelfAuditor>>) -> Self {
+        self.self_audit = self_audit;
+        self
+    }
+
+    /// Records gas spend and reimbursement events in `costs`.
+    pub fn with_costs(mut self, costs: Arc<CostLedger>) -> Self {
+        self.costs = Some(costs);
+        self
+    }
+
+    pub fn config(&self) -> &ReceiptVerifierConfig {
+        &self.config
+    }
+
+    /// Verifies the receipt of every succeeded intent not verified yet, returning the alerts
+    /// to raise.
+    pub async fn sweep(&self, now_ms: u64) -> Result<Vec<Alert>, PhalaAvsError> {
+        let mut alerts = Vec::new();
+        for intent in self.sender.unverified()? {
+            match self.verify(&intent, now_ms).await {
+                Ok(raised) => alerts.extend(raised),
+                Err(e) => warn!(
+                    "Failed to verify the receipt of {} at nonce {}: {e}",
+                    intent.call.label, intent.nonce
+                ),
+            }
+        }
+        Ok(alerts)
+    }
+
+    /// Decodes the receipt logs of `intent`, settles the challenges they answer and records
+    /// the verification with the sender. Leaves the intent unverified while the node has no
+    /// receipt for it.
+    pub async fn verify(&self, intent: &Intent, now_ms: u64) -> Result<Vec<Alert>, PhalaAvsError> {
+        let Some(tx_hash) = intent.succeeded() else {
+            return Ok(Vec::new());
+        };
+        let Some(logs) = self.receipts.receipt_logs(tx_hash).await? else {
+            return Ok(Vec::new());
+        };
+        let events = decode_receipt(&logs, self.oracle, self.service_manager, self.operator);
+        let mut alerts = Vec::new();
+        let mut wasted = Vec::new();
+        for event in &events {
+            match *event {
+                ReceiptEvent::Accepted { challenge_id } => {
+                    METRICS.inc_counter(SUBMISSION_VERDICTS_METRIC, &[("verdict", "accepted")], 1);
+                    self.settle(
+                        challenge_id,
+                        ChallengeState::Responded,
+                        "accepted by the oracle",
+                    )?;
+                }
+                ReceiptEvent::Rejected {
+                    challenge_id,
+                    reason_code,
+                } => {
+                    METRICS.inc_counter(SUBMISSION_VERDICTS_METRIC, &[("verdict", "rejected")], 1);
+                    let reason = rejection_message(reason_code);
+                    self.settle(
+                        challenge_id,
+                        ChallengeState::RejectedByOracle,
+                        format!("rejected by the oracle: {reason}"),
+                    )?;
+                    let rejection = OracleRejection {
+                        tx_hash,
+                        reason_code,
+                        reason: reason.clone(),
+                    };
+                    if let Err(e) = self.file_dispute(challenge_id, rejection, now_ms) {
+                        warn!("Failed to file a dispute bundle for challenge {challenge_id}: {e}");
+                    }
+                    alerts.push(Alert::new(
+                        SOURCE,
+                        Severity::Critical,
+                        format!(
+                            "Oracle rejected our response to challenge {challenge_id} in \
+                             {tx_hash}: {reason} (code {reason_code})"
+                        ),
+                    ));
+                    wasted.push(format!("challenge {challenge_id} rejected: {reason}"));
+                }
+                ReceiptEvent::Credited {
+                    challenge_id,
+                    amount,
+                } => self.record_cost(CostEntry {
+                    unix_ms: now_ms,
+                    kind: CostKind::Credit,
+                    wei: amount,
+                    challenge_id: Some(challenge_id),
+                    tx_hash: Some(tx_hash),
+                }),
+                ReceiptEvent::Claimed { amount } => self.record_cost(CostEntry {
+                    unix_ms: now_ms,
+                    kind: CostKind::Claim,
+                    wei: amount,
+                    challenge_id: None,
+                    tx_hash: Some(tx_hash),
+                }),
+                ReceiptEvent::Other { .. } | ReceiptEvent::Unknown { .. } => {}
+            }
+        }
+        self.record_cost(CostEntry {
+            unix_ms: now_ms,
+            kind: CostKind::Gas,
+            wei: intent.max_cost_wei(),
+            challenge_id: None,
+            tx_hash: Some(tx_hash),
+        });
+        if let Some(challenge_id) = intent.call.challenge_id {
+            let settled = events.iter().any(|event| match event {
+                ReceiptEvent::Accepted { challenge_id: id }
+                | ReceiptEvent::Rejected {
+                    challenge_id: id, ..
+                } => *id == challenge_id,
+                _ => false,
+            });
+            if !settled {
+                METRICS.inc_counter(SUBMISSION_VERDICTS_METRIC, &[("verdict", "missing")], 1);
+                let alert = Alert::new(
+                    SOURCE,
+                    Severity::Warning,
+                    format!(
+                        "Our response to challenge {challenge_id} in {tx_hash} drew no verdict \
+                         from the oracle"
+                    ),
+                );
+                alerts.push(match self.tracker.remaining_window(&challenge_id) {
+                    Some(blocks) => alert.with_remaining_window(blocks),
+                    None => alert,
+                });
+                wasted.push(format!("no verdict on challenge {challenge_id}"));
+            }
+        }
+        let wasted = (!wasted.is_empty()).then(|| wasted.join("; "));
+        self.sender.record_verification(tx_hash, wasted, now_ms)?;
+        Ok(alerts)
+    }
+
+    fn record_cost(&self, entry: CostEntry) {
+        if let Some(costs) = &self.costs {
+            if let Err(e) = costs.record(entry) {
+                warn!("Failed to record a cost ledger entry: {e}");
+            }
+        }
+    }
+
+    /// Moves `challenge_id` out of `AwaitingInclusion`; challenges already settled, or not
+    /// tracked, are left alone.
+    fn settle(
+        &self,
+        challenge_id: U256,
+        to: ChallengeState,
+        cause: impl Into<String>,
+    ) -> Result<(), PhalaAvsError> {
+        match self.tracker.get(&challenge_id) {
+            Some(tracked) if tracked.state == ChallengeState::AwaitingInclusion => {
+                self.tracker.transition(challenge_id, to, cause)?;
+            }
+            Some(tracked) if tracked.state != to => warn!(
+                "Receipt verdict {to} on challenge {challenge_id}, which is {}",
+                tracked.state
+            ),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn file_dispute(
+        &self,
+        challenge_id: U256,
+        rejection: OracleRejection,
+        now_ms: u64,
+    ) -> Result<Option<DisputeBundle>, PhalaAvsError> {
+        let (Some(self_audit), Some(tracked)) = (&self.self_audit, self.tracker.get(&challenge_id))
+        else {
+            return Ok(None);
+        };
+        self_audit
+            .file_rejection(&tracked, rejection, now_ms)
+            .map(Some)
+    }
+}
+
+/// Verifies receipts every `RECEIPT_VERIFY_CHECK_SECS`, delivering the alerts raised.
+pub fn spawn_verifier(verifier: Arc<SubmissionVerifier>, notifier: Arc<dyn Notifier>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(verifier.config.check_secs));
+        loop {
+            interval.tick().await;
+            match verifier.sweep(crate::evidence::now_unix_ms()).await {
+                Ok(alerts) => {
+                    for alert in alerts {
+                        if let Err(e) = notifier.notify(alert).await {
+                            warn!("Failed to deliver receipt verification alert: {e}");
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to verify submission receipts: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IPhalaSlaOracle::respondToSlaChallengeCall;
+    use crate::challenge::ConfirmationPolicy;
+    use crate::costs::ReimbursementTotals;
+    use crate::fees::Fees;
+    use crate::fixtures::{
+        ChallengeEventFixture, ChallengeUpdateFixture, OPERATOR, ORACLE, ReimbursementEventFixture,
+        RejectionEventFixture, ResponseEventFixture,
+    };
+    use crate::lanes::{AccountSource, LaneConfig, SignerLanes, TxClass};
+    use crate::multicall::MULTICALL3_ADDRESS;
+    use crate::self_audit::{OracleChallenge, OracleLedger, SelfAuditConfig};
+    use crate::sender::{SignedTx, TxCall, TxChain, TxOutcome, TxSenderConfig};
+    use crate::state::{MemoryStateStore, StateStore};
+    use blueprint_sdk::alloy::primitives::Bytes;
+    use blueprint_sdk::alloy::sol_types::SolCall;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::Mutex;
+
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const SERVICE_MANAGER: Address = Address::repeat_byte(0x5e);
+
+    /// Reimbursement the simulated service manager credits per accepted response.
+    const CREDIT_WEI: u128 = 30_000_000;
+
+    /// An oracle that includes every transaction as it is broadcast and, like `PhalaSlaOracle`,
+    /// rejects empty responses with `SlaResponseRejected` instead of reverting. Accepted
+    /// responses are credited a reimbursement.
+    #[derive(Default)]
+    struct SimulatedOracle {
+        /// The call last estimated, which the next broadcasts carry.
+        estimated: Mutex<Option<TxCall>>,
+        included: Mutex<HashMap<B256, Vec<Log>>>,
+        nonce: Mutex<u64>,
+    }
+
+    impl SimulatedOracle {
+        fn execute(&self, call: &TxCall) -> Vec<Log> {
+            let call = respondToSlaChallengeCall::abi_decode(&call.input, true).unwrap();
+            let id = call.challengeId.saturating_to();
+            if call.responseData.is_empty() {
+                return vec![RejectionEventFixture::new(id).reason_code(1).build_log()];
+            }
+            vec![
+                ResponseEventFixture::new()
+                    .id(id)
+                    .response(call.responseData)
+                    .build_log(),
+                ReimbursementEventFixture::credited(id, CREDIT_WEI)
+                    .service_manager(SERVICE_MANAGER)
+                    .build_log(),
+            ]
+        }
+    }
+
+    impl TxChain for SimulatedOracle {
+        fn estimate_gas(
+            &self,
+            _: Address,
+            call: TxCall,
+        ) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            *self.estimated.lock().unwrap() = Some(call);
+            Box::pin(async { Ok(100_000) })
+        }
+
+        fn fees(&self) -> BoxFuture<'_, Result<Fees, PhalaAvsError>> {
+            Box::pin(async { Ok(Fees::Legacy { gas_price: 1_000 }) })
+        }
+
+        fn broadcast(&self, tx: SignedTx) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            let call = self.estimated.lock().unwrap().clone().unwrap();
+            let logs = self.execute(&call);
+            self.included.lock().unwrap().insert(tx.hash, logs);
+            *self.nonce.lock().unwrap() += 1;
+            Box::pin(async { Ok(()) })
+        }
+
+        fn receipt(
+            &self,
+            tx_hash: B256,
+        ) -> BoxFuture<'_, Result<Option<TxOutcome>, PhalaAvsError>> {
+            let included = self.included.lock().unwrap().contains_key(&tx_hash);
+            Box::pin(async move {
+                Ok(included.then_some(TxOutcome::Included {
+                    tx_hash,
+                    block: 1,
+                    success: true,
+                }))
+            })
+        }
+
+        fn included_nonce(&self, _: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            let nonce = *self.nonce.lock().unwrap();
+            Box::pin(async move { Ok(nonce) })
+        }
+    }
+
+    impl AccountSource for SimulatedOracle {
+        fn pending_nonce(&self, _: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            let nonce = *self.nonce.lock().unwrap();
+            Box::pin(async move { Ok(nonce) })
+        }
+
+        fn balance(&self, _: Address) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+            Box::pin(async { Ok(u128::MAX) })
+        }
+
+        fn maintenance_signer(&self, _: Address) -> BoxFuture<'_, Result<Address, PhalaAvsError>> {
+            Box::pin(async { Ok(Address::ZERO) })
+        }
+    }
+
+    impl ReceiptLogs for SimulatedOracle {
+        fn receipt_logs(
+            &self,
+            tx_hash: B256,
+        ) -> BoxFuture<'_, Result<Option<Vec<Log>>, PhalaAvsError>> {
+            let logs = self.included.lock().unwrap().get(&tx_hash).cloned();
+            Box::pin(async move { Ok(logs) })
+        }
+    }
+
+    /// The self-audit only files bundles here; its ledger is never read.
+    struct UnreadLedger;
+
+    impl OracleLedger for UnreadLedger {
+        fn challenge_count(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            unreachable!()
+        }
+
+        fn challenges(
+            &self,
+            _: Vec<u64>,
+        ) -> BoxFuture<'_, Result<Vec<OracleChallenge>, PhalaAvsError>> {
+            unreachable!()
+        }
+
+        fn response_window_blocks(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            unreachable!()
+        }
+
+        fn response_blocks(
+            &self,
+            _: Address,
+            _: u64,
+            _: u64,
+        ) -> BoxFuture<'_, Result<BTreeMap<U256, u64>, PhalaAvsError>> {
+            unreachable!()
+        }
+    }
+
+    /// Tracks challenge `id` and moves it to `AwaitingInclusion`.
+    fn submit(tracker: &ChallengeTracker, id: u64) {
+        let challenge = ChallengeEventFixture::new().id(id).build_observed();
+        tracker.observe(challenge, 1).unwrap();
+        for state in [
+            ChallengeState::Queued,
+            ChallengeState::Building,
+            ChallengeState::Submitting,
+            ChallengeState::AwaitingInclusion,
+        ] {
+            tracker.transition(U256::from(id), state, "test").unwrap();
+        }
+    }
+
+    fn respond(id: u64, response: &[u8]) -> TxCall {
+        let input = respondToSlaChallengeCall {
+            challengeId: U256::from(id),
+            responseData: Bytes::copy_from_slice(response),
+        }
+        .abi_encode();
+        TxCall::new("respondToSlaChallenge", ORACLE, input).responding_to(U256::from(id))
+    }
+
+    #[tokio::test]
+    async fn rejected_response_is_settled_alerted_disputed_and_marked_wasted() {
+        let costs = Arc::new(CostLedger::new(Arc::new(MemoryStateStore::default())));
+        let oracle = Arc::new(SimulatedOracle::default());
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker = Arc::new(
+            ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap(),
+        );
+        let lane_config = LaneConfig {
+            maintenance_key: None,
+            fallback_to_primary: false,
+            min_balance_wei: 0,
+            check_secs: 60,
+        };
+        let lanes = SignerLanes::new(lane_config, KEY, Arc::clone(&oracle) as _).unwrap();
+        let sender = Arc::new(TxSender::new(
+            TxSenderConfig {
+                receipt_poll: Duration::from_millis(1),
+                ..TxSenderConfig::default()
+            },
+            31337,
+            Arc::new(lanes),
+            Arc::clone(&oracle) as _,
+            Arc::clone(&store),
+        ));
+        let audit_config = SelfAuditConfig {
+            enabled: true,
+            schedule: "0 0 3 * * *".to_string(),
+            epochs: 2,
+            epoch_blocks: 100,
+            batch_size: 2,
+            max_challenges: 100,
+            gate_readiness: false,
+            multicall: MULTICALL3_ADDRESS,
+        };
+        let self_audit = SelfAuditor::new(
+            audit_config,
+            OPERATOR,
+            Arc::new(UnreadLedger),
+            Arc::clone(&tracker),
+            Arc::clone(&store),
+        )
+        .unwrap();
+        let verifier = SubmissionVerifier::new(
+            ReceiptVerifierConfig::default(),
+            OPERATOR,
+            ORACLE,
+            SERVICE_MANAGER,
+            Arc::clone(&oracle) as _,
+            Arc::clone(&tracker),
+            Arc::clone(&sender),
+        )
+        .with_self_audit(Some(Arc::new(self_audit)))
+        .with_costs(Arc::clone(&costs));
+
+        // Challenge 1 is answered properly, challenge 2 with a crafted empty proof.
+        submit(&tracker, 1);
+        submit(&tracker, 2);
+        let good = sender
+            .send(TxClass::Urgent, respond(1, b"quote"))
+            .await
+            .unwrap();
+        let bad = sender.send(TxClass::Urgent, respond(2, b"")).await.unwrap();
+        let TxOutcome::Included { tx_hash: bad, .. } = bad else {
+            panic!("{bad:?}");
+        };
+        assert!(matches!(good, TxOutcome::Included { success: true, .. }));
+
+        let alerts = verifier.sweep(1_000).await.unwrap();
+        assert_eq!(
+            tracker.get(&U256::from(1)).unwrap().state,
+            ChallengeState::Responded
+        );
+        let rejected = tracker.get(&U256::from(2)).unwrap();
+        assert_eq!(rejected.state, ChallengeState::RejectedByOracle);
+
+        assert_eq!(alerts.len(), 1, "{alerts:?}");
+        assert_eq!(alerts[0].severity, Severity::Critical);
+        assert!(
+            alerts[0].message.contains("challenge 2"),
+            "{}",
+            alerts[0].message
+        );
+        assert!(
+            alerts[0]
+                .message
+                .contains("the response was empty (code 1)")
+        );
+
+        let intents = sender.intents().unwrap();
+        assert!(intents.iter().all(|i| i.verified_unix_ms == Some(1_000)));
+        let wasted: Vec<_> = intents.iter().filter_map(|i| i.wasted.as_deref()).collect();
+        assert_eq!(wasted, ["challenge 2 rejected: the response was empty"]);
+        assert_eq!(
+            intents
+                .iter()
+                .find(|i| i.wasted.is_some())
+                .unwrap()
+                .succeeded(),
+            Some(bad)
+        );
+
+        // The dispute tooling has the rejection on file.
+        let verifier_audit = verifier.self_audit.as_ref().unwrap();
+        let bundle = verifier_audit.dispute(&U256::from(2)).unwrap().unwrap();
+        let rejection = bundle.rejection.unwrap();
+        assert_eq!((rejection.tx_hash, rejection.reason_code), (bad, 1));
+        assert_eq!(bundle.tracked.state, ChallengeState::RejectedByOracle);
+
+        // Both responses spent gas; only the accepted one is reimbursed.
+        let totals = costs.totals().unwrap();
+        let spent: u128 = intents.iter().map(Intent::max_cost_wei).sum();
+        assert_eq!(totals.gas_spent_wei, spent);
+        assert_eq!(totals.reimbursement, ReimbursementTotals {
+            eligible_responses: 1,
+            credited_wei: CREDIT_WEI,
+            claimed_wei: 0,
+            clawed_back_wei: 0,
+            pending_wei: CREDIT_WEI,
+        });
+        assert_eq!(totals.net_wei, (spent - CREDIT_WEI) as i128);
+
+        // Verified receipts are not verified again.
+        assert!(verifier.sweep(2_000).await.unwrap().is_empty());
+        assert_eq!(costs.entries().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn batch_receipts_attribute_each_verdict_to_its_challenge() {
+        let logs = [
+            ResponseEventFixture::new().id(3).build_log(),
+            RejectionEventFixture::new(4)
+                .reason_code(3)
+                .log_index(1)
+                .build_log(),
+            // Another operator's rejection in the same receipt is not ours.
+            RejectionEventFixture::new(5)
+                .operator(Address::repeat_byte(9))
+                .log_index(2)
+                .build_log(),
+            ChallengeUpdateFixture::cancelled(6)
+                .log_index(3)
+                .build_log(),
+        ];
+        let mut foreign = ResponseEventFixture::new().id(7).build_log();
+        foreign.inner.address = Address::repeat_byte(0xee);
+        let mut logs = logs.to_vec();
+        logs.push(foreign);
+
+        assert_eq!(decode_receipt(&logs, ORACLE, SERVICE_MANAGER, OPERATOR), [
+            ReceiptEvent::Accepted {
+                challenge_id: U256::from(3)
+            },
+            ReceiptEvent::Rejected {
+                challenge_id: U256::from(4),
+                reason_code: 3
+            },
+            ReceiptEvent::Unknown { address: ORACLE },
+            ReceiptEvent::Other {
+                signature: SlaChallengeCancelled::SIGNATURE
+            },
+            ReceiptEvent::Unknown {
+                address: Address::repeat_byte(0xee)
+            },
+        ]);
+        assert_eq!(
+            rejection_message(3),
+            "the attestation quote is older than the policy allows"
+        );
+        assert_eq!(rejection_message(42), "unknown reason code 42");
+    }
+}
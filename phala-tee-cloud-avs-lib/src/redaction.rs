@@ -0,0 +1,507 @@
+//! Privacy redaction of per-workload SLA proofs.
+//!
+//! Each workload has a privacy mode, set with `WORKLOAD_PRIVACY`
+//! (`<workload id>=<mode>,...`, defaulting to `WORKLOAD_PRIVACY_DEFAULT`):
+//!
+//! - `full`: listed under its id, as before.
+//! - `aggregate-only`: only counted in the proof's summed metrics.
+//! - `redacted-id`: listed under `keccak256(abi.encode(id, salt))`. Salts are persisted per
+//!   workload so the reference is stable across epochs, and can be revealed to a disputer.
+//!
+//! The proof carries a bitmask of the modes it used so the verifier applies the matching rules.
+//! Proofs over an explicit block range are wrapped in [`SlaRangeProofV1`], stating the range
+//! served, its evidence root and whether it is provisional (see [`crate::evidence::range`]).
+//! [`SlaProofBuilder::encode_for_blocks`] builds one straight from the evidence log, listing
+//! each workload that pushed evidence in the range.
+
+use crate::config::{self, env_or};
+use crate::error::PhalaAvsError;
+use crate::evidence::range::{RangeEvidence, evidence_for_range};
+use crate::evidence::{AnchorConfig, EvidenceLog, WORKLOAD_EVIDENCE};
+use crate::evm::EvmClient;
+use crate::ingestion::WorkloadEvidence;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{B256, Bytes, keccak256};
+use blueprint_sdk::alloy::sol;
+use blueprint_sdk::alloy::sol_types::SolValue;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Per-workload salts of redacted workloads.
+pub const SALT_NAMESPACE: &str = "redaction_salts";
+
+sol! {
+    /// One workload's line of an SLA proof.
+    struct WorkloadEntry {
+        bytes32 workloadRef;
+        uint8 mode;
+        uint64 requests;
+        uint32 uptimeBps;
+    }
+
+    /// SLA proof payload, version 1.
+    struct SlaProofV1 {
+        /// Bitmask of the [`PrivacyMode`]s present, `1 << mode`.
+        uint8 redactionModes;
+        WorkloadEntry[] workloads;
+        uint32 aggregateWorkloads;
+        uint64 aggregateRequests;
+        uint32 aggregateMinUptimeBps;
+    }
+
+    /// SLA proof over a block range, version 1.
+    struct SlaRangeProofV1 {
+        uint64 fromBlock;
+        uint64 toBlock;
+        uint64 fromUnixMs;
+        uint64 toUnixMs;
+        /// The range reaches into evidence that is not final yet.
+        bool provisional;
+        bytes32 evidenceRoot;
+        uint32 uptimeBps;
+        SlaProofV1 proof;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrivacyMode {
+    #[default]
+    Full,
+    AggregateOnly,
+    RedactedId,
+}
+
+impl PrivacyMode {
+    /// Value of the mode on-chain.
+    pub fn code(self) -> u8 {
+        match self {
+            PrivacyMode::Full => 0,
+            PrivacyMode::AggregateOnly => 1,
+            PrivacyMode::RedactedId => 2,
+        }
+    }
+}
+
+impl FromStr for PrivacyMode {
+    type Err = PhalaAvsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "full" => Ok(PrivacyMode::Full),
+            "aggregate-only" => Ok(PrivacyMode::AggregateOnly),
+            "redacted-id" => Ok(PrivacyMode::RedactedId),
+            other => Err(PhalaAvsError::ConfigError(format!(
+                "Unknown privacy mode {other:?}, expected full, aggregate-only or redacted-id"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for PrivacyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PrivacyMode::Full => "full",
+            PrivacyMode::AggregateOnly => "aggregate-only",
+            PrivacyMode::RedactedId => "redacted-id",
+        })
+    }
+}
+
+/// Privacy mode of every workload.
+#[derive(Clone, Debug, Default)]
+pub struct PrivacySettings {
+    pub default_mode: PrivacyMode,
+    pub workloads: BTreeMap<B256, PrivacyMode>,
+}
+
+impl PrivacySettings {
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let mut settings = Self {
+            default_mode: env_or("WORKLOAD_PRIVACY_DEFAULT", PrivacyMode::Full)?,
+            workloads: BTreeMap::new(),
+        };
+        let raw = config::lookup("WORKLOAD_PRIVACY").unwrap_or_default();
+        for item in raw.split(',').filter(|s| !s.trim().is_empty()) {
+            let (id, mode) = item.split_once('=').ok_or_else(|| {
+                PhalaAvsError::ConfigError(format!(
+                    "Invalid WORKLOAD_PRIVACY entry {item:?}, expected <workload id>=<mode>"
+                ))
+            })?;
+            let id = id.trim().parse().map_err(|e| {
+                PhalaAvsError::ConfigError(format!("Invalid workload id in WORKLOAD_PRIVACY: {e}"))
+            })?;
+            settings.workloads.insert(id, mode.parse()?);
+        }
+        Ok(settings)
+    }
+
+    pub fn mode_of(&self, workload_id: &B256) -> PrivacyMode {
+        self.workloads
+            .get(workload_id)
+            .copied()
+            .unwrap_or(self.default_mode)
+    }
+}
+
+/// What the proof states about one workload.
+#[derive(Clone, Debug)]
+pub struct WorkloadUsage {
+    pub workload_id: B256,
+    pub requests: u64,
+    pub uptime_bps: u32,
+}
+
+/// The workloads that pushed evidence in `range`, each with its pushes counted as requests and
+/// the uptime of the range.
+pub fn workload_usage(
+    log: &EvidenceLog,
+    range: &RangeEvidence,
+) -> Result<Vec<WorkloadUsage>, PhalaAvsError> {
+    let mut pushes = BTreeMap::<B256, u64>::new();
+    for (_, raw) in log.records(WORKLOAD_EVIDENCE, range.span.from_ms, range.span.to_ms)? {
+        match serde_json::from_slice::<WorkloadEvidence>(&raw) {
+            Ok(record) => *pushes.entry(record.workload_id).or_default() += 1,
+            Err(e) => warn!("Skipping unreadable workload evidence: {e}"),
+        }
+    }
+    Ok(pushes
+        .into_iter()
+        .map(|(workload_id, requests)| WorkloadUsage {
+            workload_id,
+            requests,
+            uptime_bps: range.uptime_bps,
+        })
+        .collect())
+}
+
+/// Reference under which a redacted workload appears.
+pub fn redacted_ref(workload_id: B256, salt: B256) -> B256 {
+    keccak256((workload_id, salt).abi_encode())
+}
+
+/// Salt and id disclosed to prove which workload a redacted entry stands for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkloadReveal {
+    pub workload_id: B256,
+    pub salt: B256,
+}
+
+impl WorkloadReveal {
+    pub fn verify(&self, workload_ref: B256) -> bool {
+        redacted_ref(self.workload_id, self.salt) == workload_ref
+    }
+}
+
+/// The evidence [`SlaProofBuilder::encode_for_blocks`] reads.
+#[derive(Clone, Debug)]
+struct EvidenceSource {
+    log: EvidenceLog,
+    windows: AnchorConfig,
+    heartbeat_period_ms: u64,
+}
+
+/// Builds SLA proofs, applying each workload's privacy mode.
+#[derive(Debug)]
+pub struct SlaProofBuilder {
+    settings: PrivacySettings,
+    store: Arc<dyn StateStore>,
+    evidence: Option<EvidenceSource>,
+}
+
+impl SlaProofBuilder {
+    pub fn new(settings: PrivacySettings, store: Arc<dyn StateStore>) -> Self {
+        Self {
+            settings,
+            store,
+            evidence: None,
+        }
+    }
+
+    /// Has [`Self::encode_for_blocks`] read `log`, anchored in `windows`, where a heartbeat
+    /// covers at most `heartbeat_period_ms`.
+    pub fn with_evidence(
+        mut self,
+        log: EvidenceLog,
+        windows: AnchorConfig,
+        heartbeat_period_ms: u64,
+    ) -> Self {
+        self.evidence = Some(EvidenceSource {
+            log,
+            windows,
+            heartbeat_period_ms,
+        });
+        self
+    }
+
+    /// The workload's salt, created and persisted on first use.
+    fn salt(&self, workload_id: B256) -> Result<B256, PhalaAvsError> {
+        if let Some(salt) = self
+            .store
+            .get_json(SALT_NAMESPACE, workload_id.as_slice())?
+        {
+            return Ok(salt);
+        }
+        let seed = [
+            *uuid::Uuid::new_v4().as_bytes(),
+            *uuid::Uuid::new_v4().as_bytes(),
+        ]
+        .concat();
+        let salt = keccak256(seed);
+        self.store
+            .put_json(SALT_NAMESPACE, workload_id.as_slice(), &salt)?;
+        Ok(salt)
+    }
+
+    pub fn build(&self, usage: &[WorkloadUsage]) -> Result<SlaProofV1, PhalaAvsError> {
+        let mut proof = SlaProofV1 {
+            redactionModes: 0,
+            workloads: Vec::new(),
+            aggregateWorkloads: 0,
+            aggregateRequests: 0,
+            aggregateMinUptimeBps: 0,
+        };
+        for workload in usage {
+            let mode = self.settings.mode_of(&workload.workload_id);
+            proof.redactionModes |= 1 << mode.code();
+            let workload_ref = match mode {
+                PrivacyMode::AggregateOnly => {
+                    proof.aggregateMinUptimeBps = match proof.aggregateWorkloads {
+                        0 => workload.uptime_bps,
+                        _ => proof.aggregateMinUptimeBps.min(workload.uptime_bps),
+                    };
+                    proof.aggregateWorkloads += 1;
+                    proof.aggregateRequests += workload.requests;
+                    continue;
+                }
+                PrivacyMode::Full => workload.workload_id,
+                PrivacyMode::RedactedId => {
+                    redacted_ref(workload.workload_id, self.salt(workload.workload_id)?)
+                }
+            };
+            proof.workloads.push(WorkloadEntry {
+                workloadRef: workload_ref,
+                mode: mode.code(),
+                requests: workload.requests,
+                uptimeBps: workload.uptime_bps,
+            });
+        }
+        Ok(proof)
+    }
+
+    /// Builds and ABI-encodes a proof.
+    pub fn encode(&self, usage: &[WorkloadUsage]) -> Result<Bytes, PhalaAvsError> {
+        Ok(self.build(usage)?.abi_encode().into())
+    }
+
+    /// Builds a proof over the exact range of `range`. A range with gaps in its workload evidence
+    /// still yields a proof, noted as partial.
+    pub fn build_for_range(
+        &self,
+        range: &RangeEvidence,
+        usage: &[WorkloadUsage],
+    ) -> Result<SlaRangeProofV1, PhalaAvsError> {
+        for gap in &range.gaps {
+            warn!(
+                "Building a partial proof over blocks {}..={}: workload {} pushed no evidence from {} to {}",
+                range.span.from_block,
+                range.span.to_block,
+                gap.workload_id,
+                gap.from_ms,
+                gap.to_ms.map_or("now".to_string(), |ms| ms.to_string()),
+            );
+        }
+        Ok(SlaRangeProofV1 {
+            fromBlock: range.span.from_block,
+            toBlock: range.span.to_block,
+            fromUnixMs: range.span.from_ms,
+            toUnixMs: range.span.to_ms,
+            provisional: range.provisional,
+            evidenceRoot: range.root,
+            uptimeBps: range.uptime_bps,
+            proof: self.build(usage)?,
+        })
+    }
+
+    /// Builds and ABI-encodes a range proof.
+    pub fn encode_for_range(
+        &self,
+        range: &RangeEvidence,
+        usage: &[WorkloadUsage],
+    ) -> Result<Bytes, PhalaAvsError> {
+        Ok(self.build_for_range(range, usage)?.abi_encode().into())
+    }
+
+    /// Builds and ABI-encodes the range proof over blocks `[from_block, to_block]` from the
+    /// evidence given with [`Self::with_evidence`], with the [`workload_usage`] of the range.
+    pub async fn encode_for_blocks(
+        &self,
+        evm: &dyn EvmClient,
+        from_block: u64,
+        to_block: u64,
+        now_ms: u64,
+    ) -> Result<Bytes, PhalaAvsError> {
+        let source = self.evidence.as_ref().ok_or_else(|| {
+            PhalaAvsError::ConfigError(
+                "SLA proofs over block ranges need the evidence log".to_string(),
+            )
+        })?;
+        let range = evidence_for_range(
+            &source.log,
+            &source.windows,
+            evm,
+            from_block,
+            to_block,
+            source.heartbeat_period_ms,
+            now_ms,
+        )
+        .await?;
+        let usage = workload_usage(&source.log, &range)?;
+        self.encode_for_range(&range, &usage)
+    }
+
+    /// Discloses a redacted workload, at the operator's discretion, e.g. for a dispute.
+    pub fn reveal(&self, workload_id: B256) -> Result<WorkloadReveal, PhalaAvsError> {
+        if self.settings.mode_of(&workload_id) != PrivacyMode::RedactedId {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Workload {workload_id} is not redacted"
+            )));
+        }
+        let salt = self
+            .store
+            .get_json(SALT_NAMESPACE, workload_id.as_slice())?
+            .ok_or_else(|| {
+                PhalaAvsError::ValidationError(format!(
+                    "Workload {workload_id} has not appeared in a proof yet"
+                ))
+            })?;
+        Ok(WorkloadReveal { workload_id, salt })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    fn usage(byte: u8, requests: u64, uptime_bps: u32) -> WorkloadUsage {
+        WorkloadUsage {
+            workload_id: B256::repeat_byte(byte),
+            requests,
+            uptime_bps,
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn redacted_workloads_never_appear_by_id() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let settings = PrivacySettings {
+            default_mode: PrivacyMode::Full,
+            workloads: BTreeMap::from([
+                (B256::repeat_byte(2), PrivacyMode::AggregateOnly),
+                (B256::repeat_byte(3), PrivacyMode::AggregateOnly),
+                (B256::repeat_byte(4), PrivacyMode::RedactedId),
+            ]),
+        };
+        let builder = SlaProofBuilder::new(settings.clone(), Arc::clone(&store));
+        let workloads = [
+            usage(1, 10, 10_000),
+            usage(2, 20, 9_900),
+            usage(3, 30, 9_800),
+            usage(4, 40, 9_700),
+        ];
+        let proof = builder.build(&workloads).unwrap();
+        assert_eq!(proof.redactionModes, 0b111);
+        assert_eq!(proof.aggregateWorkloads, 2);
+        assert_eq!(proof.aggregateRequests, 50);
+        assert_eq!(proof.aggregateMinUptimeBps, 9_800);
+        assert_eq!(proof.workloads.len(), 2);
+        assert_eq!(proof.workloads[0].workloadRef, B256::repeat_byte(1));
+
+        let payload = builder.encode(&workloads).unwrap();
+        assert!(contains(&payload, B256::repeat_byte(1).as_slice()));
+        for hidden in [2, 3, 4] {
+            assert!(!contains(&payload, B256::repeat_byte(hidden).as_slice()));
+        }
+
+        // The reference is stable across builders sharing the store, and reveals verify.
+        let redacted = proof.workloads[1].workloadRef;
+        let again = SlaProofBuilder::new(settings, store)
+            .build(&workloads)
+            .unwrap();
+        assert_eq!(again.workloads[1].workloadRef, redacted);
+        let reveal = builder.reveal(B256::repeat_byte(4)).unwrap();
+        assert!(reveal.verify(redacted));
+        assert!(
+            !WorkloadReveal {
+                workload_id: B256::repeat_byte(5),
+                ..reveal
+            }
+            .verify(redacted)
+        );
+        assert!(builder.reveal(B256::repeat_byte(1)).is_err());
+    }
+
+    #[test]
+    fn usage_counts_the_pushes_inside_the_range() {
+        let log = EvidenceLog::new(Arc::new(MemoryStateStore::default()));
+        let push = |unix_ms: u64, byte: u8| {
+            let record = WorkloadEvidence {
+                workload_id: B256::repeat_byte(byte),
+                received_unix_ms: unix_ms,
+                kind: "metrics".to_string(),
+                observed_unix_ms: None,
+                data: serde_json::Value::Null,
+            };
+            log.record(WORKLOAD_EVIDENCE, unix_ms, &[byte], &record)
+                .unwrap();
+        };
+        for (unix_ms, byte) in [(900, 1), (1_100, 1), (1_200, 1), (1_500, 2), (2_000, 3)] {
+            push(unix_ms, byte);
+        }
+        let range = RangeEvidence {
+            span: crate::evidence::range::BlockSpan {
+                from_block: 1,
+                to_block: 2,
+                from_ms: 1_000,
+                to_ms: 2_000,
+                past_head: false,
+            },
+            windows: Vec::new(),
+            leaves: Vec::new(),
+            root: B256::ZERO,
+            live_ms: 950,
+            maintenance_ms: 0,
+            uptime_bps: 9_500,
+            provisional: false,
+            gaps: Vec::new(),
+        };
+        let usage = workload_usage(&log, &range).unwrap();
+        let counted: Vec<_> = usage
+            .iter()
+            .map(|u| (u.workload_id, u.requests, u.uptime_bps))
+            .collect();
+        assert_eq!(counted, [
+            (B256::repeat_byte(1), 2, 9_500),
+            (B256::repeat_byte(2), 1, 9_500),
+        ]);
+    }
+
+    #[test]
+    fn privacy_modes_parse() {
+        assert_eq!(
+            "aggregate-only".parse::<PrivacyMode>().unwrap(),
+            PrivacyMode::AggregateOnly
+        );
+        assert_eq!(PrivacyMode::RedactedId.to_string(), "redacted-id");
+        assert!("hidden".parse::<PrivacyMode>().is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! Registration gate for on-chain submissions.
+//!
+//! A watcher checks whether the operator is registered with the service manager at startup and
+//! every `REGISTRATION_CHECK_SECS`. While it is not (deregistered or ejected, which the service
+//! manager doesn't distinguish), heartbeat and challenge response submissions are suspended so
+//! they don't revert and waste gas; local evidence collection continues. Submissions resume
+//! automatically once registration is restored.
+//!
+//! During a voluntary exit (see [`crate::exit`]) the gate keeps permitting submissions after the
+//! deregistration, so challenges issued before it took effect are still answered.
+
+use crate::config::{env_flag, env_or};
+use crate::display::Addr;
+use crate::error::PhalaAvsError;
+use crate::evm::EvmClient;
+use crate::metrics::METRICS;
+use blueprint_sdk::alloy::primitives::Address;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// Gauge: 1 while registered, 0 while not, absent before the first check.
+pub const REGISTERED_METRIC: &str = "phala_avs_operator_registered";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationState {
+    /// Not checked yet, or every check so far failed.
+    Unknown,
+    Registered,
+    /// Not registered with the service manager, whether deregistered or ejected.
+    Deregistered,
+}
+
+/// The cached registration state and when it last changed.
+#[derive(Clone, Debug, Serialize)]
+pub struct RegistrationSnapshot {
+    pub state: RegistrationState,
+    pub since_unix_ms: u64,
+    pub checked_unix_ms: Option<u64>,
+    /// `FORCE_SUBMIT_WHEN_UNREGISTERED` is set.
+    pub force_submit: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct RegistrationConfig {
+    pub check_secs: u64,
+    /// Keep submitting while deregistered, e.g. during a registry migration.
+    pub force_submit: bool,
+}
+
+impl RegistrationConfig {
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            check_secs: env_or("REGISTRATION_CHECK_SECS", 60)?,
+            force_submit: env_flag("FORCE_SUBMIT_WHEN_UNREGISTERED", false)?,
+        })
+    }
+}
+
+/// Consulted by every path that submits transactions on the operator's behalf.
+#[derive(Debug)]
+pub struct RegistrationGate {
+    operator: Address,
+    config: RegistrationConfig,
+    snapshot: RwLock<RegistrationSnapshot>,
+    exiting: AtomicBool,
+}
+
+impl RegistrationGate {
+    pub fn new(operator: Address, config: RegistrationConfig) -> Self {
+        let snapshot = RegistrationSnapshot {
+            state: RegistrationState::Unknown,
+            since_unix_ms: now_unix_ms(),
+            checked_unix_ms: None,
+            force_submit: config.force_submit,
+        };
+        Self {
+            operator,
+            config,
+            snapshot: RwLock::new(snapshot),
+            exiting: AtomicBool::new(false),
+        }
+    }
+
+    /// Keeps submissions going through the deregistration of a voluntary exit.
+    pub fn set_exiting(&self) {
+        self.exiting.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_exiting(&self) -> bool {
+        self.exiting.load(Ordering::SeqCst)
+    }
+
+    pub fn snapshot(&self) -> RegistrationSnapshot {
+        self.snapshot
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn state(&self) -> RegistrationState {
+        self.snapshot().state
+    }
+
+    /// Whether on-chain submissions may be made.
+    ///
+    /// Only a confirmed deregistration suspends them; while the state is unknown the operator
+    /// keeps submitting, as it did before the gate existed.
+    pub fn permits_submission(&self) -> bool {
+        self.config.force_submit
+            || self.is_exiting()
+            || self.state() != RegistrationState::Deregistered
+    }
+
+    /// Records the result of a registration check.
+    pub fn update(&self, registered: bool) {
+        let state = if registered {
+            RegistrationState::Registered
+        } else {
+            RegistrationState::Deregistered
+        };
+        let now = now_unix_ms();
+        let previous = {
+            let mut snapshot = self.snapshot.write().unwrap_or_else(|e| e.into_inner());
+            let previous = snapshot.state;
+            if previous != state {
+                snapshot.state = state;
+                snapshot.since_unix_ms = now;
+            }
+            snapshot.checked_unix_ms = Some(now);
+            previous
+        };
+        METRICS.set_gauge(
+            REGISTERED_METRIC,
+            &[("operator", &self.operator.to_string())],
+            if registered { 1.0 } else { 0.0 },
+        );
+
+        match (previous, state) {
+            (RegistrationState::Deregistered, RegistrationState::Deregistered)
+                if self.is_exiting() => {}
+            (_, RegistrationState::Deregistered) if self.is_exiting() => info!(
+                "Operator {} is deregistered; still answering the challenges issued before its exit took effect",
+                Addr(self.operator)
+            ),
+            // Repeated on every check so the alert stays visible for as long as it applies.
+            (_, RegistrationState::Deregistered) if self.config.force_submit => warn!(
+                "Operator {} is not registered, still submitting because FORCE_SUBMIT_WHEN_UNREGISTERED is set",
+                Addr(self.operator)
+            ),
+            (_, RegistrationState::Deregistered) => error!(
+                "Operator {} is not registered with the service manager; on-chain submissions are suspended until it is",
+                Addr(self.operator)
+            ),
+            (RegistrationState::Deregistered, RegistrationState::Registered) => info!(
+                "Operator {} is registered again; resuming on-chain submissions",
+                Addr(self.operator)
+            ),
+            _ => {}
+        }
+    }
+
+    /// Checks the registration once, keeping the cached state if the check fails.
+    pub async fn check(&self, evm: &dyn EvmClient) -> Result<RegistrationState, PhalaAvsError> {
+        let registered = evm.is_operator_registered(self.operator).await?;
+        self.update(registered);
+        Ok(self.state())
+    }
+}
+
+/// Re-checks the registration every `check_secs`, in the background.
+pub fn spawn_watcher(gate: Arc<RegistrationGate>, evm: Arc<dyn EvmClient>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(gate.config.check_secs));
+        loop {
+            interval.tick().await;
+            if let Err(e) = gate.check(evm.as_ref()).await {
+                warn!("Failed to check operator registration: {e}");
+            }
+        }
+    });
+}
+
+fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::{
+        ChallengeTracker, ConfirmationPolicy, ObservedChallenge, process_events,
+    };
+    use crate::evm::BoxFuture;
+    use crate::fixtures::ChallengeEventFixture;
+    use crate::state::MemoryStateStore;
+    use crate::tee::{TeeHandler, TeeHandlerConfig};
+    use blueprint_sdk::alloy::primitives::{B256, U256};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct MockEvm {
+        registered: AtomicBool,
+    }
+
+    impl EvmClient for MockEvm {
+        fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(31337) })
+        }
+
+        fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(100) })
+        }
+
+        fn block_hash(&self, _number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn block_timestamp(
+            &self,
+            _number: u64,
+        ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn is_operator_registered(
+            &self,
+            _operator: Address,
+        ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+            let registered = self.registered.load(Ordering::SeqCst);
+            Box::pin(async move { Ok(registered) })
+        }
+    }
+
+    fn challenge() -> ObservedChallenge {
+        ChallengeEventFixture::new()
+            .operator(Address::repeat_byte(4))
+            .block(90)
+            .block_hash(None)
+            .window(110)
+            .build_observed()
+    }
+
+    #[tokio::test]
+    async fn deregistration_suspends_submissions_until_restored() {
+        let operator = Address::repeat_byte(4);
+        let evm = MockEvm {
+            registered: AtomicBool::new(true),
+        };
+        let gate = RegistrationGate::new(operator, RegistrationConfig {
+            check_secs: 60,
+            force_submit: false,
+        });
+        assert!(gate.permits_submission());
+        assert_eq!(
+            gate.check(&evm).await.unwrap(),
+            RegistrationState::Registered
+        );
+
+        evm.registered.store(false, Ordering::SeqCst);
+        gate.check(&evm).await.unwrap();
+        assert!(!gate.permits_submission());
+        let label = operator.to_string();
+        assert_eq!(
+            METRICS.gauge(REGISTERED_METRIC, &[("operator", &label)]),
+            Some(0.0)
+        );
+
+        // Evidence keeps being collected while suspended, but nothing is released.
+        let store = Arc::new(MemoryStateStore::default());
+        let tracker = ChallengeTracker::new(ConfirmationPolicy::default(), store).unwrap();
+        let tee = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        tracker.observe(challenge(), 100).unwrap();
+        let processed = process_events(
+            &tracker,
+            &evm,
+            &tee,
+            operator,
+            &[],
+            gate.permits_submission(),
+            |_| 5,
+        )
+        .await
+        .unwrap();
+        assert!(processed.ready.is_empty());
+        assert!(tracker.get(&U256::from(1)).is_some());
+
+        evm.registered.store(true, Ordering::SeqCst);
+        gate.check(&evm).await.unwrap();
+        assert!(gate.permits_submission());
+        let processed = process_events(
+            &tracker,
+            &evm,
+            &tee,
+            operator,
+            &[],
+            gate.permits_submission(),
+            |_| 5,
+        )
+        .await
+        .unwrap();
+        assert_eq!(processed.ready.len(), 1);
+    }
+
+    #[test]
+    fn force_submit_overrides_the_gate() {
+        let gate = RegistrationGate::new(Address::repeat_byte(5), RegistrationConfig {
+            check_secs: 60,
+            force_submit: true,
+        });
+        gate.update(false);
+        assert_eq!(gate.state(), RegistrationState::Deregistered);
+        assert!(gate.permits_submission());
+    }
+}
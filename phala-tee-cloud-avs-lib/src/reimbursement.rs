@@ -0,0 +1,615 @@
+//! Claiming the reimbursement the service manager credits for accepted challenge responses.
+//!
+//! The oracle has the service manager credit `reimbursementPerResponse` wei for each accepted
+//! response; the credits accrue in `claimableReimbursement` until the operator claims them.
+//! Every `REIMBURSEMENT_CHECK_SECS`, the claimable amount is read and, with
+//! `REIMBURSEMENT_CLAIM_ENABLED`, claimed as a deferrable transaction once it reaches
+//! `REIMBURSEMENT_MIN_CLAIM_WEI` and `REIMBURSEMENT_CLAIM_COST_MULTIPLE` times what the claim
+//! would cost in gas, so small balances are not claimed at a loss.
+//!
+//! Each check also scans the service manager for `ReimbursementClawedBack` events against the
+//! operator since the last check (the first looks back `REIMBURSEMENT_LOOKBACK_BLOCKS`), records
+//! them in the [`CostLedger`] and raises a warning: a clawback follows a response rejected in a
+//! dispute, and what was already claimed of it is settled against future credits.
+
+use crate::config::{env_flag, env_or};
+use crate::costs::{CostEntry, CostKind, CostLedger, ReimbursementTotals};
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::evm::BoxFuture;
+use crate::lanes::TxClass;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::sender::{TxCall, TxSender};
+use crate::state::{StateStore, StateStoreExt};
+use crate::{IPhalaServiceManager, SERVICE_MANAGER_ADDRESS};
+use blueprint_sdk::alloy::primitives::{Address, B256, U256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::Filter;
+use blueprint_sdk::alloy::sol_types::{SolCall, SolEvent};
+use blueprint_sdk::evm::util::get_provider_http;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Gauge: reimbursement credited on-chain and not claimed yet, in wei.
+pub const REIMBURSEMENT_CLAIMABLE_METRIC: &str = "phala_avs_reimbursement_claimable_wei";
+/// Counter of reimbursement claims sent.
+pub const REIMBURSEMENT_CLAIMS_METRIC: &str = "phala_avs_reimbursement_claims_total";
+
+const NAMESPACE: &str = "reimbursement";
+const CURSOR_KEY: &[u8] = b"clawback_cursor";
+const SOURCE: &str = "reimbursement";
+
+#[derive(Clone, Debug)]
+pub struct ReimbursementConfig {
+    /// Whether credited reimbursement is claimed; it is tracked either way.
+    pub claim_enabled: bool,
+    pub check_secs: u64,
+    /// Smallest amount claimed, whatever the claim costs.
+    pub min_claim_wei: u128,
+    /// How many times the claim's gas cost the claimable amount must reach.
+    pub claim_cost_multiple: u64,
+    /// Blocks scanned for clawbacks on the first check.
+    pub lookback_blocks: u64,
+}
+
+impl Default for ReimbursementConfig {
+    fn default() -> Self {
+        Self {
+            claim_enabled: false,
+            check_secs: 3600,
+            min_claim_wei: 0,
+            claim_cost_multiple: 2,
+            lookback_blocks: 50_000,
+        }
+    }
+}
+
+impl ReimbursementConfig {
+    /// Reads `REIMBURSEMENT_*`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        Ok(Self {
+            claim_enabled: env_flag("REIMBURSEMENT_CLAIM_ENABLED", defaults.claim_enabled)?,
+            check_secs: env_or("REIMBURSEMENT_CHECK_SECS", defaults.check_secs)?.max(1),
+            min_claim_wei: env_or("REIMBURSEMENT_MIN_CLAIM_WEI", defaults.min_claim_wei)?,
+            claim_cost_multiple: env_or(
+                "REIMBURSEMENT_CLAIM_COST_MULTIPLE",
+                defaults.claim_cost_multiple,
+            )?,
+            lookback_blocks: env_or("REIMBURSEMENT_LOOKBACK_BLOCKS", defaults.lookback_blocks)?,
+        })
+    }
+}
+
+/// A reimbursement taken back from the operator.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clawback {
+    pub challenge_id: U256,
+    pub wei: u128,
+    pub block: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<B256>,
+}
+
+/// The service manager's reimbursement pool, as the claimer sees it.
+pub trait ReimbursementPool: Send + Sync {
+    fn head(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+
+    /// Wei credited per accepted response; zero while reimbursement is off.
+    fn per_response_wei(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>>;
+
+    fn claimable_wei(&self, operator: Address) -> BoxFuture<'_, Result<u128, PhalaAvsError>>;
+
+    /// The most a claim would cost in gas now.
+    fn claim_cost_wei(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>>;
+
+    /// Claims the credited reimbursement, returning the transaction hash.
+    fn claim(&self) -> BoxFuture<'_, Result<B256, PhalaAvsError>>;
+
+    /// Clawbacks against `operator` in `[from_block, to_block)`, by block.
+    fn clawbacks(
+        &self,
+        operator: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<Vec<Clawback>, PhalaAvsError>>;
+}
+
+/// [`ReimbursementPool`] of the service manager contract, claimed through the [`TxSender`].
+pub struct ServiceManagerReimbursements {
+    service_manager: Address,
+    sender: Arc<TxSender>,
+    rpc_url: String,
+}
+
+impl ServiceManagerReimbursements {
+    pub fn new(service_manager: Address, sender: Arc<TxSender>, rpc_url: String) -> Self {
+        Self {
+            service_manager,
+            sender,
+            rpc_url,
+        }
+    }
+
+    /// Uses `SERVICE_MANAGER_ADDRESS`.
+    pub fn from_env(sender: Arc<TxSender>, rpc_url: String) -> Self {
+        Self::new(*SERVICE_MANAGER_ADDRESS, sender, rpc_url)
+    }
+
+    fn claim_call(&self) -> TxCall {
+        TxCall::new(
+            "claimReimbursement",
+            self.service_manager,
+            IPhalaServiceManager::claimReimbursementCall {}.abi_encode(),
+        )
+    }
+}
+
+fn evm_err(call: &str, e: impl std::fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::EvmError(format!("{call} failed: {e}"))
+}
+
+impl ReimbursementPool for ServiceManagerReimbursements {
+    fn head(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            get_provider_http(&self.rpc_url)
+                .get_block_number()
+                .await
+                .map_err(|e| evm_err("eth_blockNumber", e))
+        })
+    }
+
+    fn per_response_wei(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            Ok(IPhalaServiceManager::new(self.service_manager, provider)
+                .reimbursementPerResponse()
+                .call()
+                .await
+                .map_err(|e| evm_err("reimbursementPerResponse", e))?
+                ._0
+                .saturating_to())
+        })
+    }
+
+    fn claimable_wei(&self, operator: Address) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            Ok(IPhalaServiceManager::new(self.service_manager, provider)
+                .claimableReimbursement(operator)
+                .call()
+                .await
+                .map_err(|e| evm_err("claimableReimbursement", e))?
+                ._0
+                .saturating_to())
+        })
+    }
+
+    fn claim_cost_wei(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+        Box::pin(async move {
+            self.sender
+                .estimate_cost_wei(TxClass::Deferrable, self.claim_call())
+                .await
+        })
+    }
+
+    fn claim(&self) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        Box::pin(async move {
+            self.sender
+                .send(TxClass::Deferrable, self.claim_call())
+                .await?
+                .into_success("claimReimbursement")
+        })
+    }
+
+    fn clawbacks(
+        &self,
+        operator: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<Vec<Clawback>, PhalaAvsError>> {
+        Box::pin(async move {
+            if to_block <= from_block {
+                return Ok(Vec::new());
+            }
+            let provider = get_provider_http(&self.rpc_url);
+            let filter = Filter::new()
+                .address(self.service_manager)
+                .event_signature(IPhalaServiceManager::ReimbursementClawedBack::SIGNATURE_HASH)
+                .topic1(operator.into_word())
+                .from_block(from_block)
+                .to_block(to_block - 1);
+            let logs = provider
+                .get_logs(&filter)
+                .await
+                .map_err(|e| evm_err("eth_getLogs", e))?;
+            let mut clawbacks: Vec<Clawback> = logs
+                .iter()
+                .filter_map(|log| {
+                    let event = log
+                        .log_decode::<IPhalaServiceManager::ReimbursementClawedBack>()
+                        .ok()?;
+                    Some(Clawback {
+                        challenge_id: event.inner.data.challengeId,
+                        wei: event.inner.data.amount.saturating_to(),
+                        block: log.block_number?,
+                        tx_hash: log.transaction_hash,
+                    })
+                })
+                .collect();
+            clawbacks.sort_by_key(|c| c.block);
+            Ok(clawbacks)
+        })
+    }
+}
+
+/// A claim the claimer sent.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReimbursementClaim {
+    pub tx_hash: B256,
+    pub wei: u128,
+    pub unix_ms: u64,
+}
+
+/// Reimbursement on `/status`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReimbursementStatus {
+    pub claim_enabled: bool,
+    /// What the last check read, `None` before it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per_response_wei: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claimable_wei: Option<u128>,
+    /// The amount the last check would have claimed at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claim_threshold_wei: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_checked_unix_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_claim: Option<ReimbursementClaim>,
+    /// The reimbursement flow recorded in the cost ledger.
+    pub totals: ReimbursementTotals,
+}
+
+/// Tracks and claims the operator's reimbursement.
+pub struct ReimbursementClaimer {
+    config: ReimbursementConfig,
+    operator: Address,
+    pool: Arc<dyn ReimbursementPool>,
+    costs: Arc<CostLedger>,
+    store: Arc<dyn StateStore>,
+    notifier: Arc<dyn Notifier>,
+    status: Mutex<ReimbursementStatus>,
+}
+
+impl ReimbursementClaimer {
+    pub fn new(
+        config: ReimbursementConfig,
+        operator: Address,
+        pool: Arc<dyn ReimbursementPool>,
+        costs: Arc<CostLedger>,
+        store: Arc<dyn StateStore>,
+        notifier: Arc<dyn Notifier>,
+    ) -> Self {
+        let status = ReimbursementStatus {
+            claim_enabled: config.claim_enabled,
+            ..ReimbursementStatus::default()
+        };
+        Self {
+            config,
+            operator,
+            pool,
+            costs,
+            store,
+            notifier,
+            status: Mutex::new(status),
+        }
+    }
+
+    pub fn config(&self) -> &ReimbursementConfig {
+        &self.config
+    }
+
+    /// Records new clawbacks, then claims the credited reimbursement if it is worth claiming.
+    /// Returns the claim sent, if any.
+    pub async fn tick(&self, now_ms: u64) -> Result<Option<ReimbursementClaim>, PhalaAvsError> {
+        self.scan_clawbacks(now_ms).await?;
+
+        let per_response_wei = self.pool.per_response_wei().await?;
+        let claimable_wei = self.pool.claimable_wei(self.operator).await?;
+        METRICS.set_gauge(REIMBURSEMENT_CLAIMABLE_METRIC, &[], claimable_wei as f64);
+        let threshold = if self.config.claim_enabled && claimable_wei > 0 {
+            let cost = self.pool.claim_cost_wei().await?;
+            Some(
+                cost.saturating_mul(self.config.claim_cost_multiple.into())
+                    .max(self.config.min_claim_wei)
+                    .max(1),
+            )
+        } else {
+            None
+        };
+        {
+            let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+            status.per_response_wei = Some(per_response_wei);
+            status.claimable_wei = Some(claimable_wei);
+            status.claim_threshold_wei = threshold;
+            status.last_checked_unix_ms = Some(now_ms);
+        }
+        let Some(threshold) = threshold.filter(|t| claimable_wei >= *t) else {
+            return Ok(None);
+        };
+
+        let tx_hash = self.pool.claim().await?;
+        METRICS.inc_counter(REIMBURSEMENT_CLAIMS_METRIC, &[], 1);
+        info!("Claimed {claimable_wei} wei of reimbursement (threshold {threshold}) in {tx_hash}");
+        // The receipt's `ReimbursementClaimed` event is the same entry, so it is not counted
+        // twice when the receipt is verified.
+        self.costs.record(CostEntry {
+            unix_ms: now_ms,
+            kind: CostKind::Claim,
+            wei: claimable_wei,
+            challenge_id: None,
+            tx_hash: Some(tx_hash),
+        })?;
+        let claim = ReimbursementClaim {
+            tx_hash,
+            wei: claimable_wei,
+            unix_ms: now_ms,
+        };
+        self.status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .last_claim = Some(claim.clone());
+        Ok(Some(claim))
+    }
+
+    async fn scan_clawbacks(&self, now_ms: u64) -> Result<(), PhalaAvsError> {
+        let head = self.pool.head().await?;
+        let from_block = match self.store.get_json::<u64>(NAMESPACE, CURSOR_KEY)? {
+            Some(cursor) => cursor,
+            None => head.saturating_sub(self.config.lookback_blocks),
+        };
+        let to_block = head + 1;
+        for clawback in self
+            .pool
+            .clawbacks(self.operator, from_block, to_block)
+            .await?
+        {
+            let recorded = self.costs.record(CostEntry {
+                unix_ms: now_ms,
+                kind: CostKind::Clawback,
+                wei: clawback.wei,
+                challenge_id: Some(clawback.challenge_id),
+                tx_hash: clawback.tx_hash,
+            })?;
+            if !recorded {
+                continue;
+            }
+            let alert = Alert::new(
+                SOURCE,
+                Severity::Warning,
+                format!(
+                    "The service manager clawed back {} wei reimbursed for our response to \
+                     challenge {} in block {}; the response was rejected in a dispute",
+                    clawback.wei, clawback.challenge_id, clawback.block
+                ),
+            );
+            if let Err(e) = self.notifier.notify(alert).await {
+                warn!("Failed to deliver reimbursement clawback alert: {e}");
+            }
+        }
+        self.store.put_json(NAMESPACE, CURSOR_KEY, &to_block)
+    }
+
+    pub fn status(&self) -> ReimbursementStatus {
+        let mut status = self
+            .status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        match self.costs.totals() {
+            Ok(totals) => status.totals = totals.reimbursement,
+            Err(e) => warn!("Failed to read the cost ledger: {e}"),
+        }
+        status
+    }
+}
+
+/// Checks, and claims, reimbursement every `REIMBURSEMENT_CHECK_SECS`.
+pub fn spawn_reimbursement_claimer(claimer: Arc<ReimbursementClaimer>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(claimer.config.check_secs));
+        loop {
+            interval.tick().await;
+            if let Err(e) = claimer.tick(now_unix_ms()).await {
+                warn!("Failed to check reimbursement: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    const OPERATOR: Address = Address::repeat_byte(0x0a);
+
+    #[derive(Default)]
+    struct MockPool {
+        claimable: Mutex<u128>,
+        cost: u128,
+        clawbacks: Mutex<Vec<Clawback>>,
+        claims: Mutex<Vec<u128>>,
+    }
+
+    impl ReimbursementPool for MockPool {
+        fn head(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(1_000) })
+        }
+
+        fn per_response_wei(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+            Box::pin(async { Ok(100) })
+        }
+
+        fn claimable_wei(&self, operator: Address) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+            assert_eq!(operator, OPERATOR);
+            Box::pin(async { Ok(*self.claimable.lock().unwrap()) })
+        }
+
+        fn claim_cost_wei(&self) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+            Box::pin(async { Ok(self.cost) })
+        }
+
+        fn claim(&self) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            let claimed = std::mem::take(&mut *self.claimable.lock().unwrap());
+            let mut claims = self.claims.lock().unwrap();
+            claims.push(claimed);
+            let tx_hash = B256::with_last_byte(claims.len() as u8);
+            Box::pin(async move { Ok(tx_hash) })
+        }
+
+        fn clawbacks(
+            &self,
+            _: Address,
+            from_block: u64,
+            to_block: u64,
+        ) -> BoxFuture<'_, Result<Vec<Clawback>, PhalaAvsError>> {
+            let clawbacks = self
+                .clawbacks
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|c| (from_block..to_block).contains(&c.block))
+                .cloned()
+                .collect();
+            Box::pin(async move { Ok(clawbacks) })
+        }
+    }
+
+    struct Recorded(Mutex<Vec<Alert>>);
+
+    impl Notifier for Recorded {
+        fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.0.lock().unwrap().push(alert);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn claimer(
+        config: ReimbursementConfig,
+        pool: Arc<MockPool>,
+        notifier: Arc<Recorded>,
+    ) -> (ReimbursementClaimer, Arc<CostLedger>) {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let costs = Arc::new(CostLedger::new(Arc::clone(&store)));
+        let claimer =
+            ReimbursementClaimer::new(config, OPERATOR, pool, Arc::clone(&costs), store, notifier);
+        (claimer, costs)
+    }
+
+    fn credit(costs: &CostLedger, challenge: u64, wei: u128) {
+        costs
+            .record(CostEntry {
+                unix_ms: challenge,
+                kind: CostKind::Credit,
+                wei,
+                challenge_id: Some(U256::from(challenge)),
+                tx_hash: None,
+            })
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn claims_only_once_the_claimable_amount_outweighs_the_claim_cost() {
+        let pool = Arc::new(MockPool {
+            cost: 150,
+            ..MockPool::default()
+        });
+        let config = ReimbursementConfig {
+            claim_enabled: true,
+            min_claim_wei: 200,
+            ..ReimbursementConfig::default()
+        };
+        let (claimer, costs) = claimer(
+            config,
+            Arc::clone(&pool),
+            Arc::new(Recorded(Mutex::default())),
+        );
+        credit(&costs, 1, 100);
+        credit(&costs, 2, 100);
+        *pool.claimable.lock().unwrap() = 200;
+
+        // Above the minimum, but below twice the claim's cost.
+        assert_eq!(claimer.tick(1_000).await.unwrap(), None);
+        let status = claimer.status();
+        assert_eq!(status.claim_threshold_wei, Some(300));
+        assert_eq!(status.totals.pending_wei, 200);
+
+        credit(&costs, 3, 100);
+        *pool.claimable.lock().unwrap() = 300;
+        let claim = claimer.tick(2_000).await.unwrap().unwrap();
+        assert_eq!(claim.wei, 300);
+        assert_eq!(*pool.claims.lock().unwrap(), [300]);
+
+        let totals = costs.totals().unwrap().reimbursement;
+        assert_eq!((totals.claimed_wei, totals.pending_wei), (300, 0));
+        assert_eq!(claimer.status().last_claim, Some(claim));
+
+        // Nothing left to claim.
+        assert_eq!(claimer.tick(3_000).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn claiming_disabled_still_tracks_what_is_claimable() {
+        let pool = Arc::new(MockPool::default());
+        *pool.claimable.lock().unwrap() = 10_000;
+        let (claimer, _) = claimer(
+            ReimbursementConfig::default(),
+            Arc::clone(&pool),
+            Arc::new(Recorded(Mutex::default())),
+        );
+        assert_eq!(claimer.tick(1_000).await.unwrap(), None);
+        assert!(pool.claims.lock().unwrap().is_empty());
+        let status = claimer.status();
+        assert_eq!(status.claimable_wei, Some(10_000));
+        assert_eq!(status.claim_threshold_wei, None);
+    }
+
+    #[tokio::test]
+    async fn clawbacks_cancel_their_credit_and_are_alerted_once() {
+        let pool = Arc::new(MockPool::default());
+        let notifier = Arc::new(Recorded(Mutex::default()));
+        let (claimer, costs) = claimer(
+            ReimbursementConfig::default(),
+            Arc::clone(&pool),
+            Arc::clone(&notifier),
+        );
+        credit(&costs, 7, 100);
+        credit(&costs, 8, 100);
+        pool.clawbacks.lock().unwrap().push(Clawback {
+            challenge_id: U256::from(8),
+            wei: 100,
+            block: 990,
+            tx_hash: Some(B256::repeat_byte(0xcb)),
+        });
+
+        claimer.tick(1_000).await.unwrap();
+        claimer.tick(2_000).await.unwrap();
+
+        let alerts = notifier.0.lock().unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].severity, Severity::Warning);
+        assert!(
+            alerts[0].message.contains("challenge 8"),
+            "{}",
+            alerts[0].message
+        );
+        let totals = claimer.status().totals;
+        assert_eq!(totals.clawed_back_wei, 100);
+        assert_eq!(totals.pending_wei, 100);
+    }
+}
@@ -0,0 +1,692 @@
+//! Push of the metrics registry to a Prometheus remote-write endpoint.
+//!
+//! Operators whose CVM cannot be scraped set `REMOTE_WRITE_URL`. Every
+//! `REMOTE_WRITE_INTERVAL_SECS` the exporter snapshots [`METRICS`](crate::metrics::METRICS),
+//! adds the external labels and queues the snapshot, then pushes the queue oldest first as
+//! snappy-compressed `WriteRequest` protobufs with `REMOTE_WRITE_TOKEN` as bearer token. The
+//! `/metrics` endpoint is unaffected.
+//!
+//! The external labels are the operator address and chain as `operator` and `network`, plus
+//! `REMOTE_WRITE_EXTERNAL_LABELS` (`name=value,...`), which override them. They are added to the
+//! pushed copy only; a series' own label of the same name wins, as with Prometheus.
+//!
+//! While the endpoint is unreachable, answers 429 or 5xx, snapshots stay queued and the exporter
+//! backs off under the `REMOTE_WRITE` retry policy: doubling from the interval, with jitter, up
+//! to [`MAX_BACKOFF`]. A `Retry-After` from the endpoint replaces the delay, capped at
+//! [`MAX_BACKOFF`] as well. The queue holds at most `REMOTE_WRITE_BUFFER_SAMPLES` samples; the oldest
+//! snapshots are dropped first and counted in `phala_avs_remote_write_dropped_samples_total`.
+//! Each snapshot has a timestamp of its own and leaves the queue once accepted, so a recovered
+//! endpoint receives every queued sample once. Other 4xx answers are not retried.
+//!
+//! The settings are read again before every push, so a reloaded config file applies without a
+//! restart.
+
+use crate::config::{self, env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::metrics::{METRICS, MetricsRegistry, Sample};
+use crate::retry::{Clock, RetryPolicy, TokioClock};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub const DROPPED_SAMPLES_METRIC: &str = "phala_avs_remote_write_dropped_samples_total";
+pub const SENT_SAMPLES_METRIC: &str = "phala_avs_remote_write_sent_samples_total";
+pub const FAILURES_METRIC: &str = "phala_avs_remote_write_failures_total";
+pub const BUFFERED_SAMPLES_METRIC: &str = "phala_avs_remote_write_buffered_samples";
+
+/// The longest the exporter waits between attempts while the endpoint is failing.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Retry policy of pushes to a failing endpoint, overridden by `RETRY_REMOTE_WRITE_*`.
+pub const RETRY_SITE: &str = "REMOTE_WRITE";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteWriteConfig {
+    /// `None` disables the exporter.
+    pub endpoint: Option<String>,
+    pub token: Option<String>,
+    pub interval: Duration,
+    pub buffer_samples: usize,
+    pub max_samples_per_send: usize,
+    /// Sorted by name.
+    pub external_labels: Vec<(String, String)>,
+}
+
+impl RemoteWriteConfig {
+    /// Loads `REMOTE_WRITE_URL`, `REMOTE_WRITE_TOKEN`, `REMOTE_WRITE_INTERVAL_SECS` (default
+    /// 15), `REMOTE_WRITE_BUFFER_SAMPLES` (default 100000), `REMOTE_WRITE_MAX_SAMPLES_PER_SEND`
+    /// (default 10000) and `REMOTE_WRITE_EXTERNAL_LABELS`, which is layered over `defaults`.
+    pub fn from_env(defaults: &[(String, String)]) -> Result<Self, PhalaAvsError> {
+        let mut external_labels: BTreeMap<String, String> = defaults.iter().cloned().collect();
+        let raw = config::lookup("REMOTE_WRITE_EXTERNAL_LABELS").unwrap_or_default();
+        for item in raw.split(',').filter(|s| !s.trim().is_empty()) {
+            let (name, value) = item
+                .split_once('=')
+                .filter(|(name, _)| valid_label_name(name.trim()))
+                .ok_or_else(|| {
+                    PhalaAvsError::ConfigError(format!(
+                        "Invalid REMOTE_WRITE_EXTERNAL_LABELS entry {item:?}, expected \
+                         <label name>=<value>"
+                    ))
+                })?;
+            external_labels.insert(name.trim().to_string(), value.trim().to_string());
+        }
+        Ok(Self {
+            endpoint: env_opt("REMOTE_WRITE_URL")?,
+            token: env_opt("REMOTE_WRITE_TOKEN")?,
+            interval: Duration::from_secs(env_or("REMOTE_WRITE_INTERVAL_SECS", 15u64)?.max(1)),
+            buffer_samples: env_or("REMOTE_WRITE_BUFFER_SAMPLES", 100_000)?,
+            max_samples_per_send: env_or("REMOTE_WRITE_MAX_SAMPLES_PER_SEND", 10_000)?,
+            external_labels: external_labels.into_iter().collect(),
+        })
+    }
+}
+
+fn valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+}
+
+/// One series of a snapshot, with its labels as pushed.
+#[derive(Clone, Debug, PartialEq)]
+struct Series {
+    /// Sorted by name, including `__name__`.
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+/// A registry snapshot waiting to be pushed.
+#[derive(Clone, Debug)]
+struct Snapshot {
+    timestamp_ms: i64,
+    series: Vec<Series>,
+}
+
+impl Snapshot {
+    fn new(samples: Vec<Sample>, external_labels: &[(String, String)], timestamp_ms: i64) -> Self {
+        let series = samples
+            .into_iter()
+            .map(|sample| {
+                let mut labels = sample.labels;
+                for (name, value) in external_labels {
+                    if !labels.iter().any(|(n, _)| n == name) {
+                        labels.push((name.clone(), value.clone()));
+                    }
+                }
+                labels.push(("__name__".to_string(), sample.name));
+                labels.sort();
+                Series {
+                    labels,
+                    value: sample.value,
+                }
+            })
+            .collect();
+        Self {
+            timestamp_ms,
+            series,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Backoff {
+    /// Failed pushes in a row.
+    failures: u32,
+    until_ms: u64,
+}
+
+/// Queues registry snapshots and pushes them to the configured endpoint.
+#[derive(Debug)]
+pub struct RemoteWriteExporter {
+    client: reqwest::Client,
+    queue: VecDeque<Snapshot>,
+    queued_samples: usize,
+    last_timestamp_ms: i64,
+    backoff: Option<Backoff>,
+}
+
+impl Default for RemoteWriteExporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RemoteWriteExporter {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            queue: VecDeque::new(),
+            queued_samples: 0,
+            last_timestamp_ms: 0,
+            backoff: None,
+        }
+    }
+
+    /// Samples waiting to be pushed.
+    pub fn queued_samples(&self) -> usize {
+        self.queued_samples
+    }
+
+    /// Snapshots `registry` and pushes what is queued, returning how many samples were accepted.
+    pub async fn tick(
+        &mut self,
+        registry: &MetricsRegistry,
+        config: &RemoteWriteConfig,
+        now_ms: u64,
+    ) -> Result<usize, PhalaAvsError> {
+        self.capture(registry, config, now_ms);
+        self.push(config, now_ms).await
+    }
+
+    /// Queues a snapshot of `registry`, dropping the oldest queued samples beyond the buffer.
+    pub fn capture(&mut self, registry: &MetricsRegistry, config: &RemoteWriteConfig, now_ms: u64) {
+        // Timestamps strictly increase, so no series is sent two samples at the same time.
+        let timestamp_ms = (now_ms as i64).max(self.last_timestamp_ms + 1);
+        self.last_timestamp_ms = timestamp_ms;
+        let snapshot = Snapshot::new(registry.samples(), &config.external_labels, timestamp_ms);
+        self.queued_samples += snapshot.series.len();
+        self.queue.push_back(snapshot);
+
+        let mut dropped = 0;
+        while self.queued_samples > config.buffer_samples {
+            let excess = self.queued_samples - config.buffer_samples;
+            if self.queue.len() > 1 {
+                let oldest = self.queue.pop_front().expect("queue is not empty");
+                dropped += oldest.series.len();
+                self.queued_samples -= oldest.series.len();
+            } else if let Some(only) = self.queue.front_mut() {
+                // A snapshot larger than the whole buffer keeps what fits.
+                only.series.truncate(only.series.len() - excess);
+                dropped += excess;
+                self.queued_samples -= excess;
+            } else {
+                break;
+            }
+        }
+        if dropped > 0 {
+            warn!("Remote-write buffer full, dropped the oldest {dropped} samples");
+            METRICS.inc_counter(
+                DROPPED_SAMPLES_METRIC,
+                &[("reason", "buffer_full")],
+                dropped as u64,
+            );
+        }
+        self.report();
+    }
+
+    /// Pushes the queue oldest first until it is empty or the endpoint fails.
+    pub async fn push(
+        &mut self,
+        config: &RemoteWriteConfig,
+        now_ms: u64,
+    ) -> Result<usize, PhalaAvsError> {
+        let Some(endpoint) = &config.endpoint else {
+            return Ok(0);
+        };
+        if self.backoff.is_some_and(|b| now_ms < b.until_ms) {
+            return Ok(0);
+        }
+        let mut sent = 0;
+        while !self.queue.is_empty() {
+            let (count, samples) = self.next_request(config.max_samples_per_send);
+            let body = snap::raw::Encoder::new()
+                .compress_vec(&encode_write_request(
+                    &self.queue.range(..count).collect::<Vec<_>>(),
+                ))
+                .map_err(|e| PhalaAvsError::Other(format!("Snappy compression failed: {e}")))?;
+            let mut request = self
+                .client
+                .post(endpoint)
+                .header("Content-Encoding", "snappy")
+                .header("Content-Type", "application/x-protobuf")
+                .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                .body(body);
+            if let Some(token) = &config.token {
+                request = request.bearer_auth(token);
+            }
+            let response = match request.send().await {
+                Ok(response) => response,
+                Err(e) => {
+                    self.back_off(config, now_ms, None);
+                    METRICS.inc_counter(FAILURES_METRIC, &[("status", "unreachable")], 1);
+                    return Err(PhalaAvsError::Other(format!(
+                        "Remote write to {endpoint} failed: {e}"
+                    )));
+                }
+            };
+            let status = response.status();
+            if status.is_success() {
+                self.dequeue(count);
+                self.backoff = None;
+                sent += samples;
+                METRICS.inc_counter(SENT_SAMPLES_METRIC, &[], samples as u64);
+                continue;
+            }
+            METRICS.inc_counter(FAILURES_METRIC, &[("status", status.as_str())], 1);
+            if status.as_u16() == 429 || status.is_server_error() {
+                self.back_off(config, now_ms, retry_after(&response));
+                return Err(PhalaAvsError::Other(format!(
+                    "Remote write to {endpoint} answered {status}; {} samples stay queued",
+                    self.queued_samples
+                )));
+            }
+            // The endpoint will not take these whatever we do; don't hold up the rest.
+            warn!("Remote write to {endpoint} rejected {samples} samples with {status}");
+            self.dequeue(count);
+            METRICS.inc_counter(
+                DROPPED_SAMPLES_METRIC,
+                &[("reason", "rejected")],
+                samples as u64,
+            );
+        }
+        Ok(sent)
+    }
+
+    /// How many snapshots the next request takes, and their samples: at least one, and more
+    /// while they fit in `max_samples`.
+    fn next_request(&self, max_samples: usize) -> (usize, usize) {
+        let mut count = 0;
+        let mut samples = 0;
+        for snapshot in &self.queue {
+            if count > 0 && samples + snapshot.series.len() > max_samples {
+                break;
+            }
+            count += 1;
+            samples += snapshot.series.len();
+        }
+        (count, samples)
+    }
+
+    fn dequeue(&mut self, count: usize) {
+        for snapshot in self.queue.drain(..count) {
+            self.queued_samples -= snapshot.series.len();
+        }
+        self.report();
+    }
+
+    fn back_off(&mut self, config: &RemoteWriteConfig, now_ms: u64, retry_after: Option<Duration>) {
+        let failures = self.backoff.map_or(0, |b| b.failures) + 1;
+        let delay = retry_after
+            .unwrap_or_else(|| {
+                let policy = backoff_policy(config);
+                policy.jittered(policy.backoff(failures), TokioClock.roll())
+            })
+            .min(MAX_BACKOFF);
+        if self.backoff.is_none() {
+            info!("Backing off remote write for {}s", delay.as_secs());
+        }
+        self.backoff = Some(Backoff {
+            failures,
+            until_ms: now_ms + delay.as_millis() as u64,
+        });
+    }
+
+    fn report(&self) {
+        METRICS.set_gauge(BUFFERED_SAMPLES_METRIC, &[], self.queued_samples as f64);
+    }
+}
+
+/// Doubles from the push interval, retrying for as long as the endpoint keeps failing.
+fn backoff_policy(config: &RemoteWriteConfig) -> RetryPolicy {
+    RetryPolicy::from_config_or(RETRY_SITE, RetryPolicy {
+        max_attempts: 0,
+        base_delay: config.interval,
+        max_delay: MAX_BACKOFF,
+        ..RetryPolicy::default()
+    })
+}
+
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get("retry-after")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Encodes `snapshots` as a remote-write `WriteRequest`, one `TimeSeries` per label set with its
+/// samples in time order.
+fn encode_write_request(snapshots: &[&Snapshot]) -> Vec<u8> {
+    let mut series: BTreeMap<&[(String, String)], Vec<(f64, i64)>> = BTreeMap::new();
+    for snapshot in snapshots {
+        for s in &snapshot.series {
+            series
+                .entry(&s.labels)
+                .or_default()
+                .push((s.value, snapshot.timestamp_ms));
+        }
+    }
+    let mut out = Vec::new();
+    for (labels, samples) in series {
+        put_message(&mut out, 1, |ts| {
+            for (name, value) in labels {
+                put_message(ts, 1, |label| {
+                    put_bytes(label, 1, name.as_bytes());
+                    put_bytes(label, 2, value.as_bytes());
+                });
+            }
+            for (value, timestamp_ms) in samples {
+                put_message(ts, 2, |sample| {
+                    put_key(sample, 1, 1);
+                    sample.extend_from_slice(&value.to_le_bytes());
+                    put_key(sample, 2, 0);
+                    put_varint(sample, timestamp_ms as u64);
+                });
+            }
+        });
+    }
+    out
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_key(out: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(out, field << 3 | wire_type);
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_key(out, field, 2);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn put_message(out: &mut Vec<u8>, field: u64, encode: impl FnOnce(&mut Vec<u8>)) {
+    let mut message = Vec::new();
+    encode(&mut message);
+    put_bytes(out, field, &message);
+}
+
+/// Pushes [`METRICS`] in the background while `REMOTE_WRITE_URL` is set, with `defaults` as
+/// external labels under `REMOTE_WRITE_EXTERNAL_LABELS`.
+pub fn spawn_exporter(defaults: Vec<(String, String)>) {
+    tokio::spawn(async move {
+        let mut exporter = RemoteWriteExporter::new();
+        loop {
+            let config = match RemoteWriteConfig::from_env(&defaults) {
+                Ok(config) => config,
+                Err(e) => {
+                    warn!("Invalid remote-write settings, not pushing metrics: {e}");
+                    tokio::time::sleep(Duration::from_secs(15)).await;
+                    continue;
+                }
+            };
+            if config.endpoint.is_some() {
+                if let Err(e) = exporter.tick(&METRICS, &config, now_unix_ms()).await {
+                    warn!("{e}");
+                }
+            }
+            tokio::time::sleep(config.interval).await;
+        }
+    });
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::post;
+    use std::sync::{Arc, Mutex};
+
+    /// A decoded `TimeSeries`: its labels and `(value, timestamp)` samples.
+    type DecodedSeries = (Vec<(String, String)>, Vec<(f64, i64)>);
+
+    #[derive(Clone, Default)]
+    struct RemoteWriteServer {
+        requests: Arc<Mutex<Vec<(HeaderMap, Vec<DecodedSeries>)>>>,
+        /// Status answered to the next requests, with an optional `Retry-After`.
+        failures: Arc<Mutex<VecDeque<(StatusCode, Option<u64>)>>>,
+    }
+
+    async fn receive(
+        State(server): State<RemoteWriteServer>,
+        headers: HeaderMap,
+        body: axum::body::Bytes,
+    ) -> Response {
+        if let Some((status, retry_after)) = server.failures.lock().unwrap().pop_front() {
+            return match retry_after {
+                Some(secs) => (status, [("retry-after", secs.to_string())]).into_response(),
+                None => status.into_response(),
+            };
+        }
+        let raw = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        server
+            .requests
+            .lock()
+            .unwrap()
+            .push((headers, decode_write_request(&raw)));
+        StatusCode::NO_CONTENT.into_response()
+    }
+
+    fn varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = data[0];
+            *data = &data[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// The fields of a protobuf message as `(field, wire type, payload)`.
+    fn fields(mut data: &[u8]) -> Vec<(u64, u64, Vec<u8>)> {
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = varint(&mut data);
+            let payload = match key & 7 {
+                0 => varint(&mut data).to_le_bytes().to_vec(),
+                1 => {
+                    let (payload, rest) = data.split_at(8);
+                    data = rest;
+                    payload.to_vec()
+                }
+                2 => {
+                    let len = varint(&mut data) as usize;
+                    let (payload, rest) = data.split_at(len);
+                    data = rest;
+                    payload.to_vec()
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push((key >> 3, key & 7, payload));
+        }
+        fields
+    }
+
+    fn decode_write_request(data: &[u8]) -> Vec<DecodedSeries> {
+        fields(data)
+            .into_iter()
+            .map(|(field, wire_type, series)| {
+                assert_eq!((field, wire_type), (1, 2));
+                let mut labels = Vec::new();
+                let mut samples = Vec::new();
+                for (field, _, payload) in fields(&series) {
+                    let inner = fields(&payload);
+                    match field {
+                        1 => labels.push((
+                            String::from_utf8(inner[0].2.clone()).unwrap(),
+                            String::from_utf8(inner[1].2.clone()).unwrap(),
+                        )),
+                        2 => samples.push((
+                            f64::from_le_bytes(inner[0].2.clone().try_into().unwrap()),
+                            u64::from_le_bytes(inner[1].2.clone().try_into().unwrap()) as i64,
+                        )),
+                        field => panic!("unexpected TimeSeries field {field}"),
+                    }
+                }
+                (labels, samples)
+            })
+            .collect()
+    }
+
+    async fn start(server: RemoteWriteServer) -> String {
+        let app = Router::new()
+            .route("/api/v1/write", post(receive))
+            .with_state(server);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}/api/v1/write")
+    }
+
+    fn config(endpoint: String) -> RemoteWriteConfig {
+        RemoteWriteConfig {
+            endpoint: Some(endpoint),
+            token: Some("push-secret".to_string()),
+            interval: Duration::from_secs(15),
+            buffer_samples: 1_000,
+            max_samples_per_send: 1_000,
+            external_labels: vec![
+                ("network".to_string(), "17000".to_string()),
+                ("operator".to_string(), "0xabc".to_string()),
+            ],
+        }
+    }
+
+    fn label<'a>(labels: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        labels
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pushes_decodable_series_with_auth_and_external_labels() {
+        let server = RemoteWriteServer::default();
+        let config = config(start(server.clone()).await);
+        let registry = MetricsRegistry::default();
+        registry.inc_counter("phala_avs_test_total", &[("kind", "a")], 3);
+        registry.set_gauge("phala_avs_test_gauge", &[("network", "local")], 2.5);
+        registry.observe("phala_avs_test_seconds", &[], 0.2);
+
+        let mut exporter = RemoteWriteExporter::new();
+        let sent = exporter.tick(&registry, &config, 1_000).await.unwrap();
+        assert_eq!(sent, registry.samples().len());
+        assert_eq!(exporter.queued_samples(), 0);
+
+        let requests = server.requests.lock().unwrap();
+        let (headers, series) = &requests[0];
+        assert_eq!(headers["authorization"], "Bearer push-secret");
+        assert_eq!(headers["content-encoding"], "snappy");
+        assert_eq!(headers["content-type"], "application/x-protobuf");
+        assert_eq!(headers["x-prometheus-remote-write-version"], "0.1.0");
+        assert_eq!(series.len(), registry.samples().len());
+
+        let find = |name: &str| {
+            series
+                .iter()
+                .find(|(labels, _)| label(labels, "__name__") == Some(name))
+                .unwrap()
+        };
+        let (labels, samples) = find("phala_avs_test_total");
+        assert!(labels.is_sorted());
+        assert_eq!(label(labels, "kind"), Some("a"));
+        assert_eq!(label(labels, "operator"), Some("0xabc"));
+        assert_eq!(samples, &[(3.0, 1_000)]);
+        // A series' own label wins over an external one.
+        let (labels, _) = find("phala_avs_test_gauge");
+        assert_eq!(label(labels, "network"), Some("local"));
+        assert_eq!(find("phala_avs_test_seconds_count").1, [(1.0, 1_000)]);
+
+        // The local registry is untouched.
+        assert!(!registry.render().contains("operator="));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_outage_is_buffered_and_recovered_without_duplicates() {
+        let server = RemoteWriteServer::default();
+        let mut config = config(start(server.clone()).await);
+        let registry = MetricsRegistry::default();
+        registry.set_gauge("phala_avs_test_gauge", &[], 1.0);
+        registry.set_gauge("phala_avs_test_other", &[], 2.0);
+        // Room for three snapshots of two series.
+        config.buffer_samples = 6;
+        let dropped_before = METRICS
+            .counter(DROPPED_SAMPLES_METRIC, &[("reason", "buffer_full")])
+            .unwrap_or_default();
+
+        let mut exporter = RemoteWriteExporter::new();
+        server.failures.lock().unwrap().extend([
+            (StatusCode::SERVICE_UNAVAILABLE, None),
+            (StatusCode::TOO_MANY_REQUESTS, Some(60)),
+        ]);
+        assert!(exporter.tick(&registry, &config, 1_000).await.is_err());
+        // Backing off: the next snapshot is queued, not sent.
+        assert_eq!(exporter.tick(&registry, &config, 2_000).await.unwrap(), 0);
+        assert!(exporter.tick(&registry, &config, 16_000).await.is_err());
+        // The 429 asked for 60s.
+        assert_eq!(exporter.tick(&registry, &config, 31_000).await.unwrap(), 0);
+        assert_eq!(exporter.queued_samples(), 6);
+        assert!(
+            METRICS
+                .counter(DROPPED_SAMPLES_METRIC, &[("reason", "buffer_full")])
+                .unwrap_or_default()
+                >= dropped_before + 2
+        );
+
+        // Recovered: the three buffered snapshots go out once, in order. Two snapshots in the
+        // same millisecond still get distinct timestamps.
+        let sent = exporter.tick(&registry, &config, 77_000).await.unwrap();
+        assert_eq!(sent, 6);
+        exporter.capture(&registry, &config, 77_000);
+        assert_eq!(exporter.push(&config, 77_000).await.unwrap(), 2);
+
+        let requests = server.requests.lock().unwrap();
+        let mut timestamps: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+        for (_, series) in requests.iter() {
+            for (labels, samples) in series {
+                let name = label(labels, "__name__").unwrap().to_string();
+                timestamps
+                    .entry(name)
+                    .or_default()
+                    .extend(samples.iter().map(|(_, t)| *t));
+            }
+        }
+        for timestamps in timestamps.values() {
+            assert_eq!(timestamps, &[16_000, 31_000, 77_000, 77_001]);
+        }
+        assert_eq!(exporter.queued_samples(), 0);
+    }
+
+    #[test]
+    fn backoff_is_jittered_and_capped() {
+        let config = config("http://127.0.0.1:9".to_string());
+        let mut exporter = RemoteWriteExporter::new();
+        let mut waits = Vec::new();
+        for _ in 0..8 {
+            exporter.back_off(&config, 0, None);
+            waits.push(exporter.backoff.unwrap().until_ms);
+        }
+        // Equal jitter keeps each wait between half the doubled delay and the delay.
+        assert!((7_500..=15_000).contains(&waits[0]), "{waits:?}");
+        assert!((15_000..=30_000).contains(&waits[1]), "{waits:?}");
+        assert!(waits.iter().all(|&ms| ms <= MAX_BACKOFF.as_millis() as u64));
+        assert_eq!(exporter.backoff.unwrap().failures, 8);
+
+        // A server asking for an hour gets MAX_BACKOFF.
+        exporter.back_off(&config, 0, Some(Duration::from_secs(3600)));
+        assert_eq!(
+            exporter.backoff.unwrap().until_ms,
+            MAX_BACKOFF.as_millis() as u64
+        );
+    }
+}
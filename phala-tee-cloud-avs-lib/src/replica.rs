@@ -0,0 +1,918 @@
+//! Active/standby coordination between replicas sharing an operator key.
+//!
+//! Replicas of one operator contend for a response lease in a shared [`ReplicaStore`], set
+//! with `REPLICA_LEASE=sqlite:<path>` on storage every replica reaches. Only the holder sends
+//! transactions. A standby runs the same pipeline, but [`TxSender`](crate::sender::TxSender)
+//! records the challenge responses it would have submitted instead of sending anything.
+//!
+//! The holder renews the lease every `REPLICA_LEASE_RENEW_MS`, and it lapses
+//! `REPLICA_LEASE_TTL_MS` after the last renewal. A standby tries to take it at the same
+//! interval, so a failed leader is replaced within [`ReplicaConfig::failover_bound`].
+//!
+//! Every change of holder bumps the lease's fencing token. Intents record the token they were
+//! prepared under, and are only broadcast while the lease in the store is still held under it:
+//! a leader paused or partitioned past its lease that comes back with prepared transactions
+//! fails them rather than broadcasting them next to its successor's.
+//!
+//! The leader logs each challenge response it broadcasts to the store. The standby compares its
+//! would-submit records against that log, and alerts when the leader answered differently, or
+//! not at all within `REPLICA_DIVERGENCE_GRACE_MS`: the replicas disagree on what to answer,
+//! and one of them is wrong.
+//!
+//! Without `REPLICA_LEASE` the operator is a single replica, always sending.
+
+use crate::config::{env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::sender::TxCall;
+use crate::state::{StateBackend, StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{Address, B256, U256, keccak256};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Would-submit records of a standby, by challenge and revision.
+pub const SHADOW_NAMESPACE: &str = "replica_shadow";
+/// Gauge, 1 while this replica holds the response lease (or is the only replica).
+pub const LEADER_METRIC: &str = "phala_avs_replica_leader";
+/// Counter of leases this replica took over from another holder.
+pub const TAKEOVERS_METRIC: &str = "phala_avs_replica_takeovers_total";
+/// Counter of transactions refused because their fencing token is stale.
+pub const FENCED_METRIC: &str = "phala_avs_replica_fenced_total";
+/// Counter of would-submit records the leader's submissions contradict, by `kind`.
+pub const DIVERGENCES_METRIC: &str = "phala_avs_replica_divergences_total";
+
+/// How long the leader's submissions are kept in the shared store.
+const SUBMISSION_RETENTION_MS: u64 = 7 * 86_400_000;
+
+#[derive(Clone, Debug)]
+pub struct ReplicaConfig {
+    /// Names this replica in the lease; unique among the operator's replicas.
+    pub replica_id: String,
+    /// Where the lease is kept; `None` for a single replica.
+    pub lease: Option<StateBackend>,
+    pub ttl: Duration,
+    pub renew: Duration,
+    /// How long after a standby would have submitted the leader's submission may still appear.
+    pub divergence_grace: Duration,
+}
+
+impl Default for ReplicaConfig {
+    fn default() -> Self {
+        Self {
+            replica_id: "replica".to_string(),
+            lease: None,
+            ttl: Duration::from_secs(15),
+            renew: Duration::from_secs(5),
+            divergence_grace: Duration::from_secs(120),
+        }
+    }
+}
+
+impl ReplicaConfig {
+    /// Reads `REPLICA_ID` (default: `HOSTNAME`, else a random id), `REPLICA_LEASE`,
+    /// `REPLICA_LEASE_TTL_MS`, `REPLICA_LEASE_RENEW_MS` and `REPLICA_DIVERGENCE_GRACE_MS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        let replica_id = match env_opt::<String>("REPLICA_ID")? {
+            Some(id) => id,
+            None => std::env::var("HOSTNAME")
+                .ok()
+                .filter(|host| !host.is_empty())
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        };
+        let lease = env_opt::<String>("REPLICA_LEASE")?
+            .map(|raw| raw.parse::<StateBackend>())
+            .transpose()
+            .map_err(|e| PhalaAvsError::ConfigError(format!("Invalid REPLICA_LEASE: {e}")))?;
+        if lease == Some(StateBackend::Memory) {
+            return Err(PhalaAvsError::ConfigError(
+                "REPLICA_LEASE must be shared between replicas, e.g. sqlite:<path>".to_string(),
+            ));
+        }
+        let config = Self {
+            replica_id,
+            lease,
+            ttl: Duration::from_millis(env_or(
+                "REPLICA_LEASE_TTL_MS",
+                defaults.ttl.as_millis() as u64,
+            )?),
+            renew: Duration::from_millis(env_or(
+                "REPLICA_LEASE_RENEW_MS",
+                defaults.renew.as_millis() as u64,
+            )?),
+            divergence_grace: Duration::from_millis(env_or(
+                "REPLICA_DIVERGENCE_GRACE_MS",
+                defaults.divergence_grace.as_millis() as u64,
+            )?),
+        };
+        if config.renew.is_zero() || config.renew >= config.ttl {
+            return Err(PhalaAvsError::ConfigError(
+                "REPLICA_LEASE_RENEW_MS must be at least 1 and below REPLICA_LEASE_TTL_MS"
+                    .to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    /// The longest a standby can take to replace a leader that stopped renewing: the lease
+    /// lapses a TTL after its last renewal, and the standby tries again within a renewal
+    /// interval.
+    pub fn failover_bound(&self) -> Duration {
+        self.ttl + self.renew
+    }
+}
+
+/// The response lease of an operator.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    pub holder: String,
+    /// Bumped on every change of holder.
+    pub token: u64,
+    pub expires_unix_ms: u64,
+}
+
+impl Lease {
+    pub fn is_live(&self, now_ms: u64) -> bool {
+        now_ms < self.expires_unix_ms
+    }
+
+    /// The lease after `holder` tries to take or renew it at `now_ms`.
+    fn next(current: Option<Lease>, holder: &str, ttl_ms: u64, now_ms: u64) -> Lease {
+        let expires_unix_ms = now_ms.saturating_add(ttl_ms);
+        match current {
+            Some(lease) if lease.holder == holder => Lease {
+                expires_unix_ms,
+                ..lease
+            },
+            Some(lease) if lease.is_live(now_ms) => lease,
+            current => Lease {
+                holder: holder.to_string(),
+                token: current.map_or(1, |lease| lease.token + 1),
+                expires_unix_ms,
+            },
+        }
+    }
+}
+
+/// A challenge response the leader broadcast.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Submission {
+    pub challenge_id: U256,
+    pub revision: u32,
+    /// `keccak256` of the call's input.
+    pub input_hash: B256,
+    pub tx_hash: B256,
+    pub holder: String,
+    pub token: u64,
+    pub recorded_unix_ms: u64,
+}
+
+/// Leases and the leader's submissions, shared by every replica of an operator. `identity`
+/// names the operator.
+pub trait ReplicaStore: Send + Sync {
+    /// Takes or renews the lease for `holder` until `now_ms + ttl_ms`, unless another holder's
+    /// lease is live, atomically. Returns the lease as it stands afterwards.
+    fn acquire(
+        &self,
+        identity: &str,
+        holder: &str,
+        ttl_ms: u64,
+        now_ms: u64,
+    ) -> Result<Lease, PhalaAvsError>;
+
+    fn lease(&self, identity: &str) -> Result<Option<Lease>, PhalaAvsError>;
+
+    fn record_submission(
+        &self,
+        identity: &str,
+        submission: &Submission,
+    ) -> Result<(), PhalaAvsError>;
+
+    fn submission(
+        &self,
+        identity: &str,
+        challenge_id: U256,
+        revision: u32,
+    ) -> Result<Option<Submission>, PhalaAvsError>;
+
+    /// Drops the submissions recorded before `before_ms`.
+    fn prune_submissions(&self, identity: &str, before_ms: u64) -> Result<usize, PhalaAvsError>;
+}
+
+/// A [`ReplicaStore`] shared by replicas in one process, for tests.
+#[derive(Debug, Default)]
+pub struct MemoryReplicaStore {
+    leases: Mutex<BTreeMap<String, Lease>>,
+    submissions: Mutex<BTreeMap<(String, U256, u32), Submission>>,
+}
+
+impl ReplicaStore for MemoryReplicaStore {
+    fn acquire(
+        &self,
+        identity: &str,
+        holder: &str,
+        ttl_ms: u64,
+        now_ms: u64,
+    ) -> Result<Lease, PhalaAvsError> {
+        let mut leases = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+        let lease = Lease::next(leases.get(identity).cloned(), holder, ttl_ms, now_ms);
+        leases.insert(identity.to_string(), lease.clone());
+        Ok(lease)
+    }
+
+    fn lease(&self, identity: &str) -> Result<Option<Lease>, PhalaAvsError> {
+        let leases = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(leases.get(identity).cloned())
+    }
+
+    fn record_submission(
+        &self,
+        identity: &str,
+        submission: &Submission,
+    ) -> Result<(), PhalaAvsError> {
+        let mut submissions = self.submissions.lock().unwrap_or_else(|e| e.into_inner());
+        let key = (
+            identity.to_string(),
+            submission.challenge_id,
+            submission.revision,
+        );
+        submissions.insert(key, submission.clone());
+        Ok(())
+    }
+
+    fn submission(
+        &self,
+        identity: &str,
+        challenge_id: U256,
+        revision: u32,
+    ) -> Result<Option<Submission>, PhalaAvsError> {
+        let submissions = self.submissions.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(submissions
+            .get(&(identity.to_string(), challenge_id, revision))
+            .cloned())
+    }
+
+    fn prune_submissions(&self, identity: &str, before_ms: u64) -> Result<usize, PhalaAvsError> {
+        let mut submissions = self.submissions.lock().unwrap_or_else(|e| e.into_inner());
+        let before = submissions.len();
+        submissions.retain(|(id, _, _), s| id != identity || s.recorded_unix_ms >= before_ms);
+        Ok(before - submissions.len())
+    }
+}
+
+/// A [`ReplicaStore`] in a SQLite database on storage the replicas share. The lease is taken in
+/// an immediate transaction, so two replicas never both see it free.
+#[cfg(feature = "storage-sqlite")]
+#[derive(Debug)]
+pub struct SqliteReplicaStore {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "storage-sqlite")]
+impl SqliteReplicaStore {
+    pub fn open(path: &std::path::Path) -> Result<Self, PhalaAvsError> {
+        use crate::state::sqlite::sqlite_err;
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let conn = rusqlite::Connection::open(path).map_err(sqlite_err)?;
+        conn.busy_timeout(Duration::from_secs(5))
+            .map_err(sqlite_err)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS replica_leases (
+                 identity TEXT PRIMARY KEY,
+                 lease TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS replica_submissions (
+                 identity TEXT NOT NULL,
+                 challenge_id BLOB NOT NULL,
+                 revision INTEGER NOT NULL,
+                 recorded_unix_ms INTEGER NOT NULL,
+                 submission TEXT NOT NULL,
+                 PRIMARY KEY (identity, challenge_id, revision)
+             );",
+        )
+        .map_err(sqlite_err)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, rusqlite::Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(feature = "storage-sqlite")]
+fn read_lease(conn: &rusqlite::Connection, identity: &str) -> Result<Option<Lease>, PhalaAvsError> {
+    use crate::state::sqlite::sqlite_err;
+    use rusqlite::OptionalExtension;
+
+    let raw: Option<String> = conn
+        .query_row(
+            "SELECT lease FROM replica_leases WHERE identity = ?1",
+            [identity],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(sqlite_err)?;
+    raw.map(|raw| {
+        serde_json::from_str(&raw)
+            .map_err(|e| PhalaAvsError::StorageError(format!("Unreadable replica lease: {e}")))
+    })
+    .transpose()
+}
+
+#[cfg(feature = "storage-sqlite")]
+impl ReplicaStore for SqliteReplicaStore {
+    fn acquire(
+        &self,
+        identity: &str,
+        holder: &str,
+        ttl_ms: u64,
+        now_ms: u64,
+    ) -> Result<Lease, PhalaAvsError> {
+        use crate::state::sqlite::sqlite_err;
+
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .map_err(sqlite_err)?;
+        let lease = Lease::next(read_lease(&tx, identity)?, holder, ttl_ms, now_ms);
+        let raw = serde_json::to_string(&lease)
+            .map_err(|e| PhalaAvsError::StorageError(e.to_string()))?;
+        tx.execute(
+            "INSERT INTO replica_leases (identity, lease) VALUES (?1, ?2)
+             ON CONFLICT(identity) DO UPDATE SET lease = excluded.lease",
+            rusqlite::params![identity, raw],
+        )
+        .map_err(sqlite_err)?;
+        tx.commit().map_err(sqlite_err)?;
+        Ok(lease)
+    }
+
+    fn lease(&self, identity: &str) -> Result<Option<Lease>, PhalaAvsError> {
+        read_lease(&self.conn(), identity)
+    }
+
+    fn record_submission(
+        &self,
+        identity: &str,
+        submission: &Submission,
+    ) -> Result<(), PhalaAvsError> {
+        use crate::state::sqlite::sqlite_err;
+
+        let raw = serde_json::to_string(submission)
+            .map_err(|e| PhalaAvsError::StorageError(e.to_string()))?;
+        self.conn()
+            .execute(
+                "INSERT INTO replica_submissions
+                     (identity, challenge_id, revision, recorded_unix_ms, submission)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(identity, challenge_id, revision)
+                 DO UPDATE SET recorded_unix_ms = excluded.recorded_unix_ms,
+                               submission = excluded.submission",
+                rusqlite::params![
+                    identity,
+                    submission.challenge_id.to_be_bytes::<32>().to_vec(),
+                    submission.revision,
+                    submission.recorded_unix_ms as i64,
+                    raw
+                ],
+            )
+            .map(|_| ())
+            .map_err(sqlite_err)
+    }
+
+    fn submission(
+        &self,
+        identity: &str,
+        challenge_id: U256,
+        revision: u32,
+    ) -> Result<Option<Submission>, PhalaAvsError> {
+        use crate::state::sqlite::sqlite_err;
+        use rusqlite::OptionalExtension;
+
+        let raw: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT submission FROM replica_submissions
+                 WHERE identity = ?1 AND challenge_id = ?2 AND revision = ?3",
+                rusqlite::params![
+                    identity,
+                    challenge_id.to_be_bytes::<32>().to_vec(),
+                    revision
+                ],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_err)?;
+        raw.map(|raw| {
+            serde_json::from_str(&raw).map_err(|e| {
+                PhalaAvsError::StorageError(format!("Unreadable replica submission: {e}"))
+            })
+        })
+        .transpose()
+    }
+
+    fn prune_submissions(&self, identity: &str, before_ms: u64) -> Result<usize, PhalaAvsError> {
+        use crate::state::sqlite::sqlite_err;
+
+        self.conn()
+            .execute(
+                "DELETE FROM replica_submissions WHERE identity = ?1 AND recorded_unix_ms < ?2",
+                rusqlite::params![identity, before_ms as i64],
+            )
+            .map_err(sqlite_err)
+    }
+}
+
+/// Opens the shared store `backend` names.
+pub fn open_store(backend: &StateBackend) -> Result<Arc<dyn ReplicaStore>, PhalaAvsError> {
+    match backend {
+        StateBackend::Memory => Ok(Arc::new(MemoryReplicaStore::default())),
+        #[cfg(feature = "storage-sqlite")]
+        StateBackend::Sqlite(path) => Ok(Arc::new(SqliteReplicaStore::open(path)?)),
+        #[cfg(not(feature = "storage-sqlite"))]
+        StateBackend::Sqlite(_) => Err(PhalaAvsError::ConfigError(format!(
+            "replica lease {backend} needs the storage-sqlite feature"
+        ))),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaRole {
+    /// No lease is configured; the replica always sends.
+    Single,
+    Leader,
+    Standby,
+}
+
+impl ReplicaRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Single => "single",
+            Self::Leader => "leader",
+            Self::Standby => "standby",
+        }
+    }
+}
+
+/// What a standby would have submitted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WouldSubmit {
+    pub challenge_id: U256,
+    pub revision: u32,
+    pub label: String,
+    pub input_hash: B256,
+    pub recorded_unix_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DivergenceKind {
+    /// The leader submitted another payload.
+    Payload,
+    /// The leader submitted nothing within the grace period.
+    Missing,
+}
+
+impl DivergenceKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Payload => "payload",
+            Self::Missing => "missing",
+        }
+    }
+}
+
+/// A would-submit record the leader's submissions contradict.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Divergence {
+    pub challenge_id: U256,
+    pub revision: u32,
+    pub kind: DivergenceKind,
+    /// The leader's submission, for [`DivergenceKind::Payload`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader: Option<Submission>,
+}
+
+impl Divergence {
+    pub fn alert(&self) -> Alert {
+        let message = match (&self.kind, &self.leader) {
+            (DivergenceKind::Payload, Some(leader)) => format!(
+                "Replica {} answered challenge {} (revision {}) in {} with another payload than \
+                 this standby built",
+                leader.holder, self.challenge_id, self.revision, leader.tx_hash
+            ),
+            _ => format!(
+                "No replica answered challenge {} (revision {}), which this standby would have",
+                self.challenge_id, self.revision
+            ),
+        };
+        Alert::new("replica", Severity::Critical, message)
+    }
+}
+
+/// This replica's role, for `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    pub replica_id: String,
+    pub role: ReplicaRole,
+    /// The lease as last read; `None` for a single replica.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease: Option<Lease>,
+    pub since_unix_ms: u64,
+    pub divergences: u64,
+}
+
+/// Takes and renews the response lease, fences stale transactions and compares a standby's
+/// would-submit records with the leader's submissions.
+pub struct ReplicaCoordinator {
+    config: ReplicaConfig,
+    identity: String,
+    store: Option<Arc<dyn ReplicaStore>>,
+    shadow: Arc<dyn StateStore>,
+    status: RwLock<ReplicaStatus>,
+}
+
+impl ReplicaCoordinator {
+    /// Coordinates the replicas of `operator` through `store`, keeping would-submit records in
+    /// `shadow`. Without a store the replica is the only one. A replica with a store is a
+    /// standby until its first [`tick`](Self::tick).
+    pub fn new(
+        config: ReplicaConfig,
+        operator: Address,
+        store: Option<Arc<dyn ReplicaStore>>,
+        shadow: Arc<dyn StateStore>,
+    ) -> Self {
+        let role = match store {
+            Some(_) => ReplicaRole::Standby,
+            None => ReplicaRole::Single,
+        };
+        let status = ReplicaStatus {
+            replica_id: config.replica_id.clone(),
+            role,
+            lease: None,
+            since_unix_ms: now_unix_ms(),
+            divergences: 0,
+        };
+        METRICS.set_gauge(
+            LEADER_METRIC,
+            &[],
+            (role == ReplicaRole::Single) as u8 as f64,
+        );
+        Self {
+            config,
+            identity: operator.to_string().to_lowercase(),
+            store,
+            shadow,
+            status: RwLock::new(status),
+        }
+    }
+
+    /// Reads [`ReplicaConfig::from_env`] and opens its lease store.
+    pub fn from_env(operator: Address, shadow: Arc<dyn StateStore>) -> Result<Self, PhalaAvsError> {
+        let config = ReplicaConfig::from_env()?;
+        let store = config.lease.as_ref().map(open_store).transpose()?;
+        Ok(Self::new(config, operator, store, shadow))
+    }
+
+    pub fn config(&self) -> &ReplicaConfig {
+        &self.config
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        self.status
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn role(&self) -> ReplicaRole {
+        self.status.read().unwrap_or_else(|e| e.into_inner()).role
+    }
+
+    /// Whether this replica sends transactions.
+    pub fn is_leader(&self) -> bool {
+        self.role() != ReplicaRole::Standby
+    }
+
+    /// The fencing token new intents are prepared under; `None` for a single replica.
+    pub fn token(&self) -> Option<u64> {
+        let status = self.status.read().unwrap_or_else(|e| e.into_inner());
+        match status.role {
+            ReplicaRole::Leader => status.lease.as_ref().map(|lease| lease.token),
+            ReplicaRole::Single | ReplicaRole::Standby => None,
+        }
+    }
+
+    /// Takes or renews the lease, and returns the role it leaves this replica in.
+    pub fn tick(&self, now_ms: u64) -> Result<ReplicaRole, PhalaAvsError> {
+        let Some(store) = &self.store else {
+            return Ok(ReplicaRole::Single);
+        };
+        let lease = store.acquire(
+            &self.identity,
+            &self.config.replica_id,
+            self.config.ttl.as_millis() as u64,
+            now_ms,
+        )?;
+        let role = if lease.holder == self.config.replica_id && lease.is_live(now_ms) {
+            ReplicaRole::Leader
+        } else {
+            ReplicaRole::Standby
+        };
+        let mut status = self.status.write().unwrap_or_else(|e| e.into_inner());
+        let previous = status.lease.replace(lease.clone());
+        if role != status.role {
+            status.role = role;
+            status.since_unix_ms = now_ms;
+            METRICS.set_gauge(
+                LEADER_METRIC,
+                &[],
+                (role == ReplicaRole::Leader) as u8 as f64,
+            );
+            match role {
+                ReplicaRole::Leader => {
+                    if previous.is_some_and(|previous| previous.token != lease.token) {
+                        METRICS.inc_counter(TAKEOVERS_METRIC, &[], 1);
+                    }
+                    info!(
+                        "Replica {} holds the response lease (token {})",
+                        self.config.replica_id, lease.token
+                    );
+                }
+                _ => warn!(
+                    "Replica {} is standing by; replica {} holds the response lease",
+                    self.config.replica_id, lease.holder
+                ),
+            }
+        }
+        Ok(role)
+    }
+
+    /// Fails unless the lease in the store is still held by this replica under `token`, the
+    /// token an intent was prepared under. Always passes for a single replica.
+    pub fn check_fence(&self, token: Option<u64>, now_ms: u64) -> Result<(), PhalaAvsError> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let lease = store.lease(&self.identity)?;
+        let held = lease.as_ref().is_some_and(|lease| {
+            lease.holder == self.config.replica_id
+                && lease.is_live(now_ms)
+                && Some(lease.token) == token
+        });
+        if held {
+            return Ok(());
+        }
+        METRICS.inc_counter(FENCED_METRIC, &[], 1);
+        let current = match lease {
+            Some(lease) if lease.is_live(now_ms) => {
+                format!("held by {} under token {}", lease.holder, lease.token)
+            }
+            Some(lease) => format!("lapsed since {}", lease.expires_unix_ms),
+            None => "not taken".to_string(),
+        };
+        Err(PhalaAvsError::TaskError(format!(
+            "Fenced: the transaction was prepared under token {token:?}, and the response lease \
+             is {current}"
+        )))
+    }
+
+    /// Records that a standby would have sent `call`, if it answers a challenge.
+    pub fn record_would_submit(&self, call: &TxCall, now_ms: u64) -> Result<(), PhalaAvsError> {
+        let Some(challenge_id) = call.challenge_id else {
+            return Ok(());
+        };
+        info!(
+            "Standby replica {} would have sent {} for challenge {challenge_id}",
+            self.config.replica_id, call.label
+        );
+        let record = WouldSubmit {
+            challenge_id,
+            revision: call.revision,
+            label: call.label.clone(),
+            input_hash: keccak256(&call.input),
+            recorded_unix_ms: now_ms,
+        };
+        self.shadow.put_json(
+            SHADOW_NAMESPACE,
+            &shadow_key(challenge_id, call.revision),
+            &record,
+        )
+    }
+
+    /// Logs a broadcast challenge response to the shared store, for the standby to compare.
+    pub fn record_submission(
+        &self,
+        call: &TxCall,
+        tx_hash: B256,
+        token: Option<u64>,
+        now_ms: u64,
+    ) -> Result<(), PhalaAvsError> {
+        let (Some(store), Some(challenge_id)) = (&self.store, call.challenge_id) else {
+            return Ok(());
+        };
+        store.record_submission(&self.identity, &Submission {
+            challenge_id,
+            revision: call.revision,
+            input_hash: keccak256(&call.input),
+            tx_hash,
+            holder: self.config.replica_id.clone(),
+            token: token.unwrap_or_default(),
+            recorded_unix_ms: now_ms,
+        })
+    }
+
+    /// Compares the would-submit records with the leader's submissions. Records the leader
+    /// agrees with are dropped; contradicted ones, and those the leader did not answer within
+    /// the grace period, are returned and dropped as well.
+    pub fn check_divergence(&self, now_ms: u64) -> Result<Vec<Divergence>, PhalaAvsError> {
+        let Some(store) = &self.store else {
+            return Ok(Vec::new());
+        };
+        let grace_ms = self.config.divergence_grace.as_millis() as u64;
+        let mut divergences = Vec::new();
+        for (key, raw) in self.shadow.scan(SHADOW_NAMESPACE)? {
+            let record: WouldSubmit = serde_json::from_slice(&raw).map_err(|e| {
+                PhalaAvsError::StorageError(format!("Unreadable would-submit record: {e}"))
+            })?;
+            let divergence =
+                match store.submission(&self.identity, record.challenge_id, record.revision)? {
+                    Some(leader) if leader.input_hash == record.input_hash => None,
+                    Some(leader) => Some(Divergence {
+                        challenge_id: record.challenge_id,
+                        revision: record.revision,
+                        kind: DivergenceKind::Payload,
+                        leader: Some(leader),
+                    }),
+                    None if now_ms.saturating_sub(record.recorded_unix_ms) >= grace_ms => {
+                        Some(Divergence {
+                            challenge_id: record.challenge_id,
+                            revision: record.revision,
+                            kind: DivergenceKind::Missing,
+                            leader: None,
+                        })
+                    }
+                    None => continue,
+                };
+            self.shadow.delete(SHADOW_NAMESPACE, &key)?;
+            if let Some(divergence) = divergence {
+                warn!(
+                    "Replicas diverge on challenge {} (revision {}): {}",
+                    divergence.challenge_id,
+                    divergence.revision,
+                    divergence.kind.as_str()
+                );
+                METRICS.inc_counter(DIVERGENCES_METRIC, &[("kind", divergence.kind.as_str())], 1);
+                divergences.push(divergence);
+            }
+        }
+        self.status
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .divergences += divergences.len() as u64;
+        store.prune_submissions(
+            &self.identity,
+            now_ms.saturating_sub(SUBMISSION_RETENTION_MS),
+        )?;
+        Ok(divergences)
+    }
+}
+
+fn shadow_key(challenge_id: U256, revision: u32) -> Vec<u8> {
+    [
+        challenge_id.to_be_bytes::<32>().as_slice(),
+        &revision.to_be_bytes(),
+    ]
+    .concat()
+}
+
+/// Renews the lease every `REPLICA_LEASE_RENEW_MS` and alerts on divergences. Does nothing for
+/// a single replica.
+pub fn spawn_lease(coordinator: Arc<ReplicaCoordinator>, notifier: Arc<dyn Notifier>) {
+    if coordinator.store.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(coordinator.config.renew);
+        loop {
+            interval.tick().await;
+            let now_ms = now_unix_ms();
+            if let Err(e) = coordinator.tick(now_ms) {
+                warn!("Failed to renew the response lease: {e}");
+            }
+            let divergences = match coordinator.check_divergence(now_ms) {
+                Ok(divergences) => divergences,
+                Err(e) => {
+                    warn!("Failed to compare shadow responses with the leader's: {e}");
+                    continue;
+                }
+            };
+            for divergence in divergences {
+                if let Err(e) = notifier.notify(divergence.alert()).await {
+                    warn!("Failed to deliver replica divergence alert: {e}");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+
+    const OPERATOR: Address = Address::repeat_byte(0x0a);
+
+    fn replica(id: &str, store: &Arc<MemoryReplicaStore>) -> ReplicaCoordinator {
+        ReplicaCoordinator::new(
+            ReplicaConfig {
+                replica_id: id.to_string(),
+                ttl: Duration::from_millis(300),
+                renew: Duration::from_millis(100),
+                divergence_grace: Duration::from_millis(1_000),
+                ..ReplicaConfig::default()
+            },
+            OPERATOR,
+            Some(Arc::clone(store) as Arc<dyn ReplicaStore>),
+            Arc::new(MemoryStateStore::default()),
+        )
+    }
+
+    fn respond(challenge_id: u64, input: &[u8]) -> TxCall {
+        TxCall::new("respondToSlaChallenge", Address::ZERO, input.to_vec())
+            .responding_to(U256::from(challenge_id))
+    }
+
+    #[test]
+    fn one_replica_leads_and_a_standby_takes_over_once_the_lease_lapses() {
+        let store = Arc::new(MemoryReplicaStore::default());
+        let (a, b) = (replica("a", &store), replica("b", &store));
+        assert_eq!(a.tick(1_000).unwrap(), ReplicaRole::Leader);
+        assert_eq!(b.tick(1_000).unwrap(), ReplicaRole::Standby);
+        assert_eq!(a.token(), Some(1));
+        assert_eq!(b.token(), None);
+        // Renewing keeps the token.
+        assert_eq!(a.tick(1_100).unwrap(), ReplicaRole::Leader);
+        assert!(a.check_fence(Some(1), 1_150).is_ok());
+
+        // The leader stops renewing at 1 100; the lease lapses a TTL later.
+        assert_eq!(b.tick(1_399).unwrap(), ReplicaRole::Standby);
+        assert_eq!(b.tick(1_400).unwrap(), ReplicaRole::Leader);
+        assert_eq!(b.token(), Some(2));
+        assert!(a.check_fence(Some(1), 1_450).is_err());
+        assert_eq!(a.tick(1_450).unwrap(), ReplicaRole::Standby);
+        assert!(!a.is_leader());
+    }
+
+    #[test]
+    fn shadow_responses_the_leader_contradicts_are_divergences() {
+        let store = Arc::new(MemoryReplicaStore::default());
+        let (leader, standby) = (replica("a", &store), replica("b", &store));
+        leader.tick(1_000).unwrap();
+        standby.tick(1_000).unwrap();
+
+        for (id, input) in [(1, b"same"), (2, b"ours"), (3, b"late")] {
+            standby
+                .record_would_submit(&respond(id, input), 1_000)
+                .unwrap();
+        }
+        leader
+            .record_submission(
+                &respond(1, b"same"),
+                B256::repeat_byte(1),
+                leader.token(),
+                1_010,
+            )
+            .unwrap();
+        leader
+            .record_submission(
+                &respond(2, b"else"),
+                B256::repeat_byte(2),
+                leader.token(),
+                1_010,
+            )
+            .unwrap();
+
+        let divergences = standby.check_divergence(1_500).unwrap();
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].challenge_id, U256::from(2));
+        assert_eq!(divergences[0].kind, DivergenceKind::Payload);
+        // Challenge 3 is still within the grace period.
+        let divergences = standby.check_divergence(2_000).unwrap();
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].kind, DivergenceKind::Missing);
+        assert!(standby.check_divergence(3_000).unwrap().is_empty());
+        assert_eq!(standby.status().divergences, 2);
+    }
+}
@@ -0,0 +1,920 @@
+//! Signed performance summaries for delegation marketplaces.
+//!
+//! A [`ReputationSummary`] covers the trailing `REPUTATION_WINDOWS_DAYS` (30 and 90 by default)
+//! and is served at `/reputation`, or written by `reputation export`. It holds:
+//!
+//! - per window: the response rate and latency percentiles of the operator's challenges first
+//!   seen in the window and since closed, and heartbeat uptime (heartbeats in maintenance are
+//!   not counted);
+//! - an SLA score per epoch of `REPUTATION_EPOCH_BLOCKS`: the share of the challenges whose
+//!   response window closed in the epoch that were answered. Epochs without challenges or
+//!   strikes are left out;
+//! - strikes: the oracle's `SlaChallengeExpired` reports against the operator. The oracle
+//!   records a liveness failure with the service manager for each, which is the slashing hook;
+//! - the evidence roots anchored for the period;
+//! - from version 2, the status of the operator's latency objectives (see [`crate::slo`]), when
+//!   they are tracked;
+//! - from version 3, the build the operator runs (see [`crate::build_info`]);
+//! - from version 4, the challenge response reimbursement credited, claimed and clawed back (see
+//!   [`crate::costs`]), when a cost ledger is kept.
+//!
+//! The block span covering the longest window is estimated with `REPUTATION_BLOCK_SECS`.
+//!
+//! The summary is signed with the operator key (EIP-191) over its canonical serialization: the
+//! compact JSON produced by [`canonical_bytes`], fields in declaration order. Everything but the
+//! strikes and anchors is self-reported; [`verify_reputation_summary`] checks the signature and
+//! re-reads those two from the chain, so a marketplace only has to trust the rest.
+//!
+//! The document is versioned with [`REPUTATION_VERSION`]; verifiers reject versions they do not
+//! know. Older versions are the current one without the fields added since, and still verify.
+
+use crate::IPhalaServiceManager;
+use crate::IPhalaSlaOracle::SlaChallengeExpired;
+use crate::build_info::BuildInfo;
+use crate::challenge::{ChallengeState, ChallengeTracker, TrackedChallenge};
+use crate::config::{env_opt, env_or};
+use crate::costs::{CostLedger, ReimbursementTotals};
+use crate::error::PhalaAvsError;
+use crate::evidence::{
+    ANCHOR_NAMESPACE, AnchoredWindow, EvidenceLog, HEARTBEAT_EVIDENCE, HeartbeatEvidence,
+};
+use crate::evm::BoxFuture;
+use crate::self_audit::{LatencySummary, responded};
+use crate::slo::{SloMonitor, SloStatus};
+use crate::state::StateStore;
+use crate::{SERVICE_MANAGER_ADDRESS, SLA_ORACLE_ADDRESS};
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, PrimitiveSignature, U256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::Filter;
+use blueprint_sdk::alloy::signers::SignerSync;
+use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+use blueprint_sdk::alloy::sol_types::SolEvent;
+use blueprint_sdk::evm::util::get_provider_http;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// Version of the summary document format.
+pub const REPUTATION_VERSION: u32 = 4;
+/// Versions verifiers accept.
+pub const SUPPORTED_REPUTATION_VERSIONS: &[u32] = &[1, 2, 3, 4];
+
+const DAY_MS: u64 = 86_400_000;
+
+#[derive(Clone, Debug)]
+pub struct ReputationConfig {
+    /// Trailing windows reported, in days.
+    pub windows_days: Vec<u64>,
+    pub epoch_blocks: u64,
+    /// Average block time, to find the blocks of the longest window.
+    pub block_secs: u64,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            windows_days: vec![30, 90],
+            epoch_blocks: 7_200,
+            block_secs: 12,
+        }
+    }
+}
+
+impl ReputationConfig {
+    /// Reads `REPUTATION_WINDOWS_DAYS` (comma-separated), `REPUTATION_EPOCH_BLOCKS` and
+    /// `REPUTATION_BLOCK_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let default = Self::default();
+        let windows_days = match env_opt::<String>("REPUTATION_WINDOWS_DAYS")? {
+            Some(raw) => raw
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|days| {
+                    days.parse().map_err(|e| {
+                        PhalaAvsError::ConfigError(format!(
+                            "Invalid REPUTATION_WINDOWS_DAYS entry {days}: {e}"
+                        ))
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            None => default.windows_days,
+        };
+        let config = Self {
+            windows_days,
+            epoch_blocks: env_or("REPUTATION_EPOCH_BLOCKS", default.epoch_blocks)?,
+            block_secs: env_or("REPUTATION_BLOCK_SECS", default.block_secs)?,
+        };
+        if config.windows_days.is_empty() || config.windows_days.contains(&0) {
+            return Err(PhalaAvsError::ConfigError(
+                "REPUTATION_WINDOWS_DAYS needs at least one window of a day or more".to_string(),
+            ));
+        }
+        if config.epoch_blocks == 0 || config.block_secs == 0 {
+            return Err(PhalaAvsError::ConfigError(
+                "REPUTATION_EPOCH_BLOCKS and REPUTATION_BLOCK_SECS must be at least 1".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    fn longest_days(&self) -> u64 {
+        self.windows_days.iter().copied().max().unwrap_or_default()
+    }
+}
+
+/// Performance over one trailing window.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindowStats {
+    pub days: u64,
+    pub from_unix_ms: u64,
+    pub to_unix_ms: u64,
+    /// Challenges first seen in the window whose response window has closed.
+    pub challenges: u64,
+    pub responded: u64,
+    /// `None` without challenges.
+    pub response_rate_bps: Option<u32>,
+    /// Milliseconds from first sight to the response's inclusion.
+    pub latency_ms: LatencySummary,
+    /// Heartbeats outside maintenance.
+    pub heartbeats: u64,
+    pub live_heartbeats: u64,
+    /// `None` without heartbeats.
+    pub uptime_bps: Option<u32>,
+}
+
+/// The SLA score of one epoch.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EpochScore {
+    pub epoch: u64,
+    /// Challenges whose response window closed in the epoch.
+    pub challenges: u64,
+    pub responded: u64,
+    pub strikes: u64,
+    /// Answered share of the challenges; `None` without challenges.
+    pub score_bps: Option<u32>,
+}
+
+/// A challenge the oracle reported as expired unanswered.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Strike {
+    pub challenge_id: U256,
+    pub block: u64,
+}
+
+/// An evidence root anchored for the period.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnchorRef {
+    pub window_id: u64,
+    pub from_unix: u64,
+    pub to_unix: u64,
+    pub root: B256,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReputationSummary {
+    pub version: u32,
+    pub operator: Address,
+    pub chain_id: u64,
+    pub generated_unix_ms: u64,
+    /// Blocks covered by the epochs and strikes, `[from_block, to_block)`.
+    pub from_block: u64,
+    pub to_block: u64,
+    pub epoch_blocks: u64,
+    pub windows: Vec<WindowStats>,
+    pub epochs: Vec<EpochScore>,
+    pub strikes: Vec<Strike>,
+    pub anchors: Vec<AnchorRef>,
+    /// Self-reported, like the windows.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slo: Vec<SloStatus>,
+    /// Self-reported; a quote from the operator attests it (see [`crate::build_info`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildInfo>,
+    /// Self-reported; clawbacks can be checked against the service manager's events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reimbursement: Option<ReimbursementTotals>,
+}
+
+/// A summary with the operator's signature over its [`canonical_bytes`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignedReputationSummary {
+    pub summary: ReputationSummary,
+    pub signature: Bytes,
+}
+
+impl SignedReputationSummary {
+    /// The account that signed the summary.
+    pub fn signer(&self) -> Result<Address, PhalaAvsError> {
+        let signature = PrimitiveSignature::try_from(self.signature.as_ref())
+            .map_err(|e| PhalaAvsError::ValidationError(format!("Malformed signature: {e}")))?;
+        signature
+            .recover_address_from_msg(canonical_bytes(&self.summary)?)
+            .map_err(|e| PhalaAvsError::ValidationError(format!("Unrecoverable signature: {e}")))
+    }
+}
+
+/// The bytes a summary is signed over.
+pub fn canonical_bytes(summary: &ReputationSummary) -> Result<Vec<u8>, PhalaAvsError> {
+    serde_json::to_vec(summary)
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to serialize the summary: {e}")))
+}
+
+fn bps(part: u64, whole: u64) -> Option<u32> {
+    (whole > 0).then(|| (part.saturating_mul(10_000) / whole) as u32)
+}
+
+/// The on-chain records a summary is cross-checked against.
+pub trait ReputationChain: Send + Sync {
+    /// `SlaChallengeExpired` reports against `operator` in `[from_block, to_block)`.
+    fn strikes(
+        &self,
+        operator: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<Vec<Strike>, PhalaAvsError>>;
+
+    /// The root `operator` anchored for `window_id`, zero if none.
+    fn anchored_root(
+        &self,
+        operator: Address,
+        window_id: u64,
+    ) -> BoxFuture<'_, Result<B256, PhalaAvsError>>;
+}
+
+/// [`ReputationChain`] read from the oracle and service manager contracts.
+#[derive(Clone, Debug)]
+pub struct ContractReputationChain {
+    oracle: Address,
+    service_manager: Address,
+    rpc_url: String,
+}
+
+impl ContractReputationChain {
+    pub fn new(oracle: Address, service_manager: Address, rpc_url: String) -> Self {
+        Self {
+            oracle,
+            service_manager,
+            rpc_url,
+        }
+    }
+
+    /// Uses `SLA_ORACLE_ADDRESS` and `SERVICE_MANAGER_ADDRESS`.
+    pub fn from_env(rpc_url: String) -> Self {
+        Self::new(*SLA_ORACLE_ADDRESS, *SERVICE_MANAGER_ADDRESS, rpc_url)
+    }
+}
+
+fn evm_err(call: &str, e: impl std::fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::EvmError(format!("{call} failed: {e}"))
+}
+
+impl ReputationChain for ContractReputationChain {
+    fn strikes(
+        &self,
+        operator: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<Vec<Strike>, PhalaAvsError>> {
+        Box::pin(async move {
+            if to_block <= from_block {
+                return Ok(Vec::new());
+            }
+            let provider = get_provider_http(&self.rpc_url);
+            let filter = Filter::new()
+                .address(self.oracle)
+                .event_signature(SlaChallengeExpired::SIGNATURE_HASH)
+                .topic2(operator.into_word())
+                .from_block(from_block)
+                .to_block(to_block - 1);
+            let logs = provider
+                .get_logs(&filter)
+                .await
+                .map_err(|e| evm_err("eth_getLogs", e))?;
+            let mut strikes: Vec<Strike> = logs
+                .iter()
+                .filter_map(|log| {
+                    let event = log.log_decode::<SlaChallengeExpired>().ok()?;
+                    Some(Strike {
+                        challenge_id: event.inner.data.challengeId,
+                        block: log.block_number?,
+                    })
+                })
+                .collect();
+            strikes.sort();
+            Ok(strikes)
+        })
+    }
+
+    fn anchored_root(
+        &self,
+        operator: Address,
+        window_id: u64,
+    ) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            Ok(IPhalaServiceManager::new(self.service_manager, provider)
+                .evidenceRoots(operator, window_id)
+                .call()
+                .await
+                .map_err(|e| evm_err("evidenceRoots", e))?
+                ._0)
+        })
+    }
+}
+
+/// Builds and signs the operator's summaries.
+pub struct ReputationReporter {
+    config: ReputationConfig,
+    chain_id: u64,
+    store: Arc<dyn StateStore>,
+    evidence: EvidenceLog,
+    tracker: Arc<ChallengeTracker>,
+    chain: Arc<dyn ReputationChain>,
+    signer: PrivateKeySigner,
+    slo: Option<Arc<SloMonitor>>,
+    costs: Option<Arc<CostLedger>>,
+}
+
+impl ReputationReporter {
+    pub fn new(
+        config: ReputationConfig,
+        chain_id: u64,
+        store: Arc<dyn StateStore>,
+        tracker: Arc<ChallengeTracker>,
+        chain: Arc<dyn ReputationChain>,
+        signer: PrivateKeySigner,
+    ) -> Self {
+        Self {
+            config,
+            chain_id,
+            evidence: EvidenceLog::new(Arc::clone(&store)),
+            store,
+            tracker,
+            chain,
+            signer,
+            slo: None,
+            costs: None,
+        }
+    }
+
+    /// Includes the latest evaluation of `monitor`'s objectives in summaries.
+    pub fn with_slo(mut self, monitor: Arc<SloMonitor>) -> Self {
+        self.slo = Some(monitor);
+        self
+    }
+
+    /// Includes the reimbursement totals of `costs` in summaries.
+    pub fn with_costs(mut self, costs: Arc<CostLedger>) -> Self {
+        self.costs = Some(costs);
+        self
+    }
+
+    pub fn config(&self) -> &ReputationConfig {
+        &self.config
+    }
+
+    pub fn operator(&self) -> Address {
+        self.signer.address()
+    }
+
+    /// The summary up to block `head` at `now_ms`, unsigned.
+    pub async fn summary(
+        &self,
+        head: u64,
+        now_ms: u64,
+    ) -> Result<ReputationSummary, PhalaAvsError> {
+        let operator = self.operator();
+        let span_blocks = self.config.longest_days() * 86_400 / self.config.block_secs;
+        let from_block = head.saturating_sub(span_blocks);
+        let closed: Vec<TrackedChallenge> = self
+            .tracker
+            .closed_between(0, head)?
+            .into_iter()
+            .filter(|t| t.challenge.operator == operator)
+            .collect();
+        let strikes = self.chain.strikes(operator, from_block, head).await?;
+
+        let mut windows = Vec::new();
+        for &days in &self.config.windows_days {
+            windows.push(self.window(days, now_ms, &closed)?);
+        }
+
+        let epoch_of = |block: u64| block / self.config.epoch_blocks;
+        let mut epochs = Vec::new();
+        for epoch in epoch_of(from_block)..=epoch_of(head.saturating_sub(1)) {
+            let in_epoch =
+                |block: u64| epoch_of(block) == epoch && (from_block..head).contains(&block);
+            let challenges: Vec<_> = closed
+                .iter()
+                .filter(|t| in_epoch(t.challenge.deadline_block))
+                .collect();
+            let responded = challenges.iter().filter(|t| responded(t).is_some()).count() as u64;
+            let struck = strikes.iter().filter(|s| in_epoch(s.block)).count() as u64;
+            if challenges.is_empty() && struck == 0 {
+                continue;
+            }
+            epochs.push(EpochScore {
+                epoch,
+                challenges: challenges.len() as u64,
+                responded,
+                strikes: struck,
+                score_bps: bps(responded, challenges.len() as u64),
+            });
+        }
+
+        let from_ms = now_ms.saturating_sub(self.config.longest_days() * DAY_MS);
+        let anchors = self
+            .store
+            .scan(ANCHOR_NAMESPACE)?
+            .into_iter()
+            // The anchoring cursor shares the namespace under a non-window key.
+            .filter(|(key, _)| key.len() == 8)
+            .filter_map(|(_, raw)| serde_json::from_slice::<AnchoredWindow>(&raw).ok())
+            .filter(|w| w.anchored && w.to_unix * 1000 > from_ms && w.from_unix * 1000 < now_ms)
+            .map(|w| AnchorRef {
+                window_id: w.window_id,
+                from_unix: w.from_unix,
+                to_unix: w.to_unix,
+                root: w.root,
+            })
+            .collect();
+
+        Ok(ReputationSummary {
+            version: REPUTATION_VERSION,
+            operator,
+            chain_id: self.chain_id,
+            generated_unix_ms: now_ms,
+            from_block,
+            to_block: head,
+            epoch_blocks: self.config.epoch_blocks,
+            windows,
+            epochs,
+            strikes,
+            anchors,
+            slo: self
+                .slo
+                .as_ref()
+                .map(|monitor| monitor.evaluate(head, now_ms))
+                .transpose()?
+                .unwrap_or_default(),
+            build: Some(BuildInfo::current().clone()),
+            reimbursement: self
+                .costs
+                .as_ref()
+                .map(|costs| costs.totals())
+                .transpose()?
+                .map(|totals| totals.reimbursement),
+        })
+    }
+
+    fn window(
+        &self,
+        days: u64,
+        now_ms: u64,
+        closed: &[TrackedChallenge],
+    ) -> Result<WindowStats, PhalaAvsError> {
+        let from_ms = now_ms.saturating_sub(days * DAY_MS);
+        // Challenges the oracle withdrew asked nothing of the operator.
+        let seen: Vec<_> = closed
+            .iter()
+            .filter(|t| t.state != ChallengeState::Cancelled)
+            .filter(|t| (from_ms..=now_ms).contains(&t.first_seen_unix_ms))
+            .collect();
+        let latencies: Vec<u64> = seen
+            .iter()
+            .filter_map(|t| responded(t).map(|at| at.saturating_sub(t.latency_origin_ms())))
+            .collect();
+        let mut heartbeats = 0;
+        let mut live_heartbeats = 0;
+        for (_, raw) in self.evidence.records(HEARTBEAT_EVIDENCE, from_ms, now_ms)? {
+            let Ok(heartbeat) = serde_json::from_slice::<HeartbeatEvidence>(&raw) else {
+                continue;
+            };
+            if heartbeat.in_maintenance {
+                continue;
+            }
+            heartbeats += 1;
+            if heartbeat.live == Some(true) {
+                live_heartbeats += 1;
+            }
+        }
+        Ok(WindowStats {
+            days,
+            from_unix_ms: from_ms,
+            to_unix_ms: now_ms,
+            challenges: seen.len() as u64,
+            responded: latencies.len() as u64,
+            response_rate_bps: bps(latencies.len() as u64, seen.len() as u64),
+            latency_ms: LatencySummary::of(latencies),
+            heartbeats,
+            live_heartbeats,
+            uptime_bps: bps(live_heartbeats, heartbeats),
+        })
+    }
+
+    /// The summary up to block `head` at `now_ms`, signed with the operator key.
+    pub async fn export(
+        &self,
+        head: u64,
+        now_ms: u64,
+    ) -> Result<SignedReputationSummary, PhalaAvsError> {
+        let summary = self.summary(head, now_ms).await?;
+        let signature = self
+            .signer
+            .sign_message_sync(&canonical_bytes(&summary)?)
+            .map_err(|e| PhalaAvsError::Other(format!("Failed to sign the summary: {e}")))?;
+        Ok(SignedReputationSummary {
+            summary,
+            signature: Bytes::copy_from_slice(&signature.as_bytes()),
+        })
+    }
+}
+
+/// Checks that `signed` is a summary of a known version signed by its operator, and that its
+/// strikes and anchored roots match `chain`.
+pub async fn verify_reputation_summary(
+    signed: &SignedReputationSummary,
+    chain: &dyn ReputationChain,
+) -> Result<(), PhalaAvsError> {
+    let summary = &signed.summary;
+    if !SUPPORTED_REPUTATION_VERSIONS.contains(&summary.version) {
+        return Err(PhalaAvsError::ValidationError(format!(
+            "Unsupported reputation summary version {}",
+            summary.version
+        )));
+    }
+    let signer = signed.signer()?;
+    if signer != summary.operator {
+        return Err(PhalaAvsError::ValidationError(format!(
+            "The summary of {} is signed by {signer}",
+            summary.operator
+        )));
+    }
+
+    let claimed: BTreeSet<_> = summary.strikes.iter().collect();
+    let on_chain = chain
+        .strikes(summary.operator, summary.from_block, summary.to_block)
+        .await?;
+    let on_chain: BTreeSet<_> = on_chain.iter().collect();
+    if claimed != on_chain {
+        let omitted: Vec<String> = on_chain
+            .difference(&claimed)
+            .map(|s| s.challenge_id.to_string())
+            .collect();
+        let invented: Vec<String> = claimed
+            .difference(&on_chain)
+            .map(|s| s.challenge_id.to_string())
+            .collect();
+        return Err(PhalaAvsError::ValidationError(format!(
+            "Strikes differ from the chain: omitted [{}], not on-chain [{}]",
+            omitted.join(", "),
+            invented.join(", ")
+        )));
+    }
+
+    for anchor in &summary.anchors {
+        let root = chain
+            .anchored_root(summary.operator, anchor.window_id)
+            .await?;
+        if root != anchor.root {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Window {} is anchored on-chain with root {root}, the summary claims {}",
+                anchor.window_id, anchor.root
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Fetches a signed summary from a running operator.
+pub async fn fetch_reputation(
+    operator_url: &str,
+    token: &str,
+) -> Result<SignedReputationSummary, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/reputation", operator_url.trim_end_matches('/')))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(PhalaAvsError::Other(format!(
+            "Operator returned {status} for the reputation summary: {body}"
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to read the reputation summary: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::ConfirmationPolicy;
+    use crate::evidence::now_unix_ms;
+    use crate::fixtures::ChallengeEventFixture;
+    use crate::slo::{ResponseIndex, SloConfig};
+    use crate::state::{MemoryStateStore, StateStoreExt};
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const HEAD: u64 = 20_000;
+
+    /// A chain holding the strikes and anchored roots it was seeded with.
+    #[derive(Default)]
+    struct SimulatedChain {
+        strikes: Mutex<Vec<Strike>>,
+        roots: Mutex<BTreeMap<u64, B256>>,
+    }
+
+    impl ReputationChain for SimulatedChain {
+        fn strikes(
+            &self,
+            _operator: Address,
+            from_block: u64,
+            to_block: u64,
+        ) -> BoxFuture<'_, Result<Vec<Strike>, PhalaAvsError>> {
+            let mut strikes: Vec<Strike> = self
+                .strikes
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|s| (from_block..to_block).contains(&s.block))
+                .cloned()
+                .collect();
+            strikes.sort();
+            Box::pin(async move { Ok(strikes) })
+        }
+
+        fn anchored_root(
+            &self,
+            _operator: Address,
+            window_id: u64,
+        ) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            let root = self
+                .roots
+                .lock()
+                .unwrap()
+                .get(&window_id)
+                .copied()
+                .unwrap_or_default();
+            Box::pin(async move { Ok(root) })
+        }
+    }
+
+    fn track(tracker: &ChallengeTracker, operator: Address, id: u64, block: u64, answer: bool) {
+        let challenge = ChallengeEventFixture::new()
+            .id(id)
+            .operator(operator)
+            .block(block)
+            .build_observed();
+        tracker.observe(challenge, block).unwrap();
+        let path: &[ChallengeState] = if answer {
+            &[
+                ChallengeState::Queued,
+                ChallengeState::Building,
+                ChallengeState::Submitting,
+                ChallengeState::AwaitingInclusion,
+                ChallengeState::Responded,
+            ]
+        } else {
+            &[ChallengeState::Missed]
+        };
+        for &state in path {
+            tracker.transition(U256::from(id), state, "test").unwrap();
+        }
+    }
+
+    /// A reporter over three answered challenges, one missed and struck, heartbeats and an
+    /// anchored window.
+    fn seeded() -> (ReputationReporter, Arc<SimulatedChain>) {
+        let signer: PrivateKeySigner = KEY.parse().unwrap();
+        let operator = signer.address();
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker = Arc::new(
+            ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap(),
+        );
+        for (id, block) in [(1, 100), (2, 7_300), (3, 7_400)] {
+            track(&tracker, operator, id, block, true);
+        }
+        track(&tracker, operator, 4, 7_500, false);
+        // Another operator's challenge is not counted.
+        track(&tracker, Address::repeat_byte(9), 5, 7_600, true);
+
+        let now = now_unix_ms();
+        let evidence = EvidenceLog::new(Arc::clone(&store));
+        for (i, (live, in_maintenance)) in [
+            (Some(true), false),
+            (Some(true), false),
+            (Some(false), false),
+            (None, false),
+            (Some(false), true),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let unix_ms = now - 1_000 * (i as u64 + 1);
+            let heartbeat = HeartbeatEvidence {
+                unix_ms,
+                live,
+                in_maintenance,
+                health_score: None,
+                healthy: None,
+            };
+            evidence
+                .record(HEARTBEAT_EVIDENCE, unix_ms, &[], &heartbeat)
+                .unwrap();
+        }
+
+        let root = B256::repeat_byte(0xab);
+        let window = AnchoredWindow {
+            window_id: 7,
+            from_unix: now / 1000 - 3_600,
+            to_unix: now / 1000,
+            root,
+            leaves: Vec::new(),
+            anchored: true,
+            transaction_hash: None,
+        };
+        store
+            .put_json(ANCHOR_NAMESPACE, &7u64.to_be_bytes(), &window)
+            .unwrap();
+        store.put_json(ANCHOR_NAMESPACE, b"cursor", &7u64).unwrap();
+
+        let chain = Arc::new(SimulatedChain::default());
+        chain.strikes.lock().unwrap().push(Strike {
+            challenge_id: U256::from(4),
+            block: 7_560,
+        });
+        chain.roots.lock().unwrap().insert(7, root);
+        let reporter = ReputationReporter::new(
+            ReputationConfig::default(),
+            31337,
+            store,
+            tracker,
+            Arc::clone(&chain) as _,
+            signer,
+        );
+        (reporter, chain)
+    }
+
+    #[tokio::test]
+    async fn seeded_summary_is_signed_and_verifies_against_the_chain() {
+        let (reporter, chain) = seeded();
+        let signed = reporter.export(HEAD, now_unix_ms()).await.unwrap();
+        let summary = &signed.summary;
+
+        assert_eq!(summary.operator, reporter.operator());
+        assert_eq!(summary.windows.len(), 2);
+        for window in &summary.windows {
+            assert_eq!(window.challenges, 4);
+            assert_eq!(window.responded, 3);
+            assert_eq!(window.response_rate_bps, Some(7_500));
+            assert_eq!(window.latency_ms.count, 3);
+            assert_eq!(window.heartbeats, 4);
+            assert_eq!(window.live_heartbeats, 2);
+            assert_eq!(window.uptime_bps, Some(5_000));
+        }
+        // Challenge 1 closes in epoch 0, the rest in epoch 1.
+        let scores: Vec<_> = summary
+            .epochs
+            .iter()
+            .map(|e| (e.epoch, e.challenges, e.strikes, e.score_bps))
+            .collect();
+        assert_eq!(scores, vec![
+            (0, 1, 0, Some(10_000)),
+            (1, 3, 1, Some(6_666))
+        ]);
+        assert_eq!(summary.strikes.len(), 1);
+        assert_eq!(summary.anchors.len(), 1);
+
+        assert_eq!(signed.signer().unwrap(), reporter.operator());
+        verify_reputation_summary(&signed, chain.as_ref())
+            .await
+            .unwrap();
+
+        // The document survives the round trip through its JSON form.
+        let json = serde_json::to_string(&signed).unwrap();
+        let parsed: SignedReputationSummary = serde_json::from_str(&json).unwrap();
+        verify_reputation_summary(&parsed, chain.as_ref())
+            .await
+            .unwrap();
+    }
+
+    struct NoInclusions;
+
+    impl ResponseIndex for NoInclusions {
+        fn inclusion_blocks(&self) -> Result<BTreeMap<U256, u64>, PhalaAvsError> {
+            Ok(BTreeMap::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn objectives_are_signed_in_and_version_1_still_verifies() {
+        let (reporter, chain) = seeded();
+        let monitor = SloMonitor::new(
+            SloConfig::default(),
+            reporter.operator(),
+            Arc::clone(&reporter.tracker),
+            Arc::new(NoInclusions),
+        );
+        let reporter = reporter.with_slo(Arc::new(monitor));
+        let now = now_unix_ms() + 1;
+        let signed = reporter.export(HEAD, now).await.unwrap();
+        let slo = &signed.summary.slo;
+        assert_eq!(slo.len(), 1);
+        // The missed challenge is bad, the answered ones were timely.
+        assert_eq!((slo[0].total, slo[0].good), (4, 3));
+        assert_eq!(signed.summary.build.as_ref(), Some(BuildInfo::current()));
+        verify_reputation_summary(&signed, chain.as_ref())
+            .await
+            .unwrap();
+
+        let mut v1 = reporter.summary(HEAD, now).await.unwrap();
+        v1.version = 1;
+        v1.slo.clear();
+        v1.build = None;
+        v1.reimbursement = None;
+        let signature = reporter
+            .signer
+            .sign_message_sync(&canonical_bytes(&v1).unwrap())
+            .unwrap();
+        let v1 = SignedReputationSummary {
+            summary: v1,
+            signature: Bytes::copy_from_slice(&signature.as_bytes()),
+        };
+        let json = serde_json::to_string(&v1).unwrap();
+        assert!(!json.contains("slo") && !json.contains("build"));
+        verify_reputation_summary(&v1, chain.as_ref())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tampered_summary_fails_verification() {
+        let (reporter, chain) = seeded();
+        let signed = reporter.export(HEAD, now_unix_ms()).await.unwrap();
+
+        let mut tampered = signed.clone();
+        tampered.summary.windows[0].response_rate_bps = Some(10_000);
+        let err = verify_reputation_summary(&tampered, chain.as_ref())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PhalaAvsError::ValidationError(_)), "{err}");
+
+        let mut unknown = signed.clone();
+        unknown.summary.version = REPUTATION_VERSION + 1;
+        assert!(
+            verify_reputation_summary(&unknown, chain.as_ref())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn strikes_and_anchors_are_cross_checked_against_the_chain() {
+        let (reporter, chain) = seeded();
+        let signed = reporter.export(HEAD, now_unix_ms()).await.unwrap();
+
+        // A strike recorded on-chain that the summary does not carry.
+        chain.strikes.lock().unwrap().push(Strike {
+            challenge_id: U256::from(8),
+            block: 9_000,
+        });
+        let err = verify_reputation_summary(&signed, chain.as_ref())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("omitted [8]"), "{err}");
+        chain.strikes.lock().unwrap().pop();
+
+        // Strikes outside the summarized blocks are not the summary's to report.
+        chain.strikes.lock().unwrap().push(Strike {
+            challenge_id: U256::from(9),
+            block: HEAD,
+        });
+        verify_reputation_summary(&signed, chain.as_ref())
+            .await
+            .unwrap();
+
+        chain
+            .roots
+            .lock()
+            .unwrap()
+            .insert(7, B256::repeat_byte(0xcd));
+        let err = verify_reputation_summary(&signed, chain.as_ref())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Window 7"), "{err}");
+    }
+}
@@ -0,0 +1,868 @@
+//! Safety checks run before signing an aggregator task response.
+//!
+//! A wrong response to a task is slashable, so [`ResponseSafety::evaluate`] gates the signature:
+//!
+//! - the response is re-derived through the [`RedundancyCheck`] registered for the task kind, a
+//!   computation path independent of the one that produced it, and must match;
+//! - the digests other parties hold for the task are gathered from every [`PeerSource`]: trusted
+//!   operators' `/task-responses/{kind}/{index}`, and the aggregator's `get_majority_digest`.
+//!   With at least `RESPONSE_SAFETY_MIN_OBSERVATIONS` of them, a single other digest holding
+//!   `RESPONSE_SAFETY_MAJORITY_BPS` of the observations is a majority disagreement.
+//!
+//! A disagreement blocks the signature: a [`Review`] is recorded, an alert raised, and the response
+//! stays unsigned until an admin overrides the review at
+//! `POST /admin/response-safety/{id}/override`, an approval-gated action. Peers are queried
+//! concurrently, each bounded by `RESPONSE_SAFETY_TIMEOUT_MS` so the check fits in response
+//! deadlines; one that fails or is too slow is left out. Each evaluation's latency is observed in
+//! [`SAFETY_SECONDS_METRIC`]. Task kinds listed in `RESPONSE_SAFETY_SKIP_KINDS` are signed
+//! without checks.
+
+use crate::config::{self, env_or};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{B256, Bytes, keccak256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Histogram of evaluation latency in seconds, by `kind`.
+pub const SAFETY_SECONDS_METRIC: &str = "phala_avs_response_safety_seconds";
+/// Counter of evaluations by `kind` and `verdict`.
+pub const SAFETY_VERDICTS_METRIC: &str = "phala_avs_response_safety_verdicts_total";
+
+/// Blocked responses waiting for, or past, manual review, by review id.
+const REVIEWS_NAMESPACE: &str = "response_safety_reviews";
+/// Digests of the responses cleared for signing, by `kind/task_index`.
+const DIGESTS_NAMESPACE: &str = "response_safety_digests";
+
+#[derive(Clone, Debug)]
+pub struct SafetyConfig {
+    /// Task kinds signed without checks.
+    pub skip_kinds: BTreeSet<String>,
+    /// Status servers of trusted operators to compare with.
+    pub peers: Vec<String>,
+    /// API key the peers issued this operator.
+    pub peer_token: Option<String>,
+    /// Aggregator JSON-RPC endpoint asked for its majority digest.
+    pub aggregator_url: Option<String>,
+    /// Observations needed before a majority is trusted.
+    pub min_observations: u32,
+    /// Share of the observations, in basis points, another digest needs to block.
+    pub majority_bps: u32,
+    /// Bound on each peer query.
+    pub peer_timeout: Duration,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        Self {
+            skip_kinds: BTreeSet::new(),
+            peers: Vec::new(),
+            peer_token: None,
+            aggregator_url: None,
+            min_observations: 3,
+            majority_bps: 6_667,
+            peer_timeout: Duration::from_millis(1_500),
+        }
+    }
+}
+
+impl SafetyConfig {
+    /// Reads `RESPONSE_SAFETY_SKIP_KINDS` and `RESPONSE_SAFETY_PEERS` (comma-separated),
+    /// `RESPONSE_SAFETY_PEER_TOKEN`, `RESPONSE_SAFETY_AGGREGATOR_URL`,
+    /// `RESPONSE_SAFETY_MIN_OBSERVATIONS`, `RESPONSE_SAFETY_MAJORITY_BPS` and
+    /// `RESPONSE_SAFETY_TIMEOUT_MS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        let list = |key: &str| -> Result<Vec<String>, PhalaAvsError> {
+            Ok(env_or(key, String::new())?
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect())
+        };
+        let majority_bps = env_or("RESPONSE_SAFETY_MAJORITY_BPS", defaults.majority_bps)?;
+        if !(1..=10_000).contains(&majority_bps) {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "RESPONSE_SAFETY_MAJORITY_BPS must be between 1 and 10000, got {majority_bps}"
+            )));
+        }
+        Ok(Self {
+            skip_kinds: list("RESPONSE_SAFETY_SKIP_KINDS")?.into_iter().collect(),
+            peers: list("RESPONSE_SAFETY_PEERS")?,
+            peer_token: config::lookup("RESPONSE_SAFETY_PEER_TOKEN"),
+            aggregator_url: config::lookup("RESPONSE_SAFETY_AGGREGATOR_URL"),
+            min_observations: env_or(
+                "RESPONSE_SAFETY_MIN_OBSERVATIONS",
+                defaults.min_observations,
+            )?
+            .max(1),
+            majority_bps,
+            peer_timeout: Duration::from_millis(env_or(
+                "RESPONSE_SAFETY_TIMEOUT_MS",
+                defaults.peer_timeout.as_millis() as u64,
+            )?),
+        })
+    }
+}
+
+/// A task response about to be signed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub kind: String,
+    pub task_index: u32,
+    /// The ABI-encoded task.
+    pub task: Bytes,
+    /// The ABI-encoded response.
+    pub response: Bytes,
+}
+
+impl Candidate {
+    /// Digest of the response, as the aggregator computes it.
+    pub fn digest(&self) -> B256 {
+        keccak256(&self.response)
+    }
+
+    /// Id of the review of this exact response.
+    pub fn review_id(&self) -> B256 {
+        let mut buf = self.kind.as_bytes().to_vec();
+        buf.extend_from_slice(&self.task_index.to_be_bytes());
+        buf.extend_from_slice(self.digest().as_slice());
+        keccak256(buf)
+    }
+}
+
+/// Re-derives a task kind's response independently of the path that produced it.
+pub trait RedundancyCheck: Send + Sync {
+    /// The ABI-encoded response `candidate.task` should get.
+    fn recompute<'a>(
+        &'a self,
+        candidate: &'a Candidate,
+    ) -> BoxFuture<'a, Result<Bytes, PhalaAvsError>>;
+}
+
+/// How many times a digest was seen for a task.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DigestCount {
+    pub digest: B256,
+    pub count: u32,
+}
+
+/// Somewhere other parties' responses to a task can be observed.
+pub trait PeerSource: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// The digests seen for `task_index` of `kind`; empty when there are none yet.
+    fn observe<'a>(
+        &'a self,
+        kind: &'a str,
+        task_index: u32,
+    ) -> BoxFuture<'a, Result<Vec<DigestCount>, PhalaAvsError>>;
+}
+
+/// Why a response was not cleared for signing.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "check", rename_all = "snake_case")]
+pub enum Disagreement {
+    /// The redundancy check derived a different response.
+    Redundancy { expected: B256 },
+    /// The redundancy check could not run.
+    RedundancyFailed { error: String },
+    /// Another digest holds the majority of the observations.
+    Majority {
+        digest: B256,
+        share_bps: u32,
+        observations: u32,
+    },
+}
+
+/// The outcome of an evaluation.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "verdict", rename_all = "snake_case")]
+pub enum Verdict {
+    /// Every check agreed; sign.
+    Sign,
+    /// The task kind is exempt by policy; sign.
+    Skipped,
+    /// A disagreement was overridden by an admin; sign.
+    Overridden,
+    /// Do not sign until the review is overridden.
+    Blocked { review: B256, reason: Disagreement },
+}
+
+impl Verdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sign => "sign",
+            Self::Skipped => "skipped",
+            Self::Overridden => "overridden",
+            Self::Blocked { .. } => "blocked",
+        }
+    }
+
+    /// Whether the response may be signed.
+    pub fn permits_signing(&self) -> bool {
+        !matches!(self, Self::Blocked { .. })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewStatus {
+    Pending,
+    /// An admin cleared the response for signing.
+    Overridden,
+    /// A later evaluation agreed, so the response was signed without an override.
+    Cleared,
+}
+
+/// A blocked response recorded for manual review.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Review {
+    pub id: B256,
+    pub kind: String,
+    pub task_index: u32,
+    pub digest: B256,
+    pub reason: Disagreement,
+    /// What each peer reported.
+    pub observations: BTreeMap<String, Vec<DigestCount>>,
+    pub status: ReviewStatus,
+    pub created_unix_ms: u64,
+    pub decided_unix_ms: Option<u64>,
+}
+
+/// Gates task response signatures on redundancy and majority checks.
+pub struct ResponseSafety {
+    config: SafetyConfig,
+    redundancy: HashMap<String, Arc<dyn RedundancyCheck>>,
+    peers: Vec<Arc<dyn PeerSource>>,
+    store: Arc<dyn StateStore>,
+    notifier: Arc<dyn Notifier>,
+}
+
+impl ResponseSafety {
+    /// Consults the configured peers and aggregator; redundancy checks are added per kind with
+    /// [`with_redundancy`](Self::with_redundancy).
+    pub fn new(
+        config: SafetyConfig,
+        store: Arc<dyn StateStore>,
+        notifier: Arc<dyn Notifier>,
+    ) -> Self {
+        let peers = config
+            .peers
+            .iter()
+            .map(|url| {
+                Arc::new(HttpPeer::new(url.clone(), config.peer_token.clone()))
+                    as Arc<dyn PeerSource>
+            })
+            .chain(aggregator_peer(&config))
+            .collect();
+        Self {
+            config,
+            redundancy: HashMap::new(),
+            peers,
+            store,
+            notifier,
+        }
+    }
+
+    pub fn with_redundancy(mut self, kind: &str, check: Arc<dyn RedundancyCheck>) -> Self {
+        self.redundancy.insert(kind.to_string(), check);
+        self
+    }
+
+    pub fn with_peer(mut self, peer: Arc<dyn PeerSource>) -> Self {
+        self.peers.push(peer);
+        self
+    }
+
+    /// Decides whether `candidate` may be signed, recording and alerting on a disagreement.
+    pub async fn evaluate(
+        &self,
+        candidate: &Candidate,
+        now_ms: u64,
+    ) -> Result<Verdict, PhalaAvsError> {
+        let started = Instant::now();
+        let verdict = self.decide(candidate, now_ms).await?;
+        METRICS.observe(
+            SAFETY_SECONDS_METRIC,
+            &[("kind", candidate.kind.as_str())],
+            started.elapsed().as_secs_f64(),
+        );
+        METRICS.inc_counter(
+            SAFETY_VERDICTS_METRIC,
+            &[
+                ("kind", candidate.kind.as_str()),
+                ("verdict", verdict.as_str()),
+            ],
+            1,
+        );
+        if verdict.permits_signing() {
+            self.store.put_json(
+                DIGESTS_NAMESPACE,
+                digest_key(&candidate.kind, candidate.task_index).as_bytes(),
+                &candidate.digest(),
+            )?;
+        }
+        Ok(verdict)
+    }
+
+    async fn decide(&self, candidate: &Candidate, now_ms: u64) -> Result<Verdict, PhalaAvsError> {
+        if self.config.skip_kinds.contains(&candidate.kind) {
+            return Ok(Verdict::Skipped);
+        }
+        let id = candidate.review_id();
+        let existing: Option<Review> = self.store.get_json(REVIEWS_NAMESPACE, id.as_slice())?;
+        if existing
+            .as_ref()
+            .is_some_and(|r| r.status == ReviewStatus::Overridden)
+        {
+            return Ok(Verdict::Overridden);
+        }
+
+        let digest = candidate.digest();
+        let mut observations = BTreeMap::new();
+        let mut reason = match self.redundancy.get(&candidate.kind) {
+            Some(check) => match check.recompute(candidate).await {
+                Ok(expected) if keccak256(&expected) == digest => None,
+                Ok(expected) => Some(Disagreement::Redundancy {
+                    expected: keccak256(&expected),
+                }),
+                Err(e) => Some(Disagreement::RedundancyFailed {
+                    error: e.to_string(),
+                }),
+            },
+            None => None,
+        };
+        if reason.is_none() {
+            observations = self.observe(candidate).await;
+            reason = self.majority(digest, &observations);
+        }
+
+        let Some(reason) = reason else {
+            if let Some(mut review) = existing {
+                review.status = ReviewStatus::Cleared;
+                review.decided_unix_ms = Some(now_ms);
+                self.store
+                    .put_json(REVIEWS_NAMESPACE, id.as_slice(), &review)?;
+            }
+            return Ok(Verdict::Sign);
+        };
+        if existing.is_none() {
+            let review = Review {
+                id,
+                kind: candidate.kind.clone(),
+                task_index: candidate.task_index,
+                digest,
+                reason: reason.clone(),
+                observations,
+                status: ReviewStatus::Pending,
+                created_unix_ms: now_ms,
+                decided_unix_ms: None,
+            };
+            self.store
+                .put_json(REVIEWS_NAMESPACE, id.as_slice(), &review)?;
+            let alert = Alert::new(
+                "response_safety",
+                Severity::Critical,
+                format!(
+                    "Refused to sign the {} response to task {}: {}; review {id}",
+                    candidate.kind,
+                    candidate.task_index,
+                    describe(&reason)
+                ),
+            );
+            if let Err(e) = self.notifier.notify(alert).await {
+                warn!("Failed to deliver the response safety alert: {e}");
+            }
+        }
+        Ok(Verdict::Blocked { review: id, reason })
+    }
+
+    /// Every peer's observations, leaving out those that failed or timed out.
+    async fn observe(&self, candidate: &Candidate) -> BTreeMap<String, Vec<DigestCount>> {
+        let queries = self.peers.iter().map(|peer| async move {
+            let observed = tokio::time::timeout(
+                self.config.peer_timeout,
+                peer.observe(&candidate.kind, candidate.task_index),
+            )
+            .await;
+            match observed {
+                Ok(Ok(digests)) => Some((peer.name().to_string(), digests)),
+                Ok(Err(e)) => {
+                    warn!("Response safety peer {} failed: {e}", peer.name());
+                    None
+                }
+                Err(_) => {
+                    warn!("Response safety peer {} timed out", peer.name());
+                    None
+                }
+            }
+        });
+        futures::future::join_all(queries)
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// A disagreement when a digest other than `ours` holds the configured majority.
+    fn majority(
+        &self,
+        ours: B256,
+        observations: &BTreeMap<String, Vec<DigestCount>>,
+    ) -> Option<Disagreement> {
+        let mut counts: BTreeMap<B256, u32> = BTreeMap::new();
+        for seen in observations.values().flatten() {
+            *counts.entry(seen.digest).or_default() += seen.count;
+        }
+        let total: u32 = counts.values().sum();
+        if total < self.config.min_observations {
+            return None;
+        }
+        let (digest, count) = counts.into_iter().max_by_key(|(_, count)| *count)?;
+        let share_bps = (u64::from(count) * 10_000 / u64::from(total)) as u32;
+        (digest != ours && share_bps >= self.config.majority_bps).then_some(
+            Disagreement::Majority {
+                digest,
+                share_bps,
+                observations: total,
+            },
+        )
+    }
+
+    /// Every recorded review, by id.
+    pub fn reviews(&self) -> Result<Vec<Review>, PhalaAvsError> {
+        self.store
+            .scan(REVIEWS_NAMESPACE)?
+            .into_iter()
+            .map(|(_, raw)| {
+                serde_json::from_slice(&raw).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Corrupt response safety review: {e}"))
+                })
+            })
+            .collect()
+    }
+
+    /// Clears the blocked response of review `id` for signing.
+    pub fn override_review(&self, id: B256, now_ms: u64) -> Result<Review, PhalaAvsError> {
+        let mut review: Review = self
+            .store
+            .get_json(REVIEWS_NAMESPACE, id.as_slice())?
+            .ok_or_else(|| PhalaAvsError::ValidationError(format!("No review {id}")))?;
+        if review.status != ReviewStatus::Pending {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Review {id} is already {:?}",
+                review.status
+            )));
+        }
+        review.status = ReviewStatus::Overridden;
+        review.decided_unix_ms = Some(now_ms);
+        self.store
+            .put_json(REVIEWS_NAMESPACE, id.as_slice(), &review)?;
+        Ok(review)
+    }
+
+    /// The digest of the response cleared for signing for `task_index` of `kind`, served to
+    /// peers comparing theirs.
+    pub fn signed_digest(
+        &self,
+        kind: &str,
+        task_index: u32,
+    ) -> Result<Option<B256>, PhalaAvsError> {
+        self.store
+            .get_json(DIGESTS_NAMESPACE, digest_key(kind, task_index).as_bytes())
+    }
+}
+
+#[cfg(feature = "aggregator")]
+fn aggregator_peer(config: &SafetyConfig) -> Option<Arc<dyn PeerSource>> {
+    let url = config.aggregator_url.clone()?;
+    Some(Arc::new(AggregatorMajority::new(url)))
+}
+
+#[cfg(not(feature = "aggregator"))]
+fn aggregator_peer(_config: &SafetyConfig) -> Option<Arc<dyn PeerSource>> {
+    None
+}
+
+fn digest_key(kind: &str, task_index: u32) -> String {
+    format!("{kind}/{task_index:010}")
+}
+
+fn describe(reason: &Disagreement) -> String {
+    match reason {
+        Disagreement::Redundancy { expected } => {
+            format!("the redundancy check expected digest {expected}")
+        }
+        Disagreement::RedundancyFailed { error } => {
+            format!("the redundancy check failed: {error}")
+        }
+        Disagreement::Majority {
+            digest,
+            share_bps,
+            observations,
+        } => format!(
+            "digest {digest} holds {}.{:02}% of {observations} observations",
+            share_bps / 100,
+            share_bps % 100
+        ),
+    }
+}
+
+/// What `/task-responses/{kind}/{index}` answers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskDigest {
+    pub kind: String,
+    pub task_index: u32,
+    pub digest: B256,
+}
+
+/// A trusted operator's status server.
+#[derive(Clone, Debug)]
+pub struct HttpPeer {
+    url: String,
+    token: Option<String>,
+    client: reqwest::Client,
+}
+
+impl HttpPeer {
+    pub fn new(url: String, token: Option<String>) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            token,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl PeerSource for HttpPeer {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn observe<'a>(
+        &'a self,
+        kind: &'a str,
+        task_index: u32,
+    ) -> BoxFuture<'a, Result<Vec<DigestCount>, PhalaAvsError>> {
+        Box::pin(async move {
+            let mut request = self
+                .client
+                .get(format!("{}/task-responses/{kind}/{task_index}", self.url));
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+            let response = request
+                .send()
+                .await
+                .map_err(|e| PhalaAvsError::Other(format!("Failed to reach peer: {e}")))?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(Vec::new());
+            }
+            let reply: TaskDigest = response
+                .error_for_status()
+                .map_err(|e| PhalaAvsError::Other(format!("Peer refused the query: {e}")))?
+                .json()
+                .await
+                .map_err(|e| PhalaAvsError::Other(format!("Invalid peer reply: {e}")))?;
+            Ok(vec![DigestCount {
+                digest: reply.digest,
+                count: 1,
+            }])
+        })
+    }
+}
+
+/// The responses the aggregator accepted for a task, through `get_majority_digest`.
+#[cfg(feature = "aggregator")]
+#[derive(Clone, Debug)]
+pub struct AggregatorMajority {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "aggregator")]
+impl AggregatorMajority {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[cfg(feature = "aggregator")]
+impl PeerSource for AggregatorMajority {
+    fn name(&self) -> &str {
+        "aggregator"
+    }
+
+    fn observe<'a>(
+        &'a self,
+        _kind: &'a str,
+        task_index: u32,
+    ) -> BoxFuture<'a, Result<Vec<DigestCount>, PhalaAvsError>> {
+        use crate::aggregator_wire::{MAJORITY_DIGEST_METHOD, MajorityDigest, MajorityRequest};
+        Box::pin(async move {
+            let request = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": MAJORITY_DIGEST_METHOD,
+                "params": MajorityRequest { task_index },
+            });
+            let reply: serde_json::Value = self
+                .client
+                .post(&self.url)
+                .json(&request)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| PhalaAvsError::Other(format!("Failed to reach aggregator: {e}")))?
+                .json()
+                .await
+                .map_err(|e| PhalaAvsError::Other(format!("Invalid aggregator reply: {e}")))?;
+            let majority: MajorityDigest =
+                crate::aggregator_admin::parse_reply(MAJORITY_DIGEST_METHOD, reply)?;
+            Ok(majority.digests)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStateStore;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Recorded(Mutex<Vec<Alert>>);
+
+    impl Notifier for Recorded {
+        fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.0.lock().unwrap().push(alert);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// Squares the task's single byte, as the task kind's second computation path.
+    #[derive(Default)]
+    struct Squaring {
+        calls: AtomicUsize,
+    }
+
+    impl RedundancyCheck for Squaring {
+        fn recompute<'a>(
+            &'a self,
+            candidate: &'a Candidate,
+        ) -> BoxFuture<'a, Result<Bytes, PhalaAvsError>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let n = u16::from(candidate.task[0]);
+            Box::pin(async move { Ok(Bytes::from((n * n).to_be_bytes().to_vec())) })
+        }
+    }
+
+    struct Peer {
+        name: &'static str,
+        seen: Mutex<Vec<DigestCount>>,
+        calls: AtomicUsize,
+    }
+
+    impl Peer {
+        fn new(name: &'static str, seen: Vec<DigestCount>) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                seen: Mutex::new(seen),
+                calls: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    impl PeerSource for Peer {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn observe<'a>(
+            &'a self,
+            _kind: &'a str,
+            _task_index: u32,
+        ) -> BoxFuture<'a, Result<Vec<DigestCount>, PhalaAvsError>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let seen = self.seen.lock().unwrap().clone();
+            Box::pin(async move { Ok(seen) })
+        }
+    }
+
+    struct Down;
+
+    impl PeerSource for Down {
+        fn name(&self) -> &str {
+            "down"
+        }
+
+        fn observe<'a>(
+            &'a self,
+            _kind: &'a str,
+            _task_index: u32,
+        ) -> BoxFuture<'a, Result<Vec<DigestCount>, PhalaAvsError>> {
+            Box::pin(async { Err(PhalaAvsError::Other("connection refused".to_string())) })
+        }
+    }
+
+    fn candidate(kind: &str, n: u8, squared: u16) -> Candidate {
+        Candidate {
+            kind: kind.to_string(),
+            task_index: 7,
+            task: Bytes::from(vec![n]),
+            response: Bytes::from(squared.to_be_bytes().to_vec()),
+        }
+    }
+
+    fn seen(c: &Candidate, count: u32) -> DigestCount {
+        DigestCount {
+            digest: c.digest(),
+            count,
+        }
+    }
+
+    fn safety(config: SafetyConfig) -> (ResponseSafety, Arc<Squaring>, Arc<Recorded>) {
+        let squaring = Arc::new(Squaring::default());
+        let notifier = Arc::new(Recorded::default());
+        let safety = ResponseSafety::new(
+            config,
+            Arc::new(MemoryStateStore::default()),
+            notifier.clone(),
+        )
+        .with_redundancy("squaring", squaring.clone());
+        (safety, squaring, notifier)
+    }
+
+    #[tokio::test]
+    async fn agreeing_responses_are_signed() {
+        let (safety, squaring, notifier) = safety(SafetyConfig::default());
+        let ours = candidate("squaring", 7, 49);
+        let aggregator = Peer::new("aggregator", vec![seen(&ours, 3)]);
+        let safety = safety
+            .with_peer(aggregator.clone())
+            .with_peer(Arc::new(Down));
+
+        assert_eq!(safety.evaluate(&ours, 1_000).await.unwrap(), Verdict::Sign);
+        assert_eq!(squaring.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(aggregator.calls.load(Ordering::SeqCst), 1);
+        assert!(notifier.0.lock().unwrap().is_empty());
+        // Peers comparing with us now see the digest.
+        assert_eq!(
+            safety.signed_digest("squaring", 7).unwrap(),
+            Some(ours.digest())
+        );
+        assert!(
+            METRICS
+                .histogram(SAFETY_SECONDS_METRIC, &[("kind", "squaring")])
+                .is_some_and(|(count, _)| count >= 1)
+        );
+
+        // Too few observations of another digest is no majority.
+        let other = candidate("squaring", 7, 50);
+        *aggregator.seen.lock().unwrap() = vec![seen(&other, 2)];
+        let ours = candidate("squaring", 7, 49);
+        assert_eq!(safety.evaluate(&ours, 2_000).await.unwrap(), Verdict::Sign);
+    }
+
+    #[tokio::test]
+    async fn redundancy_disagreement_blocks_the_signature() {
+        let (safety, _, notifier) = safety(SafetyConfig::default());
+        let aggregator = Peer::new("aggregator", Vec::new());
+        let safety = safety.with_peer(aggregator.clone());
+        // The TEE computed 7 * 7 wrong.
+        let wrong = candidate("squaring", 7, 48);
+
+        let verdict = safety.evaluate(&wrong, 1_000).await.unwrap();
+        let expected = keccak256(49u16.to_be_bytes());
+        assert_eq!(verdict, Verdict::Blocked {
+            review: wrong.review_id(),
+            reason: Disagreement::Redundancy { expected },
+        });
+        assert!(!verdict.permits_signing());
+        // The peers are not consulted once our own computation disagrees.
+        assert_eq!(aggregator.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(safety.signed_digest("squaring", 7).unwrap(), None);
+
+        let reviews = safety.reviews().unwrap();
+        assert_eq!(reviews.len(), 1);
+        assert_eq!(reviews[0].status, ReviewStatus::Pending);
+        assert_eq!(reviews[0].digest, wrong.digest());
+        // Evaluating it again does not alert twice.
+        safety.evaluate(&wrong, 2_000).await.unwrap();
+        let alerts = notifier.0.lock().unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].severity, Severity::Critical);
+    }
+
+    #[tokio::test]
+    async fn majority_disagreement_blocks_until_overridden() {
+        let (safety, _, _) = safety(SafetyConfig::default());
+        // A task kind without a redundancy check, where the majority is all there is.
+        let ours = candidate("attestation", 1, 1);
+        let majority = candidate("attestation", 1, 2);
+        let safety = safety
+            .with_peer(Peer::new("aggregator", vec![
+                seen(&majority, 3),
+                seen(&ours, 1),
+            ]))
+            .with_peer(Peer::new("peer", vec![seen(&majority, 1)]));
+
+        let verdict = safety.evaluate(&ours, 1_000).await.unwrap();
+        let review = ours.review_id();
+        assert_eq!(verdict, Verdict::Blocked {
+            review,
+            reason: Disagreement::Majority {
+                digest: majority.digest(),
+                share_bps: 8_000,
+                observations: 5,
+            },
+        });
+        let reviews = safety.reviews().unwrap();
+        assert_eq!(reviews[0].observations.len(), 2);
+
+        let overridden = safety.override_review(review, 2_000).unwrap();
+        assert_eq!(overridden.status, ReviewStatus::Overridden);
+        assert_eq!(overridden.decided_unix_ms, Some(2_000));
+        assert_eq!(
+            safety.evaluate(&ours, 3_000).await.unwrap(),
+            Verdict::Overridden
+        );
+        assert_eq!(
+            safety.signed_digest("attestation", 7).unwrap(),
+            Some(ours.digest())
+        );
+        // An override is given once.
+        assert!(safety.override_review(review, 4_000).is_err());
+    }
+
+    #[tokio::test]
+    async fn skipped_kinds_are_signed_without_checks() {
+        let (safety, squaring, _) = safety(SafetyConfig {
+            skip_kinds: ["squaring".to_string()].into(),
+            ..Default::default()
+        });
+        let aggregator = Peer::new("aggregator", Vec::new());
+        let safety = safety.with_peer(aggregator.clone());
+        let wrong = candidate("squaring", 7, 48);
+
+        assert_eq!(
+            safety.evaluate(&wrong, 1_000).await.unwrap(),
+            Verdict::Skipped
+        );
+        assert_eq!(squaring.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(aggregator.calls.load(Ordering::SeqCst), 0);
+        assert!(safety.reviews().unwrap().is_empty());
+    }
+}
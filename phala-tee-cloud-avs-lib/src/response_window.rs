@@ -0,0 +1,301 @@
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use blueprint_sdk::alloy::primitives::Address;
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+
+/// Gauge exposing the current safety margin (in blocks) per oracle target.
+pub const SAFETY_MARGIN_METRIC: &str = "phala_avs_response_safety_margin_blocks";
+
+/// An oracle contract on a specific chain that we submit responses to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OracleTarget {
+    pub chain_id: u64,
+    pub oracle: Address,
+}
+
+impl OracleTarget {
+    pub fn new(chain_id: u64, oracle: Address) -> Self {
+        Self { chain_id, oracle }
+    }
+}
+
+/// Configuration for [`InclusionLatencyPredictor`].
+#[derive(Clone, Debug)]
+pub struct SafetyMarginConfig {
+    /// Margin used until a target has `min_samples` observations.
+    pub static_margin_blocks: u64,
+    /// The predictor never returns a margin below this value.
+    pub floor_blocks: u64,
+    /// Number of inclusion samples required before the learned margin is used.
+    pub min_samples: usize,
+    /// Number of most recent samples kept per target.
+    pub max_samples: usize,
+    /// Latency quantile (0..1) used as the base margin.
+    pub quantile: f64,
+    /// Number of recent base fee observations used for the congestion trend.
+    pub base_fee_window: usize,
+}
+
+impl Default for SafetyMarginConfig {
+    fn default() -> Self {
+        Self {
+            static_margin_blocks: 5,
+            floor_blocks: 2,
+            min_samples: 20,
+            max_samples: 500,
+            quantile: 0.95,
+            base_fee_window: 20,
+        }
+    }
+}
+
+impl SafetyMarginConfig {
+    /// Loads the configuration from `RESPONSE_MARGIN_*` environment variables.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        let config = Self {
+            static_margin_blocks: env_or(
+                "RESPONSE_MARGIN_STATIC_BLOCKS",
+                defaults.static_margin_blocks,
+            )?,
+            floor_blocks: env_or("RESPONSE_MARGIN_FLOOR_BLOCKS", defaults.floor_blocks)?,
+            min_samples: env_or("RESPONSE_MARGIN_MIN_SAMPLES", defaults.min_samples)?,
+            max_samples: env_or("RESPONSE_MARGIN_MAX_SAMPLES", defaults.max_samples)?,
+            quantile: env_or("RESPONSE_MARGIN_QUANTILE", defaults.quantile)?,
+            base_fee_window: env_or("RESPONSE_MARGIN_BASE_FEE_WINDOW", defaults.base_fee_window)?,
+        };
+        if !(0.0..=1.0).contains(&config.quantile) {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "RESPONSE_MARGIN_QUANTILE must be within 0..1, got {}",
+                config.quantile
+            )));
+        }
+        if config.max_samples < config.min_samples {
+            return Err(PhalaAvsError::ConfigError(
+                "RESPONSE_MARGIN_MAX_SAMPLES must be >= RESPONSE_MARGIN_MIN_SAMPLES".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// How a pending submission relates to its deadline given the current safety margin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubmissionUrgency {
+    /// There is more than twice the margin left; submit normally.
+    Comfortable,
+    /// Within twice the margin of the deadline; escalate gas.
+    Escalate,
+    /// Less than the margin is left; inclusion before the deadline is unlikely,
+    /// route the challenge to the dispute path instead.
+    TooLate,
+}
+
+#[derive(Debug, Default)]
+struct TargetHistory {
+    latencies: VecDeque<u64>,
+    base_fees: VecDeque<u128>,
+}
+
+/// Learns per-target submission-to-inclusion latency and derives a dynamic safety margin.
+///
+/// The margin is the configured quantile of our own observed inclusion latencies, scaled up
+/// when the pending base fee is trending upward. Until a target has enough samples the
+/// configured static margin is used, and the configured floor is always respected.
+#[derive(Debug)]
+pub struct InclusionLatencyPredictor {
+    config: SafetyMarginConfig,
+    targets: RwLock<HashMap<OracleTarget, TargetHistory>>,
+}
+
+impl InclusionLatencyPredictor {
+    pub fn new(config: SafetyMarginConfig) -> Self {
+        Self {
+            config,
+            targets: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Records that a response submitted at `submitted_block` was included at `included_block`.
+    pub fn record_inclusion(
+        &self,
+        target: OracleTarget,
+        submitted_block: u64,
+        included_block: u64,
+    ) {
+        let latency = included_block.saturating_sub(submitted_block);
+        {
+            let mut targets = self.targets.write().unwrap_or_else(|e| e.into_inner());
+            let history = targets.entry(target).or_default();
+            history.latencies.push_back(latency);
+            while history.latencies.len() > self.config.max_samples {
+                history.latencies.pop_front();
+            }
+        }
+        self.publish(target);
+    }
+
+    /// Records the pending base fee observed for the target's chain.
+    pub fn record_base_fee(&self, target: OracleTarget, base_fee: u128) {
+        {
+            let mut targets = self.targets.write().unwrap_or_else(|e| e.into_inner());
+            let history = targets.entry(target).or_default();
+            history.base_fees.push_back(base_fee);
+            while history.base_fees.len() > self.config.base_fee_window {
+                history.base_fees.pop_front();
+            }
+        }
+        self.publish(target);
+    }
+
+    /// Returns the number of inclusion samples recorded for `target`.
+    pub fn sample_count(&self, target: &OracleTarget) -> usize {
+        let targets = self.targets.read().unwrap_or_else(|e| e.into_inner());
+        targets.get(target).map_or(0, |h| h.latencies.len())
+    }
+
+    /// Returns the current safety margin in blocks for `target`.
+    pub fn safety_margin(&self, target: &OracleTarget) -> u64 {
+        let targets = self.targets.read().unwrap_or_else(|e| e.into_inner());
+        let margin = match targets.get(target) {
+            Some(history) if history.latencies.len() >= self.config.min_samples => {
+                let base = quantile(&history.latencies, self.config.quantile) as f64;
+                let factor = 1.0 + congestion_trend(&history.base_fees);
+                (base * factor).ceil() as u64
+            }
+            _ => self.config.static_margin_blocks,
+        };
+        margin.max(self.config.floor_blocks)
+    }
+
+    /// Returns the last block at which a submission is still expected to land by `deadline_block`.
+    ///
+    /// This is the ordering key for the response priority queue.
+    pub fn latest_safe_submit_block(&self, target: &OracleTarget, deadline_block: u64) -> u64 {
+        deadline_block.saturating_sub(self.safety_margin(target))
+    }
+
+    /// Classifies a pending submission against its deadline.
+    pub fn urgency(
+        &self,
+        target: &OracleTarget,
+        current_block: u64,
+        deadline_block: u64,
+    ) -> SubmissionUrgency {
+        let margin = self.safety_margin(target);
+        let remaining = deadline_block.saturating_sub(current_block);
+        if remaining < margin {
+            SubmissionUrgency::TooLate
+        } else if remaining < margin.saturating_mul(2) {
+            SubmissionUrgency::Escalate
+        } else {
+            SubmissionUrgency::Comfortable
+        }
+    }
+
+    fn publish(&self, target: OracleTarget) {
+        let margin = self.safety_margin(&target);
+        let chain_id = target.chain_id.to_string();
+        let oracle = target.oracle.to_string();
+        METRICS.set_gauge(
+            SAFETY_MARGIN_METRIC,
+            &[("chain_id", &chain_id), ("oracle", &oracle)],
+            margin as f64,
+        );
+    }
+}
+
+/// Nearest-rank quantile of the samples.
+fn quantile(samples: &VecDeque<u64>, q: f64) -> u64 {
+    let mut sorted: Vec<u64> = samples.iter().copied().collect();
+    sorted.sort_unstable();
+    let rank = ((q * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+/// Relative increase of the base fee between the older and newer half of the window, in `0..=1`.
+fn congestion_trend(base_fees: &VecDeque<u128>) -> f64 {
+    if base_fees.len() < 2 {
+        return 0.0;
+    }
+    let mid = base_fees.len() / 2;
+    let older = base_fees.iter().take(mid).sum::<u128>() as f64 / mid as f64;
+    let newer = base_fees.iter().skip(mid).sum::<u128>() as f64 / (base_fees.len() - mid) as f64;
+    if older <= 0.0 {
+        return 0.0;
+    }
+    ((newer - older) / older).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(chain_id: u64) -> OracleTarget {
+        OracleTarget::new(chain_id, Address::repeat_byte(0x11))
+    }
+
+    fn predictor() -> InclusionLatencyPredictor {
+        InclusionLatencyPredictor::new(SafetyMarginConfig {
+            static_margin_blocks: 6,
+            floor_blocks: 2,
+            min_samples: 10,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn falls_back_to_static_margin_until_enough_samples() {
+        let predictor = predictor();
+        for block in 0..9 {
+            predictor.record_inclusion(target(1), block, block + 1);
+        }
+        assert_eq!(predictor.safety_margin(&target(1)), 6);
+        predictor.record_inclusion(target(1), 9, 10);
+        assert_eq!(predictor.safety_margin(&target(1)), 2);
+    }
+
+    #[test]
+    fn fast_chain_is_clamped_to_floor() {
+        let predictor = predictor();
+        for block in 0..50 {
+            predictor.record_inclusion(target(2), block, block);
+        }
+        assert_eq!(predictor.safety_margin(&target(2)), 2);
+        assert_eq!(
+            METRICS.gauge(SAFETY_MARGIN_METRIC, &[
+                ("chain_id", "2"),
+                ("oracle", &target(2).oracle.to_string())
+            ]),
+            Some(2.0)
+        );
+    }
+
+    #[test]
+    fn congested_chain_widens_margin() {
+        let predictor = predictor();
+        for block in 0..50 {
+            predictor.record_inclusion(target(3), block, block + 8);
+        }
+        assert_eq!(predictor.safety_margin(&target(3)), 8);
+
+        for fee in [10u128, 10, 10, 10, 20, 20, 20, 20] {
+            predictor.record_base_fee(target(3), fee);
+        }
+        assert_eq!(predictor.safety_margin(&target(3)), 16);
+        assert_eq!(
+            predictor.urgency(&target(3), 100, 110),
+            SubmissionUrgency::TooLate
+        );
+        assert_eq!(
+            predictor.urgency(&target(3), 100, 130),
+            SubmissionUrgency::Escalate
+        );
+        assert_eq!(
+            predictor.urgency(&target(3), 100, 140),
+            SubmissionUrgency::Comfortable
+        );
+    }
+}
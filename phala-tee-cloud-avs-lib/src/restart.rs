@@ -0,0 +1,405 @@
+//! Controlled restarts: quiescing response submission so the operator can be restarted without
+//! abandoning a challenge mid-submission.
+//!
+//! `POST /admin/prepare-restart`, or the `prepare-restart` subcommand, stops submitting responses.
+//! Challenges keep being observed, tracked and queued, but the response queue holds them as
+//! deferred instead of picking them up. Preparation then waits, for at most
+//! `RESTART_DRAIN_TIMEOUT_SECS`, for challenges already building, submitting or awaiting inclusion
+//! to settle, flushes the producer cursors, and reports whether a restart is safe: it is not while
+//! a submission is still in flight, nor while a deferred challenge's deadline falls within
+//! `RESTART_EXPECTED_BLOCKS` of the head, since the restarted operator may not answer it in time.
+//! `POST /admin/abort-restart` resumes submission.
+//!
+//! Preparation is not persisted: a restarted operator submits again at once.
+
+use crate::challenge::{ChallengeState, ChallengeTracker};
+use crate::config::env_or;
+use crate::cursor::CursorStore;
+use crate::error::PhalaAvsError;
+use crate::evm::EvmClient;
+use crate::metrics::METRICS;
+use blueprint_sdk::alloy::primitives::U256;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Gauge set to 1 while the operator is prepared for a restart.
+pub const RESTART_PREPARING_METRIC: &str = "phala_avs_restart_preparing";
+
+/// How often preparation checks whether in-flight submissions settled.
+const DRAIN_POLL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug)]
+pub struct RestartConfig {
+    /// Blocks a restart is expected to take; deferred deadlines closer than this refuse it.
+    pub expected_blocks: u64,
+    /// How long preparation waits for in-flight submissions.
+    pub drain_timeout_secs: u64,
+}
+
+impl RestartConfig {
+    /// Reads `RESTART_EXPECTED_BLOCKS` and `RESTART_DRAIN_TIMEOUT_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            expected_blocks: env_or("RESTART_EXPECTED_BLOCKS", 25)?,
+            drain_timeout_secs: env_or("RESTART_DRAIN_TIMEOUT_SECS", 60)?,
+        })
+    }
+}
+
+/// The outcome of preparing for a restart, as returned by `/admin/prepare-restart`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestartReport {
+    pub safe: bool,
+    /// Why a restart is not safe yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+    pub prepared_unix_ms: u64,
+    pub head: u64,
+    /// Unsettled challenges held until the operator resumes.
+    pub deferred: usize,
+    pub nearest_deferred_deadline: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nearest_deferred_challenge: Option<U256>,
+    /// Submissions that had not settled when the drain timed out.
+    pub in_flight: usize,
+}
+
+impl RestartReport {
+    pub fn is_safe(&self) -> bool {
+        self.safe
+    }
+}
+
+fn is_in_flight(state: ChallengeState) -> bool {
+    matches!(
+        state,
+        ChallengeState::Building | ChallengeState::Submitting | ChallengeState::AwaitingInclusion
+    )
+}
+
+/// Holds response submission while the operator is prepared for a restart.
+pub struct RestartCoordinator {
+    config: RestartConfig,
+    preparing: AtomicBool,
+    report: Mutex<Option<RestartReport>>,
+}
+
+impl RestartCoordinator {
+    pub fn new(config: RestartConfig) -> Self {
+        Self {
+            config,
+            preparing: AtomicBool::new(false),
+            report: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> &RestartConfig {
+        &self.config
+    }
+
+    pub fn is_preparing(&self) -> bool {
+        self.preparing.load(Ordering::SeqCst)
+    }
+
+    /// Whether a queued response may be picked up for submission.
+    pub fn permits_submission(&self) -> bool {
+        !self.is_preparing()
+    }
+
+    /// The report of the current preparation, if one is under way.
+    pub fn report(&self) -> Option<RestartReport> {
+        self.report
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn set_preparing(&self, preparing: bool) -> bool {
+        METRICS.set_gauge(
+            RESTART_PREPARING_METRIC,
+            &[],
+            if preparing { 1.0 } else { 0.0 },
+        );
+        self.preparing.swap(preparing, Ordering::SeqCst)
+    }
+
+    /// Stops submission, waits for in-flight submissions and reports whether a restart is safe.
+    ///
+    /// Preparing again re-checks and returns a fresh report.
+    pub async fn prepare(
+        &self,
+        evm: &dyn EvmClient,
+        tracker: &ChallengeTracker,
+        cursors: &CursorStore,
+        now_ms: u64,
+    ) -> Result<RestartReport, PhalaAvsError> {
+        if !self.set_preparing(true) {
+            info!("Preparing for a restart; holding response submission");
+        }
+        let timeout = Duration::from_secs(self.config.drain_timeout_secs);
+        let started = Instant::now();
+        let mut challenges = tracker.snapshot();
+        while challenges.iter().any(|c| is_in_flight(c.state)) && started.elapsed() < timeout {
+            tokio::time::sleep(DRAIN_POLL.min(timeout.saturating_sub(started.elapsed()))).await;
+            challenges = tracker.snapshot();
+        }
+        cursors.flush()?;
+        let head = evm.block_number().await?;
+
+        let in_flight = challenges.iter().filter(|c| is_in_flight(c.state)).count();
+        let deferred: Vec<_> = challenges
+            .iter()
+            .filter(|c| !c.state.is_settled() && !is_in_flight(c.state))
+            .collect();
+        let nearest = deferred.iter().min_by_key(|c| c.challenge.deadline_block);
+        let refusal = if in_flight > 0 {
+            Some(format!(
+                "{in_flight} submission(s) still in flight after {}s",
+                self.config.drain_timeout_secs
+            ))
+        } else {
+            nearest
+                .filter(|c| c.challenge.deadline_block <= head + self.config.expected_blocks)
+                .map(|c| {
+                    format!(
+                        "challenge {} is due at block {}, within the {} blocks a restart is \
+                         expected to take from head {head}",
+                        c.challenge.challenge_id,
+                        c.challenge.deadline_block,
+                        self.config.expected_blocks
+                    )
+                })
+        };
+        let report = RestartReport {
+            safe: refusal.is_none(),
+            refusal,
+            prepared_unix_ms: now_ms,
+            head,
+            deferred: deferred.len(),
+            nearest_deferred_deadline: nearest.map(|c| c.challenge.deadline_block),
+            nearest_deferred_challenge: nearest.map(|c| c.challenge.challenge_id),
+            in_flight,
+        };
+        match &report.refusal {
+            None => info!("Safe to restart: {} challenge(s) deferred", report.deferred),
+            Some(refusal) => warn!("Not safe to restart: {refusal}"),
+        }
+        *self.report.lock().unwrap_or_else(|e| e.into_inner()) = Some(report.clone());
+        Ok(report)
+    }
+
+    /// Resumes submission, returning whether a preparation was under way.
+    pub fn abort(&self) -> bool {
+        self.report.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let was_preparing = self.set_preparing(false);
+        if was_preparing {
+            info!("Restart aborted; resuming response submission");
+        }
+        was_preparing
+    }
+}
+
+/// Prepares a running operator for a restart through its admin API.
+pub async fn request_prepare(
+    operator_url: &str,
+    token: &str,
+) -> Result<RestartReport, PhalaAvsError> {
+    post(operator_url, token, "prepare-restart").await
+}
+
+/// Resumes a running operator prepared for a restart through its admin API.
+pub async fn request_abort(operator_url: &str, token: &str) -> Result<bool, PhalaAvsError> {
+    post(operator_url, token, "abort-restart").await
+}
+
+async fn post<T: serde::de::DeserializeOwned>(
+    operator_url: &str,
+    token: &str,
+    endpoint: &str,
+) -> Result<T, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/admin/{endpoint}",
+            operator_url.trim_end_matches('/')
+        ))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    if !response.status().is_success() {
+        return Err(PhalaAvsError::Other(format!(
+            "Operator returned {} for {endpoint}",
+            response.status()
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to read {endpoint} reply: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::ConfirmationPolicy;
+    use crate::evm::BoxFuture;
+    use crate::fixtures::ChallengeEventFixture;
+    use crate::state::{MemoryStateStore, StateStore};
+    use blueprint_sdk::alloy::primitives::{Address, B256};
+    use std::sync::Arc;
+
+    struct Head(u64);
+
+    impl EvmClient for Head {
+        fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(31337) })
+        }
+
+        fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            let head = self.0;
+            Box::pin(async move { Ok(head) })
+        }
+
+        fn block_hash(&self, _number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn block_timestamp(
+            &self,
+            _number: u64,
+        ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn is_operator_registered(
+            &self,
+            _operator: Address,
+        ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+            Box::pin(async { Ok(true) })
+        }
+    }
+
+    struct Setup {
+        tracker: Arc<ChallengeTracker>,
+        cursors: CursorStore,
+        coordinator: RestartCoordinator,
+    }
+
+    fn setup(drain_timeout_secs: u64) -> Setup {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        Setup {
+            tracker: Arc::new(
+                ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap(),
+            ),
+            cursors: CursorStore::new(store).unwrap(),
+            coordinator: RestartCoordinator::new(RestartConfig {
+                expected_blocks: 20,
+                drain_timeout_secs,
+            }),
+        }
+    }
+
+    /// Tracks challenge `id`, issued at block 100 and due `window` blocks later, in `state`.
+    fn track(tracker: &ChallengeTracker, id: u64, window: u64, state: ChallengeState) {
+        let challenge = ChallengeEventFixture::new()
+            .id(id)
+            .block(100)
+            .window(window)
+            .build_observed();
+        tracker.observe(challenge, 100).unwrap();
+        let path = [
+            ChallengeState::Queued,
+            ChallengeState::Building,
+            ChallengeState::Submitting,
+        ];
+        for to in path.into_iter().take_while(|s| *s <= state) {
+            tracker.transition(U256::from(id), to, "test").unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn prepared_operator_is_safe_once_in_flight_submissions_settle() {
+        let s = setup(5);
+        track(&s.tracker, 1, 200, ChallengeState::Queued);
+        track(&s.tracker, 2, 500, ChallengeState::Provisional);
+        track(&s.tracker, 3, 50, ChallengeState::Submitting);
+
+        let tracker = Arc::clone(&s.tracker);
+        let settle = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let id = U256::from(3);
+            tracker
+                .transition(id, ChallengeState::AwaitingInclusion, "sent")
+                .unwrap();
+            tracker
+                .transition(id, ChallengeState::Responded, "included")
+                .unwrap();
+        });
+        let report = s
+            .coordinator
+            .prepare(&Head(120), &s.tracker, &s.cursors, 1_000)
+            .await
+            .unwrap();
+        settle.await.unwrap();
+
+        assert!(report.is_safe(), "{report:?}");
+        assert!(!s.coordinator.permits_submission());
+        assert_eq!(
+            (report.deferred, report.in_flight, report.head),
+            (2, 0, 120)
+        );
+        assert_eq!(report.nearest_deferred_deadline, Some(300));
+        assert_eq!(report.nearest_deferred_challenge, Some(U256::from(1)));
+        assert_eq!(s.coordinator.report(), Some(report));
+    }
+
+    #[tokio::test]
+    async fn restart_is_refused_near_a_deferred_deadline_or_with_submissions_in_flight() {
+        let s = setup(0);
+        track(&s.tracker, 1, 30, ChallengeState::Queued);
+        let report = s
+            .coordinator
+            .prepare(&Head(115), &s.tracker, &s.cursors, 1_000)
+            .await
+            .unwrap();
+        // Due at block 130, within the 20 blocks a restart takes from block 115.
+        assert!(!report.is_safe());
+        assert_eq!(report.nearest_deferred_deadline, Some(130));
+        assert!(report.refusal.unwrap().contains("due at block 130"));
+        // Once the head leaves more room than a restart takes, it is safe.
+        let report = s
+            .coordinator
+            .prepare(&Head(109), &s.tracker, &s.cursors, 2_000)
+            .await
+            .unwrap();
+        assert!(report.is_safe());
+
+        track(&s.tracker, 2, 500, ChallengeState::Building);
+        let report = s
+            .coordinator
+            .prepare(&Head(100), &s.tracker, &s.cursors, 3_000)
+            .await
+            .unwrap();
+        assert!(!report.is_safe());
+        assert_eq!((report.in_flight, report.deferred), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn abort_resumes_submission() {
+        let s = setup(0);
+        assert!(s.coordinator.permits_submission());
+        assert!(!s.coordinator.abort());
+
+        s.coordinator
+            .prepare(&Head(100), &s.tracker, &s.cursors, 1_000)
+            .await
+            .unwrap();
+        assert!(!s.coordinator.permits_submission());
+        assert!(s.coordinator.abort());
+        assert!(s.coordinator.permits_submission());
+        assert_eq!(s.coordinator.report(), None);
+    }
+}
@@ -0,0 +1,615 @@
+//! Retry and backoff shared by every component that retries a failed call.
+//!
+//! A [`RetryPolicy`] bounds the attempts, the exponential delay between them and their jitter,
+//! and optionally a total budget: each attempt is cut short when the budget runs out and a retry
+//! that would start after it is not made, so a retried response never outlives the challenge it
+//! answers. [`retry_with`] runs an operation under a policy, retrying the errors the policy's
+//! classifier accepts (by default [`PhalaAvsError::is_retryable`]) until it succeeds, the
+//! attempts or budget run out, or its [`CancelToken`] is cancelled. Operations failing with
+//! errors of their own use [`retry_with_decision`], which takes the retry decision as a closure.
+//!
+//! Each site names its policy, and `RETRY_<SITE>_MAX_ATTEMPTS`, `_BASE_DELAY_MS`,
+//! `_MULTIPLIER`, `_MAX_DELAY_MS`, `_JITTER` (`none`, `full` or `equal`) and `_DEADLINE_MS`
+//! override the site's defaults. Sites read their policy when they start retrying, so a config
+//! reload applies to the next retry.
+
+use crate::config::{env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Counter of attempts made under a retry policy, by site and outcome.
+pub const RETRY_ATTEMPTS_METRIC: &str = "phala_avs_retry_attempts_total";
+
+/// How a backoff delay is randomized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Jitter {
+    /// The exact exponential delay.
+    None,
+    /// Uniform between zero and the delay.
+    Full,
+    /// Uniform between half the delay and the delay.
+    #[default]
+    Equal,
+}
+
+impl FromStr for Jitter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "full" => Ok(Self::Full),
+            "equal" => Ok(Self::Equal),
+            other => Err(format!("unknown jitter {other:?}")),
+        }
+    }
+}
+
+/// Decides whether a failed attempt is retried.
+pub type Classifier = fn(&PhalaAvsError) -> bool;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first included; zero retries until cancelled.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub base_delay: Duration,
+    /// Factor applied to the delay after each retry.
+    pub multiplier: f64,
+    /// Upper bound of a single delay, before jitter.
+    pub max_delay: Duration,
+    pub jitter: Jitter,
+    /// Budget of the whole operation, from its first attempt.
+    pub deadline: Option<Duration>,
+    pub classifier: Classifier,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30),
+            jitter: Jitter::Equal,
+            deadline: None,
+            classifier: PhalaAvsError::is_retryable,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads the `RETRY_<site>_*` overrides of `defaults`.
+    pub fn from_config(site: &str, defaults: Self) -> Result<Self, PhalaAvsError> {
+        let key = |setting: &str| format!("RETRY_{site}_{setting}");
+        let multiplier = env_or(&key("MULTIPLIER"), defaults.multiplier)?;
+        if !multiplier.is_finite() || multiplier < 1.0 {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "{} must be at least 1, got {multiplier}",
+                key("MULTIPLIER")
+            )));
+        }
+        Ok(Self {
+            max_attempts: env_or(&key("MAX_ATTEMPTS"), defaults.max_attempts)?,
+            base_delay: Duration::from_millis(env_or(
+                &key("BASE_DELAY_MS"),
+                defaults.base_delay.as_millis() as u64,
+            )?),
+            multiplier,
+            max_delay: Duration::from_millis(env_or(
+                &key("MAX_DELAY_MS"),
+                defaults.max_delay.as_millis() as u64,
+            )?),
+            jitter: env_or(&key("JITTER"), defaults.jitter)?,
+            deadline: env_opt::<u64>(&key("DEADLINE_MS"))?
+                .map(Duration::from_millis)
+                .or(defaults.deadline),
+            classifier: defaults.classifier,
+        })
+    }
+
+    /// Like [`from_config`](Self::from_config), falling back to `defaults` when the overrides
+    /// are invalid, for sites that must keep retrying.
+    pub fn from_config_or(site: &str, defaults: Self) -> Self {
+        Self::from_config(site, defaults.clone()).unwrap_or_else(|e| {
+            warn!("Ignoring the retry policy overrides of {site}: {e}");
+            defaults
+        })
+    }
+
+    /// Caps the budget at `remaining`, such as what is left of a challenge's response window.
+    pub fn with_deadline(mut self, remaining: Duration) -> Self {
+        self.deadline = Some(self.deadline.map_or(remaining, |d| d.min(remaining)));
+        self
+    }
+
+    pub fn with_classifier(mut self, classifier: Classifier) -> Self {
+        self.classifier = classifier;
+        self
+    }
+
+    /// The delay before retry `retry` (from 1), before jitter.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .powi(retry.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let millis = self.base_delay.as_millis() as f64 * factor;
+        Duration::from_millis(millis.min(self.max_delay.as_millis() as f64) as u64)
+    }
+
+    /// Applies the policy's jitter to `delay`, drawing from `roll`.
+    pub fn jittered(&self, delay: Duration, roll: u64) -> Duration {
+        let millis = delay.as_millis() as u64;
+        Duration::from_millis(match self.jitter {
+            Jitter::None => millis,
+            Jitter::Full => roll % (millis + 1),
+            Jitter::Equal => millis - millis / 2 + roll % (millis / 2 + 1),
+        })
+    }
+
+    fn exhausted(&self, attempts: u32) -> bool {
+        self.max_attempts != 0 && attempts >= self.max_attempts
+    }
+}
+
+/// Cooperative cancellation of a retry loop, shared by clones.
+#[derive(Clone, Debug)]
+pub struct CancelToken(Arc<watch::Sender<bool>>);
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the token is cancelled.
+    pub async fn cancelled(&self) {
+        let mut rx = self.0.subscribe();
+        // The sender lives in `self`, so this only returns once cancelled.
+        let _ = rx.wait_for(|cancelled| *cancelled).await;
+    }
+}
+
+/// Time source of the retry loop, replaced in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration) -> BoxFuture<'_, ()>;
+    /// A random draw for jitter.
+    fn roll(&self) -> u64;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'_, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn roll(&self) -> u64 {
+        uuid::Uuid::new_v4().as_u64_pair().0
+    }
+}
+
+/// Why the retry loop ended without an outcome of the operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupted {
+    /// The [`CancelToken`] was cancelled.
+    Cancelled,
+    /// An attempt outlived the policy's deadline, the budget given.
+    TimedOut(Duration),
+}
+
+/// What became of an attempt, as recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Ok,
+    Retry,
+    Fatal,
+    Exhausted,
+    Deadline,
+    Cancelled,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ok => "ok",
+            Self::Retry => "retry",
+            Self::Fatal => "fatal",
+            Self::Exhausted => "exhausted",
+            Self::Deadline => "deadline",
+            Self::Cancelled => "cancelled",
+        })
+    }
+}
+
+/// Runs `op` under `policy`, recording each attempt against `site`.
+///
+/// Returns the first success, or the last error once it is not retryable or the attempts or
+/// budget run out. Cancelling `cancel` stops the loop at its next attempt or backoff.
+pub async fn retry_with<T, F, Fut>(
+    policy: &RetryPolicy,
+    site: &str,
+    cancel: Option<&CancelToken>,
+    op: F,
+) -> Result<T, PhalaAvsError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, PhalaAvsError>>,
+{
+    retry_with_clock(&TokioClock, policy, site, cancel, op).await
+}
+
+/// [`retry_with`] on an explicit [`Clock`].
+pub async fn retry_with_clock<T, F, Fut>(
+    clock: &dyn Clock,
+    policy: &RetryPolicy,
+    site: &str,
+    cancel: Option<&CancelToken>,
+    op: F,
+) -> Result<T, PhalaAvsError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, PhalaAvsError>>,
+{
+    retry_with_decision(
+        clock,
+        policy,
+        site,
+        cancel,
+        policy.classifier,
+        |interrupted| match interrupted {
+            Interrupted::Cancelled => {
+                PhalaAvsError::Other(format!("Retries of {site} were cancelled"))
+            }
+            Interrupted::TimedOut(budget) => {
+                PhalaAvsError::Other(format!("{site} did not complete within {budget:?}"))
+            }
+        },
+        op,
+    )
+    .await
+}
+
+/// [`retry_with_clock`] for operations failing with errors of their own: `retryable` decides
+/// which failures are retried, in place of the policy's classifier, and `interrupted` is the
+/// error returned when the loop is cancelled or an attempt is cut short by the deadline.
+pub async fn retry_with_decision<T, E, F, Fut>(
+    clock: &dyn Clock,
+    policy: &RetryPolicy,
+    site: &str,
+    cancel: Option<&CancelToken>,
+    retryable: impl Fn(&E) -> bool,
+    interrupted: impl Fn(Interrupted) -> E,
+    mut op: F,
+) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let record = |outcome: Outcome| {
+        METRICS.inc_counter(
+            RETRY_ATTEMPTS_METRIC,
+            &[("site", site), ("outcome", &outcome.to_string())],
+            1,
+        );
+    };
+    let cancelled = || {
+        record(Outcome::Cancelled);
+        interrupted(Interrupted::Cancelled)
+    };
+    let deadline = policy.deadline.map(|budget| (clock.now() + budget, budget));
+
+    let mut attempts = 0;
+    loop {
+        if cancel.is_some_and(CancelToken::is_cancelled) {
+            return Err(cancelled());
+        }
+        attempts += 1;
+        let outcome = match deadline {
+            Some((deadline, budget)) => {
+                let remaining = deadline.saturating_duration_since(clock.now());
+                tokio::time::timeout(remaining, op())
+                    .await
+                    .unwrap_or_else(|_| Err(interrupted(Interrupted::TimedOut(budget))))
+            }
+            None => op().await,
+        };
+        let error = match outcome {
+            Ok(value) => {
+                record(Outcome::Ok);
+                return Ok(value);
+            }
+            Err(e) => e,
+        };
+        if !retryable(&error) {
+            record(Outcome::Fatal);
+            return Err(error);
+        }
+        if policy.exhausted(attempts) {
+            record(Outcome::Exhausted);
+            return Err(error);
+        }
+        let delay = policy.jittered(policy.backoff(attempts), clock.roll());
+        if deadline.is_some_and(|(deadline, _)| clock.now() + delay >= deadline) {
+            record(Outcome::Deadline);
+            return Err(error);
+        }
+        record(Outcome::Retry);
+        warn!(
+            "Attempt {attempts} of {site} failed, retrying in {}ms: {error}",
+            delay.as_millis()
+        );
+        match cancel {
+            Some(cancel) => tokio::select! {
+                _ = clock.sleep(delay) => {}
+                _ = cancel.cancelled() => return Err(cancelled()),
+            },
+            None => clock.sleep(delay).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Advances instantly on sleep and records the delays slept.
+    struct MockClock {
+        now: Mutex<Instant>,
+        slept: Mutex<Vec<Duration>>,
+        roll: u64,
+    }
+
+    impl MockClock {
+        fn new(roll: u64) -> Self {
+            Self {
+                now: Mutex::new(Instant::now()),
+                slept: Mutex::new(Vec::new()),
+                roll,
+            }
+        }
+
+        fn slept(&self) -> Vec<Duration> {
+            self.slept.lock().unwrap().clone()
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'_, ()> {
+            *self.now.lock().unwrap() += duration;
+            self.slept.lock().unwrap().push(duration);
+            Box::pin(async {})
+        }
+
+        fn roll(&self) -> u64 {
+            self.roll
+        }
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_millis(500),
+            jitter: Jitter::None,
+            deadline: None,
+            classifier: PhalaAvsError::is_retryable,
+        }
+    }
+
+    fn transient() -> PhalaAvsError {
+        PhalaAvsError::EvmError("connection reset".to_string())
+    }
+
+    #[tokio::test]
+    async fn backoff_grows_to_the_cap_and_stops_after_max_attempts() {
+        let clock = MockClock::new(0);
+        let mut calls = 0;
+        let result: Result<(), _> = retry_with_clock(&clock, &policy(6), "test", None, || {
+            calls += 1;
+            async { Err(transient()) }
+        })
+        .await;
+
+        assert!(matches!(result, Err(PhalaAvsError::EvmError(_))));
+        assert_eq!(calls, 6);
+        let ms: Vec<_> = clock.slept().iter().map(Duration::as_millis).collect();
+        assert_eq!(ms, [100, 200, 400, 500, 500]);
+
+        let mut calls = 0;
+        let value = retry_with_clock(&clock, &policy(6), "test", None, || {
+            calls += 1;
+            let result = if calls < 3 {
+                Err(transient())
+            } else {
+                Ok(calls)
+            };
+            async move { result }
+        })
+        .await
+        .unwrap();
+        assert_eq!(value, 3);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_bounds() {
+        let delay = Duration::from_millis(1000);
+        for roll in [0, 1, 499, 500, 501, 999, 1000, u64::MAX] {
+            let full = RetryPolicy {
+                jitter: Jitter::Full,
+                ..policy(1)
+            }
+            .jittered(delay, roll);
+            assert!(full <= delay);
+            let equal = RetryPolicy {
+                jitter: Jitter::Equal,
+                ..policy(1)
+            }
+            .jittered(delay, roll);
+            assert!(equal >= delay / 2 && equal <= delay, "{equal:?}");
+        }
+        assert_eq!(policy(1).jittered(delay, 7), delay);
+        assert_eq!("Full".parse::<Jitter>().unwrap(), Jitter::Full);
+        assert!("sometimes".parse::<Jitter>().is_err());
+    }
+
+    #[tokio::test]
+    async fn no_retry_starts_past_the_deadline() {
+        let clock = MockClock::new(0);
+        let started = clock.now();
+        let policy = policy(0).with_deadline(Duration::from_millis(1000));
+        let mut calls = 0;
+        let result: Result<(), _> = retry_with_clock(&clock, &policy, "test", None, || {
+            calls += 1;
+            async { Err(transient()) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        // Slept 100 + 200 + 400; the next 500ms would end past the 1000ms budget.
+        assert_eq!(calls, 4);
+        assert!(clock.now() - started < Duration::from_millis(1000));
+        assert_eq!(
+            policy.with_deadline(Duration::from_secs(5)).deadline,
+            Some(Duration::from_millis(1000))
+        );
+    }
+
+    #[tokio::test]
+    async fn cancellation_interrupts_a_backoff() {
+        let cancel = CancelToken::default();
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(3600),
+            ..policy(0)
+        };
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            canceller.cancel();
+        });
+
+        let mut calls = 0;
+        let result: Result<(), _> = tokio::time::timeout(
+            Duration::from_secs(5),
+            retry_with(&policy, "test", Some(&cancel), || {
+                calls += 1;
+                async { Err(transient()) }
+            }),
+        )
+        .await
+        .expect("cancellation ends the backoff");
+
+        assert!(result.unwrap_err().to_string().contains("cancelled"));
+        assert_eq!(calls, 1);
+        assert!(cancel.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn the_classifier_decides_what_is_retried() {
+        let clock = MockClock::new(0);
+        let mut calls = 0;
+        let result: Result<(), _> = retry_with_clock(&clock, &policy(5), "test", None, || {
+            calls += 1;
+            async { Err(PhalaAvsError::ValidationError("bad input".to_string())) }
+        })
+        .await;
+        assert!(matches!(result, Err(PhalaAvsError::ValidationError(_))));
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let policy = policy(5).with_classifier(|e| !matches!(e, PhalaAvsError::EvmError(_)));
+        let result: Result<(), _> = retry_with_clock(&clock, &policy, "test", None, || {
+            calls += 1;
+            async { Err(transient()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+        assert!(clock.slept().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_decision_closure_retries_errors_of_their_own_within_the_deadline() {
+        let clock = MockClock::new(0);
+        let budget = Duration::from_millis(250);
+        let policy = policy(0).with_deadline(budget);
+        let mut calls = 0;
+        let result: Result<(), String> = retry_with_decision(
+            &clock,
+            &policy,
+            "decision",
+            None,
+            |e: &String| e != "fatal",
+            |interrupted| format!("{interrupted:?}"),
+            || {
+                calls += 1;
+                let first = calls == 1;
+                async move {
+                    if first {
+                        return Err("503".to_string());
+                    }
+                    // Never answers; the deadline cuts it short.
+                    std::future::pending().await
+                }
+            },
+        )
+        .await;
+        assert_eq!(result, Err(format!("{:?}", Interrupted::TimedOut(budget))));
+        assert_eq!(calls, 2);
+        let attempts = |outcome| {
+            METRICS.counter(RETRY_ATTEMPTS_METRIC, &[
+                ("site", "decision"),
+                ("outcome", outcome),
+            ])
+        };
+        assert_eq!(attempts("retry"), Some(1));
+        assert_eq!(attempts("deadline"), Some(1));
+
+        let mut calls = 0;
+        let result: Result<(), String> = retry_with_decision(
+            &clock,
+            &policy,
+            "decision",
+            None,
+            |e: &String| e != "fatal",
+            |interrupted| format!("{interrupted:?}"),
+            || {
+                calls += 1;
+                async { Err("fatal".to_string()) }
+            },
+        )
+        .await;
+        assert_eq!(result, Err("fatal".to_string()));
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,816 @@
+//! Canary rollout of new response encoder versions, per oracle target.
+//!
+//! `ENCODER_ROLLOUT` picks, per oracle and challenge kind, the encoder version responses are built
+//! with and optionally a shadow version: `<oracle>:<kind>=<version>[+<shadow>],...`, e.g.
+//! `0xab..:attestation=1+2`. Kinds without an entry are answered in the version the challenge
+//! asks for.
+//!
+//! A shadow encoder runs alongside the active one for every response, but only the active payload
+//! is submitted. The shadow payload goes through the encoder's local verifier and an `eth_call`
+//! simulation of `respondToSlaChallenge`, and so does the active payload, so that a failure both
+//! share does not count against the new version. Every result is persisted under
+//! [`SHADOW_NAMESPACE`], so a promotion decision can be audited later.
+//!
+//! `POST /admin/rollout/{oracle}/{kind}/promote`, or `rollout promote`, makes the shadow version
+//! active once its latest `ENCODER_ROLLOUT_PROMOTION_SAMPLES` results are all clean. Promotions are
+//! persisted and take precedence over `ENCODER_ROLLOUT`.
+
+use crate::IPhalaSlaOracle;
+use crate::challenge::ObservedChallenge;
+use crate::config::{self, env_or};
+use crate::encoding::{ResponseEncoder, ResponseInputs, SchemaKey, encoder_for, kind_id, versions};
+use crate::error::PhalaAvsError;
+use crate::error_catalog;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, U256};
+use blueprint_sdk::alloy::providers::Provider;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+/// StateStore namespace holding promoted rollouts, by oracle and kind.
+pub const ROLLOUT_NAMESPACE: &str = "encoder_rollout";
+/// StateStore namespace holding shadow results, by oracle, kind, shadow version and time.
+pub const SHADOW_NAMESPACE: &str = "encoder_shadow";
+
+/// Counter of shadow results, by oracle, kind, shadow version and outcome.
+pub const SHADOW_RESULTS_METRIC: &str = "phala_avs_encoder_shadow_results_total";
+
+/// A challenge kind at one oracle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct RolloutKey {
+    pub oracle: Address,
+    pub kind: B256,
+}
+
+impl RolloutKey {
+    fn to_bytes(self) -> Vec<u8> {
+        [self.oracle.as_slice(), self.kind.as_slice()].concat()
+    }
+
+    fn from_bytes(raw: &[u8]) -> Option<Self> {
+        (raw.len() == 52).then(|| Self {
+            oracle: Address::from_slice(&raw[..20]),
+            kind: B256::from_slice(&raw[20..]),
+        })
+    }
+
+    /// Prefix of the shadow results of `version`.
+    fn shadow_prefix(self, version: u32) -> Vec<u8> {
+        [self.to_bytes(), version.to_be_bytes().to_vec()].concat()
+    }
+}
+
+/// The encoder versions of one kind at one oracle.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rollout {
+    /// Version responses are submitted in.
+    pub active: u32,
+    /// Version run alongside, whose payloads are only checked.
+    pub shadow: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub promoted_unix_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RolloutConfig {
+    pub rollouts: BTreeMap<RolloutKey, Rollout>,
+    /// Clean shadow results, most recent first, a promotion needs.
+    pub promotion_samples: usize,
+}
+
+impl Default for RolloutConfig {
+    fn default() -> Self {
+        Self {
+            rollouts: BTreeMap::new(),
+            promotion_samples: 100,
+        }
+    }
+}
+
+/// Parses a kind given by name, e.g. `attestation`, or by its 32-byte id.
+pub fn parse_kind(raw: &str) -> Result<B256, PhalaAvsError> {
+    if let Ok(kind) = raw.parse::<B256>() {
+        return Ok(kind);
+    }
+    let kind = kind_id(raw);
+    if versions(kind).is_empty() {
+        return Err(PhalaAvsError::ValidationError(format!(
+            "no response encoder for kind {raw}"
+        )));
+    }
+    Ok(kind)
+}
+
+fn compiled(kind: B256, version: u32) -> Result<&'static dyn ResponseEncoder, PhalaAvsError> {
+    encoder_for(&SchemaKey { kind, version }).ok_or_else(|| {
+        let held: Vec<_> = versions(kind).iter().map(|e| e.version()).collect();
+        PhalaAvsError::ValidationError(format!(
+            "no response encoder for {kind} v{version}; compiled versions are {held:?}"
+        ))
+    })
+}
+
+/// Parses rollouts given as `<oracle>:<kind>=<version>[+<shadow>],...`.
+fn parse_rollouts(raw: &str) -> Result<BTreeMap<RolloutKey, Rollout>, PhalaAvsError> {
+    let mut rollouts = BTreeMap::new();
+    for item in raw.split(',').filter(|s| !s.trim().is_empty()) {
+        let invalid = |e: String| {
+            PhalaAvsError::ConfigError(format!("Invalid ENCODER_ROLLOUT entry {item:?}: {e}"))
+        };
+        let (target, versions) = item
+            .split_once('=')
+            .ok_or_else(|| invalid("expected <oracle>:<kind>=<version>[+<shadow>]".into()))?;
+        let (oracle, kind) = target
+            .split_once(':')
+            .ok_or_else(|| invalid("expected <oracle>:<kind>".into()))?;
+        let key = RolloutKey {
+            oracle: oracle.trim().parse().map_err(|e| invalid(format!("{e}")))?,
+            kind: parse_kind(kind.trim()).map_err(|e| invalid(e.to_string()))?,
+        };
+        let (active, shadow) = match versions.split_once('+') {
+            Some((active, shadow)) => (active, Some(shadow)),
+            None => (versions, None),
+        };
+        let version = |v: &str| -> Result<u32, PhalaAvsError> {
+            let version = v.trim().parse().map_err(|e| invalid(format!("{e}")))?;
+            compiled(key.kind, version).map_err(|e| invalid(e.to_string()))?;
+            Ok(version)
+        };
+        let rollout = Rollout {
+            active: version(active)?,
+            shadow: shadow.map(version).transpose()?,
+            promoted_unix_ms: None,
+        };
+        if rollout.shadow == Some(rollout.active) {
+            return Err(invalid("the shadow version is the active one".into()));
+        }
+        rollouts.insert(key, rollout);
+    }
+    Ok(rollouts)
+}
+
+impl RolloutConfig {
+    /// Reads `ENCODER_ROLLOUT` (`<oracle>:<kind>=<version>[+<shadow>],...`) and
+    /// `ENCODER_ROLLOUT_PROMOTION_SAMPLES`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        Ok(Self {
+            rollouts: parse_rollouts(&config::lookup("ENCODER_ROLLOUT").unwrap_or_default())?,
+            promotion_samples: env_or(
+                "ENCODER_ROLLOUT_PROMOTION_SAMPLES",
+                defaults.promotion_samples,
+            )?,
+        })
+    }
+}
+
+/// What checking a shadow payload found.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", content = "detail", rename_all = "snake_case")]
+pub enum ShadowOutcome {
+    /// The shadow payload passed local verification and simulation.
+    Passed,
+    /// The active payload passed a check the shadow payload failed.
+    Diverged(String),
+    /// The shadow encoder could not build a payload.
+    Failed(String),
+    /// Both payloads failed, so the result says nothing about the shadow version.
+    Inconclusive(String),
+}
+
+impl ShadowOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShadowOutcome::Passed => "passed",
+            ShadowOutcome::Diverged(_) => "diverged",
+            ShadowOutcome::Failed(_) => "failed",
+            ShadowOutcome::Inconclusive(_) => "inconclusive",
+        }
+    }
+
+    /// Whether the result blocks a promotion.
+    pub fn is_error(&self) -> bool {
+        matches!(self, ShadowOutcome::Diverged(_) | ShadowOutcome::Failed(_))
+    }
+
+    /// Whether the result counts towards the samples a promotion needs.
+    pub fn is_sample(&self) -> bool {
+        !matches!(self, ShadowOutcome::Inconclusive(_))
+    }
+}
+
+/// A persisted shadow run.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShadowResult {
+    pub challenge_id: U256,
+    pub active_version: u32,
+    pub shadow_version: u32,
+    pub unix_ms: u64,
+    #[serde(flatten)]
+    pub outcome: ShadowOutcome,
+}
+
+/// Dry-runs a response against the oracle.
+pub trait ResponseSimulator: Send + Sync {
+    /// Simulates `respondToSlaChallenge(challengeId, payload)` on `oracle` with `eth_call`.
+    fn simulate(
+        &self,
+        oracle: Address,
+        challenge_id: U256,
+        payload: Bytes,
+    ) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+}
+
+/// [`ResponseSimulator`] calling the oracle from the operator's address.
+pub struct OracleSimulator<P> {
+    provider: P,
+    operator: Address,
+}
+
+impl<P> OracleSimulator<P> {
+    pub fn new(provider: P, operator: Address) -> Self {
+        Self { provider, operator }
+    }
+}
+
+impl<P: Provider + Send + Sync + 'static> ResponseSimulator for OracleSimulator<P> {
+    fn simulate(
+        &self,
+        oracle: Address,
+        challenge_id: U256,
+        payload: Bytes,
+    ) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(async move {
+            IPhalaSlaOracle::new(oracle, &self.provider)
+                .respondToSlaChallenge(challenge_id, payload)
+                .from(self.operator)
+                .call()
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    PhalaAvsError::EvmError(error_catalog::describe_contract_error(
+                        "eth_call of respondToSlaChallenge",
+                        &e,
+                    ))
+                })
+        })
+    }
+}
+
+/// A rollout with its shadow results, as shown on `/rollout`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RolloutStatus {
+    pub oracle: Address,
+    pub kind: B256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind_name: Option<String>,
+    #[serde(flatten)]
+    pub rollout: Rollout,
+    /// Samples among the latest results the promotion decision looks at.
+    pub samples: usize,
+    pub errors: usize,
+    pub required_samples: usize,
+    pub promotable: bool,
+}
+
+/// Which encoder version answers each kind at each oracle, and the shadow runs of new ones.
+pub struct EncoderRollout {
+    config: RolloutConfig,
+    store: Arc<dyn StateStore>,
+    simulator: Arc<dyn ResponseSimulator>,
+    /// Resolves encoder versions; the compiled encoders unless replaced in tests.
+    lookup: EncoderLookup,
+    rollouts: RwLock<BTreeMap<RolloutKey, Rollout>>,
+}
+
+/// Finds the encoder of a schema.
+pub type EncoderLookup = fn(&SchemaKey) -> Option<&'static dyn ResponseEncoder>;
+
+impl EncoderRollout {
+    /// Loads the configured rollouts, replaced by any persisted promotion.
+    pub fn new(
+        config: RolloutConfig,
+        store: Arc<dyn StateStore>,
+        simulator: Arc<dyn ResponseSimulator>,
+    ) -> Result<Self, PhalaAvsError> {
+        let mut rollouts = config.rollouts.clone();
+        for (key, raw) in store.scan(ROLLOUT_NAMESPACE)? {
+            let Some(key) = RolloutKey::from_bytes(&key) else {
+                warn!("Ignoring malformed encoder rollout key");
+                continue;
+            };
+            match serde_json::from_slice::<Rollout>(&raw) {
+                Ok(rollout) => {
+                    rollouts.insert(key, rollout);
+                }
+                Err(e) => warn!("Ignoring unreadable encoder rollout: {e}"),
+            }
+        }
+        Ok(Self {
+            config,
+            store,
+            simulator,
+            lookup: encoder_for,
+            rollouts: RwLock::new(rollouts),
+        })
+    }
+
+    /// Resolves encoder versions with `lookup` instead of the compiled encoders.
+    pub fn with_encoders(mut self, lookup: EncoderLookup) -> Self {
+        self.lookup = lookup;
+        self
+    }
+
+    fn encoder(
+        &self,
+        kind: B256,
+        version: u32,
+    ) -> Result<&'static dyn ResponseEncoder, PhalaAvsError> {
+        (self.lookup)(&SchemaKey { kind, version }).map_or_else(|| compiled(kind, version), Ok)
+    }
+
+    pub fn config(&self) -> &RolloutConfig {
+        &self.config
+    }
+
+    pub fn rollout(&self, oracle: Address, kind: B256) -> Option<Rollout> {
+        self.rollouts
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&RolloutKey { oracle, kind })
+            .cloned()
+    }
+
+    /// The schema to answer a challenge asking for `key` at `oracle` in.
+    pub fn response_key(&self, oracle: Address, key: SchemaKey) -> SchemaKey {
+        match self.rollout(oracle, key.kind) {
+            Some(rollout) => SchemaKey {
+                kind: key.kind,
+                version: rollout.active,
+            },
+            None => key,
+        }
+    }
+
+    /// Builds the shadow payload of a response, if the kind has a shadow version at `oracle`,
+    /// checks it against `active_payload` and persists the result.
+    pub async fn shadow(
+        &self,
+        challenge: &ObservedChallenge,
+        inputs: &ResponseInputs,
+        active_payload: &Bytes,
+        now_ms: u64,
+    ) -> Result<Option<ShadowResult>, PhalaAvsError> {
+        let kind = SchemaKey::of(challenge).kind;
+        let Some(rollout) = self.rollout(challenge.oracle, kind) else {
+            return Ok(None);
+        };
+        let Some(shadow_version) = rollout.shadow else {
+            return Ok(None);
+        };
+        let active = self.encoder(kind, rollout.active)?;
+        let shadow = self.encoder(kind, shadow_version)?;
+        let outcome = match shadow.encode(challenge, inputs) {
+            Err(e) => ShadowOutcome::Failed(e.to_string()),
+            Ok(payload) => {
+                let active_check = self.check(active, challenge, active_payload).await;
+                match (active_check, self.check(shadow, challenge, &payload).await) {
+                    (_, Ok(())) => ShadowOutcome::Passed,
+                    (Ok(()), Err(e)) => ShadowOutcome::Diverged(e),
+                    (Err(a), Err(s)) => {
+                        ShadowOutcome::Inconclusive(format!("{s}; the active payload: {a}"))
+                    }
+                }
+            }
+        };
+        let key = RolloutKey {
+            oracle: challenge.oracle,
+            kind,
+        };
+        let result = ShadowResult {
+            challenge_id: challenge.challenge_id,
+            active_version: rollout.active,
+            shadow_version,
+            unix_ms: now_ms,
+            outcome,
+        };
+        let id = [
+            key.shadow_prefix(shadow_version),
+            now_ms.to_be_bytes().to_vec(),
+            challenge.challenge_id.to_be_bytes::<32>().to_vec(),
+        ]
+        .concat();
+        self.store.put_json(SHADOW_NAMESPACE, &id, &result)?;
+        METRICS.inc_counter(
+            SHADOW_RESULTS_METRIC,
+            &[
+                ("oracle", &challenge.oracle.to_string()),
+                (
+                    "kind",
+                    &SchemaKey {
+                        kind,
+                        version: shadow_version,
+                    }
+                    .to_string(),
+                ),
+                ("outcome", result.outcome.as_str()),
+            ],
+            1,
+        );
+        if result.outcome.is_error() {
+            warn!(
+                "Shadow encoder {} v{shadow_version} {} on challenge {}: {:?}",
+                shadow.kind_name(),
+                result.outcome.as_str(),
+                challenge.challenge_id,
+                result.outcome
+            );
+        }
+        Ok(Some(result))
+    }
+
+    /// Runs the local verifier and the simulation on a payload, describing the first failure.
+    async fn check(
+        &self,
+        encoder: &dyn ResponseEncoder,
+        challenge: &ObservedChallenge,
+        payload: &Bytes,
+    ) -> Result<(), String> {
+        encoder
+            .validate(challenge, payload)
+            .map_err(|e| format!("local verifier: {e}"))?;
+        self.simulator
+            .simulate(challenge.oracle, challenge.challenge_id, payload.clone())
+            .await
+            .map_err(|e| format!("simulation: {e}"))
+    }
+
+    /// The shadow results of `version` of a kind at `oracle`, oldest first.
+    pub fn results(
+        &self,
+        oracle: Address,
+        kind: B256,
+        version: u32,
+    ) -> Result<Vec<ShadowResult>, PhalaAvsError> {
+        let key = RolloutKey { oracle, kind };
+        let from = key.shadow_prefix(version);
+        let to = key.shadow_prefix(version + 1);
+        self.store
+            .scan_range(SHADOW_NAMESPACE, &from, &to)?
+            .into_iter()
+            .map(|(_, raw)| {
+                serde_json::from_slice(&raw).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Unreadable shadow result: {e}"))
+                })
+            })
+            .collect()
+    }
+
+    /// The latest `promotion_samples` samples of the shadow version and how many are errors.
+    fn window(&self, key: RolloutKey, shadow: u32) -> Result<(usize, usize), PhalaAvsError> {
+        let results = self.results(key.oracle, key.kind, shadow)?;
+        let latest: Vec<_> = results
+            .iter()
+            .rev()
+            .filter(|r| r.outcome.is_sample())
+            .take(self.config.promotion_samples)
+            .collect();
+        let errors = latest.iter().filter(|r| r.outcome.is_error()).count();
+        Ok((latest.len(), errors))
+    }
+
+    pub fn status(&self) -> Result<Vec<RolloutStatus>, PhalaAvsError> {
+        let rollouts = self
+            .rollouts
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        rollouts
+            .into_iter()
+            .map(|(key, rollout)| {
+                let (samples, errors) = match rollout.shadow {
+                    Some(shadow) => self.window(key, shadow)?,
+                    None => (0, 0),
+                };
+                Ok(RolloutStatus {
+                    oracle: key.oracle,
+                    kind: key.kind,
+                    kind_name: versions(key.kind)
+                        .first()
+                        .map(|e| e.kind_name().to_string()),
+                    promotable: rollout.shadow.is_some()
+                        && samples >= self.config.promotion_samples
+                        && errors == 0,
+                    rollout,
+                    samples,
+                    errors,
+                    required_samples: self.config.promotion_samples,
+                })
+            })
+            .collect()
+    }
+
+    /// Makes the shadow version of a kind at `oracle` the active one, if its latest results
+    /// allow it.
+    pub fn promote(
+        &self,
+        oracle: Address,
+        kind: B256,
+        now_ms: u64,
+    ) -> Result<Rollout, PhalaAvsError> {
+        let key = RolloutKey { oracle, kind };
+        let current = self.rollout(oracle, kind);
+        let Some((active, shadow)) = current
+            .as_ref()
+            .and_then(|r| r.shadow.map(|shadow| (r.active, shadow)))
+        else {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "{kind} has no shadow encoder at {oracle}"
+            )));
+        };
+        let (samples, errors) = self.window(key, shadow)?;
+        let required = self.config.promotion_samples;
+        if samples < required {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "v{shadow} has {samples} of the {required} shadow samples a promotion needs"
+            )));
+        }
+        if errors > 0 {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "{errors} of the latest {samples} v{shadow} shadow results diverged or failed"
+            )));
+        }
+        let promoted = Rollout {
+            active: shadow,
+            shadow: None,
+            promoted_unix_ms: Some(now_ms),
+        };
+        self.store
+            .put_json(ROLLOUT_NAMESPACE, &key.to_bytes(), &promoted)?;
+        self.rollouts
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key, promoted.clone());
+        info!(
+            "Promoted {} v{shadow} over v{active} at oracle {oracle} after {samples} clean shadow \
+             results",
+            SchemaKey {
+                kind,
+                version: shadow
+            }
+        );
+        Ok(promoted)
+    }
+}
+
+/// Fetches a running operator's rollouts.
+pub async fn fetch_rollouts(
+    operator_url: &str,
+    token: &str,
+) -> Result<Vec<RolloutStatus>, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/rollout", operator_url.trim_end_matches('/')))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    read_reply(response).await
+}
+
+/// Promotes the shadow encoder of `kind` at `oracle` on a running operator.
+pub async fn request_promotion(
+    operator_url: &str,
+    token: &str,
+    oracle: Address,
+    kind: &str,
+) -> Result<Rollout, PhalaAvsError> {
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/admin/rollout/{oracle}/{kind}/promote",
+            operator_url.trim_end_matches('/')
+        ))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to reach operator: {e}")))?;
+    read_reply(response).await
+}
+
+async fn read_reply<T: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<T, PhalaAvsError> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(PhalaAvsError::Other(format!(
+            "Operator returned {status} for the rollout: {body}"
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| PhalaAvsError::Other(format!("Failed to read rollout: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{ATTESTATION_KIND, AttestationEncoderV2, AttestationQuote};
+    use crate::fixtures::ChallengeEventFixture;
+    use crate::state::MemoryStateStore;
+    use crate::tee::platform::TeePlatform;
+
+    const ORACLE: Address = Address::repeat_byte(0x0a);
+
+    /// Accepts every response.
+    struct Accepting;
+
+    impl ResponseSimulator for Accepting {
+        fn simulate(
+            &self,
+            _oracle: Address,
+            _challenge_id: U256,
+            _payload: Bytes,
+        ) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn rollout(store: &Arc<dyn StateStore>) -> EncoderRollout {
+        let key = RolloutKey {
+            oracle: ORACLE,
+            kind: kind_id(ATTESTATION_KIND),
+        };
+        let config = RolloutConfig {
+            rollouts: BTreeMap::from([(key, Rollout {
+                active: 1,
+                shadow: Some(2),
+                promoted_unix_ms: None,
+            })]),
+            promotion_samples: 3,
+        };
+        EncoderRollout::new(config, Arc::clone(store), Arc::new(Accepting)).unwrap()
+    }
+
+    fn challenge(id: u64) -> ObservedChallenge {
+        ChallengeEventFixture::new()
+            .id(id)
+            .oracle(ORACLE)
+            .kind(ATTESTATION_KIND, 1, B256::repeat_byte(5).to_vec())
+            .build_observed()
+    }
+
+    fn inputs() -> ResponseInputs {
+        ResponseInputs {
+            attestation: Some(AttestationQuote {
+                platform: TeePlatform::Tdx,
+                quoted_at_unix: 1_700_000_000,
+                quote: Bytes::from_static(&[4, 0, 0, 0, 0x81]),
+            }),
+            ..ResponseInputs::default()
+        }
+    }
+
+    /// Attestation v2 answering the wrong challenge, as a botched new encoder might.
+    struct DivergentAttestationV2;
+
+    impl ResponseEncoder for DivergentAttestationV2 {
+        fn kind_name(&self) -> &'static str {
+            ATTESTATION_KIND
+        }
+
+        fn version(&self) -> u32 {
+            2
+        }
+
+        fn schema(&self) -> &'static str {
+            AttestationEncoderV2.schema()
+        }
+
+        fn encode(
+            &self,
+            challenge: &ObservedChallenge,
+            inputs: &ResponseInputs,
+        ) -> Result<Bytes, PhalaAvsError> {
+            let mut shifted = challenge.clone();
+            shifted.challenge_id += U256::from(1);
+            AttestationEncoderV2.encode(&shifted, inputs)
+        }
+
+        fn validate(
+            &self,
+            challenge: &ObservedChallenge,
+            payload: &[u8],
+        ) -> Result<(), PhalaAvsError> {
+            AttestationEncoderV2.validate(challenge, payload)
+        }
+    }
+
+    fn divergent(key: &SchemaKey) -> Option<&'static dyn ResponseEncoder> {
+        if *key == SchemaKey::new(ATTESTATION_KIND, 2) {
+            return Some(&DivergentAttestationV2);
+        }
+        encoder_for(key)
+    }
+
+    /// Answers challenges 1 to `count` at `ORACLE`, returning the shadow outcomes.
+    async fn respond(rollout: &EncoderRollout, count: u64) -> Vec<ShadowOutcome> {
+        let mut outcomes = Vec::new();
+        for id in 1..=count {
+            let challenge = challenge(id);
+            let key = rollout.response_key(ORACLE, SchemaKey::of(&challenge));
+            let active = encoder_for(&key)
+                .unwrap()
+                .encode(&challenge, &inputs())
+                .unwrap();
+            let result = rollout
+                .shadow(&challenge, &inputs(), &active, 1_000 + id)
+                .await
+                .unwrap()
+                .unwrap();
+            outcomes.push(result.outcome);
+        }
+        outcomes
+    }
+
+    #[tokio::test]
+    async fn divergent_shadow_encoder_is_recorded_and_blocks_promotion() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let rollout = rollout(&store).with_encoders(divergent);
+        let kind = kind_id(ATTESTATION_KIND);
+        // Submissions stay on the active version.
+        let asked = SchemaKey::of(&challenge(1));
+        assert_eq!(rollout.response_key(ORACLE, asked).version, 1);
+
+        let outcomes = respond(&rollout, 3).await;
+        assert!(
+            outcomes
+                .iter()
+                .all(|o| matches!(o, ShadowOutcome::Diverged(detail) if detail.contains("local verifier"))),
+            "{outcomes:?}"
+        );
+        // The results are persisted for audit.
+        let results = rollout.results(ORACLE, kind, 2).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].challenge_id, U256::from(1));
+        let status = &rollout.status().unwrap()[0];
+        assert_eq!(
+            (status.samples, status.errors, status.promotable),
+            (3, 3, false)
+        );
+
+        let err = rollout.promote(ORACLE, kind, 5_000).unwrap_err();
+        assert!(err.to_string().contains("3 of the latest 3"), "{err}");
+        assert_eq!(rollout.rollout(ORACLE, kind).unwrap().active, 1);
+    }
+
+    #[tokio::test]
+    async fn clean_shadow_encoder_is_promoted_and_stays_promoted() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let rollout = rollout(&store);
+        let kind = kind_id(ATTESTATION_KIND);
+
+        respond(&rollout, 2).await;
+        // Too few samples yet.
+        let err = rollout.promote(ORACLE, kind, 5_000).unwrap_err();
+        assert!(err.to_string().contains("2 of the 3"), "{err}");
+
+        let outcomes = respond(&rollout, 3).await;
+        assert!(outcomes.iter().all(|o| *o == ShadowOutcome::Passed));
+        assert!(rollout.status().unwrap()[0].promotable);
+        let promoted = rollout.promote(ORACLE, kind, 6_000).unwrap();
+        assert_eq!((promoted.active, promoted.shadow), (2, None));
+
+        // The flip applies to new responses, and survives a restart over the old config.
+        let asked = SchemaKey::of(&challenge(9));
+        assert_eq!(asked.version, 1);
+        assert_eq!(rollout.response_key(ORACLE, asked).version, 2);
+        let restarted = self::rollout(&store);
+        assert_eq!(restarted.response_key(ORACLE, asked).version, 2);
+        assert_eq!(
+            restarted.rollout(ORACLE, kind).unwrap().promoted_unix_ms,
+            Some(6_000)
+        );
+        // Other oracles keep answering in the version the challenge asks for.
+        assert_eq!(restarted.response_key(Address::ZERO, asked).version, 1);
+    }
+
+    #[test]
+    fn rollouts_are_parsed_against_the_compiled_encoders() {
+        let oracle = ORACLE.to_string();
+        let parse = |raw: String| parse_rollouts(&raw);
+        let rollouts = parse(format!("{oracle}:attestation=1+2")).unwrap();
+        let rollout = &rollouts[&RolloutKey {
+            oracle: ORACLE,
+            kind: kind_id(ATTESTATION_KIND),
+        }];
+        assert_eq!((rollout.active, rollout.shadow), (1, Some(2)));
+        assert!(parse(format!("{oracle}:attestation=1+3")).is_err());
+        assert!(parse(format!("{oracle}:attestation=2+2")).is_err());
+        assert!(parse(format!("{oracle}:unknown=1")).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Sanitization of strings that come from outside the operator before they reach logs, status,
+//! alerts or stores.
+//!
+//! Challenge decoders, TEE replies, pushed evidence, schema URIs and aggregator replies are all
+//! controlled by someone else. At those boundaries, free text goes through [`text`] (or
+//! [`lossy`] for raw bytes, or [`message`] for errors built from a peer's reply):
+//!
+//! - ANSI escape sequences (CSI, OSC and two-byte escapes) are removed;
+//! - other control characters, and the bidi overrides that reorder what a terminal displays,
+//!   are escaped (`\n`, `\u{1b}`, `\u{202e}`);
+//! - invalid UTF-8 is replaced with U+FFFD;
+//! - the result is capped at the field's limit and ends with `…[truncated N bytes]` if cut.
+//!
+//! Values used as more than text are validated instead: URIs with [`uri`] (`https://` or
+//! `ipfs://`, printable ASCII, at most [`MAX_URI_BYTES`]) and names with [`identifier`]. Every
+//! change or rejection is counted in [`SANITIZE_VIOLATIONS_METRIC`] and logged with a short,
+//! already-sanitized preview.
+
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use std::borrow::Cow;
+use std::fmt;
+use tracing::warn;
+
+/// Counter of external strings altered or rejected, by `field` and `reason`.
+pub const SANITIZE_VIOLATIONS_METRIC: &str = "phala_avs_sanitize_violations_total";
+
+/// Limit of error messages relayed from a peer.
+pub const MAX_MESSAGE_BYTES: usize = 1024;
+/// Limit of a schema or spec URI.
+pub const MAX_URI_BYTES: usize = 512;
+/// Limit of a name used as an identifier, e.g. an evidence kind.
+pub const MAX_IDENTIFIER_BYTES: usize = 128;
+/// How much of an offending value is shown when a violation is logged.
+const PREVIEW_BYTES: usize = 64;
+
+const ESC: char = '\u{1b}';
+
+/// Why an external string was altered or rejected; the `reason` label of
+/// [`SANITIZE_VIOLATIONS_METRIC`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+    Ansi,
+    Control,
+    InvalidUtf8,
+    Truncated,
+    BadUri,
+    BadIdentifier,
+}
+
+impl Violation {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Ansi => "ansi",
+            Self::Control => "control",
+            Self::InvalidUtf8 => "invalid_utf8",
+            Self::Truncated => "truncated",
+            Self::BadUri => "bad_uri",
+            Self::BadIdentifier => "bad_identifier",
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+fn record(field: &str, violation: Violation, raw: &str) {
+    METRICS.inc_counter(
+        SANITIZE_VIOLATIONS_METRIC,
+        &[("field", field), ("reason", violation.label())],
+        1,
+    );
+    warn!(
+        field,
+        reason = violation.label(),
+        "Sanitized external value: {}",
+        clean(raw, PREVIEW_BYTES, 0).0
+    );
+}
+
+/// Whether `c` is escaped rather than shown: controls, and the bidi embeddings, overrides and
+/// isolates that can make displayed text differ from what is stored.
+fn escaped(c: char) -> bool {
+    c.is_control()
+        || matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Bytes of the ANSI sequence at the start of `rest`, which starts with ESC.
+fn ansi_len(rest: &str) -> usize {
+    let mut chars = rest.char_indices().skip(1);
+    match chars.next() {
+        // CSI: parameters and intermediates, then a final byte in `@`..=`~`.
+        Some((_, '[')) => chars
+            .find(|(_, c)| ('@'..='~').contains(c))
+            .map_or(rest.len(), |(i, c)| i + c.len_utf8()),
+        // OSC: up to BEL or ST (`ESC \`).
+        Some((_, ']')) => {
+            let mut previous = None;
+            for (i, c) in chars {
+                if c == '\u{7}' {
+                    return i + 1;
+                }
+                if previous == Some(ESC) && c == '\\' {
+                    return i + 1;
+                }
+                previous = Some(c);
+            }
+            rest.len()
+        }
+        Some((i, c)) => i + c.len_utf8(),
+        None => rest.len(),
+    }
+}
+
+fn note(found: &mut Vec<Violation>, violation: Violation) {
+    if !found.contains(&violation) {
+        found.push(violation);
+    }
+}
+
+/// `raw` with ANSI sequences removed and controls escaped, cut at `max_bytes`, and what was
+/// done to it. `beyond` bytes of the original value were already dropped before `raw`.
+fn clean(raw: &str, max_bytes: usize, beyond: usize) -> (String, Vec<Violation>) {
+    let mut out = String::with_capacity(raw.len().min(max_bytes));
+    let mut found = Vec::new();
+    let mut at = 0;
+    while at < raw.len() {
+        let rest = &raw[at..];
+        let c = rest.chars().next().unwrap_or_default();
+        if c == ESC {
+            note(&mut found, Violation::Ansi);
+            at += ansi_len(rest);
+            continue;
+        }
+        let escape;
+        let shown = if escaped(c) {
+            note(&mut found, Violation::Control);
+            escape = c.escape_default().to_string();
+            escape.as_str()
+        } else {
+            &rest[..c.len_utf8()]
+        };
+        if out.len() + shown.len() > max_bytes {
+            break;
+        }
+        out.push_str(shown);
+        at += c.len_utf8();
+    }
+    let dropped = raw.len() - at + beyond;
+    if dropped > 0 {
+        note(&mut found, Violation::Truncated);
+        out.push_str(&format!("…[truncated {dropped} bytes]"));
+    }
+    (out, found)
+}
+
+/// `raw` made safe to log and store as the value of `field`, at most `max_bytes` plus the
+/// truncation marker.
+pub fn text(field: &str, raw: &str, max_bytes: usize) -> String {
+    let (out, found) = clean(raw, max_bytes, 0);
+    for violation in found {
+        record(field, violation, raw);
+    }
+    out
+}
+
+/// [`text`] of bytes that should be UTF-8, replacing invalid sequences.
+pub fn lossy(field: &str, raw: &[u8], max_bytes: usize) -> String {
+    // Escaping only grows text, so nothing past four times the limit can be shown.
+    let head = &raw[..raw.len().min(max_bytes.saturating_mul(4))];
+    let decoded = String::from_utf8_lossy(head);
+    let (out, mut found) = clean(&decoded, max_bytes, raw.len() - head.len());
+    if matches!(decoded, Cow::Owned(_)) {
+        note(&mut found, Violation::InvalidUtf8);
+    }
+    for violation in found {
+        record(field, violation, &decoded);
+    }
+    out
+}
+
+/// [`text`] of an error relayed from a peer, at most [`MAX_MESSAGE_BYTES`].
+pub fn message(field: &str, error: impl fmt::Display) -> String {
+    text(field, &error.to_string(), MAX_MESSAGE_BYTES)
+}
+
+/// `raw` if it is an `https://` or `ipfs://` URI of printable ASCII, at most [`MAX_URI_BYTES`].
+///
+/// ASCII-only rejects lookalike hosts (`https://gіthub.com` with a Cyrillic `і`) as well as
+/// whitespace and controls.
+pub fn uri<'a>(field: &str, raw: &'a str) -> Result<&'a str, PhalaAvsError> {
+    let rest = raw
+        .strip_prefix("https://")
+        .or_else(|| raw.strip_prefix("ipfs://"));
+    let reason = if raw.len() > MAX_URI_BYTES {
+        Some(format!("longer than {MAX_URI_BYTES} bytes"))
+    } else if !raw.bytes().all(|b| b.is_ascii_graphic()) {
+        Some("not printable ASCII".to_string())
+    } else if rest.is_none_or(str::is_empty) {
+        Some("not an https:// or ipfs:// URI".to_string())
+    } else {
+        None
+    };
+    match reason {
+        None => Ok(raw),
+        Some(reason) => {
+            record(field, Violation::BadUri, raw);
+            Err(PhalaAvsError::ValidationError(format!(
+                "Invalid {field} {:?}: {reason}",
+                clean(raw, PREVIEW_BYTES, 0).0
+            )))
+        }
+    }
+}
+
+/// `raw` if it is an identifier: an ASCII letter or digit, then letters, digits, `_`, `.`, `:`
+/// or `-`, at most [`MAX_IDENTIFIER_BYTES`] in all.
+pub fn identifier<'a>(field: &str, raw: &'a str) -> Result<&'a str, PhalaAvsError> {
+    let valid = raw.len() <= MAX_IDENTIFIER_BYTES
+        && raw
+            .bytes()
+            .next()
+            .is_some_and(|b| b.is_ascii_alphanumeric())
+        && raw
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b':' | b'-'));
+    if valid {
+        return Ok(raw);
+    }
+    record(field, Violation::BadIdentifier, raw);
+    Err(PhalaAvsError::ValidationError(format!(
+        "Invalid {field} {:?}: expected [A-Za-z0-9][A-Za-z0-9_.:-]*, at most {MAX_IDENTIFIER_BYTES} bytes",
+        clean(raw, PREVIEW_BYTES, 0).0
+    )))
+}
+
+/// Adversarial strings for the tests of every boundary that sanitizes.
+#[cfg(test)]
+pub(crate) mod adversarial {
+    /// Fragments combined by [`strings`].
+    const FRAGMENTS: &[&str] = &[
+        "ok",
+        "\u{1b}[31mred\u{1b}[0m",
+        "\u{1b}]0;title\u{7}",
+        "\u{1b}]8;;https://evil\u{1b}\\link\u{1b}]8;;\u{1b}\\",
+        "\u{1b}[2J\u{1b}[H",
+        "\u{1b}",
+        "\u{1b}[",
+        "\r\nFAKE LOG LINE level=ERROR",
+        "\u{0}\u{7}\u{8}\u{7f}\u{9b}",
+        "\u{202e}txt.exe",
+        "é漢🦀",
+        "https://gіthub.com",
+    ];
+
+    /// Deterministic combinations of [`FRAGMENTS`], including ANSI bombs and inputs far over
+    /// any limit.
+    pub(crate) fn strings() -> Vec<String> {
+        let mut out: Vec<String> = FRAGMENTS.iter().map(|f| f.to_string()).collect();
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..64 {
+            let mut s = String::new();
+            for _ in 0..(seed % 7 + 1) {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                s.push_str(FRAGMENTS[(seed % FRAGMENTS.len() as u64) as usize]);
+            }
+            out.push(s);
+        }
+        out.push("\u{1b}[1;31m".repeat(200_000));
+        out.push("A".repeat(4 << 20));
+        out.push("🦀".repeat(1 << 18));
+        out
+    }
+
+    /// Invalid UTF-8, including a truncated multi-byte sequence at the end.
+    pub(crate) fn bytes() -> Vec<Vec<u8>> {
+        vec![
+            vec![0xff, 0xfe, b'a'],
+            b"ok\xc3".to_vec(),
+            [b"\x1b[31m".as_slice(), &[0x80; 10_000]].concat(),
+            vec![0xc0; 3 << 20],
+        ]
+    }
+
+    /// Whether `s` is safe downstream: no raw controls or bidi overrides, and at most `max`
+    /// bytes plus the truncation marker.
+    pub(crate) fn assert_safe(s: &str, max: usize) {
+        assert!(!s.chars().any(super::escaped), "unescaped control in {s:?}");
+        assert!(
+            s.len() <= max + 40,
+            "{} bytes over a limit of {max}",
+            s.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::adversarial::{assert_safe, bytes, strings};
+    use super::*;
+
+    #[test]
+    fn text_strips_ansi_and_escapes_controls() {
+        assert_eq!(text("t", "\u{1b}[31mred\u{1b}[0m", 100), "red");
+        assert_eq!(text("t", "\u{1b}]0;title\u{7}x", 100), "x");
+        assert_eq!(text("t", "a\nb\u{0}", 100), "a\\nb\\u{0}");
+        assert_eq!(text("t", "\u{202e}gnp.exe", 100), "\\u{202e}gnp.exe");
+        assert_eq!(text("t", "plain é", 100), "plain é");
+    }
+
+    #[test]
+    fn text_is_bounded_with_a_marker() {
+        let out = text("t", &"é".repeat(100), 11);
+        assert_eq!(out, format!("{}…[truncated 190 bytes]", "é".repeat(5)));
+        assert_eq!(text("t", "short", 5), "short");
+    }
+
+    #[test]
+    fn adversarial_strings_come_out_bounded_and_escaped() {
+        for s in strings() {
+            assert_safe(&text("t", &s, 256), 256);
+            assert_safe(&message("t", &s), MAX_MESSAGE_BYTES);
+            if let Ok(uri) = uri("t", &s) {
+                assert_safe(uri, MAX_URI_BYTES);
+            }
+            if let Ok(id) = identifier("t", &s) {
+                assert_safe(id, MAX_IDENTIFIER_BYTES);
+            }
+        }
+        for b in bytes() {
+            let out = lossy("t", &b, 256);
+            assert_safe(&out, 256);
+            assert!(out.contains('\u{fffd}'));
+        }
+    }
+
+    #[test]
+    fn uris_must_be_https_or_ipfs_ascii() {
+        assert!(uri("u", "https://schemas.example/liveness/1").is_ok());
+        assert!(
+            uri(
+                "u",
+                "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi"
+            )
+            .is_ok()
+        );
+        for bad in [
+            "http://schemas.example",
+            "https://",
+            "javascript:alert(1)",
+            "https://gіthub.com/x",
+            "https://example.com/\u{1b}[2J",
+            "https://example.com/a b",
+            &format!("https://{}", "a".repeat(MAX_URI_BYTES)),
+        ] {
+            let err = uri("u", bad).unwrap_err().to_string();
+            assert!(!err.contains(ESC) && err.len() < 256, "{err}");
+        }
+    }
+
+    #[test]
+    fn identifiers_follow_the_pattern() {
+        for good in ["liveness", "cpu.load", "00-4bf92f-01", "kind_v2:beta"] {
+            assert_eq!(identifier("i", good).unwrap(), good);
+        }
+        for bad in [
+            "",
+            "-leading",
+            "has space",
+            "tab\t",
+            "ünicode",
+            &"a".repeat(129),
+        ] {
+            assert!(identifier("i", bad).is_err(), "{bad:?}");
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! Fair ordering of challenge responses across oracle targets.
+//!
+//! Within a target, responses are ordered by deadline. Across targets, deficit round-robin with
+//! per-target weights (`RESPONSE_SCHEDULER_WEIGHTS`) keeps one target's burst from starving the
+//! others, with two overrides, in order:
+//!
+//! 1. A response within `RESPONSE_SCHEDULER_URGENT_BLOCKS` of its deadline goes first, so
+//!    fairness never costs a deadline.
+//! 2. A target whose oldest queued response has waited `RESPONSE_SCHEDULER_STARVATION_SECS` is
+//!    served next.
+//!
+//! With a single target this is plain deadline ordering.
+
+use crate::config::{self, env_or};
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::response_window::OracleTarget;
+use blueprint_sdk::alloy::primitives::Address;
+use std::collections::{BTreeMap, HashMap};
+
+/// Counter of scheduling decisions, by oracle and reason.
+pub const SCHEDULER_DECISIONS_METRIC: &str = "phala_avs_scheduler_decisions_total";
+/// Histogram of queue wait time in seconds, by oracle.
+pub const SCHEDULER_WAIT_METRIC: &str = "phala_avs_scheduler_wait_seconds";
+
+#[derive(Clone, Debug)]
+pub struct SchedulerConfig {
+    /// Weights by oracle address; unlisted oracles get `default_weight`.
+    pub weights: HashMap<Address, u32>,
+    pub default_weight: u32,
+    pub urgent_blocks: u64,
+    pub starvation_ms: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            weights: HashMap::new(),
+            default_weight: 1,
+            urgent_blocks: 5,
+            starvation_ms: 30_000,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    /// Reads `RESPONSE_SCHEDULER_WEIGHTS` (`<oracle>=<weight>,...`),
+    /// `RESPONSE_SCHEDULER_URGENT_BLOCKS` and `RESPONSE_SCHEDULER_STARVATION_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        let mut weights = HashMap::new();
+        let raw = config::lookup("RESPONSE_SCHEDULER_WEIGHTS").unwrap_or_default();
+        for item in raw.split(',').filter(|s| !s.trim().is_empty()) {
+            let invalid = |e: String| {
+                PhalaAvsError::ConfigError(format!(
+                    "Invalid RESPONSE_SCHEDULER_WEIGHTS entry {item:?}: {e}"
+                ))
+            };
+            let (oracle, weight) = item
+                .split_once('=')
+                .ok_or_else(|| invalid("expected <oracle>=<weight>".to_string()))?;
+            let oracle: Address = oracle.trim().parse().map_err(|e| invalid(format!("{e}")))?;
+            let weight: u32 = weight.trim().parse().map_err(|e| invalid(format!("{e}")))?;
+            if weight == 0 {
+                return Err(invalid("weights must be positive".to_string()));
+            }
+            weights.insert(oracle, weight);
+        }
+        let starvation_secs: u64 = env_or(
+            "RESPONSE_SCHEDULER_STARVATION_SECS",
+            defaults.starvation_ms / 1000,
+        )?;
+        Ok(Self {
+            weights,
+            default_weight: defaults.default_weight,
+            urgent_blocks: env_or("RESPONSE_SCHEDULER_URGENT_BLOCKS", defaults.urgent_blocks)?,
+            starvation_ms: starvation_secs * 1000,
+        })
+    }
+
+    fn weight(&self, target: &OracleTarget) -> u32 {
+        self.weights
+            .get(&target.oracle)
+            .copied()
+            .unwrap_or(self.default_weight)
+    }
+}
+
+/// Why an item was picked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScheduleReason {
+    /// Only one target had queued work.
+    Deadline,
+    Urgent,
+    Starvation,
+    Fair,
+}
+
+impl ScheduleReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            ScheduleReason::Deadline => "deadline",
+            ScheduleReason::Urgent => "urgent",
+            ScheduleReason::Starvation => "starvation",
+            ScheduleReason::Fair => "fair",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Scheduled<T> {
+    pub target: OracleTarget,
+    pub deadline_block: u64,
+    pub waited_ms: u64,
+    pub reason: ScheduleReason,
+    pub item: T,
+}
+
+#[derive(Debug)]
+struct Queued<T> {
+    enqueued_ms: u64,
+    item: T,
+}
+
+#[derive(Debug)]
+struct TargetQueue<T> {
+    target: OracleTarget,
+    /// By `(deadline, sequence)`, so equal deadlines stay in arrival order.
+    items: BTreeMap<(u64, u64), Queued<T>>,
+    deficit: u64,
+}
+
+impl<T> TargetQueue<T> {
+    fn earliest_deadline(&self) -> Option<u64> {
+        self.items.keys().next().map(|(deadline, _)| *deadline)
+    }
+
+    fn oldest_enqueued(&self) -> Option<u64> {
+        self.items.values().map(|q| q.enqueued_ms).min()
+    }
+}
+
+/// Queued responses of every target.
+#[derive(Debug)]
+pub struct FairScheduler<T> {
+    config: SchedulerConfig,
+    /// Targets with queued items, in round-robin order.
+    queues: Vec<TargetQueue<T>>,
+    cursor: usize,
+    sequence: u64,
+}
+
+impl<T> FairScheduler<T> {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config,
+            queues: Vec::new(),
+            cursor: 0,
+            sequence: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.items.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    pub fn push(&mut self, target: OracleTarget, deadline_block: u64, now_ms: u64, item: T) {
+        let index = match self.queues.iter().position(|q| q.target == target) {
+            Some(index) => index,
+            None => {
+                self.queues.push(TargetQueue {
+                    target,
+                    items: BTreeMap::new(),
+                    deficit: 0,
+                });
+                self.queues.len() - 1
+            }
+        };
+        self.sequence += 1;
+        self.queues[index]
+            .items
+            .insert((deadline_block, self.sequence), Queued {
+                enqueued_ms: now_ms,
+                item,
+            });
+    }
+
+    /// Drops the queued items `keep` rejects. Returns how many were dropped.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) -> usize {
+        let before = self.len();
+        for queue in &mut self.queues {
+            queue.items.retain(|_, queued| keep(&queued.item));
+        }
+        let mut index = 0;
+        while index < self.queues.len() {
+            if !self.queues[index].items.is_empty() {
+                index += 1;
+                continue;
+            }
+            self.queues.remove(index);
+            if self.cursor > index {
+                self.cursor -= 1;
+            }
+        }
+        if self.cursor >= self.queues.len() {
+            self.cursor = 0;
+        }
+        before - self.len()
+    }
+
+    /// Picks the next item to respond to at `head_block`.
+    pub fn pop(&mut self, head_block: u64, now_ms: u64) -> Option<Scheduled<T>> {
+        let (index, reason) = self.choose(head_block, now_ms)?;
+        let queue = &mut self.queues[index];
+        let ((deadline_block, _), queued) = queue.items.pop_first()?;
+        let target = queue.target;
+        if queue.items.is_empty() {
+            self.queues.remove(index);
+            if self.cursor > index {
+                self.cursor -= 1;
+            }
+            if self.cursor >= self.queues.len() {
+                self.cursor = 0;
+            }
+        }
+
+        let waited_ms = now_ms.saturating_sub(queued.enqueued_ms);
+        let oracle = target.oracle.to_string();
+        METRICS.inc_counter(
+            SCHEDULER_DECISIONS_METRIC,
+            &[("oracle", &oracle), ("reason", reason.as_str())],
+            1,
+        );
+        METRICS.observe(
+            SCHEDULER_WAIT_METRIC,
+            &[("oracle", &oracle)],
+            waited_ms as f64 / 1000.0,
+        );
+        Some(Scheduled {
+            target,
+            deadline_block,
+            waited_ms,
+            reason,
+            item: queued.item,
+        })
+    }
+
+    fn choose(&mut self, head_block: u64, now_ms: u64) -> Option<(usize, ScheduleReason)> {
+        if self.queues.len() <= 1 {
+            return (!self.queues.is_empty()).then_some((0, ScheduleReason::Deadline));
+        }
+
+        let urgent = self
+            .queues
+            .iter()
+            .enumerate()
+            .filter_map(|(i, q)| q.earliest_deadline().map(|d| (d, i)))
+            .filter(|(deadline, _)| {
+                deadline.saturating_sub(head_block) <= self.config.urgent_blocks
+            })
+            .min();
+        if let Some((_, index)) = urgent {
+            return Some((index, ScheduleReason::Urgent));
+        }
+
+        let starved = self
+            .queues
+            .iter()
+            .enumerate()
+            .filter_map(|(i, q)| q.oldest_enqueued().map(|t| (t, i)))
+            .filter(|(enqueued, _)| now_ms.saturating_sub(*enqueued) >= self.config.starvation_ms)
+            .min();
+        if let Some((_, index)) = starved {
+            return Some((index, ScheduleReason::Starvation));
+        }
+
+        // Deficit round-robin: each turn, a target sends up to its weight in responses.
+        let index = self.cursor;
+        let weight = u64::from(self.config.weight(&self.queues[index].target));
+        let queue = &mut self.queues[index];
+        if queue.deficit == 0 {
+            queue.deficit = weight;
+        }
+        queue.deficit -= 1;
+        if queue.deficit == 0 {
+            self.cursor = (index + 1) % self.queues.len();
+        }
+        Some((index, ScheduleReason::Fair))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(byte: u8) -> OracleTarget {
+        OracleTarget::new(31337, Address::repeat_byte(byte))
+    }
+
+    #[test]
+    fn single_target_is_deadline_ordered() {
+        let mut scheduler = FairScheduler::new(SchedulerConfig::default());
+        let deadlines = [40, 12, 33, 12, 90, 7];
+        for (i, deadline) in deadlines.iter().enumerate() {
+            scheduler.push(target(1), *deadline, 0, i);
+        }
+        let mut order = Vec::new();
+        while let Some(next) = scheduler.pop(0, 60_000) {
+            assert_eq!(next.reason, ScheduleReason::Deadline);
+            order.push((next.deadline_block, next.item));
+        }
+        assert_eq!(order, vec![
+            (7, 5),
+            (12, 1),
+            (12, 3),
+            (33, 2),
+            (40, 0),
+            (90, 4)
+        ]);
+    }
+
+    #[test]
+    fn retained_items_keep_their_order() {
+        let mut scheduler = FairScheduler::new(SchedulerConfig::default());
+        scheduler.push(target(1), 30, 0, 1);
+        scheduler.push(target(2), 20, 0, 2);
+        scheduler.push(target(1), 10, 0, 3);
+        assert_eq!(scheduler.retain(|item| *item != 2), 1);
+        assert_eq!(scheduler.retain(|_| true), 0);
+        assert_eq!(scheduler.pop(0, 0).map(|next| next.item), Some(3));
+        assert_eq!(scheduler.pop(0, 0).map(|next| next.item), Some(1));
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn flooded_target_does_not_starve_the_other() {
+        let (a, b) = (target(0xa), target(0xb));
+        let config = SchedulerConfig {
+            weights: HashMap::from([(a.oracle, 20)]),
+            default_weight: 1,
+            urgent_blocks: 2,
+            starvation_ms: 5_000,
+        };
+        let mut scheduler = FairScheduler::new(config.clone());
+        // One response per block and per second. A opens with a burst and keeps the workers
+        // saturated with short windows; B queues a few long ones.
+        const B_COUNT: u64 = 3;
+        for i in 0..5 {
+            scheduler.push(a, 15, 0, ('a', i));
+        }
+        for i in 0..B_COUNT {
+            scheduler.push(b, 1_000, 0, ('b', i));
+        }
+
+        let mut served_b = 0;
+        for head in 0..200u64 {
+            let now_ms = head * 1_000;
+            if (1..40).contains(&head) {
+                scheduler.push(a, head + 15, now_ms, ('a', head + 4));
+            }
+            let Some(next) = scheduler.pop(head, now_ms) else {
+                break;
+            };
+            assert!(
+                head <= next.deadline_block,
+                "{:?} missed its deadline at {head}",
+                next.item
+            );
+            if next.target == b {
+                served_b += 1;
+                // Escalation starts at the threshold, then B's backlog drains one per block.
+                assert!(
+                    next.waited_ms <= config.starvation_ms + B_COUNT * 1_000,
+                    "{:?} waited {}ms",
+                    next.item,
+                    next.waited_ms
+                );
+            }
+        }
+        assert_eq!(served_b, B_COUNT);
+        assert!(scheduler.is_empty());
+        let oracle = b.oracle.to_string();
+        assert!(
+            METRICS
+                .histogram(SCHEDULER_WAIT_METRIC, &[("oracle", &oracle)])
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,453 @@
+//! Registry of the response schemas the oracle expects, per challenge kind and version.
+//!
+//! The oracle publishes a schema hash and URI per kind, either on-chain through
+//! `IPhalaSlaOracle.responseSchemas()` or as a manifest signed by a configured key. The registry
+//! fetches them at startup and periodically, compares each against the encoders compiled into
+//! [`crate::encoding`], and refuses to respond to kinds whose published hash we don't implement.
+
+use crate::IPhalaSlaOracle;
+use crate::config::{env_address, env_opt, env_or};
+use crate::encoding::{ResponseEncoder, SchemaKey, encoder_for, encoders};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::sanitize;
+use blueprint_sdk::alloy::primitives::{Address, B256, PrimitiveSignature, keccak256};
+use blueprint_sdk::alloy::providers::Provider;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Gauge set to 1 for every published kind we refuse to respond to.
+pub const SCHEMA_MISMATCH_METRIC: &str = "phala_avs_response_schema_mismatch";
+
+/// A response schema as published by the oracle.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishedSchema {
+    pub kind: B256,
+    pub version: u32,
+    pub schema_hash: B256,
+    pub uri: String,
+}
+
+impl PublishedSchema {
+    pub fn key(&self) -> SchemaKey {
+        SchemaKey {
+            kind: self.kind,
+            version: self.version,
+        }
+    }
+}
+
+/// Published schemas plus an EIP-191 signature over the keccak256 of their JSON encoding.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedManifest {
+    pub schemas: Vec<PublishedSchema>,
+    /// Hex-encoded 65-byte signature.
+    pub signature: String,
+}
+
+impl SignedManifest {
+    pub fn digest(schemas: &[PublishedSchema]) -> Result<B256, PhalaAvsError> {
+        let encoded = serde_json::to_vec(schemas)
+            .map_err(|e| PhalaAvsError::Other(format!("Failed to encode manifest: {e}")))?;
+        Ok(keccak256(encoded))
+    }
+
+    /// Returns the schemas if the manifest was signed by `signer`.
+    pub fn verify(self, signer: Address) -> Result<Vec<PublishedSchema>, PhalaAvsError> {
+        let invalid = |reason: String| {
+            PhalaAvsError::ValidationError(format!("invalid schema manifest: {reason}"))
+        };
+        let raw = hex::decode(self.signature.trim_start_matches("0x"))
+            .map_err(|e| invalid(e.to_string()))?;
+        let signature =
+            PrimitiveSignature::try_from(raw.as_slice()).map_err(|e| invalid(e.to_string()))?;
+        let recovered = signature
+            .recover_address_from_msg(Self::digest(&self.schemas)?)
+            .map_err(|e| invalid(e.to_string()))?;
+        if recovered != signer {
+            return Err(invalid(format!("signed by {recovered}, expected {signer}")));
+        }
+        Ok(self.schemas)
+    }
+}
+
+/// Where the published schemas come from.
+pub trait SchemaSource: Send + Sync {
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<PublishedSchema>, PhalaAvsError>>;
+}
+
+/// Reads the schemas published on the oracle contract.
+pub struct OracleSchemaSource<P> {
+    provider: P,
+    oracle: Address,
+}
+
+impl<P> OracleSchemaSource<P> {
+    pub fn new(provider: P, oracle: Address) -> Self {
+        Self { provider, oracle }
+    }
+}
+
+impl<P: Provider + Send + Sync + 'static> SchemaSource for OracleSchemaSource<P> {
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<PublishedSchema>, PhalaAvsError>> {
+        Box::pin(async move {
+            let schemas = IPhalaSlaOracle::new(self.oracle, &self.provider)
+                .responseSchemas()
+                .call()
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(format!("responseSchemas failed: {e}")))?
+                ._0;
+            Ok(schemas
+                .into_iter()
+                .map(|s| PublishedSchema {
+                    kind: s.kind,
+                    version: s.version,
+                    schema_hash: s.schemaHash,
+                    uri: s.uri,
+                })
+                .collect())
+        })
+    }
+}
+
+/// Downloads a [`SignedManifest`] and verifies it against the expected signer.
+pub struct ManifestSchemaSource {
+    url: String,
+    signer: Address,
+}
+
+impl ManifestSchemaSource {
+    pub fn new(url: String, signer: Address) -> Self {
+        Self { url, signer }
+    }
+}
+
+impl SchemaSource for ManifestSchemaSource {
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<PublishedSchema>, PhalaAvsError>> {
+        Box::pin(async move {
+            let manifest: SignedManifest = reqwest::get(&self.url)
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| PhalaAvsError::Other(format!("Failed to fetch schema manifest: {e}")))?
+                .json()
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::ValidationError(format!("invalid schema manifest: {e}"))
+                })?;
+            manifest.verify(self.signer)
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SchemaRegistryConfig {
+    pub refresh_secs: u64,
+    /// Manifest URL and its signer; the oracle contract is read when unset.
+    pub manifest: Option<(String, Address)>,
+}
+
+impl SchemaRegistryConfig {
+    /// Reads `SCHEMA_REFRESH_SECS`, `SCHEMA_MANIFEST_URL` and `SCHEMA_MANIFEST_SIGNER`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let manifest = match (
+            env_opt::<String>("SCHEMA_MANIFEST_URL")?,
+            env_address("SCHEMA_MANIFEST_SIGNER")?,
+        ) {
+            (Some(url), Some(signer)) => Some((url, signer)),
+            (None, None) => None,
+            _ => {
+                return Err(PhalaAvsError::ConfigError(
+                    "SCHEMA_MANIFEST_URL and SCHEMA_MANIFEST_SIGNER must be set together"
+                        .to_string(),
+                ));
+            }
+        };
+        Ok(Self {
+            refresh_secs: env_or("SCHEMA_REFRESH_SECS", 600)?,
+            manifest,
+        })
+    }
+}
+
+/// How a challenge kind's published schema compares to what we implement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaStatus {
+    /// The published hash is the one our encoder produces.
+    Match,
+    /// We have an encoder for the kind, but it produces a different schema.
+    Mismatch,
+    /// The oracle publishes a kind we have no encoder for.
+    Unimplemented,
+    /// We implement the kind, but the oracle doesn't publish it.
+    Unpublished,
+}
+
+impl SchemaStatus {
+    /// Whether responses may be sent for a kind in this state.
+    pub fn permits_response(self) -> bool {
+        matches!(self, Self::Match | Self::Unpublished)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SchemaCheck {
+    pub key: SchemaKey,
+    /// The kind's name, when we implement it.
+    pub kind_name: Option<&'static str>,
+    pub status: SchemaStatus,
+    pub published_hash: Option<B256>,
+    pub implemented_hash: Option<B256>,
+    pub uri: Option<String>,
+}
+
+/// Compares `published` against the compiled encoders.
+pub fn compare(published: &[PublishedSchema]) -> Vec<SchemaCheck> {
+    let mut checks: BTreeMap<SchemaKey, SchemaCheck> = BTreeMap::new();
+    for encoder in encoders() {
+        checks.insert(encoder.key(), SchemaCheck {
+            key: encoder.key(),
+            kind_name: Some(encoder.kind_name()),
+            status: SchemaStatus::Unpublished,
+            published_hash: None,
+            implemented_hash: Some(encoder.schema_hash()),
+            uri: None,
+        });
+    }
+    for schema in published {
+        let encoder = encoder_for(&schema.key());
+        let status = match encoder {
+            Some(e) if e.schema_hash() == schema.schema_hash => SchemaStatus::Match,
+            Some(_) => SchemaStatus::Mismatch,
+            None => SchemaStatus::Unimplemented,
+        };
+        checks.insert(schema.key(), SchemaCheck {
+            key: schema.key(),
+            kind_name: encoder.map(|e| e.kind_name()),
+            status,
+            published_hash: Some(schema.schema_hash),
+            implemented_hash: encoder.map(|e| e.schema_hash()),
+            // An unusable URI is dropped; the hash comparison doesn't depend on it.
+            uri: sanitize::uri("schema_uri", &schema.uri)
+                .ok()
+                .map(str::to_string),
+        });
+    }
+    checks.into_values().collect()
+}
+
+/// The latest comparison of published and implemented schemas.
+pub struct SchemaRegistry {
+    config: SchemaRegistryConfig,
+    source: Arc<dyn SchemaSource>,
+    checks: RwLock<Option<Vec<SchemaCheck>>>,
+}
+
+impl SchemaRegistry {
+    pub fn new(config: SchemaRegistryConfig, source: Arc<dyn SchemaSource>) -> Self {
+        Self {
+            config,
+            source,
+            checks: RwLock::new(None),
+        }
+    }
+
+    /// Fetches the published schemas and recomputes the comparison, alerting on every kind we
+    /// can no longer respond to.
+    pub async fn refresh(&self) -> Result<Vec<SchemaCheck>, PhalaAvsError> {
+        let checks = compare(&self.source.fetch().await?);
+        for check in &checks {
+            let kind = check.key.to_string();
+            let refused = !check.status.permits_response();
+            METRICS.set_gauge(
+                SCHEMA_MISMATCH_METRIC,
+                &[("kind", &kind)],
+                if refused { 1.0 } else { 0.0 },
+            );
+            if refused {
+                warn!(
+                    "Oracle publishes response schema {} for {kind} ({:?}, ours: {:?}); refusing to respond to it until the operator is upgraded",
+                    check.published_hash.unwrap_or_default(),
+                    check.status,
+                    check.implemented_hash
+                );
+            }
+        }
+        info!("Verified {} response schemas", checks.len());
+        *self.checks.write().unwrap_or_else(|e| e.into_inner()) = Some(checks.clone());
+        Ok(checks)
+    }
+
+    /// The latest comparison, or `None` before the first successful refresh.
+    pub fn checks(&self) -> Option<Vec<SchemaCheck>> {
+        self.checks
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// The encoder to answer a challenge of `key` with, unless the oracle expects a schema we
+    /// don't implement.
+    ///
+    /// Before the first successful refresh, compiled encoders are used as-is.
+    pub fn encoder(&self, key: &SchemaKey) -> Result<&'static dyn ResponseEncoder, PhalaAvsError> {
+        let checks = self.checks.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(check) = checks.iter().flatten().find(|c| c.key == *key) {
+            if !check.status.permits_response() {
+                return Err(PhalaAvsError::ValidationError(format!(
+                    "oracle expects response schema {} for {key}, which this operator doesn't implement",
+                    check.published_hash.unwrap_or_default()
+                )));
+            }
+        }
+        encoder_for(key)
+            .ok_or_else(|| PhalaAvsError::ValidationError(format!("no response encoder for {key}")))
+    }
+}
+
+/// Refreshes `registry` every `refresh_secs`, in the background.
+pub fn spawn_refresh(registry: Arc<SchemaRegistry>) {
+    tokio::spawn(async move {
+        let period = Duration::from_secs(registry.config.refresh_secs);
+        loop {
+            tokio::time::sleep(period).await;
+            if let Err(e) = registry.refresh().await {
+                warn!("Failed to refresh response schemas: {e}");
+            }
+        }
+    });
+}
+
+/// Builds the registry's source from its configuration.
+pub fn source_from_config(
+    config: &SchemaRegistryConfig,
+    provider: impl Provider + Send + Sync + 'static,
+    oracle: Address,
+) -> Arc<dyn SchemaSource> {
+    match &config.manifest {
+        Some((url, signer)) => Arc::new(ManifestSchemaSource::new(url.clone(), *signer)),
+        None => Arc::new(OracleSchemaSource::new(provider, oracle)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{LIVENESS_KIND, LivenessEncoderV1, kind_id};
+    use blueprint_sdk::alloy::signers::SignerSync;
+    use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+
+    struct FixedSource(Vec<PublishedSchema>);
+
+    impl SchemaSource for FixedSource {
+        fn fetch(&self) -> BoxFuture<'_, Result<Vec<PublishedSchema>, PhalaAvsError>> {
+            Box::pin(async move { Ok(self.0.clone()) })
+        }
+    }
+
+    fn published(kind: &str, version: u32, hash: B256) -> PublishedSchema {
+        PublishedSchema {
+            kind: kind_id(kind),
+            version,
+            schema_hash: hash,
+            uri: format!("ipfs://{kind}/{version}"),
+        }
+    }
+
+    fn registry(schemas: Vec<PublishedSchema>) -> SchemaRegistry {
+        let config = SchemaRegistryConfig {
+            refresh_secs: 600,
+            manifest: None,
+        };
+        SchemaRegistry::new(config, Arc::new(FixedSource(schemas)))
+    }
+
+    #[tokio::test]
+    async fn matching_schema_permits_responses() {
+        let liveness = SchemaKey::new(LIVENESS_KIND, 1);
+        let registry = registry(vec![published(
+            LIVENESS_KIND,
+            1,
+            LivenessEncoderV1.schema_hash(),
+        )]);
+        let checks = registry.refresh().await.unwrap();
+        let check = checks.iter().find(|c| c.key == liveness).unwrap();
+        assert_eq!(check.status, SchemaStatus::Match);
+        assert_eq!(registry.encoder(&liveness).unwrap().version(), 1);
+    }
+
+    #[tokio::test]
+    async fn mismatched_or_unknown_schema_is_refused_and_alerted() {
+        let liveness = SchemaKey::new(LIVENESS_KIND, 1);
+        let registry = registry(vec![
+            published(LIVENESS_KIND, 1, B256::repeat_byte(7)),
+            published("compute", 1, B256::repeat_byte(8)),
+        ]);
+        // Compiled encoders are used until the published schemas are known.
+        assert!(registry.encoder(&liveness).is_ok());
+
+        let checks = registry.refresh().await.unwrap();
+        let status: Vec<_> = checks.iter().map(|c| c.status).collect();
+        assert!(status.contains(&SchemaStatus::Mismatch));
+        assert!(status.contains(&SchemaStatus::Unimplemented));
+
+        let err = registry.encoder(&liveness).err().unwrap();
+        assert!(err.to_string().contains(&B256::repeat_byte(7).to_string()));
+        assert!(registry.encoder(&SchemaKey::new("compute", 1)).is_err());
+        let compute = SchemaKey::new("compute", 1).to_string();
+        assert_eq!(
+            METRICS.gauge(SCHEMA_MISMATCH_METRIC, &[("kind", &compute)]),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn manifest_signature_is_verified() {
+        let signer = PrivateKeySigner::random();
+        let schemas = vec![published(LIVENESS_KIND, 1, LivenessEncoderV1.schema_hash())];
+        let digest = SignedManifest::digest(&schemas).unwrap();
+        let signature = signer.sign_message_sync(digest.as_slice()).unwrap();
+        let manifest = SignedManifest {
+            schemas: schemas.clone(),
+            signature: hex::encode(signature.as_bytes()),
+        };
+
+        assert_eq!(manifest.clone().verify(signer.address()).unwrap(), schemas);
+        assert!(manifest.clone().verify(Address::repeat_byte(1)).is_err());
+
+        let mut tampered = manifest;
+        tampered.schemas[0].schema_hash = B256::repeat_byte(9);
+        assert!(tampered.verify(signer.address()).is_err());
+    }
+
+    #[test]
+    fn unusable_uris_are_dropped_but_still_compared() {
+        let hash = LivenessEncoderV1.schema_hash();
+        let mut hostile: Vec<String> = crate::sanitize::adversarial::strings();
+        hostile.push("https://gіthub.com/schemas/liveness".to_string());
+        hostile.push("http://schemas.example/liveness".to_string());
+        for uri in hostile {
+            let schema = PublishedSchema {
+                uri: uri.clone(),
+                ..published(LIVENESS_KIND, 1, hash)
+            };
+            let checks = compare(&[schema]);
+            let liveness = SchemaKey::new(LIVENESS_KIND, 1);
+            let check = checks.iter().find(|c| c.key == liveness).unwrap();
+            assert_eq!(check.status, SchemaStatus::Match);
+            match &check.uri {
+                Some(kept) => assert_eq!(kept, &uri),
+                None => assert!(crate::sanitize::uri("schema_uri", &uri).is_err()),
+            }
+        }
+        let kept = compare(&[published(LIVENESS_KIND, 1, hash)]);
+        assert!(
+            kept.iter()
+                .any(|c| c.uri.as_deref() == Some("ipfs://liveness/1"))
+        );
+    }
+}
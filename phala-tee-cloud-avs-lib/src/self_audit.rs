@@ -0,0 +1,1170 @@
+//! Reconciliation of the operator's own response records against the oracle's.
+//!
+//! A divergence between what we think happened and what the oracle recorded otherwise surfaces
+//! as a slash. On `SELF_AUDIT_SCHEDULE` (daily by default), every challenge whose response window
+//! closed in the last `SELF_AUDIT_EPOCHS` epochs of `SELF_AUDIT_EPOCH_BLOCKS` is compared with the
+//! oracle's `getChallengeDetails`, read in multicall batches walking back from
+//! `challengeCounter`. Each challenge lands in one category:
+//!
+//! - matched: both sides agree on whether we responded;
+//! - `responded_not_on_chain`: we recorded a response the oracle does not have. A critical alert
+//!   is raised and a [`DisputeBundle`] is generated and persisted;
+//! - `unseen`: the oracle issued us a challenge we never tracked, i.e. event delivery failed. A
+//!   critical alert is raised and the issuing block is queued on the
+//!   [log checker](crate::log_consistency), which recovers the log for normal processing;
+//! - `rejected_by_oracle`: our response was included, but the oracle rejected it (see
+//!   [`crate::receipts`]), so the challenge expires unless answered again. A critical alert is
+//!   raised and the [`DisputeBundle`] filed when the rejection was found is refreshed;
+//! - `mismatch`: any other disagreement, e.g. a response on-chain we recorded as missed.
+//!
+//! Response latencies are compared too: ours from first sight to `Responded`, the chain's from
+//! issuance to the `SlaChallengeResponded` block. The latest [`SelfAuditReport`] is persisted,
+//! served at `/export/self-audit`, exported as gauges and, with `SELF_AUDIT_GATE_READINESS`,
+//! fails `/readyz` while it has critical divergences. Both the report and the dispute bundles
+//! are served with the operator's [annotations](crate::annotations) on the entities they cover.
+
+use crate::IPhalaSlaOracle;
+use crate::IPhalaSlaOracle::SlaChallengeResponded;
+use crate::SLA_ORACLE_ADDRESS;
+use crate::annotations::{Annotation, AnnotationTarget, Annotations};
+use crate::challenge::{ChallengeState, ChallengeTracker, TrackedChallenge};
+use crate::config::{env_flag, env_or};
+use crate::error::PhalaAvsError;
+use crate::evidence::{
+    EvidenceAnchorer, EvidenceLog, InclusionProof, RESPONSE_EVIDENCE, ResponseEvidence,
+};
+use crate::evm::BoxFuture;
+use crate::log_consistency::LogConsistencyChecker;
+use crate::metrics::METRICS;
+use crate::multicall::{MULTICALL3_ADDRESS, MulticallBatch, decode};
+use crate::notify::{Alert, Notifier, Severity};
+use crate::receipts::OracleRejection;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{Address, U256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::Filter;
+use blueprint_sdk::alloy::sol_types::SolEvent;
+use blueprint_sdk::evm::util::get_provider_http;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Gauge of divergences found by the latest audit, by category.
+pub const SELF_AUDIT_DIVERGENCES_METRIC: &str = "phala_avs_self_audit_divergences";
+/// Gauge of challenges the latest audit found in agreement.
+pub const SELF_AUDIT_MATCHED_METRIC: &str = "phala_avs_self_audit_matched";
+/// Gauge of response latency quantiles of the latest audit, by source (`local_ms`,
+/// `chain_blocks`) and quantile.
+pub const SELF_AUDIT_LATENCY_METRIC: &str = "phala_avs_self_audit_response_latency";
+/// Gauge: unix time of the latest audit.
+pub const SELF_AUDIT_LAST_RUN_METRIC: &str = "phala_avs_self_audit_last_run_unix";
+
+const NAMESPACE: &str = "self_audit";
+const DISPUTES_NAMESPACE: &str = "self_audit_disputes";
+const REPORT_KEY: &[u8] = b"report";
+/// Challenge ids named in an alert before the rest are summarized.
+const ALERT_IDS: usize = 5;
+
+#[derive(Clone, Debug)]
+pub struct SelfAuditConfig {
+    pub enabled: bool,
+    /// Cron schedule of the audit job, with seconds.
+    pub schedule: String,
+    /// Epochs audited, counting back from head.
+    pub epochs: u64,
+    pub epoch_blocks: u64,
+    /// Challenge details read per multicall.
+    pub batch_size: usize,
+    /// Most challenge ids read back from `challengeCounter` per audit.
+    pub max_challenges: u64,
+    /// Fail `/readyz` while the latest report has critical divergences.
+    pub gate_readiness: bool,
+    pub multicall: Address,
+}
+
+impl SelfAuditConfig {
+    /// Reads `SELF_AUDIT_ENABLED`, `SELF_AUDIT_SCHEDULE`, `SELF_AUDIT_EPOCHS`,
+    /// `SELF_AUDIT_EPOCH_BLOCKS`, `SELF_AUDIT_BATCH_SIZE`, `SELF_AUDIT_MAX_CHALLENGES`,
+    /// `SELF_AUDIT_GATE_READINESS` and `MULTICALL_ADDRESS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let config = Self {
+            enabled: env_flag("SELF_AUDIT_ENABLED", true)?,
+            schedule: env_or("SELF_AUDIT_SCHEDULE", "0 0 3 * * *".to_string())?,
+            epochs: env_or("SELF_AUDIT_EPOCHS", 7)?,
+            epoch_blocks: env_or("SELF_AUDIT_EPOCH_BLOCKS", 7_200)?,
+            batch_size: env_or("SELF_AUDIT_BATCH_SIZE", 200)?,
+            max_challenges: env_or("SELF_AUDIT_MAX_CHALLENGES", 10_000)?,
+            gate_readiness: env_flag("SELF_AUDIT_GATE_READINESS", false)?,
+            multicall: env_or("MULTICALL_ADDRESS", MULTICALL3_ADDRESS)?,
+        };
+        if config.batch_size == 0 {
+            return Err(PhalaAvsError::ConfigError(
+                "SELF_AUDIT_BATCH_SIZE must be at least 1".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Blocks audited, counting back from head.
+    pub fn span_blocks(&self) -> u64 {
+        self.epochs.saturating_mul(self.epoch_blocks)
+    }
+}
+
+/// The oracle's record of a challenge.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OracleChallenge {
+    pub challenge_id: U256,
+    /// Zero for an id the oracle does not know.
+    pub operator: Address,
+    pub deadline_block: u64,
+    pub responded: bool,
+    pub reported: bool,
+}
+
+/// The oracle's authoritative challenge state.
+pub trait OracleLedger: Send + Sync {
+    /// Challenges issued so far; ids run from 1 to this count.
+    fn challenge_count(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+
+    /// The records of `ids`, in order.
+    fn challenges(
+        &self,
+        ids: Vec<u64>,
+    ) -> BoxFuture<'_, Result<Vec<OracleChallenge>, PhalaAvsError>>;
+
+    fn response_window_blocks(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+
+    /// The block of each `SlaChallengeResponded` of `operator` in `[from_block, to_block]`, by
+    /// challenge id.
+    fn response_blocks(
+        &self,
+        operator: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<BTreeMap<U256, u64>, PhalaAvsError>>;
+}
+
+/// [`OracleLedger`] read from the `PhalaSlaOracle` contract.
+#[derive(Clone, Debug)]
+pub struct SlaOracleLedger {
+    oracle: Address,
+    multicall: Address,
+    rpc_url: String,
+}
+
+impl SlaOracleLedger {
+    pub fn new(oracle: Address, multicall: Address, rpc_url: String) -> Self {
+        Self {
+            oracle,
+            multicall,
+            rpc_url,
+        }
+    }
+
+    /// Uses `SLA_ORACLE_ADDRESS`.
+    pub fn from_env(rpc_url: String, multicall: Address) -> Self {
+        Self::new(*SLA_ORACLE_ADDRESS, multicall, rpc_url)
+    }
+}
+
+fn evm_err(call: &str, e: impl std::fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::EvmError(format!("{call} failed: {e}"))
+}
+
+impl OracleLedger for SlaOracleLedger {
+    fn challenge_count(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            let count = IPhalaSlaOracle::new(self.oracle, &provider)
+                .challengeCounter()
+                .call()
+                .await
+                .map_err(|e| evm_err("challengeCounter", e))?
+                ._0;
+            u64::try_from(count).map_err(|_| {
+                PhalaAvsError::EvmError(format!("challengeCounter {count} is out of range"))
+            })
+        })
+    }
+
+    fn challenges(
+        &self,
+        ids: Vec<u64>,
+    ) -> BoxFuture<'_, Result<Vec<OracleChallenge>, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            let mut batch = MulticallBatch::new();
+            for &id in &ids {
+                batch.add(self.oracle, &IPhalaSlaOracle::getChallengeDetailsCall {
+                    challengeId: U256::from(id),
+                });
+            }
+            let results = batch.execute(&provider, self.multicall).await?;
+            ids.iter()
+                .zip(&results)
+                .map(|(&id, data)| {
+                    let details =
+                        decode::<IPhalaSlaOracle::getChallengeDetailsCall>(data.as_ref())?;
+                    Ok(OracleChallenge {
+                        challenge_id: U256::from(id),
+                        operator: details.operator,
+                        deadline_block: details.responseWindowEndBlock.saturating_to(),
+                        responded: details.responded,
+                        reported: details.reported,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn response_window_blocks(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            let window = IPhalaSlaOracle::new(self.oracle, &provider)
+                .responseWindowBlocks()
+                .call()
+                .await
+                .map_err(|e| evm_err("responseWindowBlocks", e))?
+                ._0;
+            Ok(window.saturating_to())
+        })
+    }
+
+    fn response_blocks(
+        &self,
+        operator: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<BTreeMap<U256, u64>, PhalaAvsError>> {
+        Box::pin(async move {
+            let provider = get_provider_http(&self.rpc_url);
+            let filter = Filter::new()
+                .address(self.oracle)
+                .event_signature(SlaChallengeResponded::SIGNATURE_HASH)
+                .topic2(operator.into_word())
+                .from_block(from_block)
+                .to_block(to_block);
+            let logs = provider
+                .get_logs(&filter)
+                .await
+                .map_err(|e| evm_err("eth_getLogs", e))?;
+            Ok(logs
+                .iter()
+                .filter_map(|log| {
+                    let event = log.log_decode::<SlaChallengeResponded>().ok()?;
+                    Some((event.inner.data.challengeId, log.block_number?))
+                })
+                .collect())
+        })
+    }
+}
+
+/// How a challenge's local and on-chain records disagree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DivergenceKind {
+    /// We recorded a response the oracle does not have.
+    RespondedNotOnChain,
+    /// The oracle issued us a challenge we never tracked.
+    Unseen,
+    /// The oracle rejected a response of ours that was included.
+    RejectedByOracle,
+    /// Any other disagreement.
+    Mismatch,
+}
+
+impl DivergenceKind {
+    pub const ALL: [Self; 4] = [
+        Self::RespondedNotOnChain,
+        Self::Unseen,
+        Self::RejectedByOracle,
+        Self::Mismatch,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::RespondedNotOnChain => "responded_not_on_chain",
+            Self::Unseen => "unseen",
+            Self::RejectedByOracle => "rejected_by_oracle",
+            Self::Mismatch => "mismatch",
+        }
+    }
+
+    pub fn severity(self) -> Severity {
+        match self {
+            Self::RespondedNotOnChain | Self::Unseen | Self::RejectedByOracle => Severity::Critical,
+            Self::Mismatch => Severity::Warning,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Divergence {
+    pub challenge_id: U256,
+    pub kind: DivergenceKind,
+    pub deadline_block: u64,
+    /// `None` when we never tracked the challenge.
+    pub local_state: Option<ChallengeState>,
+    /// `None` when the oracle has no record of the challenge for us.
+    pub chain_responded: Option<bool>,
+    pub chain_reported: Option<bool>,
+}
+
+/// Quantiles of a set of response latencies.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub p50: u64,
+    pub p90: u64,
+    pub max: u64,
+}
+
+impl LatencySummary {
+    pub fn of(mut values: Vec<u64>) -> Self {
+        values.sort_unstable();
+        let quantile = |q: f64| {
+            let index = ((values.len() as f64 * q).ceil() as usize).saturating_sub(1);
+            values.get(index).copied().unwrap_or_default()
+        };
+        Self {
+            count: values.len(),
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+            max: values.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Our response latency against the oracle's, over the challenges both agree we answered.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyComparison {
+    /// Milliseconds from first sight to `Responded`.
+    pub local_ms: LatencySummary,
+    /// Blocks from issuance to the `SlaChallengeResponded` block.
+    pub chain_blocks: LatencySummary,
+}
+
+/// The result of the latest audit.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelfAuditReport {
+    pub ran_unix_ms: u64,
+    /// Challenges whose response window closed in `[from_block, to_block)` were audited.
+    pub from_block: u64,
+    pub to_block: u64,
+    pub matched: usize,
+    pub divergences: Vec<Divergence>,
+    pub latency: LatencyComparison,
+    /// Challenges a dispute bundle was generated for.
+    pub disputes: Vec<U256>,
+    /// The walk back from `challengeCounter` stopped at `SELF_AUDIT_MAX_CHALLENGES` before
+    /// reaching `from_block`, so older challenges were not audited.
+    pub truncated: bool,
+    /// Notes on the audited epochs and the divergent challenges, attached when served.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
+}
+
+impl SelfAuditReport {
+    pub fn count(&self, kind: DivergenceKind) -> usize {
+        self.divergences.iter().filter(|d| d.kind == kind).count()
+    }
+
+    /// Divergences that risk a slash.
+    pub fn critical(&self) -> usize {
+        self.divergences
+            .iter()
+            .filter(|d| d.kind.severity() == Severity::Critical)
+            .count()
+    }
+}
+
+/// What we hold to contest the oracle's record of a challenge we responded to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DisputeBundle {
+    pub challenge_id: U256,
+    pub generated_unix_ms: u64,
+    /// Our record of the challenge, with its transitions.
+    pub tracked: TrackedChallenge,
+    pub oracle: OracleChallenge,
+    pub response_evidence: Vec<ResponseEvidence>,
+    /// Inclusion proofs of the evidence recorded while the challenge was open, for the windows
+    /// already anchored.
+    pub proofs: Vec<InclusionProof>,
+    /// The oracle's rejection of our included response, if it rejected it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<OracleRejection>,
+    /// Notes on the challenge and the epoch of its deadline, attached when served.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
+}
+
+/// Audits the operator's records against the oracle's.
+pub struct SelfAuditor {
+    config: SelfAuditConfig,
+    operator: Address,
+    ledger: Arc<dyn OracleLedger>,
+    tracker: Arc<ChallengeTracker>,
+    evidence: EvidenceLog,
+    anchorer: Option<Arc<EvidenceAnchorer>>,
+    log_checker: Option<Arc<LogConsistencyChecker>>,
+    annotations: Option<Arc<Annotations>>,
+    store: Arc<dyn StateStore>,
+    report: Mutex<Option<SelfAuditReport>>,
+}
+
+impl SelfAuditor {
+    /// Resumes from the persisted report, so readiness stays gated across restarts.
+    pub fn new(
+        config: SelfAuditConfig,
+        operator: Address,
+        ledger: Arc<dyn OracleLedger>,
+        tracker: Arc<ChallengeTracker>,
+        store: Arc<dyn StateStore>,
+    ) -> Result<Self, PhalaAvsError> {
+        let report = store.get_json(NAMESPACE, REPORT_KEY)?;
+        Ok(Self {
+            config,
+            operator,
+            ledger,
+            tracker,
+            evidence: EvidenceLog::new(Arc::clone(&store)),
+            anchorer: None,
+            log_checker: None,
+            annotations: None,
+            store,
+            report: Mutex::new(report),
+        })
+    }
+
+    /// Attaches inclusion proofs from `anchorer` to dispute bundles.
+    pub fn with_anchorer(mut self, anchorer: Option<Arc<EvidenceAnchorer>>) -> Self {
+        self.anchorer = anchorer;
+        self
+    }
+
+    /// Queues the issuing blocks of unseen challenges on `log_checker`.
+    pub fn with_log_checker(mut self, log_checker: Option<Arc<LogConsistencyChecker>>) -> Self {
+        self.log_checker = log_checker;
+        self
+    }
+
+    /// Serves reports and dispute bundles with the notes from `annotations`.
+    pub fn with_annotations(mut self, annotations: Arc<Annotations>) -> Self {
+        self.annotations = Some(annotations);
+        self
+    }
+
+    pub fn config(&self) -> &SelfAuditConfig {
+        &self.config
+    }
+
+    /// The latest report, `None` before the first audit.
+    pub fn report(&self) -> Option<SelfAuditReport> {
+        let report = self
+            .report
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()?;
+        Some(self.annotate_report(report))
+    }
+
+    /// The dispute bundle generated for a challenge.
+    pub fn dispute(&self, challenge_id: &U256) -> Result<Option<DisputeBundle>, PhalaAvsError> {
+        let bundle: Option<DisputeBundle> = self
+            .store
+            .get_json(DISPUTES_NAMESPACE, &challenge_id.to_be_bytes::<32>())?;
+        bundle.map(|b| self.annotate_bundle(b)).transpose()
+    }
+
+    /// Attaches the notes on the report's epochs and divergent challenges; a report is still
+    /// served when they cannot be read.
+    fn annotate_report(&self, mut report: SelfAuditReport) -> SelfAuditReport {
+        let Some(annotations) = &self.annotations else {
+            return report;
+        };
+        let epoch_blocks = self.config.epoch_blocks.max(1);
+        let epochs =
+            (report.from_block / epoch_blocks)..=(report.to_block.saturating_sub(1) / epoch_blocks);
+        let targets = epochs.map(AnnotationTarget::Epoch).chain(
+            report
+                .divergences
+                .iter()
+                .map(|d| AnnotationTarget::Challenge(d.challenge_id)),
+        );
+        match annotations.for_targets(targets) {
+            Ok(notes) => report.annotations = notes,
+            Err(e) => warn!("Failed to read self-audit annotations: {e}"),
+        }
+        report
+    }
+
+    /// Attaches the notes on the disputed challenge and the epoch of its deadline.
+    fn annotate_bundle(&self, mut bundle: DisputeBundle) -> Result<DisputeBundle, PhalaAvsError> {
+        if let Some(annotations) = &self.annotations {
+            let epoch = bundle.tracked.challenge.deadline_block / self.config.epoch_blocks.max(1);
+            bundle.annotations = annotations.for_targets([
+                AnnotationTarget::Challenge(bundle.challenge_id),
+                AnnotationTarget::Epoch(epoch),
+            ])?;
+        }
+        Ok(bundle)
+    }
+
+    /// Files a dispute bundle for a response the oracle rejected, without waiting for the next
+    /// audit.
+    pub fn file_rejection(
+        &self,
+        tracked: &TrackedChallenge,
+        rejection: OracleRejection,
+        now_ms: u64,
+    ) -> Result<DisputeBundle, PhalaAvsError> {
+        // The oracle leaves a rejected challenge open.
+        let oracle = OracleChallenge {
+            challenge_id: tracked.challenge.challenge_id,
+            operator: self.operator,
+            deadline_block: tracked.challenge.deadline_block,
+            responded: false,
+            reported: false,
+        };
+        let bundle = self.bundle(tracked, oracle, Some(rejection), now_ms)?;
+        self.store.put_json(
+            DISPUTES_NAMESPACE,
+            &tracked.challenge.challenge_id.to_be_bytes::<32>(),
+            &bundle,
+        )?;
+        self.annotate_bundle(bundle)
+    }
+
+    /// Why readiness is gated, if `gate_readiness` is set and the latest report has critical
+    /// divergences.
+    pub fn readiness_blocker(&self) -> Option<String> {
+        if !self.config.gate_readiness {
+            return None;
+        }
+        let critical = self.report()?.critical();
+        (critical > 0).then(|| format!("self-audit found {critical} critical divergences"))
+    }
+
+    /// The oracle's records of our challenges whose window closed in `[from_block, to_block)`,
+    /// and whether the walk was cut short by `max_challenges`.
+    async fn chain_challenges(
+        &self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<(BTreeMap<U256, OracleChallenge>, bool), PhalaAvsError> {
+        let mut found = BTreeMap::new();
+        let mut next = self.ledger.challenge_count().await?;
+        let floor = next.saturating_sub(self.config.max_challenges);
+        while next > floor {
+            let low = next
+                .saturating_sub(self.config.batch_size as u64)
+                .max(floor);
+            let ids: Vec<u64> = (low + 1..=next).rev().collect();
+            let records = self.ledger.challenges(ids).await?;
+            // Ids are issued in order, so once a batch reaches deadlines before the range,
+            // every older challenge closed before it too.
+            let reached_start = records.iter().any(|c| c.deadline_block < from_block);
+            for record in records {
+                if record.operator == self.operator
+                    && (from_block..to_block).contains(&record.deadline_block)
+                {
+                    found.insert(record.challenge_id, record);
+                }
+            }
+            if reached_start {
+                return Ok((found, false));
+            }
+            next = low;
+        }
+        Ok((found, floor > 0))
+    }
+
+    /// Audits the challenges closed in the last `epochs` epochs before `head`, alerting through
+    /// `notifier`.
+    pub async fn run(
+        &self,
+        head: u64,
+        now_ms: u64,
+        notifier: &dyn Notifier,
+    ) -> Result<SelfAuditReport, PhalaAvsError> {
+        let from_block = head.saturating_sub(self.config.span_blocks());
+        let local: BTreeMap<U256, TrackedChallenge> = self
+            .tracker
+            .closed_between(from_block, head)?
+            .into_iter()
+            .filter(|t| t.challenge.operator == self.operator)
+            .map(|t| (t.challenge.challenge_id, t))
+            .collect();
+        let (chain, truncated) = self.chain_challenges(from_block, head).await?;
+        let window = self.ledger.response_window_blocks().await?;
+        let responded_at = self
+            .ledger
+            .response_blocks(self.operator, from_block.saturating_sub(window), head)
+            .await?;
+
+        let mut matched = 0;
+        let mut divergences = Vec::new();
+        let mut local_latency = Vec::new();
+        let mut chain_latency = Vec::new();
+        let ids: BTreeSet<U256> = local.keys().chain(chain.keys()).copied().collect();
+        for id in ids {
+            let tracked = local.get(&id);
+            let on_chain = chain.get(&id);
+            let locally_responded = tracked.is_some_and(|t| responded(t).is_some());
+            let kind = match (tracked, on_chain) {
+                (Some(t), _) if t.state == ChallengeState::RejectedByOracle => {
+                    Some(DivergenceKind::RejectedByOracle)
+                }
+                (Some(_), Some(c)) if locally_responded == c.responded => None,
+                (Some(_), _) if locally_responded => Some(DivergenceKind::RespondedNotOnChain),
+                (None, Some(_)) => Some(DivergenceKind::Unseen),
+                _ => Some(DivergenceKind::Mismatch),
+            };
+            let Some(kind) = kind else {
+                matched += 1;
+                if let Some(tracked) = tracked.filter(|_| locally_responded) {
+                    local_latency.extend(
+                        responded(tracked).map(|at| at.saturating_sub(tracked.latency_origin_ms())),
+                    );
+                    chain_latency.extend(
+                        responded_at
+                            .get(&id)
+                            .map(|block| block.saturating_sub(tracked.challenge.issued_block)),
+                    );
+                }
+                continue;
+            };
+            divergences.push(Divergence {
+                challenge_id: id,
+                kind,
+                deadline_block: tracked
+                    .map(|t| t.challenge.deadline_block)
+                    .or(on_chain.map(|c| c.deadline_block))
+                    .unwrap_or_default(),
+                local_state: tracked.map(|t| t.state),
+                chain_responded: on_chain.map(|c| c.responded),
+                chain_reported: on_chain.map(|c| c.reported),
+            });
+        }
+
+        let mut disputes = Vec::new();
+        for divergence in &divergences {
+            match divergence.kind {
+                DivergenceKind::RespondedNotOnChain | DivergenceKind::RejectedByOracle => {
+                    let tracked = &local[&divergence.challenge_id];
+                    // The oracle may have no record of the challenge for us at all.
+                    let oracle =
+                        chain
+                            .get(&divergence.challenge_id)
+                            .cloned()
+                            .unwrap_or_else(|| OracleChallenge {
+                                challenge_id: divergence.challenge_id,
+                                operator: Address::ZERO,
+                                deadline_block: tracked.challenge.deadline_block,
+                                responded: false,
+                                reported: false,
+                            });
+                    // Keeps the rejection recorded when the bundle was first filed.
+                    let rejection = self
+                        .dispute(&divergence.challenge_id)?
+                        .and_then(|bundle| bundle.rejection);
+                    let bundle = self.bundle(tracked, oracle, rejection, now_ms)?;
+                    self.store.put_json(
+                        DISPUTES_NAMESPACE,
+                        &divergence.challenge_id.to_be_bytes::<32>(),
+                        &bundle,
+                    )?;
+                    disputes.push(divergence.challenge_id);
+                }
+                DivergenceKind::Unseen => {
+                    if let Some(checker) = &self.log_checker {
+                        checker.recheck([divergence.deadline_block.saturating_sub(window)]);
+                    }
+                }
+                DivergenceKind::Mismatch => {}
+            }
+        }
+
+        let report = SelfAuditReport {
+            ran_unix_ms: now_ms,
+            from_block,
+            to_block: head,
+            matched,
+            divergences,
+            latency: LatencyComparison {
+                local_ms: LatencySummary::of(local_latency),
+                chain_blocks: LatencySummary::of(chain_latency),
+            },
+            disputes,
+            truncated,
+            annotations: Vec::new(),
+        };
+        export(&report);
+        self.store.put_json(NAMESPACE, REPORT_KEY, &report)?;
+        *self.report.lock().unwrap_or_else(|e| e.into_inner()) = Some(report.clone());
+        info!(
+            "Self-audit of blocks {from_block}..{head}: {} matched, {} divergent",
+            report.matched,
+            report.divergences.len()
+        );
+
+        for alert in alerts(&report) {
+            if let Err(e) = notifier.notify(alert).await {
+                warn!("Failed to deliver self-audit alert: {e}");
+            }
+        }
+        Ok(self.annotate_report(report))
+    }
+
+    fn bundle(
+        &self,
+        tracked: &TrackedChallenge,
+        oracle: OracleChallenge,
+        rejection: Option<OracleRejection>,
+        now_ms: u64,
+    ) -> Result<DisputeBundle, PhalaAvsError> {
+        let id = tracked.challenge.challenge_id.to_string();
+        // Response evidence is written once the response lands, after the last transition.
+        let response_evidence = self
+            .evidence
+            .records(RESPONSE_EVIDENCE, tracked.first_seen_unix_ms, u64::MAX)?
+            .into_iter()
+            .filter_map(|(_, raw)| serde_json::from_slice::<ResponseEvidence>(&raw).ok())
+            .filter(|record| record.challenge_id == id)
+            .collect();
+        let until_ms = tracked
+            .history
+            .last()
+            .map_or(now_ms, |t| t.unix_ms)
+            .saturating_add(1);
+        let proofs = match &self.anchorer {
+            Some(anchorer) => anchorer
+                .proofs_between(tracked.first_seen_unix_ms / 1000, until_ms.div_ceil(1000))?,
+            None => Vec::new(),
+        };
+        Ok(DisputeBundle {
+            challenge_id: tracked.challenge.challenge_id,
+            generated_unix_ms: now_ms,
+            tracked: tracked.clone(),
+            oracle,
+            response_evidence,
+            proofs,
+            rejection,
+            annotations: Vec::new(),
+        })
+    }
+}
+
+/// When the challenge reached `Responded`, if it did.
+pub(crate) fn responded(tracked: &TrackedChallenge) -> Option<u64> {
+    tracked
+        .history
+        .iter()
+        .find(|t| t.to == ChallengeState::Responded)
+        .map(|t| t.unix_ms)
+}
+
+fn export(report: &SelfAuditReport) {
+    for kind in DivergenceKind::ALL {
+        METRICS.set_gauge(
+            SELF_AUDIT_DIVERGENCES_METRIC,
+            &[("category", kind.as_str())],
+            report.count(kind) as f64,
+        );
+    }
+    METRICS.set_gauge(SELF_AUDIT_MATCHED_METRIC, &[], report.matched as f64);
+    for (source, summary) in [
+        ("local_ms", &report.latency.local_ms),
+        ("chain_blocks", &report.latency.chain_blocks),
+    ] {
+        for (quantile, value) in [("0.5", summary.p50), ("0.9", summary.p90)] {
+            METRICS.set_gauge(
+                SELF_AUDIT_LATENCY_METRIC,
+                &[("source", source), ("quantile", quantile)],
+                value as f64,
+            );
+        }
+    }
+    METRICS.set_gauge(
+        SELF_AUDIT_LAST_RUN_METRIC,
+        &[],
+        (report.ran_unix_ms / 1000) as f64,
+    );
+}
+
+/// One alert per divergence category found.
+fn alerts(report: &SelfAuditReport) -> Vec<Alert> {
+    DivergenceKind::ALL
+        .into_iter()
+        .filter_map(|kind| {
+            let ids: Vec<String> = report
+                .divergences
+                .iter()
+                .filter(|d| d.kind == kind)
+                .map(|d| d.challenge_id.to_string())
+                .collect();
+            if ids.is_empty() {
+                return None;
+            }
+            let mut named = ids[..ids.len().min(ALERT_IDS)].join(", ");
+            if ids.len() > ALERT_IDS {
+                named.push_str(&format!(" and {} more", ids.len() - ALERT_IDS));
+            }
+            let message = match kind {
+                DivergenceKind::RespondedNotOnChain => format!(
+                    "Oracle has no response to challenges we responded to: {named}; dispute \
+                     bundles generated"
+                ),
+                DivergenceKind::Unseen => {
+                    format!("Oracle issued challenges we never saw: {named}; event delivery failed")
+                }
+                DivergenceKind::RejectedByOracle => format!(
+                    "Oracle rejected our included responses to challenges: {named}; dispute \
+                     bundles generated"
+                ),
+                DivergenceKind::Mismatch => {
+                    format!("Oracle and local records disagree on challenges: {named}")
+                }
+            };
+            Some(Alert::new("self_audit", kind.severity(), message))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::ConfirmationPolicy;
+    use crate::evidence::now_unix_ms;
+    use crate::fixtures::{ChallengeEventFixture, DEFAULT_WINDOW_BLOCKS, OPERATOR};
+    use crate::log_consistency::{LogCheckConfig, LogSource};
+    use crate::state::MemoryStateStore;
+    use blueprint_sdk::alloy::primitives::Bloom;
+    use blueprint_sdk::alloy::rpc::types::Log;
+
+    /// An oracle holding the challenges it was seeded with.
+    #[derive(Default)]
+    struct SimulatedOracle {
+        challenges: BTreeMap<u64, OracleChallenge>,
+        responses: BTreeMap<U256, u64>,
+        reads: Mutex<usize>,
+    }
+
+    impl SimulatedOracle {
+        fn issue(&mut self, id: u64, operator: Address, deadline_block: u64, responded: bool) {
+            self.challenges.insert(id, OracleChallenge {
+                challenge_id: U256::from(id),
+                operator,
+                deadline_block,
+                responded,
+                reported: !responded,
+            });
+            if responded {
+                self.responses
+                    .insert(U256::from(id), deadline_block - DEFAULT_WINDOW_BLOCKS + 4);
+            }
+        }
+    }
+
+    impl OracleLedger for SimulatedOracle {
+        fn challenge_count(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            let count = self.challenges.keys().last().copied().unwrap_or_default();
+            Box::pin(async move { Ok(count) })
+        }
+
+        fn challenges(
+            &self,
+            ids: Vec<u64>,
+        ) -> BoxFuture<'_, Result<Vec<OracleChallenge>, PhalaAvsError>> {
+            *self.reads.lock().unwrap() += ids.len();
+            let records = ids
+                .into_iter()
+                .map(|id| {
+                    self.challenges
+                        .get(&id)
+                        .cloned()
+                        .unwrap_or(OracleChallenge {
+                            challenge_id: U256::from(id),
+                            operator: Address::ZERO,
+                            deadline_block: 0,
+                            responded: false,
+                            reported: false,
+                        })
+                })
+                .collect();
+            Box::pin(async move { Ok(records) })
+        }
+
+        fn response_window_blocks(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(DEFAULT_WINDOW_BLOCKS) })
+        }
+
+        fn response_blocks(
+            &self,
+            operator: Address,
+            _from_block: u64,
+            _to_block: u64,
+        ) -> BoxFuture<'_, Result<BTreeMap<U256, u64>, PhalaAvsError>> {
+            assert_eq!(operator, OPERATOR);
+            let responses = self.responses.clone();
+            Box::pin(async move { Ok(responses) })
+        }
+    }
+
+    /// A provider that has challenge 5's log in its issuing block.
+    struct RecoveringSource;
+
+    impl LogSource for RecoveringSource {
+        fn name(&self) -> &str {
+            "recovering"
+        }
+
+        fn logs_bloom(&self, _block: u64) -> BoxFuture<'_, Result<Option<Bloom>, PhalaAvsError>> {
+            Box::pin(async { Ok(Some(Bloom::repeat_byte(0xff))) })
+        }
+
+        fn challenge_logs(
+            &self,
+            block: u64,
+            _oracle: Address,
+        ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>> {
+            let log = ChallengeEventFixture::new().id(5).block(block).build_log();
+            Box::pin(async move { Ok(vec![log]) })
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorded(Mutex<Vec<Alert>>);
+
+    impl Notifier for Recorded {
+        fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.0.lock().unwrap().push(alert);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn config() -> SelfAuditConfig {
+        SelfAuditConfig {
+            enabled: true,
+            schedule: "0 0 3 * * *".to_string(),
+            epochs: 2,
+            epoch_blocks: 100,
+            batch_size: 2,
+            max_challenges: 100,
+            gate_readiness: true,
+            multicall: MULTICALL3_ADDRESS,
+        }
+    }
+
+    /// Tracks challenge `id` issued at `block` and moves it to `to`.
+    fn track(tracker: &ChallengeTracker, id: u64, block: u64, to: ChallengeState) {
+        let challenge = ChallengeEventFixture::new()
+            .id(id)
+            .block(block)
+            .build_observed();
+        tracker.observe(challenge, block).unwrap();
+        let path: &[ChallengeState] = match to {
+            ChallengeState::Responded => &[
+                ChallengeState::Queued,
+                ChallengeState::Building,
+                ChallengeState::Submitting,
+                ChallengeState::AwaitingInclusion,
+                ChallengeState::Responded,
+            ],
+            ChallengeState::Missed => &[ChallengeState::Missed],
+            _ => &[],
+        };
+        for &state in path {
+            tracker.transition(U256::from(id), state, "test").unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn each_divergence_category_is_classified_alerted_and_acted_on() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker = Arc::new(
+            ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap(),
+        );
+        let evidence = EvidenceLog::new(Arc::clone(&store));
+        let mut oracle = SimulatedOracle::default();
+        // Head is 300, so challenges closing in [100, 300) are audited.
+        // 1: closed before the range; never read past.
+        oracle.issue(1, OPERATOR, 60, true);
+        // 2: matched response.
+        track(&tracker, 2, 110, ChallengeState::Responded);
+        oracle.issue(2, OPERATOR, 160, true);
+        // 3: another operator's challenge.
+        oracle.issue(3, Address::repeat_byte(9), 170, false);
+        // 4: we responded, the oracle says we did not.
+        track(&tracker, 4, 130, ChallengeState::Responded);
+        evidence
+            .record(RESPONSE_EVIDENCE, now_unix_ms(), b"4", &ResponseEvidence {
+                unix_ms: now_unix_ms(),
+                challenge_id: "4".to_string(),
+                issued_block: 130,
+                deadline_block: 180,
+                release_reason: "confirmed".to_string(),
+                artifacts_url: None,
+                detection_delay_blocks: Some(0),
+                maintenance_window_id: None,
+                sla_proof_hash: None,
+            })
+            .unwrap();
+        oracle.issue(4, OPERATOR, 180, false);
+        // 5: issued to us, never delivered.
+        oracle.issue(5, OPERATOR, 200, false);
+        // 6: a response on-chain we recorded as missed.
+        track(&tracker, 6, 160, ChallengeState::Missed);
+        oracle.issue(6, OPERATOR, 210, true);
+        // 7: still open at head.
+        oracle.issue(7, OPERATOR, 320, false);
+        let oracle = Arc::new(oracle);
+
+        let checker = Arc::new(LogConsistencyChecker::new(
+            LogCheckConfig {
+                enabled: true,
+                sample_rate: 0.0,
+                lag_blocks: 0,
+                max_blocks: 0,
+                retry_delay_ms: 0,
+                rpc_urls: Vec::new(),
+            },
+            crate::fixtures::ORACLE,
+            Arc::new(RecoveringSource),
+            Vec::new(),
+        ));
+        let auditor = SelfAuditor::new(
+            config(),
+            OPERATOR,
+            Arc::clone(&oracle) as Arc<dyn OracleLedger>,
+            Arc::clone(&tracker),
+            Arc::clone(&store),
+        )
+        .unwrap()
+        .with_log_checker(Some(Arc::clone(&checker)));
+        let notifier = Recorded::default();
+
+        let report = auditor.run(300, 1_000, &notifier).await.unwrap();
+        assert_eq!((report.from_block, report.to_block), (100, 300));
+        assert_eq!(report.matched, 1);
+        let kinds: Vec<(U256, DivergenceKind)> = report
+            .divergences
+            .iter()
+            .map(|d| (d.challenge_id, d.kind))
+            .collect();
+        assert_eq!(kinds, vec![
+            (U256::from(4), DivergenceKind::RespondedNotOnChain),
+            (U256::from(5), DivergenceKind::Unseen),
+            (U256::from(6), DivergenceKind::Mismatch),
+        ]);
+        assert_eq!(report.critical(), 2);
+        assert!(!report.truncated);
+        // Batches of two from id 7 down stop at the batch reaching challenge 1.
+        assert_eq!(*oracle.reads.lock().unwrap(), 7);
+        assert_eq!(report.latency.chain_blocks, LatencySummary {
+            count: 1,
+            p50: 4,
+            p90: 4,
+            max: 4
+        });
+        assert_eq!(report.latency.local_ms.count, 1);
+
+        let alerts = notifier.0.lock().unwrap();
+        let severities: Vec<Severity> = alerts.iter().map(|a| a.severity).collect();
+        assert_eq!(severities, vec![
+            Severity::Critical,
+            Severity::Critical,
+            Severity::Warning
+        ]);
+        assert!(alerts[1].message.contains('5'));
+
+        // The contested response has a bundle with its evidence.
+        assert_eq!(report.disputes, vec![U256::from(4)]);
+        let bundle = auditor.dispute(&U256::from(4)).unwrap().unwrap();
+        assert_eq!(bundle.tracked.state, ChallengeState::Responded);
+        assert!(!bundle.oracle.responded);
+        assert_eq!(bundle.response_evidence.len(), 1);
+        assert!(auditor.dispute(&U256::from(5)).unwrap().is_none());
+
+        // The unseen challenge's issuing block is checked even though nothing is sampled, and
+        // its log recovered.
+        let checked = checker.check(400).await.unwrap();
+        assert_eq!(checked.checked, 1);
+        assert_eq!(checked.recovered[0].block_number, Some(150));
+
+        // The report survives a restart and gates readiness.
+        let resumed = SelfAuditor::new(config(), OPERATOR, oracle, tracker, store).unwrap();
+        assert_eq!(resumed.report(), Some(report));
+        assert!(resumed.readiness_blocker().is_some());
+    }
+
+    #[tokio::test]
+    async fn notes_are_served_with_the_report_and_dispute_bundles() {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker = Arc::new(
+            ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap(),
+        );
+        let mut oracle = SimulatedOracle::default();
+        track(&tracker, 2, 110, ChallengeState::Responded);
+        oracle.issue(2, OPERATOR, 160, true);
+        track(&tracker, 4, 130, ChallengeState::Responded);
+        oracle.issue(4, OPERATOR, 180, false);
+        let notes = Arc::new(Annotations::new(Arc::clone(&store), Default::default()));
+        let auditor = SelfAuditor::new(
+            config(),
+            OPERATOR,
+            Arc::new(oracle),
+            tracker,
+            Arc::clone(&store),
+        )
+        .unwrap()
+        .with_annotations(Arc::clone(&notes));
+
+        let disputed = AnnotationTarget::Challenge(U256::from(4));
+        notes
+            .add(disputed.clone(), "ops", "RPC outage, ticket #123", 1)
+            .unwrap();
+        notes
+            .add(AnnotationTarget::Epoch(1), "ops", "node upgrade", 2)
+            .unwrap();
+        // Neither divergent nor in an audited epoch.
+        notes
+            .add(AnnotationTarget::Challenge(U256::from(2)), "ops", "fine", 3)
+            .unwrap();
+        notes
+            .add(AnnotationTarget::Epoch(9), "ops", "later", 4)
+            .unwrap();
+
+        let report = auditor.run(300, 1_000, &Recorded::default()).await.unwrap();
+        assert_eq!(report.disputes, vec![U256::from(4)]);
+        let targets: Vec<&AnnotationTarget> =
+            report.annotations.iter().map(|n| &n.target).collect();
+        assert_eq!(targets, [&AnnotationTarget::Epoch(1), &disputed]);
+
+        // Notes added after the audit are served too, without being persisted in the report.
+        notes
+            .add(AnnotationTarget::Epoch(2), "alice", "maintenance", 5)
+            .unwrap();
+        assert_eq!(auditor.report().unwrap().annotations.len(), 3);
+        let stored: SelfAuditReport = store.get_json(NAMESPACE, REPORT_KEY).unwrap().unwrap();
+        assert!(stored.annotations.is_empty());
+
+        let bundle = auditor.dispute(&U256::from(4)).unwrap().unwrap();
+        let bundle_notes: Vec<&str> = bundle.annotations.iter().map(|n| n.note.as_str()).collect();
+        assert_eq!(bundle_notes, ["RPC outage, ticket #123", "node upgrade"]);
+    }
+
+    #[test]
+    fn latency_quantiles_are_nearest_rank() {
+        let summary = LatencySummary::of((1..=10).rev().collect());
+        assert_eq!(summary, LatencySummary {
+            count: 10,
+            p50: 5,
+            p90: 9,
+            max: 10
+        });
+        assert_eq!(LatencySummary::of(Vec::new()), LatencySummary::default());
+    }
+}
@@ -0,0 +1,1511 @@
+//! Transaction sending with write-ahead intent records.
+//!
+//! A crash between taking a nonce and learning a transaction's fate leaves the operator unable to
+//! tell whether a response went out, and a transaction signed but never broadcast leaves a nonce
+//! gap every later transaction waits behind. So every transaction is sent through an [`Intent`]
+//! in the state store, written before each step:
+//!
+//! 1. `prepared`, before signing: the call, the challenge it answers, the lane, nonce, gas and
+//!    fees;
+//! 2. `signed`, before broadcast: the transaction's hash and signed bytes;
+//! 3. `broadcast`, once a node accepted it;
+//! 4. `finalized`, once it or a fee-bumped replacement was included, or another transaction took
+//!    its nonce.
+//!
+//! [`TxSender::recover`] replays the unfinalized intents at startup, before anything else is sent:
+//! prepared ones are signed and broadcast, signed ones re-broadcast with the same nonce, and
+//! broadcast ones are watched like any other pending transaction, fee-bumped by `TX_BUMP_PCT`
+//! every `TX_BUMP_AFTER_SECS` they stay pending. A challenge is answered by at most one intent:
+//! [`TxSender::send`] resumes the intent a challenge already has instead of sending another.
+//! Amending a challenge starts a new revision, answered by an intent of its own.
+//!
+//! When the oracle cancels or amends a challenge while its response is pending,
+//! [`TxSender::withdraw`] replaces the response with a no-op transfer at the same nonce, if that
+//! costs less than letting the response land and revert.
+//!
+//! With replicas (see [`crate::replica`]), a standby records what it would have sent instead of
+//! sending it, and an intent carries the fencing token of the lease it was prepared under; it is
+//! failed rather than broadcast once that lease is lost.
+//!
+//! Finalized intents beyond the latest `TX_INTENTS_RETAINED` are pruned. The log is included in
+//! diagnostics bundles.
+
+#[cfg(feature = "chaos")]
+use crate::chaos::ChaosEngine;
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use crate::error_catalog;
+use crate::evidence::now_unix_ms;
+use crate::evm::BoxFuture;
+use crate::fees::{FeeModels, Fees, ProviderFeeProbe};
+use crate::lanes::{Lane, LaneId, SignerLanes, TxClass};
+use crate::metrics::METRICS;
+use crate::replica::ReplicaCoordinator;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::eips::eip2718::Encodable2718;
+use blueprint_sdk::alloy::network::{EthereumWallet, TransactionBuilder};
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, U256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::TransactionRequest;
+use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Intents by account and nonce.
+pub const INTENT_NAMESPACE: &str = "tx_intents";
+/// Counter of intents replayed at startup, by the stage they were found in.
+pub const INTENTS_REPLAYED_METRIC: &str = "phala_avs_tx_intents_replayed_total";
+/// Counter of fee-bumped replacements, by lane.
+pub const FEE_BUMPS_METRIC: &str = "phala_avs_tx_fee_bumps_total";
+/// Counter of attempts to withdraw pending responses, by result.
+pub const WITHDRAWALS_METRIC: &str = "phala_avs_tx_withdrawals_total";
+/// Counter of included transactions that achieved nothing, by call label.
+pub const WASTED_METRIC: &str = "phala_avs_tx_wasted_total";
+/// Counter of gwei spent at most on wasted transactions (gas limit at the signed max fee), by
+/// call label.
+pub const WASTED_GWEI_METRIC: &str = "phala_avs_tx_wasted_gwei_total";
+
+/// Gas of the no-op transfer replacing a withdrawn transaction.
+const NOOP_GAS: u64 = 21_000;
+
+#[derive(Clone, Debug)]
+pub struct TxSenderConfig {
+    /// How often a pending transaction's receipt is looked for.
+    pub receipt_poll: Duration,
+    /// How long a transaction may stay pending before it is replaced with higher fees.
+    pub bump_after: Duration,
+    pub bump_pct: u32,
+    /// Headroom added to the gas estimate, in percent.
+    pub gas_margin_pct: u64,
+    /// Finalized intents kept.
+    pub retained: usize,
+}
+
+impl Default for TxSenderConfig {
+    fn default() -> Self {
+        Self {
+            receipt_poll: Duration::from_secs(2),
+            bump_after: Duration::from_secs(60),
+            bump_pct: 15,
+            gas_margin_pct: 20,
+            retained: 1000,
+        }
+    }
+}
+
+impl TxSenderConfig {
+    /// Reads `TX_RECEIPT_POLL_MS`, `TX_BUMP_AFTER_SECS`, `TX_BUMP_PCT`, `TX_GAS_MARGIN_PCT` and
+    /// `TX_INTENTS_RETAINED`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        Ok(Self {
+            receipt_poll: Duration::from_millis(env_or(
+                "TX_RECEIPT_POLL_MS",
+                defaults.receipt_poll.as_millis() as u64,
+            )?),
+            bump_after: Duration::from_secs(env_or(
+                "TX_BUMP_AFTER_SECS",
+                defaults.bump_after.as_secs(),
+            )?),
+            bump_pct: env_or("TX_BUMP_PCT", defaults.bump_pct)?,
+            gas_margin_pct: env_or("TX_GAS_MARGIN_PCT", defaults.gas_margin_pct)?,
+            retained: env_or("TX_INTENTS_RETAINED", defaults.retained)?,
+        })
+    }
+}
+
+/// A step a crash can be injected after, with the `chaos` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrashPoint {
+    /// The intent is recorded, nothing is signed.
+    AfterPrepare,
+    /// The transaction is signed and recorded, not broadcast.
+    AfterSign,
+    /// The transaction is broadcast, which is not yet recorded.
+    AfterBroadcast,
+    /// The transaction is included, which is not yet recorded.
+    AfterInclusion,
+}
+
+/// A contract call to send.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxCall {
+    /// Names the call in logs, e.g. `anchorEvidenceRoot`.
+    pub label: String,
+    pub to: Address,
+    pub input: Bytes,
+    /// The challenge the call answers, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge_id: Option<U256>,
+    /// How many times the challenge was amended before this answer.
+    #[serde(default)]
+    pub revision: u32,
+    /// Whether the first transaction already pays `bump_pct` over current fees.
+    #[serde(default)]
+    pub escalated: bool,
+}
+
+impl TxCall {
+    pub fn new(label: &str, to: Address, input: impl Into<Bytes>) -> Self {
+        Self {
+            label: label.to_string(),
+            to,
+            input: input.into(),
+            challenge_id: None,
+            revision: 0,
+            escalated: false,
+        }
+    }
+
+    /// Marks the call as the response to `challenge_id`, so it is sent at most once per
+    /// revision.
+    pub fn responding_to(mut self, challenge_id: U256) -> Self {
+        self.challenge_id = Some(challenge_id);
+        self
+    }
+
+    /// Marks the call as answering the challenge after `revision` amendments.
+    pub fn at_revision(mut self, revision: u32) -> Self {
+        self.revision = revision;
+        self
+    }
+
+    /// Prices the first transaction as if it had already been bumped once, for calls that
+    /// can't wait out a replacement.
+    pub fn escalated(mut self, escalated: bool) -> Self {
+        self.escalated = escalated;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntentStage {
+    Prepared,
+    Signed,
+    Broadcast,
+    Finalized,
+    /// The transaction could not be signed or no node accepted it, and its nonce was released.
+    Failed,
+}
+
+impl IntentStage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Prepared => "prepared",
+            Self::Signed => "signed",
+            Self::Broadcast => "broadcast",
+            Self::Finalized => "finalized",
+            Self::Failed => "failed",
+        }
+    }
+
+    /// Whether the intent still holds its nonce and has to be driven to an outcome.
+    pub fn is_pending(self) -> bool {
+        matches!(self, Self::Prepared | Self::Signed | Self::Broadcast)
+    }
+}
+
+/// What became of a transaction's nonce.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum TxOutcome {
+    /// The transaction, or one of its replacements, was included.
+    Included {
+        tx_hash: B256,
+        block: u64,
+        success: bool,
+    },
+    /// The nonce was taken by a transaction that is not one of the intent's.
+    NonceTaken,
+    /// The no-op replacing the withdrawn transaction was included; the call was not made.
+    Withdrawn { tx_hash: B256, block: u64 },
+    /// A standby replica recorded the call instead of sending it.
+    Shadowed,
+}
+
+impl TxOutcome {
+    /// The hash of the included transaction, failing unless it succeeded.
+    pub fn into_success(self, label: &str) -> Result<B256, PhalaAvsError> {
+        match self {
+            Self::Included {
+                tx_hash,
+                success: true,
+                ..
+            } => Ok(tx_hash),
+            Self::Included { tx_hash, .. } => Err(PhalaAvsError::EvmError(format!(
+                "{label} reverted in {tx_hash}"
+            ))),
+            Self::NonceTaken => Err(PhalaAvsError::EvmError(format!(
+                "{label} was superseded by another transaction at its nonce"
+            ))),
+            Self::Withdrawn { tx_hash, .. } => Err(PhalaAvsError::EvmError(format!(
+                "{label} was withdrawn by {tx_hash}"
+            ))),
+            Self::Shadowed => Err(PhalaAvsError::EvmError(format!(
+                "{label} was not sent by this standby replica"
+            ))),
+        }
+    }
+}
+
+/// A transaction the operator has committed to, and how far it got.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Intent {
+    pub call: TxCall,
+    pub class: TxClass,
+    pub lane: LaneId,
+    pub account: Address,
+    pub nonce: u64,
+    pub gas_limit: u64,
+    pub fees: Fees,
+    pub stage: IntentStage,
+    /// The token of the response lease the intent was prepared under, with replicas.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fencing_token: Option<u64>,
+    /// Hash of the latest signed transaction.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<B256>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<Bytes>,
+    /// Earlier transactions replaced by fee bumps, any of which may still be the one included.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replaced: Vec<B256>,
+    pub prepared_unix_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broadcast_unix_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finalized_unix_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<TxOutcome>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Why the call was withdrawn, if it was; later signatures are no-op transfers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub withdrawn: Option<String>,
+    /// Hashes of the no-op transfers among the intent's transactions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub noops: Vec<B256>,
+    /// When the logs of the included transaction were checked for what the contract made of it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verified_unix_ms: Option<u64>,
+    /// Why the spend on the included transaction was wasted, e.g. the oracle rejected the
+    /// response it carried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wasted: Option<String>,
+}
+
+/// What [`TxSender::withdraw`] did with a challenge's pending response.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Withdrawal {
+    /// No response of the challenge was pending.
+    NothingPending,
+    /// The response is not broadcast yet; its sender is left to finish it.
+    NotBroadcast { nonce: u64 },
+    /// A no-op transfer at the response's nonce was broadcast.
+    Replaced { nonce: u64, tx_hash: B256 },
+    /// Replacing the response would cost more than letting it land.
+    LeftPending {
+        nonce: u64,
+        replacement_wei: u128,
+        response_wei: u128,
+    },
+    /// The replacement could not be signed or broadcast.
+    Failed { nonce: u64, error: String },
+}
+
+impl Withdrawal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NothingPending => "nothing_pending",
+            Self::NotBroadcast { .. } => "not_broadcast",
+            Self::Replaced { .. } => "replaced",
+            Self::LeftPending { .. } => "left_pending",
+            Self::Failed { .. } => "failed",
+        }
+    }
+}
+
+impl Intent {
+    fn key(&self) -> Vec<u8> {
+        intent_key(self.account, self.nonce)
+    }
+
+    /// Every transaction signed for the intent, latest first.
+    fn hashes(&self) -> impl Iterator<Item = &B256> {
+        self.tx_hash.iter().chain(self.replaced.iter().rev())
+    }
+
+    /// The included transaction carrying the intent's call, if it succeeded.
+    pub fn succeeded(&self) -> Option<B256> {
+        match self.outcome {
+            Some(TxOutcome::Included {
+                tx_hash,
+                success: true,
+                ..
+            }) => Some(tx_hash),
+            _ => None,
+        }
+    }
+
+    /// The most the intent's transaction could have cost.
+    pub fn max_cost_wei(&self) -> u128 {
+        self.fees
+            .max_per_gas()
+            .saturating_mul(self.gas_limit.into())
+    }
+}
+
+fn intent_key(account: Address, nonce: u64) -> Vec<u8> {
+    [account.as_slice(), &nonce.to_be_bytes()].concat()
+}
+
+/// A signed transaction, as handed to the chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedTx {
+    pub hash: B256,
+    pub from: Address,
+    pub nonce: u64,
+    pub raw: Bytes,
+}
+
+/// The chain transactions are sent to.
+pub trait TxChain: Send + Sync {
+    fn estimate_gas(
+        &self,
+        from: Address,
+        call: TxCall,
+    ) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+
+    /// Current fees under the chain's fee model.
+    fn fees(&self) -> BoxFuture<'_, Result<Fees, PhalaAvsError>>;
+
+    fn broadcast(&self, tx: SignedTx) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+
+    /// The outcome of an included transaction, `None` while it is not included.
+    fn receipt(&self, tx_hash: B256) -> BoxFuture<'_, Result<Option<TxOutcome>, PhalaAvsError>>;
+
+    /// Transactions of `account` included so far, i.e. the nonce its next included transaction
+    /// will have.
+    fn included_nonce(&self, account: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+}
+
+/// [`TxChain`] backed by an alloy [`Provider`].
+#[derive(Clone, Debug)]
+pub struct ProviderTxChain<P> {
+    provider: P,
+    chain_id: u64,
+    fee_models: Arc<FeeModels>,
+}
+
+impl<P> ProviderTxChain<P> {
+    pub fn new(provider: P, chain_id: u64, fee_models: Arc<FeeModels>) -> Self {
+        Self {
+            provider,
+            chain_id,
+            fee_models,
+        }
+    }
+}
+
+impl<P: Provider + Clone + Send + Sync + 'static> TxChain for ProviderTxChain<P> {
+    fn estimate_gas(
+        &self,
+        from: Address,
+        call: TxCall,
+    ) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            let request = TransactionRequest::default()
+                .with_from(from)
+                .with_to(call.to)
+                .with_input(call.input);
+            self.provider.estimate_gas(request).await.map_err(|e| {
+                let call = format!("eth_estimateGas of {}", call.label);
+                PhalaAvsError::EvmError(error_catalog::describe_rpc_error(&call, &e))
+            })
+        })
+    }
+
+    fn fees(&self) -> BoxFuture<'_, Result<Fees, PhalaAvsError>> {
+        Box::pin(async move {
+            let probe = ProviderFeeProbe::new(self.provider.clone());
+            self.fee_models.estimate(self.chain_id, &probe).await
+        })
+    }
+
+    fn broadcast(&self, tx: SignedTx) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .send_raw_transaction(&tx.raw)
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    PhalaAvsError::EvmError(error_catalog::describe_rpc_error(
+                        "eth_sendRawTransaction",
+                        &e,
+                    ))
+                })
+        })
+    }
+
+    fn receipt(&self, tx_hash: B256) -> BoxFuture<'_, Result<Option<TxOutcome>, PhalaAvsError>> {
+        Box::pin(async move {
+            let receipt = self
+                .provider
+                .get_transaction_receipt(tx_hash)
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::EvmError(format!("eth_getTransactionReceipt failed: {e}"))
+                })?;
+            Ok(receipt.map(|r| TxOutcome::Included {
+                tx_hash,
+                block: r.block_number.unwrap_or_default(),
+                success: r.status(),
+            }))
+        })
+    }
+
+    fn included_nonce(&self, account: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_transaction_count(account)
+                .latest()
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::EvmError(format!("eth_getTransactionCount failed: {e}"))
+                })
+        })
+    }
+}
+
+/// A node's answer to a transaction it already holds.
+fn already_known(error: &PhalaAvsError) -> bool {
+    let message = error.to_string().to_ascii_lowercase();
+    message.contains("already known") || message.contains("known transaction")
+}
+
+/// Signs, sends and watches transactions from the signer lanes, recording each step first.
+pub struct TxSender {
+    config: TxSenderConfig,
+    chain_id: u64,
+    lanes: Arc<SignerLanes>,
+    chain: Arc<dyn TxChain>,
+    store: Arc<dyn StateStore>,
+    replica: Option<Arc<ReplicaCoordinator>>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<Arc<ChaosEngine>>,
+}
+
+impl TxSender {
+    pub fn new(
+        config: TxSenderConfig,
+        chain_id: u64,
+        lanes: Arc<SignerLanes>,
+        chain: Arc<dyn TxChain>,
+        store: Arc<dyn StateStore>,
+    ) -> Self {
+        Self {
+            config,
+            chain_id,
+            lanes,
+            chain,
+            store,
+            replica: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+
+    /// Sends only while `replica` holds the response lease, under its fencing token.
+    pub fn with_replica(mut self, replica: Arc<ReplicaCoordinator>) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    /// Crashes at the [`CrashPoint`]s armed on `engine`.
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, engine: Arc<ChaosEngine>) -> Self {
+        self.chaos = Some(engine);
+        self
+    }
+
+    #[cfg(feature = "chaos")]
+    fn crash_point(&self, point: CrashPoint) {
+        if let Some(engine) = &self.chaos {
+            engine.crash_if_armed(point);
+        }
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    fn crash_point(&self, _point: CrashPoint) {}
+
+    pub fn config(&self) -> &TxSenderConfig {
+        &self.config
+    }
+
+    /// Every intent, by account and nonce.
+    pub fn intents(&self) -> Result<Vec<Intent>, PhalaAvsError> {
+        self.store
+            .scan(INTENT_NAMESPACE)?
+            .into_iter()
+            .map(|(_, raw)| {
+                serde_json::from_slice(&raw).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Unreadable transaction intent: {e}"))
+                })
+            })
+            .collect()
+    }
+
+    /// The latest intent answering `challenge_id`, unless it failed.
+    pub fn intent_for(&self, challenge_id: U256) -> Result<Option<Intent>, PhalaAvsError> {
+        Ok(self
+            .intents()?
+            .into_iter()
+            .filter(|intent| {
+                intent.call.challenge_id == Some(challenge_id)
+                    && intent.stage != IntentStage::Failed
+            })
+            .max_by_key(|intent| intent.prepared_unix_ms))
+    }
+
+    /// The most sending `call` as a `class` transaction would cost at current fees, with the
+    /// gas margin [`send`](Self::send) applies.
+    pub async fn estimate_cost_wei(
+        &self,
+        class: TxClass,
+        call: TxCall,
+    ) -> Result<u128, PhalaAvsError> {
+        let lane = self.lanes.signer_for(class)?;
+        let estimate = self.chain.estimate_gas(lane.address(), call).await?;
+        let gas_limit =
+            estimate.saturating_add(estimate.saturating_mul(self.config.gas_margin_pct) / 100);
+        let fees = self.chain.fees().await?;
+        Ok(fees.max_per_gas().saturating_mul(gas_limit.into()))
+    }
+
+    fn persist(&self, intent: &Intent) -> Result<(), PhalaAvsError> {
+        self.store.put_json(INTENT_NAMESPACE, &intent.key(), intent)
+    }
+
+    fn lane(&self, intent: &Intent) -> Result<&Lane, PhalaAvsError> {
+        self.lanes
+            .lane(intent.lane)
+            .filter(|lane| lane.address() == intent.account)
+            .ok_or_else(|| {
+                PhalaAvsError::ConfigError(format!(
+                    "The {} account of the transaction intent at nonce {} is no longer configured",
+                    intent.lane.as_str(),
+                    intent.nonce
+                ))
+            })
+    }
+
+    /// Sends `call` as a `class` transaction and waits for its outcome. A call answering a
+    /// challenge revision that already has an intent resumes that intent instead. A standby
+    /// replica only records the call.
+    pub async fn send(&self, class: TxClass, call: TxCall) -> Result<TxOutcome, PhalaAvsError> {
+        if let Some(replica) = self.replica.as_ref().filter(|r| !r.is_leader()) {
+            replica.record_would_submit(&call, now_unix_ms())?;
+            return Ok(TxOutcome::Shadowed);
+        }
+        if let Some(challenge_id) = call.challenge_id {
+            let existing = self
+                .intent_for(challenge_id)?
+                .filter(|intent| intent.call.revision == call.revision);
+            if let Some(existing) = existing {
+                info!(
+                    "Challenge {challenge_id} already has a transaction at nonce {}; not sending another",
+                    existing.nonce
+                );
+                return self.drive(existing).await;
+            }
+        }
+
+        let lane = self.lanes.signer_for(class)?;
+        let estimate = self
+            .chain
+            .estimate_gas(lane.address(), call.clone())
+            .await?;
+        let gas_limit =
+            estimate.saturating_add(estimate.saturating_mul(self.config.gas_margin_pct) / 100);
+        let mut fees = self.chain.fees().await?;
+        if call.escalated {
+            fees = fees.escalate(self.config.bump_pct);
+        }
+        let nonce = self.lanes.next_nonce(lane).await?;
+        let intent = Intent {
+            call,
+            class,
+            lane: lane.id(),
+            account: lane.address(),
+            nonce,
+            gas_limit,
+            fees,
+            stage: IntentStage::Prepared,
+            fencing_token: self.replica.as_ref().and_then(|r| r.token()),
+            tx_hash: None,
+            raw: None,
+            replaced: Vec::new(),
+            prepared_unix_ms: now_unix_ms(),
+            broadcast_unix_ms: None,
+            finalized_unix_ms: None,
+            outcome: None,
+            error: None,
+            withdrawn: None,
+            noops: Vec::new(),
+            verified_unix_ms: None,
+            wasted: None,
+        };
+        let held = self
+            .store
+            .get_json::<Intent>(INTENT_NAMESPACE, &intent.key())
+            .and_then(|held| match held {
+                Some(held) if held.stage.is_pending() => Err(PhalaAvsError::EvmError(format!(
+                    "Nonce {nonce} of the {} account is held by a pending {} transaction",
+                    lane.id().as_str(),
+                    held.call.label
+                ))),
+                _ => self.persist(&intent),
+            });
+        if let Err(e) = held {
+            self.lanes.record_failed(lane);
+            return Err(e);
+        }
+        self.crash_point(CrashPoint::AfterPrepare);
+        self.drive(intent).await
+    }
+
+    /// Drives `intent` to its outcome.
+    async fn drive(&self, intent: Intent) -> Result<TxOutcome, PhalaAvsError> {
+        let intent = self.advance(intent).await?;
+        self.watch(intent).await
+    }
+
+    /// Signs and broadcasts a prepared or signed intent, returning it once broadcast or settled.
+    async fn advance(&self, mut intent: Intent) -> Result<Intent, PhalaAvsError> {
+        if intent.stage == IntentStage::Prepared {
+            if let Err(e) = self.sign(&mut intent).await {
+                return self.unsent(intent, e).await;
+            }
+            self.persist(&intent)?;
+            self.crash_point(CrashPoint::AfterSign);
+        }
+        if intent.stage == IntentStage::Signed {
+            if let Err(e) = self.fenced(&intent) {
+                return self.unsent(intent, e).await;
+            }
+            let tx = self.signed(&intent)?;
+            if let Err(e) = self.chain.broadcast(tx).await {
+                if !already_known(&e) {
+                    return self.unsent(intent, e).await;
+                }
+            }
+            self.crash_point(CrashPoint::AfterBroadcast);
+            intent.stage = IntentStage::Broadcast;
+            intent.broadcast_unix_ms = Some(now_unix_ms());
+            self.persist(&intent)?;
+            let lane = self.lane(&intent)?;
+            self.lanes
+                .record_sent(lane, intent.class, intent.tx_hash.unwrap_or_default());
+            if let Some(replica) = &self.replica {
+                if let Err(e) = replica.record_submission(
+                    &intent.call,
+                    intent.tx_hash.unwrap_or_default(),
+                    intent.fencing_token,
+                    now_unix_ms(),
+                ) {
+                    warn!(
+                        "Failed to share the submission of {}: {e}",
+                        intent.call.label
+                    );
+                }
+            }
+        }
+        Ok(intent)
+    }
+
+    /// Fails unless the intent's fencing token still holds the response lease.
+    fn fenced(&self, intent: &Intent) -> Result<(), PhalaAvsError> {
+        match &self.replica {
+            Some(replica) => replica.check_fence(intent.fencing_token, now_unix_ms()),
+            None => Ok(()),
+        }
+    }
+
+    /// Signs the intent at its nonce and fees; a no-op transfer once it is withdrawn.
+    async fn sign(&self, intent: &mut Intent) -> Result<(), PhalaAvsError> {
+        let lane = self.lane(intent)?;
+        let signer = lane
+            .private_key()
+            .parse::<PrivateKeySigner>()
+            .map_err(|e| {
+                PhalaAvsError::ConfigError(format!("Invalid {} key: {e}", lane.id().as_str()))
+            })?;
+        let (to, input, gas_limit) = match intent.withdrawn {
+            Some(_) => (intent.account, Bytes::new(), NOOP_GAS),
+            None => (intent.call.to, intent.call.input.clone(), intent.gas_limit),
+        };
+        let request = intent.fees.apply(
+            TransactionRequest::default()
+                .with_from(intent.account)
+                .with_to(to)
+                .with_input(input)
+                .with_nonce(intent.nonce)
+                .with_gas_limit(gas_limit)
+                .with_chain_id(self.chain_id),
+        );
+        let envelope = request
+            .build(&EthereumWallet::from(signer))
+            .await
+            .map_err(|e| {
+                PhalaAvsError::EvmError(format!("Failed to sign {}: {e}", intent.call.label))
+            })?;
+        intent.tx_hash = Some(*envelope.tx_hash());
+        intent.raw = Some(envelope.encoded_2718().into());
+        intent.stage = IntentStage::Signed;
+        if intent.withdrawn.is_some() {
+            intent.noops.push(*envelope.tx_hash());
+        }
+        Ok(())
+    }
+
+    fn signed(&self, intent: &Intent) -> Result<SignedTx, PhalaAvsError> {
+        match (intent.tx_hash, &intent.raw) {
+            (Some(hash), Some(raw)) => Ok(SignedTx {
+                hash,
+                from: intent.account,
+                nonce: intent.nonce,
+                raw: raw.clone(),
+            }),
+            _ => Err(PhalaAvsError::StorageError(format!(
+                "The signed transaction intent at nonce {} has no transaction",
+                intent.nonce
+            ))),
+        }
+    }
+
+    /// Settles an intent that could not be signed or broadcast: as included or superseded when
+    /// its nonce is taken, else as failed, releasing the nonce.
+    async fn unsent(
+        &self,
+        mut intent: Intent,
+        error: PhalaAvsError,
+    ) -> Result<Intent, PhalaAvsError> {
+        if let Some(outcome) = self.settled(&intent).await? {
+            self.finalize(&mut intent, outcome)?;
+            return Ok(intent);
+        }
+        warn!(
+            "Failed to send {} at nonce {}: {error}",
+            intent.call.label, intent.nonce
+        );
+        intent.stage = IntentStage::Failed;
+        intent.error = Some(error.to_string());
+        self.persist(&intent)?;
+        self.lanes.record_failed(self.lane(&intent)?);
+        Err(error)
+    }
+
+    /// The outcome of the intent's nonce, once one of the account's transactions took it.
+    async fn settled(&self, intent: &Intent) -> Result<Option<TxOutcome>, PhalaAvsError> {
+        if self.chain.included_nonce(intent.account).await? <= intent.nonce {
+            return Ok(None);
+        }
+        for hash in intent.hashes() {
+            if let Some(outcome) = self.chain.receipt(*hash).await? {
+                return Ok(Some(outcome));
+            }
+        }
+        Ok(Some(TxOutcome::NonceTaken))
+    }
+
+    fn finalize(&self, intent: &mut Intent, outcome: TxOutcome) -> Result<(), PhalaAvsError> {
+        self.crash_point(CrashPoint::AfterInclusion);
+        if outcome == TxOutcome::NonceTaken {
+            warn!(
+                "Nonce {} of the {} account was taken by another transaction than {}",
+                intent.nonce,
+                intent.lane.as_str(),
+                intent.call.label
+            );
+        }
+        let outcome = match outcome {
+            TxOutcome::Included { tx_hash, block, .. } if intent.noops.contains(&tx_hash) => {
+                TxOutcome::Withdrawn { tx_hash, block }
+            }
+            outcome => outcome,
+        };
+        intent.stage = IntentStage::Finalized;
+        intent.finalized_unix_ms = Some(now_unix_ms());
+        intent.outcome = Some(outcome);
+        self.persist(intent)?;
+        self.prune()
+    }
+
+    /// Waits for a broadcast intent's outcome, replacing it with higher fees while it stays
+    /// pending.
+    async fn watch(&self, mut intent: Intent) -> Result<TxOutcome, PhalaAvsError> {
+        let mut sent_at = Instant::now();
+        loop {
+            // The intent may have been withdrawn since.
+            if let Some(stored) = self
+                .store
+                .get_json::<Intent>(INTENT_NAMESPACE, &intent.key())?
+                .filter(|stored| stored.prepared_unix_ms == intent.prepared_unix_ms)
+            {
+                intent = stored;
+            }
+            match intent.stage {
+                IntentStage::Finalized => {
+                    return intent.outcome.ok_or_else(|| {
+                        PhalaAvsError::StorageError(format!(
+                            "The finalized transaction intent at nonce {} has no outcome",
+                            intent.nonce
+                        ))
+                    });
+                }
+                IntentStage::Broadcast => {}
+                stage => {
+                    return Err(PhalaAvsError::EvmError(format!(
+                        "The {} transaction at nonce {} is {}",
+                        intent.call.label,
+                        intent.nonce,
+                        stage.as_str()
+                    )));
+                }
+            }
+            if let Some(outcome) = self.settled(&intent).await? {
+                self.finalize(&mut intent, outcome)?;
+                continue;
+            }
+            if sent_at.elapsed() >= self.config.bump_after {
+                self.bump(&mut intent).await?;
+                sent_at = Instant::now();
+            }
+            tokio::time::sleep(self.config.receipt_poll).await;
+        }
+    }
+
+    /// Replaces a pending intent's transaction with one paying `bump_pct` more.
+    async fn bump(&self, intent: &mut Intent) -> Result<(), PhalaAvsError> {
+        // The transactions already out stay watched; a new leader settles the nonce.
+        if let Err(e) = self.fenced(intent) {
+            warn!(
+                "Not bumping {} at nonce {}: {e}",
+                intent.call.label, intent.nonce
+            );
+            return Ok(());
+        }
+        let previous = intent.tx_hash;
+        intent.fees = intent.fees.escalate(self.config.bump_pct);
+        self.sign(intent).await?;
+        intent.replaced.extend(previous);
+        self.persist(intent)?;
+        let tx = self.signed(intent)?;
+        match self.chain.broadcast(tx).await {
+            Err(e) if !already_known(&e) => {
+                // The earlier transactions are still watched; the next bump retries.
+                warn!(
+                    "Failed to broadcast the fee bump of {} at nonce {}: {e}",
+                    intent.call.label, intent.nonce
+                );
+            }
+            _ => {
+                METRICS.inc_counter(FEE_BUMPS_METRIC, &[("lane", intent.lane.as_str())], 1);
+                info!(
+                    "Bumped the fees of {} at nonce {} to {} per gas",
+                    intent.call.label,
+                    intent.nonce,
+                    intent.fees.max_per_gas()
+                );
+            }
+        }
+        intent.stage = IntentStage::Broadcast;
+        self.persist(intent)
+    }
+
+    /// Replaces the pending response to `challenge_id` with a no-op transfer at its nonce, when
+    /// that costs less than the response: the oracle cancelled or amended the challenge, so the
+    /// response would only revert. `reason` is recorded on the intent.
+    ///
+    /// Best effort: the response may still be included first, which its sender learns as usual.
+    pub async fn withdraw(
+        &self,
+        challenge_id: U256,
+        reason: &str,
+    ) -> Result<Withdrawal, PhalaAvsError> {
+        let pending = self.intents()?.into_iter().find(|intent| {
+            intent.call.challenge_id == Some(challenge_id)
+                && intent.stage.is_pending()
+                && intent.withdrawn.is_none()
+        });
+        let withdrawal = match pending {
+            None => Withdrawal::NothingPending,
+            Some(intent) if intent.stage != IntentStage::Broadcast => Withdrawal::NotBroadcast {
+                nonce: intent.nonce,
+            },
+            Some(mut intent) => {
+                let fees = intent.fees.escalate(self.config.bump_pct);
+                let replacement_wei = fees.max_per_gas().saturating_mul(NOOP_GAS.into());
+                let response_wei = intent.max_cost_wei();
+                if replacement_wei >= response_wei {
+                    Withdrawal::LeftPending {
+                        nonce: intent.nonce,
+                        replacement_wei,
+                        response_wei,
+                    }
+                } else {
+                    let nonce = intent.nonce;
+                    match self.replace_with_noop(&mut intent, fees, reason).await {
+                        Ok(tx_hash) => Withdrawal::Replaced { nonce, tx_hash },
+                        Err(e) => Withdrawal::Failed {
+                            nonce,
+                            error: e.to_string(),
+                        },
+                    }
+                }
+            }
+        };
+        METRICS.inc_counter(WITHDRAWALS_METRIC, &[("result", withdrawal.as_str())], 1);
+        info!("Withdrawing the response to challenge {challenge_id} ({reason}): {withdrawal:?}");
+        Ok(withdrawal)
+    }
+
+    async fn replace_with_noop(
+        &self,
+        intent: &mut Intent,
+        fees: Fees,
+        reason: &str,
+    ) -> Result<B256, PhalaAvsError> {
+        self.fenced(intent)?;
+        let mut replacement = intent.clone();
+        replacement.withdrawn = Some(reason.to_string());
+        replacement.fees = fees;
+        self.sign(&mut replacement).await?;
+        replacement.replaced.extend(intent.tx_hash);
+        // The response stays broadcast while its watcher picks the no-op up.
+        replacement.stage = IntentStage::Broadcast;
+        self.persist(&replacement)?;
+        let tx = self.signed(&replacement)?;
+        if let Err(e) = self.chain.broadcast(tx).await {
+            if !already_known(&e) {
+                return Err(e);
+            }
+        }
+        *intent = replacement;
+        intent
+            .tx_hash
+            .ok_or_else(|| PhalaAvsError::Other("The signed no-op has no hash".to_string()))
+    }
+
+    /// Succeeded intents whose receipt logs were not verified yet.
+    pub fn unverified(&self) -> Result<Vec<Intent>, PhalaAvsError> {
+        Ok(self
+            .intents()?
+            .into_iter()
+            .filter(|intent| intent.succeeded().is_some() && intent.verified_unix_ms.is_none())
+            .collect())
+    }
+
+    /// Records that the receipt logs of the intent included as `tx_hash` were verified, and
+    /// if the call achieved nothing, why its spend was wasted. Returns the updated intent,
+    /// `None` if no retained intent was included as `tx_hash`.
+    pub fn record_verification(
+        &self,
+        tx_hash: B256,
+        wasted: Option<String>,
+        now_ms: u64,
+    ) -> Result<Option<Intent>, PhalaAvsError> {
+        let Some(mut intent) = self
+            .intents()?
+            .into_iter()
+            .find(|intent| intent.succeeded() == Some(tx_hash))
+        else {
+            return Ok(None);
+        };
+        if let Some(reason) = &wasted {
+            let labels = [("label", intent.call.label.as_str())];
+            METRICS.inc_counter(WASTED_METRIC, &labels, 1);
+            let gwei = (intent.max_cost_wei() / 1_000_000_000).min(u64::MAX.into());
+            METRICS.inc_counter(WASTED_GWEI_METRIC, &labels, gwei as u64);
+            warn!(
+                "{} in {tx_hash} was wasted spend: {reason}",
+                intent.call.label
+            );
+        }
+        intent.verified_unix_ms = Some(now_ms);
+        intent.wasted = wasted;
+        self.persist(&intent)?;
+        Ok(Some(intent))
+    }
+
+    /// Replays every unfinalized intent: prepared and signed ones are (re-)broadcast with their
+    /// nonce. Returns the broadcast ones, which [`watch_recovered`](Self::watch_recovered) waits
+    /// for. Must complete before anything else is sent, so no nonce is handed out twice.
+    pub async fn recover(&self) -> Result<Vec<Intent>, PhalaAvsError> {
+        let mut pending = Vec::new();
+        for intent in self.intents()? {
+            if !intent.stage.is_pending() {
+                continue;
+            }
+            METRICS.inc_counter(
+                INTENTS_REPLAYED_METRIC,
+                &[("stage", intent.stage.as_str())],
+                1,
+            );
+            info!(
+                "Replaying the {} {} transaction at nonce {}",
+                intent.stage.as_str(),
+                intent.call.label,
+                intent.nonce
+            );
+            match self.advance(intent).await {
+                Ok(intent) if intent.stage == IntentStage::Broadcast => pending.push(intent),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to replay a transaction intent: {e}"),
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Waits for the outcome of every intent [`recover`](Self::recover) returned.
+    pub async fn watch_recovered(
+        &self,
+        pending: Vec<Intent>,
+    ) -> Vec<Result<TxOutcome, PhalaAvsError>> {
+        futures::future::join_all(pending.into_iter().map(|intent| self.watch(intent))).await
+    }
+
+    /// Drops the oldest finalized and failed intents beyond the retained count.
+    fn prune(&self) -> Result<(), PhalaAvsError> {
+        let mut settled: Vec<_> = self
+            .intents()?
+            .into_iter()
+            .filter(|intent| !intent.stage.is_pending())
+            .collect();
+        if settled.len() <= self.config.retained {
+            return Ok(());
+        }
+        settled.sort_by_key(|intent| intent.finalized_unix_ms.unwrap_or(intent.prepared_unix_ms));
+        for intent in &settled[..settled.len() - self.config.retained] {
+            self.store.delete(INTENT_NAMESPACE, &intent.key())?;
+        }
+        Ok(())
+    }
+}
+
+/// Watches the intents found pending at startup to their outcome.
+pub fn spawn_replay(sender: Arc<TxSender>, pending: Vec<Intent>) {
+    if pending.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        for result in sender.watch_recovered(pending).await {
+            match result {
+                Ok(outcome) => info!("Replayed transaction settled: {outcome:?}"),
+                Err(e) => warn!("Failed to settle a replayed transaction: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lanes::{AccountSource, LaneConfig};
+    use crate::replica::{MemoryReplicaStore, ReplicaConfig, ReplicaRole, ReplicaStore};
+    use crate::state::MemoryStateStore;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const ORACLE: Address = Address::repeat_byte(0x0c);
+
+    /// A chain that includes pending transactions when mined.
+    #[derive(Default)]
+    struct Chain {
+        pending: Mutex<BTreeMap<(Address, u64), B256>>,
+        included: Mutex<BTreeMap<Address, Vec<B256>>>,
+        broadcasts: Mutex<Vec<B256>>,
+        /// Broadcasts never complete while set.
+        stalled: Mutex<bool>,
+    }
+
+    impl Chain {
+        fn mine(&self) {
+            let mut included = self.included.lock().unwrap();
+            let mut pending = self.pending.lock().unwrap();
+            for ((account, nonce), hash) in std::mem::take(&mut *pending) {
+                let mined = included.entry(account).or_default();
+                if mined.len() as u64 == nonce {
+                    mined.push(hash);
+                } else {
+                    pending.insert((account, nonce), hash);
+                }
+            }
+        }
+    }
+
+    impl TxChain for Chain {
+        fn estimate_gas(&self, _: Address, _: TxCall) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(100_000) })
+        }
+
+        fn fees(&self) -> BoxFuture<'_, Result<Fees, PhalaAvsError>> {
+            Box::pin(async { Ok(Fees::Legacy { gas_price: 1_000 }) })
+        }
+
+        fn broadcast(&self, tx: SignedTx) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            if *self.stalled.lock().unwrap() {
+                return Box::pin(std::future::pending());
+            }
+            let included = self
+                .included
+                .lock()
+                .unwrap()
+                .get(&tx.from)
+                .map_or(0, Vec::len);
+            self.broadcasts.lock().unwrap().push(tx.hash);
+            let result = if (tx.nonce as usize) < included {
+                Err(PhalaAvsError::EvmError("nonce too low".to_string()))
+            } else {
+                self.pending
+                    .lock()
+                    .unwrap()
+                    .insert((tx.from, tx.nonce), tx.hash);
+                Ok(())
+            };
+            Box::pin(async move { result })
+        }
+
+        fn receipt(
+            &self,
+            tx_hash: B256,
+        ) -> BoxFuture<'_, Result<Option<TxOutcome>, PhalaAvsError>> {
+            let included = self.included.lock().unwrap();
+            let block = included
+                .values()
+                .flat_map(|hashes| hashes.iter().position(|h| *h == tx_hash))
+                .next();
+            let outcome = block.map(|block| TxOutcome::Included {
+                tx_hash,
+                block: block as u64,
+                success: true,
+            });
+            Box::pin(async move { Ok(outcome) })
+        }
+
+        fn included_nonce(&self, account: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            let nonce = self
+                .included
+                .lock()
+                .unwrap()
+                .get(&account)
+                .map_or(0, Vec::len);
+            Box::pin(async move { Ok(nonce as u64) })
+        }
+    }
+
+    impl AccountSource for Chain {
+        fn pending_nonce(&self, account: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            let included = self
+                .included
+                .lock()
+                .unwrap()
+                .get(&account)
+                .map_or(0, Vec::len);
+            let pending = self
+                .pending
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|(a, _)| *a == account)
+                .count();
+            Box::pin(async move { Ok((included + pending) as u64) })
+        }
+
+        fn balance(&self, _: Address) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+            Box::pin(async { Ok(u128::MAX) })
+        }
+
+        fn maintenance_signer(&self, _: Address) -> BoxFuture<'_, Result<Address, PhalaAvsError>> {
+            Box::pin(async { Ok(Address::ZERO) })
+        }
+    }
+
+    fn sender(chain: &Arc<Chain>, store: &Arc<dyn StateStore>, config: TxSenderConfig) -> TxSender {
+        let config_lanes = LaneConfig {
+            maintenance_key: None,
+            fallback_to_primary: false,
+            min_balance_wei: 0,
+            check_secs: 60,
+        };
+        let lanes = SignerLanes::new(
+            config_lanes,
+            KEY,
+            Arc::clone(chain) as Arc<dyn AccountSource>,
+        )
+        .unwrap();
+        TxSender::new(
+            config,
+            31337,
+            Arc::new(lanes),
+            Arc::clone(chain) as Arc<dyn TxChain>,
+            Arc::clone(store),
+        )
+    }
+
+    fn fast() -> TxSenderConfig {
+        TxSenderConfig {
+            receipt_poll: Duration::from_millis(1),
+            bump_after: Duration::from_secs(3600),
+            ..TxSenderConfig::default()
+        }
+    }
+
+    fn respond(challenge_id: u64) -> TxCall {
+        TxCall::new("respondToSlaChallenge", ORACLE, vec![challenge_id as u8])
+            .responding_to(U256::from(challenge_id))
+    }
+
+    #[tokio::test]
+    async fn pending_transactions_are_fee_bumped_until_one_is_included() {
+        let chain = Arc::new(Chain::default());
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let sender = Arc::new(sender(&chain, &store, TxSenderConfig {
+            bump_after: Duration::ZERO,
+            ..fast()
+        }));
+        let sending = tokio::spawn({
+            let sender = Arc::clone(&sender);
+            async move { sender.send(TxClass::Urgent, respond(1)).await }
+        });
+        while chain.broadcasts.lock().unwrap().len() < 3 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        chain.mine();
+        let outcome = sending.await.unwrap().unwrap();
+
+        let intent = sender.intent_for(U256::from(1)).unwrap().unwrap();
+        assert_eq!(intent.stage, IntentStage::Finalized);
+        assert!(intent.replaced.len() >= 2);
+        assert!(intent.fees.max_per_gas() > 1_000);
+        // A replacement was the one included, at the one nonce.
+        let TxOutcome::Included { tx_hash, .. } = outcome else {
+            panic!("unexpected outcome {outcome:?}");
+        };
+        assert_ne!(tx_hash, intent.replaced[0]);
+        assert!(intent.hashes().any(|hash| *hash == tx_hash));
+        assert_eq!(chain.included.lock().unwrap()[&intent.account], [tx_hash]);
+    }
+
+    #[tokio::test]
+    async fn a_challenge_is_answered_by_one_transaction() {
+        let chain = Arc::new(Chain::default());
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let sender = sender(&chain, &store, fast());
+        let first = tokio::spawn({
+            let chain = Arc::clone(&chain);
+            async move {
+                while chain.broadcasts.lock().unwrap().is_empty() {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                chain.mine();
+            }
+        });
+        let outcome = sender.send(TxClass::Urgent, respond(7)).await.unwrap();
+        first.await.unwrap();
+
+        assert_eq!(
+            sender.send(TxClass::Urgent, respond(7)).await.unwrap(),
+            outcome
+        );
+        assert_eq!(chain.broadcasts.lock().unwrap().len(), 1);
+        assert_eq!(sender.intents().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn escalated_calls_start_above_current_fees() {
+        let chain = Arc::new(Chain::default());
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let sender = sender(&chain, &store, fast());
+        let mined = tokio::spawn({
+            let chain = Arc::clone(&chain);
+            async move {
+                while chain.broadcasts.lock().unwrap().len() < 2 {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                chain.mine();
+            }
+        });
+        let (plain, escalated) = tokio::join!(
+            sender.send(TxClass::Urgent, respond(1)),
+            sender.send(TxClass::Urgent, respond(2).escalated(true)),
+        );
+        plain.unwrap();
+        escalated.unwrap();
+        mined.await.unwrap();
+
+        let fees = |id: u64| {
+            sender
+                .intent_for(U256::from(id))
+                .unwrap()
+                .unwrap()
+                .fees
+                .max_per_gas()
+        };
+        assert_eq!(fees(1), 1_000);
+        assert!(fees(2) > 1_000);
+    }
+
+    #[tokio::test]
+    async fn finalized_intents_are_pruned_to_the_retained_count() {
+        let chain = Arc::new(Chain::default());
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let sender = Arc::new(sender(&chain, &store, TxSenderConfig {
+            retained: 2,
+            ..fast()
+        }));
+        for id in 0..4u64 {
+            let sending = tokio::spawn({
+                let sender = Arc::clone(&sender);
+                async move { sender.send(TxClass::Urgent, respond(id)).await }
+            });
+            while chain.broadcasts.lock().unwrap().len() <= id as usize {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            chain.mine();
+            sending.await.unwrap().unwrap();
+        }
+
+        let kept: Vec<_> = sender.intents().unwrap().iter().map(|i| i.nonce).collect();
+        assert_eq!(kept, [2, 3]);
+    }
+    #[tokio::test]
+    async fn a_withdrawn_response_is_replaced_by_a_noop() {
+        let chain = Arc::new(Chain::default());
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let sender = Arc::new(sender(&chain, &store, fast()));
+        assert_eq!(
+            sender.withdraw(U256::from(3), "cancelled").await.unwrap(),
+            Withdrawal::NothingPending
+        );
+        let sending = tokio::spawn({
+            let sender = Arc::clone(&sender);
+            async move { sender.send(TxClass::Urgent, respond(3)).await }
+        });
+        while chain.broadcasts.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // 21k gas at 15% more per gas is far cheaper than the 100k gas response.
+        let withdrawal = sender.withdraw(U256::from(3), "cancelled").await.unwrap();
+        let Withdrawal::Replaced { nonce: 0, tx_hash } = withdrawal else {
+            panic!("unexpected withdrawal {withdrawal:?}");
+        };
+        chain.mine();
+        let outcome = sending.await.unwrap().unwrap();
+        assert!(matches!(outcome, TxOutcome::Withdrawn { tx_hash: hash, .. } if hash == tx_hash));
+        assert!(outcome.into_success("respondToSlaChallenge").is_err());
+
+        let intent = sender.intent_for(U256::from(3)).unwrap().unwrap();
+        assert_eq!(intent.withdrawn.as_deref(), Some("cancelled"));
+        assert_eq!(intent.noops, [tx_hash]);
+        assert_eq!(chain.included.lock().unwrap()[&intent.account], [tx_hash]);
+    }
+
+    #[tokio::test]
+    async fn a_standby_takes_over_a_killed_leader_and_one_response_lands() {
+        let chain = Arc::new(Chain::default());
+        let leases = Arc::new(MemoryReplicaStore::default());
+        let config = ReplicaConfig {
+            ttl: Duration::from_millis(200),
+            renew: Duration::from_millis(50),
+            ..ReplicaConfig::default()
+        };
+        let replica = |id: &str| {
+            Arc::new(ReplicaCoordinator::new(
+                ReplicaConfig {
+                    replica_id: id.to_string(),
+                    ..config.clone()
+                },
+                Address::ZERO,
+                Some(Arc::clone(&leases) as Arc<dyn ReplicaStore>),
+                Arc::new(MemoryStateStore::default()),
+            ))
+        };
+        let (a, b) = (replica("a"), replica("b"));
+        let store_a: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let store_b: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let leader = Arc::new(sender(&chain, &store_a, fast()).with_replica(Arc::clone(&a)));
+        let standby = sender(&chain, &store_b, fast()).with_replica(Arc::clone(&b));
+        let start = now_unix_ms();
+        assert_eq!(a.tick(start).unwrap(), ReplicaRole::Leader);
+        assert_eq!(b.tick(start).unwrap(), ReplicaRole::Standby);
+
+        // The standby builds the response too, but only records it.
+        assert_eq!(
+            standby.send(TxClass::Urgent, respond(5)).await.unwrap(),
+            TxOutcome::Shadowed
+        );
+
+        // The leader is killed once its response is signed, before it is broadcast.
+        *chain.stalled.lock().unwrap() = true;
+        let killed = tokio::spawn({
+            let leader = Arc::clone(&leader);
+            async move { leader.send(TxClass::Urgent, respond(5)).await }
+        });
+        while leader
+            .intent_for(U256::from(5))
+            .unwrap()
+            .is_none_or(|intent| intent.stage != IntentStage::Signed)
+        {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        killed.abort();
+        *chain.stalled.lock().unwrap() = false;
+
+        // Retrying at every renewal, the standby takes over within the bound.
+        let mut now = start;
+        while b.tick(now).unwrap() != ReplicaRole::Leader {
+            now += config.renew.as_millis() as u64;
+        }
+        assert!(Duration::from_millis(now - start) <= config.failover_bound());
+
+        // The old leader comes back and replays its signed response, which is fenced.
+        assert!(leader.recover().await.unwrap().is_empty());
+        let stale = &leader.intents().unwrap()[0];
+        assert_eq!(stale.stage, IntentStage::Failed);
+        assert!(stale.error.as_ref().unwrap().contains("Fenced"));
+        assert!(chain.broadcasts.lock().unwrap().is_empty());
+
+        let mining = tokio::spawn({
+            let chain = Arc::clone(&chain);
+            async move {
+                while chain.broadcasts.lock().unwrap().is_empty() {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                chain.mine();
+            }
+        });
+        let outcome = standby.send(TxClass::Urgent, respond(5)).await.unwrap();
+        mining.await.unwrap();
+        assert!(matches!(outcome, TxOutcome::Included { success: true, .. }));
+        assert_eq!(chain.broadcasts.lock().unwrap().len(), 1);
+        assert_eq!(chain.included.lock().unwrap()[&stale.account].len(), 1);
+        assert_eq!(
+            standby
+                .intent_for(U256::from(5))
+                .unwrap()
+                .unwrap()
+                .fencing_token,
+            Some(2)
+        );
+        // What the standby recorded is what it sent as leader.
+        assert!(b.check_divergence(now_unix_ms()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,647 @@
+//! Replay protection for signed off-chain payloads such as heartbeats.
+//!
+//! Each payload the operator signs for an off-chain consumer is wrapped in a [`SignedPayload`]
+//! carrying:
+//!
+//! - a sequence number, strictly increasing per payload kind;
+//! - the number and hash of the chain head when it was signed;
+//! - an expiry, `SIGNED_PAYLOAD_TTL_SECS` after signing.
+//!
+//! The EIP-712 signature covers all three together with the keccak hash of the payload's JSON,
+//! under the `PhalaTeeCloudAvs` domain of the connected chain. [`PayloadVerifier`] rejects bad
+//! signatures, expired payloads and sequences at or below the highest one seen from the operator
+//! for that kind; given a chain client it also checks the block hash is canonical and recent.
+//!
+//! Sequences are reserved `SIGNED_PAYLOAD_SEQUENCE_BLOCK` at a time: the end of the reserved
+//! block is persisted before any number in it is used, so signing does not write to the store
+//! each time, and after a crash numbering resumes past everything that may have been handed out.
+
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use crate::evm::EvmClient;
+use crate::state::{StateStore, StateStoreExt};
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, PrimitiveSignature, U256, keccak256};
+use blueprint_sdk::alloy::signers::SignerSync;
+use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+use blueprint_sdk::alloy::sol;
+use blueprint_sdk::alloy::sol_types::{Eip712Domain, SolStruct};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// End of the reserved sequence block, per payload kind.
+pub const SEQUENCE_NAMESPACE: &str = "payload_sequences";
+
+/// Payload kind of signed heartbeats.
+pub const HEARTBEAT_PAYLOAD: &str = "heartbeat";
+
+const DOMAIN_NAME: &str = "PhalaTeeCloudAvs";
+const DOMAIN_VERSION: &str = "1";
+
+sol! {
+    /// What the operator signs for an off-chain payload.
+    struct OperatorPayload {
+        string kind;
+        address operator;
+        uint64 sequence;
+        uint64 blockNumber;
+        bytes32 blockHash;
+        uint64 expiresAt;
+        bytes32 payloadHash;
+    }
+}
+
+/// The EIP-712 domain payloads are signed under on `chain_id`.
+pub fn domain(chain_id: u64) -> Eip712Domain {
+    Eip712Domain::new(
+        Some(DOMAIN_NAME.into()),
+        Some(DOMAIN_VERSION.into()),
+        Some(U256::from(chain_id)),
+        None,
+        None,
+    )
+}
+
+#[derive(Clone, Debug)]
+pub struct PayloadConfig {
+    /// How long a signed payload stays valid.
+    pub ttl_secs: u64,
+    /// Sequence numbers reserved per store write.
+    pub sequence_block: u64,
+}
+
+impl PayloadConfig {
+    /// Reads `SIGNED_PAYLOAD_TTL_SECS` and `SIGNED_PAYLOAD_SEQUENCE_BLOCK`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let config = Self {
+            ttl_secs: env_or("SIGNED_PAYLOAD_TTL_SECS", 300)?,
+            sequence_block: env_or("SIGNED_PAYLOAD_SEQUENCE_BLOCK", 1000)?,
+        };
+        if config.sequence_block == 0 {
+            return Err(PhalaAvsError::ConfigError(
+                "SIGNED_PAYLOAD_SEQUENCE_BLOCK must be at least 1".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// A payload with its replay protection and the operator's EIP-712 signature.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignedPayload<T> {
+    pub kind: String,
+    pub operator: Address,
+    pub chain_id: u64,
+    pub sequence: u64,
+    pub block_number: u64,
+    pub block_hash: B256,
+    /// Unix seconds after which the payload is no longer accepted.
+    pub expires_at: u64,
+    pub payload: T,
+    pub signature: Bytes,
+}
+
+impl<T: Serialize> SignedPayload<T> {
+    /// The EIP-712 digest the signature is over.
+    pub fn signing_hash(&self) -> Result<B256, PhalaAvsError> {
+        let payload = serde_json::to_vec(&self.payload)
+            .map_err(|e| PhalaAvsError::Other(format!("Failed to serialize the payload: {e}")))?;
+        let message = OperatorPayload {
+            kind: self.kind.clone(),
+            operator: self.operator,
+            sequence: self.sequence,
+            blockNumber: self.block_number,
+            blockHash: self.block_hash,
+            expiresAt: self.expires_at,
+            payloadHash: keccak256(payload),
+        };
+        Ok(message.eip712_signing_hash(&domain(self.chain_id)))
+    }
+
+    /// The account that signed the payload.
+    pub fn signer(&self) -> Result<Address, PhalaAvsError> {
+        let signature = PrimitiveSignature::try_from(self.signature.as_ref())
+            .map_err(|e| PhalaAvsError::ValidationError(format!("Malformed signature: {e}")))?;
+        signature
+            .recover_address_from_prehash(&self.signing_hash()?)
+            .map_err(|e| PhalaAvsError::ValidationError(format!("Unrecoverable signature: {e}")))
+    }
+}
+
+#[derive(Debug)]
+struct Reserved {
+    next: u64,
+    /// Last sequence of the persisted block; `next` past it needs a new reservation.
+    end: u64,
+}
+
+/// Hands out sequence numbers per payload kind, never the same one twice across restarts.
+pub struct SequenceAllocator {
+    store: Arc<dyn StateStore>,
+    block: u64,
+    reserved: Mutex<HashMap<String, Reserved>>,
+}
+
+impl SequenceAllocator {
+    pub fn new(store: Arc<dyn StateStore>, block: u64) -> Self {
+        Self {
+            store,
+            block: block.max(1),
+            reserved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The next sequence for `kind`, starting at 1.
+    pub fn next(&self, kind: &str) -> Result<u64, PhalaAvsError> {
+        let mut reserved = self.reserved.lock().unwrap();
+        let entry = match reserved.entry(kind.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // Anything up to the persisted end may have been used before a restart.
+                let end = self
+                    .store
+                    .get_json::<u64>(SEQUENCE_NAMESPACE, kind.as_bytes())?
+                    .unwrap_or(0);
+                entry.insert(Reserved { next: end + 1, end })
+            }
+        };
+        if entry.next > entry.end {
+            let end = entry.end + self.block;
+            self.store
+                .put_json(SEQUENCE_NAMESPACE, kind.as_bytes(), &end)?;
+            entry.end = end;
+        }
+        let sequence = entry.next;
+        entry.next += 1;
+        Ok(sequence)
+    }
+}
+
+/// Signs payloads with the operator key, adding sequence, chain head and expiry.
+pub struct PayloadSigner {
+    config: PayloadConfig,
+    signer: PrivateKeySigner,
+    chain: Arc<dyn EvmClient>,
+    sequences: SequenceAllocator,
+}
+
+impl PayloadSigner {
+    pub fn new(
+        config: PayloadConfig,
+        signer: PrivateKeySigner,
+        chain: Arc<dyn EvmClient>,
+        store: Arc<dyn StateStore>,
+    ) -> Self {
+        let sequences = SequenceAllocator::new(store, config.sequence_block);
+        Self {
+            config,
+            signer,
+            chain,
+            sequences,
+        }
+    }
+
+    pub fn operator(&self) -> Address {
+        self.signer.address()
+    }
+
+    /// Signs `payload` as `kind` at `now_secs`, against the current chain head.
+    pub async fn sign<T: Serialize>(
+        &self,
+        kind: &str,
+        payload: T,
+        now_secs: u64,
+    ) -> Result<SignedPayload<T>, PhalaAvsError> {
+        let chain_id = self.chain.chain_id().await?;
+        let block_number = self.chain.block_number().await?;
+        let block_hash = self.chain.block_hash(block_number).await?.ok_or_else(|| {
+            PhalaAvsError::EvmError(format!("No hash for head block {block_number}"))
+        })?;
+        let mut signed = SignedPayload {
+            kind: kind.to_string(),
+            operator: self.signer.address(),
+            chain_id,
+            sequence: self.sequences.next(kind)?,
+            block_number,
+            block_hash,
+            expires_at: now_secs + self.config.ttl_secs,
+            payload,
+            signature: Bytes::new(),
+        };
+        let signature = self
+            .signer
+            .sign_hash_sync(&signed.signing_hash()?)
+            .map_err(|e| PhalaAvsError::Other(format!("Failed to sign the {kind} payload: {e}")))?;
+        signed.signature = Bytes::copy_from_slice(&signature.as_bytes());
+        Ok(signed)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VerifierConfig {
+    /// How far behind the head the signed block may be, when checked against a chain.
+    pub max_staleness_blocks: u64,
+    /// Grace given to expiries for clock differences between signer and verifier.
+    pub clock_skew_secs: u64,
+}
+
+impl Default for VerifierConfig {
+    fn default() -> Self {
+        Self {
+            max_staleness_blocks: 50,
+            clock_skew_secs: 30,
+        }
+    }
+}
+
+/// Why a signed payload was not accepted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    BadSignature(String),
+    WrongChain {
+        expected: u64,
+        found: u64,
+    },
+    Expired {
+        expires_at: u64,
+    },
+    /// The sequence is not above the highest already accepted.
+    Replayed {
+        sequence: u64,
+        highest: u64,
+    },
+    /// The signed block is not the canonical block at that height.
+    UnknownBlock {
+        number: u64,
+    },
+    StaleBlock {
+        number: u64,
+        head: u64,
+    },
+    Chain(String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::BadSignature(reason) => write!(f, "bad signature: {reason}"),
+            Rejection::WrongChain { expected, found } => {
+                write!(f, "signed for chain {found}, expected {expected}")
+            }
+            Rejection::Expired { expires_at } => write!(f, "expired at {expires_at}"),
+            Rejection::Replayed { sequence, highest } => {
+                write!(f, "sequence {sequence} replayed, already saw {highest}")
+            }
+            Rejection::UnknownBlock { number } => {
+                write!(f, "block hash is not canonical at {number}")
+            }
+            Rejection::StaleBlock { number, head } => {
+                write!(f, "signed at block {number}, too far behind head {head}")
+            }
+            Rejection::Chain(e) => write!(f, "could not check the chain: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+impl From<Rejection> for PhalaAvsError {
+    fn from(e: Rejection) -> Self {
+        PhalaAvsError::ValidationError(e.to_string())
+    }
+}
+
+/// Accepts each operator's payloads of a kind at most once and in sequence order.
+pub struct PayloadVerifier {
+    config: VerifierConfig,
+    chain_id: u64,
+    highest: Mutex<HashMap<(Address, String), u64>>,
+}
+
+impl PayloadVerifier {
+    pub fn new(config: VerifierConfig, chain_id: u64) -> Self {
+        Self {
+            config,
+            chain_id,
+            highest: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The highest sequence accepted from `operator` for `kind`.
+    pub fn highest(&self, operator: Address, kind: &str) -> Option<u64> {
+        let highest = self.highest.lock().unwrap();
+        highest.get(&(operator, kind.to_string())).copied()
+    }
+
+    /// Checks `signed` at `now_secs` and records its sequence. With `chain`, the signed block must
+    /// also be canonical and within `max_staleness_blocks` of the head.
+    pub async fn verify<T: Serialize>(
+        &self,
+        signed: &SignedPayload<T>,
+        now_secs: u64,
+        chain: Option<&dyn EvmClient>,
+    ) -> Result<(), Rejection> {
+        let signer = signed
+            .signer()
+            .map_err(|e| Rejection::BadSignature(e.to_string()))?;
+        if signer != signed.operator {
+            return Err(Rejection::BadSignature(format!(
+                "signed by {signer}, not {}",
+                signed.operator
+            )));
+        }
+        if signed.chain_id != self.chain_id {
+            return Err(Rejection::WrongChain {
+                expected: self.chain_id,
+                found: signed.chain_id,
+            });
+        }
+        if signed.expires_at + self.config.clock_skew_secs < now_secs {
+            return Err(Rejection::Expired {
+                expires_at: signed.expires_at,
+            });
+        }
+        if let Some(chain) = chain {
+            let number = signed.block_number;
+            let canonical = chain
+                .block_hash(number)
+                .await
+                .map_err(|e| Rejection::Chain(e.to_string()))?;
+            if canonical != Some(signed.block_hash) {
+                return Err(Rejection::UnknownBlock { number });
+            }
+            let head = chain
+                .block_number()
+                .await
+                .map_err(|e| Rejection::Chain(e.to_string()))?;
+            if head.saturating_sub(number) > self.config.max_staleness_blocks {
+                return Err(Rejection::StaleBlock { number, head });
+            }
+        }
+        let mut highest = self.highest.lock().unwrap();
+        let seen = highest
+            .entry((signed.operator, signed.kind.clone()))
+            .or_insert(0);
+        if signed.sequence <= *seen {
+            return Err(Rejection::Replayed {
+                sequence: signed.sequence,
+                highest: *seen,
+            });
+        }
+        *seen = signed.sequence;
+        Ok(())
+    }
+}
+
+/// Parses and verifies a JSON-encoded payload.
+pub async fn verify_json<T: Serialize + DeserializeOwned>(
+    verifier: &PayloadVerifier,
+    json: &[u8],
+    now_secs: u64,
+    chain: Option<&dyn EvmClient>,
+) -> Result<SignedPayload<T>, PhalaAvsError> {
+    let signed: SignedPayload<T> = serde_json::from_slice(json)
+        .map_err(|e| PhalaAvsError::ValidationError(format!("Malformed signed payload: {e}")))?;
+    verifier.verify(&signed, now_secs, chain).await?;
+    Ok(signed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evidence::HeartbeatEvidence;
+    use crate::evm::BoxFuture;
+    use crate::state::MemoryStateStore;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const CHAIN_ID: u64 = 31337;
+
+    #[derive(Default)]
+    struct MockChain {
+        head: AtomicU64,
+    }
+
+    fn hash_of(number: u64) -> B256 {
+        keccak256(number.to_be_bytes())
+    }
+
+    impl EvmClient for MockChain {
+        fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(CHAIN_ID) })
+        }
+
+        fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(self.head.load(Ordering::SeqCst)) })
+        }
+
+        fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+            let head = self.head.load(Ordering::SeqCst);
+            Box::pin(async move { Ok((number <= head).then(|| hash_of(number))) })
+        }
+
+        fn block_timestamp(
+            &self,
+            number: u64,
+        ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+            Box::pin(async move { Ok(Some(number * 12)) })
+        }
+
+        fn is_operator_registered(
+            &self,
+            _operator: Address,
+        ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+            Box::pin(async { Ok(true) })
+        }
+    }
+
+    fn config(sequence_block: u64) -> PayloadConfig {
+        PayloadConfig {
+            ttl_secs: 300,
+            sequence_block,
+        }
+    }
+
+    fn heartbeat(unix_ms: u64) -> HeartbeatEvidence {
+        HeartbeatEvidence {
+            unix_ms,
+            live: Some(true),
+            in_maintenance: false,
+            health_score: Some(100),
+            healthy: Some(true),
+        }
+    }
+
+    fn setup() -> (Arc<MockChain>, Arc<dyn StateStore>, PrivateKeySigner) {
+        let chain = Arc::new(MockChain::default());
+        chain.head.store(100, Ordering::SeqCst);
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        (chain, store, PrivateKeySigner::random())
+    }
+
+    #[tokio::test]
+    async fn sequences_progress_and_verify_against_the_chain() {
+        let (chain, store, key) = setup();
+        let signer = PayloadSigner::new(config(10), key.clone(), chain.clone(), store);
+        let verifier = PayloadVerifier::new(VerifierConfig::default(), CHAIN_ID);
+
+        for (i, now) in [1_000, 1_060, 1_120].into_iter().enumerate() {
+            chain.head.fetch_add(5, Ordering::SeqCst);
+            let signed = signer
+                .sign(HEARTBEAT_PAYLOAD, heartbeat(now * 1000), now)
+                .await
+                .unwrap();
+            assert_eq!(signed.sequence, i as u64 + 1);
+            assert_eq!(signed.signer().unwrap(), key.address());
+            let json = serde_json::to_vec(&signed).unwrap();
+            let verified =
+                verify_json::<HeartbeatEvidence>(&verifier, &json, now, Some(chain.as_ref()))
+                    .await
+                    .unwrap();
+            assert_eq!(verified, signed);
+        }
+        assert_eq!(verifier.highest(key.address(), HEARTBEAT_PAYLOAD), Some(3));
+        // Kinds are numbered independently.
+        let other = signer.sign("telemetry", 7u32, 1_200).await.unwrap();
+        assert_eq!(other.sequence, 1);
+        verifier.verify(&other, 1_200, None).await.unwrap();
+
+        // A hash that was never canonical, or a head long left behind, is refused.
+        let mut forged = signer
+            .sign(HEARTBEAT_PAYLOAD, heartbeat(0), 1_200)
+            .await
+            .unwrap();
+        forged.block_hash = B256::repeat_byte(9);
+        assert!(matches!(
+            verifier.verify(&forged, 1_200, Some(chain.as_ref())).await,
+            Err(Rejection::BadSignature(_))
+        ));
+        let stale = signer
+            .sign(HEARTBEAT_PAYLOAD, heartbeat(0), 1_200)
+            .await
+            .unwrap();
+        chain.head.fetch_add(51, Ordering::SeqCst);
+        assert_eq!(
+            verifier.verify(&stale, 1_200, Some(chain.as_ref())).await,
+            Err(Rejection::StaleBlock {
+                number: stale.block_number,
+                head: stale.block_number + 51
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn replayed_sequences_are_rejected() {
+        let (chain, store, key) = setup();
+        let signer = PayloadSigner::new(config(10), key, chain, store);
+        let verifier = PayloadVerifier::new(VerifierConfig::default(), CHAIN_ID);
+
+        let first = signer
+            .sign(HEARTBEAT_PAYLOAD, heartbeat(1), 1_000)
+            .await
+            .unwrap();
+        let second = signer
+            .sign(HEARTBEAT_PAYLOAD, heartbeat(2), 1_000)
+            .await
+            .unwrap();
+        verifier.verify(&first, 1_000, None).await.unwrap();
+        assert_eq!(
+            verifier.verify(&first, 1_000, None).await,
+            Err(Rejection::Replayed {
+                sequence: 1,
+                highest: 1
+            })
+        );
+        verifier.verify(&second, 1_000, None).await.unwrap();
+        // Once a later sequence is accepted, an earlier one arriving late is a replay too.
+        assert_eq!(
+            verifier.verify(&first, 1_000, None).await,
+            Err(Rejection::Replayed {
+                sequence: 1,
+                highest: 2
+            })
+        );
+
+        // Changing the sequence breaks the signature.
+        let mut bumped = first.clone();
+        bumped.sequence = 3;
+        assert!(matches!(
+            verifier.verify(&bumped, 1_000, None).await,
+            Err(Rejection::BadSignature(_))
+        ));
+        assert_eq!(verifier.highest(first.operator, HEARTBEAT_PAYLOAD), Some(2));
+    }
+
+    #[tokio::test]
+    async fn expired_payloads_are_rejected() {
+        let (chain, store, key) = setup();
+        let signer = PayloadSigner::new(config(10), key, chain, store);
+        let verifier = PayloadVerifier::new(
+            VerifierConfig {
+                max_staleness_blocks: 50,
+                clock_skew_secs: 30,
+            },
+            CHAIN_ID,
+        );
+
+        let signed = signer
+            .sign(HEARTBEAT_PAYLOAD, heartbeat(1), 1_000)
+            .await
+            .unwrap();
+        assert_eq!(signed.expires_at, 1_300);
+        assert_eq!(
+            verifier.verify(&signed, 1_331, None).await,
+            Err(Rejection::Expired { expires_at: 1_300 })
+        );
+        // Within the clock skew it is still accepted, and a rejected payload leaves no mark.
+        assert_eq!(verifier.highest(signed.operator, HEARTBEAT_PAYLOAD), None);
+        verifier.verify(&signed, 1_330, None).await.unwrap();
+
+        let other_chain = PayloadVerifier::new(VerifierConfig::default(), 1);
+        assert_eq!(
+            other_chain.verify(&signed, 1_000, None).await,
+            Err(Rejection::WrongChain {
+                expected: 1,
+                found: CHAIN_ID
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn sequences_are_not_reused_after_a_crash() {
+        let (chain, store, key) = setup();
+        let verifier = PayloadVerifier::new(VerifierConfig::default(), CHAIN_ID);
+
+        let signer = PayloadSigner::new(config(4), key.clone(), chain.clone(), Arc::clone(&store));
+        for expected in 1..=6 {
+            let signed = signer
+                .sign(HEARTBEAT_PAYLOAD, heartbeat(expected), 1_000)
+                .await
+                .unwrap();
+            assert_eq!(signed.sequence, expected);
+            verifier.verify(&signed, 1_000, None).await.unwrap();
+        }
+        // Two blocks reserved for six heartbeats: one store write per block, not per heartbeat.
+        let end: Option<u64> = store
+            .get_json(SEQUENCE_NAMESPACE, HEARTBEAT_PAYLOAD.as_bytes())
+            .unwrap();
+        assert_eq!(end, Some(8));
+
+        // The process dies with 7 and 8 reserved but unused; the restart skips past them.
+        drop(signer);
+        let restarted = PayloadSigner::new(config(4), key, chain, Arc::clone(&store));
+        let signed = restarted
+            .sign(HEARTBEAT_PAYLOAD, heartbeat(7), 1_060)
+            .await
+            .unwrap();
+        assert_eq!(signed.sequence, 9);
+        verifier.verify(&signed, 1_060, None).await.unwrap();
+        let end: Option<u64> = store
+            .get_json(SEQUENCE_NAMESPACE, HEARTBEAT_PAYLOAD.as_bytes())
+            .unwrap();
+        assert_eq!(end, Some(12));
+    }
+}
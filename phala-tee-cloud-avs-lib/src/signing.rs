@@ -0,0 +1,215 @@
+//! Batched signing of task response digests.
+//!
+//! During a catch-up burst every response needs a signature, and with a remote or TEE-backed
+//! keystore each one is a round trip. [`SigningBatcher`] collects the digests requested within
+//! `SIGN_BATCH_WINDOW_MS` of the first one, or until `SIGN_BATCH_MAX` are pending, and signs
+//! them with a single [`DigestSigner::sign_batch`] call, or one by one when the backend has no
+//! batch support. No digest waits longer than the window before its signing starts, and every
+//! digest gets its own result: one failed signature does not fail the others.
+
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use blueprint_sdk::alloy::primitives::B256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// A keystore backend signing response digests.
+pub trait DigestSigner: Send + Sync + 'static {
+    type Signature: Send + 'static;
+
+    fn sign(&self, digest: B256) -> BoxFuture<'_, Result<Self::Signature, PhalaAvsError>>;
+
+    /// Signs `digests` in one round trip, a result per digest in order; `None` when the backend
+    /// cannot batch.
+    fn sign_batch(
+        &self,
+        _digests: &[B256],
+    ) -> Option<BoxFuture<'_, Vec<Result<Self::Signature, PhalaAvsError>>>> {
+        None
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BatchConfig {
+    pub window: Duration,
+    pub max_batch: usize,
+}
+
+impl BatchConfig {
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            window: Duration::from_millis(env_or("SIGN_BATCH_WINDOW_MS", 200)?),
+            max_batch: env_or("SIGN_BATCH_MAX", 32usize)?.max(1),
+        })
+    }
+}
+
+type Reply<S> = oneshot::Sender<Result<S, PhalaAvsError>>;
+
+/// Handle to the background task batching signature requests.
+pub struct SigningBatcher<S: DigestSigner> {
+    requests: mpsc::UnboundedSender<(B256, Reply<S::Signature>)>,
+}
+
+impl<S: DigestSigner> Clone for SigningBatcher<S> {
+    fn clone(&self) -> Self {
+        Self {
+            requests: self.requests.clone(),
+        }
+    }
+}
+
+impl<S: DigestSigner> SigningBatcher<S> {
+    /// Spawns the batching task; it stops once every handle is dropped.
+    pub fn spawn(config: BatchConfig, signer: Arc<S>) -> Self {
+        let (requests, mut pending) = mpsc::unbounded_channel::<(B256, Reply<S::Signature>)>();
+        tokio::spawn(async move {
+            while let Some(first) = pending.recv().await {
+                let deadline = Instant::now() + config.window;
+                let mut batch = vec![first];
+                while batch.len() < config.max_batch {
+                    match tokio::time::timeout_at(deadline, pending.recv()).await {
+                        Ok(Some(request)) => batch.push(request),
+                        Ok(None) | Err(_) => break,
+                    }
+                }
+                sign_all(signer.as_ref(), batch).await;
+            }
+        });
+        Self { requests }
+    }
+
+    /// Signs `digest` with the next batch.
+    pub async fn sign(&self, digest: B256) -> Result<S::Signature, PhalaAvsError> {
+        let stopped = || PhalaAvsError::Other("Signing batcher stopped".to_string());
+        let (reply, signature) = oneshot::channel();
+        self.requests.send((digest, reply)).map_err(|_| stopped())?;
+        signature.await.map_err(|_| stopped())?
+    }
+}
+
+async fn sign_all<S: DigestSigner>(signer: &S, batch: Vec<(B256, Reply<S::Signature>)>) {
+    let digests: Vec<B256> = batch.iter().map(|(digest, _)| *digest).collect();
+    let Some(signing) = signer.sign_batch(&digests) else {
+        for (digest, reply) in batch {
+            let _ = reply.send(signer.sign(digest).await);
+        }
+        return;
+    };
+    let signatures = signing.await;
+    if signatures.len() != batch.len() {
+        let message = format!(
+            "Batch signer returned {} signatures for {} digests",
+            signatures.len(),
+            batch.len()
+        );
+        for (_, reply) in batch {
+            let _ = reply.send(Err(PhalaAvsError::Other(message.clone())));
+        }
+        return;
+    }
+    for ((_, reply), signature) in batch.into_iter().zip(signatures) {
+        let _ = reply.send(signature);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Signs a digest as itself, failing on the zero digest, and counts backend calls.
+    #[derive(Default)]
+    struct MockSigner {
+        batching: bool,
+        singles: AtomicUsize,
+        batches: AtomicUsize,
+    }
+
+    fn mock_sign(digest: B256) -> Result<B256, PhalaAvsError> {
+        if digest.is_zero() {
+            return Err(PhalaAvsError::TeeError("key unavailable".to_string()));
+        }
+        Ok(digest)
+    }
+
+    impl DigestSigner for MockSigner {
+        type Signature = B256;
+
+        fn sign(&self, digest: B256) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            self.singles.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { mock_sign(digest) })
+        }
+
+        fn sign_batch(
+            &self,
+            digests: &[B256],
+        ) -> Option<BoxFuture<'_, Vec<Result<B256, PhalaAvsError>>>> {
+            if !self.batching {
+                return None;
+            }
+            self.batches.fetch_add(1, Ordering::SeqCst);
+            let signatures = digests.iter().map(|d| mock_sign(*d)).collect();
+            Some(Box::pin(async move { signatures }))
+        }
+    }
+
+    const CONFIG: BatchConfig = BatchConfig {
+        window: Duration::from_millis(100),
+        max_batch: 8,
+    };
+
+    async fn sign_burst(signer: &Arc<MockSigner>) -> Vec<Result<B256, PhalaAvsError>> {
+        let batcher = SigningBatcher::spawn(CONFIG, Arc::clone(signer));
+        let digests = [1u8, 2, 0, 3, 4].map(|b| {
+            if b == 0 {
+                B256::ZERO
+            } else {
+                B256::repeat_byte(b)
+            }
+        });
+        futures::future::join_all(digests.map(|d| batcher.sign(d))).await
+    }
+
+    fn assert_isolated(results: &[Result<B256, PhalaAvsError>]) {
+        assert_eq!(results.len(), 5);
+        assert!(results[2].is_err());
+        for (i, byte) in [(0, 1u8), (1, 2), (3, 3), (4, 4)] {
+            assert_eq!(*results[i].as_ref().unwrap(), B256::repeat_byte(byte));
+        }
+    }
+
+    #[tokio::test]
+    async fn bursts_are_signed_in_one_backend_call_within_the_window() {
+        let signer = Arc::new(MockSigner {
+            batching: true,
+            ..Default::default()
+        });
+        let started = std::time::Instant::now();
+        let results = sign_burst(&signer).await;
+        assert_isolated(&results);
+        assert_eq!(signer.batches.load(Ordering::SeqCst), 1);
+        assert_eq!(signer.singles.load(Ordering::SeqCst), 0);
+        // Nothing waits for more than the window, however few responses arrive.
+        assert!(started.elapsed() < CONFIG.window + Duration::from_millis(80));
+
+        let batcher = SigningBatcher::spawn(CONFIG, Arc::clone(&signer));
+        let started = std::time::Instant::now();
+        batcher.sign(B256::repeat_byte(9)).await.unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= CONFIG.window);
+        assert!(elapsed < CONFIG.window + Duration::from_millis(80));
+    }
+
+    #[tokio::test]
+    async fn backends_without_batching_sign_sequentially() {
+        let signer = Arc::new(MockSigner::default());
+        let results = sign_burst(&signer).await;
+        assert_isolated(&results);
+        assert_eq!(signer.batches.load(Ordering::SeqCst), 0);
+        assert_eq!(signer.singles.load(Ordering::SeqCst), 5);
+    }
+}
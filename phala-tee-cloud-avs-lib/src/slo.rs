@@ -0,0 +1,721 @@
+//! Service level objectives on challenge response latency, with error budgets.
+//!
+//! An objective reads "`target`% of challenges answered within `threshold` blocks of issuance,
+//! over `window` days", and is configured in `SLO_OBJECTIVES` as
+//! `<name>:<target percent>:<threshold blocks>:<window days>,...`. Every `SLO_CHECK_SECS` the
+//! challenges first seen in each window are evaluated:
+//!
+//! - a challenge answered within the threshold is good. Latency runs from the issuing block, or
+//!   the block of its last amendment, to the block the response was included in, as recorded by
+//!   the [`ResponseIndex`]; responses it has no block for are converted from their local latency
+//!   at `SLO_BLOCK_TIME_MS`;
+//! - a challenge answered late, missed, rejected by the oracle or disputed is bad: it counts
+//!   fully against the budget;
+//! - cancelled and invalid challenges asked nothing of the operator and are left out. Only
+//!   challenges whose response window has closed, or which are settled, are counted.
+//!
+//! The error budget is the share of challenges the target allows to be bad; the burn rate of a
+//! window is how fast it is being spent there, 1.0 spending exactly the budget over the
+//! objective's window. Burn rates are alerted on over two windows, as in SRE practice: a fast
+//! burn over `SLO_FAST_BURN_WINDOW_SECS` (one hour) at `SLO_FAST_BURN_RATE` is critical, a slow
+//! burn over `SLO_SLOW_BURN_WINDOW_SECS` (a day) at `SLO_SLOW_BURN_RATE` a warning. Alerts are
+//! raised when an objective's burn level changes, and once more when it recovers.
+//!
+//! Rates and shares are kept in basis points so the status can be signed into the reputation
+//! summary; a burn rate of 1.0 is 10 000 bps.
+
+use crate::challenge::{ChallengeState, ChallengeTracker, TrackedChallenge};
+use crate::config::{env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::evm::EvmClient;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::self_audit::responded;
+use crate::sender::{TxOutcome, TxSender};
+use blueprint_sdk::alloy::primitives::{Address, U256};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::warn;
+
+/// Gauge of the share of good challenges over an objective's window, in bps.
+pub const SLO_COMPLIANCE_METRIC: &str = "phala_avs_slo_compliance_bps";
+/// Gauge of the error budget left over an objective's window, in bps; negative once overspent.
+pub const SLO_BUDGET_METRIC: &str = "phala_avs_slo_error_budget_remaining_bps";
+/// Gauge of the burn rate of an objective, in bps of the sustainable rate, by `window`.
+pub const SLO_BURN_RATE_METRIC: &str = "phala_avs_slo_burn_rate_bps";
+
+const DAY_MS: u64 = 86_400_000;
+
+/// One latency objective.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SloObjective {
+    pub name: String,
+    /// Share of challenges to answer within the threshold, in bps.
+    pub target_bps: u32,
+    pub threshold_blocks: u64,
+    pub window_days: u64,
+}
+
+impl std::str::FromStr for SloObjective {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').map(str::trim).collect();
+        let [name, target, threshold, window] = parts[..] else {
+            return Err(format!(
+                "expected <name>:<target percent>:<threshold blocks>:<window days>, got {s}"
+            ));
+        };
+        let target: f64 = target
+            .parse()
+            .map_err(|e| format!("invalid target {target}: {e}"))?;
+        if !(0.0..100.0).contains(&target) {
+            return Err(format!("target {target} must be at least 0 and below 100"));
+        }
+        let window_days: u64 = window
+            .parse()
+            .map_err(|e| format!("invalid window {window}: {e}"))?;
+        if name.is_empty() || window_days == 0 {
+            return Err(format!("{s} needs a name and a window of a day or more"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            target_bps: (target * 100.0).round() as u32,
+            threshold_blocks: threshold
+                .parse()
+                .map_err(|e| format!("invalid threshold {threshold}: {e}"))?,
+            window_days,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SloConfig {
+    pub objectives: Vec<SloObjective>,
+    pub fast_window_secs: u64,
+    /// Burn rate over the fast window that is critical.
+    pub fast_burn_rate: f64,
+    pub slow_window_secs: u64,
+    /// Burn rate over the slow window that is a warning.
+    pub slow_burn_rate: f64,
+    /// Converts the local latency of responses without an inclusion block.
+    pub block_time_ms: u64,
+    pub check_secs: u64,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            objectives: vec![SloObjective {
+                name: "challenge_latency".to_string(),
+                target_bps: 9_900,
+                threshold_blocks: 10,
+                window_days: 30,
+            }],
+            fast_window_secs: 3_600,
+            fast_burn_rate: 14.4,
+            slow_window_secs: 86_400,
+            slow_burn_rate: 3.0,
+            block_time_ms: 12_000,
+            check_secs: 60,
+        }
+    }
+}
+
+impl SloConfig {
+    /// Reads the `SLO_*` settings.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let d = Self::default();
+        let objectives = match env_opt::<String>("SLO_OBJECTIVES")? {
+            Some(raw) => raw
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|objective| {
+                    objective.parse().map_err(|e| {
+                        PhalaAvsError::ConfigError(format!("Invalid SLO_OBJECTIVES entry: {e}"))
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            None => d.objectives,
+        };
+        Ok(Self {
+            objectives,
+            fast_window_secs: env_or("SLO_FAST_BURN_WINDOW_SECS", d.fast_window_secs)?.max(1),
+            fast_burn_rate: env_or("SLO_FAST_BURN_RATE", d.fast_burn_rate)?,
+            slow_window_secs: env_or("SLO_SLOW_BURN_WINDOW_SECS", d.slow_window_secs)?.max(1),
+            slow_burn_rate: env_or("SLO_SLOW_BURN_RATE", d.slow_burn_rate)?,
+            block_time_ms: env_or("SLO_BLOCK_TIME_MS", d.block_time_ms)?.max(1),
+            check_secs: env_or("SLO_CHECK_SECS", d.check_secs)?.max(1),
+        })
+    }
+}
+
+/// Where responses were included, by the challenge they answer.
+pub trait ResponseIndex: Send + Sync {
+    fn inclusion_blocks(&self) -> Result<BTreeMap<U256, u64>, PhalaAvsError>;
+}
+
+impl ResponseIndex for TxSender {
+    fn inclusion_blocks(&self) -> Result<BTreeMap<U256, u64>, PhalaAvsError> {
+        let mut latest = BTreeMap::new();
+        for intent in self.intents()? {
+            let (Some(id), Some(TxOutcome::Included { block, .. })) =
+                (intent.call.challenge_id, intent.outcome)
+            else {
+                continue;
+            };
+            latest
+                .entry(id)
+                .and_modify(|(at, included)| {
+                    if intent.prepared_unix_ms > *at {
+                        *at = intent.prepared_unix_ms;
+                        *included = block;
+                    }
+                })
+                .or_insert((intent.prepared_unix_ms, block));
+        }
+        Ok(latest
+            .into_iter()
+            .map(|(id, (_, block))| (id, block))
+            .collect())
+    }
+}
+
+/// What a counted challenge came to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SloEvent {
+    pub first_seen_unix_ms: u64,
+    /// Blocks from issuance to the included response; `None` if it went unanswered.
+    pub latency_blocks: Option<u64>,
+}
+
+impl SloEvent {
+    /// What `tracked` counts as at `head`, or `None` if it isn't counted (yet).
+    pub fn of(
+        tracked: &TrackedChallenge,
+        inclusions: &BTreeMap<U256, u64>,
+        block_time_ms: u64,
+        head: u64,
+    ) -> Option<Self> {
+        if matches!(
+            tracked.state,
+            ChallengeState::Cancelled | ChallengeState::Invalid
+        ) {
+            return None;
+        }
+        if !tracked.state.is_settled() && tracked.challenge.deadline_block >= head {
+            return None;
+        }
+        let origin = tracked
+            .amendments
+            .last()
+            .map_or(tracked.challenge.issued_block, |a| a.block);
+        let latency_blocks = match inclusions.get(&tracked.challenge.challenge_id) {
+            _ if tracked.state != ChallengeState::Responded => None,
+            Some(block) => Some(block.saturating_sub(origin)),
+            None => responded(tracked).map(|at| {
+                // Detection delay was measured against the original issuance.
+                let detection = if tracked.amendments.is_empty() {
+                    tracked.detection_delay_blocks.unwrap_or_default()
+                } else {
+                    0
+                };
+                detection
+                    + at.saturating_sub(tracked.latency_origin_ms())
+                        .div_ceil(block_time_ms)
+            }),
+        };
+        Some(Self {
+            first_seen_unix_ms: tracked.first_seen_unix_ms,
+            latency_blocks,
+        })
+    }
+}
+
+/// Good and bad challenges over a window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SloWindow {
+    pub secs: u64,
+    pub total: u64,
+    pub bad: u64,
+    /// `None` without challenges.
+    pub burn_rate_bps: Option<u64>,
+}
+
+/// Which burn an objective is in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BurnLevel {
+    #[default]
+    Ok,
+    SlowBurn,
+    FastBurn,
+}
+
+/// Compliance of one objective.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SloStatus {
+    pub objective: SloObjective,
+    pub evaluated_unix_ms: u64,
+    pub total: u64,
+    pub good: u64,
+    /// `None` without challenges.
+    pub compliance_bps: Option<u32>,
+    /// Share of the error budget left; negative once overspent.
+    pub budget_remaining_bps: i64,
+    pub fast: SloWindow,
+    pub slow: SloWindow,
+    pub level: BurnLevel,
+}
+
+fn window(objective: &SloObjective, events: &[SloEvent], now_ms: u64, secs: u64) -> SloWindow {
+    let from_ms = now_ms.saturating_sub(secs * 1000);
+    let (mut total, mut bad) = (0, 0);
+    for event in events {
+        if (from_ms..now_ms).contains(&event.first_seen_unix_ms) {
+            total += 1;
+            if !event
+                .latency_blocks
+                .is_some_and(|blocks| blocks <= objective.threshold_blocks)
+            {
+                bad += 1;
+            }
+        }
+    }
+    let allowed_bps = 10_000 - u64::from(objective.target_bps.min(9_999));
+    SloWindow {
+        secs,
+        total,
+        bad,
+        burn_rate_bps: (total > 0).then(|| bad * 10_000 * 10_000 / (total * allowed_bps)),
+    }
+}
+
+/// The status of `objective` at `now_ms`, from the challenges in `events`.
+pub fn evaluate(
+    config: &SloConfig,
+    objective: &SloObjective,
+    events: &[SloEvent],
+    now_ms: u64,
+) -> SloStatus {
+    let whole = window(
+        objective,
+        events,
+        now_ms,
+        objective.window_days * DAY_MS / 1000,
+    );
+    let fast = window(objective, events, now_ms, config.fast_window_secs);
+    let slow = window(objective, events, now_ms, config.slow_window_secs);
+    let level = if fast
+        .burn_rate_bps
+        .is_some_and(|r| r as f64 >= config.fast_burn_rate * 10_000.0)
+    {
+        BurnLevel::FastBurn
+    } else if slow
+        .burn_rate_bps
+        .is_some_and(|r| r as f64 >= config.slow_burn_rate * 10_000.0)
+    {
+        BurnLevel::SlowBurn
+    } else {
+        BurnLevel::Ok
+    };
+    let good = whole.total - whole.bad;
+    SloStatus {
+        objective: objective.clone(),
+        evaluated_unix_ms: now_ms,
+        total: whole.total,
+        good,
+        compliance_bps: (whole.total > 0).then(|| (good * 10_000 / whole.total) as u32),
+        // The whole window's burn rate is the share of the budget spent.
+        budget_remaining_bps: 10_000 - whole.burn_rate_bps.unwrap_or_default() as i64,
+        fast,
+        slow,
+        level,
+    }
+}
+
+/// Evaluates the objectives and alerts on burn level changes.
+pub struct SloMonitor {
+    config: SloConfig,
+    operator: Address,
+    tracker: Arc<ChallengeTracker>,
+    responses: Arc<dyn ResponseIndex>,
+    levels: Mutex<BTreeMap<String, BurnLevel>>,
+    latest: RwLock<Option<Vec<SloStatus>>>,
+}
+
+impl SloMonitor {
+    pub fn new(
+        config: SloConfig,
+        operator: Address,
+        tracker: Arc<ChallengeTracker>,
+        responses: Arc<dyn ResponseIndex>,
+    ) -> Self {
+        Self {
+            config,
+            operator,
+            tracker,
+            responses,
+            levels: Mutex::new(BTreeMap::new()),
+            latest: RwLock::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &SloConfig {
+        &self.config
+    }
+
+    /// The latest evaluation, or `None` before the first.
+    pub fn statuses(&self) -> Option<Vec<SloStatus>> {
+        self.latest
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// The challenges counted at `head`.
+    pub fn events(&self, head: u64) -> Result<Vec<SloEvent>, PhalaAvsError> {
+        let inclusions = self.responses.inclusion_blocks()?;
+        Ok(self
+            .tracker
+            .closed_between(0, u64::MAX)?
+            .iter()
+            .filter(|t| t.challenge.operator == self.operator)
+            .filter_map(|t| SloEvent::of(t, &inclusions, self.config.block_time_ms, head))
+            .collect())
+    }
+
+    /// The status of every objective at `head` and `now_ms`, without alerting.
+    pub fn evaluate(&self, head: u64, now_ms: u64) -> Result<Vec<SloStatus>, PhalaAvsError> {
+        let events = self.events(head)?;
+        Ok(self
+            .config
+            .objectives
+            .iter()
+            .map(|objective| evaluate(&self.config, objective, &events, now_ms))
+            .collect())
+    }
+
+    /// Evaluates every objective at `head` and `now_ms`, as [`Self::observe`] does.
+    pub fn check(
+        &self,
+        head: u64,
+        now_ms: u64,
+    ) -> Result<(Vec<SloStatus>, Vec<Alert>), PhalaAvsError> {
+        let events = self.events(head)?;
+        Ok(self.observe(&events, now_ms))
+    }
+
+    /// Evaluates every objective over `events`, exporting the results and returning the alerts
+    /// of the objectives whose burn level changed.
+    pub fn observe(&self, events: &[SloEvent], now_ms: u64) -> (Vec<SloStatus>, Vec<Alert>) {
+        let mut levels = self.levels.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statuses = Vec::new();
+        let mut alerts = Vec::new();
+        for objective in &self.config.objectives {
+            let status = evaluate(&self.config, objective, events, now_ms);
+            export(&status);
+            let previous = levels
+                .insert(objective.name.clone(), status.level)
+                .unwrap_or_default();
+            if previous != status.level {
+                alerts.push(alert(&status));
+            }
+            statuses.push(status);
+        }
+        drop(levels);
+        *self.latest.write().unwrap_or_else(|e| e.into_inner()) = Some(statuses.clone());
+        (statuses, alerts)
+    }
+}
+
+fn rate(window: &SloWindow) -> f64 {
+    window.burn_rate_bps.unwrap_or_default() as f64 / 10_000.0
+}
+
+fn alert(status: &SloStatus) -> Alert {
+    let name = &status.objective.name;
+    let budget = status.budget_remaining_bps as f64 / 100.0;
+    match status.level {
+        BurnLevel::FastBurn => Alert::new(
+            "slo",
+            Severity::Critical,
+            format!(
+                "SLO {name} is burning its error budget {:.1}x too fast over the last {}s \
+                 ({} of {} challenges bad); {budget:.1}% of the budget is left",
+                rate(&status.fast),
+                status.fast.secs,
+                status.fast.bad,
+                status.fast.total
+            ),
+        ),
+        BurnLevel::SlowBurn => Alert::new(
+            "slo",
+            Severity::Warning,
+            format!(
+                "SLO {name} is burning its error budget {:.1}x too fast over the last {}s \
+                 ({} of {} challenges bad); {budget:.1}% of the budget is left",
+                rate(&status.slow),
+                status.slow.secs,
+                status.slow.bad,
+                status.slow.total
+            ),
+        ),
+        BurnLevel::Ok => Alert::new(
+            "slo",
+            Severity::Info,
+            format!("SLO {name} is no longer burning fast; {budget:.1}% of the budget is left"),
+        ),
+    }
+}
+
+fn export(status: &SloStatus) {
+    let labels = [("slo", status.objective.name.as_str())];
+    if let Some(compliance) = status.compliance_bps {
+        METRICS.set_gauge(SLO_COMPLIANCE_METRIC, &labels, compliance as f64);
+    }
+    METRICS.set_gauge(
+        SLO_BUDGET_METRIC,
+        &labels,
+        status.budget_remaining_bps as f64,
+    );
+    for (window, stats) in [("fast", &status.fast), ("slow", &status.slow)] {
+        METRICS.set_gauge(
+            SLO_BURN_RATE_METRIC,
+            &[("slo", status.objective.name.as_str()), ("window", window)],
+            stats.burn_rate_bps.unwrap_or_default() as f64,
+        );
+    }
+}
+
+/// Evaluates the objectives every `check_secs`, delivering alerts through `notifier`.
+pub fn spawn_monitor(
+    monitor: Arc<SloMonitor>,
+    evm: Arc<dyn EvmClient>,
+    notifier: Arc<dyn Notifier>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(monitor.config.check_secs));
+        loop {
+            interval.tick().await;
+            let head = match evm.block_number().await {
+                Ok(head) => head,
+                Err(e) => {
+                    warn!("Failed to read the head for SLO evaluation: {e}");
+                    continue;
+                }
+            };
+            let alerts = match monitor.check(head, now_unix_ms()) {
+                Ok((_, alerts)) => alerts,
+                Err(e) => {
+                    warn!("Failed to evaluate SLOs: {e}");
+                    continue;
+                }
+            };
+            for alert in alerts {
+                if let Err(e) = notifier.notify(alert).await {
+                    warn!("Failed to deliver SLO alert: {e}");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::{Amendment, ConfirmationPolicy};
+    use crate::fixtures::ChallengeEventFixture;
+    use crate::state::{MemoryStateStore, StateStore};
+
+    const HOUR_MS: u64 = 3_600_000;
+    /// Challenges are first seen every five minutes.
+    const STEP_MS: u64 = 300_000;
+    const T0: u64 = 1_700_000_000_000;
+
+    struct NoResponses;
+
+    impl ResponseIndex for NoResponses {
+        fn inclusion_blocks(&self) -> Result<BTreeMap<U256, u64>, PhalaAvsError> {
+            Ok(BTreeMap::new())
+        }
+    }
+
+    fn monitor() -> SloMonitor {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker =
+            Arc::new(ChallengeTracker::new(ConfirmationPolicy::default(), store).unwrap());
+        SloMonitor::new(
+            SloConfig::default(),
+            Address::ZERO,
+            tracker,
+            Arc::new(NoResponses),
+        )
+    }
+
+    /// Thirty days of challenges answered in 3 blocks, except those `bad` says went wrong.
+    fn history(bad: impl Fn(u64) -> Option<Option<u64>>) -> Vec<SloEvent> {
+        (0..30 * 24 * HOUR_MS / STEP_MS)
+            .map(|i| {
+                let at = T0 + i * STEP_MS;
+                SloEvent {
+                    first_seen_unix_ms: at,
+                    latency_blocks: bad(at - T0).unwrap_or(Some(3)),
+                }
+            })
+            .collect()
+    }
+
+    /// Replays `events` with an hourly check, returning the last status and the hour and
+    /// severity of every alert.
+    fn replay(events: &[SloEvent]) -> (SloStatus, Vec<(u64, Severity)>) {
+        let monitor = monitor();
+        let mut fired = Vec::new();
+        let mut last = None;
+        for hour in 1..=30 * 24 {
+            let (statuses, alerts) = monitor.observe(events, T0 + hour * HOUR_MS);
+            fired.extend(alerts.into_iter().map(|a| (hour, a.severity)));
+            last = statuses.into_iter().next();
+        }
+        (last.unwrap(), fired)
+    }
+
+    #[test]
+    fn compliant_history_keeps_most_of_its_budget_quietly() {
+        // One late response every other day.
+        let events =
+            history(|offset| (offset % (2 * 24 * HOUR_MS) == 25 * HOUR_MS).then_some(Some(50)));
+        let (status, fired) = replay(&events);
+
+        assert!(fired.is_empty(), "{fired:?}");
+        assert_eq!((status.total, status.good), (8_640, 8_625));
+        assert_eq!(status.compliance_bps, Some(9_982));
+        // 15 of the 86.4 challenges the budget allows.
+        assert_eq!(status.budget_remaining_bps, 8_264);
+        assert_eq!(status.level, BurnLevel::Ok);
+    }
+
+    #[test]
+    fn outage_fast_burns_then_slow_burns_until_it_leaves_the_day() {
+        // Two hours of missed challenges on day 20.
+        let outage = 20 * 24 * HOUR_MS..20 * 24 * HOUR_MS + 2 * HOUR_MS;
+        let events = history(|offset| outage.contains(&offset).then_some(None));
+        let (status, fired) = replay(&events);
+
+        let start = 20 * 24;
+        assert_eq!(fired, vec![
+            (start + 1, Severity::Critical),
+            (start + 3, Severity::Warning),
+            (start + 2 + 24, Severity::Info),
+        ]);
+        assert_eq!(status.total - status.good, 24);
+        assert_eq!(status.budget_remaining_bps, 7_223);
+        assert_eq!(status.level, BurnLevel::Ok);
+    }
+
+    #[test]
+    fn sustained_lateness_slow_burns_without_paging() {
+        // For a day and a half, one challenge an hour is answered late or missed.
+        let degraded = 10 * 24 * HOUR_MS..10 * 24 * HOUR_MS + 36 * HOUR_MS;
+        let events = history(|offset| {
+            if !degraded.contains(&offset) || offset % HOUR_MS != 0 {
+                return None;
+            }
+            Some((offset / HOUR_MS % 2 == 0).then_some(40))
+        });
+        let (status, fired) = replay(&events);
+
+        // Nine bad challenges in a day (3.1%) burn three times too fast; recovery waits for
+        // all but eight to leave the day.
+        let start = 10 * 24;
+        assert_eq!(fired, vec![
+            (start + 9, Severity::Warning),
+            (start + 36 + 16, Severity::Info),
+        ]);
+        assert_eq!(status.total - status.good, 36);
+        assert_eq!(status.budget_remaining_bps, 5_834);
+    }
+
+    fn track(tracker: &ChallengeTracker, id: u64, path: &[ChallengeState]) -> TrackedChallenge {
+        let challenge = ChallengeEventFixture::new()
+            .id(id)
+            .block(100)
+            .build_observed();
+        tracker.observe(challenge, 100).unwrap();
+        let mut tracked = None;
+        for &state in path {
+            tracked = Some(tracker.transition(U256::from(id), state, "test").unwrap());
+        }
+        tracked.unwrap()
+    }
+
+    #[test]
+    fn events_follow_final_states() {
+        use ChallengeState::*;
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let tracker = ChallengeTracker::new(ConfirmationPolicy::default(), store).unwrap();
+        let answered = track(&tracker, 1, &[
+            Queued,
+            Building,
+            Submitting,
+            AwaitingInclusion,
+            Responded,
+        ]);
+        let missed = track(&tracker, 2, &[Queued, Missed]);
+        let cancelled = track(&tracker, 3, &[Queued, Cancelled]);
+        let rejected = track(&tracker, 4, &[
+            Queued,
+            Building,
+            Submitting,
+            AwaitingInclusion,
+            RejectedByOracle,
+        ]);
+        let inclusions = BTreeMap::from([(U256::from(1), 104), (U256::from(4), 104)]);
+        let event = |t: &TrackedChallenge| SloEvent::of(t, &inclusions, 12_000, 1_000);
+
+        assert_eq!(event(&answered).unwrap().latency_blocks, Some(4));
+        assert_eq!(event(&missed).unwrap().latency_blocks, None);
+        assert_eq!(event(&rejected).unwrap().latency_blocks, None);
+        assert_eq!(event(&cancelled), None);
+
+        // An amended challenge is timed from its amendment.
+        let mut amended = answered.clone();
+        amended.amendments.push(Amendment {
+            block: 102,
+            unix_ms: answered.first_seen_unix_ms,
+            from: Queued,
+            previous_data: Default::default(),
+            previous_deadline_block: 0,
+            withdrawal: None,
+        });
+        assert_eq!(event(&amended).unwrap().latency_blocks, Some(2));
+
+        // Without an inclusion block, the local latency is converted.
+        let local = SloEvent::of(&answered, &BTreeMap::new(), 12_000, 1_000).unwrap();
+        assert!(local.latency_blocks.is_some());
+
+        // An open challenge isn't counted until its window closes.
+        let open = track(&tracker, 5, &[Queued]);
+        let deadline = open.challenge.deadline_block;
+        assert_eq!(SloEvent::of(&open, &inclusions, 12_000, deadline), None);
+        let closed = SloEvent::of(&open, &inclusions, 12_000, deadline + 1).unwrap();
+        assert_eq!(closed.latency_blocks, None);
+    }
+
+    #[test]
+    fn objectives_parse_from_config() {
+        let objective: SloObjective = "fast:99.5:5:7".parse().unwrap();
+        assert_eq!(objective.target_bps, 9_950);
+        assert_eq!((objective.threshold_blocks, objective.window_days), (5, 7));
+        assert!("x:100:5:7".parse::<SloObjective>().is_err());
+        assert!("x:99:5".parse::<SloObjective>().is_err());
+        assert!("x:99:5:0".parse::<SloObjective>().is_err());
+    }
+}
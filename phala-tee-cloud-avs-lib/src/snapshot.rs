@@ -0,0 +1,582 @@
+//! Chain snapshots for replaying an incident window offline.
+//!
+//! `snapshot capture` runs the challenge pipeline over a block range against an archive RPC,
+//! with every [`EvmClient`] and [`HistorySource`] call going through a [`CapturingChain`]. The
+//! chain is pinned to each block of the range in turn, the way the log producer delivers them,
+//! and every answer is recorded into a [`ChainSnapshot`]: block hashes and timestamps, the
+//! registration status and the oracle's logs of every block. `snapshot replay` runs the same
+//! pipeline against a [`SnapshotChain`] serving only what was recorded, with the placeholder TEE
+//! and a fresh in-memory store, so the tracker reaches the same outcomes on a laptop. A request
+//! the capture did not make fails with [`SNAPSHOT_MISS_MARKER`] rather than being answered, so a
+//! gap in the snapshot cannot pass for an empty chain.
+//!
+//! The pipeline only reads the chain through these two traits; transaction receipts are not
+//! requested while observing challenges, so none are recorded.
+
+use crate::challenge::{ChallengeTracker, ConfirmationPolicy, ObservedChallenge, process_events};
+use crate::error::PhalaAvsError;
+use crate::evm::{BoxFuture, EvmClient};
+use crate::state::rebuild::{ChallengeOutcome, HistorySource, RebuildScope};
+use crate::state::{MemoryStateStore, StateStore};
+use crate::tee::{TeeHandler, TeeHandlerConfig};
+use blueprint_sdk::alloy::primitives::{Address, B256, U256};
+use blueprint_sdk::alloy::rpc::types::Log;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+
+/// Version of the [`ChainSnapshot`] format.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// In the message of every error for a request the snapshot holds no answer to.
+pub const SNAPSHOT_MISS_MARKER: &str = "not in the snapshot";
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Everything the pipeline read from the chain over a block range.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChainSnapshot {
+    pub version: u32,
+    pub chain_id: Option<u64>,
+    pub scope: Option<RebuildScope>,
+    pub from_block: u64,
+    pub to_block: u64,
+    /// `None` for a block the archive node did not have.
+    pub block_hashes: BTreeMap<u64, Option<B256>>,
+    pub block_timestamps: BTreeMap<u64, Option<u64>>,
+    /// Registration status of each operator asked about, at capture time.
+    pub registrations: BTreeMap<Address, bool>,
+    /// The oracle's logs of every block read, in chain order; empty for a block without any.
+    pub logs: BTreeMap<u64, Vec<Log>>,
+}
+
+impl ChainSnapshot {
+    /// Writes the snapshot to `path` as zstd-compressed JSON.
+    pub fn write(&self, path: &Path) -> Result<(), PhalaAvsError> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| PhalaAvsError::Other(format!("Failed to encode the snapshot: {e}")))?;
+        std::fs::write(path, zstd::encode_all(json.as_slice(), COMPRESSION_LEVEL)?)?;
+        Ok(())
+    }
+
+    /// Reads a snapshot written by [`Self::write`].
+    pub fn read(path: &Path) -> Result<Self, PhalaAvsError> {
+        let json = zstd::decode_all(std::fs::read(path)?.as_slice())?;
+        let snapshot: Self = serde_json::from_slice(&json).map_err(|e| {
+            PhalaAvsError::ValidationError(format!("{} is not a snapshot: {e}", path.display()))
+        })?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "{} is a version {} snapshot; version {SNAPSHOT_VERSION} is supported",
+                path.display(),
+                snapshot.version
+            )));
+        }
+        Ok(snapshot)
+    }
+
+    /// The scope the snapshot was captured for.
+    pub fn scope(&self) -> Result<&RebuildScope, PhalaAvsError> {
+        self.scope
+            .as_ref()
+            .ok_or_else(|| PhalaAvsError::ValidationError("The snapshot has no scope".into()))
+    }
+}
+
+/// A chain whose head can be pinned to a past block, as the producer saw it.
+pub trait PinnedChain: EvmClient + HistorySource {
+    fn pin(&self, head: u64);
+}
+
+/// [`PinnedChain`] forwarding to a live chain and recording every answer.
+pub struct CapturingChain {
+    evm: Arc<dyn EvmClient>,
+    history: Arc<dyn HistorySource>,
+    head: AtomicU64,
+    recorded: Mutex<ChainSnapshot>,
+}
+
+impl CapturingChain {
+    /// Records `[from_block, to_block]` of `scope` on the chain behind `evm` and `history`.
+    pub fn new(
+        evm: Arc<dyn EvmClient>,
+        history: Arc<dyn HistorySource>,
+        scope: RebuildScope,
+        from_block: u64,
+        to_block: u64,
+    ) -> Self {
+        Self {
+            evm,
+            history,
+            head: AtomicU64::new(from_block),
+            recorded: Mutex::new(ChainSnapshot {
+                version: SNAPSHOT_VERSION,
+                scope: Some(scope),
+                from_block,
+                to_block,
+                ..ChainSnapshot::default()
+            }),
+        }
+    }
+
+    fn record(&self, f: impl FnOnce(&mut ChainSnapshot)) {
+        f(&mut self.recorded.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    /// What was recorded so far.
+    pub fn snapshot(&self) -> ChainSnapshot {
+        self.recorded
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+impl PinnedChain for CapturingChain {
+    fn pin(&self, head: u64) {
+        self.head.store(head, Ordering::SeqCst);
+    }
+}
+
+impl EvmClient for CapturingChain {
+    fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            let chain_id = self.evm.chain_id().await?;
+            self.record(|s| s.chain_id = Some(chain_id));
+            Ok(chain_id)
+        })
+    }
+
+    fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        let head = self.head.load(Ordering::SeqCst);
+        Box::pin(async move { Ok(head) })
+    }
+
+    fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+        Box::pin(async move {
+            // The chain is only as long as the pinned head, whatever the archive node has since.
+            if number > self.head.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            let hash = self.evm.block_hash(number).await?;
+            self.record(|s| {
+                s.block_hashes.insert(number, hash);
+            });
+            Ok(hash)
+        })
+    }
+
+    fn block_timestamp(&self, number: u64) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+        Box::pin(async move {
+            if number > self.head.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            let timestamp = self.evm.block_timestamp(number).await?;
+            self.record(|s| {
+                s.block_timestamps.insert(number, timestamp);
+            });
+            Ok(timestamp)
+        })
+    }
+
+    fn is_operator_registered(
+        &self,
+        operator: Address,
+    ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+        Box::pin(async move {
+            let registered = self.evm.is_operator_registered(operator).await?;
+            self.record(|s| {
+                s.registrations.insert(operator, registered);
+            });
+            Ok(registered)
+        })
+    }
+}
+
+impl HistorySource for CapturingChain {
+    fn head(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        self.block_number()
+    }
+
+    fn oracle_logs(
+        &self,
+        oracle: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>> {
+        Box::pin(async move {
+            let logs = self
+                .history
+                .oracle_logs(oracle, from_block, to_block)
+                .await?;
+            self.record(|s| {
+                for block in from_block..=to_block {
+                    s.logs.entry(block).or_default();
+                }
+                for log in &logs {
+                    let block = log.block_number.unwrap_or_default();
+                    s.logs.entry(block).or_default().push(log.clone());
+                }
+            });
+            Ok(logs)
+        })
+    }
+}
+
+/// [`PinnedChain`] serving a [`ChainSnapshot`], and nothing else.
+pub struct SnapshotChain {
+    snapshot: ChainSnapshot,
+    head: AtomicU64,
+}
+
+impl SnapshotChain {
+    pub fn new(snapshot: ChainSnapshot) -> Self {
+        let head = AtomicU64::new(snapshot.from_block);
+        Self { snapshot, head }
+    }
+
+    pub fn snapshot(&self) -> &ChainSnapshot {
+        &self.snapshot
+    }
+
+    fn miss(&self, request: String) -> PhalaAvsError {
+        error!(
+            "Replay requested {request}, which the capture of blocks {} to {} never did",
+            self.snapshot.from_block, self.snapshot.to_block
+        );
+        PhalaAvsError::EvmError(format!("{request} is {SNAPSHOT_MISS_MARKER}"))
+    }
+}
+
+impl PinnedChain for SnapshotChain {
+    fn pin(&self, head: u64) {
+        self.head.store(head, Ordering::SeqCst);
+    }
+}
+
+impl EvmClient for SnapshotChain {
+    fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        let chain_id = self
+            .snapshot
+            .chain_id
+            .ok_or_else(|| self.miss("the chain id".into()));
+        Box::pin(async move { chain_id })
+    }
+
+    fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        let head = self.head.load(Ordering::SeqCst);
+        Box::pin(async move { Ok(head) })
+    }
+
+    fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+        let hash = match self.snapshot.block_hashes.get(&number) {
+            _ if number > self.head.load(Ordering::SeqCst) => Ok(None),
+            Some(hash) => Ok(*hash),
+            None => Err(self.miss(format!("the hash of block {number}"))),
+        };
+        Box::pin(async move { hash })
+    }
+
+    fn block_timestamp(&self, number: u64) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+        let timestamp = match self.snapshot.block_timestamps.get(&number) {
+            _ if number > self.head.load(Ordering::SeqCst) => Ok(None),
+            Some(timestamp) => Ok(*timestamp),
+            None => Err(self.miss(format!("the timestamp of block {number}"))),
+        };
+        Box::pin(async move { timestamp })
+    }
+
+    fn is_operator_registered(
+        &self,
+        operator: Address,
+    ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+        let registered = self
+            .snapshot
+            .registrations
+            .get(&operator)
+            .copied()
+            .ok_or_else(|| self.miss(format!("the registration of {operator}")));
+        Box::pin(async move { registered })
+    }
+}
+
+impl HistorySource for SnapshotChain {
+    fn head(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        self.block_number()
+    }
+
+    fn oracle_logs(
+        &self,
+        oracle: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>> {
+        let recorded_oracle = self.snapshot.scope.as_ref().map(|scope| scope.oracle);
+        let logs = (from_block..=to_block)
+            .map(|block| match self.snapshot.logs.get(&block) {
+                Some(logs) if recorded_oracle == Some(oracle) => Ok(logs.clone()),
+                _ => Err(self.miss(format!("the logs of {oracle} in block {block}"))),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|blocks| blocks.concat());
+        Box::pin(async move { logs })
+    }
+}
+
+/// What came of each challenge the tracker holds.
+pub fn outcomes(tracker: &ChallengeTracker) -> BTreeMap<U256, ChallengeOutcome> {
+    tracker
+        .snapshot()
+        .iter()
+        .map(|tracked| {
+            (
+                tracked.challenge.challenge_id,
+                ChallengeOutcome::from(tracked),
+            )
+        })
+        .collect()
+}
+
+/// Runs the challenge pipeline over `[from_block, to_block]` of `chain`, delivering each
+/// block's oracle logs at that block as the producer does. Submissions are released while the
+/// operator is registered, as the registration gate decides at startup.
+pub async fn run_window<C: PinnedChain>(
+    chain: &C,
+    tracker: &ChallengeTracker,
+    tee: &TeeHandler,
+    scope: &RebuildScope,
+    from_block: u64,
+    to_block: u64,
+    safety_margin: impl Fn(&ObservedChallenge) -> u64 + Copy,
+) -> Result<(), PhalaAvsError> {
+    chain.pin(from_block);
+    chain.chain_id().await?;
+    let registered = chain.is_operator_registered(scope.operator).await?;
+    for block in from_block..=to_block {
+        chain.pin(block);
+        let logs = chain.oracle_logs(scope.oracle, block, block).await?;
+        process_events(
+            tracker,
+            chain,
+            tee,
+            scope.operator,
+            &logs,
+            registered,
+            safety_margin,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Runs the pipeline over `chain` with a fresh in-memory tracker and the placeholder TEE.
+async fn run_fresh<C: PinnedChain>(
+    chain: &C,
+    policy: ConfirmationPolicy,
+    scope: &RebuildScope,
+    from_block: u64,
+    to_block: u64,
+) -> Result<BTreeMap<U256, ChallengeOutcome>, PhalaAvsError> {
+    let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+    let tracker = ChallengeTracker::new(policy, store)?;
+    let tee = TeeHandler::new(TeeHandlerConfig::default()).await?;
+    run_window(chain, &tracker, &tee, scope, from_block, to_block, |_| 0).await?;
+    Ok(outcomes(&tracker))
+}
+
+/// Captures `[from_block, to_block]` of `scope` from a live chain, returning the snapshot and
+/// the outcomes the pipeline reached while capturing.
+pub async fn capture(
+    evm: Arc<dyn EvmClient>,
+    history: Arc<dyn HistorySource>,
+    policy: ConfirmationPolicy,
+    scope: RebuildScope,
+    from_block: u64,
+    to_block: u64,
+) -> Result<(ChainSnapshot, BTreeMap<U256, ChallengeOutcome>), PhalaAvsError> {
+    if from_block > to_block {
+        return Err(PhalaAvsError::ValidationError(format!(
+            "Cannot capture from block {from_block} to block {to_block}"
+        )));
+    }
+    let head = history.head().await?;
+    if head < to_block {
+        return Err(PhalaAvsError::ValidationError(format!(
+            "Cannot capture up to block {to_block}; the chain is at block {head}"
+        )));
+    }
+    info!("Capturing blocks {from_block} to {to_block}");
+    let chain = CapturingChain::new(evm, history, scope.clone(), from_block, to_block);
+    let outcomes = run_fresh(&chain, policy, &scope, from_block, to_block).await?;
+    Ok((chain.snapshot(), outcomes))
+}
+
+/// Replays a snapshot offline, returning the outcomes the pipeline reaches.
+pub async fn replay(
+    snapshot: ChainSnapshot,
+    policy: ConfirmationPolicy,
+) -> Result<BTreeMap<U256, ChallengeOutcome>, PhalaAvsError> {
+    let scope = snapshot.scope()?.clone();
+    let (from_block, to_block) = (snapshot.from_block, snapshot.to_block);
+    info!("Replaying blocks {from_block} to {to_block} from the snapshot");
+    let chain = SnapshotChain::new(snapshot);
+    run_fresh(&chain, policy, &scope, from_block, to_block).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::challenge::ChallengeState;
+    use crate::fixtures::{
+        ChallengeEventFixture, ChallengeUpdateFixture, OPERATOR, ORACLE, block_hash,
+    };
+    use blueprint_sdk::alloy::primitives::Bytes;
+
+    const FROM: u64 = 100;
+    const TO: u64 = 160;
+
+    /// An archive node with a few challenges in `[FROM, TO]`.
+    struct ArchiveChain;
+
+    impl ArchiveChain {
+        fn logs(block: u64) -> Vec<Log> {
+            let issued = |id: u64, window: u64| {
+                ChallengeEventFixture::new()
+                    .id(id)
+                    .data(Bytes::from_static(b"liveness"))
+                    .window(window)
+                    .block(block)
+                    .build_log()
+            };
+            match block {
+                105 => vec![issued(1, 20), issued(2, 20)],
+                110 => vec![
+                    ChallengeUpdateFixture::cancelled(2)
+                        .block(block)
+                        .build_log(),
+                ],
+                120 => vec![issued(3, 10)],
+                // Another operator's challenge is ignored.
+                125 => vec![
+                    ChallengeEventFixture::new()
+                        .id(4)
+                        .operator(Address::repeat_byte(9))
+                        .block(block)
+                        .build_log(),
+                ],
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    impl EvmClient for ArchiveChain {
+        fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(31337) })
+        }
+
+        fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(TO + 1_000) })
+        }
+
+        fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+            Box::pin(async move { Ok(Some(block_hash(number))) })
+        }
+
+        fn block_timestamp(
+            &self,
+            number: u64,
+        ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+            Box::pin(async move { Ok(Some(number * 12)) })
+        }
+
+        fn is_operator_registered(
+            &self,
+            _operator: Address,
+        ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+            Box::pin(async { Ok(true) })
+        }
+    }
+
+    impl HistorySource for ArchiveChain {
+        fn head(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            self.block_number()
+        }
+
+        fn oracle_logs(
+            &self,
+            oracle: Address,
+            from_block: u64,
+            to_block: u64,
+        ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>> {
+            let logs = match oracle == ORACLE {
+                true => (from_block..=to_block).flat_map(Self::logs).collect(),
+                false => Vec::new(),
+            };
+            Box::pin(async move { Ok(logs) })
+        }
+    }
+
+    fn scope() -> RebuildScope {
+        RebuildScope {
+            operator: OPERATOR,
+            oracle: ORACLE,
+            service_manager: Address::repeat_byte(3),
+        }
+    }
+
+    async fn captured() -> (ChainSnapshot, BTreeMap<U256, ChallengeOutcome>) {
+        let archive = Arc::new(ArchiveChain);
+        capture(
+            Arc::clone(&archive) as _,
+            archive,
+            ConfirmationPolicy::default(),
+            scope(),
+            FROM,
+            TO,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn replay_reaches_the_outcomes_of_the_capture() {
+        let (snapshot, live) = captured().await;
+        assert_eq!(live.len(), 3);
+        assert_eq!(live[&U256::from(2)].state, ChallengeState::Cancelled);
+        assert_eq!(snapshot.logs.len() as u64, TO - FROM + 1);
+
+        let dir = std::env::temp_dir().join(format!("snapshot-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("incident.snapshot");
+        snapshot.write(&path).unwrap();
+        let read = ChainSnapshot::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read, snapshot);
+
+        let replayed = replay(read, ConfirmationPolicy::default()).await.unwrap();
+        assert_eq!(replayed, live);
+    }
+
+    #[tokio::test]
+    async fn unrecorded_requests_fail_loudly() {
+        let (snapshot, _) = captured().await;
+        let chain = SnapshotChain::new(snapshot);
+        chain.pin(TO);
+
+        let beyond = chain.oracle_logs(ORACLE, TO, TO + 1).await.unwrap_err();
+        assert!(
+            beyond.to_string().contains(SNAPSHOT_MISS_MARKER),
+            "{beyond}"
+        );
+        let other = chain.oracle_logs(Address::ZERO, FROM, FROM).await;
+        assert!(other.is_err());
+        let stranger = chain.is_operator_registered(Address::ZERO).await;
+        assert!(stranger.is_err());
+        // A block past the pinned head does not exist yet, recorded or not.
+        assert_eq!(chain.block_hash(TO + 1).await.unwrap(), None);
+    }
+}
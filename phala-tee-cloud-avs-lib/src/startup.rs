@@ -0,0 +1,381 @@
+//! Explicit, staged startup of the operator's subsystems.
+//!
+//! The status server comes up first so operators can watch the remaining stages progress on
+//! `/status`. Each later stage declares the stages it depends on, an init timeout and whether it
+//! is required: a required stage failing aborts startup with a [`StartupReport`], an optional one
+//! is recorded as degraded and startup continues.
+
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use serde::Serialize;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const STATUS: &str = "status";
+pub const STORAGE: &str = "storage";
+pub const EVM: &str = "evm";
+pub const KEYSTORE: &str = "keystore";
+pub const TEE: &str = "tee";
+pub const SCHEMAS: &str = "schemas";
+pub const PRODUCERS: &str = "producers";
+
+/// A subsystem initialization step.
+#[derive(Clone, Debug)]
+pub struct Stage {
+    pub name: &'static str,
+    pub depends_on: &'static [&'static str],
+    pub required: bool,
+    pub timeout: Duration,
+}
+
+impl Stage {
+    pub fn required(
+        name: &'static str,
+        depends_on: &'static [&'static str],
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            name,
+            depends_on,
+            required: true,
+            timeout,
+        }
+    }
+
+    pub fn optional(
+        name: &'static str,
+        depends_on: &'static [&'static str],
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            required: false,
+            ..Self::required(name, depends_on, timeout)
+        }
+    }
+
+    /// Overrides the timeout from `STARTUP_<NAME>_TIMEOUT_SECS` when set.
+    fn with_env_timeout(mut self) -> Result<Self, PhalaAvsError> {
+        let key = format!("STARTUP_{}_TIMEOUT_SECS", self.name.to_ascii_uppercase());
+        self.timeout = Duration::from_secs(env_or(&key, self.timeout.as_secs())?);
+        Ok(self)
+    }
+}
+
+/// The operator's startup plan, in order.
+///
+/// The TEE is optional: a TEE that is slow to boot degrades the operator instead of keeping it
+/// from starting. So is the response schema check; until it succeeds, compiled encoders are used.
+pub fn default_plan() -> Result<Vec<Stage>, PhalaAvsError> {
+    [
+        Stage::required(STATUS, &[], Duration::from_secs(5)),
+        Stage::required(STORAGE, &[STATUS], Duration::from_secs(30)),
+        Stage::required(EVM, &[STORAGE], Duration::from_secs(30)),
+        Stage::required(KEYSTORE, &[EVM], Duration::from_secs(10)),
+        Stage::optional(TEE, &[KEYSTORE], Duration::from_secs(60)),
+        Stage::optional(SCHEMAS, &[EVM], Duration::from_secs(30)),
+        Stage::required(PRODUCERS, &[EVM, KEYSTORE], Duration::from_secs(30)),
+    ]
+    .into_iter()
+    .map(Stage::with_env_timeout)
+    .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InitState {
+    Pending,
+    Initializing,
+    Ready,
+    /// An optional stage failed; the operator runs without it.
+    Degraded,
+    Failed,
+}
+
+/// Initialization progress of a single subsystem, as shown on `/status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SubsystemStatus {
+    pub name: String,
+    pub required: bool,
+    pub state: InitState,
+    /// Time spent initializing so far, or in total once finished.
+    pub elapsed_ms: Option<u64>,
+    pub error: Option<String>,
+    #[serde(skip)]
+    started_at: Option<Instant>,
+}
+
+/// Shared, live view of startup progress.
+#[derive(Clone, Debug, Default)]
+pub struct StartupStatus {
+    subsystems: Arc<RwLock<Vec<SubsystemStatus>>>,
+}
+
+impl StartupStatus {
+    /// Returns the current progress of every declared subsystem, in startup order.
+    pub fn snapshot(&self) -> Vec<SubsystemStatus> {
+        let subsystems = self.subsystems.read().unwrap_or_else(|e| e.into_inner());
+        subsystems
+            .iter()
+            .map(|s| {
+                let mut s = s.clone();
+                if s.state == InitState::Initializing {
+                    s.elapsed_ms = s.started_at.map(|t| t.elapsed().as_millis() as u64);
+                }
+                s
+            })
+            .collect()
+    }
+
+    /// Returns the state of `name`, if it has been declared.
+    pub fn state(&self, name: &str) -> Option<InitState> {
+        let subsystems = self.subsystems.read().unwrap_or_else(|e| e.into_inner());
+        subsystems.iter().find(|s| s.name == name).map(|s| s.state)
+    }
+
+    /// True once every required subsystem is ready.
+    pub fn is_ready(&self) -> bool {
+        let subsystems = self.subsystems.read().unwrap_or_else(|e| e.into_inner());
+        subsystems
+            .iter()
+            .all(|s| !s.required || s.state == InitState::Ready)
+    }
+
+    fn declare(&self, stage: &Stage) {
+        let mut subsystems = self.subsystems.write().unwrap_or_else(|e| e.into_inner());
+        if subsystems.iter().all(|s| s.name != stage.name) {
+            subsystems.push(SubsystemStatus {
+                name: stage.name.to_string(),
+                required: stage.required,
+                state: InitState::Pending,
+                elapsed_ms: None,
+                error: None,
+                started_at: None,
+            });
+        }
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut SubsystemStatus)) {
+        let mut subsystems = self.subsystems.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(s) = subsystems.iter_mut().find(|s| s.name == name) {
+            f(s);
+        }
+    }
+}
+
+/// Per-stage outcome of startup, attached to the error when a required stage fails.
+#[derive(Clone, Debug)]
+pub struct StartupReport(pub Vec<SubsystemStatus>);
+
+impl fmt::Display for StartupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for s in &self.0 {
+            write!(f, "\n  {:<10} {:?}", s.name, s.state)?;
+            if let Some(ms) = s.elapsed_ms {
+                write!(f, " after {ms}ms")?;
+            }
+            if let Some(error) = &s.error {
+                write!(f, ": {error}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs startup stages in dependency order, recording progress in a [`StartupStatus`].
+#[derive(Debug)]
+pub struct StartupOrchestrator {
+    plan: Vec<Stage>,
+    status: StartupStatus,
+}
+
+impl StartupOrchestrator {
+    /// Declares every stage of `plan` as pending so `/status` shows the whole plan up front.
+    pub fn new(plan: Vec<Stage>, status: StartupStatus) -> Self {
+        for stage in &plan {
+            status.declare(stage);
+        }
+        Self { plan, status }
+    }
+
+    pub fn status(&self) -> &StartupStatus {
+        &self.status
+    }
+
+    pub fn report(&self) -> StartupReport {
+        StartupReport(self.status.snapshot())
+    }
+
+    /// Runs the initializer of stage `name`.
+    ///
+    /// Returns `Ok(None)` when an optional stage failed, timed out or had an unavailable
+    /// dependency; a required stage in that situation aborts startup.
+    pub async fn run<T, F>(&self, name: &str, init: F) -> Result<Option<T>, PhalaAvsError>
+    where
+        F: Future<Output = Result<T, PhalaAvsError>>,
+    {
+        let stage = self
+            .plan
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| PhalaAvsError::StartupError(format!("unknown stage {name}")))?;
+
+        let started_at = Instant::now();
+        let result = match stage
+            .depends_on
+            .iter()
+            .find(|dep| self.status.state(dep) != Some(InitState::Ready))
+        {
+            Some(dep) => Err(format!("dependency {dep} is not ready")),
+            None => {
+                self.status.update(name, |s| {
+                    s.state = InitState::Initializing;
+                    s.started_at = Some(started_at);
+                });
+                match tokio::time::timeout(stage.timeout, init).await {
+                    Ok(Ok(value)) => Ok(value),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err(format!("timed out after {:?}", stage.timeout)),
+                }
+            }
+        };
+        let elapsed_ms = started_at.elapsed().as_millis() as u64;
+
+        match result {
+            Ok(value) => {
+                info!("Startup stage {name} ready after {elapsed_ms}ms");
+                self.status.update(name, |s| {
+                    s.state = InitState::Ready;
+                    s.elapsed_ms = Some(elapsed_ms);
+                });
+                Ok(Some(value))
+            }
+            Err(error) if !stage.required => {
+                warn!("Optional startup stage {name} degraded: {error}");
+                self.status.update(name, |s| {
+                    s.state = InitState::Degraded;
+                    s.elapsed_ms = Some(elapsed_ms);
+                    s.error = Some(error);
+                });
+                Ok(None)
+            }
+            Err(error) => {
+                self.status.update(name, |s| {
+                    s.state = InitState::Failed;
+                    s.elapsed_ms = Some(elapsed_ms);
+                    s.error = Some(error);
+                });
+                Err(PhalaAvsError::StartupError(format!(
+                    "required stage {name} failed{}",
+                    self.report()
+                )))
+            }
+        }
+    }
+
+    /// Like [`run`](Self::run), for stages whose output the operator cannot run without.
+    pub async fn run_required<T, F>(&self, name: &str, init: F) -> Result<T, PhalaAvsError>
+    where
+        F: Future<Output = Result<T, PhalaAvsError>>,
+    {
+        self.run(name, init).await?.ok_or_else(|| {
+            PhalaAvsError::StartupError(format!(
+                "stage {name} is optional but its output is required"
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "http-api")]
+    use crate::status::{StatusState, spawn_status_server};
+
+    fn plan() -> Vec<Stage> {
+        vec![
+            Stage::required(STATUS, &[], Duration::from_secs(1)),
+            Stage::required(STORAGE, &[STATUS], Duration::from_secs(1)),
+            Stage::required(EVM, &[STORAGE], Duration::from_millis(50)),
+            Stage::optional(TEE, &[STORAGE], Duration::from_secs(3600)),
+        ]
+    }
+
+    #[cfg(feature = "http-api")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn hanging_tee_init_does_not_block_status() {
+        let orchestrator = Arc::new(StartupOrchestrator::new(plan(), StartupStatus::default()));
+        let state = StatusState::new(orchestrator.status().clone());
+        let addr = orchestrator
+            .run_required(
+                STATUS,
+                spawn_status_server("127.0.0.1:0".parse().unwrap(), state),
+            )
+            .await
+            .unwrap();
+        orchestrator
+            .run_required(STORAGE, async { Ok(()) })
+            .await
+            .unwrap();
+
+        let tee = Arc::clone(&orchestrator);
+        tokio::spawn(async move {
+            tee.run(TEE, std::future::pending::<Result<(), PhalaAvsError>>())
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let body = reqwest::get(format!("http://{addr}/status"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let tee = status["subsystems"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|s| s["name"] == TEE)
+            .unwrap();
+        assert_eq!(tee["state"], "initializing");
+        assert_eq!(status["ready"], false);
+    }
+
+    #[tokio::test]
+    async fn required_stage_failure_aborts_with_report() {
+        let orchestrator = StartupOrchestrator::new(plan(), StartupStatus::default());
+        orchestrator
+            .run_required(STATUS, async { Ok(()) })
+            .await
+            .unwrap();
+        orchestrator
+            .run_required(STORAGE, async { Ok(()) })
+            .await
+            .unwrap();
+
+        let err = orchestrator
+            .run_required(EVM, std::future::pending::<Result<(), PhalaAvsError>>())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("required stage evm failed"), "{err}");
+        assert!(err.contains("storage    Ready after"), "{err}");
+        assert!(err.contains("evm        Failed after"), "{err}");
+        assert!(err.contains("timed out"), "{err}");
+        assert!(err.contains("tee        Pending"), "{err}");
+
+        // An optional stage whose dependency is missing degrades instead of aborting.
+        let orchestrator = StartupOrchestrator::new(plan(), StartupStatus::default());
+        assert!(
+            orchestrator
+                .run(TEE, async { Ok(()) })
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(orchestrator.status().state(TEE), Some(InitState::Degraded));
+    }
+}
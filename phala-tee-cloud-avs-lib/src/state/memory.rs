@@ -0,0 +1,73 @@
+use super::StateStore;
+use crate::error::PhalaAvsError;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// A volatile [`StateStore`] kept entirely in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStateStore {
+    namespaces: RwLock<BTreeMap<String, BTreeMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl StateStore for MemoryStateStore {
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, PhalaAvsError> {
+        let namespaces = self.namespaces.read().unwrap_or_else(|e| e.into_inner());
+        Ok(namespaces
+            .get(namespace)
+            .and_then(|ns| ns.get(key))
+            .cloned())
+    }
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), PhalaAvsError> {
+        let mut namespaces = self.namespaces.write().unwrap_or_else(|e| e.into_inner());
+        namespaces
+            .entry(namespace.to_string())
+            .or_default()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<(), PhalaAvsError> {
+        let mut namespaces = self.namespaces.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(ns) = namespaces.get_mut(namespace) {
+            ns.remove(key);
+            if ns.is_empty() {
+                namespaces.remove(namespace);
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&self, namespace: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        let namespaces = self.namespaces.read().unwrap_or_else(|e| e.into_inner());
+        Ok(namespaces
+            .get(namespace)
+            .map(|ns| ns.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default())
+    }
+
+    fn scan_range(
+        &self,
+        namespace: &str,
+        from: &[u8],
+        to: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        if from >= to {
+            return Ok(Vec::new());
+        }
+        let namespaces = self.namespaces.read().unwrap_or_else(|e| e.into_inner());
+        Ok(namespaces
+            .get(namespace)
+            .map(|ns| {
+                ns.range(from.to_vec()..to.to_vec())
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>, PhalaAvsError> {
+        let namespaces = self.namespaces.read().unwrap_or_else(|e| e.into_inner());
+        Ok(namespaces.keys().cloned().collect())
+    }
+}
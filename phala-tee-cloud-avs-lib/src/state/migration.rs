@@ -0,0 +1,415 @@
+use super::{StateBackend, StateStore, StateStoreExt, namespace_checksum};
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::primitives::{B256, hex};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Namespace in the migration target holding per-namespace copy progress.
+pub const MIGRATION_NAMESPACE: &str = "__migration";
+
+/// File in the state directory naming the backend that became primary after a finalize.
+const BACKEND_MARKER_FILE: &str = "state-backend.json";
+
+/// Upper bound on differing keys listed per namespace in a report.
+const MAX_REPORTED_KEYS: usize = 100;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct NamespaceProgress {
+    /// Every entry present when the copy started has been written to the target.
+    copied: bool,
+}
+
+/// Result of comparing one namespace between the primary and the target.
+#[derive(Clone, Debug, Serialize)]
+pub struct NamespaceVerification {
+    pub namespace: String,
+    pub primary_checksum: B256,
+    pub target_checksum: B256,
+    /// Hex-encoded keys that are missing on either side or hold different values.
+    pub differing_keys: Vec<String>,
+    /// The namespace was not fully copied yet.
+    pub incomplete: bool,
+}
+
+impl NamespaceVerification {
+    pub fn matches(&self) -> bool {
+        !self.incomplete && self.primary_checksum == self.target_checksum
+    }
+}
+
+/// Per-namespace comparison of the primary and migration target.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VerificationReport {
+    pub namespaces: Vec<NamespaceVerification>,
+}
+
+impl VerificationReport {
+    /// True when every namespace was fully copied and matches byte for byte.
+    pub fn is_consistent(&self) -> bool {
+        self.namespaces.iter().all(NamespaceVerification::matches)
+    }
+
+    pub fn mismatches(&self) -> impl Iterator<Item = &NamespaceVerification> {
+        self.namespaces.iter().filter(|ns| !ns.matches())
+    }
+}
+
+/// Outcome of [`MigratingStateStore::finalize`].
+#[derive(Debug)]
+pub enum FinalizeOutcome {
+    /// The target is now the primary; takes effect on the next start.
+    Finalized(VerificationReport),
+    /// Verification failed; nothing was changed.
+    Refused(VerificationReport),
+}
+
+/// A [`StateStore`] in migration mode.
+///
+/// All mutations are written to both the primary and the target, reads are always served by the
+/// primary. Existing data is streamed over by [`copy_existing`](Self::copy_existing), whose
+/// progress is persisted in the target so an interrupted copy resumes where it left off. A
+/// namespace is only marked copied once all of its entries are written, so the target is never
+/// considered complete while partial.
+#[derive(Debug)]
+pub struct MigratingStateStore {
+    primary: Arc<dyn StateStore>,
+    target: Arc<dyn StateStore>,
+    target_backend: StateBackend,
+    /// Namespaces whose dual-write to the target failed and which must be copied again.
+    dirty: Mutex<HashSet<String>>,
+}
+
+impl MigratingStateStore {
+    pub fn new(
+        primary: Arc<dyn StateStore>,
+        target: Arc<dyn StateStore>,
+        target_backend: StateBackend,
+    ) -> Self {
+        Self {
+            primary,
+            target,
+            target_backend,
+            dirty: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn target_backend(&self) -> &StateBackend {
+        &self.target_backend
+    }
+
+    fn progress(&self, namespace: &str) -> Result<NamespaceProgress, PhalaAvsError> {
+        Ok(self
+            .target
+            .get_json(MIGRATION_NAMESPACE, namespace.as_bytes())?
+            .unwrap_or_default())
+    }
+
+    fn set_progress(&self, namespace: &str, copied: bool) -> Result<(), PhalaAvsError> {
+        self.target.put_json(
+            MIGRATION_NAMESPACE,
+            namespace.as_bytes(),
+            &NamespaceProgress { copied },
+        )
+    }
+
+    fn mark_dirty(&self, namespace: &str, error: PhalaAvsError) {
+        warn!("Dual-write to migration target failed for {namespace}: {error}");
+        self.dirty
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(namespace.to_string());
+        // Best effort; the in-memory flag covers the case where the target is unavailable.
+        let _ = self.set_progress(namespace, false);
+    }
+
+    fn is_dirty(&self, namespace: &str) -> bool {
+        self.dirty
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(namespace)
+    }
+
+    /// Streams every namespace not yet copied from the primary to the target, writing at most
+    /// `rate_limit` entries per second.
+    ///
+    /// Values are re-read from the primary right before each write so concurrent dual-writes
+    /// are not overwritten with stale data; [`verify`](Self::verify) remains the final guard.
+    pub async fn copy_existing(&self, rate_limit: u32) -> Result<usize, PhalaAvsError> {
+        let rate_limit = rate_limit.max(1) as usize;
+        let mut copied = 0usize;
+        for namespace in self.primary.namespaces()? {
+            if namespace == MIGRATION_NAMESPACE {
+                continue;
+            }
+            if self.progress(&namespace)?.copied && !self.is_dirty(&namespace) {
+                continue;
+            }
+            self.dirty
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&namespace);
+
+            let primary_keys: BTreeSet<Vec<u8>> = self
+                .primary
+                .scan(&namespace)?
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            // Drop leftovers from an earlier interrupted run that no longer exist on the primary.
+            for (key, _) in self.target.scan(&namespace)? {
+                if !primary_keys.contains(&key) {
+                    self.target.delete(&namespace, &key)?;
+                }
+            }
+            for key in primary_keys {
+                if let Some(value) = self.primary.get(&namespace, &key)? {
+                    self.target.put(&namespace, &key, &value)?;
+                }
+                copied += 1;
+                if copied % rate_limit == 0 {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+            if !self.is_dirty(&namespace) {
+                self.set_progress(&namespace, true)?;
+                info!("Migrated namespace {namespace} to {}", self.target_backend);
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Compares every namespace of the primary and target by checksum.
+    pub fn verify(&self) -> Result<VerificationReport, PhalaAvsError> {
+        let mut names: BTreeSet<String> = self.primary.namespaces()?.into_iter().collect();
+        names.extend(self.target.namespaces()?);
+        names.remove(MIGRATION_NAMESPACE);
+
+        let mut report = VerificationReport::default();
+        for namespace in names {
+            let primary = self.primary.scan(&namespace)?;
+            let target = self.target.scan(&namespace)?;
+            let incomplete = !primary.is_empty()
+                && (self.is_dirty(&namespace) || !self.progress(&namespace)?.copied);
+            let primary_checksum = namespace_checksum(&primary);
+            let target_checksum = namespace_checksum(&target);
+            let differing_keys = if primary_checksum == target_checksum {
+                Vec::new()
+            } else {
+                differing_keys(primary, target)
+            };
+            report.namespaces.push(NamespaceVerification {
+                namespace,
+                primary_checksum,
+                target_checksum,
+                differing_keys,
+                incomplete,
+            });
+        }
+        Ok(report)
+    }
+
+    /// Verifies the migration and, if consistent, atomically makes the target the primary.
+    pub fn finalize(&self, state_dir: &Path) -> Result<FinalizeOutcome, PhalaAvsError> {
+        let report = self.verify()?;
+        if !report.is_consistent() {
+            warn!(
+                "Refusing to finalize state migration: {} namespace(s) differ",
+                report.mismatches().count()
+            );
+            return Ok(FinalizeOutcome::Refused(report));
+        }
+        write_backend_marker(state_dir, &self.target_backend)?;
+        info!(
+            "State migration finalized, primary is now {}",
+            self.target_backend
+        );
+        Ok(FinalizeOutcome::Finalized(report))
+    }
+}
+
+impl StateStore for MigratingStateStore {
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, PhalaAvsError> {
+        self.primary.get(namespace, key)
+    }
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), PhalaAvsError> {
+        self.primary.put(namespace, key, value)?;
+        if let Err(e) = self.target.put(namespace, key, value) {
+            self.mark_dirty(namespace, e);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<(), PhalaAvsError> {
+        self.primary.delete(namespace, key)?;
+        if let Err(e) = self.target.delete(namespace, key) {
+            self.mark_dirty(namespace, e);
+        }
+        Ok(())
+    }
+
+    fn scan(&self, namespace: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        self.primary.scan(namespace)
+    }
+
+    fn scan_range(
+        &self,
+        namespace: &str,
+        from: &[u8],
+        to: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        self.primary.scan_range(namespace, from, to)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>, PhalaAvsError> {
+        self.primary.namespaces()
+    }
+
+    fn size(&self, namespace: &str) -> Result<u64, PhalaAvsError> {
+        self.primary.size(namespace)
+    }
+}
+
+/// Runs [`MigratingStateStore::copy_existing`] in the background.
+pub fn spawn_copier(store: Arc<MigratingStateStore>, rate_limit: u32) {
+    tokio::spawn(async move {
+        match store.copy_existing(rate_limit).await {
+            Ok(copied) => info!("State migration copier finished ({copied} entries)"),
+            Err(e) => warn!("State migration copier failed, will resume on restart: {e}"),
+        }
+    });
+}
+
+pub(super) fn differing_keys(
+    primary: Vec<(Vec<u8>, Vec<u8>)>,
+    target: Vec<(Vec<u8>, Vec<u8>)>,
+) -> Vec<String> {
+    let primary: BTreeMap<_, _> = primary.into_iter().collect();
+    let target: BTreeMap<_, _> = target.into_iter().collect();
+    let keys: BTreeSet<&Vec<u8>> = primary.keys().chain(target.keys()).collect();
+    keys.into_iter()
+        .filter(|key| primary.get(*key) != target.get(*key))
+        .take(MAX_REPORTED_KEYS)
+        .map(hex::encode)
+        .collect()
+}
+
+pub(crate) fn read_backend_marker(state_dir: &Path) -> Result<Option<StateBackend>, PhalaAvsError> {
+    let path = state_dir.join(BACKEND_MARKER_FILE);
+    match fs::read(&path) {
+        Ok(raw) => serde_json::from_slice(&raw)
+            .map(Some)
+            .map_err(|e| PhalaAvsError::StorageError(format!("Corrupt {}: {e}", path.display()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes the backend marker via a temporary file and rename so it is never half-written.
+pub(super) fn write_backend_marker(
+    state_dir: &Path,
+    backend: &StateBackend,
+) -> Result<(), PhalaAvsError> {
+    fs::create_dir_all(state_dir)?;
+    let tmp = state_dir.join(format!("{BACKEND_MARKER_FILE}.tmp"));
+    let raw = serde_json::to_vec_pretty(backend)
+        .map_err(|e| PhalaAvsError::StorageError(format!("Failed to encode marker: {e}")))?;
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&raw)?;
+    file.sync_all()?;
+    fs::rename(&tmp, state_dir.join(BACKEND_MARKER_FILE))?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "storage-sqlite"))]
+mod tests {
+    use super::*;
+    use crate::state::{MemoryStateStore, SqliteStateStore};
+    use blueprint_sdk::testing::tempfile::TempDir;
+
+    fn populated_primary() -> Arc<dyn StateStore> {
+        let primary = MemoryStateStore::default();
+        for i in 0u32..50 {
+            primary.put("cursors", &i.to_be_bytes(), b"block").unwrap();
+            primary
+                .put("tracker", &i.to_be_bytes(), &i.to_le_bytes())
+                .unwrap();
+        }
+        Arc::new(primary)
+    }
+
+    #[tokio::test]
+    async fn memory_to_sqlite_refuses_mismatch_then_finalizes() {
+        let dir = TempDir::new().unwrap();
+        let db = dir.path().join("state.db");
+        let target: Arc<dyn StateStore> = Arc::new(SqliteStateStore::open(&db).unwrap());
+        let store = MigratingStateStore::new(
+            populated_primary(),
+            Arc::clone(&target),
+            StateBackend::Sqlite(db.clone()),
+        );
+
+        // Nothing copied yet: finalize must refuse even though dual-writes already landed.
+        store.put("tracker", b"new", b"value").unwrap();
+        assert!(matches!(
+            store.finalize(dir.path()).unwrap(),
+            FinalizeOutcome::Refused(_)
+        ));
+
+        store.copy_existing(10_000).await.unwrap();
+        assert!(store.verify().unwrap().is_consistent());
+
+        // Corrupt one value directly on the target.
+        target
+            .put("tracker", &7u32.to_be_bytes(), b"corrupt")
+            .unwrap();
+        match store.finalize(dir.path()).unwrap() {
+            FinalizeOutcome::Refused(report) => {
+                let mismatch: Vec<_> = report.mismatches().collect();
+                assert_eq!(mismatch.len(), 1);
+                assert_eq!(mismatch[0].namespace, "tracker");
+                assert_eq!(mismatch[0].differing_keys, vec![hex::encode(
+                    7u32.to_be_bytes()
+                )]);
+            }
+            FinalizeOutcome::Finalized(_) => panic!("finalized inconsistent migration"),
+        }
+        assert!(read_backend_marker(dir.path()).unwrap().is_none());
+
+        // Rewriting through the store fixes the target, after which finalize succeeds.
+        store
+            .put("tracker", &7u32.to_be_bytes(), &7u32.to_le_bytes())
+            .unwrap();
+        assert!(matches!(
+            store.finalize(dir.path()).unwrap(),
+            FinalizeOutcome::Finalized(_)
+        ));
+        assert_eq!(
+            read_backend_marker(dir.path()).unwrap(),
+            Some(StateBackend::Sqlite(db))
+        );
+    }
+
+    #[tokio::test]
+    async fn interrupted_copy_resumes() {
+        let target: Arc<dyn StateStore> = Arc::new(SqliteStateStore::open_in_memory().unwrap());
+        let primary = populated_primary();
+
+        // Simulate a crash mid-namespace: a few entries present, progress never recorded.
+        target
+            .put("cursors", &0u32.to_be_bytes(), b"block")
+            .unwrap();
+        target.put("cursors", b"stale", b"gone").unwrap();
+
+        let store = MigratingStateStore::new(primary, target, StateBackend::Memory);
+        assert!(!store.verify().unwrap().is_consistent());
+        store.copy_existing(10_000).await.unwrap();
+        assert!(store.verify().unwrap().is_consistent());
+    }
+}
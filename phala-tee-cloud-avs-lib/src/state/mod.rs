@@ -0,0 +1,213 @@
+//! Pluggable persistent state for the operator.
+//!
+//! All components store their data in namespaced key/value form through [`StateStore`], so the
+//! backing storage (in-memory, SQLite) can be chosen by configuration and migrated online. The
+//! SQLite backend is only built with the `storage-sqlite` feature.
+
+pub mod memory;
+pub mod migration;
+pub mod rebuild;
+#[cfg(feature = "storage-sqlite")]
+pub mod sqlite;
+
+use crate::config::env_opt;
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::primitives::{B256, keccak256};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub use memory::MemoryStateStore;
+pub use migration::{FinalizeOutcome, MigratingStateStore, VerificationReport};
+#[cfg(feature = "storage-sqlite")]
+pub use sqlite::SqliteStateStore;
+
+/// A namespaced key/value store.
+///
+/// Implementations must be safe to share between jobs; writes to a single key are atomic.
+pub trait StateStore: Send + Sync + fmt::Debug {
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, PhalaAvsError>;
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), PhalaAvsError>;
+
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<(), PhalaAvsError>;
+
+    /// Returns every entry of `namespace`, ordered by key.
+    fn scan(&self, namespace: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError>;
+
+    /// Returns the entries of `namespace` with a key in `[from, to)`, ordered by key.
+    fn scan_range(
+        &self,
+        namespace: &str,
+        from: &[u8],
+        to: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        Ok(self
+            .scan(namespace)?
+            .into_iter()
+            .filter(|(key, _)| (from..to).contains(&key.as_slice()))
+            .collect())
+    }
+
+    /// Returns the names of all non-empty namespaces, ordered by name.
+    fn namespaces(&self) -> Result<Vec<String>, PhalaAvsError>;
+
+    /// Returns the bytes of keys and values stored in `namespace`.
+    fn size(&self, namespace: &str) -> Result<u64, PhalaAvsError> {
+        Ok(self
+            .scan(namespace)?
+            .iter()
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum())
+    }
+}
+
+/// JSON convenience helpers available on every [`StateStore`].
+pub trait StateStoreExt: StateStore {
+    fn get_json<T: DeserializeOwned>(
+        &self,
+        namespace: &str,
+        key: &[u8],
+    ) -> Result<Option<T>, PhalaAvsError> {
+        self.get(namespace, key)?
+            .map(|raw| {
+                serde_json::from_slice(&raw).map_err(|e| {
+                    PhalaAvsError::StorageError(format!("Corrupt record in {namespace}: {e}"))
+                })
+            })
+            .transpose()
+    }
+
+    fn put_json<T: Serialize>(
+        &self,
+        namespace: &str,
+        key: &[u8],
+        value: &T,
+    ) -> Result<(), PhalaAvsError> {
+        let raw = serde_json::to_vec(value)
+            .map_err(|e| PhalaAvsError::StorageError(format!("Failed to encode record: {e}")))?;
+        self.put(namespace, key, &raw)
+    }
+}
+
+impl<S: StateStore + ?Sized> StateStoreExt for S {}
+
+/// Checksum over the ordered contents of a namespace, used to compare backends.
+pub fn namespace_checksum(entries: &[(Vec<u8>, Vec<u8>)]) -> B256 {
+    let mut buf = Vec::new();
+    for (key, value) in entries {
+        buf.extend_from_slice(&(key.len() as u64).to_be_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        buf.extend_from_slice(value);
+    }
+    keccak256(buf)
+}
+
+/// Which storage backend to use.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "path", rename_all = "lowercase")]
+pub enum StateBackend {
+    Memory,
+    Sqlite(PathBuf),
+}
+
+impl FromStr for StateBackend {
+    type Err = String;
+
+    /// Parses `memory` or `sqlite:<path>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(Self::Memory),
+            Some(("sqlite", path)) if !path.is_empty() => Ok(Self::Sqlite(PathBuf::from(path))),
+            _ => Err(format!(
+                "unknown state backend {s:?}, expected `memory` or `sqlite:<path>`"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StateBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory => write!(f, "memory"),
+            Self::Sqlite(path) => write!(f, "sqlite:{}", path.display()),
+        }
+    }
+}
+
+impl StateBackend {
+    /// Opens the backend.
+    pub fn open(&self) -> Result<Arc<dyn StateStore>, PhalaAvsError> {
+        match self {
+            Self::Memory => Ok(Arc::new(MemoryStateStore::default())),
+            #[cfg(feature = "storage-sqlite")]
+            Self::Sqlite(path) => Ok(Arc::new(SqliteStateStore::open(path)?)),
+            #[cfg(not(feature = "storage-sqlite"))]
+            Self::Sqlite(_) => Err(PhalaAvsError::ConfigError(format!(
+                "state backend {self} needs the storage-sqlite feature"
+            ))),
+        }
+    }
+}
+
+/// Storage configuration.
+#[derive(Clone, Debug)]
+pub struct StateConfig {
+    /// Directory holding the backend marker written when a migration is finalized.
+    pub state_dir: PathBuf,
+    /// The configured primary backend. Superseded by a finalized migration marker.
+    pub primary: StateBackend,
+    /// When set, the store runs in dual-write migration mode towards this backend.
+    pub migration_target: Option<StateBackend>,
+    /// Maximum number of entries per second copied by the background migration copier.
+    pub migration_rate_limit: u32,
+}
+
+impl StateConfig {
+    /// Loads the configuration from `STATE_*` environment variables.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            state_dir: env_opt("STATE_DIR")?.unwrap_or_else(|| PathBuf::from("./data")),
+            primary: env_opt("STATE_BACKEND")?.unwrap_or(StateBackend::Memory),
+            migration_target: env_opt("STATE_MIGRATION_TARGET")?,
+            migration_rate_limit: env_opt("STATE_MIGRATION_RATE_LIMIT")?.unwrap_or(1000),
+        })
+    }
+
+    /// The primary backend, honoring a finalized migration marker if one exists.
+    pub fn effective_primary(&self) -> Result<StateBackend, PhalaAvsError> {
+        Ok(
+            migration::read_backend_marker(&self.state_dir)?
+                .unwrap_or_else(|| self.primary.clone()),
+        )
+    }
+
+    /// Opens the migration wrapper if a migration target is configured and has not already
+    /// become the primary.
+    pub fn open_migration(&self) -> Result<Option<Arc<MigratingStateStore>>, PhalaAvsError> {
+        let primary_backend = self.effective_primary()?;
+        match &self.migration_target {
+            Some(target) if *target != primary_backend => Ok(Some(Arc::new(
+                MigratingStateStore::new(primary_backend.open()?, target.open()?, target.clone()),
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Opens the configured store.
+    ///
+    /// In migration mode the returned store dual-writes to the target and a background copier
+    /// is spawned to stream existing namespaces over.
+    pub fn open(&self) -> Result<Arc<dyn StateStore>, PhalaAvsError> {
+        match self.open_migration()? {
+            Some(migrating) => {
+                migration::spawn_copier(Arc::clone(&migrating), self.migration_rate_limit);
+                Ok(migrating)
+            }
+            None => self.effective_primary()?.open(),
+        }
+    }
+}
@@ -0,0 +1,1089 @@
+//! Rebuild of the derived state from the chain and the raw evidence.
+//!
+//! When local state is suspected corrupt (a bad disk, a bug), `state rebuild` reconstructs what
+//! can be derived instead of trusting it. Every log of the oracle since
+//! `STATE_REBUILD_FROM_BLOCK` is read in ranges of `STATE_REBUILD_RANGE_BLOCKS`, at most
+//! `STATE_REBUILD_RATE_LIMIT` requests per second, and replayed into a fresh store through the
+//! decoders and the [`ChallengeTracker`] state machine the operator uses live. Progress is
+//! reported like the startup [catch-up](crate::catchup), and the last replayed block is persisted
+//! in the fresh store, so an interrupted rebuild resumes where it stopped.
+//!
+//! - The tracker's records ([`REPLAYED_NAMESPACES`]) are replayed. The standing and scores
+//!   reported to delegators are computed from them, so they follow. The raw response evidence
+//!   supplies what the chain does not record: how late each challenge was detected and why it
+//!   was released.
+//! - The evidence summaries ([`RESET_NAMESPACES`]) are left out, to be rebuilt from the raw
+//!   evidence on first use.
+//! - Every other namespace, the raw evidence first of all, is copied over byte for byte and
+//!   verified by checksum. The existing store is only ever read.
+//!
+//! The replayed challenges are compared with the local records by outcome, since the local
+//! records also hold when this operator saw each move happen. A local record with the same
+//! outcome is carried over, keeping its history; every other one is named in the
+//! [`RebuildReport`] and replaced by its replay. [`StateRebuild::swap`] then makes the fresh
+//! store the primary, through the same marker a finalized [migration](super::migration) writes.
+
+use super::migration::{MIGRATION_NAMESPACE, differing_keys, write_backend_marker};
+use super::{NamespaceVerification, StateBackend, StateStore, StateStoreExt, namespace_checksum};
+use crate::catchup::{CatchUp, CatchUpConfig, CatchUpMode, CatchUpStatus};
+use crate::challenge::tracker::{ARCHIVE_NAMESPACE, TRACKER_NAMESPACE};
+use crate::challenge::{
+    ChallengeState, ChallengeTracker, ConfirmationPolicy, ObservedChallenge, ProcessedEvents,
+    TrackedChallenge, apply_updates, decode_challenge, decode_update,
+};
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use crate::evidence::summary::{DIRTY_NAMESPACE, SUMMARY_NAMESPACE};
+use crate::evidence::{RESPONSE_EVIDENCE, ResponseEvidence, now_unix_ms};
+use crate::evm::BoxFuture;
+use crate::receipts::{ReceiptEvent, decode_receipt, rejection_message};
+use crate::{PRIVATE_KEY, SERVICE_MANAGER_ADDRESS, SLA_ORACLE_ADDRESS};
+use blueprint_sdk::alloy::primitives::{Address, U256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::{Filter, Log};
+use blueprint_sdk::alloy::signers::local::PrivateKeySigner;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Namespace in the fresh store holding the rebuild's parameters and progress.
+pub const REBUILD_NAMESPACE: &str = "__rebuild";
+/// Namespaces replayed from the chain.
+pub const REPLAYED_NAMESPACES: &[&str] = &[TRACKER_NAMESPACE, ARCHIVE_NAMESPACE];
+/// Namespaces left out of the fresh store, rebuilt from the raw evidence on first use.
+pub const RESET_NAMESPACES: &[&str] = &[SUMMARY_NAMESPACE, DIRTY_NAMESPACE];
+
+const PARAMS_KEY: &[u8] = b"params";
+const CURSOR_KEY: &[u8] = b"scanned_through";
+
+/// The moves the live pipeline makes from provisional up to inclusion, in order.
+const INCLUSION_PATH: [ChallengeState; 4] = [
+    ChallengeState::Queued,
+    ChallengeState::Building,
+    ChallengeState::Submitting,
+    ChallengeState::AwaitingInclusion,
+];
+
+#[derive(Clone, Debug)]
+pub struct RebuildConfig {
+    /// First block replayed; the oracle's deployment block is early enough.
+    pub from_block: u64,
+    /// Blocks per `eth_getLogs` request.
+    pub range_blocks: u64,
+    /// Most `eth_getLogs` requests per second.
+    pub rate_limit: u32,
+    /// Ranges between progress log lines.
+    pub log_every: u64,
+}
+
+impl RebuildConfig {
+    /// Reads `STATE_REBUILD_FROM_BLOCK`, `STATE_REBUILD_RANGE_BLOCKS`,
+    /// `STATE_REBUILD_RATE_LIMIT` and `STATE_REBUILD_LOG_EVERY`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let config = Self {
+            from_block: env_or("STATE_REBUILD_FROM_BLOCK", 0)?,
+            range_blocks: env_or("STATE_REBUILD_RANGE_BLOCKS", 2_000)?,
+            rate_limit: env_or("STATE_REBUILD_RATE_LIMIT", 5)?,
+            log_every: env_or("STATE_REBUILD_LOG_EVERY", 50u64)?.max(1),
+        };
+        if config.range_blocks == 0 {
+            return Err(PhalaAvsError::ConfigError(
+                "STATE_REBUILD_RANGE_BLOCKS must be at least 1".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// Whose history is replayed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RebuildScope {
+    pub operator: Address,
+    pub oracle: Address,
+    pub service_manager: Address,
+}
+
+impl RebuildScope {
+    /// The operator of `PRIVATE_KEY`, with `SLA_ORACLE_ADDRESS` and `SERVICE_MANAGER_ADDRESS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let operator = PRIVATE_KEY
+            .parse::<PrivateKeySigner>()
+            .map_err(|e| PhalaAvsError::ConfigError(format!("Invalid PRIVATE_KEY: {e}")))?
+            .address();
+        Ok(Self {
+            operator,
+            oracle: *SLA_ORACLE_ADDRESS,
+            service_manager: *SERVICE_MANAGER_ADDRESS,
+        })
+    }
+}
+
+/// What a fresh store was started for; a rebuild only resumes into a store started for the
+/// same.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct RebuildParams {
+    scope: RebuildScope,
+    from_block: u64,
+}
+
+/// The fresh store a rebuild of `primary` writes to in `state_dir`: whichever of two files is
+/// not the primary, so the state a rebuild swapped in can itself be rebuilt.
+pub fn rebuild_backend(state_dir: &Path, primary: &StateBackend) -> StateBackend {
+    ["rebuild-a.sqlite", "rebuild-b.sqlite"]
+        .map(|file| StateBackend::Sqlite(state_dir.join(file)))
+        .into_iter()
+        .find(|backend| backend != primary)
+        .expect("the primary is one backend at most")
+}
+
+/// The chain history a rebuild replays.
+pub trait HistorySource: Send + Sync {
+    fn head(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>>;
+
+    /// Every log `oracle` emitted in `[from_block, to_block]`, in chain order.
+    fn oracle_logs(
+        &self,
+        oracle: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>>;
+}
+
+/// [`HistorySource`] backed by an alloy [`Provider`].
+#[derive(Clone, Debug)]
+pub struct ProviderHistorySource<P> {
+    provider: P,
+}
+
+impl<P> ProviderHistorySource<P> {
+    pub fn new(provider: P) -> Self {
+        Self { provider }
+    }
+}
+
+impl<P: Provider + Send + Sync + 'static> HistorySource for ProviderHistorySource<P> {
+    fn head(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async move {
+            self.provider
+                .get_block_number()
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_blockNumber failed: {e}")))
+        })
+    }
+
+    fn oracle_logs(
+        &self,
+        oracle: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>> {
+        Box::pin(async move {
+            let filter = Filter::new()
+                .from_block(from_block)
+                .to_block(to_block)
+                .address(oracle);
+            self.provider
+                .get_logs(&filter)
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(format!("eth_getLogs failed: {e}")))
+        })
+    }
+}
+
+/// What is compared of a challenge: what came of it, not when this operator saw it happen.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ChallengeOutcome {
+    pub state: ChallengeState,
+    pub issued_block: u64,
+    pub deadline_block: u64,
+    /// Amendments by the oracle.
+    pub revision: u32,
+}
+
+impl From<&TrackedChallenge> for ChallengeOutcome {
+    fn from(tracked: &TrackedChallenge) -> Self {
+        Self {
+            state: tracked.state,
+            issued_block: tracked.challenge.issued_block,
+            deadline_block: tracked.challenge.deadline_block,
+            revision: tracked.revision(),
+        }
+    }
+}
+
+fn outcome(raw: &[u8]) -> Option<ChallengeOutcome> {
+    serde_json::from_slice::<TrackedChallenge>(raw)
+        .ok()
+        .map(|tracked| ChallengeOutcome::from(&tracked))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DifferenceKind {
+    /// Issued to the operator on chain, but not in the local store.
+    Missing,
+    /// In the local store, but not issued to the operator in the replayed blocks.
+    Extra,
+    /// In both, with a different outcome.
+    Changed,
+    /// The local record does not decode.
+    Corrupt,
+}
+
+/// A local challenge record the rebuild replaces or drops.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChallengeDifference {
+    /// `None` for a record whose key is not a challenge id.
+    pub challenge_id: Option<U256>,
+    /// The namespace holding the local record, if there is one.
+    pub namespace: Option<String>,
+    pub kind: DifferenceKind,
+    pub local: Option<ChallengeOutcome>,
+    pub rebuilt: Option<ChallengeOutcome>,
+}
+
+/// The result of a rebuild, printed before the operator confirms the swap.
+#[derive(Clone, Debug, Serialize)]
+pub struct RebuildReport {
+    pub target: String,
+    pub from_block: u64,
+    pub scanned_through: u64,
+    /// Challenges of the operator in the rebuilt store.
+    pub challenges: usize,
+    pub differences: Vec<ChallengeDifference>,
+    /// Namespaces copied over unchanged.
+    pub copied: Vec<NamespaceVerification>,
+    /// Namespaces left out, to be rebuilt from the raw evidence on first use.
+    pub reset: Vec<String>,
+}
+
+impl RebuildReport {
+    /// Whether any local challenge record is replaced or dropped.
+    pub fn differs(&self) -> bool {
+        !self.differences.is_empty()
+    }
+
+    /// Whether every copied namespace matches the existing one byte for byte.
+    pub fn copied_intact(&self) -> bool {
+        self.copied.iter().all(NamespaceVerification::matches)
+    }
+}
+
+/// Whether `namespace` is copied over unchanged.
+fn is_carried(namespace: &str) -> bool {
+    !REPLAYED_NAMESPACES.contains(&namespace)
+        && !RESET_NAMESPACES.contains(&namespace)
+        && namespace != REBUILD_NAMESPACE
+        && namespace != MIGRATION_NAMESPACE
+}
+
+/// Rebuilds the derived state of `existing` into a fresh store.
+pub struct StateRebuild {
+    config: RebuildConfig,
+    scope: RebuildScope,
+    policy: ConfirmationPolicy,
+    existing: Arc<dyn StateStore>,
+    target: Arc<dyn StateStore>,
+    target_backend: StateBackend,
+    source: Arc<dyn HistorySource>,
+    progress: CatchUp,
+}
+
+impl StateRebuild {
+    pub fn new(
+        config: RebuildConfig,
+        scope: RebuildScope,
+        existing: Arc<dyn StateStore>,
+        target: Arc<dyn StateStore>,
+        target_backend: StateBackend,
+        source: Arc<dyn HistorySource>,
+    ) -> Self {
+        let progress = CatchUp::new(
+            CatchUpConfig {
+                mode: CatchUpMode::Full,
+                skip_margin_blocks: 0,
+                log_every: config.log_every,
+            },
+            Arc::clone(&target),
+        );
+        Self {
+            config,
+            scope,
+            policy: ConfirmationPolicy::default(),
+            existing,
+            target,
+            target_backend,
+            source,
+            progress,
+        }
+    }
+
+    /// Tracks the replayed challenges with the operator's confirmation policy.
+    pub fn with_policy(mut self, policy: ConfirmationPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The fresh store.
+    pub fn target(&self) -> &Arc<dyn StateStore> {
+        &self.target
+    }
+
+    /// The replay's progress, once it started.
+    pub fn status(&self) -> Option<CatchUpStatus> {
+        self.progress.status(now_unix_ms())
+    }
+
+    /// Replays the chain to its head and builds the fresh store.
+    pub async fn run(&self) -> Result<RebuildReport, PhalaAvsError> {
+        self.replay().await?;
+        self.finish()
+    }
+
+    fn check_params(&self) -> Result<(), PhalaAvsError> {
+        let params = RebuildParams {
+            scope: self.scope.clone(),
+            from_block: self.config.from_block,
+        };
+        match self
+            .target
+            .get_json::<RebuildParams>(REBUILD_NAMESPACE, PARAMS_KEY)?
+        {
+            Some(started) if started == params => Ok(()),
+            Some(_) => Err(PhalaAvsError::ConfigError(format!(
+                "{} holds a rebuild started for another operator, oracle or start block; remove \
+                 it to start over",
+                self.target_backend
+            ))),
+            None if self.target.namespaces()?.is_empty() => {
+                self.target.put_json(REBUILD_NAMESPACE, PARAMS_KEY, &params)
+            }
+            None => Err(PhalaAvsError::StorageError(format!(
+                "{} already holds state; move it away to rebuild into it",
+                self.target_backend
+            ))),
+        }
+    }
+
+    /// Replays the oracle's logs from where an earlier run stopped up to the head, persisting
+    /// progress after every range. Returns the last replayed block.
+    pub async fn replay(&self) -> Result<u64, PhalaAvsError> {
+        self.check_params()?;
+        let tracker = ChallengeTracker::new(self.policy.clone(), Arc::clone(&self.target))?;
+        let evidence = self.response_evidence()?;
+        let head = self.source.head().await?;
+        let resumed: Option<u64> = self.target.get_json(REBUILD_NAMESPACE, CURSOR_KEY)?;
+        let from = resumed.map_or(self.config.from_block, |block| block + 1);
+        if from > head {
+            return Ok(from.saturating_sub(1));
+        }
+        if let Some(block) = resumed {
+            info!("Resuming the state rebuild after block {block}");
+        }
+        info!(
+            "Replaying {} blocks of oracle history, from {from} to {head}",
+            head + 1 - from
+        );
+        self.progress.follow(from, head, now_unix_ms());
+
+        let pause = Duration::from_millis(1_000 / u64::from(self.config.rate_limit.max(1)));
+        let mut start = from;
+        loop {
+            let end = start.saturating_add(self.config.range_blocks - 1).min(head);
+            let logs = self
+                .source
+                .oracle_logs(self.scope.oracle, start, end)
+                .await?;
+            let found = self.replay_logs(&tracker, &logs, &evidence)?;
+            self.close_windows(&tracker, end)?;
+            self.target.put_json(REBUILD_NAMESPACE, CURSOR_KEY, &end)?;
+            self.progress.observe(end, found, now_unix_ms());
+            if end >= head {
+                return Ok(end);
+            }
+            start = end + 1;
+            tokio::time::sleep(pause).await;
+        }
+    }
+
+    /// The latest response evidence of each challenge.
+    fn response_evidence(&self) -> Result<BTreeMap<U256, ResponseEvidence>, PhalaAvsError> {
+        let mut evidence = BTreeMap::new();
+        for (_, raw) in self.existing.scan(RESPONSE_EVIDENCE)? {
+            let record = match serde_json::from_slice::<ResponseEvidence>(&raw) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Skipping undecodable response evidence: {e}");
+                    continue;
+                }
+            };
+            match record.challenge_id.parse::<U256>() {
+                Ok(challenge_id) => {
+                    evidence.insert(challenge_id, record);
+                }
+                Err(e) => warn!("Skipping response evidence of {}: {e}", record.challenge_id),
+            }
+        }
+        Ok(evidence)
+    }
+
+    /// Feeds one range's logs, in chain order, through the decoders used live. Returns how many
+    /// challenges of the operator were tracked.
+    fn replay_logs(
+        &self,
+        tracker: &ChallengeTracker,
+        logs: &[Log],
+        evidence: &BTreeMap<U256, ResponseEvidence>,
+    ) -> Result<usize, PhalaAvsError> {
+        let mut found = 0;
+        for log in logs {
+            let block = log.block_number.unwrap_or_default();
+            if let Some(challenge) = decode_challenge(log) {
+                if challenge.operator == self.scope.operator
+                    && challenge.oracle == self.scope.oracle
+                {
+                    // The evidence recorded how late the challenge was first decoded.
+                    let delay = evidence
+                        .get(&challenge.challenge_id)
+                        .and_then(|record| record.detection_delay_blocks)
+                        .unwrap_or_default();
+                    let head = challenge.issued_block + delay;
+                    found += usize::from(tracker.observe(challenge, head)?);
+                }
+                continue;
+            }
+            if decode_update(log).is_some() {
+                let no_margin = |_: &ObservedChallenge| 0;
+                let mut processed = ProcessedEvents::default();
+                apply_updates(
+                    tracker,
+                    block,
+                    std::slice::from_ref(log),
+                    &no_margin,
+                    &mut processed,
+                );
+                continue;
+            }
+            let verdicts = decode_receipt(
+                std::slice::from_ref(log),
+                self.scope.oracle,
+                self.scope.service_manager,
+                self.scope.operator,
+            );
+            match verdicts.first() {
+                Some(ReceiptEvent::Accepted { challenge_id }) => self.settle(
+                    tracker,
+                    *challenge_id,
+                    ChallengeState::Responded,
+                    format!("response included in block {block}"),
+                    evidence,
+                )?,
+                Some(ReceiptEvent::Rejected {
+                    challenge_id,
+                    reason_code,
+                }) => self.settle(
+                    tracker,
+                    *challenge_id,
+                    ChallengeState::RejectedByOracle,
+                    format!(
+                        "rejected by the oracle in block {block}: {}",
+                        rejection_message(*reason_code)
+                    ),
+                    evidence,
+                )?,
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+
+    /// Walks a challenge through the moves the live pipeline makes up to inclusion, then to the
+    /// oracle's verdict `to`. Challenges already settled are left alone.
+    fn settle(
+        &self,
+        tracker: &ChallengeTracker,
+        challenge_id: U256,
+        to: ChallengeState,
+        cause: String,
+        evidence: &BTreeMap<U256, ResponseEvidence>,
+    ) -> Result<(), PhalaAvsError> {
+        let Some(tracked) = tracker.get(&challenge_id) else {
+            warn!(
+                "Verdict on challenge {challenge_id}, which was not issued in the replayed blocks"
+            );
+            return Ok(());
+        };
+        if tracked.state.is_settled() {
+            return Ok(());
+        }
+        let next = INCLUSION_PATH
+            .iter()
+            .position(|state| *state == tracked.state)
+            .map_or(0, |i| i + 1);
+        for state in &INCLUSION_PATH[next..] {
+            let step = match (state, evidence.get(&challenge_id)) {
+                (ChallengeState::Queued, Some(record)) => {
+                    format!(
+                        "released ({}), per the response evidence",
+                        record.release_reason
+                    )
+                }
+                _ => "replayed from the chain".to_string(),
+            };
+            tracker.transition(challenge_id, *state, step)?;
+        }
+        tracker.transition(challenge_id, to, cause)?;
+        Ok(())
+    }
+
+    /// Marks missed the challenges whose response window closed by `block` without a verdict.
+    fn close_windows(&self, tracker: &ChallengeTracker, block: u64) -> Result<(), PhalaAvsError> {
+        for tracked in tracker.snapshot() {
+            if tracked.state.is_settled() || tracked.challenge.deadline_block >= block {
+                continue;
+            }
+            let cause = format!(
+                "response window closed at block {}",
+                tracked.challenge.deadline_block
+            );
+            tracker.transition(
+                tracked.challenge.challenge_id,
+                ChallengeState::Missed,
+                cause,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Copies the namespaces carried over, compares the replayed challenges with the local
+    /// records and reports. May be run again, e.g. after the existing store changed.
+    pub fn finish(&self) -> Result<RebuildReport, PhalaAvsError> {
+        let scanned_through: u64 = self
+            .target
+            .get_json(REBUILD_NAMESPACE, CURSOR_KEY)?
+            .ok_or_else(|| {
+                PhalaAvsError::ValidationError("nothing was replayed yet".to_string())
+            })?;
+        let existing = self.existing.namespaces()?;
+        let copied = existing
+            .iter()
+            .filter(|namespace| is_carried(namespace))
+            .map(|namespace| self.copy(namespace))
+            .collect::<Result<Vec<_>, _>>()?;
+        let differences = self.reconcile()?;
+        let challenges = REPLAYED_NAMESPACES
+            .iter()
+            .map(|namespace| self.target.scan(namespace).map(|entries| entries.len()))
+            .sum::<Result<usize, _>>()?;
+        Ok(RebuildReport {
+            target: self.target_backend.to_string(),
+            from_block: self.config.from_block,
+            scanned_through,
+            challenges,
+            differences,
+            copied,
+            reset: existing
+                .into_iter()
+                .filter(|namespace| RESET_NAMESPACES.contains(&namespace.as_str()))
+                .collect(),
+        })
+    }
+
+    /// Copies `namespace` over unchanged, dropping entries left by an earlier run.
+    fn copy(&self, namespace: &str) -> Result<NamespaceVerification, PhalaAvsError> {
+        let entries = self.existing.scan(namespace)?;
+        let keys: BTreeSet<&Vec<u8>> = entries.iter().map(|(key, _)| key).collect();
+        for (key, _) in self.target.scan(namespace)? {
+            if !keys.contains(&key) {
+                self.target.delete(namespace, &key)?;
+            }
+        }
+        for (key, value) in &entries {
+            self.target.put(namespace, key, value)?;
+        }
+        let copied = self.target.scan(namespace)?;
+        let primary_checksum = namespace_checksum(&entries);
+        let target_checksum = namespace_checksum(&copied);
+        Ok(NamespaceVerification {
+            namespace: namespace.to_string(),
+            primary_checksum,
+            target_checksum,
+            differing_keys: if primary_checksum == target_checksum {
+                Vec::new()
+            } else {
+                differing_keys(entries, copied)
+            },
+            incomplete: false,
+        })
+    }
+
+    /// Compares the replayed challenges with the local records, carrying over each local record
+    /// with the same outcome as its replay.
+    fn reconcile(&self) -> Result<Vec<ChallengeDifference>, PhalaAvsError> {
+        let records = |store: &dyn StateStore| {
+            let mut records: BTreeMap<Vec<u8>, (&'static str, Vec<u8>)> = BTreeMap::new();
+            for namespace in REPLAYED_NAMESPACES {
+                for (key, value) in store.scan(namespace)? {
+                    records.entry(key).or_insert((*namespace, value));
+                }
+            }
+            Ok::<_, PhalaAvsError>(records)
+        };
+        let local = records(self.existing.as_ref())?;
+        let rebuilt = records(self.target.as_ref())?;
+
+        let mut differences = Vec::new();
+        let keys: BTreeSet<&Vec<u8>> = local.keys().chain(rebuilt.keys()).collect();
+        for key in keys {
+            let local_record = local.get(key);
+            let local_outcome = local_record.map(|(_, raw)| outcome(raw));
+            let rebuilt_outcome = rebuilt.get(key).and_then(|(_, raw)| outcome(raw));
+            let kind = match (&local_outcome, &rebuilt_outcome) {
+                (Some(Some(local)), Some(rebuilt)) if local == rebuilt => {
+                    let (namespace, raw) = local_record.expect("compared above");
+                    self.carry_over(namespace, key, raw)?;
+                    continue;
+                }
+                (Some(Some(_)), Some(_)) => DifferenceKind::Changed,
+                (Some(None), _) => DifferenceKind::Corrupt,
+                (Some(Some(_)), None) => DifferenceKind::Extra,
+                (None, _) => DifferenceKind::Missing,
+            };
+            differences.push(ChallengeDifference {
+                challenge_id: U256::try_from_be_slice(key),
+                namespace: local_record.map(|(namespace, _)| namespace.to_string()),
+                kind,
+                local: local_outcome.flatten(),
+                rebuilt: rebuilt_outcome,
+            });
+        }
+        Ok(differences)
+    }
+
+    /// Puts a local record in the fresh store in place of its replay, in the local namespace.
+    fn carry_over(&self, namespace: &str, key: &[u8], raw: &[u8]) -> Result<(), PhalaAvsError> {
+        for other in REPLAYED_NAMESPACES
+            .iter()
+            .filter(|other| **other != namespace)
+        {
+            self.target.delete(other, key)?;
+        }
+        self.target.put(namespace, key, raw)
+    }
+
+    /// Makes the fresh store the primary on the next start, once the namespaces carried over
+    /// still match the existing store. Run with the operator stopped.
+    pub fn swap(&self, state_dir: &Path) -> Result<(), PhalaAvsError> {
+        if self.target.get(REBUILD_NAMESPACE, CURSOR_KEY)?.is_none() {
+            return Err(PhalaAvsError::ValidationError(
+                "nothing was rebuilt yet".to_string(),
+            ));
+        }
+        for namespace in self.existing.namespaces()? {
+            if !is_carried(&namespace) {
+                continue;
+            }
+            let existing = namespace_checksum(&self.existing.scan(&namespace)?);
+            if existing != namespace_checksum(&self.target.scan(&namespace)?) {
+                return Err(PhalaAvsError::ValidationError(format!(
+                    "{namespace} changed since it was copied; run the rebuild again with the \
+                     operator stopped"
+                )));
+            }
+        }
+        // A store that was swapped in is not a rebuild to resume anymore.
+        self.target.delete(REBUILD_NAMESPACE, PARAMS_KEY)?;
+        self.target.delete(REBUILD_NAMESPACE, CURSOR_KEY)?;
+        write_backend_marker(state_dir, &self.target_backend)?;
+        info!(
+            "Swapped in the rebuilt state, primary is now {}",
+            self.target_backend
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::{AnnotationQuery, AnnotationTarget, Annotations};
+    use crate::evidence::EvidenceLog;
+    use crate::fixtures::{
+        ChallengeEventFixture, ChallengeUpdateFixture, OPERATOR, ORACLE, RejectionEventFixture,
+        ResponseEventFixture,
+    };
+    use crate::state::MemoryStateStore;
+    use crate::state::migration::read_backend_marker;
+    use blueprint_sdk::testing::tempfile::TempDir;
+    use std::sync::Mutex;
+
+    const HEAD: u64 = 200;
+    const SERVICE_MANAGER: Address = Address::repeat_byte(9);
+
+    /// The oracle's logs up to [`HEAD`], optionally failing its `fail_at`th range request.
+    #[derive(Default)]
+    struct SimulatedHistory {
+        logs: Vec<Log>,
+        fail_at: Option<usize>,
+        requests: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl HistorySource for SimulatedHistory {
+        fn head(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(HEAD) })
+        }
+
+        fn oracle_logs(
+            &self,
+            oracle: Address,
+            from_block: u64,
+            to_block: u64,
+        ) -> BoxFuture<'_, Result<Vec<Log>, PhalaAvsError>> {
+            Box::pin(async move {
+                let mut requests = self.requests.lock().unwrap();
+                requests.push((from_block, to_block));
+                if self.fail_at == Some(requests.len()) {
+                    return Err(PhalaAvsError::EvmError("rate limited".to_string()));
+                }
+                Ok(self
+                    .logs
+                    .iter()
+                    .filter(|log| log.address() == oracle)
+                    .filter(|log| {
+                        (from_block..=to_block).contains(&log.block_number.unwrap_or_default())
+                    })
+                    .cloned()
+                    .collect())
+            })
+        }
+    }
+
+    /// Challenge 1 answered, 2 missed, 3 rejected, 4 cancelled, 5 issued to someone else and 6
+    /// still open at the head.
+    fn history() -> Vec<Log> {
+        let issued = |id: u64, block: u64| ChallengeEventFixture::new().id(id).block(block);
+        vec![
+            issued(1, 10).build_log(),
+            issued(2, 12).build_log(),
+            ResponseEventFixture::new().id(1).block(20).build_log(),
+            issued(3, 30).build_log(),
+            RejectionEventFixture::new(3).block(40).build_log(),
+            issued(4, 50).build_log(),
+            ChallengeUpdateFixture::cancelled(4).block(55).build_log(),
+            issued(5, 60).operator(Address::repeat_byte(7)).build_log(),
+            issued(6, 190).build_log(),
+        ]
+    }
+
+    fn config(range_blocks: u64) -> RebuildConfig {
+        RebuildConfig {
+            from_block: 0,
+            range_blocks,
+            rate_limit: 1_000,
+            log_every: 10,
+        }
+    }
+
+    fn rebuild(
+        existing: Arc<dyn StateStore>,
+        target: Arc<dyn StateStore>,
+        source: Arc<SimulatedHistory>,
+        range_blocks: u64,
+    ) -> StateRebuild {
+        let scope = RebuildScope {
+            operator: OPERATOR,
+            oracle: ORACLE,
+            service_manager: SERVICE_MANAGER,
+        };
+        StateRebuild::new(
+            config(range_blocks),
+            scope,
+            existing,
+            target,
+            StateBackend::Memory,
+            source,
+        )
+    }
+
+    fn outcomes(store: &dyn StateStore) -> BTreeMap<Vec<u8>, ChallengeOutcome> {
+        REPLAYED_NAMESPACES
+            .iter()
+            .flat_map(|namespace| store.scan(namespace).unwrap())
+            .map(|(key, raw)| (key, outcome(&raw).unwrap()))
+            .collect()
+    }
+
+    fn key(id: u64) -> Vec<u8> {
+        U256::from(id).to_be_bytes::<32>().to_vec()
+    }
+
+    /// A known-good store: the replay of an empty one, with the response evidence of challenge
+    /// 1 and an evidence summary.
+    async fn control() -> Arc<dyn StateStore> {
+        let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        EvidenceLog::new(Arc::clone(&store))
+            .record(RESPONSE_EVIDENCE, 5_000, b"1", &ResponseEvidence {
+                unix_ms: 5_000,
+                challenge_id: "1".to_string(),
+                issued_block: 10,
+                deadline_block: 60,
+                release_reason: "confirmed".to_string(),
+                artifacts_url: None,
+                detection_delay_blocks: Some(3),
+                maintenance_window_id: None,
+                sla_proof_hash: None,
+            })
+            .unwrap();
+        store.put(SUMMARY_NAMESPACE, b"params", b"{}").unwrap();
+        let built = rebuild(
+            Arc::clone(&store),
+            Arc::new(MemoryStateStore::default()),
+            Arc::new(SimulatedHistory {
+                logs: history(),
+                ..Default::default()
+            }),
+            64,
+        );
+        let report = built.run().await.unwrap();
+        // Everything is missing from a store without challenges.
+        assert!(
+            report
+                .differences
+                .iter()
+                .all(|d| d.kind == DifferenceKind::Missing)
+        );
+        for namespace in REPLAYED_NAMESPACES {
+            for (key, value) in built.target().scan(namespace).unwrap() {
+                store.put(namespace, &key, &value).unwrap();
+            }
+        }
+        store
+    }
+
+    #[tokio::test]
+    async fn replay_follows_the_live_state_machine() {
+        let store = control().await;
+        let states: Vec<_> = outcomes(store.as_ref())
+            .into_iter()
+            .map(|(key, outcome)| (U256::from_be_slice(&key), outcome.state))
+            .collect();
+        assert_eq!(states, [
+            (U256::from(1), ChallengeState::Responded),
+            (U256::from(2), ChallengeState::Missed),
+            (U256::from(3), ChallengeState::RejectedByOracle),
+            (U256::from(4), ChallengeState::Cancelled),
+            (U256::from(6), ChallengeState::Provisional),
+        ]);
+        let tracker = ChallengeTracker::new(ConfirmationPolicy::default(), store).unwrap();
+        let answered = tracker.get(&U256::from(1)).unwrap();
+        // The evidence supplied the detection delay and the release reason.
+        assert_eq!(answered.detection_delay_blocks, Some(3));
+        let moves: Vec<_> = answered.history.iter().map(|t| t.to).collect();
+        assert_eq!(moves, [
+            ChallengeState::Seen,
+            ChallengeState::Provisional,
+            ChallengeState::Queued,
+            ChallengeState::Building,
+            ChallengeState::Submitting,
+            ChallengeState::AwaitingInclusion,
+            ChallengeState::Responded,
+        ]);
+        assert!(answered.history[2].cause.contains("confirmed"));
+    }
+
+    #[tokio::test]
+    async fn corrupted_records_are_named_and_replaced_by_the_replay() {
+        let control = control().await;
+        let local: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        for namespace in control.namespaces().unwrap() {
+            for (key, value) in control.scan(&namespace).unwrap() {
+                local.put(&namespace, &key, &value).unwrap();
+            }
+        }
+        // Challenge 4 was spilled to the archive; its record is intact.
+        let cancelled = local.get(TRACKER_NAMESPACE, &key(4)).unwrap().unwrap();
+        local.delete(TRACKER_NAMESPACE, &key(4)).unwrap();
+        local.put(ARCHIVE_NAMESPACE, &key(4), &cancelled).unwrap();
+        // Challenge 1 rewritten as missed, 2 lost, 3 garbled and 99 made up.
+        let mut answered: TrackedChallenge =
+            local.get_json(TRACKER_NAMESPACE, &key(1)).unwrap().unwrap();
+        answered.state = ChallengeState::Missed;
+        local
+            .put_json(TRACKER_NAMESPACE, &key(1), &answered)
+            .unwrap();
+        local.delete(TRACKER_NAMESPACE, &key(2)).unwrap();
+        local.put(TRACKER_NAMESPACE, &key(3), b"{\"chall").unwrap();
+        let mut made_up = answered.clone();
+        made_up.challenge.challenge_id = U256::from(99);
+        local
+            .put_json(ARCHIVE_NAMESPACE, &key(99), &made_up)
+            .unwrap();
+        let evidence_before = local.scan(RESPONSE_EVIDENCE).unwrap();
+
+        let rebuilt = rebuild(
+            Arc::clone(&local),
+            Arc::new(MemoryStateStore::default()),
+            Arc::new(SimulatedHistory {
+                logs: history(),
+                ..Default::default()
+            }),
+            64,
+        );
+        let report = rebuilt.run().await.unwrap();
+
+        let named: Vec<_> = report
+            .differences
+            .iter()
+            .map(|d| (d.challenge_id.unwrap(), d.kind))
+            .collect();
+        assert_eq!(named, [
+            (U256::from(1), DifferenceKind::Changed),
+            (U256::from(2), DifferenceKind::Missing),
+            (U256::from(3), DifferenceKind::Corrupt),
+            (U256::from(99), DifferenceKind::Extra),
+        ]);
+        assert_eq!(
+            report.differences[0].local.as_ref().unwrap().state,
+            ChallengeState::Missed
+        );
+        assert_eq!(
+            report.differences[0].rebuilt.as_ref().unwrap().state,
+            ChallengeState::Responded
+        );
+        assert_eq!(report.challenges, 5);
+
+        // The rebuilt store matches the control, and intact records keep their local history.
+        let target = rebuilt.target();
+        assert_eq!(outcomes(target.as_ref()), outcomes(control.as_ref()));
+        assert_eq!(
+            target.get(ARCHIVE_NAMESPACE, &key(4)).unwrap(),
+            Some(cancelled)
+        );
+        assert_eq!(target.get(TRACKER_NAMESPACE, &key(4)).unwrap(), None);
+
+        // Raw evidence is copied unchanged and never written to; summaries are left out.
+        assert!(report.copied_intact());
+        assert_eq!(target.scan(RESPONSE_EVIDENCE).unwrap(), evidence_before);
+        assert_eq!(local.scan(RESPONSE_EVIDENCE).unwrap(), evidence_before);
+        assert_eq!(report.reset, [SUMMARY_NAMESPACE]);
+        assert!(target.scan(SUMMARY_NAMESPACE).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn operator_annotations_survive_a_rebuild() {
+        let local = control().await;
+        // Challenge 1 recorded as missed, so the replay rewrites it.
+        let mut answered: TrackedChallenge =
+            local.get_json(TRACKER_NAMESPACE, &key(1)).unwrap().unwrap();
+        answered.state = ChallengeState::Missed;
+        local
+            .put_json(TRACKER_NAMESPACE, &key(1), &answered)
+            .unwrap();
+        let notes = Annotations::new(Arc::clone(&local), Default::default());
+        let challenge = AnnotationTarget::Challenge(U256::from(1));
+        notes
+            .add(challenge.clone(), "ops", "RPC outage, ticket #123", 1_000)
+            .unwrap();
+        notes
+            .add(AnnotationTarget::Epoch(0), "alice", "node upgrade", 2_000)
+            .unwrap();
+        notes
+            .add(
+                AnnotationTarget::Incident("3f2a9c01b7de".into()),
+                "ops",
+                "paged",
+                3_000,
+            )
+            .unwrap();
+
+        let rebuilt = rebuild(
+            Arc::clone(&local),
+            Arc::new(MemoryStateStore::default()),
+            Arc::new(SimulatedHistory {
+                logs: history(),
+                ..Default::default()
+            }),
+            64,
+        );
+        let report = rebuilt.run().await.unwrap();
+        assert_eq!(report.differences.len(), 1);
+        assert!(report.copied_intact());
+
+        let kept = Annotations::new(Arc::clone(rebuilt.target()), Default::default());
+        let all = AnnotationQuery::default();
+        assert_eq!(kept.search(&all).unwrap(), notes.search(&all).unwrap());
+        assert_eq!(kept.for_target(&challenge).unwrap()[0].author, "ops");
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_rebuild_resumes_after_its_last_range() {
+        let local: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let target: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+        let flaky = Arc::new(SimulatedHistory {
+            logs: history(),
+            fail_at: Some(3),
+            ..Default::default()
+        });
+        let first = rebuild(Arc::clone(&local), Arc::clone(&target), flaky, 25);
+        assert!(first.run().await.is_err());
+        assert_eq!(first.status().unwrap().scanned_through, Some(49));
+
+        let source = Arc::new(SimulatedHistory {
+            logs: history(),
+            ..Default::default()
+        });
+        let second = rebuild(local, target, Arc::clone(&source), 25);
+        let report = second.run().await.unwrap();
+        assert_eq!(report.scanned_through, HEAD);
+        assert_eq!(source.requests.lock().unwrap()[0], (50, 74));
+        assert_eq!(second.status().unwrap().blocks_remaining, 0);
+        assert_eq!(
+            outcomes(second.target().as_ref()),
+            outcomes(control().await.as_ref())
+        );
+    }
+
+    #[tokio::test]
+    async fn swap_refuses_a_copy_that_went_stale() {
+        let dir = TempDir::new().unwrap();
+        let local = control().await;
+        let rebuilt = rebuild(
+            Arc::clone(&local),
+            Arc::new(MemoryStateStore::default()),
+            Arc::new(SimulatedHistory {
+                logs: history(),
+                ..Default::default()
+            }),
+            64,
+        );
+        assert!(rebuilt.swap(dir.path()).is_err());
+        rebuilt.run().await.unwrap();
+
+        local.put(RESPONSE_EVIDENCE, b"late", b"{}").unwrap();
+        assert!(rebuilt.swap(dir.path()).is_err());
+        assert_eq!(read_backend_marker(dir.path()).unwrap(), None);
+
+        rebuilt.finish().unwrap();
+        rebuilt.swap(dir.path()).unwrap();
+        assert_eq!(
+            read_backend_marker(dir.path()).unwrap(),
+            Some(StateBackend::Memory)
+        );
+        // A swapped-in store is not resumed into.
+        assert!(rebuilt.replay().await.is_err());
+    }
+
+    #[test]
+    fn rebuilds_alternate_between_two_files() {
+        let dir = Path::new("/data");
+        let first = rebuild_backend(dir, &StateBackend::Sqlite(dir.join("avs.sqlite")));
+        assert_eq!(first, StateBackend::Sqlite(dir.join("rebuild-a.sqlite")));
+        assert_eq!(
+            rebuild_backend(dir, &first),
+            StateBackend::Sqlite(dir.join("rebuild-b.sqlite"))
+        );
+    }
+}
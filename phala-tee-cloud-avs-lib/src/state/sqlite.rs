@@ -0,0 +1,135 @@
+use super::StateStore;
+use crate::error::PhalaAvsError;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A [`StateStore`] backed by a single SQLite database file.
+#[derive(Debug)]
+pub struct SqliteStateStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStateStore {
+    /// Opens (creating if needed) the database at `path`.
+    pub fn open(path: &Path) -> Result<Self, PhalaAvsError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::from_connection(Connection::open(path).map_err(sqlite_err)?)
+    }
+
+    /// Opens a private in-memory database, mostly useful for tests.
+    pub fn open_in_memory() -> Result<Self, PhalaAvsError> {
+        Self::from_connection(Connection::open_in_memory().map_err(sqlite_err)?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<Self, PhalaAvsError> {
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS kv (
+                 namespace TEXT NOT NULL,
+                 key BLOB NOT NULL,
+                 value BLOB NOT NULL,
+                 PRIMARY KEY (namespace, key)
+             );",
+        )
+        .map_err(sqlite_err)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StateStore for SqliteStateStore {
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, PhalaAvsError> {
+        self.conn()
+            .query_row(
+                "SELECT value FROM kv WHERE namespace = ?1 AND key = ?2",
+                params![namespace, key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_err)
+    }
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), PhalaAvsError> {
+        self.conn()
+            .execute(
+                "INSERT INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(namespace, key) DO UPDATE SET value = excluded.value",
+                params![namespace, key, value],
+            )
+            .map(|_| ())
+            .map_err(sqlite_err)
+    }
+
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<(), PhalaAvsError> {
+        self.conn()
+            .execute("DELETE FROM kv WHERE namespace = ?1 AND key = ?2", params![
+                namespace, key
+            ])
+            .map(|_| ())
+            .map_err(sqlite_err)
+    }
+
+    fn scan(&self, namespace: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT key, value FROM kv WHERE namespace = ?1 ORDER BY key")
+            .map_err(sqlite_err)?;
+        let rows = stmt
+            .query_map(params![namespace], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(sqlite_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sqlite_err)
+    }
+
+    fn scan_range(
+        &self,
+        namespace: &str,
+        from: &[u8],
+        to: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare(
+                "SELECT key, value FROM kv WHERE namespace = ?1 AND key >= ?2 AND key < ?3
+                 ORDER BY key",
+            )
+            .map_err(sqlite_err)?;
+        let rows = stmt
+            .query_map(params![namespace, from, to], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .map_err(sqlite_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sqlite_err)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>, PhalaAvsError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT DISTINCT namespace FROM kv ORDER BY namespace")
+            .map_err(sqlite_err)?;
+        let rows = stmt.query_map([], |row| row.get(0)).map_err(sqlite_err)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sqlite_err)
+    }
+
+    fn size(&self, namespace: &str) -> Result<u64, PhalaAvsError> {
+        self.conn()
+            .query_row(
+                "SELECT COALESCE(SUM(length(key) + length(value)), 0) FROM kv WHERE namespace = ?1",
+                params![namespace],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|size| size as u64)
+            .map_err(sqlite_err)
+    }
+}
+
+pub(crate) fn sqlite_err(e: rusqlite::Error) -> PhalaAvsError {
+    PhalaAvsError::StorageError(format!("SQLite: {e}"))
+}
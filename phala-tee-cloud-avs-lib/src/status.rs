@@ -0,0 +1,1525 @@
+//! Operator status and admin HTTP server.
+//!
+//! Started before any other subsystem so `/status` is reachable while the rest of the operator
+//! is still initializing. Endpoints that need the [`PhalaAvsContext`] answer `503` until it has
+//! been attached. `/healthz` only says the server is up and is always open; every other route
+//! is guarded by an [`Access`] class checked by the [`enforce`] middleware against `Authorization: Bearer <key>` (see [`crate::api_keys`]); admin endpoints
+//! are disabled when neither `ADMIN_TOKEN` nor `API_KEYS_FILE` is configured. `/artifacts` is for
+//! the oracle's verifiers and also accepts `ARTIFACTS_TOKEN`, so they need not hold an admin key.
+//! Workloads register their challenge responders at `/workloads/{id}/responder` and push evidence
+//! to `/workloads/{id}/evidence` with their own tokens instead (see [`crate::delegation`] and
+//! [`crate::ingestion`]).
+//!
+//! Destructive admin actions (forcing an exit, suspending a target, rotating a workload token,
+//! stepping a keystore migration) run at once unless `ADMIN_APPROVERS` is set; then they answer `202` with the action waiting
+//! for approvals at `POST /admin/approvals/{id}` (see [`crate::approvals`]).
+
+use crate::annotations::{Annotation, AnnotationQuery, AnnotationTarget};
+use crate::api_keys::{Access, ApiAuth, AuditEntry, AuditOutcome, Scope};
+use crate::approvals::{ActionStatus, AdminAction, ApprovalRejection, PendingAction};
+use crate::artifacts::ArtifactBundle;
+use crate::build_info::BuildInfo;
+use crate::capacity::CapacityStatus;
+use crate::catchup::{CatchUpStatus, SkippedRange};
+use crate::challenge::{TrackedChallenge, Transition, WindowSummary};
+use crate::config::{self, EffectiveValue, env_or};
+use crate::context::PhalaAvsContext;
+use crate::costs::CostReport;
+use crate::cursor::CursorStatus;
+use crate::delegation::RegisteredResponder;
+use crate::diagnostics::{BundleFormat, DiagnosticsBundle, Section};
+use crate::disk::DiskReport;
+use crate::display::parse_address;
+use crate::drift::DriftReport;
+use crate::duties::DutyForecast;
+use crate::encoding::SchemaKey;
+use crate::error::PhalaAvsError;
+use crate::evidence::{HeartbeatEvidence, now_unix_ms};
+use crate::exit::ExitState;
+use crate::failure_domain::DomainStatus;
+use crate::freshness::WorkloadFreshness;
+use crate::health::{ContributorHealth, HealthRegistry, HealthReport};
+use crate::ingestion::{IngestionStatus, Rejection};
+use crate::keystore::MigrationState;
+use crate::lanes::LaneStatus;
+use crate::logs::{LOG_RING, LogEntry, LogQuery, LogRingConfig};
+use crate::maintenance::{MaintenanceWindow, WorkloadScope};
+use crate::memory::ComponentUsage;
+use crate::metrics::METRICS;
+use crate::operator_set::{OperatorSetSnapshot, OperatorSetTracker};
+use crate::registration::RegistrationSnapshot;
+use crate::reimbursement::ReimbursementStatus;
+use crate::replica::ReplicaStatus;
+use crate::reputation::SignedReputationSummary;
+use crate::response_safety::{Review, TaskDigest};
+use crate::response_window::OracleTarget;
+use crate::restart::RestartReport;
+use crate::rollout::{Rollout, RolloutStatus, parse_kind};
+use crate::schema::SchemaCheck;
+use crate::self_audit::{DisputeBundle, SelfAuditReport, SelfAuditor};
+use crate::signed_payload::SignedPayload;
+use crate::slo::SloStatus;
+use crate::startup::{StartupStatus, SubsystemStatus};
+use crate::tee::attestation::AttestationReport;
+use crate::tee::collateral::CollateralStatus;
+use crate::tee::platform::TeePlatform;
+use crate::upgrade::{UpgradeEvent, UpgradeStatus};
+use axum::extract::{Extension, MatchedPath, Path, Query, Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use blueprint_sdk::alloy::primitives::{B256, U256};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use tracing::{error, info};
+
+/// Reads the status server listen address from `STATUS_ADDR`.
+pub fn status_addr_from_env() -> Result<SocketAddr, PhalaAvsError> {
+    env_or("STATUS_ADDR", SocketAddr::from(([0, 0, 0, 0], 9100)))
+}
+
+/// State shared with the status server handlers.
+#[derive(Clone)]
+pub struct StatusState {
+    pub startup: StartupStatus,
+    context: Arc<OnceLock<PhalaAvsContext>>,
+    auth: Arc<ApiAuth>,
+    health: Arc<HealthRegistry>,
+}
+
+impl StatusState {
+    /// Without credentials: status endpoints are open and admin endpoints disabled.
+    pub fn new(startup: StartupStatus) -> Self {
+        let health = Arc::new(HealthRegistry::default());
+        health.register(Arc::new(startup.clone()));
+        Self {
+            startup,
+            context: Arc::default(),
+            auth: Arc::default(),
+            health,
+        }
+    }
+
+    pub fn with_auth(mut self, auth: Arc<ApiAuth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Like [`new`](Self::new), with the API keys and tokens from the environment.
+    pub fn from_env(startup: StartupStatus) -> Result<Self, PhalaAvsError> {
+        Ok(Self::new(startup).with_auth(Arc::new(ApiAuth::from_env()?)))
+    }
+
+    /// Makes the operator context available to handlers once startup has built it, and
+    /// registers its subsystems' health.
+    pub fn attach_context(&self, context: PhalaAvsContext) {
+        if self.context.get().is_some() {
+            return;
+        }
+        self.health.register(Arc::clone(&context.registration) as _);
+        self.health.register(Arc::clone(&context.domains) as _);
+        self.health.register(Arc::clone(&context.ingestion) as _);
+        if let Some(auditor) = &context.self_audit {
+            self.health.register(Arc::clone(auditor) as _);
+        }
+        if let Some(disk) = &context.disk {
+            self.health.register(Arc::clone(disk) as _);
+        }
+        let _ = self.context.set(context);
+    }
+
+    /// The health of every registered subsystem.
+    pub fn health(&self) -> &Arc<HealthRegistry> {
+        &self.health
+    }
+
+    pub fn auth(&self) -> &Arc<ApiAuth> {
+        &self.auth
+    }
+
+    fn context(&self) -> Result<&PhalaAvsContext, ApiError> {
+        self.context.get().ok_or_else(|| {
+            ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "operator is still starting".to_string(),
+            )
+        })
+    }
+
+    /// Decides a request to `endpoint` and records it in the audit log, returning the id of the
+    /// key it was made with.
+    fn authorize(
+        &self,
+        headers: &HeaderMap,
+        endpoint: &str,
+        access: Access,
+    ) -> Result<Option<String>, ApiError> {
+        let token = bearer_token(headers);
+        let now_ms = now_unix_ms();
+        let disabled = matches!(access, Access::Require(_)) && !self.auth.enabled();
+        let result = if disabled {
+            Err(AuditOutcome::Forbidden)
+        } else {
+            self.auth.authorize(token, endpoint, access, now_ms)
+        };
+        let (key_id, outcome) = match &result {
+            Ok(key_id) => (key_id.clone(), AuditOutcome::Allowed),
+            Err(outcome) => (None, outcome.clone()),
+        };
+        // Public requests are not audited: nobody is accountable for them.
+        if token.is_some() || outcome != AuditOutcome::Allowed {
+            self.auth.record(
+                AuditEntry {
+                    key_id,
+                    endpoint: endpoint.to_string(),
+                    outcome,
+                    unix_ms: now_ms,
+                    approved_action: None,
+                },
+                self.context.get().map(|c| c.state.as_ref()),
+            );
+        }
+        result.map_err(|outcome| match outcome {
+            _ if disabled => ApiError(
+                StatusCode::FORBIDDEN,
+                "admin API is disabled; set ADMIN_TOKEN or API_KEYS_FILE".to_string(),
+            ),
+            AuditOutcome::Forbidden => ApiError(
+                StatusCode::FORBIDDEN,
+                format!("key lacks the scope for {endpoint}"),
+            ),
+            AuditOutcome::RateLimited => ApiError(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate limit exceeded".to_string(),
+            ),
+            _ => ApiError(StatusCode::UNAUTHORIZED, "invalid API key".to_string()),
+        })
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// The id of the API key a request was made with, set by [`enforce`].
+#[derive(Clone, Debug)]
+pub struct Requester(pub Option<String>);
+
+/// Rejects requests whose key does not grant the route's `access`.
+pub async fn enforce(
+    State((state, access)): State<(StatusState, Access)>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.extensions().get::<MatchedPath>().map_or_else(
+        || request.uri().path().to_string(),
+        |p| p.as_str().to_string(),
+    );
+    match state.authorize(request.headers(), &path, access) {
+        Ok(key_id) => {
+            request.extensions_mut().insert(Requester(key_id));
+            next.run(request).await
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// An error response with a JSON `{"error": ...}` body.
+#[derive(Debug)]
+pub struct ApiError(pub StatusCode, pub String);
+
+impl From<PhalaAvsError> for ApiError {
+    fn from(e: PhalaAvsError) -> Self {
+        let status = match e {
+            PhalaAvsError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            PhalaAvsError::NotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self(status, e.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusResponse {
+    /// Whether `/readyz` passes.
+    pub ready: bool,
+    /// What this binary was built from.
+    pub build: BuildInfo,
+    pub subsystems: Vec<SubsystemStatus>,
+    /// The health of every subsystem, as `/readyz` aggregates it.
+    pub health: HealthReport,
+    /// Operator set summary, once the context is attached and the tracker is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operator_set: Option<OperatorSetSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registration: Option<RegistrationSnapshot>,
+    /// Published response schemas compared against ours, once verified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schemas: Option<Vec<SchemaCheck>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contract_recently_upgraded: Option<bool>,
+    /// Host resources and what is advertised on-chain, when capacity reporting is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<CapacityStatus>,
+    /// Each producer cursor and its lag behind the chain head.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursors: Option<Vec<CursorStatus>>,
+    /// Progress of the startup catch-up, once started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub catch_up: Option<CatchUpStatus>,
+    /// Ranges startup catch-ups left unscanned, for auditing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped_ranges: Option<Vec<SkippedRange>>,
+    /// The latest workload drift reconciliation, when enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drift: Option<DriftReport>,
+    /// The platform's collateral, its time to expiry and TCB status, when tracked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collateral: Option<CollateralStatus>,
+    /// How recently each workload pushed evidence, against its cadence.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub evidence_freshness: Option<Vec<WorkloadFreshness>>,
+    /// Challenge response reimbursement: claimable on-chain, claimed and pending.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reimbursement: Option<ReimbursementStatus>,
+    /// Duties expected over the next day; estimates, never a limit on what is handled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duties: Option<DutyForecast>,
+    /// Latency objectives and their remaining error budgets, once first checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slo: Option<Vec<SloStatus>>,
+    /// This replica's role and the response lease, once the context is attached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replica: Option<ReplicaStatus>,
+    /// Pending challenges and the blocks left to answer each, once the chain head is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub challenges: Option<WindowSummary>,
+    /// Progress of a voluntary exit, once one was started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit: Option<ExitState>,
+    /// The latest restart preparation, while the operator is prepared for a restart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart: Option<RestartReport>,
+    /// Workload evidence ingestion, including any outage losing or delaying pushed evidence.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingestion: Option<IngestionStatus>,
+    /// State store usage against the disk budget, when one is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk: Option<DiskReport>,
+    /// Failure domain of each oracle target responded to so far.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domains: Option<Vec<DomainStatus>>,
+    /// Each signer account, its balance and the transactions sent from it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signers: Option<Vec<LaneStatus>>,
+    /// The TEE platform this host runs on, once the context is attached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tee_platform: Option<TeePlatform>,
+    /// What the last locally verified attestation response attested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<AttestationReport>,
+    /// Destructive admin actions waiting for approvals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_actions: Option<Vec<PendingAction>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OperatorSetSummary {
+    pub block: Option<u64>,
+    pub operators: BTreeMap<u8, usize>,
+    /// Our share (0..1) of each quorum's stake.
+    pub our_shares: BTreeMap<u8, f64>,
+}
+
+/// A tracked challenge with the operator's notes on it.
+#[derive(Debug, Serialize)]
+pub struct ChallengeDetail {
+    pub challenge: TrackedChallenge,
+    pub annotations: Vec<Annotation>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnnotateRequest {
+    pub note: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleMaintenanceRequest {
+    /// A workload id, or `all`.
+    pub workload: String,
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterResponderRequest {
+    /// URL the operator posts the workload's challenges to.
+    pub endpoint: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IngestReceipt {
+    /// Records waiting to be appended to the evidence log, this one included.
+    pub queued: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResponderToken {
+    pub workload_id: B256,
+    /// Shown this once; the operator only keeps its hash.
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApproveActionRequest {
+    /// EIP-191 signature over the action id by an approver's admin key, hex encoded.
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExecutedAction {
+    pub action: PendingAction,
+    /// What the action's endpoint answers when run without approvals.
+    pub result: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiagnosticsQuery {
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+pub fn router(state: StatusState) -> Router {
+    let guarded = |routes: Router<StatusState>, access: Access| {
+        routes.route_layer(middleware::from_fn_with_state(
+            (state.clone(), access),
+            enforce,
+        ))
+    };
+    let probes = Router::new()
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics));
+    let views = Router::new()
+        .route("/status", get(status))
+        .route("/maintenance", get(list_maintenance))
+        .route("/operator-set", get(operator_set))
+        .route("/operator-set/history", get(operator_set_history))
+        .route("/upgrades", get(upgrades))
+        .route("/rollout", get(rollouts))
+        .route("/exit", get(exit_status))
+        .route("/keystore/migration", get(keystore_migration))
+        .route("/task-responses/{kind}/{index}", get(task_response_digest))
+        .route("/challenges", get(challenges))
+        .route("/challenges/{id}", get(challenge_detail))
+        .route("/challenges/{id}/history", get(challenge_history))
+        .route("/annotations", get(search_annotations));
+    let exports = Router::new()
+        .route("/artifacts/{hash}", get(artifacts))
+        .route("/export/self-audit", get(self_audit_report))
+        .route("/export/self-audit/disputes/{id}", get(self_audit_dispute))
+        .route("/export/costs", get(cost_report))
+        .route("/reputation", get(reputation))
+        .route("/heartbeat", get(signed_heartbeat))
+        .route("/admin/diagnostics", get(diagnostics))
+        .route("/logs", get(logs));
+    let acks = Router::new()
+        .route("/admin/upgrades/ack", post(acknowledge_upgrades))
+        .route("/admin/annotations/{kind}/{id}", post(annotate));
+    let state_admin = Router::new()
+        .route("/admin/maintenance", post(schedule_maintenance))
+        .route("/admin/maintenance/{id}", delete(cancel_maintenance))
+        .route("/admin/exit", post(start_exit))
+        .route("/admin/prepare-restart", post(prepare_restart))
+        .route("/admin/abort-restart", post(abort_restart))
+        .route(
+            "/admin/keystore/migration/start",
+            post(start_keystore_migration),
+        )
+        .route(
+            "/admin/keystore/migration/finalize",
+            post(finalize_keystore_migration),
+        )
+        .route(
+            "/admin/keystore/migration/abort",
+            post(abort_keystore_migration),
+        )
+        .route(
+            "/admin/rollout/{oracle}/{kind}/promote",
+            post(promote_rollout),
+        )
+        .route(
+            "/admin/workloads/{id}/responder-token",
+            post(issue_responder_token),
+        )
+        .route(
+            "/admin/workloads/{id}/evidence-token",
+            post(issue_evidence_token),
+        )
+        .route(
+            "/admin/domains/{chain_id}/{oracle}/suspend",
+            post(suspend_domain),
+        )
+        .route(
+            "/admin/domains/{chain_id}/{oracle}/resume",
+            post(resume_domain),
+        )
+        .route("/admin/response-safety", get(safety_reviews))
+        .route(
+            "/admin/response-safety/{id}/override",
+            post(override_response_safety),
+        )
+        .route("/admin/approvals", get(list_actions))
+        .route("/admin/approvals/{id}", post(approve_action));
+    // Authenticated by the handlers, with the workload's own tokens.
+    let workloads = Router::new()
+        .route("/workloads/{id}/responder", put(register_responder))
+        .route("/workloads/{id}/evidence", post(ingest_evidence));
+    let config_admin = Router::new()
+        .route("/admin/memory", get(memory_usage).put(configure_memory))
+        .route("/admin/api-keys/reload", post(reload_api_keys))
+        .route("/admin/config", get(effective_config))
+        .route("/admin/config/reload", post(reload_config))
+        .route(
+            "/admin/measurement-policy/reload",
+            post(reload_measurement_policy),
+        )
+        .route("/admin/logs", get(log_ring_config).put(configure_log_ring));
+    #[cfg(feature = "chaos")]
+    let config_admin = config_admin.route("/admin/chaos", get(chaos_status).put(configure_chaos));
+    Router::new()
+        .merge(guarded(probes, Access::Probe))
+        .merge(guarded(views, Access::Read))
+        .merge(guarded(exports, Access::Require(Scope::Export)))
+        .merge(guarded(acks, Access::Require(Scope::AckAlerts)))
+        .merge(guarded(state_admin, Access::Require(Scope::AdminState)))
+        .merge(guarded(config_admin, Access::Require(Scope::AdminConfig)))
+        .merge(workloads)
+        .route("/healthz", get(healthz))
+        .with_state(state.clone())
+}
+
+/// Binds the status server and serves it in the background, returning the bound address.
+pub async fn spawn_status_server(
+    addr: SocketAddr,
+    state: StatusState,
+) -> Result<SocketAddr, PhalaAvsError> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!("Status server listening on {local_addr}");
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router(state)).await {
+            error!("Status server stopped: {e}");
+        }
+    });
+    Ok(local_addr)
+}
+
+async fn status(State(state): State<StatusState>) -> Json<StatusResponse> {
+    let health = state.health.report();
+    let challenges = match state.context.get() {
+        Some(context) => pending_windows(context).await,
+        None => None,
+    };
+    Json(StatusResponse {
+        ready: health.ready,
+        build: BuildInfo::current().clone(),
+        subsystems: state.startup.snapshot(),
+        health,
+        operator_set: state
+            .context
+            .get()
+            .and_then(|c| c.operator_set.as_ref())
+            .map(|tracker| {
+                let current = tracker.current();
+                OperatorSetSummary {
+                    block: current.as_ref().map(|s| s.block),
+                    operators: current
+                        .map(|s| s.quorums.iter().map(|(q, ops)| (*q, ops.len())).collect())
+                        .unwrap_or_default(),
+                    our_shares: tracker.our_shares(),
+                }
+            }),
+        registration: state.context.get().map(|c| c.registration.snapshot()),
+        schemas: state.context.get().and_then(|c| c.schemas.checks()),
+        contract_recently_upgraded: state.context.get().map(|c| c.upgrades.recently_upgraded()),
+        capacity: state
+            .context
+            .get()
+            .and_then(|c| c.capacity.as_ref().and_then(|r| r.status())),
+        cursors: state.context.get().map(|c| c.cursors.status()),
+        catch_up: state
+            .context
+            .get()
+            .and_then(|c| c.catch_up.status(now_unix_ms())),
+        skipped_ranges: state
+            .context
+            .get()
+            .and_then(|c| {
+                c.catch_up
+                    .skipped()
+                    .inspect_err(|e| error!("Failed to read skipped catch-up ranges: {e}"))
+                    .ok()
+            })
+            .filter(|ranges| !ranges.is_empty()),
+        drift: state
+            .context
+            .get()
+            .and_then(|c| c.drift.as_ref().map(|d| d.report())),
+        collateral: state
+            .context
+            .get()
+            .and_then(|c| c.collateral.as_ref()?.status(now_unix_ms() / 1000)),
+        evidence_freshness: state
+            .context
+            .get()
+            .and_then(|c| Some(c.freshness.as_ref()?.status(now_unix_ms())))
+            .filter(|workloads| !workloads.is_empty()),
+        reimbursement: state.context.get().map(|c| c.reimbursement.status()),
+        duties: state.context.get().and_then(|c| c.duties.forecast()),
+        slo: state.context.get().and_then(|c| c.slo.statuses()),
+        replica: state.context.get().map(|c| c.replica.status()),
+        challenges,
+        exit: state.context.get().and_then(|c| c.exit.state()),
+        restart: state.context.get().and_then(|c| c.restart.report()),
+        ingestion: state.context.get().map(|c| c.ingestion.status()),
+        disk: state
+            .context
+            .get()
+            .and_then(|c| c.disk.as_ref().and_then(|d| d.report())),
+        domains: state
+            .context
+            .get()
+            .map(|c| c.domains.status())
+            .filter(|d| !d.is_empty()),
+        signers: state.context.get().map(|c| c.lanes.status()),
+        tee_platform: state.context.get().map(|c| c.tee_handler.platform()),
+        attestation: state
+            .context
+            .get()
+            .and_then(|c| c.preflight.known_good().ok().flatten())
+            .and_then(|known_good| known_good.report),
+        pending_actions: state
+            .context
+            .get()
+            .and_then(|c| {
+                c.approvals
+                    .pending(now_unix_ms())
+                    .inspect_err(|e| error!("Failed to read pending admin actions: {e}"))
+                    .ok()
+            })
+            .filter(|actions| !actions.is_empty()),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    /// Some `degraded_ok` subsystem is failing.
+    pub degraded: bool,
+    /// Why the operator is not ready, by failing critical subsystem; empty when it is.
+    pub reasons: Vec<String>,
+    /// The failing critical and `degraded_ok` subsystems.
+    pub failing: Vec<String>,
+    pub contributors: Vec<ContributorHealth>,
+}
+
+/// `503` while a critical subsystem is failing, `200` otherwise, flagged `degraded` while a
+/// `degraded_ok` one is. See [`crate::health`] for the subsystems and their criticality.
+async fn readyz(State(state): State<StatusState>) -> (StatusCode, Json<ReadinessResponse>) {
+    let report = state.health.report();
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let reasons = report.blockers();
+    (
+        status,
+        Json(ReadinessResponse {
+            ready: report.ready,
+            degraded: report.degraded,
+            reasons,
+            failing: report.failing,
+            contributors: report.contributors,
+        }),
+    )
+}
+
+/// Liveness of the server itself, for orchestrators that cannot hold a key.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+async fn metrics() -> String {
+    METRICS.render()
+}
+
+async fn list_maintenance(
+    State(state): State<StatusState>,
+) -> Result<Json<Vec<MaintenanceWindow>>, ApiError> {
+    Ok(Json(state.context()?.maintenance.windows()?))
+}
+
+fn operator_set_tracker(state: &StatusState) -> Result<&OperatorSetTracker, ApiError> {
+    state.context()?.operator_set.as_deref().ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            "operator set tracking is not configured".to_string(),
+        )
+    })
+}
+
+async fn operator_set(
+    State(state): State<StatusState>,
+) -> Result<Json<Option<OperatorSetSnapshot>>, ApiError> {
+    Ok(Json(operator_set_tracker(&state)?.current()))
+}
+
+async fn operator_set_history(
+    State(state): State<StatusState>,
+) -> Result<Json<Vec<OperatorSetSnapshot>>, ApiError> {
+    Ok(Json(operator_set_tracker(&state)?.history()?))
+}
+
+async fn upgrades(State(state): State<StatusState>) -> Result<Json<UpgradeStatus>, ApiError> {
+    Ok(Json(state.context()?.upgrades.status()))
+}
+
+/// Encoder rollouts and how far their shadow versions are from promotion.
+async fn rollouts(State(state): State<StatusState>) -> Result<Json<Vec<RolloutStatus>>, ApiError> {
+    Ok(Json(state.context()?.rollout.status()?))
+}
+
+/// The pending challenges and their remaining windows, each with its oracle's current safety
+/// margin when the chain id can be read.
+async fn pending_windows(context: &PhalaAvsContext) -> Option<WindowSummary> {
+    let mut summary = context.challenge_tracker.window_summary()?;
+    if let Ok(chain_id) = context.evm.chain_id().await {
+        for window in &mut summary.pending {
+            let target = OracleTarget::new(chain_id, window.oracle);
+            window.safety_margin = Some(context.margin_predictor.safety_margin(&target));
+        }
+    }
+    Some(summary)
+}
+
+/// Pending challenges and the blocks left to answer each, most urgent first.
+async fn challenges(State(state): State<StatusState>) -> Result<Json<WindowSummary>, ApiError> {
+    pending_windows(state.context()?)
+        .await
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "the chain head was not observed yet".into(),
+            )
+        })
+}
+
+/// A tracked challenge, its transitions and the notes attached to it.
+async fn challenge_detail(
+    State(state): State<StatusState>,
+    Path(id): Path<String>,
+) -> Result<Json<ChallengeDetail>, ApiError> {
+    let challenge_id: U256 = id.parse().map_err(|_| {
+        ApiError(
+            StatusCode::BAD_REQUEST,
+            format!("invalid challenge id {id}"),
+        )
+    })?;
+    let context = state.context()?;
+    let challenge = context
+        .challenge_tracker
+        .get(&challenge_id)
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                format!("challenge {challenge_id} is not tracked"),
+            )
+        })?;
+    let annotations = context
+        .annotations
+        .for_target(&AnnotationTarget::Challenge(challenge_id))?;
+    Ok(Json(ChallengeDetail {
+        challenge,
+        annotations,
+    }))
+}
+
+/// Every recorded state transition of a challenge, oldest first.
+async fn challenge_history(
+    State(state): State<StatusState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Transition>>, ApiError> {
+    let challenge_id: U256 = id.parse().map_err(|_| {
+        ApiError(
+            StatusCode::BAD_REQUEST,
+            format!("invalid challenge id {id}"),
+        )
+    })?;
+    state
+        .context()?
+        .challenge_tracker
+        .history(&challenge_id)
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                format!("challenge {challenge_id} is not tracked"),
+            )
+        })
+}
+
+fn self_auditor(state: &StatusState) -> Result<&SelfAuditor, ApiError> {
+    state.context()?.self_audit.as_deref().ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            "self-audit is not enabled".to_string(),
+        )
+    })
+}
+
+/// The latest self-audit report, `null` before the first audit.
+async fn self_audit_report(
+    State(state): State<StatusState>,
+) -> Result<Json<Option<SelfAuditReport>>, ApiError> {
+    Ok(Json(self_auditor(&state)?.report()))
+}
+
+/// The cost ledger: gas spent, reimbursement, and what is left net.
+async fn cost_report(State(state): State<StatusState>) -> Result<Json<CostReport>, ApiError> {
+    Ok(Json(state.context()?.costs.report()?))
+}
+
+/// The operator's signed performance summary up to the current head.
+async fn reputation(
+    State(state): State<StatusState>,
+) -> Result<Json<SignedReputationSummary>, ApiError> {
+    let context = state.context()?;
+    let head = context.evm.block_number().await?;
+    Ok(Json(context.reputation.export(head, now_unix_ms()).await?))
+}
+
+/// The latest heartbeat, signed with replay protection.
+async fn signed_heartbeat(
+    State(state): State<StatusState>,
+) -> Result<Json<SignedPayload<HeartbeatEvidence>>, ApiError> {
+    state
+        .context()?
+        .heartbeat
+        .signed()
+        .map(Json)
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, "no heartbeat signed yet".to_string()))
+}
+
+/// The dispute bundle the self-audit generated for a challenge.
+async fn self_audit_dispute(
+    State(state): State<StatusState>,
+    Path(id): Path<String>,
+) -> Result<Json<DisputeBundle>, ApiError> {
+    let challenge_id: U256 = id.parse().map_err(|_| {
+        ApiError(
+            StatusCode::BAD_REQUEST,
+            format!("invalid challenge id {id}"),
+        )
+    })?;
+    self_auditor(&state)?
+        .dispute(&challenge_id)?
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                format!("no dispute bundle for challenge {challenge_id}"),
+            )
+        })
+}
+
+/// The artifact bundle archived for an on-chain payload hash.
+async fn artifacts(
+    State(state): State<StatusState>,
+    Path(hash): Path<String>,
+) -> Result<Json<ArtifactBundle>, ApiError> {
+    let payload_hash: B256 = hash.parse().map_err(|_| {
+        ApiError(
+            StatusCode::BAD_REQUEST,
+            format!("invalid payload hash {hash}"),
+        )
+    })?;
+    state
+        .context()?
+        .artifacts
+        .get(payload_hash)?
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                format!("no artifacts for payload {payload_hash}"),
+            )
+        })
+}
+
+/// Notes matching the query's filters, newest first.
+async fn search_annotations(
+    State(state): State<StatusState>,
+    Query(query): Query<AnnotationQuery>,
+) -> Result<Json<Vec<Annotation>>, ApiError> {
+    Ok(Json(state.context()?.annotations.search(&query)?))
+}
+
+/// Attaches a note to a challenge, epoch or incident, authored by the requesting key.
+async fn annotate(
+    State(state): State<StatusState>,
+    Extension(requester): Extension<Requester>,
+    Path((kind, id)): Path<(String, String)>,
+    Json(request): Json<AnnotateRequest>,
+) -> Result<Json<Annotation>, ApiError> {
+    let target = AnnotationTarget::parse(&kind, &id)?;
+    let author = requester.0.unwrap_or_else(|| "anonymous".to_string());
+    let annotation =
+        state
+            .context()?
+            .annotations
+            .add(target, &author, &request.note, now_unix_ms())?;
+    info!("{author} annotated {}", annotation.target);
+    Ok(Json(annotation))
+}
+
+/// Clears `contract_recently_upgraded`, releasing held responses.
+async fn acknowledge_upgrades(
+    State(state): State<StatusState>,
+) -> Result<Json<Vec<UpgradeEvent>>, ApiError> {
+    Ok(Json(state.context()?.upgrades.acknowledge()))
+}
+
+async fn schedule_maintenance(
+    State(state): State<StatusState>,
+    Json(request): Json<ScheduleMaintenanceRequest>,
+) -> Result<Json<MaintenanceWindow>, ApiError> {
+    let scope: WorkloadScope = request.workload.parse()?;
+    let window = state
+        .context()?
+        .maintenance
+        .schedule(scope, request.from, request.to)
+        .await?;
+    Ok(Json(window))
+}
+
+async fn cancel_maintenance(
+    State(state): State<StatusState>,
+    Path(id): Path<String>,
+) -> Result<Json<MaintenanceWindow>, ApiError> {
+    let window_id: U256 = id.parse().map_err(|_| {
+        ApiError(
+            StatusCode::BAD_REQUEST,
+            format!("invalid maintenance window id {id}"),
+        )
+    })?;
+    Ok(Json(state.context()?.maintenance.cancel(window_id).await?))
+}
+
+async fn exit_status(
+    State(state): State<StatusState>,
+) -> Result<Json<Option<ExitState>>, ApiError> {
+    Ok(Json(state.context()?.exit.state()))
+}
+
+/// Starts draining for a voluntary exit, or returns the exit in progress.
+async fn start_exit(
+    State(state): State<StatusState>,
+    Extension(requester): Extension<Requester>,
+) -> Result<Response, ApiError> {
+    run_or_hold(&state, requester, AdminAction::ForceExit).await
+}
+
+/// Holds response submission and reports once in-flight submissions settled whether it is
+/// safe to restart.
+async fn prepare_restart(
+    State(state): State<StatusState>,
+) -> Result<Json<RestartReport>, ApiError> {
+    let context = state.context()?;
+    let report = context
+        .restart
+        .prepare(
+            context.evm.as_ref(),
+            &context.challenge_tracker,
+            &context.cursors,
+            now_unix_ms(),
+        )
+        .await?;
+    Ok(Json(report))
+}
+
+/// The keystore migration's phase, canary check and fallbacks.
+async fn keystore_migration(
+    State(state): State<StatusState>,
+) -> Result<Json<MigrationState>, ApiError> {
+    Ok(Json(state.context()?.keystore.state()))
+}
+
+/// Checks the secondary signer with a canary digest and starts the soak if it passes.
+async fn start_keystore_migration(
+    State(state): State<StatusState>,
+    Extension(requester): Extension<Requester>,
+) -> Result<Response, ApiError> {
+    run_or_hold(&state, requester, AdminAction::StartKeystoreMigration).await
+}
+
+/// Takes the primary signer out of the signing path after a clean soak.
+async fn finalize_keystore_migration(
+    State(state): State<StatusState>,
+    Extension(requester): Extension<Requester>,
+) -> Result<Response, ApiError> {
+    run_or_hold(&state, requester, AdminAction::FinalizeKeystoreMigration).await
+}
+
+/// Returns signing to the primary signer.
+async fn abort_keystore_migration(
+    State(state): State<StatusState>,
+    Extension(requester): Extension<Requester>,
+) -> Result<Response, ApiError> {
+    run_or_hold(&state, requester, AdminAction::AbortKeystoreMigration).await
+}
+
+/// Makes the shadow encoder of a kind at an oracle the active one, once its shadow results allow
+/// it and the oracle accepts its schema.
+async fn promote_rollout(
+    State(state): State<StatusState>,
+    Path((oracle, kind)): Path<(String, String)>,
+) -> Result<Json<Rollout>, ApiError> {
+    let context = state.context()?;
+    let oracle = parse_address(&oracle)?;
+    let kind = parse_kind(&kind)?;
+    if let Some(shadow) = context.rollout.rollout(oracle, kind).and_then(|r| r.shadow) {
+        context.schemas.encoder(&SchemaKey {
+            kind,
+            version: shadow,
+        })?;
+    }
+    Ok(Json(context.rollout.promote(
+        oracle,
+        kind,
+        now_unix_ms(),
+    )?))
+}
+
+/// Resumes response submission, returning whether a restart was being prepared.
+async fn abort_restart(State(state): State<StatusState>) -> Result<Json<bool>, ApiError> {
+    Ok(Json(state.context()?.restart.abort()))
+}
+
+fn parse_workload_id(id: &str) -> Result<B256, ApiError> {
+    id.parse()
+        .map_err(|_| ApiError(StatusCode::BAD_REQUEST, format!("invalid workload id {id}")))
+}
+
+/// Issues a workload the token it registers its responder with, revoking any previous one.
+async fn issue_responder_token(
+    State(state): State<StatusState>,
+    Extension(requester): Extension<Requester>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let workload_id = parse_workload_id(&id)?;
+    run_or_hold(&state, requester, AdminAction::RotateResponderToken {
+        workload_id,
+    })
+    .await
+}
+
+/// Registers the endpoint a workload answers its own challenges at.
+async fn register_responder(
+    State(state): State<StatusState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<RegisterResponderRequest>,
+) -> Result<Json<RegisteredResponder>, ApiError> {
+    let workload_id = parse_workload_id(&id)?;
+    let token = bearer_token(&headers).unwrap_or_default();
+    state
+        .context()?
+        .delegation
+        .registry()
+        .register(workload_id, token, &request.endpoint, now_unix_ms())?
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::UNAUTHORIZED,
+                format!("invalid responder token for workload {workload_id}"),
+            )
+        })
+}
+
+/// Rotates a workload's evidence token and pushes the new one to the workload.
+async fn issue_evidence_token(
+    State(state): State<StatusState>,
+    Extension(requester): Extension<Requester>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let workload_id = parse_workload_id(&id)?;
+    run_or_hold(&state, requester, AdminAction::RotateEvidenceToken {
+        workload_id,
+    })
+    .await
+}
+
+/// Queues evidence pushed by a workload.
+async fn ingest_evidence(
+    State(state): State<StatusState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<(StatusCode, Json<IngestReceipt>), Response> {
+    let workload_id = parse_workload_id(&id).map_err(IntoResponse::into_response)?;
+    let context = state.context().map_err(IntoResponse::into_response)?;
+    let queued = context
+        .ingestion
+        .ingest(workload_id, bearer_token(&headers), &body, now_unix_ms())
+        .map_err(|rejection| {
+            let status = match rejection {
+                Rejection::Unauthorized => StatusCode::UNAUTHORIZED,
+                Rejection::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                Rejection::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+                Rejection::Invalid(_) => StatusCode::BAD_REQUEST,
+                Rejection::QueueFull { .. } | Rejection::Unavailable(_) => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+            };
+            let mut response = ApiError(status, rejection.to_string()).into_response();
+            if let Some(retry_after) = rejection.retry_after() {
+                // Rounded up, so a workload honoring it is not turned away again.
+                let secs = (retry_after.as_millis() as u64).div_ceil(1000).max(1);
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, secs.into());
+            }
+            response
+        })?;
+    Ok((StatusCode::ACCEPTED, Json(IngestReceipt { queued })))
+}
+
+fn oracle_target(chain_id: u64, oracle: &str) -> Result<OracleTarget, ApiError> {
+    Ok(OracleTarget::new(chain_id, parse_address(oracle)?))
+}
+
+/// Stops submissions to one oracle target until it is resumed.
+async fn suspend_domain(
+    State(state): State<StatusState>,
+    Extension(requester): Extension<Requester>,
+    Path((chain_id, oracle)): Path<(u64, String)>,
+) -> Result<Response, ApiError> {
+    let target = oracle_target(chain_id, &oracle)?;
+    let action = AdminAction::SuspendDomain {
+        chain_id: target.chain_id,
+        oracle: target.oracle,
+    };
+    run_or_hold(&state, requester, action).await
+}
+
+async fn resume_domain(
+    State(state): State<StatusState>,
+    Path((chain_id, oracle)): Path<(u64, String)>,
+) -> Result<Json<DomainStatus>, ApiError> {
+    let target = oracle_target(chain_id, &oracle)?;
+    Ok(Json(state.context()?.domains.resume(target)))
+}
+
+/// The digest of the response this operator cleared for signing for a task, for trusted peers'
+/// pre-signing checks.
+async fn task_response_digest(
+    State(state): State<StatusState>,
+    Path((kind, index)): Path<(String, u32)>,
+) -> Result<Json<TaskDigest>, ApiError> {
+    let digest = state
+        .context()?
+        .response_safety
+        .signed_digest(&kind, index)?
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                format!("no {kind} response signed for task {index}"),
+            )
+        })?;
+    Ok(Json(TaskDigest {
+        kind,
+        task_index: index,
+        digest,
+    }))
+}
+
+/// Task responses the pre-signing safety checks blocked, and what became of them.
+async fn safety_reviews(State(state): State<StatusState>) -> Result<Json<Vec<Review>>, ApiError> {
+    Ok(Json(state.context()?.response_safety.reviews()?))
+}
+
+/// Clears a blocked task response for signing.
+async fn override_response_safety(
+    State(state): State<StatusState>,
+    Extension(requester): Extension<Requester>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let review: B256 = id
+        .parse()
+        .map_err(|_| ApiError(StatusCode::BAD_REQUEST, format!("invalid review id {id}")))?;
+    run_or_hold(&state, requester, AdminAction::OverrideResponseSafety {
+        review,
+    })
+    .await
+}
+
+/// Runs a destructive admin action, or when approvals are required records it and answers `202`
+/// with the action waiting for them.
+async fn run_or_hold(
+    state: &StatusState,
+    requester: Requester,
+    action: AdminAction,
+) -> Result<Response, ApiError> {
+    let context = state.context()?;
+    if !context.approvals.enabled() {
+        return Ok(Json(execute(context, &action).await?).into_response());
+    }
+    let pending = context
+        .approvals
+        .request(action, requester.0, now_unix_ms())?;
+    Ok((StatusCode::ACCEPTED, Json(pending)).into_response())
+}
+
+/// Runs a destructive admin action, returning what its endpoint answers.
+async fn execute(
+    context: &PhalaAvsContext,
+    action: &AdminAction,
+) -> Result<serde_json::Value, ApiError> {
+    let now_ms = now_unix_ms();
+    let result = match *action {
+        AdminAction::ForceExit => serde_json::to_value(context.exit.start(now_ms)?),
+        AdminAction::SuspendDomain { chain_id, oracle } => serde_json::to_value(
+            context
+                .domains
+                .suspend(OracleTarget::new(chain_id, oracle), now_ms),
+        ),
+        AdminAction::RotateResponderToken { workload_id } => {
+            let token = context
+                .delegation
+                .registry()
+                .issue_token(workload_id, now_ms)?;
+            serde_json::to_value(ResponderToken { workload_id, token })
+        }
+        AdminAction::RotateEvidenceToken { workload_id } => serde_json::to_value(
+            context
+                .ingestion
+                .rotate_token(&context.tee_handler, workload_id, now_ms)
+                .await?,
+        ),
+        AdminAction::OverrideResponseSafety { review } => {
+            serde_json::to_value(context.response_safety.override_review(review, now_ms)?)
+        }
+        AdminAction::StartKeystoreMigration => {
+            serde_json::to_value(context.keystore.start(now_ms).await?)
+        }
+        AdminAction::FinalizeKeystoreMigration => {
+            serde_json::to_value(context.keystore.finalize(now_ms)?)
+        }
+        AdminAction::AbortKeystoreMigration => {
+            serde_json::to_value(context.keystore.abort(now_ms)?)
+        }
+    };
+    result.map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Every destructive admin action recorded for approval, oldest first.
+async fn list_actions(
+    State(state): State<StatusState>,
+) -> Result<Json<Vec<PendingAction>>, ApiError> {
+    Ok(Json(state.context()?.approvals.actions(now_unix_ms())?))
+}
+
+/// Adds an admin's approval to a pending action, running it once it has enough. The approval
+/// trail of a run action is appended to the audit log.
+async fn approve_action(
+    State(state): State<StatusState>,
+    Path(id): Path<String>,
+    Json(request): Json<ApproveActionRequest>,
+) -> Result<Response, ApiError> {
+    let context = state.context()?;
+    let id: B256 = id
+        .parse()
+        .map_err(|_| ApiError(StatusCode::BAD_REQUEST, format!("invalid action id {id}")))?;
+    let approved = context
+        .approvals
+        .approve(id, &request.signature, now_unix_ms())
+        .map_err(|rejection| {
+            let status = match rejection {
+                ApprovalRejection::NotFound => StatusCode::NOT_FOUND,
+                ApprovalRejection::Expired | ApprovalRejection::Decided(_) => StatusCode::CONFLICT,
+                ApprovalRejection::InvalidSignature(_) => StatusCode::BAD_REQUEST,
+                ApprovalRejection::NotAnApprover(_) => StatusCode::FORBIDDEN,
+                ApprovalRejection::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            ApiError(status, rejection.to_string())
+        })?;
+    if approved.status != ActionStatus::Approved {
+        return Ok((StatusCode::ACCEPTED, Json(approved)).into_response());
+    }
+    let result = execute(context, &approved.action).await;
+    let outcome = result.as_ref().map(|_| ()).map_err(|e| e.1.clone());
+    let decided = context.approvals.complete(id, outcome, now_unix_ms())?;
+    state.auth.record(
+        AuditEntry {
+            key_id: decided.requested_by.clone(),
+            endpoint: "/admin/approvals/{id}".to_string(),
+            outcome: AuditOutcome::Allowed,
+            unix_ms: decided.decided_unix_ms.unwrap_or_else(now_unix_ms),
+            approved_action: Some(decided.clone()),
+        },
+        Some(context.state.as_ref()),
+    );
+    Ok(Json(ExecutedAction {
+        action: decided,
+        result: result?,
+    })
+    .into_response())
+}
+
+#[cfg(feature = "chaos")]
+async fn chaos_status(
+    State(state): State<StatusState>,
+) -> Result<Json<crate::chaos::ChaosStatus>, ApiError> {
+    Ok(Json(state.context()?.chaos.status()))
+}
+
+/// Replaces the fault policies; the generator is reseeded from the new config.
+#[cfg(feature = "chaos")]
+async fn configure_chaos(
+    State(state): State<StatusState>,
+    Json(config): Json<crate::chaos::ChaosConfig>,
+) -> Result<Json<crate::chaos::ChaosStatus>, ApiError> {
+    let chaos = &state.context()?.chaos;
+    chaos.reconfigure(config)?;
+    Ok(Json(chaos.status()))
+}
+
+async fn memory_usage(
+    State(state): State<StatusState>,
+) -> Result<Json<BTreeMap<String, ComponentUsage>>, ApiError> {
+    Ok(Json(state.context()?.memory.snapshot()))
+}
+
+/// Replaces the budgets of the named components, in bytes.
+async fn configure_memory(
+    State(state): State<StatusState>,
+    Json(budgets): Json<BTreeMap<String, u64>>,
+) -> Result<Json<BTreeMap<String, ComponentUsage>>, ApiError> {
+    let memory = &state.context()?.memory;
+    memory.set_budgets(budgets)?;
+    Ok(Json(memory.snapshot()))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReloadKeysResponse {
+    pub keys: usize,
+}
+
+/// Re-reads `API_KEYS_FILE` without waiting for the next change check.
+async fn reload_api_keys(
+    State(state): State<StatusState>,
+) -> Result<Json<ReloadKeysResponse>, ApiError> {
+    Ok(Json(ReloadKeysResponse {
+        keys: state.auth.reload()?,
+    }))
+}
+
+/// Every setting with its source, secrets redacted.
+async fn effective_config() -> Json<Vec<EffectiveValue>> {
+    Json(
+        config::effective()
+            .into_iter()
+            .map(EffectiveValue::redacted)
+            .collect(),
+    )
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReloadConfigResponse {
+    pub values: usize,
+}
+
+/// Re-reads the config file the operator was started with. Settings read at startup keep
+/// their value until restart.
+async fn reload_config() -> Result<Json<ReloadConfigResponse>, ApiError> {
+    Ok(Json(ReloadConfigResponse {
+        values: config::reload_file()?,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReloadMeasurementPolicyResponse {
+    pub images: usize,
+}
+
+/// Re-reads `TEE_MEASUREMENT_POLICY_PATH`; quotes are checked against it from the next one on.
+async fn reload_measurement_policy(
+    State(state): State<StatusState>,
+) -> Result<Json<ReloadMeasurementPolicyResponse>, ApiError> {
+    Ok(Json(ReloadMeasurementPolicyResponse {
+        images: state.context()?.tee_handler.reload_measurement_policy()?,
+    }))
+}
+
+/// Recent log events matching the query, oldest first.
+async fn logs(Query(query): Query<LogQuery>) -> Result<Json<Vec<LogEntry>>, ApiError> {
+    Ok(Json(LOG_RING.query(&query)?))
+}
+
+async fn log_ring_config() -> Json<LogRingConfig> {
+    Json(LOG_RING.config())
+}
+
+/// Resizes the log ring and sets the minimum level it captures.
+async fn configure_log_ring(
+    Json(config): Json<LogRingConfig>,
+) -> Result<Json<LogRingConfig>, ApiError> {
+    LOG_RING.configure(&config)?;
+    Ok(Json(LOG_RING.config()))
+}
+
+async fn diagnostics(
+    State(state): State<StatusState>,
+    Query(query): Query<DiagnosticsQuery>,
+) -> Result<Response, ApiError> {
+    let format: BundleFormat = query.format.as_deref().unwrap_or("json").parse()?;
+    let mut bundle = DiagnosticsBundle::collect(&state.startup, state.context.get())?;
+    bundle
+        .sections
+        .push(Section::json("audit", &state.auth.audit_log())?);
+    let content_type = match format {
+        BundleFormat::Json => "application/json",
+        BundleFormat::Compact => "application/octet-stream",
+    };
+    Ok((
+        [(header::CONTENT_TYPE, content_type)],
+        bundle.render(format)?,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::ApiAuthConfig;
+    use axum::http::HeaderValue;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!("Bearer {token}")).unwrap();
+        headers.insert(header::AUTHORIZATION, value);
+        headers
+    }
+
+    #[test]
+    fn artifacts_require_a_verifier_or_admin_token() {
+        let artifacts = |state: &StatusState, headers: &HeaderMap| {
+            let access = Access::Require(Scope::Export);
+            state.authorize(headers, "/artifacts/{hash}", access)
+        };
+        let state = StatusState::new(StartupStatus::default());
+        assert_eq!(
+            artifacts(&state, &bearer("x")).unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+
+        let state = state.with_auth(Arc::new(
+            ApiAuth::new(ApiAuthConfig {
+                artifacts_token: Some("verifier".to_string()),
+                admin_token: Some("admin".to_string()),
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        assert_eq!(
+            artifacts(&state, &HeaderMap::new()).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            artifacts(&state, &bearer("wrong")).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(artifacts(&state, &bearer("verifier")).is_ok());
+        assert!(artifacts(&state, &bearer("admin")).is_ok());
+        // The verifier token does not open the admin API.
+        let admin = Access::Require(Scope::AdminState);
+        assert!(
+            state
+                .authorize(&bearer("verifier"), "/admin/maintenance", admin)
+                .is_err()
+        );
+        assert_eq!(state.auth.audit_log().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn healthz_is_open_when_probes_need_a_key() {
+        let keys = std::env::temp_dir().join(format!(
+            "phala-avs-healthz-keys-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&keys, "[]").unwrap();
+        let state = StatusState::new(StartupStatus::default()).with_auth(Arc::new(
+            ApiAuth::new(ApiAuthConfig {
+                keys_file: Some(keys),
+                admin_token: Some("admin".to_string()),
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        let addr = spawn_status_server("127.0.0.1:0".parse().unwrap(), state)
+            .await
+            .unwrap();
+        let get = |path: &str| reqwest::get(format!("http://{addr}{path}"));
+        let healthz = get("/healthz").await.unwrap();
+        assert_eq!(healthz.status(), reqwest::StatusCode::OK);
+        assert_eq!(healthz.text().await.unwrap(), "ok");
+        let readyz = get("/readyz").await.unwrap();
+        assert_eq!(readyz.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn readyz_names_the_failing_contributors() {
+        use crate::startup::{STATUS, Stage, StartupOrchestrator, TEE};
+        use std::time::Duration;
+
+        let plan = vec![
+            Stage::required(STATUS, &[], Duration::from_secs(1)),
+            Stage::optional(TEE, &[STATUS], Duration::from_secs(1)),
+        ];
+        let orchestrator = StartupOrchestrator::new(plan, StartupStatus::default());
+        let state = StatusState::new(orchestrator.status().clone());
+        // Its dependency is not ready yet, so the TEE degrades.
+        orchestrator.run(TEE, async { Ok(()) }).await.unwrap();
+
+        let (code, Json(body)) = readyz(State(state.clone())).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        let body = serde_json::to_value(&body).unwrap();
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["failing"], serde_json::json!(["status", "tee"]));
+        assert_eq!(body["reasons"], serde_json::json!(["status: not started"]));
+
+        orchestrator.run(STATUS, async { Ok(()) }).await.unwrap();
+        let (code, Json(body)) = readyz(State(state)).await;
+        assert_eq!(code, StatusCode::OK);
+        assert!(body.ready && body.degraded);
+        assert_eq!(body.failing, ["tee"]);
+        let tee = body.contributors.iter().find(|c| c.name == TEE).unwrap();
+        assert_eq!(tee.criticality, crate::health::Criticality::DegradedOk);
+        assert!(tee.detail.as_deref().unwrap().contains("dependency status"));
+    }
+}
@@ -0,0 +1,137 @@
+//! Restartable job producers.
+//!
+//! A producer handed to the runner cannot be replaced once the runner owns it. The supervisor
+//! instead drives the producer in its own task and forwards its items through a channel, so the
+//! runner keeps the same [`SupervisedProducer`] while the producer behind it is recreated, either
+//! on [`ProducerSupervisor::restart`] or when the producer stream ends.
+
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use crate::retry::{RetryPolicy, retry_with};
+use futures::{Stream, StreamExt};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{Notify, mpsc};
+use tracing::{error, warn};
+
+/// Counter of producer restarts, by producer.
+pub const PRODUCER_RESTARTS_METRIC: &str = "phala_avs_producer_restarts_total";
+
+/// Retry policy of recreating a producer, overridden by `RETRY_PRODUCER_RECREATE_*`.
+pub const RECREATE_RETRY_SITE: &str = "PRODUCER_RECREATE";
+
+/// Retries a failed producer creation until it succeeds, whatever the error: a producer that
+/// stays down stops the operator from seeing new tasks.
+fn recreate_policy() -> RetryPolicy {
+    RetryPolicy::from_config_or(
+        RECREATE_RETRY_SITE,
+        RetryPolicy {
+            max_attempts: 0,
+            base_delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(60),
+            ..RetryPolicy::default()
+        }
+        .with_classifier(|_| true),
+    )
+}
+
+/// Restart control of one supervised producer.
+#[derive(Debug)]
+pub struct ProducerSupervisor {
+    name: &'static str,
+    restart: Notify,
+    restarts: AtomicU64,
+}
+
+impl ProducerSupervisor {
+    pub fn new(name: &'static str) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            restart: Notify::new(),
+            restarts: AtomicU64::new(0),
+        })
+    }
+
+    /// Asks for the producer to be recreated. Requests made while one is pending coalesce.
+    pub fn restart(&self) {
+        self.restart.notify_one();
+    }
+
+    /// How many times the producer has been recreated.
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::SeqCst)
+    }
+
+    /// Drives `initial`, recreating it with `factory` on restart or when it ends.
+    pub fn supervise<T, S, F, Fut>(
+        self: &Arc<Self>,
+        initial: S,
+        mut factory: F,
+    ) -> SupervisedProducer<T>
+    where
+        T: Send + 'static,
+        S: Stream<Item = T> + Send + 'static,
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<S, PhalaAvsError>> + Send,
+    {
+        let (tx, rx) = mpsc::channel(16);
+        let supervisor = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut producer = Box::pin(initial);
+            loop {
+                loop {
+                    tokio::select! {
+                        item = producer.next() => match item {
+                            Some(item) => {
+                                if tx.send(item).await.is_err() {
+                                    // The runner is gone.
+                                    return;
+                                }
+                            }
+                            None => {
+                                warn!("Producer {} ended; recreating it", supervisor.name);
+                                break;
+                            }
+                        },
+                        _ = supervisor.restart.notified() => {
+                            warn!("Restarting producer {}", supervisor.name);
+                            break;
+                        }
+                    }
+                }
+                producer = loop {
+                    match retry_with(&recreate_policy(), supervisor.name, None, &mut factory).await
+                    {
+                        Ok(producer) => break Box::pin(producer),
+                        Err(e) => error!("Failed to recreate producer {}: {e}", supervisor.name),
+                    }
+                };
+                supervisor.restarts.fetch_add(1, Ordering::SeqCst);
+                METRICS.inc_counter(
+                    PRODUCER_RESTARTS_METRIC,
+                    &[("producer", supervisor.name)],
+                    1,
+                );
+            }
+        });
+        SupervisedProducer { rx }
+    }
+}
+
+/// The stream handed to the runner in place of a supervised producer.
+#[derive(Debug)]
+pub struct SupervisedProducer<T> {
+    rx: mpsc::Receiver<T>,
+}
+
+impl<T> Stream for SupervisedProducer<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.rx.poll_recv(cx)
+    }
+}
@@ -1,41 +0,0 @@
-use crate::error::PhalaAvsError;
-use tracing::info;
-
-/// Placeholder for handling interactions with the Phala TEE Cloud software.
-///
-/// This might involve:
-/// - Verifying TEE attestations.
-/// - Communicating with the local TEE service to manage workloads.
-/// - Querying TEE status for SLA checks.
-#[derive(Clone, Debug)] // Debug for now, remove if it contains sensitive data
-pub struct TeeHandler {
-    // Add fields needed for TEE interaction, e.g.:
-    // - TEE communication endpoint
-    // - Attestation verification keys/config
-    // ...
-}
-
-impl TeeHandler {
-    /// Creates a new TeeHandler.
-    pub async fn new() -> Result<Self, PhalaAvsError> {
-        info!("Initializing TEE Handler (Placeholder)");
-        // TODO: Implement actual TEE connection/setup logic here.
-        Ok(Self {})
-    }
-
-    /// Placeholder function to simulate checking TEE/node liveness.
-    ///
-    /// In a real implementation, this would interact with the TEE
-    /// or the node management system to confirm availability.
-    pub async fn check_liveness(&self) -> Result<bool, PhalaAvsError> {
-        info!("Checking TEE liveness (Placeholder)");
-        // TODO: Implement actual liveness check logic
-        // For now, assume it's always live.
-        Ok(true)
-    }
-
-    // TODO: Add other methods as needed, e.g.:
-    // - `verify_attestation(...)`
-    // - `deploy_workload(...)`
-    // - `get_workload_status(...)`
-}
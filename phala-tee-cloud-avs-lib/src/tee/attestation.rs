@@ -0,0 +1,466 @@
+//! Platform-specific extraction of what a quote attests.
+//!
+//! Each [`TeePlatform`] has an [`AttestationVerifier`] that parses its quote format into a
+//! common [`AttestationReport`]: the platform's measurement registers, the digest allow-listed
+//! in the oracle's policy, and the report data, along with the header, the body's TCB fields and
+//! the certification data of the signature. [`crate::preflight`] picks the verifier from the
+//! quote header, so a response is checked under the flow that produced it.
+//!
+//! Every length is checked against the quote before it is read: a quote shorter than its
+//! declared lengths, or certification data that does not fill what it claims, is rejected.
+//! Reports serialize to JSON for logs and posts; [`AttestationReport::summary`] is one line.
+
+use super::TeeHandler;
+use super::platform::TeePlatform;
+use super::quote::{
+    HEADER_LEN, QuoteHeader, SGX_BODY_LEN, SgxQuote, SignatureData, TDX_BODY_LEN, TdxQuote,
+};
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::primitives::{B256, Bytes, FixedBytes};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// TDATTRIBUTES within a TD quote body.
+const TDX_ATTRIBUTES: Range<usize> = 120..128;
+/// ATTRIBUTES within an SGX report body.
+const SGX_ATTRIBUTES: Range<usize> = 48..64;
+
+/// The measurement registers of a quote.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "platform", rename_all = "lowercase")]
+pub enum Measurements {
+    Tdx {
+        mr_td: FixedBytes<48>,
+        rtmrs: [FixedBytes<48>; 4],
+    },
+    Sgx {
+        mr_enclave: B256,
+        mr_signer: B256,
+        isv_prod_id: u16,
+        isv_svn: u16,
+    },
+}
+
+impl Measurements {
+    pub fn platform(&self) -> TeePlatform {
+        match self {
+            Measurements::Tdx { .. } => TeePlatform::Tdx,
+            Measurements::Sgx { .. } => TeePlatform::Sgx,
+        }
+    }
+}
+
+/// The header of a quote, common to both platforms.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportHeader {
+    pub version: u16,
+    pub attestation_key_type: u16,
+    pub tee_type: u32,
+    /// Reserved, and zero, in TDX quotes.
+    pub qe_svn: u16,
+    pub pce_svn: u16,
+    pub qe_vendor_id: FixedBytes<16>,
+    pub user_data: FixedBytes<20>,
+}
+
+impl ReportHeader {
+    /// The header of `quote`, at least [`HEADER_LEN`] bytes long.
+    fn read(quote: &[u8]) -> Self {
+        let u16_at = |offset: usize| u16::from_le_bytes([quote[offset], quote[offset + 1]]);
+        Self {
+            version: u16_at(0),
+            attestation_key_type: u16_at(2),
+            tee_type: u32::from_le_bytes(quote[4..8].try_into().unwrap()),
+            qe_svn: u16_at(8),
+            pce_svn: u16_at(10),
+            qe_vendor_id: FixedBytes::from_slice(&quote[12..28]),
+            user_data: FixedBytes::from_slice(&quote[28..HEADER_LEN]),
+        }
+    }
+}
+
+/// The TCB fields of a quote body, besides its measurements.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportBody {
+    /// TEE_TCB_SVN of a TDX quote, CPUSVN of an SGX quote.
+    pub tcb_svn: FixedBytes<16>,
+    /// TDATTRIBUTES (8 bytes) of a TDX quote, ATTRIBUTES (16 bytes) of an SGX quote.
+    pub attributes: Bytes,
+}
+
+/// What a quote's signature data carries.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CertificationData {
+    /// ECDSA signature of the header and body by the attestation key.
+    pub quote_signature: FixedBytes<64>,
+    pub attestation_key: FixedBytes<64>,
+    /// The quoting enclave's report, binding the attestation key.
+    pub qe_report: Bytes,
+    pub qe_report_signature: FixedBytes<64>,
+    pub qe_auth_data: Bytes,
+    /// The PCK certificate chain, in PEM.
+    pub pck_chain: String,
+}
+
+impl CertificationData {
+    fn new(data: SignatureData<'_>) -> Self {
+        let pck_chain = String::from_utf8_lossy(data.pck_chain);
+        Self {
+            quote_signature: FixedBytes::from_slice(data.quote_signature),
+            attestation_key: FixedBytes::from_slice(data.attestation_key),
+            qe_report: Bytes::copy_from_slice(data.qe_report),
+            qe_report_signature: FixedBytes::from_slice(data.qe_signature),
+            qe_auth_data: Bytes::copy_from_slice(data.qe_auth_data),
+            pck_chain: pck_chain.trim_end_matches('\0').to_string(),
+        }
+    }
+
+    /// Certificates in the PCK chain.
+    pub fn pck_certificates(&self) -> usize {
+        self.pck_chain
+            .matches("-----BEGIN CERTIFICATE-----")
+            .count()
+    }
+}
+
+/// What a parsed quote attests.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttestationReport {
+    pub platform: TeePlatform,
+    /// The digest of the measurements the oracle's policy allow-lists.
+    pub measurement: B256,
+    pub measurements: Measurements,
+    pub report_data: Bytes,
+    #[serde(default)]
+    pub header: ReportHeader,
+    #[serde(default)]
+    pub body: ReportBody,
+    /// Absent from quotes with empty signature data, such as test fixtures.
+    #[serde(default)]
+    pub certification: Option<CertificationData>,
+}
+
+impl AttestationReport {
+    /// Parses a quote of either platform, as told by its header; see [`parse_quote`].
+    pub fn parse(quote: &[u8]) -> Result<Self, PhalaAvsError> {
+        parse_quote(quote)
+    }
+
+    /// The report of `quote`, whose body the platform's quote type has already checked to
+    /// be `body_len` bytes and parsed into the rest.
+    fn new(
+        quote: &[u8],
+        platform: TeePlatform,
+        measurement: B256,
+        measurements: Measurements,
+        report_data: &[u8; 64],
+    ) -> Result<Self, PhalaAvsError> {
+        let (body_len, attributes) = match platform {
+            TeePlatform::Tdx => (TDX_BODY_LEN, TDX_ATTRIBUTES),
+            TeePlatform::Sgx => (SGX_BODY_LEN, SGX_ATTRIBUTES),
+        };
+        let body = &quote[HEADER_LEN..HEADER_LEN + body_len];
+        let signature_data = &quote[HEADER_LEN + body_len + 4..];
+        let certification = match signature_data {
+            [] => None,
+            data => Some(CertificationData::new(SignatureData::parse(
+                data, platform,
+            )?)),
+        };
+        Ok(Self {
+            platform,
+            measurement,
+            measurements,
+            report_data: Bytes::copy_from_slice(report_data),
+            header: ReportHeader::read(quote),
+            body: ReportBody {
+                tcb_svn: FixedBytes::from_slice(&body[..16]),
+                attributes: Bytes::copy_from_slice(&body[attributes]),
+            },
+            certification,
+        })
+    }
+
+    /// One line for logs: the platform and version, the registers, the report data and the
+    /// PCK chain, with long values shortened.
+    pub fn summary(&self) -> String {
+        let registers = match &self.measurements {
+            Measurements::Tdx { mr_td, rtmrs } => format!(
+                "mr_td={} rtmr0={} rtmr3={}",
+                short(mr_td.as_slice()),
+                short(rtmrs[0].as_slice()),
+                short(rtmrs[3].as_slice())
+            ),
+            Measurements::Sgx {
+                mr_enclave,
+                mr_signer,
+                isv_prod_id,
+                isv_svn,
+            } => format!(
+                "mr_enclave={} mr_signer={} isv_prod_id={isv_prod_id} isv_svn={isv_svn}",
+                short(mr_enclave.as_slice()),
+                short(mr_signer.as_slice())
+            ),
+        };
+        let certification = match &self.certification {
+            Some(data) => format!("pck_chain={} certs", data.pck_certificates()),
+            None => "no certification data".to_string(),
+        };
+        format!(
+            "{} v{} {registers} report_data={} tcb_svn={} {certification}",
+            self.platform,
+            self.header.version,
+            short(&self.report_data),
+            short(self.body.tcb_svn.as_slice())
+        )
+    }
+}
+
+/// `bytes` in hex, keeping the first and last four of longer values.
+fn short(bytes: &[u8]) -> String {
+    match bytes.len() {
+        0..=8 => format!("0x{}", hex::encode(bytes)),
+        len => format!(
+            "0x{}..{}",
+            hex::encode(&bytes[..4]),
+            hex::encode(&bytes[len - 4..])
+        ),
+    }
+}
+
+/// Parses the quotes of one platform.
+pub trait AttestationVerifier: Send + Sync {
+    fn platform(&self) -> TeePlatform;
+
+    fn parse(&self, quote: &[u8]) -> Result<AttestationReport, PhalaAvsError>;
+}
+
+/// TDX quotes: MRTD and RTMRs, with the MRTD allow-listed.
+pub struct TdxVerifier;
+
+impl AttestationVerifier for TdxVerifier {
+    fn platform(&self) -> TeePlatform {
+        TeePlatform::Tdx
+    }
+
+    fn parse(&self, quote: &[u8]) -> Result<AttestationReport, PhalaAvsError> {
+        let parsed = TdxQuote::parse(quote)?;
+        AttestationReport::new(
+            quote,
+            TeePlatform::Tdx,
+            parsed.measurement(),
+            Measurements::Tdx {
+                mr_td: parsed.mr_td.into(),
+                rtmrs: parsed.rtmrs.map(FixedBytes::from),
+            },
+            &parsed.report_data,
+        )
+    }
+}
+
+/// SGX quotes: MRENCLAVE, MRSIGNER and the ISV SVN, all three allow-listed together.
+pub struct SgxVerifier;
+
+impl AttestationVerifier for SgxVerifier {
+    fn platform(&self) -> TeePlatform {
+        TeePlatform::Sgx
+    }
+
+    fn parse(&self, quote: &[u8]) -> Result<AttestationReport, PhalaAvsError> {
+        let parsed = SgxQuote::parse(quote)?;
+        AttestationReport::new(
+            quote,
+            TeePlatform::Sgx,
+            parsed.measurement(),
+            Measurements::Sgx {
+                mr_enclave: parsed.mr_enclave.into(),
+                mr_signer: parsed.mr_signer.into(),
+                isv_prod_id: parsed.isv_prod_id,
+                isv_svn: parsed.isv_svn,
+            },
+            &parsed.report_data,
+        )
+    }
+}
+
+pub fn verifier_for(platform: TeePlatform) -> &'static dyn AttestationVerifier {
+    match platform {
+        TeePlatform::Tdx => &TdxVerifier,
+        TeePlatform::Sgx => &SgxVerifier,
+    }
+}
+
+/// Parses a quote of either platform, as told by its header.
+pub fn parse_quote(quote: &[u8]) -> Result<AttestationReport, PhalaAvsError> {
+    let header = QuoteHeader::parse(quote)?;
+    let platform = header.platform().ok_or_else(|| {
+        PhalaAvsError::ValidationError(format!(
+            "Invalid quote: version {} with TEE type {:#x} is neither TDX nor SGX",
+            header.version, header.tee_type
+        ))
+    })?;
+    verifier_for(platform).parse(quote)
+}
+
+impl TeeHandler {
+    /// The verifier of the quotes this host produces.
+    pub fn attestation_verifier(&self) -> &'static dyn AttestationVerifier {
+        verifier_for(self.platform)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{QuoteFixture, SGX_MR_ENCLAVE, SGX_MR_SIGNER, TDX_MR_TD};
+    use blueprint_sdk::alloy::primitives::keccak256;
+    use std::path::PathBuf;
+
+    fn fixture(path: &str) -> Vec<u8> {
+        std::fs::read(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(path),
+        )
+        .unwrap()
+    }
+
+    /// The signed and certified TDX quote of the DCAP tests.
+    fn tdx_quote() -> Vec<u8> {
+        fixture("dcap/tdx_quote.bin")
+    }
+
+    /// An SGX quote of [`SGX_MR_ENCLAVE`] and [`SGX_MR_SIGNER`] at product 1, SVN 2, over
+    /// "phala-avs sgx quote fixture", with placeholder signatures and a real PCK chain.
+    fn sgx_quote() -> Vec<u8> {
+        fixture("quotes/sgx_quote.bin")
+    }
+
+    #[test]
+    fn each_platform_quote_is_parsed_by_its_verifier() {
+        let tdx = QuoteFixture::tdx().report_data([3; 64]).build();
+        let report = parse_quote(&tdx).unwrap();
+        assert_eq!(report.platform, TeePlatform::Tdx);
+        assert_eq!(report.measurement, keccak256(TDX_MR_TD));
+        let Measurements::Tdx { mr_td, rtmrs } = &report.measurements else {
+            panic!("expected TDX measurements, got {:?}", report.measurements);
+        };
+        assert_eq!(mr_td.as_slice(), TDX_MR_TD);
+        assert_eq!(rtmrs[3], FixedBytes::repeat_byte(0x34));
+        assert_eq!(report.report_data.as_ref(), [3; 64]);
+
+        let sgx = QuoteFixture::sgx().isv_svn(9).build();
+        let report = parse_quote(&sgx).unwrap();
+        assert_eq!(report.platform, TeePlatform::Sgx);
+        assert_eq!(report.measurements, Measurements::Sgx {
+            mr_enclave: SGX_MR_ENCLAVE.into(),
+            mr_signer: SGX_MR_SIGNER.into(),
+            isv_prod_id: 1,
+            isv_svn: 9,
+        });
+        // The SVN is part of what is allow-listed.
+        let older = parse_quote(&QuoteFixture::sgx().isv_svn(8).build()).unwrap();
+        assert_ne!(report.measurement, older.measurement);
+
+        // Verifiers reject the other platform's quotes.
+        assert!(TdxVerifier.parse(&sgx).is_err());
+        assert!(SgxVerifier.parse(&tdx).is_err());
+        assert_eq!(verifier_for(TeePlatform::Sgx).platform(), TeePlatform::Sgx);
+    }
+
+    #[test]
+    fn certified_quotes_are_parsed_in_full() {
+        let report = AttestationReport::parse(&tdx_quote()).unwrap();
+        assert_eq!(report.platform, TeePlatform::Tdx);
+        assert_eq!(report.header.version, 4);
+        assert_eq!(report.header.attestation_key_type, 2);
+        assert_eq!(report.header.tee_type, 0x81);
+        assert_eq!(report.header.pce_svn, 13);
+        assert_eq!(
+            hex::encode(report.header.qe_vendor_id),
+            "939a7233f79c4ca9940a0db3957f0607"
+        );
+        assert_eq!(report.body.tcb_svn[..3], [5, 0, 2]);
+        assert_eq!(report.body.attributes.len(), 8);
+        let certification = report.certification.as_ref().unwrap();
+        assert_eq!(certification.qe_report.len(), 384);
+        assert_eq!(certification.pck_certificates(), 3);
+        assert!(
+            certification
+                .pck_chain
+                .ends_with("-----END CERTIFICATE-----\n")
+        );
+
+        let report = AttestationReport::parse(&sgx_quote()).unwrap();
+        assert_eq!(report.measurements, Measurements::Sgx {
+            mr_enclave: SGX_MR_ENCLAVE.into(),
+            mr_signer: SGX_MR_SIGNER.into(),
+            isv_prod_id: 1,
+            isv_svn: 2,
+        });
+        assert!(
+            report
+                .report_data
+                .starts_with(b"phala-avs sgx quote fixture")
+        );
+        assert_eq!((report.header.version, report.header.qe_svn), (3, 8));
+        assert_eq!(report.body.tcb_svn[0], 1);
+        assert_eq!(report.body.attributes.len(), 16);
+        let certification = report.certification.as_ref().unwrap();
+        assert_eq!(certification.quote_signature, FixedBytes::repeat_byte(0x11));
+        assert_eq!(certification.attestation_key, FixedBytes::repeat_byte(0x22));
+        assert_eq!(
+            certification.qe_report_signature,
+            FixedBytes::repeat_byte(0x44)
+        );
+        assert_eq!(certification.qe_auth_data.len(), 32);
+        assert_eq!(certification.pck_certificates(), 3);
+        assert_eq!(
+            report.summary(),
+            "sgx v3 mr_enclave=0xe1e1e1e1..e1e1e1e1 mr_signer=0x51515151..51515151 \
+             isv_prod_id=1 isv_svn=2 report_data=0x7068616c..00000000 \
+             tcb_svn=0x01020304..0d0e0f10 pck_chain=3 certs"
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["header"]["qe_svn"], 8);
+        assert_eq!(
+            json["certification"]["qe_auth_data"]
+                .as_str()
+                .unwrap()
+                .len(),
+            66
+        );
+        let uncertified = parse_quote(&QuoteFixture::tdx().build()).unwrap();
+        assert_eq!(uncertified.certification, None);
+        assert!(uncertified.summary().ends_with("no certification data"));
+    }
+
+    #[test]
+    fn quotes_shorter_than_their_declared_lengths_are_rejected() {
+        for quote in [tdx_quote(), sgx_quote(), QuoteFixture::sgx().build()] {
+            for len in 0..quote.len() {
+                assert!(
+                    AttestationReport::parse(&quote[..len]).is_err(),
+                    "a quote truncated to {len} bytes parsed"
+                );
+            }
+        }
+
+        // Signature data cut short, with its length field and the PCK chain's lowered to match.
+        let mut quote = sgx_quote();
+        let signature_len = HEADER_LEN + SGX_BODY_LEN;
+        quote.truncate(quote.len() - 100);
+        let shortened = (quote.len() - signature_len - 4) as u32;
+        quote[signature_len..signature_len + 4].copy_from_slice(&shortened.to_le_bytes());
+        let err = AttestationReport::parse(&quote).unwrap_err();
+        assert!(err.to_string().contains("claims"), "{err}");
+
+        // An authentication data length running past the end.
+        let mut quote = sgx_quote();
+        let auth_len = signature_len + 4 + 64 + 64 + 384 + 64;
+        quote[auth_len..auth_len + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        let err = AttestationReport::parse(&quote).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{err}");
+    }
+}
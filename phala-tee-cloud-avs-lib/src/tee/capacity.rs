@@ -0,0 +1,108 @@
+//! TEE resources of this host, as reported by the dstack host API.
+
+pub use super::platform::TeePlatform;
+
+use super::TeeHandler;
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::sanitize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resources {
+    pub vcpus: u32,
+    pub memory_mb: u64,
+    pub storage_gb: u64,
+}
+
+impl Resources {
+    pub fn saturating_sub(self, other: Resources) -> Resources {
+        Resources {
+            vcpus: self.vcpus.saturating_sub(other.vcpus),
+            memory_mb: self.memory_mb.saturating_sub(other.memory_mb),
+            storage_gb: self.storage_gb.saturating_sub(other.storage_gb),
+        }
+    }
+
+    pub fn saturating_add(self, other: Resources) -> Resources {
+        Resources {
+            vcpus: self.vcpus.saturating_add(other.vcpus),
+            memory_mb: self.memory_mb.saturating_add(other.memory_mb),
+            storage_gb: self.storage_gb.saturating_add(other.storage_gb),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TeeCapacity {
+    pub total: Resources,
+    /// What is not taken by running workloads.
+    pub available: Resources,
+    pub platform: TeePlatform,
+}
+
+/// The local dstack host API.
+pub trait HostApi: Send + Sync + fmt::Debug {
+    fn capacity(&self) -> BoxFuture<'_, Result<TeeCapacity, PhalaAvsError>>;
+}
+
+/// [`HostApi`] reading `<url>/capacity`.
+#[derive(Clone, Debug)]
+pub struct HttpHostApi {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpHostApi {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl HostApi for HttpHostApi {
+    fn capacity(&self) -> BoxFuture<'_, Result<TeeCapacity, PhalaAvsError>> {
+        Box::pin(async move {
+            self.client
+                .get(format!("{}/capacity", self.url.trim_end_matches('/')))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| {
+                    PhalaAvsError::TeeError(format!(
+                        "Host capacity query failed: {}",
+                        sanitize::message("tee_reply", e)
+                    ))
+                })?
+                .json()
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::TeeError(format!(
+                        "Invalid host capacity reply: {}",
+                        sanitize::message("tee_reply", e)
+                    ))
+                })
+        })
+    }
+}
+
+impl TeeHandler {
+    /// Queries `host` for this host's resources.
+    pub fn with_host(mut self, host: Arc<dyn HostApi>) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// Total and available resources of this host.
+    pub async fn get_capacity(&self) -> Result<TeeCapacity, PhalaAvsError> {
+        self.inject_faults().await?;
+        let host = self.host.as_ref().ok_or_else(|| {
+            PhalaAvsError::TeeError("No dstack host API is configured (TEE_HOST_URL)".to_string())
+        })?;
+        host.capacity().await
+    }
+}
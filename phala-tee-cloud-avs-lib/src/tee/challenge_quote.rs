@@ -0,0 +1,195 @@
+//! Quotes bound to one SLA challenge, so an old quote cannot be replayed as a fresh answer.
+//!
+//! [`TeeHandler::generate_challenge_quote`] has tappd quote over the report data of a
+//! [`ChallengeBinding`]: the challenge id and nonce, the operator answering and when it quoted.
+//! The bundle it returns carries the binding, so whoever checks the answer can recompute the
+//! report data from the same fields with [`verify_challenge_quote`] rather than trust it. The
+//! report data is `keccak256(abi.encode(challengeId, nonce, operator, quotedAtUnix))` followed by
+//! 32 zero bytes, which a contract can recompute as well.
+
+use super::TeeHandler;
+use super::attestation::{AttestationReport, parse_quote};
+use super::quote::QuoteBundle;
+use crate::display::Addr;
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use blueprint_sdk::alloy::primitives::{Address, B256, U256, keccak256};
+use blueprint_sdk::alloy::sol_types::SolValue;
+use serde::{Deserialize, Serialize};
+
+/// The fields a challenge quote's report data is the hash of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChallengeBinding {
+    pub challenge_id: U256,
+    pub nonce: B256,
+    /// The operator the quote answers for.
+    pub operator: Address,
+    pub quoted_at_unix: u64,
+}
+
+impl ChallengeBinding {
+    /// The report data of quotes bound to these fields.
+    pub fn report_data(&self) -> [u8; 64] {
+        let digest = keccak256(
+            (
+                self.challenge_id,
+                self.nonce,
+                self.operator,
+                self.quoted_at_unix,
+            )
+                .abi_encode(),
+        );
+        let mut report_data = [0u8; 64];
+        report_data[..32].copy_from_slice(digest.as_slice());
+        report_data
+    }
+}
+
+/// Checks that `quote` is bound to `binding`, returning what it attests. Whether its
+/// measurements are acceptable and it is recent enough is left to the caller.
+pub fn verify_challenge_quote(
+    quote: &[u8],
+    binding: &ChallengeBinding,
+) -> Result<AttestationReport, PhalaAvsError> {
+    let report = parse_quote(quote)?;
+    if report.report_data[..] != binding.report_data() {
+        return Err(PhalaAvsError::ValidationError(format!(
+            "The quote is not bound to challenge {} of operator {} with nonce {} quoted at {}",
+            binding.challenge_id,
+            Addr(binding.operator),
+            binding.nonce,
+            binding.quoted_at_unix
+        )));
+    }
+    Ok(report)
+}
+
+impl TeeHandler {
+    /// Binds challenge quotes to `operator`.
+    pub fn with_operator(mut self, operator: Address) -> Self {
+        self.operator = Some(operator);
+        self
+    }
+
+    /// A quote bound to challenge `challenge_id` and its `nonce`, for the operator and now.
+    pub async fn generate_challenge_quote(
+        &self,
+        challenge_id: U256,
+        nonce: B256,
+    ) -> Result<QuoteBundle, PhalaAvsError> {
+        let operator = self.operator.ok_or_else(|| {
+            PhalaAvsError::TeeError(
+                "Challenge quotes need the operator address the handler answers for".to_string(),
+            )
+        })?;
+        let binding = ChallengeBinding {
+            challenge_id,
+            nonce,
+            operator,
+            quoted_at_unix: now_unix_ms() / 1000,
+        };
+        let bundle = self.quote_bundle(binding.report_data()).await?;
+        Ok(QuoteBundle {
+            challenge: Some(binding),
+            ..bundle
+        })
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::tee::quote::{TDX_TEE_TYPE, TdxQuote};
+    use crate::tee::{TeeEndpoint, TeeHandlerConfig};
+    use axum::Router;
+    use axum::routing::post;
+
+    const OPERATOR: Address = Address::repeat_byte(1);
+
+    /// A tappd quoting over whatever report data it is asked for.
+    async fn quoting_tappd() -> TeeHandler {
+        let app = Router::new().route(
+            "/prpc/Tappd.TdxQuote",
+            post(|body: String| async move {
+                let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+                let report_data = hex::decode(request["report_data"].as_str().unwrap()).unwrap();
+                let quote = TdxQuote {
+                    version: 4,
+                    tee_type: TDX_TEE_TYPE,
+                    mr_td: [7; 48],
+                    rtmrs: [[1; 48], [2; 48], [3; 48], [4; 48]],
+                    report_data: report_data.try_into().unwrap(),
+                };
+                serde_json::json!({ "quote": hex::encode(quote.to_bytes()) }).to_string()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        TeeHandler::new(TeeHandlerConfig {
+            endpoint: TeeEndpoint::parse(&format!("http://{addr}")).unwrap(),
+            ..TeeHandlerConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn challenge_quotes_verify_against_their_binding() {
+        let tee = quoting_tappd().await.with_operator(OPERATOR);
+        let before = now_unix_ms() / 1000;
+        let bundle = tee
+            .generate_challenge_quote(U256::from(7), B256::repeat_byte(9))
+            .await
+            .unwrap();
+        let binding = bundle.challenge.unwrap();
+        assert_eq!(binding.challenge_id, U256::from(7));
+        assert_eq!(binding.nonce, B256::repeat_byte(9));
+        assert_eq!(binding.operator, OPERATOR);
+        assert!(binding.quoted_at_unix >= before);
+        let report = verify_challenge_quote(&bundle.raw, &binding).unwrap();
+        assert_eq!(report.report_data[..], binding.report_data());
+    }
+
+    #[tokio::test]
+    async fn altering_any_bound_field_fails_verification() {
+        let tee = quoting_tappd().await.with_operator(OPERATOR);
+        let bundle = tee
+            .generate_challenge_quote(U256::from(7), B256::repeat_byte(9))
+            .await
+            .unwrap();
+        let binding = bundle.challenge.unwrap();
+        let altered = [
+            ChallengeBinding {
+                challenge_id: U256::from(8),
+                ..binding
+            },
+            ChallengeBinding {
+                nonce: B256::repeat_byte(10),
+                ..binding
+            },
+            ChallengeBinding {
+                operator: Address::repeat_byte(2),
+                ..binding
+            },
+            ChallengeBinding {
+                quoted_at_unix: binding.quoted_at_unix - 1,
+                ..binding
+            },
+        ];
+        for altered in altered {
+            let err = verify_challenge_quote(&bundle.raw, &altered).unwrap_err();
+            assert!(err.to_string().contains("not bound"), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn challenge_quotes_need_the_operator() {
+        let tee = quoting_tappd().await;
+        assert!(matches!(
+            tee.generate_challenge_quote(U256::from(7), B256::ZERO)
+                .await,
+            Err(PhalaAvsError::TeeError(_))
+        ));
+    }
+}
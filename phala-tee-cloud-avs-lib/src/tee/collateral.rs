@@ -0,0 +1,1474 @@
+//! DCAP collateral of this host's platform, and the TCB status it gives the platform.
+//!
+//! A quote is verified against the TCB info and QE identity Intel publishes for the platform's
+//! FMSPC, and verifiers stop accepting either after its `nextUpdate`. With
+//! `COLLATERAL_CHECK_ENABLED`, both are fetched every `COLLATERAL_CHECK_SECS` from the PCCS at
+//! `COLLATERAL_PCCS_URL`, falling back to Intel's PCS at `COLLATERAL_PCS_URL`, and the time
+//! left until the first of them expires is exported and shown on `/status`. The
+//! [duty calendar](crate::duties) refreshes them, and the quote with them,
+//! `DUTY_COLLATERAL_LEAD_SECS` ahead of that expiry.
+//!
+//! The platform's TCB level is the first level of the TCB info that its SVNs
+//! (`COLLATERAL_CPU_SVN` and `COLLATERAL_PCE_SVN` of the PCK certificate, and for TDX the
+//! quote's `COLLATERAL_TEE_TCB_SVN`) reach. When a TCB recovery leaves the platform at
+//! `SWHardeningNeeded` or worse, a critical alert names the advisories, and
+//! [preflight](crate::preflight) holds back attestation responses: neither attestation schema
+//! can carry the status, so the oracle would reject them.
+//!
+//! Only dates, identities, levels and statuses are read here. [`super::dcap`] verifies quotes
+//! against the collateral; the signatures over the collateral documents are not checked.
+//!
+//! Verifying a quote elsewhere, on chain or at the aggregator, takes the collateral of the
+//! quote's own platform. [`HttpCollateralSource::quote_collateral`] reads the FMSPC and issuing
+//! CA off the quote's PCK certificate and fetches the TCB info, QE identity and CRLs as served,
+//! issuer chains included, so the verifier can check their signatures. They come from the PCCS,
+//! or from the PCS when no PCCS is configured. Collateral is kept per FMSPC for
+//! `COLLATERAL_QUOTE_TTL_SECS`, or until it expires if sooner, and collateral already past its
+//! `nextUpdate` is refused. An [`AttestationPackage`] carries a quote with its collateral as
+//! challenge evidence.
+
+use super::attestation::parse_quote;
+use super::pck;
+use super::platform::TeePlatform;
+use crate::config::{env_flag, env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::notify::{Alert, Notifier, Severity};
+use crate::sanitize;
+use blueprint_sdk::alloy::primitives::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Gauge of the seconds until the collateral expires, by collateral (`tcb_info`, `qe_identity`).
+pub const COLLATERAL_EXPIRY_METRIC: &str = "phala_avs_tee_collateral_expiry_seconds";
+/// Set to 1 for the platform's current TCB status, by platform and status.
+pub const TCB_STATUS_METRIC: &str = "phala_avs_tee_tcb_status";
+/// Counter of collateral fetches, by source (`pccs`, `pcs`) and outcome.
+pub const COLLATERAL_FETCH_METRIC: &str = "phala_avs_tee_collateral_fetch_total";
+
+pub const DEFAULT_PCS_URL: &str = "https://api.trustedservices.intel.com";
+
+/// The SVNs the platform's TCB level is looked up by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlatformTcb {
+    pub cpu_svn: [u8; 16],
+    pub pce_svn: u16,
+    /// TDX only.
+    pub tee_tcb_svn: Option<[u8; 16]>,
+}
+
+impl PlatformTcb {
+    /// Whether every SVN is at least the level's.
+    fn reaches(&self, level: &TcbLevel) -> bool {
+        let at_least = |svns: &[u8; 16], required: &[u8]| {
+            required
+                .iter()
+                .enumerate()
+                .all(|(i, r)| svns.get(i).is_some_and(|svn| svn >= r))
+        };
+        at_least(&self.cpu_svn, &level.sgx_svns)
+            && self.pce_svn >= level.pce_svn
+            && (level.tdx_svns.is_empty()
+                || self
+                    .tee_tcb_svn
+                    .is_some_and(|svns| at_least(&svns, &level.tdx_svns)))
+    }
+
+    /// The platform's status under `collateral`. A platform below every listed level is
+    /// [`TcbStatus::OutOfDate`].
+    pub fn assess(&self, collateral: &Collateral) -> TcbAssessment {
+        let level = collateral.tcb_levels.iter().find(|l| self.reaches(l));
+        TcbAssessment {
+            status: level.map_or(TcbStatus::OutOfDate, |l| l.status),
+            advisory_ids: level.map(|l| l.advisory_ids.clone()).unwrap_or_default(),
+            tcb_evaluation_data_number: collateral.tcb_evaluation_data_number,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CollateralConfig {
+    pub enabled: bool,
+    pub pccs_url: Option<String>,
+    pub pcs_url: String,
+    /// FMSPC of this host's PCK certificate, in hex.
+    pub fmspc: String,
+    pub check_secs: u64,
+    /// How long the collateral of quotes' platforms is kept.
+    pub quote_ttl_secs: u64,
+    /// Unset leaves the TCB status unassessed; expiry is tracked either way.
+    pub platform_tcb: Option<PlatformTcb>,
+}
+
+impl CollateralConfig {
+    /// Reads the `COLLATERAL_*` settings; `COLLATERAL_FMSPC` is required when enabled.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let enabled = env_flag("COLLATERAL_CHECK_ENABLED", false)?;
+        let fmspc: Option<String> = env_opt("COLLATERAL_FMSPC")?;
+        if enabled && fmspc.is_none() {
+            return Err(PhalaAvsError::ConfigError(
+                "COLLATERAL_CHECK_ENABLED requires COLLATERAL_FMSPC".to_string(),
+            ));
+        }
+        let cpu_svn = env_opt::<String>("COLLATERAL_CPU_SVN")?
+            .map(|raw| svns("COLLATERAL_CPU_SVN", &raw))
+            .transpose()?;
+        let tee_tcb_svn = env_opt::<String>("COLLATERAL_TEE_TCB_SVN")?
+            .map(|raw| svns("COLLATERAL_TEE_TCB_SVN", &raw))
+            .transpose()?;
+        let pce_svn: Option<u16> = env_opt("COLLATERAL_PCE_SVN")?;
+        Ok(Self {
+            enabled,
+            pccs_url: env_opt("COLLATERAL_PCCS_URL")?,
+            pcs_url: env_or("COLLATERAL_PCS_URL", DEFAULT_PCS_URL.to_string())?,
+            fmspc: fmspc.unwrap_or_default(),
+            check_secs: env_or("COLLATERAL_CHECK_SECS", 3_600)?.max(60),
+            quote_ttl_secs: env_or("COLLATERAL_QUOTE_TTL_SECS", 3_600)?,
+            platform_tcb: cpu_svn.zip(pce_svn).map(|(cpu_svn, pce_svn)| PlatformTcb {
+                cpu_svn,
+                pce_svn,
+                tee_tcb_svn,
+            }),
+        })
+    }
+}
+
+fn svns(key: &str, raw: &str) -> Result<[u8; 16], PhalaAvsError> {
+    hex::decode(raw.trim().trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| PhalaAvsError::ConfigError(format!("{key} must be 16 bytes of hex")))
+}
+
+/// Intel's TCB statuses, from best to worst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TcbStatus {
+    UpToDate,
+    #[serde(rename = "SWHardeningNeeded")]
+    SwHardeningNeeded,
+    ConfigurationNeeded,
+    #[serde(rename = "ConfigurationAndSWHardeningNeeded")]
+    ConfigurationAndSwHardeningNeeded,
+    OutOfDate,
+    OutOfDateConfigurationNeeded,
+    Revoked,
+}
+
+impl TcbStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TcbStatus::UpToDate => "UpToDate",
+            TcbStatus::SwHardeningNeeded => "SWHardeningNeeded",
+            TcbStatus::ConfigurationNeeded => "ConfigurationNeeded",
+            TcbStatus::ConfigurationAndSwHardeningNeeded => "ConfigurationAndSWHardeningNeeded",
+            TcbStatus::OutOfDate => "OutOfDate",
+            TcbStatus::OutOfDateConfigurationNeeded => "OutOfDateConfigurationNeeded",
+            TcbStatus::Revoked => "Revoked",
+        }
+    }
+
+    /// `SWHardeningNeeded` or worse.
+    pub fn degraded(self) -> bool {
+        self >= TcbStatus::SwHardeningNeeded
+    }
+}
+
+/// One level of the TCB info.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcbLevel {
+    pub sgx_svns: Vec<u8>,
+    pub pce_svn: u16,
+    /// Empty in SGX TCB info.
+    pub tdx_svns: Vec<u8>,
+    pub tcb_date_unix: u64,
+    pub status: TcbStatus,
+    pub advisory_ids: Vec<String>,
+}
+
+/// The platform's TCB status under one TCB info.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcbAssessment {
+    pub status: TcbStatus,
+    /// Advisories of the platform's level.
+    pub advisory_ids: Vec<String>,
+    pub tcb_evaluation_data_number: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollateralOrigin {
+    Pccs,
+    Pcs,
+    /// Read from files shipped with the operator, for offline verification.
+    Bundled,
+}
+
+impl CollateralOrigin {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CollateralOrigin::Pccs => "pccs",
+            CollateralOrigin::Pcs => "pcs",
+            CollateralOrigin::Bundled => "bundled",
+        }
+    }
+}
+
+/// The TCB info and QE identity of a platform, as far as expiry and status go.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Collateral {
+    pub origin: CollateralOrigin,
+    /// FMSPC the TCB info is for, in lowercase hex.
+    pub fmspc: String,
+    pub tcb_info_issued_unix: u64,
+    pub tcb_info_next_update_unix: u64,
+    pub qe_identity_next_update_unix: u64,
+    pub tcb_evaluation_data_number: u32,
+    /// Best first, as published.
+    pub tcb_levels: Vec<TcbLevel>,
+    pub qe_identity: QeIdentity,
+}
+
+/// The quoting enclave Intel vouches for.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QeIdentity {
+    pub mr_signer: [u8; 32],
+    pub isv_prod_id: u16,
+    /// Best first, as published.
+    pub tcb_levels: Vec<QeTcbLevel>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QeTcbLevel {
+    pub isv_svn: u16,
+    pub status: TcbStatus,
+}
+
+impl QeIdentity {
+    /// The status of a quoting enclave at `isv_svn`; [`TcbStatus::OutOfDate`] below every
+    /// listed level.
+    pub fn status(&self, isv_svn: u16) -> TcbStatus {
+        self.tcb_levels
+            .iter()
+            .find(|level| isv_svn >= level.isv_svn)
+            .map_or(TcbStatus::OutOfDate, |level| level.status)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TcbInfoDocument {
+    tcb_info: TcbInfoBody,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TcbInfoBody {
+    issue_date: String,
+    next_update: String,
+    fmspc: String,
+    tcb_evaluation_data_number: u32,
+    tcb_levels: Vec<RawTcbLevel>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawTcbLevel {
+    tcb: RawTcb,
+    tcb_date: String,
+    tcb_status: TcbStatus,
+    #[serde(default, rename = "advisoryIDs")]
+    advisory_ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RawTcb {
+    sgxtcbcomponents: Vec<RawComponent>,
+    pcesvn: u16,
+    #[serde(default)]
+    tdxtcbcomponents: Vec<RawComponent>,
+}
+
+#[derive(Deserialize)]
+struct RawComponent {
+    svn: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QeIdentityDocument {
+    enclave_identity: QeIdentityBody,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QeIdentityBody {
+    next_update: String,
+    mrsigner: String,
+    isvprodid: u16,
+    tcb_levels: Vec<RawQeTcbLevel>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawQeTcbLevel {
+    tcb: RawQeTcb,
+    tcb_status: TcbStatus,
+}
+
+#[derive(Deserialize)]
+struct RawQeTcb {
+    isvsvn: u16,
+}
+
+impl Collateral {
+    /// Reads the PCS v4 `tcbInfo` and `enclaveIdentity` documents.
+    pub fn from_json(
+        tcb_info: &str,
+        qe_identity: &str,
+        origin: CollateralOrigin,
+    ) -> Result<Self, PhalaAvsError> {
+        let invalid = |what: &str, e: serde_json::Error| {
+            PhalaAvsError::TeeError(format!(
+                "Invalid {what} from the {}: {}",
+                origin.as_str(),
+                sanitize::message("collateral", e)
+            ))
+        };
+        let tcb_info: TcbInfoDocument =
+            serde_json::from_str(tcb_info).map_err(|e| invalid("TCB info", e))?;
+        let qe_identity: QeIdentityDocument =
+            serde_json::from_str(qe_identity).map_err(|e| invalid("QE identity", e))?;
+        let svns = |components: &[RawComponent]| components.iter().map(|c| c.svn).collect();
+        let tcb_levels = tcb_info
+            .tcb_info
+            .tcb_levels
+            .iter()
+            .map(|level| {
+                Ok(TcbLevel {
+                    sgx_svns: svns(&level.tcb.sgxtcbcomponents),
+                    pce_svn: level.tcb.pcesvn,
+                    tdx_svns: svns(&level.tcb.tdxtcbcomponents),
+                    tcb_date_unix: parse_timestamp(&level.tcb_date)?,
+                    status: level.tcb_status,
+                    advisory_ids: level.advisory_ids.clone(),
+                })
+            })
+            .collect::<Result<_, PhalaAvsError>>()?;
+        let qe = &qe_identity.enclave_identity;
+        let mr_signer = hex::decode(&qe.mrsigner)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                PhalaAvsError::TeeError(format!(
+                    "Invalid QE identity from the {}: MRSIGNER is not 32 bytes of hex",
+                    origin.as_str()
+                ))
+            })?;
+        Ok(Self {
+            origin,
+            fmspc: tcb_info.tcb_info.fmspc.to_ascii_lowercase(),
+            tcb_info_issued_unix: parse_timestamp(&tcb_info.tcb_info.issue_date)?,
+            tcb_info_next_update_unix: parse_timestamp(&tcb_info.tcb_info.next_update)?,
+            qe_identity_next_update_unix: parse_timestamp(
+                &qe_identity.enclave_identity.next_update,
+            )?,
+            tcb_evaluation_data_number: tcb_info.tcb_info.tcb_evaluation_data_number,
+            tcb_levels,
+            qe_identity: QeIdentity {
+                mr_signer,
+                isv_prod_id: qe.isvprodid,
+                tcb_levels: qe
+                    .tcb_levels
+                    .iter()
+                    .map(|level| QeTcbLevel {
+                        isv_svn: level.tcb.isvsvn,
+                        status: level.tcb_status,
+                    })
+                    .collect(),
+            },
+        })
+    }
+
+    /// When the first of the TCB info and QE identity expires.
+    pub fn expires_unix(&self) -> u64 {
+        self.tcb_info_next_update_unix
+            .min(self.qe_identity_next_update_unix)
+    }
+}
+
+/// Seconds since the epoch of a UTC `YYYY-MM-DDTHH:MM:SS[.fff]Z` timestamp.
+pub(super) fn parse_timestamp(raw: &str) -> Result<u64, PhalaAvsError> {
+    let invalid = || {
+        PhalaAvsError::TeeError(format!(
+            "Invalid collateral timestamp {}",
+            sanitize::message("collateral", raw)
+        ))
+    };
+    let raw = raw.strip_suffix('Z').ok_or_else(invalid)?;
+    let raw = raw.split_once('.').map_or(raw, |(whole, _)| whole);
+    let (date, time) = raw.split_once('T').ok_or_else(invalid)?;
+    let numbers = |s: &str, sep: char| -> Option<Vec<u64>> {
+        s.split(sep).map(|part| part.parse().ok()).collect()
+    };
+    let (Some(date), Some(time)) = (numbers(date, '-'), numbers(time, ':')) else {
+        return Err(invalid());
+    };
+    let (&[year, month, day], &[hour, minute, second]) = (&date[..], &time[..]) else {
+        return Err(invalid());
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(invalid());
+    }
+    // Days from the civil date, counting March-based years so leap days fall last.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era)
+        .checked_sub(719_468)
+        .ok_or_else(invalid)?;
+    Ok(days * 86_400 + hour * 3_600 + minute * 60 + second)
+}
+
+/// Where collateral is fetched from.
+pub trait CollateralSource: Send + Sync {
+    fn fetch(&self, platform: TeePlatform) -> BoxFuture<'_, Result<Collateral, PhalaAvsError>>;
+}
+
+/// [`CollateralSource`] over the PCS v4 API, asking the PCCS first when one is configured. It
+/// also fetches and keeps the collateral of quotes' own platforms; see the module docs.
+pub struct HttpCollateralSource {
+    pccs_url: Option<String>,
+    pcs_url: String,
+    fmspc: String,
+    client: reqwest::Client,
+    quote_ttl: Duration,
+    /// The collateral of quotes' platforms.
+    quotes: Mutex<HashMap<CollateralKey, QuoteCollateral>>,
+}
+
+/// A collateral reply: its body and the issuer chain header asked for.
+struct Reply {
+    body: Vec<u8>,
+    issuer_chain: String,
+}
+
+impl Reply {
+    fn text(self, what: &str) -> Result<String, PhalaAvsError> {
+        String::from_utf8(self.body).map_err(|_| {
+            PhalaAvsError::TeeError(format!("Invalid collateral reply: {what} is not UTF-8"))
+        })
+    }
+}
+
+enum FetchError {
+    /// Nothing is served at that path.
+    NotFound,
+    Failed(PhalaAvsError),
+}
+
+impl From<FetchError> for PhalaAvsError {
+    fn from(e: FetchError) -> Self {
+        match e {
+            FetchError::NotFound => {
+                PhalaAvsError::TeeError("Collateral request failed: 404 Not Found".to_string())
+            }
+            FetchError::Failed(e) => e,
+        }
+    }
+}
+
+impl HttpCollateralSource {
+    pub fn new(config: &CollateralConfig) -> Self {
+        Self {
+            pccs_url: config.pccs_url.clone(),
+            pcs_url: config.pcs_url.clone(),
+            fmspc: config.fmspc.clone(),
+            client: reqwest::Client::new(),
+            quote_ttl: Duration::from_secs(config.quote_ttl_secs),
+            quotes: Mutex::default(),
+        }
+    }
+
+    /// The v4 API of `platform` at `base`.
+    fn api(base: &str, platform: TeePlatform) -> String {
+        format!(
+            "{}/{}/certification/v4",
+            base.trim_end_matches('/'),
+            match platform {
+                TeePlatform::Tdx => "tdx",
+                TeePlatform::Sgx => "sgx",
+            }
+        )
+    }
+
+    async fn get(&self, url: String, issuer_chain: Option<&str>) -> Result<Reply, FetchError> {
+        let failed = |e: reqwest::Error| {
+            FetchError::Failed(PhalaAvsError::TeeError(format!(
+                "Collateral request failed: {}",
+                sanitize::message("collateral", e)
+            )))
+        };
+        let response = self
+            .client
+            .get(url)
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await
+            .map_err(failed)?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(FetchError::NotFound);
+        }
+        let response = response.error_for_status().map_err(failed)?;
+        let issuer_chain = match issuer_chain {
+            Some(header) => response
+                .headers()
+                .get(header)
+                .and_then(|value| value.to_str().ok())
+                .and_then(percent_decode)
+                .ok_or_else(|| {
+                    FetchError::Failed(PhalaAvsError::TeeError(format!(
+                        "The collateral reply lacks the {header} header"
+                    )))
+                })?,
+            None => String::new(),
+        };
+        let body = response.bytes().await.map_err(failed)?.to_vec();
+        Ok(Reply { body, issuer_chain })
+    }
+
+    async fn fetch_from(
+        &self,
+        origin: CollateralOrigin,
+        base: &str,
+        platform: TeePlatform,
+    ) -> Result<Collateral, PhalaAvsError> {
+        let api = Self::api(base, platform);
+        let tcb_info = self
+            .get(format!("{api}/tcb?fmspc={}", self.fmspc), None)
+            .await?
+            .text("the TCB info")?;
+        let qe_identity = self
+            .get(format!("{api}/qe/identity"), None)
+            .await?
+            .text("the QE identity")?;
+        Collateral::from_json(&tcb_info, &qe_identity, origin)
+    }
+
+    fn quotes(&self) -> std::sync::MutexGuard<'_, HashMap<CollateralKey, QuoteCollateral>> {
+        self.quotes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The collateral of `quote`'s platform as of `now_unix`, fetched unless a fresh copy is
+    /// kept.
+    pub async fn quote_collateral(
+        &self,
+        quote: &[u8],
+        now_unix: u64,
+    ) -> Result<QuoteCollateral, PhalaAvsError> {
+        let key = CollateralKey::of(quote)?;
+        let fresh_until = |collateral: &QuoteCollateral| {
+            collateral
+                .expires_unix
+                .min(collateral.fetched_unix + self.quote_ttl.as_secs())
+        };
+        let kept = self.quotes().get(&key).cloned();
+        if let Some(kept) = kept.filter(|kept| now_unix < fresh_until(kept)) {
+            return Ok(kept);
+        }
+        let (origin, base) = match &self.pccs_url {
+            Some(url) => (CollateralOrigin::Pccs, url.as_str()),
+            None => (CollateralOrigin::Pcs, self.pcs_url.as_str()),
+        };
+        let collateral = self
+            .fetch_quote_collateral(&key, origin, base, now_unix)
+            .await;
+        let outcome = if collateral.is_ok() { "ok" } else { "failed" };
+        METRICS.inc_counter(
+            COLLATERAL_FETCH_METRIC,
+            &[("source", origin.as_str()), ("outcome", outcome)],
+            1,
+        );
+        let collateral = collateral?;
+        self.quotes().insert(key, collateral.clone());
+        Ok(collateral)
+    }
+
+    async fn fetch_quote_collateral(
+        &self,
+        key: &CollateralKey,
+        origin: CollateralOrigin,
+        base: &str,
+        now_unix: u64,
+    ) -> Result<QuoteCollateral, PhalaAvsError> {
+        let served_by = origin.as_str().to_uppercase();
+        let (api, sgx) = (
+            Self::api(base, key.platform),
+            Self::api(base, TeePlatform::Sgx),
+        );
+        let tcb_info = self
+            .get(
+                format!("{api}/tcb?fmspc={}", key.fmspc),
+                Some("TCB-Info-Issuer-Chain"),
+            )
+            .await
+            .map_err(|e| match e {
+                FetchError::NotFound => PhalaAvsError::TeeError(format!(
+                    "The {served_by} has no {} TCB info for FMSPC {}",
+                    key.platform, key.fmspc
+                )),
+                FetchError::Failed(e) => e,
+            })?;
+        let qe_identity = self
+            .get(
+                format!("{api}/qe/identity"),
+                Some("SGX-Enclave-Identity-Issuer-Chain"),
+            )
+            .await?;
+        let pck_crl = self
+            .get(
+                format!("{sgx}/pckcrl?ca={}&encoding=der", key.pck_ca),
+                Some("SGX-PCK-CRL-Issuer-Chain"),
+            )
+            .await?;
+        let root_ca_crl = self.get(format!("{sgx}/rootcacrl"), None).await?.body;
+        let tcb_info_issuer_chain = tcb_info.issuer_chain.clone();
+        let qe_identity_issuer_chain = qe_identity.issuer_chain.clone();
+        let tcb_info = tcb_info.text("the TCB info")?;
+        let qe_identity = qe_identity.text("the QE identity")?;
+        let parsed = Collateral::from_json(&tcb_info, &qe_identity, origin)?;
+        if parsed.fmspc != key.fmspc {
+            return Err(PhalaAvsError::TeeError(format!(
+                "The {served_by} served TCB info for FMSPC {} when asked for {}",
+                parsed.fmspc, key.fmspc
+            )));
+        }
+        for (what, next_update) in [
+            ("TCB info", parsed.tcb_info_next_update_unix),
+            ("QE identity", parsed.qe_identity_next_update_unix),
+        ] {
+            if next_update <= now_unix {
+                return Err(PhalaAvsError::TeeError(format!(
+                    "The {served_by} served stale {what} for FMSPC {}, due for an update at \
+                     {next_update}",
+                    key.fmspc
+                )));
+            }
+        }
+        Ok(QuoteCollateral {
+            platform: key.platform,
+            origin,
+            fmspc: key.fmspc.clone(),
+            pck_ca: key.pck_ca.to_string(),
+            tcb_info,
+            tcb_info_issuer_chain,
+            qe_identity,
+            qe_identity_issuer_chain,
+            pck_crl: pck_crl.body.into(),
+            pck_crl_issuer_chain: pck_crl.issuer_chain,
+            // The PCCS serves the root CA's CRL hex-encoded; Intel's PCS serves DER.
+            root_ca_crl: std::str::from_utf8(&root_ca_crl)
+                .ok()
+                .and_then(|text| hex::decode(text.trim()).ok())
+                .unwrap_or(root_ca_crl)
+                .into(),
+            fetched_unix: now_unix,
+            expires_unix: parsed.expires_unix(),
+        })
+    }
+}
+
+impl CollateralSource for HttpCollateralSource {
+    fn fetch(&self, platform: TeePlatform) -> BoxFuture<'_, Result<Collateral, PhalaAvsError>> {
+        Box::pin(async move {
+            let pccs = self
+                .pccs_url
+                .as_deref()
+                .map(|url| (CollateralOrigin::Pccs, url));
+            let mut failure = None;
+            for (origin, base) in pccs
+                .into_iter()
+                .chain([(CollateralOrigin::Pcs, &*self.pcs_url)])
+            {
+                let result = self.fetch_from(origin, base, platform).await;
+                let outcome = if result.is_ok() { "ok" } else { "failed" };
+                METRICS.inc_counter(
+                    COLLATERAL_FETCH_METRIC,
+                    &[("source", origin.as_str()), ("outcome", outcome)],
+                    1,
+                );
+                match result {
+                    Ok(collateral) => return Ok(collateral),
+                    Err(e) => {
+                        warn!(
+                            "Failed to fetch collateral from the {}: {e}",
+                            origin.as_str()
+                        );
+                        failure = Some(e);
+                    }
+                }
+            }
+            Err(failure.unwrap_or_else(|| {
+                PhalaAvsError::TeeError("No collateral source is configured".to_string())
+            }))
+        })
+    }
+}
+
+/// The collateral a quote is verified against, as it was served.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuoteCollateral {
+    pub platform: TeePlatform,
+    pub origin: CollateralOrigin,
+    /// FMSPC of the quote's PCK certificate, in lowercase hex.
+    pub fmspc: String,
+    /// The CA that issued the PCK certificate, `platform` or `processor`.
+    pub pck_ca: String,
+    /// The PCS v4 `tcbInfo` document, signature included.
+    pub tcb_info: String,
+    pub tcb_info_issuer_chain: String,
+    /// The PCS v4 `enclaveIdentity` document of the quoting enclave, signature included.
+    pub qe_identity: String,
+    pub qe_identity_issuer_chain: String,
+    /// DER of the CRL of `pck_ca`.
+    pub pck_crl: Bytes,
+    pub pck_crl_issuer_chain: String,
+    /// DER of the root CA's CRL.
+    pub root_ca_crl: Bytes,
+    pub fetched_unix: u64,
+    /// When the first of the TCB info and QE identity expires.
+    pub expires_unix: u64,
+}
+
+impl QuoteCollateral {
+    /// The dates, levels and identities of the TCB info and QE identity.
+    pub fn collateral(&self) -> Result<Collateral, PhalaAvsError> {
+        Collateral::from_json(&self.tcb_info, &self.qe_identity, self.origin)
+    }
+}
+
+/// A quote with the collateral to verify it offline, submitted as challenge evidence.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttestationPackage {
+    pub quote: Bytes,
+    pub collateral: QuoteCollateral,
+}
+
+impl AttestationPackage {
+    /// Packages `quote` with the collateral of its platform from `source`.
+    pub async fn fetch(quote: &[u8], source: &HttpCollateralSource) -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            collateral: source.quote_collateral(quote, now_unix_ms() / 1000).await?,
+            quote: Bytes::copy_from_slice(quote),
+        })
+    }
+}
+
+/// What the collateral of a quote's platform is looked up by.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CollateralKey {
+    platform: TeePlatform,
+    fmspc: String,
+    pck_ca: &'static str,
+}
+
+impl CollateralKey {
+    /// The platform and PCK certificate of `quote`.
+    fn of(quote: &[u8]) -> Result<Self, PhalaAvsError> {
+        let report = parse_quote(quote)?;
+        let certification = report.certification.ok_or_else(|| {
+            PhalaAvsError::ValidationError(
+                "The quote carries no PCK certificate to look its collateral up by".to_string(),
+            )
+        })?;
+        let chain = pck::parse_pem_chain(certification.pck_chain.as_bytes())?;
+        let (pck, sgx) = chain
+            .first()
+            .and_then(|pck| Some((pck, pck.sgx?)))
+            .ok_or_else(|| {
+                PhalaAvsError::ValidationError(
+                    "The quote's PCK certificate has no SGX extension".to_string(),
+                )
+            })?;
+        let pck_ca = match pck.issuer_common_name() {
+            Some(ca) if ca.ends_with("Processor CA") => "processor",
+            Some(ca) if ca.ends_with("Platform CA") => "platform",
+            ca => {
+                return Err(PhalaAvsError::ValidationError(format!(
+                    "The quote's PCK certificate is issued by {ca:?}, not a platform or \
+                     processor CA"
+                )));
+            }
+        };
+        Ok(Self {
+            platform: report.platform,
+            fmspc: hex::encode(sgx.fmspc),
+            pck_ca,
+        })
+    }
+}
+
+/// The text of a percent-encoded header value, as issuer chains are sent.
+fn percent_decode(raw: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(raw.len());
+    let mut rest = raw.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let digits = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(digits, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The collateral as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollateralStatus {
+    pub platform: TeePlatform,
+    pub origin: CollateralOrigin,
+    pub fetched_unix: u64,
+    pub tcb_info_next_update_unix: u64,
+    pub qe_identity_next_update_unix: u64,
+    /// Until the first of them expires; negative once expired.
+    pub expires_in_secs: i64,
+    pub tcb_evaluation_data_number: u32,
+    /// Unset without the platform's SVNs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcb: Option<TcbAssessment>,
+}
+
+#[derive(Debug, Default)]
+struct MonitorState {
+    collateral: Option<Collateral>,
+    fetched_unix: u64,
+    assessment: Option<TcbAssessment>,
+    /// The degraded status last alerted on, so it is alerted once.
+    alerted: Option<(TcbStatus, Vec<String>)>,
+}
+
+/// Tracks the collateral of this host's platform.
+pub struct CollateralMonitor {
+    config: CollateralConfig,
+    platform: TeePlatform,
+    source: Arc<dyn CollateralSource>,
+    notifier: Arc<dyn Notifier>,
+    state: Mutex<MonitorState>,
+}
+
+impl CollateralMonitor {
+    pub fn new(
+        config: CollateralConfig,
+        platform: TeePlatform,
+        source: Arc<dyn CollateralSource>,
+        notifier: Arc<dyn Notifier>,
+    ) -> Self {
+        Self {
+            config,
+            platform,
+            source,
+            notifier,
+            state: Mutex::new(MonitorState::default()),
+        }
+    }
+
+    pub fn config(&self) -> &CollateralConfig {
+        &self.config
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MonitorState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Fetches the collateral and reassesses the platform, alerting when a TCB recovery
+    /// degraded it.
+    pub async fn refresh(&self, now_unix: u64) -> Result<CollateralStatus, PhalaAvsError> {
+        let collateral = self.source.fetch(self.platform).await?;
+        let assessment = self.config.platform_tcb.map(|tcb| tcb.assess(&collateral));
+        let alert = {
+            let mut state = self.state();
+            let alert = match &assessment {
+                Some(a) if a.status.degraded() => {
+                    let alerted = (a.status, a.advisory_ids.clone());
+                    (state.alerted.as_ref() != Some(&alerted)).then(|| {
+                        state.alerted = Some(alerted);
+                        self.degraded_alert(a)
+                    })
+                }
+                _ => {
+                    state.alerted = None;
+                    None
+                }
+            };
+            info!(
+                "Refreshed the {} collateral from the {}, expiring at {}",
+                self.platform,
+                collateral.origin.as_str(),
+                collateral.expires_unix()
+            );
+            state.collateral = Some(collateral);
+            state.fetched_unix = now_unix;
+            state.assessment = assessment;
+            alert
+        };
+        if let Some(alert) = alert {
+            error!("{}", alert.message);
+            if let Err(e) = self.notifier.notify(alert).await {
+                warn!("Failed to deliver the TCB status alert: {e}");
+            }
+        }
+        self.export(now_unix);
+        self.status(now_unix).ok_or_else(|| {
+            PhalaAvsError::TeeError("Collateral is missing after a refresh".to_string())
+        })
+    }
+
+    fn degraded_alert(&self, assessment: &TcbAssessment) -> Alert {
+        let advisories = if assessment.advisory_ids.is_empty() {
+            "no advisories listed".to_string()
+        } else {
+            format!("advisories {}", assessment.advisory_ids.join(", "))
+        };
+        Alert::new(
+            "collateral",
+            Severity::Critical,
+            format!(
+                "The {} platform's TCB is {} under TCB evaluation data number {} ({advisories}); \
+                 attestation responses are held back until the platform is updated",
+                self.platform,
+                assessment.status.as_str(),
+                assessment.tcb_evaluation_data_number
+            ),
+        )
+    }
+
+    /// When the collateral expires, `None` before the first fetch.
+    pub fn expires_unix(&self) -> Option<u64> {
+        self.state()
+            .collateral
+            .as_ref()
+            .map(Collateral::expires_unix)
+    }
+
+    /// The platform's assessment when `platform` is this host's and its TCB is degraded.
+    pub fn degraded(&self, platform: TeePlatform) -> Option<TcbAssessment> {
+        if platform != self.platform {
+            return None;
+        }
+        self.state()
+            .assessment
+            .clone()
+            .filter(|a| a.status.degraded())
+    }
+
+    pub fn status(&self, now_unix: u64) -> Option<CollateralStatus> {
+        let state = self.state();
+        let collateral = state.collateral.as_ref()?;
+        Some(CollateralStatus {
+            platform: self.platform,
+            origin: collateral.origin,
+            fetched_unix: state.fetched_unix,
+            tcb_info_next_update_unix: collateral.tcb_info_next_update_unix,
+            qe_identity_next_update_unix: collateral.qe_identity_next_update_unix,
+            expires_in_secs: collateral.expires_unix() as i64 - now_unix as i64,
+            tcb_evaluation_data_number: collateral.tcb_evaluation_data_number,
+            tcb: state.assessment.clone(),
+        })
+    }
+
+    /// Updates the expiry and status gauges as of `now_unix`.
+    pub fn export(&self, now_unix: u64) {
+        let state = self.state();
+        let Some(collateral) = &state.collateral else {
+            return;
+        };
+        let left = |at: u64| at as f64 - now_unix as f64;
+        METRICS.replace_gauges(COLLATERAL_EXPIRY_METRIC, &[
+            (
+                vec![("collateral", "tcb_info")],
+                left(collateral.tcb_info_next_update_unix),
+            ),
+            (
+                vec![("collateral", "qe_identity")],
+                left(collateral.qe_identity_next_update_unix),
+            ),
+        ]);
+        if let Some(assessment) = &state.assessment {
+            METRICS.replace_gauges(TCB_STATUS_METRIC, &[(
+                vec![
+                    ("platform", self.platform.as_str()),
+                    ("status", assessment.status.as_str()),
+                ],
+                1.0,
+            )]);
+        }
+    }
+}
+
+/// Refreshes the collateral every `check_secs`, updating the expiry gauges every minute in
+/// between.
+pub fn spawn_collateral_monitor(monitor: Arc<CollateralMonitor>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        let mut next_refresh = 0;
+        loop {
+            interval.tick().await;
+            let now = now_unix_ms() / 1000;
+            if now < next_refresh {
+                monitor.export(now);
+                continue;
+            }
+            match monitor.refresh(now).await {
+                Ok(_) => next_refresh = now + monitor.config.check_secs,
+                Err(e) => {
+                    warn!("Failed to refresh the collateral: {e}");
+                    monitor.export(now);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::path::PathBuf;
+
+    /// 2024-10-01T09:00:00Z, the fixture QE identity's `nextUpdate`.
+    const QE_NEXT_UPDATE: u64 = 1_727_773_200;
+
+    pub(crate) fn fixture(name: &str) -> String {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/collateral")
+            .join(name);
+        std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("missing collateral fixture {}: {e}", path.display()))
+    }
+
+    fn collateral(tcb_info: &str) -> Collateral {
+        Collateral::from_json(
+            &fixture(tcb_info),
+            &fixture("tdx_qe_identity.json"),
+            CollateralOrigin::Pcs,
+        )
+        .unwrap()
+    }
+
+    /// The SVNs of the fixture's `UpToDate` level before the recovery.
+    fn platform_tcb() -> PlatformTcb {
+        let mut cpu_svn = [0; 16];
+        cpu_svn[..8].copy_from_slice(&[2, 2, 2, 2, 3, 1, 0, 3]);
+        let mut tee_tcb_svn = [0; 16];
+        tee_tcb_svn[..3].copy_from_slice(&[5, 0, 2]);
+        PlatformTcb {
+            cpu_svn,
+            pce_svn: 13,
+            tee_tcb_svn: Some(tee_tcb_svn),
+        }
+    }
+
+    #[derive(Default)]
+    struct Queued(Mutex<VecDeque<Collateral>>);
+
+    impl CollateralSource for Queued {
+        fn fetch(
+            &self,
+            _platform: TeePlatform,
+        ) -> BoxFuture<'_, Result<Collateral, PhalaAvsError>> {
+            let next = self.0.lock().unwrap().pop_front();
+            Box::pin(async move {
+                next.ok_or_else(|| PhalaAvsError::TeeError("PCS unreachable".to_string()))
+            })
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorded(Mutex<Vec<Alert>>);
+
+    impl Notifier for Recorded {
+        fn notify(&self, alert: Alert) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+            self.0.lock().unwrap().push(alert);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn config() -> CollateralConfig {
+        CollateralConfig {
+            enabled: true,
+            pccs_url: None,
+            pcs_url: DEFAULT_PCS_URL.to_string(),
+            fmspc: "90c06f000000".to_string(),
+            check_secs: 3_600,
+            quote_ttl_secs: 3_600,
+            platform_tcb: Some(platform_tcb()),
+        }
+    }
+
+    #[test]
+    fn levels_are_matched_and_expiry_is_the_first_next_update() {
+        let collateral = collateral("tdx_tcb_info.json");
+        assert_eq!(collateral.tcb_evaluation_data_number, 17);
+        assert_eq!(collateral.tcb_info_next_update_unix, 1_727_863_200);
+        assert_eq!(collateral.expires_unix(), QE_NEXT_UPDATE);
+        assert_eq!(collateral.tcb_levels.len(), 2);
+        assert_eq!(collateral.fmspc, "90c06f000000");
+        assert_eq!(collateral.qe_identity.isv_prod_id, 2);
+        assert_eq!(collateral.qe_identity.status(4), TcbStatus::UpToDate);
+        assert_eq!(collateral.qe_identity.status(3), TcbStatus::OutOfDate);
+
+        let tcb = platform_tcb();
+        assert_eq!(tcb.assess(&collateral).status, TcbStatus::UpToDate);
+        // An older TDX module only reaches the second level.
+        let mut older = tcb;
+        older.tee_tcb_svn.as_mut().unwrap()[0] = 4;
+        let assessment = older.assess(&collateral);
+        assert_eq!(assessment.status, TcbStatus::OutOfDate);
+        assert_eq!(assessment.advisory_ids, ["INTEL-SA-00837"]);
+        // Below every level.
+        let mut oldest = tcb;
+        oldest.pce_svn = 10;
+        assert_eq!(oldest.assess(&collateral).status, TcbStatus::OutOfDate);
+        assert!(oldest.assess(&collateral).advisory_ids.is_empty());
+
+        assert_eq!(
+            parse_timestamp("2024-02-29T23:59:59.123Z").unwrap(),
+            1_709_251_199
+        );
+        assert!(parse_timestamp("2024-13-01T00:00:00Z").is_err());
+        assert!(parse_timestamp("2024-10-01 09:00:00").is_err());
+    }
+
+    #[tokio::test]
+    async fn a_tcb_recovery_alerts_with_its_advisories_once() {
+        let source = Arc::new(Queued::default());
+        source.0.lock().unwrap().extend([
+            collateral("tdx_tcb_info.json"),
+            collateral("tdx_tcb_info_recovery.json"),
+            collateral("tdx_tcb_info_recovery.json"),
+        ]);
+        let notifier = Arc::new(Recorded::default());
+        let monitor =
+            CollateralMonitor::new(config(), TeePlatform::Tdx, source.clone(), notifier.clone());
+        assert!(monitor.status(0).is_none());
+
+        let now = QE_NEXT_UPDATE - 7_200;
+        let status = monitor.refresh(now).await.unwrap();
+        assert_eq!(status.expires_in_secs, 7_200);
+        assert_eq!(status.tcb.unwrap().status, TcbStatus::UpToDate);
+        assert!(monitor.degraded(TeePlatform::Tdx).is_none());
+        assert!(notifier.0.lock().unwrap().is_empty());
+
+        // The recovery adds a level above the platform's, which is now SWHardeningNeeded.
+        let status = monitor.refresh(now + 60).await.unwrap();
+        let tcb = status.tcb.unwrap();
+        assert_eq!(tcb.status, TcbStatus::SwHardeningNeeded);
+        assert_eq!(tcb.tcb_evaluation_data_number, 18);
+        let alerts = notifier.0.lock().unwrap().clone();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].severity, Severity::Critical);
+        assert!(alerts[0].message.contains("SWHardeningNeeded"));
+        assert!(alerts[0].message.contains("INTEL-SA-01036, INTEL-SA-01079"));
+        assert_eq!(
+            monitor.degraded(TeePlatform::Tdx).map(|a| a.advisory_ids),
+            Some(vec![
+                "INTEL-SA-01036".to_string(),
+                "INTEL-SA-01079".to_string()
+            ])
+        );
+        assert!(monitor.degraded(TeePlatform::Sgx).is_none());
+        assert_eq!(
+            METRICS.gauge(TCB_STATUS_METRIC, &[
+                ("platform", "tdx"),
+                ("status", "SWHardeningNeeded")
+            ]),
+            Some(1.0)
+        );
+
+        // The same status is not alerted again, and a failed fetch keeps the last collateral.
+        monitor.refresh(now + 120).await.unwrap();
+        assert_eq!(notifier.0.lock().unwrap().len(), 1);
+        assert!(monitor.refresh(now + 180).await.is_err());
+        assert!(monitor.degraded(TeePlatform::Tdx).is_some());
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod http_tests {
+    use super::tests::fixture;
+    use super::*;
+    use axum::Router;
+    use axum::extract::{Query, State};
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Clone, Default)]
+    struct Server {
+        /// Answers 503 to everything.
+        down: bool,
+        requests: Arc<AtomicUsize>,
+    }
+
+    async fn tcb_info(
+        State(server): State<Server>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Result<String, StatusCode> {
+        server.requests.fetch_add(1, Ordering::SeqCst);
+        if server.down {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert_eq!(query.get("fmspc").map(String::as_str), Some("90c06f000000"));
+        Ok(fixture("tdx_tcb_info.json"))
+    }
+
+    async fn qe_identity(State(server): State<Server>) -> Result<String, StatusCode> {
+        server.requests.fetch_add(1, Ordering::SeqCst);
+        if server.down {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        Ok(fixture("tdx_qe_identity.json"))
+    }
+
+    async fn start(server: Server) -> String {
+        let app = Router::new()
+            .route("/tdx/certification/v4/tcb", get(tcb_info))
+            .route("/tdx/certification/v4/qe/identity", get(qe_identity))
+            .with_state(server);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}")
+    }
+
+    fn source(pccs_url: Option<String>, pcs_url: String) -> HttpCollateralSource {
+        HttpCollateralSource::new(&CollateralConfig {
+            enabled: true,
+            pccs_url,
+            pcs_url,
+            fmspc: "90c06f000000".to_string(),
+            check_secs: 3_600,
+            quote_ttl_secs: 600,
+            platform_tcb: None,
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn collateral_comes_from_the_pccs_with_fallback_to_the_pcs() {
+        let pccs = Server::default();
+        let pcs = Server::default();
+        let (pccs_url, pcs_url) = (start(pccs.clone()).await, start(pcs.clone()).await);
+
+        let collateral = source(Some(pccs_url), pcs_url.clone())
+            .fetch(TeePlatform::Tdx)
+            .await
+            .unwrap();
+        assert_eq!(collateral.origin, CollateralOrigin::Pccs);
+        assert_eq!(pccs.requests.load(Ordering::SeqCst), 2);
+        assert_eq!(pcs.requests.load(Ordering::SeqCst), 0);
+
+        let down = Server {
+            down: true,
+            ..Default::default()
+        };
+        let down_url = start(down.clone()).await;
+        let failed_before = METRICS
+            .counter(COLLATERAL_FETCH_METRIC, &[
+                ("outcome", "failed"),
+                ("source", "pccs"),
+            ])
+            .unwrap_or_default();
+        let collateral = source(Some(down_url.clone()), pcs_url)
+            .fetch(TeePlatform::Tdx)
+            .await
+            .unwrap();
+        assert_eq!(collateral.origin, CollateralOrigin::Pcs);
+        assert_eq!(collateral.tcb_evaluation_data_number, 17);
+        assert_eq!(pcs.requests.load(Ordering::SeqCst), 2);
+        assert!(
+            METRICS
+                .counter(COLLATERAL_FETCH_METRIC, &[
+                    ("outcome", "failed"),
+                    ("source", "pccs")
+                ])
+                .unwrap()
+                > failed_before
+        );
+
+        // Both down is an error.
+        let err = source(Some(down_url.clone()), down_url)
+            .fetch(TeePlatform::Tdx)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Collateral request failed"));
+    }
+
+    /// 2024-09-15T00:00:00Z, within the fixture collateral and certificates.
+    const NOW: u64 = 1_726_358_400;
+
+    const ISSUER_CHAIN: &str =
+        "-----BEGIN%20CERTIFICATE-----%0AAAAA%0A-----END%20CERTIFICATE-----%0A";
+
+    /// A reply with the issuer chain `header`.
+    fn issued(header: &'static str, body: String) -> ([(&'static str, &'static str); 1], String) {
+        ([(header, ISSUER_CHAIN)], body)
+    }
+
+    /// A PCCS with the TCB info `tcb_info` of FMSPC `fmspc`, counting requests.
+    #[derive(Clone)]
+    struct Pccs {
+        fmspc: &'static str,
+        tcb_info: String,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl Pccs {
+        fn new(fmspc: &'static str, tcb_info: String) -> Self {
+            Self {
+                fmspc,
+                tcb_info,
+                requests: Arc::default(),
+            }
+        }
+
+        async fn start(self) -> String {
+            let app = Router::new()
+                .route(
+                    "/tdx/certification/v4/tcb",
+                    get(
+                        |State(pccs): State<Pccs>,
+                         Query(query): Query<HashMap<String, String>>| async move {
+                            pccs.requests.fetch_add(1, Ordering::SeqCst);
+                            if query.get("fmspc").map(String::as_str) != Some(pccs.fmspc) {
+                                return Err(StatusCode::NOT_FOUND);
+                            }
+                            Ok(issued("TCB-Info-Issuer-Chain", pccs.tcb_info))
+                        },
+                    ),
+                )
+                .route(
+                    "/tdx/certification/v4/qe/identity",
+                    get(|| async {
+                        issued(
+                            "SGX-Enclave-Identity-Issuer-Chain",
+                            fixture("tdx_qe_identity.json"),
+                        )
+                    }),
+                )
+                .route(
+                    "/sgx/certification/v4/pckcrl",
+                    get(|| async {
+                        issued("SGX-PCK-CRL-Issuer-Chain", "pck crl".to_string())
+                    }),
+                )
+                .route(
+                    "/sgx/certification/v4/rootcacrl",
+                    get(|| async { hex::encode("root crl") }),
+                )
+                .with_state(self);
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            format!("http://{addr}")
+        }
+    }
+
+    /// A TDX quote from a platform of FMSPC 90c06f000000.
+    fn quote() -> Vec<u8> {
+        std::fs::read(
+            std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/dcap/tdx_quote.bin"),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quote_collateral_is_fetched_once_per_fmspc() {
+        let pccs = Pccs::new("90c06f000000", fixture("tdx_tcb_info.json"));
+        let url = pccs.clone().start().await;
+        let source = source(Some(url.clone()), url);
+
+        let collateral = source.quote_collateral(&quote(), NOW).await.unwrap();
+        assert_eq!(collateral.platform, TeePlatform::Tdx);
+        assert_eq!(collateral.origin, CollateralOrigin::Pccs);
+        assert_eq!(collateral.fmspc, "90c06f000000");
+        assert_eq!(collateral.pck_ca, "platform");
+        assert_eq!(collateral.tcb_info, fixture("tdx_tcb_info.json"));
+        assert_eq!(
+            collateral.tcb_info_issuer_chain,
+            "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n"
+        );
+        assert_eq!(collateral.pck_crl, Bytes::from_static(b"pck crl"));
+        assert_eq!(collateral.root_ca_crl, Bytes::from_static(b"root crl"));
+        assert_eq!(collateral.expires_unix, 1_727_773_200);
+        assert_eq!(
+            collateral.collateral().unwrap().tcb_evaluation_data_number,
+            17
+        );
+        assert_eq!(pccs.requests.load(Ordering::SeqCst), 1);
+
+        // A cache hit, then a refetch once the TTL is over.
+        assert_eq!(
+            source.quote_collateral(&quote(), NOW + 599).await.unwrap(),
+            collateral
+        );
+        assert_eq!(pccs.requests.load(Ordering::SeqCst), 1);
+        source.quote_collateral(&quote(), NOW + 600).await.unwrap();
+        assert_eq!(pccs.requests.load(Ordering::SeqCst), 2);
+
+        let package = AttestationPackage {
+            quote: quote().into(),
+            collateral,
+        };
+        let json = serde_json::to_string(&package).unwrap();
+        assert_eq!(
+            serde_json::from_str::<AttestationPackage>(&json).unwrap(),
+            package
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unknown_fmspcs_and_stale_tcb_info_are_refused() {
+        let url = Pccs::new("00906ed50000", fixture("tdx_tcb_info.json"))
+            .start()
+            .await;
+        let err = source(Some(url.clone()), url)
+            .quote_collateral(&quote(), NOW)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("no tdx TCB info for FMSPC 90c06f000000"),
+            "{err}"
+        );
+
+        let stale = fixture("tdx_tcb_info.json").replace(
+            r#""nextUpdate": "2024-10-02T10:00:00Z""#,
+            r#""nextUpdate": "2024-09-10T10:00:00Z""#,
+        );
+        let pccs = Pccs::new("90c06f000000", stale);
+        let url = pccs.clone().start().await;
+        let source = source(Some(url.clone()), url);
+        let err = source.quote_collateral(&quote(), NOW).await.unwrap_err();
+        assert!(err.to_string().contains("stale TCB info"), "{err}");
+        // Stale collateral is not kept.
+        assert!(source.quote_collateral(&quote(), NOW).await.is_err());
+        assert_eq!(pccs.requests.load(Ordering::SeqCst), 2);
+    }
+}
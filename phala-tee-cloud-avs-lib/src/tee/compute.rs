@@ -0,0 +1,354 @@
+//! Sandboxed execution of oracle-specified computations.
+//!
+//! Challenge kinds flagged `tee_compute` carry a program id and parameters chosen by the oracle.
+//! They are only ever run by the TEE's computation endpoint: the host checks the program id
+//! against `TEE_COMPUTE_PROGRAMS` and the parameter and input sizes, forwards the request, and
+//! checks that the returned binding commits to what it sent. There is no host-side fallback, and
+//! [`TeeComputation`] can only be obtained from [`TeeHandler::compute_in_tee`].
+
+use super::TeeHandler;
+use crate::config::{self, env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::sanitize;
+use blueprint_sdk::alloy::primitives::{B256, Bytes, keccak256};
+use blueprint_sdk::alloy::sol_types::SolValue;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+pub struct ComputeConfig {
+    /// Base URL of the TEE's computation endpoint; `tee_compute` challenges fail without it.
+    pub url: Option<String>,
+    pub allowed_programs: Vec<B256>,
+    pub max_params_bytes: usize,
+    pub max_inputs_bytes: usize,
+}
+
+impl ComputeConfig {
+    /// Reads `TEE_COMPUTE_URL`, `TEE_COMPUTE_PROGRAMS` (comma-separated program ids),
+    /// `TEE_COMPUTE_MAX_PARAMS_BYTES` and `TEE_COMPUTE_MAX_INPUTS_BYTES`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let allowed_programs = config::lookup("TEE_COMPUTE_PROGRAMS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| {
+                s.trim().parse().map_err(|e| {
+                    PhalaAvsError::ConfigError(format!(
+                        "Invalid TEE_COMPUTE_PROGRAMS entry {s:?}: {e}"
+                    ))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            url: env_opt("TEE_COMPUTE_URL")?,
+            allowed_programs,
+            max_params_bytes: env_or("TEE_COMPUTE_MAX_PARAMS_BYTES", 4096)?,
+            max_inputs_bytes: env_or("TEE_COMPUTE_MAX_INPUTS_BYTES", 1 << 20)?,
+        })
+    }
+}
+
+/// What the TEE attests to having computed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComputeBinding {
+    pub program_id: B256,
+    pub params_hash: B256,
+    pub inputs_hash: B256,
+    pub output_hash: B256,
+}
+
+impl ComputeBinding {
+    /// The digest the TEE's attestation report data commits to.
+    pub fn digest(&self) -> B256 {
+        keccak256(
+            (
+                self.program_id,
+                self.params_hash,
+                self.inputs_hash,
+                self.output_hash,
+            )
+                .abi_encode(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ComputeRequest {
+    pub program_id: B256,
+    pub params: Bytes,
+    pub inputs: Bytes,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ComputeReply {
+    pub output: Bytes,
+    pub binding: ComputeBinding,
+    /// Attestation over [`ComputeBinding::digest`].
+    pub attestation: Bytes,
+}
+
+/// The TEE-side computation endpoint.
+pub trait ComputeBackend: Send + Sync + fmt::Debug {
+    fn compute(
+        &self,
+        request: &ComputeRequest,
+    ) -> BoxFuture<'_, Result<ComputeReply, PhalaAvsError>>;
+}
+
+/// [`ComputeBackend`] posting requests to `<url>/compute`.
+#[derive(Clone, Debug)]
+pub struct HttpComputeBackend {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpComputeBackend {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl ComputeBackend for HttpComputeBackend {
+    fn compute(
+        &self,
+        request: &ComputeRequest,
+    ) -> BoxFuture<'_, Result<ComputeReply, PhalaAvsError>> {
+        let request = request.clone();
+        Box::pin(async move {
+            self.client
+                .post(format!("{}/compute", self.url.trim_end_matches('/')))
+                .json(&request)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| {
+                    PhalaAvsError::TeeError(format!(
+                        "TEE computation failed: {}",
+                        sanitize::message("tee_reply", e)
+                    ))
+                })?
+                .json()
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::TeeError(format!(
+                        "Invalid TEE computation reply: {}",
+                        sanitize::message("tee_reply", e)
+                    ))
+                })
+        })
+    }
+}
+
+/// Allow-list and endpoint used by [`TeeHandler::compute_in_tee`].
+#[derive(Clone, Debug)]
+pub(super) struct ComputeSandbox {
+    config: ComputeConfig,
+    backend: Arc<dyn ComputeBackend>,
+}
+
+/// Result of a computation run in the TEE, with its attested binding.
+#[derive(Clone, Debug)]
+pub struct TeeComputation {
+    output: Bytes,
+    binding: ComputeBinding,
+    attestation: Bytes,
+}
+
+impl TeeComputation {
+    pub fn output(&self) -> &Bytes {
+        &self.output
+    }
+
+    pub fn binding(&self) -> &ComputeBinding {
+        &self.binding
+    }
+
+    pub fn attestation(&self) -> &Bytes {
+        &self.attestation
+    }
+}
+
+impl TeeHandler {
+    /// Routes `tee_compute` challenges to `backend`, within the limits of `config`.
+    pub fn with_compute(mut self, config: ComputeConfig, backend: Arc<dyn ComputeBackend>) -> Self {
+        self.compute = Some(ComputeSandbox { config, backend });
+        self
+    }
+
+    /// Runs an allow-listed program in the TEE.
+    ///
+    /// Requests are validated before anything is sent, and the reply is only accepted if its
+    /// binding commits to the request and the returned output.
+    pub async fn compute_in_tee(
+        &self,
+        program_id: B256,
+        params: Bytes,
+        inputs: Bytes,
+    ) -> Result<TeeComputation, PhalaAvsError> {
+        let sandbox = self.compute.as_ref().ok_or_else(|| {
+            PhalaAvsError::TeeError(
+                "No TEE computation endpoint is configured (TEE_COMPUTE_URL)".to_string(),
+            )
+        })?;
+        let config = &sandbox.config;
+        if !config.allowed_programs.contains(&program_id) {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Program {program_id} is not in TEE_COMPUTE_PROGRAMS"
+            )));
+        }
+        if params.len() > config.max_params_bytes {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Computation parameters are {} bytes, the limit is {}",
+                params.len(),
+                config.max_params_bytes
+            )));
+        }
+        if inputs.len() > config.max_inputs_bytes {
+            return Err(PhalaAvsError::ValidationError(format!(
+                "Computation inputs are {} bytes, the limit is {}",
+                inputs.len(),
+                config.max_inputs_bytes
+            )));
+        }
+
+        self.inject_faults().await?;
+        let expected = ComputeBinding {
+            program_id,
+            params_hash: keccak256(&params),
+            inputs_hash: keccak256(&inputs),
+            output_hash: B256::ZERO,
+        };
+        let reply = sandbox
+            .backend
+            .compute(&ComputeRequest {
+                program_id,
+                params,
+                inputs,
+            })
+            .await?;
+        let expected = ComputeBinding {
+            output_hash: keccak256(&reply.output),
+            ..expected
+        };
+        if reply.binding != expected {
+            return Err(PhalaAvsError::TeeError(format!(
+                "TEE computation binding {:?} does not match the request, expected {expected:?}",
+                reply.binding
+            )));
+        }
+        Ok(TeeComputation {
+            output: reply.output,
+            binding: reply.binding,
+            attestation: reply.attestation,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandlerConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const PROGRAM: B256 = B256::repeat_byte(0x11);
+
+    /// Hashes parameters and inputs together, attesting with the binding digest itself.
+    #[derive(Debug, Default)]
+    struct MockTee {
+        calls: AtomicUsize,
+    }
+
+    impl ComputeBackend for MockTee {
+        fn compute(
+            &self,
+            request: &ComputeRequest,
+        ) -> BoxFuture<'_, Result<ComputeReply, PhalaAvsError>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let output = Bytes::from(
+                keccak256([request.params.as_ref(), &request.inputs].concat()).to_vec(),
+            );
+            let binding = ComputeBinding {
+                program_id: request.program_id,
+                params_hash: keccak256(&request.params),
+                inputs_hash: keccak256(&request.inputs),
+                output_hash: keccak256(&output),
+            };
+            let attestation = Bytes::from(binding.digest().to_vec());
+            Box::pin(async move {
+                Ok(ComputeReply {
+                    output,
+                    binding,
+                    attestation,
+                })
+            })
+        }
+    }
+
+    async fn handler(tee: &Arc<MockTee>) -> TeeHandler {
+        let config = ComputeConfig {
+            url: None,
+            allowed_programs: vec![PROGRAM],
+            max_params_bytes: 64,
+            max_inputs_bytes: 1024,
+        };
+        TeeHandler::new(TeeHandlerConfig::default())
+            .await
+            .unwrap()
+            .with_compute(config, Arc::clone(tee) as Arc<dyn ComputeBackend>)
+    }
+
+    #[tokio::test]
+    async fn computation_runs_in_the_tee_with_a_binding() {
+        let tee = Arc::new(MockTee::default());
+        let handler = handler(&tee).await;
+        let computation = handler
+            .compute_in_tee(
+                PROGRAM,
+                Bytes::from_static(b"blocks 1-9"),
+                Bytes::from_static(b"log"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(tee.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(computation.binding().program_id, PROGRAM);
+        assert_eq!(
+            computation.binding().output_hash,
+            keccak256(computation.output())
+        );
+        assert_eq!(
+            computation.attestation().as_ref(),
+            computation.binding().digest().as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_never_reach_the_tee() {
+        let tee = Arc::new(MockTee::default());
+        let handler = handler(&tee).await;
+
+        let unknown = handler
+            .compute_in_tee(B256::repeat_byte(0x22), Bytes::new(), Bytes::new())
+            .await;
+        assert!(matches!(unknown, Err(PhalaAvsError::ValidationError(_))));
+
+        let oversized = handler
+            .compute_in_tee(PROGRAM, Bytes::from(vec![0u8; 65]), Bytes::new())
+            .await;
+        assert!(matches!(oversized, Err(PhalaAvsError::ValidationError(_))));
+        assert_eq!(tee.calls.load(Ordering::SeqCst), 0);
+
+        // Without an endpoint there is nothing to fall back to.
+        let bare = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        assert!(
+            bare.compute_in_tee(PROGRAM, Bytes::new(), Bytes::new())
+                .await
+                .is_err()
+        );
+    }
+}
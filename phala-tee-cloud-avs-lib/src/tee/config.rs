@@ -0,0 +1,351 @@
+//! Where and how [`TeeHandler`](super::TeeHandler) reaches the TEE agent.
+//!
+//! Inside a dstack CVM tappd listens on a unix socket; outside one, e.g. against the dstack
+//! simulator, it is reached over HTTP(S). `TEE_ENDPOINT` takes either, with the older
+//! `TEE_TAPPD_SOCKET` still read when it is unset. Requests are bounded by
+//! `TEE_REQUEST_TIMEOUT_SECS`, retries included, and retried up to `TEE_RETRIES` times when the
+//! agent cannot be reached, times out or fails with a 5xx (see [`super::retries`]). The liveness
+//! probe has its own `TEE_LIVENESS_TIMEOUT_SECS` and is never retried, so a wedged agent cannot
+//! hang the heartbeat. Its latency thresholds are `TEE_LIVENESS_WARN_MS` and
+//! `TEE_LIVENESS_FAIL_MS` (see [`super::liveness`]). Quotes are reused for
+//! `TEE_QUOTE_CACHE_TTL_SECS` (see [`super::quote_cache`]). An agent outside the host is trusted
+//! over RA-TLS with `TEE_RA_TLS` (see [`super::ra_tls`]). Invalid settings fail handler
+//! construction rather than the first request.
+
+use crate::config::{self, parse_flag, parse_opt, parse_or};
+use crate::error::PhalaAvsError;
+use crate::retry::RetryPolicy;
+use reqwest::Url;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const DEFAULT_TAPPD_SOCKET: &str = "/var/run/tappd.sock";
+
+/// Site of the `RETRY_TEE_*` overrides of [`TeeHandlerConfig::retry_policy`].
+pub const TEE_RETRY_SITE: &str = "TEE";
+
+fn invalid(reason: impl fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::TeeError(format!("Invalid TEE configuration: {reason}"))
+}
+
+/// Where the TEE agent listens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TeeEndpoint {
+    /// A unix socket, as dstack exposes tappd inside a CVM.
+    Socket(PathBuf),
+    /// An `http` or `https` base URL.
+    Http(Url),
+}
+
+impl TeeEndpoint {
+    /// Reads a socket path, absolute or as a `unix://` URL, or an `http(s)://` URL.
+    pub fn parse(raw: &str) -> Result<Self, PhalaAvsError> {
+        let raw = raw.trim();
+        if let Some(path) = raw
+            .strip_prefix("unix://")
+            .or(raw.starts_with('/').then_some(raw))
+        {
+            if !path.starts_with('/') {
+                return Err(invalid(format!(
+                    "TEE_ENDPOINT {raw:?} is not an absolute socket path"
+                )));
+            }
+            return Ok(Self::Socket(PathBuf::from(path)));
+        }
+        let url = Url::parse(raw).map_err(|e| {
+            invalid(format!(
+                "TEE_ENDPOINT {raw:?} is neither a socket path nor a URL: {e}"
+            ))
+        })?;
+        match url.scheme() {
+            "http" | "https" if url.host().is_some() => Ok(Self::Http(url)),
+            "http" | "https" => Err(invalid(format!("TEE_ENDPOINT {raw:?} has no host"))),
+            scheme => Err(invalid(format!(
+                "TEE_ENDPOINT {raw:?} has scheme {scheme}, not http, https or unix"
+            ))),
+        }
+    }
+
+    pub fn is_https(&self) -> bool {
+        matches!(self, Self::Http(url) if url.scheme() == "https")
+    }
+}
+
+impl fmt::Display for TeeEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Socket(path) => write!(f, "unix://{}", path.display()),
+            Self::Http(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+/// TLS settings of an `https` endpoint.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TeeTlsConfig {
+    /// PEM of a CA to trust besides the system roots, e.g. a self-signed gateway's.
+    pub ca_cert: Option<PathBuf>,
+    /// Skips certificate verification; for test setups only.
+    pub accept_invalid_certs: bool,
+}
+
+impl TeeTlsConfig {
+    fn is_set(&self) -> bool {
+        self.ca_cert.is_some() || self.accept_invalid_certs
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TeeHandlerConfig {
+    pub endpoint: TeeEndpoint,
+    /// Bounds a request, from connecting to reading the reply, its retries included.
+    pub request_timeout: Duration,
+    /// Bounds the liveness probe, which is not retried.
+    pub liveness_timeout: Duration,
+    /// Liveness probes answered slower than this are reported as slow.
+    pub liveness_warn_after: Duration,
+    /// Liveness probes answered slower than this count as not live. Above `liveness_timeout`,
+    /// the timeout is reached first.
+    pub liveness_fail_after: Duration,
+    /// Further attempts at a request that could not reach the agent, timed out or failed with a
+    /// 5xx.
+    pub retries: u32,
+    pub tls: TeeTlsConfig,
+    /// Trusts an `https` agent for the quote in its certificate rather than for its CA; see
+    /// [`super::ra_tls`].
+    pub ra_tls: bool,
+    /// How long a quote is reused for the same report data; zero never reuses one.
+    pub quote_cache_ttl: Duration,
+}
+
+impl Default for TeeHandlerConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: TeeEndpoint::Socket(PathBuf::from(DEFAULT_TAPPD_SOCKET)),
+            request_timeout: Duration::from_secs(10),
+            liveness_timeout: Duration::from_secs(5),
+            liveness_warn_after: Duration::from_secs(2),
+            liveness_fail_after: Duration::from_secs(4),
+            retries: 2,
+            tls: TeeTlsConfig::default(),
+            ra_tls: false,
+            quote_cache_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+impl TeeHandlerConfig {
+    /// Reads `TEE_ENDPOINT` (or `TEE_TAPPD_SOCKET`), `TEE_REQUEST_TIMEOUT_SECS`,
+    /// `TEE_LIVENESS_TIMEOUT_SECS`, `TEE_LIVENESS_WARN_MS`, `TEE_LIVENESS_FAIL_MS`,
+    /// `TEE_RETRIES`, `TEE_TLS_CA_CERT`, `TEE_TLS_ACCEPT_INVALID_CERTS`, `TEE_RA_TLS` and
+    /// `TEE_QUOTE_CACHE_TTL_SECS`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Self::from_lookup(config::lookup)
+    }
+
+    /// [`Self::from_env`], reading settings through `lookup`.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, PhalaAvsError> {
+        // A setting that does not parse is as invalid as one `validate` rejects.
+        let config = Self::parse(lookup).map_err(|e| match e {
+            PhalaAvsError::ConfigError(reason) => invalid(reason.trim_start_matches("Invalid ")),
+            e => e,
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn parse(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        let endpoint = match parse_opt::<String>("TEE_ENDPOINT", lookup("TEE_ENDPOINT"))? {
+            Some(raw) => TeeEndpoint::parse(&raw)?,
+            None => match parse_opt::<PathBuf>("TEE_TAPPD_SOCKET", lookup("TEE_TAPPD_SOCKET"))? {
+                Some(path) => TeeEndpoint::Socket(path),
+                None => defaults.endpoint,
+            },
+        };
+        let secs = |key: &str, default: Duration| -> Result<Duration, PhalaAvsError> {
+            parse_or(key, lookup(key), default.as_secs()).map(Duration::from_secs)
+        };
+        let millis = |key: &str, default: Duration| -> Result<Duration, PhalaAvsError> {
+            parse_or(key, lookup(key), default.as_millis() as u64).map(Duration::from_millis)
+        };
+        Ok(Self {
+            endpoint,
+            request_timeout: secs("TEE_REQUEST_TIMEOUT_SECS", defaults.request_timeout)?,
+            liveness_timeout: secs("TEE_LIVENESS_TIMEOUT_SECS", defaults.liveness_timeout)?,
+            liveness_warn_after: millis("TEE_LIVENESS_WARN_MS", defaults.liveness_warn_after)?,
+            liveness_fail_after: millis("TEE_LIVENESS_FAIL_MS", defaults.liveness_fail_after)?,
+            retries: parse_or("TEE_RETRIES", lookup("TEE_RETRIES"), defaults.retries)?,
+            tls: TeeTlsConfig {
+                ca_cert: parse_opt("TEE_TLS_CA_CERT", lookup("TEE_TLS_CA_CERT"))?,
+                accept_invalid_certs: parse_flag(
+                    "TEE_TLS_ACCEPT_INVALID_CERTS",
+                    lookup("TEE_TLS_ACCEPT_INVALID_CERTS"),
+                    false,
+                )?,
+            },
+            ra_tls: parse_flag("TEE_RA_TLS", lookup("TEE_RA_TLS"), false)?,
+            quote_cache_ttl: secs("TEE_QUOTE_CACHE_TTL_SECS", defaults.quote_cache_ttl)?,
+        })
+    }
+
+    /// How requests are retried: `retries` more attempts, backing off exponentially from 200ms
+    /// with jitter, all within `request_timeout`. `RETRY_TEE_*` settings override the backoff
+    /// and attempts, but not the timeout.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::from_config_or(TEE_RETRY_SITE, RetryPolicy {
+            max_attempts: self.retries.saturating_add(1),
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(2),
+            ..RetryPolicy::default()
+        })
+        .with_deadline(self.request_timeout)
+    }
+
+    /// Rejects settings no request could succeed with.
+    pub fn validate(&self) -> Result<(), PhalaAvsError> {
+        if self.request_timeout.is_zero() {
+            return Err(invalid("TEE_REQUEST_TIMEOUT_SECS must be positive"));
+        }
+        if self.liveness_timeout.is_zero() {
+            return Err(invalid("TEE_LIVENESS_TIMEOUT_SECS must be positive"));
+        }
+        if self.liveness_warn_after > self.liveness_fail_after {
+            return Err(invalid(
+                "TEE_LIVENESS_WARN_MS must not be above TEE_LIVENESS_FAIL_MS",
+            ));
+        }
+        if (self.tls.is_set() || self.ra_tls) && !self.endpoint.is_https() {
+            return Err(invalid(format!(
+                "TLS options need an https TEE_ENDPOINT, not {}",
+                self.endpoint
+            )));
+        }
+        if self.ra_tls && self.tls.is_set() {
+            return Err(invalid(
+                "TEE_RA_TLS trusts the agent's quote, not TEE_TLS_* certificate settings",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandler;
+    use std::collections::BTreeMap;
+
+    fn from(vars: &[(&str, &str)]) -> Result<TeeHandlerConfig, PhalaAvsError> {
+        let vars: BTreeMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        TeeHandlerConfig::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn unset_settings_fall_back_to_the_tappd_socket() {
+        assert_eq!(from(&[]).unwrap(), TeeHandlerConfig::default());
+        let config = from(&[("TEE_TAPPD_SOCKET", "/run/dstack/tappd.sock")]).unwrap();
+        assert_eq!(
+            config.endpoint,
+            TeeEndpoint::Socket(PathBuf::from("/run/dstack/tappd.sock"))
+        );
+        // TEE_ENDPOINT wins over the older setting.
+        let config = from(&[
+            ("TEE_TAPPD_SOCKET", "/run/dstack/tappd.sock"),
+            ("TEE_ENDPOINT", "unix:///tmp/tappd.sock"),
+        ])
+        .unwrap();
+        assert_eq!(config.endpoint.to_string(), "unix:///tmp/tappd.sock");
+    }
+
+    #[test]
+    fn settings_are_parsed() {
+        let config = from(&[
+            ("TEE_ENDPOINT", "https://simulator.local:8090"),
+            ("TEE_REQUEST_TIMEOUT_SECS", "30"),
+            ("TEE_LIVENESS_TIMEOUT_SECS", "2"),
+            ("TEE_LIVENESS_WARN_MS", "500"),
+            ("TEE_LIVENESS_FAIL_MS", "1500"),
+            ("TEE_RETRIES", "0"),
+            ("TEE_TLS_CA_CERT", "/etc/tee/ca.pem"),
+            ("TEE_TLS_ACCEPT_INVALID_CERTS", "false"),
+            ("TEE_QUOTE_CACHE_TTL_SECS", "0"),
+        ])
+        .unwrap();
+        assert_eq!(config, TeeHandlerConfig {
+            endpoint: TeeEndpoint::Http(Url::parse("https://simulator.local:8090").unwrap()),
+            request_timeout: Duration::from_secs(30),
+            liveness_timeout: Duration::from_secs(2),
+            liveness_warn_after: Duration::from_millis(500),
+            liveness_fail_after: Duration::from_millis(1500),
+            retries: 0,
+            tls: TeeTlsConfig {
+                ca_cert: Some(PathBuf::from("/etc/tee/ca.pem")),
+                accept_invalid_certs: false,
+            },
+            ra_tls: false,
+            quote_cache_ttl: Duration::ZERO,
+        });
+        // A single attempt, with the whole timeout.
+        let policy = config.retry_policy();
+        assert_eq!(policy.max_attempts, 1);
+        assert_eq!(policy.deadline, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn invalid_settings_fail_with_a_tee_error() {
+        let tee_error = |vars: &[(&str, &str)], expected: &str| match from(vars) {
+            Err(PhalaAvsError::TeeError(message)) => {
+                assert!(message.contains(expected), "{message}")
+            }
+            other => panic!("expected a TEE error for {vars:?}, got {other:?}"),
+        };
+        tee_error(&[("TEE_ENDPOINT", "http//no-scheme")], "neither");
+        tee_error(&[("TEE_ENDPOINT", "ftp://agent")], "scheme ftp");
+        tee_error(&[("TEE_ENDPOINT", "unix://relative.sock")], "absolute");
+        tee_error(&[("TEE_REQUEST_TIMEOUT_SECS", "0")], "must be positive");
+        tee_error(&[("TEE_LIVENESS_TIMEOUT_SECS", "0")], "must be positive");
+        tee_error(&[("TEE_LIVENESS_WARN_MS", "5000")], "must not be above");
+        tee_error(
+            &[
+                ("TEE_ENDPOINT", "http://localhost:8090"),
+                ("TEE_TLS_ACCEPT_INVALID_CERTS", "true"),
+            ],
+            "need an https",
+        );
+        tee_error(
+            &[
+                ("TEE_ENDPOINT", "http://localhost:8090"),
+                ("TEE_RA_TLS", "true"),
+            ],
+            "need an https",
+        );
+        tee_error(
+            &[
+                ("TEE_ENDPOINT", "https://agent.example:8090"),
+                ("TEE_RA_TLS", "true"),
+                ("TEE_TLS_CA_CERT", "/etc/tee/ca.pem"),
+            ],
+            "not TEE_TLS_*",
+        );
+        tee_error(&[("TEE_RETRIES", "many")], r#"TEE_RETRIES="many""#);
+        tee_error(&[("TEE_LIVENESS_WARN_MS", "soon")], "TEE_LIVENESS_WARN_MS");
+        tee_error(&[("TEE_RA_TLS", "maybe")], "expected a boolean");
+    }
+
+    #[tokio::test]
+    async fn handler_construction_rejects_invalid_settings() {
+        let config = TeeHandlerConfig {
+            request_timeout: Duration::ZERO,
+            ..TeeHandlerConfig::default()
+        };
+        assert!(matches!(
+            TeeHandler::new(config).await,
+            Err(PhalaAvsError::TeeError(_))
+        ));
+    }
+}
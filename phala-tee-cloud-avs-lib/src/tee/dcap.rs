@@ -0,0 +1,442 @@
+//! Offline DCAP verification of quotes from other operators.
+//!
+//! Watchers and challengers must check another operator's quote before signing off on it.
+//! [`TeeHandler::verify_attestation`] verifies a TDX or SGX quote against a trust anchor and
+//! collateral bundled with the operator, without calling out to a PCCS:
+//!
+//! 1. the PCK certificate chain in the quote's certification data runs up to the root CA at
+//!    `DCAP_ROOT_CA_PATH`, and every certificate in it is valid now;
+//! 2. the PCK key signed the quoting enclave's report, which binds the attestation key;
+//! 3. the attestation key signed the quote header and body;
+//! 4. the quoting enclave is the one of the bundled QE identity, at a known TCB level;
+//! 5. the bundled TCB info of the platform's FMSPC has not expired, and the platform's SVNs
+//!    reach one of its levels.
+//!
+//! Collateral is read at startup from `DCAP_COLLATERAL_DIR`, as the PCS v4 documents
+//! `tdx_tcb_info.json` and `tdx_qe_identity.json`, and `sgx_tcb_info.json` and
+//! `sgx_qe_identity.json`, for whichever platforms are present. A revoked platform is rejected;
+//! any other TCB status is returned in the [`AttestationVerdict`] for the caller to weigh.
+//! Quotes that verify must still carry the measurements of an approved image; see
+//! [`super::measurement_policy`].
+//!
+//! Every length and offset is checked before it is used, so malformed quotes are rejected with
+//! an error and never panic.
+
+use super::TeeHandler;
+use super::attestation::{AttestationReport, parse_quote};
+use super::collateral::{Collateral, CollateralOrigin, PlatformTcb, TcbAssessment, TcbStatus};
+use super::pck::{self, Certificate};
+use super::platform::TeePlatform;
+use super::quote::{
+    HEADER_LEN, ISV_PROD_ID, ISV_SVN, MR_SIGNER, SGX_BODY_LEN, SGX_REPORT_DATA, SignatureData,
+    TDX_BODY_LEN, signed_body,
+};
+use crate::config::env_opt;
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Attestation key type of ECDSA-256 with P-256.
+const ECDSA_P256: u16 = 2;
+
+fn rejected(reason: impl std::fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::ValidationError(format!("Quote verification failed: {reason}"))
+}
+
+/// What a verified quote attests, and the TCB standing of the platform that produced it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttestationVerdict {
+    /// Measurements and report data, as parsed from the quote body.
+    pub report: AttestationReport,
+    /// FMSPC of the PCK certificate, in lowercase hex.
+    pub fmspc: String,
+    /// The platform's TCB level under the bundled TCB info, with its advisories.
+    pub tcb: TcbAssessment,
+    /// The quoting enclave's TCB status under the bundled QE identity.
+    pub qe_tcb_status: TcbStatus,
+}
+
+impl AttestationVerdict {
+    /// Whether the platform or its quoting enclave is `SWHardeningNeeded` or worse.
+    pub fn degraded(&self) -> bool {
+        self.tcb.status.degraded() || self.qe_tcb_status.degraded()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DcapConfig {
+    /// PEM of the root CA every PCK chain must end at.
+    pub root_ca_path: Option<PathBuf>,
+    pub collateral_dir: Option<PathBuf>,
+}
+
+impl DcapConfig {
+    /// Reads `DCAP_ROOT_CA_PATH` and `DCAP_COLLATERAL_DIR`, which are set together.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let config = Self {
+            root_ca_path: env_opt("DCAP_ROOT_CA_PATH")?,
+            collateral_dir: env_opt("DCAP_COLLATERAL_DIR")?,
+        };
+        if config.root_ca_path.is_some() != config.collateral_dir.is_some() {
+            return Err(PhalaAvsError::ConfigError(
+                "DCAP_ROOT_CA_PATH and DCAP_COLLATERAL_DIR must be set together".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// Verifies quotes against a trusted root and per-platform collateral.
+#[derive(Debug)]
+pub struct DcapVerifier {
+    root: Certificate,
+    tdx: Option<Collateral>,
+    sgx: Option<Collateral>,
+}
+
+impl DcapVerifier {
+    /// A verifier trusting the self-signed root certificate in `root_pem`, with no collateral.
+    pub fn new(root_pem: &[u8]) -> Result<Self, PhalaAvsError> {
+        let root = match pck::parse_pem_chain(root_pem)?.as_slice() {
+            [root] => root.clone(),
+            _ => {
+                return Err(PhalaAvsError::ConfigError(
+                    "The DCAP root CA must be a single certificate".to_string(),
+                ));
+            }
+        };
+        root.verify_issued_by(&root).map_err(|_| {
+            PhalaAvsError::ConfigError("The DCAP root CA is not self-signed".to_string())
+        })?;
+        Ok(Self {
+            root,
+            tdx: None,
+            sgx: None,
+        })
+    }
+
+    pub fn with_collateral(mut self, platform: TeePlatform, collateral: Collateral) -> Self {
+        match platform {
+            TeePlatform::Tdx => self.tdx = Some(collateral),
+            TeePlatform::Sgx => self.sgx = Some(collateral),
+        }
+        self
+    }
+
+    /// The verifier `config` describes, or `None` when DCAP verification is not configured.
+    pub fn from_config(config: &DcapConfig) -> Result<Option<Self>, PhalaAvsError> {
+        let (Some(root_ca_path), Some(dir)) = (&config.root_ca_path, &config.collateral_dir) else {
+            return Ok(None);
+        };
+        let mut verifier = Self::new(&read(root_ca_path)?)?;
+        for platform in [TeePlatform::Tdx, TeePlatform::Sgx] {
+            let tcb_info = dir.join(format!("{}_tcb_info.json", platform.as_str()));
+            if !tcb_info.exists() {
+                continue;
+            }
+            let qe_identity = dir.join(format!("{}_qe_identity.json", platform.as_str()));
+            let collateral = Collateral::from_json(
+                &String::from_utf8_lossy(&read(&tcb_info)?),
+                &String::from_utf8_lossy(&read(&qe_identity)?),
+                CollateralOrigin::Bundled,
+            )?;
+            verifier = verifier.with_collateral(platform, collateral);
+        }
+        if verifier.tdx.is_none() && verifier.sgx.is_none() {
+            return Err(PhalaAvsError::ConfigError(format!(
+                "No TCB info in DCAP_COLLATERAL_DIR {}",
+                dir.display()
+            )));
+        }
+        Ok(Some(verifier))
+    }
+
+    fn collateral(&self, platform: TeePlatform) -> Option<&Collateral> {
+        match platform {
+            TeePlatform::Tdx => self.tdx.as_ref(),
+            TeePlatform::Sgx => self.sgx.as_ref(),
+        }
+    }
+
+    /// Verifies `quote` as of `now_unix`.
+    pub fn verify(&self, quote: &[u8], now_unix: u64) -> Result<AttestationVerdict, PhalaAvsError> {
+        let report = parse_quote(quote)?;
+        let platform = report.platform;
+        if u16::from_le_bytes([quote[2], quote[3]]) != ECDSA_P256 {
+            return Err(rejected("the attestation key is not ECDSA P-256"));
+        }
+        let body_len = match platform {
+            TeePlatform::Tdx => TDX_BODY_LEN,
+            TeePlatform::Sgx => SGX_BODY_LEN,
+        };
+        let body = signed_body(quote, body_len, platform.as_str())?;
+        let signed = &quote[..HEADER_LEN + body_len];
+        let signature_data = SignatureData::parse(&quote[HEADER_LEN + body_len + 4..], platform)?;
+
+        let chain = pck::parse_pem_chain(signature_data.pck_chain)?;
+        let pck = pck::verify_chain(&chain, &self.root, now_unix)?;
+        let sgx = pck
+            .sgx
+            .ok_or_else(|| rejected("the PCK certificate has no SGX extension"))?;
+        verify(
+            pck.public_key(),
+            signature_data.qe_report,
+            signature_data.qe_signature,
+        )
+        .map_err(|_| rejected("the QE report is not signed by the PCK key"))?;
+        let binding: [u8; 32] = Sha256::new()
+            .chain_update(signature_data.attestation_key)
+            .chain_update(signature_data.qe_auth_data)
+            .finalize()
+            .into();
+        let qe_report_data = &signature_data.qe_report[SGX_REPORT_DATA..SGX_REPORT_DATA + 64];
+        if qe_report_data[..32] != binding || qe_report_data[32..].iter().any(|&b| b != 0) {
+            return Err(rejected("the QE report does not bind the attestation key"));
+        }
+        let mut attestation_key = [0x04; 65];
+        attestation_key[1..].copy_from_slice(signature_data.attestation_key);
+        let attestation_key = VerifyingKey::from_sec1_bytes(&attestation_key)
+            .map_err(|_| rejected("the attestation key is not a P-256 point"))?;
+        verify(&attestation_key, signed, signature_data.quote_signature)
+            .map_err(|_| rejected("the quote is not signed by the attestation key"))?;
+
+        let collateral = self.collateral(platform).ok_or_else(|| {
+            PhalaAvsError::TeeError(format!("No {platform} collateral is bundled"))
+        })?;
+        if now_unix > collateral.expires_unix() {
+            return Err(rejected(format!(
+                "the bundled {platform} collateral expired at {}",
+                collateral.expires_unix()
+            )));
+        }
+        let qe_report = signature_data.qe_report;
+        let qe = &collateral.qe_identity;
+        let qe_isv_prod_id =
+            u16::from_le_bytes([qe_report[ISV_PROD_ID], qe_report[ISV_PROD_ID + 1]]);
+        if qe_report[MR_SIGNER..MR_SIGNER + 32] != qe.mr_signer || qe_isv_prod_id != qe.isv_prod_id
+        {
+            return Err(rejected(
+                "the quoting enclave is not the one of the QE identity",
+            ));
+        }
+        let qe_tcb_status = qe.status(u16::from_le_bytes([
+            qe_report[ISV_SVN],
+            qe_report[ISV_SVN + 1],
+        ]));
+        let fmspc = hex::encode(sgx.fmspc);
+        if fmspc != collateral.fmspc {
+            return Err(rejected(format!(
+                "the platform's FMSPC {fmspc} is not the bundled TCB info's {}",
+                collateral.fmspc
+            )));
+        }
+        let platform_tcb = PlatformTcb {
+            cpu_svn: sgx.cpu_svn,
+            pce_svn: sgx.pce_svn,
+            tee_tcb_svn: (platform == TeePlatform::Tdx)
+                .then(|| body[..16].try_into().expect("16 bytes")),
+        };
+        let tcb = platform_tcb.assess(collateral);
+        if tcb.status == TcbStatus::Revoked || qe_tcb_status == TcbStatus::Revoked {
+            return Err(rejected("the platform's TCB is revoked"));
+        }
+        Ok(AttestationVerdict {
+            report,
+            fmspc,
+            tcb,
+            qe_tcb_status,
+        })
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, PhalaAvsError> {
+    std::fs::read(path)
+        .map_err(|e| PhalaAvsError::ConfigError(format!("Failed to read {}: {e}", path.display())))
+}
+
+fn verify(key: &VerifyingKey, message: &[u8], signature: &[u8]) -> Result<(), PhalaAvsError> {
+    let signature =
+        Signature::from_slice(signature).map_err(|_| rejected("a signature is out of range"))?;
+    key.verify(message, &signature)
+        .map_err(|_| rejected("a signature does not verify"))
+}
+
+impl TeeHandler {
+    pub fn with_dcap(mut self, verifier: Arc<DcapVerifier>) -> Self {
+        self.dcap = Some(verifier);
+        self.share_ra_tls_trust();
+        self
+    }
+
+    /// DCAP verification of another operator's quote, against the bundled collateral, and of
+    /// its measurements against the measurement policy.
+    pub fn verify_attestation(&self, quote: &[u8]) -> Result<AttestationVerdict, PhalaAvsError> {
+        let verifier = self.dcap.as_ref().ok_or_else(|| {
+            PhalaAvsError::TeeError(
+                "DCAP verification needs DCAP_ROOT_CA_PATH and DCAP_COLLATERAL_DIR".to_string(),
+            )
+        })?;
+        let verdict = verifier.verify(quote, now_unix_ms() / 1000)?;
+        self.check_measurements(&verdict.report.measurements)?;
+        Ok(verdict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandlerConfig;
+    use crate::tee::attestation::Measurements;
+    use blueprint_sdk::alloy::primitives::FixedBytes;
+    use sha2::Sha512;
+
+    /// 2024-09-15T00:00:00Z, within the fixture collateral and certificates.
+    const NOW: u64 = 1_726_358_400;
+    /// 2024-10-01T09:00:00Z, when the fixture QE identity expires.
+    const QE_NEXT_UPDATE: u64 = 1_727_773_200;
+
+    fn fixture(path: &str) -> Vec<u8> {
+        std::fs::read(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(path),
+        )
+        .unwrap()
+    }
+
+    fn collateral(tcb_info: &str) -> Collateral {
+        Collateral::from_json(
+            &String::from_utf8(fixture(&format!("collateral/{tcb_info}"))).unwrap(),
+            &String::from_utf8(fixture("collateral/tdx_qe_identity.json")).unwrap(),
+            CollateralOrigin::Bundled,
+        )
+        .unwrap()
+    }
+
+    fn verifier(tcb_info: &str) -> DcapVerifier {
+        DcapVerifier::new(&fixture("dcap/root_ca.pem"))
+            .unwrap()
+            .with_collateral(TeePlatform::Tdx, collateral(tcb_info))
+    }
+
+    /// A TDX quote over sha512("phala-avs dcap fixture"), from a platform at the `UpToDate`
+    /// level of `tdx_tcb_info.json`, chained to `root_ca.pem`.
+    fn quote() -> Vec<u8> {
+        fixture("dcap/tdx_quote.bin")
+    }
+
+    #[test]
+    fn fixture_quote_verifies_with_its_measurements_and_tcb() {
+        let verdict = verifier("tdx_tcb_info.json").verify(&quote(), NOW).unwrap();
+        assert_eq!(verdict.report.platform, TeePlatform::Tdx);
+        let Measurements::Tdx { mr_td, rtmrs } = &verdict.report.measurements else {
+            panic!(
+                "expected TDX measurements, got {:?}",
+                verdict.report.measurements
+            );
+        };
+        assert_eq!(*mr_td, FixedBytes::repeat_byte(0x5a));
+        assert_eq!(rtmrs[2], FixedBytes::repeat_byte(0x33));
+        assert_eq!(
+            verdict.report.report_data.as_ref(),
+            Sha512::digest(b"phala-avs dcap fixture").as_slice()
+        );
+        assert_eq!(verdict.fmspc, "90c06f000000");
+        assert_eq!(verdict.tcb.status, TcbStatus::UpToDate);
+        assert!(verdict.tcb.advisory_ids.is_empty());
+        assert_eq!(verdict.qe_tcb_status, TcbStatus::UpToDate);
+        assert!(!verdict.degraded());
+
+        // After the recovery the same platform verifies, at a degraded level.
+        let verdict = verifier("tdx_tcb_info_recovery.json")
+            .verify(&quote(), NOW)
+            .unwrap();
+        assert_eq!(verdict.tcb.status, TcbStatus::SwHardeningNeeded);
+        assert_eq!(verdict.tcb.advisory_ids, [
+            "INTEL-SA-01036",
+            "INTEL-SA-01079"
+        ]);
+        assert_eq!(verdict.tcb.tcb_evaluation_data_number, 18);
+        assert!(verdict.degraded());
+    }
+
+    #[test]
+    fn untrusted_roots_and_expired_collateral_are_rejected() {
+        let other = DcapVerifier::new(&fixture("dcap/other_root_ca.pem"))
+            .unwrap()
+            .with_collateral(TeePlatform::Tdx, collateral("tdx_tcb_info.json"));
+        let err = other.verify(&quote(), NOW).unwrap_err();
+        assert!(err.to_string().contains("trusted root"), "{err}");
+
+        let err = verifier("tdx_tcb_info.json")
+            .verify(&quote(), QE_NEXT_UPDATE + 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("collateral expired"), "{err}");
+
+        let bare = DcapVerifier::new(&fixture("dcap/root_ca.pem")).unwrap();
+        assert!(bare.verify(&quote(), NOW).is_err());
+    }
+
+    #[tokio::test]
+    async fn handler_needs_a_configured_verifier() {
+        let handler = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        assert!(handler.verify_attestation(&quote()).is_err());
+        let handler = handler.with_dcap(Arc::new(verifier("tdx_tcb_info.json")));
+        // The fixture collateral has expired by now.
+        let err = handler.verify_attestation(&quote()).unwrap_err();
+        assert!(err.to_string().contains("collateral expired"), "{err}");
+    }
+
+    #[test]
+    fn truncated_quotes_are_rejected() {
+        let verifier = verifier("tdx_tcb_info.json");
+        let quote = quote();
+        for len in 0..quote.len() {
+            assert!(
+                verifier.verify(&quote[..len], NOW).is_err(),
+                "a quote truncated to {len} bytes verified"
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_quotes_are_rejected() {
+        let verifier = verifier("tdx_tcb_info.json");
+        let quote = quote();
+        for i in (0..quote.len()).step_by(7) {
+            let mut corrupted = quote.clone();
+            corrupted[i] ^= 0xff;
+            assert!(
+                verifier.verify(&corrupted, NOW).is_err(),
+                "corrupting byte {i} went unnoticed"
+            );
+        }
+
+        // Seeded runs of random bytes at random offsets, and random garbage.
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..200 {
+            let mut corrupted = quote.clone();
+            let at = next() as usize % corrupted.len();
+            let len = (1 + next() as usize % 16).min(corrupted.len() - at);
+            for byte in &mut corrupted[at..at + len] {
+                *byte = next() as u8;
+            }
+            if corrupted != quote {
+                assert!(verifier.verify(&corrupted, NOW).is_err());
+            }
+            let garbage: Vec<u8> = (0..next() % 4_096).map(|_| next() as u8).collect();
+            assert!(verifier.verify(&garbage, NOW).is_err());
+        }
+    }
+}
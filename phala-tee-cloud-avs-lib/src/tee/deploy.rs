@@ -0,0 +1,839 @@
+//! Deployment of workloads to Phala Cloud.
+//!
+//! [`TeeHandler::deploy_workload`] posts a docker-compose manifest and its resource requests to
+//! the Phala Cloud API at `PHALA_CLOUD_API_URL`, authenticated with `PHALA_CLOUD_API_KEY`, then
+//! polls the CVM it was assigned every `PHALA_CLOUD_POLL_INTERVAL_MS` until the CVM is running,
+//! has failed, or `PHALA_CLOUD_BOOT_TIMEOUT_SECS` have elapsed. Jobs report failures on-chain, so
+//! each phase fails with its own [`DeployFailure`]: the API rejecting the manifest, the account's
+//! quota being exceeded, the API being unavailable, the CVM failing to boot and the CVM not
+//! booting in time. The API being briefly unavailable while the CVM boots is waited out, and so
+//! is a CVM that runs but is not yet healthy; see [`super::workload_status`] for how the CVM's
+//! state is read.
+
+use super::capacity::Resources;
+use super::retries::retrying;
+use super::workload_list::{CvmPage, WorkloadFilter};
+use super::workload_logs::LogChunks;
+use super::workload_status::{CvmInfo, WorkloadStatus};
+use super::{TeeHandler, TeeHandlerConfig};
+use crate::config::{env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::retry::{CancelToken, Interrupted, RetryPolicy};
+use crate::sanitize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Counter of deployments, by outcome: `running` or the [`DeployFailure::phase`].
+pub const WORKLOAD_DEPLOYMENTS_METRIC: &str = "phala_avs_workload_deployments_total";
+
+#[derive(Clone)]
+pub struct CloudConfig {
+    /// Base URL of the Phala Cloud API, e.g. `https://cloud-api.phala.network/api/v1`;
+    /// deployments fail without it.
+    pub api_url: Option<String>,
+    pub api_key: Option<String>,
+    pub boot_timeout: Duration,
+    pub poll_interval: Duration,
+    /// How long stopping, restarting or terminating a CVM may take; see [`super::lifecycle`].
+    pub transition_timeout: Duration,
+    /// Most workloads [`TeeHandler::list_workloads`] returns; see [`super::workload_list`].
+    pub list_limit: usize,
+}
+
+impl Default for CloudConfig {
+    fn default() -> Self {
+        Self {
+            api_url: None,
+            api_key: None,
+            boot_timeout: Duration::from_secs(600),
+            poll_interval: Duration::from_secs(5),
+            transition_timeout: Duration::from_secs(300),
+            list_limit: 1000,
+        }
+    }
+}
+
+// The API key is left out.
+impl fmt::Debug for CloudConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CloudConfig")
+            .field("api_url", &self.api_url)
+            .field("boot_timeout", &self.boot_timeout)
+            .field("poll_interval", &self.poll_interval)
+            .field("transition_timeout", &self.transition_timeout)
+            .field("list_limit", &self.list_limit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CloudConfig {
+    /// Reads `PHALA_CLOUD_API_URL`, `PHALA_CLOUD_API_KEY`, `PHALA_CLOUD_BOOT_TIMEOUT_SECS`,
+    /// `PHALA_CLOUD_POLL_INTERVAL_MS`, `PHALA_CLOUD_TRANSITION_TIMEOUT_SECS` and
+    /// `PHALA_CLOUD_LIST_LIMIT`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        Ok(Self {
+            api_url: env_opt("PHALA_CLOUD_API_URL")?,
+            api_key: env_opt("PHALA_CLOUD_API_KEY")?,
+            boot_timeout: Duration::from_secs(env_or(
+                "PHALA_CLOUD_BOOT_TIMEOUT_SECS",
+                defaults.boot_timeout.as_secs(),
+            )?),
+            poll_interval: Duration::from_millis(env_or(
+                "PHALA_CLOUD_POLL_INTERVAL_MS",
+                defaults.poll_interval.as_millis() as u64,
+            )?),
+            transition_timeout: Duration::from_secs(env_or(
+                "PHALA_CLOUD_TRANSITION_TIMEOUT_SECS",
+                defaults.transition_timeout.as_secs(),
+            )?),
+            list_limit: env_or("PHALA_CLOUD_LIST_LIMIT", defaults.list_limit)?,
+        })
+    }
+}
+
+/// A docker-compose workload and the resources it asks for.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkloadManifest {
+    /// Name of the CVM.
+    pub name: String,
+    /// The docker-compose file, as YAML.
+    pub compose: String,
+    pub resources: Resources,
+}
+
+impl WorkloadManifest {
+    /// Rejects manifests no deployment could succeed with, before anything is sent.
+    pub fn validate(&self) -> Result<(), PhalaAvsError> {
+        let invalid = |reason: &str| {
+            PhalaAvsError::ValidationError(format!("Invalid workload manifest: {reason}"))
+        };
+        if self.name.trim().is_empty() {
+            return Err(invalid("the name is empty"));
+        }
+        if self.compose.trim().is_empty() {
+            return Err(invalid("the compose file is empty"));
+        }
+        let Resources {
+            vcpus,
+            memory_mb,
+            storage_gb,
+        } = self.resources;
+        if vcpus == 0 || memory_mb == 0 || storage_gb == 0 {
+            return Err(invalid("vCPUs, memory and disk must all be requested"));
+        }
+        Ok(())
+    }
+}
+
+/// The id Phala Cloud assigned to a deployed CVM.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct WorkloadId(pub String);
+
+impl fmt::Display for WorkloadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Why a deployment failed, by phase.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeployFailure {
+    /// The API refused the manifest.
+    Rejected { status: u16, reason: String },
+    /// The account cannot take the requested resources.
+    QuotaExceeded { reason: String },
+    /// The API could not be reached or failed.
+    Unavailable(String),
+    /// The CVM stopped before it ran.
+    BootFailed { id: WorkloadId, status: String },
+    /// The API knows no CVM of this id.
+    NotFound(WorkloadId),
+    /// The CVM was not running within the boot timeout.
+    BootTimeout {
+        id: WorkloadId,
+        after_secs: u64,
+        last_status: String,
+    },
+}
+
+impl DeployFailure {
+    /// Label of the `outcome` of [`WORKLOAD_DEPLOYMENTS_METRIC`].
+    pub fn phase(&self) -> &'static str {
+        match self {
+            Self::Rejected { .. } => "rejected",
+            Self::QuotaExceeded { .. } => "quota_exceeded",
+            Self::Unavailable(_) => "unavailable",
+            Self::BootFailed { .. } => "boot_failed",
+            Self::NotFound(_) => "not_found",
+            Self::BootTimeout { .. } => "boot_timeout",
+        }
+    }
+}
+
+impl fmt::Display for DeployFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected { status, reason } => {
+                write!(f, "Phala Cloud rejected the manifest ({status}): {reason}")
+            }
+            Self::QuotaExceeded { reason } => write!(f, "Phala Cloud quota exceeded: {reason}"),
+            Self::Unavailable(reason) => write!(f, "Phala Cloud API unavailable: {reason}"),
+            Self::BootFailed { id, status } => write!(f, "CVM {id} failed to boot: {status}"),
+            Self::NotFound(id) => write!(f, "CVM {id} does not exist"),
+            Self::BootTimeout {
+                id,
+                after_secs,
+                last_status,
+            } => write!(
+                f,
+                "CVM {id} was not running after {after_secs}s (last status: {last_status})"
+            ),
+        }
+    }
+}
+
+/// The Phala Cloud CVM API.
+pub trait CloudApi: Send + Sync + fmt::Debug {
+    /// Creates a CVM running `manifest`, returning its id.
+    fn create(
+        &self,
+        manifest: &WorkloadManifest,
+    ) -> BoxFuture<'_, Result<WorkloadId, DeployFailure>>;
+
+    /// The CVM, as the API reports it.
+    fn cvm(&self, id: &WorkloadId) -> BoxFuture<'_, Result<CvmInfo, DeployFailure>>;
+
+    /// Asks the CVM to shut down, keeping it for a later start.
+    fn stop(&self, id: &WorkloadId) -> BoxFuture<'_, Result<(), DeployFailure>>;
+
+    /// Asks the CVM to reboot.
+    fn restart(&self, id: &WorkloadId) -> BoxFuture<'_, Result<(), DeployFailure>>;
+
+    /// Asks for the CVM to be deleted.
+    fn delete(&self, id: &WorkloadId) -> BoxFuture<'_, Result<(), DeployFailure>>;
+
+    /// The page of CVMs starting at `cursor`, or the first page, narrowed by `filter` as far as
+    /// the API supports it.
+    fn list(
+        &self,
+        filter: &WorkloadFilter,
+        cursor: Option<&str>,
+    ) -> BoxFuture<'_, Result<CvmPage, DeployFailure>>;
+
+    /// The body of the CVM's logs from `since` ago on, kept open for new lines if `follow`.
+    fn logs(
+        &self,
+        id: &WorkloadId,
+        since: Duration,
+        follow: bool,
+    ) -> BoxFuture<'_, Result<LogChunks, DeployFailure>>;
+
+    /// The CVM's resource use, as the API reports it; see [`super::resource_metrics`].
+    fn metrics(&self, id: &WorkloadId) -> BoxFuture<'_, Result<serde_json::Value, DeployFailure>>;
+}
+
+/// [`CloudApi`] over `GET` and `POST <url>/cvms`, `GET` and `DELETE <url>/cvms/<id>`,
+/// `POST <url>/cvms/<id>/stop` and `/restart`, and `GET <url>/cvms/<id>/logs` and `/metrics`.
+///
+/// Reads are retried as [`super::retries`] describes. The `POST` creating a CVM is sent once: a
+/// failure there may come after the CVM was created, and a retry would create another. Stops,
+/// restarts and deletions are only retried when they could not connect, as one whose reply was
+/// lost may have been carried out, and a second restart would restart the CVM again.
+#[derive(Clone)]
+pub struct HttpCloudApi {
+    url: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+    retry_policy: RetryPolicy,
+    cancel: CancelToken,
+}
+
+#[derive(Serialize)]
+struct CreateCvm<'a> {
+    name: &'a str,
+    compose_file: &'a str,
+    vcpu: u32,
+    memory_mb: u64,
+    disk_size_gb: u64,
+}
+
+#[derive(Deserialize)]
+struct CreatedCvm {
+    id: String,
+}
+
+impl fmt::Debug for HttpCloudApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpCloudApi")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HttpCloudApi {
+    pub fn new(url: String, api_key: Option<String>) -> Self {
+        Self {
+            url,
+            api_key,
+            client: reqwest::Client::new(),
+            retry_policy: TeeHandlerConfig::default().retry_policy(),
+            cancel: CancelToken::default(),
+        }
+    }
+
+    /// Retries calls under `policy`, usually the [`TeeHandlerConfig::retry_policy`] of the
+    /// handler the API is given to.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Gives up retrying calls once `cancel` is cancelled, usually on shutdown.
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}{path}", self.url.trim_end_matches('/')));
+        match &self.api_key {
+            Some(key) => request.header("X-API-Key", key),
+            None => request,
+        }
+    }
+
+    /// Sends `GET path` with `query`, reading a JSON reply.
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, DeployFailure> {
+        Self::json(self.succeed(reqwest::Method::GET, path, query).await?).await
+    }
+
+    async fn json<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, DeployFailure> {
+        response.json().await.map_err(|e| {
+            DeployFailure::Unavailable(format!(
+                "invalid reply: {}",
+                sanitize::message("tee_reply", e)
+            ))
+        })
+    }
+
+    /// Sends `method path` about the CVM `id`, ignoring the reply. Only attempts that could not
+    /// connect are retried.
+    fn act(
+        &self,
+        method: reqwest::Method,
+        path: String,
+        id: &WorkloadId,
+    ) -> BoxFuture<'_, Result<(), DeployFailure>> {
+        let id = id.clone();
+        Box::pin(async move {
+            let outcome = retrying(
+                &self.retry_policy,
+                &self.cancel,
+                &format!("{method} {path} to Phala Cloud"),
+                |failure: &ActionFailure| !failure.connected,
+                |interrupted| ActionFailure {
+                    failure: interruption(interrupted),
+                    connected: true,
+                },
+                || {
+                    let request = self.request(method.clone(), &path);
+                    async move {
+                        match request.send().await {
+                            Err(e) if e.is_connect() => Err(ActionFailure {
+                                failure: unavailable(e),
+                                connected: false,
+                            }),
+                            sent => Self::reply(sent).await.map_err(|failure| ActionFailure {
+                                failure,
+                                connected: true,
+                            }),
+                        }
+                    }
+                },
+            )
+            .await;
+            match outcome.map_err(|e| e.failure) {
+                Ok(_) => Ok(()),
+                Err(DeployFailure::Rejected { status: 404, .. }) => {
+                    Err(DeployFailure::NotFound(id))
+                }
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Sends `method path` with `query` under the retry policy, retrying while the API is
+    /// unavailable.
+    async fn succeed(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<reqwest::Response, DeployFailure> {
+        retrying(
+            &self.retry_policy,
+            &self.cancel,
+            &format!("{method} {path} to Phala Cloud"),
+            |e| matches!(e, DeployFailure::Unavailable(_)),
+            interruption,
+            || Self::attempt(self.request(method.clone(), path).query(query)),
+        )
+        .await
+    }
+
+    /// Sends `request` once, sorting failures into phases.
+    async fn attempt(request: reqwest::RequestBuilder) -> Result<reqwest::Response, DeployFailure> {
+        Self::reply(request.send().await).await
+    }
+
+    /// Sorts the outcome of sending a request into phases.
+    async fn reply(
+        sent: Result<reqwest::Response, reqwest::Error>,
+    ) -> Result<reqwest::Response, DeployFailure> {
+        let response = sent.map_err(unavailable)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        let reason = sanitize::message("tee_reply", body.trim());
+        Err(if status.is_server_error() {
+            DeployFailure::Unavailable(format!("{status}: {reason}"))
+        } else if status == reqwest::StatusCode::PAYMENT_REQUIRED
+            || reason.to_ascii_lowercase().contains("quota")
+        {
+            DeployFailure::QuotaExceeded { reason }
+        } else {
+            DeployFailure::Rejected {
+                status: status.as_u16(),
+                reason,
+            }
+        })
+    }
+}
+
+/// The API as unavailable to a call given up on.
+fn interruption(interrupted: Interrupted) -> DeployFailure {
+    DeployFailure::Unavailable(match interrupted {
+        Interrupted::Cancelled => "cancelled".to_string(),
+        Interrupted::TimedOut(budget) => format!("no reply within {budget:?}"),
+    })
+}
+
+fn unavailable(e: reqwest::Error) -> DeployFailure {
+    DeployFailure::Unavailable(sanitize::message("tee_reply", e))
+}
+
+/// A failed stop, restart or deletion, and whether it reached the API at all.
+struct ActionFailure {
+    failure: DeployFailure,
+    connected: bool,
+}
+
+impl fmt::Display for ActionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.failure.fmt(f)
+    }
+}
+
+impl CloudApi for HttpCloudApi {
+    fn create(
+        &self,
+        manifest: &WorkloadManifest,
+    ) -> BoxFuture<'_, Result<WorkloadId, DeployFailure>> {
+        let request = self
+            .request(reqwest::Method::POST, "/cvms")
+            .json(&CreateCvm {
+                name: &manifest.name,
+                compose_file: &manifest.compose,
+                vcpu: manifest.resources.vcpus,
+                memory_mb: manifest.resources.memory_mb,
+                disk_size_gb: manifest.resources.storage_gb,
+            });
+        Box::pin(async move {
+            let created: CreatedCvm = Self::json(Self::attempt(request).await?).await?;
+            Ok(WorkloadId(created.id))
+        })
+    }
+
+    fn cvm(&self, id: &WorkloadId) -> BoxFuture<'_, Result<CvmInfo, DeployFailure>> {
+        let id = id.clone();
+        Box::pin(async move {
+            match self.get(&format!("/cvms/{id}"), &[]).await {
+                Err(DeployFailure::Rejected { status: 404, .. }) => {
+                    Err(DeployFailure::NotFound(id))
+                }
+                reply => reply,
+            }
+        })
+    }
+
+    fn stop(&self, id: &WorkloadId) -> BoxFuture<'_, Result<(), DeployFailure>> {
+        self.act(reqwest::Method::POST, format!("/cvms/{id}/stop"), id)
+    }
+
+    fn restart(&self, id: &WorkloadId) -> BoxFuture<'_, Result<(), DeployFailure>> {
+        self.act(reqwest::Method::POST, format!("/cvms/{id}/restart"), id)
+    }
+
+    fn delete(&self, id: &WorkloadId) -> BoxFuture<'_, Result<(), DeployFailure>> {
+        self.act(reqwest::Method::DELETE, format!("/cvms/{id}"), id)
+    }
+
+    fn list(
+        &self,
+        filter: &WorkloadFilter,
+        cursor: Option<&str>,
+    ) -> BoxFuture<'_, Result<CvmPage, DeployFailure>> {
+        let mut query = filter.query();
+        query.extend(cursor.map(|cursor| ("cursor", cursor.to_string())));
+        Box::pin(async move { self.get("/cvms", &query).await })
+    }
+
+    fn logs(
+        &self,
+        id: &WorkloadId,
+        since: Duration,
+        follow: bool,
+    ) -> BoxFuture<'_, Result<LogChunks, DeployFailure>> {
+        let query = [
+            ("since_secs", since.as_secs().to_string()),
+            ("follow", follow.to_string()),
+        ];
+        let id = id.clone();
+        Box::pin(async move {
+            let path = format!("/cvms/{id}/logs");
+            let response = match self.succeed(reqwest::Method::GET, &path, &query).await {
+                Err(DeployFailure::Rejected { status: 404, .. }) => {
+                    return Err(DeployFailure::NotFound(id));
+                }
+                response => response?,
+            };
+            let chunks = futures::stream::unfold(Some(response), |response| async move {
+                let mut response = response?;
+                match response.chunk().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk.to_vec()), Some(response))),
+                    Ok(None) => None,
+                    Err(e) => Some((
+                        Err(DeployFailure::Unavailable(sanitize::message(
+                            "tee_reply",
+                            e,
+                        ))),
+                        None,
+                    )),
+                }
+            });
+            Ok(Box::pin(chunks) as LogChunks)
+        })
+    }
+
+    fn metrics(&self, id: &WorkloadId) -> BoxFuture<'_, Result<serde_json::Value, DeployFailure>> {
+        let id = id.clone();
+        Box::pin(async move {
+            match self.get(&format!("/cvms/{id}/metrics"), &[]).await {
+                Err(DeployFailure::Rejected { status: 404, .. }) => {
+                    Err(DeployFailure::NotFound(id))
+                }
+                reply => reply,
+            }
+        })
+    }
+}
+
+/// The API and timing used by [`TeeHandler::deploy_workload`] and the [`super::lifecycle`]
+/// methods.
+#[derive(Clone, Debug)]
+pub(super) struct CloudDeployer {
+    config: CloudConfig,
+    api: Arc<dyn CloudApi>,
+}
+
+impl CloudDeployer {
+    pub(super) fn api(&self) -> &dyn CloudApi {
+        self.api.as_ref()
+    }
+
+    pub(super) fn config(&self) -> &CloudConfig {
+        &self.config
+    }
+
+    async fn deploy(&self, manifest: &WorkloadManifest) -> Result<WorkloadId, DeployFailure> {
+        let id = self.api.create(manifest).await?;
+        info!(
+            "Phala Cloud assigned CVM {id} to workload {}",
+            manifest.name
+        );
+        let started = Instant::now();
+        let mut last_status = "unknown".to_string();
+        loop {
+            match self.api.cvm(&id).await {
+                Ok(cvm) => match WorkloadStatus::from(&cvm) {
+                    WorkloadStatus::Running { .. } => return Ok(id),
+                    WorkloadStatus::Stopped { exit_reason } => {
+                        let status = match exit_reason {
+                            Some(reason) => format!("{} ({reason})", cvm.status),
+                            None => cvm.status,
+                        };
+                        return Err(DeployFailure::BootFailed { id, status });
+                    }
+                    _ => last_status = cvm.status,
+                },
+                Err(DeployFailure::Unavailable(e)) => {
+                    warn!("Failed to poll CVM {id}, retrying: {e}");
+                }
+                Err(e) => return Err(e),
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= self.config.boot_timeout {
+                return Err(DeployFailure::BootTimeout {
+                    id,
+                    after_secs: elapsed.as_secs(),
+                    last_status,
+                });
+            }
+            tokio::time::sleep(
+                self.config
+                    .poll_interval
+                    .min(self.config.boot_timeout - elapsed),
+            )
+            .await;
+        }
+    }
+}
+
+impl TeeHandler {
+    /// Deploys workloads through `api`, waiting for them as `config` says.
+    pub fn with_cloud(mut self, config: CloudConfig, api: Arc<dyn CloudApi>) -> Self {
+        self.cloud = Some(CloudDeployer { config, api });
+        self
+    }
+
+    pub(super) fn cloud_deployer(&self) -> Result<&CloudDeployer, PhalaAvsError> {
+        self.cloud.as_ref().ok_or_else(|| {
+            PhalaAvsError::TeeError(
+                "Managing cloud workloads needs the Phala Cloud API (PHALA_CLOUD_API_URL)"
+                    .to_string(),
+            )
+        })
+    }
+
+    /// Deploys `manifest` to Phala Cloud, returning the id of its CVM once it is running.
+    pub async fn deploy_workload(
+        &self,
+        manifest: WorkloadManifest,
+    ) -> Result<WorkloadId, PhalaAvsError> {
+        self.inject_faults().await?;
+        manifest.validate()?;
+        let outcome = self.cloud_deployer()?.deploy(&manifest).await;
+        let label = match &outcome {
+            Ok(_) => "running",
+            Err(failure) => failure.phase(),
+        };
+        METRICS.inc_counter(WORKLOAD_DEPLOYMENTS_METRIC, &[("outcome", label)], 1);
+        outcome.map_err(|failure| {
+            PhalaAvsError::TeeError(format!(
+                "Deployment of workload {} failed: {failure}",
+                manifest.name
+            ))
+        })
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandlerConfig;
+    use axum::extract::{Path, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A Phala Cloud stand-in: creation answers `create`, and the CVM reports `statuses` in turn,
+    /// repeating the last.
+    #[derive(Default)]
+    struct MockCloud {
+        create: Mutex<Option<(StatusCode, serde_json::Value)>>,
+        statuses: Vec<&'static str>,
+        polls: AtomicUsize,
+        created: Mutex<Vec<serde_json::Value>>,
+    }
+
+    async fn create(
+        State(cloud): State<Arc<MockCloud>>,
+        headers: HeaderMap,
+        Json(body): Json<serde_json::Value>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        if headers.get("x-api-key").and_then(|k| k.to_str().ok()) != Some("test-key") {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(serde_json::json!({"error": "bad key"})),
+            );
+        }
+        cloud.created.lock().unwrap().push(body);
+        let (status, reply) = cloud
+            .create
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or((StatusCode::CREATED, serde_json::json!({"id": "cvm-7f3a"})));
+        (status, Json(reply))
+    }
+
+    async fn status(
+        State(cloud): State<Arc<MockCloud>>,
+        Path(id): Path<String>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        if id != "cvm-7f3a" {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({})));
+        }
+        let poll = cloud.polls.fetch_add(1, Ordering::SeqCst);
+        let status = cloud.statuses[poll.min(cloud.statuses.len() - 1)];
+        (StatusCode::OK, Json(serde_json::json!({"status": status})))
+    }
+
+    async fn handler(cloud: Arc<MockCloud>) -> TeeHandler {
+        let app = Router::new()
+            .route("/api/v1/cvms", post(create))
+            .route("/api/v1/cvms/{id}", get(status))
+            .with_state(cloud);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let api = HttpCloudApi::new(
+            format!("http://{addr}/api/v1/"),
+            Some("test-key".to_string()),
+        );
+        let config = CloudConfig {
+            boot_timeout: Duration::from_millis(300),
+            poll_interval: Duration::from_millis(20),
+            ..CloudConfig::default()
+        };
+        TeeHandler::new(TeeHandlerConfig::default())
+            .await
+            .unwrap()
+            .with_cloud(config, Arc::new(api))
+    }
+
+    fn manifest() -> WorkloadManifest {
+        WorkloadManifest {
+            name: "sla-probe".to_string(),
+            compose: "services:\n  probe:\n    image: phala/sla-probe:1.2\n".to_string(),
+            resources: Resources {
+                vcpus: 2,
+                memory_mb: 4096,
+                storage_gb: 20,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn deployment_waits_for_the_cvm_to_run() {
+        let cloud = Arc::new(MockCloud {
+            statuses: vec!["creating", "starting", "running"],
+            ..MockCloud::default()
+        });
+        let tee = handler(cloud.clone()).await;
+        let id = tee.deploy_workload(manifest()).await.unwrap();
+        assert_eq!(id, WorkloadId("cvm-7f3a".to_string()));
+        assert_eq!(cloud.polls.load(Ordering::SeqCst), 3);
+        assert_eq!(cloud.created.lock().unwrap()[..], [serde_json::json!({
+            "name": "sla-probe",
+            "compose_file": manifest().compose,
+            "vcpu": 2,
+            "memory_mb": 4096,
+            "disk_size_gb": 20,
+        })]);
+    }
+
+    #[tokio::test]
+    async fn quota_and_rejections_fail_before_booting() {
+        let cloud = Arc::new(MockCloud {
+            create: Mutex::new(Some((
+                StatusCode::PAYMENT_REQUIRED,
+                serde_json::json!({"error": "vCPU limit of 4 reached"}),
+            ))),
+            statuses: vec!["running"],
+            ..MockCloud::default()
+        });
+        let tee = handler(cloud.clone()).await;
+        let err = tee.deploy_workload(manifest()).await.unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("Phala Cloud quota exceeded") && message.contains("vCPU limit"),
+            "{message}"
+        );
+
+        *cloud.create.lock().unwrap() = Some((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            serde_json::json!({"error": "invalid compose file"}),
+        ));
+        let err = tee.deploy_workload(manifest()).await.unwrap_err();
+        assert!(
+            err.to_string().contains("rejected the manifest (422)"),
+            "{err}"
+        );
+        assert_eq!(cloud.polls.load(Ordering::SeqCst), 0);
+
+        // Invalid manifests are not sent at all.
+        let empty = WorkloadManifest {
+            compose: " ".to_string(),
+            ..manifest()
+        };
+        assert!(matches!(
+            tee.deploy_workload(empty).await,
+            Err(PhalaAvsError::ValidationError(_))
+        ));
+        assert_eq!(cloud.created.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn boot_timeouts_and_failures_are_told_apart() {
+        let booting = Arc::new(MockCloud {
+            statuses: vec!["starting"],
+            ..MockCloud::default()
+        });
+        let err = handler(booting.clone())
+            .await
+            .deploy_workload(manifest())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("CVM cvm-7f3a was not running after")
+                && err.to_string().contains("(last status: starting)"),
+            "{err}"
+        );
+        assert!(booting.polls.load(Ordering::SeqCst) > 1);
+
+        let failing = Arc::new(MockCloud {
+            statuses: vec!["starting", "failed"],
+            ..MockCloud::default()
+        });
+        let err = handler(failing)
+            .await
+            .deploy_workload(manifest())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("CVM cvm-7f3a failed to boot: failed"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Fleet mode: one handler watching several CVMs run under the same AVS registration.
+//!
+//! `TEE_FLEET` names the CVMs' agents as comma-separated `name=endpoint` pairs, each endpoint
+//! read like `TEE_ENDPOINT` and reached with the handler's other TEE settings.
+//! [`TeeHandler::check_liveness_all`] probes every instance, at most `TEE_FLEET_CONCURRENCY` at
+//! a time, and sums them up as a [`FleetVerdict`]. An instance whose probe fails is reported as
+//! such without cutting the sweep short. Without a fleet, the sweep probes the handler's own
+//! endpoint as the single instance `local`.
+
+use super::TeeHandler;
+use super::config::{TeeEndpoint, TeeHandlerConfig};
+use super::liveness::{LivenessCheck, probe};
+use super::tappd::TappdClient;
+use crate::config::{self, env_or};
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+
+/// Gauge set to 1 for each fleet instance found live by the last sweep and 0 for the others.
+pub const TEE_FLEET_LIVE_METRIC: &str = "phala_avs_tee_fleet_live";
+
+/// Name of the handler's own endpoint in a sweep without a fleet.
+pub const LOCAL_INSTANCE: &str = "local";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FleetConfig {
+    /// Named agents, in the order sweeps report them.
+    pub instances: Vec<(String, TeeEndpoint)>,
+    /// Probes a sweep runs at once.
+    pub concurrency: usize,
+}
+
+impl Default for FleetConfig {
+    fn default() -> Self {
+        Self {
+            instances: Vec::new(),
+            concurrency: 4,
+        }
+    }
+}
+
+impl FleetConfig {
+    /// Reads `TEE_FLEET` and `TEE_FLEET_CONCURRENCY`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let mut names = BTreeSet::new();
+        let instances = config::lookup("TEE_FLEET")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|entry| {
+                let invalid = |reason: &str| {
+                    PhalaAvsError::ConfigError(format!(
+                        "Invalid TEE_FLEET entry {entry:?}: {reason}"
+                    ))
+                };
+                let (name, endpoint) = entry
+                    .split_once('=')
+                    .ok_or_else(|| invalid("expected name=endpoint"))?;
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(invalid("the name is empty"));
+                }
+                if !names.insert(name.to_string()) {
+                    return Err(invalid("the name is taken"));
+                }
+                Ok((name.to_string(), TeeEndpoint::parse(endpoint)?))
+            })
+            .collect::<Result<_, _>>()?;
+        let concurrency = env_or("TEE_FLEET_CONCURRENCY", Self::default().concurrency)?;
+        if concurrency == 0 {
+            return Err(PhalaAvsError::ConfigError(
+                "TEE_FLEET_CONCURRENCY must be positive".to_string(),
+            ));
+        }
+        Ok(Self {
+            instances,
+            concurrency,
+        })
+    }
+}
+
+/// The agents of a fleet.
+#[derive(Clone, Debug)]
+pub(super) struct Fleet {
+    instances: Vec<(String, TappdClient)>,
+    concurrency: usize,
+}
+
+/// How much of the fleet is live.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FleetVerdict {
+    AllLive,
+    PartiallyLive,
+    NoneLive,
+}
+
+impl fmt::Display for FleetVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::AllLive => "all live",
+            Self::PartiallyLive => "partially live",
+            Self::NoneLive => "none live",
+        })
+    }
+}
+
+/// What the sweep found at one instance.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceLiveness {
+    pub name: String,
+    /// The probe, or why it could not be made.
+    pub check: Result<LivenessCheck, String>,
+}
+
+impl InstanceLiveness {
+    pub fn is_live(&self) -> bool {
+        self.check.as_ref().is_ok_and(LivenessCheck::is_live)
+    }
+}
+
+/// A sweep of the fleet.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FleetLiveness {
+    /// Every instance, in configuration order.
+    pub instances: Vec<InstanceLiveness>,
+    pub verdict: FleetVerdict,
+}
+
+impl FleetLiveness {
+    pub fn new(instances: Vec<InstanceLiveness>) -> Self {
+        let live = instances.iter().filter(|i| i.is_live()).count();
+        let verdict = match live {
+            0 => FleetVerdict::NoneLive,
+            live if live == instances.len() => FleetVerdict::AllLive,
+            _ => FleetVerdict::PartiallyLive,
+        };
+        Self { instances, verdict }
+    }
+
+    pub fn live_count(&self) -> usize {
+        self.instances.iter().filter(|i| i.is_live()).count()
+    }
+}
+
+impl Fleet {
+    pub(super) fn clients(&self) -> impl Iterator<Item = &TappdClient> {
+        self.instances.iter().map(|(_, tappd)| tappd)
+    }
+}
+
+impl TeeHandler {
+    /// Sweeps the agents `config` names, reached with the handler's TEE settings; an empty
+    /// fleet leaves the handler sweeping its own endpoint.
+    pub fn with_fleet(mut self, config: FleetConfig) -> Result<Self, PhalaAvsError> {
+        if config.instances.is_empty() {
+            self.fleet = None;
+            return Ok(self);
+        }
+        let instances = config
+            .instances
+            .into_iter()
+            .map(|(name, endpoint)| {
+                let settings = TeeHandlerConfig {
+                    endpoint,
+                    ..self.tappd.config().clone()
+                };
+                Ok((
+                    name,
+                    TappdClient::new(settings)?.with_cancel(self.retries.clone()),
+                ))
+            })
+            .collect::<Result<_, PhalaAvsError>>()?;
+        self.fleet = Some(Arc::new(Fleet {
+            instances,
+            concurrency: config.concurrency.max(1),
+        }));
+        self.share_ra_tls_trust();
+        Ok(self)
+    }
+
+    pub fn has_fleet(&self) -> bool {
+        self.fleet.is_some()
+    }
+
+    /// Probes every instance of the fleet, or the handler's own endpoint without one.
+    pub async fn check_liveness_all(&self) -> FleetLiveness {
+        let (instances, concurrency): (Vec<(&str, &TappdClient)>, usize) = match &self.fleet {
+            Some(fleet) => (
+                fleet
+                    .instances
+                    .iter()
+                    .map(|(name, tappd)| (name.as_str(), tappd))
+                    .collect(),
+                fleet.concurrency,
+            ),
+            None => (vec![(LOCAL_INSTANCE, &self.tappd)], 1),
+        };
+        let instances = stream::iter(instances)
+            .map(|(name, tappd)| async move {
+                let check = match self.inject_faults().await {
+                    Ok(()) => probe(tappd).await,
+                    Err(e) => Err(e),
+                };
+                let instance = InstanceLiveness {
+                    name: name.to_string(),
+                    check: check.map_err(|e| e.to_string()),
+                };
+                let live = if instance.is_live() { 1.0 } else { 0.0 };
+                METRICS.set_gauge(TEE_FLEET_LIVE_METRIC, &[("instance", name)], live);
+                instance
+            })
+            .buffered(concurrency)
+            .collect()
+            .await;
+        FleetLiveness::new(instances)
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::tee::liveness::Liveness;
+    use axum::Router;
+    use axum::routing::get;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Probes in flight, and the most there were at once.
+    #[derive(Default)]
+    struct InFlight {
+        now: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    /// A live agent answering after 50ms.
+    async fn agent(in_flight: Arc<InFlight>) -> TeeEndpoint {
+        let app = Router::new().route(
+            "/prpc/Tappd.Info",
+            get(move || async move {
+                let now = in_flight.now.fetch_add(1, Ordering::SeqCst) + 1;
+                in_flight.max.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                in_flight.now.fetch_sub(1, Ordering::SeqCst);
+                "{}"
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        TeeEndpoint::parse(&format!("http://{addr}")).unwrap()
+    }
+
+    async fn fleet(instances: Vec<(&str, TeeEndpoint)>, concurrency: usize) -> TeeHandler {
+        let config = FleetConfig {
+            instances: instances
+                .into_iter()
+                .map(|(name, endpoint)| (name.to_string(), endpoint))
+                .collect(),
+            concurrency,
+        };
+        TeeHandler::new(TeeHandlerConfig::default())
+            .await
+            .unwrap()
+            .with_fleet(config)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_down_instance_leaves_the_fleet_partially_live() {
+        let in_flight = Arc::new(InFlight::default());
+        let down = TeeEndpoint::parse("http://127.0.0.1:1").unwrap();
+        let tee = fleet(
+            vec![
+                ("cvm-a", agent(in_flight.clone()).await),
+                ("cvm-b", down),
+                ("cvm-c", agent(in_flight.clone()).await),
+            ],
+            3,
+        )
+        .await;
+        let sweep = tee.check_liveness_all().await;
+        assert_eq!(sweep.verdict, FleetVerdict::PartiallyLive);
+        assert_eq!(sweep.live_count(), 2);
+        let names: Vec<_> = sweep.instances.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["cvm-a", "cvm-b", "cvm-c"]);
+        assert!(sweep.instances[0].is_live() && sweep.instances[2].is_live());
+        assert_eq!(
+            sweep.instances[1].check.as_ref().unwrap().liveness,
+            Liveness::Unreachable {
+                endpoint: "http://127.0.0.1:1/".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn probes_run_at_most_concurrency_at_a_time() {
+        let in_flight = Arc::new(InFlight::default());
+        let mut instances = Vec::new();
+        for name in ["cvm-a", "cvm-b", "cvm-c", "cvm-d"] {
+            instances.push((name, agent(in_flight.clone()).await));
+        }
+        let sweep = fleet(instances, 2).await.check_liveness_all().await;
+        assert_eq!(sweep.verdict, FleetVerdict::AllLive);
+        assert_eq!(in_flight.max.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn without_a_fleet_the_own_endpoint_is_swept() {
+        let down = TeeHandler::new(TeeHandlerConfig {
+            endpoint: TeeEndpoint::parse("http://127.0.0.1:1").unwrap(),
+            ..TeeHandlerConfig::default()
+        })
+        .await
+        .unwrap();
+        assert!(!down.has_fleet());
+        let sweep = down.check_liveness_all().await;
+        assert_eq!(sweep.verdict, FleetVerdict::NoneLive);
+        assert_eq!(sweep.instances[0].name, LOCAL_INSTANCE);
+    }
+}
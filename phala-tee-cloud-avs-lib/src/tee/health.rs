@@ -0,0 +1,583 @@
+//! Composite health of the TEE, scored from several sub-checks.
+//!
+//! Liveness alone says little about whether SLA attestations can be trusted, so
+//! [`TeeHandler::health_check`] also has the TEE quote, reads the status of the
+//! `TEE_HEALTH_WORKLOAD` workload, compares the host clock with the chain head and checks the
+//! TEE's disk headroom. Reachability is scored from the liveness probe, which the heartbeat has
+//! already run, and the quote comes through the quote cache, so health checks do not ask tappd
+//! for more than the heartbeat does. The other sub-checks run concurrently under their own
+//! `TEE_HEALTH_CHECK_TIMEOUT_MS`, and one failing or hanging does not keep the others from
+//! reporting. The score is the share of the weights of the sub-checks that passed, from 0 to
+//! 100; sub-checks that cannot run here, like the workload one without a workload, are left out
+//! rather than failed. The node attests while the score is at least `TEE_HEALTH_THRESHOLD`.
+
+use super::TeeHandler;
+use super::deploy::WorkloadId;
+use super::liveness::LivenessCheck;
+use super::platform::TeePlatform;
+use crate::config::{env_opt, env_or};
+use crate::error::PhalaAvsError;
+use crate::evm::EvmClient;
+use crate::metrics::METRICS;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Gauge of the last health score.
+pub const TEE_HEALTH_SCORE_METRIC: &str = "phala_avs_tee_health_score";
+
+/// Report data of health-check quotes, so they are told apart from challenge quotes.
+const HEALTH_REPORT_DATA: &[u8] = b"phala-avs-health-check";
+
+/// [`HEALTH_REPORT_DATA`], zero-padded to the size of a quote's report data.
+pub(super) fn health_report_data() -> [u8; 64] {
+    let mut report_data = [0; 64];
+    report_data[..HEALTH_REPORT_DATA.len()].copy_from_slice(HEALTH_REPORT_DATA);
+    report_data
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthConfig {
+    /// Lowest score at which the node attests.
+    pub threshold: u8,
+    /// Bounds each sub-check on its own.
+    pub check_timeout: Duration,
+    /// Largest difference between the host clock and the chain head's timestamp.
+    pub max_clock_skew: Duration,
+    /// Share of the TEE's disk that must stay free, in percent.
+    pub min_disk_free_pct: u8,
+    /// The workload whose status counts towards the score.
+    pub workload: Option<WorkloadId>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 80,
+            check_timeout: Duration::from_secs(5),
+            max_clock_skew: Duration::from_secs(30),
+            min_disk_free_pct: 10,
+            workload: None,
+        }
+    }
+}
+
+impl HealthConfig {
+    /// Reads `TEE_HEALTH_THRESHOLD`, `TEE_HEALTH_CHECK_TIMEOUT_MS`,
+    /// `TEE_HEALTH_MAX_CLOCK_SKEW_SECS`, `TEE_HEALTH_MIN_DISK_FREE_PCT` and `TEE_HEALTH_WORKLOAD`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let defaults = Self::default();
+        let config = Self {
+            threshold: env_or("TEE_HEALTH_THRESHOLD", defaults.threshold)?,
+            check_timeout: Duration::from_millis(env_or(
+                "TEE_HEALTH_CHECK_TIMEOUT_MS",
+                defaults.check_timeout.as_millis() as u64,
+            )?),
+            max_clock_skew: Duration::from_secs(env_or(
+                "TEE_HEALTH_MAX_CLOCK_SKEW_SECS",
+                defaults.max_clock_skew.as_secs(),
+            )?),
+            min_disk_free_pct: env_or("TEE_HEALTH_MIN_DISK_FREE_PCT", defaults.min_disk_free_pct)?,
+            workload: env_opt::<String>("TEE_HEALTH_WORKLOAD")?.map(WorkloadId),
+        };
+        if config.threshold > 100 || config.min_disk_free_pct > 100 {
+            return Err(PhalaAvsError::ConfigError(
+                "TEE_HEALTH_THRESHOLD and TEE_HEALTH_MIN_DISK_FREE_PCT are percentages".to_string(),
+            ));
+        }
+        if config.check_timeout.is_zero() {
+            return Err(PhalaAvsError::ConfigError(
+                "TEE_HEALTH_CHECK_TIMEOUT_MS must be positive".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// A sub-check of the health score.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthComponent {
+    /// The agent answers the liveness probe in time.
+    Reachability,
+    /// The TEE produces a quote.
+    Quote,
+    /// The configured workload is running and healthy.
+    Workload,
+    /// The host clock agrees with the chain.
+    ClockSkew,
+    /// The TEE's disk has room left.
+    DiskHeadroom,
+}
+
+impl HealthComponent {
+    /// How much the sub-check counts towards the score; the weights sum to 100.
+    pub fn weight(self) -> u32 {
+        match self {
+            Self::Reachability => 30,
+            Self::Quote => 25,
+            Self::Workload => 20,
+            Self::ClockSkew => 15,
+            Self::DiskHeadroom => 10,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Reachability => "reachability",
+            Self::Quote => "quote",
+            Self::Workload => "workload",
+            Self::ClockSkew => "clock_skew",
+            Self::DiskHeadroom => "disk_headroom",
+        }
+    }
+}
+
+impl fmt::Display for HealthComponent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What a sub-check found.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", content = "reason", rename_all = "snake_case")]
+pub enum CheckOutcome {
+    Passed,
+    Failed(String),
+    /// The sub-check does not apply here, and is left out of the score.
+    Skipped(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComponentHealth {
+    pub component: HealthComponent,
+    pub outcome: CheckOutcome,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Share of the weights of the counted sub-checks that passed, from 0 to 100.
+    pub score: u8,
+    pub threshold: u8,
+    /// Whether the score reaches the threshold, so the node may attest.
+    pub healthy: bool,
+    /// The sub-checks that failed.
+    pub failed: Vec<HealthComponent>,
+    pub components: Vec<ComponentHealth>,
+}
+
+impl HealthReport {
+    /// Scores `components` against `threshold`. With no sub-check counted, the score is 0.
+    pub fn new(components: Vec<ComponentHealth>, threshold: u8) -> Self {
+        let weight = |passed_only: bool| -> u32 {
+            components
+                .iter()
+                .filter(|c| match &c.outcome {
+                    CheckOutcome::Passed => true,
+                    CheckOutcome::Failed(_) => !passed_only,
+                    CheckOutcome::Skipped(_) => false,
+                })
+                .map(|c| c.component.weight())
+                .sum()
+        };
+        let (passed, counted) = (weight(true), weight(false));
+        let score = if counted == 0 {
+            0
+        } else {
+            (passed * 100 / counted) as u8
+        };
+        let failed = components
+            .iter()
+            .filter(|c| matches!(c.outcome, CheckOutcome::Failed(_)))
+            .map(|c| c.component)
+            .collect();
+        Self {
+            score,
+            threshold,
+            healthy: score >= threshold,
+            failed,
+            components,
+        }
+    }
+}
+
+/// The chain whose head the host clock is compared with.
+#[derive(Clone)]
+pub(super) struct ChainClock(Arc<dyn EvmClient>);
+
+impl fmt::Debug for ChainClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChainClock").finish_non_exhaustive()
+    }
+}
+
+/// Runs `check` as `component`, failing it once `timeout` elapses.
+async fn run_check(
+    component: HealthComponent,
+    timeout: Duration,
+    check: impl Future<Output = CheckOutcome>,
+) -> ComponentHealth {
+    let outcome = tokio::time::timeout(timeout, check)
+        .await
+        .unwrap_or_else(|_| CheckOutcome::Failed(format!("no result within {timeout:?}")));
+    ComponentHealth { component, outcome }
+}
+
+fn failed(e: impl fmt::Display) -> CheckOutcome {
+    CheckOutcome::Failed(e.to_string())
+}
+
+impl TeeHandler {
+    /// Scores the TEE's health as `config` says.
+    pub fn with_health(mut self, config: HealthConfig) -> Self {
+        self.health = config;
+        self
+    }
+
+    /// Compares the host clock with the head of `evm` in health checks.
+    pub fn with_chain(mut self, evm: Arc<dyn EvmClient>) -> Self {
+        self.chain = Some(ChainClock(evm));
+        self
+    }
+
+    /// Probes liveness, then runs every other sub-check and scores them; see the module docs.
+    pub async fn health_check(&self) -> HealthReport {
+        let liveness = self.check_liveness().await;
+        self.score_health(&liveness).await
+    }
+
+    /// Runs every sub-check but reachability, which `liveness` answers, and scores them.
+    pub async fn score_health(
+        &self,
+        liveness: &Result<LivenessCheck, PhalaAvsError>,
+    ) -> HealthReport {
+        let timeout = self.health.check_timeout;
+        let reachability = ComponentHealth {
+            component: HealthComponent::Reachability,
+            outcome: match liveness {
+                Ok(check) if check.is_live() => CheckOutcome::Passed,
+                Ok(check) => failed(&check.liveness),
+                Err(e) => failed(e),
+            },
+        };
+        let (quote, workload, clock_skew, disk_headroom) = tokio::join!(
+            run_check(HealthComponent::Quote, timeout, self.check_quote()),
+            run_check(HealthComponent::Workload, timeout, self.check_workload()),
+            run_check(HealthComponent::ClockSkew, timeout, self.check_clock_skew()),
+            run_check(
+                HealthComponent::DiskHeadroom,
+                timeout,
+                self.check_disk_headroom()
+            ),
+        );
+        let report = HealthReport::new(
+            vec![reachability, quote, workload, clock_skew, disk_headroom],
+            self.health.threshold,
+        );
+        METRICS.set_gauge(TEE_HEALTH_SCORE_METRIC, &[], f64::from(report.score));
+        report
+    }
+
+    /// Quotes through the cache, so tappd is asked at most once per `TEE_QUOTE_CACHE_TTL_SECS`.
+    /// An agent that went down since is caught by reachability.
+    async fn check_quote(&self) -> CheckOutcome {
+        if self.platform() != TeePlatform::Tdx {
+            return CheckOutcome::Skipped(format!("no quotes on {}", self.platform()));
+        }
+        match self.quote_bundle(health_report_data()).await {
+            Ok(_) => CheckOutcome::Passed,
+            Err(e) => failed(e),
+        }
+    }
+
+    async fn check_workload(&self) -> CheckOutcome {
+        let Some(id) = &self.health.workload else {
+            return CheckOutcome::Skipped("no TEE_HEALTH_WORKLOAD".to_string());
+        };
+        match self.get_workload_status(id).await {
+            Ok(status) if status.is_healthy() => CheckOutcome::Passed,
+            Ok(status) => failed(format!("workload {id} is {}", status.state())),
+            Err(e) => failed(e),
+        }
+    }
+
+    async fn check_clock_skew(&self) -> CheckOutcome {
+        let Some(ChainClock(evm)) = &self.chain else {
+            return CheckOutcome::Skipped("no chain to compare with".to_string());
+        };
+        let head = match evm.block_number().await {
+            Ok(head) => head,
+            Err(e) => return failed(e),
+        };
+        let chain_secs = match evm.block_timestamp(head).await {
+            Ok(Some(secs)) => secs,
+            Ok(None) => return failed(format!("block {head} has no timestamp")),
+            Err(e) => return failed(e),
+        };
+        let host_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let skew = host_secs.abs_diff(chain_secs);
+        if skew > self.health.max_clock_skew.as_secs() {
+            return failed(format!(
+                "the host clock is {skew}s off block {head}, over {}s",
+                self.health.max_clock_skew.as_secs()
+            ));
+        }
+        CheckOutcome::Passed
+    }
+
+    async fn check_disk_headroom(&self) -> CheckOutcome {
+        let usage = match self.get_resource_metrics(None).await {
+            Ok(metrics) => metrics.disk_usage(),
+            Err(e) => return failed(e),
+        };
+        let Some(usage) = usage else {
+            return CheckOutcome::Skipped("tappd reports no disk use".to_string());
+        };
+        let free_pct = (1.0 - usage) * 100.0;
+        if free_pct < f64::from(self.health.min_disk_free_pct) {
+            return failed(format!(
+                "{free_pct:.1}% of the disk is free, under {}%",
+                self.health.min_disk_free_pct
+            ));
+        }
+        CheckOutcome::Passed
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::evm::BoxFuture;
+    use crate::tee::TeeHandlerConfig;
+    use crate::tee::deploy::{CloudConfig, HttpCloudApi};
+    use crate::tee::quote::{TDX_TEE_TYPE, TdxQuote};
+    use axum::Router;
+    use axum::routing::{get, post};
+    use blueprint_sdk::alloy::primitives::{Address, B256};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn component(component: HealthComponent, outcome: CheckOutcome) -> ComponentHealth {
+        ComponentHealth { component, outcome }
+    }
+
+    #[test]
+    fn the_score_weighs_the_counted_sub_checks() {
+        let all_passed: Vec<_> = [
+            HealthComponent::Reachability,
+            HealthComponent::Quote,
+            HealthComponent::Workload,
+            HealthComponent::ClockSkew,
+            HealthComponent::DiskHeadroom,
+        ]
+        .into_iter()
+        .map(|c| component(c, CheckOutcome::Passed))
+        .collect();
+        let report = HealthReport::new(all_passed.clone(), 80);
+        assert_eq!(report.score, 100);
+        assert!(report.healthy && report.failed.is_empty());
+
+        // 30 + 25 + 15 of 100.
+        let mut degraded = all_passed.clone();
+        degraded[2].outcome = CheckOutcome::Failed("workload cvm-1 is stopped".to_string());
+        degraded[4].outcome = CheckOutcome::Failed("3% free".to_string());
+        let report = HealthReport::new(degraded, 80);
+        assert_eq!(report.score, 70);
+        assert!(!report.healthy);
+        assert_eq!(report.failed, [
+            HealthComponent::Workload,
+            HealthComponent::DiskHeadroom
+        ]);
+
+        // Skipped sub-checks are left out: 30 + 25 of 30 + 25 + 10, rounded down.
+        let mut skipped = all_passed;
+        skipped[2].outcome = CheckOutcome::Skipped("no workload".to_string());
+        skipped[3].outcome = CheckOutcome::Skipped("no chain".to_string());
+        skipped[4].outcome = CheckOutcome::Failed("3% free".to_string());
+        let report = HealthReport::new(skipped, 80);
+        assert_eq!(report.score, 84);
+        assert!(report.healthy);
+
+        assert_eq!(HealthReport::new(Vec::new(), 0).score, 0);
+    }
+
+    /// A chain whose head is `lag_secs` behind the host clock.
+    struct Chain {
+        lag_secs: u64,
+    }
+
+    impl EvmClient for Chain {
+        fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(1) })
+        }
+
+        fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+            Box::pin(async { Ok(100) })
+        }
+
+        fn block_hash(&self, _: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn block_timestamp(&self, _: u64) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let secs = now.as_secs() - self.lag_secs;
+            Box::pin(async move { Ok(Some(secs)) })
+        }
+
+        fn is_operator_registered(&self, _: Address) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+            Box::pin(async { Ok(true) })
+        }
+    }
+
+    /// A TEE whose agent is live and reports its disk 95% full, but whose quotes hang, and a
+    /// Phala Cloud whose workload is running.
+    async fn handler(lag_secs: u64) -> TeeHandler {
+        let app = Router::new()
+            .route("/prpc/Tappd.Info", get(|| async { "{}" }))
+            .route(
+                "/prpc/Tappd.Metrics",
+                get(|| async { r#"{"disk": {"used": 95, "total": 100}}"# }),
+            )
+            .route(
+                "/prpc/Tappd.TdxQuote",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    "{}"
+                }),
+            )
+            .route(
+                "/cvms/{id}",
+                get(|| async { r#"{"status": "running", "uptime_secs": 60}"# }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let config = TeeHandlerConfig {
+            endpoint: crate::tee::TeeEndpoint::parse(&format!("http://{addr}")).unwrap(),
+            ..TeeHandlerConfig::default()
+        };
+        TeeHandler::new(config)
+            .await
+            .unwrap()
+            .with_cloud(
+                CloudConfig::default(),
+                Arc::new(HttpCloudApi::new(format!("http://{addr}"), None)),
+            )
+            .with_chain(Arc::new(Chain { lag_secs }))
+            .with_health(HealthConfig {
+                check_timeout: Duration::from_millis(300),
+                workload: Some(WorkloadId("cvm-1".to_string())),
+                ..HealthConfig::default()
+            })
+    }
+
+    fn outcome(report: &HealthReport, component: HealthComponent) -> &CheckOutcome {
+        &report
+            .components
+            .iter()
+            .find(|c| c.component == component)
+            .unwrap()
+            .outcome
+    }
+
+    #[tokio::test]
+    async fn failing_sub_checks_leave_the_others_running() {
+        let report = handler(5).await.health_check().await;
+        // The quote timed out and the disk is too full; everything else passed.
+        assert_eq!(report.failed, [
+            HealthComponent::Quote,
+            HealthComponent::DiskHeadroom
+        ]);
+        assert!(
+            matches!(
+                outcome(&report, HealthComponent::Quote),
+                CheckOutcome::Failed(reason) if reason.contains("no result within")
+            ),
+            "{report:?}"
+        );
+        assert_eq!(
+            outcome(&report, HealthComponent::Workload),
+            &CheckOutcome::Passed
+        );
+        assert_eq!(report.score, 65);
+        assert!(!report.healthy);
+
+        let report = handler(600).await.health_check().await;
+        assert!(
+            report.failed.contains(&HealthComponent::ClockSkew),
+            "{report:?}"
+        );
+        assert_eq!(report.score, 50);
+    }
+
+    #[tokio::test]
+    async fn health_checks_reuse_the_liveness_probe_and_cached_quotes() {
+        let calls = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let (probes, quotes) = (calls.clone(), calls.clone());
+        let app = Router::new()
+            .route(
+                "/prpc/Tappd.Info",
+                get(move || async move {
+                    probes.0.fetch_add(1, Ordering::SeqCst);
+                    "{}"
+                }),
+            )
+            .route(
+                "/prpc/Tappd.TdxQuote",
+                post(move |body: String| async move {
+                    quotes.1.fetch_add(1, Ordering::SeqCst);
+                    let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+                    let report_data = request["report_data"].as_str().unwrap();
+                    let quote = TdxQuote {
+                        version: 4,
+                        tee_type: TDX_TEE_TYPE,
+                        mr_td: [7; 48],
+                        rtmrs: [[1; 48]; 4],
+                        report_data: hex::decode(report_data).unwrap().try_into().unwrap(),
+                    };
+                    serde_json::json!({ "quote": hex::encode(quote.to_bytes()) }).to_string()
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let tee = TeeHandler::new(TeeHandlerConfig {
+            endpoint: crate::tee::TeeEndpoint::parse(&format!("http://{addr}")).unwrap(),
+            ..TeeHandlerConfig::default()
+        })
+        .await
+        .unwrap();
+
+        let liveness = tee.check_liveness().await;
+        for _ in 0..3 {
+            let report = tee.score_health(&liveness).await;
+            assert_eq!(
+                outcome(&report, HealthComponent::Reachability),
+                &CheckOutcome::Passed
+            );
+            assert_eq!(
+                outcome(&report, HealthComponent::Quote),
+                &CheckOutcome::Passed
+            );
+        }
+        assert_eq!(calls.0.load(Ordering::SeqCst), 1);
+        assert_eq!(calls.1.load(Ordering::SeqCst), 1);
+
+        let down = Err(PhalaAvsError::TeeError("tappd is down".to_string()));
+        let report = tee.score_health(&down).await;
+        assert!(
+            matches!(
+                outcome(&report, HealthComponent::Reachability),
+                CheckOutcome::Failed(reason) if reason.contains("tappd is down")
+            ),
+            "{report:?}"
+        );
+        assert_eq!(calls.0.load(Ordering::SeqCst), 1);
+    }
+}
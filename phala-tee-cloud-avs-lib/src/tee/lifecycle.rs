@@ -0,0 +1,513 @@
+//! Stopping, restarting and terminating workloads deployed to Phala Cloud.
+//!
+//! These are the remediation primitives of the heartbeat job. Each asks the Phala Cloud API for
+//! the transition, then polls the CVM every `PHALA_CLOUD_POLL_INTERVAL_MS` until it reaches the
+//! target state or `PHALA_CLOUD_TRANSITION_TIMEOUT_SECS` have elapsed:
+//!
+//! - [`TeeHandler::stop_workload`] waits for the CVM to be stopped;
+//! - [`TeeHandler::restart_workload`] waits for it to run again with an attestation newer than
+//!   the one it had before the restart, so a CVM that never went down, or came back without
+//!   attesting, is not taken for restarted;
+//! - [`TeeHandler::terminate_workload`] waits for the API to forget the CVM, and succeeds at once
+//!   when it already has.
+//!
+//! Failures name their phase; see [`LifecycleFailure`].
+
+use super::TeeHandler;
+use super::deploy::{CloudDeployer, DeployFailure, WorkloadId};
+use super::workload_status::{CvmInfo, WorkloadStatus};
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use std::fmt;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Counter of lifecycle actions, by `action` and `outcome`: `done` or the
+/// [`LifecycleFailure::phase`].
+pub const WORKLOAD_LIFECYCLE_METRIC: &str = "phala_avs_workload_lifecycle_total";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LifecycleAction {
+    Stop,
+    Restart,
+    Terminate,
+}
+
+impl LifecycleAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stop => "stop",
+            Self::Restart => "restart",
+            Self::Terminate => "terminate",
+        }
+    }
+
+    /// The state waited for, as in "was not ... within".
+    fn target(&self) -> &'static str {
+        match self {
+            Self::Stop => "stopped",
+            Self::Restart => "running again",
+            Self::Terminate => "deleted",
+        }
+    }
+}
+
+/// Why a lifecycle action failed, by phase.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LifecycleFailure {
+    /// The API refused or failed the request.
+    Request(DeployFailure),
+    /// The CVM did not reach the target state.
+    Transition { id: WorkloadId, reason: String },
+    /// The CVM reached the target state, but could not be shown to have restarted.
+    Verification { id: WorkloadId, reason: String },
+}
+
+impl LifecycleFailure {
+    /// Label of the `outcome` of [`WORKLOAD_LIFECYCLE_METRIC`].
+    pub fn phase(&self) -> &'static str {
+        match self {
+            Self::Request(_) => "request",
+            Self::Transition { .. } => "transition",
+            Self::Verification { .. } => "verification",
+        }
+    }
+}
+
+impl fmt::Display for LifecycleFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request(failure) => failure.fmt(f),
+            Self::Transition { id, reason } | Self::Verification { id, reason } => {
+                write!(f, "CVM {id} {reason}")
+            }
+        }
+    }
+}
+
+/// What a poll of the CVM showed.
+enum Progress {
+    Reached,
+    /// Not there yet, in this raw status.
+    Waiting(String),
+    /// In the target state, but not yet verified to be there afresh.
+    Unverified(String),
+    /// Will not get there.
+    Failed(String),
+}
+
+impl CloudDeployer {
+    async fn run(&self, action: LifecycleAction, id: &WorkloadId) -> Result<(), LifecycleFailure> {
+        match action {
+            LifecycleAction::Stop => {
+                self.api()
+                    .stop(id)
+                    .await
+                    .map_err(LifecycleFailure::Request)?;
+                self.wait_for(action, id, |polled| match polled {
+                    Ok(cvm) if is_stopped(&cvm) => Progress::Reached,
+                    Ok(cvm) => Progress::Waiting(cvm.status),
+                    Err(e) => Progress::Failed(gone_or(e)),
+                })
+                .await
+            }
+            LifecycleAction::Restart => {
+                let before = self
+                    .api()
+                    .cvm(id)
+                    .await
+                    .map_err(LifecycleFailure::Request)?
+                    .last_attestation_at;
+                self.api()
+                    .restart(id)
+                    .await
+                    .map_err(LifecycleFailure::Request)?;
+                self.wait_for(action, id, |polled| match polled {
+                    Ok(cvm) => match WorkloadStatus::from(&cvm) {
+                        WorkloadStatus::Running {
+                            last_attestation_at,
+                            ..
+                        } if last_attestation_at > before => Progress::Reached,
+                        WorkloadStatus::Running { .. } => Progress::Unverified(unattested(before)),
+                        _ => Progress::Waiting(cvm.status),
+                    },
+                    Err(e) => Progress::Failed(gone_or(e)),
+                })
+                .await
+            }
+            LifecycleAction::Terminate => {
+                match self.api().delete(id).await {
+                    Err(DeployFailure::NotFound(_)) => {
+                        info!("CVM {id} is already gone");
+                        return Ok(());
+                    }
+                    requested => requested.map_err(LifecycleFailure::Request)?,
+                }
+                self.wait_for(action, id, |polled| match polled {
+                    Ok(cvm) if is_deleted(&cvm) => Progress::Reached,
+                    Ok(cvm) => Progress::Waiting(cvm.status),
+                    Err(DeployFailure::NotFound(_)) => Progress::Reached,
+                    Err(e) => Progress::Failed(format!("could not be read: {e}")),
+                })
+                .await
+            }
+        }
+    }
+
+    /// Polls the CVM, judging each reply with `check`, until the transition is done, fails or
+    /// times out. The API being briefly unavailable is waited out.
+    async fn wait_for(
+        &self,
+        action: LifecycleAction,
+        id: &WorkloadId,
+        mut check: impl FnMut(Result<CvmInfo, DeployFailure>) -> Progress,
+    ) -> Result<(), LifecycleFailure> {
+        let config = self.config();
+        let waiting = |status: &str| LifecycleFailure::Transition {
+            id: id.clone(),
+            reason: format!(
+                "was not {} within {:?} (last status: {status})",
+                action.target(),
+                config.transition_timeout
+            ),
+        };
+        let started = Instant::now();
+        let mut timed_out = waiting("unknown");
+        loop {
+            match self.api().cvm(id).await {
+                Err(DeployFailure::Unavailable(e)) => {
+                    warn!("Failed to poll CVM {id}, retrying: {e}");
+                }
+                polled => {
+                    timed_out = match check(polled) {
+                        Progress::Reached => return Ok(()),
+                        Progress::Failed(reason) => {
+                            return Err(LifecycleFailure::Transition {
+                                id: id.clone(),
+                                reason,
+                            });
+                        }
+                        Progress::Waiting(status) => waiting(&status),
+                        Progress::Unverified(reason) => LifecycleFailure::Verification {
+                            id: id.clone(),
+                            reason,
+                        },
+                    }
+                }
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= config.transition_timeout {
+                return Err(timed_out);
+            }
+            tokio::time::sleep(
+                config
+                    .poll_interval
+                    .min(config.transition_timeout - elapsed),
+            )
+            .await;
+        }
+    }
+}
+
+/// Stopped for good, not on the way there.
+fn is_stopped(cvm: &CvmInfo) -> bool {
+    matches!(WorkloadStatus::from(cvm), WorkloadStatus::Stopped { .. })
+        && !cvm.status.trim().eq_ignore_ascii_case("stopping")
+}
+
+fn is_deleted(cvm: &CvmInfo) -> bool {
+    let status = cvm.status.trim();
+    status.eq_ignore_ascii_case("terminated") || status.eq_ignore_ascii_case("deleted")
+}
+
+fn unattested(before: Option<u64>) -> String {
+    match before {
+        Some(at) => format!("has not attested since the restart (last at {at})"),
+        None => "has not attested since the restart".to_string(),
+    }
+}
+
+fn gone_or(e: DeployFailure) -> String {
+    match e {
+        DeployFailure::NotFound(_) => "no longer exists".to_string(),
+        e => format!("could not be read: {e}"),
+    }
+}
+
+impl TeeHandler {
+    /// Stops the workload deployed as `id`, returning once its CVM is stopped.
+    pub async fn stop_workload(&self, id: &WorkloadId) -> Result<(), PhalaAvsError> {
+        self.run_lifecycle(LifecycleAction::Stop, id).await
+    }
+
+    /// Restarts the workload deployed as `id`, returning once its CVM runs again and has
+    /// attested since.
+    pub async fn restart_workload(&self, id: &WorkloadId) -> Result<(), PhalaAvsError> {
+        self.run_lifecycle(LifecycleAction::Restart, id).await
+    }
+
+    /// Deletes the workload deployed as `id`, returning once Phala Cloud no longer has it. A
+    /// workload that is already gone is not an error.
+    pub async fn terminate_workload(&self, id: &WorkloadId) -> Result<(), PhalaAvsError> {
+        self.run_lifecycle(LifecycleAction::Terminate, id).await
+    }
+
+    async fn run_lifecycle(
+        &self,
+        action: LifecycleAction,
+        id: &WorkloadId,
+    ) -> Result<(), PhalaAvsError> {
+        self.inject_faults().await?;
+        info!("Asking Phala Cloud to {} CVM {id}", action.as_str());
+        let outcome = self.cloud_deployer()?.run(action, id).await;
+        let label = match &outcome {
+            Ok(()) => "done",
+            Err(failure) => failure.phase(),
+        };
+        METRICS.inc_counter(
+            WORKLOAD_LIFECYCLE_METRIC,
+            &[("action", action.as_str()), ("outcome", label)],
+            1,
+        );
+        outcome.map_err(|failure| match failure {
+            LifecycleFailure::Request(DeployFailure::NotFound(id)) => {
+                PhalaAvsError::NotFound(format!("Phala Cloud has no workload {id}"))
+            }
+            failure => PhalaAvsError::TeeError(format!(
+                "Failed to {} workload {id} ({}): {failure}",
+                action.as_str(),
+                failure.phase()
+            )),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandlerConfig;
+    use crate::tee::deploy::{CloudConfig, HttpCloudApi};
+    use axum::Router;
+    use axum::extract::{Path, State};
+    use axum::http::StatusCode;
+    use axum::routing::{get, post};
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// A Phala Cloud stand-in. Each CVM answers its replies in turn, repeating the last, where
+    /// `null` stands for a 404. A request `"<action> <id>"` with a script replaces the CVM's
+    /// replies with it; one without fails with a 503.
+    #[derive(Default)]
+    struct MockCloud {
+        cvms: Mutex<HashMap<String, Vec<Value>>>,
+        scripts: HashMap<String, Vec<Value>>,
+        requests: Mutex<Vec<String>>,
+    }
+
+    impl MockCloud {
+        fn new(cvms: &[(&str, Value)], scripts: &[(&str, Vec<Value>)]) -> Arc<Self> {
+            Arc::new(Self {
+                cvms: Mutex::new(
+                    cvms.iter()
+                        .map(|(id, reply)| (id.to_string(), vec![reply.clone()]))
+                        .collect(),
+                ),
+                scripts: scripts
+                    .iter()
+                    .map(|(request, replies)| (request.to_string(), replies.clone()))
+                    .collect(),
+                ..Self::default()
+            })
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    fn reply(body: Value) -> (StatusCode, String) {
+        match body {
+            Value::Null => (
+                StatusCode::NOT_FOUND,
+                json!({"error": "no such CVM"}).to_string(),
+            ),
+            body => (StatusCode::OK, body.to_string()),
+        }
+    }
+
+    async fn poll(
+        State(cloud): State<Arc<MockCloud>>,
+        Path(id): Path<String>,
+    ) -> (StatusCode, String) {
+        let mut cvms = cloud.cvms.lock().unwrap();
+        match cvms.get_mut(&id) {
+            Some(replies) if replies.len() > 1 => reply(replies.remove(0)),
+            Some(replies) => reply(replies[0].clone()),
+            None => reply(Value::Null),
+        }
+    }
+
+    async fn request(
+        State(cloud): State<Arc<MockCloud>>,
+        Path((id, action)): Path<(String, String)>,
+    ) -> (StatusCode, String) {
+        act(&cloud, &action, id)
+    }
+
+    async fn delete(
+        State(cloud): State<Arc<MockCloud>>,
+        Path(id): Path<String>,
+    ) -> (StatusCode, String) {
+        act(&cloud, "terminate", id)
+    }
+
+    fn act(cloud: &MockCloud, action: &str, id: String) -> (StatusCode, String) {
+        let request = format!("{action} {id}");
+        cloud.requests.lock().unwrap().push(request.clone());
+        let mut cvms = cloud.cvms.lock().unwrap();
+        match cvms.get(&id).and_then(|replies| replies.first()) {
+            None | Some(Value::Null) => return reply(Value::Null),
+            Some(_) => {}
+        }
+        match cloud.scripts.get(&request) {
+            Some(script) => {
+                cvms.insert(id, script.clone());
+                (StatusCode::ACCEPTED, String::new())
+            }
+            None => (StatusCode::SERVICE_UNAVAILABLE, "maintenance".to_string()),
+        }
+    }
+
+    async fn handler(cloud: Arc<MockCloud>) -> TeeHandler {
+        let app = Router::new()
+            .route("/cvms/{id}", get(poll).delete(delete))
+            .route("/cvms/{id}/{action}", post(request))
+            .with_state(cloud);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let config = CloudConfig {
+            poll_interval: Duration::from_millis(10),
+            transition_timeout: Duration::from_millis(200),
+            ..CloudConfig::default()
+        };
+        TeeHandler::new(TeeHandlerConfig::default())
+            .await
+            .unwrap()
+            .with_cloud(
+                config,
+                Arc::new(HttpCloudApi::new(format!("http://{addr}"), None)),
+            )
+    }
+
+    fn id(id: &str) -> WorkloadId {
+        WorkloadId(id.to_string())
+    }
+
+    #[tokio::test]
+    async fn stop_waits_for_the_cvm_to_stop() {
+        let running = json!({"status": "running", "last_attestation_at": 100});
+        let cloud = MockCloud::new(
+            &[
+                ("cvm-1", running.clone()),
+                ("cvm-2", running.clone()),
+                ("cvm-3", running),
+            ],
+            &[
+                ("stop cvm-1", vec![
+                    json!({"status": "stopping"}),
+                    json!({"status": "stopped", "exit_reason": "stop requested"}),
+                ]),
+                ("stop cvm-2", vec![json!({"status": "stopping"})]),
+            ],
+        );
+        let tee = handler(cloud.clone()).await;
+        tee.stop_workload(&id("cvm-1")).await.unwrap();
+
+        let err = tee.stop_workload(&id("cvm-2")).await.unwrap_err();
+        assert!(
+            err.to_string().contains("(transition)")
+                && err.to_string().contains("was not stopped within")
+                && err.to_string().contains("(last status: stopping)"),
+            "{err}"
+        );
+        let err = tee.stop_workload(&id("cvm-3")).await.unwrap_err();
+        assert!(
+            err.to_string().contains("(request)") && err.to_string().contains("unavailable"),
+            "{err}"
+        );
+        assert!(matches!(
+            tee.stop_workload(&id("cvm-0")).await,
+            Err(PhalaAvsError::NotFound(_))
+        ));
+        // The 503 was not retried: the stop reached the API, which may have carried it out.
+        assert_eq!(cloud.requests(), [
+            "stop cvm-1",
+            "stop cvm-2",
+            "stop cvm-3",
+            "stop cvm-0"
+        ]);
+    }
+
+    #[tokio::test]
+    async fn restart_waits_for_a_fresh_attestation() {
+        let running = json!({"status": "running", "last_attestation_at": 100});
+        let cloud = MockCloud::new(
+            &[("cvm-1", running.clone()), ("cvm-2", running.clone())],
+            &[
+                ("restart cvm-1", vec![
+                    json!({"status": "restarting"}),
+                    running.clone(),
+                    json!({"status": "running", "last_attestation_at": 160}),
+                ]),
+                // Never goes down, so never attests again.
+                ("restart cvm-2", vec![running]),
+            ],
+        );
+        let tee = handler(cloud).await;
+        tee.restart_workload(&id("cvm-1")).await.unwrap();
+        assert_eq!(
+            tee.get_workload_status(&id("cvm-1")).await.unwrap(),
+            WorkloadStatus::Running {
+                uptime_secs: None,
+                last_attestation_at: Some(160),
+            }
+        );
+
+        let err = tee.restart_workload(&id("cvm-2")).await.unwrap_err();
+        assert!(
+            err.to_string().contains("(verification)")
+                && err
+                    .to_string()
+                    .contains("has not attested since the restart (last at 100)"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn terminate_is_idempotent() {
+        let cloud = MockCloud::new(&[("cvm-1", json!({"status": "running"}))], &[(
+            "terminate cvm-1",
+            vec![json!({"status": "terminating"}), Value::Null],
+        )]);
+        let tee = handler(cloud.clone()).await;
+        tee.terminate_workload(&id("cvm-1")).await.unwrap();
+        assert!(matches!(
+            tee.get_workload_status(&id("cvm-1")).await,
+            Err(PhalaAvsError::NotFound(_))
+        ));
+        // Gone already, or never there at all.
+        tee.terminate_workload(&id("cvm-1")).await.unwrap();
+        tee.terminate_workload(&id("cvm-0")).await.unwrap();
+        assert_eq!(cloud.requests(), [
+            "terminate cvm-1",
+            "terminate cvm-1",
+            "terminate cvm-0"
+        ]);
+
+        let bare = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        assert!(bare.terminate_workload(&id("cvm-1")).await.is_err());
+    }
+}
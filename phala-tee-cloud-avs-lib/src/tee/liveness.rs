@@ -0,0 +1,287 @@
+//! Liveness of the local dstack guest agent (tappd).
+//!
+//! The probe asks tappd for `Tappd.Info` at its endpoint (see [`super::tappd`]). Everything from
+//! connecting to reading the reply is bounded by `TEE_LIVENESS_TIMEOUT_SECS`, and the probe is
+//! not retried, so a wedged agent cannot hang the heartbeat. A TEE that is not live is reported
+//! as a [`Liveness`] telling a missing socket or unreachable URL, an agent that did not answer in
+//! time and one that answered with an error status apart; errors are kept for probes that could
+//! not be made at all, e.g. a socket the operator may not open.
+//!
+//! A TEE answering in 20 seconds would still miss SLA windows, so each probe's round trip is
+//! timed too: answers slower than `TEE_LIVENESS_WARN_MS` are flagged as slow, and those slower
+//! than `TEE_LIVENESS_FAIL_MS` count as not live even though they arrived.
+
+use super::TeeHandler;
+use super::tappd::{TappdClient, TappdError};
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Counter of liveness probes that found the TEE not live, by reason.
+pub const TEE_LIVENESS_FAILURES_METRIC: &str = "phala_avs_tee_liveness_failures_total";
+/// Histogram of the round trip of liveness probes.
+pub const TEE_LIVENESS_LATENCY_METRIC: &str = "phala_avs_tee_liveness_latency_seconds";
+
+/// The tappd endpoint probed; it is cheap and answers without touching the TEE's keys.
+const INFO_PATH: &str = "/prpc/Tappd.Info?json";
+/// How much of an error reply's body is kept.
+const MAX_BODY_CHARS: usize = 200;
+
+/// What a liveness probe found.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Liveness {
+    Live,
+    /// Nothing listens at the socket path: dstack is not running, or the path is wrong.
+    SocketMissing {
+        path: PathBuf,
+    },
+    /// Nothing accepted the connection at the HTTP(S) endpoint.
+    Unreachable {
+        endpoint: String,
+    },
+    /// The agent accepted the connection but did not answer in time.
+    TimedOut {
+        after_ms: u64,
+    },
+    /// The agent answered with a non-success status.
+    ErrorStatus {
+        status: u16,
+        body: String,
+    },
+    /// The agent answered, but slower than `TEE_LIVENESS_FAIL_MS`.
+    TooSlow {
+        latency_ms: u64,
+        limit_ms: u64,
+    },
+}
+
+impl Liveness {
+    pub fn is_live(&self) -> bool {
+        matches!(self, Self::Live)
+    }
+
+    /// Label of the `reason` of [`TEE_LIVENESS_FAILURES_METRIC`].
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Live => "live",
+            Self::SocketMissing { .. } => "socket_missing",
+            Self::Unreachable { .. } => "unreachable",
+            Self::TimedOut { .. } => "timed_out",
+            Self::ErrorStatus { .. } => "error_status",
+            Self::TooSlow { .. } => "too_slow",
+        }
+    }
+}
+
+impl fmt::Display for Liveness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Live => f.write_str("live"),
+            Self::SocketMissing { path } => {
+                write!(f, "no tappd socket at {}", path.display())
+            }
+            Self::Unreachable { endpoint } => write!(f, "tappd at {endpoint} is unreachable"),
+            Self::TimedOut { after_ms } => write!(f, "tappd did not answer within {after_ms}ms"),
+            Self::ErrorStatus { status, body } if body.is_empty() => {
+                write!(f, "tappd answered with status {status}")
+            }
+            Self::ErrorStatus { status, body } => {
+                write!(f, "tappd answered with status {status}: {body}")
+            }
+            Self::TooSlow {
+                latency_ms,
+                limit_ms,
+            } => write!(
+                f,
+                "tappd took {latency_ms}ms to answer, over the {limit_ms}ms limit"
+            ),
+        }
+    }
+}
+
+/// A liveness probe and how long it took.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LivenessCheck {
+    pub liveness: Liveness,
+    /// From sending the probe to reading the reply, or to giving up.
+    pub latency: Duration,
+    /// Whether the probe took longer than `TEE_LIVENESS_WARN_MS`.
+    pub slow: bool,
+}
+
+impl LivenessCheck {
+    pub fn is_live(&self) -> bool {
+        self.liveness.is_live()
+    }
+
+    pub fn latency_ms(&self) -> u64 {
+        self.latency.as_millis() as u64
+    }
+}
+
+impl TeeHandler {
+    /// Checks that the local TEE agent is up and answering in time.
+    pub async fn check_liveness(&self) -> Result<LivenessCheck, PhalaAvsError> {
+        self.inject_faults().await?;
+        probe(&self.tappd).await
+    }
+}
+
+/// Probes the agent `tappd` reaches, recording the outcome.
+pub(super) async fn probe(tappd: &TappdClient) -> Result<LivenessCheck, PhalaAvsError> {
+    let config = tappd.config();
+    let probe = tappd.call("GET", INFO_PATH, None);
+    let started = Instant::now();
+    let outcome = tokio::time::timeout(config.liveness_timeout, probe).await;
+    let latency = started.elapsed();
+    let liveness = match outcome {
+        Ok(Ok(reply)) if reply.is_success() && latency > config.liveness_fail_after => {
+            Liveness::TooSlow {
+                latency_ms: latency.as_millis() as u64,
+                limit_ms: config.liveness_fail_after.as_millis() as u64,
+            }
+        }
+        Ok(Ok(reply)) if reply.is_success() => Liveness::Live,
+        Ok(Ok(reply)) => Liveness::ErrorStatus {
+            status: reply.status,
+            body: reply.body.trim().chars().take(MAX_BODY_CHARS).collect(),
+        },
+        Ok(Err(TappdError::SocketMissing(path))) => Liveness::SocketMissing { path },
+        Ok(Err(TappdError::Unreachable(_))) => Liveness::Unreachable {
+            endpoint: config.endpoint.to_string(),
+        },
+        Ok(Err(e)) => return Err(e.into_tee_error(&config.endpoint)),
+        Err(_) => Liveness::TimedOut {
+            after_ms: config.liveness_timeout.as_millis() as u64,
+        },
+    };
+    METRICS.observe(TEE_LIVENESS_LATENCY_METRIC, &[], latency.as_secs_f64());
+    if !liveness.is_live() {
+        METRICS.inc_counter(
+            TEE_LIVENESS_FAILURES_METRIC,
+            &[("reason", liveness.reason())],
+            1,
+        );
+    }
+    Ok(LivenessCheck {
+        liveness,
+        latency,
+        slow: latency > config.liveness_warn_after,
+    })
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::tee::tappd::fake_tappd;
+    use crate::tee::{TeeEndpoint, TeeHandlerConfig};
+    use blueprint_sdk::testing::tempfile::TempDir;
+
+    async fn check(socket: PathBuf) -> Liveness {
+        let handler = TeeHandler::new(TeeHandlerConfig {
+            endpoint: TeeEndpoint::Socket(socket),
+            liveness_timeout: Duration::from_millis(200),
+            ..TeeHandlerConfig::default()
+        })
+        .await
+        .unwrap();
+        handler.check_liveness().await.unwrap().liveness
+    }
+
+    #[tokio::test]
+    async fn answering_agent_is_live() {
+        let dir = TempDir::new().unwrap();
+        let reply = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}";
+        let socket = fake_tappd(dir.path(), Some(reply.to_string()));
+        assert_eq!(check(socket).await, Liveness::Live);
+    }
+
+    #[tokio::test]
+    async fn missing_socket_is_told_apart() {
+        let dir = TempDir::new().unwrap();
+        let socket = dir.path().join("absent.sock");
+        assert_eq!(check(socket.clone()).await, Liveness::SocketMissing {
+            path: socket
+        });
+    }
+
+    #[tokio::test]
+    async fn silent_agent_times_out() {
+        let dir = TempDir::new().unwrap();
+        let socket = fake_tappd(dir.path(), None);
+        assert_eq!(check(socket).await, Liveness::TimedOut { after_ms: 200 });
+    }
+
+    #[tokio::test]
+    async fn error_status_carries_the_reply() {
+        let dir = TempDir::new().unwrap();
+        let reply = "HTTP/1.1 503 Service Unavailable\r\n\r\nguest agent starting\n";
+        let socket = fake_tappd(dir.path(), Some(reply.to_string()));
+        let liveness = check(socket).await;
+        assert_eq!(liveness, Liveness::ErrorStatus {
+            status: 503,
+            body: "guest agent starting".to_string(),
+        });
+        assert_eq!(
+            liveness.to_string(),
+            "tappd answered with status 503: guest agent starting"
+        );
+    }
+
+    /// Probes an agent that answers after `delay`, warning above 50ms and failing above 200ms.
+    async fn check_delayed(delay: Duration) -> LivenessCheck {
+        let app = axum::Router::new().route(
+            "/prpc/Tappd.Info",
+            axum::routing::get(move || async move {
+                tokio::time::sleep(delay).await;
+                "{}"
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let handler = TeeHandler::new(TeeHandlerConfig {
+            endpoint: TeeEndpoint::parse(&format!("http://{addr}")).unwrap(),
+            liveness_timeout: Duration::from_secs(2),
+            liveness_warn_after: Duration::from_millis(50),
+            liveness_fail_after: Duration::from_millis(200),
+            ..TeeHandlerConfig::default()
+        })
+        .await
+        .unwrap();
+        handler.check_liveness().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn latency_is_measured_against_the_thresholds() {
+        let fast = check_delayed(Duration::ZERO).await;
+        assert_eq!(fast.liveness, Liveness::Live);
+        assert!(!fast.slow, "{fast:?}");
+
+        let slow = check_delayed(Duration::from_millis(100)).await;
+        assert_eq!(slow.liveness, Liveness::Live);
+        assert!(slow.slow && slow.latency_ms() >= 100, "{slow:?}");
+
+        // The answer arrives, but too late to count.
+        let late = check_delayed(Duration::from_millis(300)).await;
+        assert!(!late.is_live());
+        let Liveness::TooSlow {
+            latency_ms,
+            limit_ms,
+        } = late.liveness
+        else {
+            panic!("{late:?}");
+        };
+        assert!(latency_ms >= 300 && limit_ms == 200, "{late:?}");
+        assert!(late.slow);
+        assert!(
+            late.liveness.to_string().ends_with("over the 200ms limit"),
+            "{}",
+            late.liveness
+        );
+    }
+}
@@ -0,0 +1,553 @@
+//! Allow-list of the TDX images whose quotes are accepted.
+//!
+//! A quote that verifies only proves that some TDX guest produced it. [`TeeHandler`] also
+//! requires its MRTD and RTMRs to be those of an approved image, listed in a
+//! [`MeasurementPolicy`]: each image gives its MRTD and four RTMRs in hex, or `"*"` for a
+//! register whose value does not matter (e.g. an RTMR extended with per-deployment events).
+//!
+//! The policy is read from `TEE_MEASUREMENT_POLICY_PATH`, as TOML when the file ends in `.toml`
+//! and JSON otherwise, or inline from `TEE_MEASUREMENT_POLICY` as JSON. The file is re-read by
+//! [`TeeHandler::reload_measurement_policy`] without rebuilding the handler; a file that no
+//! longer loads leaves the previous policy in place. A policy approving no image is an error,
+//! never allow-all, and so is checking measurements without a policy.
+
+use super::TeeHandler;
+use super::attestation::Measurements;
+use super::platform::TeePlatform;
+use crate::config::env_opt;
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use blueprint_sdk::alloy::primitives::FixedBytes;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+/// Counter of quotes rejected for their measurements, by first mismatched register.
+pub const MEASUREMENT_REJECTIONS_METRIC: &str = "phala_avs_tee_measurement_rejections_total";
+
+/// Names of the registers of an image, MRTD first.
+const REGISTERS: [&str; 5] = ["MRTD", "RTMR0", "RTMR1", "RTMR2", "RTMR3"];
+
+/// The accepted value of one register, written in hex or as `"*"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RegisterPattern {
+    Any,
+    Exact(FixedBytes<48>),
+}
+
+impl RegisterPattern {
+    pub fn matches(&self, value: &FixedBytes<48>) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Exact(expected) => expected == value,
+        }
+    }
+}
+
+impl TryFrom<String> for RegisterPattern {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        if raw.trim() == "*" {
+            return Ok(Self::Any);
+        }
+        raw.trim()
+            .parse()
+            .map(Self::Exact)
+            .map_err(|e| format!("{raw:?} is neither \"*\" nor 48 bytes of hex: {e}"))
+    }
+}
+
+impl From<RegisterPattern> for String {
+    fn from(pattern: RegisterPattern) -> Self {
+        pattern.to_string()
+    }
+}
+
+impl fmt::Display for RegisterPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => f.write_str("*"),
+            Self::Exact(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// The measurements of an approved image.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovedImage {
+    /// Shown in rejections; images without one are numbered.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub mr_td: RegisterPattern,
+    pub rtmrs: [RegisterPattern; 4],
+}
+
+impl ApprovedImage {
+    /// The registers whose values differ from this image's, as indexes into [`REGISTERS`].
+    fn mismatches(&self, mr_td: &FixedBytes<48>, rtmrs: &[FixedBytes<48>; 4]) -> Vec<usize> {
+        std::iter::once((&self.mr_td, mr_td))
+            .chain(self.rtmrs.iter().zip(rtmrs))
+            .enumerate()
+            .filter(|(_, (pattern, value))| !pattern.matches(value))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn expected(&self, register: usize) -> RegisterPattern {
+        match register {
+            0 => self.mr_td,
+            i => self.rtmrs[i - 1],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    images: Vec<ApprovedImage>,
+}
+
+/// The approved images; never empty.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MeasurementPolicy {
+    images: Vec<ApprovedImage>,
+}
+
+impl MeasurementPolicy {
+    pub fn new(images: Vec<ApprovedImage>) -> Result<Self, PhalaAvsError> {
+        if images.is_empty() {
+            return Err(PhalaAvsError::ConfigError(
+                "The measurement policy approves no image".to_string(),
+            ));
+        }
+        Ok(Self { images })
+    }
+
+    /// Reads `{"images": [...]}`.
+    pub fn from_json(raw: &str) -> Result<Self, PhalaAvsError> {
+        let file: PolicyFile = serde_json::from_str(raw)
+            .map_err(|e| PhalaAvsError::ConfigError(format!("Invalid measurement policy: {e}")))?;
+        Self::new(file.images)
+    }
+
+    /// Reads a list of `[[images]]` tables.
+    pub fn from_toml(raw: &str) -> Result<Self, PhalaAvsError> {
+        let file: PolicyFile = toml::from_str(raw)
+            .map_err(|e| PhalaAvsError::ConfigError(format!("Invalid measurement policy: {e}")))?;
+        Self::new(file.images)
+    }
+
+    /// Reads `path`, as TOML if it ends in `.toml` and JSON otherwise.
+    pub fn load(path: &Path) -> Result<Self, PhalaAvsError> {
+        let raw = std::fs::read_to_string(path).map_err(|e| {
+            PhalaAvsError::ConfigError(format!(
+                "Failed to read the measurement policy {}: {e}",
+                path.display()
+            ))
+        })?;
+        let policy = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&raw),
+            _ => Self::from_json(&raw),
+        };
+        policy.map_err(|e| PhalaAvsError::ConfigError(format!("{}: {e}", path.display())))
+    }
+
+    pub fn images(&self) -> &[ApprovedImage] {
+        &self.images
+    }
+
+    /// The first image `measurements` match, or how they differ from the closest one.
+    pub fn check(
+        &self,
+        measurements: &Measurements,
+    ) -> Result<&ApprovedImage, MeasurementMismatch> {
+        let Measurements::Tdx { mr_td, rtmrs } = measurements else {
+            return Err(MeasurementMismatch::NotTdx {
+                platform: measurements.platform(),
+            });
+        };
+        let mut closest: Option<(usize, Vec<usize>)> = None;
+        for (i, image) in self.images.iter().enumerate() {
+            let mismatches = image.mismatches(mr_td, rtmrs);
+            if mismatches.is_empty() {
+                return Ok(image);
+            }
+            if closest
+                .as_ref()
+                .is_none_or(|(_, best)| mismatches.len() < best.len())
+            {
+                closest = Some((i, mismatches));
+            }
+        }
+        let (i, mismatches) = closest.expect("a policy approves at least one image");
+        let image = &self.images[i];
+        let register = mismatches[0];
+        let actual = match register {
+            0 => *mr_td,
+            r => rtmrs[r - 1],
+        };
+        Err(MeasurementMismatch::Register {
+            image: image.name.clone().unwrap_or_else(|| format!("#{i}")),
+            register: REGISTERS[register],
+            expected: image.expected(register),
+            actual,
+            mismatched: mismatches.len(),
+        })
+    }
+}
+
+/// Why measurements are not approved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MeasurementMismatch {
+    /// The policy lists TDX images only.
+    NotTdx { platform: TeePlatform },
+    /// No image matches. `register` is the first that differs from the image matching the most
+    /// registers, which differs in `mismatched` of them.
+    Register {
+        image: String,
+        register: &'static str,
+        expected: RegisterPattern,
+        actual: FixedBytes<48>,
+        mismatched: usize,
+    },
+}
+
+impl MeasurementMismatch {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::NotTdx { .. } => "not_tdx",
+            Self::Register { register, .. } => *register,
+        }
+    }
+}
+
+impl fmt::Display for MeasurementMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotTdx { platform } => {
+                write!(f, "the policy approves TDX images only, not {platform}")
+            }
+            Self::Register {
+                image,
+                register,
+                expected,
+                actual,
+                mismatched,
+            } => {
+                write!(
+                    f,
+                    "{register} is {actual} where the closest approved image, "
+                )?;
+                write!(f, "{image}, has {expected}")?;
+                if *mismatched > 1 {
+                    write!(f, " ({mismatched} registers differ)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<MeasurementMismatch> for PhalaAvsError {
+    fn from(mismatch: MeasurementMismatch) -> Self {
+        PhalaAvsError::ValidationError(format!("Quote measurements are not approved: {mismatch}"))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MeasurementPolicyConfig {
+    pub path: Option<PathBuf>,
+    /// A JSON policy, for setups without a file.
+    pub inline: Option<String>,
+}
+
+impl MeasurementPolicyConfig {
+    /// Reads `TEE_MEASUREMENT_POLICY_PATH` or `TEE_MEASUREMENT_POLICY`, which exclude each other.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        let config = Self {
+            path: env_opt("TEE_MEASUREMENT_POLICY_PATH")?,
+            inline: env_opt("TEE_MEASUREMENT_POLICY")?,
+        };
+        if config.path.is_some() && config.inline.is_some() {
+            return Err(PhalaAvsError::ConfigError(
+                "Set either TEE_MEASUREMENT_POLICY_PATH or TEE_MEASUREMENT_POLICY, not both"
+                    .to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// The policy in force, replaced on reload.
+#[derive(Debug)]
+pub struct MeasurementAllowlist {
+    path: Option<PathBuf>,
+    policy: RwLock<Arc<MeasurementPolicy>>,
+}
+
+impl MeasurementAllowlist {
+    /// A fixed policy, which reloads leave as is.
+    pub fn new(policy: MeasurementPolicy) -> Self {
+        Self {
+            path: None,
+            policy: RwLock::new(Arc::new(policy)),
+        }
+    }
+
+    /// Loads the configured policy, if any.
+    pub fn from_config(config: &MeasurementPolicyConfig) -> Result<Option<Self>, PhalaAvsError> {
+        if let Some(path) = &config.path {
+            let policy = MeasurementPolicy::load(path)?;
+            info!(
+                "Loaded {} approved images from {}",
+                policy.images.len(),
+                path.display()
+            );
+            return Ok(Some(Self {
+                path: Some(path.clone()),
+                ..Self::new(policy)
+            }));
+        }
+        config
+            .inline
+            .as_deref()
+            .map(|raw| MeasurementPolicy::from_json(raw).map(Self::new))
+            .transpose()
+    }
+
+    pub fn policy(&self) -> Arc<MeasurementPolicy> {
+        self.policy
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Re-reads the policy file, returning how many images it approves. The previous policy
+    /// stays in force if the file does not load.
+    pub fn reload(&self) -> Result<usize, PhalaAvsError> {
+        let Some(path) = &self.path else {
+            return Ok(self.policy().images.len());
+        };
+        let policy = MeasurementPolicy::load(path)?;
+        let count = policy.images.len();
+        self.replace(policy);
+        info!("Reloaded {count} approved images from {}", path.display());
+        Ok(count)
+    }
+
+    pub fn replace(&self, policy: MeasurementPolicy) {
+        *self.policy.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(policy);
+    }
+
+    pub fn check(&self, measurements: &Measurements) -> Result<(), PhalaAvsError> {
+        let policy = self.policy();
+        match policy.check(measurements) {
+            Ok(_) => Ok(()),
+            Err(mismatch) => {
+                METRICS.inc_counter(
+                    MEASUREMENT_REJECTIONS_METRIC,
+                    &[("register", mismatch.label())],
+                    1,
+                );
+                Err(mismatch.into())
+            }
+        }
+    }
+}
+
+impl TeeHandler {
+    pub fn with_measurement_policy(mut self, allowlist: Arc<MeasurementAllowlist>) -> Self {
+        self.measurement_policy = Some(allowlist);
+        self.share_ra_tls_trust();
+        self
+    }
+
+    fn measurement_allowlist(&self) -> Result<&MeasurementAllowlist, PhalaAvsError> {
+        self.measurement_policy.as_deref().ok_or_else(|| {
+            PhalaAvsError::TeeError(
+                "Measurement checks need TEE_MEASUREMENT_POLICY_PATH or TEE_MEASUREMENT_POLICY"
+                    .to_string(),
+            )
+        })
+    }
+
+    /// Checks that `measurements`, of this TEE's quote or another operator's, are those of an
+    /// approved image.
+    pub fn check_measurements(&self, measurements: &Measurements) -> Result<(), PhalaAvsError> {
+        self.measurement_allowlist()?.check(measurements)
+    }
+
+    /// Re-reads `TEE_MEASUREMENT_POLICY_PATH`, returning how many images it approves.
+    pub fn reload_measurement_policy(&self) -> Result<usize, PhalaAvsError> {
+        self.measurement_allowlist()?.reload()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandlerConfig;
+    use blueprint_sdk::alloy::primitives::B256;
+    use blueprint_sdk::testing::tempfile::TempDir;
+
+    fn register(byte: u8) -> FixedBytes<48> {
+        FixedBytes::repeat_byte(byte)
+    }
+
+    fn measured(mr_td: u8, rtmrs: [u8; 4]) -> Measurements {
+        Measurements::Tdx {
+            mr_td: register(mr_td),
+            rtmrs: rtmrs.map(register),
+        }
+    }
+
+    fn image(name: &str, mr_td: u8, rtmrs: [Option<u8>; 4]) -> ApprovedImage {
+        ApprovedImage {
+            name: Some(name.to_string()),
+            mr_td: RegisterPattern::Exact(register(mr_td)),
+            rtmrs: rtmrs.map(|r| {
+                r.map_or(RegisterPattern::Any, |r| {
+                    RegisterPattern::Exact(register(r))
+                })
+            }),
+        }
+    }
+
+    fn policy() -> MeasurementPolicy {
+        MeasurementPolicy::new(vec![
+            image("v1", 1, [Some(1), Some(2), Some(3), Some(4)]),
+            image("v2", 2, [Some(5), Some(6), Some(7), None]),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn approved_images_match() {
+        let policy = policy();
+        let matched = policy.check(&measured(1, [1, 2, 3, 4])).unwrap();
+        assert_eq!(matched.name.as_deref(), Some("v1"));
+        // RTMR3 of v2 may hold anything.
+        for rtmr3 in [0, 9, 0xff] {
+            let matched = policy.check(&measured(2, [5, 6, 7, rtmr3])).unwrap();
+            assert_eq!(matched.name.as_deref(), Some("v2"));
+        }
+    }
+
+    #[test]
+    fn mismatches_name_the_register_of_the_closest_image() {
+        let policy = policy();
+        assert_eq!(
+            policy.check(&measured(2, [5, 9, 7, 0])).unwrap_err(),
+            MeasurementMismatch::Register {
+                image: "v2".to_string(),
+                register: "RTMR1",
+                expected: RegisterPattern::Exact(register(6)),
+                actual: register(9),
+                mismatched: 1,
+            }
+        );
+        let mismatch = policy.check(&measured(3, [1, 2, 3, 4])).unwrap_err();
+        let MeasurementMismatch::Register {
+            image, register, ..
+        } = &mismatch
+        else {
+            panic!("expected a register mismatch, got {mismatch:?}");
+        };
+        assert_eq!((image.as_str(), *register), ("v1", "MRTD"));
+        let err = PhalaAvsError::from(mismatch);
+        assert!(err.to_string().contains("MRTD is 0x0303"), "{err}");
+
+        let sgx = Measurements::Sgx {
+            mr_enclave: B256::ZERO,
+            mr_signer: B256::ZERO,
+            isv_prod_id: 0,
+            isv_svn: 0,
+        };
+        assert_eq!(
+            policy.check(&sgx).unwrap_err(),
+            MeasurementMismatch::NotTdx {
+                platform: TeePlatform::Sgx
+            }
+        );
+    }
+
+    #[test]
+    fn empty_policies_are_errors() {
+        assert!(MeasurementPolicy::new(Vec::new()).is_err());
+        assert!(MeasurementPolicy::from_json(r#"{"images": []}"#).is_err());
+        assert!(MeasurementPolicy::from_toml("images = []").is_err());
+    }
+
+    #[test]
+    fn policies_parse_from_json_and_toml() {
+        let hex = |byte: u8| register(byte).to_string();
+        let json = format!(
+            r#"{{"images": [{{"name": "v2", "mr_td": "{}", "rtmrs": ["{}", "{}", "{}", "*"]}}]}}"#,
+            hex(2),
+            hex(5),
+            hex(6),
+            hex(7)
+        );
+        let toml = format!(
+            "[[images]]\nname = \"v2\"\nmr_td = \"{}\"\nrtmrs = [\"{}\", \"{}\", \"{}\", \"*\"]\n",
+            hex(2),
+            hex(5),
+            hex(6),
+            hex(7)
+        );
+        let expected =
+            MeasurementPolicy::new(vec![image("v2", 2, [Some(5), Some(6), Some(7), None])])
+                .unwrap();
+        assert_eq!(MeasurementPolicy::from_json(&json).unwrap(), expected);
+        assert_eq!(MeasurementPolicy::from_toml(&toml).unwrap(), expected);
+
+        // Registers are 48 bytes, and misspelt fields are not silently ignored.
+        let short = json.replace(&hex(2), &hex(2)[..50]);
+        assert!(MeasurementPolicy::from_json(&short).is_err());
+        let misspelt = json.replace("rtmrs", "rtmr");
+        assert!(MeasurementPolicy::from_json(&misspelt).is_err());
+    }
+
+    #[tokio::test]
+    async fn handler_reloads_the_policy_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.toml");
+        // Any RTMR is approved.
+        let write = |mr_td: u8| {
+            let image = format!(
+                "mr_td = \"{}\"\nrtmrs = [\"*\", \"*\", \"*\", \"*\"]",
+                register(mr_td)
+            );
+            std::fs::write(&path, format!("[[images]]\n{image}\n")).unwrap();
+        };
+        write(1);
+
+        let handler = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        assert!(handler.check_measurements(&measured(1, [0; 4])).is_err());
+        let allowlist = MeasurementAllowlist::from_config(&MeasurementPolicyConfig {
+            path: Some(path.clone()),
+            inline: None,
+        })
+        .unwrap()
+        .unwrap();
+        let handler = handler.with_measurement_policy(Arc::new(allowlist));
+        handler.check_measurements(&measured(1, [0; 4])).unwrap();
+
+        // Clones share the policy, so a reload through one applies to all.
+        let clone = handler.clone();
+        write(2);
+        assert_eq!(handler.reload_measurement_policy().unwrap(), 1);
+        assert!(clone.check_measurements(&measured(1, [0; 4])).is_err());
+        clone.check_measurements(&measured(2, [0; 4])).unwrap();
+
+        // A broken file keeps the policy in force.
+        std::fs::write(&path, "images = []").unwrap();
+        assert!(handler.reload_measurement_policy().is_err());
+        clone.check_measurements(&measured(2, [0; 4])).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+pub mod attestation;
+pub mod capacity;
+pub mod challenge_quote;
+pub mod collateral;
+pub mod compute;
+pub mod config;
+pub mod dcap;
+pub mod deploy;
+pub mod fleet;
+pub mod health;
+pub mod key_derivation;
+pub mod lifecycle;
+pub mod liveness;
+pub mod measurement_policy;
+pub mod pck;
+pub mod platform;
+pub mod quote;
+pub mod quote_cache;
+pub mod ra_tls;
+pub mod resource_metrics;
+pub mod retries;
+pub mod tappd;
+pub mod workload_list;
+pub mod workload_logs;
+pub mod workload_status;
+pub mod workloads;
+
+pub use config::{TeeEndpoint, TeeHandlerConfig, TeeTlsConfig};
+
+#[cfg(feature = "chaos")]
+use crate::chaos::{ChaosEngine, FaultTarget};
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use crate::retry::CancelToken;
+use blueprint_sdk::alloy::primitives::Address;
+use platform::TeePlatform;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+/// The operator's TEE: liveness and quotes from the local guest agent, attestation checks,
+/// computations for `tee_compute` challenges and the workloads deployed on Phala Cloud.
+///
+/// Clones share their caches and the [`CancelToken`] of [`Self::cancel_retries`].
+#[derive(Clone, Debug)]
+pub struct TeeHandler {
+    /// TDX unless set or detected otherwise; see [`platform`].
+    platform: TeePlatform,
+    /// Endpoint for `tee_compute` challenges, when configured.
+    compute: Option<compute::ComputeSandbox>,
+    /// Phala Cloud, for [`TeeHandler::deploy_workload`], [`lifecycle`] and [`workload_list`].
+    cloud: Option<deploy::CloudDeployer>,
+    /// Further CVMs swept by [`TeeHandler::check_liveness_all`]; see [`fleet`].
+    fleet: Option<Arc<fleet::Fleet>>,
+    /// Sub-checks and threshold of [`TeeHandler::health_check`]; see [`health`].
+    health: health::HealthConfig,
+    /// The chain the host clock is checked against, when given.
+    chain: Option<health::ChainClock>,
+    /// The dstack host API, for [`TeeHandler::get_capacity`].
+    host: Option<Arc<dyn capacity::HostApi>>,
+    /// Workload management on the host, for drift reconciliation.
+    workloads: Option<Arc<dyn workloads::WorkloadHost>>,
+    /// The guest agent, probed for liveness and asked for quotes; see [`tappd`].
+    tappd: tappd::TappdClient,
+    /// Recent quotes, shared by every clone; see [`quote_cache`].
+    quote_cache: Arc<quote_cache::QuoteCache>,
+    /// Where the collateral of quotes' platforms is fetched and kept, for
+    /// [`TeeHandler::warm_caches`]; see [`collateral`].
+    quote_collateral: Option<Arc<collateral::HttpCollateralSource>>,
+    /// Trust anchor and bundled collateral of [`TeeHandler::verify_attestation`]; see [`dcap`].
+    dcap: Option<Arc<dcap::DcapVerifier>>,
+    /// Images whose quotes are accepted, reloadable in place; see [`measurement_policy`].
+    measurement_policy: Option<Arc<measurement_policy::MeasurementAllowlist>>,
+    /// The operator challenge quotes are bound to; see [`challenge_quote`].
+    operator: Option<Address>,
+    /// Stops the retries of tappd calls, fleet included, once cancelled; see [`retries`].
+    retries: CancelToken,
+    #[cfg(feature = "chaos")]
+    chaos: Option<Arc<ChaosEngine>>,
+}
+
+impl TeeHandler {
+    /// Creates a TeeHandler reaching the TEE agent as `config` says, failing on invalid settings.
+    pub async fn new(config: TeeHandlerConfig) -> Result<Self, PhalaAvsError> {
+        info!("Initializing TEE Handler for {}", config.endpoint);
+        let quote_cache = Arc::new(quote_cache::QuoteCache::new(config.quote_cache_ttl));
+        let retries = CancelToken::default();
+        Ok(Self {
+            platform: TeePlatform::Tdx,
+            compute: None,
+            cloud: None,
+            fleet: None,
+            health: health::HealthConfig::default(),
+            chain: None,
+            host: None,
+            workloads: None,
+            tappd: tappd::TappdClient::new(config)?.with_cancel(retries.clone()),
+            quote_cache,
+            quote_collateral: None,
+            dcap: None,
+            measurement_policy: None,
+            operator: None,
+            retries,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        })
+    }
+
+    /// The token [`Self::cancel_retries`] cancels, for clients built outside the handler such
+    /// as the [`deploy::HttpCloudApi`] given to [`Self::with_cloud`].
+    pub fn retry_cancel(&self) -> &CancelToken {
+        &self.retries
+    }
+
+    /// Gives up the retries of calls in flight, on shutdown; later calls fail unattempted.
+    pub fn cancel_retries(&self) {
+        self.retries.cancel();
+    }
+
+    /// [`Self::new`] with the settings of [`TeeHandlerConfig::from_env`].
+    pub async fn from_env() -> Result<Self, PhalaAvsError> {
+        Self::new(TeeHandlerConfig::from_env()?).await
+    }
+
+    /// Injects [`FaultTarget::Tee`] faults from `engine` into every TEE call.
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, engine: Arc<ChaosEngine>) -> Self {
+        self.chaos = Some(engine);
+        self
+    }
+
+    async fn inject_faults(&self) -> Result<(), PhalaAvsError> {
+        #[cfg(feature = "chaos")]
+        if let Some(engine) = &self.chaos {
+            engine.inject(FaultTarget::Tee).await?;
+        }
+        Ok(())
+    }
+
+    /// Has [`Self::warm_caches`] warm the collateral `source` keeps for the platform of this
+    /// host's quotes.
+    pub fn with_quote_collateral(mut self, source: Arc<collateral::HttpCollateralSource>) -> Self {
+        self.quote_collateral = Some(source);
+        self
+    }
+
+    /// Warms the caches a challenge response is built from: the quote over the health-check
+    /// report data, kept in the [`quote_cache`], and the collateral of its platform, given
+    /// [`Self::with_quote_collateral`]. Hosts without quotes have nothing to warm.
+    ///
+    /// Called on first sight of a provisional challenge so the response is cheap to build once
+    /// the challenge is confirmed.
+    pub async fn warm_caches(&self) -> Result<(), PhalaAvsError> {
+        self.inject_faults().await?;
+        if self.platform() != TeePlatform::Tdx {
+            return Ok(());
+        }
+        let bundle = self.quote_bundle(health::health_report_data()).await?;
+        if let Some(source) = &self.quote_collateral {
+            source
+                .quote_collateral(&bundle.raw, now_unix_ms() / 1000)
+                .await?;
+        }
+        debug!("Warmed the TEE quote and collateral caches");
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+//! PCK certificate chains, as carried in the certification data of DCAP quotes.
+//!
+//! A quote's chain runs from the platform's PCK certificate through Intel's platform or
+//! processor CA up to the SGX root CA, in PEM. Only what [`super::dcap`] checks is read from the
+//! certificates: names, validity, the P-256 key and ECDSA-SHA256 signature, and in the PCK
+//! certificate the FMSPC and TCB of Intel's SGX extension. Anything that does not have exactly
+//! that shape is rejected rather than skipped, so a corrupted chain never verifies.
+
+use crate::error::PhalaAvsError;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const VERSION: u8 = 0xa0;
+const ISSUER_UNIQUE_ID: u8 = 0x81;
+const SUBJECT_UNIQUE_ID: u8 = 0x82;
+const EXTENSIONS: u8 = 0xa3;
+
+/// `AlgorithmIdentifier` contents of ecdsa-with-SHA256, which takes no parameters.
+const ECDSA_WITH_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+/// `AlgorithmIdentifier` contents of an EC public key on P-256.
+const EC_P256: &[u8] = &[
+    0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d,
+    0x03, 0x01, 0x07,
+];
+/// 2.5.4.3, the common name attribute of names.
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// `[0] { INTEGER 2 }`: X.509 version 3.
+const V3: &[u8] = &[0x02, 0x01, 0x02];
+/// 1.2.840.113741.1.13.1, Intel's SGX extension; its entries are numbered below it.
+const SGX_EXTENSION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf8, 0x4d, 0x01, 0x0d, 0x01];
+const SGX_TCB: &[u8] = &[0x02];
+const SGX_FMSPC: &[u8] = &[0x04];
+/// Below [`SGX_TCB`]: the PCE SVN and the CPU SVN.
+const SGX_TCB_PCE_SVN: &[u8] = &[0x02, 0x11];
+const SGX_TCB_CPU_SVN: &[u8] = &[0x02, 0x12];
+
+const BEGIN: &[u8] = b"-----BEGIN CERTIFICATE-----";
+const END: &[u8] = b"-----END CERTIFICATE-----";
+
+fn malformed(reason: impl std::fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::ValidationError(format!("Invalid PCK certificate chain: {reason}"))
+}
+
+/// A reader over the DER values inside one constructed value.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    /// The next value's tag, contents and whole encoding.
+    fn next(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), PhalaAvsError> {
+        let data = self.0;
+        let (&tag, rest) = data
+            .split_first()
+            .ok_or_else(|| malformed("a value is missing"))?;
+        let (&first, rest) = rest
+            .split_first()
+            .ok_or_else(|| malformed("a length is missing"))?;
+        let (len, rest) = match first {
+            0..=0x7f => (first as usize, rest),
+            0x81 => match rest.split_first() {
+                Some((&len, rest)) if len >= 0x80 => (len as usize, rest),
+                _ => return Err(malformed("a long-form length is invalid")),
+            },
+            0x82 => match rest {
+                [high, low, rest @ ..] if *high > 0 => {
+                    (u16::from_be_bytes([*high, *low]) as usize, rest)
+                }
+                _ => return Err(malformed("a long-form length is invalid")),
+            },
+            _ => return Err(malformed("a length is out of range")),
+        };
+        if rest.len() < len {
+            return Err(malformed("a value is truncated"));
+        }
+        let header_len = data.len() - rest.len();
+        let (contents, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok((tag, contents, &data[..header_len + len]))
+    }
+
+    fn expect(&mut self, tag: u8, what: &str) -> Result<&'a [u8], PhalaAvsError> {
+        match self.next()? {
+            (found, contents, _) if found == tag => Ok(contents),
+            (found, ..) => Err(malformed(format!(
+                "{what} has tag {found:#04x}, not {tag:#04x}"
+            ))),
+        }
+    }
+
+    /// The whole encoding of the next value, which must have `tag`.
+    fn expect_whole(&mut self, tag: u8, what: &str) -> Result<&'a [u8], PhalaAvsError> {
+        match self.next()? {
+            (found, _, whole) if found == tag => Ok(whole),
+            (found, ..) => Err(malformed(format!(
+                "{what} has tag {found:#04x}, not {tag:#04x}"
+            ))),
+        }
+    }
+
+    fn optional(&mut self, tag: u8, what: &str) -> Result<Option<&'a [u8]>, PhalaAvsError> {
+        if self.0.first() == Some(&tag) {
+            self.expect(tag, what).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn finish(&self, what: &str) -> Result<(), PhalaAvsError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(malformed(format!("{what} has trailing data")))
+        }
+    }
+}
+
+/// The bits of a BIT STRING with no unused bits.
+fn bits(contents: &[u8]) -> Result<&[u8], PhalaAvsError> {
+    match contents.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(malformed("a bit string is not byte-aligned")),
+    }
+}
+
+fn small_integer(contents: &[u8]) -> Result<u16, PhalaAvsError> {
+    if contents.is_empty() || contents.len() > 3 || contents[0] & 0x80 != 0 {
+        return Err(malformed("an SVN is not a small non-negative integer"));
+    }
+    let value = contents
+        .iter()
+        .fold(0u32, |value, &byte| (value << 8) | byte as u32);
+    u16::try_from(value).map_err(|_| malformed("an SVN is out of range"))
+}
+
+/// Seconds since the epoch of a UTCTime or GeneralizedTime in UTC, to the second.
+fn time(tag: u8, contents: &[u8]) -> Result<u64, PhalaAvsError> {
+    let digits = match (tag, contents) {
+        (UTC_TIME, [digits @ .., b'Z']) if digits.len() == 12 => digits,
+        (GENERALIZED_TIME, [digits @ .., b'Z']) if digits.len() == 14 => digits,
+        _ => return Err(malformed("a validity time is not a UTC time to the second")),
+    };
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return Err(malformed("a validity time is not numeric"));
+    }
+    let digits = std::str::from_utf8(digits).expect("ASCII digits");
+    let full = match digits.len() {
+        // Two-digit years of UTCTime are 1950 to 2049.
+        12 if digits < "50" => format!("20{digits}"),
+        12 => format!("19{digits}"),
+        _ => digits.to_string(),
+    };
+    super::collateral::parse_timestamp(&format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &full[0..4],
+        &full[4..6],
+        &full[6..8],
+        &full[8..10],
+        &full[10..12],
+        &full[12..14]
+    ))
+    .map_err(|_| malformed("a validity time is not a valid date"))
+}
+
+/// What Intel's SGX extension of a PCK certificate says about the platform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SgxExtension {
+    pub fmspc: [u8; 6],
+    pub cpu_svn: [u8; 16],
+    pub pce_svn: u16,
+}
+
+impl SgxExtension {
+    fn parse(value: &[u8]) -> Result<Self, PhalaAvsError> {
+        let mut outer = Der(value);
+        let mut entries = Der(outer.expect(SEQUENCE, "the SGX extension")?);
+        outer.finish("the SGX extension")?;
+        let (mut fmspc, mut tcb) = (None, None);
+        while !entries.is_empty() {
+            let mut entry = Der(entries.expect(SEQUENCE, "an SGX extension entry")?);
+            let id = entry.expect(OID, "an SGX extension entry id")?;
+            let (tag, contents, _) = entry.next()?;
+            entry.finish("an SGX extension entry")?;
+            match id.strip_prefix(SGX_EXTENSION) {
+                Some(SGX_FMSPC) if tag == OCTET_STRING => {
+                    fmspc = Some(
+                        contents
+                            .try_into()
+                            .map_err(|_| malformed("the FMSPC is not 6 bytes"))?,
+                    );
+                }
+                Some(SGX_TCB) if tag == SEQUENCE => tcb = Some(Self::tcb(contents)?),
+                Some(SGX_FMSPC | SGX_TCB) => {
+                    return Err(malformed("an SGX extension entry has the wrong type"));
+                }
+                _ => {}
+            }
+        }
+        let (Some(fmspc), Some((cpu_svn, pce_svn))) = (fmspc, tcb) else {
+            return Err(malformed("the SGX extension lacks the FMSPC or TCB"));
+        };
+        Ok(Self {
+            fmspc,
+            cpu_svn,
+            pce_svn,
+        })
+    }
+
+    /// The CPU SVN and PCE SVN of the TCB entry; the per-component SVNs repeat the CPU SVN.
+    fn tcb(components: &[u8]) -> Result<([u8; 16], u16), PhalaAvsError> {
+        let mut components = Der(components);
+        let (mut cpu_svn, mut pce_svn) = (None, None);
+        while !components.is_empty() {
+            let mut component = Der(components.expect(SEQUENCE, "a TCB component")?);
+            let id = component.expect(OID, "a TCB component id")?;
+            let (tag, contents, _) = component.next()?;
+            component.finish("a TCB component")?;
+            match id.strip_prefix(SGX_EXTENSION) {
+                Some(SGX_TCB_CPU_SVN) if tag == OCTET_STRING => {
+                    cpu_svn = Some(
+                        contents
+                            .try_into()
+                            .map_err(|_| malformed("the CPU SVN is not 16 bytes"))?,
+                    );
+                }
+                Some(SGX_TCB_PCE_SVN) if tag == INTEGER => pce_svn = Some(small_integer(contents)?),
+                Some(_) if tag == INTEGER => {
+                    small_integer(contents)?;
+                }
+                _ => return Err(malformed("a TCB component is not an SVN")),
+            }
+        }
+        cpu_svn
+            .zip(pce_svn)
+            .ok_or_else(|| malformed("the TCB lacks the CPU SVN or PCE SVN"))
+    }
+}
+
+/// A parsed X.509 certificate with a P-256 key, signed with ECDSA-SHA256.
+#[derive(Clone, Debug)]
+pub struct Certificate {
+    /// The whole encoding.
+    pub der: Vec<u8>,
+    tbs: Vec<u8>,
+    signature: Signature,
+    issuer: Vec<u8>,
+    subject: Vec<u8>,
+    pub not_before_unix: u64,
+    pub not_after_unix: u64,
+    public_key: VerifyingKey,
+    /// The whole `SubjectPublicKeyInfo`.
+    public_key_info: Vec<u8>,
+    /// Present in PCK certificates.
+    pub sgx: Option<SgxExtension>,
+    /// Every extension, as the contents of its id and of its value.
+    extensions: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Certificate {
+    pub fn from_der(der: &[u8]) -> Result<Self, PhalaAvsError> {
+        let mut outer = Der(der);
+        let mut certificate = Der(outer.expect(SEQUENCE, "the certificate")?);
+        outer.finish("the certificate")?;
+        let tbs = certificate.expect_whole(SEQUENCE, "the TBS certificate")?;
+        if certificate.expect(SEQUENCE, "the signature algorithm")? != ECDSA_WITH_SHA256 {
+            return Err(malformed("a certificate is not signed with ECDSA-SHA256"));
+        }
+        let signature = bits(certificate.expect(BIT_STRING, "the signature")?)?;
+        certificate.finish("the certificate")?;
+        let signature = Signature::from_der(signature)
+            .map_err(|_| malformed("a signature is not a DER ECDSA signature"))?;
+
+        let mut fields = Der(Der(tbs).expect(SEQUENCE, "the TBS certificate")?);
+        if fields.optional(VERSION, "the version")? != Some(V3) {
+            return Err(malformed("a certificate is not X.509 version 3"));
+        }
+        fields.expect(INTEGER, "the serial number")?;
+        if fields.expect(SEQUENCE, "the TBS signature algorithm")? != ECDSA_WITH_SHA256 {
+            return Err(malformed("a certificate is not signed with ECDSA-SHA256"));
+        }
+        let issuer = fields.expect_whole(SEQUENCE, "the issuer")?;
+        let mut validity = Der(fields.expect(SEQUENCE, "the validity")?);
+        let (tag, contents, _) = validity.next()?;
+        let not_before_unix = time(tag, contents)?;
+        let (tag, contents, _) = validity.next()?;
+        let not_after_unix = time(tag, contents)?;
+        validity.finish("the validity")?;
+        let subject = fields.expect_whole(SEQUENCE, "the subject")?;
+        let public_key_info = fields.expect_whole(SEQUENCE, "the public key info")?;
+        let mut key_info = Der(Der(public_key_info).expect(SEQUENCE, "the public key info")?);
+        if key_info.expect(SEQUENCE, "the public key algorithm")? != EC_P256 {
+            return Err(malformed("a public key is not on P-256"));
+        }
+        let public_key =
+            VerifyingKey::from_sec1_bytes(bits(key_info.expect(BIT_STRING, "the public key")?)?)
+                .map_err(|_| malformed("a public key is not a P-256 point"))?;
+        key_info.finish("the public key info")?;
+        fields.optional(ISSUER_UNIQUE_ID, "the issuer unique id")?;
+        fields.optional(SUBJECT_UNIQUE_ID, "the subject unique id")?;
+        let extensions = match fields.optional(EXTENSIONS, "the extensions")? {
+            Some(extensions) => extension_list(extensions)?,
+            None => Vec::new(),
+        };
+        let sgx = extensions
+            .iter()
+            .find(|(id, _)| id == SGX_EXTENSION)
+            .map(|(_, value)| SgxExtension::parse(value))
+            .transpose()?;
+        fields.finish("the TBS certificate")?;
+
+        Ok(Self {
+            der: der.to_vec(),
+            tbs: tbs.to_vec(),
+            signature,
+            issuer: issuer.to_vec(),
+            subject: subject.to_vec(),
+            not_before_unix,
+            not_after_unix,
+            public_key,
+            public_key_info: public_key_info.to_vec(),
+            sgx,
+            extensions,
+        })
+    }
+
+    pub fn public_key(&self) -> &VerifyingKey {
+        &self.public_key
+    }
+
+    /// The DER of the `SubjectPublicKeyInfo`.
+    pub fn public_key_info(&self) -> &[u8] {
+        &self.public_key_info
+    }
+
+    /// The value of the extension with the OID of contents `id`, if present.
+    pub fn extension(&self, id: &[u8]) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|(found, _)| found == id)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The common name of the issuer, such as `Intel SGX PCK Platform CA`.
+    pub fn issuer_common_name(&self) -> Option<String> {
+        common_name(&self.issuer)
+    }
+
+    /// Checks that `issuer` issued and signed this certificate.
+    pub fn verify_issued_by(&self, issuer: &Certificate) -> Result<(), PhalaAvsError> {
+        if self.issuer != issuer.subject {
+            return Err(malformed(
+                "a certificate's issuer is not the next in the chain",
+            ));
+        }
+        issuer
+            .public_key
+            .verify(&self.tbs, &self.signature)
+            .map_err(|_| malformed("a certificate's signature does not verify"))
+    }
+
+    pub fn valid_at(&self, now_unix: u64) -> bool {
+        (self.not_before_unix..=self.not_after_unix).contains(&now_unix)
+    }
+}
+
+/// The common name in the DER of a `Name`, if it has one.
+fn common_name(name: &[u8]) -> Option<String> {
+    let mut names = Der(Der(name).expect(SEQUENCE, "a name").ok()?);
+    while !names.is_empty() {
+        let mut attributes = Der(names.expect(SET, "a relative name").ok()?);
+        while !attributes.is_empty() {
+            let mut attribute = Der(attributes.expect(SEQUENCE, "a name attribute").ok()?);
+            if attribute.expect(OID, "an attribute type").ok()? != COMMON_NAME {
+                continue;
+            }
+            return match attribute.next().ok()? {
+                (UTF8_STRING | PRINTABLE_STRING, value, _) => {
+                    String::from_utf8(value.to_vec()).ok()
+                }
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+/// The ids and values of the extensions.
+fn extension_list(explicit: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, PhalaAvsError> {
+    let mut outer = Der(explicit);
+    let mut extensions = Der(outer.expect(SEQUENCE, "the extensions")?);
+    outer.finish("the extensions")?;
+    let mut list = Vec::new();
+    while !extensions.is_empty() {
+        let mut extension = Der(extensions.expect(SEQUENCE, "an extension")?);
+        let id = extension.expect(OID, "an extension id")?;
+        extension.optional(BOOLEAN, "an extension's criticality")?;
+        let value = extension.expect(OCTET_STRING, "an extension value")?;
+        extension.finish("an extension")?;
+        list.push((id.to_vec(), value.to_vec()));
+    }
+    Ok(list)
+}
+
+/// The contents of `der`, if it is exactly one OCTET STRING.
+pub fn octet_string(der: &[u8]) -> Option<&[u8]> {
+    let mut value = Der(der);
+    let contents = value.expect(OCTET_STRING, "an octet string").ok()?;
+    value.is_empty().then_some(contents)
+}
+
+/// The certificates of a PEM chain, in order. Only whitespace and NUL padding may surround them.
+pub fn parse_pem_chain(pem: &[u8]) -> Result<Vec<Certificate>, PhalaAvsError> {
+    let mut chain = Vec::new();
+    let mut rest = pem;
+    loop {
+        let start = rest
+            .iter()
+            .position(|&b| !b.is_ascii_whitespace() && b != 0)
+            .unwrap_or(rest.len());
+        rest = &rest[start..];
+        if rest.is_empty() {
+            break;
+        }
+        rest = rest
+            .strip_prefix(BEGIN)
+            .ok_or_else(|| malformed("expected a PEM certificate"))?;
+        let end = rest
+            .windows(END.len())
+            .position(|window| window == END)
+            .ok_or_else(|| malformed("a PEM certificate is not terminated"))?;
+        chain.push(Certificate::from_der(&base64(&rest[..end])?)?);
+        rest = &rest[end + END.len()..];
+    }
+    if chain.is_empty() {
+        return Err(malformed("no certificates"));
+    }
+    Ok(chain)
+}
+
+/// Decodes padded standard base64, ignoring whitespace.
+pub(super) fn base64(text: &[u8]) -> Result<Vec<u8>, PhalaAvsError> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let (mut acc, mut acc_bits, mut symbols, mut padding) = (0u32, 0u32, 0usize, 0usize);
+    for &c in text.iter().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                padding += 1;
+                symbols += 1;
+                continue;
+            }
+            _ => return Err(malformed("a PEM certificate is not base64")),
+        };
+        if padding > 0 {
+            return Err(malformed("a PEM certificate has data after its padding"));
+        }
+        symbols += 1;
+        acc = (acc << 6) | value as u32;
+        acc_bits += 6;
+        if acc_bits >= 8 {
+            acc_bits -= 8;
+            out.push((acc >> acc_bits) as u8);
+            acc &= (1 << acc_bits) - 1;
+        }
+    }
+    if symbols % 4 != 0 || padding > 2 || acc != 0 {
+        return Err(malformed("a PEM certificate has invalid base64 padding"));
+    }
+    Ok(out)
+}
+
+/// Checks that `chain` runs from its first certificate up to the trusted `root`, each
+/// certificate signed by the next and valid at `now_unix`, and returns the first.
+pub fn verify_chain<'a>(
+    chain: &'a [Certificate],
+    root: &Certificate,
+    now_unix: u64,
+) -> Result<&'a Certificate, PhalaAvsError> {
+    let [leaf, .., last] = chain else {
+        return Err(malformed("a chain needs a leaf and a root"));
+    };
+    if last.der != root.der {
+        return Err(malformed("the chain does not end at the trusted root"));
+    }
+    for pair in chain.windows(2) {
+        pair[0].verify_issued_by(&pair[1])?;
+    }
+    if let Some(expired) = chain.iter().find(|cert| !cert.valid_at(now_unix)) {
+        return Err(malformed(format!(
+            "a certificate is only valid from {} to {}",
+            expired.not_before_unix, expired.not_after_unix
+        )));
+    }
+    Ok(leaf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/dcap")
+                .join(name),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn roots_are_self_signed_and_corruption_is_rejected() {
+        let pem = fixture("root_ca.pem");
+        let chain = parse_pem_chain(&pem).unwrap();
+        let [root] = &chain[..] else {
+            panic!("expected one certificate");
+        };
+        root.verify_issued_by(root).unwrap();
+        assert!(root.sgx.is_none());
+        assert_eq!(
+            root.issuer_common_name().as_deref(),
+            Some("Phala AVS Test Root CA")
+        );
+        // 2024-01-01 to 2034-01-01.
+        assert_eq!(
+            (root.not_before_unix, root.not_after_unix),
+            (1_704_067_200, 2_019_686_400)
+        );
+
+        let other = parse_pem_chain(&fixture("other_root_ca.pem")).unwrap();
+        assert!(root.verify_issued_by(&other[0]).is_err());
+
+        let der = root.der.clone();
+        for i in 0..der.len() {
+            let mut corrupted = der.clone();
+            corrupted[i] ^= 0xff;
+            let verified = Certificate::from_der(&corrupted).and_then(|cert| {
+                cert.verify_issued_by(root)?;
+                Ok(cert)
+            });
+            assert!(verified.is_err(), "corrupting byte {i} went unnoticed");
+        }
+        assert!(parse_pem_chain(b"").is_err());
+        assert!(parse_pem_chain(&pem[..pem.len() - 30]).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! The TEE platform this host runs on.
+//!
+//! Phala hosts are either Intel TDX or legacy SGX, and their attestation flows differ (see
+//! [`super::attestation`]). `TEE_PLATFORM` pins the platform; with `auto`, the default, it is
+//! read from the dstack host API, and hosts without one are assumed to be TDX.
+
+use super::TeeHandler;
+use crate::config::env_or;
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// Gauge set to 1 for the platform this host runs on.
+pub const TEE_PLATFORM_METRIC: &str = "phala_avs_tee_platform";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TeePlatform {
+    Tdx,
+    Sgx,
+}
+
+impl TeePlatform {
+    pub const ALL: [Self; 2] = [Self::Tdx, Self::Sgx];
+
+    /// The platform code `updateCapacity` and measurement policy entries use.
+    pub fn code(self) -> u8 {
+        match self {
+            TeePlatform::Tdx => 1,
+            TeePlatform::Sgx => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.code() == code)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TeePlatform::Tdx => "tdx",
+            TeePlatform::Sgx => "sgx",
+        }
+    }
+}
+
+impl fmt::Display for TeePlatform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TeePlatform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tdx" => Ok(Self::Tdx),
+            "sgx" => Ok(Self::Sgx),
+            other => Err(format!("expected tdx or sgx, got {other}")),
+        }
+    }
+}
+
+/// How the platform is determined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlatformSetting {
+    /// Asked from the host API.
+    #[default]
+    Auto,
+    Fixed(TeePlatform),
+}
+
+impl FromStr for PlatformSetting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            other => other
+                .parse()
+                .map(Self::Fixed)
+                .map_err(|_| format!("expected auto, tdx or sgx, got {other}")),
+        }
+    }
+}
+
+impl PlatformSetting {
+    /// Reads `TEE_PLATFORM`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        env_or("TEE_PLATFORM", Self::Auto)
+    }
+}
+
+impl TeeHandler {
+    pub fn with_platform(mut self, platform: TeePlatform) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    pub fn platform(&self) -> TeePlatform {
+        self.platform
+    }
+
+    /// Sets the platform from `setting`, asking the host API when it is `auto`.
+    ///
+    /// A host API that cannot be reached leaves the platform at TDX with a warning rather than
+    /// failing startup; `TEE_PLATFORM` pins it on such hosts.
+    pub async fn detect_platform(self, setting: PlatformSetting) -> Self {
+        let platform = match (setting, &self.host) {
+            (PlatformSetting::Fixed(platform), _) => platform,
+            (PlatformSetting::Auto, None) => {
+                info!("No dstack host API (TEE_HOST_URL) to ask; assuming a TDX host");
+                TeePlatform::Tdx
+            }
+            (PlatformSetting::Auto, Some(host)) => match host.capacity().await {
+                Ok(capacity) => capacity.platform,
+                Err(e) => {
+                    warn!("Failed to detect the TEE platform, assuming TDX; set TEE_PLATFORM: {e}");
+                    TeePlatform::Tdx
+                }
+            },
+        };
+        info!("Running on a {platform} host");
+        for p in TeePlatform::ALL {
+            METRICS.set_gauge(
+                TEE_PLATFORM_METRIC,
+                &[("platform", p.as_str())],
+                if p == platform { 1.0 } else { 0.0 },
+            );
+        }
+        self.with_platform(platform)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm::BoxFuture;
+    use crate::tee::TeeHandlerConfig;
+    use crate::tee::capacity::{HostApi, Resources, TeeCapacity};
+    use std::sync::Arc;
+
+    /// A host agent reporting `platform`, or failing without one.
+    #[derive(Debug)]
+    struct MockAgent(Option<TeePlatform>);
+
+    impl HostApi for MockAgent {
+        fn capacity(&self) -> BoxFuture<'_, Result<TeeCapacity, PhalaAvsError>> {
+            let reply = self
+                .0
+                .map(|platform| TeeCapacity {
+                    total: Resources::default(),
+                    available: Resources::default(),
+                    platform,
+                })
+                .ok_or_else(|| PhalaAvsError::TeeError("agent is down".to_string()));
+            Box::pin(async move { reply })
+        }
+    }
+
+    async fn detected(agent: Option<MockAgent>, setting: PlatformSetting) -> TeePlatform {
+        let handler = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        let handler = match agent {
+            Some(agent) => handler.with_host(Arc::new(agent)),
+            None => handler,
+        };
+        handler.detect_platform(setting).await.platform()
+    }
+
+    #[tokio::test]
+    async fn platform_is_detected_from_the_agent_unless_pinned() {
+        let sgx = || Some(MockAgent(Some(TeePlatform::Sgx)));
+        assert_eq!(
+            detected(sgx(), PlatformSetting::Auto).await,
+            TeePlatform::Sgx
+        );
+        assert_eq!(
+            detected(sgx(), PlatformSetting::Fixed(TeePlatform::Tdx)).await,
+            TeePlatform::Tdx
+        );
+        // Without an agent, or with one that is down, the host is taken to be TDX.
+        assert_eq!(
+            detected(None, PlatformSetting::Auto).await,
+            TeePlatform::Tdx
+        );
+        assert_eq!(
+            detected(Some(MockAgent(None)), PlatformSetting::Auto).await,
+            TeePlatform::Tdx
+        );
+    }
+
+    #[test]
+    fn settings_and_codes_parse() {
+        assert_eq!("auto".parse(), Ok(PlatformSetting::Auto));
+        assert_eq!("sgx".parse(), Ok(PlatformSetting::Fixed(TeePlatform::Sgx)));
+        assert!("sev".parse::<PlatformSetting>().is_err());
+        for platform in TeePlatform::ALL {
+            assert_eq!(TeePlatform::from_code(platform.code()), Some(platform));
+        }
+        assert_eq!(TeePlatform::from_code(0), None);
+    }
+}
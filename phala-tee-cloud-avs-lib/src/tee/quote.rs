@@ -0,0 +1,512 @@
+//! Parsing of TDX (version 4) and SGX (version 3) quotes.
+//!
+//! Only the fields SLA verification looks at are extracted: the header's version and TEE type,
+//! the measurement registers (MRTD and RTMRs for TDX; MRENCLAVE, MRSIGNER and the ISV SVN for
+//! SGX) and the report data. The signature data is bounds-checked but not verified, and split
+//! into the signatures, the attestation key, the QE report and the PCK chain it carries.
+//!
+//! [`TeeHandler::get_quote`] has tappd (see [`super::tappd`]) produce a TDX quote over given
+//! report data, bounded by `TEE_REQUEST_TIMEOUT_SECS` and retried `TEE_RETRIES` times. The quote
+//! is only handed out once it parses and embeds exactly the report data asked for, and is then
+//! reused for the same report data for a while; see [`super::quote_cache`].
+
+use super::TeeHandler;
+use super::challenge_quote::ChallengeBinding;
+use super::platform::TeePlatform;
+use crate::error::PhalaAvsError;
+use blueprint_sdk::alloy::primitives::{B256, keccak256};
+use serde::Deserialize;
+
+/// TEE type of TDX quotes.
+pub const TDX_TEE_TYPE: u32 = 0x81;
+/// TEE type of SGX quotes; the field is reserved, and zero, in version 3 headers.
+pub const SGX_TEE_TYPE: u32 = 0x00;
+
+pub(super) const HEADER_LEN: usize = 48;
+pub(super) const TDX_BODY_LEN: usize = 584;
+pub(super) const SGX_BODY_LEN: usize = 384;
+/// Offsets within the TD quote body.
+const MR_TD: usize = 136;
+const RTMR: usize = 328;
+const TDX_REPORT_DATA: usize = 520;
+/// Offsets within the SGX report body.
+const MR_ENCLAVE: usize = 64;
+pub(super) const MR_SIGNER: usize = 128;
+pub(super) const ISV_PROD_ID: usize = 256;
+pub(super) const ISV_SVN: usize = 258;
+pub(super) const SGX_REPORT_DATA: usize = 320;
+/// Certification data carrying the QE report, its signature and the PCK chain (TDX).
+const QE_REPORT_CERTIFICATION: u16 = 6;
+/// Certification data carrying the PCK chain in PEM.
+const PCK_CHAIN_CERTIFICATION: u16 = 5;
+const SGX_REPORT_LEN: usize = 384;
+
+fn invalid(reason: String) -> PhalaAvsError {
+    PhalaAvsError::ValidationError(format!("Invalid quote: {reason}"))
+}
+
+/// The version and TEE type every quote starts with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuoteHeader {
+    pub version: u16,
+    pub tee_type: u32,
+}
+
+impl QuoteHeader {
+    pub fn parse(raw: &[u8]) -> Result<Self, PhalaAvsError> {
+        if raw.len() < 8 {
+            return Err(invalid(format!(
+                "{} bytes, too short for a header",
+                raw.len()
+            )));
+        }
+        Ok(Self {
+            version: u16::from_le_bytes([raw[0], raw[1]]),
+            tee_type: u32::from_le_bytes(raw[4..8].try_into().unwrap()),
+        })
+    }
+
+    /// The platform a quote with this header comes from, if it is one we parse.
+    pub fn platform(&self) -> Option<TeePlatform> {
+        match (self.version, self.tee_type) {
+            (4, TDX_TEE_TYPE) => Some(TeePlatform::Tdx),
+            (3, SGX_TEE_TYPE) => Some(TeePlatform::Sgx),
+            _ => None,
+        }
+    }
+}
+
+/// The body of a quote of `body_len` bytes, after checking the signature data length.
+pub(super) fn signed_body(
+    raw: &[u8],
+    body_len: usize,
+    platform: &str,
+) -> Result<&[u8], PhalaAvsError> {
+    let signed_len = HEADER_LEN + body_len + 4;
+    if raw.len() < signed_len {
+        return Err(invalid(format!(
+            "{} bytes, a {platform} quote has at least {signed_len}",
+            raw.len()
+        )));
+    }
+    let signature_len =
+        u32::from_le_bytes(raw[HEADER_LEN + body_len..signed_len].try_into().unwrap()) as usize;
+    if raw.len() != signed_len + signature_len {
+        return Err(invalid(format!(
+            "{} bytes, but the signature data claims {signature_len}",
+            raw.len()
+        )));
+    }
+    Ok(&raw[HEADER_LEN..HEADER_LEN + body_len])
+}
+
+/// The parts of a quote's signature data.
+pub(super) struct SignatureData<'a> {
+    pub(super) quote_signature: &'a [u8],
+    pub(super) attestation_key: &'a [u8],
+    pub(super) qe_report: &'a [u8],
+    pub(super) qe_signature: &'a [u8],
+    pub(super) qe_auth_data: &'a [u8],
+    pub(super) pck_chain: &'a [u8],
+}
+
+impl<'a> SignatureData<'a> {
+    /// Splits the signature data. TDX quotes wrap the QE report and PCK chain in certification
+    /// data of their own; SGX quotes carry them directly.
+    pub(super) fn parse(data: &'a [u8], platform: TeePlatform) -> Result<Self, PhalaAvsError> {
+        let mut data = Cursor(data);
+        let quote_signature = data.take(64, "quote signature")?;
+        let attestation_key = data.take(64, "attestation key")?;
+        let mut qe = match platform {
+            TeePlatform::Tdx => Cursor(data.certification(QE_REPORT_CERTIFICATION)?),
+            TeePlatform::Sgx => data,
+        };
+        let qe_report = qe.take(SGX_REPORT_LEN, "QE report")?;
+        let qe_signature = qe.take(64, "QE report signature")?;
+        let auth_len = qe.u16("QE authentication data length")? as usize;
+        let qe_auth_data = qe.take(auth_len, "QE authentication data")?;
+        let pck_chain = qe.certification(PCK_CHAIN_CERTIFICATION)?;
+        Ok(Self {
+            quote_signature,
+            attestation_key,
+            qe_report,
+            qe_signature,
+            qe_auth_data,
+            pck_chain,
+        })
+    }
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize, what: &str) -> Result<&'a [u8], PhalaAvsError> {
+        if self.0.len() < len {
+            return Err(invalid(format!("the {what} is truncated")));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, PhalaAvsError> {
+        let bytes = self.take(2, what)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Certification data of `kind`, which must fill the rest of the cursor.
+    fn certification(&mut self, kind: u16) -> Result<&'a [u8], PhalaAvsError> {
+        let found = self.u16("certification data type")?;
+        if found != kind {
+            return Err(invalid(format!(
+                "certification data of type {found}, not {kind}"
+            )));
+        }
+        let len = self.take(4, "certification data length")?;
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if self.0.len() != len {
+            return Err(invalid(format!(
+                "certification data of {} bytes claims {len}",
+                self.0.len()
+            )));
+        }
+        self.take(len, "certification data")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TdxQuote {
+    pub version: u16,
+    pub tee_type: u32,
+    pub mr_td: [u8; 48],
+    /// Runtime measurement registers 0 to 3.
+    pub rtmrs: [[u8; 48]; 4],
+    pub report_data: [u8; 64],
+}
+
+impl TdxQuote {
+    pub fn parse(raw: &[u8]) -> Result<Self, PhalaAvsError> {
+        let body = signed_body(raw, TDX_BODY_LEN, "TDX")?;
+        let header = QuoteHeader::parse(raw)?;
+        if header.version != 4 {
+            return Err(invalid(format!("unsupported version {}", header.version)));
+        }
+        let register =
+            |offset: usize| -> [u8; 48] { body[offset..offset + 48].try_into().unwrap() };
+        Ok(Self {
+            version: header.version,
+            tee_type: header.tee_type,
+            mr_td: register(MR_TD),
+            rtmrs: std::array::from_fn(|i| register(RTMR + 48 * i)),
+            report_data: body[TDX_REPORT_DATA..TDX_REPORT_DATA + 64]
+                .try_into()
+                .unwrap(),
+        })
+    }
+
+    /// keccak256 of the MRTD, as allow-listed on-chain. RTMRs are extended at runtime, so they
+    /// are reported but not pinned.
+    pub fn measurement(&self) -> B256 {
+        keccak256(self.mr_td)
+    }
+
+    /// A quote with these fields and empty signature data, for fixtures and tests.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = vec![0u8; HEADER_LEN + TDX_BODY_LEN + 4];
+        raw[0..2].copy_from_slice(&self.version.to_le_bytes());
+        raw[4..8].copy_from_slice(&self.tee_type.to_le_bytes());
+        let body = &mut raw[HEADER_LEN..HEADER_LEN + TDX_BODY_LEN];
+        body[MR_TD..MR_TD + 48].copy_from_slice(&self.mr_td);
+        for (i, rtmr) in self.rtmrs.iter().enumerate() {
+            body[RTMR + 48 * i..RTMR + 48 * (i + 1)].copy_from_slice(rtmr);
+        }
+        body[TDX_REPORT_DATA..TDX_REPORT_DATA + 64].copy_from_slice(&self.report_data);
+        raw
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SgxQuote {
+    pub version: u16,
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+    pub report_data: [u8; 64],
+}
+
+impl SgxQuote {
+    pub fn parse(raw: &[u8]) -> Result<Self, PhalaAvsError> {
+        let body = signed_body(raw, SGX_BODY_LEN, "SGX")?;
+        let header = QuoteHeader::parse(raw)?;
+        if header.version != 3 {
+            return Err(invalid(format!("unsupported version {}", header.version)));
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([body[offset], body[offset + 1]]);
+        Ok(Self {
+            version: header.version,
+            mr_enclave: body[MR_ENCLAVE..MR_ENCLAVE + 32].try_into().unwrap(),
+            mr_signer: body[MR_SIGNER..MR_SIGNER + 32].try_into().unwrap(),
+            isv_prod_id: u16_at(ISV_PROD_ID),
+            isv_svn: u16_at(ISV_SVN),
+            report_data: body[SGX_REPORT_DATA..SGX_REPORT_DATA + 64]
+                .try_into()
+                .unwrap(),
+        })
+    }
+
+    /// `keccak256(abi.encodePacked(mrEnclave, mrSigner, isvSvn))`, as allow-listed on-chain.
+    pub fn measurement(&self) -> B256 {
+        keccak256(
+            [
+                &self.mr_enclave[..],
+                &self.mr_signer,
+                &self.isv_svn.to_be_bytes(),
+            ]
+            .concat(),
+        )
+    }
+
+    /// A quote with these fields and empty signature data, for fixtures and tests.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = vec![0u8; HEADER_LEN + SGX_BODY_LEN + 4];
+        raw[0..2].copy_from_slice(&self.version.to_le_bytes());
+        let body = &mut raw[HEADER_LEN..HEADER_LEN + SGX_BODY_LEN];
+        body[MR_ENCLAVE..MR_ENCLAVE + 32].copy_from_slice(&self.mr_enclave);
+        body[MR_SIGNER..MR_SIGNER + 32].copy_from_slice(&self.mr_signer);
+        body[ISV_PROD_ID..ISV_PROD_ID + 2].copy_from_slice(&self.isv_prod_id.to_le_bytes());
+        body[ISV_SVN..ISV_SVN + 2].copy_from_slice(&self.isv_svn.to_le_bytes());
+        body[SGX_REPORT_DATA..SGX_REPORT_DATA + 64].copy_from_slice(&self.report_data);
+        raw
+    }
+}
+
+/// tappd's quoting endpoint. With the `raw` hash algorithm the report data is embedded as given.
+const TDX_QUOTE_PATH: &str = "/prpc/Tappd.TdxQuote?json";
+
+/// A quote produced in this TEE, with its parsed header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuoteBundle {
+    pub header: QuoteHeader,
+    pub raw: Vec<u8>,
+    /// The fields its report data is the hash of, for quotes bound to a challenge; see
+    /// [`super::challenge_quote`].
+    pub challenge: Option<ChallengeBinding>,
+}
+
+#[derive(Deserialize)]
+struct TdxQuoteReply {
+    quote: String,
+}
+
+impl TeeHandler {
+    /// A TDX quote embedding `report_data`, as raw bytes.
+    pub async fn get_quote(&self, report_data: [u8; 64]) -> Result<Vec<u8>, PhalaAvsError> {
+        self.quote_bundle(report_data)
+            .await
+            .map(|bundle| bundle.raw)
+    }
+
+    /// A TDX quote embedding `report_data`, with its header. Jobs may quote concurrently;
+    /// those asking for the same report data share one quote.
+    pub async fn quote_bundle(&self, report_data: [u8; 64]) -> Result<QuoteBundle, PhalaAvsError> {
+        self.inject_faults().await?;
+        if self.platform() != TeePlatform::Tdx {
+            return Err(PhalaAvsError::TeeError(format!(
+                "Quotes are retrieved from tappd on TDX hosts only, this host is {}",
+                self.platform()
+            )));
+        }
+        self.quote_cache
+            .get_or_quote(&report_data, || self.fetch_quote(report_data))
+            .await
+    }
+
+    /// Forgets the quotes kept for reuse, so the next requests reach tappd.
+    pub fn invalidate_quote_cache(&self) {
+        self.quote_cache.invalidate();
+    }
+
+    /// Has tappd produce a quote over `report_data`, over its own connection.
+    pub(super) async fn fetch_quote(
+        &self,
+        report_data: [u8; 64],
+    ) -> Result<QuoteBundle, PhalaAvsError> {
+        let request = serde_json::json!({
+            "report_data": hex::encode(report_data),
+            "hash_algorithm": "raw",
+        })
+        .to_string();
+        let reply = self
+            .tappd
+            .request("POST", TDX_QUOTE_PATH, Some(&request))
+            .await?;
+        if !reply.is_success() {
+            return Err(PhalaAvsError::TeeError(format!(
+                "tappd answered the quote request with status {}: {}",
+                reply.status,
+                reply.body.trim()
+            )));
+        }
+        let reply: TdxQuoteReply = serde_json::from_str(&reply.body).map_err(|e| {
+            PhalaAvsError::TeeError(format!("Unreadable quote reply from tappd: {e}"))
+        })?;
+        let raw = hex::decode(reply.quote.trim().trim_start_matches("0x"))
+            .map_err(|e| PhalaAvsError::TeeError(format!("tappd returned a non-hex quote: {e}")))?;
+        bundle_quote(raw, &report_data)
+    }
+}
+
+/// Checks that `raw` is a TDX quote over `report_data`.
+fn bundle_quote(raw: Vec<u8>, report_data: &[u8; 64]) -> Result<QuoteBundle, PhalaAvsError> {
+    if raw.is_empty() {
+        return Err(PhalaAvsError::TeeError(
+            "tappd returned an empty quote".to_string(),
+        ));
+    }
+    let quote = TdxQuote::parse(&raw)?;
+    if quote.tee_type != TDX_TEE_TYPE {
+        return Err(invalid(format!(
+            "TEE type {:#x} is not TDX",
+            quote.tee_type
+        )));
+    }
+    if &quote.report_data != report_data {
+        return Err(invalid(format!(
+            "it embeds report data 0x{}, not the 0x{} requested",
+            hex::encode(quote.report_data),
+            hex::encode(report_data)
+        )));
+    }
+    Ok(QuoteBundle {
+        header: QuoteHeader::parse(&raw)?,
+        raw,
+        challenge: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tee::tappd::fake_tappd;
+    use crate::tee::{TeeEndpoint, TeeHandlerConfig};
+    use blueprint_sdk::testing::tempfile::TempDir;
+    use std::path::PathBuf;
+
+    /// A `Tappd.TdxQuote` reply, with a structurally valid quote over [`FIXTURE_REPORT_DATA`].
+    fn fixture_reply() -> String {
+        std::fs::read_to_string(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/quotes/tdx_quote_reply.json"),
+        )
+        .unwrap()
+    }
+
+    const FIXTURE_REPORT_DATA: &str = "68ec9752e3225a8256440371ef03eaecc4a3c2c2093b23460074ab764f443a13\
+                                       cd39484f008fc38e5e7103512807c7c092e6939baa4e8657a8b4223ee7c1b01d";
+
+    fn http_ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    async fn handler(dir: &TempDir, reply: String) -> TeeHandler {
+        let socket = fake_tappd(dir.path(), Some(reply));
+        TeeHandler::new(TeeHandlerConfig {
+            endpoint: TeeEndpoint::Socket(socket),
+            ..TeeHandlerConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn recorded_quote_is_bundled_with_its_header() {
+        let dir = TempDir::new().unwrap();
+        let tee = handler(&dir, http_ok(&fixture_reply())).await;
+        let report_data: [u8; 64] = hex::decode(FIXTURE_REPORT_DATA)
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        // Concurrent jobs quote independently.
+        let (a, b) = tokio::join!(tee.quote_bundle(report_data), tee.get_quote(report_data));
+        let bundle = a.unwrap();
+        assert_eq!(bundle.header, QuoteHeader {
+            version: 4,
+            tee_type: TDX_TEE_TYPE
+        });
+        assert_eq!(bundle.header.platform(), Some(TeePlatform::Tdx));
+        assert_eq!(b.unwrap(), bundle.raw);
+        assert_eq!(
+            TdxQuote::parse(&bundle.raw).unwrap().report_data,
+            report_data
+        );
+
+        // The same quote does not pass for other report data.
+        let err = tee.get_quote([0; 64]).await.unwrap_err();
+        assert!(err.to_string().contains("not the 0x0000"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn empty_and_failed_quotes_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        let tee = handler(&dir, http_ok(r#"{"quote":"","event_log":"[]"}"#)).await;
+        let err = tee.get_quote([1; 64]).await.unwrap_err();
+        assert!(err.to_string().contains("empty quote"), "{err}");
+
+        let dir = TempDir::new().unwrap();
+        let reply = "HTTP/1.1 500 Internal Server Error\r\n\r\nquote generation failed";
+        let tee = handler(&dir, reply.to_string()).await;
+        let err = tee.get_quote([1; 64]).await.unwrap_err();
+        assert!(err.to_string().contains("status 500"), "{err}");
+    }
+
+    #[test]
+    fn quotes_round_trip_and_truncation_is_rejected() {
+        let quote = TdxQuote {
+            version: 4,
+            tee_type: TDX_TEE_TYPE,
+            mr_td: [7; 48],
+            rtmrs: [[1; 48], [2; 48], [3; 48], [4; 48]],
+            report_data: [9; 64],
+        };
+        let raw = quote.to_bytes();
+        assert_eq!(TdxQuote::parse(&raw).unwrap(), quote);
+        assert!(TdxQuote::parse(&raw[..raw.len() - 1]).is_err());
+        let mut padded = raw.clone();
+        padded.push(0);
+        assert!(TdxQuote::parse(&padded).is_err());
+
+        let quote = SgxQuote {
+            version: 3,
+            mr_enclave: [5; 32],
+            mr_signer: [6; 32],
+            isv_prod_id: 1,
+            isv_svn: 7,
+            report_data: [9; 64],
+        };
+        let raw = quote.to_bytes();
+        assert_eq!(SgxQuote::parse(&raw).unwrap(), quote);
+        assert!(SgxQuote::parse(&raw[..raw.len() - 1]).is_err());
+        // Each parser only takes its own platform's quotes.
+        assert!(TdxQuote::parse(&raw).is_err());
+    }
+
+    #[test]
+    fn headers_identify_the_platform() {
+        let platform = |version: u16, tee_type: u32| {
+            let mut raw = vec![0u8; 8];
+            raw[0..2].copy_from_slice(&version.to_le_bytes());
+            raw[4..8].copy_from_slice(&tee_type.to_le_bytes());
+            QuoteHeader::parse(&raw).unwrap().platform()
+        };
+        assert_eq!(platform(4, TDX_TEE_TYPE), Some(TeePlatform::Tdx));
+        assert_eq!(platform(3, SGX_TEE_TYPE), Some(TeePlatform::Sgx));
+        assert_eq!(platform(4, SGX_TEE_TYPE), None);
+        assert_eq!(platform(5, TDX_TEE_TYPE), None);
+        assert!(QuoteHeader::parse(&[4, 0]).is_err());
+    }
+}
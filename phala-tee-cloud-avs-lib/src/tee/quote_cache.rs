@@ -0,0 +1,189 @@
+//! Reuse of recent quotes.
+//!
+//! Heartbeats and concurrent challenge responses may ask for a quote over the same report data
+//! within seconds of each other, and each quote costs a round trip through the TEE. Quotes are
+//! kept per report data for `TEE_QUOTE_CACHE_TTL_SECS`, counted from when the request for them
+//! was sent, and callers asking while a quote is being produced wait for it rather than asking
+//! again. Failures are not kept. A TTL of zero turns the cache off.
+
+use super::quote::QuoteBundle;
+use crate::error::PhalaAvsError;
+use crate::metrics::METRICS;
+use blueprint_sdk::alloy::primitives::{B256, keccak256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Quote requests, by whether the cache answered them.
+pub const TEE_QUOTE_CACHE_METRIC: &str = "phala_avs_tee_quote_cache_total";
+
+/// The quote of one report data, once produced. Callers hold the lock while producing it.
+type Slot = Arc<tokio::sync::Mutex<Option<(Instant, QuoteBundle)>>>;
+
+/// Quotes by the keccak256 of their report data, shared by the clones of a
+/// [`TeeHandler`](super::TeeHandler).
+#[derive(Debug)]
+pub struct QuoteCache {
+    ttl: Duration,
+    slots: Mutex<HashMap<B256, Slot>>,
+}
+
+impl QuoteCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            slots: Mutex::default(),
+        }
+    }
+
+    /// The quote over `report_data` produced less than the TTL ago, or else the one `quote`
+    /// produces.
+    pub async fn get_or_quote<F, Fut>(
+        &self,
+        report_data: &[u8; 64],
+        quote: F,
+    ) -> Result<QuoteBundle, PhalaAvsError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<QuoteBundle, PhalaAvsError>>,
+    {
+        if self.ttl.is_zero() {
+            return quote().await;
+        }
+        let slot = self.slot(keccak256(report_data));
+        let mut cached = slot.lock().await;
+        let fresh = cached
+            .as_ref()
+            .filter(|(requested_at, _)| requested_at.elapsed() < self.ttl);
+        if let Some((_, bundle)) = fresh {
+            METRICS.inc_counter(TEE_QUOTE_CACHE_METRIC, &[("outcome", "hit")], 1);
+            return Ok(bundle.clone());
+        }
+        METRICS.inc_counter(TEE_QUOTE_CACHE_METRIC, &[("outcome", "miss")], 1);
+        let requested_at = Instant::now();
+        let bundle = quote().await?;
+        *cached = Some((requested_at, bundle.clone()));
+        Ok(bundle)
+    }
+
+    /// Drops every kept quote. Quotes being produced are handed to their waiting callers but
+    /// not kept.
+    pub fn invalidate(&self) {
+        self.slots.lock().unwrap().clear();
+    }
+
+    /// The slot of `key`, dropping expired slots nobody is using when adding one.
+    fn slot(&self, key: B256) -> Slot {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.get(&key) {
+            return slot.clone();
+        }
+        slots.retain(|_, slot| match slot.try_lock() {
+            Ok(cached) => cached
+                .as_ref()
+                .is_some_and(|(requested_at, _)| requested_at.elapsed() < self.ttl),
+            Err(_) => true,
+        });
+        slots.entry(key).or_default().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tee::quote::{QuoteHeader, TDX_TEE_TYPE};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn bundle(byte: u8) -> QuoteBundle {
+        QuoteBundle {
+            header: QuoteHeader {
+                version: 4,
+                tee_type: TDX_TEE_TYPE,
+            },
+            raw: vec![byte; 48],
+            challenge: None,
+        }
+    }
+
+    /// Counts its calls, taking a while to answer so concurrent callers overlap.
+    async fn produce(calls: &AtomicUsize) -> Result<QuoteBundle, PhalaAvsError> {
+        let call = calls.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(bundle(call as u8))
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_quote() {
+        let cache = QuoteCache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        let (a, b) = tokio::join!(
+            cache.get_or_quote(&[1; 64], || produce(&calls)),
+            cache.get_or_quote(&[1; 64], || produce(&calls)),
+        );
+        assert_eq!(a.unwrap(), b.unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Other report data gets its own quote.
+        cache
+            .get_or_quote(&[2; 64], || produce(&calls))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn quotes_expire_and_can_be_invalidated() {
+        let cache = QuoteCache::new(Duration::from_millis(150));
+        let calls = AtomicUsize::new(0);
+        let first = cache
+            .get_or_quote(&[1; 64], || produce(&calls))
+            .await
+            .unwrap();
+        // The TTL counts from the request, not from the answer 50ms later.
+        tokio::time::sleep(Duration::from_millis(110)).await;
+        let expired = cache
+            .get_or_quote(&[1; 64], || produce(&calls))
+            .await
+            .unwrap();
+        assert_ne!(first, expired);
+
+        let kept = cache
+            .get_or_quote(&[1; 64], || produce(&calls))
+            .await
+            .unwrap();
+        assert_eq!(kept, expired);
+        cache.invalidate();
+        cache
+            .get_or_quote(&[1; 64], || produce(&calls))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn failures_and_disabled_caches_keep_nothing() {
+        let cache = QuoteCache::new(Duration::from_secs(60));
+        let failed = cache
+            .get_or_quote(&[1; 64], || async {
+                Err(PhalaAvsError::TeeError("tappd is down".to_string()))
+            })
+            .await;
+        assert!(failed.is_err());
+        let calls = AtomicUsize::new(0);
+        cache
+            .get_or_quote(&[1; 64], || produce(&calls))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let disabled = QuoteCache::new(Duration::ZERO);
+        for _ in 0..2 {
+            disabled
+                .get_or_quote(&[1; 64], || produce(&calls))
+                .await
+                .unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
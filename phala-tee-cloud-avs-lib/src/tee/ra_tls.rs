@@ -0,0 +1,362 @@
+//! RA-TLS: trusting a remote TEE agent for the quote in its certificate.
+//!
+//! An operator running the blueprint outside the CVM reaches tappd over `https`. With
+//! `TEE_RA_TLS` set, the agent's certificate is not checked against CAs: it must carry a quote
+//! in the extension [`RA_TLS_QUOTE_OID`], as dstack's RA-TLS certificates do, and the connection
+//! is refused unless
+//!
+//! 1. the certificate parses and is valid now;
+//! 2. the quote's report data is [`report_data_for`] the certificate's public key, so the quote
+//!    was produced for this certificate and not lifted from another one;
+//! 3. the quote verifies under DCAP, when the handler has a [`DcapVerifier`];
+//! 4. its measurements are those of an image the [`MeasurementAllowlist`] approves.
+//!
+//! The TLS handshake then proves the agent holds the certificate's key. Without a measurement
+//! policy every connection is refused, as measurement checks are everywhere else.
+
+use super::TeeHandler;
+use super::attestation::{AttestationReport, parse_quote};
+use super::dcap::DcapVerifier;
+use super::measurement_policy::MeasurementAllowlist;
+use super::pck::{self, Certificate};
+use super::tappd::TappdClient;
+use crate::error::PhalaAvsError;
+use crate::evidence::now_unix_ms;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha512};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+/// 1.3.6.1.4.1.62397.1.1, the certificate extension of the quote.
+pub const RA_TLS_QUOTE_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xe7, 0x3d, 0x01, 0x01];
+
+/// Prefix of the public key in the preimage of the report data.
+const REPORT_DATA_TAG: &[u8] = b"ratls-cert:";
+
+fn refused(reason: impl fmt::Display) -> PhalaAvsError {
+    PhalaAvsError::ValidationError(format!("RA-TLS certificate refused: {reason}"))
+}
+
+/// The report data binding a quote to the certificate of `public_key_info`:
+/// `sha512("ratls-cert:" || SubjectPublicKeyInfo)`.
+pub fn report_data_for(public_key_info: &[u8]) -> [u8; 64] {
+    Sha512::new()
+        .chain_update(REPORT_DATA_TAG)
+        .chain_update(public_key_info)
+        .finalize()
+        .into()
+}
+
+/// An RA-TLS certificate, with the quote it carries bound to its key.
+#[derive(Clone, Debug)]
+pub struct RaTlsCertificate {
+    pub certificate: Certificate,
+    pub quote: Vec<u8>,
+    pub report: AttestationReport,
+}
+
+impl RaTlsCertificate {
+    /// Parses `der` and its quote, checking the quote is bound to the certificate's key.
+    pub fn parse(der: &[u8]) -> Result<Self, PhalaAvsError> {
+        let certificate = Certificate::from_der(der)?;
+        let extension = certificate
+            .extension(RA_TLS_QUOTE_OID)
+            .ok_or_else(|| refused("the certificate carries no quote"))?;
+        // dstack wraps the quote in an OCTET STRING of its own.
+        let quote = pck::octet_string(extension).unwrap_or(extension).to_vec();
+        let report = parse_quote(&quote)?;
+        if report.report_data[..] != report_data_for(certificate.public_key_info()) {
+            return Err(refused("the quote is not bound to the certificate's key"));
+        }
+        Ok(Self {
+            certificate,
+            quote,
+            report,
+        })
+    }
+}
+
+/// What RA-TLS certificates are checked against, set once the handler has it.
+#[derive(Default)]
+struct Trust {
+    measurements: Option<Arc<MeasurementAllowlist>>,
+    dcap: Option<Arc<DcapVerifier>>,
+}
+
+/// Verifies the certificates of an RA-TLS agent, in place of CA verification.
+pub struct RaTlsVerifier {
+    trust: RwLock<Trust>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl fmt::Debug for RaTlsVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let trust = self.trust.read().unwrap_or_else(|e| e.into_inner());
+        f.debug_struct("RaTlsVerifier")
+            .field("measurements", &trust.measurements.is_some())
+            .field("dcap", &trust.dcap.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for RaTlsVerifier {
+    fn default() -> Self {
+        Self {
+            trust: RwLock::default(),
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+}
+
+impl RaTlsVerifier {
+    pub fn set_measurement_policy(&self, allowlist: Arc<MeasurementAllowlist>) {
+        self.trust
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .measurements = Some(allowlist);
+    }
+
+    pub fn set_dcap(&self, verifier: Arc<DcapVerifier>) {
+        self.trust.write().unwrap_or_else(|e| e.into_inner()).dcap = Some(verifier);
+    }
+
+    /// Checks the agent certificate `der` as of `now_unix`, returning what its quote attests.
+    pub fn verify_certificate(
+        &self,
+        der: &[u8],
+        now_unix: u64,
+    ) -> Result<AttestationReport, PhalaAvsError> {
+        let (measurements, dcap) = {
+            let trust = self.trust.read().unwrap_or_else(|e| e.into_inner());
+            (trust.measurements.clone(), trust.dcap.clone())
+        };
+        let measurements = measurements.ok_or_else(|| {
+            refused("RA-TLS needs TEE_MEASUREMENT_POLICY_PATH or TEE_MEASUREMENT_POLICY")
+        })?;
+        let certificate = RaTlsCertificate::parse(der)?;
+        if !certificate.certificate.valid_at(now_unix) {
+            return Err(refused("the certificate is not valid now"));
+        }
+        let report = match dcap {
+            Some(dcap) => dcap.verify(&certificate.quote, now_unix)?.report,
+            None => certificate.report,
+        };
+        measurements.check(&report.measurements)?;
+        Ok(report)
+    }
+
+    /// A TLS client configuration verifying servers with `self`.
+    pub fn client_config(self: &Arc<Self>) -> Result<rustls::ClientConfig, PhalaAvsError> {
+        Ok(
+            rustls::ClientConfig::builder_with_provider(Arc::clone(&self.provider))
+                .with_safe_default_protocol_versions()
+                .map_err(|e| PhalaAvsError::TeeError(format!("Failed to set up RA-TLS: {e}")))?
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::clone(self) as _)
+                .with_no_client_auth(),
+        )
+    }
+}
+
+impl ServerCertVerifier for RaTlsVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.verify_certificate(end_entity, now.as_secs()) {
+            Ok(_) => Ok(ServerCertVerified::assertion()),
+            Err(e) => {
+                warn!("Refusing the TEE agent's certificate: {e}");
+                Err(rustls::Error::General(e.to_string()))
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl TeeHandler {
+    /// Has `verifier` check against the handler's measurement policy and DCAP verifier.
+    fn arm_ra_tls(&self, verifier: &RaTlsVerifier) {
+        if let Some(allowlist) = &self.measurement_policy {
+            verifier.set_measurement_policy(Arc::clone(allowlist));
+        }
+        if let Some(dcap) = &self.dcap {
+            verifier.set_dcap(Arc::clone(dcap));
+        }
+    }
+
+    /// Arms the RA-TLS verifiers of the handler's agents, its fleet's included.
+    pub(super) fn share_ra_tls_trust(&self) {
+        let fleet = self.fleet.iter().flat_map(|fleet| fleet.clients());
+        for verifier in std::iter::once(&self.tappd)
+            .chain(fleet)
+            .filter_map(TappdClient::ra_tls)
+        {
+            self.arm_ra_tls(verifier);
+        }
+    }
+
+    /// Checks the certificate `der` of an RA-TLS agent now, as connections to the handler's
+    /// agents are checked.
+    pub fn verify_ra_tls_certificate(
+        &self,
+        der: &[u8],
+    ) -> Result<AttestationReport, PhalaAvsError> {
+        let verifier = RaTlsVerifier::default();
+        self.arm_ra_tls(&verifier);
+        verifier.verify_certificate(der, now_unix_ms() / 1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tee::measurement_policy::MeasurementPolicy;
+    use crate::tee::{TeeEndpoint, TeeHandlerConfig};
+    use blueprint_sdk::alloy::primitives::keccak256;
+    use std::path::PathBuf;
+
+    /// 2024-09-15T00:00:00Z, within the fixture certificates' validity.
+    const NOW: u64 = 1_726_358_400;
+
+    /// The DER of a certificate under `tests/fixtures/ra_tls`, all self-signed P-256 with the
+    /// same key:
+    ///
+    /// - `valid.pem` carries a TDX quote of the fixture image, bound to its key;
+    /// - `tampered_quote.pem` carries the same quote bound to another key;
+    /// - `plain.pem` carries no quote.
+    fn certificate(name: &str) -> Vec<u8> {
+        let pem = std::fs::read(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/ra_tls")
+                .join(name),
+        )
+        .unwrap();
+        pck::parse_pem_chain(&pem).unwrap().remove(0).der
+    }
+
+    /// A policy approving the image of MRTD `mr_td`, whatever its RTMRs.
+    fn policy(mr_td: u8) -> Arc<MeasurementAllowlist> {
+        let raw = format!(
+            r#"{{"images": [{{"mr_td": "{}", "rtmrs": ["*", "*", "*", "*"]}}]}}"#,
+            hex::encode([mr_td; 48])
+        );
+        Arc::new(MeasurementAllowlist::new(
+            MeasurementPolicy::from_json(&raw).unwrap(),
+        ))
+    }
+
+    fn verifier(mr_td: u8) -> RaTlsVerifier {
+        let verifier = RaTlsVerifier::default();
+        verifier.set_measurement_policy(policy(mr_td));
+        verifier
+    }
+
+    #[test]
+    fn a_bound_quote_of_an_approved_image_is_trusted() {
+        let report = verifier(0x5a)
+            .verify_certificate(&certificate("valid.pem"), NOW)
+            .unwrap();
+        assert_eq!(report.measurement, keccak256([0x5a; 48]));
+
+        let parsed = RaTlsCertificate::parse(&certificate("valid.pem")).unwrap();
+        assert_eq!(
+            parsed.report.report_data[..],
+            report_data_for(parsed.certificate.public_key_info())
+        );
+    }
+
+    #[test]
+    fn tampered_plain_and_unapproved_certificates_are_refused() {
+        let refusal = |verifier: &RaTlsVerifier, der: &[u8], now: u64| {
+            verifier
+                .verify_certificate(der, now)
+                .unwrap_err()
+                .to_string()
+        };
+        let approved = verifier(0x5a);
+        let err = refusal(&approved, &certificate("tampered_quote.pem"), NOW);
+        assert!(err.contains("not bound"), "{err}");
+        let err = refusal(&approved, &certificate("plain.pem"), NOW);
+        assert!(err.contains("carries no quote"), "{err}");
+        // Valid from 2024 to 2034.
+        let err = refusal(&approved, &certificate("valid.pem"), 1_700_000_000);
+        assert!(err.contains("not valid now"), "{err}");
+
+        let err = refusal(&verifier(0x5b), &certificate("valid.pem"), NOW);
+        assert!(err.contains("MRTD"), "{err}");
+        let err = refusal(&RaTlsVerifier::default(), &certificate("valid.pem"), NOW);
+        assert!(err.contains("needs TEE_MEASUREMENT_POLICY"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn ra_tls_agents_get_the_handler_policy() {
+        let tee = TeeHandler::new(TeeHandlerConfig {
+            endpoint: TeeEndpoint::parse("https://agent.example:8090").unwrap(),
+            ra_tls: true,
+            ..TeeHandlerConfig::default()
+        })
+        .await
+        .unwrap();
+        let verifier = tee.tappd.ra_tls().unwrap().clone();
+        assert!(
+            verifier
+                .verify_certificate(&certificate("valid.pem"), NOW)
+                .is_err()
+        );
+        let tee = tee.with_measurement_policy(policy(0x5a));
+        assert!(
+            verifier
+                .verify_certificate(&certificate("valid.pem"), NOW)
+                .is_ok()
+        );
+        assert!(
+            tee.verify_ra_tls_certificate(&certificate("plain.pem"))
+                .is_err()
+        );
+
+        let plain = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        assert!(plain.tappd.ra_tls().is_none());
+    }
+}
@@ -0,0 +1,348 @@
+//! CPU, memory, disk and network use of this host or of a deployed workload.
+//!
+//! Host metrics come from tappd's `Tappd.Metrics` RPC and workload metrics from
+//! `GET <url>/cvms/<id>/metrics` of the Phala Cloud API. Both answer with sections like
+//!
+//! ```json
+//! {"cpu": {"percent": 150, "vcpus": 2},
+//!  "memory": {"used": "1.5GiB", "total": 4096, "unit": "MiB"},
+//!  "disk": {"used": 2000000000, "total": "20 GB"},
+//!  "network": {"rx": "12kB", "tx": 3400}}
+//! ```
+//!
+//! and [`ResourceMetrics`] normalizes them to bytes and to ratios between 0 and 1:
+//!
+//! - a quantity is a number of the section's `unit`, bytes by default, or a string with its
+//!   own unit; `kB`, `MB`, `GB` and `TB` are powers of 1000, while `KiB`, `MiB`, `GiB`, `TiB`
+//!   and the bare `K`, `M`, `G` and `T` of `free` and `top` are powers of 1024; units are
+//!   case-insensitive;
+//! - CPU use is a `usage` ratio, or a `percent`, which counts 100 per vCPU when `vcpus` is given;
+//!   it is clamped to 1.
+//!
+//! What the reply leaves out is `None`, never zero; a quantity that is negative, not a number or
+//! in an unknown unit fails the whole reply.
+
+use super::TeeHandler;
+use super::deploy::{DeployFailure, WorkloadId};
+use crate::error::PhalaAvsError;
+use serde::{Deserialize, Serialize};
+
+/// tappd's metrics endpoint.
+const HOST_METRICS_PATH: &str = "/prpc/Tappd.Metrics?json";
+
+/// Resource use, in bytes and ratios.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceMetrics {
+    /// Share of all vCPUs in use, from 0 to 1.
+    pub cpu_usage: Option<f64>,
+    pub memory_used_bytes: Option<u64>,
+    pub memory_total_bytes: Option<u64>,
+    pub disk_used_bytes: Option<u64>,
+    pub disk_total_bytes: Option<u64>,
+    /// Bytes received since boot.
+    pub network_rx_bytes: Option<u64>,
+    /// Bytes sent since boot.
+    pub network_tx_bytes: Option<u64>,
+}
+
+impl ResourceMetrics {
+    /// Share of the memory in use, from 0 to 1, when both sides are known.
+    pub fn memory_usage(&self) -> Option<f64> {
+        usage(self.memory_used_bytes?, self.memory_total_bytes?)
+    }
+
+    /// Share of the disk in use, from 0 to 1, when both sides are known.
+    pub fn disk_usage(&self) -> Option<f64> {
+        usage(self.disk_used_bytes?, self.disk_total_bytes?)
+    }
+
+    /// Normalizes a metrics reply; see the module docs.
+    pub fn from_reply(reply: &serde_json::Value) -> Result<Self, String> {
+        let raw = RawMetrics::deserialize(reply).map_err(|e| e.to_string())?;
+        let memory = raw.memory.unwrap_or_default();
+        let disk = raw.disk.unwrap_or_default();
+        let network = raw.network.unwrap_or_default();
+        Ok(Self {
+            cpu_usage: raw.cpu.map(|cpu| cpu.usage()).transpose()?.flatten(),
+            memory_used_bytes: memory.bytes("memory.used", &memory.used)?,
+            memory_total_bytes: memory.bytes("memory.total", &memory.total)?,
+            disk_used_bytes: disk.bytes("disk.used", &disk.used)?,
+            disk_total_bytes: disk.bytes("disk.total", &disk.total)?,
+            network_rx_bytes: network.bytes("network.rx", &network.rx)?,
+            network_tx_bytes: network.bytes("network.tx", &network.tx)?,
+        })
+    }
+}
+
+fn usage(used: u64, total: u64) -> Option<f64> {
+    (total > 0).then(|| (used as f64 / total as f64).min(1.0))
+}
+
+#[derive(Deserialize)]
+struct RawMetrics {
+    cpu: Option<RawCpu>,
+    memory: Option<RawSection>,
+    disk: Option<RawSection>,
+    network: Option<RawSection>,
+}
+
+#[derive(Deserialize)]
+struct RawCpu {
+    usage: Option<f64>,
+    percent: Option<f64>,
+    vcpus: Option<u32>,
+}
+
+impl RawCpu {
+    fn usage(&self) -> Result<Option<f64>, String> {
+        let usage = match (self.usage, self.percent) {
+            (Some(usage), _) => usage,
+            (None, Some(percent)) => percent / 100.0 / f64::from(self.vcpus.unwrap_or(1).max(1)),
+            (None, None) => return Ok(None),
+        };
+        if usage.is_nan() || usage < 0.0 {
+            return Err(format!("invalid CPU use {usage}"));
+        }
+        Ok(Some(usage.min(1.0)))
+    }
+}
+
+/// A section of quantities, with the unit of those given as bare numbers.
+#[derive(Default, Deserialize)]
+struct RawSection {
+    unit: Option<String>,
+    used: Option<Quantity>,
+    total: Option<Quantity>,
+    rx: Option<Quantity>,
+    tx: Option<Quantity>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Quantity {
+    Number(f64),
+    Text(String),
+}
+
+impl RawSection {
+    fn bytes(&self, field: &str, quantity: &Option<Quantity>) -> Result<Option<u64>, String> {
+        let Some(quantity) = quantity else {
+            return Ok(None);
+        };
+        let (value, unit) = match quantity {
+            Quantity::Number(value) => (*value, self.unit.as_deref().unwrap_or("")),
+            Quantity::Text(text) => {
+                let text = text.trim();
+                let split = text
+                    .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+                    .unwrap_or(text.len());
+                let (number, unit) = text.split_at(split);
+                let value = number
+                    .parse::<f64>()
+                    .map_err(|_| format!("{field} is not a quantity: {text:?}"))?;
+                match unit.trim() {
+                    "" => (value, self.unit.as_deref().unwrap_or("")),
+                    unit => (value, unit),
+                }
+            }
+        };
+        let multiplier =
+            unit_bytes(unit).ok_or_else(|| format!("{field} has an unknown unit {unit:?}"))?;
+        let bytes = value * multiplier;
+        if !(bytes.is_finite() && bytes >= 0.0) {
+            return Err(format!("{field} is not a size: {value}"));
+        }
+        Ok(Some(bytes.round() as u64))
+    }
+}
+
+/// Bytes in one `unit`.
+fn unit_bytes(unit: &str) -> Option<f64> {
+    let power = |base: f64, exponent: i32| Some(base.powi(exponent));
+    match unit.to_ascii_lowercase().as_str() {
+        "" | "b" | "byte" | "bytes" => Some(1.0),
+        "kb" => power(1000.0, 1),
+        "mb" => power(1000.0, 2),
+        "gb" => power(1000.0, 3),
+        "tb" => power(1000.0, 4),
+        "k" | "ki" | "kib" => power(1024.0, 1),
+        "m" | "mi" | "mib" => power(1024.0, 2),
+        "g" | "gi" | "gib" => power(1024.0, 3),
+        "t" | "ti" | "tib" => power(1024.0, 4),
+        _ => None,
+    }
+}
+
+impl TeeHandler {
+    /// Resource use of the workload deployed as `id`, or of this host without one.
+    pub async fn get_resource_metrics(
+        &self,
+        id: Option<&WorkloadId>,
+    ) -> Result<ResourceMetrics, PhalaAvsError> {
+        self.inject_faults().await?;
+        let reply = match id {
+            None => self.host_metrics().await?,
+            Some(id) => match self.cloud_deployer()?.api().metrics(id).await {
+                Ok(reply) => reply,
+                Err(DeployFailure::NotFound(id)) => {
+                    return Err(PhalaAvsError::NotFound(format!(
+                        "Phala Cloud has no workload {id}"
+                    )));
+                }
+                Err(e) => {
+                    return Err(PhalaAvsError::TeeError(format!(
+                        "Failed to read the metrics of workload {id}: {e}"
+                    )));
+                }
+            },
+        };
+        ResourceMetrics::from_reply(&reply)
+            .map_err(|e| PhalaAvsError::TeeError(format!("Invalid resource metrics: {e}")))
+    }
+
+    async fn host_metrics(&self) -> Result<serde_json::Value, PhalaAvsError> {
+        let reply = self.tappd.request("GET", HOST_METRICS_PATH, None).await?;
+        if !reply.is_success() {
+            return Err(PhalaAvsError::TeeError(format!(
+                "tappd answered the metrics request with status {}: {}",
+                reply.status,
+                reply.body.trim()
+            )));
+        }
+        serde_json::from_str(&reply.body)
+            .map_err(|e| PhalaAvsError::TeeError(format!("Invalid tappd metrics reply: {e}")))
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::tee::deploy::{CloudConfig, HttpCloudApi};
+    use crate::tee::tappd::fake_tappd;
+    use crate::tee::{TeeEndpoint, TeeHandlerConfig};
+    use axum::Router;
+    use axum::extract::Path;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use blueprint_sdk::testing::tempfile::TempDir;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn metrics(reply: serde_json::Value) -> Result<ResourceMetrics, String> {
+        ResourceMetrics::from_reply(&reply)
+    }
+
+    #[test]
+    fn units_are_normalized_to_bytes() {
+        let m = metrics(json!({
+            "memory": {"used": "1.5GiB", "total": 4096, "unit": "MiB"},
+            "disk": {"used": 2_000_000_000u64, "total": "20 GB"},
+            "network": {"rx": "12kB", "tx": "3K"},
+        }))
+        .unwrap();
+        assert_eq!(m.memory_used_bytes, Some(1_610_612_736));
+        assert_eq!(m.memory_total_bytes, Some(4_294_967_296));
+        assert_eq!(m.memory_usage(), Some(0.375));
+        assert_eq!(m.disk_used_bytes, Some(2_000_000_000));
+        assert_eq!(m.disk_total_bytes, Some(20_000_000_000));
+        assert_eq!(m.disk_usage(), Some(0.1));
+        assert_eq!(m.network_rx_bytes, Some(12_000));
+        assert_eq!(m.network_tx_bytes, Some(3_072));
+
+        // A string's own unit beats the section's; a bare string takes the section's.
+        let m = metrics(json!({"memory": {"used": "512", "total": "1gb", "unit": "kib"}})).unwrap();
+        assert_eq!(m.memory_used_bytes, Some(524_288));
+        assert_eq!(m.memory_total_bytes, Some(1_000_000_000));
+
+        // Used above total reads as full, and an empty total as unknown.
+        let m =
+            metrics(json!({"disk": {"used": 30, "total": 20}, "memory": {"used": 1, "total": 0}}))
+                .unwrap();
+        assert_eq!(m.disk_usage(), Some(1.0));
+        assert_eq!(m.memory_usage(), None);
+
+        for bad in [
+            json!({"memory": {"used": "12 parsecs"}}),
+            json!({"memory": {"used": -1}}),
+            json!({"disk": {"total": "GB"}}),
+            json!({"network": {"rx": 5, "unit": "furlongs"}}),
+        ] {
+            assert!(metrics(bad.clone()).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn cpu_use_is_a_ratio() {
+        let cpu = |cpu| metrics(json!({ "cpu": cpu })).map(|m| m.cpu_usage);
+        assert_eq!(cpu(json!({"usage": 0.25})), Ok(Some(0.25)));
+        assert_eq!(cpu(json!({"percent": 40})), Ok(Some(0.4)));
+        // top counts 100% per vCPU.
+        assert_eq!(cpu(json!({"percent": 150, "vcpus": 2})), Ok(Some(0.75)));
+        assert_eq!(cpu(json!({"percent": 250})), Ok(Some(1.0)));
+        assert_eq!(cpu(json!({"vcpus": 2})), Ok(None));
+        assert!(cpu(json!({"usage": -0.1})).is_err());
+    }
+
+    #[tokio::test]
+    async fn host_metrics_leave_missing_memory_unknown() {
+        let dir = TempDir::new().unwrap();
+        let body = r#"{"cpu": {"usage": 0.5}, "disk": {"used": "1G", "total": "4G"}}"#;
+        let reply = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        let tee = TeeHandler::new(TeeHandlerConfig {
+            endpoint: TeeEndpoint::Socket(fake_tappd(dir.path(), Some(reply))),
+            ..TeeHandlerConfig::default()
+        })
+        .await
+        .unwrap();
+        let m = tee.get_resource_metrics(None).await.unwrap();
+        assert_eq!(m, ResourceMetrics {
+            cpu_usage: Some(0.5),
+            disk_used_bytes: Some(1 << 30),
+            disk_total_bytes: Some(4 << 30),
+            ..ResourceMetrics::default()
+        });
+        assert_eq!(m.memory_used_bytes, None);
+        assert_eq!(m.memory_usage(), None);
+    }
+
+    #[tokio::test]
+    async fn workload_metrics_come_from_phala_cloud() {
+        let app = Router::new().route(
+            "/cvms/{id}/metrics",
+            get(|Path(id): Path<String>| async move {
+                match id.as_str() {
+                    "cvm-1" => (
+                        StatusCode::OK,
+                        json!({"memory": {"used": 256, "total": 1024, "unit": "MB"}}).to_string(),
+                    ),
+                    _ => (StatusCode::NOT_FOUND, String::new()),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let tee = TeeHandler::new(TeeHandlerConfig::default())
+            .await
+            .unwrap()
+            .with_cloud(
+                CloudConfig::default(),
+                Arc::new(HttpCloudApi::new(format!("http://{addr}"), None)),
+            );
+
+        let m = tee
+            .get_resource_metrics(Some(&WorkloadId("cvm-1".to_string())))
+            .await
+            .unwrap();
+        assert_eq!(m.memory_usage(), Some(0.25));
+        assert_eq!(m.cpu_usage, None);
+        assert!(matches!(
+            tee.get_resource_metrics(Some(&WorkloadId("cvm-0".to_string())))
+                .await,
+            Err(PhalaAvsError::NotFound(_))
+        ));
+    }
+}
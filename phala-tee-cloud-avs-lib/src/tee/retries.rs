@@ -0,0 +1,148 @@
+//! Retries of the calls [`TeeHandler`](super::TeeHandler) makes to tappd and Phala Cloud.
+//!
+//! Calls are retried under a [`RetryPolicy`], by default
+//! [`TeeHandlerConfig::retry_policy`](super::TeeHandlerConfig::retry_policy): exponential backoff
+//! with jitter, up to `TEE_RETRIES` more attempts. Each call says which of its failures are worth
+//! another attempt; a refused connection, a timeout or a 5xx reply are, a 4xx or malformed reply
+//! would only repeat. The policy's deadline is the budget of the whole call: each attempt is
+//! bounded by what is left of it and no retry starts past it, so calls from one cron tick cannot
+//! pile up behind the next.
+
+use crate::retry::{CancelToken, Interrupted, RetryPolicy, TokioClock, retry_with_decision};
+use std::fmt;
+use std::future::Future;
+use tracing::debug;
+
+/// Site under which TEE retries are counted in the attempt metric; the call itself is logged.
+pub const TEE_RETRY_SITE: &str = "TEE";
+
+/// Runs `attempt` through [`retry_with_decision`] under `policy` until it succeeds, fails with
+/// an error `retryable` rejects, the attempts or budget run out or `cancel` is cancelled,
+/// returning its last outcome. A cancelled loop or an attempt cut short by the budget fails with
+/// `interrupted`.
+pub(super) async fn retrying<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    cancel: &CancelToken,
+    call: &str,
+    retryable: impl Fn(&E) -> bool,
+    interrupted: impl Fn(Interrupted) -> E,
+    mut attempt: F,
+) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempts = 0;
+    let outcome = retry_with_decision(
+        &TokioClock,
+        policy,
+        TEE_RETRY_SITE,
+        Some(cancel),
+        retryable,
+        interrupted,
+        || {
+            attempts += 1;
+            attempt()
+        },
+    )
+    .await;
+    match &outcome {
+        Ok(_) => debug!("{call} succeeded after {attempts} attempt(s)"),
+        Err(e) => debug!("{call} failed after {attempts} attempt(s): {e}"),
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::Jitter;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn policy(max_attempts: u32, deadline: Option<Duration>) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(40),
+            jitter: Jitter::None,
+            deadline,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Runs an attempt failing with `errors` in turn, then succeeding, counting the attempts.
+    async fn run(
+        policy: &RetryPolicy,
+        cancel: &CancelToken,
+        errors: &[&'static str],
+    ) -> (Result<u32, String>, u32) {
+        let attempts = AtomicU32::new(0);
+        let counter = &attempts;
+        let outcome = retrying(
+            policy,
+            cancel,
+            "test call",
+            |e: &String| e != "fatal",
+            |interrupted| match interrupted {
+                Interrupted::Cancelled => "cancelled".to_string(),
+                Interrupted::TimedOut(budget) => format!("no reply within {budget:?}"),
+            },
+            || async move {
+                let attempt = counter.fetch_add(1, Ordering::SeqCst);
+                match errors.get(attempt as usize) {
+                    Some(&"hang") => {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        Ok(attempt)
+                    }
+                    Some(e) => Err(e.to_string()),
+                    None => Ok(attempt),
+                }
+            },
+        )
+        .await;
+        (outcome, attempts.into_inner())
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_until_success() {
+        let (outcome, attempts) = run(&policy(4, None), &CancelToken::default(), &[
+            "refused", "503",
+        ])
+        .await;
+        assert_eq!(outcome, Ok(2));
+        assert_eq!(attempts, 3);
+
+        let (outcome, attempts) = run(&policy(3, None), &CancelToken::default(), &["503"; 5]).await;
+        assert_eq!(outcome, Err("503".to_string()));
+        assert_eq!(attempts, 3);
+
+        let (outcome, attempts) =
+            run(&policy(3, None), &CancelToken::default(), &["fatal", "503"]).await;
+        assert_eq!(outcome, Err("fatal".to_string()));
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn attempts_share_the_deadline() {
+        let started = Instant::now();
+        let budget = Duration::from_millis(200);
+        let (outcome, attempts) = run(&policy(0, Some(budget)), &CancelToken::default(), &[
+            "503", "hang",
+        ])
+        .await;
+        assert_eq!(outcome, Err(format!("no reply within {budget:?}")));
+        assert_eq!(attempts, 2);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_token_stops_retrying() {
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let (outcome, attempts) = run(&policy(0, None), &cancel, &["503"; 5]).await;
+        assert_eq!(outcome, Err("cancelled".to_string()));
+        assert_eq!(attempts, 0);
+    }
+}
@@ -0,0 +1,464 @@
+//! Calls to the dstack guest agent (tappd).
+//!
+//! tappd serves its RPCs as plain HTTP/1.1, on a unix socket inside a CVM or at an HTTP(S) URL
+//! outside one; see [`super::config`]. Each socket call opens its own connection and asks the
+//! agent to close it, so calls from concurrent jobs share nothing and the reply is read to the
+//! end. HTTP(S) calls share one client, which is safe to use concurrently. With `TEE_RA_TLS` the
+//! client trusts the agent for the quote in its certificate instead; see [`super::ra_tls`].
+
+use super::config::{TeeEndpoint, TeeHandlerConfig};
+use super::ra_tls::RaTlsVerifier;
+use super::retries::retrying;
+use crate::error::PhalaAvsError;
+use crate::retry::{CancelToken, Interrupted};
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// An HTTP reply of tappd.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TappdReply {
+    pub status: u16,
+    pub body: String,
+}
+
+impl TappdReply {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Why a call to tappd got no reply.
+#[derive(Debug)]
+pub enum TappdError {
+    /// Nothing listens at the socket path.
+    SocketMissing(PathBuf),
+    /// The HTTP endpoint refused or could not take the connection.
+    Unreachable(String),
+    Io(std::io::Error),
+    /// The reply is not HTTP.
+    Malformed(String),
+}
+
+impl TappdError {
+    pub fn into_tee_error(self, endpoint: &TeeEndpoint) -> PhalaAvsError {
+        PhalaAvsError::TeeError(match self {
+            Self::SocketMissing(_) => format!("No tappd socket at {endpoint}"),
+            Self::Unreachable(e) => format!("tappd at {endpoint} is unreachable: {e}"),
+            Self::Io(e) => format!("tappd call failed: {e}"),
+            Self::Malformed(reply) => format!("tappd sent a malformed reply: {reply:?}"),
+        })
+    }
+
+    /// Whether another attempt may get through; a malformed reply would only repeat.
+    fn is_transient(&self) -> bool {
+        !matches!(self, Self::Malformed(_))
+    }
+}
+
+/// tappd at the configured endpoint.
+#[derive(Clone, Debug)]
+pub struct TappdClient {
+    config: TeeHandlerConfig,
+    transport: Transport,
+    /// Checks the agent's certificate under `TEE_RA_TLS`.
+    ra_tls: Option<Arc<RaTlsVerifier>>,
+    /// Stops the retries of calls in flight; see [`Self::with_cancel`].
+    cancel: CancelToken,
+}
+
+#[derive(Clone, Debug)]
+enum Transport {
+    Socket(PathBuf),
+    Http {
+        client: reqwest::Client,
+        base: reqwest::Url,
+    },
+}
+
+impl TappdClient {
+    /// Validates `config` and, for an HTTP(S) endpoint, builds its client with the TLS options.
+    pub fn new(config: TeeHandlerConfig) -> Result<Self, PhalaAvsError> {
+        config.validate()?;
+        let mut ra_tls = None;
+        let transport = match &config.endpoint {
+            TeeEndpoint::Socket(socket) => Transport::Socket(socket.clone()),
+            TeeEndpoint::Http(base) => {
+                let mut builder = reqwest::Client::builder()
+                    .danger_accept_invalid_certs(config.tls.accept_invalid_certs);
+                if config.ra_tls {
+                    let verifier = Arc::new(RaTlsVerifier::default());
+                    builder = builder.use_preconfigured_tls(verifier.client_config()?);
+                    ra_tls = Some(verifier);
+                } else if let Some(path) = &config.tls.ca_cert {
+                    let pem = std::fs::read(path).map_err(|e| {
+                        PhalaAvsError::TeeError(format!(
+                            "Failed to read TEE_TLS_CA_CERT {}: {e}",
+                            path.display()
+                        ))
+                    })?;
+                    let ca = reqwest::Certificate::from_pem(&pem).map_err(|e| {
+                        PhalaAvsError::TeeError(format!(
+                            "TEE_TLS_CA_CERT {} is not a PEM certificate: {e}",
+                            path.display()
+                        ))
+                    })?;
+                    builder = builder.add_root_certificate(ca);
+                }
+                let client = builder.build().map_err(|e| {
+                    PhalaAvsError::TeeError(format!("Failed to set up the TEE client: {e}"))
+                })?;
+                Transport::Http {
+                    client,
+                    base: base.clone(),
+                }
+            }
+        };
+        Ok(Self {
+            config,
+            transport,
+            ra_tls,
+            cancel: CancelToken::default(),
+        })
+    }
+
+    /// Gives up retrying calls once `cancel` is cancelled, usually on shutdown.
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn config(&self) -> &TeeHandlerConfig {
+        &self.config
+    }
+
+    /// The verifier of the agent's RA-TLS certificate, under `TEE_RA_TLS`.
+    pub fn ra_tls(&self) -> Option<&Arc<RaTlsVerifier>> {
+        self.ra_tls.as_ref()
+    }
+
+    /// Sends `method path` once, with a JSON `body` if given and no time limit.
+    pub async fn call(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&str>,
+    ) -> Result<TappdReply, TappdError> {
+        match &self.transport {
+            Transport::Socket(socket) => call_socket(socket, method, path, body).await,
+            Transport::Http { client, base } => call_http(client, base, method, path, body).await,
+        }
+    }
+
+    /// [`Self::call`] under [`TeeHandlerConfig::retry_policy`], retrying calls that did not
+    /// reach the agent, timed out or got a 5xx reply. Replies are returned whatever their status,
+    /// the last 5xx included once retries run out.
+    pub async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&str>,
+    ) -> Result<TappdReply, PhalaAvsError> {
+        let endpoint = &self.config.endpoint;
+        let outcome = retrying(
+            &self.config.retry_policy(),
+            &self.cancel,
+            &format!("{method} {path} to tappd"),
+            Failure::is_retryable,
+            Failure::Interrupted,
+            || async move {
+                match self.call(method, path, body).await {
+                    Ok(reply) if reply.status >= 500 => Err(Failure::Status(reply)),
+                    Ok(reply) => Ok(reply),
+                    Err(e) => Err(Failure::Call(e)),
+                }
+            },
+        )
+        .await;
+        match outcome {
+            Ok(reply) | Err(Failure::Status(reply)) => Ok(reply),
+            Err(Failure::Call(e)) => Err(e.into_tee_error(endpoint)),
+            Err(Failure::Interrupted(Interrupted::TimedOut(budget))) => {
+                Err(PhalaAvsError::TeeError(format!(
+                    "tappd at {endpoint} did not answer within {budget:?}"
+                )))
+            }
+            Err(Failure::Interrupted(Interrupted::Cancelled)) => Err(PhalaAvsError::TeeError(
+                format!("Calls to tappd at {endpoint} were cancelled"),
+            )),
+        }
+    }
+}
+
+/// A failed attempt at a request.
+enum Failure {
+    Call(TappdError),
+    Status(TappdReply),
+    Interrupted(Interrupted),
+}
+
+impl Failure {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::Call(e) => e.is_transient(),
+            Self::Status(_) => true,
+            Self::Interrupted(interrupted) => matches!(interrupted, Interrupted::TimedOut(_)),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Call(e) => write!(f, "{e:?}"),
+            Self::Status(reply) => write!(f, "status {}", reply.status),
+            Self::Interrupted(Interrupted::TimedOut(budget)) => {
+                write!(f, "no answer within {budget:?}")
+            }
+            Self::Interrupted(Interrupted::Cancelled) => write!(f, "cancelled"),
+        }
+    }
+}
+
+async fn call_http(
+    client: &reqwest::Client,
+    base: &reqwest::Url,
+    method: &str,
+    path: &str,
+    body: Option<&str>,
+) -> Result<TappdReply, TappdError> {
+    let url = base
+        .join(path)
+        .map_err(|e| TappdError::Malformed(format!("{path}: {e}")))?;
+    let method = reqwest::Method::from_bytes(method.as_bytes())
+        .map_err(|e| TappdError::Malformed(format!("{method}: {e}")))?;
+    let mut request = client.request(method, url);
+    if let Some(body) = body {
+        request = request
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+    }
+    let response = request.send().await.map_err(|e| {
+        if e.is_connect() {
+            TappdError::Unreachable(e.to_string())
+        } else {
+            TappdError::Io(std::io::Error::other(e))
+        }
+    })?;
+    let status = response.status().as_u16();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| TappdError::Io(std::io::Error::other(e)))?;
+    Ok(TappdReply { status, body })
+}
+
+/// Sends `method path` to tappd at `socket`, with a JSON `body` if given.
+async fn call_socket(
+    socket: &Path,
+    method: &str,
+    path: &str,
+    body: Option<&str>,
+) -> Result<TappdReply, TappdError> {
+    let mut stream = match UnixStream::connect(socket).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Err(TappdError::SocketMissing(socket.to_path_buf()));
+        }
+        Err(e) => return Err(TappdError::Io(e)),
+    };
+    let mut request =
+        format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n");
+    if let Some(body) = body {
+        request.push_str(&format!(
+            "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ));
+    } else {
+        request.push_str("\r\n");
+    }
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(TappdError::Io)?;
+    let mut reply = Vec::new();
+    stream
+        .read_to_end(&mut reply)
+        .await
+        .map_err(TappdError::Io)?;
+    parse_reply(&String::from_utf8_lossy(&reply))
+}
+
+fn parse_reply(reply: &str) -> Result<TappdReply, TappdError> {
+    let malformed = || TappdError::Malformed(reply.chars().take(200).collect());
+    let (head, body) = reply.split_once("\r\n\r\n").unwrap_or((reply, ""));
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(malformed)?;
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    let body = if chunked {
+        dechunk(body).ok_or_else(malformed)?
+    } else {
+        body.to_string()
+    };
+    Ok(TappdReply { status, body })
+}
+
+/// The payload of a chunked body.
+fn dechunk(mut body: &str) -> Option<String> {
+    let mut out = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n")?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(out);
+        }
+        out.push_str(rest.get(..size)?);
+        body = rest.get(size..)?.strip_prefix("\r\n")?;
+    }
+}
+
+/// A tappd stand-in answering every request with `reply`, or never answering without one.
+#[cfg(test)]
+pub(super) fn fake_tappd(dir: &Path, reply: Option<String>) -> PathBuf {
+    let socket = dir.join("tappd.sock");
+    let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).await.unwrap();
+            match &reply {
+                Some(reply) => stream.write_all(reply.as_bytes()).await.unwrap(),
+                // Hold the connection open without answering.
+                None => tokio::time::sleep(std::time::Duration::from_secs(60)).await,
+            }
+        }
+    });
+    socket
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use blueprint_sdk::testing::tempfile::TempDir;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use std::time::Instant;
+
+    /// A tappd over HTTP failing the first `failures` calls with `status`, counting calls.
+    async fn flaky_tappd(failures: u32, status: StatusCode) -> (TeeEndpoint, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let app = Router::new()
+            .route(
+                "/prpc/Tappd.TdxQuote",
+                post(move |State(calls): State<Arc<AtomicU32>>| async move {
+                    if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                        (status, "busy")
+                    } else {
+                        (StatusCode::OK, "{}")
+                    }
+                }),
+            )
+            .with_state(calls.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let endpoint = TeeEndpoint::parse(&format!("http://{addr}")).unwrap();
+        (endpoint, calls)
+    }
+
+    fn client(endpoint: TeeEndpoint, retries: u32) -> TappdClient {
+        TappdClient::new(TeeHandlerConfig {
+            endpoint,
+            retries,
+            ..TeeHandlerConfig::default()
+        })
+        .unwrap()
+    }
+
+    async fn quote(client: &TappdClient) -> Result<TappdReply, PhalaAvsError> {
+        client
+            .request("POST", "/prpc/Tappd.TdxQuote", Some("{}"))
+            .await
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_until_a_reply_succeeds() {
+        let (endpoint, calls) = flaky_tappd(2, StatusCode::SERVICE_UNAVAILABLE).await;
+        let reply = quote(&client(endpoint, 3)).await.unwrap();
+        assert!(reply.is_success());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_run_out_on_an_agent_that_always_fails() {
+        // The last 5xx is returned for the caller to report.
+        let (endpoint, calls) = flaky_tappd(u32::MAX, StatusCode::SERVICE_UNAVAILABLE).await;
+        let reply = quote(&client(endpoint, 2)).await.unwrap();
+        assert_eq!(reply.status, 503);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // A 4xx would only repeat.
+        let (endpoint, calls) = flaky_tappd(u32::MAX, StatusCode::BAD_REQUEST).await;
+        let reply = quote(&client(endpoint, 2)).await.unwrap();
+        assert_eq!(reply.status, 400);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let unreachable = TeeEndpoint::parse("http://127.0.0.1:1").unwrap();
+        let err = quote(&client(unreachable, 2)).await.unwrap_err();
+        assert!(err.to_string().contains("unreachable"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn retries_stay_within_the_request_timeout() {
+        let dir = TempDir::new().unwrap();
+        let socket = fake_tappd(dir.path(), None);
+        let client = TappdClient::new(TeeHandlerConfig {
+            endpoint: TeeEndpoint::Socket(socket),
+            request_timeout: Duration::from_secs(1),
+            retries: 5,
+            ..TeeHandlerConfig::default()
+        })
+        .unwrap();
+        let started = Instant::now();
+        let err = quote(&client).await.unwrap_err();
+        assert!(
+            err.to_string().contains("did not answer within 1s"),
+            "{err}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn chunked_replies_are_reassembled() {
+        let reply = parse_reply(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{\"a\"\r\n3\r\n:1}\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(reply, TappdReply {
+            status: 200,
+            body: "{\"a\":1}".to_string()
+        });
+        assert!(matches!(
+            parse_reply("garbage"),
+            Err(TappdError::Malformed(_))
+        ));
+    }
+}
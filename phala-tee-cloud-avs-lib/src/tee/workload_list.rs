@@ -0,0 +1,288 @@
+//! Listing of the workloads deployed to Phala Cloud.
+//!
+//! [`TeeHandler::list_workloads`] pages through `GET <url>/cvms`, following `next_cursor` until
+//! the API has no more, and keeps the CVMs matching a [`WorkloadFilter`]. The filter is sent
+//! along for the API to narrow pages with, and checked again on each CVM. Listing stops at
+//! `PHALA_CLOUD_LIST_LIMIT` workloads with [`WorkloadList::truncated`] set, so an account with
+//! very many CVMs, or an API that never stops paging, cannot exhaust memory.
+
+use super::TeeHandler;
+use super::deploy::{DeployFailure, WorkloadId};
+use super::workload_status::{CvmInfo, WorkloadStatus};
+use crate::error::PhalaAvsError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Which workloads to list. The empty filter matches every workload.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkloadFilter {
+    /// [`WorkloadStatus::state`]s to keep; any when empty.
+    pub states: Vec<String>,
+    /// Labels a workload must carry, e.g. the operator it was deployed for.
+    pub labels: BTreeMap<String, String>,
+}
+
+impl WorkloadFilter {
+    fn matches(&self, cvm: &ListedCvm, status: &WorkloadStatus) -> bool {
+        (self.states.is_empty()
+            || self
+                .states
+                .iter()
+                .any(|state| state.eq_ignore_ascii_case(status.state())))
+            && self
+                .labels
+                .iter()
+                .all(|(key, value)| cvm.labels.get(key) == Some(value))
+    }
+
+    /// The filter as query parameters: `status=<state>` and `label=<key>=<value>`, repeated.
+    pub(super) fn query(&self) -> Vec<(&'static str, String)> {
+        let states = self.states.iter().map(|state| ("status", state.clone()));
+        let labels = self
+            .labels
+            .iter()
+            .map(|(key, value)| ("label", format!("{key}={value}")));
+        states.chain(labels).collect()
+    }
+}
+
+/// A workload, as listed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkloadSummary {
+    pub id: WorkloadId,
+    pub name: String,
+    pub status: WorkloadStatus,
+    /// The node the CVM runs on, once placed.
+    pub node: Option<String>,
+    /// When the CVM was created, in unix seconds.
+    pub created_at: Option<u64>,
+}
+
+/// The workloads matching a filter, in the order the API lists them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkloadList {
+    pub workloads: Vec<WorkloadSummary>,
+    /// Whether more workloads matched than the list limit let through.
+    pub truncated: bool,
+}
+
+/// A CVM in a page of `GET <url>/cvms`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct ListedCvm {
+    pub id: WorkloadId,
+    pub name: String,
+    #[serde(flatten)]
+    pub cvm: CvmInfo,
+    #[serde(default)]
+    pub node: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A page of `GET <url>/cvms`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct CvmPage {
+    #[serde(default)]
+    pub items: Vec<ListedCvm>,
+    /// Where the next page starts; the last page has none.
+    #[serde(default)]
+    pub next_cursor: Option<String>,
+}
+
+impl TeeHandler {
+    /// The workloads deployed to Phala Cloud that match `filter`, at most `PHALA_CLOUD_LIST_LIMIT`
+    /// of them.
+    pub async fn list_workloads(
+        &self,
+        filter: WorkloadFilter,
+    ) -> Result<WorkloadList, PhalaAvsError> {
+        self.inject_faults().await?;
+        let cloud = self.cloud_deployer()?;
+        let limit = cloud.config().list_limit;
+        let failed = |e: DeployFailure| {
+            PhalaAvsError::TeeError(format!("Failed to list Phala Cloud workloads: {e}"))
+        };
+        let mut list = WorkloadList::default();
+        let mut cursor = None;
+        loop {
+            let page = cloud
+                .api()
+                .list(&filter, cursor.as_deref())
+                .await
+                .map_err(failed)?;
+            for cvm in page.items {
+                let status = WorkloadStatus::from(&cvm.cvm);
+                if !filter.matches(&cvm, &status) {
+                    continue;
+                }
+                if list.workloads.len() == limit {
+                    list.truncated = true;
+                    return Ok(list);
+                }
+                list.workloads.push(WorkloadSummary {
+                    id: cvm.id,
+                    name: cvm.name,
+                    status,
+                    node: cvm.node,
+                    created_at: cvm.created_at,
+                });
+            }
+            match page.next_cursor {
+                // Full, with pages left that may or may not match: paging on to find out could
+                // take for ever, so the list is reported truncated.
+                Some(_) if list.workloads.len() == limit => {
+                    list.truncated = true;
+                    return Ok(list);
+                }
+                Some(next) => cursor = Some(next),
+                None => return Ok(list),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandlerConfig;
+    use crate::tee::deploy::{CloudConfig, HttpCloudApi};
+    use axum::extract::{Query, State};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    type Queries = Arc<Mutex<Vec<Vec<(String, String)>>>>;
+
+    fn cvm(id: &str, status: &str, operator: &str) -> Value {
+        json!({
+            "id": id,
+            "name": format!("probe-{id}"),
+            "status": status,
+            "uptime_secs": 60,
+            "node": "prod-7",
+            "created_at": 1726358400,
+            "labels": {"operator": operator},
+        })
+    }
+
+    /// Three pages of CVMs, chained by cursors.
+    fn paged(query: &[(String, String)]) -> Value {
+        let cursor = query.iter().find(|(key, _)| key == "cursor");
+        match cursor.map(|(_, cursor)| cursor.as_str()) {
+            None => json!({
+                "items": [cvm("cvm-1", "running", "0xa"), cvm("cvm-2", "stopped", "0xa")],
+                "next_cursor": "p2",
+            }),
+            Some("p2") => json!({
+                "items": [cvm("cvm-3", "running", "0xb")],
+                "next_cursor": "p3",
+            }),
+            _ => json!({
+                "items": [cvm("cvm-4", "starting", "0xa"), cvm("cvm-5", "running", "0xa")],
+            }),
+        }
+    }
+
+    /// The same page, pointing at itself.
+    fn looping(_: &[(String, String)]) -> Value {
+        json!({
+            "items": [cvm("cvm-1", "running", "0xa"), cvm("cvm-2", "running", "0xa")],
+            "next_cursor": "again",
+        })
+    }
+
+    async fn handler(
+        pages: fn(&[(String, String)]) -> Value,
+        list_limit: usize,
+    ) -> (TeeHandler, Queries) {
+        let queries = Queries::default();
+        let app = Router::new()
+            .route(
+                "/cvms",
+                get(
+                    move |State(queries): State<Queries>,
+                          Query(query): Query<Vec<(String, String)>>| async move {
+                        queries.lock().unwrap().push(query.clone());
+                        Json(pages(&query))
+                    },
+                ),
+            )
+            .with_state(queries.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let config = CloudConfig {
+            list_limit,
+            ..CloudConfig::default()
+        };
+        let tee = TeeHandler::new(TeeHandlerConfig::default())
+            .await
+            .unwrap()
+            .with_cloud(
+                config,
+                Arc::new(HttpCloudApi::new(format!("http://{addr}"), None)),
+            );
+        (tee, queries)
+    }
+
+    fn ids(list: &WorkloadList) -> Vec<&str> {
+        list.workloads.iter().map(|w| w.id.0.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn pages_are_followed_to_the_end() {
+        let (tee, queries) = handler(paged, 5).await;
+        let list = tee.list_workloads(WorkloadFilter::default()).await.unwrap();
+        assert_eq!(ids(&list), ["cvm-1", "cvm-2", "cvm-3", "cvm-4", "cvm-5"]);
+        // Exactly at the limit, but nothing was left out.
+        assert!(!list.truncated);
+        assert_eq!(list.workloads[0], WorkloadSummary {
+            id: WorkloadId("cvm-1".to_string()),
+            name: "probe-cvm-1".to_string(),
+            status: WorkloadStatus::Running {
+                uptime_secs: Some(60),
+                last_attestation_at: None,
+            },
+            node: Some("prod-7".to_string()),
+            created_at: Some(1_726_358_400),
+        });
+        let cursors: Vec<_> = queries.lock().unwrap().clone();
+        assert_eq!(cursors, [
+            vec![],
+            vec![("cursor".to_string(), "p2".to_string())],
+            vec![("cursor".to_string(), "p3".to_string())],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn filters_are_sent_and_applied() {
+        let (tee, queries) = handler(paged, 100).await;
+        let filter = WorkloadFilter {
+            states: vec!["running".to_string()],
+            labels: BTreeMap::from([("operator".to_string(), "0xa".to_string())]),
+        };
+        let list = tee.list_workloads(filter).await.unwrap();
+        assert_eq!(ids(&list), ["cvm-1", "cvm-5"]);
+        assert_eq!(queries.lock().unwrap()[0], [
+            ("status".to_string(), "running".to_string()),
+            ("label".to_string(), "operator=0xa".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn endless_paging_stops_at_the_limit() {
+        let (tee, queries) = handler(looping, 5).await;
+        let list = tee.list_workloads(WorkloadFilter::default()).await.unwrap();
+        assert_eq!(list.workloads.len(), 5);
+        assert!(list.truncated);
+        assert_eq!(queries.lock().unwrap().len(), 3);
+
+        let (tee, _) = handler(paged, 2).await;
+        let list = tee.list_workloads(WorkloadFilter::default()).await.unwrap();
+        assert_eq!(ids(&list), ["cvm-1", "cvm-2"]);
+        assert!(list.truncated);
+    }
+}
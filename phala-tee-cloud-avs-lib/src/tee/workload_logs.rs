@@ -0,0 +1,345 @@
+//! Logs of workloads deployed to Phala Cloud, attached as evidence to SLA challenges.
+//!
+//! `GET <url>/cvms/<id>/logs?since_secs=<n>&follow=<bool>` answers with one JSON [`LogLine`] per
+//! line, chunked however the API likes. [`TeeHandler::fetch_workload_logs`] reads the lines
+//! written since then and keeps the most recent that fit in its byte budget;
+//! [`TeeHandler::follow_workload_logs`] also waits for new lines as they are written. A line
+//! longer than [`MAX_LOG_LINE_BYTES`] or that does not parse is dropped. The API closing the
+//! connection mid-read ends the logs after the last complete line rather than failing.
+
+use super::TeeHandler;
+use super::deploy::{DeployFailure, WorkloadId};
+use crate::error::PhalaAvsError;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tracing::warn;
+
+/// Longest line kept; a workload printing more in one line is not logging for a human.
+pub const MAX_LOG_LINE_BYTES: usize = 64 * 1024;
+
+/// The body of a log request, as it arrives.
+pub type LogChunks = BoxStream<'static, Result<Vec<u8>, DeployFailure>>;
+
+/// A line a workload's container wrote.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLine {
+    /// When the line was written, as the API reports it (RFC 3339).
+    pub timestamp: String,
+    pub container: String,
+    pub message: String,
+}
+
+impl LogLine {
+    /// Bytes the line counts for against a budget.
+    pub fn size(&self) -> usize {
+        self.timestamp.len() + self.container.len() + self.message.len()
+    }
+}
+
+/// Splits chunks into complete lines and parses them.
+#[derive(Default)]
+struct LineDecoder {
+    partial: Vec<u8>,
+    /// Whether the line being read is already too long to keep.
+    overlong: bool,
+}
+
+impl LineDecoder {
+    fn push(&mut self, chunk: &[u8]) -> Vec<LogLine> {
+        let mut lines = Vec::new();
+        for piece in chunk.split_inclusive(|byte| *byte == b'\n') {
+            if !self.overlong {
+                self.partial.extend_from_slice(piece);
+                if self.partial.len() > MAX_LOG_LINE_BYTES {
+                    warn!("Dropping a workload log line longer than {MAX_LOG_LINE_BYTES} bytes");
+                    self.partial.clear();
+                    self.overlong = true;
+                }
+            }
+            if piece.ends_with(b"\n") {
+                lines.extend(self.take());
+            }
+        }
+        lines
+    }
+
+    /// The line ended by the end of the body rather than a newline.
+    fn finish(&mut self) -> Option<LogLine> {
+        self.take()
+    }
+
+    fn take(&mut self) -> Option<LogLine> {
+        let line = std::mem::take(&mut self.partial);
+        if std::mem::take(&mut self.overlong) || line.trim_ascii().is_empty() {
+            return None;
+        }
+        serde_json::from_slice(&line)
+            .inspect_err(|e| warn!("Dropping a workload log line that does not parse: {e}"))
+            .ok()
+    }
+}
+
+/// The lines of `chunks`, ending at the first error.
+fn log_lines(id: WorkloadId, chunks: LogChunks) -> impl Stream<Item = LogLine> + Send + 'static {
+    struct Reader {
+        id: WorkloadId,
+        chunks: Option<LogChunks>,
+        decoder: LineDecoder,
+        ready: VecDeque<LogLine>,
+    }
+    let reader = Reader {
+        id,
+        chunks: Some(chunks),
+        decoder: LineDecoder::default(),
+        ready: VecDeque::new(),
+    };
+    stream::unfold(reader, |mut reader| async move {
+        loop {
+            if let Some(line) = reader.ready.pop_front() {
+                return Some((line, reader));
+            }
+            let next = match reader.chunks.as_mut() {
+                Some(chunks) => chunks.next().await,
+                None => return None,
+            };
+            match next {
+                Some(Ok(chunk)) => reader.ready.extend(reader.decoder.push(&chunk)),
+                Some(Err(e)) => {
+                    warn!("Logs of workload {} ended early: {e}", reader.id);
+                    reader.chunks = None;
+                }
+                None => {
+                    reader.ready.extend(reader.decoder.finish());
+                    reader.chunks = None;
+                }
+            }
+        }
+    })
+}
+
+impl TeeHandler {
+    /// The most recent lines the workload deployed as `id` logged within `since`, at most
+    /// `max_bytes` of them by [`LogLine::size`], oldest first.
+    pub async fn fetch_workload_logs(
+        &self,
+        id: &WorkloadId,
+        since: Duration,
+        max_bytes: usize,
+    ) -> Result<Vec<LogLine>, PhalaAvsError> {
+        let mut lines = std::pin::pin!(self.workload_logs(id, since, false).await?);
+        let mut kept = VecDeque::new();
+        let mut bytes = 0;
+        while let Some(line) = lines.next().await {
+            bytes += line.size();
+            kept.push_back(line);
+            while bytes > max_bytes {
+                let Some(oldest) = kept.pop_front() else {
+                    break;
+                };
+                bytes -= oldest.size();
+            }
+        }
+        Ok(kept.into())
+    }
+
+    /// The lines the workload deployed as `id` logged within `since`, then each line it logs
+    /// from now on, until the API closes the connection.
+    pub async fn follow_workload_logs(
+        &self,
+        id: &WorkloadId,
+        since: Duration,
+    ) -> Result<impl Stream<Item = LogLine> + Send + 'static, PhalaAvsError> {
+        self.workload_logs(id, since, true).await
+    }
+
+    async fn workload_logs(
+        &self,
+        id: &WorkloadId,
+        since: Duration,
+        follow: bool,
+    ) -> Result<impl Stream<Item = LogLine> + Send + 'static, PhalaAvsError> {
+        self.inject_faults().await?;
+        match self.cloud_deployer()?.api().logs(id, since, follow).await {
+            Ok(chunks) => Ok(log_lines(id.clone(), chunks)),
+            Err(DeployFailure::NotFound(id)) => Err(PhalaAvsError::NotFound(format!(
+                "Phala Cloud has no workload {id}"
+            ))),
+            Err(e) => Err(PhalaAvsError::TeeError(format!(
+                "Failed to read the logs of workload {id}: {e}"
+            ))),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandlerConfig;
+    use crate::tee::deploy::{CloudConfig, HttpCloudApi};
+    use axum::Router;
+    use axum::body::{Body, Bytes};
+    use axum::extract::{Path, Query, State};
+    use axum::http::StatusCode;
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::get;
+    use std::sync::{Arc, Mutex};
+
+    type Queries = Arc<Mutex<Vec<Vec<(String, String)>>>>;
+
+    fn line(second: u32, container: &str, message: &str) -> String {
+        let line = LogLine {
+            timestamp: format!("2024-09-15T00:00:{second:02}Z"),
+            container: container.to_string(),
+            message: message.to_string(),
+        };
+        serde_json::to_string(&line).unwrap()
+    }
+
+    /// Four lines split at awkward places, with a blank line, a line that is not JSON and no
+    /// newline at the end.
+    fn chunks() -> Vec<String> {
+        let body = [
+            line(1, "probe", "starting"),
+            String::new(),
+            line(2, "probe", "listening on :8080"),
+            "not json".to_string(),
+            line(3, "sidecar", "tick"),
+            line(4, "probe", "served 12 requests"),
+        ]
+        .join("\n");
+        let (first, rest) = body.split_at(20);
+        let (second, third) = rest.split_at(70);
+        vec![first.to_string(), second.to_string(), third.to_string()]
+    }
+
+    async fn logs(
+        State(queries): State<Queries>,
+        Path(id): Path<String>,
+        Query(query): Query<Vec<(String, String)>>,
+    ) -> Response {
+        queries.lock().unwrap().push(query);
+        match id.as_str() {
+            "cvm-1" => {
+                let chunks = chunks()
+                    .into_iter()
+                    .map(|chunk| Ok::<_, std::io::Error>(Bytes::from(chunk)));
+                Body::from_stream(stream::iter(chunks)).into_response()
+            }
+            // The first line, half the second, then the connection drops.
+            "cvm-cut" => {
+                let cut: Vec<Result<Bytes, std::io::Error>> = vec![
+                    Ok(Bytes::from(line(1, "probe", "starting") + "\n")),
+                    Ok(Bytes::from(line(2, "probe", "listening")[..20].to_string())),
+                    Err(std::io::Error::other("connection reset")),
+                ];
+                Body::from_stream(stream::iter(cut)).into_response()
+            }
+            _ => (StatusCode::NOT_FOUND, "no such CVM").into_response(),
+        }
+    }
+
+    async fn handler() -> (TeeHandler, Queries) {
+        let queries = Queries::default();
+        let app = Router::new()
+            .route("/cvms/{id}/logs", get(logs))
+            .with_state(queries.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let tee = TeeHandler::new(TeeHandlerConfig::default())
+            .await
+            .unwrap()
+            .with_cloud(
+                CloudConfig::default(),
+                Arc::new(HttpCloudApi::new(format!("http://{addr}"), None)),
+            );
+        (tee, queries)
+    }
+
+    fn messages(lines: &[LogLine]) -> Vec<&str> {
+        lines.iter().map(|line| line.message.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn chunked_logs_are_reassembled_into_lines() {
+        let (tee, queries) = handler().await;
+        let id = WorkloadId("cvm-1".to_string());
+        let lines = tee
+            .fetch_workload_logs(&id, Duration::from_secs(300), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(messages(&lines), [
+            "starting",
+            "listening on :8080",
+            "tick",
+            "served 12 requests"
+        ]);
+        assert_eq!(lines[2], LogLine {
+            timestamp: "2024-09-15T00:00:03Z".to_string(),
+            container: "sidecar".to_string(),
+            message: "tick".to_string(),
+        });
+        assert_eq!(queries.lock().unwrap()[0], [
+            ("since_secs".to_string(), "300".to_string()),
+            ("follow".to_string(), "false".to_string()),
+        ]);
+
+        // The most recent lines that fit.
+        let budget = lines[2].size() + lines[3].size();
+        let recent = tee
+            .fetch_workload_logs(&id, Duration::from_secs(300), budget)
+            .await
+            .unwrap();
+        assert_eq!(messages(&recent), ["tick", "served 12 requests"]);
+
+        let followed: Vec<_> = tee
+            .follow_workload_logs(&id, Duration::from_secs(60))
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(followed, lines);
+        assert_eq!(
+            queries.lock().unwrap()[2][1],
+            ("follow".to_string(), "true".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn a_dropped_connection_ends_the_logs() {
+        let (tee, _) = handler().await;
+        let id = WorkloadId("cvm-cut".to_string());
+        let lines = tee
+            .fetch_workload_logs(&id, Duration::from_secs(300), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(messages(&lines), ["starting"]);
+        let followed: Vec<_> = tee
+            .follow_workload_logs(&id, Duration::from_secs(300))
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(followed, lines);
+
+        let missing = WorkloadId("cvm-0".to_string());
+        assert!(matches!(
+            tee.fetch_workload_logs(&missing, Duration::from_secs(300), 1024)
+                .await,
+            Err(PhalaAvsError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn overlong_lines_are_dropped() {
+        let mut decoder = LineDecoder::default();
+        let long = line(1, "probe", &"x".repeat(MAX_LOG_LINE_BYTES));
+        let mut lines = decoder.push(long[..100].as_bytes());
+        lines.extend(decoder.push(format!("{}\n", &long[100..]).as_bytes()));
+        lines.extend(decoder.push(format!("{}\n", line(2, "probe", "short")).as_bytes()));
+        assert_eq!(messages(&lines), ["short"]);
+        assert!(decoder.partial.is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+//! State of workloads deployed to Phala Cloud.
+//!
+//! Jobs check that a workload is still healthy before signing SLA attestations for it.
+//! [`TeeHandler::get_workload_status`] reads the CVM from the Phala Cloud API and maps it to a
+//! [`WorkloadStatus`]. The raw `status` is compared trimmed and case-insensitively:
+//!
+//! - `pending`, `creating`, `provisioning`, `starting`, `booting` and `restarting` are
+//!   `Provisioning`;
+//! - `running` is `Running`, unless `healthy` is `false`, which makes it `Degraded`;
+//! - `degraded` and `unhealthy` are `Degraded`;
+//! - `stopping`, `stopped`, `exited`, `failed`, `error`, `terminated` and `deleted` are
+//!   `Stopped`, with the API's `exit_reason`;
+//! - anything else is `Unknown`, with the raw status.
+//!
+//! A workload id the API does not know fails with [`PhalaAvsError::NotFound`], never `Unknown`;
+//! a reply that does not parse fails with a TEE error.
+
+use super::TeeHandler;
+use super::deploy::{DeployFailure, WorkloadId};
+use crate::error::PhalaAvsError;
+use serde::{Deserialize, Serialize};
+
+/// A CVM as the Phala Cloud API reports it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CvmInfo {
+    pub status: String,
+    #[serde(default)]
+    pub uptime_secs: Option<u64>,
+    /// When the CVM last produced an attestation, in unix seconds.
+    #[serde(default)]
+    pub last_attestation_at: Option<u64>,
+    /// Why the CVM stopped, once it has.
+    #[serde(default)]
+    pub exit_reason: Option<String>,
+    /// Whether the workload's health checks pass, when it has any.
+    #[serde(default)]
+    pub healthy: Option<bool>,
+}
+
+/// Where a deployed workload stands.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum WorkloadStatus {
+    /// Being created or booted.
+    Provisioning {
+        status: String,
+    },
+    Running {
+        uptime_secs: Option<u64>,
+        last_attestation_at: Option<u64>,
+    },
+    /// Up, but failing its health checks.
+    Degraded {
+        uptime_secs: Option<u64>,
+        last_attestation_at: Option<u64>,
+    },
+    Stopped {
+        exit_reason: Option<String>,
+    },
+    /// A raw status with no mapping.
+    Unknown {
+        status: String,
+    },
+}
+
+impl WorkloadStatus {
+    /// Whether SLA attestations may be signed for the workload.
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, Self::Running { .. })
+    }
+
+    /// The `state` tag of the status, e.g. `running`.
+    pub fn state(&self) -> &'static str {
+        match self {
+            Self::Provisioning { .. } => "provisioning",
+            Self::Running { .. } => "running",
+            Self::Degraded { .. } => "degraded",
+            Self::Stopped { .. } => "stopped",
+            Self::Unknown { .. } => "unknown",
+        }
+    }
+}
+
+impl From<&CvmInfo> for WorkloadStatus {
+    fn from(cvm: &CvmInfo) -> Self {
+        let uptime_secs = cvm.uptime_secs;
+        let last_attestation_at = cvm.last_attestation_at;
+        match cvm.status.trim().to_ascii_lowercase().as_str() {
+            "pending" | "creating" | "provisioning" | "starting" | "booting" | "restarting" => {
+                Self::Provisioning {
+                    status: cvm.status.clone(),
+                }
+            }
+            "running" if cvm.healthy != Some(false) => Self::Running {
+                uptime_secs,
+                last_attestation_at,
+            },
+            "running" | "degraded" | "unhealthy" => Self::Degraded {
+                uptime_secs,
+                last_attestation_at,
+            },
+            "stopping" | "stopped" | "exited" | "failed" | "error" | "terminated" | "deleted" => {
+                Self::Stopped {
+                    exit_reason: cvm.exit_reason.clone(),
+                }
+            }
+            _ => Self::Unknown {
+                status: cvm.status.clone(),
+            },
+        }
+    }
+}
+
+impl TeeHandler {
+    /// The state of the workload deployed as `id`.
+    pub async fn get_workload_status(
+        &self,
+        id: &WorkloadId,
+    ) -> Result<WorkloadStatus, PhalaAvsError> {
+        self.inject_faults().await?;
+        match self.cloud_deployer()?.api().cvm(id).await {
+            Ok(cvm) => Ok(WorkloadStatus::from(&cvm)),
+            Err(DeployFailure::NotFound(id)) => Err(PhalaAvsError::NotFound(format!(
+                "Phala Cloud has no workload {id}"
+            ))),
+            Err(e) => Err(PhalaAvsError::TeeError(format!(
+                "Failed to read the status of workload {id}: {e}"
+            ))),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod tests {
+    use super::*;
+    use crate::tee::TeeHandlerConfig;
+    use crate::tee::deploy::{CloudConfig, HttpCloudApi};
+    use axum::Router;
+    use axum::extract::Path;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use std::sync::Arc;
+
+    /// Canned `GET /cvms/<id>` replies, by id.
+    fn canned(id: &str) -> (StatusCode, &'static str) {
+        let reply = match id {
+            "creating" => r#"{"status": "creating"}"#,
+            "running" => {
+                r#"{"status": "Running", "uptime_secs": 3600, "last_attestation_at": 1726358400}"#
+            }
+            "unhealthy" => r#"{"status": "running", "uptime_secs": 60, "healthy": false}"#,
+            "degraded" => r#"{"status": "degraded", "last_attestation_at": 1726358400}"#,
+            "exited" => r#"{"status": "exited", "exit_reason": "OOM killed"}"#,
+            "migrating" => r#"{"status": "migrating"}"#,
+            "malformed" => r#"{"state": "running"}"#,
+            _ => return (StatusCode::NOT_FOUND, r#"{"error": "cvm not found"}"#),
+        };
+        (StatusCode::OK, reply)
+    }
+
+    async fn handler() -> TeeHandler {
+        let app = Router::new().route(
+            "/cvms/{id}",
+            get(|Path(id): Path<String>| async move {
+                let (status, body) = canned(&id);
+                (status, [("content-type", "application/json")], body)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        TeeHandler::new(TeeHandlerConfig::default())
+            .await
+            .unwrap()
+            .with_cloud(
+                CloudConfig::default(),
+                Arc::new(HttpCloudApi::new(format!("http://{addr}"), None)),
+            )
+    }
+
+    async fn status(tee: &TeeHandler, id: &str) -> Result<WorkloadStatus, PhalaAvsError> {
+        tee.get_workload_status(&WorkloadId(id.to_string())).await
+    }
+
+    #[tokio::test]
+    async fn raw_states_map_to_workload_states() {
+        let tee = handler().await;
+        let cases = [
+            ("creating", WorkloadStatus::Provisioning {
+                status: "creating".to_string(),
+            }),
+            ("running", WorkloadStatus::Running {
+                uptime_secs: Some(3600),
+                last_attestation_at: Some(1_726_358_400),
+            }),
+            ("unhealthy", WorkloadStatus::Degraded {
+                uptime_secs: Some(60),
+                last_attestation_at: None,
+            }),
+            ("degraded", WorkloadStatus::Degraded {
+                uptime_secs: None,
+                last_attestation_at: Some(1_726_358_400),
+            }),
+            ("exited", WorkloadStatus::Stopped {
+                exit_reason: Some("OOM killed".to_string()),
+            }),
+            ("migrating", WorkloadStatus::Unknown {
+                status: "migrating".to_string(),
+            }),
+        ];
+        for (id, expected) in cases {
+            assert_eq!(status(&tee, id).await.unwrap(), expected, "{id}");
+            assert_eq!(
+                serde_json::to_value(&expected).unwrap()["state"],
+                expected.state()
+            );
+        }
+        assert!(status(&tee, "running").await.unwrap().is_healthy());
+        assert!(!status(&tee, "unhealthy").await.unwrap().is_healthy());
+    }
+
+    #[tokio::test]
+    async fn missing_and_malformed_workloads_are_errors() {
+        let tee = handler().await;
+        let err = status(&tee, "cvm-0000").await.unwrap_err();
+        assert!(
+            matches!(&err, PhalaAvsError::NotFound(message) if message.contains("cvm-0000")),
+            "{err}"
+        );
+        let err = status(&tee, "malformed").await.unwrap_err();
+        assert!(
+            matches!(&err, PhalaAvsError::TeeError(message) if message.contains("invalid reply")),
+            "{err}"
+        );
+
+        let bare = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+        assert!(status(&bare, "running").await.is_err());
+    }
+}
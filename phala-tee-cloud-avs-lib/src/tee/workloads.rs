@@ -0,0 +1,157 @@
+//! Workloads running on this host, as managed through the dstack host API.
+
+use super::TeeHandler;
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::sanitize;
+use blueprint_sdk::alloy::primitives::B256;
+use std::fmt;
+use std::sync::Arc;
+
+/// Starts, stops and lists the host's workloads.
+pub trait WorkloadHost: Send + Sync + fmt::Debug {
+    /// Ids of the workloads currently running.
+    fn running(&self) -> BoxFuture<'_, Result<Vec<B256>, PhalaAvsError>>;
+
+    fn start(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+
+    fn stop(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+
+    /// Pushes configuration to a running workload, merged into what it already holds.
+    fn configure(
+        &self,
+        workload_id: B256,
+        config: serde_json::Value,
+    ) -> BoxFuture<'_, Result<(), PhalaAvsError>>;
+}
+
+/// [`WorkloadHost`] over `<url>/workloads`.
+#[derive(Clone, Debug)]
+pub struct HttpWorkloadHost {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpWorkloadHost {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/workloads{path}", self.url.trim_end_matches('/'))
+    }
+
+    async fn post(&self, workload_id: B256, action: &str) -> Result<(), PhalaAvsError> {
+        self.client
+            .post(self.endpoint(&format!("/{workload_id}/{action}")))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| {
+                PhalaAvsError::TeeError(format!(
+                    "Failed to {action} workload {workload_id}: {}",
+                    sanitize::message("tee_reply", e)
+                ))
+            })?;
+        Ok(())
+    }
+}
+
+impl WorkloadHost for HttpWorkloadHost {
+    fn running(&self) -> BoxFuture<'_, Result<Vec<B256>, PhalaAvsError>> {
+        Box::pin(async move {
+            self.client
+                .get(self.endpoint("?state=running"))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| {
+                    PhalaAvsError::TeeError(format!(
+                        "Host workload query failed: {}",
+                        sanitize::message("tee_reply", e)
+                    ))
+                })?
+                .json()
+                .await
+                .map_err(|e| {
+                    PhalaAvsError::TeeError(format!(
+                        "Invalid host workload reply: {}",
+                        sanitize::message("tee_reply", e)
+                    ))
+                })
+        })
+    }
+
+    fn start(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(self.post(workload_id, "start"))
+    }
+
+    fn stop(&self, workload_id: B256) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(self.post(workload_id, "stop"))
+    }
+
+    fn configure(
+        &self,
+        workload_id: B256,
+        config: serde_json::Value,
+    ) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(async move {
+            self.client
+                .put(self.endpoint(&format!("/{workload_id}/config")))
+                .json(&config)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| {
+                    PhalaAvsError::TeeError(format!(
+                        "Failed to configure workload {workload_id}: {}",
+                        sanitize::message("tee_reply", e)
+                    ))
+                })?;
+            Ok(())
+        })
+    }
+}
+
+impl TeeHandler {
+    /// Manages workloads through `host`.
+    pub fn with_workloads(mut self, host: Arc<dyn WorkloadHost>) -> Self {
+        self.workloads = Some(host);
+        self
+    }
+
+    fn workload_host(&self) -> Result<&Arc<dyn WorkloadHost>, PhalaAvsError> {
+        self.workloads.as_ref().ok_or_else(|| {
+            PhalaAvsError::TeeError("No dstack host API is configured (TEE_HOST_URL)".to_string())
+        })
+    }
+
+    /// Ids of the workloads running on this host.
+    pub async fn running_workloads(&self) -> Result<Vec<B256>, PhalaAvsError> {
+        self.inject_faults().await?;
+        self.workload_host()?.running().await
+    }
+
+    pub async fn start_workload(&self, workload_id: B256) -> Result<(), PhalaAvsError> {
+        self.inject_faults().await?;
+        self.workload_host()?.start(workload_id).await
+    }
+
+    pub async fn stop_workload(&self, workload_id: B256) -> Result<(), PhalaAvsError> {
+        self.inject_faults().await?;
+        self.workload_host()?.stop(workload_id).await
+    }
+
+    /// Pushes configuration to a running workload over the host's workload config channel.
+    pub async fn configure_workload(
+        &self,
+        workload_id: B256,
+        config: serde_json::Value,
+    ) -> Result<(), PhalaAvsError> {
+        self.inject_faults().await?;
+        self.workload_host()?.configure(workload_id, config).await
+    }
+}
@@ -0,0 +1,467 @@
+//! Watches the upgradeable contracts we talk to for implementation changes.
+//!
+//! The implementation address (EIP-1967 slot) and code hash of each configured contract are
+//! recorded at startup and re-checked every `UPGRADE_CHECK_SECS` and on `Upgraded` events. On a
+//! change the operator alerts, re-verifies the oracle's response schemas against its encoders and
+//! sets the `contract_recently_upgraded` flag. With `UPGRADE_REQUIRE_ACK` set, non-urgent
+//! responses are held until an admin acknowledges the upgrade; urgent ones are never held.
+//! Contracts that aren't EIP-1967 proxies are watched by code hash only.
+
+use crate::config::{env_flag, env_or};
+use crate::display::Addr;
+use crate::error::PhalaAvsError;
+use crate::evm::BoxFuture;
+use crate::metrics::METRICS;
+use crate::schema::SchemaRegistry;
+use crate::{SERVICE_MANAGER_ADDRESS, SLA_ORACLE_ADDRESS, TASK_MANAGER_ADDRESS};
+use blueprint_sdk::alloy::primitives::{Address, B256, U256, b256, keccak256};
+use blueprint_sdk::alloy::providers::Provider;
+use blueprint_sdk::alloy::rpc::types::Log;
+use blueprint_sdk::alloy::sol;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// `bytes32(uint256(keccak256("eip1967.proxy.implementation")) - 1)`.
+pub const IMPLEMENTATION_SLOT: B256 =
+    b256!("360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc");
+
+/// Gauge: 1 while an upgrade is unacknowledged.
+pub const RECENTLY_UPGRADED_METRIC: &str = "phala_avs_contract_recently_upgraded";
+/// Counter of detected upgrades, by contract.
+pub const UPGRADES_METRIC: &str = "phala_avs_contract_upgrades_total";
+
+sol! {
+    /// Emitted by EIP-1967 proxies when their implementation changes.
+    event Upgraded(address indexed implementation);
+}
+
+/// Whether `log` is an `Upgraded` event of one of `contracts`.
+pub fn is_upgrade_event(log: &Log, contracts: &[WatchedContract]) -> bool {
+    contracts.iter().any(|c| c.address == log.address()) && log.log_decode::<Upgraded>().is_ok()
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WatchedContract {
+    pub name: String,
+    pub address: Address,
+}
+
+impl WatchedContract {
+    pub fn new(name: &str, address: Address) -> Self {
+        Self {
+            name: name.to_string(),
+            address,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UpgradeConfig {
+    pub check_secs: u64,
+    /// Hold non-urgent responses after an upgrade until it is acknowledged.
+    pub require_ack: bool,
+    pub contracts: Vec<WatchedContract>,
+}
+
+impl UpgradeConfig {
+    /// Watches the oracle, service manager and task manager; reads `UPGRADE_CHECK_SECS` and
+    /// `UPGRADE_REQUIRE_ACK`.
+    pub fn from_env() -> Result<Self, PhalaAvsError> {
+        Ok(Self {
+            check_secs: env_or("UPGRADE_CHECK_SECS", 300)?,
+            require_ack: env_flag("UPGRADE_REQUIRE_ACK", false)?,
+            contracts: vec![
+                WatchedContract::new("sla_oracle", *SLA_ORACLE_ADDRESS),
+                WatchedContract::new("service_manager", *SERVICE_MANAGER_ADDRESS),
+                WatchedContract::new("task_manager", *TASK_MANAGER_ADDRESS),
+            ],
+        })
+    }
+}
+
+/// Reads proxy implementations and code hashes.
+pub trait ContractInspector: Send + Sync {
+    /// The EIP-1967 implementation, `None` if the slot is empty.
+    fn implementation(
+        &self,
+        proxy: Address,
+    ) -> BoxFuture<'_, Result<Option<Address>, PhalaAvsError>>;
+
+    fn code_hash(&self, address: Address) -> BoxFuture<'_, Result<B256, PhalaAvsError>>;
+}
+
+pub struct ProviderContractInspector<P> {
+    provider: P,
+}
+
+impl<P> ProviderContractInspector<P> {
+    pub fn new(provider: P) -> Self {
+        Self { provider }
+    }
+}
+
+impl<P: Provider + Send + Sync> ContractInspector for ProviderContractInspector<P> {
+    fn implementation(
+        &self,
+        proxy: Address,
+    ) -> BoxFuture<'_, Result<Option<Address>, PhalaAvsError>> {
+        Box::pin(async move {
+            let slot = self
+                .provider
+                .get_storage_at(proxy, U256::from_be_bytes(IMPLEMENTATION_SLOT.0))
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(e.to_string()))?;
+            let implementation = Address::from_word(B256::from(slot));
+            Ok((implementation != Address::ZERO).then_some(implementation))
+        })
+    }
+
+    fn code_hash(&self, address: Address) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        Box::pin(async move {
+            let code = self
+                .provider
+                .get_code_at(address)
+                .await
+                .map_err(|e| PhalaAvsError::EvmError(e.to_string()))?;
+            Ok(keccak256(code))
+        })
+    }
+}
+
+/// What a contract runs, as of the last check.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ContractState {
+    pub name: String,
+    pub address: Address,
+    /// `None` for contracts that are not EIP-1967 proxies.
+    pub implementation: Option<Address>,
+    /// Code hash of the implementation, or of the contract itself when it isn't a proxy.
+    pub code_hash: B256,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UpgradeEvent {
+    pub name: String,
+    pub address: Address,
+    pub old_implementation: Option<Address>,
+    pub new_implementation: Option<Address>,
+    pub old_code_hash: B256,
+    pub new_code_hash: B256,
+    pub detected_unix_ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UpgradeStatus {
+    pub contracts: Vec<ContractState>,
+    pub contract_recently_upgraded: bool,
+    /// Upgrades detected since the last acknowledgement.
+    pub unacknowledged: Vec<UpgradeEvent>,
+    pub require_ack: bool,
+}
+
+pub struct UpgradeWatcher {
+    config: UpgradeConfig,
+    inspector: Arc<dyn ContractInspector>,
+    contracts: RwLock<BTreeMap<Address, ContractState>>,
+    unacknowledged: RwLock<Vec<UpgradeEvent>>,
+}
+
+impl UpgradeWatcher {
+    pub fn new(config: UpgradeConfig, inspector: Arc<dyn ContractInspector>) -> Self {
+        Self {
+            config,
+            inspector,
+            contracts: RwLock::default(),
+            unacknowledged: RwLock::default(),
+        }
+    }
+
+    pub fn config(&self) -> &UpgradeConfig {
+        &self.config
+    }
+
+    pub fn recently_upgraded(&self) -> bool {
+        !self
+            .unacknowledged
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+    }
+
+    /// Whether a response may be submitted now; urgent ones always may.
+    pub fn permits_submission(&self, urgent: bool) -> bool {
+        urgent || !self.config.require_ack || !self.recently_upgraded()
+    }
+
+    /// Clears the flag, returning the upgrades acknowledged.
+    pub fn acknowledge(&self) -> Vec<UpgradeEvent> {
+        let acknowledged = std::mem::take(
+            &mut *self
+                .unacknowledged
+                .write()
+                .unwrap_or_else(|e| e.into_inner()),
+        );
+        METRICS.set_gauge(RECENTLY_UPGRADED_METRIC, &[], 0.0);
+        if !acknowledged.is_empty() {
+            info!("Acknowledged {} contract upgrade(s)", acknowledged.len());
+        }
+        acknowledged
+    }
+
+    pub fn status(&self) -> UpgradeStatus {
+        UpgradeStatus {
+            contracts: self
+                .contracts
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .values()
+                .cloned()
+                .collect(),
+            contract_recently_upgraded: self.recently_upgraded(),
+            unacknowledged: self
+                .unacknowledged
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+            require_ack: self.config.require_ack,
+        }
+    }
+
+    async fn inspect(&self, contract: &WatchedContract) -> Result<ContractState, PhalaAvsError> {
+        let implementation = self.inspector.implementation(contract.address).await?;
+        let code_hash = self
+            .inspector
+            .code_hash(implementation.unwrap_or(contract.address))
+            .await?;
+        Ok(ContractState {
+            name: contract.name.clone(),
+            address: contract.address,
+            implementation,
+            code_hash,
+        })
+    }
+
+    /// Checks every contract, returning the upgrades found; the first check records the baseline.
+    pub async fn check(&self) -> Result<Vec<UpgradeEvent>, PhalaAvsError> {
+        let mut upgrades = Vec::new();
+        for contract in &self.config.contracts {
+            let current = self.inspect(contract).await?;
+            let previous = self
+                .contracts
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(contract.address, current.clone());
+            match previous {
+                None if current.implementation.is_none() => info!(
+                    "{} at {} is not an EIP-1967 proxy; watching its code hash only",
+                    contract.name,
+                    Addr(contract.address)
+                ),
+                None => info!(
+                    "{} at {} runs implementation {}",
+                    contract.name,
+                    Addr(contract.address),
+                    Addr(current.implementation.unwrap_or_default())
+                ),
+                Some(previous) if previous != current => upgrades.push(UpgradeEvent {
+                    name: contract.name.clone(),
+                    address: contract.address,
+                    old_implementation: previous.implementation,
+                    new_implementation: current.implementation,
+                    old_code_hash: previous.code_hash,
+                    new_code_hash: current.code_hash,
+                    detected_unix_ms: now_unix_ms(),
+                }),
+                Some(_) => {}
+            }
+        }
+
+        if !upgrades.is_empty() {
+            for upgrade in &upgrades {
+                error!(
+                    "Contract {} at {} was upgraded: implementation {:?} -> {:?}, code hash {} -> {}",
+                    upgrade.name,
+                    upgrade.address,
+                    upgrade.old_implementation,
+                    upgrade.new_implementation,
+                    upgrade.old_code_hash,
+                    upgrade.new_code_hash
+                );
+                METRICS.inc_counter(UPGRADES_METRIC, &[("contract", &upgrade.name)], 1);
+            }
+            self.unacknowledged
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .extend(upgrades.iter().cloned());
+            METRICS.set_gauge(RECENTLY_UPGRADED_METRIC, &[], 1.0);
+        }
+        Ok(upgrades)
+    }
+
+    /// Checks for upgrades and, if any, re-verifies the oracle's response schemas.
+    ///
+    /// The flag is set before re-verification, so responses are held while it runs.
+    pub async fn check_and_revalidate(
+        &self,
+        schemas: &SchemaRegistry,
+    ) -> Result<Vec<UpgradeEvent>, PhalaAvsError> {
+        let upgrades = self.check().await?;
+        if !upgrades.is_empty() {
+            info!("Re-verifying response schemas after contract upgrade");
+            schemas.refresh().await?;
+        }
+        Ok(upgrades)
+    }
+}
+
+/// Re-checks every `check_secs`, in the background.
+pub fn spawn_watcher(watcher: Arc<UpgradeWatcher>, schemas: Arc<SchemaRegistry>) {
+    tokio::spawn(async move {
+        let period = Duration::from_secs(watcher.config.check_secs);
+        loop {
+            tokio::time::sleep(period).await;
+            if let Err(e) = watcher.check_and_revalidate(&schemas).await {
+                warn!("Failed to check contracts for upgrades: {e}");
+            }
+        }
+    });
+}
+
+fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{LIVENESS_KIND, LivenessEncoderV1, ResponseEncoder, SchemaKey, kind_id};
+    use crate::schema::{PublishedSchema, SchemaRegistryConfig, SchemaSource, SchemaStatus};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    const ORACLE: Address = Address::repeat_byte(1);
+    const STATIC: Address = Address::repeat_byte(2);
+
+    /// A chain whose proxies can be upgraded between checks.
+    #[derive(Default)]
+    struct MockChain {
+        implementations: Mutex<HashMap<Address, Address>>,
+        code: Mutex<HashMap<Address, B256>>,
+    }
+
+    impl MockChain {
+        fn upgrade(&self, proxy: Address, implementation: Address, code_hash: B256) {
+            self.implementations
+                .lock()
+                .unwrap()
+                .insert(proxy, implementation);
+            self.code.lock().unwrap().insert(implementation, code_hash);
+        }
+    }
+
+    impl ContractInspector for MockChain {
+        fn implementation(
+            &self,
+            proxy: Address,
+        ) -> BoxFuture<'_, Result<Option<Address>, PhalaAvsError>> {
+            let implementation = self.implementations.lock().unwrap().get(&proxy).copied();
+            Box::pin(async move { Ok(implementation) })
+        }
+
+        fn code_hash(&self, address: Address) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+            let hash = self.code.lock().unwrap().get(&address).copied();
+            Box::pin(async move { Ok(hash.unwrap_or_default()) })
+        }
+    }
+
+    /// Publishes the liveness schema and counts fetches.
+    #[derive(Default)]
+    struct CountingSource {
+        fetches: Mutex<usize>,
+    }
+
+    impl SchemaSource for CountingSource {
+        fn fetch(&self) -> BoxFuture<'_, Result<Vec<PublishedSchema>, PhalaAvsError>> {
+            *self.fetches.lock().unwrap() += 1;
+            Box::pin(async {
+                Ok(vec![PublishedSchema {
+                    kind: kind_id(LIVENESS_KIND),
+                    version: 1,
+                    schema_hash: LivenessEncoderV1.schema_hash(),
+                    uri: String::new(),
+                }])
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrade_is_detected_revalidated_and_gates_non_urgent_submissions() {
+        let chain = Arc::new(MockChain::default());
+        chain.upgrade(ORACLE, Address::repeat_byte(0x10), B256::repeat_byte(0x10));
+        chain
+            .code
+            .lock()
+            .unwrap()
+            .insert(STATIC, B256::repeat_byte(0x20));
+        let watcher = UpgradeWatcher::new(
+            UpgradeConfig {
+                check_secs: 60,
+                require_ack: true,
+                contracts: vec![
+                    WatchedContract::new("sla_oracle", ORACLE),
+                    WatchedContract::new("static", STATIC),
+                ],
+            },
+            Arc::clone(&chain) as Arc<dyn ContractInspector>,
+        );
+        let source = Arc::new(CountingSource::default());
+        let schemas = SchemaRegistry::new(
+            SchemaRegistryConfig {
+                refresh_secs: 600,
+                manifest: None,
+            },
+            Arc::clone(&source) as Arc<dyn SchemaSource>,
+        );
+
+        // The baseline is not an upgrade, and the non-proxy contract is tolerated.
+        assert!(
+            watcher
+                .check_and_revalidate(&schemas)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(watcher.status().contracts[1].implementation, None);
+        assert_eq!(*source.fetches.lock().unwrap(), 0);
+        assert!(watcher.permits_submission(false));
+
+        chain.upgrade(ORACLE, Address::repeat_byte(0x11), B256::repeat_byte(0x11));
+        let upgrades = watcher.check_and_revalidate(&schemas).await.unwrap();
+        assert_eq!(upgrades.len(), 1);
+        assert_eq!(
+            upgrades[0].old_implementation,
+            Some(Address::repeat_byte(0x10))
+        );
+        assert_eq!(
+            upgrades[0].new_implementation,
+            Some(Address::repeat_byte(0x11))
+        );
+        assert_eq!(*source.fetches.lock().unwrap(), 1);
+        let liveness = SchemaKey::new(LIVENESS_KIND, 1);
+        let checks = schemas.checks().unwrap();
+        let check = checks.iter().find(|c| c.key == liveness).unwrap();
+        assert_eq!(check.status, SchemaStatus::Match);
+
+        assert!(watcher.status().contract_recently_upgraded);
+        assert!(!watcher.permits_submission(false));
+        assert!(watcher.permits_submission(true));
+        assert_eq!(watcher.acknowledge().len(), 1);
+        assert!(watcher.permits_submission(false));
+    }
+}
@@ -0,0 +1,177 @@
+//! Chaos suite: runs a simulated challenge scenario under each default fault profile and checks
+//! the pipeline's recovery invariants.
+//!
+//! Run with `cargo test -p phala-tee-cloud-avs-blueprint-lib --features chaos --test chaos`.
+//!
+//! The scenario models a producer that redelivers the blocks of a failed poll; invariants:
+//! every challenge is released for submission exactly once and before its deadline, and every
+//! error surfaced by the pipeline was injected.
+
+#![cfg(feature = "chaos")]
+
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, U256};
+use blueprint_sdk::alloy::rpc::types::Log;
+use phala_tee_cloud_avs_blueprint_lib::PhalaAvsError;
+use phala_tee_cloud_avs_blueprint_lib::challenge::{
+    ChallengeTracker, ConfirmationPolicy, process_events,
+};
+use phala_tee_cloud_avs_blueprint_lib::chaos::{
+    ChaosConfig, ChaosEngine, ChaosEvmClient, ChaosStateStore, default_profiles, is_injected,
+};
+use phala_tee_cloud_avs_blueprint_lib::evm::{BoxFuture, EvmClient};
+use phala_tee_cloud_avs_blueprint_lib::fixtures::{ChallengeEventFixture, OPERATOR, block_hash};
+use phala_tee_cloud_avs_blueprint_lib::state::{MemoryStateStore, StateStore};
+use phala_tee_cloud_avs_blueprint_lib::{TeeHandler, TeeHandlerConfig};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const CHALLENGES: u64 = 20;
+const WINDOW_BLOCKS: u64 = 30;
+const SAFETY_MARGIN: u64 = 3;
+
+/// A chain that only moves forward; the scenario drives the head.
+#[derive(Default)]
+struct SimulatedChain {
+    head: AtomicU64,
+}
+
+impl EvmClient for SimulatedChain {
+    fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async { Ok(31337) })
+    }
+
+    fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        let head = self.head.load(Ordering::SeqCst);
+        Box::pin(async move { Ok(head) })
+    }
+
+    fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+        let head = self.head.load(Ordering::SeqCst);
+        Box::pin(async move { Ok((number <= head).then(|| block_hash(number))) })
+    }
+
+    fn block_timestamp(&self, number: u64) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+        let head = self.head.load(Ordering::SeqCst);
+        Box::pin(async move { Ok((number <= head).then_some(number * 12)) })
+    }
+
+    fn is_operator_registered(
+        &self,
+        _operator: Address,
+    ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+        Box::pin(async { Ok(true) })
+    }
+}
+
+fn issued_block(id: u64) -> u64 {
+    5 + 3 * id
+}
+
+/// The `SlaChallengeIssued` logs of `block`; every challenge targets us.
+fn logs_in_block(block: u64) -> Vec<Log> {
+    (0..CHALLENGES)
+        .filter(|id| issued_block(*id) == block)
+        .map(|id| {
+            ChallengeEventFixture::new()
+                .id(id)
+                .data(Bytes::from_static(b"liveness"))
+                .window(WINDOW_BLOCKS)
+                .block(block)
+                .transaction_hash(B256::from(U256::from(1000 + id)))
+                .build_log()
+        })
+        .collect()
+}
+
+/// Runs the scenario under `config`, returning the head at which each challenge was released.
+async fn run_scenario(config: ChaosConfig) -> BTreeMap<U256, Vec<u64>> {
+    // Start without faults so the tracker can be constructed, then switch the profile on.
+    let engine = Arc::new(ChaosEngine::new(ChaosConfig::default()).unwrap());
+    let chain = Arc::new(SimulatedChain::default());
+    let evm = ChaosEvmClient::new(
+        Arc::clone(&chain) as Arc<dyn EvmClient>,
+        Arc::clone(&engine),
+    );
+    let inner: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+    let store: Arc<dyn StateStore> = Arc::new(ChaosStateStore::new(
+        Arc::clone(&inner),
+        Arc::clone(&engine),
+    ));
+    let tee = TeeHandler::new(TeeHandlerConfig::default())
+        .await
+        .unwrap()
+        .with_chaos(Arc::clone(&engine));
+    let mut tracker =
+        ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap();
+    engine.reconfigure(config).unwrap();
+
+    let last_deadline = issued_block(CHALLENGES - 1) + WINDOW_BLOCKS;
+    let mut released: BTreeMap<U256, Vec<u64>> = BTreeMap::new();
+    let mut cursor = 0;
+    for head in 1..=last_deadline + 5 {
+        chain.head.store(head, Ordering::SeqCst);
+
+        // Halfway through, the operator restarts from its persisted state.
+        if head == last_deadline / 2 {
+            tracker = loop {
+                match ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)) {
+                    Ok(tracker) => break tracker,
+                    Err(e) => assert!(is_injected(&e), "organic failure on restart: {e}"),
+                }
+            };
+        }
+
+        let logs: Vec<Log> = (cursor + 1..=head).flat_map(logs_in_block).collect();
+        let logs = engine.corrupt_events(logs);
+        match process_events(&tracker, &evm, &tee, OPERATOR, &logs, true, |_| {
+            SAFETY_MARGIN
+        })
+        .await
+        {
+            Ok(processed) => {
+                for entry in processed.ready {
+                    released
+                        .entry(entry.challenge.challenge_id)
+                        .or_default()
+                        .push(head);
+                }
+                // Blocks with a failed event are redelivered.
+                if processed.summary.failed == 0 {
+                    cursor = head;
+                }
+            }
+            Err(e) => assert!(is_injected(&e), "organic failure: {e}"),
+        }
+    }
+    released
+}
+
+#[tokio::test]
+async fn pipeline_recovers_under_default_profiles() {
+    for (name, config) in default_profiles() {
+        let released = run_scenario(config).await;
+        for id in 0..CHALLENGES {
+            let heads = released.get(&U256::from(id)).cloned().unwrap_or_default();
+            assert_eq!(
+                heads.len(),
+                1,
+                "{name}: challenge {id} released at heads {heads:?}"
+            );
+            assert!(
+                heads[0] <= issued_block(id) + WINDOW_BLOCKS,
+                "{name}: challenge {id} released after its deadline at {}",
+                heads[0]
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn same_seed_reproduces_the_run() {
+    let config = ChaosConfig::profile("everything").unwrap();
+    assert_eq!(
+        run_scenario(config.clone()).await,
+        run_scenario(config).await
+    );
+}
@@ -0,0 +1,89 @@
+//! Golden vectors of every compiled response encoder.
+//!
+//! Each encoder version has its expected payloads frozen under `tests/fixtures/encoders`, so
+//! versions of one kind held side by side for a canary rollout (see `rollout`) each keep
+//! producing exactly what the oracle was shown. `tee_compute` responses wrap a live TEE
+//! computation and have no vectors.
+
+use blueprint_sdk::alloy::primitives::{Address, Bytes, U256};
+use phala_tee_cloud_avs_blueprint_lib::challenge::ObservedChallenge;
+use phala_tee_cloud_avs_blueprint_lib::encoding::{
+    ATTESTATION_KIND, AttestationQuote, ResponseEncoder, ResponseInputs, encoders, envelope,
+    kind_id, versions,
+};
+use serde_json::Value;
+use std::path::PathBuf;
+
+fn fixture(encoder: &dyn ResponseEncoder) -> Value {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/encoders")
+        .join(format!(
+            "{}_v{}.json",
+            encoder.kind_name(),
+            encoder.version()
+        ));
+    let raw = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("missing golden vectors {}: {e}", path.display()));
+    serde_json::from_str(&raw).unwrap()
+}
+
+fn bytes(value: &Value) -> Bytes {
+    value.as_str().unwrap().parse().unwrap()
+}
+
+fn inputs(value: &Value) -> ResponseInputs {
+    ResponseInputs {
+        responded_at_unix: value["responded_at_unix"].as_u64().unwrap_or_default(),
+        live: value["live"].as_bool().unwrap_or_default(),
+        computation: None,
+        attestation: value.get("attestation").map(|a| AttestationQuote {
+            platform: a["platform"].as_str().unwrap().parse().unwrap(),
+            quoted_at_unix: a["quoted_at_unix"].as_u64().unwrap(),
+            quote: bytes(&a["quote"]),
+        }),
+    }
+}
+
+#[test]
+fn every_encoder_reproduces_its_golden_vectors() {
+    for encoder in encoders().iter().filter(|e| !e.tee_compute()) {
+        let fixture = fixture(*encoder);
+        let name = format!("{} v{}", encoder.kind_name(), encoder.version());
+        assert_eq!(fixture["schema"], encoder.schema(), "{name} schema changed");
+        let vectors = fixture["vectors"].as_array().unwrap();
+        assert!(!vectors.is_empty(), "{name} has no vectors");
+        for vector in vectors {
+            let challenge = ObservedChallenge {
+                challenge_id: U256::from(vector["challenge_id"].as_u64().unwrap()),
+                operator: Address::ZERO,
+                challenge_data: envelope(encoder.kind_name(), encoder.version(), Bytes::new()),
+                deadline_block: 0,
+                oracle: Address::ZERO,
+                issued_block: 0,
+                issued_block_hash: None,
+                transaction_hash: None,
+            };
+            let payload = encoder
+                .encode(&challenge, &inputs(&vector["inputs"]))
+                .unwrap();
+            assert_eq!(
+                payload,
+                bytes(&vector["payload"]),
+                "{name} encoding changed"
+            );
+            encoder.validate(&challenge, &payload).unwrap();
+        }
+    }
+}
+
+#[test]
+fn rolled_out_kinds_hold_every_version_side_by_side() {
+    let attestation = versions(kind_id(ATTESTATION_KIND));
+    let held: Vec<_> = attestation.iter().map(|e| e.version()).collect();
+    assert_eq!(held, [1, 2]);
+    // Each version's vectors are its own.
+    assert_ne!(
+        fixture(attestation[0])["vectors"][0]["payload"],
+        fixture(attestation[1])["vectors"][0]["payload"]
+    );
+}
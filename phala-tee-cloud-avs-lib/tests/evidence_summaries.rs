@@ -0,0 +1,108 @@
+//! Times range evidence over a synthetic month of minutely heartbeats and hourly responses, read
+//! record by record and composed from window summaries, and checks the two agree.
+//!
+//! Timing is only meaningful in release mode, so it runs on request:
+//! `cargo test --release --test evidence_summaries -- --ignored --nocapture`.
+
+use phala_tee_cloud_avs_blueprint_lib::evidence::range::BlockSpan;
+use phala_tee_cloud_avs_blueprint_lib::evidence::{
+    AnchorConfig, EvidenceLog, HEARTBEAT_EVIDENCE, HeartbeatEvidence, RESPONSE_EVIDENCE,
+    ResponseEvidence,
+};
+use phala_tee_cloud_avs_blueprint_lib::state::{MemoryStateStore, StateStore};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const WINDOWS: AnchorConfig = AnchorConfig {
+    enabled: true,
+    window_secs: 3600,
+    check_secs: 300,
+};
+const PERIOD_MS: u64 = 60_000;
+const START_MS: u64 = 1_700_000_000_000 / 3_600_000 * 3_600_000;
+const MONTH_MS: u64 = 30 * 24 * 3_600_000;
+/// Challenges answered against the month once its summaries exist.
+const CHALLENGES: u32 = 5;
+
+fn month() -> EvidenceLog {
+    let log = EvidenceLog::new(Arc::new(MemoryStateStore::default()) as Arc<dyn StateStore>);
+    for minute in 0..MONTH_MS / PERIOD_MS {
+        let unix_ms = START_MS + minute * PERIOD_MS + minute % 13;
+        let heartbeat = HeartbeatEvidence {
+            unix_ms,
+            live: Some(minute % 211 != 0),
+            in_maintenance: minute % 10_000 < 30,
+            health_score: None,
+            healthy: None,
+        };
+        log.record(HEARTBEAT_EVIDENCE, unix_ms, &[], &heartbeat)
+            .unwrap();
+        if minute % 60 == 7 {
+            let response = ResponseEvidence {
+                unix_ms,
+                challenge_id: minute.to_string(),
+                issued_block: minute,
+                deadline_block: minute + 50,
+                release_reason: "Confirmed".to_string(),
+                artifacts_url: None,
+                detection_delay_blocks: Some(1),
+            };
+            log.record(RESPONSE_EVIDENCE, unix_ms, &minute.to_be_bytes(), &response)
+                .unwrap();
+        }
+    }
+    log
+}
+
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let started = Instant::now();
+    let result = f();
+    (result, started.elapsed())
+}
+
+#[test]
+#[ignore = "builds a month of evidence; run in release mode"]
+fn composed_month_is_faster_than_a_raw_scan() {
+    let log = month();
+    // Starts and ends mid-window, so both edges are read record by record.
+    let span = BlockSpan {
+        from_block: 1,
+        to_block: 216_000,
+        from_ms: START_MS + 1_234_567,
+        to_ms: START_MS + MONTH_MS - 2_345_678,
+        past_head: false,
+    };
+    let now_ms = START_MS + MONTH_MS + 3_600_000;
+
+    let (raw, raw_time) = timed(|| {
+        log.range_evidence_raw(&WINDOWS, span, PERIOD_MS, now_ms)
+            .unwrap()
+    });
+    let (cold, cold_time) = timed(|| {
+        log.range_evidence(&WINDOWS, span, PERIOD_MS, now_ms)
+            .unwrap()
+    });
+    let mut warm_time = Duration::ZERO;
+    for _ in 0..CHALLENGES {
+        let (warm, elapsed) = timed(|| {
+            log.range_evidence(&WINDOWS, span, PERIOD_MS, now_ms)
+                .unwrap()
+        });
+        assert_eq!(
+            serde_json::to_vec(&warm).unwrap(),
+            serde_json::to_vec(&raw).unwrap()
+        );
+        warm_time += elapsed;
+    }
+    assert_eq!(cold, raw);
+    let warm_time = warm_time / CHALLENGES;
+
+    println!(
+        "{} leaves over {} windows: raw scan {raw_time:?}, first composition {cold_time:?}, \
+         composed from summaries {warm_time:?} ({:.1}x faster)",
+        raw.leaves.len(),
+        raw.windows.len(),
+        raw_time.as_secs_f64() / warm_time.as_secs_f64()
+    );
+    assert!(warm_time < raw_time);
+}
@@ -0,0 +1,236 @@
+//! Voluntary exit against a simulated chain: a challenge issued mid-drain is answered after the
+//! deregistration took effect, the exit survives a restart, and the operator is only reported
+//! safe to shut down once the challenge's response window closed.
+//!
+//! Run with `cargo test -p phala-tee-cloud-avs-blueprint-lib --features testing --test exit`.
+
+#![cfg(feature = "testing")]
+
+use blueprint_sdk::alloy::primitives::{Address, B256, Bytes, U256};
+use phala_tee_cloud_avs_blueprint_lib::PhalaAvsError;
+use phala_tee_cloud_avs_blueprint_lib::capacity::Reservations;
+use phala_tee_cloud_avs_blueprint_lib::challenge::{
+    ChallengeTracker, ConfirmationPolicy, process_events,
+};
+use phala_tee_cloud_avs_blueprint_lib::evm::{BoxFuture, EvmClient};
+use phala_tee_cloud_avs_blueprint_lib::exit::{ExitConfig, ExitPhase, ExitRegistry, ExitWorkflow};
+use phala_tee_cloud_avs_blueprint_lib::fixtures::{ChallengeEventFixture, OPERATOR, block_hash};
+use phala_tee_cloud_avs_blueprint_lib::registration::{RegistrationConfig, RegistrationGate};
+use phala_tee_cloud_avs_blueprint_lib::state::{MemoryStateStore, StateStore};
+use phala_tee_cloud_avs_blueprint_lib::tee::capacity::Resources;
+use phala_tee_cloud_avs_blueprint_lib::{TeeHandler, TeeHandlerConfig};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const EXIT_DELAY_BLOCKS: u64 = 10;
+const RESPONSE_WINDOW_BLOCKS: u64 = 30;
+const CHALLENGE_BLOCK: u64 = 11;
+
+/// A chain whose head the scenario drives, and where the operator stays registered until it
+/// deregisters.
+struct SimulatedChain {
+    head: AtomicU64,
+    registered: AtomicBool,
+}
+
+impl EvmClient for SimulatedChain {
+    fn chain_id(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async { Ok(31337) })
+    }
+
+    fn block_number(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        let head = self.head.load(Ordering::SeqCst);
+        Box::pin(async move { Ok(head) })
+    }
+
+    fn block_hash(&self, number: u64) -> BoxFuture<'_, Result<Option<B256>, PhalaAvsError>> {
+        let head = self.head.load(Ordering::SeqCst);
+        Box::pin(async move { Ok((number <= head).then(|| block_hash(number))) })
+    }
+
+    fn block_timestamp(&self, number: u64) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+        let head = self.head.load(Ordering::SeqCst);
+        Box::pin(async move { Ok((number <= head).then_some(number * 12)) })
+    }
+
+    fn is_operator_registered(
+        &self,
+        _operator: Address,
+    ) -> BoxFuture<'_, Result<bool, PhalaAvsError>> {
+        let registered = self.registered.load(Ordering::SeqCst);
+        Box::pin(async move { Ok(registered) })
+    }
+}
+
+/// The exit contracts on top of [`SimulatedChain`], recording the transactions sent.
+struct SimulatedExits {
+    chain: Arc<SimulatedChain>,
+    allowed_from: Mutex<Option<u64>>,
+    sent: Mutex<Vec<(&'static str, u64)>>,
+}
+
+impl ExitRegistry for SimulatedExits {
+    fn opt_out(&self) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        let head = self.chain.head.load(Ordering::SeqCst);
+        let mut allowed_from = self.allowed_from.lock().unwrap();
+        assert!(allowed_from.is_none(), "opted out twice");
+        *allowed_from = Some(head + EXIT_DELAY_BLOCKS);
+        self.sent.lock().unwrap().push(("opt_out", head));
+        Box::pin(async { Ok(B256::repeat_byte(1)) })
+    }
+
+    fn deregister_from_block(
+        &self,
+        _operator: Address,
+    ) -> BoxFuture<'_, Result<Option<u64>, PhalaAvsError>> {
+        let allowed_from = *self.allowed_from.lock().unwrap();
+        Box::pin(async move { Ok(allowed_from) })
+    }
+
+    fn deregister(&self, quorums: Bytes) -> BoxFuture<'_, Result<B256, PhalaAvsError>> {
+        let head = self.chain.head.load(Ordering::SeqCst);
+        let allowed_from = self.allowed_from.lock().unwrap().expect("not opted out");
+        assert!(head >= allowed_from, "deregistered before the exit delay");
+        assert_eq!(quorums, Bytes::from_static(&[0]));
+        self.chain.registered.store(false, Ordering::SeqCst);
+        self.sent.lock().unwrap().push(("deregister", head));
+        Box::pin(async { Ok(B256::repeat_byte(2)) })
+    }
+
+    fn response_window_blocks(&self) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async { Ok(RESPONSE_WINDOW_BLOCKS) })
+    }
+}
+
+/// An operator process: what a restart builds from the persisted state.
+struct Operator {
+    gate: Arc<RegistrationGate>,
+    reservations: Arc<Reservations>,
+    workflow: ExitWorkflow,
+}
+
+impl Operator {
+    fn start(store: &Arc<dyn StateStore>, exits: &Arc<SimulatedExits>) -> Self {
+        let gate = Arc::new(RegistrationGate::new(OPERATOR, RegistrationConfig {
+            check_secs: 60,
+            force_submit: false,
+        }));
+        let reservations = Arc::new(Reservations::default());
+        let workflow = ExitWorkflow::new(
+            ExitConfig {
+                check_secs: 12,
+                quorums: vec![0],
+            },
+            OPERATOR,
+            Arc::clone(exits) as Arc<dyn ExitRegistry>,
+            Arc::clone(store),
+            Arc::clone(&gate),
+            Arc::clone(&reservations),
+        )
+        .unwrap();
+        Self {
+            gate,
+            reservations,
+            workflow,
+        }
+    }
+}
+
+#[tokio::test]
+async fn exit_answers_challenges_until_their_windows_close() {
+    let chain = Arc::new(SimulatedChain {
+        head: AtomicU64::new(0),
+        registered: AtomicBool::new(true),
+    });
+    let exits = Arc::new(SimulatedExits {
+        chain: Arc::clone(&chain),
+        allowed_from: Mutex::default(),
+        sent: Mutex::default(),
+    });
+    let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+    let tracker = ChallengeTracker::new(ConfirmationPolicy::default(), Arc::clone(&store)).unwrap();
+    let tee = TeeHandler::new(TeeHandlerConfig::default()).await.unwrap();
+    let challenge = ChallengeEventFixture::new()
+        .id(1)
+        .data(Bytes::from_static(b"liveness"))
+        .window(RESPONSE_WINDOW_BLOCKS)
+        .block(CHALLENGE_BLOCK);
+    let deadline = challenge.deadline_block();
+
+    let mut operator = Operator::start(&store, &exits);
+    let mut answered_at = None;
+    let mut safe_at = None;
+    for head in 1..=80 {
+        chain.head.store(head, Ordering::SeqCst);
+        operator.gate.check(chain.as_ref()).await.unwrap();
+
+        if head == 2 {
+            operator.workflow.start(head * 12_000).unwrap();
+            let refused = operator
+                .reservations
+                .reserve("new-workload", Resources::default());
+            assert!(refused.is_err());
+        }
+        // The operator restarts mid-exit and resumes from its persisted state.
+        if head == 20 {
+            operator = Operator::start(&store, &exits);
+            assert!(operator.reservations.is_draining());
+            assert!(operator.gate.is_exiting());
+        }
+
+        let logs = if head == CHALLENGE_BLOCK {
+            vec![challenge.build_log()]
+        } else {
+            Vec::new()
+        };
+        let processed = process_events(
+            &tracker,
+            chain.as_ref(),
+            &tee,
+            OPERATOR,
+            &logs,
+            operator.gate.permits_submission(),
+            |_| 3,
+        )
+        .await
+        .unwrap();
+        if processed
+            .ready
+            .iter()
+            .any(|c| c.challenge.challenge_id == U256::from(1))
+        {
+            answered_at = Some(head);
+        }
+
+        let phase = operator
+            .workflow
+            .step(chain.as_ref(), &tracker, head * 12_000)
+            .await
+            .unwrap();
+        if phase == Some(ExitPhase::SafeToShutdown) {
+            assert!(safe_at.is_none(), "safe signalled twice");
+            safe_at = Some(head);
+        }
+        let safe = operator.workflow.state().is_some_and(|s| s.is_safe());
+        assert_eq!(safe, safe_at.is_some());
+    }
+
+    // Opted out right away, deregistered once the delay passed, never twice.
+    assert_eq!(*exits.sent.lock().unwrap(), vec![
+        ("opt_out", 2),
+        ("deregister", 2 + EXIT_DELAY_BLOCKS)
+    ]);
+    // Issued before the deregistration, released after it.
+    let answered_at = answered_at.expect("the mid-drain challenge was never answered");
+    assert!(answered_at > 2 + EXIT_DELAY_BLOCKS && answered_at <= deadline);
+    // Safe only once every window that can cover our active period closed.
+    let state = operator.workflow.state().unwrap();
+    assert_eq!(state.deregistered_block, Some(2 + EXIT_DELAY_BLOCKS));
+    assert_eq!(
+        state.final_deadline_block,
+        Some(2 + EXIT_DELAY_BLOCKS + RESPONSE_WINDOW_BLOCKS)
+    );
+    let safe_at = safe_at.expect("the exit never completed");
+    assert!(safe_at > deadline);
+    assert_eq!(safe_at, 2 + EXIT_DELAY_BLOCKS + RESPONSE_WINDOW_BLOCKS + 1);
+}
@@ -0,0 +1,45 @@
+//! Reports the size of the operator binary built with the default features and with `minimal`,
+//! so a dependency creeping into the minimal build shows up as a number rather than a surprise.
+//!
+//! Builds the binary twice in release mode, so it only runs on request:
+//! `cargo test --test footprint -- --ignored --nocapture`.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const BINARY: &str = "phala-tee-cloud-avs-blueprint-bin";
+
+/// Builds the binary with `features` into its own target directory and returns its size.
+fn release_size(workspace: &Path, name: &str, features: &[&str]) -> u64 {
+    let target_dir = workspace.join("target").join("footprint").join(name);
+    let status = Command::new(env!("CARGO"))
+        .current_dir(workspace)
+        .args(["build", "--release", "-p", BINARY, "--target-dir"])
+        .arg(&target_dir)
+        .args(features)
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "building the {name} binary failed");
+    let binary: PathBuf = target_dir.join("release").join(BINARY);
+    std::fs::metadata(&binary)
+        .unwrap_or_else(|e| panic!("missing {}: {e}", binary.display()))
+        .len()
+}
+
+#[test]
+#[ignore = "builds the operator binary twice"]
+fn minimal_build_is_smaller_than_default() {
+    let workspace = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let default = release_size(workspace, "default", &[]);
+    let minimal = release_size(workspace, "minimal", &[
+        "--no-default-features",
+        "--features",
+        "minimal",
+    ]);
+    let saved = default.saturating_sub(minimal);
+    println!(
+        "operator binary: default {default} bytes, minimal {minimal} bytes ({saved} bytes, {:.1}% smaller)",
+        saved as f64 * 100.0 / default as f64
+    );
+    assert!(minimal < default);
+}
@@ -0,0 +1,54 @@
+//! Snapshot capture against a local Anvil node, replayed offline.
+//!
+//! Run with `cargo test -p phala-tee-cloud-avs-blueprint-lib --test snapshot -- --ignored`, with
+//! an Anvil node at `ANVIL_RPC_URL` (default `http://127.0.0.1:8545`) where the oracle and
+//! service manager are deployed at `SLA_ORACLE_ADDRESS` and `SERVICE_MANAGER_ADDRESS`, and
+//! `PRIVATE_KEY` is the operator's. The last `SNAPSHOT_TEST_BLOCKS` blocks (default 100) are
+//! captured, then replayed without the node; invariant: the tracker reaches the outcomes of the
+//! live run, and the replay never asks for anything the capture did not record.
+
+use blueprint_sdk::evm::util::get_provider_http;
+use phala_tee_cloud_avs_blueprint_lib::challenge::ConfirmationPolicy;
+use phala_tee_cloud_avs_blueprint_lib::evm::ProviderEvmClient;
+use phala_tee_cloud_avs_blueprint_lib::snapshot::{self, ChainSnapshot};
+use phala_tee_cloud_avs_blueprint_lib::state::rebuild::{
+    HistorySource, ProviderHistorySource, RebuildScope,
+};
+use std::sync::Arc;
+
+#[tokio::test]
+#[ignore = "needs an Anvil node with the oracle deployed"]
+async fn anvil_window_replays_offline() {
+    let url = std::env::var("ANVIL_RPC_URL").unwrap_or_else(|_| "http://127.0.0.1:8545".into());
+    let blocks: u64 = std::env::var("SNAPSHOT_TEST_BLOCKS")
+        .ok()
+        .and_then(|blocks| blocks.parse().ok())
+        .unwrap_or(100);
+    let scope = RebuildScope::from_env().unwrap();
+    let provider = get_provider_http(&url);
+    let history = Arc::new(ProviderHistorySource::new(provider.clone()));
+    let head = history.head().await.unwrap();
+    let from_block = head.saturating_sub(blocks - 1);
+
+    let (captured, live) = snapshot::capture(
+        Arc::new(ProviderEvmClient::new(provider, scope.service_manager)),
+        history,
+        ConfirmationPolicy::default(),
+        scope,
+        from_block,
+        head,
+    )
+    .await
+    .unwrap();
+    assert_eq!(captured.logs.len() as u64, head - from_block + 1);
+
+    let path = std::env::temp_dir().join(format!("anvil-{head}.snapshot"));
+    captured.write(&path).unwrap();
+    let read = ChainSnapshot::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let replayed = snapshot::replay(read, ConfirmationPolicy::default())
+        .await
+        .unwrap();
+    assert_eq!(replayed, live);
+}
@@ -0,0 +1,249 @@
+//! Crash recovery of the transaction sender's write-ahead intents.
+//!
+//! Run with `cargo test -p phala-tee-cloud-avs-blueprint-lib --features chaos --test tx_intents`.
+//!
+//! For each crash point, a sender answering a sequence of challenges panics between two of its
+//! write-ahead steps; a fresh sender over the same state and chain then replays the intents and
+//! answers the rest. Invariants: every challenge is answered by exactly one included transaction,
+//! nonces are contiguous, and answering a challenge again sends nothing.
+
+#![cfg(feature = "chaos")]
+
+use blueprint_sdk::alloy::primitives::{Address, B256, U256};
+use phala_tee_cloud_avs_blueprint_lib::PhalaAvsError;
+use phala_tee_cloud_avs_blueprint_lib::chaos::{ChaosConfig, ChaosEngine};
+use phala_tee_cloud_avs_blueprint_lib::evm::BoxFuture;
+use phala_tee_cloud_avs_blueprint_lib::fees::Fees;
+use phala_tee_cloud_avs_blueprint_lib::lanes::{AccountSource, LaneConfig, SignerLanes, TxClass};
+use phala_tee_cloud_avs_blueprint_lib::sender::{
+    CrashPoint, IntentStage, SignedTx, TxCall, TxChain, TxOutcome, TxSender, TxSenderConfig,
+};
+use phala_tee_cloud_avs_blueprint_lib::state::{MemoryStateStore, StateStore};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const PRIMARY_KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+const ORACLE: Address = Address::repeat_byte(0x0c);
+const CHALLENGES: u64 = 6;
+/// Challenges answered before the crash is armed.
+const BEFORE_CRASH: u64 = 2;
+
+/// A chain including each account's pending transactions in nonce order when mined.
+#[derive(Default)]
+struct SimulatedTxChain {
+    pending: Mutex<BTreeMap<(Address, u64), B256>>,
+    included: Mutex<BTreeMap<Address, Vec<B256>>>,
+}
+
+impl SimulatedTxChain {
+    fn mine(&self) {
+        let mut included = self.included.lock().unwrap();
+        let mut pending = self.pending.lock().unwrap();
+        for ((account, nonce), hash) in std::mem::take(&mut *pending) {
+            let mined = included.entry(account).or_default();
+            if mined.len() as u64 == nonce {
+                mined.push(hash);
+            } else {
+                pending.insert((account, nonce), hash);
+            }
+        }
+    }
+
+    fn included_count(&self, account: Address) -> usize {
+        self.included
+            .lock()
+            .unwrap()
+            .get(&account)
+            .map_or(0, Vec::len)
+    }
+}
+
+impl TxChain for SimulatedTxChain {
+    fn estimate_gas(&self, _: Address, _: TxCall) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        Box::pin(async { Ok(100_000) })
+    }
+
+    fn fees(&self) -> BoxFuture<'_, Result<Fees, PhalaAvsError>> {
+        Box::pin(async {
+            Ok(Fees::Eip1559 {
+                max_fee_per_gas: 2_000,
+                max_priority_fee_per_gas: 100,
+            })
+        })
+    }
+
+    fn broadcast(&self, tx: SignedTx) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        let result = if (tx.nonce as usize) < self.included_count(tx.from) {
+            Err(PhalaAvsError::EvmError("nonce too low".to_string()))
+        } else {
+            self.pending
+                .lock()
+                .unwrap()
+                .insert((tx.from, tx.nonce), tx.hash);
+            Ok(())
+        };
+        Box::pin(async move { result })
+    }
+
+    fn receipt(&self, tx_hash: B256) -> BoxFuture<'_, Result<Option<TxOutcome>, PhalaAvsError>> {
+        let block = self
+            .included
+            .lock()
+            .unwrap()
+            .values()
+            .find_map(|hashes| hashes.iter().position(|h| *h == tx_hash));
+        Box::pin(async move {
+            Ok(block.map(|block| TxOutcome::Included {
+                tx_hash,
+                block: block as u64,
+                success: true,
+            }))
+        })
+    }
+
+    fn included_nonce(&self, account: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        let nonce = self.included_count(account) as u64;
+        Box::pin(async move { Ok(nonce) })
+    }
+}
+
+impl AccountSource for SimulatedTxChain {
+    fn pending_nonce(&self, account: Address) -> BoxFuture<'_, Result<u64, PhalaAvsError>> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(a, _)| *a == account)
+            .count();
+        let nonce = (self.included_count(account) + pending) as u64;
+        Box::pin(async move { Ok(nonce) })
+    }
+
+    fn balance(&self, _: Address) -> BoxFuture<'_, Result<u128, PhalaAvsError>> {
+        Box::pin(async { Ok(u128::MAX) })
+    }
+
+    fn maintenance_signer(&self, _: Address) -> BoxFuture<'_, Result<Address, PhalaAvsError>> {
+        Box::pin(async { Ok(Address::ZERO) })
+    }
+}
+
+/// A sender as built at process start: fresh lanes over the persisted state.
+fn start(chain: &Arc<SimulatedTxChain>, store: &Arc<dyn StateStore>) -> TxSender {
+    let config = LaneConfig {
+        maintenance_key: None,
+        fallback_to_primary: false,
+        min_balance_wei: 0,
+        check_secs: 60,
+    };
+    let lanes = SignerLanes::new(config, PRIMARY_KEY, Arc::clone(chain) as _).unwrap();
+    TxSender::new(
+        TxSenderConfig {
+            receipt_poll: Duration::from_millis(1),
+            ..TxSenderConfig::default()
+        },
+        31337,
+        Arc::new(lanes),
+        Arc::clone(chain) as _,
+        Arc::clone(store),
+    )
+}
+
+fn respond(challenge_id: u64) -> TxCall {
+    TxCall::new(
+        "respondToSlaChallenge",
+        ORACLE,
+        U256::from(challenge_id).to_be_bytes_vec(),
+    )
+    .responding_to(U256::from(challenge_id))
+}
+
+async fn crash_and_recover(point: CrashPoint) {
+    let chain = Arc::new(SimulatedTxChain::default());
+    let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+    let miner = tokio::spawn({
+        let chain = Arc::clone(&chain);
+        async move {
+            loop {
+                chain.mine();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+    });
+
+    let engine = Arc::new(ChaosEngine::new(ChaosConfig::default()).unwrap());
+    let crashing = Arc::new(start(&chain, &store).with_chaos(Arc::clone(&engine)));
+    let run = tokio::spawn({
+        let engine = Arc::clone(&engine);
+        async move {
+            for id in 0..CHALLENGES {
+                if id == BEFORE_CRASH {
+                    engine.arm_crash(point);
+                }
+                crashing.send(TxClass::Urgent, respond(id)).await.unwrap();
+            }
+        }
+    });
+    let crash = run.await.unwrap_err();
+    assert!(crash.is_panic(), "{point:?}: the run did not crash");
+
+    let restarted = start(&chain, &store);
+    let pending = restarted.recover().await.unwrap();
+    for result in restarted.watch_recovered(pending).await {
+        result.unwrap();
+    }
+    // Every challenge is answered again; those already answered send nothing.
+    for id in 0..CHALLENGES {
+        restarted.send(TxClass::Urgent, respond(id)).await.unwrap();
+    }
+    miner.abort();
+
+    let intents = restarted.intents().unwrap();
+    let included = chain.included.lock().unwrap()[&intents[0].account].clone();
+    assert!(
+        chain.pending.lock().unwrap().is_empty(),
+        "{point:?}: a transaction was left pending"
+    );
+    let mut answered = BTreeSet::new();
+    for hash in &included {
+        let intent = intents
+            .iter()
+            .find(|intent| intent.tx_hash == Some(*hash) || intent.replaced.contains(hash))
+            .unwrap_or_else(|| panic!("{point:?}: included {hash} has no intent"));
+        assert_eq!(intent.stage, IntentStage::Finalized, "{point:?}");
+        let challenge = intent.call.challenge_id.unwrap();
+        assert!(
+            answered.insert(challenge),
+            "{point:?}: challenge {challenge} was answered twice"
+        );
+    }
+    assert_eq!(
+        answered,
+        (0..CHALLENGES).map(U256::from).collect(),
+        "{point:?}: a challenge was lost"
+    );
+    // Nonces were handed out without gaps: the account's transactions are exactly the answers.
+    assert_eq!(included.len() as u64, CHALLENGES, "{point:?}");
+}
+
+#[tokio::test]
+async fn crash_after_prepare_loses_and_repeats_nothing() {
+    crash_and_recover(CrashPoint::AfterPrepare).await;
+}
+
+#[tokio::test]
+async fn crash_after_sign_loses_and_repeats_nothing() {
+    crash_and_recover(CrashPoint::AfterSign).await;
+}
+
+#[tokio::test]
+async fn crash_after_broadcast_loses_and_repeats_nothing() {
+    crash_and_recover(CrashPoint::AfterBroadcast).await;
+}
+
+#[tokio::test]
+async fn crash_after_inclusion_loses_and_repeats_nothing() {
+    crash_and_recover(CrashPoint::AfterInclusion).await;
+}
@@ -0,0 +1,310 @@
+//! Wire-format compatibility between operators and the aggregator, across versions.
+//!
+//! Golden requests and replies of every aggregator RPC method are frozen per wire-format version
+//! under `tests/fixtures/wire/v<N>`. The current client is run against a server simulated from
+//! the previous version's fixtures, and the current [`AggregatorServer`] is sent the requests an
+//! older client sent. A change to an envelope that breaks either direction fails here.
+#![cfg(feature = "aggregator")]
+
+use blueprint_sdk::alloy::primitives::Bytes;
+use phala_tee_cloud_avs_blueprint_lib::aggregator::client::AggregatorClient;
+use phala_tee_cloud_avs_blueprint_lib::aggregator::dedupe::{DedupeConfig, ResponseLedger};
+use phala_tee_cloud_avs_blueprint_lib::aggregator::idempotency::IdempotencyCache;
+use phala_tee_cloud_avs_blueprint_lib::aggregator::server::{Aggregation, AggregatorServer};
+use phala_tee_cloud_avs_blueprint_lib::aggregator_admin::{
+    DeadLetterEntry, EquivocationEntry, GET_TASK_RESPONSES_METHOD, LIST_DEAD_LETTERS_METHOD,
+    LIST_EQUIVOCATIONS_METHOD, REPLAY_DEAD_LETTER_METHOD, ReplayRequest, TaskResponseHistory,
+    TaskResponsesRequest, parse_reply, request,
+};
+use phala_tee_cloud_avs_blueprint_lib::aggregator_wire::{
+    CURRENT_WIRE_VERSION, FEATURE_MAJORITY_DIGEST, MAJORITY_DIGEST_METHOD,
+    METHOD_NOT_FOUND_ERROR_CODE, MajorityDigest, MajorityRequest, SERVER_INFO_METHOD,
+    SUBMIT_RESPONSE_METHOD, SUPPORTED_WIRE_VERSIONS, ServerInfo, SignedTaskResponse, SubmitReply,
+    negotiate, parse_submission,
+};
+use phala_tee_cloud_avs_blueprint_lib::error::PhalaAvsError;
+use phala_tee_cloud_avs_blueprint_lib::evm::BoxFuture;
+use phala_tee_cloud_avs_blueprint_lib::state::{MemoryStateStore, StateStore};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const ADMIN_TOKEN: &str = "admin-secret";
+
+const METHODS: &[&str] = &[
+    SUBMIT_RESPONSE_METHOD,
+    SERVER_INFO_METHOD,
+    LIST_DEAD_LETTERS_METHOD,
+    REPLAY_DEAD_LETTER_METHOD,
+    LIST_EQUIVOCATIONS_METHOD,
+    GET_TASK_RESPONSES_METHOD,
+    MAJORITY_DIGEST_METHOD,
+];
+
+/// The golden `(params, reply)` exchanges of `method` in wire-format `version`.
+fn exchanges(version: u32, method: &str) -> Vec<(Value, Value)> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/wire")
+        .join(format!("v{version}"))
+        .join(format!("{method}.json"));
+    let raw = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("missing fixture {}: {e}", path.display()));
+    let fixture: Value = serde_json::from_str(&raw).unwrap();
+    assert_eq!(fixture["method"], method);
+    fixture["exchanges"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["request"].clone(), e["reply"].clone()))
+        .collect()
+}
+
+/// A server of wire-format `version`, answering from its fixtures.
+fn simulated_server(version: u32, method: &str, params: &Value) -> Value {
+    exchanges(version, method)
+        .into_iter()
+        .find(|(request, _)| request == params)
+        .map(|(_, reply)| reply)
+        .unwrap_or_else(|| panic!("v{version} server does not understand {method} {params}"))
+}
+
+/// Accepts every response, as the BLS aggregation does validly signed ones.
+struct AcceptAll;
+
+impl Aggregation for AcceptAll {
+    fn encode(&self, task_response: &Value) -> Result<Bytes, PhalaAvsError> {
+        Ok(serde_json::to_vec(task_response).unwrap().into())
+    }
+
+    fn verify<'a>(
+        &'a self,
+        _response: &'a SignedTaskResponse,
+    ) -> BoxFuture<'a, Result<(), PhalaAvsError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn aggregate(&self, _response: SignedTaskResponse) -> BoxFuture<'_, Result<(), PhalaAvsError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// The current build's aggregator, with the admin methods enabled.
+fn current_server() -> AggregatorServer {
+    let config = DedupeConfig {
+        retention_secs: 600,
+        exclude_after: None,
+        conflict_window_secs: 600,
+    };
+    let store: Arc<dyn StateStore> = Arc::new(MemoryStateStore::default());
+    let idempotency = IdempotencyCache::new(Arc::clone(&store), 600);
+    let ledger = ResponseLedger::new(config, store).unwrap();
+    AggregatorServer::new(
+        Arc::new(AcceptAll),
+        ledger,
+        idempotency,
+        Some(ADMIN_TOKEN.to_string()),
+    )
+}
+
+/// What `server` replies to `method` with `params`.
+fn served(server: &AggregatorServer, method: &str, params: &Value) -> Value {
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let io = server.io_handler();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let reply = runtime.block_on(io.handle_request(&request.to_string()));
+    serde_json::from_str(&reply.unwrap()).unwrap()
+}
+
+fn error_code(reply: &Value) -> Option<i64> {
+    reply["error"]["code"].as_i64()
+}
+
+/// What the current client makes of the probe reply of a version's server.
+fn negotiated_with(version: u32) -> u32 {
+    let reply = simulated_server(version, SERVER_INFO_METHOD, &json!({}));
+    if error_code(&reply) == Some(METHOD_NOT_FOUND_ERROR_CODE) {
+        return negotiate(None).unwrap();
+    }
+    let info: ServerInfo = parse_reply(SERVER_INFO_METHOD, reply).unwrap();
+    negotiate(Some(&info)).unwrap()
+}
+
+/// Sends the admin calls of the current client to a version's server and parses the replies.
+fn admin_round_trip(version: u32) {
+    fn call<T: DeserializeOwned>(version: u32, method: &str, params: Value) -> Result<T, String> {
+        let reply = simulated_server(
+            version,
+            method,
+            &request(method, params, ADMIN_TOKEN)["params"],
+        );
+        parse_reply(method, reply).map_err(|e| e.to_string())
+    }
+    let dead: Vec<DeadLetterEntry> = call(version, LIST_DEAD_LETTERS_METHOD, json!({})).unwrap();
+    assert_eq!(dead[0].task_index, 7);
+    let replay = ReplayRequest {
+        task_index: 7,
+        overrides: serde_json::from_value(
+            json!({ "gas_limit": 2_000_000, "refresh_stake_indices": true }),
+        )
+        .unwrap(),
+    };
+    let replayed: DeadLetterEntry =
+        call(version, REPLAY_DEAD_LETTER_METHOD, json!(replay)).unwrap();
+    assert_eq!(replayed.attempts, 2);
+    let missing = ReplayRequest {
+        task_index: 8,
+        overrides: Default::default(),
+    };
+    let err = call::<DeadLetterEntry>(version, REPLAY_DEAD_LETTER_METHOD, json!(missing));
+    assert!(err.unwrap_err().contains("no dead letter for task 8"));
+    let equivocations: Vec<EquivocationEntry> =
+        call(version, LIST_EQUIVOCATIONS_METHOD, json!({})).unwrap();
+    assert_eq!(equivocations.len(), 1);
+    let history: TaskResponseHistory = call(
+        version,
+        GET_TASK_RESPONSES_METHOD,
+        json!(TaskResponsesRequest { task_index: 7 }),
+    )
+    .unwrap();
+    assert_eq!(history.responses.len(), 1);
+}
+
+#[test]
+fn every_method_has_fixtures_in_every_supported_version() {
+    for &version in SUPPORTED_WIRE_VERSIONS {
+        for method in METHODS {
+            assert!(
+                !exchanges(version, method).is_empty(),
+                "v{version} {method}"
+            );
+        }
+    }
+}
+
+#[test]
+fn current_client_speaks_to_a_previous_version_server() {
+    let previous = CURRENT_WIRE_VERSION - 1;
+    // The old server has no `get_server_info`, so the client falls back to its version.
+    assert_eq!(negotiated_with(previous), previous);
+    assert_eq!(negotiated_with(CURRENT_WIRE_VERSION), CURRENT_WIRE_VERSION);
+
+    for version in [previous, CURRENT_WIRE_VERSION] {
+        for (params, reply) in exchanges(version, SUBMIT_RESPONSE_METHOD) {
+            // What the client sends in the negotiated version is byte-for-byte what that
+            // version's server was recorded accepting.
+            let submission = parse_submission(params.clone()).unwrap();
+            let sent = submission.envelope(version).unwrap();
+            assert_eq!(sent, params, "v{version} envelope changed");
+            let reply = simulated_server(version, SUBMIT_RESPONSE_METHOD, &sent);
+            if error_code(&reply).is_none() {
+                let reply: SubmitReply = parse_reply(SUBMIT_RESPONSE_METHOD, reply).unwrap();
+                assert!(reply.accepted());
+            }
+        }
+        admin_round_trip(version);
+    }
+}
+
+#[test]
+fn current_server_accepts_previous_version_clients() {
+    let previous = exchanges(CURRENT_WIRE_VERSION - 1, SUBMIT_RESPONSE_METHOD);
+    let current = exchanges(CURRENT_WIRE_VERSION, SUBMIT_RESPONSE_METHOD);
+    assert_eq!(previous.len(), current.len());
+    for ((old, _), (new, _)) in previous.into_iter().zip(current) {
+        // An old envelope maps to the same submission as its current equivalent.
+        assert_eq!(
+            parse_submission(old).unwrap(),
+            parse_submission(new).unwrap()
+        );
+    }
+
+    // Without a key or trace context, the fields default to none.
+    let (unkeyed, _) = &exchanges(CURRENT_WIRE_VERSION - 1, SUBMIT_RESPONSE_METHOD)[0];
+    let submission = parse_submission(unkeyed.clone()).unwrap();
+    assert_eq!(submission.idempotency_key, None);
+    assert_eq!(submission.traceparent, None);
+
+    // Admin params of either version read into the server's request types.
+    for &version in SUPPORTED_WIRE_VERSIONS {
+        for (params, _) in exchanges(version, REPLAY_DEAD_LETTER_METHOD) {
+            let replay: ReplayRequest = serde_json::from_value(params).unwrap();
+            assert!(replay.task_index >= 7);
+        }
+        for (params, _) in exchanges(version, GET_TASK_RESPONSES_METHOD) {
+            let request: TaskResponsesRequest = serde_json::from_value(params).unwrap();
+            assert_eq!(request.task_index, 7);
+        }
+    }
+}
+
+#[test]
+fn majority_digest_is_asked_only_of_servers_announcing_it() {
+    let params = json!(MajorityRequest { task_index: 7 });
+    // A previous version's server has no such method; the safety check goes without it.
+    let reply = simulated_server(CURRENT_WIRE_VERSION - 1, MAJORITY_DIGEST_METHOD, &params);
+    assert_eq!(error_code(&reply), Some(METHOD_NOT_FOUND_ERROR_CODE));
+
+    let info: ServerInfo = parse_reply(
+        SERVER_INFO_METHOD,
+        simulated_server(CURRENT_WIRE_VERSION, SERVER_INFO_METHOD, &json!({})),
+    )
+    .unwrap();
+    assert!(info.supports(FEATURE_MAJORITY_DIGEST));
+    let reply = simulated_server(CURRENT_WIRE_VERSION, MAJORITY_DIGEST_METHOD, &params);
+    let majority: MajorityDigest = parse_reply(MAJORITY_DIGEST_METHOD, reply).unwrap();
+    assert_eq!(majority.task_index, 7);
+    assert_eq!(majority.digests[0].count, 1);
+}
+
+#[test]
+fn server_info_matches_its_golden_reply() {
+    let (_, reply) = &exchanges(CURRENT_WIRE_VERSION, SERVER_INFO_METHOD)[0];
+    assert_eq!(reply["result"], json!(ServerInfo::current(true)));
+}
+
+#[test]
+fn current_server_gives_the_golden_replies() {
+    let server = current_server();
+    let (params, reply) = &exchanges(CURRENT_WIRE_VERSION, SERVER_INFO_METHOD)[0];
+    assert_eq!(&served(&server, SERVER_INFO_METHOD, params), reply);
+
+    // An unkeyed submission and a keyed retry of it, in the envelope of each version still
+    // accepted. The last exchange conflicts with a response the server never saw.
+    for &version in SUPPORTED_WIRE_VERSIONS {
+        let server = current_server();
+        let exchanges = exchanges(version, SUBMIT_RESPONSE_METHOD);
+        for (params, reply) in &exchanges[..2] {
+            let served = served(&server, SUBMIT_RESPONSE_METHOD, params);
+            assert_eq!(&served, reply, "v{version}");
+        }
+    }
+}
+
+#[test]
+fn current_client_negotiates_with_the_current_server() {
+    let http = current_server()
+        .start(&"127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let url = format!("http://{}", http.address());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let client = runtime.block_on(AggregatorClient::connect(url)).unwrap();
+    http.close();
+    assert_eq!(client.wire_version(), CURRENT_WIRE_VERSION);
+    assert!(client.supports(FEATURE_MAJORITY_DIGEST));
+}
+
+#[test]
+fn a_breaking_envelope_change_is_caught() {
+    // Renaming the response field, as a careless refactor might.
+    let (params, _) = &exchanges(CURRENT_WIRE_VERSION, SUBMIT_RESPONSE_METHOD)[1];
+    let mut broken = params.clone();
+    let response = broken.as_object_mut().unwrap().remove("response").unwrap();
+    broken["signed_response"] = response;
+    assert!(parse_submission(broken.clone()).is_err());
+    let recorded: Vec<_> = exchanges(CURRENT_WIRE_VERSION, SUBMIT_RESPONSE_METHOD)
+        .into_iter()
+        .map(|(request, _)| request)
+        .collect();
+    assert!(!recorded.contains(&broken));
+}